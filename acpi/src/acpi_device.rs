@@ -10,7 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use address_space::GuestAddress;
 use log::error;
@@ -49,16 +49,23 @@ impl AcpiPMTimer {
                 "PM Timer read: invalid data length {}, required length is 4",
                 data.len()
             );
+            return false;
         }
-        let now = Instant::now();
-        let time_nanos = now.duration_since(self.start).as_nanos();
-        let counter: u128 = (time_nanos * PM_TIMER_FREQUENCY) / (NANOSECONDS_PER_SECOND as u128);
+        let counter = pm_timer_counter(self.start.elapsed());
 
-        data.copy_from_slice(&((counter & 0xFFFF_FFFF) as u32).to_le_bytes());
+        data.copy_from_slice(&counter.to_le_bytes());
         true
     }
 }
 
+/// Get the value of the 32-bit PM Timer counter after `elapsed` time, which rolls over
+/// to 0 about every 20 minutes.
+fn pm_timer_counter(elapsed: Duration) -> u32 {
+    let counter: u128 =
+        (elapsed.as_nanos() * PM_TIMER_FREQUENCY) / (NANOSECONDS_PER_SECOND as u128);
+    (counter & 0xFFFF_FFFF) as u32
+}
+
 #[derive(Default)]
 pub struct AcpiPmEvent {
     // PM1 Status Registers, location: PM1a_EVT_BLK.
@@ -135,3 +142,30 @@ impl AcpiPmCtrl {
         value & ACPI_BITMASK_SLEEP_ENABLE != 0
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pm_timer_counter() {
+        assert_eq!(pm_timer_counter(Duration::ZERO), 0);
+        assert_eq!(pm_timer_counter(Duration::from_secs(1)), 3_579_545);
+        assert_eq!(pm_timer_counter(Duration::from_micros(1)), 3);
+
+        // The counter rolls over after 2^32 ticks, about 1199.86 seconds.
+        let rollover = Duration::from_nanos(
+            ((1_u128 << 32) * NANOSECONDS_PER_SECOND as u128 / PM_TIMER_FREQUENCY) as u64,
+        );
+        assert_eq!(pm_timer_counter(rollover), u32::MAX);
+        assert_eq!(pm_timer_counter(rollover + Duration::from_micros(1)), 3);
+        assert_eq!(pm_timer_counter(Duration::from_secs(1200)), 486_704);
+
+        let mut timer = AcpiPMTimer::new();
+        let mut data = [0_u8; 4];
+        assert!(timer.read(&mut data, GuestAddress(0), 0));
+        assert!(u32::from_le_bytes(data) < PM_TIMER_FREQUENCY as u32);
+        let mut data = [0_u8; 2];
+        assert!(!timer.read(&mut data, GuestAddress(0), 0));
+    }
+}
@@ -293,6 +293,36 @@ impl AmlBuilder for AcpiSratProcessorAffinity {
     }
 }
 
+/// ACPI SRAT processor local x2APIC affinity structure.
+#[repr(C, packed)]
+#[derive(Default, Copy, Clone)]
+pub struct AcpiSratX2ApicAffinity {
+    /// Type ID.
+    pub type_id: u8,
+    /// The length of this structure.
+    pub length: u8,
+    /// Reserved field.
+    pub reserved1: u16,
+    /// The proximity domain to which the processor belongs.
+    pub proximity_domain: u32,
+    /// The processor local x2APIC ID.
+    pub x2apic_id: u32,
+    /// The processor affinity flags.
+    pub flags: u32,
+    /// The clock domain to which the processor belongs.
+    pub clock_domain: u32,
+    /// Reserved field.
+    pub reserved2: u32,
+}
+
+impl ByteCode for AcpiSratX2ApicAffinity {}
+
+impl AmlBuilder for AcpiSratX2ApicAffinity {
+    fn aml_bytes(&self) -> Vec<u8> {
+        Vec::from(self.as_bytes())
+    }
+}
+
 /// ACPI SRAT GICC affinity structure.
 #[repr(C, packed)]
 #[derive(Default, Copy, Clone)]
@@ -382,6 +412,32 @@ pub mod madt_subtable {
         }
     }
 
+    /// MADT processor Local x2APIC structure, used for APIC IDs that don't fit in a byte.
+    #[repr(C, packed)]
+    #[derive(Default, Copy, Clone)]
+    pub struct AcpiLocalX2Apic {
+        /// Type ID.
+        pub type_id: u8,
+        /// The length of this structure.
+        pub length: u8,
+        /// Reserved field.
+        pub reserved: u16,
+        /// The processor's local x2APIC ID.
+        pub x2apic_id: u32,
+        /// Local APIC flags.
+        pub flags: u32,
+        /// ACPI processor UID.
+        pub processor_uid: u32,
+    }
+
+    impl ByteCode for AcpiLocalX2Apic {}
+
+    impl AmlBuilder for AcpiLocalX2Apic {
+        fn aml_bytes(&self) -> Vec<u8> {
+            Vec::from(self.as_bytes())
+        }
+    }
+
     /// IO APIC structure.
     #[repr(C, packed)]
     #[derive(Default, Copy, Clone)]
@@ -407,6 +463,32 @@ pub mod madt_subtable {
             Vec::from(self.as_bytes())
         }
     }
+
+    /// Interrupt Source Override structure.
+    #[repr(C, packed)]
+    #[derive(Default, Copy, Clone)]
+    pub struct AcpiInterruptSourceOverride {
+        /// Type ID.
+        pub type_id: u8,
+        /// The length of this structure.
+        pub length: u8,
+        /// Bus of the source, 0 means ISA.
+        pub bus: u8,
+        /// Bus-relative interrupt source.
+        pub source: u8,
+        /// The GSI that this bus-relative interrupt source will signal.
+        pub gsi: u32,
+        /// MPS INTI flags, polarity and trigger mode of the interrupt.
+        pub flags: u16,
+    }
+
+    impl ByteCode for AcpiInterruptSourceOverride {}
+
+    impl AmlBuilder for AcpiInterruptSourceOverride {
+        fn aml_bytes(&self) -> Vec<u8> {
+            Vec::from(self.as_bytes())
+        }
+    }
 }
 
 /// This module describes ACPI MADT's sub-tables on aarch64 platform.
@@ -10,8 +10,9 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use arc_swap::ArcSwap;
+use std::cmp;
 use std::fmt;
 use std::fmt::Debug;
 use std::io::Write;
@@ -22,8 +23,8 @@ use util::byte_code::ByteCode;
 use util::test_helper::is_test_enabled;
 
 use crate::{
-    AddressRange, AddressSpaceError, FlatRange, GuestAddress, Listener, ListenerReqType, Region,
-    RegionIoEventFd, RegionType,
+    AddressRange, AddressSpaceError, FlatRange, GuestAddress, HostMemMapping, Listener,
+    ListenerReqType, Region, RegionIoEventFd, RegionType,
 };
 
 /// Contains an array of `FlatRange`.
@@ -48,6 +49,27 @@ pub struct RegionCache {
     pub end: u64,
 }
 
+/// Reference to the memory mapping of a region, which keeps the memory mapped in the host
+/// until it's dropped, even if the region is removed from the address space meanwhile,
+/// e.g. by memory hot unplug.
+#[derive(Clone, Debug)]
+pub struct RegionGuard {
+    _mapping: Arc<HostMemMapping>,
+}
+
+/// A part of the guest memory range translated by `AddressSpace::get_host_ranges`.
+#[derive(Clone, Debug)]
+pub struct HostRange {
+    /// Guest address of the part.
+    pub gpa: GuestAddress,
+    /// Host address of the part.
+    pub hva: u64,
+    /// Length of the part.
+    pub len: u64,
+    /// Keeps the part mapped in the host while it's accessed by `hva`.
+    pub guard: RegionGuard,
+}
+
 type ListenerObj = Arc<Mutex<dyn Listener>>;
 
 /// Address Space of memory.
@@ -378,6 +400,53 @@ impl AddressSpace {
         }
     }
 
+    /// Translate the guest memory range to host, which is split into parts at the boundaries
+    /// of the regions. Unlike `get_host_address`, the whole range is checked, and the guards
+    /// of the parts keep them mapped in the host until they're dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Guest address.
+    /// * `len` - Length of the range.
+    ///
+    /// # Errors
+    ///
+    /// Return Error if any part of the range is not memory of Ram or RamDevice region.
+    pub fn get_host_ranges(&self, addr: GuestAddress, len: u64) -> Result<Vec<HostRange>> {
+        if addr.checked_add(len).is_none() {
+            bail!(AddressSpaceError::Overflow(addr.raw_value()));
+        }
+        let view = self.flat_view.load();
+        let mut ranges = Vec::new();
+        let mut gpa = addr;
+        let mut left = len;
+        while left > 0 {
+            let fr = view
+                .find_flatrange(gpa)
+                .with_context(|| anyhow!(AddressSpaceError::RegionNotFound(gpa.raw_value())))?;
+            let region_type = fr.owner.region_type();
+            let mapping = match fr.owner.mem_mapping() {
+                Some(mapping)
+                    if region_type == RegionType::Ram || region_type == RegionType::RamDevice =>
+                {
+                    mapping
+                }
+                _ => bail!(AddressSpaceError::RegionType(region_type)),
+            };
+            let offset = gpa.offset_from(fr.addr_range.base);
+            let part = cmp::min(left, fr.addr_range.size - offset);
+            ranges.push(HostRange {
+                gpa,
+                hva: mapping.host_address() + fr.offset_in_region + offset,
+                len: part,
+                guard: RegionGuard { _mapping: mapping },
+            });
+            gpa = gpa.unchecked_add(part);
+            left -= part;
+        }
+        Ok(ranges)
+    }
+
     /// Check if the GuestAddress is in one of Ram region.
     ///
     /// # Arguments
@@ -421,6 +490,17 @@ impl AddressSpace {
             .map_or(GuestAddress(0), |fr| fr.addr_range.end_addr())
     }
 
+    /// Return the guest physical ranges backed by Ram regions in ascending order.
+    pub fn ram_ranges(&self) -> Vec<AddressRange> {
+        self.flat_view
+            .load()
+            .0
+            .iter()
+            .filter(|fr| fr.owner.region_type() == RegionType::Ram)
+            .map(|fr| fr.addr_range)
+            .collect()
+    }
+
     /// Read memory segment to `dst`.
     ///
     /// # Arguments
@@ -1034,6 +1114,13 @@ mod test {
         assert_eq!(space.address_in_memory(GuestAddress(1000), 0), false);
         assert_eq!(space.address_in_memory(GuestAddress(1500), 0), false);
         assert!(space.address_in_memory(GuestAddress(2900), 0));
+        assert_eq!(
+            space.ram_ranges(),
+            vec![
+                AddressRange::from((0, 1000)),
+                AddressRange::from((2000, 1000))
+            ]
+        );
 
         assert_eq!(
             space.get_host_address(GuestAddress(500)),
@@ -1065,6 +1152,13 @@ mod test {
         assert_eq!(space.address_in_memory(GuestAddress(1500), 0), false);
         assert_eq!(space.address_in_memory(GuestAddress(2400), 0), false);
         assert!(space.address_in_memory(GuestAddress(2900), 0));
+        assert_eq!(
+            space.ram_ranges(),
+            vec![
+                AddressRange::from((0, 1000)),
+                AddressRange::from((2500, 500))
+            ]
+        );
 
         assert_eq!(
             space.get_host_address(GuestAddress(500)),
@@ -1094,4 +1188,52 @@ mod test {
         assert_eq!(data1, 10000);
         assert!(space.write_object(&data, GuestAddress(993)).is_err());
     }
+
+    #[test]
+    fn test_get_host_ranges() {
+        let root = Region::init_container_region(8000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram1 = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 1000, None, false, false, false).unwrap(),
+        );
+        let ram2 = Arc::new(
+            HostMemMapping::new(GuestAddress(1000), None, 1000, None, false, false, false).unwrap(),
+        );
+        let (hva1, hva2) = (ram1.host_address(), ram2.host_address());
+        let region_a = Region::init_ram_region(ram1);
+        let region_b = Region::init_ram_region(ram2);
+        root.add_subregion(region_a, 0).unwrap();
+        root.add_subregion(region_b.clone(), 1000).unwrap();
+
+        // The range is split at the boundary of the regions.
+        let ranges = space.get_host_ranges(GuestAddress(900), 200).unwrap();
+        assert_eq!(ranges.len(), 2);
+        assert_eq!(
+            (ranges[0].gpa, ranges[0].hva, ranges[0].len),
+            (GuestAddress(900), hva1 + 900, 100)
+        );
+        assert_eq!(
+            (ranges[1].gpa, ranges[1].hva, ranges[1].len),
+            (GuestAddress(1000), hva2, 100)
+        );
+        assert!(space.get_host_ranges(GuestAddress(1900), 200).is_err());
+        assert!(space.get_host_ranges(GuestAddress(100), u64::MAX).is_err());
+        assert!(space
+            .get_host_ranges(GuestAddress(0), 0)
+            .unwrap()
+            .is_empty());
+        drop(ranges);
+
+        // The region is removed while the range is in use: the guest address is no longer
+        // accessible, but the guard keeps the memory mapped until it's dropped.
+        let ranges = space.get_host_ranges(GuestAddress(1000), 8).unwrap();
+        root.delete_subregion(&region_b).unwrap();
+        drop(region_b);
+        assert!(space.get_host_ranges(GuestAddress(1000), 8).is_err());
+        assert!(space.write_object(&1_u64, GuestAddress(1000)).is_err());
+        assert_eq!(Arc::strong_count(&ranges[0].guard._mapping), 1);
+        assert!(space
+            .read_object_direct::<u64>(ranges[0].hva)
+            .is_ok_and(|data| data == 0));
+    }
 }
@@ -13,12 +13,13 @@
 use std::cmp::min;
 use std::fs::File;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
 
 use anyhow::{bail, Context, Result};
 use log::{error, info};
-use machine_manager::config::{HostMemPolicy, MachineMemConfig, MemZoneConfig};
+use machine_manager::config::{HostMemPolicy, MachineMemConfig, MemLock, MemZoneConfig};
 use util::{
     syscall::mbind,
     unix::{do_mmap, host_page_size},
@@ -26,11 +27,16 @@ use util::{
 
 use crate::{AddressRange, GuestAddress};
 
-const MAX_PREALLOC_THREAD: u8 = 16;
+const MAX_PREALLOC_THREAD: u16 = 16;
 /// Verify existing pages in the mapping.
 const MPOL_MF_STRICT: u32 = 1;
 /// Move pages owned by this process to conform to mapping.
 const MPOL_MF_MOVE: u32 = 2;
+/// Bit of CAP_IPC_LOCK in the capability sets, which allows locking memory beyond RLIMIT_MEMLOCK.
+const CAP_IPC_LOCK: u32 = 14;
+
+/// Size of guest memory locked in host RAM by `mem-lock`.
+static LOCKED_MEM_SIZE: AtomicU64 = AtomicU64::new(0);
 
 /// FileBackend represents backend-file of `HostMemMapping`.
 #[derive(Clone, Debug)]
@@ -165,10 +171,10 @@ impl FileBackend {
 /// # Arguments
 ///
 /// * `nr_vcpus` - Number of vcpus.
-fn max_nr_threads(nr_vcpus: u8) -> u8 {
+fn max_nr_threads(nr_vcpus: u16) -> u16 {
     let nr_host_cpu = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_ONLN) };
     if nr_host_cpu > 0 {
-        return min(min(nr_host_cpu as u8, MAX_PREALLOC_THREAD), nr_vcpus);
+        return min(min(nr_host_cpu as u16, MAX_PREALLOC_THREAD), nr_vcpus);
     }
     // If fails to call `sysconf` function, just use a single thread to touch pages.
     1
@@ -204,7 +210,7 @@ fn touch_pages(start: u64, page_size: u64, nr_pages: u64) {
 /// * `host_addr` - The start host address to pre allocate.
 /// * `size` - Size of memory.
 /// * `nr_vcpus` - Number of vcpus.
-fn mem_prealloc(host_addr: u64, size: u64, nr_vcpus: u8) {
+fn mem_prealloc(host_addr: u64, size: u64, nr_vcpus: u16) {
     let page_size = host_page_size();
     let threads = max_nr_threads(nr_vcpus);
     let nr_pages = (size + page_size - 1) / page_size;
@@ -213,7 +219,7 @@ fn mem_prealloc(host_addr: u64, size: u64, nr_vcpus: u8) {
     let mut addr = host_addr;
     let mut threads_join = Vec::new();
     for i in 0..threads {
-        let touch_nr_pages = if i < (left as u8) {
+        let touch_nr_pages = if i < (left as u16) {
             pages_per_thread + 1
         } else {
             pages_per_thread
@@ -232,7 +238,64 @@ fn mem_prealloc(host_addr: u64, size: u64, nr_vcpus: u8) {
     }
 }
 
-/// Create HostMemMappings according to address ranges.
+/// Get the size of guest memory locked in host RAM.
+pub fn locked_mem_size() -> u64 {
+    LOCKED_MEM_SIZE.load(Ordering::Relaxed)
+}
+
+/// Check whether `size` more bytes of memory can be locked.
+///
+/// # Arguments
+///
+/// * `size` - Size of memory to lock.
+/// * `locked` - Size of memory locked already.
+/// * `limit` - Soft limit of RLIMIT_MEMLOCK in bytes.
+/// * `cap_ipc_lock` - Whether the process has CAP_IPC_LOCK, which ignores the limit.
+fn check_mem_lock_limit(size: u64, locked: u64, limit: u64, cap_ipc_lock: bool) -> Result<()> {
+    if cap_ipc_lock || limit == libc::RLIM_INFINITY {
+        return Ok(());
+    }
+    let required = locked + size;
+    if required > limit {
+        let required_kib = required.div_ceil(1024);
+        bail!(
+            "Locking {} bytes of guest memory requires RLIMIT_MEMLOCK of at least {} KiB, but it's {} KiB. \
+            Raise it, e.g. by `ulimit -l {}`, or grant CAP_IPC_LOCK",
+            size,
+            required_kib,
+            limit / 1024,
+            required_kib
+        );
+    }
+    Ok(())
+}
+
+/// Check whether `size` more bytes of guest memory can be locked by this process.
+fn check_host_mem_lock(size: u64) -> Result<()> {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    // Safe because rlimit is a valid and writable struct.
+    if unsafe { libc::getrlimit(libc::RLIMIT_MEMLOCK, &mut rlimit) } < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| "Failed to get RLIMIT_MEMLOCK");
+    }
+    let cap_ipc_lock = std::fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("CapEff:"))
+                .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        })
+        .is_some_and(|caps| caps & (1 << CAP_IPC_LOCK) != 0);
+    check_mem_lock_limit(size, locked_mem_size(), rlimit.rlim_cur, cap_ipc_lock)
+}
+
+/// Create HostMemMappings according to address ranges. The mappings are locked in host
+/// RAM if `mem_lock` of `mem_config` is set, which also applies to memory hot-added later
+/// through this function.
 ///
 /// # Arguments
 ///
@@ -241,10 +304,13 @@ fn mem_prealloc(host_addr: u64, size: u64, nr_vcpus: u8) {
 pub fn create_host_mmaps(
     ranges: &[(u64, u64)],
     mem_config: &MachineMemConfig,
-    nr_vcpus: u8,
+    nr_vcpus: u16,
 ) -> Result<Vec<Arc<HostMemMapping>>> {
     let mut f_back: Option<FileBackend> = None;
 
+    if mem_config.mem_lock != MemLock::Off {
+        check_host_mem_lock(ranges.iter().fold(0, |acc, x| acc + x.1))?;
+    }
     if let Some(path) = &mem_config.mem_path {
         let file_len = ranges.iter().fold(0, |acc, x| acc + x.1);
         f_back = Some(
@@ -287,7 +353,7 @@ pub fn create_host_mmaps(
     }
     let mut mappings = Vec::new();
     for range in ranges.iter() {
-        mappings.push(Arc::new(HostMemMapping::new(
+        let mut mapping = HostMemMapping::new(
             GuestAddress(range.0),
             Some(host_addr),
             range.1,
@@ -295,7 +361,9 @@ pub fn create_host_mmaps(
             mem_config.dump_guest_core,
             mem_config.mem_share,
             false,
-        )?));
+        )?;
+        mapping.lock(mem_config.mem_lock)?;
+        mappings.push(Arc::new(mapping));
         host_addr += range.1;
 
         if let Some(mut fb) = f_back.as_mut() {
@@ -368,6 +436,8 @@ pub struct HostMemMapping {
     host_addr: *mut u8,
     /// Represents file and offset-in-file that backs this mapping.
     file_back: Option<FileBackend>,
+    /// Whether the mapping is locked in host RAM.
+    locked: bool,
 }
 
 // Send and Sync is not auto-implemented for raw pointer type
@@ -418,9 +488,51 @@ impl HostMemMapping {
             },
             host_addr: host_addr as *mut u8,
             file_back,
+            locked: false,
         })
     }
 
+    /// Lock the mapped memory in host RAM, so that it is never swapped out.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_lock` - Lock the memory now, or when it's faulted in, or not at all.
+    pub fn lock(&mut self, mem_lock: MemLock) -> Result<()> {
+        let flags = match mem_lock {
+            MemLock::Off => return Ok(()),
+            MemLock::On => 0,
+            MemLock::OnFault => libc::MLOCK_ONFAULT,
+        };
+        if self.locked {
+            return Ok(());
+        }
+        // Safe because the range is mapped by this mapping.
+        let ret = unsafe {
+            libc::mlock2(
+                self.host_addr as *const libc::c_void,
+                self.size() as libc::size_t,
+                flags,
+            )
+        };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).with_context(|| {
+                format!(
+                    "Failed to lock {} bytes of guest memory at {:#x}, check RLIMIT_MEMLOCK",
+                    self.size(),
+                    self.start_address().raw_value()
+                )
+            });
+        }
+        self.locked = true;
+        LOCKED_MEM_SIZE.fetch_add(self.size(), Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Whether the mapped memory is locked in host RAM.
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
     /// Get size of mapped memory.
     pub fn size(&self) -> u64 {
         self.address_range.size
@@ -447,6 +559,9 @@ impl HostMemMapping {
 impl Drop for HostMemMapping {
     /// Release the memory mapping.
     fn drop(&mut self) {
+        if self.locked {
+            LOCKED_MEM_SIZE.fetch_sub(self.size(), Ordering::Relaxed);
+        }
         unsafe {
             libc::munmap(
                 self.host_addr as *mut libc::c_void,
@@ -456,6 +571,34 @@ impl Drop for HostMemMapping {
     }
 }
 
+/// Host memory ranges pinned for DMA, as (HVA, size). The same range may be
+/// pinned more than once, e.g. by several VFIO containers.
+static PINNED_HOST_RANGES: Mutex<Vec<(u64, u64)>> = Mutex::new(Vec::new());
+
+/// Record that the host memory range is pinned, its pages must not be reclaimed
+/// or moved by madvise until it is unpinned.
+pub fn pin_host_range(hva: u64, size: u64) {
+    PINNED_HOST_RANGES.lock().unwrap().push((hva, size));
+}
+
+/// Remove one record of the host memory range added by `pin_host_range`.
+pub fn unpin_host_range(hva: u64, size: u64) {
+    let mut ranges = PINNED_HOST_RANGES.lock().unwrap();
+    if let Some(pos) = ranges.iter().position(|r| *r == (hva, size)) {
+        ranges.swap_remove(pos);
+    }
+}
+
+/// Check if any part of the host memory range is pinned.
+pub fn host_range_pinned(hva: u64, size: u64) -> bool {
+    let end = hva.saturating_add(size);
+    PINNED_HOST_RANGES
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|&(start, len)| start < end && hva < start.saturating_add(len))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -572,7 +715,9 @@ mod test {
             dump_guest_core: false,
             mem_share: false,
             mem_prealloc: false,
+            mem_lock: MemLock::Off,
             mem_zones: None,
+            mem_aging: None,
         };
 
         let host_mmaps = create_host_mmaps(&addr_ranges, &mem_config, 1).unwrap();
@@ -599,6 +744,36 @@ mod test {
         assert_eq!(total_mem_size, total_mmaps_size);
     }
 
+    #[test]
+    fn test_check_mem_lock_limit() {
+        const M: u64 = 1024 * 1024;
+        // (size, locked, limit, CAP_IPC_LOCK, allowed)
+        for (size, locked, limit, cap_ipc_lock, allowed) in [
+            (64 * M, 0, 64 * M, false, true),
+            (64 * M, 0, 64 * M - 1, false, false),
+            (64 * M, 0, 8 * M, true, true),
+            (64 * M, 0, libc::RLIM_INFINITY, false, true),
+            (32 * M, 32 * M, 64 * M, false, true),
+            (32 * M, 48 * M, 64 * M, false, false),
+            (0, 0, 0, false, true),
+        ] {
+            assert_eq!(
+                check_mem_lock_limit(size, locked, limit, cap_ipc_lock).is_ok(),
+                allowed,
+                "size {} locked {} limit {}",
+                size,
+                locked,
+                limit
+            );
+        }
+
+        let err = check_mem_lock_limit(2 * M, M, 2 * M, false).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("at least 3072 KiB, but it's 2048 KiB"));
+        assert!(err.to_string().contains("ulimit -l 3072"));
+    }
+
     #[test]
     fn test_memory_prealloc() {
         // Mmap and prealloc with anonymous memory.
@@ -624,4 +799,22 @@ mod test {
         .unwrap();
         mem_prealloc(host_addr, 0x10_0000, 2);
     }
+
+    #[test]
+    fn test_pinned_host_range() {
+        // Use a range which is never mapped, the registry is shared by all tests.
+        let hva = 0xffff_0000_0000_0000;
+        assert!(!host_range_pinned(hva, 0x1000));
+        pin_host_range(hva, 0x2000);
+        pin_host_range(hva, 0x2000);
+        assert!(host_range_pinned(hva + 0x1000, 0x1000));
+        assert!(host_range_pinned(hva - 0x1000, 0x2000));
+        assert!(!host_range_pinned(hva - 0x1000, 0x1000));
+        assert!(!host_range_pinned(hva + 0x2000, 0x1000));
+
+        unpin_host_range(hva, 0x2000);
+        assert!(host_range_pinned(hva, 0x1000));
+        unpin_host_range(hva, 0x2000);
+        assert!(!host_range_pinned(hva, 0x1000));
+    }
 }
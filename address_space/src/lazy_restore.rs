@@ -0,0 +1,380 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info};
+use util::bitmap::Bitmap;
+use util::userfaultfd::Userfaultfd;
+
+/// Timeout of waiting for page faults, the handler checks whether all pages are
+/// populated after each timeout.
+const FAULT_POLL_TIMEOUT_MS: i64 = 100;
+/// Number of pages populated by the prefetcher in one batch.
+const PREFETCH_BATCH_PAGES: u64 = 64;
+/// Interval between two prefetch batches, to leave the bandwidth to page faults.
+const PREFETCH_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Install the content of pages to guest memory.
+pub trait PageInstaller: Send + Sync {
+    /// Install `data` to the page(s) at host address `addr`.
+    fn install(&self, addr: u64, data: &[u8]) -> Result<()>;
+}
+
+impl PageInstaller for Userfaultfd {
+    fn install(&self, addr: u64, data: &[u8]) -> Result<()> {
+        self.copy(addr, data)
+    }
+}
+
+/// Guest RAM block whose content is in the snapshot memory file.
+struct LazyRegion {
+    /// Start host address of the block.
+    host_addr: u64,
+    /// Size of the block.
+    size: u64,
+    /// Offset of the block content in snapshot memory file.
+    file_offset: u64,
+    /// Pages which have been populated.
+    populated: Mutex<Bitmap<u64>>,
+}
+
+/// Populate guest RAM from snapshot memory file on demand.
+pub struct LazyRestore {
+    file: Arc<File>,
+    page_size: u64,
+    regions: Vec<LazyRegion>,
+    installer: Arc<dyn PageInstaller>,
+    /// Number of pages which are not populated.
+    remaining: AtomicU64,
+}
+
+impl LazyRestore {
+    /// Create a lazy restore from snapshot memory file.
+    ///
+    /// # Arguments
+    ///
+    /// * `file` - Snapshot memory file.
+    /// * `page_size` - Host page size.
+    /// * `installer` - Install pages to guest memory.
+    pub fn new(file: Arc<File>, page_size: u64, installer: Arc<dyn PageInstaller>) -> Self {
+        LazyRestore {
+            file,
+            page_size,
+            regions: Vec::new(),
+            installer,
+            remaining: AtomicU64::new(0),
+        }
+    }
+
+    /// Add a guest RAM block to be populated lazily.
+    ///
+    /// # Arguments
+    ///
+    /// * `host_addr` - Start host address of the block, aligned to page size.
+    /// * `size` - Size of the block, aligned to page size.
+    /// * `file_offset` - Offset of the block content in snapshot memory file.
+    pub fn add_region(&mut self, host_addr: u64, size: u64, file_offset: u64) -> Result<()> {
+        if !host_addr.is_multiple_of(self.page_size) || !size.is_multiple_of(self.page_size) {
+            bail!(
+                "Region 0x{:x}+0x{:x} is not aligned to page size",
+                host_addr,
+                size
+            );
+        }
+        let pages = size / self.page_size;
+        self.regions.push(LazyRegion {
+            host_addr,
+            size,
+            file_offset,
+            populated: Mutex::new(Bitmap::new(pages as usize / 64 + 1)),
+        });
+        self.remaining.fetch_add(pages, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Host address ranges of all blocks.
+    pub fn ranges(&self) -> Vec<(u64, u64)> {
+        self.regions.iter().map(|r| (r.host_addr, r.size)).collect()
+    }
+
+    /// Number of pages which are not populated.
+    pub fn remaining_pages(&self) -> u64 {
+        self.remaining.load(Ordering::SeqCst)
+    }
+
+    /// Whether all pages are populated.
+    pub fn is_complete(&self) -> bool {
+        self.remaining_pages() == 0
+    }
+
+    /// Populate the page containing `addr` from snapshot memory file. Return `false` if
+    /// the page has been populated already.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Host address in the page.
+    pub fn populate_page(&self, addr: u64) -> Result<bool> {
+        let (index, region) = self
+            .regions
+            .iter()
+            .enumerate()
+            .find(|(_, r)| addr >= r.host_addr && addr < r.host_addr + r.size)
+            .with_context(|| format!("Address 0x{:x} is not in guest RAM", addr))?;
+        let page = (addr - region.host_addr) / self.page_size;
+        self.populate(index, page)
+    }
+
+    /// Populate the page of the block, the bitmap is locked during populating to make
+    /// sure every page is copied only once.
+    fn populate(&self, index: usize, page: u64) -> Result<bool> {
+        let region = &self.regions[index];
+        let mut populated = region.populated.lock().unwrap();
+        if populated.contain(page as usize)? {
+            return Ok(false);
+        }
+
+        let offset = page * self.page_size;
+        let mut buf = vec![0_u8; self.page_size as usize];
+        self.file
+            .read_exact_at(&mut buf, region.file_offset + offset)
+            .with_context(|| {
+                format!(
+                    "Failed to read page 0x{:x} from snapshot memory file",
+                    region.host_addr + offset
+                )
+            })?;
+        self.installer.install(region.host_addr + offset, &buf)?;
+        populated.set(page as usize)?;
+        self.remaining.fetch_sub(1, Ordering::SeqCst);
+
+        Ok(true)
+    }
+
+    /// Populate at most `count` pages which are not populated, from the position
+    /// `cursor` of (block index, page index). Return the number of populated pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `cursor` - The position to start, it's updated to the next position.
+    /// * `count` - Max number of pages to populate.
+    pub fn prefetch(&self, cursor: &mut (usize, u64), count: u64) -> Result<u64> {
+        let mut done = 0;
+        while done < count && cursor.0 < self.regions.len() {
+            let region = &self.regions[cursor.0];
+            let pages = region.size / self.page_size;
+            let next = region
+                .populated
+                .lock()
+                .unwrap()
+                .find_next_zero(cursor.1 as usize)? as u64;
+            if next >= pages {
+                *cursor = (cursor.0 + 1, 0);
+                continue;
+            }
+            if self.populate(cursor.0, next)? {
+                done += 1;
+            }
+            cursor.1 = next + 1;
+        }
+        Ok(done)
+    }
+}
+
+/// Start the threads to restore guest RAM lazily. The page faults of the blocks are
+/// handled by populating the pages from snapshot memory file, and the remaining pages
+/// are populated in background if `prefetch` is set.
+///
+/// # Arguments
+///
+/// * `lazy` - The lazy restore whose blocks are not accessed yet.
+/// * `uffd` - Userfaultfd which is the installer of `lazy`.
+/// * `prefetch` - Populate the remaining pages in background or not.
+pub fn start_lazy_restore(
+    lazy: Arc<LazyRestore>,
+    uffd: Arc<Userfaultfd>,
+    prefetch: bool,
+) -> Result<()> {
+    for (addr, size) in lazy.ranges() {
+        uffd.register(addr, size)?;
+    }
+
+    let handler_lazy = lazy.clone();
+    thread::Builder::new()
+        .name("uffd-handler".to_string())
+        .spawn(move || {
+            while !handler_lazy.is_complete() {
+                let addr = match uffd.wait_fault(FAULT_POLL_TIMEOUT_MS) {
+                    Ok(Some(addr)) => addr,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        error!("Failed to wait for page fault: {:?}", e);
+                        break;
+                    }
+                };
+                if let Err(e) = handler_lazy.populate_page(addr) {
+                    error!("Failed to handle page fault at 0x{:x}: {:?}", addr, e);
+                }
+            }
+            for (addr, size) in handler_lazy.ranges() {
+                if let Err(e) = uffd.unregister(addr, size) {
+                    error!("{:?}", e);
+                }
+            }
+            info!("Lazy restore of guest memory is finished");
+        })
+        .map_err(|e| anyhow!("Failed to create page fault handler thread: {}", e))?;
+
+    if prefetch {
+        thread::Builder::new()
+            .name("uffd-prefetch".to_string())
+            .spawn(move || {
+                let mut cursor = (0, 0);
+                loop {
+                    match lazy.prefetch(&mut cursor, PREFETCH_BATCH_PAGES) {
+                        Ok(0) => break,
+                        Ok(_) => thread::sleep(PREFETCH_INTERVAL),
+                        Err(e) => {
+                            error!("Failed to prefetch guest memory: {:?}", e);
+                            break;
+                        }
+                    }
+                }
+            })
+            .map_err(|e| anyhow!("Failed to create memory prefetch thread: {}", e))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    const PAGE_SIZE: u64 = 4096;
+
+    /// Record the pages installed, as the fault injector and the guest memory.
+    #[derive(Default)]
+    struct FakeInstaller {
+        pages: Mutex<Vec<(u64, u8)>>,
+    }
+
+    impl PageInstaller for FakeInstaller {
+        fn install(&self, addr: u64, data: &[u8]) -> Result<()> {
+            assert_eq!(data.len() as u64, PAGE_SIZE);
+            self.pages.lock().unwrap().push((addr, data[0]));
+            Ok(())
+        }
+    }
+
+    // The memory file contains 8 pages, the content of page N is N.
+    fn create_lazy_restore(installer: Arc<FakeInstaller>) -> (TempFile, LazyRestore) {
+        let tmp = TempFile::new().unwrap();
+        let mut file = tmp.as_file().try_clone().unwrap();
+        for i in 0..8_u8 {
+            file.write_all(&[i; PAGE_SIZE as usize]).unwrap();
+        }
+
+        let mut lazy = LazyRestore::new(Arc::new(file), PAGE_SIZE, installer);
+        // Two blocks, 4 pages at 0x10000 and 3 pages at 0x40000 from the second page.
+        lazy.add_region(0x10000, 4 * PAGE_SIZE, 0).unwrap();
+        lazy.add_region(0x40000, 3 * PAGE_SIZE, 5 * PAGE_SIZE)
+            .unwrap();
+        (tmp, lazy)
+    }
+
+    #[test]
+    fn test_lazy_restore_fault() {
+        let installer = Arc::new(FakeInstaller::default());
+        let (_tmp, mut lazy) = create_lazy_restore(installer.clone());
+        assert_eq!(lazy.remaining_pages(), 7);
+        assert!(lazy.add_region(0x80001, PAGE_SIZE, 0).is_err());
+
+        // Fault in the middle of a page populates the whole page.
+        assert!(lazy.populate_page(0x10000 + PAGE_SIZE + 8).unwrap());
+        assert!(lazy.populate_page(0x40000 + 2 * PAGE_SIZE).unwrap());
+        // Repeated faults don't copy the page again.
+        assert!(!lazy.populate_page(0x10000 + PAGE_SIZE).unwrap());
+        // Address out of guest RAM.
+        assert!(lazy.populate_page(0x20000).is_err());
+
+        assert_eq!(
+            *installer.pages.lock().unwrap(),
+            vec![(0x10000 + PAGE_SIZE, 1), (0x40000 + 2 * PAGE_SIZE, 7)]
+        );
+        assert_eq!(lazy.remaining_pages(), 5);
+    }
+
+    #[test]
+    fn test_lazy_restore_prefetch() {
+        let installer = Arc::new(FakeInstaller::default());
+        let (_tmp, lazy) = create_lazy_restore(installer.clone());
+        assert!(lazy.populate_page(0x10000 + 3 * PAGE_SIZE).unwrap());
+
+        let mut cursor = (0, 0);
+        assert_eq!(lazy.prefetch(&mut cursor, 4).unwrap(), 4);
+        assert_eq!(cursor, (1, 1));
+        // Faults after prefetching don't copy the page again.
+        assert!(!lazy.populate_page(0x40000).unwrap());
+        assert!(lazy.populate_page(0x40000 + 2 * PAGE_SIZE).unwrap());
+        assert_eq!(lazy.prefetch(&mut cursor, 4).unwrap(), 1);
+        assert_eq!(lazy.prefetch(&mut cursor, 4).unwrap(), 0);
+        assert!(lazy.is_complete());
+
+        let mut pages = installer.pages.lock().unwrap().clone();
+        pages.sort_unstable();
+        let expected: Vec<(u64, u8)> = (0..4)
+            .map(|i| (0x10000 + i * PAGE_SIZE, i as u8))
+            .chain((0..3).map(|i| (0x40000 + i * PAGE_SIZE, 5 + i as u8)))
+            .collect();
+        assert_eq!(pages, expected);
+    }
+
+    #[test]
+    fn test_lazy_restore_concurrent() {
+        let installer = Arc::new(FakeInstaller::default());
+        let (_tmp, lazy) = create_lazy_restore(installer.clone());
+        let lazy = Arc::new(lazy);
+
+        // Inject faults on all pages while prefetching.
+        let fault_lazy = lazy.clone();
+        let injector = thread::spawn(move || {
+            for _ in 0..4 {
+                for (addr, size) in fault_lazy.ranges() {
+                    for page in (0..size / PAGE_SIZE).rev().map(|i| addr + i * PAGE_SIZE) {
+                        fault_lazy.populate_page(page).unwrap();
+                    }
+                }
+            }
+        });
+        let mut cursor = (0, 0);
+        while lazy.prefetch(&mut cursor, 1).unwrap() != 0 {}
+        injector.join().unwrap();
+
+        assert!(lazy.is_complete());
+        let mut pages = installer.pages.lock().unwrap().clone();
+        assert_eq!(pages.len(), 7);
+        pages.sort_unstable();
+        pages.dedup_by_key(|p| p.0);
+        assert_eq!(pages.len(), 7);
+    }
+}
@@ -81,17 +81,22 @@ mod address;
 mod address_space;
 pub mod error;
 mod host_mmap;
+mod lazy_restore;
 mod listener;
 mod region;
 mod state;
 
-pub use crate::address_space::{AddressSpace, RegionCache};
+pub use crate::address_space::{AddressSpace, HostRange, RegionCache, RegionGuard};
 pub use address::{AddressRange, GuestAddress};
 pub use anyhow::Result;
 pub use error::AddressSpaceError;
-pub use host_mmap::{create_host_mmaps, set_host_memory_policy, FileBackend, HostMemMapping};
+pub use host_mmap::{
+    create_host_mmaps, host_range_pinned, locked_mem_size, pin_host_range, set_host_memory_policy,
+    unpin_host_range, FileBackend, HostMemMapping,
+};
 #[cfg(target_arch = "x86_64")]
 pub use listener::KvmIoListener;
+pub use lazy_restore::{start_lazy_restore, LazyRestore, PageInstaller};
 pub use listener::KvmMemoryListener;
 pub use listener::{Listener, ListenerReqType};
 pub use region::{FlatRange, Region, RegionIoEventFd, RegionType};
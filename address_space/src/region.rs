@@ -182,6 +182,46 @@ struct MultiOpsArgs {
     access_size: u64,
 }
 
+/// Size of the on-stack buffer used to bounce data of IO regions. Guest PIO/MMIO
+/// accesses are at most 8 bytes wide, so vCPU exits never need the heap.
+const IO_BOUNCE_BUF_SIZE: usize = 64;
+
+/// Buffer passed to the read/write ops of IO regions.
+enum IoBounceBuf {
+    Stack([u8; IO_BOUNCE_BUF_SIZE], usize),
+    Heap(Vec<u8>),
+}
+
+impl IoBounceBuf {
+    fn new(count: usize) -> Self {
+        if count <= IO_BOUNCE_BUF_SIZE {
+            IoBounceBuf::Stack([0_u8; IO_BOUNCE_BUF_SIZE], count)
+        } else {
+            IoBounceBuf::Heap(vec![0_u8; count])
+        }
+    }
+}
+
+impl std::ops::Deref for IoBounceBuf {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            IoBounceBuf::Stack(buf, len) => &buf[..*len],
+            IoBounceBuf::Heap(buf) => buf,
+        }
+    }
+}
+
+impl std::ops::DerefMut for IoBounceBuf {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            IoBounceBuf::Stack(buf, len) => &mut buf[..*len],
+            IoBounceBuf::Heap(buf) => buf,
+        }
+    }
+}
+
 /// Read/Write for multi times.
 macro_rules! rw_multi_ops {
     ( $ops: ident, $slice: expr, $args: ident ) => {
@@ -421,6 +461,11 @@ impl Region {
         self.mem_mapping.as_ref().map(|r| r.host_address())
     }
 
+    /// Get the memory mapping of the region, `None` if it is not backed by host-memory.
+    pub(crate) fn mem_mapping(&self) -> Option<Arc<HostMemMapping>> {
+        self.mem_mapping.clone()
+    }
+
     /// Get the file information if this region is backed by host-memory.
     /// Return `None` if it is not a Ram-type region.
     pub fn get_file_backend(&self) -> Option<FileBackend> {
@@ -528,7 +573,7 @@ impl Region {
                     };
                     dst.write_all(read_ret)?;
                 } else {
-                    let mut read_ret = vec![0_u8; count as usize];
+                    let mut read_ret = IoBounceBuf::new(count as usize);
 
                     let read_ops = self.ops.as_ref().unwrap().read.as_ref();
                     if !read_ops(&mut read_ret, base, offset) {
@@ -542,7 +587,7 @@ impl Region {
                 }
             }
             RegionType::IO => {
-                let mut slice = vec![0_u8; count as usize];
+                let mut slice = IoBounceBuf::new(count as usize);
                 let read_ops = self.ops.as_ref().unwrap().read.as_ref();
                 if matches!(self.max_access_size, Some(access_size) if count > access_size) {
                     let args = MultiOpsArgs {
@@ -617,7 +662,7 @@ impl Region {
                 if count >= std::usize::MAX as u64 {
                     return Err(anyhow!(AddressSpaceError::Overflow(count)));
                 }
-                let mut slice = vec![0_u8; count as usize];
+                let mut slice = IoBounceBuf::new(count as usize);
                 src.read_exact(&mut slice).with_context(|| {
                     "Failed to write buffer to slice, which will be provided for device"
                 })?;
@@ -22,8 +22,12 @@ use migration::{
 use migration_derive::{ByteCode, Desc};
 use util::byte_code::ByteCode;
 use util::unix::host_page_size;
+use util::userfaultfd::Userfaultfd;
 
-use crate::{AddressSpace, FileBackend, GuestAddress, HostMemMapping, Region};
+use crate::{
+    start_lazy_restore, AddressSpace, FileBackend, GuestAddress, HostMemMapping, LazyRestore,
+    Region,
+};
 
 const MIGRATION_HEADER_LENGTH: usize = 4096;
 
@@ -136,6 +140,50 @@ impl MigrationHook for AddressSpace {
         Ok(())
     }
 
+    fn restore_memory_lazy(&self, memory: &File, state: &[u8], prefetch: bool) -> Result<()> {
+        let address_space_state: &AddressSpaceState =
+            AddressSpaceState::from_bytes(&state[0..size_of::<AddressSpaceState>()])
+                .ok_or_else(|| anyhow!(MigrationError::FromBytesError("MEMORY")))?;
+        let uffd = Arc::new(
+            Userfaultfd::new()
+                .map_err(|e| anyhow!(MigrationError::RestoreVmMemoryErr(e.to_string())))?,
+        );
+        let mut lazy = LazyRestore::new(
+            Arc::new(memory.try_clone()?),
+            host_page_size(),
+            uffd.clone(),
+        );
+
+        for ram_state in address_space_state.ram_region_state
+            [0..address_space_state.nr_ram_region as usize]
+            .iter()
+        {
+            // Anonymous memory which is populated by userfaultfd.
+            let host_mmap = Arc::new(
+                HostMemMapping::new(
+                    GuestAddress(ram_state.base_address),
+                    None,
+                    ram_state.size,
+                    None,
+                    false,
+                    false,
+                    false,
+                )
+                .map_err(|e| anyhow!(MigrationError::RestoreVmMemoryErr(e.to_string())))?,
+            );
+            lazy.add_region(host_mmap.host_address(), ram_state.size, ram_state.offset)?;
+            self.root()
+                .add_subregion(
+                    Region::init_ram_region(host_mmap.clone()),
+                    host_mmap.start_address().raw_value(),
+                )
+                .map_err(|e| anyhow!(MigrationError::RestoreVmMemoryErr(e.to_string())))?;
+        }
+
+        start_lazy_restore(Arc::new(lazy), uffd, prefetch)
+            .map_err(|e| anyhow!(MigrationError::RestoreVmMemoryErr(e.to_string())))
+    }
+
     fn send_memory(&self, fd: &mut dyn Write, range: MemBlock) -> Result<()> {
         self.read(fd, GuestAddress(range.gpa), range.len)
             .map_err(|e| anyhow!(MigrationError::SendVmMemoryErr(e.to_string())))?;
@@ -45,7 +45,7 @@ pub enum BootLoaderError {
     #[error("Failed to open initrd image")]
     BootLoaderOpenInitrd,
     #[error("Configure cpu number({0}) above supported max cpu numbers(254)")]
-    MaxCpus(u8),
+    MaxCpus(u16),
     #[error("Invalid bzImage kernel file")]
     #[cfg(target_arch = "x86_64")]
     InvalidBzImage,
@@ -55,4 +55,7 @@ pub enum BootLoaderError {
     #[error("ELF-format kernel is not supported")]
     #[cfg(target_arch = "x86_64")]
     ElfKernel,
+    #[error("Initrd of {0} bytes doesn't fit in the window [0x{1:x}, 0x{2:x}) of guest memory")]
+    #[cfg(target_arch = "x86_64")]
+    InitrdNoSpace(u64, u64, u64),
 }
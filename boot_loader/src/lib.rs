@@ -18,6 +18,7 @@
 //!
 //! This crate offers support for:
 //! 1. Loading PE (vmlinux.bin) kernel images and bzImage kernel images (only in x86_64).
+//!    ELF (vmlinux) kernel images are booted from their PVH entry (only in x86_64).
 //! 2. Loading initrd image.
 //! 3. Initialization for architecture related information.
 //!
@@ -52,6 +53,7 @@
 //!         lapic_addr: 0xFEE0_0000,
 //!         prot64_mode: true,
 //!         ident_tss_range: None,
+//!         reserved_ranges: Vec::new(),
 //!     };
 //!
 //!     let layout = load_linux(&bootloader_config, &guest_mem, None).unwrap();
@@ -94,3 +96,5 @@ pub use x86_64::load_linux;
 pub use x86_64::X86BootLoader as BootLoader;
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::X86BootLoaderConfig as BootLoaderConfig;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::MPTABLE_MAX_CPUS;
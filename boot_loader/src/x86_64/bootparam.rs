@@ -16,8 +16,8 @@ use address_space::AddressSpace;
 use util::byte_code::ByteCode;
 
 use super::{
-    X86BootLoaderConfig, EBDA_START, MB_BIOS_BEGIN, REAL_MODE_IVT_BEGIN, VGA_RAM_BEGIN,
-    VMLINUX_RAM_START,
+    X86BootLoaderConfig, EBDA_START, INITRD_ADDR_MAX, MB_BIOS_BEGIN, REAL_MODE_IVT_BEGIN,
+    VGA_RAM_BEGIN, VMLINUX_RAM_START,
 };
 use crate::error::BootLoaderError;
 use anyhow::{anyhow, Result};
@@ -112,14 +112,45 @@ impl RealModeKernelHeader {
         self.ramdisk_image = addr;
         self.ramdisk_size = size;
     }
+
+    /// Size of the real-mode setup code in bytes, including the boot sector.
+    pub fn setup_size(&self) -> u64 {
+        let setup_sects = if self.setup_sects == 0 {
+            4
+        } else {
+            self.setup_sects as u64
+        };
+        (setup_sects + 1) << 9
+    }
+
+    /// The highest address the initrd can occupy, the field of the header is only valid
+    /// since boot protocol 2.03.
+    pub fn initrd_addr_max(&self) -> u64 {
+        if self.version >= 0x203 && self.initrd_addr_max != 0 {
+            self.initrd_addr_max as u64
+        } else {
+            INITRD_ADDR_MAX
+        }
+    }
+
+    /// End address of the protected-mode kernel of `kernel_size` bytes loaded at `code32_start`.
+    /// Since boot protocol 2.10, the memory used to decompress the kernel is also included.
+    pub fn kernel_end(&self, kernel_size: u64) -> u64 {
+        let end = self.code32_start as u64 + kernel_size;
+        if self.version >= 0x20a {
+            std::cmp::max(end, self.pref_address + self.init_size as u64)
+        } else {
+            end
+        }
+    }
 }
 
 #[repr(C, packed)]
 #[derive(Debug, Default, Copy, Clone)]
 pub struct E820Entry {
-    addr: u64,
-    size: u64,
-    type_: u32,
+    pub(crate) addr: u64,
+    pub(crate) size: u64,
+    pub(crate) type_: u32,
 }
 
 impl E820Entry {
@@ -188,6 +219,11 @@ impl BootParams {
         self.e820_entries += 1;
     }
 
+    /// The valid entries of e820 table.
+    pub fn e820_table(&self) -> Vec<E820Entry> {
+        self.e820_table[..self.e820_entries as usize].to_vec()
+    }
+
     pub fn setup_e820_entries(
         &mut self,
         config: &X86BootLoaderConfig,
@@ -218,6 +254,13 @@ impl BootParams {
                 E820_RAM,
             );
         }
+        // Reserved entries take precedence over the RAM entries they overlap.
+        if let Some((start, size)) = config.ident_tss_range {
+            self.add_e820_entry(start, size, E820_RESERVED);
+        }
+        for (start, size) in config.reserved_ranges.iter() {
+            self.add_e820_entry(*start, *size, E820_RESERVED);
+        }
     }
 }
 
@@ -261,6 +304,7 @@ mod test {
             lapic_addr: 0xFEE0_0000,
             prot64_mode: false,
             ident_tss_range: None,
+            reserved_ranges: Vec::new(),
         };
 
         let boot_hdr = RealModeKernelHeader::default();
@@ -285,5 +329,112 @@ mod test {
         assert!(boot_params.e820_table[3].addr == 0x0010_0000);
         assert!(boot_params.e820_table[3].size == 0x0ff0_0000);
         assert!(boot_params.e820_table[3].type_ == 1);
+        let config = X86BootLoaderConfig {
+            reserved_ranges: vec![(0x0800_0000, 0x0010_0000)],
+            ..config
+        };
+        let mut boot_params = BootParams::new(boot_hdr);
+        boot_params.setup_e820_entries(&config, &space);
+        assert_eq!(boot_params.e820_entries, 5);
+        assert!(boot_params.e820_table[4].addr == 0x0800_0000);
+        assert!(boot_params.e820_table[4].size == 0x0010_0000);
+        assert!(boot_params.e820_table[4].type_ == 2);
+    }
+
+    #[test]
+    fn test_e820_reserved_ranges() {
+        // (end of guest RAM, end of the gap including the 64-bit PCI hole, expected RAM
+        // entries after the low 640K).
+        let gap_start = 0x8000_0000;
+        let cases: [(u64, u64, &[(u64, u64)]); 3] = [
+            (0x4000_0000, 0x1_0000_0000, &[(0x10_0000, 0x3ff0_0000)]),
+            (
+                0x1_4000_0000,
+                0x1_0000_0000,
+                &[(0x10_0000, 0x7ff0_0000), (0x1_0000_0000, 0x4000_0000)],
+            ),
+            (
+                0x9_4000_0000,
+                0x9_0000_0000,
+                &[(0x10_0000, 0x7ff0_0000), (0x9_0000_0000, 0x4000_0000)],
+            ),
+        ];
+        let reserved_ranges = vec![
+            (0xB000_0000, 0x1000_0000),
+            (0xFEC0_0000, 0x10_0000),
+            (0xFEE0_0000, 0x10_0000),
+        ];
+
+        for (mem_end, gap_end, ram) in cases {
+            let root = Region::init_container_region(u64::max_value());
+            let space = AddressSpace::new(root.clone()).unwrap();
+            // Only the end of guest RAM matters, map the last 4K of it.
+            let ram_page = Arc::new(
+                HostMemMapping::new(
+                    GuestAddress(mem_end - 0x1000),
+                    None,
+                    0x1000,
+                    None,
+                    false,
+                    false,
+                    false,
+                )
+                .unwrap(),
+            );
+            root.add_subregion(Region::init_ram_region(ram_page), mem_end - 0x1000)
+                .unwrap();
+
+            let config = X86BootLoaderConfig {
+                kernel: None,
+                initrd: None,
+                kernel_cmdline: String::new(),
+                cpu_count: 1,
+                gap_range: (gap_start, gap_end - gap_start),
+                ioapic_addr: 0xFEC0_0000,
+                lapic_addr: 0xFEE0_0000,
+                prot64_mode: true,
+                ident_tss_range: Some((0xFEF0_C000, 0x4000)),
+                reserved_ranges: reserved_ranges.clone(),
+            };
+            let mut boot_params = BootParams::new(RealModeKernelHeader::default());
+            boot_params.setup_e820_entries(&config, &space);
+
+            let entries = &boot_params.e820_table[..boot_params.e820_entries as usize];
+            let (ram_entries, reserved_entries) = entries[3..].split_at(ram.len());
+            for (entry, (addr, size)) in ram_entries.iter().zip(ram.iter()) {
+                assert!(entry.addr == *addr && entry.size == *size && entry.type_ == E820_RAM);
+            }
+            let mut expected = vec![(0xFEF0_C000, 0x4000)];
+            expected.extend(reserved_ranges.iter());
+            assert_eq!(reserved_entries.len(), expected.len());
+            for (entry, (addr, size)) in reserved_entries.iter().zip(expected.iter()) {
+                assert!(entry.addr == *addr && entry.size == *size);
+                assert!(entry.type_ == E820_RESERVED);
+            }
+        }
+    }
+
+    #[test]
+    fn test_kernel_header_initrd_limits() {
+        let mut hdr = RealModeKernelHeader::new();
+        hdr.code32_start = 0x10_0000;
+        hdr.initrd_addr_max = 0x7fff_ffff;
+        hdr.pref_address = 0x100_0000;
+        hdr.init_size = 0x300_0000;
+
+        // Fields newer than the boot protocol of the kernel are ignored.
+        hdr.version = 0x202;
+        assert_eq!(hdr.initrd_addr_max(), INITRD_ADDR_MAX);
+        assert_eq!(hdr.kernel_end(0x80_0000), 0x90_0000);
+
+        hdr.version = 0x203;
+        assert_eq!(hdr.initrd_addr_max(), 0x7fff_ffff);
+        assert_eq!(hdr.kernel_end(0x80_0000), 0x90_0000);
+
+        hdr.version = 0x20f;
+        assert_eq!(hdr.kernel_end(0x80_0000), 0x400_0000);
+        assert_eq!(hdr.kernel_end(0x800_0000), 0x810_0000);
+        hdr.initrd_addr_max = 0;
+        assert_eq!(hdr.initrd_addr_max(), INITRD_ADDR_MAX);
     }
 }
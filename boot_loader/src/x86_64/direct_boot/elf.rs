@@ -15,7 +15,6 @@ use std::io::{Read, Seek, SeekFrom};
 use std::sync::Arc;
 
 use address_space::{AddressSpace, GuestAddress};
-use devices::legacy::{FwCfgEntryType, FwCfgOps};
 use util::byte_code::ByteCode;
 use util::num_ops::round_up;
 
@@ -121,18 +120,30 @@ struct Elf64NoteHeader {
 
 impl ByteCode for Elf64NoteHeader {}
 
-/// Parse ELF_format kernel file and find the PVH entry.
+/// Check the magic of the kernel image, the file offset is restored to the start.
+///
+/// # Arguments
+///
+/// `kernel_image` - Kernel file.
+pub fn is_elf_kernel(kernel_image: &mut File) -> Result<bool> {
+    let mut magic = [0_u8; 4];
+    kernel_image.seek(SeekFrom::Start(0))?;
+    let is_elf = match kernel_image.read_exact(&mut magic) {
+        Ok(()) => magic == [ELFMAG0, ELFMAG1, ELFMAG2, ELFMAG3],
+        Err(_) => false,
+    };
+    kernel_image.seek(SeekFrom::Start(0))?;
+    Ok(is_elf)
+}
+
+/// Parse ELF_format kernel file, load its segments to guest memory and return the PVH entry
+/// and the end address of the loaded segments.
 ///
 /// # Arguments
 ///
 /// `kernel_image` - ELF-format kernel file.
 /// `sys_mem` - Guest memory.
-/// `fwcfg` - FwCfg device.
-pub fn load_elf_kernel(
-    kernel_image: &mut File,
-    sys_mem: &Arc<AddressSpace>,
-    fwcfg: &mut dyn FwCfgOps,
-) -> Result<()> {
+pub fn load_elf_kernel(kernel_image: &mut File, sys_mem: &Arc<AddressSpace>) -> Result<(u64, u64)> {
     kernel_image.seek(SeekFrom::Start(0))?;
     let kernel_length = kernel_image.metadata().map(|m| m.len())?;
 
@@ -147,8 +158,7 @@ pub fn load_elf_kernel(
         .with_context(|| "Failed to parse ELF program header")?;
 
     let mut pvh_start_addr: Option<u64> = None;
-    let mut addr_low = u64::MAX;
-    let mut addr_max = 0_u64;
+    let mut kernel_end = 0_u64;
     for ph in &ep_hdrs {
         let ph_offset = ph.p_offset;
         let ph_size = ph.p_filesz;
@@ -164,68 +174,67 @@ pub fn load_elf_kernel(
         if ph.p_type == PT_LOAD {
             kernel_image.seek(SeekFrom::Start(ph.p_offset))?;
             sys_mem.write(kernel_image, GuestAddress(ph.p_paddr), ph.p_filesz)?;
-
-            addr_low = std::cmp::min(addr_low, ph.p_paddr);
-            addr_max = std::cmp::max(addr_max, ph.p_paddr);
+            kernel_end = std::cmp::max(kernel_end, ph.p_paddr.saturating_add(ph.p_memsz));
         }
-        if ph.p_type == PT_NOTE {
-            kernel_image.seek(SeekFrom::Start(ph.p_offset))?;
-            let mut note_hdr = Elf64NoteHeader::default();
-            let note_size = std::mem::size_of::<Elf64NoteHeader>() as u64;
-
-            // Search for the target note header that contains PVH entry.
-            let mut offset = 0;
-            while offset + note_size <= ph.p_filesz {
-                kernel_image.read_exact(note_hdr.as_mut_bytes())?;
-                offset += note_size;
-
-                let p_align = ph.p_align;
-                let aligned_namesz =
-                    round_up(note_hdr.namesz as u64, p_align).ok_or_else(|| {
-                        anyhow!(format!(
-                            "Overflows when align up: num 0x{:x}, alignment 0x{:x}",
-                            note_hdr.namesz as u64, p_align,
-                        ))
-                    })?;
-                if note_hdr.type_ == XEN_ELFNOTE_PHYS32_ENTRY {
-                    kernel_image.seek(SeekFrom::Current(aligned_namesz as i64))?;
+        if ph.p_type == PT_NOTE && pvh_start_addr.is_none() {
+            pvh_start_addr = find_pvh_entry(kernel_image, ph)?;
+        }
+    }
+
+    let pvh_entry = pvh_start_addr
+        .ok_or_else(|| anyhow!("No Note header contains PVH entry info in ELF kernel image."))?;
+    Ok((pvh_entry, kernel_end))
+}
 
+/// Search the notes of a PT_NOTE segment for the PVH entry.
+fn find_pvh_entry(kernel_image: &mut File, ph: &Elf64ProgHeader) -> Result<Option<u64>> {
+    kernel_image.seek(SeekFrom::Start(ph.p_offset))?;
+    let mut note_hdr = Elf64NoteHeader::default();
+    let note_size = std::mem::size_of::<Elf64NoteHeader>() as u64;
+    // Notes are 4-byte aligned in the kernel, whatever the alignment of the segment is.
+    let p_align = std::cmp::max(ph.p_align, 4);
+
+    let mut offset = 0;
+    while offset + note_size <= ph.p_filesz {
+        kernel_image.read_exact(note_hdr.as_mut_bytes())?;
+        offset += note_size;
+
+        let aligned_namesz = round_up(note_hdr.namesz as u64, p_align).ok_or_else(|| {
+            anyhow!(format!(
+                "Overflows when align up: num 0x{:x}, alignment 0x{:x}",
+                note_hdr.namesz as u64, p_align,
+            ))
+        })?;
+        if note_hdr.type_ == XEN_ELFNOTE_PHYS32_ENTRY {
+            kernel_image.seek(SeekFrom::Current(aligned_namesz as i64))?;
+
+            // The entry is a 32-bit physical address, stored as a pointer sized value.
+            let entry_addr = match note_hdr.descsz {
+                4 => {
+                    let mut entry_addr = 0_u32;
+                    kernel_image.read_exact(entry_addr.as_mut_bytes())?;
+                    entry_addr as u64
+                }
+                8 => {
                     let mut entry_addr = 0_u64;
                     kernel_image.read_exact(entry_addr.as_mut_bytes())?;
-                    pvh_start_addr = Some(entry_addr);
-                    break;
-                } else {
-                    let aligned_descsz =
-                        round_up(note_hdr.descsz as u64, p_align).ok_or_else(|| {
-                            anyhow!(format!(
-                                "Overflows when align up, num 0x{:x}, alignment 0x{:x}",
-                                note_hdr.descsz as u64, p_align,
-                            ))
-                        })?;
-                    let tail_size = aligned_namesz + aligned_descsz;
-
-                    kernel_image.seek(SeekFrom::Current(tail_size as i64))?;
-                    offset += tail_size;
-                    continue;
+                    entry_addr
                 }
-            }
+                size => bail!("Invalid size {} of PVH entry note", size),
+            };
+            return Ok(Some(entry_addr));
         }
-    }
-    if pvh_start_addr.is_none() {
-        bail!("No Note header contains PVH entry info in ELF kernel image.");
-    }
 
-    fwcfg.add_data_entry(
-        FwCfgEntryType::KernelEntry,
-        (pvh_start_addr.unwrap() as u32).as_bytes().to_vec(),
-    )?;
-    fwcfg.add_data_entry(
-        FwCfgEntryType::KernelAddr,
-        (addr_low as u32).as_bytes().to_vec(),
-    )?;
-    fwcfg.add_data_entry(
-        FwCfgEntryType::KernelSize,
-        (addr_max as u32 - addr_low as u32).as_bytes().to_vec(),
-    )?;
-    Ok(())
+        let aligned_descsz = round_up(note_hdr.descsz as u64, p_align).ok_or_else(|| {
+            anyhow!(format!(
+                "Overflows when align up, num 0x{:x}, alignment 0x{:x}",
+                note_hdr.descsz as u64, p_align,
+            ))
+        })?;
+        let tail_size = aligned_namesz + aligned_descsz;
+
+        kernel_image.seek(SeekFrom::Current(tail_size as i64))?;
+        offset += tail_size;
+    }
+    Ok(None)
 }
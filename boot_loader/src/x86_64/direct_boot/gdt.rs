@@ -108,12 +108,22 @@ fn write_idt_value(val: u64, guest_mem: &Arc<AddressSpace>) -> Result<()> {
     Ok(())
 }
 
+/// Setup the gdt with a 64-bit code segment, used by 64-bit direct boot.
 pub fn setup_gdt(guest_mem: &Arc<AddressSpace>) -> Result<BootGdtSegment> {
+    write_boot_gdt(0xa09b, guest_mem)
+}
+
+/// Setup the gdt with a 32-bit flat code segment, used by PVH boot.
+pub fn setup_pvh_gdt(guest_mem: &Arc<AddressSpace>) -> Result<BootGdtSegment> {
+    write_boot_gdt(0xc09b, guest_mem)
+}
+
+fn write_boot_gdt(code_flags: u64, guest_mem: &Arc<AddressSpace>) -> Result<BootGdtSegment> {
     let gdt_table: [u64; BOOT_GDT_MAX] = [
-        GdtEntry::new(0, 0, 0).into(),            // NULL
-        GdtEntry::new(0, 0, 0).into(),            // NULL
-        GdtEntry::new(0xa09b, 0, 0xfffff).into(), // CODE
-        GdtEntry::new(0xc093, 0, 0xfffff).into(), // DATA
+        GdtEntry::new(0, 0, 0).into(),                // NULL
+        GdtEntry::new(0, 0, 0).into(),                // NULL
+        GdtEntry::new(code_flags, 0, 0xfffff).into(), // CODE
+        GdtEntry::new(0xc093, 0, 0xfffff).into(),     // DATA
     ];
 
     let mut code_seg: kvm_segment = GdtEntry(gdt_table[GDT_ENTRY_BOOT_CS as usize]).into();
@@ -10,8 +10,11 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+#[allow(non_camel_case_types)]
+mod elf;
 mod gdt;
 mod mptable;
+mod pvh;
 
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
@@ -23,11 +26,13 @@ use util::byte_code::ByteCode;
 
 use self::gdt::setup_gdt;
 use self::mptable::setup_isa_mptable;
+pub use self::mptable::MPTABLE_MAX_CPUS;
+pub use self::pvh::{is_pvh_kernel, load_pvh_linux};
 use super::bootparam::{BootParams, RealModeKernelHeader, UNDEFINED_ID};
-use super::{X86BootLoader, X86BootLoaderConfig};
+use super::{initrd_load_addr, X86BootLoader, X86BootLoaderConfig};
 use super::{
-    BOOT_HDR_START, BOOT_LOADER_SP, BZIMAGE_BOOT_OFFSET, CMDLINE_START, EBDA_START,
-    INITRD_ADDR_MAX, PDE_START, PDPTE_START, PML4_START, VMLINUX_STARTUP, ZERO_PAGE_START,
+    BOOT_HDR_START, BOOT_LOADER_SP, BZIMAGE_BOOT_OFFSET, CMDLINE_START, EBDA_START, PDE_START,
+    PDPTE_START, PML4_START, VMLINUX_STARTUP, ZERO_PAGE_START,
 };
 use crate::error::BootLoaderError;
 use anyhow::{anyhow, bail, Context, Result};
@@ -65,12 +70,7 @@ fn load_bzimage(kernel_image: &mut File) -> Result<RealModeKernelHeader> {
         return Err(e);
     }
 
-    let mut setup_size = boot_hdr.setup_sects as u64;
-    if setup_size == 0 {
-        setup_size = 4;
-    }
-    setup_size = (setup_size + 1) << 9;
-    kernel_image.seek(SeekFrom::Start(setup_size))?;
+    kernel_image.seek(SeekFrom::Start(boot_hdr.setup_size()))?;
 
     Ok(boot_hdr)
 }
@@ -95,11 +95,12 @@ fn load_image(image: &mut File, start_addr: u64, sys_mem: &Arc<AddressSpace>) ->
     Ok(())
 }
 
+/// Load kernel image, return its header and the end address of the kernel.
 fn load_kernel_image(
     kernel_path: &std::path::Path,
     sys_mem: &Arc<AddressSpace>,
     boot_layout: &mut X86BootLoader,
-) -> Result<RealModeKernelHeader> {
+) -> Result<(RealModeKernelHeader, u64)> {
     let mut kernel_image =
         File::open(kernel_path).with_context(|| anyhow!(BootLoaderError::BootLoaderOpenKernel))?;
 
@@ -117,39 +118,46 @@ fn load_kernel_image(
         )
     };
 
+    let kernel_size = kernel_image.metadata()?.len() - kernel_image.seek(SeekFrom::Current(0))?;
     load_image(&mut kernel_image, vmlinux_start, sys_mem)
         .with_context(|| "Failed to load image")?;
+    let kernel_end = std::cmp::max(
+        vmlinux_start + kernel_size,
+        boot_hdr.kernel_end(kernel_size),
+    );
 
     boot_layout.boot_ip = kernel_start;
 
-    Ok(boot_hdr)
+    Ok((boot_hdr, kernel_end))
 }
 
+/// Load initrd to the top of low memory, return its (address, size) in guest memory.
+///
+/// # Arguments
+///
+/// * `config` - Boot source config, contains initrd.
+/// * `sys_mem` - Guest memory.
+/// * `initrd_addr_max` - The highest address initrd can occupy.
+/// * `kernel_end` - End address of the kernel, initrd is loaded above it.
 fn load_initrd(
     config: &X86BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
-    header: &mut RealModeKernelHeader,
-) -> Result<()> {
+    initrd_addr_max: u64,
+    kernel_end: u64,
+) -> Result<Option<(u64, u64)>> {
     if config.initrd.is_none() {
         info!("No initrd image file.");
-        return Ok(());
-    };
-
-    let mut initrd_addr_max = INITRD_ADDR_MAX;
-    if initrd_addr_max > sys_mem.memory_end_address().raw_value() {
-        initrd_addr_max = sys_mem.memory_end_address().raw_value();
+        return Ok(None);
     };
 
     let mut initrd_image = File::open(config.initrd.as_ref().unwrap())
         .with_context(|| anyhow!(BootLoaderError::BootLoaderOpenInitrd))?;
-    let initrd_size = initrd_image.metadata().unwrap().len();
-    let initrd_addr = (initrd_addr_max - initrd_size) & !0xfff_u64;
+    let initrd_size = initrd_image.metadata()?.len();
+    let initrd_addr = initrd_load_addr(initrd_size, config, sys_mem, initrd_addr_max, kernel_end)?;
 
     load_image(&mut initrd_image, initrd_addr, sys_mem).with_context(|| "Failed to load image")?;
 
-    header.set_ramdisk(initrd_addr as u32, initrd_size as u32);
-
-    Ok(())
+    Ok(Some((initrd_addr, initrd_size)))
 }
 
 /// Initial pagetables.
@@ -246,14 +254,18 @@ pub fn load_linux(
         zero_page_addr: ZERO_PAGE_START,
         ..Default::default()
     };
-    let mut boot_header = load_kernel_image(
+    let (mut boot_header, kernel_end) = load_kernel_image(
         config.kernel.as_ref().unwrap(),
         sys_mem,
         &mut boot_loader_layout,
     )?;
 
-    load_initrd(config, sys_mem, &mut boot_header)
-        .with_context(|| "Failed to load initrd to vm memory")?;
+    if let Some((initrd_addr, initrd_size)) =
+        load_initrd(config, sys_mem, boot_header.initrd_addr_max(), kernel_end)
+            .with_context(|| "Failed to load initrd to vm memory")?
+    {
+        boot_header.set_ramdisk(initrd_addr as u32, initrd_size as u32);
+    }
 
     setup_kernel_cmdline(config, sys_mem, &mut boot_header)
         .with_context(|| "Failed to setup kernel cmdline")?;
@@ -335,6 +347,7 @@ mod test {
             lapic_addr: 0xFEE0_0000,
             prot64_mode: false,
             ident_tss_range: None,
+            reserved_ranges: Vec::new(),
         };
         let mut boot_hdr = RealModeKernelHeader::new();
         assert!(setup_boot_params(&config, &space, &boot_hdr).is_ok());
@@ -402,4 +415,86 @@ mod test {
         let s = String::from_utf8(read_buffer.to_vec()).unwrap();
         assert_eq!(s, "this_is_a_piece_of_test_string".to_string());
     }
+
+    #[test]
+    fn test_load_linux_initrd_placement() {
+        use std::io::Write;
+
+        let kernel_path = "direct_boot_test_vmlinux";
+        let initrd_path = "direct_boot_test_initrd";
+        File::create(kernel_path)
+            .unwrap()
+            .write_all(&[0xf4_u8; 0x1000])
+            .unwrap();
+        File::create(initrd_path)
+            .unwrap()
+            .write_all(&[0x5a_u8; 0x1800])
+            .unwrap();
+
+        let root = Region::init_container_region(0x2000_0000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(0),
+                None,
+                0x1000_0000,
+                None,
+                false,
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+
+        let mut config = X86BootLoaderConfig {
+            kernel: Some(PathBuf::from(kernel_path)),
+            initrd: Some(PathBuf::from(initrd_path)),
+            kernel_cmdline: String::from("console=ttyS0"),
+            cpu_count: 1,
+            gap_range: (0xC000_0000, 0x4000_0000),
+            ioapic_addr: 0xFEC0_0000,
+            lapic_addr: 0xFEE0_0000,
+            prot64_mode: true,
+            ident_tss_range: None,
+            reserved_ranges: Vec::new(),
+        };
+        load_linux(&config, &space).unwrap();
+
+        // The zero page points to the initrd at the top of memory.
+        let ramdisk_image = space
+            .read_object::<u32>(GuestAddress(ZERO_PAGE_START + 0x218))
+            .unwrap();
+        let ramdisk_size = space
+            .read_object::<u32>(GuestAddress(ZERO_PAGE_START + 0x21c))
+            .unwrap();
+        assert_eq!(ramdisk_image, 0x1000_0000 - 0x2000);
+        assert_eq!(ramdisk_size, 0x1800);
+        let mut initrd = vec![0_u8; 0x1800];
+        space
+            .read(
+                &mut initrd.as_mut_slice(),
+                GuestAddress(ramdisk_image as u64),
+                0x1800,
+            )
+            .unwrap();
+        assert_eq!(initrd, vec![0x5a_u8; 0x1800]);
+
+        // Initrd larger than the memory above the kernel is rejected.
+        let oversized = 0x1000_0000 - VMLINUX_STARTUP;
+        File::create(initrd_path)
+            .unwrap()
+            .set_len(oversized)
+            .unwrap();
+        let err = load_linux(&config, &space).unwrap_err();
+        assert_eq!(
+            err.root_cause().to_string(),
+            BootLoaderError::InitrdNoSpace(oversized, 0x0100_1000, 0x1000_0000).to_string()
+        );
+        config.initrd = None;
+        assert!(load_linux(&config, &space).is_ok());
+
+        std::fs::remove_file(kernel_path).unwrap();
+        std::fs::remove_file(initrd_path).unwrap();
+    }
 }
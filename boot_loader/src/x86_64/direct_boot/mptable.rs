@@ -27,6 +27,10 @@ const CPU_FLAGS_ENABLE: u8 = 0x1;
 const CPU_FLAGS_BSP: u8 = 0x2;
 const APIC_FLAGS_ENABLE: u8 = 0x1;
 
+/// Maximum number of CPUs described by the MP table, one of the 255 APIC IDs is reserved
+/// for the IOAPIC.
+pub const MPTABLE_MAX_CPUS: u16 = 254;
+
 pub const INTERRUPT_TYPE_INT: u8 = 0;
 pub const INTERRUPT_TYPE_NMI: u8 = 1;
 pub const INTERRUPT_TYPE_EXTINT: u8 = 3;
@@ -275,20 +279,18 @@ macro_rules! write_entry {
 pub fn setup_isa_mptable(
     sys_mem: &Arc<AddressSpace>,
     start_addr: u64,
-    num_cpus: u8,
+    num_cpus: u16,
     ioapic_addr: u32,
     lapic_addr: u32,
 ) -> Result<()> {
     const BUS_ID: u8 = 0;
-    // mptable max support 255 cpus, reserve one for ioapic id
-    const MPTABLE_MAX_CPUS: u32 = 254;
     const MPTABLE_IOAPIC_NR: u8 = 16;
 
-    if u32::from(num_cpus) > MPTABLE_MAX_CPUS {
+    if num_cpus > MPTABLE_MAX_CPUS {
         return Err(anyhow!(BootLoaderError::MaxCpus(num_cpus)));
     }
 
-    let ioapic_id: u8 = num_cpus + 1;
+    let ioapic_id = num_cpus as u8 + 1;
     let header = start_addr + std::mem::size_of::<FloatingPointer>() as u64;
     sys_mem.write_object(
         &FloatingPointer::new(header as u32),
@@ -297,7 +299,7 @@ pub fn setup_isa_mptable(
 
     let mut offset = header + std::mem::size_of::<ConfigTableHeader>() as u64;
     let mut sum = 0u8;
-    for cpu_id in 0..num_cpus {
+    for cpu_id in 0..num_cpus as u8 {
         write_entry!(
             ProcessEntry::new(cpu_id, true, cpu_id == 0),
             ProcessEntry,
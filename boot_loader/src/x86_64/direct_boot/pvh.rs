@@ -0,0 +1,377 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Boot ELF kernel (vmlinux) from its PVH entry according to
+//! [`PVH boot ABI`](https://xenbits.xen.org/docs/unstable/misc/pvh.html).
+//!
+//! The vCPU starts in 32-bit protected mode with paging disabled, flat segments
+//! and `ebx` pointing to `hvm_start_info`, which carries the kernel cmdline,
+//! the initrd as the first module and the memory map.
+
+use std::fs::File;
+use std::sync::Arc;
+
+use address_space::{AddressSpace, GuestAddress};
+use util::byte_code::ByteCode;
+
+use super::elf::{is_elf_kernel, load_elf_kernel};
+use super::gdt::setup_pvh_gdt;
+use super::load_initrd;
+use super::mptable::setup_isa_mptable;
+use crate::error::BootLoaderError;
+use crate::x86_64::bootparam::{BootParams, RealModeKernelHeader};
+use crate::x86_64::{
+    X86BootLoader, X86BootLoaderConfig, BOOT_LOADER_SP, CMDLINE_START, EBDA_START,
+};
+use anyhow::{anyhow, bail, Context, Result};
+
+const XEN_HVM_START_MAGIC_VALUE: u32 = 0x336e_c578;
+/// Version 1 of `hvm_start_info` carries the memory map.
+const XEN_HVM_START_INFO_VERSION: u32 = 1;
+
+/// Guest address of `hvm_start_info`.
+const PVH_INFO_START: u64 = 0x0000_6000;
+/// Guest address of `hvm_modlist_entry` table.
+const PVH_MODLIST_START: u64 = 0x0000_6040;
+/// Guest address of `hvm_memmap_table_entry` table, the zero page is not used by PVH boot.
+const PVH_MEMMAP_START: u64 = 0x0000_7000;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct HvmStartInfo {
+    magic: u32,
+    version: u32,
+    flags: u32,
+    nr_modules: u32,
+    modlist_paddr: u64,
+    cmdline_paddr: u64,
+    rsdp_paddr: u64,
+    memmap_paddr: u64,
+    memmap_entries: u32,
+    reserved: u32,
+}
+
+impl ByteCode for HvmStartInfo {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct HvmModlistEntry {
+    paddr: u64,
+    size: u64,
+    cmdline_paddr: u64,
+    reserved: u64,
+}
+
+impl ByteCode for HvmModlistEntry {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct HvmMemmapTableEntry {
+    addr: u64,
+    size: u64,
+    type_: u32,
+    reserved: u32,
+}
+
+impl ByteCode for HvmMemmapTableEntry {}
+
+/// Whether the kernel is an ELF image, which is booted from its PVH entry.
+///
+/// # Arguments
+///
+/// * `config` - Boot source config, contains kernel.
+pub fn is_pvh_kernel(config: &X86BootLoaderConfig) -> Result<bool> {
+    let kernel = match config.kernel.as_ref() {
+        Some(kernel) => kernel,
+        None => return Ok(false),
+    };
+    let mut kernel_image =
+        File::open(kernel).with_context(|| anyhow!(BootLoaderError::BootLoaderOpenKernel))?;
+    is_elf_kernel(&mut kernel_image)
+}
+
+/// Memory map of the guest, the same as the e820 table of the linux boot protocol.
+fn setup_memmap(config: &X86BootLoaderConfig, sys_mem: &Arc<AddressSpace>) -> Result<u32> {
+    let mut boot_params = BootParams::new(RealModeKernelHeader::default());
+    boot_params.setup_e820_entries(config, sys_mem);
+
+    let mut entries = 0_u32;
+    for e820 in boot_params.e820_table() {
+        if e820.size == 0 {
+            continue;
+        }
+        let entry = HvmMemmapTableEntry {
+            addr: e820.addr,
+            size: e820.size,
+            type_: e820.type_,
+            reserved: 0,
+        };
+        let entry_addr =
+            PVH_MEMMAP_START + entries as u64 * std::mem::size_of::<HvmMemmapTableEntry>() as u64;
+        sys_mem
+            .write_object(&entry, GuestAddress(entry_addr))
+            .with_context(|| format!("Failed to load memmap entry to 0x{:x}", entry_addr))?;
+        entries += 1;
+    }
+    Ok(entries)
+}
+
+/// Load ELF linux kernel and other boot source to guest memory for PVH boot.
+///
+/// # Steps
+///
+/// 1. Load the segments of kernel and find the PVH entry.
+/// 2. Load initrd image as the first module.
+/// 3. Inject cmdline, memory map and `hvm_start_info` to guest memory.
+/// 4. Setup MP table and 32-bit gdt.
+///
+/// # Arguments
+///
+/// * `config` - Boot source config, contains kernel, initrd and kernel cmdline.
+/// * `sys_mem` - Guest memory.
+pub fn load_pvh_linux(
+    config: &X86BootLoaderConfig,
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<X86BootLoader> {
+    if config.kernel.is_none() {
+        bail!("Kernel is required for PVH boot.");
+    }
+    let mut kernel_image = File::open(config.kernel.as_ref().unwrap())
+        .with_context(|| anyhow!(BootLoaderError::BootLoaderOpenKernel))?;
+    let (pvh_entry, kernel_end) =
+        load_elf_kernel(&mut kernel_image, sys_mem).with_context(|| "Failed to load ELF kernel")?;
+
+    let mut start_info = HvmStartInfo {
+        magic: XEN_HVM_START_MAGIC_VALUE,
+        version: XEN_HVM_START_INFO_VERSION,
+        ..Default::default()
+    };
+
+    // The initrd of PVH boot is passed by `hvm_modlist_entry`, there is no kernel header to limit it.
+    if let Some((initrd_addr, initrd_size)) =
+        load_initrd(config, sys_mem, u32::MAX as u64, kernel_end)
+            .with_context(|| "Failed to load initrd to vm memory")?
+    {
+        let module = HvmModlistEntry {
+            paddr: initrd_addr,
+            size: initrd_size,
+            ..Default::default()
+        };
+        sys_mem
+            .write_object(&module, GuestAddress(PVH_MODLIST_START))
+            .with_context(|| format!("Failed to load modlist to 0x{:x}", PVH_MODLIST_START))?;
+        start_info.nr_modules = 1;
+        start_info.modlist_paddr = PVH_MODLIST_START;
+    }
+
+    // The cmdline of PVH boot is a NUL-terminated string.
+    let mut cmdline = config.kernel_cmdline.as_bytes().to_vec();
+    cmdline.push(0);
+    sys_mem
+        .write(
+            &mut cmdline.as_slice(),
+            GuestAddress(CMDLINE_START),
+            cmdline.len() as u64,
+        )
+        .with_context(|| "Failed to setup kernel cmdline")?;
+    start_info.cmdline_paddr = CMDLINE_START;
+
+    start_info.memmap_entries =
+        setup_memmap(config, sys_mem).with_context(|| "Failed to setup memory map")?;
+    start_info.memmap_paddr = PVH_MEMMAP_START;
+    sys_mem
+        .write_object(&start_info, GuestAddress(PVH_INFO_START))
+        .with_context(|| format!("Failed to load start info to 0x{:x}", PVH_INFO_START))?;
+
+    setup_isa_mptable(
+        sys_mem,
+        EBDA_START,
+        config.cpu_count,
+        config.ioapic_addr,
+        config.lapic_addr,
+    )?;
+
+    Ok(X86BootLoader {
+        boot_ip: pvh_entry,
+        boot_sp: BOOT_LOADER_SP,
+        segments: setup_pvh_gdt(sys_mem).with_context(|| "Failed to setup gdt")?,
+        pvh_start_info: Some(PVH_INFO_START),
+        ..Default::default()
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::*;
+    use address_space::{HostMemMapping, Region};
+
+    const TEST_PVH_ENTRY: u32 = 0x0100_0040;
+    const TEST_KERNEL_START: u64 = 0x0100_0000;
+
+    /// Program header of ELF64: type, offset, paddr, filesz, align.
+    fn elf64_phdr(p_type: u32, offset: u64, paddr: u64, filesz: u64, align: u64) -> Vec<u8> {
+        let mut phdr = Vec::new();
+        phdr.extend_from_slice(p_type.as_bytes());
+        phdr.extend_from_slice(0_u32.as_bytes()); // p_flags
+        phdr.extend_from_slice(offset.as_bytes());
+        phdr.extend_from_slice(paddr.as_bytes()); // p_vaddr
+        phdr.extend_from_slice(paddr.as_bytes());
+        phdr.extend_from_slice(filesz.as_bytes());
+        phdr.extend_from_slice(filesz.as_bytes()); // p_memsz
+        phdr.extend_from_slice(align.as_bytes());
+        phdr
+    }
+
+    /// ELF64 image with a PT_LOAD segment and a PT_NOTE segment containing the PVH entry.
+    fn crafted_elf_kernel(code: &[u8]) -> Vec<u8> {
+        let (ehsize, phentsize) = (64_u64, 56_u64);
+        let note_off = ehsize + 2 * phentsize;
+        let mut note = Vec::new();
+        note.extend_from_slice(4_u32.as_bytes()); // namesz
+        note.extend_from_slice(4_u32.as_bytes()); // descsz
+        note.extend_from_slice(0x12_u32.as_bytes()); // XEN_ELFNOTE_PHYS32_ENTRY
+        note.extend_from_slice(b"Xen\0");
+        note.extend_from_slice(TEST_PVH_ENTRY.as_bytes());
+        let load_off = note_off + note.len() as u64;
+
+        let mut image = vec![0_u8; ehsize as usize];
+        image[..6].copy_from_slice(&[0x7f, b'E', b'L', b'F', 2, 1]);
+        image[32..40].copy_from_slice(ehsize.as_bytes()); // e_phoff
+        image[54..56].copy_from_slice((phentsize as u16).as_bytes());
+        image[56..58].copy_from_slice(2_u16.as_bytes()); // e_phnum
+        image.extend(elf64_phdr(
+            1,
+            load_off,
+            TEST_KERNEL_START,
+            code.len() as u64,
+            0x20_0000,
+        ));
+        image.extend(elf64_phdr(4, note_off, 0, note.len() as u64, 4));
+        image.extend_from_slice(&note);
+        image.extend_from_slice(code);
+        image
+    }
+
+    #[test]
+    fn test_pvh_boot() {
+        let kernel_path = "pvh_test_vmlinux";
+        let initrd_path = "pvh_test_initrd";
+        let code = vec![0xf4_u8; 0x80];
+        File::create(kernel_path)
+            .unwrap()
+            .write_all(&crafted_elf_kernel(&code))
+            .unwrap();
+        File::create(initrd_path)
+            .unwrap()
+            .write_all(&[0x5a_u8; 0x1000])
+            .unwrap();
+
+        let root = Region::init_container_region(0x2000_0000);
+        let space = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(
+                GuestAddress(0),
+                None,
+                0x1000_0000,
+                None,
+                false,
+                false,
+                false,
+            )
+            .unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+
+        let mut config = X86BootLoaderConfig {
+            kernel: Some(PathBuf::from(kernel_path)),
+            initrd: Some(PathBuf::from(initrd_path)),
+            kernel_cmdline: String::from("console=ttyS0"),
+            cpu_count: 2,
+            gap_range: (0xC000_0000, 0x4000_0000),
+            ioapic_addr: 0xFEC0_0000,
+            lapic_addr: 0xFEE0_0000,
+            prot64_mode: false,
+            ident_tss_range: None,
+            reserved_ranges: vec![(0x0800_0000, 0x0010_0000)],
+        };
+        assert!(is_pvh_kernel(&config).unwrap());
+        let layout = load_pvh_linux(&config, &space).unwrap();
+        assert_eq!(layout.boot_ip, TEST_PVH_ENTRY as u64);
+        assert_eq!(layout.pvh_start_info, Some(PVH_INFO_START));
+        assert_eq!(layout.segments.code_segment.db, 1);
+        assert_eq!(layout.segments.code_segment.l, 0);
+        assert_eq!(layout.segments.code_segment.selector, 16);
+
+        let mut loaded = vec![0_u8; code.len()];
+        space
+            .read(
+                &mut loaded.as_mut_slice(),
+                GuestAddress(TEST_KERNEL_START),
+                code.len() as u64,
+            )
+            .unwrap();
+        assert_eq!(loaded, code);
+
+        let start_info: HvmStartInfo = space.read_object(GuestAddress(PVH_INFO_START)).unwrap();
+        assert_eq!(start_info.magic, XEN_HVM_START_MAGIC_VALUE);
+        assert_eq!(start_info.version, 1);
+        assert_eq!(start_info.cmdline_paddr, CMDLINE_START);
+        assert_eq!(start_info.rsdp_paddr, 0);
+        assert_eq!(start_info.nr_modules, 1);
+        assert_eq!(start_info.modlist_paddr, PVH_MODLIST_START);
+        assert_eq!(start_info.memmap_paddr, PVH_MEMMAP_START);
+
+        let mut cmdline = [0_u8; 14];
+        space
+            .read(&mut cmdline.as_mut(), GuestAddress(CMDLINE_START), 14)
+            .unwrap();
+        assert_eq!(&cmdline, b"console=ttyS0\0");
+
+        let module: HvmModlistEntry = space.read_object(GuestAddress(PVH_MODLIST_START)).unwrap();
+        assert_eq!(module.size, 0x1000);
+        assert_eq!(module.paddr, 0x1000_0000 - 0x1000);
+
+        // Zero sized entry of the BIOS area is dropped.
+        let expected = [
+            (0, 0x9_fc00, 1),
+            (0x9_fc00, 0x400, 2),
+            (0x10_0000, 0xff0_0000, 1),
+            (0x0800_0000, 0x10_0000, 2),
+        ];
+        assert_eq!(start_info.memmap_entries, expected.len() as u32);
+        for (i, (addr, size, type_)) in expected.iter().enumerate() {
+            let entry: HvmMemmapTableEntry = space
+                .read_object(GuestAddress(PVH_MEMMAP_START + i as u64 * 24))
+                .unwrap();
+            assert_eq!(
+                (entry.addr, entry.size, entry.type_),
+                (*addr, *size, *type_)
+            );
+        }
+
+        // bzImage and vmlinux.bin are not booted from PVH entry.
+        File::create(initrd_path)
+            .unwrap()
+            .write_all(&[0_u8; 0x400])
+            .unwrap();
+        config.kernel = Some(PathBuf::from(initrd_path));
+        assert!(!is_pvh_kernel(&config).unwrap());
+        config.kernel = None;
+        assert!(!is_pvh_kernel(&config).unwrap());
+
+        std::fs::remove_file(kernel_path).unwrap();
+        std::fs::remove_file(initrd_path).unwrap();
+    }
+}
@@ -12,6 +12,9 @@
 
 //! Boot Loader load PE and bzImage linux kernel image to guest memory according
 //! [`x86 boot protocol`](https://www.kernel.org/doc/Documentation/x86/boot.txt).
+//! ELF kernel (vmlinux) with a PVH entry note is booted according to
+//! [`PVH boot ABI`](https://xenbits.xen.org/docs/unstable/misc/pvh.html) when
+//! the machine does not boot in 64-bit protection mode.
 //!
 //! Below is x86_64 bootloader memory layout:
 //!
@@ -20,8 +23,11 @@
 //!   0x0000_0000   |  Real Mode IVT         |
 //!                 |                        |
 //!                 +------------------------+
+//!   0x0000_6000   |                        |
+//!                 |  PVH start info        |
+//!                 |                        |
 //!   0x0000_7000   |                        |
-//!                 |  Zero Page             |
+//!                 |  Zero Page / PVH memmap|
 //!                 |                        |
 //!   0x0000_9000   +------------------------+
 //!                 |  Page Map Level4       |
@@ -63,7 +69,11 @@ use address_space::AddressSpace;
 use devices::legacy::FwCfgOps;
 use kvm_bindings::kvm_segment;
 
-use anyhow::{bail, Result};
+use anyhow::{anyhow, bail, Result};
+use util::num_ops::round_up;
+
+use crate::error::BootLoaderError;
+pub use direct_boot::MPTABLE_MAX_CPUS;
 
 const ZERO_PAGE_START: u64 = 0x0000_7000;
 const PML4_START: u64 = 0x0000_9000;
@@ -78,7 +88,10 @@ const EBDA_START: u64 = 0x0009_fc00;
 const VGA_RAM_BEGIN: u64 = 0x000a_0000;
 const MB_BIOS_BEGIN: u64 = 0x000f_0000;
 pub const VMLINUX_RAM_START: u64 = 0x0010_0000;
+/// Highest address of initrd if the kernel doesn't tell it, see `initrd_addr_max` of boot protocol.
 const INITRD_ADDR_MAX: u64 = 0x37ff_ffff;
+/// Initrd is loaded to a page aligned address.
+const INITRD_ALIGN: u64 = 0x1000;
 
 const VMLINUX_STARTUP: u64 = 0x0100_0000;
 const BOOT_LOADER_SP: u64 = 0x0000_8ff0;
@@ -101,7 +114,7 @@ pub struct X86BootLoaderConfig {
     /// Kernel cmdline parameters.
     pub kernel_cmdline: String,
     /// VM's CPU count.
-    pub cpu_count: u8,
+    pub cpu_count: u16,
     /// (gap start, gap size)
     pub gap_range: (u64, u64),
     /// IO APIC base address
@@ -112,6 +125,8 @@ pub struct X86BootLoaderConfig {
     pub ident_tss_range: Option<(u64, u64)>,
     /// Boot from 64-bit protection mode or not.
     pub prot64_mode: bool,
+    /// Ranges reserved in e820 table besides `ident_tss_range`, (start, size).
+    pub reserved_ranges: Vec<(u64, u64)>,
 }
 
 /// The start address for some boot source in guest memory for `x86_64`.
@@ -123,6 +138,8 @@ pub struct X86BootLoader {
     pub boot_pml4_addr: u64,
     pub zero_page_addr: u64,
     pub segments: BootGdtSegment,
+    /// Address of `hvm_start_info` if the kernel is booted from its PVH entry.
+    pub pvh_start_info: Option<u64>,
 }
 
 #[derive(Debug, Default, Copy, Clone)]
@@ -135,6 +152,48 @@ pub struct BootGdtSegment {
     pub idt_limit: u16,
 }
 
+/// Get the highest page aligned address to load initrd, so that initrd is below the
+/// 32-bit gap, the end of guest memory and `initrd_addr_max`, and above the kernel.
+///
+/// # Arguments
+///
+/// * `initrd_size` - Size of initrd in bytes.
+/// * `config` - Boot source config, contains the 32-bit gap.
+/// * `sys_mem` - Guest memory.
+/// * `initrd_addr_max` - The highest address initrd can occupy, inclusive.
+/// * `kernel_end` - End address of the kernel.
+fn initrd_load_addr(
+    initrd_size: u64,
+    config: &X86BootLoaderConfig,
+    sys_mem: &Arc<AddressSpace>,
+    initrd_addr_max: u64,
+    kernel_end: u64,
+) -> Result<u64> {
+    let low_mem_end = std::cmp::min(sys_mem.memory_end_address().raw_value(), config.gap_range.0);
+    place_initrd(initrd_size, low_mem_end, initrd_addr_max, kernel_end)
+}
+
+fn place_initrd(
+    initrd_size: u64,
+    low_mem_end: u64,
+    initrd_addr_max: u64,
+    kernel_end: u64,
+) -> Result<u64> {
+    let window_end = std::cmp::min(low_mem_end, initrd_addr_max.saturating_add(1));
+    let window_start = round_up(kernel_end, INITRD_ALIGN).unwrap_or(u64::MAX);
+    window_end
+        .checked_sub(initrd_size)
+        .map(|addr| addr & !(INITRD_ALIGN - 1))
+        .filter(|addr| *addr >= window_start)
+        .ok_or_else(|| {
+            anyhow!(BootLoaderError::InitrdNoSpace(
+                initrd_size,
+                window_start,
+                window_end
+            ))
+        })
+}
+
 pub fn load_linux(
     config: &X86BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
@@ -142,6 +201,9 @@ pub fn load_linux(
 ) -> Result<X86BootLoader> {
     if config.prot64_mode {
         direct_boot::load_linux(config, sys_mem)
+    } else if direct_boot::is_pvh_kernel(config)? {
+        // ELF kernel boots from its PVH entry directly, bzImage is loaded by firmware.
+        direct_boot::load_pvh_linux(config, sys_mem)
     } else {
         if fwcfg.is_none() {
             bail!("Failed to load linux: No FwCfg provided");
@@ -157,3 +219,58 @@ pub fn load_linux(
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const M: u64 = 1024 * 1024;
+
+    #[test]
+    fn test_place_initrd() {
+        // Top of 256M memory.
+        assert_eq!(
+            place_initrd(0x1000, 256 * M, INITRD_ADDR_MAX, 32 * M).unwrap(),
+            256 * M - 0x1000
+        );
+        // Unaligned initrd starts at a page boundary.
+        assert_eq!(
+            place_initrd(0x1234, 256 * M, INITRD_ADDR_MAX, 32 * M).unwrap(),
+            256 * M - 0x2000
+        );
+        // 2G memory, below the default initrd_addr_max of old kernels.
+        assert_eq!(
+            place_initrd(64 * M, 2048 * M, INITRD_ADDR_MAX, 32 * M).unwrap(),
+            INITRD_ADDR_MAX + 1 - 64 * M
+        );
+        // 2G memory, initrd_addr_max of the kernel header is honoured.
+        assert_eq!(
+            place_initrd(64 * M, 2048 * M, 0x7fff_ffff, 32 * M).unwrap(),
+            2048 * M - 64 * M
+        );
+        // 8G memory, the low memory ends at the 32-bit gap.
+        assert_eq!(
+            place_initrd(1024 * M, 3072 * M, u32::MAX as u64, 32 * M).unwrap(),
+            2048 * M
+        );
+        // A 1G initrd fits below 2G if the kernel allows it.
+        assert_eq!(
+            place_initrd(1024 * M, 3072 * M, 0x7fff_ffff, 64 * M).unwrap(),
+            1024 * M
+        );
+    }
+
+    #[test]
+    fn test_place_oversized_initrd() {
+        // The initrd doesn't fit between the kernel and the end of memory.
+        let err = place_initrd(1024 * M, 1024 * M, 0x7fff_ffff, 32 * M).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Initrd of 1073741824 bytes doesn't fit in the window [0x2000000, 0x40000000) of guest memory"
+        );
+        // It can't overlap the kernel even if memory is enough.
+        assert!(place_initrd(900 * M, 3072 * M, INITRD_ADDR_MAX, 32 * M).is_err());
+        assert!(place_initrd(880 * M, 3072 * M, INITRD_ADDR_MAX, 16 * M).is_ok());
+        assert!(place_initrd(0x1000, 16 * M, INITRD_ADDR_MAX, 32 * M).is_err());
+    }
+}
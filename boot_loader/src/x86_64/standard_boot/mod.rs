@@ -10,9 +10,6 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-#[allow(non_camel_case_types)]
-mod elf;
-
 use std::fs::File;
 use std::io::{Read, Seek, SeekFrom};
 use std::sync::Arc;
@@ -22,13 +19,12 @@ use devices::legacy::{FwCfgEntryType, FwCfgOps};
 use log::{error, info};
 use util::byte_code::ByteCode;
 
-use self::elf::load_elf_kernel;
 use super::bootparam::RealModeKernelHeader;
 use super::X86BootLoaderConfig;
 use super::{BOOT_HDR_START, CMDLINE_START};
 use crate::error::BootLoaderError;
 use crate::x86_64::bootparam::{E820Entry, E820_RAM, E820_RESERVED, UEFI_OVMF_ID};
-use crate::x86_64::{INITRD_ADDR_MAX, SETUP_START};
+use crate::x86_64::{initrd_load_addr, SETUP_START};
 use anyhow::{anyhow, bail, Context, Result};
 
 fn load_image(
@@ -59,12 +55,7 @@ fn load_kernel_image(
     header: &RealModeKernelHeader,
     fwcfg: &mut dyn FwCfgOps,
 ) -> Result<Vec<u8>> {
-    let mut setup_size = header.setup_sects as u64;
-    if setup_size == 0 {
-        setup_size = 4;
-    }
-    setup_size = (setup_size + 1) << 9;
-
+    let setup_size = header.setup_size();
     let mut setup_data = vec![0_u8; setup_size as usize];
     kernel_image.seek(SeekFrom::Start(0))?;
     kernel_image.read_exact(setup_data.as_mut_slice())?;
@@ -91,21 +82,24 @@ fn load_initrd(
     config: &X86BootLoaderConfig,
     sys_mem: &Arc<AddressSpace>,
     header: &mut RealModeKernelHeader,
+    kernel_size: u64,
     fwcfg: &mut dyn FwCfgOps,
 ) -> Result<()> {
     if config.initrd.is_none() {
         info!("No initrd image file.");
         return Ok(());
     };
-    let mut initrd_addr_max = INITRD_ADDR_MAX;
-    if initrd_addr_max > sys_mem.memory_end_address().raw_value() {
-        initrd_addr_max = sys_mem.memory_end_address().raw_value();
-    };
 
     let mut initrd_image = File::open(config.initrd.as_ref().unwrap())
         .with_context(|| anyhow!(BootLoaderError::BootLoaderOpenInitrd))?;
-    let initrd_size = initrd_image.metadata().unwrap().len();
-    let initrd_addr = (initrd_addr_max - initrd_size) & !0xfff_u64;
+    let initrd_size = initrd_image.metadata()?.len();
+    let initrd_addr = initrd_load_addr(
+        initrd_size,
+        config,
+        sys_mem,
+        header.initrd_addr_max(),
+        header.kernel_end(kernel_size),
+    )?;
 
     load_image(&mut initrd_image, 0, FwCfgEntryType::InitrdData, fwcfg)
         .with_context(|| "Failed to load initrd")?;
@@ -151,6 +145,9 @@ fn setup_e820_table(
     } else {
         error!("The page-table and TSS address is not provided");
     }
+    for (start, size) in config.reserved_ranges.iter() {
+        e820_table.push(E820Entry::new(*start, *size, E820_RESERVED));
+    }
 
     let bytes = e820_table.iter().fold(Vec::new(), |mut bytes, entry| {
         bytes.extend(entry.as_bytes());
@@ -217,18 +214,13 @@ pub fn load_linux(
 
     load_kernel_cmdline(config, &mut boot_header, fwcfg)?;
     setup_e820_table(config, sys_mem, fwcfg)?;
-    load_initrd(config, sys_mem, &mut boot_header, fwcfg)?;
-    if let Err(e) = boot_header.check_valid_kernel() {
-        if let Some(err) = e.downcast_ref::<BootLoaderError>() {
-            match err {
-                BootLoaderError::ElfKernel => {
-                    load_elf_kernel(&mut kernel_image, sys_mem, fwcfg)?;
-                    return Ok(());
-                }
-                _ => return Err(e),
-            }
-        }
-    }
+    let kernel_size = kernel_image
+        .metadata()?
+        .len()
+        .saturating_sub(boot_header.setup_size());
+    load_initrd(config, sys_mem, &mut boot_header, kernel_size, fwcfg)?;
+    // ELF kernels are booted through the PVH entry directly, see `direct_boot::load_pvh_linux`.
+    boot_header.check_valid_kernel()?;
 
     let mut setup_data = load_kernel_image(&mut kernel_image, &boot_header, fwcfg)?;
     let min_setup_len = std::cmp::min(
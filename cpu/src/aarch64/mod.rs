@@ -82,7 +82,7 @@ impl ArmCPUTopology {
         ArmCPUTopology::default()
     }
 
-    pub fn set_topology(self, _topology: (u8, u8, u8)) -> Self {
+    pub fn set_topology(self, _topology: (u16, u16, u16)) -> Self {
         self
     }
 }
@@ -293,6 +293,18 @@ impl CPU {
 
         Ok(())
     }
+
+    /// Get the general purpose registers in the layout of `struct user_pt_regs`,
+    /// which is the `pr_reg` of NT_PRSTATUS note in ELF core files.
+    pub fn elf_prstatus_regs(&self) -> Result<Vec<u64>> {
+        let core_regs = get_core_regs(&self.fd)
+            .with_context(|| format!("Failed to get core registers for CPU {}", self.id))?;
+        let mut regs = core_regs.regs.regs.to_vec();
+        regs.push(core_regs.regs.sp);
+        regs.push(core_regs.regs.pc);
+        regs.push(core_regs.regs.pstate);
+        Ok(regs)
+    }
 }
 
 impl StateTransfer for CPU {
@@ -32,12 +32,14 @@ pub enum CpuError {
     KickVcpu(String),
     #[error("Failed to destroy kvm vcpu: {0}!")]
     DestroyVcpu(String),
+    #[error("Failed to inject NMI to kvm vcpu: {0}!")]
+    InjectNmi(String),
     #[error("CPU {0}/KVM halted!")]
-    VcpuHltEvent(u8),
+    VcpuHltEvent(u16),
     #[error("CPU {0}/KVM received an unexpected exit reason: {1}!")]
-    VcpuExitReason(u8, String),
+    VcpuExitReason(u16, String),
     #[error("CPU {0}/KVM received an unhandled kvm exit event!")]
-    UnhandledKvmExit(u8),
+    UnhandledKvmExit(u16),
     #[error("Vcpu not present in local thread.")]
     VcpuLocalThreadNotPresent,
     #[error("No Machine Interface saved in CPU")]
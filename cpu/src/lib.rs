@@ -35,7 +35,8 @@ mod aarch64;
 mod x86_64;
 
 pub mod error;
-use anyhow::{anyhow, Context, Result};
+pub mod rt;
+use anyhow::{anyhow, bail, Context, Result};
 pub use error::CpuError;
 
 #[cfg(target_arch = "aarch64")]
@@ -66,18 +67,24 @@ use std::cell::RefCell;
 use std::sync::atomic::{fence, AtomicBool, Ordering};
 use std::sync::{Arc, Barrier, Condvar, Mutex, Weak};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+#[cfg(target_arch = "x86_64")]
+use hypervisor::kvm::KVM_NMI;
 use kvm_ioctls::{VcpuExit, VcpuFd};
 use libc::{c_int, c_void, siginfo_t};
 use log::{error, info, warn};
-use machine_manager::config::ShutdownAction::{ShutdownActionPause, ShutdownActionPoweroff};
-use machine_manager::event;
-use machine_manager::machine::MachineInterface;
-use machine_manager::{qmp::qmp_schema as schema, qmp::QmpChannel};
+#[cfg(target_arch = "x86_64")]
+use machine_manager::config::CpuFeaturesConfig;
+use machine_manager::config::ShutdownAction::ShutdownActionPoweroff;
+use machine_manager::machine::{KvmVmState, MachineInterface};
 
+use rt::{VcpuRealtime, VcpuRtAccess, VcpuRtEvent};
+use util::rt::RtSection;
 #[cfg(not(test))]
 use util::test_helper::is_test_enabled;
+#[cfg(target_arch = "x86_64")]
+use vmm_sys_util::ioctl::ioctl;
 use vmm_sys_util::signal::{register_signal_handler, Killable};
 
 // SIGRTMIN = 34 (GNU, in MUSL is 35) and SIGRTMAX = 64  in linux, VCPU signal
@@ -90,6 +97,16 @@ const VCPU_TASK_SIGNAL: i32 = 35;
 const VCPU_RESET_SIGNAL: i32 = 35;
 #[cfg(target_env = "musl")]
 const VCPU_RESET_SIGNAL: i32 = 36;
+#[cfg(not(target_env = "musl"))]
+const VCPU_NMI_SIGNAL: i32 = 36;
+#[cfg(target_env = "musl")]
+const VCPU_NMI_SIGNAL: i32 = 37;
+
+/// Driver name of vcpu, used by `device_add` and `query-hotpluggable-cpus`.
+#[cfg(target_arch = "x86_64")]
+pub const CPU_DRIVER: &str = "host-x86-cpu";
+#[cfg(target_arch = "aarch64")]
+pub const CPU_DRIVER: &str = "host-aarch64-cpu";
 
 /// Watch `0x3ff` IO port to record the magic value trapped from guest kernel.
 #[cfg(all(target_arch = "x86_64", feature = "boot_time"))]
@@ -164,6 +181,9 @@ pub trait CPUInterface {
     /// Make `CPU` destroy because of guest inner reset.
     fn guest_reset(&self) -> Result<()>;
 
+    /// Inject NMI to `CPU`, it's delivered when the `CPU` runs.
+    fn inject_nmi(&self) -> Result<()>;
+
     /// Handle vcpu event from `kvm`.
     fn kvm_vcpu_exec(&self) -> Result<bool>;
 }
@@ -172,7 +192,7 @@ pub trait CPUInterface {
 #[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     /// ID of this virtual CPU, `0` means this cpu is primary `CPU`.
-    id: u8,
+    id: u16,
     /// The file descriptor of this kvm-based VCPU.
     fd: Arc<VcpuFd>,
     /// Architecture special CPU property.
@@ -191,6 +211,18 @@ pub struct CPU {
     boot_state: Arc<Mutex<ArchCPU>>,
     /// Sync the pause state of vCPU in kvm and userspace.
     pause_signal: Arc<AtomicBool>,
+    /// State of the realtime run loop, `None` if this vCPU runs in normal mode.
+    realtime: Arc<Mutex<Option<Arc<VcpuRealtime>>>>,
+    /// Host cpus this vCPU thread is pinned to, `None` if it's not pinned.
+    affinity: Arc<Mutex<Option<Vec<usize>>>>,
+    /// Realtime scheduling policy and priority of this vCPU thread, `None` to keep the default.
+    sched_priority: Arc<Mutex<Option<(i32, i32)>>>,
+    /// Feature flags overriding the host CPU model, applied to CPUID on reset.
+    #[cfg(target_arch = "x86_64")]
+    cpuid_features: Arc<Mutex<CpuFeaturesConfig>>,
+    /// NMI to be injected in the thread of this VCPU.
+    #[cfg(target_arch = "x86_64")]
+    nmi_pending: Arc<AtomicBool>,
 }
 
 impl CPU {
@@ -204,7 +236,7 @@ impl CPU {
     /// * `vm` - The virtual machine this `CPU` gets attached to.
     pub fn new(
         vcpu_fd: Arc<VcpuFd>,
-        id: u8,
+        id: u16,
         arch_cpu: Arc<Mutex<ArchCPU>>,
         vm: Arc<Mutex<dyn MachineInterface + Send + Sync>>,
     ) -> Self {
@@ -219,6 +251,13 @@ impl CPU {
             caps: CPUCaps::init_capabilities(),
             boot_state: Arc::new(Mutex::new(ArchCPU::default())),
             pause_signal: Arc::new(AtomicBool::new(false)),
+            realtime: Arc::new(Mutex::new(None)),
+            affinity: Arc::new(Mutex::new(None)),
+            sched_priority: Arc::new(Mutex::new(None)),
+            #[cfg(target_arch = "x86_64")]
+            cpuid_features: Arc::new(Mutex::new(CpuFeaturesConfig::default())),
+            #[cfg(target_arch = "x86_64")]
+            nmi_pending: Arc::new(AtomicBool::new(false)),
         }
     }
 
@@ -227,10 +266,15 @@ impl CPU {
     }
 
     /// Get this `CPU`'s ID.
-    pub fn id(&self) -> u8 {
+    pub fn id(&self) -> u16 {
         self.id
     }
 
+    /// Get the VM this `CPU` is attached to.
+    pub fn vm(&self) -> Option<Arc<Mutex<dyn MachineInterface + Send + Sync>>> {
+        self.vm.upgrade()
+    }
+
     /// Get this `CPU`'s file descriptor.
     pub fn fd(&self) -> &Arc<VcpuFd> {
         &self.fd
@@ -262,6 +306,190 @@ impl CPU {
     fn set_tid(&self) {
         *self.tid.lock().unwrap() = Some(util::unix::gettid());
     }
+
+    /// Run this `CPU` in realtime mode, must be called before it starts.
+    pub fn set_realtime(&self, realtime: Arc<VcpuRealtime>) {
+        *self.realtime.lock().unwrap() = Some(realtime);
+    }
+
+    /// Pin the thread of this `CPU` to host cpus, must be called before it starts.
+    pub fn set_affinity(&self, host_cpus: Vec<usize>) {
+        *self.affinity.lock().unwrap() = Some(host_cpus);
+    }
+
+    /// Get the host cpus the thread of this `CPU` is pinned to.
+    pub fn affinity(&self) -> Option<Vec<usize>> {
+        self.affinity.lock().unwrap().clone()
+    }
+
+    /// Set the realtime scheduling policy and priority of the thread of this `CPU`, must be
+    /// called before it starts.
+    pub fn set_sched_priority(&self, policy: i32, priority: i32) {
+        *self.sched_priority.lock().unwrap() = Some((policy, priority));
+    }
+
+    /// Override the features of the host CPU model, must be called before it starts.
+    #[cfg(target_arch = "x86_64")]
+    pub fn set_cpuid_features(&self, features: CpuFeaturesConfig) {
+        *self.cpuid_features.lock().unwrap() = features;
+    }
+
+    /// Inject the pending NMI. `KVM_NMI` waits for `KVM_RUN` to return, so it's only
+    /// called in the thread of this `CPU` once `KVM_RUN` is interrupted by the kick.
+    #[cfg(target_arch = "x86_64")]
+    fn handle_pending_nmi(&self) {
+        if self.nmi_pending.swap(false, Ordering::SeqCst) {
+            // SAFETY: The vcpu fd is valid, and KVM_NMI has no argument.
+            let ret = unsafe { ioctl(self.fd.as_ref(), KVM_NMI()) };
+            if ret < 0 {
+                error!(
+                    "Failed to inject NMI to vcpu{}: {}",
+                    self.id,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+    }
+
+    /// Handle the exits which are not PIO/MMIO accesses.
+    fn handle_vcpu_exit(&self, exit: VcpuExit) -> Result<bool> {
+        match exit {
+            #[cfg(target_arch = "x86_64")]
+            VcpuExit::Hlt => {
+                info!("Vcpu{} received KVM_EXIT_HLT signal", self.id());
+                Err(anyhow!(CpuError::VcpuHltEvent(self.id())))
+            }
+            #[cfg(target_arch = "x86_64")]
+            VcpuExit::Shutdown => {
+                info!("Vcpu{} received an KVM_EXIT_SHUTDOWN signal", self.id());
+                self.guest_shutdown()?;
+
+                Ok(false)
+            }
+            #[cfg(target_arch = "x86_64")]
+            VcpuExit::IoapicEoi(vector) => {
+                hypervisor::kvm::ioapic_eoi(vector);
+                Ok(true)
+            }
+            #[cfg(target_arch = "aarch64")]
+            VcpuExit::SystemEvent(event, flags) => {
+                if event == kvm_bindings::KVM_SYSTEM_EVENT_SHUTDOWN {
+                    info!(
+                        "Vcpu{} received an KVM_SYSTEM_EVENT_SHUTDOWN signal",
+                        self.id()
+                    );
+                    self.guest_shutdown()
+                        .with_context(|| "Some error occurred in guest shutdown")?;
+                    return Ok(true);
+                } else if event == kvm_bindings::KVM_SYSTEM_EVENT_RESET {
+                    info!(
+                        "Vcpu{} received an KVM_SYSTEM_EVENT_RESET signal",
+                        self.id()
+                    );
+                    self.guest_reset()
+                        .with_context(|| "Some error occurred in guest reset")?;
+                    return Ok(true);
+                } else {
+                    error!(
+                        "Vcpu{} received unexpected system event with type 0x{:x}, flags 0x{:x}",
+                        self.id(),
+                        event,
+                        flags
+                    );
+                }
+                Ok(false)
+            }
+            VcpuExit::FailEntry(reason, cpuid) => {
+                info!(
+                    "Vcpu{} received KVM_EXIT_FAIL_ENTRY signal. the vcpu could not be run due to unknown reasons({})",
+                    cpuid, reason
+                );
+                Ok(false)
+            }
+            VcpuExit::InternalError => {
+                info!("Vcpu{} received KVM_EXIT_INTERNAL_ERROR signal", self.id());
+                Ok(false)
+            }
+            r => Err(anyhow!(CpuError::VcpuExitReason(
+                self.id(),
+                format!("{:?}", r)
+            ))),
+        }
+    }
+
+    /// Handle the failure of `KVM_RUN`.
+    fn handle_vcpu_run_error(&self, e: &vmm_sys_util::errno::Error) -> Result<bool> {
+        match e.errno() {
+            libc::EAGAIN => {}
+            libc::EINTR => {
+                self.fd.set_kvm_immediate_exit(0);
+                #[cfg(target_arch = "x86_64")]
+                self.handle_pending_nmi();
+            }
+            _ => {
+                return Err(anyhow!(CpuError::UnhandledKvmExit(self.id())));
+            }
+        };
+        Ok(true)
+    }
+
+    /// Run this `CPU` in realtime mode. PIO/MMIO exits are handled in a loop without
+    /// taking the machine lock or allocating memory, any other exit or run error is
+    /// returned as is to be handled by the caller out of the realtime section.
+    ///
+    /// # Arguments
+    ///
+    /// * `rt` - State of the realtime run loop.
+    fn kvm_vcpu_exec_rt(
+        &self,
+        rt: &VcpuRealtime,
+    ) -> std::result::Result<VcpuExit<'_>, vmm_sys_util::errno::Error> {
+        loop {
+            let section = RtSection::enter();
+            let exit = self.fd.run();
+            let start = Instant::now();
+            let (ret, access, addr, len) = match exit {
+                #[cfg(target_arch = "x86_64")]
+                Ok(VcpuExit::IoIn(addr, data)) => (
+                    rt.io().pio_in(u64::from(addr), data),
+                    VcpuRtAccess::PioIn,
+                    u64::from(addr),
+                    data.len(),
+                ),
+                #[cfg(target_arch = "x86_64")]
+                Ok(VcpuExit::IoOut(addr, data)) => (
+                    rt.io().pio_out(u64::from(addr), data),
+                    VcpuRtAccess::PioOut,
+                    u64::from(addr),
+                    data.len(),
+                ),
+                Ok(VcpuExit::MmioRead(addr, data)) => (
+                    rt.io().mmio_read(addr, data),
+                    VcpuRtAccess::MmioRead,
+                    addr,
+                    data.len(),
+                ),
+                Ok(VcpuExit::MmioWrite(addr, data)) => (
+                    rt.io().mmio_write(addr, data),
+                    VcpuRtAccess::MmioWrite,
+                    addr,
+                    data.len(),
+                ),
+                other => {
+                    drop(section);
+                    return other;
+                }
+            };
+            if !ret {
+                rt.report(VcpuRtEvent::AccessFailed {
+                    access,
+                    addr,
+                    len: len as u8,
+                });
+            }
+            rt.exit_latency().record(start.elapsed().as_nanos() as u64);
+        }
+    }
 }
 
 impl CPUInterface for CPU {
@@ -437,29 +665,16 @@ impl CPUInterface for CPU {
 
     fn guest_shutdown(&self) -> Result<()> {
         if let Some(vm) = self.vm.upgrade() {
-            let shutdown_act = vm.lock().unwrap().get_shutdown_action();
-            match shutdown_act {
-                ShutdownActionPoweroff => {
-                    let (cpu_state, _) = &*self.state;
-                    *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
-                    vm.lock().unwrap().destroy();
-                }
-                ShutdownActionPause => {
-                    vm.lock().unwrap().pause();
-                }
+            let locked_vm = vm.lock().unwrap();
+            if locked_vm.get_shutdown_action() == ShutdownActionPoweroff {
+                let (cpu_state, _) = &*self.state;
+                *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
             }
+            locked_vm.shutdown_by_guest("guest-shutdown");
         } else {
             return Err(anyhow!(CpuError::NoMachineInterface));
         }
 
-        if QmpChannel::is_connected() {
-            let shutdown_msg = schema::Shutdown {
-                guest: true,
-                reason: "guest-shutdown".to_string(),
-            };
-            event!(Shutdown; shutdown_msg);
-        }
-
         Ok(())
     }
 
@@ -473,7 +688,38 @@ impl CPUInterface for CPU {
         Ok(())
     }
 
+    fn inject_nmi(&self) -> Result<()> {
+        #[cfg(target_arch = "x86_64")]
+        {
+            self.nmi_pending.store(true, Ordering::SeqCst);
+            let task = self.task.lock().unwrap();
+            match task.as_ref() {
+                Some(thread) => thread
+                    .kill(VCPU_NMI_SIGNAL)
+                    .with_context(|| anyhow!(CpuError::KickVcpu("Fail to kick vcpu".to_string()))),
+                None => {
+                    // Make the first `KVM_RUN` return to inject it.
+                    self.fd.set_kvm_immediate_exit(1);
+                    Ok(())
+                }
+            }
+        }
+        #[cfg(target_arch = "aarch64")]
+        Err(anyhow!(CpuError::InjectNmi(format!(
+            "vcpu{} doesn't support NMI",
+            self.id()
+        ))))
+    }
+
     fn kvm_vcpu_exec(&self) -> Result<bool> {
+        let realtime = self.realtime.lock().unwrap().clone();
+        if let Some(rt) = realtime.as_ref() {
+            return match self.kvm_vcpu_exec_rt(rt) {
+                Ok(exit) => self.handle_vcpu_exit(exit),
+                Err(ref e) => self.handle_vcpu_run_error(e),
+            };
+        }
+
         let vm = if let Some(vm) = self.vm.upgrade() {
             vm
         } else {
@@ -502,75 +748,9 @@ impl CPUInterface for CPU {
 
                     vm.lock().unwrap().mmio_write(addr, data);
                 }
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::Hlt => {
-                    info!("Vcpu{} received KVM_EXIT_HLT signal", self.id());
-                    return Err(anyhow!(CpuError::VcpuHltEvent(self.id())));
-                }
-                #[cfg(target_arch = "x86_64")]
-                VcpuExit::Shutdown => {
-                    info!("Vcpu{} received an KVM_EXIT_SHUTDOWN signal", self.id());
-                    self.guest_shutdown()?;
-
-                    return Ok(false);
-                }
-                #[cfg(target_arch = "aarch64")]
-                VcpuExit::SystemEvent(event, flags) => {
-                    if event == kvm_bindings::KVM_SYSTEM_EVENT_SHUTDOWN {
-                        info!(
-                            "Vcpu{} received an KVM_SYSTEM_EVENT_SHUTDOWN signal",
-                            self.id()
-                        );
-                        self.guest_shutdown()
-                            .with_context(|| "Some error occurred in guest shutdown")?;
-                        return Ok(true);
-                    } else if event == kvm_bindings::KVM_SYSTEM_EVENT_RESET {
-                        info!(
-                            "Vcpu{} received an KVM_SYSTEM_EVENT_RESET signal",
-                            self.id()
-                        );
-                        self.guest_reset()
-                            .with_context(|| "Some error occurred in guest reset")?;
-                        return Ok(true);
-                    } else {
-                        error!(
-                            "Vcpu{} received unexpected system event with type 0x{:x}, flags 0x{:x}",
-                            self.id(),
-                            event,
-                            flags
-                        );
-                    }
-                    return Ok(false);
-                }
-                VcpuExit::FailEntry(reason, cpuid) => {
-                    info!(
-                        "Vcpu{} received KVM_EXIT_FAIL_ENTRY signal. the vcpu could not be run due to unknown reasons({})",
-                        cpuid, reason
-                    );
-                    return Ok(false);
-                }
-                VcpuExit::InternalError => {
-                    info!("Vcpu{} received KVM_EXIT_INTERNAL_ERROR signal", self.id());
-                    return Ok(false);
-                }
-                r => {
-                    return Err(anyhow!(CpuError::VcpuExitReason(
-                        self.id(),
-                        format!("{:?}", r)
-                    )));
-                }
+                r => return self.handle_vcpu_exit(r),
             },
-            Err(ref e) => {
-                match e.errno() {
-                    libc::EAGAIN => {}
-                    libc::EINTR => {
-                        self.fd.set_kvm_immediate_exit(0);
-                    }
-                    _ => {
-                        return Err(anyhow!(CpuError::UnhandledKvmExit(self.id())));
-                    }
-                };
-            }
+            Err(ref e) => return self.handle_vcpu_run_error(e),
         }
         Ok(true)
     }
@@ -625,12 +805,20 @@ impl CPUThreadWorker {
                         fence(Ordering::Release)
                     });
                 }
+                VCPU_NMI_SIGNAL => {
+                    // The pending NMI is injected once `KVM_RUN` returns.
+                    let _ = CPUThreadWorker::run_on_local_thread_vcpu(|vcpu| {
+                        vcpu.fd().set_kvm_immediate_exit(1);
+                    });
+                }
                 VCPU_RESET_SIGNAL => {
                     let _ = CPUThreadWorker::run_on_local_thread_vcpu(|vcpu| {
                         if let Err(e) = vcpu.arch_cpu.lock().unwrap().reset_vcpu(
                             &vcpu.fd,
                             #[cfg(target_arch = "x86_64")]
                             &vcpu.caps,
+                            #[cfg(target_arch = "x86_64")]
+                            &vcpu.cpuid_features.lock().unwrap(),
                         ) {
                             error!("Failed to reset vcpu state: {}", e.to_string())
                         }
@@ -644,6 +832,8 @@ impl CPUThreadWorker {
             .with_context(|| "Failed to register VCPU_TASK_SIGNAL signal.")?;
         register_signal_handler(VCPU_RESET_SIGNAL, handle_signal)
             .with_context(|| "Failed to register VCPU_TASK_SIGNAL signal.")?;
+        register_signal_handler(VCPU_NMI_SIGNAL, handle_signal)
+            .with_context(|| "Failed to register VCPU_NMI_SIGNAL signal.")?;
 
         Ok(())
     }
@@ -690,6 +880,26 @@ impl CPUThreadWorker {
 
         self.thread_cpu.set_tid();
 
+        let mut affinity = self.thread_cpu.affinity.lock().unwrap();
+        if let Some(host_cpus) = affinity.as_ref() {
+            // SAFETY: pthread_self() is always successful.
+            let thread = unsafe { libc::pthread_self() };
+            if let Err(e) = util::unix::set_thread_affinity(thread, host_cpus) {
+                error!("Failed to pin cpu{} thread: {:?}", self.thread_cpu.id, e);
+                // Only report the affinity which is applied.
+                *affinity = None;
+            }
+        }
+        drop(affinity);
+        if let Some((policy, priority)) = *self.thread_cpu.sched_priority.lock().unwrap() {
+            if let Err(e) = util::unix::set_thread_sched_priority(policy, priority) {
+                error!(
+                    "Failed to set priority of cpu{} thread: {:?}",
+                    self.thread_cpu.id, e
+                );
+            }
+        }
+
         // The vcpu thread is going to run,
         // reset its running environment.
         #[cfg(not(test))]
@@ -701,6 +911,8 @@ impl CPUThreadWorker {
                 &self.thread_cpu.fd,
                 #[cfg(target_arch = "x86_64")]
                 &self.thread_cpu.caps,
+                #[cfg(target_arch = "x86_64")]
+                &self.thread_cpu.cpuid_features.lock().unwrap(),
             )
             .with_context(|| "Failed to reset for cpu register state")?;
 
@@ -708,6 +920,7 @@ impl CPUThreadWorker {
         // environment initialization.
         thread_barrier.wait();
 
+        let realtime = self.thread_cpu.realtime.lock().unwrap().clone();
         info!("vcpu{} start running", self.thread_cpu.id);
         while let Ok(true) = self.ready_for_running() {
             #[cfg(not(test))]
@@ -730,6 +943,10 @@ impl CPUThreadWorker {
             }
         }
 
+        if let Some(rt) = realtime.as_ref() {
+            rt.flush_stats(self.thread_cpu.id);
+        }
+
         // The vcpu thread is about to exit, marking the state
         // of the CPU state as Stopped.
         let (cpu_state, cvar) = &*self.thread_cpu.state;
@@ -744,19 +961,19 @@ impl CPUThreadWorker {
 #[derive(Clone)]
 pub struct CpuTopology {
     /// Number of vcpus in VM.
-    pub nrcpus: u8,
+    pub nrcpus: u16,
     /// Number of sockets in VM.
-    pub sockets: u8,
+    pub sockets: u16,
     /// Number of dies in one socket.
-    pub dies: u8,
+    pub dies: u16,
     /// Number of clusters in one die.
-    pub clusters: u8,
+    pub clusters: u16,
     /// Number of cores in one cluster.
-    pub cores: u8,
+    pub cores: u16,
     /// Number of threads in one core.
-    pub threads: u8,
+    pub threads: u16,
     /// Number of online vcpus in VM.
-    pub max_cpus: u8,
+    pub max_cpus: u16,
     /// Online mask number of all vcpus.
     pub online_mask: Arc<Mutex<Vec<u8>>>,
 }
@@ -770,13 +987,13 @@ impl CpuTopology {
     /// * `nr_threads`: Number of threads in one core.
     /// * `max_cpus`: Number of online vcpus in VM.
     pub fn new(
-        nr_cpus: u8,
-        nr_sockets: u8,
-        nr_dies: u8,
-        nr_clusters: u8,
-        nr_cores: u8,
-        nr_threads: u8,
-        max_cpus: u8,
+        nr_cpus: u16,
+        nr_sockets: u16,
+        nr_dies: u16,
+        nr_clusters: u16,
+        nr_cores: u16,
+        nr_threads: u16,
+        max_cpus: u16,
     ) -> Self {
         let mut mask: Vec<u8> = vec![0; max_cpus as usize];
         (0..nr_cpus as usize).for_each(|index| {
@@ -809,19 +1026,83 @@ impl CpuTopology {
         mask[vcpu_id]
     }
 
+    /// Mark a hotplugged cpu as online and account it in `nrcpus`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vcpu_id` - ID of vcpu.
+    pub fn plug_cpu(&mut self, vcpu_id: u16) -> Result<()> {
+        if vcpu_id >= self.max_cpus {
+            bail!(
+                "CPU {} is out of range, the max cpus is {}",
+                vcpu_id,
+                self.max_cpus
+            );
+        }
+        let mut mask = self.online_mask.lock().unwrap();
+        if mask[vcpu_id as usize] == 1 {
+            bail!("CPU {} is already present", vcpu_id);
+        }
+        mask[vcpu_id as usize] = 1;
+        self.nrcpus += 1;
+        Ok(())
+    }
+
+    /// Get the ID of the vcpu located at the given topology position, the
+    /// position out of the topology returns `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `item` - `socket-id`, `die-id`, `cluster-id`, `core-id` and `thread-id`
+    ///   of vcpu.
+    pub fn get_cpu_index(&self, item: (u16, u16, u16, u16, u16)) -> Option<u16> {
+        let (socketid, dieid, clusterid, coreid, threadid) = item;
+        if socketid >= self.sockets
+            || dieid >= self.dies
+            || clusterid >= self.clusters
+            || coreid >= self.cores
+            || threadid >= self.threads
+        {
+            return None;
+        }
+        let index = (((socketid as usize * self.dies as usize + dieid as usize)
+            * self.clusters as usize
+            + clusterid as usize)
+            * self.cores as usize
+            + coreid as usize)
+            * self.threads as usize
+            + threadid as usize;
+        if index >= self.max_cpus as usize {
+            return None;
+        }
+        Some(index as u16)
+    }
+
     /// Get single cpu topology for vcpu, return this vcpu's `socket-id`,
     /// `core-id` and `thread-id`.
     ///
     /// # Arguments
     ///
     /// * `vcpu_id` - ID of vcpu.
-    fn get_topo_item(&self, vcpu_id: usize) -> (u8, u8, u8, u8, u8) {
-        let socketid: u8 = vcpu_id as u8 / (self.dies * self.clusters * self.cores * self.threads);
-        let dieid: u8 = (vcpu_id as u8 / (self.clusters * self.cores * self.threads)) % self.dies;
-        let clusterid: u8 = (vcpu_id as u8 / (self.cores * self.threads)) % self.clusters;
-        let coreid: u8 = (vcpu_id as u8 / self.threads) % self.cores;
-        let threadid: u8 = vcpu_id as u8 % self.threads;
-        (socketid, dieid, clusterid, coreid, threadid)
+    fn get_topo_item(&self, vcpu_id: usize) -> (u16, u16, u16, u16, u16) {
+        let (dies, clusters, cores, threads) = (
+            self.dies as usize,
+            self.clusters as usize,
+            self.cores as usize,
+            self.threads as usize,
+        );
+        let socketid = vcpu_id / (dies * clusters * cores * threads);
+        let dieid = (vcpu_id / (clusters * cores * threads)) % dies;
+        let clusterid = (vcpu_id / (cores * threads)) % clusters;
+        let coreid = (vcpu_id / threads) % cores;
+        let threadid = vcpu_id % threads;
+        (
+            socketid as u16,
+            dieid as u16,
+            clusterid as u16,
+            coreid as u16,
+            threadid as u16,
+        )
     }
 
     pub fn get_topo_instance_for_qmp(&self, cpu_index: usize) -> qmp_schema::CpuInstanceProperties {
@@ -837,6 +1118,58 @@ impl CpuTopology {
             thread_id: Some(threadid as isize),
         }
     }
+
+    /// Get all possible cpus for qmp, only present cpus have `qom-path`.
+    pub fn get_hotpluggable_cpus_for_qmp(&self) -> Vec<qmp_schema::HotpluggableCPU> {
+        (0..self.max_cpus as usize)
+            .map(|cpu_index| qmp_schema::HotpluggableCPU {
+                type_: CPU_DRIVER.to_string(),
+                vcpus_count: 1,
+                props: self.get_topo_instance_for_qmp(cpu_index),
+                qom_path: if self.get_mask(cpu_index) == 1 {
+                    Some(format!("/machine/unattached/device[{}]", cpu_index))
+                } else {
+                    None
+                },
+            })
+            .collect()
+    }
+}
+
+/// Inject NMI to all the `cpus`, for `inject-nmi`. NMI stays pending in the paused
+/// VCPUs until the VM resumes.
+///
+/// # Arguments
+///
+/// * `cpus` - VCPUs of the VM.
+/// * `vm_state` - State of the VM.
+pub fn cpus_inject_nmi<T: CPUInterface>(cpus: &[Arc<T>], vm_state: KvmVmState) -> Result<()> {
+    if vm_state != KvmVmState::Running && vm_state != KvmVmState::Paused {
+        bail!("Unable to inject NMI: vm is in {:?} state", vm_state);
+    }
+    for (cpu_index, cpu) in cpus.iter().enumerate() {
+        cpu.inject_nmi()
+            .with_context(|| format!("Failed to inject NMI to vcpu{}", cpu_index))?;
+    }
+    Ok(())
+}
+
+/// Resume all the `cpus` of the suspended VM, for `system_wakeup`.
+///
+/// # Arguments
+///
+/// * `cpus` - VCPUs of the VM.
+/// * `vm_state` - State of the VM, it's `Running` after wakeup.
+pub fn cpus_wakeup<T: CPUInterface>(cpus: &[Arc<T>], vm_state: &mut KvmVmState) -> Result<()> {
+    if *vm_state != KvmVmState::Suspended {
+        bail!("Unable to wake up: guest is not in suspended state");
+    }
+    for (cpu_index, cpu) in cpus.iter().enumerate() {
+        cpu.resume()
+            .with_context(|| format!("Failed to resume vcpu{}", cpu_index))?;
+    }
+    *vm_state = KvmVmState::Running;
+    Ok(())
 }
 
 fn trace_cpu_boot_config(cpu_boot_config: &CPUBootConfig) {
@@ -866,6 +1199,7 @@ mod tests {
         KvmVmState, MachineAddressInterface, MachineInterface, MachineLifecycle,
     };
     use serial_test::serial;
+    use vmm_sys_util::eventfd::EventFd;
 
     use super::*;
 
@@ -1017,9 +1351,102 @@ mod tests {
         drop(cpu_state);
     }
 
+    #[test]
+    #[serial]
+    #[cfg(target_arch = "x86_64")]
+    fn test_cpu_exec_realtime() {
+        let kvm_fds = KVMFds::new();
+        if kvm_fds.vm_fd.is_none() {
+            return;
+        }
+        KVM_FDS.store(Arc::new(kvm_fds));
+
+        // Guest code in real mode: write 0x41 to port 0x3f8 and to 0x8000 which is out of
+        // the guest memory, then halt.
+        let code: [u8; 10] = [0xba, 0xf8, 0x03, 0xb0, 0x41, 0xee, 0xa2, 0x00, 0x80, 0xf4];
+        let mem_size = 0x4000;
+        // SAFETY: It's an anonymous mapping with valid arguments.
+        let host_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(host_addr, libc::MAP_FAILED);
+        // SAFETY: The code fits in the mapping.
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                code.as_ptr(),
+                (host_addr as *mut u8).add(0x1000),
+                code.len(),
+            )
+        };
+        let region = kvm_bindings::kvm_userspace_memory_region {
+            slot: 0,
+            flags: 0,
+            guest_phys_addr: 0,
+            memory_size: mem_size as u64,
+            userspace_addr: host_addr as u64,
+        };
+        // SAFETY: The mapping is valid until the end of the test.
+        unsafe {
+            KVM_FDS
+                .load()
+                .vm_fd
+                .as_ref()
+                .unwrap()
+                .set_user_memory_region(region)
+                .unwrap()
+        };
+
+        let vcpu_fd = KVM_FDS
+            .load()
+            .vm_fd
+            .as_ref()
+            .unwrap()
+            .create_vcpu(0)
+            .unwrap();
+        let mut sregs = vcpu_fd.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu_fd.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu_fd.get_regs().unwrap();
+        regs.rip = 0x1000;
+        regs.rflags = 0x2;
+        vcpu_fd.set_regs(&regs).unwrap();
+
+        let vm = Arc::new(Mutex::new(TestVm::new()));
+        let io = Arc::new(TestVm::new());
+        let cpu = CPU::new(
+            Arc::new(vcpu_fd),
+            0,
+            Arc::new(Mutex::new(ArchCPU::default())),
+            vm,
+        );
+        let notifier = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        cpu.set_realtime(Arc::new(VcpuRealtime::new(io.clone(), notifier)));
+
+        // PIO/MMIO exits are handled in the realtime loop, which returns at HLT.
+        assert!(cpu.kvm_vcpu_exec().is_err());
+        assert_eq!(*io.pio_out.lock().unwrap(), vec![(0x3f8, vec![0x41])]);
+        assert_eq!(*io.mmio_write.lock().unwrap(), vec![(0x8000, vec![0x41])]);
+
+        // NMI is injected once the realtime loop is kicked.
+        assert!(cpu.inject_nmi().is_ok());
+        assert!(cpu.kvm_vcpu_exec().unwrap());
+        assert!(!cpu.nmi_pending.load(Ordering::SeqCst));
+
+        // SAFETY: The vcpu doesn't run any more.
+        unsafe { libc::munmap(host_addr, mem_size) };
+    }
+
     #[test]
     fn test_cpu_get_topu() {
-        let test_nr_cpus: u8 = 16;
+        let test_nr_cpus: u16 = 16;
         let mask = Vec::with_capacity(test_nr_cpus as usize);
 
         let microvm_cpu_topo = CpuTopology {
@@ -1072,7 +1499,7 @@ mod tests {
         assert_eq!(microvm_cpu_topo_arm.get_topo_item(8), (0, 0, 1, 0, 0));
         assert_eq!(microvm_cpu_topo_arm.get_topo_item(15), (0, 0, 1, 3, 1));
 
-        let test_nr_cpus: u8 = 32;
+        let test_nr_cpus: u16 = 32;
         let mask = Vec::with_capacity(test_nr_cpus as usize);
         let test_cpu_topo = CpuTopology {
             sockets: 2,
@@ -1095,4 +1522,169 @@ mod tests {
         assert_eq!(test_cpu_topo.get_topo_item(29), (3, 0, 0, 2, 1));
         assert_eq!(test_cpu_topo.get_topo_item(31), (3, 0, 0, 3, 1));
     }
+
+    #[test]
+    fn test_cpu_topo_hotplug() {
+        // -smp cpus=2,maxcpus=8,sockets=2,dies=1,cores=2,threads=2
+        let mut cpu_topo = CpuTopology::new(2, 2, 1, 1, 2, 2, 8);
+        for cpu_index in 0..8 {
+            let item = cpu_topo.get_topo_item(cpu_index);
+            assert_eq!(cpu_topo.get_cpu_index(item), Some(cpu_index as u16));
+        }
+        assert_eq!(cpu_topo.get_cpu_index((2, 0, 0, 0, 0)), None);
+        assert_eq!(cpu_topo.get_cpu_index((0, 1, 0, 0, 0)), None);
+        assert_eq!(cpu_topo.get_cpu_index((0, 0, 0, 2, 0)), None);
+        assert_eq!(cpu_topo.get_cpu_index((0, 0, 0, 0, 2)), None);
+
+        // socket-id=1,core-id=1,thread-id=0
+        let cpu_index = cpu_topo.get_cpu_index((1, 0, 0, 1, 0)).unwrap();
+        assert_eq!(cpu_index, 6);
+        assert_eq!(cpu_topo.get_mask(6), 0);
+        assert!(cpu_topo.plug_cpu(cpu_index).is_ok());
+        assert_eq!(cpu_topo.get_mask(6), 1);
+        assert_eq!(cpu_topo.nrcpus, 3);
+        assert!(cpu_topo.plug_cpu(cpu_index).is_err());
+        assert!(cpu_topo.plug_cpu(1).is_err());
+        assert!(cpu_topo.plug_cpu(8).is_err());
+        assert_eq!(cpu_topo.nrcpus, 3);
+
+        let hotpluggable_cpus = cpu_topo.get_hotpluggable_cpus_for_qmp();
+        assert_eq!(hotpluggable_cpus.len(), 8);
+        let present = hotpluggable_cpus
+            .iter()
+            .filter(|cpu| cpu.qom_path.is_some())
+            .count();
+        assert_eq!(present, 3);
+        let cpu = &hotpluggable_cpus[6];
+        assert_eq!(cpu.type_, CPU_DRIVER);
+        assert_eq!(cpu.vcpus_count, 1);
+        assert_eq!(cpu.props.socket_id, Some(1));
+        assert_eq!(cpu.props.core_id, Some(1));
+        assert_eq!(cpu.props.thread_id, Some(0));
+        assert_eq!(
+            cpu.qom_path.as_deref(),
+            Some("/machine/unattached/device[6]")
+        );
+        assert!(hotpluggable_cpus[7].qom_path.is_none());
+    }
+
+    /// CPU layer which records the operations of the QMP commands.
+    #[derive(Default)]
+    struct MockCpu {
+        nmi_count: Mutex<u32>,
+        resume_count: Mutex<u32>,
+    }
+
+    impl CPUInterface for MockCpu {
+        fn realize(
+            &self,
+            _boot: &CPUBootConfig,
+            _topology: &CPUTopology,
+            #[cfg(target_arch = "aarch64")] _features: &CPUFeatures,
+        ) -> Result<()> {
+            Ok(())
+        }
+
+        fn start(_cpu: Arc<Self>, _thread_barrier: Arc<Barrier>, _paused: bool) -> Result<()> {
+            Ok(())
+        }
+
+        fn kick(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn pause(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn resume(&self) -> Result<()> {
+            *self.resume_count.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn destroy(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn reset(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn guest_shutdown(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn guest_reset(&self) -> Result<()> {
+            Ok(())
+        }
+
+        fn inject_nmi(&self) -> Result<()> {
+            *self.nmi_count.lock().unwrap() += 1;
+            Ok(())
+        }
+
+        fn kvm_vcpu_exec(&self) -> Result<bool> {
+            Ok(true)
+        }
+    }
+
+    #[test]
+    fn test_cpus_inject_nmi() {
+        let cpus: Vec<Arc<MockCpu>> = (0..2).map(|_| Arc::new(MockCpu::default())).collect();
+
+        // NMI is injected to all vcpus of the running or paused VM.
+        assert!(cpus_inject_nmi(&cpus, KvmVmState::Running).is_ok());
+        assert!(cpus_inject_nmi(&cpus, KvmVmState::Paused).is_ok());
+        for cpu in cpus.iter() {
+            assert_eq!(*cpu.nmi_count.lock().unwrap(), 2);
+        }
+
+        for vm_state in [
+            KvmVmState::Created,
+            KvmVmState::InMigrating,
+            KvmVmState::Migrated,
+            KvmVmState::Shutdown,
+            KvmVmState::Suspended,
+            KvmVmState::Destroyed,
+        ] {
+            assert!(cpus_inject_nmi(&cpus, vm_state).is_err());
+        }
+        for cpu in cpus.iter() {
+            assert_eq!(*cpu.nmi_count.lock().unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn test_cpus_wakeup() {
+        let cpus: Vec<Arc<MockCpu>> = (0..2).map(|_| Arc::new(MockCpu::default())).collect();
+
+        // Only the suspended VM can be woken up.
+        for vm_state in [
+            KvmVmState::Created,
+            KvmVmState::Running,
+            KvmVmState::InMigrating,
+            KvmVmState::Migrated,
+            KvmVmState::Paused,
+            KvmVmState::Shutdown,
+            KvmVmState::Destroyed,
+        ] {
+            let mut state = vm_state;
+            let err = cpus_wakeup(&cpus, &mut state).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "Unable to wake up: guest is not in suspended state"
+            );
+            assert_eq!(state, vm_state);
+        }
+        for cpu in cpus.iter() {
+            assert_eq!(*cpu.resume_count.lock().unwrap(), 0);
+        }
+
+        let mut state = KvmVmState::Suspended;
+        assert!(cpus_wakeup(&cpus, &mut state).is_ok());
+        assert_eq!(state, KvmVmState::Running);
+        for cpu in cpus.iter() {
+            assert_eq!(*cpu.resume_count.lock().unwrap(), 1);
+        }
+    }
 }
@@ -0,0 +1,174 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Realtime-safe vCPU run loop support.
+//!
+//! In realtime mode, a vCPU dispatches PIO/MMIO exits through a `MachineAddressInterface`
+//! which does not need the machine lock, and does no heap allocation while handling
+//! the common exits. Everything else (logging, statistics) is pushed to a preallocated
+//! ring and handled by the main loop.
+
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Result;
+use log::{info, warn};
+use machine_manager::machine::MachineAddressInterface;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::rt::{LatencyHistogram, RtRing};
+use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
+
+/// Capacity of the event ring of each vCPU.
+const VCPU_RT_EVENT_RING_SIZE: usize = 256;
+
+/// Kind of the guest access of an exit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcpuRtAccess {
+    #[cfg(target_arch = "x86_64")]
+    PioIn,
+    #[cfg(target_arch = "x86_64")]
+    PioOut,
+    MmioRead,
+    MmioWrite,
+}
+
+/// Rare-path work reported by a vCPU running in realtime mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VcpuRtEvent {
+    /// No device accepted the guest access.
+    AccessFailed {
+        access: VcpuRtAccess,
+        addr: u64,
+        len: u8,
+    },
+}
+
+/// Per-vCPU state of the realtime run loop.
+pub struct VcpuRealtime {
+    /// Address dispatcher which must not take the machine lock.
+    io: Arc<dyn MachineAddressInterface + Send + Sync>,
+    /// Events waiting to be handled by the main loop.
+    events: RtRing<VcpuRtEvent>,
+    /// Time spent handling exits in userspace.
+    exit_latency: LatencyHistogram,
+    /// Kicks the drainer after pushing events, shared by all vCPUs.
+    notifier: Arc<EventFd>,
+}
+
+impl VcpuRealtime {
+    pub fn new(io: Arc<dyn MachineAddressInterface + Send + Sync>, notifier: Arc<EventFd>) -> Self {
+        VcpuRealtime {
+            io,
+            events: RtRing::new(VCPU_RT_EVENT_RING_SIZE),
+            exit_latency: LatencyHistogram::new(),
+            notifier,
+        }
+    }
+
+    pub fn io(&self) -> &(dyn MachineAddressInterface + Send + Sync) {
+        self.io.as_ref()
+    }
+
+    pub fn exit_latency(&self) -> &LatencyHistogram {
+        &self.exit_latency
+    }
+
+    /// Report an event from the vCPU thread, never allocates.
+    pub fn report(&self, event: VcpuRtEvent) {
+        self.events.push(event);
+        // Ignore the error: the event stays in the ring until the next kick.
+        let _ = self.notifier.write(1);
+    }
+
+    /// Handle the pending events of vCPU `id`, called off the vCPU thread.
+    pub fn drain(&self, id: u16) {
+        while let Some(event) = self.events.pop() {
+            match event {
+                VcpuRtEvent::AccessFailed { access, addr, len } => {
+                    warn!(
+                        "Vcpu{} failed to handle {:?} exit, addr 0x{:x}, len {}",
+                        id, access, addr, len
+                    );
+                }
+            }
+        }
+        let dropped = self.events.take_dropped();
+        if dropped != 0 {
+            warn!("Vcpu{} dropped {} realtime events", id, dropped);
+        }
+    }
+
+    /// Log the summary of exit handling latency of vCPU `id`.
+    pub fn flush_stats(&self, id: u16) {
+        let lat = &self.exit_latency;
+        info!(
+            "Vcpu{} handled {} exits in realtime mode, latency(ns): p50 {}, p99 {}, p99.9 {}, max {}",
+            id,
+            lat.count(),
+            lat.percentile(500),
+            lat.percentile(990),
+            lat.percentile(999),
+            lat.percentile(1000)
+        );
+    }
+}
+
+/// Handles the events of all realtime vCPUs in the main loop.
+pub struct VcpuRtDrainer {
+    notifier: Arc<EventFd>,
+    vcpus: Vec<(u16, Arc<VcpuRealtime>)>,
+}
+
+impl VcpuRtDrainer {
+    pub fn new() -> Result<Self> {
+        Ok(VcpuRtDrainer {
+            notifier: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+            vcpus: Vec::new(),
+        })
+    }
+
+    /// Eventfd which the vCPUs write to after reporting an event.
+    pub fn notifier(&self) -> Arc<EventFd> {
+        self.notifier.clone()
+    }
+
+    pub fn add_vcpu(&mut self, id: u16, rt: Arc<VcpuRealtime>) {
+        self.vcpus.push((id, rt));
+    }
+
+    fn drain(&self) {
+        for (id, rt) in self.vcpus.iter() {
+            rt.drain(*id);
+        }
+    }
+}
+
+impl EventNotifierHelper for VcpuRtDrainer {
+    fn internal_notifiers(drainer: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let drainer_clone = drainer.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            drainer_clone.lock().unwrap().drain();
+            None
+        });
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            drainer.lock().unwrap().notifier.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        )]
+    }
+}
@@ -12,6 +12,13 @@
 
 use core::arch::x86_64::__cpuid_count;
 
+use anyhow::{bail, Context, Result};
+use kvm_bindings::{kvm_cpuid_entry2, CpuId};
+use log::warn;
+use machine_manager::config::{
+    find_cpu_feature, CpuFeature, CpuFeaturesConfig, CpuidReg, X86_CPU_FEATURES,
+};
+
 pub fn host_cpuid(
     leaf: u32,
     subleaf: u32,
@@ -29,3 +36,268 @@ pub fn host_cpuid(
         *edx = cpuid.edx;
     }
 }
+
+fn cpuid_reg(entry: &mut kvm_cpuid_entry2, reg: CpuidReg) -> &mut u32 {
+    match reg {
+        CpuidReg::Eax => &mut entry.eax,
+        CpuidReg::Ebx => &mut entry.ebx,
+        CpuidReg::Ecx => &mut entry.ecx,
+        CpuidReg::Edx => &mut entry.edx,
+    }
+}
+
+fn is_cpuid_entry_of(entry: &kvm_cpuid_entry2, feature: &CpuFeature) -> bool {
+    entry.function == feature.leaf && entry.index == feature.index
+}
+
+/// Set or clear the bits of the feature flags given by `-cpu` in CPUID entries.
+///
+/// # Arguments
+///
+/// * `entries` - CPUID entries of the vCPU.
+/// * `features` - Feature flags overriding the host CPU model.
+pub fn apply_cpu_features(
+    entries: &mut [kvm_cpuid_entry2],
+    features: &CpuFeaturesConfig,
+) -> Result<()> {
+    for (name, enabled) in features.features.iter() {
+        let feature =
+            find_cpu_feature(name).with_context(|| format!("Unknown CPU feature \'{}\'", name))?;
+        let entry = match entries
+            .iter_mut()
+            .find(|entry| is_cpuid_entry_of(entry, feature))
+        {
+            Some(entry) => entry,
+            None if !enabled => continue,
+            None => bail!(
+                "CPU feature \'{}\' is not supported, CPUID leaf {:#x} is missing",
+                name,
+                feature.leaf
+            ),
+        };
+
+        let value = cpuid_reg(entry, feature.reg);
+        if *enabled {
+            if *value & (1 << feature.bit) == 0 {
+                warn!("CPU feature \'{}\' is not supported by KVM", name);
+            }
+            *value |= 1 << feature.bit;
+        } else {
+            *value &= !(1 << feature.bit);
+        }
+    }
+    Ok(())
+}
+
+/// Get all the feature flags of `X86_CPU_FEATURES` and whether they are set in CPUID entries.
+pub fn cpu_feature_flags(entries: &[kvm_cpuid_entry2]) -> Vec<(&'static str, bool)> {
+    X86_CPU_FEATURES
+        .iter()
+        .map(|feature| {
+            let enabled = match entries
+                .iter()
+                .find(|entry| is_cpuid_entry_of(entry, feature))
+            {
+                Some(entry) => {
+                    let mut entry = *entry;
+                    *cpuid_reg(&mut entry, feature.reg) & (1 << feature.bit) != 0
+                }
+                None => false,
+            };
+            (feature.name, enabled)
+        })
+        .collect()
+}
+
+/// Build CPUID leaf 0x15 and 0x16 reporting a TSC frequency of `tsc_khz`.
+///
+/// Leaf 0x15 gives TSC = crystal clock (ECX, in Hz) * EBX / EAX. The crystal clock is
+/// the TSC itself when it fits in 32 bits, otherwise it's divided by the smallest
+/// ratio that makes it fit. Leaf 0x16 gives the base and maximum frequency in MHz.
+pub fn tsc_freq_cpuid_entries(tsc_khz: u32) -> [kvm_cpuid_entry2; 2] {
+    let tsc_hz = u64::from(tsc_khz) * 1000;
+    let ratio = tsc_hz / (u64::from(u32::MAX) + 1) + 1;
+    let tsc_mhz = (tsc_khz / 1000) & 0xffff;
+
+    [
+        kvm_cpuid_entry2 {
+            function: 0x15,
+            eax: 1,
+            ebx: ratio as u32,
+            ecx: (tsc_hz / ratio) as u32,
+            ..Default::default()
+        },
+        kvm_cpuid_entry2 {
+            function: 0x16,
+            eax: tsc_mhz,
+            ebx: tsc_mhz,
+            ..Default::default()
+        },
+    ]
+}
+
+/// Report the TSC frequency in CPUID leaf 0x15 and 0x16, replacing the values of the host.
+///
+/// # Arguments
+///
+/// * `cpuid` - CPUID of the vCPU.
+/// * `tsc_khz` - TSC frequency of the vCPU in kHz.
+pub fn set_tsc_freq_cpuid(cpuid: &mut CpuId, tsc_khz: u32) -> Result<()> {
+    for entry in cpuid.as_mut_slice().iter_mut() {
+        if entry.function == 0 && entry.eax < 0x16 {
+            entry.eax = 0x16;
+        }
+    }
+    for new_entry in tsc_freq_cpuid_entries(tsc_khz) {
+        match cpuid
+            .as_mut_slice()
+            .iter_mut()
+            .find(|entry| entry.function == new_entry.function)
+        {
+            Some(entry) => *entry = new_entry,
+            None => cpuid.push(new_entry)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpuid_entry(function: u32, index: u32, regs: [u32; 4]) -> kvm_cpuid_entry2 {
+        kvm_cpuid_entry2 {
+            function,
+            index,
+            eax: regs[0],
+            ebx: regs[1],
+            ecx: regs[2],
+            edx: regs[3],
+            ..Default::default()
+        }
+    }
+
+    /// Flags to apply, and the expected (leaf, index, [eax, ebx, ecx, edx]) afterwards.
+    type FeaturesCase = (&'static [&'static str], (u32, u32, [u32; 4]));
+
+    #[test]
+    fn test_apply_cpu_features() {
+        let cases: &[FeaturesCase] = &[
+            (&[], (0x1, 0, [0, 0, 1 << 21, 0])),
+            (&["-x2apic"], (0x1, 0, [0, 0, 0, 0])),
+            (&["+vmx"], (0x1, 0, [0, 0, 1 << 21 | 1 << 5, 0])),
+            (&["+vmx", "-vmx"], (0x1, 0, [0, 0, 1 << 21, 0])),
+            (&["-ht", "+sse2"], (0x1, 0, [0, 0, 1 << 21, 1 << 26])),
+            (&["+avx2", "-smep"], (0x7, 0, [0, 1 << 5, 0, 0])),
+            (&["+ssbd"], (0x7, 0, [0, 1 << 7, 0, 1 << 31])),
+            (&["+xsaves", "-xsaveopt"], (0xd, 1, [1 << 3, 0, 0, 0])),
+            (&["+invtsc"], (0x8000_0007, 0, [0, 0, 0, 1 << 8])),
+            (&["-lm"], (0x8000_0001, 0, [0, 0, 0, 0])),
+        ];
+
+        for (flags, (leaf, index, regs)) in cases.iter() {
+            let mut entries = vec![
+                cpuid_entry(0x1, 0, [0, 0, 1 << 21, 0]),
+                cpuid_entry(0x7, 0, [0, 1 << 7, 0, 0]),
+                cpuid_entry(0xd, 0, [0, 0, 0, 0]),
+                cpuid_entry(0xd, 1, [1, 0, 0, 0]),
+                cpuid_entry(0x8000_0001, 0, [0, 0, 0, 1 << 29]),
+                cpuid_entry(0x8000_0007, 0, [0, 0, 0, 0]),
+            ];
+            let mut features = CpuFeaturesConfig::default();
+            for flag in flags.iter() {
+                features.add_flag(flag).unwrap();
+            }
+            apply_cpu_features(&mut entries, &features).unwrap();
+
+            let entry = entries
+                .iter()
+                .find(|entry| entry.function == *leaf && entry.index == *index)
+                .unwrap();
+            assert_eq!(
+                [entry.eax, entry.ebx, entry.ecx, entry.edx],
+                *regs,
+                "flags: {:?}",
+                flags
+            );
+        }
+    }
+
+    #[test]
+    fn test_apply_cpu_features_missing_leaf() {
+        let mut entries = vec![cpuid_entry(0x1, 0, [0, 0, 0, 0])];
+        let mut features = CpuFeaturesConfig::default();
+        features.add_flag("-invtsc").unwrap();
+        assert!(apply_cpu_features(&mut entries, &features).is_ok());
+        features.add_flag("+invtsc").unwrap();
+        assert!(apply_cpu_features(&mut entries, &features).is_err());
+    }
+
+    #[test]
+    fn test_tsc_freq_cpuid_entries() {
+        // (TSC kHz, expected (eax, ebx, ecx) of leaf 0x15, expected eax of leaf 0x16)
+        let cases: &[(u32, [u32; 3], u32)] = &[
+            (1_000, [1, 1, 1_000_000], 1),
+            (2_000_000, [1, 1, 2_000_000_000], 2_000),
+            (2_903_998, [1, 1, 2_903_998_000], 2_903),
+            (4_294_967, [1, 1, 4_294_967_000], 4_294),
+            (4_294_968, [1, 2, 2_147_484_000], 4_294),
+            (4_800_000, [1, 2, 2_400_000_000], 4_800),
+            (9_000_000, [1, 3, 3_000_000_000], 9_000),
+        ];
+
+        for (tsc_khz, leaf_15, leaf_16) in cases.iter() {
+            let [entry_15, entry_16] = tsc_freq_cpuid_entries(*tsc_khz);
+            assert_eq!(entry_15.function, 0x15);
+            assert_eq!([entry_15.eax, entry_15.ebx, entry_15.ecx], *leaf_15);
+            assert_eq!(entry_16.function, 0x16);
+            assert_eq!([entry_16.eax, entry_16.ebx], [*leaf_16, *leaf_16]);
+
+            // The frequency calculated by the guest is the one reported.
+            let guest_hz =
+                u64::from(entry_15.ecx) * u64::from(entry_15.ebx) / u64::from(entry_15.eax);
+            assert!(u64::from(*tsc_khz) * 1000 - guest_hz < u64::from(entry_15.ebx));
+        }
+    }
+
+    #[test]
+    fn test_set_tsc_freq_cpuid() {
+        let mut cpuid = CpuId::from_entries(&[
+            cpuid_entry(0x0, 0, [0xd, 0, 0, 0]),
+            cpuid_entry(0x1, 0, [0, 0, 0, 0]),
+            cpuid_entry(0x15, 0, [2, 176, 0, 0]),
+        ])
+        .unwrap();
+        set_tsc_freq_cpuid(&mut cpuid, 2_000_000).unwrap();
+
+        let entries = cpuid.as_slice();
+        assert_eq!(entries.len(), 4);
+        assert_eq!(entries[0].eax, 0x16);
+        assert_eq!(
+            [entries[2].eax, entries[2].ebx, entries[2].ecx],
+            [1, 1, 2_000_000_000]
+        );
+        assert_eq!((entries[3].function, entries[3].eax), (0x16, 2_000));
+
+        // The max basic leaf is never lowered.
+        let mut cpuid = CpuId::from_entries(&[cpuid_entry(0x0, 0, [0x1f, 0, 0, 0])]).unwrap();
+        set_tsc_freq_cpuid(&mut cpuid, 2_000_000).unwrap();
+        assert_eq!(cpuid.as_slice()[0].eax, 0x1f);
+    }
+
+    #[test]
+    fn test_cpu_feature_flags() {
+        let entries = vec![
+            cpuid_entry(0x1, 0, [0, 0, 1 << 5, 1 << 4]),
+            cpuid_entry(0x7, 0, [0, 0, 0, 0]),
+        ];
+        let flags = cpu_feature_flags(&entries);
+        assert_eq!(flags.len(), X86_CPU_FEATURES.len());
+        let enabled: Vec<&str> = flags
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect();
+        assert_eq!(enabled, vec!["vmx", "tsc"]);
+    }
+}
@@ -23,13 +23,15 @@ use kvm_bindings::{
     KVM_MP_STATE_UNINITIALIZED,
 };
 use kvm_ioctls::{Kvm, VcpuFd};
+use log::warn;
+use machine_manager::config::CpuFeaturesConfig;
 use migration::{
     DeviceStateDesc, FieldDesc, MigrationError, MigrationHook, MigrationManager, StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
 use util::byte_code::ByteCode;
 
-use self::cpuid::host_cpuid;
+use self::cpuid::{apply_cpu_features, cpu_feature_flags, host_cpuid, set_tsc_freq_cpuid};
 use crate::CPU;
 
 const ECX_EPB_SHIFT: u32 = 3;
@@ -79,14 +81,17 @@ pub struct X86CPUBootConfig {
     pub idt_base: u64,
     pub idt_size: u16,
     pub pml4_start: u64,
+    /// Address of `hvm_start_info`, the vCPU starts in 32-bit protection mode
+    /// from the PVH entry with %rbx pointing to it.
+    pub pvh_start_info: Option<u64>,
 }
 
 #[allow(clippy::upper_case_acronyms)]
 #[derive(Default, Copy, Clone, Debug)]
 pub struct X86CPUTopology {
-    threads: u8,
-    cores: u8,
-    dies: u8,
+    threads: u16,
+    cores: u16,
+    dies: u16,
 }
 
 impl X86CPUTopology {
@@ -94,7 +99,7 @@ impl X86CPUTopology {
         X86CPUTopology::default()
     }
 
-    pub fn set_topology(mut self, toplogy: (u8, u8, u8)) -> Self {
+    pub fn set_topology(mut self, toplogy: (u16, u16, u16)) -> Self {
         self.threads = toplogy.0;
         self.cores = toplogy.1;
         self.dies = toplogy.2;
@@ -125,6 +130,8 @@ pub struct X86CPUState {
     xsave: kvm_xsave,
     xcrs: kvm_xcrs,
     debugregs: kvm_debugregs,
+    /// TSC frequency in kHz, 0 if KVM can't report it.
+    tsc_khz: u32,
 }
 
 impl X86CPUState {
@@ -169,6 +176,7 @@ impl X86CPUState {
         self.xsave = locked_cpu_state.xsave;
         self.xcrs = locked_cpu_state.xcrs;
         self.debugregs = locked_cpu_state.debugregs;
+        self.tsc_khz = locked_cpu_state.tsc_khz;
     }
 
     /// Set register value in `X86CPUState` according to `boot_config`.
@@ -187,6 +195,16 @@ impl X86CPUState {
         self.setup_sregs(vcpu_fd, boot_config)?;
         self.setup_fpu();
         self.setup_msrs();
+        self.tsc_khz = match vcpu_fd.get_tsc_khz() {
+            Ok(tsc_khz) => tsc_khz,
+            Err(e) => {
+                warn!(
+                    "Failed to get TSC frequency for CPU {}: {:?}",
+                    self.apic_id, e
+                );
+                0
+            }
+        };
 
         Ok(())
     }
@@ -209,8 +227,17 @@ impl X86CPUState {
     ///
     /// * `vcpu_fd` - Vcpu file descriptor in kvm.
     /// * `caps` - Vcpu capabilities in kvm.
-    pub fn reset_vcpu(&self, vcpu_fd: &Arc<VcpuFd>, caps: &caps::X86CPUCaps) -> Result<()> {
-        self.setup_cpuid(vcpu_fd)
+    /// * `features` - Feature flags overriding the host CPU model.
+    pub fn reset_vcpu(
+        &self,
+        vcpu_fd: &Arc<VcpuFd>,
+        caps: &caps::X86CPUCaps,
+        features: &CpuFeaturesConfig,
+    ) -> Result<()> {
+        if features.features.get("invtsc") == Some(&true) {
+            self.setup_tsc_khz(vcpu_fd, caps)?;
+        }
+        self.setup_cpuid(vcpu_fd, features)
             .with_context(|| format!("Failed to set cpuid for CPU {}", self.apic_id))?;
 
         vcpu_fd
@@ -252,6 +279,32 @@ impl X86CPUState {
         Ok(())
     }
 
+    /// Keep the TSC frequency of the vCPU the same as the recorded one, which differs from
+    /// the one of this host after migration. The guest relies on an invariant TSC with invtsc,
+    /// so it's refused if KVM can't scale the TSC.
+    fn setup_tsc_khz(&self, vcpu_fd: &Arc<VcpuFd>, caps: &caps::X86CPUCaps) -> Result<()> {
+        if self.tsc_khz == 0 {
+            return Ok(());
+        }
+        let host_tsc_khz = vcpu_fd
+            .get_tsc_khz()
+            .with_context(|| format!("Failed to get TSC frequency for CPU {}", self.apic_id))?;
+        if host_tsc_khz == self.tsc_khz {
+            return Ok(());
+        }
+        if !caps.has_tsc_control {
+            bail!(
+                "TSC frequency of CPU {} is {} kHz instead of {} kHz, TSC scaling is not supported",
+                self.apic_id,
+                host_tsc_khz,
+                self.tsc_khz
+            );
+        }
+        vcpu_fd
+            .set_tsc_khz(self.tsc_khz)
+            .with_context(|| format!("Failed to set TSC frequency for CPU {}", self.apic_id))
+    }
+
     fn setup_lapic(&mut self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
         // Disable nmi and external interrupt before enter protected mode
         // See: https://elixir.bootlin.com/linux/v4.19.123/source/arch/x86/include/asm/apicdef.h
@@ -291,6 +344,7 @@ impl X86CPUState {
             rsp: boot_config.boot_sp,
             rbp: boot_config.boot_sp,
             rsi: boot_config.zero_page,
+            rbx: boot_config.pvh_start_info.unwrap_or_default(),
             ..Default::default()
         };
     }
@@ -315,11 +369,35 @@ impl X86CPUState {
 
         if boot_config.prot64_mode {
             self.set_prot64_sregs(boot_config);
+        } else if boot_config.pvh_start_info.is_some() {
+            self.set_prot32_sregs(boot_config);
         }
 
         Ok(())
     }
 
+    fn set_prot32_sregs(&mut self, boot_config: &X86CPUBootConfig) {
+        // X86_CR0_PE: Protection Enable
+        // arch/x86/include/uapi/asm/processor-flags.h
+        const X86_CR0_PE: u64 = 0x1;
+
+        // Flat segments, gdt table has loaded to Guest Memory Space
+        self.sregs.cs = boot_config.code_segment;
+        self.sregs.ds = boot_config.data_segment;
+        self.sregs.es = boot_config.data_segment;
+        self.sregs.fs = boot_config.data_segment;
+        self.sregs.gs = boot_config.data_segment;
+        self.sregs.ss = boot_config.data_segment;
+
+        self.sregs.gdt.base = boot_config.gdt_base;
+        self.sregs.gdt.limit = boot_config.gdt_size;
+        self.sregs.idt.base = boot_config.idt_base;
+        self.sregs.idt.limit = boot_config.idt_size;
+
+        // Open 32-bit protected mode with paging disabled, as PVH boot ABI requires.
+        self.sregs.cr0 |= X86_CR0_PE;
+    }
+
     fn set_prot64_sregs(&mut self, boot_config: &X86CPUBootConfig) {
         // X86_CR0_PE: Protection Enable
         // EFER_LME: Long mode enable
@@ -420,10 +498,24 @@ impl X86CPUState {
         Ok(())
     }
 
-    fn setup_cpuid(&self, vcpu_fd: &Arc<VcpuFd>) -> Result<()> {
+    /// Get the offsets of the core, die and package fields in the APIC ID.
+    fn apic_id_offsets(&self) -> (u32, u32, u32) {
         let core_offset = 32u32 - (self.nr_threads - 1).leading_zeros();
         let die_offset = (32u32 - (self.nr_cores - 1).leading_zeros()) + core_offset;
         let pkg_offset = (32u32 - (self.nr_dies - 1).leading_zeros()) + die_offset;
+        (core_offset, die_offset, pkg_offset)
+    }
+
+    /// Get bits 31:26 of EAX of CPUID leaf 4, which is the maximum number of addressable
+    /// core IDs in a package minus one. The field has 6 bits, so it's capped at 63.
+    fn cache_cores_per_pkg(&self) -> u32 {
+        let (core_offset, _, pkg_offset) = self.apic_id_offsets();
+        let core_bits = (pkg_offset - core_offset).min(6);
+        ((1u32 << core_bits) - 1) << 26
+    }
+
+    fn setup_cpuid(&self, vcpu_fd: &Arc<VcpuFd>, features: &CpuFeaturesConfig) -> Result<()> {
+        let (core_offset, die_offset, pkg_offset) = self.apic_id_offsets();
         let sys_fd = match Kvm::new() {
             Ok(fd) => fd,
             _ => bail!("setup_cpuid: Open /dev/kvm failed"),
@@ -434,6 +526,9 @@ impl X86CPUState {
                 format!("Failed to get supported cpuid for CPU {}/KVM", self.apic_id)
             })?;
         self.adjust_cpuid(&mut cpuid)?;
+        if features.features.get("invtsc") == Some(&true) && self.tsc_khz != 0 {
+            set_tsc_freq_cpuid(&mut cpuid, self.tsc_khz)?;
+        }
         let entries = cpuid.as_mut_slice();
 
         for entry in entries.iter_mut() {
@@ -442,7 +537,9 @@ impl X86CPUState {
                     if entry.index == 0 {
                         entry.ecx |= 1u32 << X86_FEATURE_HYPERVISOR;
                         entry.ecx |= 1u32 << X86_FEATURE_TSC_DEADLINE_TIMER;
-                        entry.ebx = self.apic_id << 24 | 8 << 8;
+                        // Only the low 8 bits of the APIC ID fit in the initial APIC ID
+                        // field, the full x2APIC ID is reported by leaf 0xb.
+                        entry.ebx = (self.apic_id & 0xff) << 24 | 8 << 8;
                     }
                 }
                 2 => {
@@ -467,8 +564,8 @@ impl X86CPUState {
                         &mut entry.edx,
                     );
                     entry.eax &= !0xfc00_0000;
-                    if entry.eax & 0x0001_ffff != 0 && self.nr_vcpus > 1 {
-                        entry.eax |= (self.nr_vcpus - 1) << 26;
+                    if entry.eax & 0x0001_ffff != 0 {
+                        entry.eax |= self.cache_cores_per_pkg();
                     }
                 }
                 6 => {
@@ -555,6 +652,8 @@ impl X86CPUState {
                 _ => (),
             }
         }
+        apply_cpu_features(entries, features)
+            .with_context(|| format!("Failed to set CPU features for CPU {}", self.apic_id))?;
 
         vcpu_fd
             .set_cpuid2(&cpuid)
@@ -563,6 +662,66 @@ impl X86CPUState {
     }
 }
 
+impl CPU {
+    /// Get the TSC frequency of the vCPU in kHz, 0 if it's unknown.
+    pub fn tsc_khz(&self) -> u32 {
+        self.arch_cpu.lock().unwrap().tsc_khz
+    }
+
+    /// Get the feature flags of `-cpu` and whether they are enabled in the CPUID of the vCPU.
+    pub fn cpu_feature_flags(&self) -> Result<Vec<(&'static str, bool)>> {
+        let cpuid = self
+            .fd
+            .get_cpuid2(KVM_MAX_CPUID_ENTRIES)
+            .with_context(|| format!("Failed to get cpuid for CPU {}", self.id))?;
+        Ok(cpu_feature_flags(cpuid.as_slice()))
+    }
+
+    /// Get the general purpose registers in the layout of `struct user_regs_struct`,
+    /// which is the `pr_reg` of NT_PRSTATUS note in ELF core files.
+    pub fn elf_prstatus_regs(&self) -> Result<Vec<u64>> {
+        let regs = self
+            .fd
+            .get_regs()
+            .with_context(|| format!("Failed to get regs for CPU {}", self.id))?;
+        let sregs = self
+            .fd
+            .get_sregs()
+            .with_context(|| format!("Failed to get sregs for CPU {}", self.id))?;
+
+        Ok(vec![
+            regs.r15,
+            regs.r14,
+            regs.r13,
+            regs.r12,
+            regs.rbp,
+            regs.rbx,
+            regs.r11,
+            regs.r10,
+            regs.r9,
+            regs.r8,
+            regs.rax,
+            regs.rcx,
+            regs.rdx,
+            regs.rsi,
+            regs.rdi,
+            // orig_rax
+            regs.rax,
+            regs.rip,
+            u64::from(sregs.cs.selector),
+            regs.rflags,
+            regs.rsp,
+            u64::from(sregs.ss.selector),
+            sregs.fs.base,
+            sregs.gs.base,
+            u64::from(sregs.ds.selector),
+            u64::from(sregs.es.selector),
+            u64::from(sregs.fs.selector),
+            u64::from(sregs.gs.selector),
+        ])
+    }
+}
+
 impl StateTransfer for CPU {
     fn get_state_vec(&self) -> Result<Vec<u8>> {
         let mut msr_entries = self.caps.create_msr_entries()?;
@@ -619,6 +778,26 @@ mod test {
     use serial_test::serial;
     use std::sync::Arc;
 
+    #[test]
+    fn test_cpuid_cache_cores_per_pkg() {
+        // (threads, cores, dies, expected value of bits 31:26).
+        for (threads, cores, dies, cores_per_pkg) in [
+            (1_u32, 1_u32, 1_u32, 0_u32),
+            (2, 1, 1, 0),
+            (1, 4, 1, 3),
+            (2, 6, 2, 15),
+            (1, 64, 1, 63),
+            (2, 128, 4, 63),
+            (1, 1024, 1, 63),
+        ] {
+            let mut x86_cpu = X86CPUState::new(0, threads * cores * dies);
+            x86_cpu.nr_threads = threads;
+            x86_cpu.nr_cores = cores;
+            x86_cpu.nr_dies = dies;
+            assert_eq!(x86_cpu.cache_cores_per_pkg() >> 26, cores_per_pkg);
+        }
+    }
+
     #[test]
     #[serial]
     fn test_x86_64_cpu() {
@@ -671,6 +850,7 @@ mod test {
             idt_base: 0x520u64,
             idt_size: 8,
             pml4_start: 0x0000_9000,
+            pvh_start_info: None,
         };
 
         // For `get_lapic` in realize function to work,
@@ -685,7 +865,9 @@ mod test {
 
         // test setup special registers
         let cpu_caps = caps::X86CPUCaps::init_capabilities();
-        assert!(x86_cpu.reset_vcpu(&vcpu, &cpu_caps).is_ok());
+        assert!(x86_cpu
+            .reset_vcpu(&vcpu, &cpu_caps, &CpuFeaturesConfig::default())
+            .is_ok());
         let x86_sregs = vcpu.get_sregs().unwrap();
         assert_eq!(x86_sregs.cs, code_seg);
         assert_eq!(x86_sregs.ds, data_seg);
@@ -21,7 +21,7 @@ use anyhow::{anyhow, Context, Result};
 
 use hypervisor::kvm::KVM_FDS;
 use kvm_ioctls::DeviceFd;
-use log::error;
+use log::{error, warn};
 use machine_manager::machine::{KvmVmState, MachineLifecycle};
 use migration::{
     snapshot::{GICV3_ITS_SNAPSHOT_ID, GICV3_SNAPSHOT_ID},
@@ -41,7 +41,7 @@ pub struct GICv3Config {
     pub dist_range: (u64, u64),
     /// GIC redistributor address range, support multiple redistributor regions.
     pub redist_region_ranges: Vec<(u64, u64)>,
-    /// GIC ITS address ranges.
+    /// GIC ITS address ranges, no ITS is created if it's None.
     pub its_range: Option<(u64, u64)>,
 }
 
@@ -163,9 +163,14 @@ impl GICv3 {
         };
 
         if let Some(its_range) = v3config.its_range {
-            gicv3.its_dev = Some(Arc::new(
-                GICv3Its::new(&its_range).with_context(|| "Failed to create ITS")?,
-            ));
+            // PCI devices fall back to INTx if the host doesn't support ITS.
+            match GICv3Its::new(&its_range) {
+                Ok(its) => gicv3.its_dev = Some(Arc::new(its)),
+                Err(e) => warn!(
+                    "Failed to create GICv3 ITS, MSI is not available to the guest: {:?}",
+                    e
+                ),
+            }
         }
 
         Ok(gicv3)
@@ -448,6 +453,10 @@ impl GICDevice for GICv3 {
     fn get_redist_count(&self) -> u8 {
         self.redist_regions.len() as u8
     }
+
+    fn has_its(&self) -> bool {
+        self.its_dev.is_some()
+    }
 }
 
 pub struct GICv3Its {
@@ -602,6 +611,7 @@ mod tests {
         };
         let gic = GICv3::new(&gic_config).unwrap();
         assert!(gic.its_dev.is_none());
+        assert!(!gic.has_its());
         assert!(GICv3::new(&gic_config).is_err());
     }
 
@@ -19,15 +19,19 @@
 //! This module offers support for:
 //! 1. Create kvm-based interrupt controller.
 //! 2. Manager lifecycle for `GIC`.
+//! 3. Emulate `IOAPIC` in userspace with split irqchip.
 //!
 //! ## Platform Support
 //!
 //! - `aarch64`
+//! - `x86_64`
 
 #[allow(clippy::upper_case_acronyms)]
 #[cfg(target_arch = "aarch64")]
 mod aarch64;
 mod error;
+#[cfg(target_arch = "x86_64")]
+mod x86_64;
 
 #[cfg(target_arch = "aarch64")]
 pub use aarch64::GICConfig as ICGICConfig;
@@ -41,3 +45,5 @@ pub use aarch64::InterruptController;
 pub use aarch64::GIC_IRQ_MAX;
 pub use anyhow::Result;
 pub use error::InterruptError;
+#[cfg(target_arch = "x86_64")]
+pub use x86_64::{IoApic, IOAPIC_NUM_PINS};
@@ -0,0 +1,521 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use anyhow::{Context, Result};
+use hypervisor::kvm::{set_ioapic_eoi_handler, MsiVector, KVM_FDS};
+use log::error;
+
+/// Number of pins of the IOAPIC, which are connected to GSI 0 ~ 23.
+pub const IOAPIC_NUM_PINS: usize = 24;
+
+// Offsets of the MMIO registers.
+const IOAPIC_IOREGSEL: u64 = 0x00;
+const IOAPIC_IOWIN: u64 = 0x10;
+const IOAPIC_EOI: u64 = 0x40;
+
+// Indexes of the registers accessed by IOREGSEL and IOWIN.
+const IOAPIC_REG_ID: u32 = 0x00;
+const IOAPIC_REG_VERSION: u32 = 0x01;
+const IOAPIC_REG_ARB: u32 = 0x02;
+const IOAPIC_REG_REDTBL_BASE: u32 = 0x10;
+
+/// Version 0x20 has the EOI register.
+const IOAPIC_VERSION: u32 = 0x20;
+const IOAPIC_MAX_REDIR_SHIFT: u32 = 16;
+const IOAPIC_ID_SHIFT: u32 = 24;
+const IOAPIC_ID_MASK: u32 = 0xf;
+
+// Fields of the redirection table entry.
+const RTE_VECTOR_MASK: u64 = 0xff;
+const RTE_DELIVERY_MODE_SHIFT: u64 = 8;
+const RTE_DELIVERY_MODE_MASK: u64 = 0x7;
+const RTE_DEST_MODE_SHIFT: u64 = 11;
+const RTE_DELIVERY_STATUS: u64 = 1 << 12;
+const RTE_REMOTE_IRR: u64 = 1 << 14;
+const RTE_TRIGGER_LEVEL: u64 = 1 << 15;
+const RTE_MASKED: u64 = 1 << 16;
+const RTE_DEST_SHIFT: u64 = 56;
+/// Bits of the entry which are read-only for the guest.
+const RTE_RO_BITS: u64 = RTE_DELIVERY_STATUS | RTE_REMOTE_IRR;
+
+// Fields of the MSI message, see Intel SDM Vol3 10.11.
+const MSI_ADDR_BASE: u32 = 0xfee0_0000;
+const MSI_ADDR_DEST_SHIFT: u32 = 12;
+const MSI_ADDR_DEST_MODE_SHIFT: u32 = 2;
+const MSI_DATA_DELIVERY_MODE_SHIFT: u32 = 8;
+const MSI_DATA_TRIGGER_SHIFT: u32 = 15;
+
+/// Interfaces to deliver the interrupts of the IOAPIC to the local APICs as MSI messages.
+trait MsiDelivery: Send {
+    /// Set the GSI route of the pin, or remove it if `msi` is none. The irqfds of the
+    /// pin are delivered by KVM with the route, and KVM exits on EOI of the vector if
+    /// it's level-triggered.
+    fn set_route(&self, pin: u32, msi: Option<MsiVector>) -> Result<()>;
+
+    /// Send the MSI message to the local APICs.
+    fn send(&self, msi: MsiVector) -> Result<()>;
+}
+
+/// Delivery by the in-kernel local APICs.
+struct KvmMsiDelivery {}
+
+impl MsiDelivery for KvmMsiDelivery {
+    fn set_route(&self, pin: u32, msi: Option<MsiVector>) -> Result<()> {
+        let kvm_fds = KVM_FDS.load();
+        let mut locked_table = kvm_fds.irq_route_table.lock().unwrap();
+        match msi {
+            Some(msi) => locked_table.update_msi_route(pin, msi)?,
+            None => locked_table.remove_irq_route(pin),
+        }
+        drop(locked_table);
+        kvm_fds.commit_irq_routing()
+    }
+
+    fn send(&self, msi: MsiVector) -> Result<()> {
+        KVM_FDS.load().signal_msi(msi)
+    }
+}
+
+/// IOAPIC emulated in userspace with split irqchip. The pins are programmed by the
+/// redirection table, and their interrupts are delivered to the in-kernel local APICs.
+pub struct IoApic {
+    /// IOAPIC id, bits 24 ~ 27 of the ID register.
+    id: u32,
+    /// Index of the register accessed by IOWIN.
+    ioregsel: u32,
+    /// Redirection table entries of the pins.
+    redtbl: [u64; IOAPIC_NUM_PINS],
+    /// Line levels of the pins.
+    levels: u32,
+    /// Pending interrupts of the pins, which are delivered once the pins are unmasked.
+    irr: u32,
+    /// Delivery of the interrupts.
+    delivery: Box<dyn MsiDelivery>,
+}
+
+impl IoApic {
+    fn new(delivery: Box<dyn MsiDelivery>) -> Self {
+        IoApic {
+            id: 0,
+            ioregsel: 0,
+            redtbl: [RTE_MASKED; IOAPIC_NUM_PINS],
+            levels: 0,
+            irr: 0,
+            delivery,
+        }
+    }
+
+    /// Add the IOAPIC to the memory address space, and let it handle the EOI exits of vcpus.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_mem` - Memory address space of the machine.
+    /// * `region_base` - Base address of the MMIO registers.
+    /// * `region_size` - Size of the MMIO region.
+    pub fn realize(
+        sys_mem: &Arc<AddressSpace>,
+        region_base: u64,
+        region_size: u64,
+    ) -> Result<Arc<Mutex<Self>>> {
+        let ioapic = Arc::new(Mutex::new(IoApic::new(Box::new(KvmMsiDelivery {}))));
+        sys_mem
+            .root()
+            .add_subregion(
+                Region::init_io_region(region_size, Self::region_ops(&ioapic)),
+                region_base,
+            )
+            .with_context(|| "Failed to add IOAPIC region")?;
+
+        let cloned_ioapic = ioapic.clone();
+        set_ioapic_eoi_handler(Arc::new(move |vector: u8| {
+            cloned_ioapic.lock().unwrap().eoi(vector)
+        }));
+        Ok(ioapic)
+    }
+
+    fn region_ops(ioapic: &Arc<Mutex<IoApic>>) -> RegionOps {
+        let cloned_ioapic = ioapic.clone();
+        let read = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+            cloned_ioapic.lock().unwrap().read(data, offset)
+        };
+        let cloned_ioapic = ioapic.clone();
+        let write = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+            cloned_ioapic.lock().unwrap().write(data, offset)
+        };
+        RegionOps {
+            read: Arc::new(read),
+            write: Arc::new(write),
+        }
+    }
+
+    fn read(&mut self, data: &mut [u8], offset: u64) -> bool {
+        if data.len() > 4 {
+            error!("Invalid IOAPIC read size {} at 0x{:x}", data.len(), offset);
+            return false;
+        }
+        let value = match offset {
+            IOAPIC_IOREGSEL => self.ioregsel,
+            IOAPIC_IOWIN => self.read_reg(self.ioregsel),
+            _ => 0,
+        };
+        data.copy_from_slice(&value.to_le_bytes()[..data.len()]);
+        true
+    }
+
+    fn write(&mut self, data: &[u8], offset: u64) -> bool {
+        if data.len() > 4 {
+            error!("Invalid IOAPIC write size {} at 0x{:x}", data.len(), offset);
+            return false;
+        }
+        let mut bytes = [0_u8; 4];
+        bytes[..data.len()].copy_from_slice(data);
+        let value = u32::from_le_bytes(bytes);
+        match offset {
+            IOAPIC_IOREGSEL => self.ioregsel = value & 0xff,
+            IOAPIC_IOWIN => self.write_reg(self.ioregsel, value),
+            IOAPIC_EOI => self.eoi(value as u8),
+            _ => {}
+        }
+        true
+    }
+
+    /// Return the pin of the redirection table register, and whether it's the high half.
+    fn redtbl_index(reg: u32) -> Option<(usize, bool)> {
+        let index = reg.checked_sub(IOAPIC_REG_REDTBL_BASE)? as usize;
+        if index >= IOAPIC_NUM_PINS * 2 {
+            return None;
+        }
+        Some((index / 2, index % 2 == 1))
+    }
+
+    fn read_reg(&self, reg: u32) -> u32 {
+        match reg {
+            IOAPIC_REG_ID | IOAPIC_REG_ARB => self.id << IOAPIC_ID_SHIFT,
+            IOAPIC_REG_VERSION => {
+                ((IOAPIC_NUM_PINS as u32 - 1) << IOAPIC_MAX_REDIR_SHIFT) | IOAPIC_VERSION
+            }
+            _ => match Self::redtbl_index(reg) {
+                Some((pin, true)) => (self.redtbl[pin] >> 32) as u32,
+                Some((pin, false)) => self.redtbl[pin] as u32,
+                None => 0,
+            },
+        }
+    }
+
+    fn write_reg(&mut self, reg: u32, value: u32) {
+        if reg == IOAPIC_REG_ID {
+            self.id = (value >> IOAPIC_ID_SHIFT) & IOAPIC_ID_MASK;
+            return;
+        }
+        let (pin, high) = match Self::redtbl_index(reg) {
+            Some(index) => index,
+            None => return,
+        };
+
+        let old_rte = self.redtbl[pin];
+        let mut rte = if high {
+            (old_rte & 0xffff_ffff) | (u64::from(value) << 32)
+        } else {
+            (old_rte & (0xffff_ffff_0000_0000 | RTE_RO_BITS)) | (u64::from(value) & !RTE_RO_BITS)
+        };
+        // Remote IRR is only meaningful for level-triggered interrupts.
+        if rte & RTE_TRIGGER_LEVEL == 0 {
+            rte &= !RTE_REMOTE_IRR;
+        }
+        self.redtbl[pin] = rte;
+
+        if let Err(e) = self.update_route(pin) {
+            error!("Failed to update route of IOAPIC pin {}: {:?}", pin, e);
+        }
+        self.service();
+    }
+
+    fn update_route(&self, pin: usize) -> Result<()> {
+        let rte = self.redtbl[pin];
+        let msi = if rte & RTE_MASKED != 0 {
+            None
+        } else {
+            Some(Self::rte_to_msi(rte))
+        };
+        self.delivery.set_route(pin as u32, msi)
+    }
+
+    /// Compose the MSI message of the redirection table entry.
+    fn rte_to_msi(rte: u64) -> MsiVector {
+        let dest = (rte >> RTE_DEST_SHIFT) as u32;
+        let dest_mode = ((rte >> RTE_DEST_MODE_SHIFT) & 1) as u32;
+        let vector = (rte & RTE_VECTOR_MASK) as u32;
+        let delivery_mode = ((rte >> RTE_DELIVERY_MODE_SHIFT) & RTE_DELIVERY_MODE_MASK) as u32;
+        let trigger = u32::from(rte & RTE_TRIGGER_LEVEL != 0);
+
+        MsiVector {
+            msg_addr_lo: MSI_ADDR_BASE
+                | (dest << MSI_ADDR_DEST_SHIFT)
+                | (dest_mode << MSI_ADDR_DEST_MODE_SHIFT),
+            msg_addr_hi: 0,
+            msg_data: vector
+                | (delivery_mode << MSI_DATA_DELIVERY_MODE_SHIFT)
+                | (trigger << MSI_DATA_TRIGGER_SHIFT),
+            masked: false,
+        }
+    }
+
+    /// Set the line level of the pin. Edge-triggered interrupts are raised on the
+    /// rising edge, and level-triggered ones while the line is high.
+    ///
+    /// # Arguments
+    ///
+    /// * `pin` - Pin of the IOAPIC, which is the same as GSI.
+    /// * `level` - Line level of the pin.
+    pub fn set_irq(&mut self, pin: usize, level: bool) {
+        if pin >= IOAPIC_NUM_PINS {
+            error!("Invalid IOAPIC pin {}", pin);
+            return;
+        }
+        let mask = 1 << pin;
+        let old_level = self.levels & mask != 0;
+        if level {
+            self.levels |= mask;
+        } else {
+            self.levels &= !mask;
+        }
+
+        if self.redtbl[pin] & RTE_TRIGGER_LEVEL != 0 {
+            if level {
+                self.irr |= mask;
+            } else {
+                self.irr &= !mask;
+            }
+        } else if level && !old_level {
+            self.irr |= mask;
+        }
+        self.service();
+    }
+
+    /// Deliver the pending interrupts of the unmasked pins.
+    fn service(&mut self) {
+        for pin in 0..IOAPIC_NUM_PINS {
+            let mask = 1 << pin;
+            let rte = self.redtbl[pin];
+            if self.irr & mask == 0 || rte & RTE_MASKED != 0 {
+                continue;
+            }
+            if rte & RTE_TRIGGER_LEVEL != 0 {
+                // The interrupt is in service until the EOI of its vector.
+                if rte & RTE_REMOTE_IRR != 0 {
+                    continue;
+                }
+                self.redtbl[pin] |= RTE_REMOTE_IRR;
+            } else {
+                self.irr &= !mask;
+            }
+
+            if let Err(e) = self.delivery.send(Self::rte_to_msi(rte)) {
+                error!("Failed to deliver interrupt of IOAPIC pin {}: {:?}", pin, e);
+            }
+        }
+    }
+
+    /// Handle the EOI of the vector, the level-triggered interrupts of it are delivered
+    /// again if their lines are still high.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - Interrupt vector of the EOI.
+    pub fn eoi(&mut self, vector: u8) {
+        for rte in self.redtbl.iter_mut() {
+            if *rte & RTE_VECTOR_MASK == u64::from(vector) && *rte & RTE_TRIGGER_LEVEL != 0 {
+                *rte &= !RTE_REMOTE_IRR;
+            }
+        }
+        self.service();
+    }
+
+    /// Reset the IOAPIC, all the pins are masked.
+    pub fn reset(&mut self) {
+        self.id = 0;
+        self.ioregsel = 0;
+        self.irr = 0;
+        for pin in 0..IOAPIC_NUM_PINS {
+            self.redtbl[pin] = RTE_MASKED;
+            if let Err(e) = self.update_route(pin) {
+                error!("Failed to reset route of IOAPIC pin {}: {:?}", pin, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Messages sent and routes set, as (address, data) of MSI.
+    #[derive(Default)]
+    struct TestDeliveryState {
+        sent: Vec<(u32, u32)>,
+        routes: [Option<(u32, u32)>; IOAPIC_NUM_PINS],
+    }
+
+    struct TestDelivery {
+        state: Arc<Mutex<TestDeliveryState>>,
+    }
+
+    impl MsiDelivery for TestDelivery {
+        fn set_route(&self, pin: u32, msi: Option<MsiVector>) -> Result<()> {
+            self.state.lock().unwrap().routes[pin as usize] =
+                msi.map(|msi| (msi.msg_addr_lo, msi.msg_data));
+            Ok(())
+        }
+
+        fn send(&self, msi: MsiVector) -> Result<()> {
+            self.state
+                .lock()
+                .unwrap()
+                .sent
+                .push((msi.msg_addr_lo, msi.msg_data));
+            Ok(())
+        }
+    }
+
+    fn test_ioapic() -> (IoApic, Arc<Mutex<TestDeliveryState>>) {
+        let state = Arc::new(Mutex::new(TestDeliveryState::default()));
+        let delivery = TestDelivery {
+            state: state.clone(),
+        };
+        (IoApic::new(Box::new(delivery)), state)
+    }
+
+    fn read_reg(ioapic: &mut IoApic, reg: u32) -> u32 {
+        assert!(ioapic.write(&reg.to_le_bytes(), IOAPIC_IOREGSEL));
+        let mut data = [0_u8; 4];
+        assert!(ioapic.read(&mut data, IOAPIC_IOWIN));
+        u32::from_le_bytes(data)
+    }
+
+    fn write_reg(ioapic: &mut IoApic, reg: u32, value: u32) {
+        assert!(ioapic.write(&reg.to_le_bytes(), IOAPIC_IOREGSEL));
+        assert!(ioapic.write(&value.to_le_bytes(), IOAPIC_IOWIN));
+    }
+
+    #[test]
+    fn test_ioapic_register_access() {
+        let (mut ioapic, state) = test_ioapic();
+
+        assert_eq!(read_reg(&mut ioapic, IOAPIC_REG_VERSION), 0x0017_0020);
+        write_reg(&mut ioapic, IOAPIC_REG_ID, 0x0200_0000);
+        assert_eq!(read_reg(&mut ioapic, IOAPIC_REG_ID), 0x0200_0000);
+        assert_eq!(read_reg(&mut ioapic, IOAPIC_REG_ARB), 0x0200_0000);
+        let mut data = [0_u8; 4];
+        assert!(ioapic.read(&mut data, IOAPIC_IOREGSEL));
+        assert_eq!(u32::from_le_bytes(data), IOAPIC_REG_ARB);
+
+        // All the pins are masked at reset.
+        for pin in 0..IOAPIC_NUM_PINS as u32 {
+            assert_eq!(
+                read_reg(&mut ioapic, IOAPIC_REG_REDTBL_BASE + pin * 2),
+                0x1_0000
+            );
+        }
+
+        // Low and high halves of the entry of pin 5.
+        write_reg(&mut ioapic, 0x1b, 0x0300_0000);
+        write_reg(&mut ioapic, 0x1a, 0x0000_a931);
+        assert_eq!(read_reg(&mut ioapic, 0x1a), 0x0000_a931);
+        assert_eq!(read_reg(&mut ioapic, 0x1b), 0x0300_0000);
+        assert_eq!(ioapic.redtbl[5], 0x0300_0000_0000_a931);
+
+        // Delivery status and remote IRR are read-only.
+        write_reg(&mut ioapic, 0x1a, 0x0000_f931);
+        assert_eq!(read_reg(&mut ioapic, 0x1a), 0x0000_a931);
+        // The route follows the entry.
+        assert_eq!(
+            state.lock().unwrap().routes[5],
+            Some((0xfee0_3004, 0x0000_8131))
+        );
+
+        // Registers out of the table read as 0 and ignore writes.
+        let reg = IOAPIC_REG_REDTBL_BASE + IOAPIC_NUM_PINS as u32 * 2;
+        write_reg(&mut ioapic, reg, 0xffff_ffff);
+        assert_eq!(read_reg(&mut ioapic, reg), 0);
+
+        ioapic.reset();
+        assert_eq!(read_reg(&mut ioapic, 0x1a), 0x1_0000);
+        assert_eq!(read_reg(&mut ioapic, IOAPIC_REG_ID), 0);
+        assert!(state.lock().unwrap().routes[5].is_none());
+    }
+
+    #[test]
+    fn test_ioapic_mask() {
+        let (mut ioapic, state) = test_ioapic();
+
+        // Edge-triggered interrupt raised while masked is delivered once unmasked.
+        write_reg(&mut ioapic, 0x14, 0x0001_0030);
+        ioapic.set_irq(2, true);
+        ioapic.set_irq(2, false);
+        assert!(state.lock().unwrap().sent.is_empty());
+        assert!(state.lock().unwrap().routes[2].is_none());
+        write_reg(&mut ioapic, 0x14, 0x0000_0030);
+        assert_eq!(state.lock().unwrap().sent, vec![(0xfee0_0000, 0x30)]);
+        // Only the rising edge raises the interrupt.
+        ioapic.set_irq(2, true);
+        ioapic.set_irq(2, true);
+        assert_eq!(state.lock().unwrap().sent.len(), 2);
+        ioapic.set_irq(2, false);
+
+        // Level-triggered interrupt is delivered again after EOI if the line is still high.
+        state.lock().unwrap().sent.clear();
+        write_reg(&mut ioapic, 0x30, 0x0000_8040);
+        ioapic.set_irq(16, true);
+        ioapic.set_irq(16, true);
+        assert_eq!(state.lock().unwrap().sent, vec![(0xfee0_0000, 0x8040)]);
+        assert_ne!(read_reg(&mut ioapic, 0x30) & RTE_REMOTE_IRR as u32, 0);
+        ioapic.eoi(0x40);
+        assert_eq!(state.lock().unwrap().sent.len(), 2);
+
+        // Masked level-triggered interrupt is not delivered after EOI.
+        write_reg(&mut ioapic, 0x30, 0x0001_8040);
+        ioapic.eoi(0x40);
+        assert_eq!(state.lock().unwrap().sent.len(), 2);
+        ioapic.set_irq(16, false);
+        write_reg(&mut ioapic, 0x30, 0x0000_8040);
+        assert_eq!(state.lock().unwrap().sent.len(), 2);
+
+        // EOI by the register of IOAPIC.
+        ioapic.set_irq(16, true);
+        assert_eq!(state.lock().unwrap().sent.len(), 3);
+        assert!(ioapic.write(&0x40_u32.to_le_bytes(), IOAPIC_EOI));
+        assert_eq!(state.lock().unwrap().sent.len(), 4);
+    }
+
+    #[test]
+    fn test_ioapic_msi_message() {
+        // Fixed delivery of vector 0x31 to physical APIC 3, edge-triggered.
+        let msi = IoApic::rte_to_msi(0x0300_0000_0000_0031);
+        assert_eq!(msi.msg_addr_lo, 0xfee0_3000);
+        assert_eq!(msi.msg_addr_hi, 0);
+        assert_eq!(msi.msg_data, 0x31);
+
+        // Lowest priority delivery of vector 0xec to logical APICs 0xff, level-triggered
+        // and active low.
+        let msi = IoApic::rte_to_msi(0xff00_0000_0000_a9ec);
+        assert_eq!(msi.msg_addr_lo, 0xfeef_f004);
+        assert_eq!(msi.msg_data, 0x81ec);
+
+        // NMI ignores the vector.
+        let msi = IoApic::rte_to_msi(0x0100_0000_0000_0400);
+        assert_eq!(msi.msg_addr_lo, 0xfee0_1000);
+        assert_eq!(msi.msg_data, 0x400);
+    }
+}
@@ -10,8 +10,9 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeMap;
 use std::fs::{read_link, File, OpenOptions};
-use std::io::{Stdin, Stdout};
+use std::io::{Sink, Stdin, Stdout};
 use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::{UnixListener, UnixStream};
 use std::path::PathBuf;
@@ -20,12 +21,16 @@ use std::sync::{Arc, Mutex};
 
 use anyhow::{bail, Context, Result};
 use libc::{cfmakeraw, tcgetattr, tcsetattr, termios};
-use log::{error, info};
-use machine_manager::machine::{PathInfo, PTY_PATH};
-use machine_manager::{
-    config::{ChardevConfig, ChardevType},
-    temp_cleaner::TempCleaner,
+use log::{error, info, warn};
+use machine_manager::config::{
+    get_socket_chardev, register_socket_chardev, unregister_socket_chardev, ChardevConfig,
+    ChardevEvent, ChardevNotifier, ChardevType, SocketChardev,
 };
+use machine_manager::event_loop::EventLoop;
+use machine_manager::qga::GuestAgentChannel;
+use machine_manager::qmp::qmp_schema::ChardevInfo;
+use machine_manager::temp_cleaner::TempCleaner;
+use once_cell::sync::Lazy;
 use util::loop_context::{
     gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
@@ -33,14 +38,29 @@ use util::set_termi_raw_mode;
 use util::unix::limit_permission;
 use vmm_sys_util::epoll::EventSet;
 
+use super::mux::{mux_input_handle, MuxFocus, StdioMux};
+
+/// Size of the input buffer of stdio when it's received by the monitor.
+const MUX_MONITOR_BUFF_SIZE: usize = 64;
+
 /// Provide the trait that helps handle the input data.
 pub trait InputReceiver: Send {
     fn input_handle(&mut self, buffer: &[u8]);
 
     fn get_remain_space_size(&mut self) -> usize;
+
+    /// Called after the backend of the chardev is changed, the pending output can
+    /// be flushed to the new backend.
+    fn backend_changed(&mut self) {}
 }
 
 type ReceFn = Option<Arc<dyn Fn(&[u8]) + Send + Sync>>;
+/// Takes the output of the front-end in the process, returns false to leave it to the backend.
+pub type OutputHook = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Realized chardevs which are attached to front-ends, keyed by chardev id.
+static CHARDEVS: Lazy<Mutex<BTreeMap<String, Arc<Mutex<Chardev>>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
 
 /// Character device structure.
 pub struct Chardev {
@@ -62,6 +82,14 @@ pub struct Chardev {
     receive: ReceFn,
     /// Return the remain space size of receiver buffer.
     get_remain_space_size: Option<Arc<dyn Fn() -> usize + Send + Sync>>,
+    /// Notify the front-end that the backend is changed.
+    backend_changed: Option<Arc<dyn Fn() + Send + Sync>>,
+    /// Path of the slave pty for pty-type chardev.
+    pty_path: Option<PathBuf>,
+    /// Multiplexer of the stdio input, which handles `Ctrl-A` escape sequences.
+    mux: Option<Arc<Mutex<StdioMux>>>,
+    /// Client in the process which takes the output before the backend.
+    output_hook: Option<OutputHook>,
 }
 
 impl Chardev {
@@ -76,11 +104,19 @@ impl Chardev {
             deactivated: false,
             receive: None,
             get_remain_space_size: None,
+            backend_changed: None,
+            pty_path: None,
+            mux: None,
+            output_hook: None,
         }
     }
 
     pub fn realize(&mut self) -> Result<()> {
         match &self.backend {
+            ChardevType::Null => {
+                // Discard the output and never receive input.
+                self.output = Some(Arc::new(Mutex::new(std::io::sink())));
+            }
             ChardevType::Stdio => {
                 set_termi_raw_mode().with_context(|| "Failed to set terminal to raw mode")?;
                 self.input = Some(Arc::new(Mutex::new(std::io::stdin())));
@@ -90,11 +126,7 @@ impl Chardev {
                 let (master, path) =
                     set_pty_raw_mode().with_context(|| "Failed to set pty to raw mode")?;
                 info!("Pty path is: {:?}", path);
-                let path_info = PathInfo {
-                    path: format!("pty:{:?}", &path),
-                    label: self.id.clone(),
-                };
-                PTY_PATH.lock().unwrap().push(path_info);
+                self.pty_path = Some(path);
                 // Safe because `master_arc` is the only one owner for the file descriptor.
                 let master_arc = unsafe { Arc::new(Mutex::new(File::from_raw_fd(master))) };
                 self.input = Some(master_arc.clone());
@@ -102,10 +134,21 @@ impl Chardev {
             }
             ChardevType::Socket {
                 path,
-                server,
-                nowait,
+                server: false,
+                reconnect,
+                ..
             } => {
-                if !*server || !*nowait {
+                let socket = register_socket_chardev(&self.id, path, *reconnect)?;
+                if let Err(e) = SocketChardev::connect(&socket) {
+                    if *reconnect == 0 {
+                        unregister_socket_chardev(&self.id);
+                        return Err(e);
+                    }
+                    warn!("{:?}, retry connecting every {} seconds", e, reconnect);
+                }
+            }
+            ChardevType::Socket { path, nowait, .. } => {
+                if !*nowait {
                     bail!(
                         "Argument \'server\' and \'nowait\' are both required for chardev \'{}\'",
                         path
@@ -137,6 +180,14 @@ impl Chardev {
         Ok(())
     }
 
+    /// Share the stdio input between the device and the monitor by `Ctrl-A` escape
+    /// sequences. It has no effect on other backends.
+    pub fn enable_stdio_mux(&mut self) {
+        if self.backend == ChardevType::Stdio {
+            self.mux = Some(Arc::new(Mutex::new(StdioMux::default())));
+        }
+    }
+
     pub fn set_input_callback<T: 'static + InputReceiver>(&mut self, dev: &Arc<Mutex<T>>) {
         let cloned_dev = dev.clone();
         self.receive = Some(Arc::new(move |data: &[u8]| {
@@ -146,9 +197,177 @@ impl Chardev {
         self.get_remain_space_size = Some(Arc::new(move || {
             cloned_dev.lock().unwrap().get_remain_space_size()
         }));
+        let cloned_dev = dev.clone();
+        self.backend_changed = Some(Arc::new(move || {
+            cloned_dev.lock().unwrap().backend_changed()
+        }));
+    }
+
+    /// Let the client in the process take the output of the front-end, e.g. the guest agent.
+    pub fn set_output_hook(&mut self, hook: OutputHook) {
+        self.output_hook = Some(hook);
+    }
+
+    /// Get the client in the process which takes the output of the front-end.
+    pub fn output_hook(&self) -> Option<OutputHook> {
+        self.output_hook.clone()
+    }
+
+    /// Get the filename of the backend, in the format like `unix:/path,server`.
+    fn filename(&self) -> String {
+        match &self.backend {
+            ChardevType::Null => "null".to_string(),
+            ChardevType::Stdio => "stdio".to_string(),
+            ChardevType::Pty => match &self.pty_path {
+                Some(path) => format!("pty:{}", path.display()),
+                None => "pty".to_string(),
+            },
+            ChardevType::Socket { path, server, .. } => {
+                if *server {
+                    format!("unix:{},server", path)
+                } else {
+                    format!("unix:{}", path)
+                }
+            }
+            ChardevType::File(path) => format!("file:{}", path),
+        }
+    }
+
+    /// Stop handling the events of the current backend and release it. The output
+    /// written by the front-end is flushed, while the input not yet read is dropped.
+    fn release_backend(&mut self) {
+        let mut fds = Vec::new();
+        // The stream of server-mode socket parks the listener, so delete it first.
+        if let Some(stream_fd) = self.stream_fd.take() {
+            fds.push(stream_fd);
+        }
+        match &self.backend {
+            ChardevType::Stdio | ChardevType::Pty => {
+                if let Some(input) = self.input.as_ref() {
+                    fds.push(input.lock().unwrap().as_raw_fd());
+                }
+            }
+            ChardevType::Socket { server: false, .. } => unregister_socket_chardev(&self.id),
+            ChardevType::Socket { .. } => {
+                if let Some(listener) = self.listener.as_ref() {
+                    fds.push(listener.as_raw_fd());
+                }
+            }
+            _ => (),
+        }
+        for fd in fds {
+            if let Err(e) = EventLoop::update_event(gen_delete_notifiers(&[fd]), None) {
+                warn!("Failed to delete event of chardev {}: {:?}", self.id, e);
+            }
+        }
+
+        if let Some(output) = self.output.take() {
+            if let Err(e) = output.lock().unwrap().flush() {
+                warn!("Failed to flush output of chardev {}: {:?}", self.id, e);
+            }
+        }
+        self.input = None;
+        self.listener = None;
+        self.pty_path = None;
+        self.mux = None;
     }
 }
 
+/// Register the realized chardev, so that it can be queried and changed by qmp.
+pub fn register_chardev(chardev: &Arc<Mutex<Chardev>>) {
+    let id = chardev.lock().unwrap().id.clone();
+    CHARDEVS.lock().unwrap().insert(id, chardev.clone());
+}
+
+/// Get the registered chardev by id.
+pub fn get_chardev(id: &str) -> Option<Arc<Mutex<Chardev>>> {
+    CHARDEVS.lock().unwrap().get(id).cloned()
+}
+
+/// Get the information of all registered chardevs.
+pub fn query_chardevs() -> Vec<ChardevInfo> {
+    CHARDEVS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(id, chardev)| {
+            let locked_chardev = chardev.lock().unwrap();
+            ChardevInfo {
+                open: locked_chardev.receive.is_some() && !locked_chardev.deactivated,
+                filename: locked_chardev.filename(),
+                label: id.clone(),
+            }
+        })
+        .collect()
+}
+
+/// Change the backend of the registered chardev. The new backend is realized before
+/// the old one is released, so the chardev keeps working with the old backend if it
+/// fails. The front-end is notified after the change.
+///
+/// # Arguments
+///
+/// * `id` - Id of the chardev.
+/// * `backend` - The new backend.
+pub fn change_chardev(id: &str, backend: ChardevType) -> Result<()> {
+    let chardev = CHARDEVS
+        .lock()
+        .unwrap()
+        .get(id)
+        .cloned()
+        .with_context(|| format!("Chardev {} not found", id))?;
+    if backend == ChardevType::Stdio {
+        bail!(
+            "Changing the backend of chardev {} to stdio is not supported",
+            id
+        );
+    }
+
+    let mut new_chardev = Chardev::new(ChardevConfig {
+        id: id.to_string(),
+        backend,
+    });
+    let old_is_client = matches!(
+        chardev.lock().unwrap().backend,
+        ChardevType::Socket { server: false, .. }
+    );
+    let new_is_client = matches!(
+        new_chardev.backend,
+        ChardevType::Socket { server: false, .. }
+    );
+    // The client-mode socket is registered by chardev id, the old one has to be
+    // released before realizing the new one, and is restored if it fails.
+    let released = old_is_client && new_is_client;
+    if released {
+        chardev.lock().unwrap().release_backend();
+    }
+    if let Err(e) = new_chardev.realize() {
+        if released {
+            chardev.lock().unwrap().realize()?;
+            EventLoop::update_event(EventNotifierHelper::internal_notifiers(chardev), None)?;
+        }
+        return Err(e.context(format!("Failed to realize new backend of chardev {}", id)));
+    }
+
+    let mut locked_chardev = chardev.lock().unwrap();
+    locked_chardev.release_backend();
+    locked_chardev.backend = new_chardev.backend.clone();
+    locked_chardev.listener = new_chardev.listener.take();
+    locked_chardev.input = new_chardev.input.take();
+    locked_chardev.output = new_chardev.output.take();
+    locked_chardev.pty_path = new_chardev.pty_path.take();
+    let backend_changed = locked_chardev.backend_changed.clone();
+    drop(locked_chardev);
+
+    EventLoop::update_event(EventNotifierHelper::internal_notifiers(chardev), None)
+        .with_context(|| format!("Failed to register events of chardev {}", id))?;
+    // The front-end may lock the chardev, call it without holding the lock.
+    if let Some(backend_changed) = backend_changed {
+        backend_changed();
+    }
+    Ok(())
+}
+
 fn set_pty_raw_mode() -> Result<(i32, PathBuf)> {
     let mut master: libc::c_int = 0;
     let master_ptr: *mut libc::c_int = &mut master;
@@ -208,14 +427,29 @@ fn get_notifier_handler(
             if locked_chardev.deactivated {
                 return None;
             }
-            let buff_size = locked_chardev.get_remain_space_size.as_ref().unwrap()();
+            let mux = locked_chardev.mux.clone();
+            // The monitor reads the input even if the device can't receive it.
+            let buff_size = match mux.as_ref() {
+                Some(mux) if mux.lock().unwrap().focus() == MuxFocus::Monitor => {
+                    MUX_MONITOR_BUFF_SIZE
+                }
+                _ => locked_chardev.get_remain_space_size.as_ref().unwrap()(),
+            };
             let mut buffer = vec![0_u8; buff_size];
             let input_h = locked_chardev.input.clone();
             let receive = locked_chardev.receive.clone();
             drop(locked_chardev);
             if let Some(input) = input_h {
                 if let Ok(index) = input.lock().unwrap().chr_read_raw(&mut buffer) {
-                    receive.as_ref().unwrap()(&mut buffer[..index]);
+                    match mux {
+                        Some(mux) => {
+                            let guest_data = mux_input_handle(&mux, &buffer[..index]);
+                            if !guest_data.is_empty() {
+                                receive.as_ref().unwrap()(&guest_data);
+                            }
+                        }
+                        None => receive.as_ref().unwrap()(&mut buffer[..index]),
+                    }
                 } else {
                     error!("Failed to read input data");
                 }
@@ -239,24 +473,11 @@ fn get_notifier_handler(
 
             let cloned_chardev = chardev.clone();
             let inner_handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
-                let mut locked_chardev = cloned_chardev.lock().unwrap();
                 if event == EventSet::IN {
-                    if locked_chardev.deactivated {
-                        return None;
-                    }
-                    let buff_size = locked_chardev.get_remain_space_size.as_ref().unwrap()();
-                    let mut buffer = vec![0_u8; buff_size];
-                    if let Some(input) = locked_chardev.input.clone() {
-                        if let Ok(index) = input.lock().unwrap().chr_read_raw(&mut buffer) {
-                            locked_chardev.receive.as_ref().unwrap()(&mut buffer[..index]);
-                        } else {
-                            error!("Failed to read input data");
-                        }
-                    } else {
-                        error!("Failed to get chardev input fd");
-                    }
+                    handle_socket_input(&cloned_chardev);
                     None
                 } else if event & EventSet::HANG_UP == EventSet::HANG_UP {
+                    let mut locked_chardev = cloned_chardev.lock().unwrap();
                     // Always allow disconnect even if has deactivated.
                     locked_chardev.input = None;
                     locked_chardev.output = None;
@@ -274,7 +495,86 @@ fn get_notifier_handler(
                 vec![inner_handler],
             )])
         }),
-        ChardevType::File(_) => Rc::new(move |_, _| None),
+        ChardevType::Null | ChardevType::File(_) => Rc::new(move |_, _| None),
+    }
+}
+
+/// Read the input data of the socket stream and pass it to the device.
+fn handle_socket_input(chardev: &Arc<Mutex<Chardev>>) {
+    let locked_chardev = chardev.lock().unwrap();
+    if locked_chardev.deactivated {
+        return;
+    }
+    let buff_size = locked_chardev.get_remain_space_size.as_ref().unwrap()();
+    let mut buffer = vec![0_u8; buff_size];
+    if let Some(input) = locked_chardev.input.clone() {
+        if let Ok(index) = input.lock().unwrap().chr_read_raw(&mut buffer) {
+            locked_chardev.receive.as_ref().unwrap()(&mut buffer[..index]);
+        } else {
+            error!("Failed to read input data");
+        }
+    } else {
+        error!("Failed to get chardev input fd");
+    }
+}
+
+/// Handle connection state changes of a client-mode socket chardev.
+fn handle_socket_event(chardev: &Arc<Mutex<Chardev>>, event: ChardevEvent) {
+    match event {
+        ChardevEvent::Connected(stream) => {
+            let stream_fd = stream.as_raw_fd();
+            let stream_arc = Arc::new(Mutex::new(stream));
+            let mut locked_chardev = chardev.lock().unwrap();
+            locked_chardev.stream_fd = Some(stream_fd);
+            locked_chardev.input = Some(stream_arc.clone());
+            locked_chardev.output = Some(stream_arc);
+            drop(locked_chardev);
+
+            // Hang up is handled by the socket chardev, which tells us by `Disconnected`.
+            let cloned_chardev = chardev.clone();
+            let handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
+                if event == EventSet::IN {
+                    handle_socket_input(&cloned_chardev);
+                }
+                None
+            });
+            let notifier = EventNotifier::new(
+                NotifierOperation::AddShared,
+                stream_fd,
+                None,
+                EventSet::IN,
+                vec![handler],
+            );
+            if let Err(e) = EventLoop::update_event(vec![notifier], None) {
+                error!("Failed to add event of chardev stream: {:?}", e);
+            }
+        }
+        ChardevEvent::Disconnected => {
+            let mut locked_chardev = chardev.lock().unwrap();
+            // Delete the event before closing the stream.
+            if let Some(stream_fd) = locked_chardev.stream_fd.take() {
+                if let Err(e) = EventLoop::update_event(gen_delete_notifiers(&[stream_fd]), None) {
+                    error!("Failed to delete event of chardev stream: {:?}", e);
+                }
+            }
+            locked_chardev.input = None;
+            locked_chardev.output = None;
+        }
+    }
+}
+
+/// The guest agent talks to the front-end of the chardev in the process.
+impl GuestAgentChannel for Chardev {
+    fn send(&mut self, buf: &[u8]) -> Result<()> {
+        if self.deactivated {
+            bail!("Chardev {} is not opened by the front-end", self.id);
+        }
+        let receive = self
+            .receive
+            .as_ref()
+            .with_context(|| format!("Chardev {} has no front-end", self.id))?;
+        receive(buf);
+        Ok(())
     }
 }
 
@@ -295,6 +595,39 @@ impl EventNotifierHelper for Chardev {
                     ));
                 }
             }
+            ChardevType::Socket { server: false, .. } => {
+                let (id, stream_fd) = {
+                    let locked_chardev = chardev.lock().unwrap();
+                    (locked_chardev.id.clone(), locked_chardev.stream_fd)
+                };
+                if let Some(stream_fd) = stream_fd {
+                    notifiers.push(EventNotifier::new(
+                        NotifierOperation::Resume,
+                        stream_fd,
+                        None,
+                        EventSet::IN,
+                        Vec::new(),
+                    ));
+                } else if let Some(socket) = get_socket_chardev(&id) {
+                    let handler_chardev = chardev.clone();
+                    let notifier: ChardevNotifier =
+                        Arc::new(move |event| handle_socket_event(&handler_chardev, event));
+                    let mut locked_socket = socket.lock().unwrap();
+                    locked_socket.add_notifier(&id, notifier);
+                    let stream = locked_socket
+                        .is_connected()
+                        .then(|| locked_socket.get_stream());
+                    drop(locked_socket);
+                    match stream {
+                        Some(Ok(stream)) => {
+                            handle_socket_event(&cloned_chardev, ChardevEvent::Connected(stream))
+                        }
+                        Some(Err(e)) => error!("Failed to get stream of chardev {}: {:?}", id, e),
+                        // Not connected yet, wait for the connection in the main loop.
+                        None => (),
+                    }
+                }
+            }
             ChardevType::Socket { .. } => {
                 if chardev.lock().unwrap().stream_fd.is_some() {
                     notifiers.push(EventNotifier::new(
@@ -314,7 +647,7 @@ impl EventNotifierHelper for Chardev {
                     ));
                 }
             }
-            ChardevType::File(_) => (),
+            ChardevType::Null | ChardevType::File(_) => (),
         }
         notifiers
     }
@@ -343,3 +676,79 @@ impl CommunicatInInterface for Stdin {}
 impl CommunicatOutInterface for UnixStream {}
 impl CommunicatOutInterface for File {}
 impl CommunicatOutInterface for Stdout {}
+impl CommunicatOutInterface for Sink {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Default)]
+    struct TestReceiver {
+        changed: u32,
+    }
+
+    impl InputReceiver for TestReceiver {
+        fn input_handle(&mut self, _buffer: &[u8]) {}
+
+        fn get_remain_space_size(&mut self) -> usize {
+            0
+        }
+
+        fn backend_changed(&mut self) {
+            self.changed += 1;
+        }
+    }
+
+    fn chardev_info(id: &str) -> Option<ChardevInfo> {
+        query_chardevs().into_iter().find(|info| info.label == id)
+    }
+
+    #[test]
+    fn test_chardev_change() {
+        EventLoop::object_init(&None).unwrap();
+        let id = "chardev_change";
+        let chardev = Arc::new(Mutex::new(Chardev::new(ChardevConfig {
+            id: id.to_string(),
+            backend: ChardevType::Null,
+        })));
+        chardev.lock().unwrap().realize().unwrap();
+        assert!(chardev_info(id).is_none());
+
+        let receiver = Arc::new(Mutex::new(TestReceiver::default()));
+        chardev.lock().unwrap().set_input_callback(&receiver);
+        register_chardev(&chardev);
+        let info = chardev_info(id).unwrap();
+        assert_eq!(info.filename, "null");
+        assert!(info.open);
+
+        // Unknown chardev and stdio backend are rejected.
+        assert!(change_chardev("chardev_unknown", ChardevType::Null).is_err());
+        assert!(change_chardev(id, ChardevType::Stdio).is_err());
+        assert_eq!(receiver.lock().unwrap().changed, 0);
+
+        let path = format!("/tmp/test_chardev_change_{}.sock", std::process::id());
+        let socket = ChardevType::Socket {
+            path: path.clone(),
+            server: true,
+            nowait: true,
+            reconnect: 0,
+        };
+        change_chardev(id, socket.clone()).unwrap();
+        assert_eq!(receiver.lock().unwrap().changed, 1);
+        assert_eq!(chardev.lock().unwrap().backend, socket);
+        assert!(chardev.lock().unwrap().listener.is_some());
+        let info = chardev_info(id).unwrap();
+        assert_eq!(info.filename, format!("unix:{},server", path));
+
+        // Failing to realize the new backend keeps the old one.
+        assert!(change_chardev(id, socket.clone()).is_err());
+        assert_eq!(receiver.lock().unwrap().changed, 1);
+        assert!(chardev.lock().unwrap().listener.is_some());
+
+        change_chardev(id, ChardevType::Null).unwrap();
+        assert_eq!(receiver.lock().unwrap().changed, 2);
+        assert!(chardev.lock().unwrap().listener.is_none());
+        assert_eq!(chardev_info(id).unwrap().filename, "null");
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -28,7 +28,10 @@
 mod chardev;
 pub mod error;
 mod fwcfg;
+mod mux;
 mod pflash;
+#[cfg(target_arch = "x86_64")]
+mod pit_stub;
 #[cfg(target_arch = "aarch64")]
 mod pl011;
 #[cfg(target_arch = "aarch64")]
@@ -41,14 +44,19 @@ mod serial;
 #[cfg(target_arch = "x86_64")]
 pub use self::rtc::{RTC, RTC_PORT_INDEX};
 pub use anyhow::Result;
-pub use chardev::{Chardev, InputReceiver};
+pub use chardev::{
+    change_chardev, get_chardev, query_chardevs, register_chardev, Chardev, InputReceiver,
+};
 pub use error::LegacyError;
 #[cfg(target_arch = "x86_64")]
 pub use fwcfg::FwCfgIO;
 #[cfg(target_arch = "aarch64")]
 pub use fwcfg::FwCfgMem;
 pub use fwcfg::{FwCfgEntryType, FwCfgOps};
+pub use mux::register_mux_machine;
 pub use pflash::PFlash;
+#[cfg(target_arch = "x86_64")]
+pub use pit_stub::PitStub;
 #[cfg(target_arch = "aarch64")]
 pub use pl011::PL011;
 #[cfg(target_arch = "aarch64")]
@@ -0,0 +1,334 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Multiplexer of the serial console on stdio.
+//!
+//! The input of stdio is shared by the guest console and a tiny monitor, and switched by
+//! escape sequences started with `Ctrl-A`:
+//! - `Ctrl-A x`: terminate the VM.
+//! - `Ctrl-A c`: switch between the guest console and the monitor.
+//! - `Ctrl-A h`: print the help.
+//! - `Ctrl-A Ctrl-A`: send `Ctrl-A` to the guest.
+//!
+//! The terminal stays in raw mode in the monitor, which echoes the input itself, so the
+//! mode saved before entering raw mode is still restored at exit.
+
+use std::io::Write;
+use std::sync::{Arc, Mutex, Weak};
+
+use log::error;
+use machine_manager::machine::MachineExternalInterface;
+use machine_manager::qmp::exec_human_monitor_command;
+use once_cell::sync::Lazy;
+
+/// `Ctrl-A` starts an escape sequence.
+pub const MUX_ESCAPE_CHAR: u8 = 0x01;
+/// Max length of a command line of the monitor.
+const MONITOR_LINE_MAX: usize = 256;
+const MONITOR_PROMPT: &str = "(stratovirt) ";
+const MUX_HELP: &str = "\r\n\
+C-a h    print this help\r\n\
+C-a x    exit StratoVirt\r\n\
+C-a c    switch between console and monitor\r\n\
+C-a C-a  sends C-a\r\n";
+const MONITOR_HELP: &str = "quit|stop|cont|info status\r\n";
+
+/// The machine controlled by the monitor.
+static MUX_MACHINE: Lazy<Mutex<Option<Weak<Mutex<dyn MachineExternalInterface + Send + Sync>>>>> =
+    Lazy::new(|| Mutex::new(None));
+
+/// Register the machine controlled by the monitor of the stdio console.
+pub fn register_mux_machine(machine: &Arc<Mutex<dyn MachineExternalInterface + Send + Sync>>) {
+    *MUX_MACHINE.lock().unwrap() = Some(Arc::downgrade(machine));
+}
+
+fn mux_machine() -> Option<Arc<Mutex<dyn MachineExternalInterface + Send + Sync>>> {
+    MUX_MACHINE.lock().unwrap().as_ref().and_then(Weak::upgrade)
+}
+
+/// Receiver of the input of stdio.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxFocus {
+    Guest,
+    Monitor,
+}
+
+/// What to do for an input byte.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MuxAction {
+    /// Nothing to do, the byte is swallowed.
+    None,
+    /// Pass the byte to the guest.
+    Guest(u8),
+    /// Echo the bytes to the terminal.
+    Echo(Vec<u8>),
+    /// Execute a command line of the monitor.
+    Command(String),
+    /// The focus is switched to the given receiver.
+    Switch(MuxFocus),
+    /// Print the help of escape sequences.
+    Help,
+    /// Terminate the VM.
+    Quit,
+}
+
+/// State machine of the escape sequences, which is fed byte by byte.
+pub struct StdioMux {
+    focus: MuxFocus,
+    /// Whether `Ctrl-A` is received and the next byte is the escape command.
+    escape: bool,
+    /// The command line being edited in the monitor.
+    line: String,
+}
+
+impl Default for StdioMux {
+    fn default() -> Self {
+        StdioMux {
+            focus: MuxFocus::Guest,
+            escape: false,
+            line: String::new(),
+        }
+    }
+}
+
+impl StdioMux {
+    pub fn focus(&self) -> MuxFocus {
+        self.focus
+    }
+
+    pub fn feed(&mut self, byte: u8) -> MuxAction {
+        if self.escape {
+            self.escape = false;
+            return match byte {
+                b'x' => MuxAction::Quit,
+                b'c' => {
+                    self.focus = match self.focus {
+                        MuxFocus::Guest => MuxFocus::Monitor,
+                        MuxFocus::Monitor => MuxFocus::Guest,
+                    };
+                    self.line.clear();
+                    MuxAction::Switch(self.focus)
+                }
+                b'h' => MuxAction::Help,
+                MUX_ESCAPE_CHAR if self.focus == MuxFocus::Guest => {
+                    MuxAction::Guest(MUX_ESCAPE_CHAR)
+                }
+                _ => MuxAction::None,
+            };
+        }
+        if byte == MUX_ESCAPE_CHAR {
+            self.escape = true;
+            return MuxAction::None;
+        }
+
+        match self.focus {
+            MuxFocus::Guest => MuxAction::Guest(byte),
+            MuxFocus::Monitor => self.edit_line(byte),
+        }
+    }
+
+    fn edit_line(&mut self, byte: u8) -> MuxAction {
+        match byte {
+            b'\r' | b'\n' => MuxAction::Command(std::mem::take(&mut self.line)),
+            // Backspace and delete.
+            0x08 | 0x7f => match self.line.pop() {
+                Some(_) => MuxAction::Echo(b"\x08 \x08".to_vec()),
+                None => MuxAction::None,
+            },
+            0x20..=0x7e if self.line.len() < MONITOR_LINE_MAX => {
+                self.line.push(byte as char);
+                MuxAction::Echo(vec![byte])
+            }
+            _ => MuxAction::None,
+        }
+    }
+}
+
+/// Execute a command line of the monitor, return its output.
+fn monitor_command(command_line: &str) -> String {
+    let machine = match mux_machine() {
+        Some(machine) => machine,
+        None => return "Error: no machine is available\r\n".to_string(),
+    };
+    let args = command_line.split_whitespace().collect::<Vec<&str>>();
+    let done = match args.as_slice() {
+        ["quit" | "q"] => machine.lock().unwrap().destroy(),
+        ["stop"] => machine.lock().unwrap().pause(),
+        ["cont" | "c"] => machine.lock().unwrap().resume(),
+        ["help" | "?"] => return MONITOR_HELP.to_string(),
+        _ => return exec_human_monitor_command(&*machine.lock().unwrap(), command_line),
+    };
+    if done {
+        String::new()
+    } else {
+        format!("Error: failed to {}\r\n", args[0])
+    }
+}
+
+fn mux_write(bytes: &[u8]) {
+    let mut stdout = std::io::stdout();
+    if let Err(e) = stdout.write_all(bytes).and_then(|_| stdout.flush()) {
+        error!("Failed to write to the stdio console: {:?}", e);
+    }
+}
+
+/// Handle the input of stdio by `mux`, return the bytes passed to the guest.
+pub fn mux_input_handle(mux: &Mutex<StdioMux>, data: &[u8]) -> Vec<u8> {
+    let mut guest_data = Vec::new();
+    for byte in data {
+        // Don't hold the lock while executing commands, which may wait for the vCPUs
+        // using the serial device.
+        let action = mux.lock().unwrap().feed(*byte);
+        match action {
+            MuxAction::None => (),
+            MuxAction::Guest(byte) => guest_data.push(byte),
+            MuxAction::Echo(bytes) => mux_write(&bytes),
+            MuxAction::Command(command_line) => {
+                mux_write(b"\r\n");
+                mux_write(monitor_command(&command_line).as_bytes());
+                mux_write(MONITOR_PROMPT.as_bytes());
+            }
+            MuxAction::Switch(MuxFocus::Monitor) => {
+                mux_write(format!("\r\nStratoVirt monitor\r\n{}", MONITOR_PROMPT).as_bytes())
+            }
+            MuxAction::Switch(MuxFocus::Guest) => mux_write(b"\r\n"),
+            MuxAction::Help => mux_write(MUX_HELP.as_bytes()),
+            MuxAction::Quit => {
+                mux_write(b"\r\nStratoVirt: terminating on Ctrl-A x\r\n");
+                match mux_machine() {
+                    Some(machine) => {
+                        machine.lock().unwrap().destroy();
+                    }
+                    None => error!("No machine to terminate by Ctrl-A x"),
+                }
+            }
+        }
+    }
+    guest_data
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn feed_all(mux: &mut StdioMux, data: &[u8]) -> Vec<MuxAction> {
+        data.iter().map(|byte| mux.feed(*byte)).collect()
+    }
+
+    #[test]
+    fn test_mux_guest_input() {
+        let mut mux = StdioMux::default();
+        assert_eq!(
+            feed_all(&mut mux, b"ls\r"),
+            vec![
+                MuxAction::Guest(b'l'),
+                MuxAction::Guest(b's'),
+                MuxAction::Guest(b'\r')
+            ]
+        );
+
+        // Ctrl-A Ctrl-A sends a literal Ctrl-A.
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, MUX_ESCAPE_CHAR, b'a']),
+            vec![
+                MuxAction::None,
+                MuxAction::Guest(MUX_ESCAPE_CHAR),
+                MuxAction::Guest(b'a')
+            ]
+        );
+
+        // Unknown escape is dropped, and the escape state is left.
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'z', b'z']),
+            vec![MuxAction::None, MuxAction::None, MuxAction::Guest(b'z')]
+        );
+
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'h']),
+            vec![MuxAction::None, MuxAction::Help]
+        );
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'x']),
+            vec![MuxAction::None, MuxAction::Quit]
+        );
+        assert_eq!(mux.focus(), MuxFocus::Guest);
+    }
+
+    #[test]
+    fn test_mux_monitor_input() {
+        let mut mux = StdioMux::default();
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'c']),
+            vec![MuxAction::None, MuxAction::Switch(MuxFocus::Monitor)]
+        );
+        assert_eq!(mux.focus(), MuxFocus::Monitor);
+
+        // The line is echoed and edited.
+        assert_eq!(
+            feed_all(&mut mux, b"stopp\x7f"),
+            vec![
+                MuxAction::Echo(b"s".to_vec()),
+                MuxAction::Echo(b"t".to_vec()),
+                MuxAction::Echo(b"o".to_vec()),
+                MuxAction::Echo(b"p".to_vec()),
+                MuxAction::Echo(b"p".to_vec()),
+                MuxAction::Echo(b"\x08 \x08".to_vec()),
+            ]
+        );
+        assert_eq!(mux.feed(b'\r'), MuxAction::Command("stop".to_string()));
+        assert_eq!(mux.feed(b'\r'), MuxAction::Command(String::new()));
+        // Nothing to delete, and control bytes are ignored.
+        assert_eq!(feed_all(&mut mux, b"\x08\x1b"), vec![MuxAction::None; 2]);
+
+        let actions = feed_all(&mut mux, b"info status\n");
+        assert_eq!(
+            actions.last(),
+            Some(&MuxAction::Command("info status".to_string()))
+        );
+
+        // Ctrl-A Ctrl-A is not sent to the guest from the monitor.
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, MUX_ESCAPE_CHAR]),
+            vec![MuxAction::None; 2]
+        );
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'x']),
+            vec![MuxAction::None, MuxAction::Quit]
+        );
+
+        // The unfinished line is dropped when switching back to the guest.
+        mux.feed(b'q');
+        assert_eq!(
+            feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'c', b'\r']),
+            vec![
+                MuxAction::None,
+                MuxAction::Switch(MuxFocus::Guest),
+                MuxAction::Guest(b'\r')
+            ]
+        );
+        feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'c']);
+        assert_eq!(mux.feed(b'\r'), MuxAction::Command(String::new()));
+    }
+
+    #[test]
+    fn test_mux_monitor_line_limit() {
+        let mut mux = StdioMux::default();
+        feed_all(&mut mux, &[MUX_ESCAPE_CHAR, b'c']);
+        let actions = feed_all(&mut mux, &[b'a'; MONITOR_LINE_MAX + 1]);
+        assert_eq!(actions[MONITOR_LINE_MAX - 1], MuxAction::Echo(vec![b'a']));
+        assert_eq!(actions[MONITOR_LINE_MAX], MuxAction::None);
+        assert_eq!(
+            mux.feed(b'\r'),
+            MuxAction::Command("a".repeat(MONITOR_LINE_MAX))
+        );
+    }
+}
@@ -1087,4 +1087,62 @@ mod test {
 
         fs::remove_file(file_name).unwrap();
     }
+
+    #[test]
+    fn test_command_sequence_persistence() {
+        use std::io::{Read, Seek, SeekFrom};
+
+        let file_name = "flash_vars_for_write_4.fd";
+        let dev = pflash_dev_init(file_name);
+        let base = GuestAddress(0x0000);
+        let read_file = |offset: u64| {
+            let mut fd = File::open(file_name).unwrap();
+            fd.seek(SeekFrom::Start(offset)).unwrap();
+            let mut buf = vec![0_u8; 4];
+            fd.read_exact(&mut buf).unwrap();
+            buf
+        };
+        let mut read_data = vec![0_u8; 4];
+
+        // CFI query, then back to read array mode.
+        assert!(dev.lock().unwrap().write(&[0x98, 0, 0x98, 0], base, 0));
+        assert!(dev.lock().unwrap().read(&mut read_data, base, 0x40));
+        assert_eq!(read_data, vec![0x51, 0x00, 0x51, 0x00]);
+        assert!(dev.lock().unwrap().write(&[0xff, 0, 0xff, 0], base, 0));
+        assert_eq!(dev.lock().unwrap().cmd, 0x00);
+        assert_eq!(dev.lock().unwrap().write_cycle, 0);
+
+        // Program a word, which is written back to the file at once.
+        let data = vec![0x12_u8, 0x34, 0x56, 0x78];
+        let offset = 0x4_0100_u64;
+        assert!(dev.lock().unwrap().write(&[0x40, 0, 0x40, 0], base, offset));
+        assert!(dev.lock().unwrap().write(&data, base, offset));
+        assert!(dev.lock().unwrap().read(&mut read_data, base, offset));
+        assert_eq!(read_data[0] & 0x80, 0x80);
+        assert!(dev.lock().unwrap().write(&[0xff, 0, 0xff, 0], base, 0));
+        assert!(dev.lock().unwrap().read(&mut read_data, base, offset));
+        assert_eq!(read_data, data);
+        assert_eq!(read_file(offset), data);
+
+        // Erase the block of the word and confirm it.
+        assert!(dev.lock().unwrap().write(&[0x20, 0, 0x20, 0], base, offset));
+        assert!(dev.lock().unwrap().write(&[0xd0, 0, 0xd0, 0], base, offset));
+        assert_eq!(dev.lock().unwrap().write_cycle, 0);
+        assert!(dev.lock().unwrap().write(&[0xff, 0, 0xff, 0], base, 0));
+        assert!(dev.lock().unwrap().read(&mut read_data, base, 0x4_0000));
+        assert_eq!(read_data, vec![0xff_u8; 4]);
+        assert_eq!(read_file(offset), vec![0xff_u8; 4]);
+        assert_eq!(read_file(0x4_0000), vec![0xff_u8; 4]);
+        // The previous block is untouched.
+        assert_eq!(read_file(0x3_fffc), vec![0_u8; 4]);
+
+        // The read-only PFlash refuses programming.
+        dev.lock().unwrap().read_only = true;
+        assert!(dev.lock().unwrap().write(&[0x40, 0, 0x40, 0], base, 0));
+        assert!(dev.lock().unwrap().write(&data, base, 0));
+        assert_eq!(dev.lock().unwrap().status & 0x10, 0x10);
+        assert_eq!(read_file(0), vec![0_u8; 4]);
+
+        fs::remove_file(file_name).unwrap();
+    }
 }
@@ -0,0 +1,105 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::Arc;
+
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use anyhow::{Context, Result};
+
+/// IO ports of the i8254 PIT: counter 0-2 and the control word register.
+pub const PIT_PORT_BASE: u64 = 0x40;
+const PIT_PORT_SIZE: u64 = 4;
+/// IO port of NMI status and control register, which gates the speaker by PIT counter 2.
+pub const SPEAKER_PORT: u64 = 0x61;
+/// Bit 5 of the speaker port: output of PIT counter 2. Kernel older than 4.18 gets stuck
+/// in `pit_calibrate_tsc()` if it's not set.
+const SPEAKER_TIMER2_OUT: u8 = 0x20;
+
+/// Stub of the PIT and the speaker port when the in-kernel PIT is disabled. The guest
+/// is expected to use kvmclock or TSC, so the accesses just get sane defaults: counters
+/// read as 0, and writes are ignored.
+pub struct PitStub {}
+
+impl PitStub {
+    fn region_ops(default: u8) -> RegionOps {
+        let read = move |data: &mut [u8], _addr: GuestAddress, _offset: u64| -> bool {
+            data.fill(default);
+            true
+        };
+        let write = |_data: &[u8], _addr: GuestAddress, _offset: u64| -> bool { true };
+        RegionOps {
+            read: Arc::new(read),
+            write: Arc::new(write),
+        }
+    }
+
+    /// Add the stub regions of PIT and speaker port to the IO address space.
+    ///
+    /// # Arguments
+    ///
+    /// * `sys_io` - IO address space of the machine.
+    pub fn realize(sys_io: &Arc<AddressSpace>) -> Result<()> {
+        sys_io
+            .root()
+            .add_subregion(
+                Region::init_io_region(PIT_PORT_SIZE, Self::region_ops(0)),
+                PIT_PORT_BASE,
+            )
+            .with_context(|| "Failed to add PIT stub region")?;
+        sys_io
+            .root()
+            .add_subregion(
+                Region::init_io_region(1, Self::region_ops(SPEAKER_TIMER2_OUT)),
+                SPEAKER_PORT,
+            )
+            .with_context(|| "Failed to add speaker port stub region")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pit_stub() {
+        let root = Region::init_container_region(1 << 16);
+        let sys_io = AddressSpace::new(root).unwrap();
+        let mut data = [0xff_u8; 1];
+        assert!(sys_io
+            .read(&mut data.as_mut(), GuestAddress(PIT_PORT_BASE), 1)
+            .is_err());
+
+        PitStub::realize(&sys_io).unwrap();
+        for port in PIT_PORT_BASE..PIT_PORT_BASE + PIT_PORT_SIZE {
+            data[0] = 0xff;
+            sys_io
+                .read(&mut data.as_mut(), GuestAddress(port), 1)
+                .unwrap();
+            assert_eq!(data[0], 0);
+            sys_io
+                .write(&mut [0x34_u8].as_ref(), GuestAddress(port), 1)
+                .unwrap();
+        }
+        sys_io
+            .read(&mut data.as_mut(), GuestAddress(SPEAKER_PORT), 1)
+            .unwrap();
+        assert_eq!(data[0], SPEAKER_TIMER2_OUT);
+        sys_io
+            .write(&mut [0x3_u8].as_ref(), GuestAddress(SPEAKER_PORT), 1)
+            .unwrap();
+        // The ports nearby are not covered.
+        assert!(sys_io
+            .read(&mut data.as_mut(), GuestAddress(0x60), 1)
+            .is_err());
+    }
+}
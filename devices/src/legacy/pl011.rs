@@ -12,7 +12,7 @@
 
 use std::sync::{Arc, Mutex};
 
-use super::chardev::{Chardev, InputReceiver};
+use super::chardev::{register_chardev, Chardev, InputReceiver};
 use super::error::LegacyError;
 use acpi::{
     AmlActiveLevel, AmlBuilder, AmlDevice, AmlEdgeLevel, AmlExtendedInterrupt, AmlIntShare,
@@ -182,7 +182,11 @@ impl PL011 {
             PL011_SNAPSHOT_ID,
         );
         let locked_dev = dev.lock().unwrap();
-        locked_dev.chardev.lock().unwrap().set_input_callback(&dev);
+        register_chardev(&locked_dev.chardev);
+        let mut locked_chardev = locked_dev.chardev.lock().unwrap();
+        locked_chardev.enable_stdio_mux();
+        locked_chardev.set_input_callback(&dev);
+        drop(locked_chardev);
         EventLoop::update_event(
             EventNotifierHelper::internal_notifiers(locked_dev.chardev.clone()),
             None,
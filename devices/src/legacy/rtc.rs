@@ -10,8 +10,9 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::sync::{Arc, Mutex};
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::cmp;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use acpi::{
     AmlBuilder, AmlDevice, AmlEisaId, AmlIoDecode, AmlIoResource, AmlIrqNoFlags, AmlNameDecl,
@@ -19,7 +20,9 @@ use acpi::{
 };
 use address_space::GuestAddress;
 use anyhow::Result;
+use hypervisor::kvm::KVM_FDS;
 use log::{debug, error, warn};
+use machine_manager::{config::RtcBase, event_loop::EventLoop};
 use sysbus::{SysBus, SysBusDevOps, SysBusDevType, SysRes};
 use vmm_sys_util::eventfd::EventFd;
 
@@ -27,11 +30,16 @@ use util::time::{mktime64, NANOSECONDS_PER_SECOND};
 
 /// IO port of RTC device to select Register to read/write.
 pub const RTC_PORT_INDEX: u64 = 0x70;
+/// IRQ of RTC device, which is fixed on PC.
+const RTC_IRQ: i32 = 8;
 
 /// Index of register of time in RTC static RAM.
 const RTC_SECONDS: u8 = 0x00;
+const RTC_SECONDS_ALARM: u8 = 0x01;
 const RTC_MINUTES: u8 = 0x02;
+const RTC_MINUTES_ALARM: u8 = 0x03;
 const RTC_HOURS: u8 = 0x04;
+const RTC_HOURS_ALARM: u8 = 0x05;
 const RTC_DAY_OF_WEEK: u8 = 0x06;
 const RTC_DAY_OF_MONTH: u8 = 0x07;
 const RTC_MONTH: u8 = 0x08;
@@ -44,9 +52,34 @@ const RTC_CENTURY_BCD: u8 = 0x32;
 
 // Update in progress (UIP) bit.
 const REG_A_UIP: u8 = 0x80;
+// Rate selection bits of periodic interrupt.
+const REG_A_RATE: u8 = 0x0F;
 // UIP bit held for last 244 us of every second.
 const UIP_HOLD_LENGTH: u64 = 8 * NANOSECONDS_PER_SECOND / 32768;
 
+// Periodic interrupt enable (PIE), alarm interrupt enable (AIE) and update-ended interrupt
+// enable (UIE) bits, which are in the same position as their flags in Register-C.
+const REG_B_PIE: u8 = 0x40;
+const REG_B_AIE: u8 = 0x20;
+const REG_B_UIE: u8 = 0x10;
+// Data mode (DM) bit, time is in binary if it's set, or in BCD.
+const REG_B_DM_BINARY: u8 = 0x04;
+// Hour format bit, 24-hour mode if it's set, or 12-hour mode.
+const REG_B_24H: u8 = 0x02;
+
+// Interrupt request flag (IRQF), it's set if any flag is set with its interrupt enabled.
+const REG_C_IRQF: u8 = 0x80;
+// Periodic interrupt flag (PF), alarm interrupt flag (AF) and update-ended interrupt flag (UF).
+const REG_C_PF: u8 = 0x40;
+const REG_C_AF: u8 = 0x20;
+const REG_C_UF: u8 = 0x10;
+const REG_C_MASK: u8 = REG_C_PF | REG_C_AF | REG_C_UF;
+
+// PM bit of hours in 12-hour mode.
+const HOURS_PM: u8 = 0x80;
+// An alarm field with its two high bits set matches any value.
+const ALARM_DONT_CARE: u8 = 0xC0;
+
 // Index of memory data in RTC static RAM.
 // 0x15/0x16 stores low/high byte below 1MB, range is [0, 640KB].
 const CMOS_BASE_MEM: (u8, u8) = (0x15, 0x16);
@@ -59,8 +92,8 @@ const CMOS_MEM_BELOW_4GB: (u8, u8) = (0x34, 0x35);
 // 0x5B/0x5C/0x5D stores low/middle/high byte of memory above 4GB, unit is 64KB.
 const CMOS_MEM_ABOVE_4GB: (u8, u8, u8) = (0x5B, 0x5C, 0x5D);
 
-fn rtc_time_to_tm(time_val: i64) -> libc::tm {
-    let mut dest_tm = libc::tm {
+fn empty_tm() -> libc::tm {
+    libc::tm {
         tm_sec: 0,
         tm_min: 0,
         tm_hour: 0,
@@ -72,7 +105,11 @@ fn rtc_time_to_tm(time_val: i64) -> libc::tm {
         tm_isdst: 0,
         tm_gmtoff: 0,
         tm_zone: std::ptr::null_mut(),
-    };
+    }
+}
+
+fn rtc_time_to_tm(time_val: i64) -> libc::tm {
+    let mut dest_tm = empty_tm();
 
     // SAFETY: `libc::gmtime_r` just convert calendar time to
     // broken-down format, and saved to `dest_tm`.
@@ -81,6 +118,26 @@ fn rtc_time_to_tm(time_val: i64) -> libc::tm {
     dest_tm
 }
 
+/// Get the time of the host in seconds since 1970-01-01 00:00:00, in UTC or in the
+/// local time of the host.
+fn host_time(base: RtcBase) -> u64 {
+    // Since 1970-01-01 00:00:00, it never cause overflow.
+    let utc = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time wrong")
+        .as_secs();
+    match base {
+        RtcBase::Utc => utc,
+        RtcBase::LocalTime => {
+            let mut local_tm = empty_tm();
+            // SAFETY: `libc::localtime_r` just convert calendar time to
+            // broken-down format of local time, and saved to `local_tm`.
+            unsafe { libc::localtime_r(&(utc as i64), &mut local_tm) };
+            (utc as i64 + local_tm.tm_gmtoff) as u64
+        }
+    }
+}
+
 /// Transfer binary coded decimal to BCD coded decimal.
 fn bin_to_bcd(src: u8) -> u8 {
     ((src / 10) << 4) + (src % 10)
@@ -88,7 +145,7 @@ fn bin_to_bcd(src: u8) -> u8 {
 
 /// Transfer BCD coded decimal to binary coded decimal.
 fn bcd_to_bin(src: u8) -> u64 {
-    if (src >> 4) > 9 || (src & 0x0f) > 9 {
+    if !is_valid_bcd(src) {
         warn!("RTC: The BCD coded format is wrong.");
         return 0_u64;
     }
@@ -96,6 +153,10 @@ fn bcd_to_bin(src: u8) -> u64 {
     (((src >> 4) * 10) + (src & 0x0f)) as u64
 }
 
+fn is_valid_bcd(src: u8) -> bool {
+    (src >> 4) <= 9 && (src & 0x0f) <= 9
+}
+
 #[allow(clippy::upper_case_acronyms)]
 /// RTC device.
 pub struct RTC {
@@ -115,11 +176,23 @@ pub struct RTC {
     tick_offset: u64,
     /// Record the real time.
     base_time: Instant,
+    /// The clock value when the update-ended and alarm flags were last checked.
+    last_update: i64,
+    /// Deadline of the next periodic interrupt, `None` if it's disabled.
+    periodic_deadline: Option<Instant>,
+    /// Generation of the timer, the timers of the previous generations are ignored.
+    timer_gen: u64,
+    /// The RTC device itself, used by the timer.
+    self_weak: Weak<Mutex<RTC>>,
 }
 
 impl RTC {
     /// Construct function of RTC device.
-    pub fn new() -> Result<RTC> {
+    ///
+    /// # Arguments
+    ///
+    /// * `base` - The RTC runs in UTC or in the local time of the host.
+    pub fn new(base: RtcBase) -> Result<RTC> {
         let mut rtc = RTC {
             cmos_data: [0_u8; 128],
             cur_index: 0_u8,
@@ -131,18 +204,18 @@ impl RTC {
             },
             mem_size: 0,
             gap_start: 0,
-            // Since 1970-01-01 00:00:00, it never cause overflow.
-            tick_offset: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("time wrong")
-                .as_secs(),
+            tick_offset: host_time(base),
             base_time: Instant::now(),
+            last_update: 0,
+            periodic_deadline: None,
+            timer_gen: 0,
+            self_weak: Weak::new(),
         };
 
-        let tm = rtc_time_to_tm(rtc.get_current_value());
-        rtc.set_rtc_cmos(tm);
-
         rtc.init_rtc_reg();
+        rtc.last_update = rtc.get_current_value();
+        let tm = rtc_time_to_tm(rtc.last_update);
+        rtc.set_rtc_cmos(tm);
 
         Ok(rtc)
     }
@@ -205,6 +278,7 @@ impl RTC {
             return false;
         }
 
+        self.update_flags();
         let tm = rtc_time_to_tm(self.get_current_value());
         self.set_rtc_cmos(tm);
         match self.cur_index {
@@ -213,12 +287,12 @@ impl RTC {
                 // UIP(update in progress) bit will be set at last 244us of every second.
                 if self.update_in_progress() {
                     data[0] |= REG_A_UIP;
-                    self.inject_interrupt();
                 }
             }
             RTC_REG_C => {
-                // The interrupt request flag (IRQF), alarm interrupt flag (AF).
-                data[0] = 1 << 7 | 1 << 5;
+                // The flags are cleared once read, the interrupt is acknowledged then.
+                data[0] = self.cmos_data[RTC_REG_C as usize];
+                self.cmos_data[RTC_REG_C as usize] = 0;
             }
             _ => {
                 data[0] = self.cmos_data[self.cur_index as usize];
@@ -247,6 +321,21 @@ impl RTC {
                     );
                 }
             }
+            RTC_REG_A => {
+                // UIP bit is read-only.
+                self.cmos_data[RTC_REG_A as usize] = data[0] & !REG_A_UIP;
+                self.periodic_deadline = None;
+                self.update_flags();
+                self.arm_timer();
+            }
+            RTC_REG_B => {
+                self.cmos_data[RTC_REG_B as usize] = data[0];
+                // The time registers follow the new data mode and hour format.
+                let tm = rtc_time_to_tm(self.get_current_value());
+                self.set_rtc_cmos(tm);
+                self.update_flags();
+                self.arm_timer();
+            }
             RTC_REG_C | RTC_REG_D => {
                 warn!(
                     "Failed to write: read-only register, index {}, data {}",
@@ -268,6 +357,10 @@ impl RTC {
 
         let dev = Arc::new(Mutex::new(self));
         sysbus.attach_device(&dev, region_base, region_size)?;
+
+        let mut locked_dev = dev.lock().unwrap();
+        locked_dev.self_weak = Arc::downgrade(&dev);
+        locked_dev.arm_timer();
         Ok(())
     }
 
@@ -286,15 +379,65 @@ impl RTC {
         (self.base_time.elapsed().as_secs() as i128 + self.tick_offset as i128) as i64
     }
 
+    fn is_binary_mode(&self) -> bool {
+        self.cmos_data[RTC_REG_B as usize] & REG_B_DM_BINARY != 0
+    }
+
+    fn is_24h_mode(&self) -> bool {
+        self.cmos_data[RTC_REG_B as usize] & REG_B_24H != 0
+    }
+
+    /// Transfer the binary value to the register in the current data mode.
+    fn bin_to_reg(&self, val: u8) -> u8 {
+        if self.is_binary_mode() {
+            val
+        } else {
+            bin_to_bcd(val)
+        }
+    }
+
+    /// Transfer the register in the current data mode to the binary value.
+    fn reg_to_bin(&self, val: u8) -> u64 {
+        if self.is_binary_mode() {
+            val as u64
+        } else {
+            bcd_to_bin(val)
+        }
+    }
+
+    fn hours_to_reg(&self, hours: u8) -> u8 {
+        if self.is_24h_mode() {
+            return self.bin_to_reg(hours);
+        }
+        let pm = if hours >= 12 { HOURS_PM } else { 0 };
+        match hours % 12 {
+            0 => self.bin_to_reg(12) | pm,
+            hours => self.bin_to_reg(hours) | pm,
+        }
+    }
+
+    fn reg_to_hours(&self, val: u8) -> u64 {
+        if self.is_24h_mode() {
+            return self.reg_to_bin(val);
+        }
+        let hours = self.reg_to_bin(val & !HOURS_PM) % 12;
+        if val & HOURS_PM != 0 {
+            hours + 12
+        } else {
+            hours
+        }
+    }
+
     fn set_rtc_cmos(&mut self, tm: libc::tm) {
-        self.cmos_data[RTC_SECONDS as usize] = bin_to_bcd(tm.tm_sec as u8);
-        self.cmos_data[RTC_MINUTES as usize] = bin_to_bcd(tm.tm_min as u8);
-        self.cmos_data[RTC_HOURS as usize] = bin_to_bcd(tm.tm_hour as u8);
-        self.cmos_data[RTC_DAY_OF_WEEK as usize] = bin_to_bcd((tm.tm_wday + 1) as u8);
-        self.cmos_data[RTC_DAY_OF_MONTH as usize] = bin_to_bcd(tm.tm_mday as u8);
-        self.cmos_data[RTC_MONTH as usize] = bin_to_bcd((tm.tm_mon + 1) as u8);
-        self.cmos_data[RTC_YEAR as usize] = bin_to_bcd(((tm.tm_year + 1900) % 100) as u8);
-        self.cmos_data[RTC_CENTURY_BCD as usize] = bin_to_bcd(((tm.tm_year + 1900) / 100) as u8);
+        self.cmos_data[RTC_SECONDS as usize] = self.bin_to_reg(tm.tm_sec as u8);
+        self.cmos_data[RTC_MINUTES as usize] = self.bin_to_reg(tm.tm_min as u8);
+        self.cmos_data[RTC_HOURS as usize] = self.hours_to_reg(tm.tm_hour as u8);
+        self.cmos_data[RTC_DAY_OF_WEEK as usize] = self.bin_to_reg((tm.tm_wday + 1) as u8);
+        self.cmos_data[RTC_DAY_OF_MONTH as usize] = self.bin_to_reg(tm.tm_mday as u8);
+        self.cmos_data[RTC_MONTH as usize] = self.bin_to_reg((tm.tm_mon + 1) as u8);
+        self.cmos_data[RTC_YEAR as usize] = self.bin_to_reg(((tm.tm_year + 1900) % 100) as u8);
+        self.cmos_data[RTC_CENTURY_BCD as usize] =
+            self.bin_to_reg(((tm.tm_year + 1900) / 100) as u8);
     }
 
     fn rtc_valid_check(&self, val: u8) -> bool {
@@ -311,11 +454,22 @@ impl RTC {
             [0, 99], // Year
         ];
 
-        if (val >> 4) > 9 || (val & 0x0f) > 9 {
-            return false;
-        }
-
-        let value = bcd_to_bin(val);
+        let value = if self.cur_index == RTC_HOURS && !self.is_24h_mode() {
+            let hours = val & !HOURS_PM;
+            if !self.is_binary_mode() && !is_valid_bcd(hours) {
+                return false;
+            }
+            // Hours are in range [1, 12] in 12-hour mode.
+            if !(1..=12).contains(&self.reg_to_bin(hours)) {
+                return false;
+            }
+            self.reg_to_hours(val)
+        } else {
+            if !self.is_binary_mode() && !is_valid_bcd(val) {
+                return false;
+            }
+            self.reg_to_bin(val)
+        };
 
         if self.cur_index <= 9
             && (value < range[self.cur_index as usize][0]
@@ -328,13 +482,13 @@ impl RTC {
     }
 
     fn update_rtc_time(&mut self) {
-        let sec = bcd_to_bin(self.cmos_data[RTC_SECONDS as usize]);
-        let min = bcd_to_bin(self.cmos_data[RTC_MINUTES as usize]);
-        let hour = bcd_to_bin(self.cmos_data[RTC_HOURS as usize]);
-        let day = bcd_to_bin(self.cmos_data[RTC_DAY_OF_MONTH as usize]);
-        let mon = bcd_to_bin(self.cmos_data[RTC_MONTH as usize]);
-        let year = bcd_to_bin(self.cmos_data[RTC_YEAR as usize])
-            + bcd_to_bin(self.cmos_data[RTC_CENTURY_BCD as usize]) * 100;
+        let sec = self.reg_to_bin(self.cmos_data[RTC_SECONDS as usize]);
+        let min = self.reg_to_bin(self.cmos_data[RTC_MINUTES as usize]);
+        let hour = self.reg_to_hours(self.cmos_data[RTC_HOURS as usize]);
+        let day = self.reg_to_bin(self.cmos_data[RTC_DAY_OF_MONTH as usize]);
+        let mon = self.reg_to_bin(self.cmos_data[RTC_MONTH as usize]);
+        let year = self.reg_to_bin(self.cmos_data[RTC_YEAR as usize])
+            + self.reg_to_bin(self.cmos_data[RTC_CENTURY_BCD as usize]) * 100;
 
         // Check rtc time is valid to prevent tick_offset overflow.
         if year < 1970 || !(1..=12).contains(&mon) || !(1..=31).contains(&day) {
@@ -348,11 +502,130 @@ impl RTC {
         self.tick_offset = mktime64(year, mon, day, hour, min, sec);
 
         self.base_time = Instant::now();
+        // Setting the time doesn't end an update.
+        self.last_update = self.get_current_value();
+        self.arm_timer();
     }
 
     fn update_in_progress(&self) -> bool {
         self.base_time.elapsed().subsec_nanos() >= (NANOSECONDS_PER_SECOND - UIP_HOLD_LENGTH) as u32
     }
+
+    /// Get the period of the periodic interrupt selected in Register-A, `None` if it's off.
+    fn periodic_period(&self) -> Option<Duration> {
+        let mut rate = self.cmos_data[RTC_REG_A as usize] & REG_A_RATE;
+        if rate == 0 {
+            return None;
+        }
+        // Rate 1 and 2 are the same as rate 8 and 9 with 32.768KHz time base.
+        if rate <= 2 {
+            rate += 7;
+        }
+        // The frequency is (32768 >> (rate - 1)) Hz.
+        Some(Duration::from_nanos(
+            (NANOSECONDS_PER_SECOND << (rate - 1)) / 32768,
+        ))
+    }
+
+    fn alarm_matches(&self, tm: &libc::tm) -> bool {
+        let field_matches = |index: u8, value: u8| {
+            let alarm = self.cmos_data[index as usize];
+            alarm & ALARM_DONT_CARE == ALARM_DONT_CARE || alarm == value
+        };
+        field_matches(RTC_SECONDS_ALARM, self.bin_to_reg(tm.tm_sec as u8))
+            && field_matches(RTC_MINUTES_ALARM, self.bin_to_reg(tm.tm_min as u8))
+            && field_matches(RTC_HOURS_ALARM, self.hours_to_reg(tm.tm_hour as u8))
+    }
+
+    /// Set the flags in Register-C for the periodic, update-ended and alarm events
+    /// since the last check, and inject the interrupt if any of them is enabled.
+    fn update_flags(&mut self) {
+        let now = Instant::now();
+        match self.periodic_period() {
+            Some(period) => {
+                let deadline = *self.periodic_deadline.get_or_insert(now + period);
+                if now >= deadline {
+                    self.cmos_data[RTC_REG_C as usize] |= REG_C_PF;
+                    // The missed periodic interrupts are not reinjected.
+                    let missed = (now - deadline).as_nanos() / period.as_nanos() + 1;
+                    let delay = Duration::from_nanos((period.as_nanos() * missed) as u64);
+                    self.periodic_deadline = Some(deadline + delay);
+                }
+            }
+            None => self.periodic_deadline = None,
+        }
+
+        let current = self.get_current_value();
+        if current != self.last_update {
+            self.cmos_data[RTC_REG_C as usize] |= REG_C_UF;
+            if self.alarm_matches(&rtc_time_to_tm(current)) {
+                self.cmos_data[RTC_REG_C as usize] |= REG_C_AF;
+            }
+            self.last_update = current;
+        }
+
+        let reg_c = self.cmos_data[RTC_REG_C as usize];
+        if reg_c & REG_C_IRQF == 0 && reg_c & self.cmos_data[RTC_REG_B as usize] & REG_C_MASK != 0 {
+            self.cmos_data[RTC_REG_C as usize] |= REG_C_IRQF;
+            self.inject_interrupt();
+        }
+    }
+
+    /// Get the time until the next event whose interrupt is enabled.
+    fn next_deadline(&self) -> Option<Duration> {
+        let reg_b = self.cmos_data[RTC_REG_B as usize];
+        let mut deadline = None;
+        if reg_b & REG_B_PIE != 0 {
+            if let Some(periodic) = self.periodic_deadline {
+                deadline = Some(periodic.saturating_duration_since(Instant::now()));
+            }
+        }
+        if reg_b & (REG_B_AIE | REG_B_UIE) != 0 {
+            let next_second = Duration::from_nanos(
+                NANOSECONDS_PER_SECOND - self.base_time.elapsed().subsec_nanos() as u64,
+            );
+            deadline = Some(deadline.map_or(next_second, |d| cmp::min(d, next_second)));
+        }
+        deadline
+    }
+
+    /// Arm the timer for the next enabled event, the timer armed before is cancelled.
+    fn arm_timer(&mut self) {
+        self.timer_gen = self.timer_gen.wrapping_add(1);
+        // The timer can't be armed until the device is realized.
+        if self.self_weak.strong_count() == 0 {
+            return;
+        }
+        let delay = match self.next_deadline() {
+            Some(delay) => delay,
+            None => return,
+        };
+        let ctx = match EventLoop::get_ctx(None) {
+            Some(ctx) => ctx,
+            None => {
+                error!("cmos rtc: main loop is not available for the timer.");
+                return;
+            }
+        };
+
+        let rtc = self.self_weak.clone();
+        let gen = self.timer_gen;
+        ctx.delay_call(
+            Box::new(move || rtc_timer_expired(&rtc, gen)),
+            delay.as_nanos() as u64,
+        );
+    }
+}
+
+fn rtc_timer_expired(rtc: &Weak<Mutex<RTC>>, gen: u64) {
+    if let Some(rtc) = rtc.upgrade() {
+        let mut locked_rtc = rtc.lock().unwrap();
+        if locked_rtc.timer_gen != gen {
+            return;
+        }
+        locked_rtc.update_flags();
+        locked_rtc.arm_timer();
+    }
 }
 
 impl SysBusDevOps for RTC {
@@ -382,6 +655,15 @@ impl SysBusDevOps for RTC {
         self.interrupt_evt.as_ref()
     }
 
+    fn set_irq(&mut self, _sysbus: &mut SysBus) -> sysbus::Result<i32> {
+        let mut irq: i32 = -1;
+        if let Some(e) = self.interrupt_evt() {
+            irq = RTC_IRQ;
+            KVM_FDS.load().register_irqfd(e, irq as u32)?;
+        }
+        Ok(irq)
+    }
+
     fn get_sys_resource(&mut self) -> Option<&mut SysRes> {
         Some(&mut self.res)
     }
@@ -394,6 +676,8 @@ impl SysBusDevOps for RTC {
         self.cmos_data.fill(0);
         self.init_rtc_reg();
         self.set_memory(self.mem_size, self.gap_start);
+        self.periodic_deadline = None;
+        self.arm_timer();
         Ok(())
     }
 }
@@ -442,7 +726,7 @@ mod test {
 
     #[test]
     fn test_set_year_20xx() -> Result<()> {
-        let mut rtc = RTC::new().with_context(|| "Failed to create RTC device")?;
+        let mut rtc = RTC::new(RtcBase::Utc).with_context(|| "Failed to create RTC device")?;
         // Set rtc time: 2013-11-13 02:04:56
         cmos_write(&mut rtc, RTC_CENTURY_BCD, 0x20);
         cmos_write(&mut rtc, RTC_YEAR, 0x13);
@@ -476,7 +760,7 @@ mod test {
 
     #[test]
     fn test_set_year_1970() -> Result<()> {
-        let mut rtc = RTC::new().with_context(|| "Failed to create RTC device")?;
+        let mut rtc = RTC::new(RtcBase::Utc).with_context(|| "Failed to create RTC device")?;
         // Set rtc time (min): 1970-01-01 00:00:00
         cmos_write(&mut rtc, RTC_CENTURY_BCD, 0x19);
         cmos_write(&mut rtc, RTC_YEAR, 0x70);
@@ -499,7 +783,7 @@ mod test {
 
     #[test]
     fn test_invalid_rtc_time() -> Result<()> {
-        let mut rtc = RTC::new().with_context(|| "Failed to create RTC device")?;
+        let mut rtc = RTC::new(RtcBase::Utc).with_context(|| "Failed to create RTC device")?;
         // Set rtc year: 1969
         cmos_write(&mut rtc, RTC_CENTURY_BCD, 0x19);
         cmos_write(&mut rtc, RTC_YEAR, 0x69);
@@ -527,4 +811,140 @@ mod test {
 
         Ok(())
     }
+
+    #[test]
+    fn test_binary_and_12h_mode() -> Result<()> {
+        let mut rtc = RTC::new(RtcBase::Utc).with_context(|| "Failed to create RTC device")?;
+        // Set rtc time in BCD and 24-hour mode: 2013-11-13 14:04:56
+        cmos_write(&mut rtc, RTC_CENTURY_BCD, 0x20);
+        cmos_write(&mut rtc, RTC_YEAR, 0x13);
+        cmos_write(&mut rtc, RTC_MONTH, 0x11);
+        cmos_write(&mut rtc, RTC_DAY_OF_MONTH, 0x13);
+        cmos_write(&mut rtc, RTC_HOURS, 0x14);
+        cmos_write(&mut rtc, RTC_MINUTES, 0x04);
+        cmos_write(&mut rtc, RTC_SECONDS, 0x00);
+
+        // Binary and 24-hour mode.
+        cmos_write(&mut rtc, RTC_REG_B, REG_B_DM_BINARY | REG_B_24H);
+        assert!(cmos_read(&mut rtc, RTC_SECONDS) <= WIGGLE);
+        assert_eq!(cmos_read(&mut rtc, RTC_MINUTES), 4);
+        assert_eq!(cmos_read(&mut rtc, RTC_HOURS), 14);
+        assert_eq!(cmos_read(&mut rtc, RTC_DAY_OF_MONTH), 13);
+        assert_eq!(cmos_read(&mut rtc, RTC_MONTH), 11);
+        assert_eq!(cmos_read(&mut rtc, RTC_YEAR), 13);
+        assert_eq!(cmos_read(&mut rtc, RTC_CENTURY_BCD), 20);
+
+        // The time is set in binary, and BCD coded value like 0x59 is out of range.
+        cmos_write(&mut rtc, RTC_MINUTES, 45);
+        assert_eq!(cmos_read(&mut rtc, RTC_MINUTES), 45);
+        cmos_write(&mut rtc, RTC_MINUTES, 0x59);
+        assert_eq!(cmos_read(&mut rtc, RTC_MINUTES), 45);
+
+        // Binary and 12-hour mode, the PM bit is set for 14 o'clock.
+        cmos_write(&mut rtc, RTC_REG_B, REG_B_DM_BINARY);
+        assert_eq!(cmos_read(&mut rtc, RTC_HOURS), HOURS_PM | 2);
+
+        // BCD and 12-hour mode.
+        cmos_write(&mut rtc, RTC_REG_B, 0);
+        assert_eq!(cmos_read(&mut rtc, RTC_HOURS), HOURS_PM | 0x02);
+        // 12 AM is 0 o'clock, and 0 is invalid in 12-hour mode.
+        cmos_write(&mut rtc, RTC_HOURS, 0x12);
+        assert_eq!(cmos_read(&mut rtc, RTC_HOURS), 0x12);
+        cmos_write(&mut rtc, RTC_HOURS, HOURS_PM);
+        assert_eq!(cmos_read(&mut rtc, RTC_HOURS), 0x12);
+        cmos_write(&mut rtc, RTC_HOURS, HOURS_PM | 0x11);
+
+        // Back to BCD and 24-hour mode, 11 PM is 23 o'clock.
+        cmos_write(&mut rtc, RTC_REG_B, REG_B_24H);
+        assert_eq!(cmos_read(&mut rtc, RTC_HOURS), 0x23);
+        assert_eq!(cmos_read(&mut rtc, RTC_MINUTES), 0x45);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_periodic_rate() -> Result<()> {
+        let mut rtc = RTC::new(RtcBase::Utc).with_context(|| "Failed to create RTC device")?;
+        // 1024Hz by default.
+        assert_eq!(rtc.periodic_period(), Some(Duration::from_nanos(976_562)));
+        cmos_write(&mut rtc, RTC_REG_A, 0x23);
+        assert_eq!(rtc.periodic_period(), Some(Duration::from_nanos(122_070)));
+        // Rate 1 is the same as rate 8, 256Hz.
+        cmos_write(&mut rtc, RTC_REG_A, 0x21);
+        assert_eq!(rtc.periodic_period(), Some(Duration::from_nanos(3_906_250)));
+        cmos_write(&mut rtc, RTC_REG_A, 0x20);
+        assert_eq!(rtc.periodic_period(), None);
+
+        // PF is set without interrupt if PIE is not set.
+        cmos_write(&mut rtc, RTC_REG_A, 0x23);
+        std::thread::sleep(Duration::from_millis(1));
+        let reg_c = cmos_read(&mut rtc, RTC_REG_C);
+        assert_eq!(reg_c & (REG_C_IRQF | REG_C_PF), REG_C_PF);
+        assert!(rtc.interrupt_evt.as_ref().unwrap().read().is_err());
+
+        // The interrupt is injected if PIE is set.
+        cmos_write(&mut rtc, RTC_REG_B, REG_B_24H | REG_B_PIE);
+        std::thread::sleep(Duration::from_millis(1));
+        let reg_c = cmos_read(&mut rtc, RTC_REG_C);
+        assert_eq!(reg_c & (REG_C_IRQF | REG_C_PF), REG_C_IRQF | REG_C_PF);
+        assert_eq!(rtc.interrupt_evt.as_ref().unwrap().read().unwrap(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_alarm_and_update_interrupt() -> Result<()> {
+        let mut rtc = RTC::new(RtcBase::Utc).with_context(|| "Failed to create RTC device")?;
+        // Disable periodic interrupt.
+        cmos_write(&mut rtc, RTC_REG_A, 0x20);
+        // The alarm fires every second.
+        cmos_write(&mut rtc, RTC_SECONDS_ALARM, ALARM_DONT_CARE);
+        cmos_write(&mut rtc, RTC_MINUTES_ALARM, ALARM_DONT_CARE);
+        cmos_write(&mut rtc, RTC_HOURS_ALARM, ALARM_DONT_CARE);
+        cmos_write(&mut rtc, RTC_REG_B, REG_B_24H | REG_B_AIE);
+        cmos_read(&mut rtc, RTC_REG_C);
+        let _ = rtc.interrupt_evt.as_ref().unwrap().read();
+
+        // Pretend that a second has passed.
+        rtc.last_update -= 1;
+        assert_eq!(
+            cmos_read(&mut rtc, RTC_REG_C),
+            REG_C_IRQF | REG_C_AF | REG_C_UF
+        );
+        assert_eq!(rtc.interrupt_evt.as_ref().unwrap().read().unwrap(), 1);
+
+        // The alarm doesn't match, only UF is set which is not enabled.
+        let current = rtc_time_to_tm(rtc.get_current_value());
+        let hours = ((current.tm_hour + 1) % 24) as u8;
+        cmos_write(&mut rtc, RTC_HOURS_ALARM, bin_to_bcd(hours));
+        rtc.last_update -= 1;
+        assert_eq!(cmos_read(&mut rtc, RTC_REG_C), REG_C_UF);
+        assert!(rtc.interrupt_evt.as_ref().unwrap().read().is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_memory_size() -> Result<()> {
+        let mut rtc = RTC::new(RtcBase::Utc).with_context(|| "Failed to create RTC device")?;
+        // 4GiB memory with 3GiB below 4GiB.
+        rtc.set_memory(4 << 30, 3 << 30);
+        assert_eq!(cmos_read(&mut rtc, CMOS_BASE_MEM.0), 0x80);
+        assert_eq!(cmos_read(&mut rtc, CMOS_BASE_MEM.1), 0x02);
+        assert_eq!(cmos_read(&mut rtc, CMOS_EXT_MEM.0), 0x00);
+        assert_eq!(cmos_read(&mut rtc, CMOS_EXT_MEM.1), 0xFC);
+        // (3GiB - 16MiB) / 64KiB = 0xBF00.
+        assert_eq!(cmos_read(&mut rtc, CMOS_MEM_BELOW_4GB.0), 0x00);
+        assert_eq!(cmos_read(&mut rtc, CMOS_MEM_BELOW_4GB.1), 0xBF);
+        // 1GiB / 64KiB = 0x4000.
+        assert_eq!(cmos_read(&mut rtc, CMOS_MEM_ABOVE_4GB.0), 0x00);
+        assert_eq!(cmos_read(&mut rtc, CMOS_MEM_ABOVE_4GB.1), 0x40);
+        assert_eq!(cmos_read(&mut rtc, CMOS_MEM_ABOVE_4GB.2), 0x00);
+
+        // Register-C and Register-D are read-only.
+        cmos_write(&mut rtc, RTC_REG_D, 0);
+        assert_eq!(cmos_read(&mut rtc, RTC_REG_D), 0x80);
+
+        Ok(())
+    }
 }
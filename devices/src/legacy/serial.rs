@@ -34,7 +34,7 @@ use util::byte_code::ByteCode;
 use util::loop_context::EventNotifierHelper;
 use vmm_sys_util::eventfd::EventFd;
 
-use super::chardev::{Chardev, InputReceiver};
+use super::chardev::{register_chardev, Chardev, InputReceiver};
 use super::error::LegacyError;
 use anyhow::{anyhow, bail, Context, Result};
 pub const SERIAL_ADDR: u64 = 0x3f8;
@@ -165,7 +165,11 @@ impl Serial {
             value: format!("uart,mmio,0x{:08x}", region_base),
         });
         let locked_dev = dev.lock().unwrap();
-        locked_dev.chardev.lock().unwrap().set_input_callback(&dev);
+        register_chardev(&locked_dev.chardev);
+        let mut locked_chardev = locked_dev.chardev.lock().unwrap();
+        locked_chardev.enable_stdio_mux();
+        locked_chardev.set_input_callback(&dev);
+        drop(locked_chardev);
         EventLoop::update_event(
             EventNotifierHelper::internal_notifiers(locked_dev.chardev.clone()),
             None,
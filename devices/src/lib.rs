@@ -13,12 +13,14 @@
 //! Interfaces for simulating various devices.
 //!
 //! This crate simulates:
-//! - interrupt controller (aarch64)
+//! - interrupt controller (aarch64 GIC, x86_64 IOAPIC)
 //! - legacy devices, such as serial devices
+//! - SMBIOS tables passed to firmware
 
 pub mod acpi;
 mod interrupt_controller;
 pub mod legacy;
+pub mod smbios;
 pub mod usb;
 
 #[cfg(target_arch = "aarch64")]
@@ -26,4 +28,6 @@ pub use interrupt_controller::{
     ICGICConfig, ICGICv2Config, ICGICv3Config, InterruptController, InterruptError as IntCtrlErrs,
     GIC_IRQ_MAX,
 };
+#[cfg(target_arch = "x86_64")]
+pub use interrupt_controller::{IoApic, IOAPIC_NUM_PINS};
 pub use legacy::error::LegacyError as LegacyErrs;
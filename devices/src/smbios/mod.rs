@@ -0,0 +1,657 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! SMBIOS tables according to [`SMBIOS 3.0`](https://www.dmtf.org/standards/smbios).
+//!
+//! The tables and the 64-bit entry point are passed to firmware by fw_cfg files
+//! `etc/smbios/smbios-tables` and `etc/smbios/smbios-anchor`, the firmware copies them to
+//! guest memory and fills the address of the tables in the entry point.
+
+use anyhow::Result;
+use machine_manager::config::{parse_uuid, SmbiosConfig};
+
+pub const SMBIOS_TABLE_FILE: &str = "etc/smbios/smbios-tables";
+pub const SMBIOS_ANCHOR_FILE: &str = "etc/smbios/smbios-anchor";
+
+const SMBIOS_MANUFACTURER: &str = "StratoVirt";
+const SMBIOS_PRODUCT: &str = "Virtual Machine";
+const SMBIOS_VERSION: Option<&str> = option_env!("CARGO_PKG_VERSION");
+
+/// Memory is described by memory devices of 16GiB at most.
+const MAX_DIMM_SIZE: u64 = 16 << 30;
+/// Speed of processor in MHz.
+const PROCESSOR_SPEED: u16 = 2000;
+/// The handle isn't provided.
+const HANDLE_NOT_PROVIDED: u16 = 0xFFFE;
+/// There is no such structure.
+const HANDLE_NONE: u16 = 0xFFFF;
+
+// Structures are placed at handle `type << 8` and the following ones.
+const TYPE0_HANDLE: u16 = 0x0000;
+const TYPE1_HANDLE: u16 = 0x0100;
+const TYPE2_HANDLE: u16 = 0x0200;
+const TYPE3_HANDLE: u16 = 0x0300;
+const TYPE4_HANDLE: u16 = 0x0400;
+const TYPE16_HANDLE: u16 = 0x1000;
+const TYPE17_HANDLE: u16 = 0x1100;
+const TYPE19_HANDLE: u16 = 0x1300;
+const TYPE32_HANDLE: u16 = 0x2000;
+const TYPE127_HANDLE: u16 = 0x7F00;
+
+/// A structure is the formatted area started with the header, followed by the string set.
+struct SmbiosStructure {
+    formatted: Vec<u8>,
+    strings: Vec<String>,
+}
+
+impl SmbiosStructure {
+    fn new(type_: u8, handle: u16) -> Self {
+        let mut formatted = vec![type_, 0];
+        formatted.extend_from_slice(&handle.to_le_bytes());
+        SmbiosStructure {
+            formatted,
+            strings: Vec::new(),
+        }
+    }
+
+    fn add_u8(&mut self, value: u8) -> &mut Self {
+        self.formatted.push(value);
+        self
+    }
+
+    fn add_u16(&mut self, value: u16) -> &mut Self {
+        self.formatted.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn add_u32(&mut self, value: u32) -> &mut Self {
+        self.formatted.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn add_u64(&mut self, value: u64) -> &mut Self {
+        self.formatted.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn add_bytes(&mut self, value: &[u8]) -> &mut Self {
+        self.formatted.extend_from_slice(value);
+        self
+    }
+
+    /// Add the number of the string in the string set, 0 means the string isn't set.
+    fn add_string(&mut self, value: Option<&str>) -> &mut Self {
+        match value {
+            Some(s) if !s.is_empty() => {
+                self.strings.push(s.to_string());
+                let index = self.strings.len() as u8;
+                self.add_u8(index)
+            }
+            _ => self.add_u8(0),
+        }
+    }
+
+    /// Each string of the string set is terminated by nul, and the string set is terminated
+    /// by an additional nul. The string set is two nul if there is no string.
+    fn to_vec(&self) -> Vec<u8> {
+        let mut bytes = self.formatted.clone();
+        bytes[1] = self.formatted.len() as u8;
+        for s in self.strings.iter() {
+            bytes.extend_from_slice(s.as_bytes());
+            bytes.push(0);
+        }
+        if self.strings.is_empty() {
+            bytes.push(0);
+        }
+        bytes.push(0);
+        bytes
+    }
+}
+
+/// Layout of the vCPUs, which are described as one processor per socket.
+#[derive(Debug, Clone, Copy)]
+pub struct SmbiosCpuTopology {
+    pub sockets: u16,
+    /// Number of cores in one socket.
+    pub cores: u16,
+    /// Number of threads in one core.
+    pub threads: u16,
+}
+
+#[derive(Default)]
+struct SmbiosTables {
+    data: Vec<u8>,
+    max_structure_size: usize,
+}
+
+impl SmbiosTables {
+    fn push(&mut self, structure: &SmbiosStructure) {
+        let bytes = structure.to_vec();
+        self.max_structure_size = std::cmp::max(self.max_structure_size, bytes.len());
+        self.data.extend(bytes);
+    }
+
+    /// Type 0: BIOS Information.
+    fn build_type0(&mut self) {
+        let mut table = SmbiosStructure::new(0, TYPE0_HANDLE);
+        table
+            .add_string(Some(SMBIOS_MANUFACTURER))
+            .add_string(SMBIOS_VERSION)
+            // BIOS starting address segment.
+            .add_u16(0xE800)
+            // BIOS release date.
+            .add_string(None)
+            // BIOS ROM size.
+            .add_u8(0)
+            // BIOS characteristics are not supported.
+            .add_u64(0x08)
+            // Targeted content distribution is enabled, and it's a virtual machine.
+            .add_bytes(&[0, 0x14])
+            // System BIOS major and minor release.
+            .add_bytes(&[0, 0])
+            // Embedded controller firmware major and minor release are unknown.
+            .add_bytes(&[0xFF, 0xFF]);
+        self.push(&table);
+    }
+
+    /// Type 1: System Information.
+    fn build_type1(&mut self, config: &SmbiosConfig, vm_uuid: Option<&str>) -> Result<()> {
+        let type1 = &config.type1;
+        // The first three fields of uuid are little endian in SMBIOS.
+        let mut uuid = [0_u8; 16];
+        if let Some(s) = type1.uuid.as_deref().or(vm_uuid) {
+            uuid = parse_uuid(s)?;
+            uuid[0..4].reverse();
+            uuid[4..6].reverse();
+            uuid[6..8].reverse();
+        }
+
+        let mut table = SmbiosStructure::new(1, TYPE1_HANDLE);
+        table
+            .add_string(Some(
+                type1.manufacturer.as_deref().unwrap_or(SMBIOS_MANUFACTURER),
+            ))
+            .add_string(Some(type1.product.as_deref().unwrap_or(SMBIOS_PRODUCT)))
+            .add_string(type1.version.as_deref().or(SMBIOS_VERSION))
+            .add_string(type1.serial.as_deref())
+            .add_bytes(&uuid)
+            // Wake-up type: power switch.
+            .add_u8(0x06)
+            .add_string(type1.sku.as_deref())
+            .add_string(type1.family.as_deref());
+        self.push(&table);
+        Ok(())
+    }
+
+    /// Type 2: Baseboard Information.
+    fn build_type2(&mut self) {
+        let mut table = SmbiosStructure::new(2, TYPE2_HANDLE);
+        table
+            .add_string(Some(SMBIOS_MANUFACTURER))
+            .add_string(Some(SMBIOS_PRODUCT))
+            .add_string(SMBIOS_VERSION)
+            // Serial number and asset tag.
+            .add_string(None)
+            .add_string(None)
+            // Feature flags: it's a hosting board.
+            .add_u8(0x01)
+            // Location in chassis.
+            .add_string(None)
+            .add_u16(TYPE3_HANDLE)
+            // Board type: motherboard.
+            .add_u8(0x0A)
+            // No contained object handles.
+            .add_u8(0);
+        self.push(&table);
+    }
+
+    /// Type 3: System Enclosure or Chassis.
+    fn build_type3(&mut self) {
+        let mut table = SmbiosStructure::new(3, TYPE3_HANDLE);
+        table
+            .add_string(Some(SMBIOS_MANUFACTURER))
+            // Chassis type: other.
+            .add_u8(0x01)
+            .add_string(SMBIOS_VERSION)
+            // Serial number and asset tag.
+            .add_string(None)
+            .add_string(None)
+            // Boot-up, power supply and thermal states are safe.
+            .add_bytes(&[0x03, 0x03, 0x03])
+            // Security status: unknown.
+            .add_u8(0x02)
+            // OEM-defined.
+            .add_u32(0)
+            // Height, number of power cords, contained element count and record length.
+            .add_bytes(&[0, 0, 0, 0])
+            // SKU number.
+            .add_string(None);
+        self.push(&table);
+    }
+
+    /// Type 4: Processor Information, one for each socket.
+    fn build_type4(&mut self, topology: &SmbiosCpuTopology) {
+        let cores = topology.cores;
+        let threads = topology.cores.saturating_mul(topology.threads);
+        // The 8-bit counts are 0xFF and the 16-bit counts are used if there are more than 255.
+        let count8 = |count: u16| if count > 0xFF { 0xFF } else { count as u8 };
+        for socket in 0..topology.sockets {
+            let designation = format!("CPU {}", socket);
+            let mut table = SmbiosStructure::new(4, TYPE4_HANDLE + socket);
+            table
+                .add_string(Some(&designation))
+                // Processor type: central processor.
+                .add_u8(0x03)
+                // Processor family: other.
+                .add_u8(0x01)
+                .add_string(Some(SMBIOS_MANUFACTURER))
+                // Processor ID.
+                .add_u64(0)
+                .add_string(None)
+                // Voltage and external clock are unknown.
+                .add_u8(0)
+                .add_u16(0)
+                // Max speed and current speed.
+                .add_u16(PROCESSOR_SPEED)
+                .add_u16(PROCESSOR_SPEED)
+                // Status: socket populated, CPU enabled.
+                .add_u8(0x41)
+                // Processor upgrade: other.
+                .add_u8(0x01)
+                // No L1, L2 and L3 cache information.
+                .add_u16(HANDLE_NONE)
+                .add_u16(HANDLE_NONE)
+                .add_u16(HANDLE_NONE)
+                // Serial number, asset tag and part number.
+                .add_string(None)
+                .add_string(None)
+                .add_string(None)
+                // Core count, core enabled and thread count.
+                .add_u8(count8(cores))
+                .add_u8(count8(cores))
+                .add_u8(count8(threads))
+                // Processor characteristics: 64-bit capable.
+                .add_u16(0x04)
+                // Processor family 2: other.
+                .add_u16(0x01)
+                .add_u16(cores)
+                .add_u16(cores)
+                .add_u16(threads);
+            self.push(&table);
+        }
+    }
+
+    /// Type 16: Physical Memory Array.
+    fn build_type16(&mut self, mem_size: u64, dimm_num: u16) {
+        let size_kb = mem_size >> 10;
+        // The size is in the extended maximum capacity if it's not less than 2TiB.
+        let (capacity, extended_capacity) = if size_kb < 0x8000_0000 {
+            (size_kb as u32, 0)
+        } else {
+            (0x8000_0000, mem_size)
+        };
+        let mut table = SmbiosStructure::new(16, TYPE16_HANDLE);
+        table
+            // Location: other.
+            .add_u8(0x01)
+            // Use: system memory.
+            .add_u8(0x03)
+            // Memory error correction: multi-bit ECC.
+            .add_u8(0x06)
+            .add_u32(capacity)
+            .add_u16(HANDLE_NOT_PROVIDED)
+            .add_u16(dimm_num)
+            .add_u64(extended_capacity);
+        self.push(&table);
+    }
+
+    /// Type 17: Memory Device.
+    fn build_type17(&mut self, index: u16, size: u64) {
+        let size_mb = size >> 20;
+        // The size is in the extended size if it's not less than 32GiB - 1MiB.
+        let (size_field, extended_size) = if size_mb < 0x7FFF {
+            (size_mb as u16, 0)
+        } else {
+            (0x7FFF, size_mb as u32)
+        };
+        let locator = format!("DIMM {}", index);
+        let mut table = SmbiosStructure::new(17, TYPE17_HANDLE + index);
+        table
+            .add_u16(TYPE16_HANDLE)
+            .add_u16(HANDLE_NOT_PROVIDED)
+            // Total width and data width are unknown.
+            .add_u16(0xFFFF)
+            .add_u16(0xFFFF)
+            .add_u16(size_field)
+            // Form factor: DIMM.
+            .add_u8(0x09)
+            // Device set.
+            .add_u8(0)
+            .add_string(Some(&locator))
+            // Bank locator.
+            .add_string(None)
+            // Memory type: RAM.
+            .add_u8(0x07)
+            // Type detail: other.
+            .add_u16(0x02)
+            // Speed is unknown.
+            .add_u16(0)
+            .add_string(Some(SMBIOS_MANUFACTURER))
+            // Serial number, asset tag and part number.
+            .add_string(None)
+            .add_string(None)
+            .add_string(None)
+            // Attributes.
+            .add_u8(0)
+            .add_u32(extended_size)
+            // Configured memory speed, minimum, maximum and configured voltage are unknown.
+            .add_u16(0)
+            .add_u16(0)
+            .add_u16(0)
+            .add_u16(0);
+        self.push(&table);
+    }
+
+    /// Type 19: Memory Array Mapped Address, one for each range of guest ram.
+    fn build_type19(&mut self, index: u16, start: u64, size: u64) {
+        let start_kb = start >> 10;
+        let end_kb = ((start + size) >> 10) - 1;
+        // The addresses are in the extended addresses if the range isn't below 4TiB.
+        let (start_field, end_field, extended_start, extended_end) = if end_kb < 0xFFFF_FFFF {
+            (start_kb as u32, end_kb as u32, 0, 0)
+        } else {
+            (0xFFFF_FFFF, 0xFFFF_FFFF, start, start + size - 1)
+        };
+        let mut table = SmbiosStructure::new(19, TYPE19_HANDLE + index);
+        table
+            .add_u32(start_field)
+            .add_u32(end_field)
+            .add_u16(TYPE16_HANDLE)
+            // Partition width.
+            .add_u8(1)
+            .add_u64(extended_start)
+            .add_u64(extended_end);
+        self.push(&table);
+    }
+
+    /// Type 32: System Boot Information.
+    fn build_type32(&mut self) {
+        let mut table = SmbiosStructure::new(32, TYPE32_HANDLE);
+        table
+            // Reserved.
+            .add_bytes(&[0; 6])
+            // Boot status: no errors detected.
+            .add_u8(0);
+        self.push(&table);
+    }
+
+    /// Type 127: End-of-Table.
+    fn build_type127(&mut self) {
+        self.push(&SmbiosStructure::new(127, TYPE127_HANDLE));
+    }
+}
+
+/// Build the SMBIOS 3.0 (64-bit) entry point for the tables.
+fn build_entry_point(tables_len: usize) -> Vec<u8> {
+    let mut ep = Vec::new();
+    ep.extend_from_slice(b"_SM3_");
+    // Checksum.
+    ep.push(0);
+    // Entry point length.
+    ep.push(0x18);
+    // SMBIOS major version, minor version and docrev.
+    ep.extend_from_slice(&[3, 0, 0]);
+    // Entry point revision, and reserved.
+    ep.extend_from_slice(&[0x01, 0]);
+    // Structure table maximum size.
+    ep.extend_from_slice(&(tables_len as u32).to_le_bytes());
+    // Structure table address, which is filled by firmware.
+    ep.extend_from_slice(&0_u64.to_le_bytes());
+
+    let sum = ep.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b));
+    ep[5] = 0_u8.wrapping_sub(sum);
+    ep
+}
+
+/// Build SMBIOS tables, return the tables and the entry point.
+///
+/// # Arguments
+///
+/// * `config` - Overrides of the tables set by `-smbios`.
+/// * `vm_uuid` - Uuid of the VM, which is used if type 1 doesn't set it.
+/// * `topology` - Layout of the vCPUs.
+/// * `ram_ranges` - Ranges of guest ram, (start, size).
+pub fn build_smbios_tables(
+    config: &SmbiosConfig,
+    vm_uuid: Option<&str>,
+    topology: &SmbiosCpuTopology,
+    ram_ranges: &[(u64, u64)],
+) -> Result<(Vec<u8>, Vec<u8>)> {
+    let mut tables = SmbiosTables::default();
+    tables.build_type0();
+    tables.build_type1(config, vm_uuid)?;
+    tables.build_type2();
+    tables.build_type3();
+    tables.build_type4(topology);
+
+    let mem_size: u64 = ram_ranges.iter().map(|(_, size)| size).sum();
+    let dimm_num = ((mem_size + MAX_DIMM_SIZE - 1) / MAX_DIMM_SIZE) as u16;
+    tables.build_type16(mem_size, dimm_num);
+    for index in 0..dimm_num {
+        let size = std::cmp::min(MAX_DIMM_SIZE, mem_size - index as u64 * MAX_DIMM_SIZE);
+        tables.build_type17(index, size);
+    }
+    for (index, (start, size)) in ram_ranges.iter().filter(|(_, size)| *size > 0).enumerate() {
+        tables.build_type19(index as u16, *start, *size);
+    }
+    tables.build_type32();
+    tables.build_type127();
+
+    let ep = build_entry_point(tables.data.len());
+    Ok((tables.data, ep))
+}
+
+#[cfg(test)]
+mod test {
+    use machine_manager::config::SmbiosType1Config;
+
+    use super::*;
+
+    /// A structure parsed from the tables.
+    struct ParsedStructure {
+        type_: u8,
+        handle: u16,
+        formatted: Vec<u8>,
+        strings: Vec<String>,
+    }
+
+    impl ParsedStructure {
+        fn string(&self, offset: usize) -> Option<&str> {
+            match self.formatted[offset] {
+                0 => None,
+                n => Some(&self.strings[n as usize - 1]),
+            }
+        }
+
+        fn u16(&self, offset: usize) -> u16 {
+            u16::from_le_bytes(self.formatted[offset..offset + 2].try_into().unwrap())
+        }
+
+        fn u32(&self, offset: usize) -> u32 {
+            u32::from_le_bytes(self.formatted[offset..offset + 4].try_into().unwrap())
+        }
+    }
+
+    fn parse_tables(mut data: &[u8]) -> Vec<ParsedStructure> {
+        let mut structures = Vec::new();
+        while !data.is_empty() {
+            let len = data[1] as usize;
+            assert!(len >= 4);
+            let formatted = data[..len].to_vec();
+            let mut strings = Vec::new();
+            let mut pos = len;
+            // Two nul end the string set.
+            if data[pos] == 0 {
+                assert_eq!(data[pos + 1], 0);
+                pos += 2;
+            } else {
+                while data[pos] != 0 {
+                    let end = pos + data[pos..].iter().position(|b| *b == 0).unwrap();
+                    strings.push(String::from_utf8(data[pos..end].to_vec()).unwrap());
+                    pos = end + 1;
+                }
+                pos += 1;
+            }
+            structures.push(ParsedStructure {
+                type_: formatted[0],
+                handle: u16::from_le_bytes([formatted[2], formatted[3]]),
+                formatted,
+                strings,
+            });
+            data = &data[pos..];
+        }
+        structures
+    }
+
+    #[test]
+    fn test_smbios_tables() {
+        let topology = SmbiosCpuTopology {
+            sockets: 2,
+            cores: 4,
+            threads: 2,
+        };
+        // 3GiB below the gap and 33GiB above 4GiB.
+        let ram_ranges = [(0, 3 << 30), (4 << 30, 33 << 30)];
+        let (data, ep) = build_smbios_tables(
+            &SmbiosConfig::default(),
+            Some("a1b2c3d4-0000-1111-2222-333344445555"),
+            &topology,
+            &ram_ranges,
+        )
+        .unwrap();
+
+        assert_eq!(ep.len(), 0x18);
+        assert_eq!(&ep[..5], b"_SM3_");
+        assert_eq!(ep.iter().fold(0_u8, |sum, b| sum.wrapping_add(*b)), 0);
+        assert_eq!(
+            u32::from_le_bytes(ep[12..16].try_into().unwrap()) as usize,
+            data.len()
+        );
+
+        let structures = parse_tables(&data);
+        let summary: Vec<(u8, u16, usize)> = structures
+            .iter()
+            .map(|s| (s.type_, s.handle, s.formatted.len()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (0, 0x0000, 0x18),
+                (1, 0x0100, 0x1B),
+                (2, 0x0200, 0x0F),
+                (3, 0x0300, 0x16),
+                (4, 0x0400, 0x30),
+                (4, 0x0401, 0x30),
+                (16, 0x1000, 0x17),
+                (17, 0x1100, 0x28),
+                (17, 0x1101, 0x28),
+                (17, 0x1102, 0x28),
+                (19, 0x1300, 0x1F),
+                (19, 0x1301, 0x1F),
+                (32, 0x2000, 0x0B),
+                (127, 0x7F00, 0x04),
+            ]
+        );
+
+        let type1 = &structures[1];
+        assert_eq!(type1.string(4), Some(SMBIOS_MANUFACTURER));
+        assert_eq!(type1.string(5), Some(SMBIOS_PRODUCT));
+        assert_eq!(type1.string(7), None);
+        assert_eq!(
+            type1.formatted[8..24],
+            [
+                0xd4, 0xc3, 0xb2, 0xa1, 0x00, 0x00, 0x11, 0x11, 0x22, 0x22, 0x33, 0x33, 0x44, 0x44,
+                0x55, 0x55
+            ]
+        );
+
+        let cpu1 = &structures[5];
+        assert_eq!(cpu1.string(4), Some("CPU 1"));
+        assert_eq!(cpu1.formatted[35..38], [4, 4, 8]);
+
+        // 36GiB memory in 3 memory devices.
+        let mem_array = &structures[6];
+        assert_eq!(mem_array.u32(7), 36 << 20);
+        assert_eq!(mem_array.u16(13), 3);
+        let dimm_sizes: Vec<u16> = structures[7..10].iter().map(|s| s.u16(12)).collect();
+        assert_eq!(dimm_sizes, vec![16 << 10, 16 << 10, 4 << 10]);
+        assert_eq!(structures[9].string(16), Some("DIMM 2"));
+        assert_eq!(structures[9].u16(4), 0x1000);
+
+        let high_mem = &structures[11];
+        assert_eq!(high_mem.u32(4), 4 << 20);
+        assert_eq!(high_mem.u32(8), (37 << 20) - 1);
+
+        // The string set of End-of-Table is two nul.
+        assert_eq!(&data[data.len() - 6..], &[127, 4, 0x00, 0x7F, 0, 0]);
+    }
+
+    #[test]
+    fn test_smbios_type1_override() {
+        let config = SmbiosConfig {
+            type1: SmbiosType1Config {
+                manufacturer: Some("Vendor".to_string()),
+                product: Some("Cloud VM".to_string()),
+                serial: Some("SN-0001".to_string()),
+                family: Some("virt".to_string()),
+                uuid: Some("00112233-4455-6677-8899-aabbccddeeff".to_string()),
+                ..Default::default()
+            },
+        };
+        let topology = SmbiosCpuTopology {
+            sockets: 1,
+            cores: 1,
+            threads: 1,
+        };
+        let (data, _) = build_smbios_tables(
+            &config,
+            Some("a1b2c3d4-0000-1111-2222-333344445555"),
+            &topology,
+            &[(0, 1 << 30)],
+        )
+        .unwrap();
+        let structures = parse_tables(&data);
+        let type1 = &structures[1];
+        assert_eq!(type1.type_, 1);
+        assert_eq!(type1.string(4), Some("Vendor"));
+        assert_eq!(type1.string(5), Some("Cloud VM"));
+        assert_eq!(type1.string(6), SMBIOS_VERSION);
+        assert_eq!(type1.string(7), Some("SN-0001"));
+        assert_eq!(type1.string(25), None);
+        assert_eq!(type1.string(26), Some("virt"));
+        assert_eq!(
+            type1.formatted[8..24],
+            [
+                0x33, 0x22, 0x11, 0x00, 0x55, 0x44, 0x77, 0x66, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 0xdd,
+                0xee, 0xff
+            ]
+        );
+        // The baseboard isn't overridden.
+        assert_eq!(structures[2].string(4), Some(SMBIOS_MANUFACTURER));
+
+        // Without uuid, it's all zero.
+        let (data, _) =
+            build_smbios_tables(&SmbiosConfig::default(), None, &topology, &[(0, 1 << 30)])
+                .unwrap();
+        assert_eq!(parse_tables(&data)[1].formatted[8..24], [0; 16]);
+    }
+}
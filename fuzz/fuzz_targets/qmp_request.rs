@@ -0,0 +1,25 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Feed raw bytes to the qmp request parser, as if they were sent by a qmp client.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use machine_manager::config::QmpCompatPolicy;
+use machine_manager::qmp::decode_qmp_request;
+
+fuzz_target!(|data: &[u8]| {
+    for policy in [QmpCompatPolicy::Warn, QmpCompatPolicy::Strict] {
+        let _ = decode_qmp_request(data, policy);
+    }
+});
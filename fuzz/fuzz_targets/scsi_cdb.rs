@@ -0,0 +1,61 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Parse and emulate arbitrary cdbs on an in-memory scsi device.
+//!
+//! The first byte of the input selects the device:
+//! - bit 0: the device is a disk (0) or a cdrom (1);
+//! - bit 1: the lun of the request, the device is lun 0;
+//! - bit 2: the device has a medium of 1GiB, or no medium.
+//!
+//! The following 32 bytes are the cdb.
+
+#![no_main]
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use libfuzzer_sys::fuzz_target;
+use machine_manager::config::ScsiDevConfig;
+use virtio::ScsiBus::{scsi_emulate_cdb, ScsiBus};
+use virtio::ScsiCntlr::VIRTIO_SCSI_CDB_DEFAULT_SIZE;
+use virtio::ScsiDisk::{ScsiDevice, SCSI_TYPE_DISK, SCSI_TYPE_ROM};
+
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 1 + VIRTIO_SCSI_CDB_DEFAULT_SIZE {
+        return;
+    }
+    let scsi_type = if data[0] & 0x1 == 0 {
+        SCSI_TYPE_DISK
+    } else {
+        SCSI_TYPE_ROM
+    };
+    let lun = u16::from((data[0] >> 1) & 0x1);
+
+    let bus = Arc::new(Mutex::new(ScsiBus::new("fuzz".to_string(), Weak::new())));
+    let mut dev = ScsiDevice::new(
+        ScsiDevConfig::default(),
+        scsi_type,
+        Arc::new(Mutex::new(HashMap::new())),
+    );
+    dev.realize().unwrap();
+    if data[0] & 0x4 != 0 {
+        dev.disk_sectors = 1 << 21;
+    }
+    dev.parent_bus = Arc::downgrade(&bus);
+    let dev = Arc::new(Mutex::new(dev));
+    bus.lock().unwrap().devices.insert((0, 0), dev.clone());
+
+    let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+    cdb.copy_from_slice(&data[1..=VIRTIO_SCSI_CDB_DEFAULT_SIZE]);
+    let _ = scsi_emulate_cdb(cdb, &dev, lun);
+});
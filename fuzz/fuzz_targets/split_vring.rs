@@ -0,0 +1,77 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Walk the descriptor chains of a split vring in a synthetic guest memory image.
+//!
+//! The first byte of the input is the low byte of the negotiated features, bit 0 of which
+//! stands for VIRTIO_F_RING_EVENT_IDX. The rest is the guest memory from address 0, where
+//! the vring of 256 entries is placed as:
+//! - descriptor table: 0x0;
+//! - available ring: 0x1000;
+//! - used ring: 0x2000.
+
+#![no_main]
+
+use std::sync::Arc;
+
+use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
+use libfuzzer_sys::fuzz_target;
+use virtio::{Queue, QueueConfig, QUEUE_TYPE_SPLIT_VRING, VIRTIO_F_RING_EVENT_IDX};
+
+const MEM_SIZE: u64 = 1 << 20;
+const QUEUE_SIZE: u16 = 256;
+
+fn guest_memory(image: &[u8]) -> Arc<AddressSpace> {
+    let root = Region::init_container_region(MEM_SIZE);
+    let sys_mem = AddressSpace::new(root).unwrap();
+    let host_mmap = Arc::new(
+        HostMemMapping::new(GuestAddress(0), None, MEM_SIZE, None, false, false, false).unwrap(),
+    );
+    sys_mem
+        .root()
+        .add_subregion(Region::init_ram_region(host_mmap), 0)
+        .unwrap();
+    sys_mem
+        .write(&mut &image[..], GuestAddress(0), image.len() as u64)
+        .unwrap();
+    sys_mem
+}
+
+fuzz_target!(|data: &[u8]| {
+    let (features, image) = match data.split_first() {
+        Some((features, image)) if image.len() as u64 <= MEM_SIZE => {
+            (u64::from(features & 0x1) << VIRTIO_F_RING_EVENT_IDX, image)
+        }
+        _ => return,
+    };
+    let sys_mem = guest_memory(image);
+
+    let mut config = QueueConfig::new(QUEUE_SIZE);
+    config.desc_table = GuestAddress(0);
+    config.avail_ring = GuestAddress(0x1000);
+    config.used_ring = GuestAddress(0x2000);
+    config.addr_cache.desc_table_host = sys_mem.get_host_address(config.desc_table).unwrap();
+    config.addr_cache.avail_ring_host = sys_mem.get_host_address(config.avail_ring).unwrap();
+    config.addr_cache.used_ring_host = sys_mem.get_host_address(config.used_ring).unwrap();
+    config.ready = true;
+    let mut queue = Queue::new(config, QUEUE_TYPE_SPLIT_VRING).unwrap();
+    assert!(queue.is_valid(&sys_mem));
+
+    for _ in 0..QUEUE_SIZE {
+        match queue.vring.pop_avail(&sys_mem, features) {
+            Ok(elem) if elem.desc_num != 0 => {
+                queue.vring.add_used(&sys_mem, elem.index, 0).unwrap();
+            }
+            _ => break,
+        }
+    }
+});
@@ -25,4 +25,6 @@ pub enum HypervisorError {
         #[from]
         source: kvm_ioctls::Error,
     },
+    #[error("KVM capability {0} is required, but it's not supported by the host kernel")]
+    KvmCapMissing(String),
 }
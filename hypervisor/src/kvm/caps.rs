@@ -0,0 +1,197 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, Result};
+#[cfg(target_arch = "x86_64")]
+use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+use kvm_ioctls::{Cap, Kvm};
+
+use crate::HypervisorError;
+
+/// CPUID leaf of KVM paravirtual features.
+#[cfg(target_arch = "x86_64")]
+const KVM_CPUID_FEATURES: u32 = 0x4000_0001;
+/// Bit of `KVM_CPUID_FEATURES` EAX: kvmclock is exposed by MSR 0x4b564d00/0x4b564d01.
+#[cfg(target_arch = "x86_64")]
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 3;
+
+/// Interface to check the capabilities of KVM.
+pub trait KvmCapChecker {
+    /// Check whether the capability `cap` is supported.
+    fn check_extension(&self, cap: Cap) -> bool;
+
+    /// Get the max number of vCPUs of a VM, reported by `KVM_CAP_MAX_VCPUS`.
+    fn max_vcpus(&self) -> usize;
+
+    /// Check whether kvmclock MSRs are exposed to the guest by KVM, x86 only.
+    fn kvmclock(&self) -> bool;
+}
+
+impl KvmCapChecker for Kvm {
+    fn check_extension(&self, cap: Cap) -> bool {
+        Kvm::check_extension(self, cap)
+    }
+
+    fn max_vcpus(&self) -> usize {
+        self.get_max_vcpus()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn kvmclock(&self) -> bool {
+        self.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES)
+            .map(|cpuid| {
+                cpuid.as_slice().iter().any(|entry| {
+                    entry.function == KVM_CPUID_FEATURES
+                        && entry.eax & (1 << KVM_FEATURE_CLOCKSOURCE2) != 0
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    fn kvmclock(&self) -> bool {
+        false
+    }
+}
+
+/// Capabilities of KVM probed at startup.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KvmCaps {
+    /// `KVM_CAP_IRQCHIP`: in-kernel interrupt controller.
+    pub irqchip: bool,
+    /// `KVM_CAP_PIT2`: in-kernel PIT, x86 only.
+    pub pit2: bool,
+    /// `KVM_CAP_SET_TSS_ADDR`: set the address of TSS, x86 only.
+    pub set_tss_addr: bool,
+    /// kvmclock paravirtual clocksource, x86 only.
+    pub kvmclock: bool,
+    /// Max number of vCPUs of a VM.
+    pub max_vcpus: usize,
+}
+
+impl KvmCaps {
+    /// Probe the capabilities of KVM.
+    ///
+    /// # Arguments
+    ///
+    /// * `checker` - Checker of KVM capabilities.
+    pub fn probe(checker: &dyn KvmCapChecker) -> Self {
+        KvmCaps {
+            irqchip: checker.check_extension(Cap::Irqchip),
+            #[cfg(target_arch = "x86_64")]
+            pit2: checker.check_extension(Cap::Pit2),
+            #[cfg(not(target_arch = "x86_64"))]
+            pit2: false,
+            #[cfg(target_arch = "x86_64")]
+            set_tss_addr: checker.check_extension(Cap::SetTssAddr),
+            #[cfg(not(target_arch = "x86_64"))]
+            set_tss_addr: false,
+            kvmclock: checker.kvmclock(),
+            max_vcpus: checker.max_vcpus(),
+        }
+    }
+
+    /// Check that the capabilities StratoVirt can't work without are supported.
+    pub fn check_required(&self) -> Result<()> {
+        if !self.irqchip {
+            return Err(anyhow!(HypervisorError::KvmCapMissing(
+                "KVM_CAP_IRQCHIP".to_string()
+            )));
+        }
+        #[cfg(target_arch = "x86_64")]
+        if !self.set_tss_addr {
+            return Err(anyhow!(HypervisorError::KvmCapMissing(
+                "KVM_CAP_SET_TSS_ADDR".to_string()
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockChecker {
+        caps: Vec<u32>,
+        max_vcpus: usize,
+        kvmclock: bool,
+    }
+
+    impl KvmCapChecker for MockChecker {
+        fn check_extension(&self, cap: Cap) -> bool {
+            self.caps.contains(&(cap as u32))
+        }
+
+        fn max_vcpus(&self) -> usize {
+            self.max_vcpus
+        }
+
+        fn kvmclock(&self) -> bool {
+            self.kvmclock
+        }
+    }
+
+    #[test]
+    fn test_kvm_caps_probe() {
+        #[allow(unused_mut)]
+        let mut caps = vec![Cap::Irqchip as u32, Cap::SetTssAddr as u32];
+        #[cfg(target_arch = "x86_64")]
+        caps.push(Cap::Pit2 as u32);
+        let checker = MockChecker {
+            caps,
+            max_vcpus: 288,
+            kvmclock: true,
+        };
+        let caps = KvmCaps::probe(&checker);
+        assert!(caps.irqchip);
+        assert_eq!(caps.pit2, cfg!(target_arch = "x86_64"));
+        assert_eq!(caps.set_tss_addr, cfg!(target_arch = "x86_64"));
+        assert_eq!(caps.max_vcpus, 288);
+        assert!(caps.kvmclock);
+        assert!(caps.check_required().is_ok());
+
+        // PIT is optional.
+        let checker = MockChecker {
+            caps: vec![Cap::Irqchip as u32, Cap::SetTssAddr as u32],
+            max_vcpus: 1024,
+            kvmclock: false,
+        };
+        let caps = KvmCaps::probe(&checker);
+        assert!(!caps.pit2);
+        assert_eq!(caps.max_vcpus, 1024);
+        assert!(caps.check_required().is_ok());
+
+        // Irqchip is required.
+        let checker = MockChecker {
+            caps: vec![Cap::SetTssAddr as u32],
+            max_vcpus: 1024,
+            kvmclock: false,
+        };
+        let caps = KvmCaps::probe(&checker);
+        assert!(!caps.irqchip);
+        let err = caps.check_required().unwrap_err();
+        assert!(err.to_string().contains("KVM_CAP_IRQCHIP"));
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let checker = MockChecker {
+                caps: vec![Cap::Irqchip as u32],
+                max_vcpus: 1024,
+                kvmclock: false,
+            };
+            let caps = KvmCaps::probe(&checker);
+            let err = caps.check_required().unwrap_err();
+            assert!(err.to_string().contains("KVM_CAP_SET_TSS_ADDR"));
+        }
+    }
+}
@@ -119,6 +119,17 @@ impl IrqRouteTable {
         }
     }
 
+    /// Init irq route table in arch x86_64 with split irqchip, in which only the local
+    /// APICs are emulated in kernel. The GSIs of IOAPIC pins are reserved, and their
+    /// routes are set by the userspace IOAPIC as the guest programs it.
+    #[cfg(target_arch = "x86_64")]
+    pub fn init_split_irq_route_table(&mut self) {
+        for i in 0..IOAPIC_NUM_PINS {
+            // This unwrap() will never fail, it is safe.
+            self.gsi_bitmap.set(i as usize).unwrap();
+        }
+    }
+
     /// Init irq route table in arch aarch64.
     #[cfg(target_arch = "aarch64")]
     pub fn init_irq_route_table(&mut self) {
@@ -151,7 +162,8 @@ impl IrqRouteTable {
         Ok(())
     }
 
-    fn remove_irq_route(&mut self, gsi: u32) {
+    /// Remove all the irq routes of the gsi from irq routing table.
+    pub fn remove_irq_route(&mut self, gsi: u32) {
         while let Some((index, _)) = self
             .irq_routes
             .iter()
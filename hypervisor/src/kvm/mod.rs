@@ -24,9 +24,11 @@ use vmm_sys_util::{
 };
 
 use anyhow::{Context, Result};
+pub use caps::{KvmCapChecker, KvmCaps};
 pub use interrupt::MsiVector;
 use interrupt::{refact_vec_with_field, IrqRoute, IrqRouteEntry, IrqRouteTable};
 
+mod caps;
 mod interrupt;
 
 // See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/asm-generic/kvm.h
@@ -39,6 +41,10 @@ pub const KVM_SIGNAL_MSI: u32 = 0x4020_aea5;
 ioctl_iow_nr!(KVM_SET_GSI_ROUTING, KVMIO, 0x6a, kvm_irq_routing);
 ioctl_iow_nr!(KVM_IRQFD, KVMIO, 0x76, kvm_irqfd);
 ioctl_io_nr!(KVM_GET_API_VERSION, KVMIO, 0x00);
+ioctl_io_nr!(KVM_CHECK_EXTENSION, KVMIO, 0x03);
+ioctl_io_nr!(KVM_CREATE_VCPU, KVMIO, 0x41);
+#[cfg(target_arch = "x86_64")]
+ioctl_iowr_nr!(KVM_GET_MSR_INDEX_LIST, KVMIO, 0x02, kvm_msr_list);
 ioctl_ior_nr!(KVM_GET_MP_STATE, KVMIO, 0x98, kvm_mp_state);
 ioctl_ior_nr!(KVM_GET_VCPU_EVENTS, KVMIO, 0x9f, kvm_vcpu_events);
 #[cfg(target_arch = "x86_64")]
@@ -64,6 +70,8 @@ ioctl_iow_nr!(KVM_SET_MSRS, KVMIO, 0x89, kvm_msrs);
 #[cfg(target_arch = "x86_64")]
 ioctl_iow_nr!(KVM_SET_VCPU_EVENTS, KVMIO, 0xa0, kvm_vcpu_events);
 #[cfg(target_arch = "x86_64")]
+ioctl_io_nr!(KVM_NMI, KVMIO, 0x9a);
+#[cfg(target_arch = "x86_64")]
 ioctl_ior_nr!(KVM_GET_PIT2, KVMIO, 0x9f, kvm_pit_state2);
 ioctl_ior_nr!(KVM_GET_CLOCK, KVMIO, 0x7c, kvm_clock_data);
 ioctl_iowr_nr!(KVM_GET_IRQCHIP, KVMIO, 0x62, kvm_irqchip);
@@ -128,6 +136,14 @@ impl KVMFds {
         }
     }
 
+    /// Probe the capabilities of KVM, and fail if a required one is missing.
+    pub fn probe_caps(&self) -> Result<KvmCaps> {
+        let kvm = self.fd.as_ref().with_context(|| "KVM is not available")?;
+        let caps = KvmCaps::probe(kvm);
+        caps.check_required()?;
+        Ok(caps)
+    }
+
     /// Sets the gsi routing table entries. It will overwrite previously set entries.
     pub fn commit_irq_routing(&self) -> Result<()> {
         let routes = self.irq_route_table.lock().unwrap().irq_routes.clone();
@@ -148,6 +164,46 @@ impl KVMFds {
         }
     }
 
+    /// Create the in-kernel local APICs only, the IOAPIC and PIC are left to userspace.
+    ///
+    /// # Arguments
+    ///
+    /// * `ioapic_pins` - Number of IOAPIC pins, whose GSIs are reserved for userspace.
+    #[cfg(target_arch = "x86_64")]
+    pub fn create_split_irq_chip(&self, ioapic_pins: u32) -> Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_SPLIT_IRQCHIP,
+            args: [ioapic_pins as u64, 0, 0, 0],
+            ..Default::default()
+        };
+        self.vm_fd
+            .as_ref()
+            .unwrap()
+            .enable_cap(&cap)
+            .with_context(|| "Failed to enable split irqchip of KVM")
+    }
+
+    /// Inject the MSI message to the in-kernel local APICs.
+    pub fn signal_msi(&self, msi_vector: MsiVector) -> Result<()> {
+        let msi = kvm_msi {
+            address_lo: msi_vector.msg_addr_lo,
+            address_hi: msi_vector.msg_addr_hi,
+            data: msi_vector.msg_data,
+            ..Default::default()
+        };
+        self.vm_fd
+            .as_ref()
+            .unwrap()
+            .signal_msi(msi)
+            .with_context(|| {
+                format!(
+                    "Failed to signal msi: addr 0x{:x}, data 0x{:x}",
+                    msi.address_lo, msi.data
+                )
+            })?;
+        Ok(())
+    }
+
     pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
         self.vm_fd
             .as_ref()
@@ -252,3 +308,26 @@ impl KVMFds {
 }
 
 pub static KVM_FDS: Lazy<ArcSwap<KVMFds>> = Lazy::new(|| ArcSwap::from(Arc::new(KVMFds::new())));
+
+/// Handler of the EOI of a level-triggered vector from the userspace IOAPIC, which
+/// exits from vCPU as `KVM_EXIT_IOAPIC_EOI` with split irqchip.
+#[cfg(target_arch = "x86_64")]
+pub type IoapicEoiHandler = Arc<dyn Fn(u8) + Send + Sync>;
+
+#[cfg(target_arch = "x86_64")]
+static IOAPIC_EOI_HANDLER: Lazy<Mutex<Option<IoapicEoiHandler>>> = Lazy::new(|| Mutex::new(None));
+
+/// Set the handler of IOAPIC EOI exits of vCPUs.
+#[cfg(target_arch = "x86_64")]
+pub fn set_ioapic_eoi_handler(handler: IoapicEoiHandler) {
+    *IOAPIC_EOI_HANDLER.lock().unwrap() = Some(handler);
+}
+
+/// Handle the IOAPIC EOI exit of vCPU with the vector.
+#[cfg(target_arch = "x86_64")]
+pub fn ioapic_eoi(vector: u8) {
+    let handler = IOAPIC_EOI_HANDLER.lock().unwrap().clone();
+    if let Some(handler) = handler {
+        handler(vector);
+    }
+}
@@ -0,0 +1,351 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Kernel loading shared by the micro and standard machines.
+//!
+//! A machine describes where the boot loader may place things with a `BootPlan`,
+//! and `load_boot_plan` loads the kernel, initrd and the optional extra kernel
+//! (`-kernel2`) into guest memory. The extra kernel is loaded as is to its fixed
+//! address, which is reserved in the e820 table (x86_64) or the memory reservation
+//! block of the device tree (aarch64). Its address and size are passed to the guest
+//! by the `${kernel2_addr}` and `${kernel2_size}` placeholders in kernel cmdline.
+
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+
+use address_space::{AddressSpace, GuestAddress};
+use anyhow::{anyhow, bail, Context, Result};
+use boot_loader::{load_linux, BootLoader, BootLoaderConfig};
+use cpu::CPUBootConfig;
+use devices::legacy::FwCfgOps;
+use machine_manager::config::{BootSource, ExtraKernelConfig};
+#[cfg(target_arch = "aarch64")]
+use util::device_tree::{FdtBuilder, FdtReserveEntry};
+use util::num_ops::round_up;
+
+use crate::MachineError;
+
+/// Placeholder in kernel cmdline for the load address of kernel2.
+const KERNEL2_ADDR_PLACEHOLDER: &str = "${kernel2_addr}";
+/// Placeholder in kernel cmdline for the size of kernel2.
+const KERNEL2_SIZE_PLACEHOLDER: &str = "${kernel2_size}";
+/// Granularity of the memory range reserved for kernel2.
+const KERNEL2_RESERVE_ALIGN: u64 = 0x1000;
+
+/// Machine specific layout used to load the boot source.
+pub(crate) struct BootPlan {
+    /// Number of vCPUs, written to the MP table.
+    #[cfg(target_arch = "x86_64")]
+    pub cpu_count: u16,
+    /// Range of the 32-bit PCI hole, (start, size).
+    #[cfg(target_arch = "x86_64")]
+    pub gap_range: (u64, u64),
+    #[cfg(target_arch = "x86_64")]
+    pub ioapic_addr: u32,
+    #[cfg(target_arch = "x86_64")]
+    pub lapic_addr: u32,
+    /// Range of the identity page table and TSS, reserved in e820 table.
+    #[cfg(target_arch = "x86_64")]
+    pub ident_tss_range: Option<(u64, u64)>,
+    /// Ranges of the machine reserved in e820 table, such as PCIe ECAM and APIC.
+    #[cfg(target_arch = "x86_64")]
+    pub reserved_ranges: Vec<(u64, u64)>,
+    /// Boot from 64-bit protection mode directly, or from firmware. ELF kernel with
+    /// PVH entry is booted directly in 32-bit protection mode if it is not set.
+    #[cfg(target_arch = "x86_64")]
+    pub prot64_mode: bool,
+    /// Start address of guest RAM.
+    #[cfg(target_arch = "aarch64")]
+    pub mem_start: u64,
+}
+
+impl BootPlan {
+    #[cfg(target_arch = "x86_64")]
+    fn loader_config(
+        &self,
+        boot_source: &BootSource,
+        reserved_ranges: Vec<(u64, u64)>,
+    ) -> BootLoaderConfig {
+        BootLoaderConfig {
+            kernel: boot_source.kernel_file.clone(),
+            initrd: boot_source.initrd.as_ref().map(|b| b.initrd_file.clone()),
+            kernel_cmdline: boot_source.kernel_cmdline.to_string(),
+            cpu_count: self.cpu_count,
+            gap_range: self.gap_range,
+            ioapic_addr: self.ioapic_addr,
+            lapic_addr: self.lapic_addr,
+            ident_tss_range: self.ident_tss_range,
+            prot64_mode: self.prot64_mode,
+            reserved_ranges: [self.reserved_ranges.as_slice(), &reserved_ranges].concat(),
+        }
+    }
+
+    /// The reserved ranges are added to the device tree by `add_kernel2_mem_reserve`.
+    #[cfg(target_arch = "aarch64")]
+    fn loader_config(
+        &self,
+        boot_source: &BootSource,
+        _reserved_ranges: Vec<(u64, u64)>,
+    ) -> BootLoaderConfig {
+        BootLoaderConfig {
+            kernel: boot_source.kernel_file.clone(),
+            initrd: boot_source.initrd.as_ref().map(|b| b.initrd_file.clone()),
+            mem_start: self.mem_start,
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn cpu_boot_config(&self, layout: &BootLoader, _boot_source: &mut BootSource) -> CPUBootConfig {
+        CPUBootConfig {
+            prot64_mode: self.prot64_mode,
+            boot_ip: layout.boot_ip,
+            boot_sp: layout.boot_sp,
+            boot_selector: layout.boot_selector,
+            zero_page: layout.zero_page_addr,
+            code_segment: layout.segments.code_segment,
+            data_segment: layout.segments.data_segment,
+            gdt_base: layout.segments.gdt_base,
+            gdt_size: layout.segments.gdt_limit,
+            idt_base: layout.segments.idt_base,
+            idt_size: layout.segments.idt_limit,
+            pml4_start: layout.boot_pml4_addr,
+            pvh_start_info: layout.pvh_start_info,
+        }
+    }
+
+    /// The location of initrd is recorded in `boot_source` for the device tree.
+    #[cfg(target_arch = "aarch64")]
+    fn cpu_boot_config(&self, layout: &BootLoader, boot_source: &mut BootSource) -> CPUBootConfig {
+        if let Some(rd) = &mut boot_source.initrd {
+            rd.initrd_addr = layout.initrd_start;
+            rd.initrd_size = layout.initrd_size;
+        }
+        CPUBootConfig {
+            fdt_addr: layout.dtb_start,
+            boot_pc: layout.boot_pc,
+        }
+    }
+}
+
+/// Load the boot source to guest memory as planned, and return the boot config of vCPUs.
+///
+/// # Arguments
+///
+/// * `plan` - The layout of the machine.
+/// * `boot_source` - The boot source, kernel cmdline and the loaded sizes are updated.
+/// * `sys_mem` - Guest memory.
+/// * `fwcfg` - FwCfg device, required when the kernel is loaded by firmware.
+pub(crate) fn load_boot_plan(
+    plan: &BootPlan,
+    boot_source: &mut BootSource,
+    sys_mem: &Arc<AddressSpace>,
+    fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>,
+) -> Result<CPUBootConfig> {
+    let reserved_ranges = prepare_kernel2(boot_source, sys_mem)?;
+    let bootloader_config = plan.loader_config(boot_source, reserved_ranges);
+    let layout = load_linux(&bootloader_config, sys_mem, fwcfg)
+        .with_context(|| anyhow!(MachineError::LoadKernErr))?;
+    // Load kernel2 last, so it is not overwritten by the boot loader.
+    if let Some(kernel2) = &boot_source.kernel2 {
+        load_kernel2(kernel2, sys_mem)?;
+    }
+
+    Ok(plan.cpu_boot_config(&layout, boot_source))
+}
+
+/// Check the range of kernel2, fill the placeholders in kernel cmdline, and return
+/// the guest memory ranges to reserve.
+fn prepare_kernel2(
+    boot_source: &mut BootSource,
+    sys_mem: &Arc<AddressSpace>,
+) -> Result<Vec<(u64, u64)>> {
+    let kernel2 = match boot_source.kernel2.as_mut() {
+        Some(kernel2) => kernel2,
+        None => return Ok(Vec::new()),
+    };
+    let size = std::fs::metadata(&kernel2.kernel_file)
+        .with_context(|| format!("Failed to get size of kernel2 {:?}", kernel2.kernel_file))?
+        .len();
+    if size == 0 {
+        bail!("Kernel2 {:?} is empty", kernel2.kernel_file);
+    }
+    let reserved_size = round_up(size, KERNEL2_RESERVE_ALIGN)
+        .with_context(|| format!("Kernel2 {:?} is too large", kernel2.kernel_file))?;
+    if !sys_mem.address_in_memory(GuestAddress(kernel2.addr), reserved_size) {
+        bail!(
+            "Kernel2 range [{:#x}, {:#x}) is out of guest memory",
+            kernel2.addr,
+            kernel2.addr.saturating_add(reserved_size)
+        );
+    }
+    kernel2.size = size;
+
+    let addr = format!("{:#x}", kernel2.addr);
+    let size = format!("{:#x}", kernel2.size);
+    for param in boot_source.kernel_cmdline.params.iter_mut() {
+        param.value = param
+            .value
+            .replace(KERNEL2_ADDR_PLACEHOLDER, &addr)
+            .replace(KERNEL2_SIZE_PLACEHOLDER, &size);
+    }
+    Ok(vec![(kernel2.addr, reserved_size)])
+}
+
+fn load_kernel2(kernel2: &ExtraKernelConfig, sys_mem: &Arc<AddressSpace>) -> Result<()> {
+    let mut file = File::open(&kernel2.kernel_file)
+        .with_context(|| format!("Failed to open kernel2 {:?}", kernel2.kernel_file))?;
+    sys_mem
+        .write(&mut file, GuestAddress(kernel2.addr), kernel2.size)
+        .with_context(|| format!("Failed to load kernel2 to {:#x}", kernel2.addr))
+}
+
+/// Reserve the range of kernel2 in the device tree. It must be called before any
+/// node is added to `fdt`.
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn add_kernel2_mem_reserve(
+    fdt: &mut FdtBuilder,
+    boot_source: &BootSource,
+) -> Result<()> {
+    if let Some(kernel2) = &boot_source.kernel2 {
+        let size = round_up(kernel2.size, KERNEL2_RESERVE_ALIGN)
+            .with_context(|| "Kernel2 is too large")?;
+        fdt.add_mem_reserve(&[FdtReserveEntry::new(kernel2.addr, size)])?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::*;
+    use address_space::{HostMemMapping, Region};
+    use machine_manager::config::{Param, VmConfig};
+
+    fn test_sys_mem(size: u64) -> Arc<AddressSpace> {
+        let root = Region::init_container_region(u64::max_value());
+        let sys_mem = AddressSpace::new(root.clone()).unwrap();
+        let ram = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, size, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(ram), 0).unwrap();
+        sys_mem
+    }
+
+    fn test_boot_source(kernel2_path: &str, addr: u64) -> BootSource {
+        let mut vm_config = VmConfig::default();
+        vm_config.add_kernel("kernel").unwrap();
+        vm_config.add_kernel_cmdline(&[
+            "console=ttyS0".to_string(),
+            "kexec=${kernel2_addr},${kernel2_size}".to_string(),
+        ]);
+        vm_config.boot_source.kernel2 = Some(ExtraKernelConfig {
+            kernel_file: PathBuf::from(kernel2_path),
+            addr,
+            size: 0,
+        });
+        vm_config.boot_source
+    }
+
+    #[test]
+    fn test_load_kernel2() {
+        let kernel2_path = "boot_test_kernel2.bin";
+        let image = vec![0x5a_u8; 0x1800];
+        File::create(kernel2_path)
+            .unwrap()
+            .write_all(&image)
+            .unwrap();
+        let sys_mem = test_sys_mem(0x10_0000);
+
+        let mut boot_source = test_boot_source(kernel2_path, 0x8_0000);
+        let reserved = prepare_kernel2(&mut boot_source, &sys_mem).unwrap();
+        assert_eq!(reserved, vec![(0x8_0000, 0x2000)]);
+        assert_eq!(
+            boot_source.kernel_cmdline.to_string(),
+            "console=ttyS0 kexec=0x80000,0x1800"
+        );
+        let kernel2 = boot_source.kernel2.clone().unwrap();
+        assert_eq!(kernel2.size, 0x1800);
+        load_kernel2(&kernel2, &sys_mem).unwrap();
+        let mut loaded = vec![0_u8; image.len()];
+        sys_mem
+            .read(
+                &mut loaded.as_mut_slice(),
+                GuestAddress(0x8_0000),
+                image.len() as u64,
+            )
+            .unwrap();
+        assert_eq!(loaded, image);
+
+        // Kernel2 must fit in guest memory.
+        let mut boot_source = test_boot_source(kernel2_path, 0xF_F000);
+        assert!(prepare_kernel2(&mut boot_source, &sys_mem).is_err());
+
+        // Nothing is reserved without kernel2.
+        let mut boot_source = BootSource::default();
+        boot_source.kernel_cmdline.push(Param {
+            param_type: String::new(),
+            value: "quiet".to_string(),
+        });
+        assert!(prepare_kernel2(&mut boot_source, &sys_mem)
+            .unwrap()
+            .is_empty());
+
+        std::fs::remove_file(kernel2_path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_boot_plan_loader_config() {
+        // The plans of micro and standard machines only differ in the boot mode.
+        let micro_plan = BootPlan {
+            cpu_count: 2,
+            gap_range: (0xC000_0000, 0x4000_0000),
+            ioapic_addr: 0xFEC0_0000,
+            lapic_addr: 0xFEE0_0000,
+            ident_tss_range: None,
+            prot64_mode: true,
+            reserved_ranges: Vec::new(),
+        };
+        let std_plan = BootPlan {
+            ident_tss_range: Some((0xFEF0_C000, 0x4000)),
+            prot64_mode: false,
+            reserved_ranges: vec![(0xB000_0000, 0x1000_0000)],
+            ..micro_plan
+        };
+        let boot_source = test_boot_source("kernel2", 0x8_0000);
+        for plan in [micro_plan, std_plan] {
+            let config = plan.loader_config(&boot_source, vec![(0x8_0000, 0x2000)]);
+            assert_eq!(config.kernel, Some(PathBuf::from("kernel")));
+            assert_eq!(config.cpu_count, 2);
+            assert_eq!(config.gap_range, (0xC000_0000, 0x4000_0000));
+            assert_eq!(config.prot64_mode, plan.prot64_mode);
+            assert_eq!(config.ident_tss_range, plan.ident_tss_range);
+            let mut reserved_ranges = plan.reserved_ranges.clone();
+            reserved_ranges.push((0x8_0000, 0x2000));
+            assert_eq!(config.reserved_ranges, reserved_ranges);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_boot_plan_loader_config() {
+        let plan = BootPlan {
+            mem_start: 0x4000_0000,
+        };
+        let boot_source = test_boot_source("kernel2", 0x8_0000);
+        let config = plan.loader_config(&boot_source, vec![(0x8_0000, 0x2000)]);
+        assert_eq!(config.kernel, Some(PathBuf::from("kernel")));
+        assert_eq!(config.mem_start, 0x4000_0000);
+    }
+}
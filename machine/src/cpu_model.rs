@@ -0,0 +1,78 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use anyhow::{bail, Result};
+use cpu::{CPU, CPU_DRIVER};
+use machine_manager::config::CpuConfig;
+#[cfg(target_arch = "aarch64")]
+use machine_manager::config::PmuConfig;
+use machine_manager::qmp::qmp_schema::{CpuDefinitionInfo, CpuModelExpansionInfo, CpuModelInfo};
+
+/// Name of the only CPU model, which passes through the host CPU.
+const HOST_CPU_MODEL: &str = "host";
+
+/// List the CPU models which can be used by `-cpu`, for `query-cpu-definitions`.
+pub(crate) fn qmp_query_cpu_definitions() -> Vec<CpuDefinitionInfo> {
+    vec![CpuDefinitionInfo {
+        name: HOST_CPU_MODEL.to_string(),
+        typename: CPU_DRIVER.to_string(),
+        // The host model differs between hosts, so it's neither static nor migration safe.
+        static_model: false,
+        migration_safe: false,
+        unavailable_features: Vec::new(),
+        deprecated: false,
+    }]
+}
+
+/// Expand the host CPU model to the feature flags of the vCPUs, for `query-cpu-model-expansion`.
+///
+/// # Arguments
+///
+/// * `cpus` - vCPUs of the VM.
+/// * `cpu_config` - The config of `-cpu`.
+/// * `expansion_type` - `static` or `full`.
+/// * `model` - The CPU model to expand.
+pub(crate) fn qmp_query_cpu_model_expansion(
+    #[cfg_attr(target_arch = "aarch64", allow(unused_variables))] cpus: &[Arc<CPU>],
+    #[cfg_attr(target_arch = "x86_64", allow(unused_variables))] cpu_config: &CpuConfig,
+    expansion_type: &str,
+    model: &CpuModelInfo,
+) -> Result<CpuModelExpansionInfo> {
+    if expansion_type != "static" && expansion_type != "full" {
+        bail!("Invalid expansion type \'{}\'", expansion_type);
+    }
+    if model.name != HOST_CPU_MODEL {
+        bail!("CPU model \'{}\' is not supported", model.name);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    let props: BTreeMap<String, bool> = match cpus.first() {
+        Some(cpu) => cpu
+            .cpu_feature_flags()?
+            .into_iter()
+            .map(|(name, enabled)| (name.to_string(), enabled))
+            .collect(),
+        None => bail!("No vCPU found"),
+    };
+    #[cfg(target_arch = "aarch64")]
+    let props = BTreeMap::from([("pmu".to_string(), cpu_config.pmu == PmuConfig::On)]);
+
+    Ok(CpuModelExpansionInfo {
+        model: CpuModelInfo {
+            name: HOST_CPU_MODEL.to_string(),
+            props: Some(props),
+        },
+    })
+}
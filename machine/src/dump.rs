@@ -0,0 +1,400 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Guest memory dump in ELF64 core format.
+//!
+//! The core file starts with the ELF header, followed by one PT_NOTE program
+//! header and one PT_LOAD program header per RAM range of the guest. The PT_NOTE
+//! segment holds a NT_PRSTATUS note with the general purpose registers of each
+//! vCPU, and the PT_LOAD segments hold the guest memory, addressed by `p_paddr`.
+//! The memory is streamed in chunks, so the dump does not need to fit in host memory.
+
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::mem::size_of;
+use std::sync::{Arc, Condvar, Mutex};
+
+use address_space::AddressSpace;
+use anyhow::{bail, Context, Result};
+use cpu::CPU;
+use log::error;
+use machine_manager::machine::{KvmVmState, MachineLifecycle};
+use machine_manager::qmp::qmp_schema::DumpGuestMemoryArgument;
+use util::byte_code::ByteCode;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_CORE: u16 = 4;
+#[cfg(target_arch = "x86_64")]
+const EM_ARCH: u16 = 62;
+#[cfg(target_arch = "aarch64")]
+const EM_ARCH: u16 = 183;
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const NT_PRSTATUS: u32 = 1;
+/// Name of the notes written by the kernel for core files, including the trailing NUL.
+const NOTE_NAME_CORE: &[u8] = b"CORE\0";
+
+/// Offset of `pr_pid` in `struct elf_prstatus`.
+const PRSTATUS_PID_OFFSET: usize = 32;
+/// Offset of `pr_reg` in `struct elf_prstatus`.
+const PRSTATUS_REG_OFFSET: usize = 112;
+/// Size of `pr_fpvalid` and the tail padding of `struct elf_prstatus`.
+const PRSTATUS_TAIL_SIZE: usize = 8;
+
+/// Alignment of the memory data in the core file.
+const DUMP_DATA_ALIGN: u64 = 4096;
+/// Size of guest memory written to the core file at a time.
+const DUMP_CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct Elf64Ehdr {
+    e_ident: [u8; 16],
+    e_type: u16,
+    e_machine: u16,
+    e_version: u32,
+    e_entry: u64,
+    e_phoff: u64,
+    e_shoff: u64,
+    e_flags: u32,
+    e_ehsize: u16,
+    e_phentsize: u16,
+    e_phnum: u16,
+    e_shentsize: u16,
+    e_shnum: u16,
+    e_shstrndx: u16,
+}
+
+impl ByteCode for Elf64Ehdr {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct Elf64Phdr {
+    p_type: u32,
+    p_flags: u32,
+    p_offset: u64,
+    p_vaddr: u64,
+    p_paddr: u64,
+    p_filesz: u64,
+    p_memsz: u64,
+    p_align: u64,
+}
+
+impl ByteCode for Elf64Phdr {}
+
+#[repr(C)]
+#[derive(Debug, Default, Copy, Clone)]
+struct Elf64Nhdr {
+    n_namesz: u32,
+    n_descsz: u32,
+    n_type: u32,
+}
+
+impl ByteCode for Elf64Nhdr {}
+
+fn align_up(value: u64, align: u64) -> u64 {
+    (value + align - 1) & !(align - 1)
+}
+
+/// Build the NT_PRSTATUS note of a vCPU.
+///
+/// # Arguments
+///
+/// * `pid` - Thread id reported for the vCPU, gdb shows it as `LWP <pid>`.
+/// * `regs` - General purpose registers in the layout of `pr_reg`.
+fn prstatus_note(pid: u32, regs: &[u64]) -> Vec<u8> {
+    let mut desc = vec![0_u8; PRSTATUS_REG_OFFSET + regs.len() * 8 + PRSTATUS_TAIL_SIZE];
+    desc[PRSTATUS_PID_OFFSET..PRSTATUS_PID_OFFSET + 4].copy_from_slice(&pid.to_le_bytes());
+    for (i, reg) in regs.iter().enumerate() {
+        let offset = PRSTATUS_REG_OFFSET + i * 8;
+        desc[offset..offset + 8].copy_from_slice(&reg.to_le_bytes());
+    }
+
+    let nhdr = Elf64Nhdr {
+        n_namesz: NOTE_NAME_CORE.len() as u32,
+        n_descsz: desc.len() as u32,
+        n_type: NT_PRSTATUS,
+    };
+    let mut note = nhdr.as_bytes().to_vec();
+    note.extend_from_slice(NOTE_NAME_CORE);
+    note.resize(align_up(note.len() as u64, 4) as usize, 0);
+    note.extend_from_slice(&desc);
+    note.resize(align_up(note.len() as u64, 4) as usize, 0);
+    note
+}
+
+/// Write the ELF64 core file of the guest to `writer`.
+///
+/// # Arguments
+///
+/// * `writer` - Destination of the core file.
+/// * `sys_mem` - Address space whose RAM ranges are dumped.
+/// * `cpu_regs` - Thread id and `pr_reg` of each vCPU.
+fn write_elf_core(
+    writer: &mut dyn Write,
+    sys_mem: &Arc<AddressSpace>,
+    cpu_regs: &[(u32, Vec<u64>)],
+) -> Result<()> {
+    let ranges = sys_mem.ram_ranges();
+    let phnum = ranges.len() + 1;
+    if phnum >= u16::MAX as usize {
+        bail!("Too many memory ranges to dump: {}", ranges.len());
+    }
+
+    let notes = cpu_regs
+        .iter()
+        .flat_map(|(pid, regs)| prstatus_note(*pid, regs))
+        .collect::<Vec<u8>>();
+    let ehdr_size = size_of::<Elf64Ehdr>() as u64;
+    let phdr_size = size_of::<Elf64Phdr>() as u64;
+    let note_offset = ehdr_size + phdr_size * phnum as u64;
+    let data_offset = align_up(note_offset + notes.len() as u64, DUMP_DATA_ALIGN);
+
+    let mut ehdr = Elf64Ehdr {
+        e_type: ET_CORE,
+        e_machine: EM_ARCH,
+        e_version: EV_CURRENT as u32,
+        e_phoff: ehdr_size,
+        e_ehsize: ehdr_size as u16,
+        e_phentsize: phdr_size as u16,
+        e_phnum: phnum as u16,
+        ..Default::default()
+    };
+    ehdr.e_ident[..4].copy_from_slice(&ELF_MAGIC);
+    ehdr.e_ident[4] = ELFCLASS64;
+    ehdr.e_ident[5] = ELFDATA2LSB;
+    ehdr.e_ident[6] = EV_CURRENT;
+    writer.write_all(ehdr.as_bytes())?;
+
+    let note_phdr = Elf64Phdr {
+        p_type: PT_NOTE,
+        p_offset: note_offset,
+        p_filesz: notes.len() as u64,
+        p_memsz: notes.len() as u64,
+        ..Default::default()
+    };
+    writer.write_all(note_phdr.as_bytes())?;
+
+    let mut offset = data_offset;
+    for range in ranges.iter() {
+        let load_phdr = Elf64Phdr {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_W | PF_X,
+            p_offset: offset,
+            p_paddr: range.base.raw_value(),
+            p_filesz: range.size,
+            p_memsz: range.size,
+            ..Default::default()
+        };
+        writer.write_all(load_phdr.as_bytes())?;
+        offset += range.size;
+    }
+
+    writer.write_all(&notes)?;
+    let padding = data_offset - note_offset - notes.len() as u64;
+    writer.write_all(&vec![0_u8; padding as usize])?;
+
+    for range in ranges.iter() {
+        let mut done = 0;
+        while done < range.size {
+            let len = std::cmp::min(DUMP_CHUNK_SIZE, range.size - done);
+            let addr = range.base.unchecked_add(done);
+            sys_mem
+                .read(writer, addr, len)
+                .with_context(|| format!("Failed to dump memory at 0x{:x}", addr.raw_value()))?;
+            done += len;
+        }
+    }
+
+    Ok(())
+}
+
+/// Dump the guest memory and vCPU registers to an ELF64 core file at `path`.
+/// The vCPUs must be paused, so that the registers stay consistent with the memory.
+fn dump_guest_memory(path: &str, sys_mem: &Arc<AddressSpace>, cpus: &[Arc<CPU>]) -> Result<()> {
+    let mut cpu_regs = Vec::with_capacity(cpus.len());
+    for cpu in cpus.iter() {
+        // Thread id 0 is not accepted by gdb, so count from 1 as QEMU does.
+        cpu_regs.push((u32::from(cpu.id()) + 1, cpu.elf_prstatus_regs()?));
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .with_context(|| format!("Failed to open dump file {}", path))?;
+    let mut writer = BufWriter::new(file);
+    write_elf_core(&mut writer, sys_mem, &cpu_regs)?;
+    writer
+        .flush()
+        .with_context(|| format!("Failed to flush dump file {}", path))?;
+
+    Ok(())
+}
+
+/// Get the path of the core file from the arguments of `dump-guest-memory`.
+fn dump_file_path(args: &DumpGuestMemoryArgument) -> Result<&str> {
+    if args.paging {
+        bail!("Paging is not supported by dump-guest-memory");
+    }
+    if args.detach == Some(true) {
+        bail!("Detach is not supported by dump-guest-memory");
+    }
+    if args.begin.is_some() || args.length.is_some() {
+        bail!("Begin and length are not supported by dump-guest-memory");
+    }
+    if let Some(format) = &args.format {
+        if format != "elf" {
+            bail!(
+                "Dump format {} is not supported, only elf is supported",
+                format
+            );
+        }
+    }
+    match args.protocol.strip_prefix("file:") {
+        Some(path) if !path.is_empty() => Ok(path),
+        _ => bail!(
+            "Invalid protocol {}, only file:<path> is supported",
+            args.protocol
+        ),
+    }
+}
+
+/// Handle `dump-guest-memory`. A running VM is paused during the dump, and
+/// resumed after it.
+pub fn qmp_dump_guest_memory(
+    vm: &dyn MachineLifecycle,
+    vm_state: &Arc<(Mutex<KvmVmState>, Condvar)>,
+    args: &DumpGuestMemoryArgument,
+    sys_mem: &Arc<AddressSpace>,
+    cpus: &[Arc<CPU>],
+) -> Result<()> {
+    let path = dump_file_path(args)?;
+    let running = *vm_state.0.lock().unwrap() == KvmVmState::Running;
+    if running && !vm.pause() {
+        bail!("Failed to pause VM for dump-guest-memory");
+    }
+
+    let ret = dump_guest_memory(path, sys_mem, cpus);
+    if running && !vm.resume() {
+        error!("Failed to resume VM after dump-guest-memory");
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address_space::{GuestAddress, HostMemMapping, Region};
+
+    fn phdr_at(core: &[u8], index: usize) -> Elf64Phdr {
+        let start = size_of::<Elf64Ehdr>() + index * size_of::<Elf64Phdr>();
+        *Elf64Phdr::from_bytes(&core[start..start + size_of::<Elf64Phdr>()]).unwrap()
+    }
+
+    #[test]
+    fn test_dump_file_path() {
+        let mut args = DumpGuestMemoryArgument {
+            protocol: "file:/tmp/vm.core".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(dump_file_path(&args).unwrap(), "/tmp/vm.core");
+
+        args.format = Some("kdump-zlib".to_string());
+        assert!(dump_file_path(&args).is_err());
+        args.format = Some("elf".to_string());
+        args.detach = Some(true);
+        assert!(dump_file_path(&args).is_err());
+        args.detach = Some(false);
+        args.paging = true;
+        assert!(dump_file_path(&args).is_err());
+        args.paging = false;
+        args.protocol = "fd:dumpfd".to_string();
+        assert!(dump_file_path(&args).is_err());
+        args.protocol = "file:".to_string();
+        assert!(dump_file_path(&args).is_err());
+    }
+
+    #[test]
+    fn test_write_elf_core() {
+        // Memory layout: [0, 0x2000) and [0x10000, 0x11000) are RAM.
+        let root = Region::init_container_region(0x20000);
+        let sys_mem = AddressSpace::new(root.clone()).unwrap();
+        for (base, size) in [(0_u64, 0x2000_u64), (0x10000, 0x1000)] {
+            let ram = Arc::new(
+                HostMemMapping::new(GuestAddress(base), None, size, None, false, false, false)
+                    .unwrap(),
+            );
+            root.add_subregion(Region::init_ram_region(ram), base)
+                .unwrap();
+        }
+        sys_mem
+            .write_object(&0x1234_5678_u32, GuestAddress(0x1ffc))
+            .unwrap();
+        sys_mem
+            .write_object(&0x9abc_def0_u32, GuestAddress(0x10000))
+            .unwrap();
+
+        let cpu_regs = vec![(1, vec![1_u64; 27]), (2, vec![2_u64; 27])];
+        let mut core = Vec::new();
+        write_elf_core(&mut core, &sys_mem, &cpu_regs).unwrap();
+
+        let ehdr = *Elf64Ehdr::from_bytes(&core[..size_of::<Elf64Ehdr>()]).unwrap();
+        assert_eq!(ehdr.e_ident[..4], ELF_MAGIC);
+        assert_eq!(ehdr.e_ident[4], ELFCLASS64);
+        assert_eq!(ehdr.e_type, ET_CORE);
+        assert_eq!(ehdr.e_machine, EM_ARCH);
+        assert_eq!(ehdr.e_phoff, 64);
+        assert_eq!(ehdr.e_phentsize, 56);
+        assert_eq!(ehdr.e_phnum, 3);
+
+        // Two notes of 12 bytes header, 8 bytes name and 112 + 27 * 8 + 8 bytes desc.
+        let note = phdr_at(&core, 0);
+        assert_eq!(note.p_type, PT_NOTE);
+        assert_eq!(note.p_offset, 64 + 3 * 56);
+        assert_eq!(note.p_filesz, 2 * (12 + 8 + 336));
+        let first = note.p_offset as usize;
+        let nhdr = *Elf64Nhdr::from_bytes(&core[first..first + 12]).unwrap();
+        assert_eq!(nhdr.n_type, NT_PRSTATUS);
+        assert_eq!(nhdr.n_descsz, 336);
+        assert_eq!(&core[first + 12..first + 17], NOTE_NAME_CORE);
+        let pid_offset = first + 20 + PRSTATUS_PID_OFFSET;
+        assert_eq!(core[pid_offset..pid_offset + 4], 1_u32.to_le_bytes());
+        let second = first + 12 + 8 + 336;
+        let pid_offset = second + 20 + PRSTATUS_PID_OFFSET;
+        assert_eq!(core[pid_offset..pid_offset + 4], 2_u32.to_le_bytes());
+
+        let load0 = phdr_at(&core, 1);
+        assert_eq!(load0.p_type, PT_LOAD);
+        assert_eq!(load0.p_offset, DUMP_DATA_ALIGN);
+        assert_eq!(load0.p_paddr, 0);
+        assert_eq!(load0.p_filesz, 0x2000);
+        assert_eq!(load0.p_memsz, 0x2000);
+        let load1 = phdr_at(&core, 2);
+        assert_eq!(load1.p_offset, DUMP_DATA_ALIGN + 0x2000);
+        assert_eq!(load1.p_paddr, 0x10000);
+        assert_eq!(load1.p_filesz, 0x1000);
+        assert_eq!(core.len() as u64, load1.p_offset + load1.p_filesz);
+
+        let data = load0.p_offset as usize + 0x1ffc;
+        assert_eq!(core[data..data + 4], 0x1234_5678_u32.to_le_bytes());
+        let data = load1.p_offset as usize;
+        assert_eq!(core[data..data + 4], 0x9abc_def0_u32.to_le_bytes());
+    }
+}
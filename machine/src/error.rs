@@ -100,11 +100,11 @@ pub enum MachineError {
     #[error("Failed to register event notifier.")]
     RegNotifierErr,
     #[error("Failed to run vcpu{0}.")]
-    StartVcpuErr(u8),
+    StartVcpuErr(u16),
     #[error("Failed to pause vcpu{0}.")]
-    PauseVcpuErr(u8),
+    PauseVcpuErr(u16),
     #[error("Failed to resume vcpu{0}")]
-    ResumeVcpuErr(u8),
+    ResumeVcpuErr(u16),
     #[error("Failed to destroy vcpu{0}.")]
-    DestroyVcpuErr(u8),
+    DestroyVcpuErr(u16),
 }
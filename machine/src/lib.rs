@@ -10,9 +10,15 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod boot;
+mod cpu_model;
+mod dump;
 pub mod error;
+mod mem_aging;
 mod micro_vm;
+mod placement;
 pub mod standard_vm;
+mod vcpu_rt;
 #[cfg(target_arch = "x86_64")]
 mod vm_state;
 
@@ -33,14 +39,15 @@ pub use micro_vm::LightMachine;
 #[cfg(target_arch = "x86_64")]
 use address_space::KvmIoListener;
 use address_space::{
-    create_host_mmaps, set_host_memory_policy, AddressSpace, KvmMemoryListener, Region,
+    create_host_mmaps, locked_mem_size, set_host_memory_policy, AddressSpace, KvmMemoryListener,
+    Region,
 };
 pub use anyhow::Result;
 use anyhow::{anyhow, bail, Context};
 #[cfg(target_arch = "aarch64")]
 use cpu::CPUFeatures;
-use cpu::{ArchCPU, CPUBootConfig, CPUInterface, CPUTopology, CPU};
-use devices::legacy::FwCfgOps;
+use cpu::{ArchCPU, CPUBootConfig, CPUInterface, CPUTopology, CPU, CPU_DRIVER};
+use devices::legacy::{get_chardev, FwCfgOps};
 #[cfg(target_arch = "aarch64")]
 use devices::InterruptController;
 
@@ -49,35 +56,46 @@ use devices::usb::{
     keyboard::UsbKeyboard, tablet::UsbTablet, xhci::xhci_pci::XhciPciDevice, UsbDeviceOps,
 };
 use hypervisor::kvm::KVM_FDS;
+#[cfg(target_arch = "x86_64")]
+use machine_manager::config::RtcBase;
 use machine_manager::config::{
     complete_numa_node, get_multi_function, get_pci_bdf, parse_balloon, parse_blk, parse_demo_dev,
-    parse_device_id, parse_fs, parse_net, parse_numa_distance, parse_numa_mem, parse_rng_dev,
-    parse_root_port, parse_scsi_controller, parse_scsi_device, parse_vfio,
-    parse_vhost_user_blk_pci, parse_virtconsole, parse_virtio_serial, parse_vsock, BootIndexInfo,
-    DriveFile, Incoming, MachineMemConfig, MigrateMode, NumaConfig, NumaDistance, NumaNode,
-    NumaNodes, PFlashConfig, PciBdf, SerialConfig, VfioConfig, VmConfig, FAST_UNPLUG_ON,
+    parse_device_drive, parse_device_id, parse_fs, parse_net, parse_numa_distance, parse_numa_mem,
+    parse_rng_dev, parse_root_port, parse_scsi_controller, parse_scsi_device, parse_vfio,
+    parse_vhost_user_blk_pci, parse_virtio_serial, parse_virtserialport, parse_vsock,
+    sort_boot_order, BootDeviceClass, BootIndexInfo, BootOrderConfig, DriveFile, Incoming,
+    MachineMemConfig, MemLock, MigrateMode, NumaConfig, NumaDistance, NumaNode, NumaNodes,
+    PFlashConfig, PciBdf, SeccompMode, SerialConfig, VfioConfig, VmConfig, FAST_UNPLUG_ON,
     MAX_VIRTIO_QUEUE,
 };
 #[cfg(not(target_env = "musl"))]
 use machine_manager::config::{parse_gpu, parse_usb_keyboard, parse_usb_tablet, parse_xhci};
-use machine_manager::machine::{KvmVmState, MachineInterface};
+use machine_manager::machine::{
+    register_machine_type, KvmVmState, MachineInterface, MachineLifecycle,
+};
+use machine_manager::qga::{
+    guest_agent_output, register_guest_agent_channel, GUEST_AGENT_PORT_NAME,
+};
+use machine_manager::qmp::qmp_schema;
+use machine_manager::qom::{self, QomObject};
 use migration::MigrationManager;
 use pci::{demo_dev::DemoDev, PciBus, PciDevOps, PciHost, RootPort};
 use standard_vm::Result as StdResult;
 pub use standard_vm::StdMachine;
-use sysbus::{SysBus, SysBusDevOps};
+use sysbus::SysBus;
 use util::{
     arg_parser,
-    seccomp::{BpfRule, SeccompOpt, SyscallFilter},
+    seccomp::{BpfRule, SyscallFilter},
 };
 use vfio::{VfioDevice, VfioPciDevice};
-#[cfg(not(target_env = "musl"))]
-use virtio::Gpu;
 use virtio::{
-    balloon_allow_list, vhost, Balloon, Block, BlockState, Console, Rng, RngState, ScsiBus,
-    ScsiCntlr, ScsiDisk, VhostKern, VhostUser, VirtioConsoleState, VirtioDevice, VirtioMmioDevice,
-    VirtioMmioState, VirtioNetState, VirtioPciDevice,
+    balloon_allow_list, register_resizable_block, register_resizable_scsi_device, vhost, Balloon,
+    Block, BlockState, Rng, RngState, ScsiBus, ScsiCntlr, ScsiDisk, Serial, VhostKern, VhostUser,
+    VirtioDevice, VirtioMmioDevice, VirtioMmioState, VirtioNetState, VirtioPciDevice,
+    VirtioSerialState,
 };
+#[cfg(not(target_env = "musl"))]
+use virtio::{gpu_build_edid, Gpu};
 use ScsiCntlr::ScsiCntlrMap;
 use ScsiDisk::{SCSI_TYPE_DISK, SCSI_TYPE_ROM};
 
@@ -113,7 +131,7 @@ pub trait MachineOps {
         mem_config: &MachineMemConfig,
         #[cfg(target_arch = "x86_64")] sys_io: &Arc<AddressSpace>,
         sys_mem: &Arc<AddressSpace>,
-        nr_cpus: u8,
+        nr_cpus: u16,
     ) -> Result<()> {
         // KVM_CREATE_VM system call is invoked when KVM_FDS is used for the first time. The system
         // call registers some notifier functions in the KVM, which are frequently triggered when
@@ -128,6 +146,10 @@ pub trait MachineOps {
             set_host_memory_policy(&mem_mappings, &mem_config.mem_zones)
                 .with_context(|| "Failed to set host memory NUMA policy.")?;
         }
+        if let Some(mem_aging) = &mem_config.mem_aging {
+            mem_aging::start_mem_aging(mem_aging, &mem_mappings)
+                .with_context(|| "Failed to start memory aging.")?;
+        }
 
         sys_mem
             .register_listener(Arc::new(Mutex::new(KvmMemoryListener::new(
@@ -155,6 +177,54 @@ pub trait MachineOps {
         Ok(())
     }
 
+    /// Create a vcpu in kvm and register it for migration.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - `MachineInterface` to obtain functions cpu can use.
+    /// * `vcpu_id` - ID of the vcpu.
+    /// * `nr_cpus` - The number of vcpus at boot.
+    fn create_vcpu(
+        vm: Arc<Mutex<dyn MachineInterface + Send + Sync>>,
+        vcpu_id: u16,
+        #[cfg_attr(target_arch = "aarch64", allow(unused_variables))] nr_cpus: u16,
+    ) -> Result<Arc<CPU>>
+    where
+        Self: Sized,
+    {
+        let vcpu_fd = KVM_FDS
+            .load()
+            .vm_fd
+            .as_ref()
+            .unwrap()
+            .create_vcpu(vcpu_id as u64)
+            .with_context(|| "Create vcpu failed")?;
+        #[cfg(target_arch = "aarch64")]
+        let arch_cpu = ArchCPU::new(u32::from(vcpu_id));
+        #[cfg(target_arch = "x86_64")]
+        let arch_cpu = ArchCPU::new(u32::from(vcpu_id), u32::from(nr_cpus));
+
+        let cpu = Arc::new(CPU::new(
+            Arc::new(vcpu_fd),
+            vcpu_id,
+            Arc::new(Mutex::new(arch_cpu)),
+            vm,
+        ));
+        if let Some(host_cpus) = placement::vcpu_host_cpus(vcpu_id) {
+            cpu.set_affinity(host_cpus);
+        }
+        if let Some((policy, priority)) = placement::vcpu_sched_priority() {
+            cpu.set_sched_priority(policy, priority);
+        }
+        MigrationManager::register_cpu_instance(cpu::ArchCPU::descriptor(), cpu.clone(), vcpu_id);
+        qom::register_object(
+            &format!("/machine/unattached/device[{}]", vcpu_id),
+            QomObject::new(CPU_DRIVER),
+        );
+
+        Ok(cpu)
+    }
+
     /// Init vcpu register with boot message.
     ///
     /// # Arguments
@@ -164,7 +234,7 @@ pub trait MachineOps {
     /// * `boot_cfg` - Boot message generated by reading boot source to guest memory.
     fn init_vcpu(
         vm: Arc<Mutex<dyn MachineInterface + Send + Sync>>,
-        nr_cpus: u8,
+        nr_cpus: u16,
         topology: &CPUTopology,
         boot_cfg: &Option<CPUBootConfig>,
         #[cfg(target_arch = "aarch64")] vcpu_cfg: &Option<CPUFeatures>,
@@ -175,27 +245,7 @@ pub trait MachineOps {
         let mut cpus = Vec::<Arc<CPU>>::new();
 
         for vcpu_id in 0..nr_cpus {
-            let vcpu_fd = KVM_FDS
-                .load()
-                .vm_fd
-                .as_ref()
-                .unwrap()
-                .create_vcpu(vcpu_id as u64)
-                .with_context(|| "Create vcpu failed")?;
-            #[cfg(target_arch = "aarch64")]
-            let arch_cpu = ArchCPU::new(u32::from(vcpu_id));
-            #[cfg(target_arch = "x86_64")]
-            let arch_cpu = ArchCPU::new(u32::from(vcpu_id), u32::from(nr_cpus));
-
-            let cpu = Arc::new(CPU::new(
-                Arc::new(vcpu_fd),
-                vcpu_id,
-                Arc::new(Mutex::new(arch_cpu)),
-                vm.clone(),
-            ));
-            cpus.push(cpu.clone());
-
-            MigrationManager::register_cpu_instance(cpu::ArchCPU::descriptor(), cpu, vcpu_id);
+            cpus.push(Self::create_vcpu(vm.clone(), vcpu_id, nr_cpus)?);
         }
 
         if let Some(boot_config) = boot_cfg {
@@ -226,7 +276,11 @@ pub trait MachineOps {
     fn init_interrupt_controller(&mut self, vcpu_count: u64) -> Result<()>;
 
     /// Add RTC device.
-    fn add_rtc_device(&mut self, #[cfg(target_arch = "x86_64")] mem_size: u64) -> Result<()>;
+    fn add_rtc_device(
+        &mut self,
+        #[cfg(target_arch = "x86_64")] mem_size: u64,
+        #[cfg(target_arch = "x86_64")] rtc_base: RtcBase,
+    ) -> Result<()>;
 
     /// Add Generic event device.
     #[cfg(target_arch = "aarch64")]
@@ -313,6 +367,9 @@ pub trait MachineOps {
         None
     }
 
+    /// Get the virtio-serial device, which virtconsole and virtserialport are attached to.
+    fn get_virtio_serial(&mut self) -> &mut Option<Arc<Mutex<Serial>>>;
+
     /// Add net device.
     ///
     /// # Arguments
@@ -351,62 +408,83 @@ pub trait MachineOps {
         Ok(())
     }
 
-    /// Add console device.
+    /// Add virtio-serial device.
     ///
     /// # Arguments
     ///
     /// * `vm_config` - VM configuration.
     /// * `cfg_args` - Device configuration args.
-    fn add_virtio_console(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
-        let device_cfg = parse_virtconsole(vm_config, cfg_args)?;
-        let sys_mem = self.get_sys_mem();
-        let console = Arc::new(Mutex::new(Console::new(device_cfg.clone())));
-        if let Some(serial) = &vm_config.virtio_serial {
-            if serial.pci_bdf.is_none() {
-                let device = VirtioMmioDevice::new(sys_mem, console.clone());
-                MigrationManager::register_device_instance(
-                    VirtioMmioState::descriptor(),
-                    self.realize_virtio_mmio_device(device)
-                        .with_context(|| anyhow!(MachineError::RlzVirtioMmioErr))?,
-                    &device_cfg.id,
-                );
-            } else {
-                let virtio_serial_info = if let Some(serial_info) = &vm_config.virtio_serial {
-                    serial_info
-                } else {
-                    bail!("No virtio-serial-pci device configured for virtconsole");
-                };
-                // Reasonable, because for virtio-serial-pci device, the bdf has been checked.
-                let bdf = virtio_serial_info.pci_bdf.clone().unwrap();
-                let multi_func = virtio_serial_info.multifunction;
-                let (devfn, parent_bus) = self.get_devfn_and_parent_bus(&bdf)?;
-                let sys_mem = self.get_sys_mem().clone();
-                let virtio_pci_device = VirtioPciDevice::new(
-                    device_cfg.id.clone(),
-                    devfn,
-                    sys_mem,
-                    console.clone(),
-                    parent_bus,
-                    multi_func,
-                );
-                virtio_pci_device
-                    .realize()
-                    .with_context(|| "Failed  to add virtio pci console device")?;
-            }
+    fn add_virtio_serial(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
+        parse_virtio_serial(vm_config, cfg_args)?;
+        // Reasonable, because the virtio-serial config has been set above.
+        let serial_cfg = vm_config.virtio_serial.clone().unwrap();
+        let serial = Arc::new(Mutex::new(Serial::new(serial_cfg.clone())));
+
+        if let Some(bdf) = &serial_cfg.pci_bdf {
+            self.add_virtio_pci_device(
+                &serial_cfg.id,
+                bdf,
+                serial.clone(),
+                serial_cfg.multifunction,
+                false,
+            )
+            .with_context(|| "Failed to add virtio pci serial device")?;
         } else {
-            bail!("No virtio-serial-bus specified");
+            let sys_mem = self.get_sys_mem();
+            let device = VirtioMmioDevice::new(sys_mem, serial.clone());
+            MigrationManager::register_device_instance(
+                VirtioMmioState::descriptor(),
+                self.realize_virtio_mmio_device(device)
+                    .with_context(|| anyhow!(MachineError::RlzVirtioMmioErr))?,
+                &serial_cfg.id,
+            );
         }
         MigrationManager::register_device_instance(
-            VirtioConsoleState::descriptor(),
-            console,
-            &device_cfg.id,
+            VirtioSerialState::descriptor(),
+            serial.clone(),
+            &serial_cfg.id,
         );
+        *self.get_virtio_serial() = Some(serial);
 
         Ok(())
     }
 
-    fn add_virtio_serial(&mut self, vm_config: &mut VmConfig, cfg_args: &str) -> Result<()> {
-        parse_virtio_serial(vm_config, cfg_args)?;
+    /// Add virtconsole or virtserialport to the virtio-serial device.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_config` - VM configuration.
+    /// * `cfg_args` - Device configuration args.
+    /// * `is_console` - Whether the port is virtconsole.
+    fn add_virtio_serial_port(
+        &mut self,
+        vm_config: &mut VmConfig,
+        cfg_args: &str,
+        is_console: bool,
+    ) -> Result<()> {
+        let serial = self
+            .get_virtio_serial()
+            .clone()
+            .with_context(|| "No virtio-serial-bus specified")?;
+        let free_nr = serial.lock().unwrap().find_free_port_nr(is_console)?;
+        let port_cfg = parse_virtserialport(vm_config, cfg_args, free_nr)?;
+        let guest_agent = port_cfg.name.as_deref() == Some(GUEST_AGENT_PORT_NAME);
+        let chardev_id = port_cfg.chardev.id.clone();
+        serial
+            .lock()
+            .unwrap()
+            .add_port(port_cfg)
+            .with_context(|| "Failed to add virtio-serial port")?;
+        if guest_agent {
+            // `guest-agent-command` talks to the port in the process.
+            let chardev = get_chardev(&chardev_id)
+                .with_context(|| format!("Chardev {} of guest agent not found", chardev_id))?;
+            chardev
+                .lock()
+                .unwrap()
+                .set_output_hook(Arc::new(guest_agent_output));
+            register_guest_agent_channel(chardev);
+        }
         Ok(())
     }
 
@@ -519,7 +597,7 @@ pub trait MachineOps {
             pci_host
                 .lock()
                 .unwrap()
-                .reset()
+                .reset_all()
                 .with_context(|| "Fail to reset pci host")?;
         }
 
@@ -543,16 +621,21 @@ pub trait MachineOps {
     fn reset_fwcfg_boot_order(&mut self) -> Result<()> {
         // SAFETY: unwrap is safe because stand machine always make sure it not return null.
         let boot_order_vec = self.get_boot_order_list().unwrap();
-        let mut locked_boot_order_vec = boot_order_vec.lock().unwrap().clone();
-        if locked_boot_order_vec.is_empty() {
+        let locked_boot_order_vec = boot_order_vec.lock().unwrap().clone();
+        if locked_boot_order_vec.is_empty()
+            && !self.get_vm_config().lock().unwrap().boot_order.strict
+        {
             return Ok(());
         }
-        locked_boot_order_vec.sort_by(|x, y| x.boot_index.cmp(&y.boot_index));
         let mut fwcfg_boot_order_string = String::new();
-        for item in &locked_boot_order_vec {
+        for item in &sort_boot_order(&locked_boot_order_vec) {
             fwcfg_boot_order_string.push_str(&item.dev_path);
             fwcfg_boot_order_string.push('\n');
         }
+        // Firmware stops at "HALT" instead of trying the devices out of the list.
+        if self.get_vm_config().lock().unwrap().boot_order.strict {
+            fwcfg_boot_order_string.push_str("HALT\n");
+        }
         fwcfg_boot_order_string.push('\0');
 
         let fwcfg = self.get_fwcfg_dev();
@@ -582,7 +665,7 @@ pub trait MachineOps {
             .lock()
             .unwrap()
             .iter()
-            .any(|item| item.boot_index == boot_index)
+            .any(|item| !item.implicit && item.boot_index == boot_index)
         {
             bail!("Failed to add duplicated bootindex {}.", boot_index);
         }
@@ -605,6 +688,46 @@ pub trait MachineOps {
             boot_index,
             id: dev_id.to_string(),
             dev_path: dev_path.to_string(),
+            implicit: false,
+        });
+    }
+
+    /// Add the default boot index from `-boot order` for a device without `bootindex`.
+    /// Only the first device of each class gets one.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot_order` - The boot order config.
+    /// * `class` - The class of the device.
+    /// * `dev_path` - The firmware device path of the device.
+    /// * `dev_id` - The id of the device.
+    fn add_implicit_bootindex_devices(
+        &mut self,
+        boot_order: &BootOrderConfig,
+        class: BootDeviceClass,
+        dev_path: &str,
+        dev_id: &str,
+    ) {
+        let boot_index = match boot_order.default_boot_index(class) {
+            Some(index) => index,
+            None => return,
+        };
+        let boot_order_list = match self.get_boot_order_list() {
+            Some(list) => list,
+            None => return,
+        };
+        let mut locked_boot_order_list = boot_order_list.lock().unwrap();
+        if locked_boot_order_list
+            .iter()
+            .any(|item| item.implicit && item.boot_index == boot_index)
+        {
+            return;
+        }
+        locked_boot_order_list.push(BootIndexInfo {
+            boot_index,
+            id: dev_id.to_string(),
+            dev_path: dev_path.to_string(),
+            implicit: true,
         });
     }
 
@@ -634,13 +757,15 @@ pub trait MachineOps {
             self.check_bootindex(bootindex)
                 .with_context(|| "Fail to add virtio pci blk device for invalid bootindex")?;
         }
-        let device = Arc::new(Mutex::new(Block::new(
-            device_cfg.clone(),
-            self.get_drive_files(),
-        )));
+        let mut block = Block::new(device_cfg.clone(), self.get_drive_files());
+        if let Some(vm) = self.get_vm_lifecycle() {
+            block.set_vm(vm);
+        }
+        let device = Arc::new(Mutex::new(block));
         let pci_dev = self
             .add_virtio_pci_device(&device_cfg.id, &bdf, device.clone(), multi_func, false)
             .with_context(|| "Failed to add virtio pci device")?;
+        register_resizable_block(&device);
         if let Some(bootindex) = device_cfg.boot_index {
             // Eg: OpenFirmware device path(virtio-blk disk):
             // /pci@i0cf8/scsi@6[,3]/disk@0,0
@@ -652,6 +777,13 @@ pub trait MachineOps {
             if let Some(dev_path) = pci_dev.lock().unwrap().get_dev_path() {
                 self.add_bootindex_devices(bootindex, &dev_path, &device_cfg.id);
             }
+        } else if let Some(dev_path) = pci_dev.lock().unwrap().get_dev_path() {
+            self.add_implicit_bootindex_devices(
+                &vm_config.boot_order,
+                BootDeviceClass::Disk,
+                &dev_path,
+                &device_cfg.id,
+            );
         }
         MigrationManager::register_device_instance(
             BlockState::descriptor(),
@@ -701,11 +833,12 @@ pub trait MachineOps {
             self.check_bootindex(bootindex)
                 .with_context(|| "Failed to add scsi device for invalid bootindex")?;
         }
-        let device = Arc::new(Mutex::new(ScsiDisk::ScsiDevice::new(
-            device_cfg.clone(),
-            scsi_type,
-            self.get_drive_files(),
-        )));
+        let mut scsi_device =
+            ScsiDisk::ScsiDevice::new(device_cfg.clone(), scsi_type, self.get_drive_files());
+        if let Some(vm) = self.get_vm_lifecycle() {
+            scsi_device.set_vm(vm);
+        }
+        let device = Arc::new(Mutex::new(scsi_device));
 
         let cntlr_list = self
             .get_scsi_cntlr_list()
@@ -730,26 +863,52 @@ pub trait MachineOps {
                 .unwrap()
                 .devices
                 .insert((device_cfg.target, device_cfg.lun), device.clone());
-            device.lock().unwrap().parent_bus = Arc::downgrade(bus);
+            let max_sectors = bus.lock().unwrap().max_sectors;
+            let mut locked_device = device.lock().unwrap();
+            locked_device.parent_bus = Arc::downgrade(bus);
+            locked_device.max_sectors = max_sectors;
         } else {
             bail!("Wrong! Controller has no bus {} !", &device_cfg.bus);
         }
 
         device.lock().unwrap().realize()?;
-
-        if let Some(bootindex) = device_cfg.boot_index {
-            let mut cntlr_locked = cntlr.lock().unwrap();
-            // Eg: OpenFirmware device path(virtio-scsi disk):
-            // /pci@i0cf8/scsi@7[,3]/channel@0/disk@2,3
-            //   |             |  |      |          | |
-            //   |             |  |      |     target,lun.
-            //   |             |  |   channel(unused, fixed 0).
-            //   |         PCI slot,[function] holding SCSI controller.
-            //  PCI root as system bus port.
-            let dev_path = cntlr_locked.config.boot_prefix.as_mut().unwrap();
-            let str = format! {"/channel@0/disk@{:x},{:x}", device_cfg.target, device_cfg.lun};
-            dev_path.push_str(&str);
-            self.add_bootindex_devices(bootindex, dev_path, &device_cfg.id);
+        register_resizable_scsi_device(&device);
+
+        // Eg: OpenFirmware device path(virtio-scsi disk):
+        // /pci@i0cf8/scsi@7[,3]/channel@0/disk@2,3
+        //   |             |  |      |          | |
+        //   |             |  |      |     target,lun.
+        //   |             |  |   channel(unused, fixed 0).
+        //   |         PCI slot,[function] holding SCSI controller.
+        //  PCI root as system bus port.
+        let dev_path = cntlr
+            .lock()
+            .unwrap()
+            .config
+            .boot_prefix
+            .clone()
+            .map(|prefix| {
+                format!(
+                    "{}/channel@0/disk@{:x},{:x}",
+                    prefix, device_cfg.target, device_cfg.lun
+                )
+            });
+        if let Some(dev_path) = dev_path {
+            if let Some(bootindex) = device_cfg.boot_index {
+                self.add_bootindex_devices(bootindex, &dev_path, &device_cfg.id);
+            } else {
+                let class = if scsi_type == SCSI_TYPE_ROM {
+                    BootDeviceClass::Cdrom
+                } else {
+                    BootDeviceClass::Disk
+                };
+                self.add_implicit_bootindex_devices(
+                    &vm_config.boot_order,
+                    class,
+                    &dev_path,
+                    &device_cfg.id,
+                );
+            }
         }
         Ok(())
     }
@@ -781,7 +940,16 @@ pub trait MachineOps {
             );
             device
         };
-        self.add_virtio_pci_device(&device_cfg.id, &bdf, device, multi_func, need_irqfd)?;
+        let pci_dev =
+            self.add_virtio_pci_device(&device_cfg.id, &bdf, device, multi_func, need_irqfd)?;
+        if let Some(dev_path) = pci_dev.lock().unwrap().get_dev_path() {
+            self.add_implicit_bootindex_devices(
+                &vm_config.boot_order,
+                BootDeviceClass::Network,
+                &dev_path,
+                &device_cfg.id,
+            );
+        }
         self.reset_bus(&device_cfg.id)?;
         Ok(())
     }
@@ -807,9 +975,15 @@ pub trait MachineOps {
                     &device_cfg.id
                 )
             })?;
-        if let Some(bootindex) = device_cfg.boot_index {
-            if let Some(dev_path) = pci_dev.lock().unwrap().get_dev_path() {
-                self.add_bootindex_devices(bootindex, &dev_path, &device_cfg.id);
+        if let Some(dev_path) = pci_dev.lock().unwrap().get_dev_path() {
+            match device_cfg.boot_index {
+                Some(bootindex) => self.add_bootindex_devices(bootindex, &dev_path, &device_cfg.id),
+                None => self.add_implicit_bootindex_devices(
+                    &vm_config.boot_order,
+                    BootDeviceClass::Disk,
+                    &dev_path,
+                    &device_cfg.id,
+                ),
             }
         }
         self.reset_bus(&device_cfg.id)?;
@@ -866,6 +1040,20 @@ pub trait MachineOps {
         let device_cfg = parse_gpu(cfg_args)?;
         let device = Arc::new(Mutex::new(Gpu::new(device_cfg.clone())));
         self.add_virtio_pci_device(&device_cfg.id, &bdf, device, multi_func, false)?;
+
+        // Provide the preferred resolution to firmware, which drives a single display,
+        // so only the EDID of the first gpu is used.
+        if let (true, Some(fwcfg)) = (device_cfg.edid, self.get_fwcfg_dev()) {
+            let edid = gpu_build_edid(device_cfg.xres, device_cfg.yres);
+            if let Err(e) = fwcfg.lock().unwrap().add_file_entry("etc/edid", edid) {
+                if !matches!(
+                    e.downcast_ref::<devices::legacy::LegacyError>(),
+                    Some(devices::legacy::LegacyError::DuplicateFile(_))
+                ) {
+                    return Err(e).with_context(|| "Failed to add edid to fwcfg");
+                }
+            }
+        }
         Ok(())
     }
 
@@ -1172,6 +1360,8 @@ pub trait MachineOps {
         self.add_rtc_device(
             #[cfg(target_arch = "x86_64")]
             vm_config.machine_config.mem_config.mem_size,
+            #[cfg(target_arch = "x86_64")]
+            vm_config.machine_config.rtc_base,
         )
         .with_context(|| anyhow!(MachineError::AddDevErr("RTC".to_string())))?;
 
@@ -1231,7 +1421,10 @@ pub trait MachineOps {
                     self.add_virtio_serial(vm_config, cfg_args)?;
                 }
                 "virtconsole" => {
-                    self.add_virtio_console(vm_config, cfg_args)?;
+                    self.add_virtio_serial_port(vm_config, cfg_args, true)?;
+                }
+                "virtserialport" => {
+                    self.add_virtio_serial_port(vm_config, cfg_args, false)?;
                 }
                 "virtio-rng-device" | "virtio-rng-pci" => {
                     self.add_virtio_rng(vm_config, cfg_args)?;
@@ -1272,6 +1465,11 @@ pub trait MachineOps {
                     bail!("Unsupported device: {:?}", dev.0.as_str());
                 }
             }
+            let mut object = QomObject::new(dev.0.as_str());
+            if let Some(drive) = parse_device_drive(cfg_args)? {
+                object = object.with_prop("drive", drive);
+            }
+            qom::register_device(&id, object);
         }
 
         Ok(())
@@ -1301,9 +1499,21 @@ pub trait MachineOps {
     /// Return the syscall whitelist for seccomp.
     fn syscall_whitelist(&self) -> Vec<BpfRule>;
 
-    /// Register seccomp rules in syscall whitelist to seccomp.
-    fn register_seccomp(&self, balloon_enable: bool) -> Result<()> {
-        let mut seccomp_filter = SyscallFilter::new(SeccompOpt::Trap);
+    /// Register seccomp rules in syscall whitelist to seccomp, the `mode` decides
+    /// the action on the syscalls not in the whitelist.
+    fn register_seccomp(&self, balloon_enable: bool, mode: SeccompMode) -> Result<()> {
+        let default_action = match mode.default_action() {
+            Some(action) => action,
+            None => {
+                warn!("Seccomp sandbox is disabled");
+                return Ok(());
+            }
+        };
+        if mode == SeccompMode::Log {
+            warn!("Seccomp sandbox only logs the syscalls not in the allowlist");
+        }
+
+        let mut seccomp_filter = SyscallFilter::new(default_action);
         let mut bpf_rules = self.syscall_whitelist();
         if balloon_enable {
             balloon_allow_list(&mut bpf_rules);
@@ -1327,6 +1537,11 @@ pub trait MachineOps {
     /// Get the drive backend files.
     fn get_drive_files(&self) -> Arc<Mutex<HashMap<String, DriveFile>>>;
 
+    /// Get the lifecycle interface of the machine, which is used by devices to pause the VM.
+    fn get_vm_lifecycle(&self) -> Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>> {
+        None
+    }
+
     /// Fetch a cloned file from drive backend files.
     fn fetch_drive_file(&self, path: &str) -> Result<File> {
         let files = self.get_drive_files();
@@ -1480,11 +1695,39 @@ pub trait MachineOps {
         }
 
         *vm_state = KvmVmState::Running;
+        // Resubmit the requests stopped by the error policies of disks.
+        virtio::retry_stopped_requests();
+        // Connections of vsock may be stale after the VM is paused.
+        VhostKern::reset_vsock_transports();
 
         Ok(())
     }
 
-    /// Destroy VM as `Shutdown` state, destroy vcpu thread.
+    /// Shut down VM as `Shutdown` state, vcpus are paused and the VM is kept until it's
+    /// reset or destroyed.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpus` - Cpus vector restore cpu structure.
+    /// * `vm_state` - Vm kvm vm state.
+    fn vm_shutdown(
+        &self,
+        cpus: &[Arc<CPU>],
+        #[cfg(target_arch = "aarch64")] irq_chip: &Option<Arc<InterruptController>>,
+        vm_state: &mut KvmVmState,
+    ) -> Result<()> {
+        self.vm_pause(
+            cpus,
+            #[cfg(target_arch = "aarch64")]
+            irq_chip,
+            vm_state,
+        )?;
+        *vm_state = KvmVmState::Shutdown;
+
+        Ok(())
+    }
+
+    /// Destroy VM as `Destroyed` state, destroy vcpu thread.
     ///
     /// # Arguments
     ///
@@ -1496,7 +1739,7 @@ pub trait MachineOps {
                 .with_context(|| format!("Failed to destroy vcpu{}", cpu_index))?;
         }
 
-        *vm_state = KvmVmState::Shutdown;
+        *vm_state = KvmVmState::Destroyed;
 
         Ok(())
     }
@@ -1535,10 +1778,18 @@ pub trait MachineOps {
                     vm_state,
                 )
                 .with_context(|| "Failed to pause vm.")?,
-            (Paused, Running) => self
+            (Paused, Running) | (Shutdown, Running) => self
                 .vm_resume(cpus, vm_state)
                 .with_context(|| "Failed to resume vm.")?,
-            (_, Shutdown) => self
+            (Running, Shutdown) => self
+                .vm_shutdown(
+                    cpus,
+                    #[cfg(target_arch = "aarch64")]
+                    irq_chip,
+                    vm_state,
+                )
+                .with_context(|| "Failed to shut down vm.")?,
+            (_, Destroyed) => self
                 .vm_destroy(cpus, vm_state)
                 .with_context(|| "Failed to destroy vm.")?,
             (_, _) => {
@@ -1558,6 +1809,34 @@ pub trait MachineOps {
     }
 }
 
+/// Get the mode of `mem-lock` and the size of locked guest memory.
+pub(crate) fn qmp_query_memory_lock(mem_config: &MachineMemConfig) -> qmp_schema::MemoryLockInfo {
+    let mem_lock = match mem_config.mem_lock {
+        MemLock::Off => "off",
+        MemLock::On => "on",
+        MemLock::OnFault => "on-fault",
+    };
+    qmp_schema::MemoryLockInfo {
+        mem_lock: mem_lock.to_string(),
+        locked_size: locked_mem_size(),
+    }
+}
+
+/// Register the machine types supported by StratoVirt, which are reported by `query-machines`.
+pub fn register_machine_types() {
+    // The `none` machine only serves QMP and runs no vCPU.
+    register_machine_type(qmp_schema::MachineInfo {
+        hotplug: false,
+        name: "none".to_string(),
+        numa_mem_support: false,
+        cpu_max: 1,
+        deprecated: false,
+        is_default: false,
+    });
+    register_machine_type(micro_vm::machine_type_info());
+    register_machine_type(standard_vm::machine_type_info());
+}
+
 /// Normal run or resume virtual machine from migration/snapshot  .
 ///
 /// # Arguments
@@ -1586,7 +1865,14 @@ fn start_incoming_migration(vm: &Arc<Mutex<dyn MachineOps + Send + Sync>>) -> Re
     let (mode, path) = vm.lock().unwrap().get_migrate_info();
     match mode {
         MigrateMode::File => {
-            MigrationManager::restore_snapshot(&path)
+            let lazy = vm
+                .lock()
+                .unwrap()
+                .get_vm_config()
+                .lock()
+                .unwrap()
+                .lazy_restore;
+            MigrationManager::restore_snapshot(&path, lazy)
                 .with_context(|| "Failed to restore snapshot")?;
             vm.lock()
                 .unwrap()
@@ -0,0 +1,387 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Background aging of idle guest memory.
+//!
+//! Every interval, the scanner reads the soft-dirty bits of guest RAM from
+//! `/proc/self/pagemap` and clears them through `/proc/self/clear_refs`. A page
+//! which is not written for `threshold` seconds is advised to the host with
+//! MADV_COLD or MADV_PAGEOUT, so it is reclaimed (e.g. to zram) before hot pages
+//! when the host runs short of memory. Reads do not set soft-dirty bits, so pages
+//! which are only read by the guest age too; they are faulted back on access.
+//!
+//! Pages pinned for DMA by VFIO are never advised, and a pass is aborted when the
+//! block IO of the VM is heavy, as reclaim competes with it for the host disk.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use address_space::{host_range_pinned, HostMemMapping};
+use anyhow::{bail, Context, Result};
+use log::{error, info};
+use machine_manager::config::{MemAgingAdvice, MemAgingConfig, M};
+use machine_manager::qmp::qmp_schema::MemAgingInfo;
+use util::aio::aio_submitted_bytes;
+use util::unix::host_page_size;
+
+/// Not defined by the libc crate in use yet.
+pub(crate) const MADV_COLD: libc::c_int = 20;
+pub(crate) const MADV_PAGEOUT: libc::c_int = 21;
+
+/// Page is present in RAM.
+const PM_PRESENT: u64 = 1 << 63;
+/// Page is swapped out.
+const PM_SWAP: u64 = 1 << 62;
+/// Page is written since the soft-dirty bits were cleared last time.
+const PM_SOFT_DIRTY: u64 = 1 << 55;
+/// Value written to `clear_refs` to clear the soft-dirty bits.
+const CLEAR_SOFT_DIRTY: &[u8] = b"4";
+/// Number of pagemap entries read at once.
+const PAGEMAP_BATCH: usize = 512;
+/// Max bytes advised by one madvise call.
+const MAX_ADVISE_BATCH: u64 = 2 * M;
+/// Block IO rate above which the VM is considered busy, in bytes per second.
+const IO_BUSY_RATE: u64 = 32 * M;
+
+static MEM_AGING_ENABLED: AtomicBool = AtomicBool::new(false);
+/// Number of finished scan passes.
+static SCANS: AtomicU64 = AtomicU64::new(0);
+/// Number of passes aborted because of heavy IO.
+static ABORTED_SCANS: AtomicU64 = AtomicU64::new(0);
+/// Number of pages advised to the host.
+static AGED_PAGES: AtomicU64 = AtomicU64::new(0);
+/// Number of idle pages skipped because they are pinned for DMA.
+static PINNED_PAGES: AtomicU64 = AtomicU64::new(0);
+
+/// Aging state of one guest RAM mapping.
+struct AgingRange {
+    mapping: Arc<HostMemMapping>,
+    /// Idle intervals of each page.
+    ages: Vec<u16>,
+    /// Whether each page is advised since it was written last time.
+    advised: Vec<bool>,
+}
+
+/// Decide the new age of a page from its pagemap entry. Return None if the page is
+/// not in RAM and keeps its state, e.g. it is swapped out.
+fn page_age(entry: u64, age: u16) -> Option<u16> {
+    if entry & PM_SOFT_DIRTY != 0 {
+        return Some(0);
+    }
+    if entry & PM_PRESENT == 0 {
+        return if entry & PM_SWAP == 0 { Some(0) } else { None };
+    }
+    Some(age.saturating_add(1))
+}
+
+struct MemAgingScanner {
+    ranges: Vec<AgingRange>,
+    pagemap: File,
+    clear_refs: File,
+    page_size: u64,
+    /// Pages idle for so many intervals are advised.
+    threshold: u16,
+    interval: Duration,
+    /// Max bytes advised per second.
+    rate: u64,
+    advice: libc::c_int,
+}
+
+impl MemAgingScanner {
+    fn new(config: &MemAgingConfig, mappings: &[Arc<HostMemMapping>]) -> Result<Self> {
+        let page_size = host_page_size();
+        let ranges = mappings
+            .iter()
+            // Huge pages are not reclaimed by madvise, and are reported as one entry.
+            .filter(|m| m.file_backend().map_or(true, |f| f.page_size <= page_size))
+            .map(|m| {
+                let nr_pages = (m.size() / page_size) as usize;
+                AgingRange {
+                    mapping: m.clone(),
+                    ages: vec![0; nr_pages],
+                    advised: vec![false; nr_pages],
+                }
+            })
+            .collect();
+        let pagemap = File::open("/proc/self/pagemap").with_context(|| "Failed to open pagemap")?;
+        let clear_refs = OpenOptions::new()
+            .write(true)
+            .open("/proc/self/clear_refs")
+            .with_context(|| "Failed to open clear_refs")?;
+
+        Ok(MemAgingScanner {
+            ranges,
+            pagemap,
+            clear_refs,
+            page_size,
+            threshold: (config.threshold / config.interval) as u16,
+            interval: Duration::from_secs(config.interval),
+            rate: config.rate * M,
+            advice: match config.advice {
+                MemAgingAdvice::Cold => MADV_COLD,
+                MemAgingAdvice::Pageout => MADV_PAGEOUT,
+            },
+        })
+    }
+
+    fn io_busy(&self, io_bytes: u64, since: Instant) -> bool {
+        let elapsed = since.elapsed().as_millis().max(1) as u64;
+        let bytes = aio_submitted_bytes().wrapping_sub(io_bytes);
+        bytes.saturating_mul(1000) / elapsed > IO_BUSY_RATE
+    }
+
+    /// Advise `len` bytes from `hva`, and sleep to keep the advised bytes within
+    /// the rate limit.
+    fn advise(&self, hva: u64, len: u64, pass: &mut PassState) {
+        if host_range_pinned(hva, len) {
+            PINNED_PAGES.fetch_add(len / self.page_size, Ordering::Relaxed);
+            return;
+        }
+        // SAFETY: the range lies in guest RAM which stays mapped during the scan,
+        // and both advices keep the content of the pages.
+        let ret = unsafe { libc::madvise(hva as *mut libc::c_void, len as usize, self.advice) };
+        if ret != 0 {
+            error!(
+                "Failed to madvise guest memory 0x{:x}+0x{:x}: {:?}",
+                hva,
+                len,
+                std::io::Error::last_os_error()
+            );
+            return;
+        }
+        AGED_PAGES.fetch_add(len / self.page_size, Ordering::Relaxed);
+        pass.advised_bytes += len;
+        let budget = Duration::from_millis(pass.advised_bytes.saturating_mul(1000) / self.rate);
+        if let Some(delay) = budget.checked_sub(pass.start.elapsed()) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Scan all ranges once. Return false if the pass is aborted.
+    fn scan(&mut self) -> Result<bool> {
+        let mut pass = PassState {
+            start: Instant::now(),
+            io_bytes: aio_submitted_bytes(),
+            advised_bytes: 0,
+        };
+        let mut entries = [0_u64; PAGEMAP_BATCH];
+        let mut ranges = std::mem::take(&mut self.ranges);
+        let mut result = Ok(true);
+        'ranges: for range in ranges.iter_mut() {
+            let base = range.mapping.host_address();
+            let mut run: Option<(u64, u64)> = None;
+            for first in (0..range.ages.len()).step_by(PAGEMAP_BATCH) {
+                let count = PAGEMAP_BATCH.min(range.ages.len() - first);
+                if let Err(e) =
+                    self.read_pagemap(base + first as u64 * self.page_size, &mut entries[..count])
+                {
+                    result = Err(e);
+                    break 'ranges;
+                }
+                for (i, entry) in entries[..count].iter().enumerate() {
+                    let idx = first + i;
+                    if let Some(age) = page_age(*entry, range.ages[idx]) {
+                        range.ages[idx] = age;
+                        if age == 0 {
+                            range.advised[idx] = false;
+                        }
+                    }
+                    let idle = *entry & PM_PRESENT != 0
+                        && range.ages[idx] >= self.threshold
+                        && !range.advised[idx];
+                    let hva = base + idx as u64 * self.page_size;
+                    match run {
+                        Some((start, len))
+                            if idle && start + len == hva && len < MAX_ADVISE_BATCH =>
+                        {
+                            run = Some((start, len + self.page_size));
+                        }
+                        _ => {
+                            if let Some((start, len)) = run.take() {
+                                self.advise(start, len, &mut pass);
+                                if self.io_busy(pass.io_bytes, pass.start) {
+                                    result = Ok(false);
+                                    break 'ranges;
+                                }
+                            }
+                            if idle {
+                                run = Some((hva, self.page_size));
+                            }
+                        }
+                    }
+                    if idle {
+                        range.advised[idx] = true;
+                    }
+                }
+            }
+            if let Some((start, len)) = run {
+                self.advise(start, len, &mut pass);
+            }
+        }
+        self.ranges = ranges;
+        result?;
+
+        // Pages written from now on are seen as dirty in the next pass.
+        self.clear_refs
+            .write_all(CLEAR_SOFT_DIRTY)
+            .with_context(|| "Failed to clear soft-dirty bits")?;
+        if self.io_busy(pass.io_bytes, pass.start) {
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Check that the host kernel tracks soft-dirty bits, without it all pages
+    /// would look idle.
+    fn soft_dirty_supported(&mut self) -> Result<bool> {
+        let mut probe = 0_u8;
+        let hva = &mut probe as *mut u8 as u64;
+        self.clear_refs
+            .write_all(CLEAR_SOFT_DIRTY)
+            .with_context(|| "Failed to clear soft-dirty bits")?;
+        // SAFETY: the pointer refers to the local variable.
+        unsafe { std::ptr::write_volatile(hva as *mut u8, 1) };
+        let mut entry = [0_u64; 1];
+        self.read_pagemap(hva, &mut entry)?;
+        Ok(entry[0] & PM_SOFT_DIRTY != 0)
+    }
+
+    fn read_pagemap(&self, hva: u64, entries: &mut [u64]) -> Result<()> {
+        // SAFETY: u64 has no invalid bit pattern, and the slice is viewed as bytes
+        // for its whole length only.
+        let buf = unsafe {
+            std::slice::from_raw_parts_mut(entries.as_mut_ptr() as *mut u8, entries.len() * 8)
+        };
+        self.pagemap
+            .read_exact_at(buf, hva / self.page_size * 8)
+            .with_context(|| format!("Failed to read pagemap of 0x{:x}", hva))
+    }
+}
+
+struct PassState {
+    start: Instant,
+    /// Submitted IO bytes when the pass starts.
+    io_bytes: u64,
+    advised_bytes: u64,
+}
+
+/// Start the memory aging thread for guest RAM.
+///
+/// # Arguments
+///
+/// * `config` - Config of memory aging.
+/// * `mappings` - Host mappings of guest RAM.
+pub(crate) fn start_mem_aging(
+    config: &MemAgingConfig,
+    mappings: &[Arc<HostMemMapping>],
+) -> Result<()> {
+    let mut scanner = MemAgingScanner::new(config, mappings)?;
+    // The probe also starts the first pass with clean soft-dirty bits.
+    if !scanner.soft_dirty_supported()? {
+        bail!("Soft-dirty bits are not supported by host kernel");
+    }
+    thread::Builder::new()
+        .name("mem-aging".to_string())
+        .spawn(move || loop {
+            thread::sleep(scanner.interval);
+            match scanner.scan() {
+                Ok(true) => {
+                    SCANS.fetch_add(1, Ordering::Relaxed);
+                }
+                Ok(false) => {
+                    info!("Guest memory aging pass aborted by heavy IO");
+                    ABORTED_SCANS.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => {
+                    error!("Stop guest memory aging: {:?}", e);
+                    MEM_AGING_ENABLED.store(false, Ordering::Relaxed);
+                    break;
+                }
+            }
+        })
+        .with_context(|| "Failed to create mem-aging thread")?;
+    MEM_AGING_ENABLED.store(true, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Get the counters of memory aging, return None if it is not running.
+pub(crate) fn qmp_query_mem_aging() -> Option<MemAgingInfo> {
+    if !MEM_AGING_ENABLED.load(Ordering::Relaxed) {
+        return None;
+    }
+    Some(MemAgingInfo {
+        scans: SCANS.load(Ordering::Relaxed),
+        aborted_scans: ABORTED_SCANS.load(Ordering::Relaxed),
+        aged_pages: AGED_PAGES.load(Ordering::Relaxed),
+        pinned_pages: PINNED_PAGES.load(Ordering::Relaxed),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use address_space::GuestAddress;
+
+    #[test]
+    fn test_page_age() {
+        assert_eq!(page_age(PM_PRESENT | PM_SOFT_DIRTY, 5), Some(0));
+        assert_eq!(page_age(PM_PRESENT, 5), Some(6));
+        assert_eq!(page_age(PM_PRESENT, u16::MAX), Some(u16::MAX));
+        // Swapped pages keep their state, unless written since the last pass.
+        assert_eq!(page_age(PM_SWAP, 5), None);
+        assert_eq!(page_age(PM_SWAP | PM_SOFT_DIRTY, 5), Some(0));
+        // Pages never touched or discarded are young.
+        assert_eq!(page_age(0, 5), Some(0));
+    }
+
+    #[test]
+    fn test_mem_aging_scan() {
+        let page_size = host_page_size();
+        let size = 16 * page_size;
+        let mapping = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, size, None, false, false, false).unwrap(),
+        );
+        let config = MemAgingConfig {
+            interval: 1,
+            threshold: 1,
+            rate: 1024,
+            advice: MemAgingAdvice::Cold,
+        };
+        let mut scanner = match MemAgingScanner::new(&config, &[mapping.clone()]) {
+            Ok(s) => s,
+            // Procfs may be unavailable in the test environment.
+            Err(_) => return,
+        };
+        // SAFETY: the mapping is anonymous memory with `size` bytes.
+        unsafe { std::ptr::write_bytes(mapping.host_address() as *mut u8, 1, size as usize) };
+        if !scanner.soft_dirty_supported().unwrap_or(false) {
+            return;
+        }
+        // Pages not written since the soft-dirty bits were cleared are advised once.
+        assert!(scanner.scan().is_ok());
+        assert!(scanner.ranges[0].advised.iter().all(|a| *a));
+        assert!(scanner.scan().is_ok());
+        assert!(scanner.ranges[0].ages.iter().all(|a| *a >= 2));
+
+        // Written pages become young again.
+        // SAFETY: the first page lies in the mapping.
+        unsafe { *(mapping.host_address() as *mut u8) = 2 };
+        assert!(scanner.scan().is_ok());
+        assert_eq!(scanner.ranges[0].ages[0], 0);
+        assert!(!scanner.ranges[0].advised[0]);
+    }
+}
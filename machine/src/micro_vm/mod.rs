@@ -38,38 +38,42 @@ mod mem_layout;
 mod syscall;
 
 use super::Result as MachineResult;
-use log::{error, info};
+use log::{error, info, warn};
 use std::collections::HashMap;
 use std::fmt;
 use std::fmt::Debug;
 use std::ops::Deref;
 use std::os::unix::io::RawFd;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use std::vec::Vec;
 
 use address_space::{AddressSpace, GuestAddress, Region};
-use boot_loader::{load_linux, BootLoaderConfig};
 #[cfg(target_arch = "aarch64")]
 use cpu::CPUFeatures;
 #[cfg(target_arch = "aarch64")]
 use cpu::PMU_INTR;
-use cpu::{CPUBootConfig, CPUTopology, CpuLifecycleState, CpuTopology, CPU};
+use cpu::{
+    cpus_inject_nmi, cpus_wakeup, CPUBootConfig, CPUTopology, CpuLifecycleState, CpuTopology, CPU,
+};
 #[cfg(target_arch = "aarch64")]
 use devices::legacy::PL031;
+use devices::legacy::{change_chardev, query_chardevs, register_mux_machine, FwCfgOps, Serial};
 #[cfg(target_arch = "x86_64")]
-use devices::legacy::SERIAL_ADDR;
-use devices::legacy::{FwCfgOps, Serial};
+use devices::legacy::{PitStub, SERIAL_ADDR};
 #[cfg(target_arch = "aarch64")]
 use devices::{ICGICConfig, ICGICv2Config, ICGICv3Config, InterruptController, GIC_IRQ_MAX};
 #[cfg(target_arch = "x86_64")]
-use hypervisor::kvm::KVM_FDS;
+use hypervisor::kvm::{KvmCaps, KVM_FDS};
 #[cfg(target_arch = "x86_64")]
 use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
+#[cfg(target_arch = "x86_64")]
+use machine_manager::config::RtcBase;
 use machine_manager::{
     config::{
-        parse_blk, parse_incoming_uri, parse_net, BlkDevConfig, BootSource, ConfigCheck, DriveFile,
-        Incoming, MigrateMode, NetworkInterfaceConfig, SerialConfig, VmConfig,
-        DEFAULT_VIRTQUEUE_SIZE,
+        get_chardev_backend, parse_blk, parse_error_policies, parse_incoming_uri, parse_net,
+        BlkDevConfig, BootSource, ChardevConfig, ConfigCheck, DriveFile, Incoming, MachineConfig,
+        MachineType, MigrateMode, NetworkInterfaceConfig, RebootAction, SerialConfig,
+        ShutdownAction, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
     },
     event,
     machine::{
@@ -90,11 +94,22 @@ use util::{
     loop_context::EventLoopManager, num_ops::str_to_usize, seccomp::BpfRule, set_termi_canon_mode,
 };
 use virtio::{
-    create_tap, qmp_balloon, qmp_query_balloon, Block, BlockState, Net, VhostKern, VirtioDevice,
-    VirtioMmioDevice, VirtioMmioState, VirtioNetState,
+    create_tap, qmp_balloon, qmp_block_job_cancel, qmp_block_resize, qmp_block_set_writable,
+    qmp_block_set_write_threshold, qmp_blockdev_backup, qmp_query_balloon, qmp_query_block,
+    qmp_query_block_jobs, qmp_query_blockstats, qmp_query_netdev, register_resizable_block, Block,
+    BlockState, Net, ScsiBus, VhostKern, VirtioDevice, VirtioError, VirtioMmioDevice,
+    VirtioMmioState, VirtioNetState,
 };
 
-use super::{error::MachineError, MachineOps};
+use super::{error::MachineError, qmp_query_memory_lock, MachineOps};
+#[cfg(target_arch = "aarch64")]
+use crate::boot::add_kernel2_mem_reserve;
+use crate::boot::{load_boot_plan, BootPlan};
+use crate::cpu_model::{qmp_query_cpu_definitions, qmp_query_cpu_model_expansion};
+use crate::dump::qmp_dump_guest_memory;
+use crate::mem_aging::qmp_query_mem_aging;
+use crate::placement::{self, qmp_query_placement};
+use crate::vcpu_rt::{enable_vcpu_realtime, RtAddressDispatcher};
 #[cfg(target_arch = "x86_64")]
 use crate::vm_state;
 use anyhow::{anyhow, bail, Context, Result};
@@ -103,6 +118,23 @@ use anyhow::{anyhow, bail, Context, Result};
 const MMIO_REPLACEABLE_BLK_NR: usize = 4;
 // The replaceable network device maximum count.
 const MMIO_REPLACEABLE_NET_NR: usize = 2;
+// The vCPUs maximum count, which are described by the MP table on x86_64.
+#[cfg(target_arch = "x86_64")]
+const MICRO_VM_MAX_CPUS: u16 = boot_loader::MPTABLE_MAX_CPUS;
+#[cfg(target_arch = "aarch64")]
+const MICRO_VM_MAX_CPUS: u16 = machine_manager::config::MAX_NR_CPUS as u16;
+
+/// The machine type of the micro VM, which is reported by `query-machines`.
+pub(crate) fn machine_type_info() -> qmp_schema::MachineInfo {
+    qmp_schema::MachineInfo {
+        hotplug: false,
+        name: "microvm".to_string(),
+        numa_mem_support: false,
+        cpu_max: MICRO_VM_MAX_CPUS,
+        deprecated: false,
+        is_default: MachineConfig::default().mach_type == MachineType::MicroVm,
+    }
+}
 
 // The config of replaceable device.
 #[derive(Debug)]
@@ -186,6 +218,12 @@ pub struct LightMachine {
     vm_config: Arc<Mutex<VmConfig>>,
     // Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    // Virtio-serial device.
+    virtio_serial: Option<Arc<Mutex<virtio::Serial>>>,
+    // Capabilities of KVM probed at startup.
+    kvm_caps: KvmCaps,
+    // Lifecycle interface of the machine itself, used by devices to pause the VM.
+    vm_lifecycle: Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>>,
 }
 
 impl LightMachine {
@@ -240,24 +278,36 @@ impl LightMachine {
             vm_state,
             vm_config: Arc::new(Mutex::new(vm_config.clone())),
             drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
+            virtio_serial: None,
+            kvm_caps: KvmCaps::default(),
+            vm_lifecycle: None,
         })
     }
 
     #[cfg(target_arch = "x86_64")]
-    fn arch_init() -> MachineResult<()> {
+    fn arch_init(kvm_caps: &KvmCaps, pit: bool) -> MachineResult<()> {
         let kvm_fds = KVM_FDS.load();
         let vm_fd = kvm_fds.vm_fd.as_ref().unwrap();
         vm_fd
             .set_tss_address(0xfffb_d000_usize)
             .with_context(|| anyhow!(MachineError::SetTssErr))?;
 
-        let pit_config = kvm_pit_config {
-            flags: KVM_PIT_SPEAKER_DUMMY,
-            pad: Default::default(),
-        };
-        vm_fd
-            .create_pit2(pit_config)
-            .with_context(|| anyhow!(MachineError::CrtPitErr))?;
+        if !pit {
+            if !kvm_caps.kvmclock {
+                bail!("The in-kernel PIT is disabled, but kvmclock is not supported by KVM");
+            }
+            info!("The in-kernel PIT is disabled, the guest uses kvmclock/TSC for timing");
+        } else if kvm_caps.pit2 {
+            let pit_config = kvm_pit_config {
+                flags: KVM_PIT_SPEAKER_DUMMY,
+                pad: Default::default(),
+            };
+            vm_fd
+                .create_pit2(pit_config)
+                .with_context(|| anyhow!(MachineError::CrtPitErr))?;
+        } else {
+            warn!("KVM_CAP_PIT2 is not supported, in-kernel PIT is not created");
+        }
 
         Ok(())
     }
@@ -265,10 +315,12 @@ impl LightMachine {
     fn create_replaceable_devices(&mut self) -> Result<()> {
         let mut rpl_devs: Vec<VirtioMmioDevice> = Vec::new();
         for id in 0..MMIO_REPLACEABLE_BLK_NR {
-            let block = Arc::new(Mutex::new(Block::new(
-                BlkDevConfig::default(),
-                self.get_drive_files(),
-            )));
+            let mut block = Block::new(BlkDevConfig::default(), self.get_drive_files());
+            if let Some(vm) = self.get_vm_lifecycle() {
+                block.set_vm(vm);
+            }
+            let block = Arc::new(Mutex::new(block));
+            register_resizable_block(&block);
             let virtio_mmio = VirtioMmioDevice::new(&self.sys_mem, block.clone());
             rpl_devs.push(virtio_mmio);
 
@@ -434,11 +486,12 @@ impl LightMachine {
     fn del_replaceable_device(&self, id: &str) -> Result<String> {
         // find the index of configuration by name and remove it
         let mut is_exist = false;
+        let mut drive_path = None;
         let mut configs_lock = self.replaceable_info.configs.lock().unwrap();
         for (index, config) in configs_lock.iter().enumerate() {
             if config.id == id {
                 if let Some(blkconf) = config.dev_config.as_any().downcast_ref::<BlkDevConfig>() {
-                    self.unregister_drive_file(&blkconf.path_on_host)?;
+                    drive_path = Some(blkconf.path_on_host.clone());
                 }
                 configs_lock.remove(index);
                 is_exist = true;
@@ -461,6 +514,11 @@ impl LightMachine {
             }
         }
 
+        // The drive file can be unregistered after the device is detached from it.
+        if let Some(path) = drive_path {
+            self.unregister_drive_file(&path)?;
+        }
+
         if !is_exist {
             bail!("Device {} not found", id);
         }
@@ -552,40 +610,20 @@ impl MachineOps for LightMachine {
         &self,
         fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>,
     ) -> MachineResult<CPUBootConfig> {
-        let boot_source = self.boot_source.lock().unwrap();
-        let initrd = boot_source.initrd.as_ref().map(|b| b.initrd_file.clone());
-
+        let mut boot_source = self.boot_source.lock().unwrap();
         let gap_start = MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].0
             + MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].1;
         let gap_end = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0;
-        let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
-            kernel_cmdline: boot_source.kernel_cmdline.to_string(),
+        let plan = BootPlan {
             cpu_count: self.cpu_topo.nrcpus,
             gap_range: (gap_start, gap_end - gap_start),
             ioapic_addr: MEM_LAYOUT[LayoutEntryType::IoApic as usize].0 as u32,
             lapic_addr: MEM_LAYOUT[LayoutEntryType::LocalApic as usize].0 as u32,
             ident_tss_range: None,
             prot64_mode: true,
+            reserved_ranges: Vec::new(),
         };
-        let layout = load_linux(&bootloader_config, &self.sys_mem, fwcfg)
-            .with_context(|| anyhow!(MachineError::LoadKernErr))?;
-
-        Ok(CPUBootConfig {
-            prot64_mode: true,
-            boot_ip: layout.boot_ip,
-            boot_sp: layout.boot_sp,
-            boot_selector: layout.boot_selector,
-            zero_page: layout.zero_page_addr,
-            code_segment: layout.segments.code_segment,
-            data_segment: layout.segments.data_segment,
-            gdt_base: layout.segments.gdt_base,
-            gdt_size: layout.segments.gdt_limit,
-            idt_base: layout.segments.idt_base,
-            idt_size: layout.segments.idt_limit,
-            pml4_start: layout.boot_pml4_addr,
-        })
+        load_boot_plan(&plan, &mut boot_source, &self.sys_mem, fwcfg)
     }
 
     #[cfg(target_arch = "aarch64")]
@@ -594,24 +632,10 @@ impl MachineOps for LightMachine {
         fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>,
     ) -> MachineResult<CPUBootConfig> {
         let mut boot_source = self.boot_source.lock().unwrap();
-        let initrd = boot_source.initrd.as_ref().map(|b| b.initrd_file.clone());
-
-        let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
+        let plan = BootPlan {
             mem_start: MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
         };
-        let layout = load_linux(&bootloader_config, &self.sys_mem, fwcfg)
-            .with_context(|| anyhow!(MachineError::LoadKernErr))?;
-        if let Some(rd) = &mut boot_source.initrd {
-            rd.initrd_addr = layout.initrd_start;
-            rd.initrd_size = layout.initrd_size;
-        }
-
-        Ok(CPUBootConfig {
-            fdt_addr: layout.dtb_start,
-            boot_pc: layout.boot_pc,
-        })
+        load_boot_plan(&plan, &mut boot_source, &self.sys_mem, fwcfg)
     }
 
     fn realize_virtio_mmio_device(
@@ -670,7 +694,7 @@ impl MachineOps for LightMachine {
     }
 
     #[cfg(target_arch = "x86_64")]
-    fn add_rtc_device(&mut self, _mem_size: u64) -> MachineResult<()> {
+    fn add_rtc_device(&mut self, _mem_size: u64, _rtc_base: RtcBase) -> MachineResult<()> {
         Ok(())
     }
 
@@ -752,8 +776,22 @@ impl MachineOps for LightMachine {
         self.drive_files.clone()
     }
 
+    fn get_virtio_serial(&mut self) -> &mut Option<Arc<Mutex<virtio::Serial>>> {
+        &mut self.virtio_serial
+    }
+
+    fn get_vm_lifecycle(&self) -> Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>> {
+        self.vm_lifecycle.clone()
+    }
+
     fn realize(vm: &Arc<Mutex<Self>>, vm_config: &mut VmConfig) -> MachineResult<()> {
+        let kvm_caps = KVM_FDS.load().probe_caps()?;
         let mut locked_vm = vm.lock().unwrap();
+        locked_vm.kvm_caps = kvm_caps;
+        let vm_lifecycle: Arc<Mutex<dyn MachineLifecycle + Send + Sync>> = vm.clone();
+        locked_vm.vm_lifecycle = Some(Arc::downgrade(&vm_lifecycle));
+        let mux_machine: Arc<Mutex<dyn MachineExternalInterface + Send + Sync>> = vm.clone();
+        register_mux_machine(&mux_machine);
 
         //trace for lightmachine
         trace_sysbus(&locked_vm.sysbus);
@@ -766,6 +804,8 @@ impl MachineOps for LightMachine {
         ));
         trace_cpu_topo(&topology);
 
+        placement::auto_place(vm_config, &None)?;
+        placement::pin_vcpus(vm_config)?;
         locked_vm.init_memory(
             &vm_config.machine_config.mem_config,
             #[cfg(target_arch = "x86_64")]
@@ -779,7 +819,10 @@ impl MachineOps for LightMachine {
         #[cfg(target_arch = "x86_64")]
         {
             locked_vm.init_interrupt_controller(u64::from(vm_config.machine_config.nr_cpus))?;
-            LightMachine::arch_init()?;
+            LightMachine::arch_init(&locked_vm.kvm_caps, vm_config.machine_config.pit)?;
+            if !vm_config.machine_config.pit {
+                PitStub::realize(&locked_vm.sys_io)?;
+            }
 
             // Add mmio devices
             locked_vm
@@ -801,6 +844,9 @@ impl MachineOps for LightMachine {
                 &topology,
                 &boot_config,
             )?);
+            for cpu in locked_vm.cpus.iter() {
+                cpu.set_cpuid_features(vm_config.machine_config.cpu_config.features.clone());
+            }
         }
 
         #[cfg(target_arch = "aarch64")]
@@ -836,6 +882,7 @@ impl MachineOps for LightMachine {
 
             if let Some(boot_cfg) = boot_config {
                 let mut fdt_helper = FdtBuilder::new();
+                add_kernel2_mem_reserve(&mut fdt_helper, &locked_vm.boot_source.lock().unwrap())?;
                 locked_vm
                     .generate_fdt_node(&mut fdt_helper)
                     .with_context(|| anyhow!(MachineError::GenFdtErr))?;
@@ -853,6 +900,18 @@ impl MachineOps for LightMachine {
             }
         }
 
+        if vm_config.machine_config.cpu_config.realtime {
+            let dispatcher = RtAddressDispatcher::new(
+                #[cfg(target_arch = "x86_64")]
+                &locked_vm.sys_io,
+                &locked_vm.sys_mem,
+                #[cfg(target_arch = "x86_64")]
+                vec![0x61],
+                vm.clone(),
+            );
+            enable_vcpu_realtime(&locked_vm.cpus, Arc::new(dispatcher))?;
+        }
+
         MigrationManager::register_vm_instance(vm.clone());
         #[cfg(target_arch = "x86_64")]
         MigrationManager::register_kvm_instance(
@@ -896,7 +955,7 @@ impl MachineLifecycle for LightMachine {
             *state
         };
 
-        if !self.notify_lifecycle(vmstate, KvmVmState::Shutdown) {
+        if !self.notify_lifecycle(vmstate, KvmVmState::Destroyed) {
             return false;
         }
 
@@ -907,14 +966,32 @@ impl MachineLifecycle for LightMachine {
         true
     }
 
+    fn get_shutdown_action(&self) -> ShutdownAction {
+        self.vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .shutdown_action
+    }
+
+    fn get_reboot_action(&self) -> RebootAction {
+        self.vm_config.lock().unwrap().machine_config.reboot_action
+    }
+
     fn reset(&mut self) -> bool {
+        if self.get_reboot_action() == RebootAction::Pause {
+            return self.pause();
+        }
+
         // For micro vm, the reboot command is equivalent to the shutdown command.
-        for cpu in self.cpus.iter() {
-            let (cpu_state, _) = cpu.state();
-            *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
+        if self.get_shutdown_action() == ShutdownAction::ShutdownActionPoweroff {
+            for cpu in self.cpus.iter() {
+                let (cpu_state, _) = cpu.state();
+                *cpu_state.lock().unwrap() = CpuLifecycleState::Stopped;
+            }
         }
 
-        self.destroy()
+        self.shutdown_by_guest("guest-reset")
     }
 
     fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
@@ -983,6 +1060,16 @@ impl DeviceInterface for LightMachine {
                 running: false,
                 status: qmp_schema::RunState::paused,
             },
+            KvmVmState::Suspended => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: qmp_schema::RunState::suspended,
+            },
+            KvmVmState::Shutdown => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: qmp_schema::RunState::shutdown,
+            },
             _ => Default::default(),
         };
 
@@ -1004,12 +1091,16 @@ impl DeviceInterface for LightMachine {
                     props: Some(cpu_instance),
                     CPU: cpu_index as isize,
                     thread_id: thread_id as isize,
+                    host_cpus: self.cpus[cpu_index as usize].affinity(),
                 };
                 #[cfg(target_arch = "x86_64")]
                 {
+                    let tsc_khz = self.cpus[cpu_index as usize].tsc_khz();
                     let cpu_info = qmp_schema::CpuInfo::x86 {
                         common: cpu_common,
-                        x86: qmp_schema::CpuInfoX86 {},
+                        x86: qmp_schema::CpuInfoX86 {
+                            tsc_khz: (tsc_khz != 0).then_some(tsc_khz),
+                        },
                     };
                     cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
                 }
@@ -1026,54 +1117,87 @@ impl DeviceInterface for LightMachine {
         Response::create_response(cpu_vec.into(), None)
     }
 
+    fn query_cpu_model_expansion(
+        &self,
+        expansion_type: String,
+        model: qmp_schema::CpuModelInfo,
+    ) -> Response {
+        let cpu_config = self
+            .vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .cpu_config
+            .clone();
+        match qmp_query_cpu_model_expansion(&self.cpus, &cpu_config, &expansion_type, &model) {
+            Ok(info) => Response::create_response(serde_json::to_value(&info).unwrap(), None),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn query_cpu_definitions(&self) -> Response {
+        Response::create_response(
+            serde_json::to_value(qmp_query_cpu_definitions()).unwrap(),
+            None,
+        )
+    }
+
     fn query_hotpluggable_cpus(&self) -> Response {
-        let mut hotplug_vec: Vec<serde_json::Value> = Vec::new();
-        #[cfg(target_arch = "x86_64")]
-        let cpu_type = String::from("host-x86-cpu");
-        #[cfg(target_arch = "aarch64")]
-        let cpu_type = String::from("host-aarch64-cpu");
+        let hotplug_vec = self.cpu_topo.get_hotpluggable_cpus_for_qmp();
+        Response::create_response(serde_json::to_value(hotplug_vec).unwrap(), None)
+    }
 
-        for cpu_index in 0..self.cpu_topo.max_cpus {
-            if self.cpu_topo.get_mask(cpu_index as usize) == 0 {
-                let cpu_instance = self.cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
-                let hotpluggable_cpu = qmp_schema::HotpluggableCPU {
-                    type_: cpu_type.clone(),
-                    vcpus_count: 1,
-                    props: cpu_instance,
-                    qom_path: None,
-                };
-                hotplug_vec.push(serde_json::to_value(hotpluggable_cpu).unwrap());
-            } else {
-                let cpu_instance = self.cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
-                let hotpluggable_cpu = qmp_schema::HotpluggableCPU {
-                    type_: cpu_type.clone(),
-                    vcpus_count: 1,
-                    props: cpu_instance,
-                    qom_path: Some(
-                        String::from("/machine/unattached/device[") + &cpu_index.to_string() + "]",
-                    ),
-                };
-                hotplug_vec.push(serde_json::to_value(hotpluggable_cpu).unwrap());
-            }
-        }
-        Response::create_response(hotplug_vec.into(), None)
+    fn query_kvm(&self) -> Response {
+        let kvm_fds = KVM_FDS.load();
+        let kvm = qmp_schema::KvmInfo {
+            enabled: kvm_fds.vm_fd.is_some(),
+            present: kvm_fds.fd.is_some(),
+        };
+        Response::create_response(serde_json::to_value(kvm).unwrap(), None)
     }
 
     fn balloon(&self, value: u64) -> Response {
-        if qmp_balloon(value) {
-            return Response::create_empty_response();
+        if let Err(e) = qmp_balloon(value) {
+            let err = match e.downcast_ref::<VirtioError>() {
+                Some(VirtioError::DeviceNotActivated(_)) => {
+                    qmp_schema::QmpErrorClass::DeviceNotActive(
+                        "No balloon device has been activated".to_string(),
+                    )
+                }
+                _ => qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            };
+            return Response::create_error_response(err, None);
         }
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::DeviceNotActive(
-                "No balloon device has been activated".to_string(),
+        Response::create_empty_response()
+    }
+
+    fn inject_nmi(&self) -> Response {
+        let vm_state = *self.vm_state.0.lock().unwrap();
+        match cpus_inject_nmi(&self.cpus, vm_state) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
             ),
-            None,
-        )
+        }
+    }
+
+    fn system_wakeup(&self) -> Response {
+        if let Err(e) = cpus_wakeup(&self.cpus, &mut self.vm_state.0.lock().unwrap()) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        event!(Wakeup);
+        Response::create_empty_response()
     }
 
     fn query_balloon(&self) -> Response {
-        if let Some(actual) = qmp_query_balloon() {
-            let ret = qmp_schema::BalloonInfo { actual };
+        if let Some(ret) = qmp_query_balloon() {
             return Response::create_response(serde_json::to_value(&ret).unwrap(), None);
         }
         Response::create_error_response(
@@ -1084,6 +1208,32 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    fn query_mem_aging(&self) -> Response {
+        if let Some(info) = qmp_query_mem_aging() {
+            return Response::create_response(serde_json::to_value(&info).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Memory aging is not running".to_string()),
+            None,
+        )
+    }
+
+    fn query_memory_lock(&self) -> Response {
+        let vm_config = self.get_vm_config();
+        let info = qmp_query_memory_lock(&vm_config.lock().unwrap().machine_config.mem_config);
+        Response::create_response(serde_json::to_value(&info).unwrap(), None)
+    }
+
+    fn query_placement(&self) -> Response {
+        if let Some(info) = qmp_query_placement() {
+            return Response::create_response(serde_json::to_value(&info).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Auto placement is not enabled".to_string()),
+            None,
+        )
+    }
+
     /// VNC is not supported by light machine currently.
     fn query_vnc(&self) -> Response {
         Response::create_error_response(
@@ -1094,6 +1244,131 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    fn query_name(&self) -> Response {
+        let name_info = qmp_schema::NameInfo::new(&self.vm_config.lock().unwrap().guest_name);
+        Response::create_response(serde_json::to_value(&name_info).unwrap(), None)
+    }
+
+    fn query_uuid(&self) -> Response {
+        let uuid_info = qmp_schema::UuidInfo::new(self.vm_config.lock().unwrap().uuid.as_deref());
+        Response::create_response(serde_json::to_value(&uuid_info).unwrap(), None)
+    }
+
+    fn query_chardev(&self) -> Response {
+        Response::create_response(serde_json::to_value(query_chardevs()).unwrap(), None)
+    }
+
+    fn query_vsock(&self) -> Response {
+        let vsocks = match self.vm_config.lock().unwrap().get_vsocks() {
+            Ok(vsocks) => vsocks,
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                );
+            }
+        };
+        let info: Vec<qmp_schema::VsockInfo> = vsocks
+            .into_iter()
+            .map(|vsock| qmp_schema::VsockInfo {
+                id: vsock.id,
+                guest_cid: vsock.guest_cid,
+                vhost_fd: vsock.vhost_fd,
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(info).unwrap(), None)
+    }
+
+    fn query_block(&self, reset_errors: Option<bool>) -> Response {
+        let info = qmp_query_block(reset_errors.unwrap_or(false));
+        Response::create_response(serde_json::to_value(info).unwrap(), None)
+    }
+
+    fn query_blockstats(&self) -> Response {
+        let stats = qmp_query_blockstats();
+        Response::create_response(serde_json::to_value(stats).unwrap(), None)
+    }
+
+    fn query_netdev(&self, reset_errors: Option<bool>) -> Response {
+        let info = qmp_query_netdev(reset_errors.unwrap_or(false));
+        Response::create_response(serde_json::to_value(info).unwrap(), None)
+    }
+
+    fn block_set_write_threshold(&self, device: String, write_threshold: u64) -> Response {
+        match qmp_block_set_write_threshold(&device, write_threshold) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn block_set_writable(&self, device: String) -> Response {
+        match qmp_block_set_writable(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn block_resize(&self, device: String, size: u64, allow_shrink: Option<bool>) -> Response {
+        match qmp_block_resize(&device, size, allow_shrink.unwrap_or(false)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn eject(&self, device: String, force: Option<bool>) -> Response {
+        match ScsiBus::qmp_eject(&device, force.unwrap_or(false)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn blockdev_backup(&self, device: String, target: String, sync: Option<String>) -> Response {
+        match qmp_blockdev_backup(&device, &target, sync.as_deref()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn block_job_cancel(&self, device: String) -> Response {
+        match qmp_block_job_cancel(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn query_block_jobs(&self) -> Response {
+        let jobs = qmp_query_block_jobs();
+        Response::create_response(serde_json::to_value(jobs).unwrap(), None)
+    }
+
+    fn dump_guest_memory(&mut self, args: qmp_schema::DumpGuestMemoryArgument) -> Response {
+        match qmp_dump_guest_memory(self, &self.vm_state, &args, &self.sys_mem, &self.cpus) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
         // get slot of bus by addr or lun
         let mut slot = 0;
@@ -1158,6 +1433,16 @@ impl DeviceInterface for LightMachine {
             true
         };
 
+        let (werror, rerror) =
+            match parse_error_policies(args.werror.as_deref(), args.rerror.as_deref()) {
+                Ok(policies) => policies,
+                Err(e) => {
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    );
+                }
+            };
         let config = BlkDevConfig {
             id: args.node_name.clone(),
             path_on_host: args.file.filename.clone(),
@@ -1166,17 +1451,23 @@ impl DeviceInterface for LightMachine {
             serial_num: None,
             iothread: None,
             iops: None,
+            throttle_group: None,
             queues: 1,
             boot_index: None,
             chardev: None,
             socket_path: None,
+            socket_reconnect: 0,
             // TODO Add aio option by qmp, now we set it based on "direct".
             aio: if direct {
                 AioEngine::Native
             } else {
                 AioEngine::Off
             },
+            aio_register: false,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            werror,
+            rerror,
+            slow_io_warn_ms: 0,
         };
         if let Err(e) = config.check() {
             error!("{:?}", e);
@@ -1217,16 +1508,25 @@ impl DeviceInterface for LightMachine {
     fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
         let mut config = NetworkInterfaceConfig {
             id: args.id.clone(),
+            netdev: args.id.clone(),
             host_dev_name: "".to_string(),
+            script: None,
+            downscript: None,
             mac: None,
             tap_fds: None,
             vhost_type: None,
             vhost_fds: None,
             iothread: None,
+            queue_iothreads: None,
             queues: 2,
             mq: false,
             socket_path: None,
+            chardev: None,
+            socket_reconnect: 0,
+            mtu: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            rate_limit_bps: None,
+            rate_limit_pps: None,
         };
 
         if let Some(fds) = args.fds {
@@ -1307,6 +1607,24 @@ impl DeviceInterface for LightMachine {
         )
     }
 
+    fn chardev_change(&mut self, id: String, backend: qmp_schema::BackendOptions) -> Response {
+        let result = get_chardev_backend(backend).and_then(|backend| {
+            ChardevConfig {
+                id: id.clone(),
+                backend: backend.clone(),
+            }
+            .check()?;
+            change_chardev(&id, backend)
+        });
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
     fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response {
         if let Some(fd) = if_fd {
             QmpChannel::set_fd(fd_name, fd);
@@ -1356,7 +1674,7 @@ impl MachineExternalInterface for LightMachine {}
 impl EventLoopManager for LightMachine {
     fn loop_should_exit(&self) -> bool {
         let vmstate = self.vm_state.deref().0.lock().unwrap();
-        *vmstate == KvmVmState::Shutdown
+        *vmstate == KvmVmState::Destroyed
     }
 
     fn loop_cleanup(&self) -> util::Result<()> {
@@ -10,9 +10,14 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use crate::mem_aging::{MADV_COLD, MADV_PAGEOUT};
 use hypervisor::kvm::*;
+use util::file::{BLKDISCARD, BLKGETSIZE64, BLKPBSZGET, BLKSSZGET};
 use util::seccomp::{BpfRule, SeccompCmpOpt};
-use util::tap::{TUNGETFEATURES, TUNSETIFF, TUNSETOFFLOAD, TUNSETVNETHDRSZ};
+use util::tap::{
+    TUNGETFEATURES, TUNGETIFF, TUNSETIFF, TUNSETOFFLOAD, TUNSETQUEUE, TUNSETVNETHDRSZ,
+};
+use util::userfaultfd::{UFFDIO_COPY, UFFDIO_UNREGISTER};
 use virtio::VhostKern::*;
 
 /// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/linux/futex.h
@@ -48,10 +53,10 @@ const KVM_RUN: u32 = 0xae80;
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * x86_64-unknown-gnu: 48 syscalls
-/// * x86_64-unknown-musl: 47 syscalls
-/// * aarch64-unknown-gnu: 46 syscalls
-/// * aarch64-unknown-musl: 46 syscalls
+/// * x86_64-unknown-gnu: 52 syscalls
+/// * x86_64-unknown-musl: 51 syscalls
+/// * aarch64-unknown-gnu: 50 syscalls
+/// * aarch64-unknown-musl: 50 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 pub fn syscall_whitelist() -> Vec<BpfRule> {
     vec![
@@ -74,7 +79,10 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_close),
         BpfRule::new(libc::SYS_eventfd2),
         BpfRule::new(libc::SYS_epoll_ctl),
+        BpfRule::new(libc::SYS_ppoll),
         BpfRule::new(libc::SYS_fdatasync),
+        // `block_resize` and `blockdev-backup` truncate image files at runtime.
+        BpfRule::new(libc::SYS_ftruncate),
         BpfRule::new(libc::SYS_recvmsg),
         BpfRule::new(libc::SYS_sendmsg),
         BpfRule::new(libc::SYS_recvfrom),
@@ -122,6 +130,10 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_arch = "aarch64")]
         BpfRule::new(libc::SYS_unlinkat),
         #[cfg(target_arch = "x86_64")]
+        BpfRule::new(libc::SYS_rename),
+        BpfRule::new(libc::SYS_renameat),
+        BpfRule::new(libc::SYS_renameat2),
+        #[cfg(target_arch = "x86_64")]
         BpfRule::new(libc::SYS_mkdir),
         #[cfg(target_arch = "aarch64")]
         BpfRule::new(libc::SYS_mkdirat),
@@ -129,6 +141,10 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_readlink),
         BpfRule::new(libc::SYS_getrandom),
         madvise_rule(),
+        BpfRule::new(libc::SYS_nanosleep),
+        BpfRule::new(libc::SYS_clock_nanosleep),
+        BpfRule::new(libc::SYS_sched_setaffinity),
+        BpfRule::new(libc::SYS_sched_setscheduler),
     ]
 }
 
@@ -155,9 +171,17 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_SET_MEM_TABLE() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_NET_SET_BACKEND() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNGETFEATURES() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, TUNGETIFF() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETIFF() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETOFFLOAD() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETVNETHDRSZ() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETQUEUE() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKGETSIZE64() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKSSZGET() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKPBSZGET() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKDISCARD() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_COPY() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_UNREGISTER() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_API_VERSION() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_MP_STATE() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_VCPU_EVENTS() as u32);
@@ -168,6 +192,7 @@ fn ioctl_allow_list() -> BpfRule {
 fn ioctl_arch_allow_list(bpf_rule: BpfRule) -> BpfRule {
     bpf_rule
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_PIT2() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_NMI() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_CLOCK() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_IRQCHIP() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_REGS() as u32)
@@ -194,12 +219,16 @@ fn madvise_rule() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_FREE as u32)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_DONTNEED as u32)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_WILLNEED as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32);
+        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_COLD as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_PAGEOUT as u32);
     #[cfg(not(target_env = "musl"))]
     return BpfRule::new(libc::SYS_madvise)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_DONTNEED as u32)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_WILLNEED as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32);
+        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_COLD as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_PAGEOUT as u32);
 }
 
 fn futex_rule() -> BpfRule {
@@ -223,3 +252,89 @@ fn futex_rule() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, FUTEX_WAKE_OP_PRIVATE)
         .add_constraint(SeccompCmpOpt::Eq, 1, FUTEX_WAIT_BITSET_PRIVATE);
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, Instant};
+
+    use anyhow::Result;
+    use machine_manager::event_loop::EventLoop;
+    use machine_manager::qga::{
+        guest_agent_command, guest_agent_output, register_guest_agent_channel, GuestAgentChannel,
+    };
+    use serde_json::{json, Value};
+    use util::seccomp::{SeccompOpt, SyscallFilter};
+
+    use super::*;
+
+    /// The port of the guest agent, which records the data sent to it.
+    struct TestChannel(Arc<Mutex<Vec<u8>>>);
+
+    impl GuestAgentChannel for TestChannel {
+        fn send(&mut self, buf: &[u8]) -> Result<()> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    /// Run `guest-agent-command` under the seccomp filter, return whether the first
+    /// command is replied and the second one times out.
+    fn run_guest_agent_command() -> Result<bool> {
+        EventLoop::object_init(&None)?;
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        register_guest_agent_channel(Arc::new(Mutex::new(TestChannel(sent.clone()))));
+
+        let mut seccomp_filter = SyscallFilter::new(SeccompOpt::Trap);
+        for bpf_rule in &mut syscall_whitelist() {
+            seccomp_filter.push(bpf_rule);
+        }
+        seccomp_filter.realize()?;
+
+        let results = Arc::new(Mutex::new(Vec::new()));
+        for _ in 0..2 {
+            let cloned_results = results.clone();
+            guest_agent_command(
+                json!({"execute": "guest-ping"}),
+                Duration::from_millis(10),
+                Box::new(move |ret| cloned_results.lock().unwrap().push(ret.is_ok())),
+            )?;
+        }
+        let cmd: Value = serde_json::from_slice(&std::mem::take(&mut *sent.lock().unwrap()))?;
+        let reply = json!({"return": {}, "id": cmd["id"]});
+        guest_agent_output(format!("{}\n", reply).as_bytes());
+
+        // The next command is sent and times out in the main loop.
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while results.lock().unwrap().len() < 2 && Instant::now() < deadline {
+            EventLoop::get_ctx(None).unwrap().run()?;
+        }
+        let ret = *results.lock().unwrap() == [true, false];
+        Ok(ret && !sent.lock().unwrap().is_empty())
+    }
+
+    #[test]
+    fn test_guest_agent_command_seccomp() {
+        // SAFETY: The child only runs the guest agent commands and exits.
+        let pid = unsafe { libc::fork() };
+        assert!(pid >= 0);
+        if pid == 0 {
+            let code = match run_guest_agent_command() {
+                Ok(true) => 0,
+                _ => 1,
+            };
+            // SAFETY: Exit the child without running the test harness.
+            unsafe { libc::_exit(code) };
+        }
+
+        let mut status = 0;
+        // SAFETY: The pid is the child forked above.
+        assert_eq!(unsafe { libc::waitpid(pid, &mut status, 0) }, pid);
+        // The child is killed by SIGSYS if any syscall is not in the whitelist.
+        assert!(
+            libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0,
+            "child exits with status {:#x}",
+            status
+        );
+    }
+}
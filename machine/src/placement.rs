@@ -0,0 +1,720 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Automatic placement of the VM on host NUMA nodes.
+//!
+//! With `-auto-placement numa`, each guest NUMA node is placed on one host node: its
+//! vCPUs are pinned to the host cpus of the node, and its memory is bound to the node.
+//! A guest without NUMA config is placed as a single node. Iothreads are pinned to the
+//! host node of the devices backing their drives, if it can be found in sysfs.
+//!
+//! Explicit settings always win: a memory backend with `host-nodes` keeps its binding,
+//! and the guest node using it is placed on one of these host nodes if possible. Only
+//! the host cpus the process is allowed to run on are used.
+//!
+//! vCPUs pinned by `-cpu-pin` keep their host cpu, whether auto placement is enabled or
+//! not. `-cpu-pin` also sets the realtime priority of the vCPU threads.
+
+use std::cmp::max;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use machine_manager::config::{
+    parse_numa_mem, AutoPlacement, CpuPinConfig, MemZoneConfig, NumaNodes, VmConfig,
+};
+use machine_manager::event_loop::EventLoop;
+use machine_manager::qmp::qmp_schema::{IothreadPlacement, NodePlacement, PlacementInfo};
+use util::unix::{check_thread_sched_priority, get_thread_affinity};
+
+/// Sysfs directory of host NUMA nodes.
+const HOST_NODE_PATH: &str = "/sys/devices/system/node";
+/// Sysfs directory of all devices, where the search for the node of a drive stops.
+const SYS_DEVICES_PATH: &str = "/sys/devices";
+/// Id of the memory zone created to bind the memory of a guest without memory backends.
+const AUTO_MEM_ZONE_ID: &str = "auto-placement";
+
+/// The computed placement, `None` if auto placement is not enabled.
+static PLACEMENT: Mutex<Option<PlacementInfo>> = Mutex::new(None);
+/// The vCPU pinning set by `-cpu-pin`.
+static CPU_PIN: Mutex<Option<CpuPinConfig>> = Mutex::new(None);
+
+/// A host NUMA node.
+#[derive(Clone, Debug)]
+struct HostNode {
+    id: u32,
+    /// Host cpus of the node which the process is allowed to run on.
+    cpus: Vec<usize>,
+    /// Free memory of the node in bytes.
+    free_mem: u64,
+}
+
+/// A guest NUMA node to be placed.
+#[derive(Clone, Debug)]
+struct GuestNode {
+    id: u32,
+    vcpus: Vec<u16>,
+    mem_size: u64,
+    /// Host nodes set by `host-nodes` of the memory backend of the node.
+    bound_nodes: Option<Vec<u32>>,
+}
+
+/// Parse a cpu list of sysfs, e.g. `0-3,8,10-11`.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.trim().split(',').filter(|r| !r.is_empty()) {
+        let (start, end) = match range.split_once('-') {
+            Some((start, end)) => (start.parse::<usize>(), end.parse::<usize>()),
+            None => (range.parse::<usize>(), range.parse::<usize>()),
+        };
+        let (start, end) = (
+            start.with_context(|| format!("Invalid cpu list {}", list))?,
+            end.with_context(|| format!("Invalid cpu list {}", list))?,
+        );
+        if start > end {
+            bail!("Invalid cpu list {}", list);
+        }
+        cpus.extend(start..=end);
+    }
+    Ok(cpus)
+}
+
+/// Get the free memory in bytes from the meminfo of a host node.
+fn parse_node_mem_free(meminfo: &str) -> Option<u64> {
+    // The line is like "Node 0 MemFree:   1024 kB".
+    meminfo.lines().find_map(|line| {
+        let mut fields = line.split_whitespace().skip(2);
+        if fields.next()? != "MemFree:" {
+            return None;
+        }
+        fields.next()?.parse::<u64>().ok().map(|kb| kb * 1024)
+    })
+}
+
+/// Read the topology of host NUMA nodes from sysfs.
+fn host_nodes() -> Result<Vec<HostNode>> {
+    let allowed_cpus = get_thread_affinity()?;
+    let mut nodes = Vec::new();
+    let entries = fs::read_dir(HOST_NODE_PATH)
+        .with_context(|| format!("Failed to read {}", HOST_NODE_PATH))?;
+    for entry in entries {
+        let entry = entry?;
+        let id = match entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.strip_prefix("node"))
+            .and_then(|id| id.parse::<u32>().ok())
+        {
+            Some(id) => id,
+            None => continue,
+        };
+        let cpu_list = fs::read_to_string(entry.path().join("cpulist"))
+            .with_context(|| format!("Failed to read cpus of host node {}", id))?;
+        let cpus = parse_cpu_list(&cpu_list)?
+            .into_iter()
+            .filter(|cpu| allowed_cpus.contains(cpu))
+            .collect();
+        let meminfo = fs::read_to_string(entry.path().join("meminfo"))
+            .with_context(|| format!("Failed to read meminfo of host node {}", id))?;
+        nodes.push(HostNode {
+            id,
+            cpus,
+            free_mem: parse_node_mem_free(&meminfo).unwrap_or(0),
+        });
+    }
+    nodes.sort_by_key(|node| node.id);
+    Ok(nodes)
+}
+
+/// Find the host NUMA node of the device backing a drive file from sysfs.
+fn drive_host_node(path: &str) -> Option<u32> {
+    let metadata = fs::metadata(path).ok()?;
+    let dev = if metadata.file_type().is_block_device() {
+        metadata.rdev()
+    } else {
+        metadata.dev()
+    };
+    // SAFETY: major() and minor() only do bit operations.
+    let (major, minor) = unsafe { (libc::major(dev), libc::minor(dev)) };
+    let sys_path = format!("/sys/dev/block/{}:{}", major, minor);
+    let mut dir: PathBuf = fs::canonicalize(sys_path).ok()?;
+    // Partitions and namespaces don't have the node, which is found on the device they belong to.
+    loop {
+        if let Ok(node) = fs::read_to_string(dir.join("numa_node")) {
+            // -1 means the device is not attached to any node.
+            return node.trim().parse::<u32>().ok();
+        }
+        if !dir.pop() || dir == Path::new(SYS_DEVICES_PATH) {
+            return None;
+        }
+    }
+}
+
+/// Get the drive files served by each iothread from the device config.
+fn iothread_drives(vm_config: &VmConfig) -> HashMap<String, Vec<String>> {
+    let devices: Vec<HashMap<&str, &str>> = vm_config
+        .devices
+        .iter()
+        .map(|(_, cfg)| cfg.split(',').filter_map(|p| p.split_once('=')).collect())
+        .collect();
+    let mut drives: HashMap<String, Vec<String>> = HashMap::new();
+    for dev in devices.iter() {
+        let drive = match dev.get("drive").and_then(|id| vm_config.drives.get(*id)) {
+            Some(drive) => drive,
+            None => continue,
+        };
+        // Scsi disks are served by the iothread of their controller.
+        let iothread = dev.get("iothread").copied().or_else(|| {
+            let cntlr = dev.get("bus")?.split('.').next()?;
+            devices
+                .iter()
+                .find(|d| d.get("id") == Some(&cntlr))?
+                .get("iothread")
+                .copied()
+        });
+        if let Some(iothread) = iothread {
+            drives
+                .entry(iothread.to_string())
+                .or_default()
+                .push(drive.path_on_host.clone());
+        }
+    }
+    drives
+}
+
+/// Collect the guest nodes to be placed, with the memory backend of each one.
+fn guest_nodes(
+    vm_config: &VmConfig,
+    numa_nodes: &Option<NumaNodes>,
+) -> Result<Vec<(GuestNode, Option<String>)>> {
+    let numa_nodes = match numa_nodes {
+        Some(nodes) => nodes,
+        None => {
+            let machine_config = &vm_config.machine_config;
+            let nr_vcpus = max(machine_config.nr_cpus, machine_config.max_cpus);
+            let node = GuestNode {
+                id: 0,
+                vcpus: (0..nr_vcpus).collect(),
+                mem_size: machine_config.mem_config.mem_size,
+                bound_nodes: None,
+            };
+            return Ok(vec![(node, None)]);
+        }
+    };
+
+    let mut mem_devs = HashMap::new();
+    for (kind, cfg) in vm_config.numa_nodes.iter() {
+        if kind == "node" {
+            let numa_config = parse_numa_mem(cfg)?;
+            mem_devs.insert(numa_config.numa_id, numa_config.mem_dev);
+        }
+    }
+    let mem_zones = vm_config.machine_config.mem_config.mem_zones.as_deref();
+    let mut nodes = Vec::new();
+    for (id, node) in numa_nodes.iter() {
+        let mem_dev = mem_devs.remove(id);
+        let bound_nodes = mem_zones
+            .and_then(|zones| zones.iter().find(|z| Some(&z.id) == mem_dev.as_ref()))
+            .and_then(|zone| zone.host_numa_nodes.clone());
+        let guest_node = GuestNode {
+            id: *id,
+            vcpus: node.cpus.clone(),
+            mem_size: node.size,
+            bound_nodes,
+        };
+        nodes.push((guest_node, mem_dev));
+    }
+    Ok(nodes)
+}
+
+/// Assign each guest node to a host node, the ids of host nodes are returned in the
+/// order of `guest_nodes`.
+///
+/// Guest nodes are placed from the largest one. A guest node is placed on the host node
+/// which has enough free memory for it, then has the fewest vCPUs per host cpu after
+/// placing it, then has the most free memory. A guest node with memory bound by
+/// `host-nodes` only chooses from these host nodes.
+fn assign_nodes(guest_nodes: &[GuestNode], host_nodes: &[HostNode]) -> Result<Vec<u32>> {
+    let candidates: Vec<&HostNode> = host_nodes.iter().filter(|n| !n.cpus.is_empty()).collect();
+    if candidates.is_empty() {
+        bail!("No host NUMA node has cpus to run the VM");
+    }
+    let mut free_mem: Vec<u64> = candidates.iter().map(|n| n.free_mem).collect();
+    let mut nr_vcpus: Vec<usize> = vec![0; candidates.len()];
+
+    let mut order: Vec<usize> = (0..guest_nodes.len()).collect();
+    order.sort_by(|a, b| {
+        let (a, b) = (&guest_nodes[*a], &guest_nodes[*b]);
+        b.bound_nodes
+            .is_some()
+            .cmp(&a.bound_nodes.is_some())
+            .then(b.mem_size.cmp(&a.mem_size))
+            .then(b.vcpus.len().cmp(&a.vcpus.len()))
+            .then(a.id.cmp(&b.id))
+    });
+
+    let mut assigned = vec![0; guest_nodes.len()];
+    for idx in order {
+        let guest = &guest_nodes[idx];
+        let mut choices: Vec<usize> = (0..candidates.len())
+            .filter(|i| {
+                guest
+                    .bound_nodes
+                    .as_ref()
+                    .map_or(true, |nodes| nodes.contains(&candidates[*i].id))
+            })
+            .collect();
+        if choices.is_empty() {
+            // The memory is bound to host nodes without cpus, place the vCPUs anywhere.
+            choices = (0..candidates.len()).collect();
+        }
+
+        let fits = |i: usize| free_mem[i] >= guest.mem_size;
+        // Cross multiplied to compare (vcpus of i) / (cpus of i) with the one of j.
+        let load =
+            |i: usize, j: usize| (nr_vcpus[i] + guest.vcpus.len()) * candidates[j].cpus.len();
+        let best = choices
+            .into_iter()
+            .min_by(|a, b| {
+                fits(*b)
+                    .cmp(&fits(*a))
+                    .then(load(*a, *b).cmp(&load(*b, *a)))
+                    .then(free_mem[*b].cmp(&free_mem[*a]))
+                    .then(candidates[*a].id.cmp(&candidates[*b].id))
+            })
+            .unwrap();
+
+        free_mem[best] = free_mem[best].saturating_sub(guest.mem_size);
+        nr_vcpus[best] += guest.vcpus.len();
+        assigned[idx] = candidates[best].id;
+    }
+    Ok(assigned)
+}
+
+/// Choose the host node of an iothread from the host nodes of its drives. The most
+/// common node is chosen, and the one with the lowest id for a tie.
+fn iothread_node(drive_nodes: &[u32], host_nodes: &[HostNode]) -> Option<u32> {
+    let mut count: BTreeMap<u32, usize> = BTreeMap::new();
+    for node in drive_nodes {
+        if host_nodes
+            .iter()
+            .any(|n| n.id == *node && !n.cpus.is_empty())
+        {
+            *count.entry(*node).or_default() += 1;
+        }
+    }
+    count
+        .into_iter()
+        .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+        .map(|(node, _)| node)
+}
+
+fn host_cpus(host_nodes: &[HostNode], id: u32) -> Vec<usize> {
+    host_nodes
+        .iter()
+        .find(|node| node.id == id)
+        .map(|node| node.cpus.clone())
+        .unwrap_or_default()
+}
+
+/// Compute the placement of guest nodes and iothreads on host nodes.
+///
+/// # Arguments
+///
+/// * `guest_nodes` - Guest nodes to be placed.
+/// * `host_nodes` - Host nodes to place the guest on.
+/// * `iothreads` - Iothreads with the host nodes of their drives.
+fn compute_placement(
+    guest_nodes: &[GuestNode],
+    host_nodes: &[HostNode],
+    iothreads: &BTreeMap<String, Vec<u32>>,
+) -> Result<PlacementInfo> {
+    let assigned = assign_nodes(guest_nodes, host_nodes)?;
+    let nodes = guest_nodes
+        .iter()
+        .zip(assigned)
+        .map(|(guest, host_node)| NodePlacement {
+            node: guest.id,
+            host_node,
+            vcpus: guest.vcpus.clone(),
+            host_cpus: host_cpus(host_nodes, host_node),
+            memory_host_nodes: guest.bound_nodes.clone().unwrap_or_else(|| vec![host_node]),
+        })
+        .collect();
+    let iothreads = iothreads
+        .iter()
+        .map(|(id, drive_nodes)| {
+            let host_node = iothread_node(drive_nodes, host_nodes);
+            IothreadPlacement {
+                id: id.clone(),
+                host_node,
+                host_cpus: host_node.map_or(Vec::new(), |node| host_cpus(host_nodes, node)),
+            }
+        })
+        .collect();
+
+    Ok(PlacementInfo {
+        policy: "numa".to_string(),
+        nodes,
+        iothreads,
+    })
+}
+
+/// Bind the memory of guest nodes to the host nodes they are placed on. Memory zones
+/// with `host-nodes` set are kept.
+fn bind_memory(
+    mem_zones: &mut Option<Vec<MemZoneConfig>>,
+    mem_size: u64,
+    mem_dev: Option<&str>,
+    host_node: u32,
+) {
+    if let Some(mem_dev) = mem_dev {
+        if let Some(zone) = mem_zones
+            .iter_mut()
+            .flatten()
+            .find(|zone| zone.id == mem_dev)
+        {
+            zone.host_numa_nodes.get_or_insert_with(|| vec![host_node]);
+        }
+        return;
+    }
+
+    // Without NUMA config, all memory of the guest is placed on the host node.
+    match mem_zones {
+        Some(zones) => {
+            for zone in zones.iter_mut() {
+                zone.host_numa_nodes.get_or_insert_with(|| vec![host_node]);
+            }
+        }
+        None => {
+            *mem_zones = Some(vec![MemZoneConfig {
+                id: AUTO_MEM_ZONE_ID.to_string(),
+                size: mem_size,
+                host_numa_nodes: Some(vec![host_node]),
+                policy: String::from("bind"),
+            }]);
+        }
+    }
+}
+
+/// Place the VM on host NUMA nodes if `-auto-placement` is set. Guest memory zones are
+/// bound to host nodes and iothreads are pinned, while vCPUs are pinned when they are
+/// created. It must be called before guest memory is mapped.
+///
+/// # Arguments
+///
+/// * `vm_config` - The VM config, whose memory zones are updated.
+/// * `numa_nodes` - Guest NUMA nodes.
+pub(crate) fn auto_place(vm_config: &mut VmConfig, numa_nodes: &Option<NumaNodes>) -> Result<()> {
+    match vm_config.auto_placement {
+        Some(AutoPlacement::Numa) => {}
+        None => return Ok(()),
+    }
+
+    let host_nodes = host_nodes().with_context(|| "Failed to get host NUMA topology")?;
+    let guest_nodes = guest_nodes(vm_config, numa_nodes)?;
+    let drives = iothread_drives(vm_config);
+    let mut iothreads = BTreeMap::new();
+    for iothread in vm_config.iothreads.iter().flatten() {
+        let drive_nodes = drives
+            .get(&iothread.id)
+            .map(|paths| paths.iter().filter_map(|p| drive_host_node(p)).collect())
+            .unwrap_or_default();
+        iothreads.insert(iothread.id.clone(), drive_nodes);
+    }
+
+    let nodes: Vec<GuestNode> = guest_nodes.iter().map(|(node, _)| node.clone()).collect();
+    let mut placement = compute_placement(&nodes, &host_nodes, &iothreads)?;
+
+    let mem_config = &mut vm_config.machine_config.mem_config;
+    for (node, (_, mem_dev)) in placement.nodes.iter().zip(guest_nodes.iter()) {
+        bind_memory(
+            &mut mem_config.mem_zones,
+            mem_config.mem_size,
+            mem_dev.as_deref(),
+            node.host_node,
+        );
+        info!(
+            "Auto placement: guest node {} with vcpus {:?} on host node {} with cpus {:?}, memory on host nodes {:?}",
+            node.node, node.vcpus, node.host_node, node.host_cpus, node.memory_host_nodes
+        );
+    }
+    for iothread in placement.iothreads.iter_mut() {
+        if iothread.host_cpus.is_empty() {
+            info!("Auto placement: iothread {} is not pinned", iothread.id);
+            continue;
+        }
+        if let Err(e) = EventLoop::set_iothread_affinity(&iothread.id, &iothread.host_cpus) {
+            warn!("Failed to pin iothread {}: {:?}", iothread.id, e);
+            iothread.host_cpus.clear();
+            continue;
+        }
+        info!(
+            "Auto placement: iothread {} on host node {:?} with cpus {:?}",
+            iothread.id, iothread.host_node, iothread.host_cpus
+        );
+    }
+
+    *PLACEMENT.lock().unwrap() = Some(placement);
+    Ok(())
+}
+
+/// Save the vCPU pinning of `-cpu-pin`, which is applied when vCPUs are created. The
+/// realtime priority is checked here, so that a missing capability fails the startup
+/// with a clear error instead of failing in each vCPU thread.
+///
+/// # Arguments
+///
+/// * `vm_config` - The VM config.
+pub(crate) fn pin_vcpus(vm_config: &VmConfig) -> Result<()> {
+    let cpu_pin = match vm_config.cpu_pin.as_ref() {
+        Some(cpu_pin) => cpu_pin,
+        None => return Ok(()),
+    };
+    if let Some(priority) = cpu_pin.priority {
+        check_thread_sched_priority(priority.policy.to_libc(), priority.priority)
+            .with_context(|| "Failed to set vcpu-priority of cpu-pin")?;
+    }
+    for (vcpu, host_cpu) in cpu_pin.vcpus.iter() {
+        info!("Cpu pin: vcpu {} on host cpu {}", vcpu, host_cpu);
+    }
+
+    *CPU_PIN.lock().unwrap() = Some(cpu_pin.clone());
+    Ok(())
+}
+
+/// Get the realtime scheduling policy and priority of vCPU threads set by `-cpu-pin`.
+pub(crate) fn vcpu_sched_priority() -> Option<(i32, i32)> {
+    let cpu_pin = CPU_PIN.lock().unwrap();
+    let priority = cpu_pin.as_ref()?.priority?;
+    Some((priority.policy.to_libc(), priority.priority))
+}
+
+/// Get the host cpus a vCPU is pinned to by `-cpu-pin` or auto placement.
+pub(crate) fn vcpu_host_cpus(vcpu_id: u16) -> Option<Vec<usize>> {
+    if let Some(host_cpu) = CPU_PIN
+        .lock()
+        .unwrap()
+        .as_ref()
+        .and_then(|cpu_pin| cpu_pin.vcpus.get(&vcpu_id))
+    {
+        return Some(vec![*host_cpu]);
+    }
+
+    PLACEMENT
+        .lock()
+        .unwrap()
+        .as_ref()?
+        .nodes
+        .iter()
+        .find(|node| node.vcpus.contains(&vcpu_id))
+        .map(|node| node.host_cpus.clone())
+}
+
+/// Get the computed placement, return None if auto placement is not enabled.
+pub(crate) fn qmp_query_placement() -> Option<PlacementInfo> {
+    PLACEMENT.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine_manager::config::{DriveConfig, M};
+
+    fn host_node(id: u32, cpus: &[usize], free_mem: u64) -> HostNode {
+        HostNode {
+            id,
+            cpus: cpus.to_vec(),
+            free_mem,
+        }
+    }
+
+    fn guest_node(id: u32, vcpus: &[u16], mem_size: u64, bound: Option<Vec<u32>>) -> GuestNode {
+        GuestNode {
+            id,
+            vcpus: vcpus.to_vec(),
+            mem_size,
+            bound_nodes: bound,
+        }
+    }
+
+    #[test]
+    fn test_parse_host_topology() {
+        assert_eq!(
+            parse_cpu_list("0-3,8,10-11\n").unwrap(),
+            vec![0, 1, 2, 3, 8, 10, 11]
+        );
+        assert_eq!(parse_cpu_list("5").unwrap(), vec![5]);
+        assert!(parse_cpu_list("\n").unwrap().is_empty());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("a-b").is_err());
+
+        let meminfo = "Node 1 MemTotal:       16384 kB\nNode 1 MemFree:         2048 kB\n";
+        assert_eq!(parse_node_mem_free(meminfo), Some(2 * M));
+        assert_eq!(parse_node_mem_free("Node 1 MemTotal: 16384 kB"), None);
+    }
+
+    #[test]
+    fn test_assign_nodes() {
+        let hosts = vec![
+            host_node(0, &[0, 1, 2, 3], 8192 * M),
+            host_node(1, &[4, 5, 6, 7], 8192 * M),
+        ];
+
+        // Each guest node gets its own host node.
+        let guests = vec![
+            guest_node(0, &[0, 1], 2048 * M, None),
+            guest_node(1, &[2, 3], 2048 * M, None),
+        ];
+        assert_eq!(assign_nodes(&guests, &hosts).unwrap(), vec![0, 1]);
+
+        // More guest nodes than host nodes are balanced by vCPUs per host cpu.
+        let guests = vec![
+            guest_node(0, &[0, 1, 2, 3], 1024 * M, None),
+            guest_node(1, &[4], 1024 * M, None),
+            guest_node(2, &[5], 1024 * M, None),
+        ];
+        assert_eq!(assign_nodes(&guests, &hosts).unwrap(), vec![0, 1, 1]);
+
+        // A host node without enough free memory is avoided.
+        let hosts = vec![
+            host_node(0, &[0, 1, 2, 3], 1024 * M),
+            host_node(1, &[4, 5], 8192 * M),
+        ];
+        let guests = vec![guest_node(0, &[0, 1], 4096 * M, None)];
+        assert_eq!(assign_nodes(&guests, &hosts).unwrap(), vec![1]);
+
+        // Without any node to fit the memory, the least loaded one is chosen.
+        let guests = vec![guest_node(0, &[0, 1], 16384 * M, None)];
+        assert_eq!(assign_nodes(&guests, &hosts).unwrap(), vec![0]);
+    }
+
+    #[test]
+    fn test_assign_nodes_explicit() {
+        let hosts = vec![
+            host_node(0, &[0, 1, 2, 3], 8192 * M),
+            host_node(1, &[4, 5, 6, 7], 8192 * M),
+            // A memory-only node.
+            host_node(2, &[], 8192 * M),
+        ];
+
+        // Memory bound by host-nodes decides the host node of vCPUs.
+        let guests = vec![
+            guest_node(0, &[0, 1], 2048 * M, None),
+            guest_node(1, &[2, 3], 1024 * M, Some(vec![0])),
+        ];
+        assert_eq!(assign_nodes(&guests, &hosts).unwrap(), vec![1, 0]);
+
+        // The vCPUs of a node bound to a memory-only node are placed anywhere.
+        let guests = vec![guest_node(0, &[0, 1], 2048 * M, Some(vec![2]))];
+        assert_eq!(assign_nodes(&guests, &hosts).unwrap(), vec![0]);
+
+        // Host nodes without usable cpus can't run the VM.
+        let hosts = vec![host_node(0, &[], 8192 * M)];
+        assert!(assign_nodes(&guests, &hosts).is_err());
+    }
+
+    #[test]
+    fn test_compute_placement() {
+        let hosts = vec![
+            host_node(0, &[0, 1], 4096 * M),
+            host_node(1, &[2, 3], 4096 * M),
+            host_node(2, &[], 4096 * M),
+        ];
+        let guests = vec![
+            guest_node(0, &[0, 1], 1024 * M, None),
+            guest_node(1, &[2, 3], 1024 * M, Some(vec![1, 2])),
+        ];
+        let mut iothreads = BTreeMap::new();
+        iothreads.insert("iothread0".to_string(), vec![1, 0, 1]);
+        iothreads.insert("iothread1".to_string(), vec![0, 1]);
+        iothreads.insert("iothread2".to_string(), vec![2]);
+        iothreads.insert("iothread3".to_string(), Vec::new());
+
+        let placement = compute_placement(&guests, &hosts, &iothreads).unwrap();
+        assert_eq!(placement.policy, "numa");
+        assert_eq!(placement.nodes.len(), 2);
+        assert_eq!(placement.nodes[0].host_node, 0);
+        assert_eq!(placement.nodes[0].host_cpus, vec![0, 1]);
+        assert_eq!(placement.nodes[0].memory_host_nodes, vec![0]);
+        assert_eq!(placement.nodes[1].host_node, 1);
+        assert_eq!(placement.nodes[1].host_cpus, vec![2, 3]);
+        assert_eq!(placement.nodes[1].memory_host_nodes, vec![1, 2]);
+
+        // The most common node wins, and the lowest one for a tie. Drives on nodes
+        // without cpus or on unknown nodes don't pin the iothread.
+        let iothreads = &placement.iothreads;
+        assert_eq!(iothreads[0].host_node, Some(1));
+        assert_eq!(iothreads[0].host_cpus, vec![2, 3]);
+        assert_eq!(iothreads[1].host_node, Some(0));
+        assert_eq!(iothreads[2].host_node, None);
+        assert!(iothreads[2].host_cpus.is_empty());
+        assert_eq!(iothreads[3].host_node, None);
+    }
+
+    #[test]
+    fn test_bind_memory() {
+        let zone = |id: &str, nodes: Option<Vec<u32>>| MemZoneConfig {
+            id: id.to_string(),
+            size: 1024 * M,
+            host_numa_nodes: nodes,
+            policy: String::from("bind"),
+        };
+
+        let mut zones = Some(vec![zone("mem0", None), zone("mem1", Some(vec![0]))]);
+        bind_memory(&mut zones, 2048 * M, Some("mem0"), 1);
+        bind_memory(&mut zones, 2048 * M, Some("mem1"), 1);
+        let zones = zones.unwrap();
+        assert_eq!(zones[0].host_numa_nodes, Some(vec![1]));
+        assert_eq!(zones[1].host_numa_nodes, Some(vec![0]));
+
+        let mut zones = None;
+        bind_memory(&mut zones, 2048 * M, None, 1);
+        let zones = zones.unwrap();
+        assert_eq!(zones.len(), 1);
+        assert_eq!(zones[0].size, 2048 * M);
+        assert_eq!(zones[0].host_numa_nodes, Some(vec![1]));
+    }
+
+    #[test]
+    fn test_iothread_drives() {
+        let mut vm_config = VmConfig::default();
+        for id in 0..2 {
+            let drive = DriveConfig {
+                id: format!("drive{}", id),
+                path_on_host: format!("/path/to/disk{}", id),
+                ..Default::default()
+            };
+            vm_config.drives.insert(drive.id.clone(), drive);
+        }
+        vm_config
+            .add_device(
+                "virtio-blk-pci,id=blk0,drive=drive0,iothread=iothread0,bus=pcie.0,addr=0x2",
+            )
+            .unwrap();
+        vm_config
+            .add_device("virtio-scsi-pci,id=scsi0,iothread=iothread1,bus=pcie.0,addr=0x3")
+            .unwrap();
+        vm_config
+            .add_device("scsi-hd,id=disk1,drive=drive1,bus=scsi0.0,scsi-id=0,lun=0")
+            .unwrap();
+
+        let drives = iothread_drives(&vm_config);
+        assert_eq!(drives.len(), 2);
+        assert_eq!(drives["iothread0"], vec!["/path/to/disk0".to_string()]);
+        assert_eq!(drives["iothread1"], vec!["/path/to/disk1".to_string()]);
+    }
+}
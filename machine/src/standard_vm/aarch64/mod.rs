@@ -16,13 +16,13 @@ mod syscall;
 pub use crate::error::MachineError;
 use devices::acpi::ged::{acpi_dsdt_add_power_button, Ged};
 use log::{error, info};
-use machine_manager::config::ShutdownAction;
+use machine_manager::config::{RebootAction, ShutdownAction};
 use machine_manager::event_loop::EventLoop;
 use std::borrow::Borrow;
 use std::collections::HashMap;
 use std::mem::size_of;
 use std::ops::Deref;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Condvar, Mutex, Weak};
 use vmm_sys_util::eventfd::EventFd;
 
 use acpi::{
@@ -37,21 +37,21 @@ use acpi::{
     ID_MAPPING_ENTRY_SIZE, INTERRUPT_PPIS_COUNT, INTERRUPT_SGIS_COUNT, ROOT_COMPLEX_ENTRY_SIZE,
 };
 use address_space::{AddressSpace, GuestAddress, Region};
-use boot_loader::{load_linux, BootLoaderConfig};
 use cpu::{
     CPUBootConfig, CPUFeatures, CPUInterface, CPUTopology, CpuTopology, CPU, PMU_INTR, PPI_BASE,
 };
 #[cfg(not(target_env = "musl"))]
 use devices::legacy::Ramfb;
 use devices::legacy::{
-    FwCfgEntryType, FwCfgMem, FwCfgOps, LegacyError as DevErrorKind, PFlash, PL011, PL031,
+    register_mux_machine, FwCfgEntryType, FwCfgMem, FwCfgOps, LegacyError as DevErrorKind, PFlash,
+    PL011, PL031,
 };
 
 use devices::{ICGICConfig, ICGICv3Config, InterruptController, GIC_IRQ_MAX};
-use hypervisor::kvm::KVM_FDS;
+use hypervisor::kvm::{KvmCaps, KVM_FDS};
 use machine_manager::config::{
-    parse_incoming_uri, BootIndexInfo, BootSource, DriveFile, Incoming, MigrateMode, NumaNode,
-    NumaNodes, PFlashConfig, SerialConfig, VmConfig,
+    parse_incoming_uri, parse_uuid, BootIndexInfo, BootSource, DriveFile, Incoming, MigrateMode,
+    NumaNode, NumaNodes, PFlashConfig, SerialConfig, VmConfig,
 };
 use machine_manager::event;
 use machine_manager::machine::{
@@ -60,7 +60,8 @@ use machine_manager::machine::{
 };
 use machine_manager::qmp::{qmp_schema, QmpChannel, Response};
 use migration::{MigrationManager, MigrationStatus};
-use pci::{PciDevOps, PciHost};
+use pci::intx::{swizzle_map_irq, PCI_PIN_NUM};
+use pci::{PciDevOps, PciHost, PciIntxState};
 use pci_host_root::PciHostRoot;
 use sysbus::{SysBus, SysBusDevType, SysRes, IRQ_BASE, IRQ_MAX};
 use syscall::syscall_whitelist;
@@ -73,7 +74,9 @@ use util::seccomp::BpfRule;
 use util::set_termi_canon_mode;
 
 use super::{AcpiBuilder, Result as StdResult, StdMachineOps};
-use crate::MachineOps;
+use crate::boot::{add_kernel2_mem_reserve, load_boot_plan, BootPlan};
+use crate::vcpu_rt::{enable_vcpu_realtime, RtAddressDispatcher};
+use crate::{placement, MachineOps};
 use anyhow::{anyhow, bail, Context, Result};
 use virtio::ScsiCntlr::ScsiCntlrMap;
 
@@ -96,6 +99,9 @@ pub enum LayoutEntryType {
     HighPcieMmio,
 }
 
+/// SPI number of INTA# of pci host, INTA# ~ INTD# use the SPIs below the ones of sysbus.
+const PCI_INTX_IRQ_BASE: u32 = IRQ_BASE as u32 - PCI_PIN_NUM as u32;
+
 /// Layout of aarch64
 pub const MEM_LAYOUT: &[(u64, u64)] = &[
     (0, 0x0800_0000),              // Flash
@@ -152,6 +158,12 @@ pub struct StdMachine {
     scsi_cntlr_list: ScsiCntlrMap,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// Virtio-serial device.
+    virtio_serial: Option<Arc<Mutex<virtio::Serial>>>,
+    /// Capabilities of KVM probed at startup.
+    kvm_caps: KvmCaps,
+    /// Lifecycle interface of the machine itself, used by devices to pause the VM.
+    vm_lifecycle: Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>>,
 }
 
 impl StdMachine {
@@ -207,11 +219,20 @@ impl StdMachine {
             fwcfg_dev: None,
             scsi_cntlr_list: Arc::new(Mutex::new(HashMap::new())),
             drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
+            virtio_serial: None,
+            kvm_caps: KvmCaps::default(),
+            vm_lifecycle: None,
         })
     }
 
     pub fn handle_reset_request(vm: &Arc<Mutex<Self>>) -> Result<()> {
         let mut locked_vm = vm.lock().unwrap();
+        // The VM kept after guest shutdown is brought back by reset, the reboot action
+        // doesn't apply.
+        let shutdown = *locked_vm.vm_state.0.lock().unwrap() == KvmVmState::Shutdown;
+        if !shutdown && !locked_vm.reboot_by_guest() {
+            return Ok(());
+        }
         let mut fdt_addr: u64 = 0;
 
         for (cpu_index, cpu) in locked_vm.cpus.iter().enumerate() {
@@ -248,9 +269,16 @@ impl StdMachine {
             event!(Reset; reset_msg);
         }
 
-        for (cpu_index, cpu) in locked_vm.cpus.iter().enumerate() {
-            cpu.resume()
-                .with_context(|| format!("Failed to resume vcpu{}", cpu_index))?;
+        if shutdown {
+            if !locked_vm.notify_lifecycle(KvmVmState::Shutdown, KvmVmState::Running) {
+                bail!("Failed to start the VM after reset");
+            }
+            event!(Resume);
+        } else {
+            for (cpu_index, cpu) in locked_vm.cpus.iter().enumerate() {
+                cpu.resume()
+                    .with_context(|| format!("Failed to resume vcpu{}", cpu_index))?;
+            }
         }
 
         Ok(())
@@ -311,6 +339,13 @@ impl StdMachine {
 impl StdMachineOps for StdMachine {
     fn init_pci_host(&self) -> StdResult<()> {
         let root_bus = Arc::downgrade(&self.pci_host.lock().unwrap().root_bus);
+        self.pci_host
+            .lock()
+            .unwrap()
+            .root_bus
+            .lock()
+            .unwrap()
+            .intx_state = Some(Arc::new(Mutex::new(PciIntxState::new(PCI_INTX_IRQ_BASE))));
         let mmconfig_region_ops = PciHost::build_mmconfig_ops(self.pci_host.clone());
         let mmconfig_region = Region::init_io_region(
             MEM_LAYOUT[LayoutEntryType::HighPcieEcam as usize].1,
@@ -332,7 +367,7 @@ impl StdMachineOps for StdMachine {
         Ok(())
     }
 
-    fn add_fwcfg_device(&mut self, nr_cpus: u8) -> StdResult<Option<Arc<Mutex<dyn FwCfgOps>>>> {
+    fn add_fwcfg_device(&mut self, nr_cpus: u16) -> StdResult<Option<Arc<Mutex<dyn FwCfgOps>>>> {
         if self.vm_config.lock().unwrap().pflashs.is_none() {
             return Ok(None);
         }
@@ -341,6 +376,11 @@ impl StdMachineOps for StdMachine {
         fwcfg
             .add_data_entry(FwCfgEntryType::NbCpus, nr_cpus.as_bytes().to_vec())
             .with_context(|| anyhow!(DevErrorKind::AddEntryErr("NbCpus".to_string())))?;
+        if let Some(uuid) = &self.vm_config.lock().unwrap().uuid {
+            fwcfg
+                .add_data_entry(FwCfgEntryType::Uuid, parse_uuid(uuid)?.to_vec())
+                .with_context(|| anyhow!(DevErrorKind::AddEntryErr("Uuid".to_string())))?;
+        }
 
         let cmdline = self.boot_source.lock().unwrap().kernel_cmdline.to_string();
         fwcfg
@@ -386,6 +426,12 @@ impl StdMachineOps for StdMachine {
     fn get_numa_nodes(&self) -> &Option<NumaNodes> {
         &self.numa_nodes
     }
+
+    fn has_its(&self) -> bool {
+        self.irq_chip
+            .as_ref()
+            .map_or(false, |irq_chip| irq_chip.has_its())
+    }
 }
 
 impl MachineOps for StdMachine {
@@ -394,14 +440,19 @@ impl MachineOps for StdMachine {
     }
 
     fn init_interrupt_controller(&mut self, vcpu_count: u64) -> Result<()> {
+        let its = self.vm_config.lock().unwrap().machine_config.its;
         let v3 = ICGICv3Config {
-            msi: true,
+            msi: its,
             dist_range: MEM_LAYOUT[LayoutEntryType::GicDist as usize],
             redist_region_ranges: vec![
                 MEM_LAYOUT[LayoutEntryType::GicRedist as usize],
                 MEM_LAYOUT[LayoutEntryType::HighGicRedist as usize],
             ],
-            its_range: Some(MEM_LAYOUT[LayoutEntryType::GicIts as usize]),
+            its_range: if its {
+                Some(MEM_LAYOUT[LayoutEntryType::GicIts as usize])
+            } else {
+                None
+            },
         };
         let intc_conf = ICGICConfig {
             version: None,
@@ -425,24 +476,10 @@ impl MachineOps for StdMachine {
 
     fn load_boot_source(&self, fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>) -> Result<CPUBootConfig> {
         let mut boot_source = self.boot_source.lock().unwrap();
-        let initrd = boot_source.initrd.as_ref().map(|b| b.initrd_file.clone());
-
-        let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
+        let plan = BootPlan {
             mem_start: MEM_LAYOUT[LayoutEntryType::Mem as usize].0,
         };
-        let layout = load_linux(&bootloader_config, &self.sys_mem, fwcfg)
-            .with_context(|| anyhow!(MachineError::LoadKernErr))?;
-        if let Some(rd) = &mut boot_source.initrd {
-            rd.initrd_addr = layout.initrd_start;
-            rd.initrd_size = layout.initrd_size;
-        }
-
-        Ok(CPUBootConfig {
-            fdt_addr: layout.dtb_start,
-            boot_pc: layout.boot_pc,
-        })
+        load_boot_plan(&plan, &mut boot_source, &self.sys_mem, fwcfg)
     }
 
     fn add_rtc_device(&mut self) -> Result<()> {
@@ -493,17 +530,33 @@ impl MachineOps for StdMachine {
         self.drive_files.clone()
     }
 
+    fn get_virtio_serial(&mut self) -> &mut Option<Arc<Mutex<virtio::Serial>>> {
+        &mut self.virtio_serial
+    }
+
+    fn get_vm_lifecycle(&self) -> Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>> {
+        self.vm_lifecycle.clone()
+    }
+
     fn realize(vm: &Arc<Mutex<Self>>, vm_config: &mut VmConfig) -> Result<()> {
         use super::error::StandardVmError as StdErrorKind;
 
         let nr_cpus = vm_config.machine_config.nr_cpus;
+        let kvm_caps = KVM_FDS.load().probe_caps()?;
         let clone_vm = vm.clone();
         let mut locked_vm = vm.lock().unwrap();
+        locked_vm.kvm_caps = kvm_caps;
+        let vm_lifecycle: Arc<Mutex<dyn MachineLifecycle + Send + Sync>> = vm.clone();
+        locked_vm.vm_lifecycle = Some(Arc::downgrade(&vm_lifecycle));
+        let mux_machine: Arc<Mutex<dyn MachineExternalInterface + Send + Sync>> = vm.clone();
+        register_mux_machine(&mux_machine);
         locked_vm.init_global_config(vm_config)?;
         locked_vm
             .register_reset_event(locked_vm.reset_req.clone(), clone_vm)
             .with_context(|| "Fail to register reset event")?;
         locked_vm.numa_nodes = locked_vm.add_numa_nodes(vm_config)?;
+        placement::auto_place(vm_config, &locked_vm.numa_nodes)?;
+        placement::pin_vcpus(vm_config)?;
         locked_vm.init_memory(
             &vm_config.machine_config.mem_config,
             &locked_vm.sys_mem,
@@ -537,6 +590,10 @@ impl MachineOps for StdMachine {
             &boot_config,
             &cpu_config,
         )?);
+        if vm_config.machine_config.cpu_config.realtime {
+            let dispatcher = RtAddressDispatcher::new(&locked_vm.sys_mem, vm.clone());
+            enable_vcpu_realtime(&locked_vm.cpus, Arc::new(dispatcher))?;
+        }
 
         // Interrupt Controller Chip init
         locked_vm.init_interrupt_controller(u64::from(nr_cpus))?;
@@ -549,6 +606,7 @@ impl MachineOps for StdMachine {
 
         if let Some(boot_cfg) = boot_config {
             let mut fdt_helper = FdtBuilder::new();
+            add_kernel2_mem_reserve(&mut fdt_helper, &locked_vm.boot_source.lock().unwrap())?;
             locked_vm
                 .generate_fdt_node(&mut fdt_helper)
                 .with_context(|| anyhow!(MachineError::GenFdtErr))?;
@@ -567,10 +625,17 @@ impl MachineOps for StdMachine {
         }
 
         // If it is direct kernel boot mode, the ACPI can not be enabled.
-        if migrate.0 == MigrateMode::Unknown && fwcfg.is_some() {
-            locked_vm
-                .build_acpi_tables(&fwcfg.unwrap())
-                .with_context(|| "Failed to create ACPI tables")?;
+        if migrate.0 == MigrateMode::Unknown {
+            if let Some(fwcfg) = fwcfg.as_ref() {
+                locked_vm
+                    .build_acpi_tables(fwcfg)
+                    .with_context(|| "Failed to create ACPI tables")?;
+                let ram_ranges =
+                    locked_vm.arch_ram_ranges(vm_config.machine_config.mem_config.mem_size);
+                locked_vm
+                    .build_smbios_tables(fwcfg, vm_config, &ram_ranges)
+                    .with_context(|| "Failed to create SMBIOS tables")?;
+            }
         }
 
         locked_vm
@@ -713,39 +778,7 @@ impl AcpiBuilder for StdMachine {
         acpi_data: &Arc<Mutex<Vec<u8>>>,
         loader: &mut TableLoader,
     ) -> super::Result<u64> {
-        let mut iort = AcpiTable::new(*b"IORT", 2, *b"STRATO", *b"VIRTIORT", 1);
-        iort.set_table_len(128);
-
-        // Number of IORT nodes is 2: ITS group node and Root Complex Node.
-        iort.set_field(36, 2_u32);
-        // Node offset
-        iort.set_field(40, 48_u32);
-
-        // ITS group node
-        iort.set_field(48, ACPI_IORT_NODE_ITS_GROUP);
-        // ITS node length
-        iort.set_field(49, 24_u16);
-        // ITS count
-        iort.set_field(64, 1_u32);
-
-        // Root Complex Node
-        iort.set_field(72, ACPI_IORT_NODE_PCI_ROOT_COMPLEX);
-        // Length of Root Complex node
-        let len = ROOT_COMPLEX_ENTRY_SIZE + ID_MAPPING_ENTRY_SIZE;
-        iort.set_field(73, len);
-        // Mapping counts of Root Complex Node
-        iort.set_field(80, 1_u32);
-        // Mapping offset of Root Complex Node
-        iort.set_field(84, ROOT_COMPLEX_ENTRY_SIZE as u32);
-        // Cache of coherent device
-        iort.set_field(88, 1_u32);
-        // Memory flags of coherent device
-        iort.set_field(95, 3_u8);
-        // Identity RID mapping
-        iort.set_field(112, 0xffff_u32);
-        // Without SMMU, id mapping is the first node in ITS group node
-        iort.set_field(120, 48_u32);
-
+        let iort = iort_table();
         let iort_begin = StdMachine::add_table_to_loader(acpi_data, loader, &iort)
             .with_context(|| "Fail to add IORT table to loader")?;
         Ok(iort_begin as u64)
@@ -875,11 +908,13 @@ impl AcpiBuilder for StdMachine {
         }
 
         // 4. GIC Its.
-        let mut gic_its = AcpiGicIts::default();
-        gic_its.type_id = ACPI_MADT_GENERIC_TRANSLATOR;
-        gic_its.length = 20;
-        gic_its.base_addr = MEM_LAYOUT[LayoutEntryType::GicIts as usize].0;
-        madt.append_child(&gic_its.aml_bytes());
+        if self.has_its() {
+            let mut gic_its = AcpiGicIts::default();
+            gic_its.type_id = ACPI_MADT_GENERIC_TRANSLATOR;
+            gic_its.length = 20;
+            gic_its.base_addr = MEM_LAYOUT[LayoutEntryType::GicIts as usize].0;
+            madt.append_child(&gic_its.aml_bytes());
+        }
 
         let madt_begin = StdMachine::add_table_to_loader(acpi_data, loader, &madt)
             .with_context(|| "Fail to add MADT table to loader")?;
@@ -985,7 +1020,7 @@ impl MachineLifecycle for StdMachine {
             *state
         };
 
-        if !self.notify_lifecycle(vmstate, KvmVmState::Shutdown) {
+        if !self.notify_lifecycle(vmstate, KvmVmState::Destroyed) {
             return false;
         }
 
@@ -1012,6 +1047,10 @@ impl MachineLifecycle for StdMachine {
             .shutdown_action
     }
 
+    fn get_reboot_action(&self) -> RebootAction {
+        self.vm_config.lock().unwrap().machine_config.reboot_action
+    }
+
     fn reset(&mut self) -> bool {
         if self.reset_req.write(1).is_err() {
             error!("ARM standard vm write reset req failed");
@@ -1077,7 +1116,7 @@ impl MachineTestInterface for StdMachine {}
 impl EventLoopManager for StdMachine {
     fn loop_should_exit(&self) -> bool {
         let vmstate = self.vm_state.deref().0.lock().unwrap();
-        *vmstate == KvmVmState::Shutdown
+        *vmstate == KvmVmState::Destroyed
     }
 
     fn loop_cleanup(&self) -> util::Result<()> {
@@ -1086,12 +1125,51 @@ impl EventLoopManager for StdMachine {
     }
 }
 
+/// Build the IORT table, which maps the requester ids of the PCI root complex to the ITS.
+fn iort_table() -> AcpiTable {
+    let mut iort = AcpiTable::new(*b"IORT", 2, *b"STRATO", *b"VIRTIORT", 1);
+    iort.set_table_len(128);
+
+    // Number of IORT nodes is 2: ITS group node and Root Complex Node.
+    iort.set_field(36, 2_u32);
+    // Node offset
+    iort.set_field(40, 48_u32);
+
+    // ITS group node
+    iort.set_field(48, ACPI_IORT_NODE_ITS_GROUP);
+    // ITS node length
+    iort.set_field(49, 24_u16);
+    // ITS count
+    iort.set_field(64, 1_u32);
+
+    // Root Complex Node
+    iort.set_field(72, ACPI_IORT_NODE_PCI_ROOT_COMPLEX);
+    // Length of Root Complex node
+    let len = ROOT_COMPLEX_ENTRY_SIZE + ID_MAPPING_ENTRY_SIZE;
+    iort.set_field(73, len);
+    // Mapping counts of Root Complex Node
+    iort.set_field(80, 1_u32);
+    // Mapping offset of Root Complex Node
+    iort.set_field(84, ROOT_COMPLEX_ENTRY_SIZE as u32);
+    // Cache of coherent device
+    iort.set_field(88, 1_u32);
+    // Memory flags of coherent device
+    iort.set_field(95, 3_u8);
+    // Identity RID mapping
+    iort.set_field(112, 0xffff_u32);
+    // Without SMMU, id mapping is the first node in ITS group node
+    iort.set_field(120, 48_u32);
+
+    iort
+}
+
 // Function that helps to generate pci node in device-tree.
 //
 // # Arguments
 //
 // * `fdt` - Flatted device-tree blob where node will be filled into.
-fn generate_pci_host_node(fdt: &mut FdtBuilder) -> util::Result<()> {
+// * `msi` - MSIs of PCI devices are translated by the ITS.
+fn generate_pci_host_node(fdt: &mut FdtBuilder, msi: bool) -> util::Result<()> {
     let pcie_ecam_base = MEM_LAYOUT[LayoutEntryType::HighPcieEcam as usize].0;
     let pcie_ecam_size = MEM_LAYOUT[LayoutEntryType::HighPcieEcam as usize].1;
     let pcie_buses_num = MEM_LAYOUT[LayoutEntryType::HighPcieEcam as usize].1 >> 20;
@@ -1156,7 +1234,32 @@ fn generate_pci_host_node(fdt: &mut FdtBuilder) -> util::Result<()> {
         ],
     )?;
 
-    fdt.set_property_u32("msi-parent", device_tree::GIC_ITS_PHANDLE)?;
+    if msi {
+        fdt.set_property_u32("msi-parent", device_tree::GIC_ITS_PHANDLE)?;
+    }
+
+    // INTx of the root bus, the pin of a device is swizzled by its slot number.
+    fdt.set_property_u32("#interrupt-cells", 1)?;
+    fdt.set_property_array_u32("interrupt-map-mask", &[0x1800, 0, 0, 7])?;
+    let mut irq_map = Vec::new();
+    for slot in 0..PCI_PIN_NUM {
+        for pin in 0..PCI_PIN_NUM {
+            let irq = PCI_INTX_IRQ_BASE + swizzle_map_irq(slot << 3, pin) as u32;
+            irq_map.extend_from_slice(&[
+                (slot as u32) << 11,
+                0,
+                0,
+                pin as u32 + 1,
+                device_tree::GIC_PHANDLE,
+                0,
+                0,
+                device_tree::GIC_FDT_IRQ_TYPE_SPI,
+                irq,
+                device_tree::IRQ_TYPE_LEVEL_HIGH,
+            ]);
+        }
+    }
+    fdt.set_property_array_u32("interrupt-map", &irq_map)?;
     fdt.end_node(pci_node_dep)?;
     Ok(())
 }
@@ -1371,7 +1474,7 @@ impl CompileFDTHelper for StdMachine {
             if let Some(numa_nodes) = &self.numa_nodes {
                 for numa_index in 0..numa_nodes.len() {
                     let numa_node = numa_nodes.get(&(numa_index as u32));
-                    if numa_node.unwrap().cpus.contains(&(cpu_index as u8)) {
+                    if numa_node.unwrap().cpus.contains(&cpu_index) {
                         fdt.set_property_u32("numa-node-id", numa_index as u32)?;
                     }
                 }
@@ -1475,7 +1578,7 @@ impl CompileFDTHelper for StdMachine {
         }
         generate_flash_device_node(fdt)?;
 
-        generate_pci_host_node(fdt)?;
+        generate_pci_host_node(fdt, self.has_its())?;
 
         Ok(())
     }
@@ -1559,3 +1662,43 @@ impl device_tree::CompileFDT for StdMachine {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn contains(blob: &[u8], pattern: &[u8]) -> bool {
+        blob.windows(pattern.len()).any(|w| w == pattern)
+    }
+
+    #[test]
+    fn test_pci_host_msi_parent() {
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.begin_node("").unwrap();
+        generate_pci_host_node(&mut fdt, true).unwrap();
+        fdt.end_node(root).unwrap();
+        assert!(contains(&fdt.finish().unwrap(), b"msi-parent"));
+
+        // Without ITS, PCI devices use INTx only.
+        let mut fdt = FdtBuilder::new();
+        let root = fdt.begin_node("").unwrap();
+        generate_pci_host_node(&mut fdt, false).unwrap();
+        fdt.end_node(root).unwrap();
+        let blob = fdt.finish().unwrap();
+        assert!(!contains(&blob, b"msi-parent"));
+        assert!(contains(&blob, b"interrupt-map"));
+    }
+
+    #[test]
+    fn test_iort_table() {
+        let iort = iort_table().aml_bytes();
+        assert_eq!(iort.len(), 128);
+        assert_eq!(&iort[0..4], b"IORT");
+        // ITS group node with one ITS.
+        assert_eq!(iort[48], ACPI_IORT_NODE_ITS_GROUP);
+        assert_eq!(u32::from_le_bytes(iort[64..68].try_into().unwrap()), 1);
+        // The root complex maps to the ITS group node.
+        assert_eq!(iort[72], ACPI_IORT_NODE_PCI_ROOT_COMPLEX);
+        assert_eq!(u32::from_le_bytes(iort[120..124].try_into().unwrap()), 48);
+    }
+}
@@ -26,6 +26,7 @@ use machine_manager::qmp::qmp_schema::UpdateRegionArgument;
 #[cfg(not(target_env = "musl"))]
 use ui::{
     input::{key_event, point_event},
+    screendump::qmp_screendump,
     vnc::qmp_query_vnc,
 };
 use util::aio::AioEngine;
@@ -43,7 +44,11 @@ use std::rc::Rc;
 use std::sync::{Arc, Mutex};
 
 use super::Result as MachineResult;
-use crate::MachineOps;
+use crate::cpu_model::{qmp_query_cpu_definitions, qmp_query_cpu_model_expansion};
+use crate::dump::qmp_dump_guest_memory;
+use crate::mem_aging::qmp_query_mem_aging;
+use crate::placement::qmp_query_placement;
+use crate::{qmp_query_memory_lock, MachineOps};
 #[cfg(target_arch = "x86_64")]
 use acpi::AcpiGenericAddress;
 use acpi::{
@@ -55,22 +60,34 @@ use address_space::{
 };
 pub use anyhow::Result;
 use anyhow::{bail, Context};
-use cpu::{CpuTopology, CPU};
-use devices::legacy::FwCfgOps;
+use cpu::{cpus_inject_nmi, cpus_wakeup, CpuTopology, CPU, CPU_DRIVER};
+use devices::legacy::{change_chardev, query_chardevs, FwCfgOps};
+use devices::smbios::{
+    build_smbios_tables, SmbiosCpuTopology, SMBIOS_ANCHOR_FILE, SMBIOS_TABLE_FILE,
+};
+use hypervisor::kvm::KVM_FDS;
 use machine_manager::config::{
-    get_chardev_config, get_netdev_config, get_pci_df, BlkDevConfig, ChardevType, ConfigCheck,
-    DriveConfig, NetworkInterfaceConfig, NumaNode, NumaNodes, PciBdf, ScsiCntlrConfig, VmConfig,
-    DEFAULT_VIRTQUEUE_SIZE, MAX_VIRTIO_QUEUE,
+    get_chardev_backend, get_chardev_config, get_netdev_config, get_pci_df, parse_error_policies,
+    parse_queue_iothreads, BlkDevConfig, ChardevConfig, ChardevType, ConfigCheck, DriveConfig,
+    MachineConfig, MachineType, NetworkInterfaceConfig, NumaNode, NumaNodes, PciBdf,
+    ScsiCntlrConfig, ScsiDevConfig, VmConfig, DEFAULT_SCSI_CMD_PER_LUN, DEFAULT_SCSI_MAX_SECTORS,
+    DEFAULT_SCSI_WRITE_VERIFY_MAX, DEFAULT_VIRTQUEUE_SIZE, MAX_NR_CPUS, MAX_VIRTIO_QUEUE,
+    SUPPORT_SCSI_MAX_LUN,
 };
+use machine_manager::event;
 use machine_manager::machine::{DeviceInterface, KvmVmState};
 use machine_manager::qmp::{qmp_schema, QmpChannel, Response};
+use machine_manager::qom::{self, QomObject};
 use migration::MigrationManager;
 use pci::hotplug::{handle_plug, handle_unplug_request};
 use pci::PciBus;
 use util::byte_code::ByteCode;
 use virtio::{
-    qmp_balloon, qmp_query_balloon, Block, BlockState, ScsiBus, ScsiCntlr, VhostKern, VhostUser,
-    VirtioDevice, VirtioNetState, VirtioPciDevice,
+    qmp_balloon, qmp_block_job_cancel, qmp_block_resize, qmp_block_set_writable,
+    qmp_block_set_write_threshold, qmp_blockdev_backup, qmp_query_balloon, qmp_query_block,
+    qmp_query_block_jobs, qmp_query_blockstats, qmp_query_netdev, register_resizable_block, Block,
+    BlockState, ScsiBus, ScsiCntlr, ScsiDisk, VhostKern, VhostUser, VirtioDevice, VirtioError,
+    VirtioNetState, VirtioPciDevice,
 };
 
 #[cfg(target_arch = "aarch64")]
@@ -80,6 +97,24 @@ use x86_64::{LayoutEntryType, MEM_LAYOUT};
 
 #[cfg(target_arch = "x86_64")]
 use self::x86_64::ich9_lpc::{PM_CTRL_OFFSET, PM_EVENT_OFFSET, RST_CTRL_OFFSET, SLEEP_CTRL_OFFSET};
+#[cfg(target_arch = "x86_64")]
+use self::x86_64::{GPE0_BLK_LEN, GPE0_BLK_OFFSET, SCI_IRQ};
+
+/// The machine type of the standard VM, which is reported by `query-machines`.
+pub(crate) fn machine_type_info() -> qmp_schema::MachineInfo {
+    qmp_schema::MachineInfo {
+        // Only the x86_64 standard VM supports vCPU hotplug.
+        hotplug: cfg!(target_arch = "x86_64"),
+        #[cfg(target_arch = "x86_64")]
+        name: "q35".to_string(),
+        #[cfg(target_arch = "aarch64")]
+        name: "virt".to_string(),
+        numa_mem_support: true,
+        cpu_max: MAX_NR_CPUS as u16,
+        deprecated: false,
+        is_default: MachineConfig::default().mach_type == MachineType::StandardVm,
+    }
+}
 
 trait StdMachineOps: AcpiBuilder {
     fn init_pci_host(&self) -> Result<()>;
@@ -125,10 +160,13 @@ trait StdMachineOps: AcpiBuilder {
                 .with_context(|| "Failed to build ACPI GTDT table")?;
             xsdt_entries.push(gtdt_addr);
 
-            let iort_addr = self
-                .build_iort_table(&acpi_tables, &mut loader)
-                .with_context(|| "Failed to build ACPI IORT table")?;
-            xsdt_entries.push(iort_addr);
+            // IORT only maps the root complex to the ITS.
+            if self.has_its() {
+                let iort_addr = self
+                    .build_iort_table(&acpi_tables, &mut loader)
+                    .with_context(|| "Failed to build ACPI IORT table")?;
+                xsdt_entries.push(iort_addr);
+            }
 
             let spcr_addr = self
                 .build_spcr_table(&acpi_tables, &mut loader)
@@ -179,7 +217,44 @@ trait StdMachineOps: AcpiBuilder {
         Ok(())
     }
 
-    fn add_fwcfg_device(&mut self, _nr_cpus: u8) -> Result<Option<Arc<Mutex<dyn FwCfgOps>>>> {
+    /// Build SMBIOS tables and the entry point, and add them to FwCfg as file entries.
+    ///
+    /// # Arguments
+    ///
+    /// `fw_cfg` - FwCfgOps trait object.
+    /// `vm_config` - VM configuration, contains the overrides of SMBIOS tables.
+    /// `ram_ranges` - Ranges of guest ram, (start, size).
+    fn build_smbios_tables(
+        &self,
+        fw_cfg: &Arc<Mutex<dyn FwCfgOps>>,
+        vm_config: &VmConfig,
+        ram_ranges: &[(u64, u64)],
+    ) -> Result<()> {
+        let topo = self.get_cpu_topo();
+        let topology = SmbiosCpuTopology {
+            sockets: topo.sockets,
+            cores: topo.dies * topo.clusters * topo.cores,
+            threads: topo.threads,
+        };
+        let (tables, entry_point) = build_smbios_tables(
+            &vm_config.smbios,
+            vm_config.uuid.as_deref(),
+            &topology,
+            ram_ranges,
+        )?;
+
+        let mut locked_fw_cfg = fw_cfg.lock().unwrap();
+        locked_fw_cfg
+            .add_file_entry(SMBIOS_TABLE_FILE, tables)
+            .with_context(|| "Failed to add SMBIOS tables file entry")?;
+        locked_fw_cfg
+            .add_file_entry(SMBIOS_ANCHOR_FILE, entry_point)
+            .with_context(|| "Failed to add SMBIOS anchor file entry")?;
+
+        Ok(())
+    }
+
+    fn add_fwcfg_device(&mut self, _nr_cpus: u16) -> Result<Option<Arc<Mutex<dyn FwCfgOps>>>> {
         bail!("Not implemented");
     }
 
@@ -189,6 +264,15 @@ trait StdMachineOps: AcpiBuilder {
 
     fn get_numa_nodes(&self) -> &Option<NumaNodes>;
 
+    /// Whether the MSIs of PCI devices are translated by the GICv3 ITS.
+    #[cfg(target_arch = "aarch64")]
+    fn has_its(&self) -> bool;
+
+    /// Hotplug a vcpu, which is located by the topology properties in `args`.
+    fn plug_cpu(&mut self, _args: &qmp_schema::DeviceAddArgument) -> Result<()> {
+        bail!("CPU hotplug is not supported");
+    }
+
     /// Register event notifier for reset of standard machine.
     ///
     /// # Arguments
@@ -453,6 +537,9 @@ trait AcpiBuilder {
         let mut fadt = AcpiTable::new(*b"FACP", 6, *b"STRATO", *b"VIRTFACP", 1);
 
         fadt.set_table_len(208_usize);
+        // SCI_INT bit, offset is 46.
+        #[cfg(target_arch = "x86_64")]
+        fadt.set_field(46, SCI_IRQ as u16);
         // PM1A_EVENT bit, offset is 56.
         #[cfg(target_arch = "x86_64")]
         fadt.set_field(56, 0x600);
@@ -462,6 +549,12 @@ trait AcpiBuilder {
         // PM_TMR_BLK bit, offset is 76.
         #[cfg(target_arch = "x86_64")]
         fadt.set_field(76, 0x608);
+        // GPE0_BLK bit, offset is 80.
+        #[cfg(target_arch = "x86_64")]
+        fadt.set_field(80, GPE0_BLK_OFFSET as u32);
+        // GPE0_BLK_LEN bit, offset is 92.
+        #[cfg(target_arch = "x86_64")]
+        fadt.set_field(92, GPE0_BLK_LEN);
         #[cfg(target_arch = "aarch64")]
         {
             // FADT flag: enable HW_REDUCED_ACPI bit on aarch64 plantform.
@@ -494,6 +587,10 @@ trait AcpiBuilder {
             fadt.set_field(172, 0x01_u8);
             fadt.set_field(173, 0x10_u8);
             fadt.set_field(176, PM_CTRL_OFFSET as u64);
+            // GPE0 block register, offset is 220.
+            fadt.set_field(220, 0x01_u8);
+            fadt.set_field(221, GPE0_BLK_LEN * 8);
+            fadt.set_field(224, GPE0_BLK_OFFSET as u64);
             // Sleep control register, offset is 244.
             fadt.set_field(244, 0x01_u8);
             fadt.set_field(245, 0x08_u8);
@@ -725,6 +822,15 @@ trait AcpiBuilder {
     }
 }
 
+/// Register the hotplugged device to the QOM tree.
+fn register_qom_device(args: &qmp_schema::DeviceAddArgument) {
+    let mut object = QomObject::new(&args.driver);
+    if let Some(drive) = args.drive.as_ref() {
+        object = object.with_prop("drive", drive.as_str());
+    }
+    qom::register_device(&args.id, object);
+}
+
 fn get_device_bdf(bus: Option<String>, addr: Option<String>) -> Result<PciBdf> {
     let mut pci_bdf = PciBdf {
         bus: bus.unwrap_or_else(|| String::from("pcie.0")),
@@ -760,14 +866,20 @@ impl StdMachine {
                 serial_num: args.serial_num.clone(),
                 iothread: args.iothread.clone(),
                 iops: conf.iops,
+                throttle_group: conf.throttle_group.clone(),
                 queues: args.queues.unwrap_or_else(|| {
                     VirtioPciDevice::virtio_pci_auto_queues_num(0, nr_cpus, MAX_VIRTIO_QUEUE)
                 }),
                 boot_index: args.boot_index,
                 chardev: None,
                 socket_path: None,
+                socket_reconnect: 0,
                 aio: conf.aio,
+                aio_register: conf.aio_register,
                 queue_size,
+                werror: conf.werror,
+                rerror: conf.rerror,
+                slow_io_warn_ms: 0,
             };
             dev.check()?;
             dev
@@ -783,10 +895,15 @@ impl StdMachine {
         }
 
         let blk_id = blk.id.clone();
-        let blk = Arc::new(Mutex::new(Block::new(blk, self.get_drive_files())));
+        let mut blk = Block::new(blk, self.get_drive_files());
+        if let Some(vm) = self.get_vm_lifecycle() {
+            blk.set_vm(vm);
+        }
+        let blk = Arc::new(Mutex::new(blk));
         let pci_dev = self
             .add_virtio_pci_device(&args.id, pci_bdf, blk.clone(), multifunction, false)
             .with_context(|| "Failed to add virtio pci block device")?;
+        register_resizable_block(&blk);
 
         if let Some(bootindex) = args.boot_index {
             if let Some(dev_path) = pci_dev.lock().unwrap().get_dev_path() {
@@ -814,6 +931,8 @@ impl StdMachine {
             }) as u32,
             boot_prefix: None,
             queue_size,
+            max_sectors: DEFAULT_SCSI_MAX_SECTORS,
+            cmd_per_lun: DEFAULT_SCSI_CMD_PER_LUN,
         };
         dev_cfg.check()?;
 
@@ -847,6 +966,132 @@ impl StdMachine {
         Ok(())
     }
 
+    fn plug_scsi_device(&mut self, args: &qmp_schema::DeviceAddArgument) -> Result<()> {
+        let scsi_type = if args.driver == "scsi-cd" {
+            ScsiDisk::SCSI_TYPE_ROM
+        } else {
+            ScsiDisk::SCSI_TYPE_DISK
+        };
+        let bus_name = args.bus.as_ref().with_context(|| "Scsi bus not set")?;
+        let drive = args.drive.as_ref().with_context(|| "Drive not set")?;
+        let lun = args.lun.unwrap_or(0);
+        if lun > SUPPORT_SCSI_MAX_LUN as usize {
+            bail!(
+                "Lun {} of scsi device exceeds max {}",
+                lun,
+                SUPPORT_SCSI_MAX_LUN
+            );
+        }
+        if self.find_scsi_device(&args.id).is_some() {
+            bail!("Device id {} existed", args.id);
+        }
+
+        let cntlr = self
+            .get_scsi_cntlr_list()
+            .with_context(|| "No scsi controller list found")?
+            .lock()
+            .unwrap()
+            .get(bus_name)
+            .cloned()
+            .with_context(|| format!("Scsi bus {} not found", bus_name))?;
+        let bus = cntlr
+            .lock()
+            .unwrap()
+            .bus
+            .clone()
+            .with_context(|| format!("Controller has no bus {}", bus_name))?;
+
+        let vm_config = self.get_vm_config();
+        let locked_vmconfig = vm_config.lock().unwrap();
+        let dev_cfg = if let Some(conf) = locked_vmconfig.drives.get(drive) {
+            ScsiDevConfig {
+                id: args.id.clone(),
+                path_on_host: conf.path_on_host.clone(),
+                serial: args.serial_num.clone(),
+                wwn: None,
+                bus: bus_name.clone(),
+                read_only: conf.read_only,
+                direct: conf.direct,
+                aio_type: conf.aio,
+                boot_index: None,
+                channel: 0,
+                target: args.scsi_id.unwrap_or(0),
+                lun: lun as u16,
+                werror: conf.werror,
+                rerror: conf.rerror,
+                write_verify_max: DEFAULT_SCSI_WRITE_VERIFY_MAX,
+            }
+        } else {
+            bail!("Drive not found");
+        };
+        drop(locked_vmconfig);
+
+        let mut scsi_device = ScsiDisk::ScsiDevice::new(dev_cfg, scsi_type, self.get_drive_files());
+        if let Some(vm) = self.get_vm_lifecycle() {
+            scsi_device.set_vm(vm);
+        }
+        scsi_device.realize()?;
+        let device = Arc::new(Mutex::new(scsi_device));
+        if let Err(e) = ScsiBus::scsi_bus_attach_device(&bus, &device) {
+            device.lock().unwrap().unrealize();
+            return Err(e);
+        }
+
+        self.get_vm_config()
+            .lock()
+            .unwrap()
+            .add_blk_device_config(args);
+        Ok(())
+    }
+
+    /// Find the scsi device `id` and the scsi bus which it's attached to.
+    fn find_scsi_device(
+        &mut self,
+        id: &str,
+    ) -> Option<(
+        Arc<Mutex<ScsiBus::ScsiBus>>,
+        Arc<Mutex<ScsiDisk::ScsiDevice>>,
+    )> {
+        let cntlr_list = self.get_scsi_cntlr_list()?.lock().unwrap();
+        for cntlr in cntlr_list.values() {
+            if let Some(bus) = &cntlr.lock().unwrap().bus {
+                if let Some(dev) = bus.lock().unwrap().find_device_by_id(id) {
+                    return Some((bus.clone(), dev));
+                }
+            }
+        }
+        None
+    }
+
+    fn unplug_scsi_device(
+        &mut self,
+        bus: &Arc<Mutex<ScsiBus::ScsiBus>>,
+        dev: &Arc<Mutex<ScsiDisk::ScsiDevice>>,
+    ) -> Result<()> {
+        let locked_dev = dev.lock().unwrap();
+        let (id, target, lun) = (
+            locked_dev.config.id.clone(),
+            locked_dev.config.target,
+            locked_dev.config.lun,
+        );
+        drop(locked_dev);
+
+        ScsiBus::scsi_bus_detach_device(bus, target, lun)?;
+        dev.lock().unwrap().unrealize();
+
+        self.del_bootindex_devices(&id);
+        self.get_vm_config()
+            .lock()
+            .unwrap()
+            .del_device_by_id(id.clone());
+        let device_del = qmp_schema::DeviceDeleted {
+            device: Some(id.clone()),
+            path: format!("/machine/peripheral/{}", id),
+        };
+        event!(DeviceDeleted; device_del);
+        Ok(())
+    }
+
     fn plug_vhost_user_blk_pci(
         &mut self,
         pci_bdf: &PciBdf,
@@ -861,7 +1106,7 @@ impl StdMachine {
             bail!("Chardev not set");
         };
         let queue_size = args.queue_size.unwrap_or(DEFAULT_VIRTQUEUE_SIZE);
-        let socket_path = self
+        let (socket_path, socket_reconnect) = self
             .get_socket_path(&locked_vmconfig, chardev.to_string())
             .with_context(|| "Failed to get socket path")?;
         let nr_cpus = locked_vmconfig.machine_config.nr_cpus;
@@ -872,7 +1117,8 @@ impl StdMachine {
             }),
             boot_index: args.boot_index,
             chardev: Some(chardev.to_string()),
-            socket_path,
+            socket_path: Some(socket_path),
+            socket_reconnect,
             queue_size,
             ..BlkDevConfig::default()
         };
@@ -887,7 +1133,7 @@ impl StdMachine {
         Ok(())
     }
 
-    fn get_socket_path(&self, vm_config: &VmConfig, chardev: String) -> Result<Option<String>> {
+    fn get_socket_path(&self, vm_config: &VmConfig, chardev: String) -> Result<(String, u64)> {
         let char_dev = if let Some(char_dev) = vm_config.chardev.get(&chardev) {
             char_dev
         } else {
@@ -899,6 +1145,7 @@ impl StdMachine {
                 path,
                 server,
                 nowait,
+                reconnect,
             } => {
                 if *server || *nowait {
                     bail!(
@@ -906,7 +1153,7 @@ impl StdMachine {
                         path
                     );
                 }
-                Some(path.clone())
+                (path.clone(), *reconnect)
             }
             _ => {
                 bail!("Chardev {:?} backend should be socket type.", &chardev);
@@ -932,23 +1179,35 @@ impl StdMachine {
         let mut locked_vmconfig = vm_config.lock().unwrap();
         let dev = if let Some(conf) = locked_vmconfig.netdevs.get(netdev) {
             let mut socket_path: Option<String> = None;
+            let mut socket_reconnect = 0;
             if let Some(chardev) = &conf.chardev {
-                socket_path = self
+                let (path, reconnect) = self
                     .get_socket_path(&locked_vmconfig, (&chardev).to_string())
                     .with_context(|| "Failed to get socket path")?;
+                socket_path = Some(path);
+                socket_reconnect = reconnect;
             }
             let dev = NetworkInterfaceConfig {
                 id: args.id.clone(),
+                netdev: netdev.clone(),
                 host_dev_name: conf.ifname.clone(),
+                script: conf.script.clone(),
+                downscript: conf.downscript.clone(),
                 mac: args.mac.clone(),
                 tap_fds: conf.tap_fds.clone(),
                 vhost_type: conf.vhost_type.clone(),
                 vhost_fds: conf.vhost_fds.clone(),
                 iothread: args.iothread.clone(),
+                queue_iothreads: args.queue_iothreads.as_deref().map(parse_queue_iothreads),
                 queues: conf.queues,
                 mq: conf.queues > 2,
                 socket_path,
+                chardev: conf.chardev.clone(),
+                socket_reconnect,
+                mtu: None,
                 queue_size,
+                rate_limit_bps: conf.rate_limit_bps,
+                rate_limit_pps: conf.rate_limit_pps,
             };
             dev.check()?;
             dev
@@ -1034,6 +1293,16 @@ impl DeviceInterface for StdMachine {
                 running: false,
                 status: qmp_schema::RunState::paused,
             },
+            KvmVmState::Suspended => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: qmp_schema::RunState::suspended,
+            },
+            KvmVmState::Shutdown => qmp_schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: qmp_schema::RunState::shutdown,
+            },
             _ => Default::default(),
         };
 
@@ -1046,7 +1315,12 @@ impl DeviceInterface for StdMachine {
         let cpus = self.get_cpus();
         for cpu_index in 0..cpu_topo.max_cpus {
             if cpu_topo.get_mask(cpu_index as usize) == 1 {
-                let thread_id = cpus[cpu_index as usize].tid();
+                // Hotplugged vcpus may be plugged out of order.
+                let cpu = match cpus.iter().find(|cpu| cpu.id() == cpu_index) {
+                    Some(cpu) => cpu,
+                    None => continue,
+                };
+                let thread_id = cpu.tid();
                 let cpu_instance = cpu_topo.get_topo_instance_for_qmp(cpu_index as usize);
                 let cpu_common = qmp_schema::CpuInfoCommon {
                     current: true,
@@ -1057,12 +1331,16 @@ impl DeviceInterface for StdMachine {
                     props: Some(cpu_instance),
                     CPU: cpu_index as isize,
                     thread_id: thread_id as isize,
+                    host_cpus: cpu.affinity(),
                 };
                 #[cfg(target_arch = "x86_64")]
                 {
+                    let tsc_khz = cpu.tsc_khz();
                     let cpu_info = qmp_schema::CpuInfo::x86 {
                         common: cpu_common,
-                        x86: qmp_schema::CpuInfoX86 {},
+                        x86: qmp_schema::CpuInfoX86 {
+                            tsc_khz: (tsc_khz != 0).then_some(tsc_khz),
+                        },
                     };
                     cpu_vec.push(serde_json::to_value(cpu_info).unwrap());
                 }
@@ -1079,25 +1357,87 @@ impl DeviceInterface for StdMachine {
         Response::create_response(cpu_vec.into(), None)
     }
 
+    fn query_cpu_model_expansion(
+        &self,
+        expansion_type: String,
+        model: qmp_schema::CpuModelInfo,
+    ) -> Response {
+        let cpu_config = self
+            .get_vm_config()
+            .lock()
+            .unwrap()
+            .machine_config
+            .cpu_config
+            .clone();
+        match qmp_query_cpu_model_expansion(self.get_cpus(), &cpu_config, &expansion_type, &model) {
+            Ok(info) => Response::create_response(serde_json::to_value(&info).unwrap(), None),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn query_cpu_definitions(&self) -> Response {
+        Response::create_response(
+            serde_json::to_value(qmp_query_cpu_definitions()).unwrap(),
+            None,
+        )
+    }
+
     fn query_hotpluggable_cpus(&self) -> Response {
-        Response::create_empty_response()
+        let hotplug_vec = self.get_cpu_topo().get_hotpluggable_cpus_for_qmp();
+        Response::create_response(serde_json::to_value(hotplug_vec).unwrap(), None)
+    }
+
+    fn query_kvm(&self) -> Response {
+        let kvm_fds = KVM_FDS.load();
+        let kvm = qmp_schema::KvmInfo {
+            enabled: kvm_fds.vm_fd.is_some(),
+            present: kvm_fds.fd.is_some(),
+        };
+        Response::create_response(serde_json::to_value(kvm).unwrap(), None)
     }
 
     fn balloon(&self, value: u64) -> Response {
-        if qmp_balloon(value) {
-            return Response::create_empty_response();
+        if let Err(e) = qmp_balloon(value) {
+            let err = match e.downcast_ref::<VirtioError>() {
+                Some(VirtioError::DeviceNotActivated(_)) => {
+                    qmp_schema::QmpErrorClass::DeviceNotActive(
+                        "No balloon device has been activated".to_string(),
+                    )
+                }
+                _ => qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+            };
+            return Response::create_error_response(err, None);
         }
-        Response::create_error_response(
-            qmp_schema::QmpErrorClass::DeviceNotActive(
-                "No balloon device has been activated".to_string(),
+        Response::create_empty_response()
+    }
+
+    fn inject_nmi(&self) -> Response {
+        let vm_state = *self.get_vm_state().0.lock().unwrap();
+        match cpus_inject_nmi(self.get_cpus(), vm_state) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
             ),
-            None,
-        )
+        }
+    }
+
+    fn system_wakeup(&self) -> Response {
+        if let Err(e) = cpus_wakeup(self.get_cpus(), &mut self.get_vm_state().0.lock().unwrap()) {
+            return Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            );
+        }
+        event!(Wakeup);
+        Response::create_empty_response()
     }
 
     fn query_balloon(&self) -> Response {
-        if let Some(actual) = qmp_query_balloon() {
-            let ret = qmp_schema::BalloonInfo { actual };
+        if let Some(ret) = qmp_query_balloon() {
             return Response::create_response(serde_json::to_value(&ret).unwrap(), None);
         }
         Response::create_error_response(
@@ -1108,6 +1448,32 @@ impl DeviceInterface for StdMachine {
         )
     }
 
+    fn query_mem_aging(&self) -> Response {
+        if let Some(info) = qmp_query_mem_aging() {
+            return Response::create_response(serde_json::to_value(&info).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Memory aging is not running".to_string()),
+            None,
+        )
+    }
+
+    fn query_memory_lock(&self) -> Response {
+        let vm_config = self.get_vm_config();
+        let info = qmp_query_memory_lock(&vm_config.lock().unwrap().machine_config.mem_config);
+        Response::create_response(serde_json::to_value(&info).unwrap(), None)
+    }
+
+    fn query_placement(&self) -> Response {
+        if let Some(info) = qmp_query_placement() {
+            return Response::create_response(serde_json::to_value(&info).unwrap(), None);
+        }
+        Response::create_error_response(
+            qmp_schema::QmpErrorClass::GenericError("Auto placement is not enabled".to_string()),
+            None,
+        )
+    }
+
     fn query_vnc(&self) -> Response {
         #[cfg(not(target_env = "musl"))]
         if let Some(vnc_info) = qmp_query_vnc() {
@@ -1121,6 +1487,146 @@ impl DeviceInterface for StdMachine {
         )
     }
 
+    fn query_name(&self) -> Response {
+        let name_info = qmp_schema::NameInfo::new(&self.get_vm_config().lock().unwrap().guest_name);
+        Response::create_response(serde_json::to_value(&name_info).unwrap(), None)
+    }
+
+    fn query_uuid(&self) -> Response {
+        let vm_config = self.get_vm_config();
+        let uuid_info = qmp_schema::UuidInfo::new(vm_config.lock().unwrap().uuid.as_deref());
+        Response::create_response(serde_json::to_value(&uuid_info).unwrap(), None)
+    }
+
+    fn query_chardev(&self) -> Response {
+        Response::create_response(serde_json::to_value(query_chardevs()).unwrap(), None)
+    }
+
+    fn query_vsock(&self) -> Response {
+        let vsocks = match self.get_vm_config().lock().unwrap().get_vsocks() {
+            Ok(vsocks) => vsocks,
+            Err(e) => {
+                return Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                );
+            }
+        };
+        let info: Vec<qmp_schema::VsockInfo> = vsocks
+            .into_iter()
+            .map(|vsock| qmp_schema::VsockInfo {
+                id: vsock.id,
+                guest_cid: vsock.guest_cid,
+                vhost_fd: vsock.vhost_fd,
+            })
+            .collect();
+        Response::create_response(serde_json::to_value(info).unwrap(), None)
+    }
+
+    fn query_block(&self, reset_errors: Option<bool>) -> Response {
+        let info = qmp_query_block(reset_errors.unwrap_or(false));
+        Response::create_response(serde_json::to_value(info).unwrap(), None)
+    }
+
+    fn query_blockstats(&self) -> Response {
+        let stats = qmp_query_blockstats();
+        Response::create_response(serde_json::to_value(stats).unwrap(), None)
+    }
+
+    fn query_netdev(&self, reset_errors: Option<bool>) -> Response {
+        let info = qmp_query_netdev(reset_errors.unwrap_or(false));
+        Response::create_response(serde_json::to_value(info).unwrap(), None)
+    }
+
+    fn block_set_write_threshold(&self, device: String, write_threshold: u64) -> Response {
+        match qmp_block_set_write_threshold(&device, write_threshold) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn block_set_writable(&self, device: String) -> Response {
+        match qmp_block_set_writable(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn block_resize(&self, device: String, size: u64, allow_shrink: Option<bool>) -> Response {
+        match qmp_block_resize(&device, size, allow_shrink.unwrap_or(false)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn eject(&self, device: String, force: Option<bool>) -> Response {
+        match ScsiBus::qmp_eject(&device, force.unwrap_or(false)) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn blockdev_backup(&self, device: String, target: String, sync: Option<String>) -> Response {
+        match qmp_blockdev_backup(&device, &target, sync.as_deref()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn block_job_cancel(&self, device: String) -> Response {
+        match qmp_block_job_cancel(&device) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                None,
+            ),
+        }
+    }
+
+    fn query_block_jobs(&self) -> Response {
+        let jobs = qmp_query_block_jobs();
+        Response::create_response(serde_json::to_value(jobs).unwrap(), None)
+    }
+
+    fn dump_guest_memory(&mut self, args: qmp_schema::DumpGuestMemoryArgument) -> Response {
+        let sys_mem = self.get_sys_mem().clone();
+        let vm_state = self.get_vm_state().clone();
+        let cpus = self.get_cpus().clone();
+        match qmp_dump_guest_memory(self, &vm_state, &args, &sys_mem, &cpus) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
+    #[cfg(not(target_env = "musl"))]
+    fn screendump(&self, filename: String, format: Option<String>) -> Response {
+        match qmp_screendump(&filename, format.as_deref()) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
     fn device_add(&mut self, args: Box<qmp_schema::DeviceAddArgument>) -> Response {
         if let Err(e) = self.check_device_id_existed(&args.id) {
             return Response::create_error_response(
@@ -1129,6 +1635,39 @@ impl DeviceInterface for StdMachine {
             );
         }
 
+        // vCPU is not attached to PCI bus, plug it separately.
+        if args.driver == CPU_DRIVER {
+            return match self.plug_cpu(args.as_ref()) {
+                Ok(()) => Response::create_empty_response(),
+                Err(e) => {
+                    error!("{:?}", e);
+                    let err_str = format!("Failed to add cpu: {}", e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    )
+                }
+            };
+        }
+
+        // Scsi device is attached to the scsi controller rather than PCI bus, plug it separately.
+        if args.driver == "scsi-hd" || args.driver == "scsi-cd" {
+            return match self.plug_scsi_device(args.as_ref()) {
+                Ok(()) => {
+                    register_qom_device(args.as_ref());
+                    Response::create_empty_response()
+                }
+                Err(e) => {
+                    error!("{:?}", e);
+                    let err_str = format!("Failed to add scsi device: {}", e);
+                    Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(err_str),
+                        None,
+                    )
+                }
+            };
+        }
+
         // Use args.bus.clone() and args.addr.clone() because args borrowed in the following process.
         let pci_bdf = match get_device_bdf(args.bus.clone(), args.addr.clone()) {
             Ok(bdf) => bdf,
@@ -1203,7 +1742,10 @@ impl DeviceInterface for StdMachine {
         let locked_pci_host = self.get_pci_host().unwrap().lock().unwrap();
         if let Some((bus, dev)) = PciBus::find_attached_bus(&locked_pci_host.root_bus, &args.id) {
             match handle_plug(&bus, &dev) {
-                Ok(()) => Response::create_empty_response(),
+                Ok(()) => {
+                    register_qom_device(args.as_ref());
+                    Response::create_empty_response()
+                }
                 Err(e) => {
                     if let Err(e) = PciBus::detach_device(&bus, &dev) {
                         error!("{:?}", e);
@@ -1227,6 +1769,19 @@ impl DeviceInterface for StdMachine {
     }
 
     fn device_del(&mut self, device_id: String) -> Response {
+        if let Some((bus, dev)) = self.find_scsi_device(&device_id) {
+            return match self.unplug_scsi_device(&bus, &dev) {
+                Ok(()) => {
+                    qom::unregister_device(&device_id);
+                    Response::create_empty_response()
+                }
+                Err(e) => Response::create_error_response(
+                    qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                    None,
+                ),
+            };
+        }
+
         let pci_host = match self.get_pci_host() {
             Ok(host) => host,
             Err(e) => {
@@ -1247,6 +1802,7 @@ impl DeviceInterface for StdMachine {
                     self.del_bootindex_devices(&dev_id);
                     let vm_config = self.get_vm_config();
                     let mut locked_config = vm_config.lock().unwrap();
+                    qom::unregister_device(&device_id);
                     locked_config.del_device_by_id(device_id);
                     drop(locked_config);
                     Response::create_empty_response()
@@ -1269,18 +1825,33 @@ impl DeviceInterface for StdMachine {
         } else {
             true
         };
+        let (werror, rerror) =
+            match parse_error_policies(args.werror.as_deref(), args.rerror.as_deref()) {
+                Ok(policies) => policies,
+                Err(e) => {
+                    return Response::create_error_response(
+                        qmp_schema::QmpErrorClass::GenericError(e.to_string()),
+                        None,
+                    );
+                }
+            };
         let config = DriveConfig {
             id: args.node_name,
             path_on_host: args.file.filename.clone(),
             read_only,
             direct,
             iops: args.iops,
+            throttle_group: None,
             // TODO Add aio option by qmp, now we set it based on "direct".
             aio: if direct {
                 AioEngine::Native
             } else {
                 AioEngine::Off
             },
+            aio_register: false,
+            werror,
+            rerror,
+            backing: None,
         };
 
         if let Err(e) = config.check() {
@@ -1326,17 +1897,12 @@ impl DeviceInterface for StdMachine {
     }
 
     fn blockdev_del(&self, node_name: String) -> Response {
-        match self
-            .get_vm_config()
-            .lock()
-            .unwrap()
-            .del_drive_by_id(&node_name)
-        {
-            Ok(path) => {
-                // It's safe to unwrap as the path has been registered.
-                self.unregister_drive_file(&path).unwrap();
-                Response::create_empty_response()
-            }
+        let vm_config = self.get_vm_config();
+        let mut locked_vmconfig = vm_config.lock().unwrap();
+        let drive_files = self.get_drive_files();
+        let mut locked_files = drive_files.lock().unwrap();
+        match locked_vmconfig.del_drive_by_id(&node_name, &mut locked_files) {
+            Ok(_) => Response::create_empty_response(),
             Err(e) => Response::create_error_response(
                 qmp_schema::QmpErrorClass::GenericError(e.to_string()),
                 None,
@@ -1386,6 +1952,24 @@ impl DeviceInterface for StdMachine {
         }
     }
 
+    fn chardev_change(&mut self, id: String, backend: qmp_schema::BackendOptions) -> Response {
+        let result = get_chardev_backend(backend).and_then(|backend| {
+            ChardevConfig {
+                id: id.clone(),
+                backend: backend.clone(),
+            }
+            .check()?;
+            change_chardev(&id, backend)
+        });
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => Response::create_error_response(
+                qmp_schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        }
+    }
+
     fn netdev_add(&mut self, args: Box<qmp_schema::NetDevAddArgument>) -> Response {
         let config = match get_netdev_config(args) {
             Ok(conf) => conf,
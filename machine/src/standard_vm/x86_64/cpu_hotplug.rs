@@ -0,0 +1,424 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex};
+
+use acpi::{
+    AmlAddressSpaceType, AmlArg, AmlBuffer, AmlBuilder, AmlCallWithArgs1, AmlDevice, AmlEqual,
+    AmlField, AmlFieldAccessType, AmlFieldLockRule, AmlFieldUnit, AmlFieldUpdateRule, AmlIf,
+    AmlInteger, AmlMethod, AmlName, AmlNameDecl, AmlNotify, AmlOne, AmlOpRegion, AmlReturn,
+    AmlScope, AmlScopeBuilder, AmlStore, AmlString, AmlZero,
+};
+use address_space::{AddressSpace, GuestAddress, Region, RegionOps};
+use anyhow::{bail, Context, Result};
+use hypervisor::kvm::KVM_FDS;
+use log::error;
+use util::byte_code::ByteCode;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::local_apic_entry;
+
+/// IO port of GPE0 block, status and enable registers take half of it each.
+pub const GPE0_BLK_OFFSET: u16 = 0x620;
+pub const GPE0_BLK_LEN: u8 = 4;
+/// ISA interrupt of ACPI SCI.
+pub const SCI_IRQ: u8 = 9;
+/// IO port of CPU hotplug registers.
+const CPU_HOTPLUG_OFFSET: u16 = 0xcd8;
+const CPU_HOTPLUG_LEN: u64 = 8;
+/// GPE0 bit used to notify CPU hotplug event, handled by `\_GPE._E02`.
+const GPE_CPU_HOTPLUG: u16 = 1 << 2;
+
+/// Offset of the selector register, which selects the cpu the status register refers to.
+const CPU_SELECTOR_OFFSET: u64 = 0;
+/// Offset of the status register of the selected cpu.
+const CPU_STATUS_OFFSET: u64 = 4;
+/// The selected cpu is present, read only.
+const CPU_STATUS_ENABLED: u8 = 1 << 0;
+/// The selected cpu is just inserted and not handled by guest, write 1 to clear.
+const CPU_STATUS_INSERTING: u8 = 1 << 1;
+
+#[derive(Default, Clone, Copy)]
+struct CpuSlot {
+    present: bool,
+    inserting: bool,
+}
+
+/// ACPI based CPU hotplug controller, it provides the GPE0 block and the CPU
+/// hotplug registers, and raises SCI to notify guest of new plugged cpus.
+pub struct CpuHotplugCtrl {
+    /// Hotplug state of all possible cpus.
+    slots: Vec<CpuSlot>,
+    /// Selected cpu of the status register.
+    selector: u32,
+    /// GPE0 status register.
+    gpe_sts: u16,
+    /// GPE0 enable register.
+    gpe_en: u16,
+    /// Eventfd to inject SCI.
+    sci_evt: Arc<EventFd>,
+}
+
+impl CpuHotplugCtrl {
+    /// Create CPU hotplug controller.
+    ///
+    /// # Arguments
+    ///
+    /// * `nr_cpus` - Number of vcpus present at boot.
+    /// * `max_cpus` - Number of all possible vcpus.
+    pub fn new(nr_cpus: u16, max_cpus: u16) -> Result<Self> {
+        let mut slots = vec![CpuSlot::default(); max_cpus as usize];
+        slots
+            .iter_mut()
+            .take(nr_cpus as usize)
+            .for_each(|slot| slot.present = true);
+        Ok(CpuHotplugCtrl {
+            slots,
+            selector: 0,
+            gpe_sts: 0,
+            gpe_en: 0,
+            sci_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK)?),
+        })
+    }
+
+    /// Register IO regions of controller to `sys_io`, and route SCI to kvm irqchip.
+    pub fn realize(ctrl: Arc<Mutex<Self>>, sys_io: &Arc<AddressSpace>) -> Result<()> {
+        let cloned_ctrl = ctrl.clone();
+        let read_ops = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+            cloned_ctrl.lock().unwrap().read_gpe(data, offset)
+        };
+        let cloned_ctrl = ctrl.clone();
+        let write_ops = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+            cloned_ctrl.lock().unwrap().write_gpe(data, offset)
+        };
+        let ops = RegionOps {
+            read: Arc::new(read_ops),
+            write: Arc::new(write_ops),
+        };
+        sys_io
+            .root()
+            .add_subregion(
+                Region::init_io_region(GPE0_BLK_LEN as u64, ops),
+                GPE0_BLK_OFFSET as u64,
+            )
+            .with_context(|| "Failed to register GPE0 block in I/O space.")?;
+
+        let cloned_ctrl = ctrl.clone();
+        let read_ops = move |data: &mut [u8], _addr: GuestAddress, offset: u64| -> bool {
+            cloned_ctrl.lock().unwrap().read_cpu_regs(data, offset)
+        };
+        let cloned_ctrl = ctrl.clone();
+        let write_ops = move |data: &[u8], _addr: GuestAddress, offset: u64| -> bool {
+            cloned_ctrl.lock().unwrap().write_cpu_regs(data, offset)
+        };
+        let ops = RegionOps {
+            read: Arc::new(read_ops),
+            write: Arc::new(write_ops),
+        };
+        sys_io
+            .root()
+            .add_subregion(
+                Region::init_io_region(CPU_HOTPLUG_LEN, ops),
+                CPU_HOTPLUG_OFFSET as u64,
+            )
+            .with_context(|| "Failed to register CPU hotplug registers in I/O space.")?;
+
+        let sci_evt = ctrl.lock().unwrap().sci_evt.clone();
+        KVM_FDS
+            .load()
+            .register_irqfd(&sci_evt, SCI_IRQ as u32)
+            .with_context(|| "Failed to register irqfd for SCI")?;
+        Ok(())
+    }
+
+    /// Mark `vcpu_id` as inserted and notify guest via GPE.
+    pub fn plug_cpu(&mut self, vcpu_id: u16) -> Result<()> {
+        let slot = match self.slots.get_mut(vcpu_id as usize) {
+            Some(slot) => slot,
+            None => bail!("CPU {} is out of CPU hotplug controller", vcpu_id),
+        };
+        if slot.present {
+            bail!("CPU {} is already present", vcpu_id);
+        }
+        slot.present = true;
+        slot.inserting = true;
+        self.gpe_sts |= GPE_CPU_HOTPLUG;
+        self.update_sci();
+        Ok(())
+    }
+
+    fn update_sci(&self) {
+        if self.gpe_sts & self.gpe_en != 0 && self.sci_evt.write(1).is_err() {
+            error!("Failed to inject SCI for GPE event");
+        }
+    }
+
+    fn gpe_bytes(&self) -> [u8; GPE0_BLK_LEN as usize] {
+        let mut bytes = [0_u8; GPE0_BLK_LEN as usize];
+        bytes[0..2].copy_from_slice(self.gpe_sts.as_bytes());
+        bytes[2..4].copy_from_slice(self.gpe_en.as_bytes());
+        bytes
+    }
+
+    fn read_gpe(&self, data: &mut [u8], offset: u64) -> bool {
+        let start = offset as usize;
+        let bytes = self.gpe_bytes();
+        if start + data.len() > bytes.len() {
+            error!(
+                "Invalid GPE0 read: offset {}, length {}",
+                offset,
+                data.len()
+            );
+            return false;
+        }
+        data.copy_from_slice(&bytes[start..start + data.len()]);
+        true
+    }
+
+    fn write_gpe(&mut self, data: &[u8], offset: u64) -> bool {
+        let start = offset as usize;
+        if start + data.len() > GPE0_BLK_LEN as usize {
+            error!(
+                "Invalid GPE0 write: offset {}, length {}",
+                offset,
+                data.len()
+            );
+            return false;
+        }
+        for (i, value) in data.iter().enumerate() {
+            let index = start + i;
+            let shift = (index % 2) * 8;
+            if index < 2 {
+                // Status bits are cleared by writing 1.
+                self.gpe_sts &= !((*value as u16) << shift);
+            } else {
+                self.gpe_en = (self.gpe_en & !(0xff << shift)) | ((*value as u16) << shift);
+            }
+        }
+        self.update_sci();
+        true
+    }
+
+    fn read_cpu_regs(&self, data: &mut [u8], offset: u64) -> bool {
+        let mut regs = [0_u8; CPU_HOTPLUG_LEN as usize];
+        regs[0..4].copy_from_slice(self.selector.as_bytes());
+        if let Some(slot) = self.slots.get(self.selector as usize) {
+            if slot.present {
+                regs[CPU_STATUS_OFFSET as usize] |= CPU_STATUS_ENABLED;
+            }
+            if slot.inserting {
+                regs[CPU_STATUS_OFFSET as usize] |= CPU_STATUS_INSERTING;
+            }
+        }
+
+        let start = offset as usize;
+        if start + data.len() > regs.len() {
+            error!(
+                "Invalid CPU hotplug register read: offset {}, length {}",
+                offset,
+                data.len()
+            );
+            return false;
+        }
+        data.copy_from_slice(&regs[start..start + data.len()]);
+        true
+    }
+
+    fn write_cpu_regs(&mut self, data: &[u8], offset: u64) -> bool {
+        match (offset, data.len()) {
+            (CPU_SELECTOR_OFFSET, 4) => {
+                self.selector = u32::from_le_bytes(data.try_into().unwrap());
+            }
+            (CPU_STATUS_OFFSET, 1) => {
+                if data[0] & CPU_STATUS_INSERTING != 0 {
+                    if let Some(slot) = self.slots.get_mut(self.selector as usize) {
+                        slot.inserting = false;
+                    }
+                }
+            }
+            _ => {
+                error!(
+                    "Invalid CPU hotplug register write: offset {}, length {}",
+                    offset,
+                    data.len()
+                );
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Name of the ACPI device of cpu `cpu_id`. The id is in hex so that all possible cpus fit
+/// in the four characters of an ACPI name.
+fn cpu_device_name(cpu_id: usize) -> String {
+    format!("C{:03X}", cpu_id)
+}
+
+impl AmlBuilder for CpuHotplugCtrl {
+    fn aml_bytes(&self) -> Vec<u8> {
+        let mut sb_scope = AmlScope::new("\\_SB");
+        sb_scope.append_child(AmlOpRegion::new(
+            "PRST",
+            AmlAddressSpaceType::SystemIO,
+            CPU_HOTPLUG_OFFSET as u64,
+            CPU_HOTPLUG_LEN,
+        ));
+        let mut field = AmlField::new(
+            "PRST",
+            AmlFieldAccessType::DWord,
+            AmlFieldLockRule::NoLock,
+            AmlFieldUpdateRule::Preserve,
+        );
+        field.append_child(AmlFieldUnit::new(Some("CSEL"), 32));
+        sb_scope.append_child(field);
+        let mut field = AmlField::new(
+            "PRST",
+            AmlFieldAccessType::Byte,
+            AmlFieldLockRule::NoLock,
+            AmlFieldUpdateRule::WriteAsZeros,
+        );
+        field.append_child(AmlFieldUnit::new(None, CPU_STATUS_OFFSET as u32 * 8));
+        field.append_child(AmlFieldUnit::new(Some("CPEN"), 1));
+        field.append_child(AmlFieldUnit::new(Some("CINS"), 1));
+        sb_scope.append_child(field);
+
+        // CSTA(cpu_id): get _STA of cpu.
+        let mut method = AmlMethod::new("CSTA", 1, true);
+        method.append_child(AmlStore::new(AmlArg(0), AmlName("CSEL".to_string())));
+        let mut if_scope = AmlIf::new(AmlEqual::new(AmlName("CPEN".to_string()), AmlOne));
+        if_scope.append_child(AmlReturn::with_value(AmlInteger(0xF)));
+        method.append_child(if_scope);
+        method.append_child(AmlReturn::with_value(AmlZero));
+        sb_scope.append_child(method);
+
+        // CSCN(): notify guest of all inserting cpus.
+        let mut method = AmlMethod::new("CSCN", 0, true);
+        for cpu_id in 0..self.slots.len() {
+            method.append_child(AmlStore::new(
+                AmlInteger(cpu_id as u64),
+                AmlName("CSEL".to_string()),
+            ));
+            let mut if_scope = AmlIf::new(AmlEqual::new(AmlName("CINS".to_string()), AmlOne));
+            if_scope.append_child(AmlNotify::new(AmlName(cpu_device_name(cpu_id)), AmlOne));
+            if_scope.append_child(AmlStore::new(AmlOne, AmlName("CINS".to_string())));
+            method.append_child(if_scope);
+        }
+        sb_scope.append_child(method);
+
+        for cpu_id in 0..self.slots.len() {
+            let mut dev = AmlDevice::new(cpu_device_name(cpu_id).as_str());
+            dev.append_child(AmlNameDecl::new("_HID", AmlString("ACPI0007".to_string())));
+            dev.append_child(AmlNameDecl::new("_UID", AmlInteger(cpu_id as u64)));
+            dev.append_child(AmlNameDecl::new("_PXM", AmlInteger(0)));
+            let mut method = AmlMethod::new("_STA", 0, false);
+            method.append_child(AmlReturn::with_value(AmlCallWithArgs1::new(
+                "CSTA",
+                AmlInteger(cpu_id as u64),
+            )));
+            dev.append_child(method);
+            dev.append_child(AmlNameDecl::new(
+                "_MAT",
+                AmlBuffer(local_apic_entry(cpu_id as u16, true)),
+            ));
+            sb_scope.append_child(dev);
+        }
+
+        let mut gpe_scope = AmlScope::new("\\_GPE");
+        let mut method = AmlMethod::new("_E02", 0, false);
+        method.append_child(AmlName("\\_SB.CSCN".to_string()));
+        gpe_scope.append_child(method);
+
+        let mut bytes = sb_scope.aml_bytes();
+        bytes.extend(gpe_scope.aml_bytes());
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_status(ctrl: &mut CpuHotplugCtrl, vcpu_id: u32) -> u8 {
+        assert!(ctrl.write_cpu_regs(vcpu_id.as_bytes(), CPU_SELECTOR_OFFSET));
+        let mut status = [0_u8; 1];
+        assert!(ctrl.read_cpu_regs(&mut status, CPU_STATUS_OFFSET));
+        status[0]
+    }
+
+    #[test]
+    fn test_cpu_hotplug_registers() {
+        let mut ctrl = CpuHotplugCtrl::new(2, 4).unwrap();
+        assert_eq!(read_status(&mut ctrl, 1), CPU_STATUS_ENABLED);
+        assert_eq!(read_status(&mut ctrl, 2), 0);
+        // Selector out of range reads as an absent cpu.
+        assert_eq!(read_status(&mut ctrl, 8), 0);
+
+        assert!(ctrl.plug_cpu(1).is_err());
+        assert!(ctrl.plug_cpu(4).is_err());
+        assert!(ctrl.plug_cpu(3).is_ok());
+        assert_eq!(
+            read_status(&mut ctrl, 3),
+            CPU_STATUS_ENABLED | CPU_STATUS_INSERTING
+        );
+        assert!(ctrl.plug_cpu(3).is_err());
+
+        // Guest acknowledges the inserting event.
+        let mut selector = [0_u8; 4];
+        assert!(ctrl.read_cpu_regs(&mut selector, CPU_SELECTOR_OFFSET));
+        assert_eq!(u32::from_le_bytes(selector), 3);
+        assert!(ctrl.write_cpu_regs(&[CPU_STATUS_INSERTING], CPU_STATUS_OFFSET));
+        assert_eq!(read_status(&mut ctrl, 3), CPU_STATUS_ENABLED);
+
+        // Only selector and status registers are writable.
+        assert!(!ctrl.write_cpu_regs(&[0], 1));
+        assert!(!ctrl.write_cpu_regs(&[0, 0], CPU_STATUS_OFFSET));
+    }
+
+    #[test]
+    fn test_cpu_hotplug_gpe() {
+        let mut ctrl = CpuHotplugCtrl::new(1, 2).unwrap();
+        let mut gpe = [0_u8; GPE0_BLK_LEN as usize];
+
+        // SCI is not injected before guest enables the GPE.
+        assert!(ctrl.plug_cpu(1).is_ok());
+        assert!(ctrl.sci_evt.read().is_err());
+        assert!(ctrl.read_gpe(&mut gpe, 0));
+        assert_eq!(gpe, [GPE_CPU_HOTPLUG as u8, 0, 0, 0]);
+
+        // Enabling the pending GPE injects SCI.
+        assert!(ctrl.write_gpe(&[GPE_CPU_HOTPLUG as u8], 2));
+        assert_eq!(ctrl.sci_evt.read().unwrap(), 1);
+
+        // Writing 1 clears the status bit, the enable bit is kept.
+        assert!(ctrl.write_gpe(&[GPE_CPU_HOTPLUG as u8], 0));
+        assert!(ctrl.read_gpe(&mut gpe, 0));
+        assert_eq!(gpe, [0, 0, GPE_CPU_HOTPLUG as u8, 0]);
+        assert!(ctrl.sci_evt.read().is_err());
+
+        assert!(!ctrl.read_gpe(&mut gpe, 1));
+        assert!(!ctrl.write_gpe(&[0, 0], 3));
+    }
+
+    #[test]
+    fn test_cpu_hotplug_aml() {
+        let ctrl = CpuHotplugCtrl::new(1, 2).unwrap();
+        let aml = ctrl.aml_bytes();
+        let contains = |name: &[u8]| aml.windows(name.len()).any(|w| w == name);
+        for name in [
+            b"PRST", b"CSEL", b"CSTA", b"CSCN", b"C000", b"C001", b"_E02",
+        ] {
+            assert!(contains(name), "{:?} not found", name);
+        }
+        assert!(!contains(b"C002"));
+    }
+}
@@ -20,7 +20,8 @@ use pci::{
         PciConfig, CLASS_CODE_HOST_BRIDGE, DEVICE_ID, PCI_CONFIG_SPACE_SIZE, SUB_CLASS_CODE,
         VENDOR_ID,
     },
-    le_read_u64, le_write_u16, ranges_overlap, PciBus, PciDevOps, Result as PciResult,
+    le_read_u64, le_write_u16, le_write_u64, ranges_overlap, PciBus, PciDevOps,
+    Result as PciResult,
 };
 
 use super::VENDOR_ID_INTEL;
@@ -45,6 +46,8 @@ pub struct Mch {
     parent_bus: Weak<Mutex<PciBus>>,
     mmconfig_region: Option<Region>,
     mmconfig_ops: RegionOps,
+    /// Base address and size of the ECAM mapped before the guest programs PCIEXBAR.
+    default_mmconfig: (u64, u64),
 }
 
 impl Mch {
@@ -53,14 +56,33 @@ impl Mch {
         mmconfig_region: Region,
         mmconfig_ops: RegionOps,
     ) -> Self {
+        let default_mmconfig = (mmconfig_region.offset().raw_value(), mmconfig_region.size());
         Self {
             config: PciConfig::new(PCI_CONFIG_SPACE_SIZE, 0),
             parent_bus,
             mmconfig_region: Some(mmconfig_region),
             mmconfig_ops,
+            default_mmconfig,
         }
     }
 
+    /// Map the ECAM region to `base`, the one mapped before is removed.
+    fn map_mmconfig(&mut self, base: Option<u64>, length: u64) -> Result<()> {
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+        if let Some(region) = self.mmconfig_region.take() {
+            locked_parent_bus.mem_region.delete_subregion(&region)?;
+        }
+        if let Some(base_addr) = base {
+            let region = Region::init_io_region(length, self.mmconfig_ops.clone());
+            locked_parent_bus
+                .mem_region
+                .add_subregion(region.clone(), base_addr)?;
+            self.mmconfig_region = Some(region);
+        }
+        Ok(())
+    }
+
     fn update_pciexbar_mapping(&mut self) -> Result<()> {
         let pciexbar: u64 = le_read_u64(&self.config.config, PCIEXBAR as usize)?;
         let enable = pciexbar & PCIEXBAR_ENABLE_MASK;
@@ -79,28 +101,12 @@ impl Mch {
             _ => bail!("Invalid PCIEXBAR length."),
         }
 
-        if let Some(region) = self.mmconfig_region.as_ref() {
-            self.parent_bus
-                .upgrade()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .mem_region
-                .delete_subregion(region)?;
-            self.mmconfig_region = None;
-        }
-        if enable == 0x1 {
-            let region = Region::init_io_region(length, self.mmconfig_ops.clone());
-            let base_addr: u64 = pciexbar & addr_mask;
-            self.parent_bus
-                .upgrade()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .mem_region
-                .add_subregion(region, base_addr)?;
-        }
-        Ok(())
+        let base_addr = if enable == 0x1 {
+            Some(pciexbar & addr_mask)
+        } else {
+            None
+        };
+        self.map_mmconfig(base_addr, length)
     }
 
     fn check_pciexbar_update(&self, old_pciexbar: u64) -> bool {
@@ -170,4 +176,31 @@ impl PciDevOps for Mch {
     fn name(&self) -> String {
         "Memory Controller Hub".to_string()
     }
+
+    fn reset(&mut self, _reset_child_device: bool) -> PciResult<()> {
+        self.config.reset_common_regs()?;
+        le_write_u64(&mut self.config.config, PCIEXBAR as usize, 0)?;
+
+        let (base, length) = self.default_mmconfig;
+        let mapped = self.mmconfig_region.as_ref().map_or(false, |region| {
+            region.offset().raw_value() == base && region.size() == length
+        });
+        if !mapped {
+            self.map_mmconfig(Some(base), length)?;
+        }
+        Ok(())
+    }
+
+    fn get_config_state(&self) -> Vec<u8> {
+        self.config.get_state()
+    }
+
+    fn set_config_state(&mut self, state: &[u8]) -> PciResult<()> {
+        let old_pciexbar: u64 = le_read_u64(&self.config.config, PCIEXBAR as usize)?;
+        self.config.set_state(state, None, None)?;
+        if self.check_pciexbar_update(old_pciexbar) {
+            self.update_pciexbar_mapping()?;
+        }
+        Ok(())
+    }
 }
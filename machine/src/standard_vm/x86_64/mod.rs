@@ -10,36 +10,42 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod cpu_hotplug;
 pub(crate) mod ich9_lpc;
 mod mch;
 mod syscall;
 
 use crate::error::MachineError;
-use log::{error, info};
+use log::{error, info, warn};
 use std::collections::HashMap;
 use std::io::{Seek, SeekFrom};
 use std::mem::size_of;
 use std::ops::Deref;
-use std::sync::{Arc, Condvar, Mutex};
+use std::sync::{Arc, Barrier, Condvar, Mutex, Weak};
 use vmm_sys_util::eventfd::EventFd;
 
 use acpi::{
-    AcpiIoApic, AcpiLocalApic, AcpiSratMemoryAffinity, AcpiSratProcessorAffinity, AcpiTable,
-    AmlBuilder, AmlDevice, AmlInteger, AmlNameDecl, AmlPackage, AmlScope, AmlScopeBuilder,
-    AmlString, TableLoader, IOAPIC_BASE_ADDR, LAPIC_BASE_ADDR,
+    AcpiInterruptSourceOverride, AcpiIoApic, AcpiLocalApic, AcpiLocalX2Apic,
+    AcpiSratMemoryAffinity, AcpiSratProcessorAffinity, AcpiSratX2ApicAffinity, AcpiTable,
+    AmlBuilder, AmlInteger, AmlNameDecl, AmlPackage, AmlScope, AmlScopeBuilder, TableLoader,
+    IOAPIC_BASE_ADDR, LAPIC_BASE_ADDR,
 };
 use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
-use boot_loader::{load_linux, BootLoaderConfig};
 use cpu::{CPUBootConfig, CPUInterface, CPUTopology, CpuTopology, CPU};
 use devices::legacy::{
-    error::LegacyError as DevErrorKind, FwCfgEntryType, FwCfgIO, FwCfgOps, PFlash, Serial, RTC,
-    SERIAL_ADDR,
+    error::LegacyError as DevErrorKind, register_mux_machine, FwCfgEntryType, FwCfgIO, FwCfgOps,
+    PFlash, PitStub, Serial, RTC, SERIAL_ADDR,
+};
+use devices::{IoApic, IOAPIC_NUM_PINS};
+use hypervisor::kvm::{KvmCaps, KVM_FDS};
+use kvm_bindings::{
+    kvm_enable_cap, kvm_pit_config, KVM_CAP_X2APIC_API, KVM_PIT_SPEAKER_DUMMY,
+    KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK, KVM_X2APIC_API_USE_32BIT_IDS,
 };
-use hypervisor::kvm::KVM_FDS;
-use kvm_bindings::{kvm_pit_config, KVM_PIT_SPEAKER_DUMMY};
 use machine_manager::config::{
-    parse_incoming_uri, BootIndexInfo, BootSource, DriveFile, Incoming, MigrateMode, NumaNode,
-    NumaNodes, PFlashConfig, SerialConfig, VmConfig,
+    parse_incoming_uri, parse_uuid, BootIndexInfo, BootSource, DriveFile, Incoming, KernelIrqchip,
+    MigrateMode, NumaNode, NumaNodes, PFlashConfig, RebootAction, RtcBase, SerialConfig,
+    ShutdownAction, VmConfig,
 };
 use machine_manager::event;
 use machine_manager::event_loop::EventLoop;
@@ -50,17 +56,21 @@ use machine_manager::machine::{
 use machine_manager::qmp::{qmp_schema, QmpChannel, Response};
 use mch::Mch;
 use migration::{MigrationManager, MigrationStatus};
-use pci::{PciDevOps, PciHost};
+use pci::{PciDevOps, PciHost, PciIntxState};
 use sysbus::{SysBus, IRQ_BASE, IRQ_MAX};
 use syscall::syscall_whitelist;
 use util::{
     byte_code::ByteCode, loop_context::EventLoopManager, seccomp::BpfRule, set_termi_canon_mode,
 };
 
+use self::cpu_hotplug::CpuHotplugCtrl;
+pub(crate) use self::cpu_hotplug::{GPE0_BLK_LEN, GPE0_BLK_OFFSET, SCI_IRQ};
 use self::ich9_lpc::SLEEP_CTRL_OFFSET;
 use super::error::StandardVmError;
 use super::{AcpiBuilder, StdMachineOps};
-use crate::{vm_state, MachineOps};
+use crate::boot::{load_boot_plan, BootPlan};
+use crate::vcpu_rt::{enable_vcpu_realtime, RtAddressDispatcher};
+use crate::{placement, vm_state, MachineOps};
 use anyhow::{anyhow, bail, Context, Result};
 #[cfg(not(target_env = "musl"))]
 use ui::vnc;
@@ -69,6 +79,10 @@ use virtio::ScsiCntlr::ScsiCntlrMap;
 const VENDOR_ID_INTEL: u16 = 0x8086;
 const HOLE_640K_START: u64 = 0x000A_0000;
 const HOLE_640K_END: u64 = 0x0010_0000;
+/// vCPUs with APIC IDs from this on are described as x2APIC.
+const MAX_XAPIC_ID: u16 = 255;
+/// GSI of INTA# of pci host, INTA# ~ INTD# use the IOAPIC pins which are not shared with PIC.
+const PCI_INTX_IRQ_BASE: u32 = 16;
 
 /// The type of memory layout entry on x86_64
 #[repr(usize)]
@@ -95,6 +109,55 @@ pub const MEM_LAYOUT: &[(u64, u64)] = &[
     (0x1_0000_0000, 0x80_0000_0000), // MemAbove4g
 ];
 
+/// Start address of the memory above 4GiB, which is moved up by the 64-bit PCI hole.
+fn mem_above_4g_start(pci_hole64_size: u64) -> u64 {
+    MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0 + pci_hole64_size
+}
+
+/// Calculate the ranges of guest RAM, the memory beyond the 32-bit gap is placed after
+/// the 64-bit PCI hole.
+fn std_ram_ranges(mem_size: u64, pci_hole64_size: u64) -> Vec<(u64, u64)> {
+    let gap_start = MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].0
+        + MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].1;
+
+    let mut ranges = vec![(0, std::cmp::min(gap_start, mem_size))];
+    if mem_size > gap_start {
+        ranges.push((mem_above_4g_start(pci_hole64_size), mem_size - gap_start));
+    }
+    ranges
+}
+
+/// Ranges of the devices which are reserved in e820 table, so that guest never uses
+/// them as RAM. The identity map and TSS are reserved by `ident_tss_range` of `BootPlan`.
+fn e820_reserved_ranges() -> Vec<(u64, u64)> {
+    vec![
+        MEM_LAYOUT[LayoutEntryType::PcieEcam as usize],
+        MEM_LAYOUT[LayoutEntryType::IoApic as usize],
+        MEM_LAYOUT[LayoutEntryType::LocalApic as usize],
+    ]
+}
+
+/// Place the PFlash devices of `sizes` one after another downwards from 4GiB, the first one
+/// is at the top. They must stay above the identity map and TSS.
+fn pflash_ranges(sizes: &[u64]) -> Result<Vec<(u64, u64)>> {
+    let (ident_base, ident_size) = MEM_LAYOUT[LayoutEntryType::IdentTss as usize];
+    let flash_floor = ident_base + ident_size;
+    let mut flash_end = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0;
+    let mut ranges = Vec::new();
+    for size in sizes {
+        if *size > flash_end - flash_floor {
+            bail!(
+                "PFlash of size 0x{:X} doesn't fit below 4GiB, only 0x{:X} bytes are left",
+                size,
+                flash_end - flash_floor
+            );
+        }
+        flash_end -= size;
+        ranges.push((flash_end, *size));
+    }
+    Ok(ranges)
+}
+
 /// Standard machine structure.
 pub struct StdMachine {
     /// `vCPU` topology, support sockets, cores, threads.
@@ -127,6 +190,20 @@ pub struct StdMachine {
     scsi_cntlr_list: ScsiCntlrMap,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// Virtio-serial device.
+    virtio_serial: Option<Arc<Mutex<virtio::Serial>>>,
+    /// Capabilities of KVM probed at startup.
+    kvm_caps: KvmCaps,
+    /// CPU hotplug controller.
+    cpu_controller: Arc<Mutex<CpuHotplugCtrl>>,
+    /// Boot config of vcpus, which is used to realize hotplugged vcpus.
+    cpu_boot_config: Option<CPUBootConfig>,
+    /// Lifecycle interface of the machine itself, used by devices to pause the VM.
+    vm_lifecycle: Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>>,
+    /// Size of the 64-bit PCI hole at 4GiB.
+    pci_hole64_size: u64,
+    /// IOAPIC emulated in userspace, only with split irqchip.
+    ioapic: Option<Arc<Mutex<IoApic>>>,
 }
 
 impl StdMachine {
@@ -179,11 +256,27 @@ impl StdMachine {
             fwcfg_dev: None,
             scsi_cntlr_list: Arc::new(Mutex::new(HashMap::new())),
             drive_files: Arc::new(Mutex::new(vm_config.init_drive_files()?)),
+            virtio_serial: None,
+            kvm_caps: KvmCaps::default(),
+            cpu_controller: Arc::new(Mutex::new(CpuHotplugCtrl::new(
+                vm_config.machine_config.nr_cpus,
+                vm_config.machine_config.max_cpus,
+            )?)),
+            cpu_boot_config: None,
+            vm_lifecycle: None,
+            pci_hole64_size: vm_config.machine_config.pci_hole64_size,
+            ioapic: None,
         })
     }
 
     pub fn handle_reset_request(vm: &Arc<Mutex<Self>>) -> Result<()> {
         let mut locked_vm = vm.lock().unwrap();
+        // The VM kept after guest shutdown is brought back by reset, the reboot action
+        // doesn't apply.
+        let shutdown = *locked_vm.vm_state.0.lock().unwrap() == KvmVmState::Shutdown;
+        if !shutdown && !locked_vm.reboot_by_guest() {
+            return Ok(());
+        }
 
         for (cpu_index, cpu) in locked_vm.cpus.iter().enumerate() {
             cpu.pause()
@@ -195,6 +288,9 @@ impl StdMachine {
         locked_vm
             .reset_all_devices()
             .with_context(|| "Fail to reset all devices")?;
+        if let Some(ioapic) = locked_vm.ioapic.as_ref() {
+            ioapic.lock().unwrap().reset();
+        }
         locked_vm
             .reset_fwcfg_boot_order()
             .with_context(|| "Fail to update boot order information to FwCfg device")?;
@@ -207,27 +303,26 @@ impl StdMachine {
         for (cpu_index, cpu) in locked_vm.cpus.iter().enumerate() {
             cpu.reset()
                 .with_context(|| format!("Failed to reset vcpu{}", cpu_index))?;
-            cpu.resume()
-                .with_context(|| format!("Failed to resume vcpu{}", cpu_index))?;
+            if !shutdown {
+                cpu.resume()
+                    .with_context(|| format!("Failed to resume vcpu{}", cpu_index))?;
+            }
+        }
+        if shutdown {
+            if !locked_vm.notify_lifecycle(KvmVmState::Shutdown, KvmVmState::Running) {
+                bail!("Failed to start the VM after reset");
+            }
+            event!(Resume);
         }
 
         Ok(())
     }
 
     pub fn handle_shutdown_request(vm: &Arc<Mutex<Self>>) -> bool {
-        let locked_vm = vm.lock().unwrap();
-        for (cpu_index, cpu) in locked_vm.cpus.iter().enumerate() {
-            if let Err(e) = cpu.destroy() {
-                error!("Failed to destroy vcpu{}, error is {:?}", cpu_index, e);
-            }
-        }
-
-        let mut vmstate = locked_vm.vm_state.0.lock().unwrap();
-        *vmstate = KvmVmState::Shutdown;
-        true
+        vm.lock().unwrap().shutdown_by_guest("guest-shutdown")
     }
 
-    fn arch_init() -> Result<()> {
+    fn arch_init(kvm_caps: &KvmCaps, pit: bool) -> Result<()> {
         let kvm_fds = KVM_FDS.load();
         let vm_fd = kvm_fds.vm_fd.as_ref().unwrap();
         let identity_addr: u64 = MEM_LAYOUT[LayoutEntryType::IdentTss as usize].0;
@@ -241,13 +336,22 @@ impl StdMachine {
             .set_tss_address((identity_addr + 0x1000) as usize)
             .with_context(|| anyhow!(MachineError::SetTssErr))?;
 
-        let pit_config = kvm_pit_config {
-            flags: KVM_PIT_SPEAKER_DUMMY,
-            pad: Default::default(),
-        };
-        vm_fd
-            .create_pit2(pit_config)
-            .with_context(|| anyhow!(MachineError::CrtPitErr))?;
+        if !pit {
+            if !kvm_caps.kvmclock {
+                bail!("The in-kernel PIT is disabled, but kvmclock is not supported by KVM");
+            }
+            info!("The in-kernel PIT is disabled, the guest uses kvmclock/TSC for timing");
+        } else if kvm_caps.pit2 {
+            let pit_config = kvm_pit_config {
+                flags: KVM_PIT_SPEAKER_DUMMY,
+                pad: Default::default(),
+            };
+            vm_fd
+                .create_pit2(pit_config)
+                .with_context(|| anyhow!(MachineError::CrtPitErr))?;
+        } else {
+            warn!("KVM_CAP_PIT2 is not supported, in-kernel PIT is not created");
+        }
         Ok(())
     }
 
@@ -267,6 +371,21 @@ impl StdMachine {
 impl StdMachineOps for StdMachine {
     fn init_pci_host(&self) -> Result<()> {
         let root_bus = Arc::downgrade(&self.pci_host.lock().unwrap().root_bus);
+        let mut intx_state = PciIntxState::new(PCI_INTX_IRQ_BASE);
+        if let Some(ioapic) = self.ioapic.as_ref() {
+            let ioapic = ioapic.clone();
+            intx_state.set_irq_handler(Arc::new(move |gsi: u32, level: bool| {
+                ioapic.lock().unwrap().set_irq(gsi as usize, level);
+                Ok(())
+            }));
+        }
+        self.pci_host
+            .lock()
+            .unwrap()
+            .root_bus
+            .lock()
+            .unwrap()
+            .intx_state = Some(Arc::new(Mutex::new(intx_state)));
         let mmconfig_region_ops = PciHost::build_mmconfig_ops(self.pci_host.clone());
         let mmconfig_region = Region::init_io_region(
             MEM_LAYOUT[LayoutEntryType::PcieEcam as usize].1,
@@ -298,11 +417,20 @@ impl StdMachineOps for StdMachine {
         Ok(())
     }
 
-    fn add_fwcfg_device(&mut self, nr_cpus: u8) -> super::Result<Option<Arc<Mutex<dyn FwCfgOps>>>> {
+    fn add_fwcfg_device(
+        &mut self,
+        nr_cpus: u16,
+    ) -> super::Result<Option<Arc<Mutex<dyn FwCfgOps>>>> {
         let mut fwcfg = FwCfgIO::new(self.sys_mem.clone());
         fwcfg.add_data_entry(FwCfgEntryType::NbCpus, nr_cpus.as_bytes().to_vec())?;
-        fwcfg.add_data_entry(FwCfgEntryType::MaxCpus, nr_cpus.as_bytes().to_vec())?;
+        fwcfg.add_data_entry(
+            FwCfgEntryType::MaxCpus,
+            self.cpu_topo.max_cpus.as_bytes().to_vec(),
+        )?;
         fwcfg.add_data_entry(FwCfgEntryType::Irq0Override, 1_u32.as_bytes().to_vec())?;
+        if let Some(uuid) = &self.vm_config.lock().unwrap().uuid {
+            fwcfg.add_data_entry(FwCfgEntryType::Uuid, parse_uuid(uuid)?.to_vec())?;
+        }
 
         let boot_order = Vec::<u8>::new();
         fwcfg
@@ -327,22 +455,99 @@ impl StdMachineOps for StdMachine {
     fn get_numa_nodes(&self) -> &Option<NumaNodes> {
         &self.numa_nodes
     }
+
+    fn plug_cpu(&mut self, args: &qmp_schema::DeviceAddArgument) -> Result<()> {
+        let boot_config = match self.cpu_boot_config.as_ref() {
+            Some(config) => config.clone(),
+            None => bail!("CPU hotplug is not supported for incoming VM"),
+        };
+        let item = (
+            args.socket_id.unwrap_or(0),
+            args.die_id.unwrap_or(0),
+            0,
+            args.core_id.unwrap_or(0),
+            args.thread_id.unwrap_or(0),
+        );
+        let vcpu_id = match self.cpu_topo.get_cpu_index(item) {
+            Some(id) => id,
+            None => bail!(
+                "Invalid CPU topology: socket-id {}, die-id {}, core-id {}, thread-id {}",
+                item.0,
+                item.1,
+                item.3,
+                item.4
+            ),
+        };
+        if self.cpu_topo.get_mask(vcpu_id as usize) == 1 {
+            bail!("CPU {} is already present", vcpu_id);
+        }
+
+        let vm = match self.cpus.first().and_then(|cpu| cpu.vm()) {
+            Some(vm) => vm,
+            None => bail!("No Machine Interface saved in CPU"),
+        };
+        let (nr_cpus, topology, cpu_features) = {
+            let vm_config = self.vm_config.lock().unwrap();
+            let machine_config = &vm_config.machine_config;
+            (
+                machine_config.nr_cpus,
+                CPUTopology::new().set_topology((
+                    machine_config.nr_threads,
+                    machine_config.nr_cores,
+                    machine_config.nr_dies,
+                )),
+                machine_config.cpu_config.features.clone(),
+            )
+        };
+        let cpu = <Self as MachineOps>::create_vcpu(vm, vcpu_id, nr_cpus)?;
+        cpu.set_cpuid_features(cpu_features);
+        cpu.realize(&boot_config, &topology).with_context(|| {
+            format!(
+                "Failed to realize arch cpu register/features for CPU {}/KVM",
+                vcpu_id
+            )
+        })?;
+
+        // Hotplugged vcpu waits for SIPI from guest, it runs only if VM is running.
+        let paused = *self.vm_state.0.lock().unwrap() != KvmVmState::Running;
+        let barrier = Arc::new(Barrier::new(2));
+        CPU::start(cpu.clone(), barrier.clone(), paused)
+            .with_context(|| format!("Failed to run vcpu{}", vcpu_id))?;
+        barrier.wait();
+
+        self.cpu_topo.plug_cpu(vcpu_id)?;
+        self.cpus.push(cpu);
+        self.cpu_controller.lock().unwrap().plug_cpu(vcpu_id)
+    }
 }
 
 impl MachineOps for StdMachine {
     fn arch_ram_ranges(&self, mem_size: u64) -> Vec<(u64, u64)> {
-        let gap_start = MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].0
-            + MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].1;
-
-        let mut ranges = vec![(0, std::cmp::min(gap_start, mem_size))];
-        if mem_size > gap_start {
-            let gap_end = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0;
-            ranges.push((gap_end, mem_size - gap_start));
-        }
-        ranges
+        std_ram_ranges(mem_size, self.pci_hole64_size)
     }
 
     fn init_interrupt_controller(&mut self, _vcpu_count: u64) -> Result<()> {
+        if self.cpu_topo.max_cpus > MAX_XAPIC_ID {
+            enable_x2apic_api()?;
+        }
+        if self.vm_config.lock().unwrap().machine_config.kernel_irqchip == KernelIrqchip::Split {
+            KVM_FDS
+                .load()
+                .create_split_irq_chip(IOAPIC_NUM_PINS as u32)
+                .with_context(|| anyhow!(MachineError::CrtIrqchipErr))?;
+            KVM_FDS
+                .load()
+                .irq_route_table
+                .lock()
+                .unwrap()
+                .init_split_irq_route_table();
+            let (base, size) = MEM_LAYOUT[LayoutEntryType::IoApic as usize];
+            self.ioapic = Some(
+                IoApic::realize(&self.sys_mem, base, size)
+                    .with_context(|| "Failed to realize IOAPIC")?,
+            );
+            return Ok(());
+        }
         KVM_FDS
             .load()
             .vm_fd
@@ -361,37 +566,24 @@ impl MachineOps for StdMachine {
     }
 
     fn load_boot_source(&self, fwcfg: Option<&Arc<Mutex<dyn FwCfgOps>>>) -> Result<CPUBootConfig> {
-        let boot_source = self.boot_source.lock().unwrap();
-        let initrd = boot_source.initrd.as_ref().map(|b| b.initrd_file.clone());
-
+        let mut boot_source = self.boot_source.lock().unwrap();
         let gap_start = MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].0
             + MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].1;
-        let gap_end = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0;
-        let bootloader_config = BootLoaderConfig {
-            kernel: boot_source.kernel_file.clone(),
-            initrd,
-            kernel_cmdline: boot_source.kernel_cmdline.to_string(),
+        let gap_end = mem_above_4g_start(self.pci_hole64_size);
+        let plan = BootPlan {
             cpu_count: self.cpu_topo.nrcpus,
             gap_range: (gap_start, gap_end - gap_start),
             ioapic_addr: MEM_LAYOUT[LayoutEntryType::IoApic as usize].0 as u32,
             lapic_addr: MEM_LAYOUT[LayoutEntryType::LocalApic as usize].0 as u32,
             ident_tss_range: Some(MEM_LAYOUT[LayoutEntryType::IdentTss as usize]),
             prot64_mode: false,
+            reserved_ranges: e820_reserved_ranges(),
         };
-        let layout = load_linux(&bootloader_config, &self.sys_mem, fwcfg)
-            .with_context(|| anyhow!(MachineError::LoadKernErr))?;
-
-        Ok(CPUBootConfig {
-            prot64_mode: false,
-            boot_ip: layout.boot_ip,
-            boot_sp: layout.boot_sp,
-            boot_selector: layout.boot_selector,
-            ..Default::default()
-        })
+        load_boot_plan(&plan, &mut boot_source, &self.sys_mem, fwcfg)
     }
 
-    fn add_rtc_device(&mut self, mem_size: u64) -> Result<()> {
-        let mut rtc = RTC::new().with_context(|| "Failed to create RTC device")?;
+    fn add_rtc_device(&mut self, mem_size: u64, rtc_base: RtcBase) -> Result<()> {
+        let mut rtc = RTC::new(rtc_base).with_context(|| "Failed to create RTC device")?;
         rtc.set_memory(
             mem_size,
             MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].0
@@ -420,12 +612,29 @@ impl MachineOps for StdMachine {
         self.drive_files.clone()
     }
 
+    fn get_virtio_serial(&mut self) -> &mut Option<Arc<Mutex<virtio::Serial>>> {
+        &mut self.virtio_serial
+    }
+
+    fn get_vm_lifecycle(&self) -> Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>> {
+        self.vm_lifecycle.clone()
+    }
+
     fn realize(vm: &Arc<Mutex<Self>>, vm_config: &mut VmConfig) -> Result<()> {
         let nr_cpus = vm_config.machine_config.nr_cpus;
+        let kvm_caps = KVM_FDS.load().probe_caps()?;
+        check_vcpu_limit(vm_config.machine_config.max_cpus, kvm_caps.max_vcpus)?;
         let clone_vm = vm.clone();
         let mut locked_vm = vm.lock().unwrap();
+        locked_vm.kvm_caps = kvm_caps;
+        let vm_lifecycle: Arc<Mutex<dyn MachineLifecycle + Send + Sync>> = vm.clone();
+        locked_vm.vm_lifecycle = Some(Arc::downgrade(&vm_lifecycle));
+        let mux_machine: Arc<Mutex<dyn MachineExternalInterface + Send + Sync>> = vm.clone();
+        register_mux_machine(&mux_machine);
         locked_vm.init_global_config(vm_config)?;
         locked_vm.numa_nodes = locked_vm.add_numa_nodes(vm_config)?;
+        placement::auto_place(vm_config, &locked_vm.numa_nodes)?;
+        placement::pin_vcpus(vm_config)?;
         locked_vm.init_memory(
             &vm_config.machine_config.mem_config,
             &locked_vm.sys_io,
@@ -434,7 +643,10 @@ impl MachineOps for StdMachine {
         )?;
 
         locked_vm.init_interrupt_controller(u64::from(nr_cpus))?;
-        StdMachine::arch_init()?;
+        StdMachine::arch_init(&locked_vm.kvm_caps, vm_config.machine_config.pit)?;
+        if !vm_config.machine_config.pit {
+            PitStub::realize(&locked_vm.sys_io)?;
+        }
 
         locked_vm
             .init_pci_host()
@@ -442,11 +654,14 @@ impl MachineOps for StdMachine {
         locked_vm
             .init_ich9_lpc(clone_vm)
             .with_context(|| "Fail to init LPC bridge")?;
+        CpuHotplugCtrl::realize(locked_vm.cpu_controller.clone(), &locked_vm.sys_io)
+            .with_context(|| "Fail to init CPU hotplug controller")?;
+        // Devices may add their entries to fwcfg, so it is created first.
+        let fwcfg = locked_vm.add_fwcfg_device(nr_cpus)?;
         locked_vm.add_devices(vm_config)?;
         #[cfg(not(target_env = "musl"))]
         vnc::vnc_init(&vm_config.vnc, &vm_config.object)
             .with_context(|| "Failed to init VNC server!")?;
-        let fwcfg = locked_vm.add_fwcfg_device(nr_cpus)?;
 
         let migrate = locked_vm.get_migrate_info();
         let boot_config = if migrate.0 == MigrateMode::Unknown {
@@ -465,11 +680,32 @@ impl MachineOps for StdMachine {
             &topology,
             &boot_config,
         )?);
+        for cpu in locked_vm.cpus.iter() {
+            cpu.set_cpuid_features(vm_config.machine_config.cpu_config.features.clone());
+        }
+        locked_vm.cpu_boot_config = boot_config;
+        if vm_config.machine_config.cpu_config.realtime {
+            let machine_ports = vec![0x60, 0x61, 0x62, 0x63, 0x64, SLEEP_CTRL_OFFSET as u64];
+            let dispatcher = RtAddressDispatcher::new(
+                &locked_vm.sys_io,
+                &locked_vm.sys_mem,
+                machine_ports,
+                vm.clone(),
+            );
+            enable_vcpu_realtime(&locked_vm.cpus, Arc::new(dispatcher))?;
+        }
 
-        if migrate.0 == MigrateMode::Unknown && fwcfg.is_some() {
-            locked_vm
-                .build_acpi_tables(&fwcfg.unwrap())
-                .with_context(|| "Failed to create ACPI tables")?;
+        if migrate.0 == MigrateMode::Unknown {
+            if let Some(fwcfg) = fwcfg.as_ref() {
+                locked_vm
+                    .build_acpi_tables(fwcfg)
+                    .with_context(|| "Failed to create ACPI tables")?;
+                let ram_ranges =
+                    locked_vm.arch_ram_ranges(vm_config.machine_config.mem_config.mem_size);
+                locked_vm
+                    .build_smbios_tables(fwcfg, vm_config, &ram_ranges)
+                    .with_context(|| "Failed to create SMBIOS tables")?;
+            }
         }
 
         locked_vm
@@ -492,13 +728,17 @@ impl MachineOps for StdMachine {
     fn add_pflash_device(&mut self, configs: &[PFlashConfig]) -> Result<()> {
         let mut configs_vec = configs.to_vec();
         configs_vec.sort_by_key(|c| c.unit);
-        // The two PFlash devices locates below 4GB, this variable represents the end address
-        // of current PFlash device.
-        let mut flash_end: u64 = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0;
-        for config in configs_vec {
-            let mut fd = self.fetch_drive_file(&config.path_on_host)?;
-            let pfl_size = fd.metadata().unwrap().len();
-
+        let mut fds = Vec::new();
+        for config in &configs_vec {
+            fds.push(self.fetch_drive_file(&config.path_on_host)?);
+        }
+        let sizes = fds
+            .iter()
+            .map(|fd| fd.metadata().map(|meta| meta.len()))
+            .collect::<std::io::Result<Vec<u64>>>()?;
+        // The PFlash devices locate below 4GiB, out of the guest RAM which ends at 2GiB.
+        let ranges = pflash_ranges(&sizes)?;
+        for ((config, mut fd), (flash_base, pfl_size)) in configs_vec.iter().zip(fds).zip(ranges) {
             if config.unit == 0 {
                 // According to the Linux/x86 boot protocol, the memory region of
                 // 0x000000 - 0x100000 (1 MiB) is for BIOS usage. And the top 128
@@ -535,15 +775,8 @@ impl MachineOps for StdMachine {
                 config.read_only,
             )
             .with_context(|| anyhow!(StandardVmError::InitPflashErr))?;
-            PFlash::realize(
-                pflash,
-                &mut self.sysbus,
-                flash_end - pfl_size,
-                pfl_size,
-                backend,
-            )
-            .with_context(|| anyhow!(StandardVmError::RlzPflashErr))?;
-            flash_end -= pfl_size;
+            PFlash::realize(pflash, &mut self.sysbus, flash_base, pfl_size, backend)
+                .with_context(|| anyhow!(StandardVmError::RlzPflashErr))?;
         }
 
         Ok(())
@@ -597,6 +830,114 @@ impl MachineOps for StdMachine {
     }
 }
 
+/// Check that KVM supports all the `max_cpus` possible vCPUs.
+///
+/// # Arguments
+///
+/// * `max_cpus` - The number of possible vCPUs.
+/// * `kvm_max_vcpus` - The limit of vCPUs reported by `KVM_CAP_MAX_VCPUS`.
+fn check_vcpu_limit(max_cpus: u16, kvm_max_vcpus: usize) -> Result<()> {
+    if max_cpus as usize > kvm_max_vcpus {
+        bail!(
+            "Number of vcpus {} exceeds the limit {} of KVM",
+            max_cpus,
+            kvm_max_vcpus
+        );
+    }
+    Ok(())
+}
+
+/// Enable 32-bit APIC IDs in KVM, which are required by vCPUs with IDs from 255 on.
+fn enable_x2apic_api() -> Result<()> {
+    let cap = kvm_enable_cap {
+        cap: KVM_CAP_X2APIC_API,
+        args: [
+            (KVM_X2APIC_API_USE_32BIT_IDS | KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK) as u64,
+            0,
+            0,
+            0,
+        ],
+        ..Default::default()
+    };
+    KVM_FDS
+        .load()
+        .vm_fd
+        .as_ref()
+        .unwrap()
+        .enable_cap(&cap)
+        .with_context(|| {
+            "Failed to enable x2APIC API of KVM, which is required by more than 255 vcpus"
+        })?;
+    Ok(())
+}
+
+/// Build the MADT interrupt controller structure of the vCPU `id`, which is used as both
+/// processor uid and APIC id. IDs from 255 on don't fit in the xAPIC structure, whose
+/// APIC id 0xff is the broadcast one, so they are described as local x2APIC.
+pub(crate) fn local_apic_entry(id: u16, enabled: bool) -> Vec<u8> {
+    if id < MAX_XAPIC_ID {
+        AcpiLocalApic {
+            type_id: 0,
+            length: size_of::<AcpiLocalApic>() as u8,
+            processor_uid: id as u8,
+            apic_id: id as u8,
+            // Flags: enabled.
+            flags: enabled as u32,
+        }
+        .aml_bytes()
+    } else {
+        AcpiLocalX2Apic {
+            type_id: 9,
+            length: size_of::<AcpiLocalX2Apic>() as u8,
+            reserved: 0,
+            x2apic_id: id as u32,
+            flags: enabled as u32,
+            processor_uid: id as u32,
+        }
+        .aml_bytes()
+    }
+}
+
+/// Build ACPI MADT table which lists the IOAPIC and the LAPIC of each possible vCPU.
+///
+/// # Arguments
+///
+/// * `cpu_ids` - The ids of present vCPUs, used as both processor uid and APIC id.
+/// * `max_cpus` - The number of possible vCPUs, absent ones are listed as disabled.
+fn build_madt(cpu_ids: &[u16], max_cpus: u16) -> AcpiTable {
+    let mut madt = AcpiTable::new(*b"APIC", 5, *b"STRATO", *b"VIRTAPIC", 1);
+
+    madt.append_child(LAPIC_BASE_ADDR.as_bytes());
+    // Flags: PC-AT-compatible dual-8259 setup
+    madt.append_child(1_u32.as_bytes());
+
+    let ioapic = AcpiIoApic {
+        type_id: 1_u8,
+        length: size_of::<AcpiIoApic>() as u8,
+        io_apic_id: 0,
+        reserved: 0,
+        io_apic_addr: IOAPIC_BASE_ADDR,
+        gsi_base: 0,
+    };
+    madt.append_child(ioapic.aml_bytes().as_ref());
+
+    // SCI is level triggered and active high.
+    let sci_override = AcpiInterruptSourceOverride {
+        type_id: 2,
+        length: size_of::<AcpiInterruptSourceOverride>() as u8,
+        bus: 0,
+        source: SCI_IRQ,
+        gsi: SCI_IRQ as u32,
+        flags: 0x000d,
+    };
+    madt.append_child(&sci_override.aml_bytes());
+
+    for id in 0..max_cpus {
+        madt.append_child(&local_apic_entry(id, cpu_ids.contains(&id)));
+    }
+    madt
+}
+
 impl AcpiBuilder for StdMachine {
     fn build_dsdt_table(
         &self,
@@ -605,18 +946,11 @@ impl AcpiBuilder for StdMachine {
     ) -> super::Result<u64> {
         let mut dsdt = AcpiTable::new(*b"DSDT", 2, *b"STRATO", *b"VIRTDSDT", 1);
 
-        // 1. CPU info.
-        let cpus_count = self.cpus.len() as u64;
-        let mut sb_scope = AmlScope::new("\\_SB");
-        for cpu_id in 0..cpus_count {
-            let mut dev = AmlDevice::new(format!("C{:03}", cpu_id).as_str());
-            dev.append_child(AmlNameDecl::new("_HID", AmlString("ACPI0007".to_string())));
-            dev.append_child(AmlNameDecl::new("_UID", AmlInteger(cpu_id)));
-            dev.append_child(AmlNameDecl::new("_PXM", AmlInteger(0)));
-            sb_scope.append_child(dev);
-        }
+        // 1. CPU info and hotplug methods.
+        dsdt.append_child(self.cpu_controller.lock().unwrap().aml_bytes().as_slice());
 
         // 2. Create pci host bridge node.
+        let mut sb_scope = AmlScope::new("\\_SB");
         sb_scope.append_child(self.pci_host.lock().unwrap().clone());
         dsdt.append_child(sb_scope.aml_bytes().as_slice());
 
@@ -641,50 +975,40 @@ impl AcpiBuilder for StdMachine {
         acpi_data: &Arc<Mutex<Vec<u8>>>,
         loader: &mut TableLoader,
     ) -> super::Result<u64> {
-        let mut madt = AcpiTable::new(*b"APIC", 5, *b"STRATO", *b"VIRTAPIC", 1);
-
-        madt.append_child(LAPIC_BASE_ADDR.as_bytes());
-        // Flags: PC-AT-compatible dual-8259 setup
-        madt.append_child(1_u32.as_bytes());
-
-        let ioapic = AcpiIoApic {
-            type_id: 1_u8,
-            length: size_of::<AcpiIoApic>() as u8,
-            io_apic_id: 0,
-            reserved: 0,
-            io_apic_addr: IOAPIC_BASE_ADDR,
-            gsi_base: 0,
-        };
-        madt.append_child(ioapic.aml_bytes().as_ref());
-
-        self.cpus.iter().for_each(|cpu| {
-            let lapic = AcpiLocalApic {
-                type_id: 0,
-                length: size_of::<AcpiLocalApic>() as u8,
-                processor_uid: cpu.id(),
-                apic_id: cpu.id(),
-                flags: 1, // Flags: enabled.
-            };
-            madt.append_child(&lapic.aml_bytes());
-        });
+        let cpu_ids = self.cpus.iter().map(|cpu| cpu.id()).collect::<Vec<u16>>();
+        let madt = build_madt(&cpu_ids, self.cpu_topo.max_cpus);
 
         let madt_begin = StdMachine::add_table_to_loader(acpi_data, loader, &madt)
-            .with_context(|| "Fail to add DSTD table to loader")?;
+            .with_context(|| "Fail to add MADT table to loader")?;
         Ok(madt_begin)
     }
 
     fn build_srat_cpu(&self, proximity_domain: u32, node: &NumaNode, srat: &mut AcpiTable) {
         for cpu in node.cpus.iter() {
-            srat.append_child(
-                &AcpiSratProcessorAffinity {
-                    length: size_of::<AcpiSratProcessorAffinity>() as u8,
-                    proximity_lo: proximity_domain as u8,
-                    local_apic_id: *cpu,
-                    flags: 1,
-                    ..Default::default()
-                }
-                .aml_bytes(),
-            );
+            if *cpu < MAX_XAPIC_ID {
+                srat.append_child(
+                    &AcpiSratProcessorAffinity {
+                        length: size_of::<AcpiSratProcessorAffinity>() as u8,
+                        proximity_lo: proximity_domain as u8,
+                        local_apic_id: *cpu as u8,
+                        flags: 1,
+                        ..Default::default()
+                    }
+                    .aml_bytes(),
+                );
+            } else {
+                srat.append_child(
+                    &AcpiSratX2ApicAffinity {
+                        type_id: 2,
+                        length: size_of::<AcpiSratX2ApicAffinity>() as u8,
+                        proximity_domain,
+                        x2apic_id: *cpu as u32,
+                        flags: 1,
+                        ..Default::default()
+                    }
+                    .aml_bytes(),
+                );
+            }
         }
     }
 
@@ -697,7 +1021,7 @@ impl AcpiBuilder for StdMachine {
     ) -> u64 {
         let mem_below_4g = MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].0
             + MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].1;
-        let mem_above_4g = MEM_LAYOUT[LayoutEntryType::MemAbove4g as usize].0;
+        let mem_above_4g = mem_above_4g_start(self.pci_hole64_size);
 
         let mut mem_base = base_addr;
         let mut mem_len = node.size;
@@ -813,7 +1137,7 @@ impl MachineLifecycle for StdMachine {
             *state
         };
 
-        if !self.notify_lifecycle(vmstate, KvmVmState::Shutdown) {
+        if !self.notify_lifecycle(vmstate, KvmVmState::Destroyed) {
             return false;
         }
 
@@ -824,6 +1148,18 @@ impl MachineLifecycle for StdMachine {
         true
     }
 
+    fn get_shutdown_action(&self) -> ShutdownAction {
+        self.vm_config
+            .lock()
+            .unwrap()
+            .machine_config
+            .shutdown_action
+    }
+
+    fn get_reboot_action(&self) -> RebootAction {
+        self.vm_config.lock().unwrap().machine_config.reboot_action
+    }
+
     fn reset(&mut self) -> bool {
         if self.reset_req.write(1).is_err() {
             error!("X86 standard vm write reset request failed");
@@ -916,8 +1252,10 @@ impl MachineTestInterface for StdMachine {}
 
 impl EventLoopManager for StdMachine {
     fn loop_should_exit(&self) -> bool {
+        // A VM paused or kept after guest shutdown keeps the main loop running until
+        // it's destroyed, e.g. by QMP quit.
         let vmstate = self.vm_state.deref().0.lock().unwrap();
-        *vmstate == KvmVmState::Shutdown
+        *vmstate == KvmVmState::Destroyed
     }
 
     fn loop_cleanup(&self) -> util::Result<()> {
@@ -925,3 +1263,198 @@ impl EventLoopManager for StdMachine {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use acpi::ACPI_TABLE_FILE;
+
+    /// Size of the command type and the content of a table loader entry.
+    const LOADER_ENTRY_SIZE: usize = 128;
+    const LOADER_CMD_ADD_CKSUM: u32 = 3;
+    /// Offset of (offset, start, length) in the content of an AddCksum entry.
+    const LOADER_CKSUM_ARGS_OFFSET: usize = 4 + 56;
+
+    fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// Do the checksum commands of `loader` like firmware, pointers are left as
+    /// offsets in the table file.
+    fn apply_checksums(loader: &TableLoader, acpi_data: &mut [u8]) {
+        let cmds = loader.cmd_entries();
+        for entry in cmds.chunks(LOADER_ENTRY_SIZE) {
+            if read_u32(entry, 0) != LOADER_CMD_ADD_CKSUM {
+                continue;
+            }
+            let offset = read_u32(entry, LOADER_CKSUM_ARGS_OFFSET) as usize;
+            let start = read_u32(entry, LOADER_CKSUM_ARGS_OFFSET + 4) as usize;
+            let length = read_u32(entry, LOADER_CKSUM_ARGS_OFFSET + 8) as usize;
+            let sum = acpi_data[start..start + length]
+                .iter()
+                .fold(0_u8, |sum, b| sum.wrapping_add(*b));
+            acpi_data[offset] = acpi_data[offset].wrapping_sub(sum);
+        }
+    }
+
+    fn table_sum(table: &[u8]) -> u8 {
+        let len = read_u32(table, 4) as usize;
+        table[..len]
+            .iter()
+            .fold(0_u8, |sum, b| sum.wrapping_add(*b))
+    }
+
+    #[test]
+    fn test_ram_ranges_with_pci_hole64() {
+        let gb = 1_u64 << 30;
+        assert_eq!(std_ram_ranges(gb, 0), vec![(0, gb)]);
+        assert_eq!(std_ram_ranges(gb, 32 * gb), vec![(0, gb)]);
+        assert_eq!(
+            std_ram_ranges(6 * gb, 0),
+            vec![(0, 2 * gb), (0x1_0000_0000, 4 * gb)]
+        );
+        assert_eq!(
+            std_ram_ranges(6 * gb, 32 * gb),
+            vec![(0, 2 * gb), (36 * gb, 4 * gb)]
+        );
+
+        // The reserved ranges are in the 32-bit gap, never overlapping guest RAM.
+        let gap_start = MEM_LAYOUT[LayoutEntryType::MemBelow4g as usize].1;
+        for (start, size) in e820_reserved_ranges() {
+            assert!(start >= gap_start && start + size <= mem_above_4g_start(0));
+        }
+    }
+
+    #[test]
+    fn test_pflash_ranges() {
+        // OVMF code and vars are placed downwards from 4GiB.
+        let ranges = pflash_ranges(&[0x40_0000, 0x8_4000]).unwrap();
+        assert_eq!(
+            ranges,
+            vec![(0xFFC0_0000, 0x40_0000), (0xFFB7_C000, 0x8_4000)]
+        );
+
+        // They never overlap the guest RAM or the devices below them.
+        let (ident_base, ident_size) = MEM_LAYOUT[LayoutEntryType::IdentTss as usize];
+        let max_size = mem_above_4g_start(0) - ident_base - ident_size;
+        let ranges = pflash_ranges(&[max_size - 0x1000, 0x1000]).unwrap();
+        assert_eq!(ranges[1].0, ident_base + ident_size);
+        for (start, size) in std_ram_ranges(6 << 30, 0) {
+            for (flash_start, flash_size) in ranges.iter() {
+                assert!(start + size <= *flash_start || flash_start + flash_size <= start);
+            }
+        }
+        assert!(pflash_ranges(&[max_size, 0x1000]).is_err());
+    }
+
+    #[test]
+    fn test_madt_entries() {
+        for (nr_cpus, max_cpus) in [(1_u16, 1_u16), (2, 8), (8, 8), (255, 255), (300, 1024)] {
+            let cpu_ids = (0..nr_cpus).collect::<Vec<u16>>();
+            let madt = build_madt(&cpu_ids, max_cpus).aml_bytes();
+            assert_eq!(&madt[0..4], b"APIC");
+            assert_eq!(read_u32(&madt, 4) as usize, madt.len());
+            assert_eq!(read_u32(&madt, 36), LAPIC_BASE_ADDR);
+
+            // Walk the interrupt controller structures after the header and flags.
+            let (mut ioapics, mut lapics, mut overrides) = (Vec::new(), Vec::new(), Vec::new());
+            let mut offset = 44;
+            while offset < madt.len() {
+                let len = madt[offset + 1] as usize;
+                match madt[offset] {
+                    0 => {
+                        assert_eq!(len, size_of::<AcpiLocalApic>());
+                        lapics.push((
+                            0,
+                            madt[offset + 2] as u32,
+                            madt[offset + 3] as u32,
+                            read_u32(&madt, offset + 4),
+                        ))
+                    }
+                    1 => ioapics.push(read_u32(&madt, offset + 4)),
+                    2 => overrides.push((madt[offset + 3], read_u32(&madt, offset + 4))),
+                    9 => {
+                        assert_eq!(len, size_of::<AcpiLocalX2Apic>());
+                        lapics.push((
+                            9,
+                            read_u32(&madt, offset + 12),
+                            read_u32(&madt, offset + 4),
+                            read_u32(&madt, offset + 8),
+                        ))
+                    }
+                    t => panic!("Unexpected MADT entry type {}", t),
+                }
+                offset += len;
+            }
+            assert_eq!(offset, madt.len());
+            assert_eq!(ioapics, vec![IOAPIC_BASE_ADDR]);
+            assert_eq!(overrides, vec![(SCI_IRQ, SCI_IRQ as u32)]);
+            assert_eq!(lapics.len(), max_cpus as usize);
+            for (id, lapic) in lapics.iter().enumerate() {
+                // The broadcast xAPIC ID 0xff is described as x2APIC.
+                let type_id = if id < MAX_XAPIC_ID as usize { 0 } else { 9 };
+                let enabled = (id < nr_cpus as usize) as u32;
+                assert_eq!(*lapic, (type_id, id as u32, id as u32, enabled));
+            }
+        }
+    }
+
+    #[test]
+    fn test_vcpu_limit() {
+        // Limits reported by KVM_CAP_MAX_VCPUS of different kernels.
+        for kvm_max_vcpus in [240_usize, 288, 1024, 4096] {
+            for max_cpus in [1_u16, 240, 255, 288, 1024] {
+                let ret = check_vcpu_limit(max_cpus, kvm_max_vcpus);
+                assert_eq!(ret.is_ok(), max_cpus as usize <= kvm_max_vcpus);
+            }
+        }
+    }
+
+    #[test]
+    fn test_acpi_tables_checksum_and_linkage() {
+        let mut loader = TableLoader::new();
+        let acpi_data = Arc::new(Mutex::new(Vec::new()));
+        loader
+            .add_alloc_entry(ACPI_TABLE_FILE, acpi_data.clone(), 64_u32, false)
+            .unwrap();
+
+        // Use an empty DSDT, its content is built from the devices of machine.
+        let dsdt = AcpiTable::new(*b"DSDT", 2, *b"STRATO", *b"VIRTDSDT", 1);
+        let dsdt_addr = StdMachine::add_table_to_loader(&acpi_data, &mut loader, &dsdt).unwrap();
+        let facs_addr = StdMachine::build_facs_table(&acpi_data, &mut loader).unwrap();
+        let fadt_addr = StdMachine::build_fadt_table(&acpi_data, &mut loader, dsdt_addr).unwrap();
+        let madt = build_madt(&[0, 1, 2, 3], 4);
+        let madt_addr = StdMachine::add_table_to_loader(&acpi_data, &mut loader, &madt).unwrap();
+        let mcfg_addr = StdMachine::build_mcfg_table(&acpi_data, &mut loader).unwrap();
+        let entries = vec![facs_addr, fadt_addr, madt_addr, mcfg_addr];
+        let xsdt_addr =
+            StdMachine::build_xsdt_table(&acpi_data, &mut loader, entries.clone()).unwrap();
+
+        let mut data = acpi_data.lock().unwrap().clone();
+        apply_checksums(&loader, &mut data);
+
+        for (addr, signature) in [
+            (dsdt_addr, b"DSDT"),
+            (fadt_addr, b"FACP"),
+            (madt_addr, b"APIC"),
+            (mcfg_addr, b"MCFG"),
+            (xsdt_addr, b"XSDT"),
+        ] {
+            let table = &data[addr as usize..];
+            assert_eq!(&table[0..4], signature);
+            assert_eq!(table_sum(table), 0, "Bad checksum of {:?}", signature);
+        }
+
+        // XSDT points to all the other tables.
+        let xsdt = &data[xsdt_addr as usize..];
+        assert_eq!(read_u32(xsdt, 4) as usize, 36 + 8 * entries.len());
+        for (i, entry) in entries.iter().enumerate() {
+            let offset = 36 + 8 * i;
+            let pointer = u64::from_le_bytes(xsdt[offset..offset + 8].try_into().unwrap());
+            assert_eq!(pointer, *entry);
+        }
+        // FADT points to DSDT.
+        let fadt = &data[fadt_addr as usize..];
+        assert_eq!(read_u32(fadt, 40) as u64, dsdt_addr);
+    }
+}
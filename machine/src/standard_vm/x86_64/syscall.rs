@@ -10,9 +10,14 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use crate::mem_aging::{MADV_COLD, MADV_PAGEOUT};
 use hypervisor::kvm::*;
+use util::file::{BLKDISCARD, BLKGETSIZE64, BLKPBSZGET, BLKSSZGET};
 use util::seccomp::{BpfRule, SeccompCmpOpt};
-use util::tap::{TUNGETFEATURES, TUNSETIFF, TUNSETOFFLOAD, TUNSETVNETHDRSZ};
+use util::tap::{
+    TUNGETFEATURES, TUNGETIFF, TUNSETIFF, TUNSETOFFLOAD, TUNSETQUEUE, TUNSETVNETHDRSZ,
+};
+use util::userfaultfd::{UFFDIO_COPY, UFFDIO_UNREGISTER};
 use vfio::{
     VFIO_CHECK_EXTENSION, VFIO_DEVICE_GET_INFO, VFIO_DEVICE_GET_IRQ_INFO,
     VFIO_DEVICE_GET_REGION_INFO, VFIO_DEVICE_RESET, VFIO_DEVICE_SET_IRQS, VFIO_GET_API_VERSION,
@@ -56,8 +61,8 @@ const KVM_RUN: u32 = 0xae80;
 ///
 /// # Notes
 /// This allowlist limit syscall with:
-/// * x86_64-unknown-gnu: 82 syscalls
-/// * x86_64-unknown-musl: 62 syscalls
+/// * x86_64-unknown-gnu: 83 syscalls
+/// * x86_64-unknown-musl: 63 syscalls
 /// To reduce performance losses, the syscall rules is ordered by frequency.
 pub fn syscall_whitelist() -> Vec<BpfRule> {
     vec![
@@ -82,6 +87,8 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_ppoll),
         BpfRule::new(libc::SYS_fdatasync),
+        // `block_resize` and `blockdev-backup` truncate image files at runtime.
+        BpfRule::new(libc::SYS_ftruncate),
         BpfRule::new(libc::SYS_recvmsg),
         BpfRule::new(libc::SYS_sendmsg),
         #[cfg(target_env = "gnu")]
@@ -126,6 +133,9 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_statx),
         BpfRule::new(libc::SYS_mkdir),
         BpfRule::new(libc::SYS_unlink),
+        BpfRule::new(libc::SYS_rename),
+        BpfRule::new(libc::SYS_renameat),
+        BpfRule::new(libc::SYS_renameat2),
         madvise_rule(),
         BpfRule::new(libc::SYS_msync),
         BpfRule::new(libc::SYS_readlinkat),
@@ -181,6 +191,8 @@ pub fn syscall_whitelist() -> Vec<BpfRule> {
         BpfRule::new(libc::SYS_set_robust_list),
         #[cfg(target_env = "gnu")]
         BpfRule::new(libc::SYS_sched_getaffinity),
+        BpfRule::new(libc::SYS_sched_setaffinity),
+        BpfRule::new(libc::SYS_sched_setscheduler),
     ]
 }
 
@@ -214,9 +226,17 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_GET_FEATURES() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VHOST_RESET_OWNER() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNGETFEATURES() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, TUNGETIFF() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETIFF() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETOFFLOAD() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETVNETHDRSZ() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, TUNSETQUEUE() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKGETSIZE64() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKSSZGET() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKPBSZGET() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, BLKDISCARD() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_COPY() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, UFFDIO_UNREGISTER() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_GSI_ROUTING() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_IRQFD() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, VFIO_DEVICE_SET_IRQS() as u32)
@@ -237,6 +257,7 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_MP_STATE() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_VCPU_EVENTS() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_PIT2() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_NMI() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_CLOCK() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_IRQCHIP() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_REGS() as u32)
@@ -259,6 +280,9 @@ fn ioctl_allow_list() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_MSRS() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_SET_VCPU_EVENTS() as u32)
         .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_DIRTY_LOG() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_CREATE_VCPU() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_CHECK_EXTENSION() as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 1, KVM_GET_MSR_INDEX_LIST() as u32)
 }
 
 fn madvise_rule() -> BpfRule {
@@ -268,13 +292,17 @@ fn madvise_rule() -> BpfRule {
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_DONTNEED as u32)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_WILLNEED as u32)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_DONTDUMP as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32);
+        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_COLD as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_PAGEOUT as u32);
     #[cfg(target_env = "gnu")]
     return BpfRule::new(libc::SYS_madvise)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_DONTNEED as u32)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_WILLNEED as u32)
         .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_DONTDUMP as u32)
-        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32);
+        .add_constraint(SeccompCmpOpt::Eq, 2, libc::MADV_REMOVE as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_COLD as u32)
+        .add_constraint(SeccompCmpOpt::Eq, 2, MADV_PAGEOUT as u32);
 }
 
 fn futex_rule() -> BpfRule {
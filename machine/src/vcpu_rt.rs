@@ -0,0 +1,262 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+
+use address_space::{AddressSpace, GuestAddress};
+use anyhow::{Context, Result};
+use cpu::rt::{VcpuRealtime, VcpuRtDrainer};
+use cpu::CPU;
+use machine_manager::event_loop::EventLoop;
+use machine_manager::machine::{MachineAddressInterface, MachineInterface};
+use util::loop_context::EventNotifierHelper;
+
+/// Dispatches the PIO/MMIO exits of realtime vCPUs to the address spaces directly,
+/// so only the flat view of the address space and the lock of the target device are
+/// involved. Accesses to ports which have side effects on the machine itself are
+/// forwarded to the machine with its lock held.
+pub(crate) struct RtAddressDispatcher {
+    #[cfg(target_arch = "x86_64")]
+    sys_io: Arc<AddressSpace>,
+    sys_mem: Arc<AddressSpace>,
+    /// Ports handled by the machine instead of `sys_io`.
+    #[cfg(target_arch = "x86_64")]
+    machine_ports: Vec<u64>,
+    vm: Weak<Mutex<dyn MachineInterface + Send + Sync>>,
+}
+
+impl RtAddressDispatcher {
+    pub(crate) fn new(
+        #[cfg(target_arch = "x86_64")] sys_io: &Arc<AddressSpace>,
+        sys_mem: &Arc<AddressSpace>,
+        #[cfg(target_arch = "x86_64")] machine_ports: Vec<u64>,
+        vm: Arc<Mutex<dyn MachineInterface + Send + Sync>>,
+    ) -> Self {
+        RtAddressDispatcher {
+            #[cfg(target_arch = "x86_64")]
+            sys_io: sys_io.clone(),
+            sys_mem: sys_mem.clone(),
+            #[cfg(target_arch = "x86_64")]
+            machine_ports,
+            vm: Arc::downgrade(&vm),
+        }
+    }
+}
+
+impl MachineAddressInterface for RtAddressDispatcher {
+    #[cfg(target_arch = "x86_64")]
+    fn pio_in(&self, addr: u64, mut data: &mut [u8]) -> bool {
+        if self.machine_ports.contains(&addr) {
+            return match self.vm.upgrade() {
+                Some(vm) => vm.lock().unwrap().pio_in(addr, data),
+                None => false,
+            };
+        }
+        let length = data.len() as u64;
+        self.sys_io
+            .read(&mut data, GuestAddress(addr), length)
+            .is_ok()
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    fn pio_out(&self, addr: u64, mut data: &[u8]) -> bool {
+        if self.machine_ports.contains(&addr) {
+            return match self.vm.upgrade() {
+                Some(vm) => vm.lock().unwrap().pio_out(addr, data),
+                None => false,
+            };
+        }
+        let count = data.len() as u64;
+        self.sys_io
+            .write(&mut data, GuestAddress(addr), count)
+            .is_ok()
+    }
+
+    fn mmio_read(&self, addr: u64, mut data: &mut [u8]) -> bool {
+        let length = data.len() as u64;
+        self.sys_mem
+            .read(&mut data, GuestAddress(addr), length)
+            .is_ok()
+    }
+
+    fn mmio_write(&self, addr: u64, mut data: &[u8]) -> bool {
+        let count = data.len() as u64;
+        self.sys_mem
+            .write(&mut data, GuestAddress(addr), count)
+            .is_ok()
+    }
+}
+
+/// Switch `cpus` to the realtime run loop, and handle their rare-path events in the
+/// main loop.
+///
+/// # Arguments
+///
+/// * `cpus` - vCPUs which are not started yet.
+/// * `dispatcher` - PIO/MMIO dispatcher shared by the vCPUs.
+pub(crate) fn enable_vcpu_realtime(
+    cpus: &[Arc<CPU>],
+    dispatcher: Arc<RtAddressDispatcher>,
+) -> Result<()> {
+    let mut drainer = VcpuRtDrainer::new().with_context(|| "Failed to create vCPU drainer")?;
+    for cpu in cpus {
+        let rt = Arc::new(VcpuRealtime::new(dispatcher.clone(), drainer.notifier()));
+        cpu.set_realtime(rt.clone());
+        drainer.add_vcpu(cpu.id(), rt);
+    }
+    EventLoop::update_event(
+        EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(drainer))),
+        None,
+    )
+    .with_context(|| "Failed to register realtime vCPU events to main loop")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use address_space::{Region, RegionOps};
+    use machine_manager::machine::{KvmVmState, MachineLifecycle};
+    use util::rt::{LatencyHistogram, RtSection};
+
+    struct TestVm;
+
+    impl MachineLifecycle for TestVm {
+        fn notify_lifecycle(&self, _old: KvmVmState, _new: KvmVmState) -> bool {
+            true
+        }
+    }
+
+    impl MachineAddressInterface for TestVm {
+        #[cfg(target_arch = "x86_64")]
+        fn pio_in(&self, _addr: u64, data: &mut [u8]) -> bool {
+            data.fill(0x20);
+            true
+        }
+
+        #[cfg(target_arch = "x86_64")]
+        fn pio_out(&self, _addr: u64, _data: &[u8]) -> bool {
+            true
+        }
+
+        fn mmio_read(&self, _addr: u64, _data: &mut [u8]) -> bool {
+            false
+        }
+
+        fn mmio_write(&self, _addr: u64, _data: &[u8]) -> bool {
+            false
+        }
+    }
+
+    impl MachineInterface for TestVm {}
+
+    fn test_region_ops() -> RegionOps {
+        let read = Arc::new(
+            |data: &mut [u8], _base: GuestAddress, offset: u64| -> bool {
+                data.fill(offset as u8);
+                true
+            },
+        );
+        let write = Arc::new(|_data: &[u8], _base: GuestAddress, _offset: u64| -> bool { true });
+        RegionOps { read, write }
+    }
+
+    fn test_dispatcher(vm: Arc<Mutex<dyn MachineInterface + Send + Sync>>) -> RtAddressDispatcher {
+        let root = Region::init_container_region(1 << 36);
+        let sys_mem = AddressSpace::new(root.clone()).unwrap();
+        root.add_subregion(
+            Region::init_io_region(0x1000, test_region_ops()),
+            0x1000_0000,
+        )
+        .unwrap();
+        #[cfg(target_arch = "x86_64")]
+        let sys_io = {
+            let root = Region::init_container_region(1 << 16);
+            let sys_io = AddressSpace::new(root.clone()).unwrap();
+            root.add_subregion(Region::init_io_region(0x10, test_region_ops()), 0x3f8)
+                .unwrap();
+            sys_io
+        };
+        RtAddressDispatcher::new(
+            #[cfg(target_arch = "x86_64")]
+            &sys_io,
+            &sys_mem,
+            #[cfg(target_arch = "x86_64")]
+            vec![0x61],
+            vm,
+        )
+    }
+
+    #[test]
+    fn test_rt_dispatcher() {
+        let vm: Arc<Mutex<dyn MachineInterface + Send + Sync>> = Arc::new(Mutex::new(TestVm));
+        let dispatcher = test_dispatcher(vm.clone());
+
+        let mut data = [0_u8; 4];
+        assert!(dispatcher.mmio_read(0x1000_0010, &mut data));
+        assert_eq!(data, [0x10; 4]);
+        assert!(dispatcher.mmio_write(0x1000_0010, &data));
+        assert!(!dispatcher.mmio_read(0x2000_0000, &mut data));
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut data = [0_u8; 1];
+            assert!(dispatcher.pio_in(0x3f9, &mut data));
+            assert_eq!(data, [1]);
+            // Machine ports are forwarded to the machine.
+            assert!(dispatcher.pio_in(0x61, &mut data));
+            assert_eq!(data, [0x20]);
+            assert!(!dispatcher.pio_in(0x70, &mut data));
+        }
+
+        drop(vm);
+        #[cfg(target_arch = "x86_64")]
+        assert!(!dispatcher.pio_out(0x61, &[0]));
+    }
+
+    /// Latency benchmark of the exit dispatch path, run it with
+    /// `cargo test -p machine --release --features rt_alloc_check -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_rt_dispatch_latency() {
+        const EXITS: usize = 1_000_000;
+
+        let vm: Arc<Mutex<dyn MachineInterface + Send + Sync>> = Arc::new(Mutex::new(TestVm));
+        let dispatcher = test_dispatcher(vm);
+        let hist = LatencyHistogram::new();
+        let mut data = [0_u8; 4];
+        // Warm up the thread local state of the flat view before checking allocations.
+        dispatcher.mmio_read(0x1000_0000, &mut data);
+
+        for i in 0..EXITS {
+            let _section = RtSection::enter();
+            let start = Instant::now();
+            let addr = 0x1000_0000 + (i as u64 & 0xffc);
+            if i % 2 == 0 {
+                assert!(dispatcher.mmio_read(addr, &mut data));
+            } else {
+                assert!(dispatcher.mmio_write(addr, &data));
+            }
+            hist.record(start.elapsed().as_nanos() as u64);
+        }
+
+        assert_eq!(hist.count(), EXITS as u64);
+        println!(
+            "mmio exit dispatch latency(ns): p50 {}, p99 {}, p99.9 {}, max {}",
+            hist.percentile(500),
+            hist.percentile(990),
+            hist.percentile(999),
+            hist.percentile(1000)
+        );
+    }
+}
@@ -17,7 +17,11 @@ use util::arg_parser::{Arg, ArgMatches, ArgParser};
 use util::unix::{limit_permission, parse_unix_uri};
 
 use crate::{
-    config::{add_trace_events, ChardevType, CmdParser, MachineType, VmConfig},
+    config::{
+        add_trace_events, load_config_file, merge_vmconfig, ChardevType, CmdParser, MachineType,
+        VmConfig,
+    },
+    qmp::qmp_schema::{CmdLine, CmdParameter},
     temp_cleaner::TempCleaner,
 };
 
@@ -70,377 +74,882 @@ macro_rules! add_args_to_config_multi {
     };
 }
 
+/// Type of the value of a command line parameter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParamType {
+    /// Any string, e.g. a path or an id.
+    String,
+    /// An unsigned integer.
+    Number,
+    /// `on|off`, `true|false` or `yes|no`.
+    Bool,
+    /// A size with an optional unit, e.g. `2G`.
+    Size,
+    /// A key without value, e.g. `server` of `-chardev`.
+    Flag,
+}
+
+impl ParamType {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ParamType::String => "string",
+            ParamType::Number => "number",
+            ParamType::Bool => "boolean",
+            ParamType::Size => "size",
+            ParamType::Flag => "flag",
+        }
+    }
+}
+
+/// Declaration of a parameter of a command line option. The parameter named ""
+/// is the value given without key, e.g. `microvm` in `-machine microvm`.
+#[derive(Clone, Copy, Debug)]
+pub struct ParamSpec {
+    pub name: &'static str,
+    pub param_type: ParamType,
+    pub default: Option<&'static str>,
+    /// Supported values, empty if any value of `param_type` is supported.
+    pub values: &'static [&'static str],
+    /// Whether the parameter is compiled in, e.g. `cfg!(target_arch = "aarch64")`.
+    pub compiled: bool,
+}
+
+impl ParamSpec {
+    const fn new(name: &'static str, param_type: ParamType) -> Self {
+        ParamSpec {
+            name,
+            param_type,
+            default: None,
+            values: &[],
+            compiled: true,
+        }
+    }
+
+    const fn default(mut self, default: &'static str) -> Self {
+        self.default = Some(default);
+        self
+    }
+
+    const fn values(mut self, values: &'static [&'static str]) -> Self {
+        self.values = values;
+        self
+    }
+
+    const fn compiled(mut self, compiled: bool) -> Self {
+        self.compiled = compiled;
+        self
+    }
+}
+
+const ON_OFF: &[&str] = &["on", "off"];
+
+/// How a command line option takes its value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptionValue {
+    /// The option is a flag without value.
+    None,
+    /// The option can be given once with a value.
+    Single,
+    /// The option can be given many times, each with a value.
+    Multiple,
+}
+
+/// Declaration of a command line option, which is used both to build the
+/// `ArgParser` and to report the option by introspection.
+#[derive(Clone, Copy, Debug)]
+pub struct OptionSpec {
+    /// Name to get the option from `ArgMatches`.
+    pub name: &'static str,
+    /// Name used with prefix `-`, e.g. `m` for `-m`.
+    pub long: Option<&'static str>,
+    pub short: Option<&'static str>,
+    /// Name used with prefix `--`, e.g. `dump-options-json` for `--dump-options-json`.
+    pub opt_long: Option<&'static str>,
+    pub value_name: Option<&'static str>,
+    pub help: Option<&'static str>,
+    pub value: OptionValue,
+    /// The value can be omitted, even if the option takes a value.
+    pub can_no_value: bool,
+    /// The option is accepted for compatibility, but not shown in help message.
+    pub hidden: bool,
+    pub params: &'static [ParamSpec],
+    /// Whether the option is compiled in, e.g. `cfg!(feature = "xxx")`.
+    pub compiled: bool,
+}
+
+impl OptionSpec {
+    const NONE: OptionSpec = OptionSpec {
+        name: "",
+        long: None,
+        short: None,
+        opt_long: None,
+        value_name: None,
+        help: None,
+        value: OptionValue::Single,
+        can_no_value: false,
+        hidden: false,
+        params: &[],
+        compiled: true,
+    };
+
+    /// Name of the option used in command line, without prefix.
+    pub fn option_name(&self) -> &'static str {
+        self.long.or(self.opt_long).unwrap_or(self.name)
+    }
+
+    /// Get the parameters of the option which are compiled in.
+    pub fn params(&self) -> impl Iterator<Item = &'static ParamSpec> {
+        self.params.iter().filter(|param| param.compiled)
+    }
+
+    fn to_arg(self) -> Arg<'static> {
+        let mut arg = Arg::with_name(self.name);
+        if let Some(long) = self.long {
+            arg = arg.long(long);
+        }
+        if let Some(short) = self.short {
+            arg = arg.short(short);
+        }
+        if let Some(opt_long) = self.opt_long {
+            arg = arg.opt_long(opt_long);
+        }
+        if let Some(value_name) = self.value_name {
+            arg = arg.value_name(value_name);
+        }
+        if let Some(help) = self.help {
+            arg = arg.help(help);
+        }
+        arg = match self.value {
+            OptionValue::None => arg.takes_value(false),
+            OptionValue::Single => arg.takes_value(true),
+            OptionValue::Multiple => arg.multiple(true).takes_values(true),
+        };
+        arg.can_no_value(self.can_no_value).hidden(self.hidden)
+    }
+
+    fn to_cmd_line(self) -> CmdLine {
+        CmdLine {
+            option: self.option_name().to_string(),
+            multiple: self.value == OptionValue::Multiple,
+            parameters: self
+                .params()
+                .map(|param| CmdParameter {
+                    name: param.name.to_string(),
+                    param_type: param.param_type.as_str().to_string(),
+                    default: param.default.map(|default| default.to_string()),
+                    values: param.values.iter().map(|value| value.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+}
+
+/// All command line options. Options without sub-parameters declare the
+/// parameter "" for their value, and flags declare no parameter.
+pub static OPTIONS: &[OptionSpec] = &[
+    OptionSpec {
+        name: "name",
+        long: Some("name"),
+        value_name: Some("[guest=]<vm_name>[,process=<process_name>]"),
+        help: Some("set the name of the guest, and the name of the process on the host."),
+        params: &[
+            ParamSpec::new("", ParamType::String),
+            ParamSpec::new("guest", ParamType::String),
+            ParamSpec::new("process", ParamType::String),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "machine",
+        long: Some("machine"),
+        value_name: Some("[type=]<name>[,dump_guest_core=on|off][,mem-share=on|off][,pci-hole64-size=<size>][,pit=on|off][,its=on|off][,kernel-irqchip=on|split]"),
+        help: Some("'type' selects emulated machine type and set properties. \
+                    'dump_guest_core' includes guest memory in a core dump. \
+                    'mem-share' sets guest memory is shareable. \
+                    'pci-hole64-size' sets the size of 64-bit PCI hole at 4GiB (x86_64 only). \
+                    'pit' creates the in-kernel PIT, the guest uses kvmclock/TSC if off (x86_64 only). \
+                    'its' creates the GICv3 ITS for MSI of PCI devices (aarch64 only). \
+                    'kernel-irqchip' selects the in-kernel irqchip mode, 'split' is reserved for later."),
+        params: &[
+            ParamSpec::new("", ParamType::String).values(MACHINE_TYPES),
+            ParamSpec::new("type", ParamType::String).values(MACHINE_TYPES),
+            ParamSpec::new("accel", ParamType::String).values(&["kvm", "kvm:tcg", "tcg"]),
+            ParamSpec::new("usb", ParamType::Bool).values(&["off"]),
+            ParamSpec::new("dump-guest-core", ParamType::Bool).default("on").values(ON_OFF),
+            ParamSpec::new("mem-share", ParamType::Bool).default("off").values(ON_OFF),
+            ParamSpec::new("gic-version", ParamType::Number)
+                .default("3")
+                .values(&["3"])
+                .compiled(cfg!(target_arch = "aarch64")),
+            ParamSpec::new("pci-hole64-size", ParamType::Size)
+                .default("0")
+                .compiled(cfg!(target_arch = "x86_64")),
+            ParamSpec::new("pit", ParamType::Bool)
+                .default("on")
+                .values(ON_OFF)
+                .compiled(cfg!(target_arch = "x86_64")),
+            ParamSpec::new("its", ParamType::Bool)
+                .default("on")
+                .values(ON_OFF)
+                .compiled(cfg!(target_arch = "aarch64")),
+            ParamSpec::new("kernel-irqchip", ParamType::String)
+                .default("on")
+                .values(&["on", "split"]),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "accel",
+        long: Some("accel"),
+        value_name: Some("[accel]"),
+        help: Some("select accelerator, only 'kvm' is supported now."),
+        params: &[ParamSpec::new("", ParamType::String).values(&["kvm"])],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "smp",
+        long: Some("smp"),
+        value_name: Some("[cpus=]<n>[,maxcpus=<cpus>][,sockets=<sockets>][,dies=<dies>][,clusters=<clusters>][,cores=<cores>][,threads=<threads>]"),
+        help: Some("'cpus' sets the number of CPUs to 'n' (default: 1). 'maxcpus' sets number of total CPUs, including online and offline CPUs. \
+                    'sockets' is the number of sockets on the machine. \
+                    'dies' is the number of dies in one socket. \
+                    'clusters' is the number of clusters in one die. \
+                    'cores' is the number of cores in one cluster. \
+                    'threads' is the number of threads in one core"),
+        params: &[
+            ParamSpec::new("", ParamType::Number).default("1"),
+            ParamSpec::new("cpus", ParamType::Number).default("1"),
+            ParamSpec::new("maxcpus", ParamType::Number),
+            ParamSpec::new("sockets", ParamType::Number),
+            ParamSpec::new("dies", ParamType::Number).default("1"),
+            ParamSpec::new("clusters", ParamType::Number).default("1"),
+            ParamSpec::new("cores", ParamType::Number),
+            ParamSpec::new("threads", ParamType::Number),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "cpu",
+        long: Some("cpu"),
+        value_name: Some("host[,+feature][,-feature][,pmu=on|off][,realtime=on|off]"),
+        help: Some("set CPU model and features."),
+        params: &[
+            ParamSpec::new("", ParamType::String).values(&["host"]),
+            ParamSpec::new("pmu", ParamType::Bool).default("off").values(ON_OFF),
+            ParamSpec::new("realtime", ParamType::Bool).default("off").values(ON_OFF),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "cpu-pin",
+        long: Some("cpu-pin"),
+        value_name: Some("vcpu<n>=<host cpu>[,...][,vcpu-priority=fifo|rr:<1-99>]"),
+        help: Some("pin vCPU threads to host cpus. 'vcpu-priority' sets the realtime scheduling policy \
+                    and priority of all the vCPU threads, which requires CAP_SYS_NICE"),
+        params: &[ParamSpec::new("vcpu-priority", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "freeze_cpu",
+        long: Some("freeze"),
+        short: Some("S"),
+        help: Some("freeze CPU at startup"),
+        value: OptionValue::None,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "memory",
+        long: Some("m"),
+        value_name: Some("[size=]<megs>[m|M|g|G]"),
+        help: Some("configure guest RAM(default unit: MiB)."),
+        params: &[
+            ParamSpec::new("", ParamType::Size),
+            ParamSpec::new("size", ParamType::Size),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "mem-path",
+        long: Some("mem-path"),
+        value_name: Some("<filebackend file path>"),
+        help: Some("configure file path that backs guest memory."),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "mem-prealloc",
+        long: Some("mem-prealloc"),
+        help: Some("Prealloc memory for VM"),
+        value: OptionValue::None,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "mem-aging",
+        long: Some("mem-aging"),
+        value_name: Some("interval=<60s>,threshold=<300s>[,rate=<MiB/s>][,advice=cold|pageout]"),
+        help: Some("mark guest memory which is idle for long as reclaimable to the host"),
+        params: &[
+            ParamSpec::new("interval", ParamType::String),
+            ParamSpec::new("threshold", ParamType::String),
+            ParamSpec::new("rate", ParamType::Number).default("64"),
+            ParamSpec::new("advice", ParamType::String)
+                .default("cold")
+                .values(&["cold", "pageout"]),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "numa",
+        long: Some("numa"),
+        value_name: Some("<parameters>"),
+        help: Some("\n\t\tset numa node: -numa node,nodeid=<0>,cpus=<0-1>,memdev=<mem0>; \
+                    \n\t\tset numa distance: -numa dist,src=<0>,dst=<1>,val=<20> "),
+        value: OptionValue::Multiple,
+        params: &[ParamSpec::new("", ParamType::String).values(&["node", "dist"])],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "kernel",
+        long: Some("kernel"),
+        value_name: Some("<kernel_path>"),
+        help: Some("use uncompressed kernel image"),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "kernel-cmdline",
+        long: Some("append"),
+        value_name: Some("<kernel cmdline parameters>"),
+        help: Some("use 'cmdline' as kernel command line"),
+        value: OptionValue::Multiple,
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "initrd-file",
+        long: Some("initrd"),
+        value_name: Some("<initrd_path>"),
+        help: Some("use 'initrd-file' as initial ram disk"),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "kernel2",
+        long: Some("kernel2"),
+        value_name: Some("<kernel_path>,addr=<gpa>"),
+        help: Some("load an extra kernel image to the reserved guest memory at 'addr', e.g. for kexec"),
+        params: &[
+            ParamSpec::new("", ParamType::String),
+            ParamSpec::new("addr", ParamType::Number),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "qmp",
+        long: Some("qmp"),
+        value_name: Some("unix:<socket_path>"),
+        help: Some("set QMP's unix socket path"),
+        params: &[
+            ParamSpec::new("", ParamType::String),
+            ParamSpec::new("server", ParamType::Flag),
+            ParamSpec::new("nowait", ParamType::Flag),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "mod-test",
+        long: Some("mod-test"),
+        value_name: Some("unix:socket_path"),
+        help: Some("set module test's unixsocket path"),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "drive",
+        long: Some("drive"),
+        value_name: Some("<parameters>"),
+        help: Some("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>][,throttling.group=<group_id>]; \
+                    \n\t\tset pflash drive image: -drive file=<pflash_path>,if=pflash,unit=0|1[,readonly=true|false]; \
+                    \n\t\tset scsi drive image: -drive id=<drive-scsi0-0-0-0>,file=<path_on_host>[,readonly=true|false]"),
+        value: OptionValue::Multiple,
+        params: &[
+            ParamSpec::new("if", ParamType::String)
+                .default("none")
+                .values(&["none", "pflash"]),
+            ParamSpec::new("file", ParamType::String),
+            ParamSpec::new("id", ParamType::String),
+            ParamSpec::new("readonly", ParamType::Bool).default("off").values(ON_OFF),
+            ParamSpec::new("direct", ParamType::Bool).default("on").values(ON_OFF),
+            ParamSpec::new("format", ParamType::String).default("raw"),
+            ParamSpec::new("throttling.iops-total", ParamType::Number),
+            ParamSpec::new("throttling.group", ParamType::String),
+            ParamSpec::new("aio", ParamType::String).values(&["off", "native", "io_uring"]),
+            ParamSpec::new("werror", ParamType::String)
+                .default("report")
+                .values(&["report", "stop", "ignore", "enospc", "read-only"]),
+            ParamSpec::new("rerror", ParamType::String)
+                .default("report")
+                .values(&["report", "stop", "ignore", "enospc"]),
+            ParamSpec::new("unit", ParamType::Number),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "netdev",
+        long: Some("netdev"),
+        value_name: Some("tap,id=<str>,ifname=<tap_name>[,script=<path>|no][,downscript=<path>|no][,vhost=on|off][,queue=<N>][,rate-limit-bps=<N>][,rate-limit-pps=<N>]"),
+        help: Some("configure a host TAP network with ID 'str'"),
+        value: OptionValue::Multiple,
+        params: &[
+            ParamSpec::new("", ParamType::String).values(&["tap", "vhost-user"]),
+            ParamSpec::new("id", ParamType::String),
+            ParamSpec::new("fd", ParamType::Number),
+            ParamSpec::new("fds", ParamType::String),
+            ParamSpec::new("vhost", ParamType::Bool).default("off").values(ON_OFF),
+            ParamSpec::new("ifname", ParamType::String),
+            ParamSpec::new("script", ParamType::String),
+            ParamSpec::new("downscript", ParamType::String),
+            ParamSpec::new("vhostfd", ParamType::Number),
+            ParamSpec::new("vhostfds", ParamType::String),
+            ParamSpec::new("queues", ParamType::Number).default("1"),
+            ParamSpec::new("chardev", ParamType::String),
+            ParamSpec::new("rate-limit-bps", ParamType::Number),
+            ParamSpec::new("rate-limit-pps", ParamType::Number),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "chardev",
+        long: Some("chardev"),
+        value_name: Some("socket,id=<str>,path=<socket_path>[,server=on|off][,wait=on|off][,reconnect=<seconds>]"),
+        help: Some("set char device virtio console for vm"),
+        value: OptionValue::Multiple,
+        params: &[
+            ParamSpec::new("", ParamType::String).values(&["stdio", "pty", "socket", "file"]),
+            ParamSpec::new("id", ParamType::String),
+            ParamSpec::new("path", ParamType::String),
+            ParamSpec::new("server", ParamType::Bool),
+            ParamSpec::new("nowait", ParamType::Flag),
+            ParamSpec::new("wait", ParamType::Bool),
+            ParamSpec::new("reconnect", ParamType::Number),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "device",
+        long: Some("device"),
+        value_name: Some("<parameters>"),
+        help: Some("\n\t\tadd virtio mmio block: -device virtio-blk-device,id=<blk_id>,drive=<drive_id>[,iothread=<iothread1>][,serial=<serial_num>]; \
+                    \n\t\tadd virtio pci block: -device virtio-blk-pci,id=<blk_id>,drive=<drive_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,serial=<serial_num>][,num-queues=<N>][,bootindex=<N>]; \
+                    \n\t\tadd vhost user pci block: -device vhost-user-blk-pci,id=<blk_id>,chardev=<chardev_id>,bus=<pcie.0>,addr=<0x3>[,num-queues=<N>][,bootindex=<N>]; \
+                    \n\t\tadd virtio mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
+                    \n\t\tadd virtio pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
+                    \n\t\tadd vhost mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
+                    \n\t\tadd vhost pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
+                    \n\t\tadd virtio mmio console: -device virtio-serial-device[,id=<virtio-serial0>] -device virtconsole,id=console_id,chardev=<virtioconsole1>; \
+                    \n\t\tadd virtio pci console: -device virtio-serial-pci,id=<virtio-serial0>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off] -device virtconsole,id=<console_id>,chardev=<virtioconsole1>; \
+                    \n\t\tadd vhost mmio vsock: -device vhost-vsock-device,id=<vsock_id>,guest-cid=<N>; \
+                    \n\t\tadd vhost pci vsock: -device vhost-vsock-pci,id=<vsock_id>,guest-cid=<N>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off]; \
+                    \n\t\tadd virtio mmio balloon: -device virtio-balloon-device[,deflate-on-oom=true|false][,free-page-reporting=true|false][,monitor-interval=<secs>]; \
+                    \n\t\tadd virtio pci balloon: -device virtio-balloon-pci,id=<balloon_id>,bus=<pcie.0>,addr=<0x4>[,deflate-on-oom=true|false][,free-page-reporting=true|false][,monitor-interval=<secs>][,multifunction=on|off]; \
+                    \n\t\tadd virtio mmio rng: -device virtio-rng-device,rng=<objrng0>,max-bytes=<1234>,period=<1000>; \
+                    \n\t\tadd virtio pci rng: -device virtio-rng-pci,id=<rng_id>,rng=<objrng0>,max-bytes=<1234>,period=<1000>,bus=<pcie.0>,addr=<0x1>[,multifunction=on|off]; \
+                    \n\t\tadd pcie root port: -device pcie-root-port,id=<pcie.1>,port=<0x1>,bus=<pcie.0>,addr=<0x1>[,multifunction=on|off]; \
+                    \n\t\tadd vfio pci: -device vfio-pci,id=<vfio_id>,host=<0000:1a:00.3>,bus=<pcie.0>,addr=<0x03>[,multifunction=on|off]; \
+                    \n\t\tadd usb controller: -device nec-usb-xhci,id=<xhci>,bus=<pcie.0>,addr=<0xa>; \
+                    \n\t\tadd usb keyboard: -device usb-kbd,id=<kbd>; \
+                    \n\t\tadd usb tablet-device usb-tablet,id=<tablet>; \
+                    \n\t\tadd scsi controller: -device virtio-scsi-pci,id=<scsi_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,num-queues=<N>]; \
+                    \n\t\tadd scsi hard disk: -device scsi-hd,scsi-id=<0>,bus=<scsi0.0>,lun=<0>,drive=<drive-scsi0-0-0-0>,id=<scsi0-0-0-0>; \
+                    \n\t\tadd vhost user fs: -device vhost-user-fs-pci,id=<device_id>,chardev=<chardev_id>,tag=<mount_tag>"),
+        value: OptionValue::Multiple,
+        // Parameters of a device depend on its driver, see `device-list-properties`.
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "serial",
+        long: Some("serial"),
+        value_name: Some("backend[,path=<str>,server,nowait] or chardev:<char_id>"),
+        help: Some("add serial and set chardev for it"),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "display log",
+        long: Some("D"),
+        value_name: Some("[log path][,max-size=<size>][,max-files=<num>]"),
+        help: Some("output log to logfile (default stderr), rotate it when it grows beyond max-size"),
+        can_no_value: true,
+        params: &[
+            ParamSpec::new("", ParamType::String),
+            ParamSpec::new("max-size", ParamType::Size),
+            ParamSpec::new("max-files", ParamType::Number).default("5"),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "log-level",
+        long: Some("log-level"),
+        value_name: Some("<level>|<module>=<level>[,...]"),
+        help: Some("set the log level globally or of modules, e.g. warn,virtio::scsi=debug"),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "pidfile",
+        long: Some("pidfile"),
+        value_name: Some("<pidfile path>"),
+        help: Some("write PID to 'file'"),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "daemonize",
+        long: Some("daemonize"),
+        value_name: Some(""),
+        help: Some("daemonize StratoVirt after initializing"),
+        value: OptionValue::None,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "disable-seccomp",
+        long: Some("disable-seccomp"),
+        value_name: Some(""),
+        help: Some("not use seccomp sandbox for StratoVirt"),
+        value: OptionValue::None,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "seccomp",
+        long: Some("seccomp"),
+        value_name: Some("kill|log|allow"),
+        help: Some("set the action of seccomp sandbox on a syscall not in the allowlist: kill the VM (default), log it to the audit log and allow it, or disable the sandbox"),
+        params: &[ParamSpec::new("", ParamType::String).values(&["kill", "log", "allow"])],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "incoming",
+        long: Some("incoming"),
+        value_name: Some("<parameters>"),
+        help: Some("\n\t\tdo the migration using tcp socket: -incoming tcp:<ip>:<port>; \
+                    \n\t\tdo the migration using unix socket: -incoming unix:<socket path>; \
+                    \n\t\tdo the virtual machine snapshot: -incoming file:<file path>[,lazy={on|off}][,prefetch={on|off}]"),
+        params: &[
+            ParamSpec::new("", ParamType::String),
+            ParamSpec::new("lazy", ParamType::Bool).default("off").values(ON_OFF),
+            ParamSpec::new("prefetch", ParamType::Bool).default("off").values(ON_OFF),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "object",
+        long: Some("object"),
+        value_name: Some("<parameters>"),
+        help: Some("\n\t\tadd memory backend ram object: -object memory-backend-ram,id=<memid>,size=<2G>,host-nodes=<0-1>,policy=<bind>; \
+                    \n\t\tadd iothread object: -object iothread,id=<iothread_id>; \
+                    \n\t\tadd rng object: -object rng-random,id=<rng_id>,filename=<file_path>; \
+                    \n\t\tadd vnc tls object: -object tls-creds-x509,id=<vnc_id>,dir=</etc/pki/vnc>; \
+                    \n\t\tadd authz object: -object authz-simple,id=<authz_id>,identity=<username>; \
+                    \n\t\tadd throttle group object: -object throttle-group,id=<group_id>,limits.iops-total=<500>"),
+        value: OptionValue::Multiple,
+        // Parameters of an object depend on its type.
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "mon",
+        long: Some("mon"),
+        value_name: Some("chardev=<chardev_id>,id=<mon_id>[,mode=control]"),
+        help: Some("-mon is another way to create qmp channel. To use it, the chardev should be specified"),
+        params: &[
+            ParamSpec::new("id", ParamType::String),
+            ParamSpec::new("mode", ParamType::String).values(&["control"]),
+            ParamSpec::new("chardev", ParamType::String),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "overcommit",
+        long: Some("overcommit"),
+        value_name: Some("mem-lock=on|off|on-fault"),
+        help: Some("'mem-lock' locks guest memory in host RAM, 'on-fault' locks it when it's faulted in"),
+        can_no_value: true,
+        params: &[ParamSpec::new("mem-lock", ParamType::String)
+            .default("off")
+            .values(&["on", "off", "on-fault"])],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "uuid",
+        long: Some("uuid"),
+        value_name: Some("<uuid>"),
+        help: Some("set the uuid of the guest, in format xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx."),
+        value: OptionValue::Multiple,
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "smbios",
+        long: Some("smbios"),
+        value_name: Some("type=1[,manufacturer=<str>][,product=<str>][,version=<str>][,serial=<str>][,uuid=<uuid>][,sku=<str>][,family=<str>]"),
+        help: Some("set the fields of SMBIOS System Information (type 1) table"),
+        value: OptionValue::Multiple,
+        params: &[
+            ParamSpec::new("type", ParamType::Number).values(&["1"]),
+            ParamSpec::new("manufacturer", ParamType::String),
+            ParamSpec::new("product", ParamType::String),
+            ParamSpec::new("version", ParamType::String),
+            ParamSpec::new("serial", ParamType::String),
+            ParamSpec::new("uuid", ParamType::String),
+            ParamSpec::new("sku", ParamType::String),
+            ParamSpec::new("family", ParamType::String),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "no-user-config",
+        long: Some("no-user-config"),
+        can_no_value: true,
+        hidden: true,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "nodefaults",
+        long: Some("nodefaults"),
+        can_no_value: true,
+        hidden: true,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "sandbox",
+        long: Some("sandbox"),
+        value_name: Some("[on,obsolete=deny]"),
+        can_no_value: true,
+        hidden: true,
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "msg",
+        long: Some("msg"),
+        value_name: Some("[timestamp=on]"),
+        can_no_value: true,
+        hidden: true,
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "rtc",
+        long: Some("rtc"),
+        value_name: Some("[base=utc|localtime]"),
+        help: Some("set the base of the time of the RTC, 'localtime' is needed by Windows guests"),
+        params: &[ParamSpec::new("base", ParamType::String)
+            .default("utc")
+            .values(&["utc", "localtime"])],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "action",
+        long: Some("action"),
+        value_name: Some("[reboot=reset|shutdown|pause][,shutdown=poweroff|pause]"),
+        help: Some("'reboot' sets the action when the guest reboots, 'shutdown' sets the action when the guest shuts down"),
+        value: OptionValue::Multiple,
+        params: &[
+            ParamSpec::new("reboot", ParamType::String)
+                .default("reset")
+                .values(&["reset", "shutdown", "pause"]),
+            ParamSpec::new("shutdown", ParamType::String)
+                .default("poweroff")
+                .values(&["poweroff", "pause"]),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "no-shutdown",
+        long: Some("no-shutdown"),
+        value_name: Some(""),
+        help: Some("keep StratoVirt running after guest shutdown, which is the same as '-action shutdown=pause'"),
+        can_no_value: true,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "boot",
+        long: Some("boot"),
+        value_name: Some("[order=<dcn>][,strict=on|off]"),
+        help: Some("set the boot order of device classes: c for disk, d for cdrom, n for network; strict=on disables fallback to other devices"),
+        params: &[
+            ParamSpec::new("order", ParamType::String),
+            ParamSpec::new("strict", ParamType::Bool).default("off").values(ON_OFF),
+            // Accepted for compatibility with libvirt, it does nothing.
+            ParamSpec::new("menu", ParamType::Bool).values(ON_OFF),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "nographic",
+        long: Some("nographic"),
+        can_no_value: true,
+        hidden: true,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "realtime",
+        long: Some("realtime"),
+        value_name: Some("[malock=off]"),
+        can_no_value: true,
+        hidden: true,
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "display",
+        long: Some("display"),
+        value_name: Some("[none]"),
+        can_no_value: true,
+        hidden: true,
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "usb",
+        long: Some("usb"),
+        can_no_value: true,
+        hidden: true,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "trace",
+        long: Some("trace"),
+        value_name: Some("events=<file>"),
+        help: Some("specify the file lists trace events to enable"),
+        params: &[ParamSpec::new("events", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "global",
+        long: Some("global"),
+        value_name: Some("[key=<value>]"),
+        help: Some("set global config"),
+        value: OptionValue::Multiple,
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "compat",
+        long: Some("compat"),
+        value_name: Some("qmp=strict|warn"),
+        help: Some("set the policy for deprecated QMP commands and arguments: reject them or warn in response"),
+        params: &[ParamSpec::new("qmp", ParamType::String)
+            .default("warn")
+            .values(&["strict", "warn"])],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "auto-placement",
+        long: Some("auto-placement"),
+        value_name: Some("numa"),
+        help: Some("pin vCPUs, guest memory and iothreads to host NUMA nodes automatically"),
+        params: &[ParamSpec::new("", ParamType::String).values(&["numa"])],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "vnc",
+        long: Some("vnc"),
+        value_name: Some("ip:port[,websocket=<port>][,password-file=<path>]"),
+        help: Some("specify the ip and port for vnc, and the port for websocket clients. \
+                    'password-file' enables VeNCrypt plain authentication over tls-creds"),
+        params: &[
+            ParamSpec::new("", ParamType::String),
+            ParamSpec::new("tls-creds", ParamType::String),
+            ParamSpec::new("sasl", ParamType::Flag),
+            ParamSpec::new("sasl-authz", ParamType::String),
+            ParamSpec::new("password-file", ParamType::String),
+            ParamSpec::new("websocket", ParamType::Number),
+        ],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "config",
+        long: Some("config"),
+        value_name: Some("<path.json>"),
+        help: Some("load the config of the VM from a json file, the other options take precedence over it"),
+        params: &[ParamSpec::new("", ParamType::String)],
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "dump-config",
+        long: Some("dump-config"),
+        value_name: Some(""),
+        help: Some("print the config of the VM merged from the config file and the options in json, then exit"),
+        value: OptionValue::None,
+        ..OptionSpec::NONE
+    },
+    OptionSpec {
+        name: "dump-options-json",
+        opt_long: Some("dump-options-json"),
+        help: Some("print all supported options and their parameters in json, then exit"),
+        value: OptionValue::None,
+        ..OptionSpec::NONE
+    },
+];
+
+#[cfg(target_arch = "x86_64")]
+const MACHINE_TYPES: &[&str] = &["none", "microvm", "q35"];
+#[cfg(target_arch = "aarch64")]
+const MACHINE_TYPES: &[&str] = &["none", "microvm", "virt"];
+
+/// Get the declaration of the command line option which is compiled in.
+///
+/// # Arguments
+///
+/// * `option` - Name of the option used in command line, e.g. `m` for `-m`.
+pub fn find_option(option: &str) -> Option<&'static OptionSpec> {
+    OPTIONS
+        .iter()
+        .find(|spec| spec.compiled && spec.option_name() == option)
+}
+
 /// This function is to define all commandline arguments.
 pub fn create_args_parser<'a>() -> ArgParser<'a> {
-    ArgParser::new("StratoVirt")
-        .version(VERSION.unwrap_or("unknown"))
-        .author("The StratoVirt Project Developers")
-        .about("A light kvm-based hypervisor.")
-        .arg(
-            Arg::with_name("name")
-            .long("name")
-            .value_name("[vm_name]")
-            .help("set the name of the guest.")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("machine")
-            .long("machine")
-            .value_name("[type=]<name>[,dump_guest_core=on|off][,mem-share=on|off]")
-            .help("'type' selects emulated machine type and set properties. \
-                   'dump_guest_core' includes guest memory in a core dump. \
-                   'mem-share' sets guest memory is shareable.")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("accel")
-            .long("accel")
-            .value_name("[accel]")
-            .help("select accelerator, only 'kvm' is supported now.")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("smp")
-            .long("smp")
-            .value_name("[cpus=]<n>[,maxcpus=<cpus>][,sockets=<sockets>][,dies=<dies>][,clusters=<clusters>][,cores=<cores>][,threads=<threads>]")
-            .help("'cpus' sets the number of CPUs to 'n' (default: 1). 'maxcpus' sets number of total CPUs, including online and offline CPUs. \
-                   'sockets' is the number of sockets on the machine. \
-                   'dies' is the number of dies in one socket. \
-                   'clusters' is the number of clusters in one die. \
-                   'cores' is the number of cores in one cluster. \
-                   'threads' is the number of threads in one core")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("cpu")
-            .long("cpu")
-            .value_name("host[,pmu=on|off]")
-            .help("set CPU model and features.")
-            .can_no_value(false)
-            .takes_value(true)
-        )
-        .arg(
-            Arg::with_name("freeze_cpu")
-            .short("S")
-            .long("freeze")
-            .help("freeze CPU at startup")
-            .takes_value(false)
-            .required(false),
-        )
-        .arg(
-            Arg::with_name("memory")
-            .long("m")
-            .value_name("[size=]<megs>[m|M|g|G]")
-            .help("configure guest RAM(default unit: MiB).")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("mem-path")
-            .long("mem-path")
-            .value_name("<filebackend file path>")
-            .help("configure file path that backs guest memory.")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("mem-prealloc")
-            .long("mem-prealloc")
-            .help("Prealloc memory for VM")
-            .takes_value(false)
-            .required(false),
-        )
-        .arg(
-            Arg::with_name("numa")
-            .multiple(true)
-            .long("numa")
-            .value_name("<parameters>")
-            .help("\n\t\tset numa node: -numa node,nodeid=<0>,cpus=<0-1>,memdev=<mem0>; \
-                   \n\t\tset numa distance: -numa dist,src=<0>,dst=<1>,val=<20> ")
-            .takes_values(true),
-        )
-        .arg(
-            Arg::with_name("kernel")
-            .long("kernel")
-            .value_name("<kernel_path>")
-            .help("use uncompressed kernel image")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("kernel-cmdline")
-            .multiple(true)
-            .long("append")
-            .value_name("<kernel cmdline parameters>")
-            .help("use 'cmdline' as kernel command line")
-            .takes_values(true),
-        )
-        .arg(
-            Arg::with_name("initrd-file")
-            .long("initrd")
-            .value_name("<initrd_path>")
-            .help("use 'initrd-file' as initial ram disk")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("qmp")
-            .long("qmp")
-            .value_name("unix:<socket_path>")
-            .help("set QMP's unix socket path")
-            .takes_value(true)
-        )
-        .arg(
-            Arg::with_name("mod-test")
-            .long("mod-test")
-            .value_name("unix:socket_path")
-            .help("set module test's unixsocket path")
-            .takes_value(true)
-        )
-        .arg(
-            Arg::with_name("drive")
-            .multiple(true)
-            .long("drive")
-            .value_name("<parameters>")
-            .help("\n\t\tset block drive image: -drive id=<drive_id>,file=<path_on_host>[,readonly=on|off][,direct=on|off][,throttling.iops-total=<200>]; \
-                   \n\t\tset pflash drive image: -drive file=<pflash_path>,if=pflash,unit=0|1[,readonly=true|false]; \
-                   \n\t\tset scsi drive image: -drive id=<drive-scsi0-0-0-0>,file=<path_on_host>[,readonly=true|false]")
-            .takes_values(true),
-        )
-        .arg(
-            Arg::with_name("netdev")
-            .multiple(true)
-            .long("netdev")
-            .value_name(
-                "tap,id=<str>,ifname=<tap_name>[,vhost=on|off][,queue=<N>]",
-            )
-            .help("configure a host TAP network with ID 'str'")
-            .takes_values(true),
-        )
-        .arg(
-            Arg::with_name("chardev")
-            .multiple(true)
-            .long("chardev")
-            .value_name("socket,id=<str>,path=<socket_path>")
-            .help("set char device virtio console for vm")
-            .takes_values(true),
-        )
-        .arg(
-            Arg::with_name("device")
-            .multiple(true)
-            .long("device")
-            .value_name("<parameters>")
-            .help("\n\t\tadd virtio mmio block: -device virtio-blk-device,id=<blk_id>,drive=<drive_id>[,iothread=<iothread1>][,serial=<serial_num>]; \
-                   \n\t\tadd virtio pci block: -device virtio-blk-pci,id=<blk_id>,drive=<drive_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,serial=<serial_num>][,num-queues=<N>][,bootindex=<N>]; \
-                   \n\t\tadd vhost user pci block: -device vhost-user-blk-pci,id=<blk_id>,chardev=<chardev_id>,bus=<pcie.0>,addr=<0x3>[,num-queues=<N>][,bootindex=<N>]; \
-                   \n\t\tadd virtio mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
-                   \n\t\tadd virtio pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
-                   \n\t\tadd vhost mmio net: -device virtio-net-device,id=<net_id>,netdev=<netdev_id>[,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>]; \
-                   \n\t\tadd vhost pci net: -device virtio-net-pci,id=<net_id>,netdev=<netdev_id>,bus=<pcie.0>,addr=<0x2>[,multifunction=on|off][,iothread=<iothread1>][,mac=<12:34:56:78:9A:BC>][,mq=on|off]; \
-                   \n\t\tadd virtio mmio console: -device virtio-serial-device[,id=<virtio-serial0>] -device virtconsole,id=console_id,chardev=<virtioconsole1>; \
-                   \n\t\tadd virtio pci console: -device virtio-serial-pci,id=<virtio-serial0>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off] -device virtconsole,id=<console_id>,chardev=<virtioconsole1>; \
-                   \n\t\tadd vhost mmio vsock: -device vhost-vsock-device,id=<vsock_id>,guest-cid=<N>; \
-                   \n\t\tadd vhost pci vsock: -device vhost-vsock-pci,id=<vsock_id>,guest-cid=<N>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off]; \
-                   \n\t\tadd virtio mmio balloon: -device virtio-balloon-device[,deflate-on-oom=true|false][,free-page-reporting=true|false]; \
-                   \n\t\tadd virtio pci balloon: -device virtio-balloon-pci,id=<balloon_id>,bus=<pcie.0>,addr=<0x4>[,deflate-on-oom=true|false][,free-page-reporting=true|false][,multifunction=on|off]; \
-                   \n\t\tadd virtio mmio rng: -device virtio-rng-device,rng=<objrng0>,max-bytes=<1234>,period=<1000>; \
-                   \n\t\tadd virtio pci rng: -device virtio-rng-pci,id=<rng_id>,rng=<objrng0>,max-bytes=<1234>,period=<1000>,bus=<pcie.0>,addr=<0x1>[,multifunction=on|off]; \
-                   \n\t\tadd pcie root port: -device pcie-root-port,id=<pcie.1>,port=<0x1>,bus=<pcie.0>,addr=<0x1>[,multifunction=on|off]; \
-                   \n\t\tadd vfio pci: -device vfio-pci,id=<vfio_id>,host=<0000:1a:00.3>,bus=<pcie.0>,addr=<0x03>[,multifunction=on|off]; \
-                   \n\t\tadd usb controller: -device nec-usb-xhci,id=<xhci>,bus=<pcie.0>,addr=<0xa>; \
-                   \n\t\tadd usb keyboard: -device usb-kbd,id=<kbd>; \
-                   \n\t\tadd usb tablet-device usb-tablet,id=<tablet>; \
-                   \n\t\tadd scsi controller: -device virtio-scsi-pci,id=<scsi_id>,bus=<pcie.0>,addr=<0x3>[,multifunction=on|off][,iothread=<iothread1>][,num-queues=<N>]; \
-                   \n\t\tadd scsi hard disk: -device scsi-hd,scsi-id=<0>,bus=<scsi0.0>,lun=<0>,drive=<drive-scsi0-0-0-0>,id=<scsi0-0-0-0>; \
-                   \n\t\tadd vhost user fs: -device vhost-user-fs-pci,id=<device_id>,chardev=<chardev_id>,tag=<mount_tag>")
-            .takes_values(true),
-        )
-        .arg(
-            Arg::with_name("serial")
-            .long("serial")
-            .value_name("backend[,path=<str>,server,nowait] or chardev:<char_id>")
-            .help("add serial and set chardev for it")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("display log")
-            .long("D")
-            .value_name("[log path]")
-            .help("output log to logfile (default stderr)")
-            .takes_value(true)
-            .can_no_value(true),
-        )
-        .arg(
-            Arg::with_name("pidfile")
-            .long("pidfile")
-            .value_name("<pidfile path>")
-            .help("write PID to 'file'")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("daemonize")
-            .long("daemonize")
-            .value_name("")
-            .help("daemonize StratoVirt after initializing")
-            .takes_value(false)
-            .required(false),
-        )
-        .arg(
-            Arg::with_name("disable-seccomp")
-            .long("disable-seccomp")
-            .value_name("")
-            .help("not use seccomp sandbox for StratoVirt")
-            .takes_value(false)
-            .required(false),
-        )
-        .arg(
-            Arg::with_name("incoming")
-            .long("incoming")
-            .value_name("<parameters>")
-            .help("\n\t\tdo the migration using tcp socket: -incoming tcp:<ip>:<port>; \
-                   \n\t\tdo the migration using unix socket: -incoming unix:<socket path>; \
-                   \n\t\tdo the virtual machine snapshot: -incoming file:<file path>")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("object")
-            .multiple(true)
-            .long("object")
-            .value_name("<parameters>")
-            .help("\n\t\tadd memory backend ram object: -object memory-backend-ram,id=<memid>,size=<2G>,host-nodes=<0-1>,policy=<bind>; \
-                   \n\t\tadd iothread object: -object iothread,id=<iothread_id>; \
-                   \n\t\tadd rng object: -object rng-random,id=<rng_id>,filename=<file_path>; \
-                   \n\t\tadd vnc tls object: -object tls-creds-x509,id=<vnc_id>,dir=</etc/pki/vnc>; \
-                   \n\t\tadd authz object: -object authz-simple,id=<authz_id>,identity=<username>")
-            .takes_values(true),
-        )
-        .arg(
-            Arg::with_name("mon")
-            .long("mon")
-            .value_name("chardev=<chardev_id>,id=<mon_id>[,mode=control]")
-            .help("-mon is another way to create qmp channel. To use it, the chardev should be specified")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("overcommit")
-            .long("overcommit")
-            .value_name("[mem-lock=off]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("uuid")
-            .long("uuid")
-            .value_name("[uuid]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("no-user-config")
-            .long("no-user-config")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("nodefaults")
-            .long("nodefaults")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("sandbox")
-            .long("sandbox")
-            .value_name("[on,obsolete=deny]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("msg")
-            .long("msg")
-            .value_name("[timestamp=on]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("rtc")
-            .long("rtc")
-            .value_name("[base=utc]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("no-shutdown")
-            .long("no-shutdown")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("boot")
-            .long("boot")
-            .value_name("[strict=on]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("nographic")
-            .long("nographic")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("realtime")
-            .long("realtime")
-            .value_name("[malock=off]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("display")
-            .long("display")
-            .value_name("[none]")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("usb")
-            .long("usb")
-            .hidden(true)
-            .can_no_value(true)
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("trace")
-            .multiple(false)
-            .long("trace")
-            .value_name("events=<file>")
-            .help("specify the file lists trace events to enable")
-            .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("global")
-            .multiple(true)
-            .long("global")
-            .value_name("[key=<value>]")
-            .help("set global config")
-            .takes_values(true)
-            .required(false),
-        )
-        .arg(
-            Arg::with_name("vnc")
-            .multiple(false)
-            .long("vnc")
-            .value_name("ip:port")
-            .help("specify the ip and port for vnc")
-            .takes_value(true),
-        )
+    OPTIONS.iter().filter(|spec| spec.compiled).fold(
+        ArgParser::new("StratoVirt")
+            .version(VERSION.unwrap_or("unknown"))
+            .author("The StratoVirt Project Developers")
+            .about("A light kvm-based hypervisor."),
+        |parser, spec| parser.arg(spec.to_arg()),
+    )
+}
+
+fn options_to_cmd_lines(options: &[OptionSpec], option: Option<&str>) -> Result<Vec<CmdLine>> {
+    let cmd_lines: Vec<CmdLine> = options
+        .iter()
+        .filter(|spec| spec.compiled)
+        .filter(|spec| option.is_none_or(|name| spec.option_name() == name))
+        .map(|spec| spec.to_cmd_line())
+        .collect();
+    if let Some(name) = option {
+        if cmd_lines.is_empty() {
+            bail!("invalid option name: {}", name);
+        }
+    }
+    Ok(cmd_lines)
+}
+
+/// Get the command line options and their parameters for introspection.
+///
+/// # Arguments
+///
+/// * `option` - Only get the option of this name if it's given.
+pub fn query_command_line_options(option: Option<&str>) -> Result<Vec<CmdLine>> {
+    options_to_cmd_lines(OPTIONS, option)
+}
+
+/// Get all the command line options and their parameters in json, which is
+/// printed by `--dump-options-json`.
+pub fn dump_options_json() -> Result<String> {
+    let cmd_lines = query_command_line_options(None)?;
+    serde_json::to_string_pretty(&cmd_lines).with_context(|| "Failed to serialize options")
 }
 
 /// Create `VmConfig` from `ArgMatches`'s arg.
@@ -457,30 +966,48 @@ pub fn create_args_parser<'a>() -> ArgParser<'a> {
 /// Input arguments is illegal for `VmConfig` or `VmConfig`'s health check
 /// failed -- with this unhealthy `VmConfig`, VM will not boot successfully.
 pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
-    // Parse config-file json.
-    // VmConfig can be transformed by json file which described VmConfig
-    // directly.
     let mut vm_cfg = VmConfig::default();
 
     // Parse cmdline args which need to set in VmConfig
     add_args_to_config!((args.value_of("name")), vm_cfg, add_name);
+    add_args_to_config_multi!((args.values_of("uuid")), vm_cfg, add_uuid);
+    add_args_to_config_multi!((args.values_of("smbios")), vm_cfg, add_smbios);
     add_args_to_config!((args.value_of("machine")), vm_cfg, add_machine);
     add_args_to_config!((args.value_of("accel")), vm_cfg, add_accel);
     add_args_to_config!((args.value_of("memory")), vm_cfg, add_memory);
     add_args_to_config!((args.value_of("mem-path")), vm_cfg, add_mem_path);
+    add_args_to_config!((args.value_of("mem-aging")), vm_cfg, add_mem_aging);
+    add_args_to_config!((args.value_of("overcommit")), vm_cfg, add_overcommit);
     add_args_to_config!((args.value_of("smp")), vm_cfg, add_cpu);
     add_args_to_config!((args.value_of("cpu")), vm_cfg, add_cpu_feature);
+    add_args_to_config!((args.value_of("cpu-pin")), vm_cfg, add_cpu_pin);
     add_args_to_config!((args.value_of("kernel")), vm_cfg, add_kernel);
     add_args_to_config!((args.value_of("initrd-file")), vm_cfg, add_initrd);
+    add_args_to_config!((args.value_of("kernel2")), vm_cfg, add_kernel2);
     add_args_to_config!((args.value_of("serial")), vm_cfg, add_serial);
     add_args_to_config!((args.value_of("incoming")), vm_cfg, add_incoming);
     add_args_to_config!((args.value_of("vnc")), vm_cfg, add_vnc);
+    add_args_to_config!((args.value_of("compat")), vm_cfg, add_compat);
+    add_args_to_config!(
+        (args.value_of("auto-placement")),
+        vm_cfg,
+        add_auto_placement
+    );
+    add_args_to_config!((args.value_of("boot")), vm_cfg, add_boot);
+    add_args_to_config!((args.value_of("rtc")), vm_cfg, add_rtc);
     add_args_to_config!(
         (args.is_present("no-shutdown")),
         vm_cfg,
         add_no_shutdown,
         bool
     );
+    add_args_to_config!((args.value_of("seccomp")), vm_cfg, add_seccomp);
+    add_args_to_config!(
+        (args.is_present("disable-seccomp")),
+        vm_cfg,
+        disable_seccomp,
+        bool
+    );
     add_args_to_config!(
         (args.is_present("mem-prealloc")),
         vm_cfg,
@@ -500,11 +1027,19 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
     add_args_to_config_multi!((args.values_of("device")), vm_cfg, add_device);
     add_args_to_config_multi!((args.values_of("global")), vm_cfg, add_global_config);
     add_args_to_config_multi!((args.values_of("numa")), vm_cfg, add_numa);
+    add_args_to_config_multi!((args.values_of("action")), vm_cfg, add_action);
 
     if let Some(s) = args.value_of("trace") {
         add_trace_events(&s)?;
     }
 
+    // The json config file describes VmConfig directly, the options given on the command
+    // line take precedence over it.
+    if let Some(path) = args.value_of("config") {
+        let file_cfg = load_config_file(&path)?;
+        vm_cfg = merge_vmconfig(&file_cfg, &vm_cfg)?;
+    }
+
     // Check the mini-set for Vm to start is ok
     if vm_cfg.machine_config.mach_type != MachineType::None {
         vm_cfg
@@ -526,8 +1061,7 @@ pub fn create_vmconfig(args: &ArgMatches) -> Result<VmConfig> {
 pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<Vec<UnixListener>> {
     let mut sock_paths = Vec::new();
     if let Some(qmp_config) = args.value_of("qmp") {
-        let mut cmd_parser = CmdParser::new("qmp");
-        cmd_parser.push("").push("server").push("nowait");
+        let mut cmd_parser = CmdParser::for_option("qmp");
 
         cmd_parser.parse(&qmp_config)?;
         if let Some(uri) = cmd_parser.get_value::<String>("")? {
@@ -545,8 +1079,7 @@ pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<
         }
     }
     if let Some(mon_config) = args.value_of("mon") {
-        let mut cmd_parser = CmdParser::new("monitor");
-        cmd_parser.push("id").push("mode").push("chardev");
+        let mut cmd_parser = CmdParser::for_option("mon");
 
         cmd_parser.parse(&mon_config)?;
 
@@ -569,6 +1102,7 @@ pub fn check_api_channel(args: &ArgMatches, vm_config: &mut VmConfig) -> Result<
                 path,
                 server,
                 nowait,
+                ..
             } = cfg.backend
             {
                 if !server || !nowait {
@@ -609,3 +1143,125 @@ fn bind_socket(path: String) -> Result<UnixListener> {
         .with_context(|| format!("Failed to limit permission for socket file {}", &path))?;
     Ok(listener)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_options_unique() {
+        let mut names = HashSet::new();
+        let mut option_names = HashSet::new();
+        for spec in OPTIONS {
+            assert!(names.insert(spec.name), "{} is duplicated", spec.name);
+            assert!(option_names.insert(spec.option_name()));
+            let mut params = HashSet::new();
+            for param in spec.params {
+                assert!(params.insert(param.name));
+                if let Some(default) = param.default {
+                    assert!(param.values.is_empty() || param.values.contains(&default));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_params_drive_parser() {
+        let mut cmd_parser = CmdParser::for_option("boot");
+        assert!(cmd_parser.parse("order=dc,strict=on,menu=on").is_ok());
+        let mut cmd_parser = CmdParser::for_option("boot");
+        assert!(cmd_parser.parse("order=dc,splash=on").is_err());
+
+        let mut cmd_parser = CmdParser::for_option("qmp");
+        assert!(cmd_parser.parse("unix:/tmp/qmp.sock,server,nowait").is_ok());
+        assert_eq!(
+            cmd_parser.get_value::<String>("server").unwrap(),
+            Some("".to_string())
+        );
+
+        let mut cmd_parser = CmdParser::for_option("machine");
+        let ret = cmd_parser.parse("virt,gic-version=3");
+        assert_eq!(ret.is_ok(), cfg!(target_arch = "aarch64"));
+
+        let mut cmd_parser = CmdParser::for_option("nonexistent");
+        assert!(cmd_parser.parse("a=1").is_err());
+    }
+
+    #[test]
+    fn test_query_command_line_options() {
+        let cmd_lines = query_command_line_options(None).unwrap();
+        assert_eq!(
+            cmd_lines.len(),
+            OPTIONS.iter().filter(|spec| spec.compiled).count()
+        );
+
+        let cmd_lines = query_command_line_options(Some("m")).unwrap();
+        assert_eq!(cmd_lines.len(), 1);
+        assert!(!cmd_lines[0].multiple);
+        let params: Vec<&str> = cmd_lines[0]
+            .parameters
+            .iter()
+            .map(|param| param.name.as_str())
+            .collect();
+        assert_eq!(params, vec!["", "size"]);
+        assert_eq!(cmd_lines[0].parameters[1].param_type, "size");
+
+        let cmd_lines = query_command_line_options(Some("drive")).unwrap();
+        assert!(cmd_lines[0].multiple);
+        let if_param = &cmd_lines[0].parameters[0];
+        assert_eq!(if_param.default.as_deref(), Some("none"));
+        assert_eq!(if_param.values, vec!["none", "pflash"]);
+
+        assert!(query_command_line_options(Some("memory")).is_err());
+
+        let dump: Vec<CmdLine> = serde_json::from_str(&dump_options_json().unwrap()).unwrap();
+        assert_eq!(dump, query_command_line_options(None).unwrap());
+    }
+
+    #[test]
+    fn test_options_compiled() {
+        // Options and parameters which are not compiled in are neither parsed nor reported,
+        // whichever of the features or targets is built.
+        let machine = query_command_line_options(Some("machine")).unwrap();
+        assert_eq!(
+            machine[0]
+                .parameters
+                .iter()
+                .any(|param| param.name == "gic-version"),
+            cfg!(target_arch = "aarch64")
+        );
+        assert_eq!(
+            machine[0]
+                .parameters
+                .iter()
+                .any(|param| param.name == "its"),
+            cfg!(target_arch = "aarch64")
+        );
+
+        const PARAMS: &[ParamSpec] = &[
+            ParamSpec::new("", ParamType::String),
+            ParamSpec::new("disabled", ParamType::Bool).compiled(false),
+        ];
+        let options = [
+            OptionSpec {
+                name: "enabled",
+                long: Some("enabled"),
+                params: PARAMS,
+                ..OptionSpec::NONE
+            },
+            OptionSpec {
+                name: "disabled",
+                long: Some("disabled"),
+                compiled: false,
+                ..OptionSpec::NONE
+            },
+        ];
+        let cmd_lines = options_to_cmd_lines(&options, None).unwrap();
+        assert_eq!(cmd_lines.len(), 1);
+        assert_eq!(cmd_lines[0].option, "enabled");
+        assert_eq!(cmd_lines[0].parameters.len(), 1);
+        assert!(options_to_cmd_lines(&options, Some("disabled")).is_err());
+    }
+}
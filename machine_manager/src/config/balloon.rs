@@ -14,13 +14,20 @@ use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check, ConfigCheck, MAX_STRING_LENGTH};
-use crate::config::{CmdParser, ExBool, VmConfig};
+use crate::config::{memory_unit_conversion, CmdParser, ExBool, VmConfig};
+
+/// Max interval in seconds to poll the memory statistics of guest.
+const MAX_MONITOR_INTERVAL: u64 = 3600;
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BalloonConfig {
     pub id: String,
     pub deflate_on_oom: bool,
     pub free_page_reporting: bool,
+    /// Interval in seconds to poll the memory statistics of guest, 0 to disable.
+    pub monitor_interval: u64,
+    /// Minimum memory size in bytes the guest can be ballooned down to.
+    pub min_guest_memory: u64,
 }
 
 impl ConfigCheck for BalloonConfig {
@@ -31,6 +38,15 @@ impl ConfigCheck for BalloonConfig {
                 MAX_STRING_LENGTH,
             )));
         }
+        if self.monitor_interval > MAX_MONITOR_INTERVAL {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "balloon monitor-interval".to_string(),
+                0,
+                true,
+                MAX_MONITOR_INTERVAL,
+                true,
+            )));
+        }
 
         Ok(())
     }
@@ -48,7 +64,9 @@ pub fn parse_balloon(vm_config: &mut VmConfig, balloon_config: &str) -> Result<B
         .push("multifunction")
         .push("id")
         .push("deflate-on-oom")
-        .push("free-page-reporting");
+        .push("free-page-reporting")
+        .push("monitor-interval")
+        .push("min-guest-memory");
     cmd_parser.parse(balloon_config)?;
 
     pci_args_check(&cmd_parser)?;
@@ -59,10 +77,24 @@ pub fn parse_balloon(vm_config: &mut VmConfig, balloon_config: &str) -> Result<B
     if let Some(default) = cmd_parser.get_value::<ExBool>("free-page-reporting")? {
         balloon.free_page_reporting = default.into();
     }
+    if let Some(interval) = cmd_parser.get_value::<u64>("monitor-interval")? {
+        balloon.monitor_interval = interval;
+    }
+    if let Some(min_mem) = cmd_parser.get_value::<String>("min-guest-memory")? {
+        balloon.min_guest_memory = memory_unit_conversion(&min_mem)?;
+    }
     if let Some(id) = cmd_parser.get_value::<String>("id")? {
         balloon.id = id;
     }
     balloon.check()?;
+    let mem_size = vm_config.machine_config.mem_config.mem_size;
+    if balloon.min_guest_memory > mem_size {
+        bail!(
+            "Balloon min-guest-memory {} is larger than the guest memory size {}",
+            balloon.min_guest_memory,
+            mem_size
+        );
+    }
     vm_config.dev_name.insert("balloon".to_string(), 1);
     Ok(balloon)
 }
@@ -178,4 +210,64 @@ mod tests {
         );
         assert!(bln_cfg_res6.is_err());
     }
+
+    #[test]
+    fn test_monitor_interval_balloon_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        let bln_cfg = parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,deflate-on-oom=true,monitor-interval=5,id=balloon0",
+        )
+        .unwrap();
+        assert_eq!(bln_cfg.deflate_on_oom, true);
+        assert_eq!(bln_cfg.monitor_interval, 5);
+
+        let mut vm_config = VmConfig::default();
+        let bln_cfg = parse_balloon(&mut vm_config, "virtio-balloon-device,id=balloon0").unwrap();
+        assert_eq!(bln_cfg.monitor_interval, 0);
+
+        let mut vm_config = VmConfig::default();
+        assert!(parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,monitor-interval=3601,id=balloon0"
+        )
+        .is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,monitor-interval=-1,id=balloon0"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_min_guest_memory_balloon_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        vm_config.machine_config.mem_config.mem_size = 1024 * 1024 * 1024;
+        let bln_cfg = parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,min-guest-memory=256M,id=balloon0",
+        )
+        .unwrap();
+        assert_eq!(bln_cfg.min_guest_memory, 256 * 1024 * 1024);
+
+        let mut vm_config = VmConfig::default();
+        let bln_cfg = parse_balloon(&mut vm_config, "virtio-balloon-device,id=balloon0").unwrap();
+        assert_eq!(bln_cfg.min_guest_memory, 0);
+
+        // The minimum can't exceed the memory size of guest.
+        let mut vm_config = VmConfig::default();
+        vm_config.machine_config.mem_config.mem_size = 1024 * 1024 * 1024;
+        assert!(parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,min-guest-memory=2G,id=balloon0"
+        )
+        .is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(parse_balloon(
+            &mut vm_config,
+            "virtio-balloon-device,min-guest-memory=256K,id=balloon0"
+        )
+        .is_err());
+    }
 }
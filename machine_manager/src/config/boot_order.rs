@@ -0,0 +1,195 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use super::{BootIndexInfo, CmdParser, ExBool, VmConfig};
+
+/// Class of boot devices, selected by the legacy order letters of `-boot`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BootDeviceClass {
+    /// `c`: the first hard disk.
+    Disk,
+    /// `d`: the first CD-ROM.
+    Cdrom,
+    /// `n`: the first network device.
+    Network,
+}
+
+impl BootDeviceClass {
+    fn from_letter(letter: char) -> Option<Self> {
+        match letter {
+            'c' => Some(BootDeviceClass::Disk),
+            'd' => Some(BootDeviceClass::Cdrom),
+            'n' => Some(BootDeviceClass::Network),
+            _ => None,
+        }
+    }
+}
+
+/// Config struct for `-boot`.
+#[derive(Default, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BootOrderConfig {
+    /// Device classes in boot order.
+    pub order: Vec<BootDeviceClass>,
+    /// Do not fall back to devices out of the boot order list.
+    pub strict: bool,
+}
+
+impl BootOrderConfig {
+    /// Get the default boot index of the first device of `class`, for devices
+    /// without `bootindex`. Return None if the class is not in the boot order.
+    pub fn default_boot_index(&self, class: BootDeviceClass) -> Option<u8> {
+        self.order
+            .iter()
+            .position(|c| *c == class)
+            .map(|pos| pos as u8)
+    }
+}
+
+/// Sort boot devices by boot index. Explicit boot indexes are kept as they are. A
+/// default boot index derived from `-boot order` which conflicts with others is
+/// moved to the next free index.
+pub fn sort_boot_order(list: &[BootIndexInfo]) -> Vec<BootIndexInfo> {
+    let mut sorted: Vec<BootIndexInfo> = list.iter().filter(|i| !i.implicit).cloned().collect();
+    let mut implicit: Vec<&BootIndexInfo> = list.iter().filter(|i| i.implicit).collect();
+    implicit.sort_by_key(|i| i.boot_index);
+    for item in implicit {
+        let free =
+            (item.boot_index..=u8::MAX).find(|idx| sorted.iter().all(|i| i.boot_index != *idx));
+        match free {
+            Some(idx) => {
+                if idx != item.boot_index {
+                    warn!(
+                        "Boot index {} of device {} from boot order conflicts with bootindex, use {} instead",
+                        item.boot_index, item.id, idx
+                    );
+                }
+                sorted.push(BootIndexInfo {
+                    boot_index: idx,
+                    ..item.clone()
+                });
+            }
+            None => warn!("No free boot index for device {}", item.id),
+        }
+    }
+    sorted.sort_by_key(|i| i.boot_index);
+    sorted
+}
+
+impl VmConfig {
+    /// Add argument `boot` to `VmConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `boot` - The boot order config, e.g. `order=dc,strict=on`.
+    pub fn add_boot(&mut self, boot: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("boot");
+        cmd_parser.parse(boot)?;
+
+        let mut config = BootOrderConfig::default();
+        if let Some(order) = cmd_parser.get_value::<String>("order")? {
+            for letter in order.chars() {
+                let class = match BootDeviceClass::from_letter(letter) {
+                    Some(class) => class,
+                    None => bail!("Unsupported boot device '{}' in boot order", letter),
+                };
+                if config.order.contains(&class) {
+                    bail!("Boot device '{}' is duplicated in boot order", letter);
+                }
+                config.order.push(class);
+            }
+        }
+        if let Some(strict) = cmd_parser.get_value::<ExBool>("strict")? {
+            config.strict = strict.into();
+        }
+        self.boot_order = config;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boot_info(boot_index: u8, id: &str, implicit: bool) -> BootIndexInfo {
+        BootIndexInfo {
+            boot_index,
+            id: id.to_string(),
+            dev_path: format!("/{}", id),
+            implicit,
+        }
+    }
+
+    #[test]
+    fn test_add_boot() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_boot("order=dc,strict=on").is_ok());
+        assert_eq!(
+            vm_config.boot_order.order,
+            vec![BootDeviceClass::Cdrom, BootDeviceClass::Disk]
+        );
+        assert!(vm_config.boot_order.strict);
+        assert_eq!(
+            vm_config
+                .boot_order
+                .default_boot_index(BootDeviceClass::Disk),
+            Some(1)
+        );
+        assert_eq!(
+            vm_config
+                .boot_order
+                .default_boot_index(BootDeviceClass::Network),
+            None
+        );
+
+        assert!(vm_config.add_boot("order=n").is_ok());
+        assert_eq!(vm_config.boot_order.order, vec![BootDeviceClass::Network]);
+        assert!(!vm_config.boot_order.strict);
+        assert!(vm_config.add_boot("strict=off").is_ok());
+        assert!(vm_config.boot_order.order.is_empty());
+
+        assert!(vm_config.add_boot("order=a").is_err());
+        assert!(vm_config.add_boot("order=cdc").is_err());
+        assert!(vm_config.add_boot("order=c,strict=maybe").is_err());
+        assert!(vm_config.add_boot("menu=on,strict=on").is_ok());
+        assert!(vm_config.add_boot("once=c").is_err());
+    }
+
+    #[test]
+    fn test_sort_boot_order() {
+        // Explicit indexes are sorted as they are.
+        let list = vec![boot_info(2, "blk1", false), boot_info(0, "blk0", false)];
+        let sorted = sort_boot_order(&list);
+        assert_eq!(sorted[0].id, "blk0");
+        assert_eq!(sorted[1].id, "blk1");
+
+        // -boot order=dc, an explicit bootindex=0 wins the conflict with the cdrom.
+        let list = vec![
+            boot_info(1, "disk", true),
+            boot_info(0, "cdrom", true),
+            boot_info(0, "net", false),
+            boot_info(5, "blk", false),
+        ];
+        let sorted = sort_boot_order(&list);
+        let order: Vec<(u8, &str)> = sorted
+            .iter()
+            .map(|i| (i.boot_index, i.id.as_str()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![(0, "net"), (1, "cdrom"), (2, "disk"), (5, "blk")]
+        );
+    }
+}
@@ -14,9 +14,13 @@ use std::fmt;
 use std::path::PathBuf;
 
 use super::error::ConfigError;
-use crate::config::{ConfigCheck, VmConfig, MAX_PATH_LENGTH, MAX_STRING_LENGTH};
-use anyhow::{anyhow, Result};
+use crate::config::{CmdParser, ConfigCheck, VmConfig, MAX_PATH_LENGTH, MAX_STRING_LENGTH};
+use anyhow::{anyhow, bail, Result};
 use serde::{Deserialize, Serialize};
+use util::num_ops::str_to_usize;
+
+/// Load address of extra kernel images must be aligned to page.
+const EXTRA_KERNEL_ALIGN: u64 = 0x1000;
 
 /// Config struct for boot-source.
 /// Contains `kernel_file`, `kernel_cmdline` and `initrd`.
@@ -28,6 +32,8 @@ pub struct BootSource {
     pub kernel_cmdline: KernelParams,
     /// Config of initrd.
     pub initrd: Option<InitrdConfig>,
+    /// Config of the extra kernel image.
+    pub kernel2: Option<ExtraKernelConfig>,
 }
 
 impl BootSource {
@@ -57,6 +63,12 @@ impl ConfigCheck for BootSource {
         if self.initrd.is_some() {
             self.initrd.as_ref().unwrap().check()?;
         }
+        if let Some(kernel2) = &self.kernel2 {
+            if self.kernel_file.is_none() {
+                bail!("kernel2 requires a kernel to boot");
+            }
+            kernel2.check()?;
+        }
 
         Ok(())
     }
@@ -99,6 +111,42 @@ impl ConfigCheck for InitrdConfig {
     }
 }
 
+/// Config of an extra kernel image, which is loaded to a fixed guest address
+/// besides the boot kernel, e.g. as the target of kexec in tests.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ExtraKernelConfig {
+    /// Path of the kernel image.
+    pub kernel_file: PathBuf,
+    /// Guest physical address to load the image.
+    pub addr: u64,
+    /// Size of the loaded image, set by boot loader.
+    pub size: u64,
+}
+
+impl ConfigCheck for ExtraKernelConfig {
+    fn check(&self) -> Result<()> {
+        if self.kernel_file.to_str().unwrap().len() > MAX_PATH_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "kernel2 path".to_string(),
+                MAX_PATH_LENGTH,
+            )));
+        }
+        if !self.kernel_file.is_file() {
+            return Err(anyhow!(ConfigError::UnRegularFile(
+                "Input kernel2".to_string()
+            )));
+        }
+        if self.addr & (EXTRA_KERNEL_ALIGN - 1) != 0 {
+            return Err(anyhow!(ConfigError::Unaligned(
+                "kernel2 addr".to_string(),
+                self.addr,
+                EXTRA_KERNEL_ALIGN
+            )));
+        }
+        Ok(())
+    }
+}
+
 /// Struct `KernelParams` used to parse kernel cmdline to config.
 /// Contains a `Vec<Param>` and its `len()`.
 #[derive(Default, Clone, Debug, Serialize, Deserialize)]
@@ -243,6 +291,27 @@ impl VmConfig {
         self.boot_source.initrd = Some(InitrdConfig::new(initrd));
         Ok(())
     }
+
+    /// Add `-kernel2 kernel_path,addr=gpa` config to `VmConfig`
+    pub fn add_kernel2(&mut self, kernel2: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("kernel2");
+        cmd_parser.parse(kernel2)?;
+
+        let kernel_file = match cmd_parser.get_value::<String>("")? {
+            Some(path) => PathBuf::from(path),
+            None => return Err(anyhow!(ConfigError::FieldIsMissing("path", "kernel2"))),
+        };
+        let addr = match cmd_parser.get_value::<String>("addr")? {
+            Some(addr) => str_to_usize(addr)? as u64,
+            None => return Err(anyhow!(ConfigError::FieldIsMissing("addr", "kernel2"))),
+        };
+        self.boot_source.kernel2 = Some(ExtraKernelConfig {
+            kernel_file,
+            addr,
+            size: 0,
+        });
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -304,4 +373,42 @@ mod tests {
         std::fs::remove_file(&kernel_path).unwrap();
         std::fs::remove_file(&initrd_path).unwrap();
     }
+
+    #[test]
+    fn test_add_kernel2() {
+        let kernel_path = String::from("kernel2_test.bin");
+        let kernel2_path = String::from("kernel2_test_target.bin");
+        File::create(&kernel_path).unwrap();
+        File::create(&kernel2_path).unwrap();
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_kernel2(&format!("{},addr=0x2000000", kernel2_path))
+            .is_ok());
+        let kernel2 = vm_config.boot_source.kernel2.clone().unwrap();
+        assert_eq!(kernel2.kernel_file, PathBuf::from(&kernel2_path));
+        assert_eq!(kernel2.addr, 0x200_0000);
+        assert_eq!(kernel2.size, 0);
+        // The boot kernel is required.
+        assert!(vm_config.boot_source.check().is_err());
+        assert!(vm_config.add_kernel(&kernel_path).is_ok());
+        assert!(vm_config.boot_source.check().is_ok());
+
+        assert!(vm_config
+            .add_kernel2(&format!("{},addr=0x2000100", kernel2_path))
+            .is_ok());
+        assert!(vm_config.boot_source.check().is_err());
+        assert!(vm_config
+            .add_kernel2("kernel2_not_exist.bin,addr=0x2000000")
+            .is_ok());
+        assert!(vm_config.boot_source.check().is_err());
+        assert!(vm_config.add_kernel2(&kernel2_path).is_err());
+        assert!(vm_config.add_kernel2("addr=0x2000000").is_err());
+        assert!(vm_config
+            .add_kernel2(&format!("{},addr=2M", kernel2_path))
+            .is_err());
+
+        std::fs::remove_file(&kernel_path).unwrap();
+        std::fs::remove_file(&kernel2_path).unwrap();
+    }
 }
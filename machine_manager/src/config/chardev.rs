@@ -10,35 +10,88 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::{BTreeMap, HashMap};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
 use anyhow::{anyhow, bail, Context, Result};
-use log::error;
+use log::{error, info, warn};
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use util::loop_context::{
+    gen_delete_notifiers, EventNotifier, NotifierCallback, NotifierOperation,
+};
+use util::time::NANOSECONDS_PER_SECOND;
+use vmm_sys_util::epoll::EventSet;
 
 use super::{error::ConfigError, get_pci_bdf, pci_args_check, PciBdf};
 use crate::config::{CmdParser, ConfigCheck, ExBool, VmConfig, MAX_PATH_LENGTH, MAX_STRING_LENGTH};
+use crate::event_loop::EventLoop;
 use crate::qmp::qmp_schema;
 
 const MAX_GUEST_CID: u64 = 4_294_967_295;
 const MIN_GUEST_CID: u64 = 3;
 
+/// Default number of ports of virtio-serial-pci device.
+const DEFAULT_SERIAL_PORTS_NUMBER: u32 = 31;
+/// Max number of ports of virtio-serial-device. Each port takes two queues and the
+/// control port takes another two, while virtio-mmio supports 8 queues at most.
+const MAX_SERIAL_PORTS_MMIO: u32 = 3;
+
 /// Charecter device options.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum ChardevType {
+    Null,
     Stdio,
     Pty,
     Socket {
         path: String,
         server: bool,
         nowait: bool,
+        /// Interval in seconds to retry connecting in client mode, 0 means never retry.
+        reconnect: u64,
     },
     File(String),
 }
 
-/// Config structure for virtio-console.
+/// Config structure for virtconsole and virtserialport.
 #[derive(Debug, Clone)]
-pub struct VirtioConsole {
+pub struct VirtioSerialPort {
     pub id: String,
     pub chardev: ChardevConfig,
+    /// Port number on the virtio-serial bus.
+    pub nr: u32,
+    /// Port name exposed to guest, e.g. `org.qemu.guest_agent.0`.
+    pub name: Option<String>,
+    /// Whether the port is a console port(virtconsole).
+    pub is_console: bool,
+}
+
+impl ConfigCheck for VirtioSerialPort {
+    fn check(&self) -> Result<()> {
+        if self.id.len() > MAX_STRING_LENGTH {
+            return Err(anyhow!(ConfigError::StringLengthTooLong(
+                "virtio-serial port id".to_string(),
+                MAX_STRING_LENGTH,
+            )));
+        }
+        if let Some(name) = &self.name {
+            if name.len() > MAX_STRING_LENGTH {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "virtio-serial port name".to_string(),
+                    MAX_STRING_LENGTH,
+                )));
+            }
+        }
+        if self.nr == 0 && !self.is_console {
+            bail!("Port number 0 on virtio-serial devices reserved for virtconsole devices");
+        }
+
+        Ok(())
+    }
 }
 
 /// Config structure for character device.
@@ -73,61 +126,47 @@ impl ConfigCheck for ChardevConfig {
     }
 }
 
-fn check_chardev_args(cmd_parser: CmdParser) -> Result<()> {
+fn check_chardev_args(cmd_parser: &CmdParser) -> Result<()> {
     if let Some(chardev_type) = cmd_parser.get_value::<String>("")? {
         let chardev_str = chardev_type.as_str();
-        let server = cmd_parser.get_value::<String>("server")?;
-        let nowait = cmd_parser.get_value::<String>("nowait")?;
-        match chardev_str {
-            "stdio" | "pty" | "file" => {
-                if server.is_some() {
-                    bail!(
-                        "Chardev of {}-type does not support \'server\' argument",
-                        chardev_str
-                    );
-                }
-                if nowait.is_some() {
+        if matches!(chardev_str, "null" | "stdio" | "pty" | "file") {
+            for arg in ["server", "nowait", "wait", "reconnect"] {
+                if cmd_parser.get_value::<String>(arg)?.is_some() {
                     bail!(
-                        "Chardev of {}-type does not support \'nowait\' argument",
-                        chardev_str
+                        "Chardev of {}-type does not support \'{}\' argument",
+                        chardev_str,
+                        arg
                     );
                 }
             }
-            "socket" => {
-                if let Some(server) = server {
-                    if server.ne("") {
-                        bail!("No parameter needed for server");
-                    }
-                }
-                if let Some(nowait) = nowait {
-                    if nowait.ne("") {
-                        bail!("No parameter needed for nowait");
-                    }
-                }
-            }
-            _ => (),
         }
     }
     Ok(())
 }
 
+/// Get the value of a switch which is either a bare flag or `on|off`, e.g. `server` or `server=off`.
+fn get_switch_value(cmd_parser: &CmdParser, name: &str) -> Result<Option<bool>> {
+    match cmd_parser.get_value::<String>(name)? {
+        Some(value) if value.is_empty() => Ok(Some(true)),
+        Some(value) => match ExBool::from_str(&value) {
+            Ok(switch) => Ok(Some(switch.into())),
+            Err(_) => Err(anyhow!(ConfigError::InvalidParam(value, name.to_string()))),
+        },
+        None => Ok(None),
+    }
+}
+
 pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
     let chardev_id = if let Some(chardev_id) = cmd_parser.get_value::<String>("id")? {
         chardev_id
     } else {
         return Err(anyhow!(ConfigError::FieldIsMissing("id", "chardev")));
     };
+    check_chardev_args(&cmd_parser)?;
     let backend = cmd_parser.get_value::<String>("")?;
     let path = cmd_parser.get_value::<String>("path")?;
-    let server = if let Some(server) = cmd_parser.get_value::<String>("server")? {
-        if server.ne("") {
-            bail!("No parameter needed for server");
-        }
-        true
-    } else {
-        false
-    };
-    let nowait = if let Some(nowait) = cmd_parser.get_value::<String>("nowait")? {
+    let server = get_switch_value(&cmd_parser, "server")?.unwrap_or(false);
+    let mut nowait = if let Some(nowait) = cmd_parser.get_value::<String>("nowait")? {
         if nowait.ne("") {
             bail!("No parameter needed for nowait");
         }
@@ -135,9 +174,25 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
     } else {
         false
     };
-    check_chardev_args(cmd_parser)?;
+    if let Some(wait) = get_switch_value(&cmd_parser, "wait")? {
+        if nowait {
+            bail!(
+                "Argument \'wait\' conflicts with \'nowait\' for chardev {}",
+                chardev_id
+            );
+        }
+        nowait = !wait;
+    }
+    let reconnect = cmd_parser.get_value::<u64>("reconnect")?.unwrap_or(0);
+    if server && reconnect != 0 {
+        bail!(
+            "Argument \'reconnect\' is only supported by client-mode chardev, conflicts with \'server\' for chardev {}",
+            chardev_id
+        );
+    }
     let chardev_type = if let Some(backend) = backend {
         match backend.as_str() {
+            "null" => ChardevType::Null,
             "stdio" => ChardevType::Stdio,
             "pty" => ChardevType::Pty,
             "socket" => {
@@ -146,6 +201,7 @@ pub fn parse_chardev(cmd_parser: CmdParser) -> Result<ChardevConfig> {
                         path,
                         server,
                         nowait,
+                        reconnect,
                     }
                 } else {
                     return Err(anyhow!(ConfigError::FieldIsMissing(
@@ -195,8 +251,7 @@ pub fn get_chardev_config(args: qmp_schema::CharDevAddArgument) -> Result<Charde
         )));
     }
 
-    let data = backend.backend_data;
-    if data.server {
+    if backend.backend_data.server {
         error!("Not support chardev socket as server now.");
         return Err(anyhow!(ConfigError::InvalidParam(
             "backend".to_string(),
@@ -204,38 +259,60 @@ pub fn get_chardev_config(args: qmp_schema::CharDevAddArgument) -> Result<Charde
         )));
     }
 
-    let addr = data.addr;
-    if addr.addr_type.as_str() != "unix" {
-        error!("Just support \"unix\" addr type option now.");
-        return Err(anyhow!(ConfigError::InvalidParam(
-            "backend".to_string(),
-            "addr".to_string()
-        )));
-    }
-
     Ok(ChardevConfig {
         id: args.id,
-        backend: ChardevType::Socket {
-            path: addr.addr_data.path,
-            server: data.server,
-            nowait: false,
-        },
+        backend: get_chardev_backend(backend)?,
     })
 }
 
-/// Get chardev socket path from ChardevConfig struct.
+/// Get chardev backend from qmp backend options, the server-mode socket doesn't
+/// wait for the connection.
 ///
 /// # Arguments
 ///
-/// * `char_dev` - ChardevConfig struct reference.
+/// * `backend` - The qmp backend options.
+pub fn get_chardev_backend(backend: qmp_schema::BackendOptions) -> Result<ChardevType> {
+    match backend.backend_type.as_str() {
+        "null" => Ok(ChardevType::Null),
+        "socket" => {
+            let data = backend.backend_data;
+            let addr = data.addr;
+            if addr.addr_type.as_str() != "unix" {
+                error!("Just support \"unix\" addr type option now.");
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "backend".to_string(),
+                    "addr".to_string()
+                )));
+            }
+            Ok(ChardevType::Socket {
+                path: addr.addr_data.path,
+                server: data.server,
+                nowait: data.server,
+                reconnect: 0,
+            })
+        }
+        _ => Err(anyhow!(ConfigError::InvalidParam(
+            "backend".to_string(),
+            backend.backend_type
+        ))),
+    }
+}
+
+/// Get the socket path and the reconnect interval of a client-mode socket chardev,
+/// the chardev is taken from `vm_config` as it can only be used by one device.
+///
+/// # Arguments
+///
+/// * `chardev` - Id of the chardev.
 /// * `vm_config` - mutable VmConfig struct reference.
-pub fn get_chardev_socket_path(chardev: &str, vm_config: &mut VmConfig) -> Result<String> {
+pub fn get_chardev_socket(chardev: &str, vm_config: &mut VmConfig) -> Result<(String, u64)> {
     if let Some(char_dev) = vm_config.chardev.remove(chardev) {
         match char_dev.backend.clone() {
             ChardevType::Socket {
                 path,
                 server,
                 nowait,
+                reconnect,
             } => {
                 if server || nowait {
                     bail!(
@@ -243,7 +320,7 @@ pub fn get_chardev_socket_path(chardev: &str, vm_config: &mut VmConfig) -> Resul
                         path
                     );
                 }
-                Ok(path)
+                Ok((path, reconnect))
             }
             _ => {
                 bail!("Chardev {:?} backend should be socket type.", &char_dev.id);
@@ -254,31 +331,301 @@ pub fn get_chardev_socket_path(chardev: &str, vm_config: &mut VmConfig) -> Resul
     }
 }
 
-pub fn parse_virtconsole(vm_config: &mut VmConfig, config_args: &str) -> Result<VirtioConsole> {
-    let mut cmd_parser = CmdParser::new("virtconsole");
-    cmd_parser.push("").push("id").push("chardev");
+/// Get chardev socket path from ChardevConfig struct.
+///
+/// # Arguments
+///
+/// * `char_dev` - ChardevConfig struct reference.
+/// * `vm_config` - mutable VmConfig struct reference.
+pub fn get_chardev_socket_path(chardev: &str, vm_config: &mut VmConfig) -> Result<String> {
+    get_chardev_socket(chardev, vm_config).map(|(path, _)| path)
+}
+
+/// Connection state change of a client-mode socket chardev.
+pub enum ChardevEvent {
+    /// Connected to the peer, carries a stream for the device to talk with the peer.
+    Connected(UnixStream),
+    /// The peer hung up.
+    Disconnected,
+}
+
+/// Callback of a device on connection state changes of its chardev.
+pub type ChardevNotifier = Arc<dyn Fn(ChardevEvent) + Send + Sync>;
+
+/// Client-mode socket chardevs in use, keyed by chardev id.
+static SOCKET_CHARDEVS: Lazy<Mutex<HashMap<String, Arc<Mutex<SocketChardev>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Runtime state of a client-mode socket chardev, which is shared by the devices using it.
+pub struct SocketChardev {
+    /// Id of the chardev.
+    pub id: String,
+    /// Path of the unix socket to connect to.
+    pub path: String,
+    /// Interval in seconds to retry connecting, 0 means never retry.
+    pub reconnect: u64,
+    /// Stream of the latest connection, watched for hang up.
+    stream: Option<UnixStream>,
+    /// Whether the peer is connected.
+    connected: bool,
+    /// Callbacks of the devices using the chardev, keyed by device id.
+    notifiers: BTreeMap<String, ChardevNotifier>,
+    /// Whether a connecting attempt has been scheduled in the main loop.
+    reconnect_scheduled: bool,
+    /// The chardev has been unregistered and never reconnects.
+    removed: bool,
+}
+
+impl SocketChardev {
+    fn new(id: &str, path: &str, reconnect: u64) -> Self {
+        SocketChardev {
+            id: id.to_string(),
+            path: path.to_string(),
+            reconnect,
+            stream: None,
+            connected: false,
+            notifiers: BTreeMap::new(),
+            reconnect_scheduled: false,
+            removed: false,
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Get a stream of the current connection for a device to talk with the peer.
+    pub fn get_stream(&self) -> Result<UnixStream> {
+        match &self.stream {
+            Some(stream) if self.connected => stream
+                .try_clone()
+                .with_context(|| format!("Failed to clone stream of chardev {}", self.id)),
+            _ => bail!("Chardev {} is not connected", self.id),
+        }
+    }
+
+    /// Add the callback of device `dev_id`, which is called on later connection state changes.
+    pub fn add_notifier(&mut self, dev_id: &str, notifier: ChardevNotifier) {
+        self.notifiers.insert(dev_id.to_string(), notifier);
+    }
+
+    /// Delete the callback of device `dev_id`.
+    pub fn del_notifier(&mut self, dev_id: &str) {
+        self.notifiers.remove(dev_id);
+    }
+
+    /// Connect to the peer. If it fails, retry every `reconnect` seconds in the main loop
+    /// until the peer appears.
+    pub fn connect(chardev: &Arc<Mutex<Self>>) -> Result<()> {
+        if let Err(e) = Self::try_connect(chardev) {
+            Self::schedule_reconnect(chardev);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn try_connect(chardev: &Arc<Mutex<Self>>) -> Result<()> {
+        let mut locked_chardev = chardev.lock().unwrap();
+        if locked_chardev.connected || locked_chardev.removed {
+            return Ok(());
+        }
+        let stream = UnixStream::connect(&locked_chardev.path).with_context(|| {
+            format!(
+                "Failed to connect socket {} of chardev {}",
+                locked_chardev.path, locked_chardev.id
+            )
+        })?;
+        let mut callbacks = Vec::new();
+        for notifier in locked_chardev.notifiers.values() {
+            let cloned_stream = stream.try_clone().with_context(|| {
+                format!("Failed to clone stream of chardev {}", locked_chardev.id)
+            })?;
+            callbacks.push((notifier.clone(), cloned_stream));
+        }
+        let stream_fd = stream.as_raw_fd();
+        // The old stream is dropped here, it has been removed from the main loop on hang up.
+        locked_chardev.stream = Some(stream);
+        locked_chardev.connected = true;
+        info!(
+            "Chardev {} is connected to {}",
+            locked_chardev.id, locked_chardev.path
+        );
+        drop(locked_chardev);
+
+        EventLoop::update_event(vec![hang_up_notifier(chardev, stream_fd)], None)?;
+        for (notifier, stream) in callbacks {
+            notifier(ChardevEvent::Connected(stream));
+        }
+        Ok(())
+    }
+
+    fn schedule_reconnect(chardev: &Arc<Mutex<Self>>) {
+        let mut locked_chardev = chardev.lock().unwrap();
+        if locked_chardev.reconnect == 0
+            || locked_chardev.reconnect_scheduled
+            || locked_chardev.removed
+        {
+            return;
+        }
+        locked_chardev.reconnect_scheduled = true;
+        let delay = locked_chardev
+            .reconnect
+            .saturating_mul(NANOSECONDS_PER_SECOND);
+        drop(locked_chardev);
+
+        let cloned_chardev = chardev.clone();
+        let func = Box::new(move || {
+            cloned_chardev.lock().unwrap().reconnect_scheduled = false;
+            if SocketChardev::try_connect(&cloned_chardev).is_err() {
+                SocketChardev::schedule_reconnect(&cloned_chardev);
+            }
+        });
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            ctx.delay_call(func, delay);
+        } else {
+            error!("Failed to get ctx to delay chardev reconnecting");
+        }
+    }
+
+    fn handle_hang_up(chardev: &Arc<Mutex<Self>>) {
+        let mut locked_chardev = chardev.lock().unwrap();
+        if !locked_chardev.connected {
+            return;
+        }
+        locked_chardev.connected = false;
+        warn!(
+            "Chardev {} is disconnected from {}",
+            locked_chardev.id, locked_chardev.path
+        );
+        let notifiers: Vec<ChardevNotifier> = locked_chardev.notifiers.values().cloned().collect();
+        drop(locked_chardev);
+
+        for notifier in notifiers {
+            notifier(ChardevEvent::Disconnected);
+        }
+        Self::schedule_reconnect(chardev);
+    }
+}
+
+fn hang_up_notifier(chardev: &Arc<Mutex<SocketChardev>>, stream_fd: RawFd) -> EventNotifier {
+    let cloned_chardev = chardev.clone();
+    let handler: Rc<NotifierCallback> = Rc::new(move |event, fd| {
+        if event & EventSet::HANG_UP == EventSet::HANG_UP {
+            SocketChardev::handle_hang_up(&cloned_chardev);
+            return Some(gen_delete_notifiers(&[fd]));
+        }
+        None
+    });
+    EventNotifier::new(
+        NotifierOperation::AddShared,
+        stream_fd,
+        None,
+        EventSet::HANG_UP,
+        vec![handler],
+    )
+}
+
+/// Register a client-mode socket chardev so that it can be connected and shared by devices.
+///
+/// # Arguments
+///
+/// * `id` - Id of the chardev.
+/// * `path` - Path of the unix socket to connect to.
+/// * `reconnect` - Interval in seconds to retry connecting, 0 means never retry.
+pub fn register_socket_chardev(
+    id: &str,
+    path: &str,
+    reconnect: u64,
+) -> Result<Arc<Mutex<SocketChardev>>> {
+    let mut chardevs = SOCKET_CHARDEVS.lock().unwrap();
+    if chardevs.contains_key(id) {
+        bail!("Socket chardev {} has been registered", id);
+    }
+    let chardev = Arc::new(Mutex::new(SocketChardev::new(id, path, reconnect)));
+    chardevs.insert(id.to_string(), chardev.clone());
+    Ok(chardev)
+}
+
+/// Unregister the socket chardev, disconnect it and stop reconnecting.
+pub fn unregister_socket_chardev(id: &str) {
+    let chardev = match SOCKET_CHARDEVS.lock().unwrap().remove(id) {
+        Some(chardev) => chardev,
+        None => return,
+    };
+    let mut locked_chardev = chardev.lock().unwrap();
+    locked_chardev.removed = true;
+    locked_chardev.notifiers.clear();
+    if locked_chardev.connected {
+        let stream_fd = locked_chardev.stream.as_ref().unwrap().as_raw_fd();
+        if let Err(e) = EventLoop::update_event(gen_delete_notifiers(&[stream_fd]), None) {
+            error!("Failed to delete event of chardev {}: {:?}", id, e);
+        }
+        locked_chardev.connected = false;
+    }
+    locked_chardev.stream = None;
+}
+
+/// Get the registered socket chardev by id.
+pub fn get_socket_chardev(id: &str) -> Option<Arc<Mutex<SocketChardev>>> {
+    SOCKET_CHARDEVS.lock().unwrap().get(id).cloned()
+}
+
+/// Parse the config of virtconsole or virtserialport.
+///
+/// # Arguments
+///
+/// * `vm_config` - VM configuration.
+/// * `config_args` - Port configuration args.
+/// * `free_nr` - Port number used if `nr` is not set by user.
+pub fn parse_virtserialport(
+    vm_config: &mut VmConfig,
+    config_args: &str,
+    free_nr: u32,
+) -> Result<VirtioSerialPort> {
+    let mut cmd_parser = CmdParser::new("virtserialport");
+    cmd_parser
+        .push("")
+        .push("id")
+        .push("chardev")
+        .push("nr")
+        .push("name");
     cmd_parser.parse(config_args)?;
 
+    let is_console = matches!(
+        cmd_parser.get_value::<String>("")?.as_deref(),
+        Some("virtconsole")
+    );
+    let dev_name = if is_console {
+        "virtconsole"
+    } else {
+        "virtserialport"
+    };
+
     let chardev_name = if let Some(chardev) = cmd_parser.get_value::<String>("chardev")? {
         chardev
     } else {
-        return Err(anyhow!(ConfigError::FieldIsMissing(
-            "chardev",
-            "virtconsole"
-        )));
+        return Err(anyhow!(ConfigError::FieldIsMissing("chardev", dev_name)));
     };
 
     let id = if let Some(chardev_id) = cmd_parser.get_value::<String>("id")? {
         chardev_id
     } else {
-        return Err(anyhow!(ConfigError::FieldIsMissing("id", "virtconsole")));
+        return Err(anyhow!(ConfigError::FieldIsMissing("id", dev_name)));
     };
 
+    let nr = cmd_parser.get_value::<u32>("nr")?.unwrap_or(free_nr);
+    let name = cmd_parser.get_value::<String>("name")?;
+
     if let Some(char_dev) = vm_config.chardev.remove(&chardev_name) {
-        return Ok(VirtioConsole {
+        let port = VirtioSerialPort {
             id,
             chardev: char_dev,
-        });
+            nr,
+            name,
+            is_console,
+        };
+        port.check()?;
+        return Ok(port);
     }
     bail!("Chardev {:?} not found or is in use", &chardev_name);
 }
@@ -286,14 +633,7 @@ pub fn parse_virtconsole(vm_config: &mut VmConfig, config_args: &str) -> Result<
 impl VmConfig {
     /// Add chardev config to `VmConfig`.
     pub fn add_chardev(&mut self, chardev_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("chardev");
-        cmd_parser
-            .push("")
-            .push("id")
-            .push("path")
-            .push("server")
-            .push("nowait");
-
+        let mut cmd_parser = CmdParser::for_option("chardev");
         cmd_parser.parse(chardev_config)?;
 
         let chardev = parse_chardev(cmd_parser)?;
@@ -437,14 +777,39 @@ pub fn parse_vsock(vsock_config: &str) -> Result<VsockConfig> {
         guest_cid,
         vhost_fd,
     };
+    vsock.check()?;
     Ok(vsock)
 }
 
+impl VmConfig {
+    /// Get the configs of all vsock devices, the guest-cid of each device must be unique.
+    pub fn get_vsocks(&self) -> Result<Vec<VsockConfig>> {
+        let mut vsocks: Vec<VsockConfig> = Vec::new();
+        for (dev_type, dev_cfg) in self.devices.iter() {
+            if dev_type != "vhost-vsock-pci" && dev_type != "vhost-vsock-device" {
+                continue;
+            }
+            let vsock = parse_vsock(dev_cfg)?;
+            if let Some(used) = vsocks.iter().find(|v| v.guest_cid == vsock.guest_cid) {
+                return Err(anyhow!(ConfigError::GuestCidRepeat(
+                    vsock.guest_cid,
+                    used.id.clone(),
+                    vsock.id
+                )));
+            }
+            vsocks.push(vsock);
+        }
+        Ok(vsocks)
+    }
+}
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct VirtioSerialInfo {
     pub id: String,
     pub pci_bdf: Option<PciBdf>,
     pub multifunction: bool,
+    /// Max number of ports, including port 0.
+    pub max_ports: u32,
 }
 
 impl ConfigCheck for VirtioSerialInfo {
@@ -456,6 +821,21 @@ impl ConfigCheck for VirtioSerialInfo {
             )));
         }
 
+        let max_ports_limit = if self.pci_bdf.is_some() {
+            DEFAULT_SERIAL_PORTS_NUMBER
+        } else {
+            MAX_SERIAL_PORTS_MMIO
+        };
+        if self.max_ports < 1 || self.max_ports > max_ports_limit {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "virtio-serial max_ports".to_string(),
+                1,
+                true,
+                max_ports_limit as u64,
+                true,
+            )));
+        }
+
         Ok(())
     }
 }
@@ -467,7 +847,8 @@ pub fn parse_virtio_serial(vm_config: &mut VmConfig, serial_config: &str) -> Res
         .push("id")
         .push("bus")
         .push("addr")
-        .push("multifunction");
+        .push("multifunction")
+        .push("max_ports");
     cmd_parser.parse(serial_config)?;
     pci_args_check(&cmd_parser)?;
 
@@ -488,12 +869,18 @@ pub fn parse_virtio_serial(vm_config: &mut VmConfig, serial_config: &str) -> Res
                 id,
                 pci_bdf: Some(pci_bdf),
                 multifunction,
+                max_ports: cmd_parser
+                    .get_value::<u32>("max_ports")?
+                    .unwrap_or(DEFAULT_SERIAL_PORTS_NUMBER),
             }
         } else {
             VirtioSerialInfo {
                 id,
                 pci_bdf: None,
                 multifunction,
+                max_ports: cmd_parser
+                    .get_value::<u32>("max_ports")?
+                    .unwrap_or(MAX_SERIAL_PORTS_MMIO),
             }
         };
         virtio_serial.check()?;
@@ -507,6 +894,8 @@ pub fn parse_virtio_serial(vm_config: &mut VmConfig, serial_config: &str) -> Res
 
 #[cfg(test)]
 mod tests {
+    use std::os::unix::net::UnixListener;
+
     use super::*;
     use crate::config::parse_virtio_serial;
 
@@ -517,19 +906,24 @@ mod tests {
         assert!(vm_config
             .add_chardev("socket,id=test_console,path=/path/to/socket,server,nowait")
             .is_ok());
-        let virt_console = parse_virtconsole(
+        let virt_console = parse_virtserialport(
             &mut vm_config,
             "virtconsole,chardev=test_console,id=console1",
+            0,
         );
         assert!(virt_console.is_ok());
         let console_cfg = virt_console.unwrap();
         assert_eq!(console_cfg.id, "console1");
+        assert_eq!(console_cfg.nr, 0);
+        assert!(console_cfg.is_console);
+        assert_eq!(vm_config.virtio_serial.as_ref().unwrap().max_ports, 3);
         assert_eq!(
             console_cfg.chardev.backend,
             ChardevType::Socket {
                 path: "/path/to/socket".to_string(),
                 server: true,
                 nowait: true,
+                reconnect: 0,
             }
         );
 
@@ -547,9 +941,10 @@ mod tests {
         assert!(vm_config
             .add_chardev("socket,id=test_console,path=/path/to/socket,server,nowait")
             .is_ok());
-        let virt_console = parse_virtconsole(
+        let virt_console = parse_virtserialport(
             &mut vm_config,
             "virtconsole,chardev=test_console1,id=console1",
+            0,
         );
         // test_console1 does not exist.
         assert!(virt_console.is_err());
@@ -565,9 +960,10 @@ mod tests {
         assert!(vm_config
             .add_chardev("socket,id=test_console,path=/path/to/socket,server,nowait")
             .is_ok());
-        let virt_console = parse_virtconsole(
+        let virt_console = parse_virtserialport(
             &mut vm_config,
             "virtconsole,chardev=test_console,id=console1",
+            0,
         );
         assert!(virt_console.is_ok());
         let console_cfg = virt_console.unwrap();
@@ -584,6 +980,7 @@ mod tests {
                 path: "/path/to/socket".to_string(),
                 server: true,
                 nowait: true,
+                reconnect: 0,
             }
         );
 
@@ -593,6 +990,54 @@ mod tests {
             "virtio-serial-pci,bus=pcie.0,addr=0x1.0x2,multifunction=on"
         )
         .is_ok());
+
+        // The number of ports is limited by the number of queues of virtio-mmio.
+        let mut vm_config = VmConfig::default();
+        assert!(parse_virtio_serial(&mut vm_config, "virtio-serial-device,max_ports=4").is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(parse_virtio_serial(
+            &mut vm_config,
+            "virtio-serial-pci,bus=pcie.0,addr=0x1,max_ports=0"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_serial_port_config_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(parse_virtio_serial(
+            &mut vm_config,
+            "virtio-serial-pci,id=serial0,bus=pcie.0,addr=0x1,max_ports=8"
+        )
+        .is_ok());
+        assert_eq!(vm_config.virtio_serial.as_ref().unwrap().max_ports, 8);
+        for id in ["qga", "port0", "port1"] {
+            let chardev = format!("socket,id={},path=/path/to/{},server,nowait", id, id);
+            assert!(vm_config.add_chardev(&chardev).is_ok());
+        }
+
+        let port = parse_virtserialport(
+            &mut vm_config,
+            "virtserialport,chardev=qga,id=channel0,name=org.qemu.guest_agent.0",
+            1,
+        )
+        .unwrap();
+        assert_eq!(port.id, "channel0");
+        assert_eq!(port.nr, 1);
+        assert_eq!(port.name, Some("org.qemu.guest_agent.0".to_string()));
+        assert!(!port.is_console);
+
+        let port =
+            parse_virtserialport(&mut vm_config, "virtserialport,chardev=port1,id=p1,nr=5", 2)
+                .unwrap();
+        assert_eq!(port.nr, 5);
+        assert_eq!(port.name, None);
+
+        // Port 0 is reserved for virtconsole.
+        assert!(
+            parse_virtserialport(&mut vm_config, "virtserialport,chardev=port0,id=p0,nr=0", 2)
+                .is_err()
+        );
     }
 
     #[test]
@@ -614,6 +1059,35 @@ mod tests {
         assert_eq!(vsock_config.guest_cid, 3);
         assert_eq!(vsock_config.vhost_fd, Some(4));
         assert!(vsock_config.check().is_ok());
+
+        // Guest-cid 0 ~ 2 are reserved.
+        assert!(parse_vsock("vhost-vsock-device,id=test_vsock,guest-cid=2").is_err());
+        assert!(parse_vsock("vhost-vsock-device,id=test_vsock,guest-cid=0").is_err());
+        assert!(parse_vsock("vhost-vsock-device,id=test_vsock,guest-cid=4294967295").is_err());
+    }
+
+    #[test]
+    fn test_vsock_guest_cid_unique() {
+        let mut vm_config = VmConfig::default();
+        vm_config
+            .add_device("vhost-vsock-pci,id=vsock0,guest-cid=3,bus=pcie.0,addr=0x3")
+            .unwrap();
+        vm_config
+            .add_device("vhost-vsock-pci,id=vsock1,guest-cid=4,bus=pcie.0,addr=0x4")
+            .unwrap();
+        let vsocks = vm_config.get_vsocks().unwrap();
+        assert_eq!(vsocks.len(), 2);
+        assert_eq!(vsocks[1].id, "vsock1");
+        assert_eq!(vsocks[1].guest_cid, 4);
+
+        vm_config
+            .add_device("vhost-vsock-pci,id=vsock2,guest-cid=3,bus=pcie.0,addr=0x5")
+            .unwrap();
+        let err = vm_config.get_vsocks().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Vsock guest-cid 3 of vsock2 is already used by vsock0."
+        );
     }
 
     #[test]
@@ -632,10 +1106,120 @@ mod tests {
                     path: "/path/to/socket".to_string(),
                     server: false,
                     nowait: false,
+                    reconnect: 0,
                 }
             );
         } else {
             assert!(false);
         }
+
+        assert!(vm_config.add_chardev("null,id=null_id").is_ok());
+        assert_eq!(
+            vm_config.chardev.get("null_id").unwrap().backend,
+            ChardevType::Null
+        );
+        assert!(vm_config.add_chardev("null,id=null_id2,server").is_err());
+    }
+
+    #[test]
+    fn test_chardev_socket_mode_cmdline_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_chardev("socket,id=chardev0,path=/path/to/socket,server=on,wait=off")
+            .is_ok());
+        assert!(vm_config
+            .add_chardev("socket,id=chardev1,path=/path/to/socket,server=off,reconnect=5")
+            .is_ok());
+        assert_eq!(
+            vm_config.chardev.get("chardev0").unwrap().backend,
+            ChardevType::Socket {
+                path: "/path/to/socket".to_string(),
+                server: true,
+                nowait: true,
+                reconnect: 0,
+            }
+        );
+        assert_eq!(
+            vm_config.chardev.get("chardev1").unwrap().backend,
+            ChardevType::Socket {
+                path: "/path/to/socket".to_string(),
+                server: false,
+                nowait: false,
+                reconnect: 5,
+            }
+        );
+        assert_eq!(
+            get_chardev_socket("chardev1", &mut vm_config).unwrap(),
+            ("/path/to/socket".to_string(), 5)
+        );
+
+        // A server never reconnects.
+        assert!(vm_config
+            .add_chardev("socket,id=chardev2,path=/path/to/socket,server,nowait,reconnect=5")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=chardev2,path=/path/to/socket,server=on,reconnect=1")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=chardev2,path=/path/to/socket,server=maybe")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=chardev2,path=/path/to/socket,server,nowait,wait=off")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("pty,id=chardev2,reconnect=5")
+            .is_err());
+        assert!(vm_config.add_chardev("stdio,id=chardev2,wait=on").is_err());
+    }
+
+    #[test]
+    fn test_socket_chardev_reconnect() {
+        EventLoop::object_init(&None).unwrap();
+        let path = format!("/tmp/test_chardev_reconnect_{}.sock", std::process::id());
+        let _ = std::fs::remove_file(&path);
+        let chardev = register_socket_chardev("chardev_reconnect", &path, 1).unwrap();
+        assert!(register_socket_chardev("chardev_reconnect", &path, 1).is_err());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let cloned_events = events.clone();
+        chardev.lock().unwrap().add_notifier(
+            "dev0",
+            Arc::new(move |event| {
+                let connected = matches!(event, ChardevEvent::Connected(_));
+                cloned_events.lock().unwrap().push(connected);
+            }),
+        );
+
+        // The backend is not started yet.
+        assert!(SocketChardev::connect(&chardev).is_err());
+        assert!(!chardev.lock().unwrap().is_connected());
+        assert!(chardev.lock().unwrap().get_stream().is_err());
+        assert!(events.lock().unwrap().is_empty());
+
+        let run_until = |connected: bool| {
+            let ctx = EventLoop::get_ctx(None).unwrap();
+            let start = std::time::Instant::now();
+            while chardev.lock().unwrap().is_connected() != connected {
+                assert!(start.elapsed().as_secs() < 10);
+                ctx.run().unwrap();
+            }
+        };
+
+        // The backend appears after VM start, the timer reconnects to it.
+        let listener = UnixListener::bind(&path).unwrap();
+        run_until(true);
+        assert_eq!(*events.lock().unwrap(), vec![true]);
+        assert!(chardev.lock().unwrap().get_stream().is_ok());
+
+        // The backend restarts.
+        let (peer, _) = listener.accept().unwrap();
+        drop(peer);
+        run_until(false);
+        assert_eq!(*events.lock().unwrap(), vec![true, false]);
+        run_until(true);
+        assert_eq!(*events.lock().unwrap(), vec![true, false, true]);
+
+        unregister_socket_chardev("chardev_reconnect");
+        assert!(get_socket_chardev("chardev_reconnect").is_none());
+        std::fs::remove_file(&path).unwrap();
     }
 }
@@ -0,0 +1,77 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{CmdParser, VmConfig};
+
+/// How to handle deprecated QMP commands and arguments.
+#[derive(Default, PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum QmpCompatPolicy {
+    /// Accept them and append a warning to the response.
+    #[default]
+    Warn,
+    /// Reject them with an error.
+    Strict,
+}
+
+impl FromStr for QmpCompatPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(QmpCompatPolicy::Warn),
+            "strict" => Ok(QmpCompatPolicy::Strict),
+            _ => Err(()),
+        }
+    }
+}
+
+impl VmConfig {
+    /// Add argument `compat` to `VmConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `compat` - The compat policy, e.g. `qmp=strict`.
+    pub fn add_compat(&mut self, compat: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("compat");
+        cmd_parser.parse(compat)?;
+
+        match cmd_parser.get_value::<QmpCompatPolicy>("qmp")? {
+            Some(policy) => self.qmp_compat = policy,
+            None => bail!("No policy is specified for compat"),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_compat() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.qmp_compat, QmpCompatPolicy::Warn);
+        assert!(vm_config.add_compat("qmp=strict").is_ok());
+        assert_eq!(vm_config.qmp_compat, QmpCompatPolicy::Strict);
+        assert!(vm_config.add_compat("qmp=warn").is_ok());
+        assert_eq!(vm_config.qmp_compat, QmpCompatPolicy::Warn);
+
+        assert!(vm_config.add_compat("qmp=error").is_err());
+        assert!(vm_config.add_compat("qmp").is_err());
+        assert!(vm_config.add_compat("qga=strict").is_err());
+    }
+}
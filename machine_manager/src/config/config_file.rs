@@ -0,0 +1,374 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Json config file of the VM given by `-config`.
+//!
+//! The file describes `VmConfig` directly, e.g. the output of `-dump-config`. The fields of
+//! the structs can be omitted to be default, while the entries of the maps, e.g. a drive,
+//! and the optional structs are given completely.
+//!
+//! The options given on the command line take precedence over the file: the structs are
+//! merged field by field, and any other value set by the command line replaces the one of
+//! the file, except the lists in `APPENDED_LISTS` which the command line appends to.
+
+use std::fs;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+
+use super::{ConfigCheck, VmConfig};
+
+/// Lists of `VmConfig` which the entries given on the command line are appended to.
+const APPENDED_LISTS: &[&str] = &["/devices", "/numa_nodes", "/iothreads", "/pflashs"];
+
+/// Escape a reference token of json pointer, see RFC 6901.
+fn pointer_token(token: &str) -> String {
+    token.replace('~', "~0").replace('/', "~1")
+}
+
+enum Frame {
+    /// The key of the current member, and whether a key is expected next.
+    Object(Option<String>, bool),
+    /// The index of the current element.
+    Array(usize),
+}
+
+/// Get the json pointer of the value at `line` and `column` of `text`, which is the
+/// position of an error reported by serde_json.
+fn json_pointer_at(text: &str, line: usize, column: usize) -> String {
+    let end = text
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.len())
+        .sum::<usize>()
+        + column;
+    let bytes = text.as_bytes();
+    let mut frames = Vec::new();
+    let mut pos = 0;
+    while pos < end.min(bytes.len()) {
+        match bytes[pos] {
+            b'{' => frames.push(Frame::Object(None, true)),
+            b'[' => frames.push(Frame::Array(0)),
+            b'}' | b']' => {
+                frames.pop();
+            }
+            b':' => {
+                if let Some(Frame::Object(_, expect_key)) = frames.last_mut() {
+                    *expect_key = false;
+                }
+            }
+            b',' => match frames.last_mut() {
+                Some(Frame::Object(key, expect_key)) => {
+                    *key = None;
+                    *expect_key = true;
+                }
+                Some(Frame::Array(index)) => *index += 1,
+                None => {}
+            },
+            b'"' => {
+                let start = pos;
+                pos += 1;
+                while pos < bytes.len() && bytes[pos] != b'"' {
+                    if bytes[pos] == b'\\' {
+                        pos += 1;
+                    }
+                    pos += 1;
+                }
+                if let Some(Frame::Object(key, true)) = frames.last_mut() {
+                    *key = serde_json::from_str(text.get(start..=pos).unwrap_or_default()).ok();
+                }
+            }
+            _ => {}
+        }
+        pos += 1;
+    }
+
+    frames
+        .iter()
+        .filter_map(|frame| match frame {
+            Frame::Object(key, _) => key.as_ref().map(|key| format!("/{}", pointer_token(key))),
+            Frame::Array(index) => Some(format!("/{}", index)),
+        })
+        .collect()
+}
+
+/// Check that all the fields of the file are known, `known` is the value of the config
+/// parsed from the file, which has all the fields of `VmConfig`.
+fn check_known_fields(value: &Value, known: &Value, pointer: &str) -> Result<()> {
+    match (value, known) {
+        (Value::Object(map), Value::Object(known_map)) => {
+            for (key, value) in map {
+                let pointer = format!("{}/{}", pointer, pointer_token(key));
+                match known_map.get(key) {
+                    Some(known) => check_known_fields(value, known, &pointer)?,
+                    None => bail!("Unknown field at {:?}", pointer),
+                }
+            }
+        }
+        (Value::Array(list), Value::Array(known_list)) => {
+            for (index, (value, known)) in list.iter().zip(known_list).enumerate() {
+                check_known_fields(value, known, &format!("{}/{}", pointer, index))?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Check the config loaded from the file, the error tells the json pointer of the
+/// invalid config.
+fn check_config_file(vm_config: &VmConfig) -> Result<()> {
+    let mut checks: Vec<(String, &dyn ConfigCheck)> = vec![
+        ("/machine_config".to_string(), &vm_config.machine_config),
+        ("/boot_source".to_string(), &vm_config.boot_source),
+    ];
+    let mut ids = Vec::new();
+    for (key, drive) in &vm_config.drives {
+        let pointer = format!("/drives/{}", pointer_token(key));
+        drive
+            .check_path()
+            .with_context(|| format!("Invalid config at {:?}", pointer))?;
+        ids.push((pointer.clone(), key, &drive.id));
+        checks.push((pointer, drive));
+    }
+    for (key, netdev) in &vm_config.netdevs {
+        let pointer = format!("/netdevs/{}", pointer_token(key));
+        ids.push((pointer.clone(), key, &netdev.id));
+        checks.push((pointer, netdev));
+    }
+    for (key, chardev) in &vm_config.chardev {
+        let pointer = format!("/chardev/{}", pointer_token(key));
+        ids.push((pointer.clone(), key, &chardev.id));
+        checks.push((pointer, chardev));
+    }
+    if let Some(virtio_serial) = &vm_config.virtio_serial {
+        checks.push(("/virtio_serial".to_string(), virtio_serial));
+    }
+    for (index, iothread) in vm_config.iothreads.iter().flatten().enumerate() {
+        checks.push((format!("/iothreads/{}", index), iothread));
+    }
+    for (index, pflash) in vm_config.pflashs.iter().flatten().enumerate() {
+        checks.push((format!("/pflashs/{}", index), pflash));
+    }
+
+    for (pointer, key, id) in ids {
+        if key != id {
+            bail!(
+                "Invalid config at {:?}: id {} is different from its key",
+                pointer,
+                id
+            );
+        }
+    }
+    for (pointer, config) in checks {
+        config
+            .check()
+            .with_context(|| format!("Invalid config at {:?}", pointer))?;
+    }
+    Ok(())
+}
+
+/// Parse the config file in json into `VmConfig`, and check it.
+fn parse_config_json(text: &str) -> Result<VmConfig> {
+    let value: Value = serde_json::from_str(text).with_context(|| "Invalid json")?;
+    // The fields of the structs which are not given in the file are default.
+    let default = serde_json::to_value(VmConfig::default())?;
+    let mut config = default.clone();
+    merge_value(&mut config, value.clone(), &default, "");
+
+    // The error of deserializing from text tells the position of the invalid value, which
+    // is converted into json pointer. It's the same in the file, but the position is not.
+    let config_text = serde_json::to_string_pretty(&config)?;
+    let vm_config: VmConfig = serde_json::from_str(&config_text).map_err(|e| {
+        let pointer = json_pointer_at(&config_text, e.line(), e.column());
+        let msg = e.to_string();
+        let msg = msg
+            .rsplit_once(" at line ")
+            .map_or(msg.as_str(), |(msg, _)| msg);
+        anyhow!("Invalid value at {:?}: {}", pointer, msg)
+    })?;
+    check_known_fields(&value, &serde_json::to_value(&vm_config)?, "")?;
+    check_config_file(&vm_config)?;
+    Ok(vm_config)
+}
+
+/// Load the config of the VM from the json file given by `-config`.
+///
+/// # Arguments
+///
+/// * `path` - The path of the config file.
+pub fn load_config_file(path: &str) -> Result<VmConfig> {
+    let text =
+        fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path))?;
+    parse_config_json(&text).with_context(|| format!("Failed to load config file {}", path))
+}
+
+fn merge_value(value: &mut Value, cmdline: Value, default: &Value, pointer: &str) {
+    if cmdline == *default {
+        return;
+    }
+    match (value, cmdline) {
+        // The fields of a struct are merged one by one, but an entry which has no
+        // default, e.g. a drive or an optional struct, is replaced as a whole.
+        (Value::Object(map), Value::Object(cmdline_map)) if default.is_object() => {
+            for (key, cmdline_value) in cmdline_map {
+                let default_value = default.get(&key).unwrap_or(&Value::Null);
+                let pointer = format!("{}/{}", pointer, pointer_token(&key));
+                match map.get_mut(&key) {
+                    Some(value) => merge_value(value, cmdline_value, default_value, &pointer),
+                    None => {
+                        map.insert(key, cmdline_value);
+                    }
+                }
+            }
+        }
+        (Value::Array(list), Value::Array(cmdline_list)) if APPENDED_LISTS.contains(&pointer) => {
+            list.extend(cmdline_list)
+        }
+        (value, cmdline) => *value = cmdline,
+    }
+}
+
+/// Merge the config given on the command line into the config of the file. The values
+/// which are left default on the command line don't change the config of the file.
+///
+/// # Arguments
+///
+/// * `file_config` - The config loaded from the config file.
+/// * `cmdline_config` - The config given on the command line.
+pub fn merge_vmconfig(file_config: &VmConfig, cmdline_config: &VmConfig) -> Result<VmConfig> {
+    let default = serde_json::to_value(VmConfig::default())?;
+    let mut merged = serde_json::to_value(file_config)?;
+    merge_value(
+        &mut merged,
+        serde_json::to_value(cmdline_config)?,
+        &default,
+        "",
+    );
+    serde_json::from_value(merged)
+        .with_context(|| "Failed to merge the command line with the config file")
+}
+
+/// Get the config of the VM in json, which is printed by `-dump-config` and can be
+/// loaded by `-config`.
+pub fn dump_vmconfig(vm_config: &VmConfig) -> Result<String> {
+    serde_json::to_string_pretty(vm_config).with_context(|| "Failed to serialize VmConfig")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use super::*;
+
+    fn cmdline_config(drive_path: &str) -> VmConfig {
+        let mut vm_config = VmConfig::default();
+        vm_config.add_name("vm1").unwrap();
+        vm_config.add_memory("2G").unwrap();
+        vm_config.add_cpu("4").unwrap();
+        vm_config
+            .add_drive(&format!(
+                "id=rootfs,file={},direct=off,aio=off,readonly=on",
+                drive_path
+            ))
+            .unwrap();
+        vm_config
+            .add_device("virtio-blk-pci,id=blk0,drive=rootfs,bus=pcie.0,addr=0x1")
+            .unwrap();
+        vm_config
+    }
+
+    #[test]
+    fn test_config_file_round_trip() {
+        let drive_path = format!("/tmp/test_config_file_{}.img", std::process::id());
+        File::create(&drive_path).unwrap().set_len(4096).unwrap();
+
+        let vm_config = cmdline_config(&drive_path);
+        let dump = dump_vmconfig(&vm_config).unwrap();
+        let loaded = parse_config_json(&dump).unwrap();
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&vm_config).unwrap()
+        );
+        assert_eq!(loaded.drives["rootfs"].path_on_host, drive_path);
+        assert_eq!(loaded.devices.len(), 1);
+
+        // Merging an empty command line keeps the config of the file.
+        let merged = merge_vmconfig(&loaded, &VmConfig::default()).unwrap();
+        assert_eq!(dump_vmconfig(&merged).unwrap(), dump);
+
+        std::fs::remove_file(drive_path).unwrap();
+    }
+
+    #[test]
+    fn test_config_file_precedence() {
+        let drive_path = format!("/tmp/test_config_precedence_{}.img", std::process::id());
+        File::create(&drive_path).unwrap().set_len(4096).unwrap();
+        let file_config = cmdline_config(&drive_path);
+
+        let mut cmdline = VmConfig::default();
+        cmdline.add_cpu("8").unwrap();
+        cmdline
+            .add_drive(&format!("id=rootfs,file={},direct=off,aio=off", drive_path))
+            .unwrap();
+        cmdline.add_device("virtio-rng-pci,id=rng0").unwrap();
+        let merged = merge_vmconfig(&file_config, &cmdline).unwrap();
+
+        // The command line wins, and the values it leaves default come from the file.
+        assert_eq!(merged.machine_config.nr_cpus, 8);
+        assert_eq!(
+            merged.machine_config.mem_config.mem_size,
+            file_config.machine_config.mem_config.mem_size
+        );
+        assert_eq!(merged.guest_name, "vm1");
+        // A drive of the same id is replaced, and the devices are appended.
+        assert!(!merged.drives["rootfs"].read_only);
+        assert_eq!(merged.devices.len(), 2);
+        assert_eq!(merged.devices[1].0, "virtio-rng-pci");
+
+        std::fs::remove_file(drive_path).unwrap();
+    }
+
+    #[test]
+    fn test_config_file_errors() {
+        assert!(parse_config_json("{\"guest_name\": ").is_err());
+
+        let err = parse_config_json("{\"machine_config\": {\"nr_cpus\": \"four\"}}").unwrap_err();
+        assert!(format!("{:?}", err).contains("\"/machine_config/nr_cpus\""));
+
+        let err = parse_config_json("{\"devices\": [[\"virtio-rng-pci\", \"id=rng0\"], [1, 2]]}")
+            .unwrap_err();
+        assert!(format!("{:?}", err).contains("\"/devices/1/0\""));
+
+        let err = parse_config_json("{\"machine_config\": {\"nr_cpu\": 4}}").unwrap_err();
+        assert!(format!("{:?}", err).contains("\"/machine_config/nr_cpu\""));
+
+        let mut vm_config = VmConfig::default();
+        vm_config.machine_config.mem_config.mem_size = 1 << 20;
+        let err = parse_config_json(&dump_vmconfig(&vm_config).unwrap()).unwrap_err();
+        assert!(format!("{:?}", err).contains("\"/machine_config\""));
+
+        let drive_path = format!("/tmp/test_config_errors_{}.img", std::process::id());
+        File::create(&drive_path).unwrap().set_len(4096).unwrap();
+        let mut vm_config = cmdline_config(&drive_path);
+        let drive = vm_config.drives.remove("rootfs").unwrap();
+        vm_config.drives.insert("a/b".to_string(), drive);
+        let err = parse_config_json(&dump_vmconfig(&vm_config).unwrap()).unwrap_err();
+        assert!(format!("{:?}", err).contains("\"/drives/a~1b\""));
+        std::fs::remove_file(drive_path).unwrap();
+
+        assert_eq!(
+            json_pointer_at("{\"a~\": [0, {\"b\": 1}]}", 1, 18),
+            "/a~0/1/b"
+        );
+    }
+}
@@ -0,0 +1,232 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::error::ConfigError;
+
+/// Output register of a CPUID leaf.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuidReg {
+    Eax,
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+/// A x86 CPU feature flag, which is reported by one bit of CPUID.
+#[derive(Clone, Copy, Debug)]
+pub struct CpuFeature {
+    /// Name of the flag, the same as the one in /proc/cpuinfo of Linux mostly.
+    pub name: &'static str,
+    /// CPUID leaf (EAX).
+    pub leaf: u32,
+    /// CPUID sub-leaf (ECX), 0 if the leaf has no sub-leaf.
+    pub index: u32,
+    pub reg: CpuidReg,
+    pub bit: u32,
+}
+
+const fn feature(name: &'static str, leaf: u32, index: u32, reg: CpuidReg, bit: u32) -> CpuFeature {
+    CpuFeature {
+        name,
+        leaf,
+        index,
+        reg,
+        bit,
+    }
+}
+
+/// CPU feature flags which can be set or cleared by `-cpu host,+feature,-feature`.
+pub const X86_CPU_FEATURES: &[CpuFeature] = &[
+    // CPUID.01H:ECX
+    feature("sse3", 0x1, 0, CpuidReg::Ecx, 0),
+    feature("pclmulqdq", 0x1, 0, CpuidReg::Ecx, 1),
+    feature("monitor", 0x1, 0, CpuidReg::Ecx, 3),
+    feature("vmx", 0x1, 0, CpuidReg::Ecx, 5),
+    feature("est", 0x1, 0, CpuidReg::Ecx, 7),
+    feature("ssse3", 0x1, 0, CpuidReg::Ecx, 9),
+    feature("fma", 0x1, 0, CpuidReg::Ecx, 12),
+    feature("cx16", 0x1, 0, CpuidReg::Ecx, 13),
+    feature("pdcm", 0x1, 0, CpuidReg::Ecx, 15),
+    feature("pcid", 0x1, 0, CpuidReg::Ecx, 17),
+    feature("sse4.1", 0x1, 0, CpuidReg::Ecx, 19),
+    feature("sse4.2", 0x1, 0, CpuidReg::Ecx, 20),
+    feature("x2apic", 0x1, 0, CpuidReg::Ecx, 21),
+    feature("movbe", 0x1, 0, CpuidReg::Ecx, 22),
+    feature("popcnt", 0x1, 0, CpuidReg::Ecx, 23),
+    feature("tsc-deadline", 0x1, 0, CpuidReg::Ecx, 24),
+    feature("aes", 0x1, 0, CpuidReg::Ecx, 25),
+    feature("xsave", 0x1, 0, CpuidReg::Ecx, 26),
+    feature("avx", 0x1, 0, CpuidReg::Ecx, 28),
+    feature("f16c", 0x1, 0, CpuidReg::Ecx, 29),
+    feature("rdrand", 0x1, 0, CpuidReg::Ecx, 30),
+    feature("hypervisor", 0x1, 0, CpuidReg::Ecx, 31),
+    // CPUID.01H:EDX
+    feature("vme", 0x1, 0, CpuidReg::Edx, 1),
+    feature("pse", 0x1, 0, CpuidReg::Edx, 3),
+    feature("tsc", 0x1, 0, CpuidReg::Edx, 4),
+    feature("pae", 0x1, 0, CpuidReg::Edx, 6),
+    feature("mce", 0x1, 0, CpuidReg::Edx, 7),
+    feature("cx8", 0x1, 0, CpuidReg::Edx, 8),
+    feature("apic", 0x1, 0, CpuidReg::Edx, 9),
+    feature("mtrr", 0x1, 0, CpuidReg::Edx, 12),
+    feature("pge", 0x1, 0, CpuidReg::Edx, 13),
+    feature("mca", 0x1, 0, CpuidReg::Edx, 14),
+    feature("pat", 0x1, 0, CpuidReg::Edx, 16),
+    feature("pse36", 0x1, 0, CpuidReg::Edx, 17),
+    feature("clflush", 0x1, 0, CpuidReg::Edx, 19),
+    feature("mmx", 0x1, 0, CpuidReg::Edx, 23),
+    feature("sse", 0x1, 0, CpuidReg::Edx, 25),
+    feature("sse2", 0x1, 0, CpuidReg::Edx, 26),
+    feature("ss", 0x1, 0, CpuidReg::Edx, 27),
+    feature("ht", 0x1, 0, CpuidReg::Edx, 28),
+    // CPUID.(EAX=07H,ECX=0):EBX
+    feature("fsgsbase", 0x7, 0, CpuidReg::Ebx, 0),
+    feature("tsc-adjust", 0x7, 0, CpuidReg::Ebx, 1),
+    feature("bmi1", 0x7, 0, CpuidReg::Ebx, 3),
+    feature("hle", 0x7, 0, CpuidReg::Ebx, 4),
+    feature("avx2", 0x7, 0, CpuidReg::Ebx, 5),
+    feature("smep", 0x7, 0, CpuidReg::Ebx, 7),
+    feature("bmi2", 0x7, 0, CpuidReg::Ebx, 8),
+    feature("erms", 0x7, 0, CpuidReg::Ebx, 9),
+    feature("invpcid", 0x7, 0, CpuidReg::Ebx, 10),
+    feature("rtm", 0x7, 0, CpuidReg::Ebx, 11),
+    feature("avx512f", 0x7, 0, CpuidReg::Ebx, 16),
+    feature("avx512dq", 0x7, 0, CpuidReg::Ebx, 17),
+    feature("rdseed", 0x7, 0, CpuidReg::Ebx, 18),
+    feature("adx", 0x7, 0, CpuidReg::Ebx, 19),
+    feature("smap", 0x7, 0, CpuidReg::Ebx, 20),
+    feature("clflushopt", 0x7, 0, CpuidReg::Ebx, 23),
+    feature("clwb", 0x7, 0, CpuidReg::Ebx, 24),
+    feature("avx512cd", 0x7, 0, CpuidReg::Ebx, 28),
+    feature("sha-ni", 0x7, 0, CpuidReg::Ebx, 29),
+    feature("avx512bw", 0x7, 0, CpuidReg::Ebx, 30),
+    feature("avx512vl", 0x7, 0, CpuidReg::Ebx, 31),
+    // CPUID.(EAX=07H,ECX=0):ECX
+    feature("avx512vbmi", 0x7, 0, CpuidReg::Ecx, 1),
+    feature("umip", 0x7, 0, CpuidReg::Ecx, 2),
+    feature("pku", 0x7, 0, CpuidReg::Ecx, 3),
+    feature("waitpkg", 0x7, 0, CpuidReg::Ecx, 5),
+    feature("gfni", 0x7, 0, CpuidReg::Ecx, 8),
+    feature("vaes", 0x7, 0, CpuidReg::Ecx, 9),
+    feature("vpclmulqdq", 0x7, 0, CpuidReg::Ecx, 10),
+    feature("rdpid", 0x7, 0, CpuidReg::Ecx, 22),
+    // CPUID.(EAX=07H,ECX=0):EDX
+    feature("md-clear", 0x7, 0, CpuidReg::Edx, 10),
+    feature("serialize", 0x7, 0, CpuidReg::Edx, 14),
+    feature("spec-ctrl", 0x7, 0, CpuidReg::Edx, 26),
+    feature("arch-capabilities", 0x7, 0, CpuidReg::Edx, 29),
+    feature("ssbd", 0x7, 0, CpuidReg::Edx, 31),
+    // CPUID.(EAX=0DH,ECX=1):EAX
+    feature("xsaveopt", 0xd, 1, CpuidReg::Eax, 0),
+    feature("xsavec", 0xd, 1, CpuidReg::Eax, 1),
+    feature("xgetbv1", 0xd, 1, CpuidReg::Eax, 2),
+    feature("xsaves", 0xd, 1, CpuidReg::Eax, 3),
+    // CPUID.80000001H:ECX
+    feature("lahf-lm", 0x8000_0001, 0, CpuidReg::Ecx, 0),
+    feature("svm", 0x8000_0001, 0, CpuidReg::Ecx, 2),
+    feature("abm", 0x8000_0001, 0, CpuidReg::Ecx, 5),
+    feature("sse4a", 0x8000_0001, 0, CpuidReg::Ecx, 6),
+    feature("misalignsse", 0x8000_0001, 0, CpuidReg::Ecx, 7),
+    feature("3dnowprefetch", 0x8000_0001, 0, CpuidReg::Ecx, 8),
+    feature("topoext", 0x8000_0001, 0, CpuidReg::Ecx, 22),
+    // CPUID.80000001H:EDX
+    feature("syscall", 0x8000_0001, 0, CpuidReg::Edx, 11),
+    feature("nx", 0x8000_0001, 0, CpuidReg::Edx, 20),
+    feature("pdpe1gb", 0x8000_0001, 0, CpuidReg::Edx, 26),
+    feature("rdtscp", 0x8000_0001, 0, CpuidReg::Edx, 27),
+    feature("lm", 0x8000_0001, 0, CpuidReg::Edx, 29),
+    // CPUID.80000007H:EDX
+    feature("invtsc", 0x8000_0007, 0, CpuidReg::Edx, 8),
+];
+
+/// Find the CPU feature flag by name.
+pub fn find_cpu_feature(name: &str) -> Option<&'static CpuFeature> {
+    X86_CPU_FEATURES.iter().find(|feature| feature.name == name)
+}
+
+/// CPU feature flags set or cleared by `-cpu`, which override the features of the host model.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CpuFeaturesConfig {
+    /// Whether the feature is enabled, keyed by the name of the feature.
+    pub features: BTreeMap<String, bool>,
+}
+
+impl CpuFeaturesConfig {
+    /// Add a feature flag like `+vmx` or `-x2apic`, the last flag of a feature wins.
+    pub fn add_flag(&mut self, flag: &str) -> Result<()> {
+        let (enabled, name) = if let Some(name) = flag.strip_prefix('+') {
+            (true, name)
+        } else if let Some(name) = flag.strip_prefix('-') {
+            (false, name)
+        } else {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                flag.to_string(),
+                "cpu".to_string()
+            )));
+        };
+        if cfg!(target_arch = "aarch64") {
+            bail!("CPU feature flags are only supported on x86_64");
+        }
+        if find_cpu_feature(name).is_none() {
+            bail!("Unknown CPU feature \'{}\'", name);
+        }
+        self.features.insert(name.to_string(), enabled);
+        Ok(())
+    }
+}
+
+#[cfg(all(test, target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_feature_table() {
+        for (i, feature) in X86_CPU_FEATURES.iter().enumerate() {
+            assert!(feature.bit < 32);
+            // Names are unique, and no two features share one bit.
+            assert!(X86_CPU_FEATURES[i + 1..].iter().all(|other| {
+                other.name != feature.name
+                    && (other.leaf, other.index, other.reg, other.bit)
+                        != (feature.leaf, feature.index, feature.reg, feature.bit)
+            }));
+        }
+        let x2apic = find_cpu_feature("x2apic").unwrap();
+        assert_eq!(
+            (x2apic.leaf, x2apic.reg, x2apic.bit),
+            (1, CpuidReg::Ecx, 21)
+        );
+        assert!(find_cpu_feature("x2APIC").is_none());
+    }
+
+    #[test]
+    fn test_add_cpu_feature_flag() {
+        let mut config = CpuFeaturesConfig::default();
+        config.add_flag("+vmx").unwrap();
+        config.add_flag("-x2apic").unwrap();
+        config.add_flag("+invtsc").unwrap();
+        config.add_flag("-invtsc").unwrap();
+        assert_eq!(config.features.len(), 3);
+        assert_eq!(config.features.get("vmx"), Some(&true));
+        assert_eq!(config.features.get("x2apic"), Some(&false));
+        assert_eq!(config.features.get("invtsc"), Some(&false));
+
+        assert!(config.add_flag("vmx").is_err());
+        assert!(config.add_flag("+").is_err());
+        assert!(config.add_flag("+no-such-feature").is_err());
+        assert_eq!(config.features.len(), 3);
+    }
+}
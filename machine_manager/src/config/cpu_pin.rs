@@ -0,0 +1,201 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{error::ConfigError, VmConfig};
+use util::unix::host_cpu_count;
+
+/// Minimum and maximum realtime priority of Linux.
+const MIN_RT_PRIORITY: i32 = 1;
+const MAX_RT_PRIORITY: i32 = 99;
+
+/// Realtime scheduling policy of the vCPU threads.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum SchedPolicy {
+    Fifo,
+    Rr,
+}
+
+impl SchedPolicy {
+    /// The policy passed to `sched_setscheduler`.
+    pub fn to_libc(self) -> libc::c_int {
+        match self {
+            SchedPolicy::Fifo => libc::SCHED_FIFO,
+            SchedPolicy::Rr => libc::SCHED_RR,
+        }
+    }
+}
+
+/// Realtime scheduling policy and priority of the vCPU threads, e.g. `fifo:10`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct VcpuPriority {
+    pub policy: SchedPolicy,
+    pub priority: i32,
+}
+
+impl FromStr for VcpuPriority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (policy, priority) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("vcpu-priority should be <fifo|rr>:<priority>, got {}", s))?;
+        let policy = match policy {
+            "fifo" => SchedPolicy::Fifo,
+            "rr" => SchedPolicy::Rr,
+            _ => bail!(
+                "Invalid scheduling policy {}, only fifo and rr are supported",
+                policy
+            ),
+        };
+        let priority = priority
+            .parse::<i32>()
+            .map_err(|_| anyhow!("Invalid realtime priority {}", priority))?;
+        if !(MIN_RT_PRIORITY..=MAX_RT_PRIORITY).contains(&priority) {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "vcpu-priority".to_string(),
+                MIN_RT_PRIORITY as u64,
+                true,
+                MAX_RT_PRIORITY as u64,
+                true,
+            )));
+        }
+        Ok(VcpuPriority { policy, priority })
+    }
+}
+
+/// Config of `-cpu-pin`, which pins vCPU threads to host cpus.
+#[derive(PartialEq, Eq, Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CpuPinConfig {
+    /// Host cpu of each pinned vCPU.
+    pub vcpus: BTreeMap<u16, usize>,
+    /// Realtime priority of all the vCPU threads.
+    pub priority: Option<VcpuPriority>,
+}
+
+impl CpuPinConfig {
+    /// Check that the pinned vCPUs exist in the VM.
+    pub fn check_vcpus(&self, max_cpus: u16) -> Result<()> {
+        if let Some(vcpu) = self.vcpus.keys().find(|vcpu| **vcpu >= max_cpus) {
+            bail!(
+                "vcpu{} in cpu-pin doesn't exist, the VM has {} vCPUs at most",
+                vcpu,
+                max_cpus
+            );
+        }
+        Ok(())
+    }
+}
+
+impl VmConfig {
+    /// Add argument `cpu-pin` to `VmConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `cpu_pin` - The host cpus of the vCPUs, e.g. `vcpu0=2,vcpu1=3,vcpu-priority=fifo:10`.
+    pub fn add_cpu_pin(&mut self, cpu_pin: &str) -> Result<()> {
+        // The keys `vcpuN` are not fixed, they can't be parsed by CmdParser.
+        let host_cpus = host_cpu_count();
+        let mut config = CpuPinConfig::default();
+        for param in cpu_pin.split(',') {
+            let (key, value) = param.split_once('=').ok_or_else(|| {
+                anyhow!(ConfigError::InvalidParam(
+                    param.to_string(),
+                    "cpu-pin".to_string()
+                ))
+            })?;
+            if key == "vcpu-priority" {
+                config.priority = Some(value.parse::<VcpuPriority>()?);
+                continue;
+            }
+            let vcpu = key
+                .strip_prefix("vcpu")
+                .and_then(|id| id.parse::<u16>().ok())
+                .ok_or_else(|| {
+                    anyhow!(ConfigError::InvalidParam(
+                        key.to_string(),
+                        "cpu-pin".to_string()
+                    ))
+                })?;
+            let host_cpu = value.parse::<usize>().map_err(|_| {
+                anyhow!(ConfigError::InvalidParam(
+                    value.to_string(),
+                    key.to_string()
+                ))
+            })?;
+            if host_cpu >= host_cpus {
+                bail!(
+                    "Host cpu {} of vcpu{} doesn't exist, the host has {} cpus",
+                    host_cpu,
+                    vcpu,
+                    host_cpus
+                );
+            }
+            if config.vcpus.insert(vcpu, host_cpu).is_some() {
+                bail!("vcpu{} is pinned more than once", vcpu);
+            }
+        }
+        self.cpu_pin = Some(config);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_cpu_pin() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.cpu_pin.is_none());
+        vm_config
+            .add_cpu_pin("vcpu0=0,vcpu1=0,vcpu-priority=fifo:10")
+            .unwrap();
+        let config = vm_config.cpu_pin.clone().unwrap();
+        assert_eq!(config.vcpus, BTreeMap::from([(0, 0), (1, 0)]));
+        assert_eq!(
+            config.priority,
+            Some(VcpuPriority {
+                policy: SchedPolicy::Fifo,
+                priority: 10
+            })
+        );
+        assert!(config.check_vcpus(2).is_ok());
+        assert!(config.check_vcpus(1).is_err());
+
+        vm_config.add_cpu_pin("vcpu-priority=rr:99").unwrap();
+        let config = vm_config.cpu_pin.clone().unwrap();
+        assert!(config.vcpus.is_empty());
+        assert_eq!(config.priority.unwrap().policy.to_libc(), libc::SCHED_RR);
+
+        // The host cpus are validated against the host.
+        let host_cpus = host_cpu_count();
+        assert!(vm_config
+            .add_cpu_pin(&format!("vcpu0={}", host_cpus))
+            .is_err());
+
+        assert!(vm_config.add_cpu_pin("vcpu0=0,vcpu0=0").is_err());
+        assert!(vm_config.add_cpu_pin("cpu0=0").is_err());
+        assert!(vm_config.add_cpu_pin("vcpux=0").is_err());
+        assert!(vm_config.add_cpu_pin("vcpu0").is_err());
+        assert!(vm_config.add_cpu_pin("vcpu0=-1").is_err());
+        assert!(vm_config.add_cpu_pin("vcpu-priority=fifo:0").is_err());
+        assert!(vm_config.add_cpu_pin("vcpu-priority=fifo:100").is_err());
+        assert!(vm_config.add_cpu_pin("vcpu-priority=other:10").is_err());
+        assert!(vm_config.add_cpu_pin("vcpu-priority=10").is_err());
+    }
+}
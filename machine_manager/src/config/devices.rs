@@ -52,6 +52,15 @@ pub fn parse_device_id(device_config: &str) -> Result<String> {
     }
 }
 
+/// Get the drive of the device, none if the device is not backed by a drive.
+pub fn parse_device_drive(device_config: &str) -> Result<Option<String>> {
+    let mut cmd_parser = CmdParser::new("device");
+    cmd_parser.push("drive");
+
+    cmd_parser.get_parameters(device_config)?;
+    cmd_parser.get_value::<String>("drive")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -69,5 +78,10 @@ mod tests {
         assert!(ret.is_ok());
         let id = ret.unwrap();
         assert_eq!("", id);
+
+        let drive = parse_device_drive(test_conf).unwrap();
+        assert_eq!(drive, Some("rootfs".to_string()));
+        let drive = parse_device_drive("virtio-net-pci,netdev=net0,id=net0").unwrap();
+        assert_eq!(drive, None);
     }
 }
@@ -10,9 +10,12 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::HashMap;
 use std::fs::{metadata, File};
 use std::os::linux::fs::MetadataExt;
 use std::path::Path;
+use std::str::FromStr;
+use std::sync::Arc;
 
 use anyhow::{anyhow, bail, Result};
 use log::error;
@@ -20,13 +23,13 @@ use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check};
 use crate::config::{
-    get_chardev_socket_path, CmdParser, ConfigCheck, ExBool, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
+    get_chardev_socket, CmdParser, ConfigCheck, ExBool, VmConfig, DEFAULT_VIRTQUEUE_SIZE,
     MAX_PATH_LENGTH, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
 };
-use crate::qmp::qmp_schema;
-use util::aio::{aio_probe, AioEngine};
+use crate::qmp::{fdset::parse_fdset_path, qmp_schema};
+use util::aio::{aio_probe, AioEngine, BackingImage};
 const MAX_SERIAL_NUM: usize = 20;
-const MAX_IOPS: u64 = 1_000_000;
+pub(crate) const MAX_IOPS: u64 = 1_000_000;
 const MAX_UNIT_ID: usize = 2;
 
 // Seg_max = queue_size - 2. So, size of each virtqueue for virtio-blk should be larger than 2.
@@ -40,6 +43,8 @@ pub struct DriveFile {
     pub file: File,
     /// The num of drives share same file.
     pub count: u32,
+    /// Ids of the devices which are realized against the file.
+    pub devices: Vec<String>,
     /// File path.
     pub path: String,
     /// File is read only or not.
@@ -50,6 +55,8 @@ pub struct DriveFile {
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
     pub buf_align: u32,
+    /// Backing image which the clusters not written to the file are read from.
+    pub backing: Option<Arc<BackingImage>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,12 +69,23 @@ pub struct BlkDevConfig {
     pub serial_num: Option<String>,
     pub iothread: Option<String>,
     pub iops: Option<u64>,
+    /// Throttle group sharing its limits with other drives.
+    pub throttle_group: Option<String>,
     pub queues: u16,
     pub boot_index: Option<u8>,
     pub chardev: Option<String>,
     pub socket_path: Option<String>,
+    /// Interval in seconds to reconnect the vhost-user socket, 0 means never reconnect.
+    pub socket_reconnect: u64,
     pub aio: AioEngine,
+    /// Register the disk image and the guest memory to the io_uring aio engine.
+    pub aio_register: bool,
     pub queue_size: u16,
+    pub werror: BlockErrorPolicy,
+    pub rerror: BlockErrorPolicy,
+    /// Requests in flight for longer than it in milliseconds are reported by
+    /// `BLOCK_IO_SLOW`, 0 means they are not detected.
+    pub slow_io_warn_ms: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -75,6 +93,8 @@ pub struct BootIndexInfo {
     pub boot_index: u8,
     pub id: String,
     pub dev_path: String,
+    /// The boot index is derived from `-boot order` rather than set by `bootindex`.
+    pub implicit: bool,
 }
 
 impl Default for BlkDevConfig {
@@ -87,16 +107,85 @@ impl Default for BlkDevConfig {
             serial_num: None,
             iothread: None,
             iops: None,
+            throttle_group: None,
             queues: 1,
             boot_index: None,
             chardev: None,
             socket_path: None,
+            socket_reconnect: 0,
             aio: AioEngine::Native,
+            aio_register: false,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            werror: BlockErrorPolicy::default(),
+            rerror: BlockErrorPolicy::default(),
+            slow_io_warn_ms: 0,
         }
     }
 }
 
+/// Action to take when the backend of a disk fails a request.
+#[derive(Debug, Default, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
+pub enum BlockErrorPolicy {
+    /// Report the error to the guest.
+    #[default]
+    Report,
+    /// Pause the VM and retry the request when it is resumed.
+    Stop,
+    /// Complete the request as if it succeeded.
+    Ignore,
+    /// Behave as `Stop` for ENOSPC and as `Report` for other errors.
+    Enospc,
+    /// Turn the disk read-only for ENOSPC until `block-set-writable`, and behave as
+    /// `Report` for other errors. It's only valid for write errors.
+    ReadOnly,
+}
+
+impl FromStr for BlockErrorPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "report" => Ok(BlockErrorPolicy::Report),
+            "stop" => Ok(BlockErrorPolicy::Stop),
+            "ignore" => Ok(BlockErrorPolicy::Ignore),
+            "enospc" => Ok(BlockErrorPolicy::Enospc),
+            "read-only" => Ok(BlockErrorPolicy::ReadOnly),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Parse the `werror` and `rerror` policies given by QMP, the default policy is used for
+/// the one not set.
+pub fn parse_error_policies(
+    werror: Option<&str>,
+    rerror: Option<&str>,
+) -> Result<(BlockErrorPolicy, BlockErrorPolicy)> {
+    let parse = |param: &str, policy: Option<&str>| match policy {
+        Some(policy) => BlockErrorPolicy::from_str(policy).map_err(|_| {
+            anyhow!(ConfigError::InvalidParam(
+                policy.to_string(),
+                param.to_string()
+            ))
+        }),
+        None => Ok(BlockErrorPolicy::default()),
+    };
+    let (werror, rerror) = (parse("werror", werror)?, parse("rerror", rerror)?);
+    check_rerror(rerror)?;
+    Ok((werror, rerror))
+}
+
+/// Check the policy of read errors, `read-only` is only valid for write errors.
+pub(crate) fn check_rerror(rerror: BlockErrorPolicy) -> Result<()> {
+    if rerror == BlockErrorPolicy::ReadOnly {
+        return Err(anyhow!(ConfigError::InvalidParam(
+            "read-only".to_string(),
+            "rerror".to_string()
+        )));
+    }
+    Ok(())
+}
+
 /// Config struct for `drive`.
 /// Contains block device's attr.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -107,7 +196,15 @@ pub struct DriveConfig {
     pub read_only: bool,
     pub direct: bool,
     pub iops: Option<u64>,
+    /// Throttle group sharing its limits with other drives.
+    pub throttle_group: Option<String>,
     pub aio: AioEngine,
+    /// Register the disk image and the guest memory to the io_uring aio engine.
+    pub aio_register: bool,
+    pub werror: BlockErrorPolicy,
+    pub rerror: BlockErrorPolicy,
+    /// Path of the read-only backing image, the drive file is a copy-on-write overlay of it.
+    pub backing: Option<String>,
 }
 
 impl Default for DriveConfig {
@@ -118,14 +215,23 @@ impl Default for DriveConfig {
             read_only: false,
             direct: true,
             iops: None,
+            throttle_group: None,
             aio: AioEngine::Native,
+            aio_register: false,
+            werror: BlockErrorPolicy::default(),
+            rerror: BlockErrorPolicy::default(),
+            backing: None,
         }
     }
 }
 
 impl DriveConfig {
-    /// Check whether the drive file path on the host is valid.
+    /// Check whether the drive file path on the host is valid. The fdset referred to
+    /// by `/dev/fdset/<id>` is checked when the file is opened.
     pub fn check_path(&self) -> Result<()> {
+        if parse_fdset_path(&self.path_on_host).is_some() {
+            return Ok(());
+        }
         let blk = Path::new(&self.path_on_host);
         match metadata(blk) {
             Ok(meta) => {
@@ -200,7 +306,33 @@ impl ConfigCheck for DriveConfig {
                 "low performance expected when use sync io with \"direct\" on".to_string(),
             )));
         }
-        Ok(())
+        if self.aio_register && self.aio != AioEngine::IoUring {
+            return Err(anyhow!(ConfigError::InvalidParam(
+                "aio-register".to_string(),
+                "registering is only supported by io_uring aio type".to_string(),
+            )));
+        }
+        if let Some(backing) = &self.backing {
+            if backing.len() > MAX_PATH_LENGTH {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "Drive backing path".to_string(),
+                    MAX_PATH_LENGTH,
+                )));
+            }
+            if backing == &self.path_on_host {
+                bail!(
+                    "The backing image of drive {} is the drive file itself",
+                    self.id
+                );
+            }
+            if self.read_only {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "backing".to_string(),
+                    "the overlay of backing image should be writable".to_string(),
+                )));
+            }
+        }
+        check_rerror(self.rerror)
     }
 }
 
@@ -256,6 +388,7 @@ impl ConfigCheck for BlkDevConfig {
             direct: self.direct,
             iops: self.iops,
             aio: self.aio,
+            aio_register: self.aio_register,
             ..Default::default()
         };
         fake_drive.check()?;
@@ -264,7 +397,7 @@ impl ConfigCheck for BlkDevConfig {
             fake_drive.check_path()?;
         }
 
-        Ok(())
+        check_rerror(self.rerror)
     }
 }
 
@@ -296,6 +429,7 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
         drive.direct = direct.into();
     }
     drive.iops = cmd_parser.get_value::<u64>("throttling.iops-total")?;
+    drive.throttle_group = cmd_parser.get_value::<String>("throttling.group")?;
     drive.aio = cmd_parser.get_value::<AioEngine>("aio")?.unwrap_or({
         if drive.direct {
             AioEngine::Native
@@ -303,12 +437,26 @@ fn parse_drive(cmd_parser: CmdParser) -> Result<DriveConfig> {
             AioEngine::Off
         }
     });
+    if let Some(aio_register) = cmd_parser.get_value::<ExBool>("aio-register")? {
+        drive.aio_register = aio_register.into();
+    }
+    if let Some(werror) = cmd_parser.get_value::<BlockErrorPolicy>("werror")? {
+        drive.werror = werror;
+    }
+    if let Some(rerror) = cmd_parser.get_value::<BlockErrorPolicy>("rerror")? {
+        drive.rerror = rerror;
+    }
+    drive.backing = cmd_parser.get_value::<String>("backing")?;
     drive.check()?;
     #[cfg(not(test))]
     drive.check_path()?;
     Ok(drive)
 }
 
+/// Properties of virtio-blk devices which can be set by `-global`.
+pub const BLK_GLOBAL_PROPERTIES: &[&str] =
+    &["iothread", "num-queues", "queue-size", "werror", "rerror"];
+
 pub fn parse_blk(
     vm_config: &mut VmConfig,
     drive_config: &str,
@@ -326,9 +474,13 @@ pub fn parse_blk(
         .push("serial")
         .push("iothread")
         .push("num-queues")
-        .push("queue-size");
+        .push("queue-size")
+        .push("werror")
+        .push("rerror")
+        .push("slow-io-warn-ms");
 
     cmd_parser.parse(drive_config)?;
+    cmd_parser.apply_global_config(&vm_config.global_config);
 
     pci_args_check(&cmd_parser)?;
 
@@ -372,10 +524,33 @@ pub fn parse_blk(
         blkdevcfg.read_only = drive_arg.read_only;
         blkdevcfg.direct = drive_arg.direct;
         blkdevcfg.iops = drive_arg.iops;
+        blkdevcfg.throttle_group = drive_arg.throttle_group.clone();
         blkdevcfg.aio = drive_arg.aio;
+        blkdevcfg.aio_register = drive_arg.aio_register;
+        blkdevcfg.werror = drive_arg.werror;
+        blkdevcfg.rerror = drive_arg.rerror;
     } else {
         bail!("No drive configured matched for blk device");
     }
+    if let Some(group) = &blkdevcfg.throttle_group {
+        if !vm_config.object.throttle_group.contains_key(group) {
+            bail!(
+                "Throttle group {} of drive {} is not found",
+                group,
+                blkdrive
+            );
+        }
+    }
+    // Policies set on the device override the ones of the drive.
+    if let Some(werror) = cmd_parser.get_value::<BlockErrorPolicy>("werror")? {
+        blkdevcfg.werror = werror;
+    }
+    if let Some(rerror) = cmd_parser.get_value::<BlockErrorPolicy>("rerror")? {
+        blkdevcfg.rerror = rerror;
+    }
+    if let Some(slow_io_warn_ms) = cmd_parser.get_value::<u64>("slow-io-warn-ms")? {
+        blkdevcfg.slow_io_warn_ms = slow_io_warn_ms;
+    }
     blkdevcfg.check()?;
     Ok(blkdevcfg)
 }
@@ -432,7 +607,9 @@ pub fn parse_vhost_user_blk_pci(
     }
 
     if let Some(chardev) = &blkdevcfg.chardev {
-        blkdevcfg.socket_path = Some(get_chardev_socket_path(chardev, vm_config)?);
+        let (path, reconnect) = get_chardev_socket(chardev, vm_config)?;
+        blkdevcfg.socket_path = Some(path);
+        blkdevcfg.socket_reconnect = reconnect;
     }
     blkdevcfg.check()?;
     Ok(blkdevcfg)
@@ -505,7 +682,12 @@ impl VmConfig {
             .push("format")
             .push("if")
             .push("throttling.iops-total")
-            .push("aio");
+            .push("throttling.group")
+            .push("aio")
+            .push("aio-register")
+            .push("werror")
+            .push("rerror")
+            .push("backing");
 
         cmd_parser.parse(block_config)?;
         let drive_cfg = parse_drive(cmd_parser)?;
@@ -574,17 +756,31 @@ impl VmConfig {
 
         self.devices.push((args.driver.clone(), device_info));
     }
-    /// Delete drive config in vm config by id.
+    /// Delete drive config in vm config by id, and remove its file from drive file store.
+    /// It fails if the file is still used by a device.
     ///
     /// # Arguments
     ///
     /// * `drive_id` - Drive id.
-    pub fn del_drive_by_id(&mut self, drive_id: &str) -> Result<String> {
-        if self.drives.get(drive_id).is_some() {
-            Ok(self.drives.remove(drive_id).unwrap().path_on_host)
-        } else {
-            bail!("Drive {} not found", drive_id);
+    /// * `drive_files` - Drive file store.
+    pub fn del_drive_by_id(
+        &mut self,
+        drive_id: &str,
+        drive_files: &mut HashMap<String, DriveFile>,
+    ) -> Result<String> {
+        let path = match self.drives.get(drive_id) {
+            Some(drive) => drive.path_on_host.clone(),
+            None => bail!("Drive {} not found", drive_id),
+        };
+        if let Some(dev_id) = drive_files
+            .get(&path)
+            .and_then(|drive_file| drive_file.devices.first())
+        {
+            bail!("Drive {} is in use by device {}", drive_id, dev_id);
         }
+        Self::remove_drive_file(drive_files, &path)?;
+        self.drives.remove(drive_id);
+        Ok(path)
     }
 
     /// Add new flash device to `VmConfig`.
@@ -647,6 +843,8 @@ impl VmConfig {
 
 #[cfg(test)]
 mod tests {
+    use vmm_sys_util::tempfile::TempFile;
+
     use crate::config::get_pci_bdf;
 
     use super::*;
@@ -661,7 +859,7 @@ mod tests {
             .is_ok());
         let blk_cfg_res = parse_blk(
             &mut vm_config,
-            "virtio-blk-device,drive=rootfs,id=rootfs,iothread=iothread1,serial=111111,num-queues=4",
+            "virtio-blk-device,drive=rootfs,id=rootfs,iothread=iothread1,serial=111111,num-queues=4,slow-io-warn-ms=5000",
             None,
         );
         assert!(blk_cfg_res.is_ok());
@@ -672,6 +870,7 @@ mod tests {
         assert_eq!(blk_device_config.read_only, false);
         assert_eq!(blk_device_config.serial_num, Some(String::from("111111")));
         assert_eq!(blk_device_config.queues, 4);
+        assert_eq!(blk_device_config.slow_io_warn_ms, 5000);
 
         let mut vm_config = VmConfig::default();
         assert!(vm_config
@@ -685,6 +884,172 @@ mod tests {
         assert!(blk_cfg_res.is_err()); // Can not find drive named "rootfs1".
     }
 
+    #[test]
+    fn test_blk_global_config() {
+        let mut vm_config = VmConfig::default();
+        for id in ["rootfs", "rootfs1", "rootfs2"] {
+            vm_config
+                .add_drive(&format!("id={},file=/path/to/{}", id, id))
+                .unwrap();
+        }
+        vm_config
+            .add_global_config("virtio-blk-device.queue-size=512")
+            .unwrap();
+        vm_config
+            .add_global_config("virtio-blk-device.num-queues=4")
+            .unwrap();
+
+        // Global defaults are applied to the unset properties.
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=rootfs,num-queues=2",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.queue_size, 512);
+        assert_eq!(blk_cfg.queues, 2);
+
+        // Explicit value wins.
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs1,id=rootfs1,queue-size=128",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.queue_size, 128);
+        assert_eq!(blk_cfg.queues, 4);
+
+        // Globals of other drivers are not applied.
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-pci,drive=rootfs2,id=rootfs2,bus=pcie.0,addr=0x1.0x2",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.queue_size, DEFAULT_VIRTQUEUE_SIZE);
+
+        // The global value fails to be converted when it's applied.
+        let mut vm_config = VmConfig::default();
+        vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,readonly=off,direct=on")
+            .unwrap();
+        vm_config
+            .add_global_config("virtio-blk-device.queue-size=abc")
+            .unwrap();
+        let err = parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=rootfs,id=rootfs",
+            None,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("queue-size"));
+    }
+
+    #[test]
+    fn test_drive_throttle_group_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_object("throttle-group,id=tg0,limits.iops-total=500")
+            .is_ok());
+        for id in 0..2 {
+            assert!(vm_config
+                .add_drive(&format!(
+                    "id=drive{},file=/path/to/disk{},throttling.group=tg0",
+                    id, id
+                ))
+                .is_ok());
+            let blk_cfg = parse_blk(
+                &mut vm_config,
+                &format!("virtio-blk-device,drive=drive{},id=blk{}", id, id),
+                None,
+            )
+            .unwrap();
+            assert_eq!(blk_cfg.throttle_group, Some("tg0".to_string()));
+        }
+
+        // The throttle group is not found.
+        assert!(vm_config
+            .add_drive("id=drive2,file=/path/to/disk2,throttling.group=tg1")
+            .is_ok());
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-device,drive=drive2,id=blk2",
+            None
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_drive_error_policy_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,werror=enospc,rerror=ignore")
+            .is_ok());
+        let drive = vm_config.drives.get("rootfs").unwrap();
+        assert_eq!(drive.werror, BlockErrorPolicy::Enospc);
+        assert_eq!(drive.rerror, BlockErrorPolicy::Ignore);
+
+        // The device inherits the policies of the drive unless it overrides them.
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-pci,id=rootfs,bus=pcie.0,addr=0x1,drive=rootfs,werror=stop",
+            None,
+        )
+        .unwrap();
+        assert_eq!(blk_cfg.werror, BlockErrorPolicy::Stop);
+        assert_eq!(blk_cfg.rerror, BlockErrorPolicy::Ignore);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs")
+            .is_ok());
+        let drive = vm_config.drives.get("rootfs").unwrap();
+        assert_eq!(drive.werror, BlockErrorPolicy::Report);
+        assert_eq!(drive.rerror, BlockErrorPolicy::Report);
+        assert!(vm_config
+            .add_drive("id=rootfs1,file=/path/to/rootfs,werror=retry")
+            .is_err());
+
+        // read-only is only valid for write errors.
+        assert!(vm_config
+            .add_drive("id=rootfs2,file=/path/to/rootfs,werror=read-only")
+            .is_ok());
+        let drive = vm_config.drives.get("rootfs2").unwrap();
+        assert_eq!(drive.werror, BlockErrorPolicy::ReadOnly);
+        assert!(vm_config
+            .add_drive("id=rootfs3,file=/path/to/rootfs,rerror=read-only")
+            .is_err());
+        assert!(parse_blk(
+            &mut vm_config,
+            "virtio-blk-pci,id=rootfs2,bus=pcie.0,addr=0x1,drive=rootfs2,rerror=read-only",
+            None,
+        )
+        .is_err());
+        assert!(parse_error_policies(Some("read-only"), None).is_ok());
+        assert!(parse_error_policies(None, Some("read-only")).is_err());
+    }
+
+    #[test]
+    fn test_drive_aio_register_parser() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=rootfs,file=/path/to/rootfs,aio=io_uring,aio-register=on")
+            .is_ok());
+        assert!(vm_config.drives.get("rootfs").unwrap().aio_register);
+        let blk_cfg = parse_blk(
+            &mut vm_config,
+            "virtio-blk-pci,id=rootfs,bus=pcie.0,addr=0x1,drive=rootfs",
+            None,
+        )
+        .unwrap();
+        assert!(blk_cfg.aio_register);
+
+        // Only io_uring supports registering.
+        assert!(vm_config
+            .add_drive("id=rootfs1,file=/path/to/rootfs,aio=native,aio-register=on")
+            .is_err());
+    }
+
     #[test]
     fn test_pci_block_config_cmdline_parser() {
         let mut vm_config = VmConfig::default();
@@ -827,22 +1192,118 @@ mod tests {
     #[test]
     fn test_del_drive_by_id() {
         let mut vm_config = VmConfig::default();
+        let mut drive_files = HashMap::new();
 
-        assert!(vm_config.del_drive_by_id("drive-0").is_err());
+        assert!(vm_config
+            .del_drive_by_id("drive-0", &mut drive_files)
+            .is_err());
 
         let drive_list = ["drive-0", "drive-1", "drive-2"];
-        for id in drive_list.iter() {
+        let images: Vec<TempFile> = drive_list
+            .iter()
+            .map(|_| TempFile::new().unwrap())
+            .collect();
+        for (id, image) in drive_list.iter().zip(images.iter()) {
             let mut drive_conf = DriveConfig::default();
             drive_conf.id = String::from(*id);
+            drive_conf.path_on_host = image.as_path().to_str().unwrap().to_string();
+            drive_conf.direct = false;
+            VmConfig::add_drive_file(&mut drive_files, &drive_conf.path_on_host, false, false)
+                .unwrap();
             assert!(vm_config.add_drive_with_config(drive_conf).is_ok());
         }
 
         for id in drive_list.iter() {
-            let mut drive_conf = DriveConfig::default();
-            drive_conf.id = String::from(*id);
             assert!(vm_config.drives.get(*id).is_some());
-            assert!(vm_config.del_drive_by_id(*id).is_ok());
+            assert!(vm_config.del_drive_by_id(id, &mut drive_files).is_ok());
             assert!(vm_config.drives.get(*id).is_none());
         }
+        assert!(drive_files.is_empty());
+    }
+
+    #[test]
+    fn test_del_drive_in_use() {
+        let mut vm_config = VmConfig::default();
+        let mut drive_files = HashMap::new();
+        let image = TempFile::new().unwrap();
+        let path = image.as_path().to_str().unwrap().to_string();
+
+        // Add the drive, and attach a device to it.
+        let mut drive_conf = DriveConfig::default();
+        drive_conf.id = "drive-0".to_string();
+        drive_conf.path_on_host = path.clone();
+        VmConfig::add_drive_file(&mut drive_files, &path, false, false).unwrap();
+        vm_config.add_drive_with_config(drive_conf).unwrap();
+        VmConfig::attach_drive_file(&mut drive_files, &path, "blk-0").unwrap();
+        assert!(VmConfig::attach_drive_file(&mut drive_files, "/not/exist", "blk-0").is_err());
+
+        // The drive can't be deleted while the device uses it, and the file is kept open.
+        let err = vm_config
+            .del_drive_by_id("drive-0", &mut drive_files)
+            .unwrap_err();
+        assert_eq!(err.to_string(), "Drive drive-0 is in use by device blk-0");
+        assert!(vm_config.drives.contains_key("drive-0"));
+        assert!(VmConfig::remove_drive_file(&mut drive_files, &path).is_err());
+        assert!(VmConfig::fetch_drive_file(&drive_files, &path).is_ok());
+
+        // The same image can't be added again while it's held.
+        assert!(VmConfig::add_drive_file(&mut drive_files, &path, false, false).is_err());
+
+        // The drive is deleted and the file is closed after the device is detached.
+        VmConfig::detach_drive_file(&mut drive_files, &path, "blk-0");
+        assert_eq!(
+            vm_config
+                .del_drive_by_id("drive-0", &mut drive_files)
+                .unwrap(),
+            path
+        );
+        assert!(!vm_config.drives.contains_key("drive-0"));
+        assert!(!drive_files.contains_key(&path));
+    }
+
+    #[test]
+    fn test_drive_backing() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_drive("id=overlay,file=/path/to/overlay,backing=/path/to/base")
+            .is_ok());
+        let drive = vm_config.drives.get("overlay").unwrap();
+        assert_eq!(drive.backing.as_deref(), Some("/path/to/base"));
+        assert!(vm_config
+            .add_drive("id=overlay1,file=/path/to/overlay,backing=/path/to/overlay")
+            .is_err());
+        assert!(vm_config
+            .add_drive("id=overlay2,file=/path/to/overlay,backing=/path/to/base,readonly=on")
+            .is_err());
+
+        let mut drive_files = HashMap::new();
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(0x10000).unwrap();
+        let base_path = base.as_path().to_str().unwrap().to_string();
+        let overlay = TempFile::new().unwrap();
+        let path = overlay.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(&mut drive_files, &path, false, false).unwrap();
+
+        // The overlay must have the same size as the backing image.
+        assert!(VmConfig::add_drive_backing(&mut drive_files, &path, &base_path).is_err());
+        assert!(!drive_files.contains_key(&base_path));
+        assert!(VmConfig::fetch_drive_backing(&drive_files, &path)
+            .unwrap()
+            .is_none());
+
+        overlay.as_file().set_len(0x10000).unwrap();
+        VmConfig::add_drive_backing(&mut drive_files, &path, &base_path).unwrap();
+        let backing = VmConfig::fetch_drive_backing(&drive_files, &path)
+            .unwrap()
+            .unwrap();
+        assert_eq!(backing.path(), base_path);
+        let base_file = drive_files.get(&base_path).unwrap();
+        assert!(base_file.read_only);
+        // The backing image can't be used as a writable drive.
+        assert!(VmConfig::add_drive_file(&mut drive_files, &base_path, false, false).is_err());
+
+        // The backing image is released with the overlay.
+        VmConfig::remove_drive_file(&mut drive_files, &path).unwrap();
+        assert!(drive_files.is_empty());
     }
 }
@@ -62,4 +62,6 @@ pub enum ConfigError {
     UnitIdError(String, usize, usize),
     #[error("Directory {0} does not exist")]
     DirNotExist(String),
+    #[error("Vsock guest-cid {0} of {2} is already used by {1}.")]
+    GuestCidRepeat(u64, String, String),
 }
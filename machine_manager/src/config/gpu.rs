@@ -20,6 +20,11 @@ pub const VIRTIO_GPU_MAX_SCANOUTS: usize = 16;
 
 pub const VIRTIO_GPU_MAX_HOSTMEM: u64 = 256 * M;
 
+/// The range of the preferred resolution.
+pub const VIRTIO_GPU_MIN_RESOLUTION: u32 = 16;
+pub const VIRTIO_GPU_MAX_XRES: u32 = 2560;
+pub const VIRTIO_GPU_MAX_YRES: u32 = 2048;
+
 #[derive(Clone, Debug)]
 pub struct GpuDevConfig {
     pub id: String,
@@ -62,6 +67,26 @@ impl ConfigCheck for GpuDevConfig {
             )));
         }
 
+        if !(VIRTIO_GPU_MIN_RESOLUTION..=VIRTIO_GPU_MAX_XRES).contains(&self.xres) {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "xres".to_string(),
+                VIRTIO_GPU_MIN_RESOLUTION as u64,
+                true,
+                VIRTIO_GPU_MAX_XRES as u64,
+                true
+            )));
+        }
+
+        if !(VIRTIO_GPU_MIN_RESOLUTION..=VIRTIO_GPU_MAX_YRES).contains(&self.yres) {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "yres".to_string(),
+                VIRTIO_GPU_MIN_RESOLUTION as u64,
+                true,
+                VIRTIO_GPU_MAX_YRES as u64,
+                true
+            )));
+        }
+
         if self.max_hostmem == 0 {
             return Err(anyhow!(ConfigError::IllegalValueUnilateral(
                 "max_hostmem".to_string(),
@@ -168,5 +193,12 @@ mod tests {
             max_outputs=1,edid=true,xres=1024,yres=768,max_hostmem=0";
         let gpu_cfg_ = parse_gpu(&gpu_cfg_cmdline);
         assert!(gpu_cfg_.is_err());
+        // xres or yres is illegal
+        let gpu_cfg_cmdline = "virtio-gpu-pci,id=gpu_1,bus=pcie.0,addr=0x4.0x0,xres=0,yres=768";
+        assert!(parse_gpu(gpu_cfg_cmdline).is_err());
+        let gpu_cfg_cmdline = "virtio-gpu-pci,id=gpu_1,bus=pcie.0,addr=0x4.0x0,xres=1024,yres=4096";
+        assert!(parse_gpu(gpu_cfg_cmdline).is_err());
+        let gpu_cfg_cmdline = "virtio-gpu-pci,id=gpu_1,bus=pcie.0,addr=0x4.0x0,xres=2560,yres=2048";
+        assert!(parse_gpu(gpu_cfg_cmdline).is_ok());
     }
 }
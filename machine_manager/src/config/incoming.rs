@@ -15,7 +15,7 @@ use std::net::Ipv4Addr;
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
-use super::VmConfig;
+use super::{CmdParser, ExBool, VmConfig};
 
 #[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum MigrateMode {
@@ -70,10 +70,37 @@ pub fn parse_incoming_uri(uri: &str) -> Result<(MigrateMode, String)> {
 
 pub type Incoming = (MigrateMode, String);
 
+/// Config of restoring the snapshot memory lazily by userfaultfd.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct LazyRestoreConfig {
+    /// Populate the memory which is not accessed in background.
+    pub prefetch: bool,
+}
+
 impl VmConfig {
     /// Add incoming mode and path.
     pub fn add_incoming(&mut self, config: &str) -> Result<()> {
-        let (mode, uri) = parse_incoming_uri(config)?;
+        let mut cmd_parser = CmdParser::for_option("incoming");
+        cmd_parser.parse(config)?;
+
+        let uri = cmd_parser.get_value::<String>("")?.unwrap_or_default();
+        let (mode, uri) = parse_incoming_uri(&uri)?;
+        let lazy = cmd_parser
+            .get_value::<ExBool>("lazy")?
+            .map_or(false, |lazy| lazy.into());
+        let prefetch = cmd_parser
+            .get_value::<ExBool>("prefetch")?
+            .map_or(false, |prefetch| prefetch.into());
+        if lazy && mode != MigrateMode::File {
+            bail!("Lazy memory restore is only supported for snapshot file");
+        }
+        if prefetch && !lazy {
+            bail!("Prefetch is only supported for lazy memory restore");
+        }
+        if lazy {
+            self.lazy_restore = Some(LazyRestoreConfig { prefetch });
+        }
+
         let incoming = match mode {
             MigrateMode::File => (MigrateMode::File, uri),
             MigrateMode::Unix => (MigrateMode::Unix, uri),
@@ -150,4 +177,37 @@ mod tests {
         let mut vm_config_case2 = VmConfig::default();
         assert!(vm_config_case2.add_incoming("unkonw:/tmp/").is_err());
     }
+
+    #[test]
+    fn test_add_incoming_lazy() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_incoming("file:/tmp/template").is_ok());
+        assert_eq!(vm_config.lazy_restore, None);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_incoming("file:/tmp/template,lazy=on,prefetch=on")
+            .is_ok());
+        assert_eq!(
+            vm_config.incoming.unwrap(),
+            (MigrateMode::File, "/tmp/template".to_string())
+        );
+        assert_eq!(
+            vm_config.lazy_restore,
+            Some(LazyRestoreConfig { prefetch: true })
+        );
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_incoming("unix:/tmp/stratovirt.sock,lazy=on")
+            .is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_incoming("file:/tmp/template,prefetch=on")
+            .is_err());
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_incoming("file:/tmp/template,lazy=maybe")
+            .is_err());
+    }
 }
@@ -0,0 +1,86 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+
+use super::machine_config::memory_unit_conversion;
+use super::CmdParser;
+
+/// Default number of rotated log files to keep.
+const DEFAULT_LOG_FILES: u32 = 5;
+/// Max number of rotated log files to keep.
+const MAX_LOG_FILES: u32 = 100;
+
+/// Config of the log file set by `-D`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogConfig {
+    /// Path of the log file.
+    pub path: String,
+    /// Rotate the log file when it grows beyond the size in bytes, 0 means never.
+    pub max_size: u64,
+    /// Number of rotated log files to keep.
+    pub max_files: u32,
+}
+
+/// Parse the argument of `-D`, e.g. `/var/log/vm.log,max-size=10M,max-files=3`.
+pub fn parse_log_config(log_config: &str) -> Result<LogConfig> {
+    let mut cmd_parser = CmdParser::for_option("D");
+    cmd_parser.parse(log_config)?;
+
+    let path = match cmd_parser.get_value::<String>("")? {
+        Some(path) if !path.is_empty() => path,
+        _ => bail!("Log file path is required for log rotation"),
+    };
+    let max_size = match cmd_parser.get_value::<String>("max-size")? {
+        Some(size) => memory_unit_conversion(&size)?,
+        None => 0,
+    };
+    let max_files = cmd_parser
+        .get_value::<u32>("max-files")?
+        .unwrap_or(DEFAULT_LOG_FILES);
+    if max_files > MAX_LOG_FILES {
+        bail!(
+            "max-files of log should be no more than {}, got {}",
+            MAX_LOG_FILES,
+            max_files
+        );
+    }
+
+    Ok(LogConfig {
+        path,
+        max_size,
+        max_files,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::M;
+
+    #[test]
+    fn test_parse_log_config() {
+        let config = parse_log_config("/tmp/vm.log").unwrap();
+        assert_eq!(config.path, "/tmp/vm.log");
+        assert_eq!(config.max_size, 0);
+        assert_eq!(config.max_files, DEFAULT_LOG_FILES);
+
+        let config = parse_log_config("/tmp/vm.log,max-size=10M,max-files=3").unwrap();
+        assert_eq!(config.max_size, 10 * M);
+        assert_eq!(config.max_files, 3);
+
+        assert!(parse_log_config("max-size=10M").is_err());
+        assert!(parse_log_config("/tmp/vm.log,max-files=101").is_err());
+        assert!(parse_log_config("/tmp/vm.log,max-size=big").is_err());
+        assert!(parse_log_config("/tmp/vm.log,level=debug").is_err());
+    }
+}
@@ -14,21 +14,27 @@ use std::str::FromStr;
 
 use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
+use util::seccomp::SeccompOpt;
 
 use super::error::ConfigError;
 use crate::config::{
-    CmdParser, ConfigCheck, ExBool, IntegerList, VmConfig, MAX_NODES, MAX_STRING_LENGTH,
+    CmdParser, ConfigCheck, CpuFeaturesConfig, ExBool, IntegerList, MemAgingConfig, VmConfig,
+    MAX_NODES, MAX_STRING_LENGTH,
 };
 
-const DEFAULT_CPUS: u8 = 1;
-const DEFAULT_THREADS: u8 = 1;
-const DEFAULT_CORES: u8 = 1;
-const DEFAULT_DIES: u8 = 1;
-const DEFAULT_CLUSTERS: u8 = 1;
-const DEFAULT_SOCKETS: u8 = 1;
-const DEFAULT_MAX_CPUS: u8 = 1;
+const DEFAULT_CPUS: u16 = 1;
+const DEFAULT_THREADS: u16 = 1;
+const DEFAULT_CORES: u16 = 1;
+const DEFAULT_DIES: u16 = 1;
+const DEFAULT_CLUSTERS: u16 = 1;
+const DEFAULT_SOCKETS: u16 = 1;
+const DEFAULT_MAX_CPUS: u16 = 1;
 const DEFAULT_MEMSIZE: u64 = 256;
-const MAX_NR_CPUS: u64 = 254;
+/// The vCPUs beyond 254 are only supported with x2APIC on x86_64.
+#[cfg(target_arch = "x86_64")]
+pub const MAX_NR_CPUS: u64 = 1024;
+#[cfg(target_arch = "aarch64")]
+pub const MAX_NR_CPUS: u64 = 254;
 const MIN_NR_CPUS: u64 = 1;
 const MAX_MEMSIZE: u64 = 549_755_813_888;
 const MIN_MEMSIZE: u64 = 134_217_728;
@@ -99,6 +105,30 @@ impl Default for MemZoneConfig {
     }
 }
 
+/// Whether guest memory is locked in host RAM, set by `-overcommit mem-lock`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum MemLock {
+    #[default]
+    Off,
+    /// Guest memory is populated and locked when it's allocated.
+    On,
+    /// Guest memory is locked when it's faulted in by the guest.
+    OnFault,
+}
+
+impl FromStr for MemLock {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(MemLock::Off),
+            "on" => Ok(MemLock::On),
+            "on-fault" => Ok(MemLock::OnFault),
+            _ => Err(()),
+        }
+    }
+}
+
 /// Config that contains machine's memory information config.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MachineMemConfig {
@@ -107,7 +137,9 @@ pub struct MachineMemConfig {
     pub dump_guest_core: bool,
     pub mem_share: bool,
     pub mem_prealloc: bool,
+    pub mem_lock: MemLock,
     pub mem_zones: Option<Vec<MemZoneConfig>>,
+    pub mem_aging: Option<MemAgingConfig>,
 }
 
 impl Default for MachineMemConfig {
@@ -118,7 +150,9 @@ impl Default for MachineMemConfig {
             dump_guest_core: true,
             mem_share: false,
             mem_prealloc: false,
+            mem_lock: MemLock::Off,
             mem_zones: None,
+            mem_aging: None,
         }
     }
 }
@@ -126,6 +160,11 @@ impl Default for MachineMemConfig {
 #[derive(Clone, Debug, Serialize, Deserialize, Default)]
 pub struct CpuConfig {
     pub pmu: PmuConfig,
+    /// Run vCPUs in the realtime-safe loop, which handles PIO/MMIO exits without
+    /// taking the machine lock or allocating memory.
+    pub realtime: bool,
+    /// Feature flags overriding the host CPU model.
+    pub features: CpuFeaturesConfig,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -152,21 +191,153 @@ pub enum ShutdownAction {
     ShutdownActionPause,
 }
 
+impl FromStr for ShutdownAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "poweroff" => Ok(ShutdownAction::ShutdownActionPoweroff),
+            "pause" => Ok(ShutdownAction::ShutdownActionPause),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Action taken when the guest requests a reset, set by `-action reboot`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum RebootAction {
+    /// Reset the VM.
+    #[default]
+    Reset,
+    /// Shut down the VM as if the guest requested a shutdown, then the shutdown action applies.
+    Shutdown,
+    /// Pause the VM without resetting it.
+    Pause,
+}
+
+impl FromStr for RebootAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "reset" => Ok(RebootAction::Reset),
+            "shutdown" => Ok(RebootAction::Shutdown),
+            "pause" => Ok(RebootAction::Pause),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Base of the time of the RTC device, set by `-rtc base`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum RtcBase {
+    /// The RTC runs in UTC.
+    #[default]
+    Utc,
+    /// The RTC runs in the local time of the host, which is needed by Windows guests.
+    LocalTime,
+}
+
+impl FromStr for RtcBase {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "utc" => Ok(RtcBase::Utc),
+            "localtime" => Ok(RtcBase::LocalTime),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Action taken by the seccomp filter of StratoVirt on a syscall which is not
+/// in the allowlist.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum SeccompMode {
+    /// The syscall is denied and the process is killed with SIGSYS.
+    #[default]
+    Kill,
+    /// The syscall is allowed but logged to the audit log.
+    Log,
+    /// The seccomp filter is not installed.
+    Allow,
+}
+
+impl FromStr for SeccompMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "kill" => Ok(SeccompMode::Kill),
+            "log" => Ok(SeccompMode::Log),
+            "allow" => Ok(SeccompMode::Allow),
+            _ => Err(()),
+        }
+    }
+}
+
+impl SeccompMode {
+    /// Action of the seccomp filter for the syscalls not in the allowlist, none if
+    /// the filter should not be installed.
+    pub fn default_action(&self) -> Option<SeccompOpt> {
+        match self {
+            SeccompMode::Kill => Some(SeccompOpt::Trap),
+            SeccompMode::Log => Some(SeccompOpt::Log),
+            SeccompMode::Allow => None,
+        }
+    }
+}
+
+/// Mode of the in-kernel interrupt controller.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub enum KernelIrqchip {
+    /// PIC, IOAPIC and LAPIC are all emulated by KVM.
+    #[default]
+    On,
+    /// Only LAPIC is emulated by KVM, PIC and IOAPIC are left to userspace, x86 only.
+    Split,
+}
+
+impl FromStr for KernelIrqchip {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "on" => Ok(KernelIrqchip::On),
+            "split" => Ok(KernelIrqchip::Split),
+            _ => Err(()),
+        }
+    }
+}
+
 /// Config struct for machine-config.
 /// Contains some basic Vm config about cpu, memory, name.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MachineConfig {
     pub mach_type: MachineType,
-    pub nr_cpus: u8,
-    pub nr_threads: u8,
-    pub nr_cores: u8,
-    pub nr_dies: u8,
-    pub nr_clusters: u8,
-    pub nr_sockets: u8,
-    pub max_cpus: u8,
+    pub nr_cpus: u16,
+    pub nr_threads: u16,
+    pub nr_cores: u16,
+    pub nr_dies: u16,
+    pub nr_clusters: u16,
+    pub nr_sockets: u16,
+    pub max_cpus: u16,
     pub mem_config: MachineMemConfig,
     pub cpu_config: CpuConfig,
     pub shutdown_action: ShutdownAction,
+    pub reboot_action: RebootAction,
+    /// Size of the 64-bit PCI hole, which is placed at 4GiB and moves the memory above
+    /// 4GiB up, only for x86_64 standard machine.
+    pub pci_hole64_size: u64,
+    pub seccomp_mode: SeccompMode,
+    /// Whether the in-kernel PIT is created, only for x86_64. The guest relies on
+    /// kvmclock or TSC for timing if it's off.
+    pub pit: bool,
+    /// Whether the GICv3 ITS is created, only for aarch64 standard machine. PCI devices
+    /// use INTx instead of MSI if it's off.
+    pub its: bool,
+    pub kernel_irqchip: KernelIrqchip,
+    pub rtc_base: RtcBase,
 }
 
 impl Default for MachineConfig {
@@ -184,6 +355,13 @@ impl Default for MachineConfig {
             mem_config: MachineMemConfig::default(),
             cpu_config: CpuConfig::default(),
             shutdown_action: ShutdownAction::default(),
+            reboot_action: RebootAction::default(),
+            pci_hole64_size: 0,
+            seccomp_mode: SeccompMode::default(),
+            pit: true,
+            its: true,
+            kernel_irqchip: KernelIrqchip::default(),
+            rtc_base: RtcBase::default(),
         }
     }
 }
@@ -194,6 +372,15 @@ impl ConfigCheck for MachineConfig {
             bail!("Memory size must >= 128MiB and <= 512GiB, default unit: MiB, current memory size: {:?} bytes",
             &self.mem_config.mem_size);
         }
+        if self.kernel_irqchip == KernelIrqchip::Split {
+            if !cfg!(target_arch = "x86_64") || self.mach_type != MachineType::StandardVm {
+                bail!("kernel-irqchip=split is only supported by the x86_64 standard machine");
+            }
+            // The in-kernel PIT is wired to the in-kernel PIC, which doesn't exist in split mode.
+            if self.pit {
+                bail!("kernel-irqchip=split requires pit=off, PIC is not emulated");
+            }
+        }
 
         Ok(())
     }
@@ -206,16 +393,7 @@ impl VmConfig {
     ///
     /// * `name` - The name `String` added to `VmConfig`.
     pub fn add_machine(&mut self, mach_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("machine");
-        cmd_parser
-            .push("")
-            .push("type")
-            .push("accel")
-            .push("usb")
-            .push("dump-guest-core")
-            .push("mem-share");
-        #[cfg(target_arch = "aarch64")]
-        cmd_parser.push("gic-version");
+        let mut cmd_parser = CmdParser::for_option("machine");
         cmd_parser.parse(mach_config)?;
 
         #[cfg(target_arch = "aarch64")]
@@ -254,14 +432,38 @@ impl VmConfig {
         if let Some(mem_share) = cmd_parser.get_value::<ExBool>("mem-share")? {
             self.machine_config.mem_config.mem_share = mem_share.into();
         }
+        #[cfg(target_arch = "x86_64")]
+        if let Some(hole_size) = cmd_parser.get_value::<String>("pci-hole64-size")? {
+            let hole_size = memory_unit_conversion(&hole_size)?;
+            if hole_size % G != 0 || hole_size > MAX_MEMSIZE {
+                bail!(
+                    "pci-hole64-size must be a multiple of 1GiB and <= 512GiB, current size: {} bytes",
+                    hole_size
+                );
+            }
+            self.machine_config.pci_hole64_size = hole_size;
+        }
+        #[cfg(target_arch = "x86_64")]
+        if let Some(pit) = cmd_parser.get_value::<ExBool>("pit")? {
+            self.machine_config.pit = pit.into();
+        }
+        #[cfg(target_arch = "aarch64")]
+        if let Some(its) = cmd_parser.get_value::<ExBool>("its")? {
+            self.machine_config.its = its.into();
+        }
+        if let Some(irqchip) = cmd_parser
+            .get_value::<KernelIrqchip>("kernel-irqchip")
+            .with_context(|| "Only \'on\' and \'split\' are supported for \'kernel-irqchip\'")?
+        {
+            self.machine_config.kernel_irqchip = irqchip;
+        }
 
         Ok(())
     }
 
     /// Add '-accel' accelerator config to `VmConfig`.
     pub fn add_accel(&mut self, accel_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("accel");
-        cmd_parser.push("");
+        let mut cmd_parser = CmdParser::for_option("accel");
         cmd_parser.parse(accel_config)?;
 
         if let Some(accel) = cmd_parser.get_value::<String>("")? {
@@ -275,9 +477,7 @@ impl VmConfig {
 
     /// Add '-m' memory config to `VmConfig`.
     pub fn add_memory(&mut self, mem_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("m");
-        cmd_parser.push("").push("size");
-
+        let mut cmd_parser = CmdParser::for_option("m");
         cmd_parser.parse(mem_config)?;
 
         let mem = if let Some(mem_size) = cmd_parser.get_value::<String>("")? {
@@ -295,17 +495,7 @@ impl VmConfig {
 
     /// Add '-smp' cpu config to `VmConfig`.
     pub fn add_cpu(&mut self, cpu_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("smp");
-        cmd_parser
-            .push("")
-            .push("maxcpus")
-            .push("sockets")
-            .push("dies")
-            .push("clusters")
-            .push("cores")
-            .push("threads")
-            .push("cpus");
-
+        let mut cmd_parser = CmdParser::for_option("smp");
         cmd_parser.parse(cpu_config)?;
 
         let cpu = if let Some(cpu) = cmd_parser.get_value::<u64>("")? {
@@ -375,22 +565,30 @@ impl VmConfig {
             bail!("sockets * dies * clusters * cores * threads must be equal to max_cpus");
         }
 
-        self.machine_config.nr_cpus = cpu as u8;
-        self.machine_config.nr_threads = threads as u8;
-        self.machine_config.nr_cores = cores as u8;
-        self.machine_config.nr_dies = dies as u8;
-        self.machine_config.nr_clusters = clusters as u8;
-        self.machine_config.nr_sockets = sockets as u8;
-        self.machine_config.max_cpus = max_cpus as u8;
+        self.machine_config.nr_cpus = cpu as u16;
+        self.machine_config.nr_threads = threads as u16;
+        self.machine_config.nr_cores = cores as u16;
+        self.machine_config.nr_dies = dies as u16;
+        self.machine_config.nr_clusters = clusters as u16;
+        self.machine_config.nr_sockets = sockets as u16;
+        self.machine_config.max_cpus = max_cpus as u16;
 
         Ok(())
     }
 
     pub fn add_cpu_feature(&mut self, features: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("cpu");
-        cmd_parser.push("");
-        cmd_parser.push("pmu");
-        cmd_parser.parse(features)?;
+        // Feature flags like `+vmx` and `-x2apic` have no key, they can't be parsed by CmdParser.
+        let mut cpu_features = self.machine_config.cpu_config.features.clone();
+        let mut params = Vec::new();
+        for param in features.split(',') {
+            if param.starts_with('+') || param.starts_with('-') {
+                cpu_features.add_flag(param)?;
+            } else {
+                params.push(param);
+            }
+        }
+        let mut cmd_parser = CmdParser::for_option("cpu");
+        cmd_parser.parse(&params.join(","))?;
         //Check PMU when actually enabling PMU.
         if let Some(k) = cmd_parser.get_value::<String>("pmu")? {
             self.machine_config.cpu_config.pmu = match k.as_ref() {
@@ -399,6 +597,10 @@ impl VmConfig {
                 _ => bail!("Invalid PMU option,must be one of \'on\" or \"off\"."),
             }
         }
+        if let Some(realtime) = cmd_parser.get_value::<ExBool>("realtime")? {
+            self.machine_config.cpu_config.realtime = realtime.into();
+        }
+        self.machine_config.cpu_config.features = cpu_features;
         Ok(())
     }
 
@@ -411,10 +613,83 @@ impl VmConfig {
         self.machine_config.mem_config.mem_prealloc = true;
     }
 
+    /// Add '-overcommit' config to `VmConfig`.
+    pub fn add_overcommit(&mut self, overcommit: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("overcommit");
+        cmd_parser.parse(overcommit)?;
+
+        if let Some(mem_lock) = cmd_parser
+            .get_value::<MemLock>("mem-lock")
+            .with_context(|| {
+                "Only \'on\', \'off\' and \'on-fault\' are supported for \'mem-lock\'"
+            })?
+        {
+            self.machine_config.mem_config.mem_lock = mem_lock;
+        }
+
+        Ok(())
+    }
+
     pub fn add_no_shutdown(&mut self) -> bool {
         self.machine_config.shutdown_action = ShutdownAction::ShutdownActionPause;
         true
     }
+
+    /// Add '-action' config to `VmConfig`.
+    pub fn add_action(&mut self, action: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("action");
+        cmd_parser.parse(action)?;
+
+        if let Some(reboot) = cmd_parser
+            .get_value::<RebootAction>("reboot")
+            .with_context(|| {
+                "Only \'reset\', \'shutdown\' and \'pause\' are supported for \'reboot\'"
+            })?
+        {
+            self.machine_config.reboot_action = reboot;
+        }
+        if let Some(shutdown) = cmd_parser
+            .get_value::<ShutdownAction>("shutdown")
+            .with_context(|| "Only \'poweroff\' and \'pause\' are supported for \'shutdown\'")?
+        {
+            self.machine_config.shutdown_action = shutdown;
+        }
+
+        Ok(())
+    }
+
+    /// Add '-rtc' config to `VmConfig`.
+    pub fn add_rtc(&mut self, rtc: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("rtc");
+        cmd_parser.parse(rtc)?;
+
+        if let Some(base) = cmd_parser
+            .get_value::<RtcBase>("base")
+            .with_context(|| "Only \'utc\' and \'localtime\' are supported for \'base\'")?
+        {
+            self.machine_config.rtc_base = base;
+        }
+
+        Ok(())
+    }
+
+    pub fn add_seccomp(&mut self, mode: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("seccomp");
+        cmd_parser.parse(mode)?;
+
+        let mode = cmd_parser.get_value::<String>("")?.unwrap_or_default();
+        self.machine_config.seccomp_mode = SeccompMode::from_str(&mode).map_err(|_| {
+            anyhow!(ConfigError::InvalidParam(
+                mode.clone(),
+                "seccomp".to_string()
+            ))
+        })?;
+        Ok(())
+    }
+
+    pub fn disable_seccomp(&mut self) {
+        self.machine_config.seccomp_mode = SeccompMode::Allow;
+    }
 }
 
 impl VmConfig {
@@ -538,8 +813,8 @@ fn smp_read_and_check(cmd_parser: &CmdParser, name: &str, default_val: u64) -> R
                 name.to_string(),
                 1,
                 true,
-                u8::MAX as u64,
-                false
+                MAX_NR_CPUS,
+                true
             )));
         }
         Ok(values)
@@ -592,7 +867,7 @@ fn adjust_topology(
 /// # Arguments
 ///
 /// * `origin_value` - The origin memory value from user.
-fn memory_unit_conversion(origin_value: &str) -> Result<u64> {
+pub(crate) fn memory_unit_conversion(origin_value: &str) -> Result<u64> {
     if (origin_value.ends_with('M') | origin_value.ends_with('m'))
         && (origin_value.contains('M') ^ origin_value.contains('m'))
     {
@@ -659,7 +934,9 @@ mod tests {
             mem_share: false,
             dump_guest_core: false,
             mem_prealloc: false,
+            mem_lock: MemLock::Off,
             mem_zones: None,
+            mem_aging: None,
         };
         let mut machine_config = MachineConfig {
             mach_type: MachineType::MicroVm,
@@ -669,18 +946,25 @@ mod tests {
             nr_dies: 1,
             nr_clusters: 1,
             nr_sockets: 1,
-            max_cpus: MIN_NR_CPUS as u8,
+            max_cpus: MIN_NR_CPUS as u16,
             mem_config: memory_config,
             cpu_config: CpuConfig::default(),
             shutdown_action: ShutdownAction::default(),
+            reboot_action: RebootAction::default(),
+            pci_hole64_size: 0,
+            seccomp_mode: SeccompMode::default(),
+            pit: true,
+            its: true,
+            kernel_irqchip: KernelIrqchip::default(),
+            rtc_base: RtcBase::default(),
         };
         assert!(machine_config.check().is_ok());
 
-        machine_config.nr_cpus = MAX_NR_CPUS as u8;
+        machine_config.nr_cpus = MAX_NR_CPUS as u16;
         machine_config.mem_config.mem_size = MAX_MEMSIZE;
         assert!(machine_config.check().is_ok());
 
-        machine_config.nr_cpus = MIN_NR_CPUS as u8;
+        machine_config.nr_cpus = MIN_NR_CPUS as u16;
         machine_config.mem_config.mem_size = MIN_MEMSIZE - 1;
         assert!(!machine_config.check().is_ok());
         machine_config.mem_config.mem_size = MAX_MEMSIZE + 1;
@@ -688,6 +972,16 @@ mod tests {
         machine_config.mem_config.mem_size = MIN_MEMSIZE;
 
         assert!(machine_config.check().is_ok());
+
+        machine_config.kernel_irqchip = KernelIrqchip::Split;
+        assert!(machine_config.check().is_err());
+        machine_config.mach_type = MachineType::StandardVm;
+        assert!(machine_config.check().is_err());
+        machine_config.pit = false;
+        #[cfg(target_arch = "x86_64")]
+        assert!(machine_config.check().is_ok());
+        #[cfg(target_arch = "aarch64")]
+        assert!(machine_config.check().is_err());
     }
 
     #[test]
@@ -903,6 +1197,47 @@ mod tests {
         let machine_cfg_ret = vm_config.add_machine(memory_cfg_str);
         assert!(machine_cfg_ret.is_err());
 
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config
+                .add_machine("type=q35,pci-hole64-size=32G")
+                .is_ok());
+            assert_eq!(vm_config.machine_config.pci_hole64_size, 32 * G);
+
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config
+                .add_machine("type=q35,pci-hole64-size=1536M")
+                .is_err());
+            assert!(vm_config
+                .add_machine("type=q35,pci-hole64-size=1024G")
+                .is_err());
+            assert_eq!(vm_config.machine_config.pci_hole64_size, 0);
+
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config.machine_config.pit);
+            assert!(vm_config.add_machine("type=microvm,pit=off").is_ok());
+            assert!(!vm_config.machine_config.pit);
+            assert!(vm_config.add_machine("type=microvm,pit=on").is_ok());
+            assert!(vm_config.machine_config.pit);
+            assert!(vm_config.add_machine("type=microvm,pit=auto").is_err());
+        }
+
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.kernel_irqchip, KernelIrqchip::On);
+        assert!(vm_config
+            .add_machine("type=none,kernel-irqchip=split")
+            .is_ok());
+        assert_eq!(
+            vm_config.machine_config.kernel_irqchip,
+            KernelIrqchip::Split
+        );
+        assert!(vm_config.add_machine("type=none,kernel-irqchip=on").is_ok());
+        assert_eq!(vm_config.machine_config.kernel_irqchip, KernelIrqchip::On);
+        assert!(vm_config
+            .add_machine("type=none,kernel-irqchip=off")
+            .is_err());
+
         #[cfg(target_arch = "aarch64")]
         {
             let mut vm_config = VmConfig::default();
@@ -929,6 +1264,14 @@ mod tests {
             let memory_cfg_str = "type=none,gic-version=4";
             let machine_cfg_ret = vm_config.add_machine(memory_cfg_str);
             assert!(machine_cfg_ret.is_err());
+
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config.machine_config.its);
+            assert!(vm_config.add_machine("type=virt,its=off").is_ok());
+            assert!(!vm_config.machine_config.its);
+            assert!(vm_config.add_machine("type=virt,its=on").is_ok());
+            assert!(vm_config.machine_config.its);
+            assert!(vm_config.add_machine("type=virt,its=auto").is_err());
         }
     }
 
@@ -958,6 +1301,72 @@ mod tests {
         assert_eq!(mem_prealloc, true);
     }
 
+    #[test]
+    fn test_add_action() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(
+            vm_config.machine_config.shutdown_action,
+            ShutdownAction::ShutdownActionPoweroff
+        );
+        assert_eq!(vm_config.machine_config.reboot_action, RebootAction::Reset);
+
+        vm_config.add_action("shutdown=pause").unwrap();
+        assert_eq!(
+            vm_config.machine_config.shutdown_action,
+            ShutdownAction::ShutdownActionPause
+        );
+        assert_eq!(vm_config.machine_config.reboot_action, RebootAction::Reset);
+        vm_config
+            .add_action("reboot=shutdown,shutdown=poweroff")
+            .unwrap();
+        assert_eq!(
+            vm_config.machine_config.shutdown_action,
+            ShutdownAction::ShutdownActionPoweroff
+        );
+        assert_eq!(
+            vm_config.machine_config.reboot_action,
+            RebootAction::Shutdown
+        );
+        vm_config.add_action("reboot=pause").unwrap();
+        assert_eq!(vm_config.machine_config.reboot_action, RebootAction::Pause);
+
+        assert!(vm_config.add_action("reboot=poweroff").is_err());
+        assert!(vm_config.add_action("shutdown=reset").is_err());
+        assert!(vm_config.add_action("panic=pause").is_err());
+    }
+
+    #[test]
+    fn test_add_rtc() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.rtc_base, RtcBase::Utc);
+
+        vm_config.add_rtc("base=localtime").unwrap();
+        assert_eq!(vm_config.machine_config.rtc_base, RtcBase::LocalTime);
+        vm_config.add_rtc("base=utc").unwrap();
+        assert_eq!(vm_config.machine_config.rtc_base, RtcBase::Utc);
+
+        assert!(vm_config.add_rtc("base=2006-06-17T16:01:21").is_err());
+        assert!(vm_config.add_rtc("clock=host").is_err());
+    }
+
+    #[test]
+    fn test_add_overcommit() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.mem_config.mem_lock, MemLock::Off);
+        vm_config.add_overcommit("mem-lock=on").unwrap();
+        assert_eq!(vm_config.machine_config.mem_config.mem_lock, MemLock::On);
+        vm_config.add_overcommit("mem-lock=on-fault").unwrap();
+        assert_eq!(
+            vm_config.machine_config.mem_config.mem_lock,
+            MemLock::OnFault
+        );
+        vm_config.add_overcommit("mem-lock=off").unwrap();
+        assert_eq!(vm_config.machine_config.mem_config.mem_lock, MemLock::Off);
+
+        assert!(vm_config.add_overcommit("mem-lock=yes").is_err());
+        assert!(vm_config.add_overcommit("cpu-pm=on").is_err());
+    }
+
     #[test]
     fn test_add_cpu() {
         let mut vm_config = VmConfig::default();
@@ -985,9 +1394,46 @@ mod tests {
         assert_eq!(nr_cpu, 254);
 
         let mut vm_config = VmConfig::default();
-        let cpu_cfg_str = "cpus=255,sockets=255,cores=1,threads=1";
-        let cpu_cfg_ret = vm_config.add_cpu(cpu_cfg_str);
+        let cpu_cfg_str = format!(
+            "cpus={},sockets={},cores=1,threads=1",
+            MAX_NR_CPUS + 1,
+            MAX_NR_CPUS + 1
+        );
+        let cpu_cfg_ret = vm_config.add_cpu(&cpu_cfg_str);
         assert!(cpu_cfg_ret.is_err());
+
+        // Large guests with x2APIC.
+        #[cfg(target_arch = "x86_64")]
+        {
+            let mut vm_config = VmConfig::default();
+            let cpu_cfg_str = "cpus=300,maxcpus=1024,sockets=4,dies=2,cores=64,threads=2";
+            let cpu_cfg_ret = vm_config.add_cpu(cpu_cfg_str);
+            assert!(cpu_cfg_ret.is_ok());
+            assert_eq!(vm_config.machine_config.nr_cpus, 300);
+            assert_eq!(vm_config.machine_config.max_cpus, 1024);
+        }
+    }
+
+    #[test]
+    fn test_add_seccomp() {
+        let mut vm_config = VmConfig::default();
+        assert_eq!(vm_config.machine_config.seccomp_mode, SeccompMode::Kill);
+
+        // The action bytes of the default rule at the end of the BPF program.
+        for (mode, action) in [
+            ("kill", Some(0x0003_0000)),
+            ("log", Some(0x7ffc_0000)),
+            ("allow", None),
+        ] {
+            assert!(vm_config.add_seccomp(mode).is_ok());
+            let seccomp_mode = vm_config.machine_config.seccomp_mode;
+            assert_eq!(seccomp_mode.default_action().map(u32::from), action);
+        }
+        assert!(vm_config.add_seccomp("trap").is_err());
+
+        let mut vm_config = VmConfig::default();
+        vm_config.disable_seccomp();
+        assert_eq!(vm_config.machine_config.seccomp_mode, SeccompMode::Allow);
     }
 
     #[test]
@@ -1048,4 +1494,47 @@ mod tests {
         vm_config.add_cpu_feature("pmu=on").unwrap();
         assert!(vm_config.machine_config.cpu_config.pmu == PmuConfig::On);
     }
+
+    #[test]
+    fn test_cpu_realtime() {
+        let mut vm_config = VmConfig::default();
+        vm_config.add_cpu_feature("host").unwrap();
+        assert!(!vm_config.machine_config.cpu_config.realtime);
+        vm_config.add_cpu_feature("host,realtime=on").unwrap();
+        assert!(vm_config.machine_config.cpu_config.realtime);
+        vm_config.add_cpu_feature("host,realtime=off").unwrap();
+        assert!(!vm_config.machine_config.cpu_config.realtime);
+        assert!(vm_config.add_cpu_feature("host,realtime=maybe").is_err());
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_cpu_feature_flags() {
+        let mut vm_config = VmConfig::default();
+        vm_config
+            .add_cpu_feature("host,+vmx,-x2apic,realtime=on,+x2apic,-tsc-deadline")
+            .unwrap();
+        let cpu_config = &vm_config.machine_config.cpu_config;
+        assert!(cpu_config.realtime);
+        assert_eq!(cpu_config.features.features.len(), 3);
+        assert_eq!(cpu_config.features.features.get("vmx"), Some(&true));
+        assert_eq!(cpu_config.features.features.get("x2apic"), Some(&true));
+        assert_eq!(
+            cpu_config.features.features.get("tsc-deadline"),
+            Some(&false)
+        );
+
+        // Unknown features are rejected, and nothing is changed.
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_cpu_feature("host,+vmx,+foo").is_err());
+        assert!(vm_config
+            .add_cpu_feature("host,+vmx,pmu=maybe,x2apic")
+            .is_err());
+        assert!(vm_config
+            .machine_config
+            .cpu_config
+            .features
+            .features
+            .is_empty());
+    }
 }
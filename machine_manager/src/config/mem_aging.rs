@@ -0,0 +1,200 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{error::ConfigError, CmdParser, ConfigCheck, VmConfig};
+
+/// Default rate of aging guest memory, in MiB per second.
+const DEFAULT_MEM_AGING_RATE: u64 = 64;
+/// The age of a page is counted in scan intervals with a u16.
+const MAX_MEM_AGING_INTERVALS: u64 = u16::MAX as u64;
+
+/// Advice applied to the guest pages which are idle for long.
+#[derive(Default, Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MemAgingAdvice {
+    /// MADV_COLD: deactivate the pages, the host reclaims them first under pressure.
+    #[default]
+    Cold,
+    /// MADV_PAGEOUT: reclaim the pages right away.
+    Pageout,
+}
+
+impl FromStr for MemAgingAdvice {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "cold" => Ok(MemAgingAdvice::Cold),
+            "pageout" => Ok(MemAgingAdvice::Pageout),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Config of the background scanner which marks idle guest memory as reclaimable.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MemAgingConfig {
+    /// Seconds between two scans.
+    pub interval: u64,
+    /// Seconds a page stays untouched before it is aged.
+    pub threshold: u64,
+    /// Maximum size of memory aged per second, in MiB.
+    pub rate: u64,
+    pub advice: MemAgingAdvice,
+}
+
+impl ConfigCheck for MemAgingConfig {
+    fn check(&self) -> Result<()> {
+        if self.interval == 0 {
+            return Err(anyhow!(ConfigError::IllegalValueUnilateral(
+                "interval".to_string(),
+                true,
+                false,
+                0
+            )));
+        }
+        if self.threshold < self.interval
+            || self.threshold / self.interval > MAX_MEM_AGING_INTERVALS
+        {
+            bail!(
+                "The threshold of mem-aging should be between 1 and {} intervals",
+                MAX_MEM_AGING_INTERVALS
+            );
+        }
+        if self.rate == 0 {
+            return Err(anyhow!(ConfigError::IllegalValueUnilateral(
+                "rate".to_string(),
+                true,
+                false,
+                0
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Convert a duration like `30`, `30s`, `5m` or `1h` to seconds.
+fn duration_to_secs(origin_value: &str) -> Result<u64> {
+    let (value, unit) = match origin_value.char_indices().last() {
+        Some((idx, 's')) => (&origin_value[..idx], 1),
+        Some((idx, 'm')) => (&origin_value[..idx], 60),
+        Some((idx, 'h')) => (&origin_value[..idx], 3600),
+        _ => (origin_value, 1),
+    };
+    value
+        .parse::<u64>()
+        .ok()
+        .and_then(|v| v.checked_mul(unit))
+        .ok_or_else(|| {
+            anyhow!(ConfigError::ConvertValueFailed(
+                origin_value.to_string(),
+                String::from("seconds")
+            ))
+        })
+}
+
+impl VmConfig {
+    /// Add argument `mem-aging` to `VmConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_aging` - The config of mem aging, e.g. `interval=60s,threshold=300s`.
+    pub fn add_mem_aging(&mut self, mem_aging: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("mem-aging");
+        cmd_parser.parse(mem_aging)?;
+
+        let interval = match cmd_parser.get_value::<String>("interval")? {
+            Some(interval) => duration_to_secs(&interval)?,
+            None => {
+                return Err(anyhow!(ConfigError::FieldIsMissing(
+                    "interval",
+                    "mem-aging"
+                )))
+            }
+        };
+        let threshold = match cmd_parser.get_value::<String>("threshold")? {
+            Some(threshold) => duration_to_secs(&threshold)?,
+            None => {
+                return Err(anyhow!(ConfigError::FieldIsMissing(
+                    "threshold",
+                    "mem-aging"
+                )))
+            }
+        };
+        let config = MemAgingConfig {
+            interval,
+            threshold,
+            rate: cmd_parser
+                .get_value::<u64>("rate")?
+                .unwrap_or(DEFAULT_MEM_AGING_RATE),
+            advice: cmd_parser
+                .get_value::<MemAgingAdvice>("advice")?
+                .unwrap_or_default(),
+        };
+        config.check()?;
+        self.machine_config.mem_config.mem_aging = Some(config);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_mem_aging() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.machine_config.mem_config.mem_aging.is_none());
+        assert!(vm_config.add_mem_aging("interval=60s,threshold=5m").is_ok());
+        assert_eq!(
+            vm_config.machine_config.mem_config.mem_aging,
+            Some(MemAgingConfig {
+                interval: 60,
+                threshold: 300,
+                rate: DEFAULT_MEM_AGING_RATE,
+                advice: MemAgingAdvice::Cold,
+            })
+        );
+
+        assert!(vm_config
+            .add_mem_aging("interval=10,threshold=1h,rate=16,advice=pageout")
+            .is_ok());
+        let config = vm_config.machine_config.mem_config.mem_aging.unwrap();
+        assert_eq!(config.interval, 10);
+        assert_eq!(config.threshold, 3600);
+        assert_eq!(config.rate, 16);
+        assert_eq!(config.advice, MemAgingAdvice::Pageout);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_mem_aging("interval=60s").is_err());
+        assert!(vm_config.add_mem_aging("threshold=60s").is_err());
+        assert!(vm_config.add_mem_aging("interval=0,threshold=60s").is_err());
+        assert!(vm_config
+            .add_mem_aging("interval=60s,threshold=30s")
+            .is_err());
+        assert!(vm_config
+            .add_mem_aging("interval=1s,threshold=24h")
+            .is_err());
+        assert!(vm_config.add_mem_aging("interval=1d,threshold=2d").is_err());
+        assert!(vm_config
+            .add_mem_aging("interval=60s,threshold=300s,rate=0")
+            .is_err());
+        assert!(vm_config
+            .add_mem_aging("interval=60s,threshold=300s,advice=free")
+            .is_err());
+        assert!(vm_config.machine_config.mem_config.mem_aging.is_none());
+    }
+}
@@ -11,8 +11,13 @@
 // See the Mulan PSL v2 for more details.
 
 pub use balloon::*;
+pub use boot_order::*;
 pub use boot_source::*;
 pub use chardev::*;
+pub use compat::*;
+pub use config_file::*;
+pub use cpu_pin::*;
+pub use cpu_features::*;
 pub use demo_dev::*;
 pub use devices::*;
 pub use drive::*;
@@ -21,21 +26,31 @@ pub use fs::*;
 pub use gpu::*;
 pub use incoming::*;
 pub use iothread::*;
+pub use logging::*;
 pub use machine_config::*;
+pub use mem_aging::*;
 pub use network::*;
 pub use numa::*;
 pub use pci::*;
+pub use placement::*;
 pub use rng::*;
 pub use sasl_auth::*;
 pub use scsi::*;
+pub use smbios::*;
+pub use throttle_group::*;
 pub use tls_creds::*;
 pub use usb::*;
 pub use vfio::*;
 pub use vnc::*;
 
 mod balloon;
+mod boot_order;
 mod boot_source;
 mod chardev;
+mod compat;
+mod config_file;
+mod cpu_pin;
+mod cpu_features;
 mod demo_dev;
 mod devices;
 mod drive;
@@ -44,13 +59,18 @@ mod fs;
 mod gpu;
 mod incoming;
 mod iothread;
+mod logging;
 mod machine_config;
+mod mem_aging;
 mod network;
 mod numa;
 mod pci;
+mod placement;
 mod rng;
 mod sasl_auth;
 mod scsi;
+mod smbios;
+mod throttle_group;
 mod tls_creds;
 mod usb;
 mod vfio;
@@ -58,8 +78,9 @@ pub mod vnc;
 
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
+use std::os::unix::io::FromRawFd;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use serde::{Deserialize, Serialize};
 
@@ -68,12 +89,18 @@ use log::error;
 #[cfg(target_arch = "aarch64")]
 use util::device_tree::{self, FdtBuilder};
 use util::{
-    file::{get_file_alignment, open_file},
+    aio::BackingImage,
+    file::{
+        get_block_device_sector_size, get_file_alignment, get_file_size, is_block_device, open_file,
+    },
     test_helper::is_test_enabled,
     trace::enable_trace_events,
     AsAny,
 };
 
+use crate::cmdline::find_option;
+use crate::qmp::{fdset::parse_fdset_path, QmpChannel};
+
 pub const MAX_STRING_LENGTH: usize = 255;
 pub const MAX_PATH_LENGTH: usize = 4096;
 // Maximum length of the socket path is restricted by linux.
@@ -82,10 +109,25 @@ pub const MAX_SOCK_PATH_LENGTH: usize = 108;
 pub const MAX_VIRTIO_QUEUE: usize = 32;
 pub const FAST_UNPLUG_ON: &str = "1";
 pub const FAST_UNPLUG_OFF: &str = "0";
+
+/// The drivers whose properties can be set by `-global driver.property=value`, and
+/// the properties of each of them.
+const GLOBAL_PROPERTIES: &[(&str, &[&str])] = &[
+    ("pcie-root-port", &["fast-unplug"]),
+    ("virtio-blk-device", BLK_GLOBAL_PROPERTIES),
+    ("virtio-blk-pci", BLK_GLOBAL_PROPERTIES),
+    ("virtio-net-device", NET_GLOBAL_PROPERTIES),
+    ("virtio-net-pci", NET_GLOBAL_PROPERTIES),
+    ("scsi-hd", SCSI_DEVICE_GLOBAL_PROPERTIES),
+    ("scsi-cd", SCSI_DEVICE_GLOBAL_PROPERTIES),
+];
+
 pub const MAX_TAG_LENGTH: usize = 36;
 pub const MAX_NODES: u32 = 128;
 /// Default virtqueue size for virtio devices excepts virtio-fs.
 pub const DEFAULT_VIRTQUEUE_SIZE: u16 = 256;
+/// Max length of the process name, the kernel keeps 16 bytes including the terminating nul.
+pub const MAX_PROCESS_NAME_LENGTH: usize = 15;
 
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct ObjectConfig {
@@ -93,14 +135,20 @@ pub struct ObjectConfig {
     pub mem_object: HashMap<String, MemZoneConfig>,
     pub tls_object: HashMap<String, TlsCredObjConfig>,
     pub sasl_object: HashMap<String, SaslAuthObjConfig>,
+    pub throttle_group: HashMap<String, ThrottleGroupConfig>,
 }
 
 /// This main config structure for Vm, contains Vm's basic configuration and devices.
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct VmConfig {
     pub guest_name: String,
+    /// Name of the process(main thread) shown on the host, set by `-name process=`.
+    pub process_name: Option<String>,
+    /// Uuid of the VM in format `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+    pub uuid: Option<String>,
     pub machine_config: MachineConfig,
     pub boot_source: BootSource,
+    pub boot_order: BootOrderConfig,
     pub drives: HashMap<String, DriveConfig>,
     pub netdevs: HashMap<String, NetDevcfg>,
     pub chardev: HashMap<String, ChardevConfig>,
@@ -114,7 +162,12 @@ pub struct VmConfig {
     pub global_config: HashMap<String, String>,
     pub numa_nodes: Vec<(String, String)>,
     pub incoming: Option<Incoming>,
+    pub lazy_restore: Option<LazyRestoreConfig>,
     pub vnc: Option<VncConfig>,
+    pub qmp_compat: QmpCompatPolicy,
+    pub auto_placement: Option<AutoPlacement>,
+    pub cpu_pin: Option<CpuPinConfig>,
+    pub smbios: SmbiosConfig,
 }
 
 impl VmConfig {
@@ -122,6 +175,9 @@ impl VmConfig {
     pub fn check_vmconfig(&self, is_daemonize: bool) -> Result<()> {
         self.boot_source.check()?;
         self.machine_config.check()?;
+        if let Some(cpu_pin) = self.cpu_pin.as_ref() {
+            cpu_pin.check_vcpus(self.machine_config.max_cpus)?;
+        }
 
         if self.guest_name.len() > MAX_STRING_LENGTH {
             return Err(anyhow!(ConfigError::StringLengthTooLong(
@@ -161,6 +217,8 @@ impl VmConfig {
             bail!("Can't set multiple devices redirected to stdio");
         }
 
+        self.get_vsocks()?;
+
         Ok(())
     }
 
@@ -168,9 +226,46 @@ impl VmConfig {
     ///
     /// # Arguments
     ///
-    /// * `name` - The name `String` updated to `VmConfig`.
+    /// * `name` - The name args, e.g. `guest=foo,process=bar` or just `foo`.
     pub fn add_name(&mut self, name: &str) -> Result<()> {
-        self.guest_name = name.to_string();
+        let mut cmd_parser = CmdParser::for_option("name");
+        cmd_parser.parse(name)?;
+
+        if let Some(guest_name) = cmd_parser.get_value::<String>("")? {
+            self.guest_name = guest_name;
+        }
+        if let Some(guest_name) = cmd_parser.get_value::<String>("guest")? {
+            self.guest_name = guest_name;
+        }
+        if let Some(process_name) = cmd_parser.get_value::<String>("process")? {
+            if process_name.is_empty() {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    "process".to_string(),
+                    "name".to_string()
+                )));
+            }
+            if process_name.len() > MAX_PROCESS_NAME_LENGTH {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "process name".to_string(),
+                    MAX_PROCESS_NAME_LENGTH,
+                )));
+            }
+            self.process_name = Some(process_name);
+        }
+        Ok(())
+    }
+
+    /// Add argument `uuid` to `VmConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `uuid` - The uuid in format `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`.
+    pub fn add_uuid(&mut self, uuid: &str) -> Result<()> {
+        if self.uuid.is_some() {
+            bail!("Uuid of the VM has been set");
+        }
+        parse_uuid(uuid)?;
+        self.uuid = Some(uuid.to_lowercase());
         Ok(())
     }
 
@@ -218,6 +313,9 @@ impl VmConfig {
             "authz-simple" => {
                 self.add_saslauth(object_args)?;
             }
+            "throttle-group" => {
+                self.add_throttle_group(object_args)?;
+            }
             _ => {
                 bail!("Unknow object type: {:?}", &device_type);
             }
@@ -226,30 +324,55 @@ impl VmConfig {
         Ok(())
     }
 
-    /// Add argument `global` to `VmConfig`.
+    /// Add argument `global` to `VmConfig`. The value is the default of the property
+    /// for all the devices of the driver, it's checked when it's applied to a device.
     ///
     /// # Arguments
     ///
-    /// * `global_config` - The args of global config.
+    /// * `global_config` - The args of global config, `driver.property=value`.
     pub fn add_global_config(&mut self, global_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("global");
-        cmd_parser.push("pcie-root-port.fast-unplug");
-        cmd_parser.parse(global_config)?;
+        let (key, value) = match global_config.split_once('=') {
+            Some((key, value)) if !value.is_empty() => (key, value),
+            _ => bail!(
+                "Invalid global config {}, it should be driver.property=value",
+                global_config
+            ),
+        };
+        let (driver, property) = key
+            .split_once('.')
+            .with_context(|| format!("Invalid global config {}, no driver is given", key))?;
+        let properties = match GLOBAL_PROPERTIES.iter().find(|(name, _)| *name == driver) {
+            Some((_, properties)) => properties,
+            None => bail!(
+                "Driver {} is not supported by global config, valid drivers: {}",
+                driver,
+                GLOBAL_PROPERTIES
+                    .iter()
+                    .map(|(name, _)| *name)
+                    .collect::<Vec<&str>>()
+                    .join(", ")
+            ),
+        };
+        if !properties.contains(&property) {
+            bail!(
+                "Property {} of driver {} is not supported by global config, valid properties: {}",
+                property,
+                driver,
+                properties.join(", ")
+            );
+        }
 
-        if let Some(fast_unplug_value) =
-            cmd_parser.get_value::<String>("pcie-root-port.fast-unplug")?
+        if key == "pcie-root-port.fast-unplug"
+            && value != FAST_UNPLUG_ON
+            && value != FAST_UNPLUG_OFF
         {
-            if fast_unplug_value != FAST_UNPLUG_ON && fast_unplug_value != FAST_UNPLUG_OFF {
-                bail!("The value of fast-unplug is invalid: {}", fast_unplug_value);
-            }
-            let fast_unplug_key = String::from("pcie-root-port.fast-unplug");
-            if self.global_config.get(&fast_unplug_key).is_none() {
-                self.global_config
-                    .insert(fast_unplug_key, fast_unplug_value);
-            } else {
-                bail!("Global config {} has been added", fast_unplug_key);
-            }
+            bail!("The value of fast-unplug is invalid: {}", value);
         }
+        if self.global_config.contains_key(key) {
+            bail!("Global config {} has been added", key);
+        }
+        self.global_config
+            .insert(key.to_string(), value.to_string());
         Ok(())
     }
 
@@ -273,40 +396,117 @@ impl VmConfig {
                 ));
             }
         }
-        let mut file = open_file(path, read_only, direct)?;
-        let (req_align, buf_align) = get_file_alignment(&file, direct);
+        let file = Self::open_drive_file(path, read_only, direct)?;
+        let (mut req_align, buf_align) = get_file_alignment(&file, direct);
+        if direct && is_block_device(&file) {
+            // Direct io on block device must be aligned to its logical sector size.
+            req_align = get_block_device_sector_size(&file)?.0;
+        }
         if req_align == 0 || buf_align == 0 {
             bail!(
                 "Failed to detect alignment requirement of drive file {}.",
                 path
             );
         }
-        let file_size = file.seek(SeekFrom::End(0))?;
+        let file_size = get_file_size(&file)?;
         if file_size & (req_align as u64 - 1) != 0 {
             bail!("The size of file {} is not aligned to {}.", path, req_align);
         }
         let drive_file = DriveFile {
             file,
             count: 1,
+            devices: Vec::new(),
             read_only,
             path: path.to_string(),
             locked: false,
             req_align,
             buf_align,
+            backing: None,
         };
         drive_files.insert(path.to_string(), drive_file);
         Ok(())
     }
 
-    /// Remove a file from drive file store.
+    /// Add the backing image of a drive file to drive file store. The backing image is
+    /// shared read-only, and released with the drive file.
+    ///
+    /// # Arguments
+    ///
+    /// * `drive_files` - Drive file store, which the drive file has been added to.
+    /// * `path` - Path of the drive file, which is the copy-on-write overlay.
+    /// * `backing` - Path of the backing image.
+    pub fn add_drive_backing(
+        drive_files: &mut HashMap<String, DriveFile>,
+        path: &str,
+        backing: &str,
+    ) -> Result<()> {
+        // The backing image is not accessed by direct IO, as it's never written.
+        Self::add_drive_file(drive_files, backing, true, false)?;
+        let image = Self::fetch_drive_file(drive_files, backing).and_then(|file| {
+            let overlay = drive_files
+                .get(path)
+                .ok_or_else(|| anyhow!("The file {} is not in drive backend", path))?;
+            BackingImage::new(&overlay.file, file, backing)
+        });
+        match image {
+            Ok(image) => {
+                drive_files.get_mut(path).unwrap().backing = Some(Arc::new(image));
+                Ok(())
+            }
+            Err(e) => {
+                Self::remove_drive_file(drive_files, backing)?;
+                Err(e.context(format!("Failed to add backing image of drive {}", path)))
+            }
+        }
+    }
+
+    /// Open a drive file, the path `/dev/fdset/<id>` refers to a duplicate of the
+    /// fd in the fdset whose access mode matches `read_only`.
+    fn open_drive_file(path: &str, read_only: bool, direct: bool) -> Result<File> {
+        let fdset_id = match parse_fdset_path(path) {
+            Some(id) => id,
+            None => return open_file(path, read_only, direct),
+        };
+        let fd = QmpChannel::dup_fdset_fd(fdset_id, read_only)
+            .with_context(|| format!("Failed to open the file for block {}", path))?;
+        // SAFETY: the duplicated fd is owned by the file.
+        let file = unsafe { File::from_raw_fd(fd) };
+        if direct {
+            // SAFETY: the fd is valid and only its status flags are changed.
+            let ret = unsafe {
+                let flags = libc::fcntl(fd, libc::F_GETFL);
+                libc::fcntl(fd, libc::F_SETFL, flags | libc::O_DIRECT)
+            };
+            if ret < 0 {
+                bail!(
+                    "Failed to set O_DIRECT for block {}: {}",
+                    path,
+                    std::io::Error::last_os_error()
+                );
+            }
+        }
+        Ok(file)
+    }
+
+    /// Remove a file from drive file store. The file is closed and unlocked when it's not
+    /// shared by other drives. It fails if the file is still used by a device.
     pub fn remove_drive_file(
         drive_files: &mut HashMap<String, DriveFile>,
         path: &str,
     ) -> Result<()> {
         if let Some(drive_file) = drive_files.get_mut(path) {
+            if let Some(dev_id) = drive_file.devices.first() {
+                bail!(
+                    "Failed to remove drive {}, it is in use by device {}",
+                    path,
+                    dev_id
+                );
+            }
             drive_file.count -= 1;
             if drive_file.count == 0 {
-                drive_files.remove(path);
+                if let Some(backing) = drive_files.remove(path).and_then(|file| file.backing) {
+                    Self::remove_drive_file(drive_files, backing.path())?;
+                }
             }
         } else {
             return Err(anyhow!(
@@ -317,6 +517,35 @@ impl VmConfig {
         Ok(())
     }
 
+    /// Attach a device to a file in drive file store when the device is realized, the file
+    /// can't be removed until the device is detached from it.
+    pub fn attach_drive_file(
+        drive_files: &mut HashMap<String, DriveFile>,
+        path: &str,
+        dev_id: &str,
+    ) -> Result<()> {
+        match drive_files.get_mut(path) {
+            Some(drive_file) => {
+                if !drive_file.devices.iter().any(|id| id == dev_id) {
+                    drive_file.devices.push(dev_id.to_string());
+                }
+                Ok(())
+            }
+            None => Err(anyhow!("The file {} is not in drive backend", path)),
+        }
+    }
+
+    /// Detach a device from a file in drive file store when the device is unrealized.
+    pub fn detach_drive_file(
+        drive_files: &mut HashMap<String, DriveFile>,
+        path: &str,
+        dev_id: &str,
+    ) {
+        if let Some(drive_file) = drive_files.get_mut(path) {
+            drive_file.devices.retain(|id| id != dev_id);
+        }
+    }
+
     /// Get a file from drive file store.
     pub fn fetch_drive_file(drive_files: &HashMap<String, DriveFile>, path: &str) -> Result<File> {
         match drive_files.get(path) {
@@ -328,6 +557,17 @@ impl VmConfig {
         }
     }
 
+    /// Get the backing image of a file from drive file store.
+    pub fn fetch_drive_backing(
+        drive_files: &HashMap<String, DriveFile>,
+        path: &str,
+    ) -> Result<Option<Arc<BackingImage>>> {
+        match drive_files.get(path) {
+            Some(drive_file) => Ok(drive_file.backing.clone()),
+            None => Err(anyhow!("The file {} is not in drive backend", path)),
+        }
+    }
+
     /// Get alignment requirement from drive file store.
     pub fn fetch_drive_align(
         drive_files: &HashMap<String, DriveFile>,
@@ -349,6 +589,9 @@ impl VmConfig {
                 drive.read_only,
                 drive.direct,
             )?;
+            if let Some(backing) = &drive.backing {
+                Self::add_drive_backing(&mut drive_files, &drive.path_on_host, backing)?;
+            }
         }
         if let Some(pflashs) = self.pflashs.as_ref() {
             for pflash in pflashs {
@@ -401,6 +644,22 @@ impl CmdParser {
         }
     }
 
+    /// Allocates a `CmdParser` with the parameters declared for the command
+    /// line option in `cmdline::OPTIONS`.
+    ///
+    /// # Arguments
+    ///
+    /// * `option`: Name of the option used in command line, e.g. `m` for `-m`.
+    pub fn for_option(option: &str) -> Self {
+        let mut cmd_parser = CmdParser::new(option);
+        if let Some(spec) = find_option(option) {
+            for param in spec.params() {
+                cmd_parser.push(param.name);
+            }
+        }
+        cmd_parser
+    }
+
     /// Push a new param field into `params`.
     ///
     /// # Arguments
@@ -471,6 +730,25 @@ impl CmdParser {
         Ok(())
     }
 
+    /// Set the parameters which are not given in the command line to the defaults of the
+    /// driver set by `-global`, so the explicit values always win. The driver is the first
+    /// parameter, which should be parsed already.
+    ///
+    /// # Arguments
+    ///
+    /// * `global_config`: The global config of the VM.
+    pub fn apply_global_config(&mut self, global_config: &HashMap<String, String>) {
+        let driver = match self.params.get("") {
+            Some(Some(driver)) => driver.clone(),
+            _ => return,
+        };
+        for (param, value) in self.params.iter_mut() {
+            if value.is_none() && !param.is_empty() {
+                *value = global_config.get(&format!("{}.{}", driver, param)).cloned();
+            }
+        }
+    }
+
     /// Parse all cmdline parameters string into `params`.
     ///
     /// # Arguments
@@ -568,8 +846,7 @@ impl From<ExBool> for bool {
 }
 
 pub fn add_trace_events(config: &str) -> Result<()> {
-    let mut cmd_parser = CmdParser::new("trace");
-    cmd_parser.push("events");
+    let mut cmd_parser = CmdParser::for_option("trace");
     cmd_parser.get_parameters(config)?;
 
     if let Some(file) = cmd_parser.get_value::<String>("events")? {
@@ -579,6 +856,32 @@ pub fn add_trace_events(config: &str) -> Result<()> {
     bail!("trace: events file must be set.");
 }
 
+/// Parse the uuid in format `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx` into bytes in the
+/// order they are written.
+pub fn parse_uuid(uuid: &str) -> Result<[u8; 16]> {
+    let invalid_uuid = || {
+        anyhow!(ConfigError::InvalidParam(
+            uuid.to_string(),
+            "uuid".to_string()
+        ))
+    };
+    if !uuid.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+        return Err(invalid_uuid());
+    }
+    let groups: Vec<&str> = uuid.split('-').collect();
+    let group_lens: Vec<usize> = groups.iter().map(|group| group.len()).collect();
+    if group_lens != [8, 4, 4, 4, 12] {
+        return Err(invalid_uuid());
+    }
+
+    let hex = groups.concat();
+    let mut bytes = [0_u8; 16];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(|_| invalid_uuid())?;
+    }
+    Ok(bytes)
+}
+
 pub struct IntegerList(pub Vec<u64>);
 
 impl FromStr for IntegerList {
@@ -766,4 +1069,123 @@ mod tests {
         let res = vm_config.add_global_config("pcie-root-port.fast-unplug=1");
         assert!(res.is_err());
     }
+
+    #[test]
+    fn test_add_global_config_for_devices() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_global_config("virtio-blk-pci.queue-size=512")
+            .is_ok());
+        assert!(vm_config
+            .add_global_config("virtio-net-device.mq=on")
+            .is_ok());
+        assert!(vm_config.add_global_config("scsi-hd.werror=stop").is_ok());
+        assert_eq!(
+            vm_config.global_config.get("virtio-blk-pci.queue-size"),
+            Some(&String::from("512"))
+        );
+
+        // Unknown driver.
+        let err = vm_config
+            .add_global_config("virtio-foo-pci.queue-size=512")
+            .unwrap_err();
+        assert!(err.to_string().contains("virtio-blk-pci"));
+        // Unknown property, the valid properties are listed.
+        let err = vm_config
+            .add_global_config("virtio-blk-pci.serial=111")
+            .unwrap_err();
+        assert!(err.to_string().contains("num-queues"));
+        // Property which is not per-driver.
+        assert!(vm_config.add_global_config("scsi-hd.drive=disk0").is_err());
+        // No driver or no value.
+        assert!(vm_config.add_global_config("queue-size=512").is_err());
+        assert!(vm_config
+            .add_global_config("virtio-blk-device.queue-size=")
+            .is_err());
+    }
+
+    #[test]
+    fn test_apply_global_config() {
+        let mut global_config = HashMap::new();
+        global_config.insert(
+            String::from("virtio-blk-pci.queue-size"),
+            String::from("512"),
+        );
+        global_config.insert(
+            String::from("virtio-blk-device.num-queues"),
+            String::from("4"),
+        );
+
+        let mut cmd_parser = CmdParser::new("virtio-blk");
+        cmd_parser.push("").push("queue-size").push("num-queues");
+        cmd_parser.parse("virtio-blk-pci,num-queues=2").unwrap();
+        cmd_parser.apply_global_config(&global_config);
+        assert_eq!(
+            cmd_parser.get_value::<u16>("queue-size").unwrap(),
+            Some(512)
+        );
+        // Explicit value wins, and the globals of other drivers are not applied.
+        assert_eq!(cmd_parser.get_value::<u16>("num-queues").unwrap(), Some(2));
+    }
+
+    #[test]
+    fn test_add_name() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_name("vm1").is_ok());
+        assert_eq!(vm_config.guest_name, "vm1");
+        assert!(vm_config.process_name.is_none());
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_name("guest=vm2,process=stratovirt-vm2")
+            .is_ok());
+        assert_eq!(vm_config.guest_name, "vm2");
+        assert_eq!(vm_config.process_name.as_deref(), Some("stratovirt-vm2"));
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_name("guest=vm3,process=").is_err());
+        assert!(vm_config
+            .add_name("guest=vm3,process=stratovirt-vm-03")
+            .is_err());
+        assert!(vm_config.add_name("guest=vm3,debug-threads=on").is_err());
+    }
+
+    #[test]
+    fn test_add_uuid() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_uuid("2D1A7E3C-5B4F-4E8A-9C6D-0F1E2A3B4C5D")
+            .is_ok());
+        assert_eq!(
+            vm_config.uuid.as_deref(),
+            Some("2d1a7e3c-5b4f-4e8a-9c6d-0f1e2a3b4c5d")
+        );
+        assert_eq!(
+            parse_uuid(vm_config.uuid.as_ref().unwrap()).unwrap(),
+            [
+                0x2d, 0x1a, 0x7e, 0x3c, 0x5b, 0x4f, 0x4e, 0x8a, 0x9c, 0x6d, 0x0f, 0x1e, 0x2a, 0x3b,
+                0x4c, 0x5d
+            ]
+        );
+        // Duplicate uuid.
+        assert!(vm_config
+            .add_uuid("2d1a7e3c-5b4f-4e8a-9c6d-0f1e2a3b4c5d")
+            .is_err());
+
+        // Malformed uuids.
+        let mut vm_config = VmConfig::default();
+        for uuid in [
+            "",
+            "2d1a7e3c5b4f4e8a9c6d0f1e2a3b4c5d",
+            "2d1a7e3c-5b4f-4e8a-9c6d-0f1e2a3b4c5",
+            "2d1a7e3c-5b4f-4e8a-9c6d-0f1e2a3b4c5d0",
+            "2d1a7e3c-5b4f-4e8a-9c6d0-f1e2a3b4c5d",
+            "2d1a7e3g-5b4f-4e8a-9c6d-0f1e2a3b4c5d",
+            "+d1a7e3c-5b4f-4e8a-9c6d-0f1e2a3b4c5d",
+            "2d1a7e3c-5b4f-4e8a-9c6d-0f1e2a3b4cé",
+        ] {
+            assert!(vm_config.add_uuid(uuid).is_err());
+        }
+        assert!(vm_config.uuid.is_none());
+    }
 }
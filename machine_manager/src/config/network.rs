@@ -10,18 +10,20 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::HashMap;
 use std::os::unix::io::RawFd;
 
 use anyhow::{anyhow, bail, Context, Result};
 use serde::{Deserialize, Serialize};
 
 use super::{error::ConfigError, pci_args_check};
-use crate::config::get_chardev_socket_path;
+use crate::config::get_chardev_socket;
 use crate::config::{
     CmdParser, ConfigCheck, ExBool, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_PATH_LENGTH,
     MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
 };
 use crate::qmp::{qmp_schema, QmpChannel};
+use util::tap::{TapScripts, TAP_NAME_TEMPLATE, TAP_SCRIPT_NONE};
 
 const MAC_ADDRESS_LENGTH: usize = 17;
 
@@ -29,6 +31,8 @@ const MAC_ADDRESS_LENGTH: usize = 17;
 pub const MAX_QUEUE_SIZE_NET: u16 = 4096;
 /// Max num of virtqueues.
 const MAX_QUEUE_PAIRS: usize = MAX_VIRTIO_QUEUE / 2;
+/// Min MTU of ethernet.
+const MIN_MTU_NET: u16 = 68;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct NetDevcfg {
@@ -37,8 +41,16 @@ pub struct NetDevcfg {
     pub vhost_type: Option<String>,
     pub vhost_fds: Option<Vec<i32>>,
     pub ifname: String,
+    /// Script run with the tap name as argument after the tap is created.
+    pub script: Option<String>,
+    /// Script run with the tap name as argument when the device is removed or the VM exits.
+    pub downscript: Option<String>,
     pub queues: u16,
     pub chardev: Option<String>,
+    /// Limit of bytes per second of each direction of the queue pairs.
+    pub rate_limit_bps: Option<u64>,
+    /// Limit of packets per second of each direction of the queue pairs.
+    pub rate_limit_pps: Option<u64>,
 }
 
 impl Default for NetDevcfg {
@@ -49,8 +61,12 @@ impl Default for NetDevcfg {
             vhost_type: None,
             vhost_fds: None,
             ifname: "".to_string(),
+            script: None,
+            downscript: None,
             queues: 2,
             chardev: None,
+            rate_limit_bps: None,
+            rate_limit_pps: None,
         }
     }
 }
@@ -71,6 +87,11 @@ impl ConfigCheck for NetDevcfg {
             )));
         }
 
+        check_tap_scripts(self.script.as_ref(), self.downscript.as_ref())?;
+        if self.tap_fds.is_some() && (self.script.is_some() || self.downscript.is_some()) {
+            bail!("Argument 'script' or 'downscript' is not supported by the tap passed by fd");
+        }
+
         if let Some(vhost_type) = self.vhost_type.as_ref() {
             if vhost_type != "vhost-kernel" && vhost_type != "vhost-user" {
                 return Err(anyhow!(ConfigError::UnknownVhostType));
@@ -87,43 +108,108 @@ impl ConfigCheck for NetDevcfg {
             )));
         }
 
+        check_rate_limit(
+            self.vhost_type.as_deref(),
+            self.rate_limit_bps,
+            self.rate_limit_pps,
+        )?;
+
         Ok(())
     }
 }
 
+fn check_tap_scripts(script: Option<&String>, downscript: Option<&String>) -> Result<()> {
+    if script.map_or(0, |s| s.len()) > MAX_PATH_LENGTH
+        || downscript.map_or(0, |s| s.len()) > MAX_PATH_LENGTH
+    {
+        return Err(anyhow!(ConfigError::StringLengthTooLong(
+            "tap script path".to_string(),
+            MAX_PATH_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+/// The packets of vhost net are not processed by StratoVirt, so they can't be limited.
+fn check_rate_limit(vhost_type: Option<&str>, bps: Option<u64>, pps: Option<u64>) -> Result<()> {
+    if bps.is_none() && pps.is_none() {
+        return Ok(());
+    }
+    if vhost_type.is_some() {
+        bail!("Rate limit is not supported by vhost net");
+    }
+    if bps == Some(0) || pps == Some(0) {
+        return Err(anyhow!(ConfigError::IllegalValue(
+            "rate limit of net device".to_string(),
+            1,
+            true,
+            u64::MAX,
+            true,
+        )));
+    }
+    Ok(())
+}
+
 /// Config struct for network
 /// Contains network device config, such as `host_dev_name`, `mac`...
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct NetworkInterfaceConfig {
     pub id: String,
+    /// Id of the netdev, the tap scripts are run by the tap script helper as the ones of it.
+    pub netdev: String,
     pub host_dev_name: String,
+    /// Script run with the tap name as argument after the tap is created.
+    pub script: Option<String>,
+    /// Script run with the tap name as argument when the device is removed or the VM exits.
+    pub downscript: Option<String>,
     pub mac: Option<String>,
     pub tap_fds: Option<Vec<i32>>,
     pub vhost_type: Option<String>,
     pub vhost_fds: Option<Vec<i32>>,
     pub iothread: Option<String>,
+    /// Iothreads of the queue pairs, the queue pairs not listed use `iothread`.
+    pub queue_iothreads: Option<Vec<String>>,
     pub queues: u16,
     pub mq: bool,
     pub socket_path: Option<String>,
+    /// Socket chardev connecting to the vhost-user backend.
+    pub chardev: Option<String>,
+    /// Interval in seconds to reconnect the vhost-user socket, 0 means never reconnect.
+    pub socket_reconnect: u64,
+    /// MTU advertised to the guest by VIRTIO_NET_F_MTU, only supported by vhost-user net.
+    pub mtu: Option<u16>,
     /// All queues of a net device have the same queue size now.
     pub queue_size: u16,
+    /// Limit of bytes per second of each direction of the queue pairs.
+    pub rate_limit_bps: Option<u64>,
+    /// Limit of packets per second of each direction of the queue pairs.
+    pub rate_limit_pps: Option<u64>,
 }
 
 impl Default for NetworkInterfaceConfig {
     fn default() -> Self {
         NetworkInterfaceConfig {
             id: "".to_string(),
+            netdev: "".to_string(),
             host_dev_name: "".to_string(),
+            script: None,
+            downscript: None,
             mac: None,
             tap_fds: None,
             vhost_type: None,
             vhost_fds: None,
             iothread: None,
+            queue_iothreads: None,
             queues: 2,
             mq: false,
             socket_path: None,
+            chardev: None,
+            socket_reconnect: 0,
+            mtu: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            rate_limit_bps: None,
+            rate_limit_pps: None,
         }
     }
 }
@@ -144,6 +230,8 @@ impl ConfigCheck for NetworkInterfaceConfig {
             )));
         }
 
+        check_tap_scripts(self.script.as_ref(), self.downscript.as_ref())?;
+
         if self.mac.is_some() && !check_mac_address(self.mac.as_ref().unwrap()) {
             return Err(anyhow!(ConfigError::MacFormatError));
         }
@@ -155,6 +243,22 @@ impl ConfigCheck for NetworkInterfaceConfig {
             )));
         }
 
+        if let Some(iothreads) = &self.queue_iothreads {
+            if iothreads.len() > (self.queues / 2) as usize {
+                bail!(
+                    "The num of queue iothreads {} is bigger than queue pairs {}",
+                    iothreads.len(),
+                    self.queues / 2
+                );
+            }
+            if iothreads.iter().any(|name| name.len() > MAX_STRING_LENGTH) {
+                return Err(anyhow!(ConfigError::StringLengthTooLong(
+                    "iothread name".to_string(),
+                    MAX_STRING_LENGTH,
+                )));
+            }
+        }
+
         if self.socket_path.is_some() && self.socket_path.as_ref().unwrap().len() > MAX_PATH_LENGTH
         {
             return Err(anyhow!(ConfigError::StringLengthTooLong(
@@ -163,6 +267,21 @@ impl ConfigCheck for NetworkInterfaceConfig {
             )));
         }
 
+        if let Some(mtu) = self.mtu {
+            if self.vhost_type.as_deref() != Some("vhost-user") {
+                bail!("Argument 'host_mtu' is only supported by vhost-user net");
+            }
+            if mtu < MIN_MTU_NET {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    "host_mtu of net device".to_string(),
+                    MIN_MTU_NET as u64,
+                    true,
+                    u16::MAX as u64,
+                    true
+                )));
+            }
+        }
+
         if self.queue_size < DEFAULT_VIRTQUEUE_SIZE || self.queue_size > MAX_QUEUE_SIZE_NET {
             return Err(anyhow!(ConfigError::IllegalValue(
                 "queue size of net device".to_string(),
@@ -177,6 +296,12 @@ impl ConfigCheck for NetworkInterfaceConfig {
             bail!("queue size of net device should be power of 2!");
         }
 
+        check_rate_limit(
+            self.vhost_type.as_deref(),
+            self.rate_limit_bps,
+            self.rate_limit_pps,
+        )?;
+
         Ok(())
     }
 }
@@ -197,6 +322,52 @@ fn parse_fds(cmd_parser: &CmdParser, name: &str) -> Result<Option<Vec<i32>>> {
     }
 }
 
+/// A vhost-user netdev connects to the backend by a socket chardev instead of a tap device.
+fn check_vhost_user_netdev(netdev_type: &str, net: &NetDevcfg) -> Result<()> {
+    if netdev_type != "vhost-user" {
+        if net.chardev.is_some() {
+            bail!("Argument 'chardev' is only supported by vhost-user netdev");
+        }
+        return Ok(());
+    }
+    if net.chardev.is_none() {
+        return Err(anyhow!(ConfigError::FieldIsMissing(
+            "chardev",
+            "vhost-user netdev"
+        )));
+    }
+    if net.tap_fds.is_some()
+        || !net.ifname.is_empty()
+        || net.script.is_some()
+        || net.downscript.is_some()
+    {
+        bail!("Argument 'ifname', 'fd', 'fds', 'script' or 'downscript' is not supported by vhost-user netdev");
+    }
+    Ok(())
+}
+
+/// Iothreads of the queue pairs are separated by `:`.
+pub fn parse_queue_iothreads(iothreads: &str) -> Vec<String> {
+    iothreads.split(':').map(String::from).collect()
+}
+
+/// The script `no` means no script.
+fn parse_tap_script(script: Option<String>) -> Option<String> {
+    script.filter(|s| s != TAP_SCRIPT_NONE)
+}
+
+/// Let the kernel name the tap if neither the name nor the fd of the tap is set,
+/// which is only useful if the tap is configured by the ifup script.
+fn generate_tap_name(netdev_type: &str, net: &mut NetDevcfg) {
+    if netdev_type != "vhost-user"
+        && net.ifname.is_empty()
+        && net.tap_fds.is_none()
+        && net.script.is_some()
+    {
+        net.ifname = TAP_NAME_TEMPLATE.to_string();
+    }
+}
+
 fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     let mut net = NetDevcfg::default();
     let netdev_type = if let Some(netdev_type) = cmd_parser.get_value::<String>("")? {
@@ -215,6 +386,8 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     if let Some(ifname) = cmd_parser.get_value::<String>("ifname")? {
         net.ifname = ifname;
     }
+    net.script = parse_tap_script(cmd_parser.get_value::<String>("script")?);
+    net.downscript = parse_tap_script(cmd_parser.get_value::<String>("downscript")?);
     if let Some(queue_pairs) = cmd_parser.get_value::<u16>("queues")? {
         let queues = queue_pairs.checked_mul(2);
         if queues.is_none() || !is_netdev_queues_valid(queues.unwrap()) {
@@ -255,6 +428,8 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     if let Some(chardev) = cmd_parser.get_value::<String>("chardev")? {
         net.chardev = Some(chardev);
     }
+    net.rate_limit_bps = cmd_parser.get_value::<u64>("rate-limit-bps")?;
+    net.rate_limit_pps = cmd_parser.get_value::<u64>("rate-limit-pps")?;
     if let Some(vhost_fd) = parse_fds(&cmd_parser, "vhostfd")? {
         net.vhost_fds = Some(vhost_fd);
     } else if let Some(vhost_fds) = parse_fds(&cmd_parser, "vhostfds")? {
@@ -274,6 +449,8 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     if net.vhost_fds.is_some() && net.vhost_type.is_none() {
         bail!("Argument \'vhostfd\' is not needed for virtio-net device");
     }
+    check_vhost_user_netdev(&netdev_type, &net)?;
+    generate_tap_name(&netdev_type, &mut net);
     if net.tap_fds.is_none() && net.ifname.eq("") && netdev_type.ne("vhost-user") {
         bail!("Tap device is missing, use \'ifname\' or \'fd\' to configure a tap device");
     }
@@ -283,6 +460,9 @@ fn parse_netdev(cmd_parser: CmdParser) -> Result<NetDevcfg> {
     Ok(net)
 }
 
+/// Properties of virtio-net devices which can be set by `-global`.
+pub const NET_GLOBAL_PROPERTIES: &[&str] = &["mq", "iothread", "queue-size", "host_mtu"];
+
 pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkInterfaceConfig> {
     let mut cmd_parser = CmdParser::new("virtio-net");
     cmd_parser
@@ -296,9 +476,12 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
         .push("multifunction")
         .push("mac")
         .push("iothread")
-        .push("queue-size");
+        .push("queue-iothreads")
+        .push("queue-size")
+        .push("host_mtu");
 
     cmd_parser.parse(net_config)?;
+    cmd_parser.apply_global_config(&vm_config.global_config);
     pci_args_check(&cmd_parser)?;
     let mut netdevinterfacecfg = NetworkInterfaceConfig::default();
 
@@ -317,20 +500,32 @@ pub fn parse_net(vm_config: &mut VmConfig, net_config: &str) -> Result<NetworkIn
         netdevinterfacecfg.mq = mq.inner;
     }
     netdevinterfacecfg.iothread = cmd_parser.get_value::<String>("iothread")?;
+    netdevinterfacecfg.queue_iothreads = cmd_parser
+        .get_value::<String>("queue-iothreads")?
+        .map(|iothreads| parse_queue_iothreads(&iothreads));
     netdevinterfacecfg.mac = cmd_parser.get_value::<String>("mac")?;
     if let Some(queue_size) = cmd_parser.get_value::<u16>("queue-size")? {
         netdevinterfacecfg.queue_size = queue_size;
     }
+    netdevinterfacecfg.mtu = cmd_parser.get_value::<u16>("host_mtu")?;
 
     if let Some(netcfg) = &vm_config.netdevs.remove(&netdev) {
         netdevinterfacecfg.id = netid;
+        netdevinterfacecfg.netdev = netdev.clone();
         netdevinterfacecfg.host_dev_name = netcfg.ifname.clone();
+        netdevinterfacecfg.script = netcfg.script.clone();
+        netdevinterfacecfg.downscript = netcfg.downscript.clone();
         netdevinterfacecfg.tap_fds = netcfg.tap_fds.clone();
         netdevinterfacecfg.vhost_fds = netcfg.vhost_fds.clone();
         netdevinterfacecfg.vhost_type = netcfg.vhost_type.clone();
         netdevinterfacecfg.queues = netcfg.queues;
+        netdevinterfacecfg.rate_limit_bps = netcfg.rate_limit_bps;
+        netdevinterfacecfg.rate_limit_pps = netcfg.rate_limit_pps;
         if let Some(chardev) = &netcfg.chardev {
-            netdevinterfacecfg.socket_path = Some(get_chardev_socket_path(chardev, vm_config)?);
+            let (path, reconnect) = get_chardev_socket(chardev, vm_config)?;
+            netdevinterfacecfg.socket_path = Some(path);
+            netdevinterfacecfg.socket_reconnect = reconnect;
+            netdevinterfacecfg.chardev = Some(chardev.clone());
         }
     } else {
         bail!("Netdev: {:?} not found for net device", &netdev);
@@ -387,8 +582,12 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
         vhost_type: None,
         vhost_fds: None,
         ifname: String::new(),
+        script: None,
+        downscript: None,
         queues,
         chardev: args.chardev,
+        rate_limit_bps: None,
+        rate_limit_pps: None,
     };
 
     if let Some(tap_fd) = args.fd {
@@ -426,8 +625,14 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
                 bail!("The num of vhostfds must equal to fds");
             }
         }
-    } else if let Some(if_name) = args.if_name {
-        config.ifname = if_name;
+    } else {
+        if let Some(if_name) = args.if_name {
+            config.ifname = if_name;
+        }
+        // The tap script helper only runs the scripts of the netdevs on the command line.
+        if parse_tap_script(args.script).is_some() || parse_tap_script(args.downscript).is_some() {
+            bail!("Argument 'script' or 'downscript' is only supported by the netdev on the command line");
+        }
     }
 
     // Get net device type.
@@ -446,6 +651,7 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
     if config.vhost_fds.is_some() && config.vhost_type.is_none() {
         bail!("Argument 'vhostfd' or 'vhostfds' are not needed for virtio-net device");
     }
+    check_vhost_user_netdev(&netdev_type, &config)?;
     if config.tap_fds.is_none() && config.ifname.eq("") && netdev_type.ne("vhost-user") {
         bail!("Tap device is missing, use 'ifname' or 'fd' to configure a tap device");
     }
@@ -455,19 +661,7 @@ pub fn get_netdev_config(args: Box<qmp_schema::NetDevAddArgument>) -> Result<Net
 
 impl VmConfig {
     pub fn add_netdev(&mut self, netdev_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("netdev");
-        cmd_parser
-            .push("")
-            .push("id")
-            .push("fd")
-            .push("fds")
-            .push("vhost")
-            .push("ifname")
-            .push("vhostfd")
-            .push("vhostfds")
-            .push("queues")
-            .push("chardev");
-
+        let mut cmd_parser = CmdParser::for_option("netdev");
         cmd_parser.parse(netdev_config)?;
         let drive_cfg = parse_netdev(cmd_parser)?;
         self.add_netdev_with_config(drive_cfg)
@@ -483,6 +677,23 @@ impl VmConfig {
         Ok(())
     }
 
+    /// Get the scripts of the netdevs, indexed by the netdev id, which are allowed to be run
+    /// by the tap script helper.
+    pub fn tap_scripts(&self) -> HashMap<String, TapScripts> {
+        self.netdevs
+            .values()
+            .filter(|netdev| netdev.script.is_some() || netdev.downscript.is_some())
+            .map(|netdev| {
+                let scripts = TapScripts {
+                    ifname: netdev.ifname.clone(),
+                    script: netdev.script.clone(),
+                    downscript: netdev.downscript.clone(),
+                };
+                (netdev.id.clone(), scripts)
+            })
+            .collect()
+    }
+
     pub fn del_netdev_by_id(&mut self, id: &str) -> Result<()> {
         if self.netdevs.get(id).is_some() {
             self.netdevs.remove(id);
@@ -525,6 +736,10 @@ impl VmConfig {
             device_info = format!("{},iothread={}", device_info, iothread);
         }
 
+        if let Some(iothreads) = &args.queue_iothreads {
+            device_info = format!("{},queue-iothreads={}", device_info, iothreads);
+        }
+
         if let Some(mq) = &args.mq {
             device_info = format!("{},mq={}", device_info, mq);
         }
@@ -675,6 +890,28 @@ mod tests {
         assert_eq!(network_configs.queues, 10);
         assert_eq!(network_configs.vhost_fds, Some(vec![39, 40, 41, 42, 43]));
         assert_eq!(network_configs.mq, false);
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("tap,id=eth1,ifname=tap1,queues=2")
+            .is_ok());
+        let net_cfg_res = parse_net(
+            &mut vm_config,
+            "virtio-net-pci,id=net1,netdev=eth1,bus=pcie.0,addr=0x1.0x2,mq=on,queue-iothreads=io1:io2",
+        );
+        assert_eq!(
+            net_cfg_res.unwrap().queue_iothreads,
+            Some(vec!["io1".to_string(), "io2".to_string()])
+        );
+        // More iothreads than queue pairs.
+        assert!(vm_config
+            .add_netdev("tap,id=eth1,ifname=tap1,queues=2")
+            .is_ok());
+        assert!(parse_net(
+            &mut vm_config,
+            "virtio-net-pci,id=net1,netdev=eth1,bus=pcie.0,addr=0x1.0x2,mq=on,queue-iothreads=io1:io2:io3",
+        )
+        .is_err());
     }
 
     #[test]
@@ -716,15 +953,34 @@ mod tests {
         assert!(parse_net(&mut vm_config, net_cfg).is_ok());
 
         // For vhost-user net
-        assert!(vm_config.add_netdev("vhost-user,id=netdevid").is_ok());
+        assert!(vm_config.add_netdev("vhost-user,id=netdevid").is_err());
+        assert!(vm_config
+            .add_netdev("vhost-user,id=netdevid,chardev=chardevid,ifname=tap0")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid,ifname=tap0,chardev=chardevid")
+            .is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=chardevid,path=/tmp/vhost-user-net.sock,reconnect=1")
+            .is_ok());
+        assert!(vm_config
+            .add_netdev("vhost-user,id=netdevid,chardev=chardevid")
+            .is_ok());
         let net_cfg =
-            "virtio-net-pci,id=netid,netdev=netdevid,bus=pcie.0,addr=0x2.0x0,mac=12:34:56:78:9A:BC";
+            "virtio-net-pci,id=netid,netdev=netdevid,bus=pcie.0,addr=0x2.0x0,mac=12:34:56:78:9A:BC,host_mtu=9000";
         let net_cfg_res = parse_net(&mut vm_config, net_cfg);
         assert!(net_cfg_res.is_ok());
         let network_configs = net_cfg_res.unwrap();
         assert_eq!(network_configs.id, "netid");
         assert_eq!(network_configs.vhost_type, Some("vhost-user".to_string()));
         assert_eq!(network_configs.mac, Some("12:34:56:78:9A:BC".to_string()));
+        assert_eq!(network_configs.chardev, Some("chardevid".to_string()));
+        assert_eq!(
+            network_configs.socket_path,
+            Some("/tmp/vhost-user-net.sock".to_string())
+        );
+        assert_eq!(network_configs.socket_reconnect, 1);
+        assert_eq!(network_configs.mtu, Some(9000));
 
         assert!(vm_config
             .add_netdev("vhost-user,id=netdevid2,chardev=chardevid2")
@@ -733,6 +989,20 @@ mod tests {
             "virtio-net-pci,id=netid2,netdev=netdevid2,bus=pcie.0,addr=0x2.0x0,mac=12:34:56:78:9A:BC";
         let net_cfg_res = parse_net(&mut vm_config, net_cfg);
         assert!(net_cfg_res.is_err());
+
+        // host_mtu is only supported by vhost-user net, and must be a valid ethernet mtu.
+        assert!(vm_config.add_netdev("tap,id=eth2,ifname=tap2").is_ok());
+        let net_cfg = "virtio-net-pci,id=net2,netdev=eth2,bus=pcie.0,addr=0x3.0x0,host_mtu=1500";
+        assert!(parse_net(&mut vm_config, net_cfg).is_err());
+        assert!(vm_config
+            .add_chardev("socket,id=chardevid3,path=/tmp/vhost-user-net3.sock")
+            .is_ok());
+        assert!(vm_config
+            .add_netdev("vhost-user,id=netdevid3,chardev=chardevid3")
+            .is_ok());
+        let net_cfg =
+            "virtio-net-pci,id=netid3,netdev=netdevid3,bus=pcie.0,addr=0x3.0x0,host_mtu=67";
+        assert!(parse_net(&mut vm_config, net_cfg).is_err());
     }
 
     #[test]
@@ -766,6 +1036,96 @@ mod tests {
         assert!(netdev_conf.check().is_err());
     }
 
+    #[test]
+    fn test_netdev_rate_limit() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid,ifname=tap0,rate-limit-bps=1048576,rate-limit-pps=1000")
+            .is_ok());
+        let net_cfg = parse_net(&mut vm_config, "virtio-net-device,id=net0,netdev=netdevid");
+        assert!(net_cfg.is_ok());
+        let net_cfg = net_cfg.unwrap();
+        assert_eq!(net_cfg.rate_limit_bps, Some(1048576));
+        assert_eq!(net_cfg.rate_limit_pps, Some(1000));
+
+        // Zero rate, invalid rate and vhost net are not supported.
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid1,ifname=tap1,rate-limit-pps=0")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid2,ifname=tap2,rate-limit-bps=1M")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid3,ifname=tap3,vhost=on,rate-limit-bps=1048576")
+            .is_err());
+    }
+
+    #[test]
+    fn test_netdev_tap_scripts() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid,ifname=tap0,script=/etc/ifup,downscript=/etc/ifdown")
+            .is_ok());
+        assert!(vm_config.add_netdev("tap,id=netdevid6,ifname=tap6").is_ok());
+        let scripts = vm_config.tap_scripts();
+        assert_eq!(scripts.len(), 1);
+        assert_eq!(scripts["netdevid"].ifname, "tap0");
+        assert_eq!(scripts["netdevid"].script.as_deref(), Some("/etc/ifup"));
+        assert_eq!(
+            scripts["netdevid"].downscript.as_deref(),
+            Some("/etc/ifdown")
+        );
+        let net_cfg =
+            parse_net(&mut vm_config, "virtio-net-device,id=net0,netdev=netdevid").unwrap();
+        assert_eq!(net_cfg.netdev, "netdevid");
+        assert_eq!(net_cfg.host_dev_name, "tap0");
+        assert_eq!(net_cfg.script.as_deref(), Some("/etc/ifup"));
+        assert_eq!(net_cfg.downscript.as_deref(), Some("/etc/ifdown"));
+
+        // The kernel names the tap if only the script is set.
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid1,script=/etc/ifup")
+            .is_ok());
+        let net_cfg =
+            parse_net(&mut vm_config, "virtio-net-device,id=net1,netdev=netdevid1").unwrap();
+        assert_eq!(net_cfg.host_dev_name, TAP_NAME_TEMPLATE);
+        assert!(net_cfg.downscript.is_none());
+
+        // `no` disables the script.
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid2,ifname=tap2,script=no,downscript=no")
+            .is_ok());
+        let netdev = vm_config.netdevs.get("netdevid2").unwrap();
+        assert!(netdev.script.is_none() && netdev.downscript.is_none());
+        assert!(vm_config.add_netdev("tap,id=netdevid3,script=no").is_err());
+
+        // The tap passed by fd and vhost-user netdev don't support scripts.
+        assert!(vm_config
+            .add_netdev("tap,id=netdevid4,fd=11,script=/etc/ifup")
+            .is_err());
+        assert!(vm_config
+            .add_netdev("vhost-user,id=netdevid5,chardev=chardevid,script=/etc/ifup")
+            .is_err());
+
+        // The scripts are only supported by the netdevs on the command line.
+        let netdev = Box::new(qmp_schema::NetDevAddArgument {
+            id: "netdev".to_string(),
+            script: Some("/etc/ifup".to_string()),
+            downscript: Some("no".to_string()),
+            ..qmp_schema::NetDevAddArgument::default()
+        });
+        assert!(get_netdev_config(netdev).is_err());
+        let netdev = Box::new(qmp_schema::NetDevAddArgument {
+            id: "netdev".to_string(),
+            if_name: Some("tap6".to_string()),
+            script: Some("no".to_string()),
+            downscript: Some("no".to_string()),
+            ..qmp_schema::NetDevAddArgument::default()
+        });
+        let net_cfg = get_netdev_config(netdev).unwrap();
+        assert!(net_cfg.script.is_none() && net_cfg.downscript.is_none());
+    }
+
     #[test]
     fn test_add_netdev_with_different_queues() {
         let mut vm_config = VmConfig::default();
@@ -29,7 +29,7 @@ pub struct NumaDistance {
 #[derive(Default, Debug)]
 pub struct NumaConfig {
     pub numa_id: u32,
-    pub cpus: Vec<u8>,
+    pub cpus: Vec<u16>,
     pub distances: Option<Vec<NumaDistance>>,
     pub size: u64,
     pub mem_dev: String,
@@ -37,7 +37,7 @@ pub struct NumaConfig {
 
 #[derive(Default)]
 pub struct NumaNode {
-    pub cpus: Vec<u8>,
+    pub cpus: Vec<u16>,
     pub distances: BTreeMap<u32, u8>,
     pub size: u64,
 }
@@ -51,7 +51,7 @@ pub type NumaNodes = BTreeMap<u32, NumaNode>;
 /// * `numa_nodes` - The NUMA node information parsing from user.
 /// * `nr_cpus` - The VM cpus number.
 /// * `mem_size` - The VM memory size.
-pub fn complete_numa_node(numa_nodes: &mut NumaNodes, nr_cpus: u8, mem_size: u64) -> Result<()> {
+pub fn complete_numa_node(numa_nodes: &mut NumaNodes, nr_cpus: u16, mem_size: u64) -> Result<()> {
     if numa_nodes.len() > 8 {
         bail!(
             "NUMA nodes should be less than or equal to 8, now is {}",
@@ -60,8 +60,8 @@ pub fn complete_numa_node(numa_nodes: &mut NumaNodes, nr_cpus: u8, mem_size: u64
     }
 
     let mut total_ram_size = 0_u64;
-    let mut max_cpu_id = 0_u8;
-    let mut cpus_id = HashSet::<u8>::new();
+    let mut max_cpu_id = 0_u16;
+    let mut cpus_id = HashSet::<u16>::new();
     for (_, node) in numa_nodes.iter() {
         total_ram_size += node.size;
         for id in node.cpus.iter() {
@@ -141,11 +141,11 @@ pub fn parse_numa_mem(numa_config: &str) -> Result<NumaConfig> {
         .get_value::<IntegerList>("cpus")
         .map_err(|_| {
             anyhow!(ConfigError::ConvertValueFailed(
-                String::from("u8"),
+                String::from("u16"),
                 "cpus".to_string()
             ))
         })?
-        .map(|v| v.0.iter().map(|e| *e as u8).collect::<Vec<u8>>())
+        .map(|v| v.0.iter().map(|e| *e as u16).collect::<Vec<u16>>())
     {
         cpus.sort_unstable();
         config.cpus = cpus;
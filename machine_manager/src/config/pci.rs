@@ -277,4 +277,29 @@ mod tests {
         )
         .is_err());
     }
+
+    #[test]
+    fn test_parse_root_port() {
+        let root_port =
+            parse_root_port("pcie-root-port,port=0x1,chassis=1,addr=0x1,bus=pcie.0,id=pr1")
+                .unwrap();
+        assert_eq!(root_port.port, 1);
+        assert_eq!(root_port.id, "pr1");
+        assert!(!root_port.multifunction);
+
+        let root_port = parse_root_port(
+            "pcie-root-port,port=2,chassis=1,addr=0x2,bus=pcie.0,id=pr2,multifunction=on",
+        )
+        .unwrap();
+        assert_eq!(root_port.port, 2);
+        assert!(root_port.multifunction);
+
+        // Port and id are mandatory.
+        assert!(parse_root_port("pcie-root-port,chassis=1,addr=0x1,bus=pcie.0,id=pr1").is_err());
+        assert!(parse_root_port("pcie-root-port,port=0x1,addr=0x1,bus=pcie.0").is_err());
+        // Unknown parameters are rejected.
+        assert!(
+            parse_root_port("pcie-root-port,port=0x1,slot=1,addr=0x1,bus=pcie.0,id=pr1").is_err()
+        );
+    }
 }
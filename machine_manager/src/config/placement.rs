@@ -0,0 +1,70 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{error::ConfigError, VmConfig};
+
+/// Policy to place the threads and memory of the VM on host automatically.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Serialize, Deserialize)]
+pub enum AutoPlacement {
+    /// Place each guest NUMA node on one host NUMA node.
+    Numa,
+}
+
+impl FromStr for AutoPlacement {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "numa" => Ok(AutoPlacement::Numa),
+            _ => Err(()),
+        }
+    }
+}
+
+impl VmConfig {
+    /// Add argument `auto-placement` to `VmConfig`.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - The placement policy, e.g. `numa`.
+    pub fn add_auto_placement(&mut self, policy: &str) -> Result<()> {
+        let policy = AutoPlacement::from_str(policy).map_err(|_| {
+            anyhow!(ConfigError::InvalidParam(
+                policy.to_string(),
+                "auto-placement".to_string()
+            ))
+        })?;
+        self.auto_placement = Some(policy);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_auto_placement() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.auto_placement.is_none());
+        assert!(vm_config.add_auto_placement("numa").is_ok());
+        assert_eq!(vm_config.auto_placement, Some(AutoPlacement::Numa));
+
+        assert!(vm_config.add_auto_placement("node").is_err());
+        assert!(vm_config.add_auto_placement("").is_err());
+    }
+}
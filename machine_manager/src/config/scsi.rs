@@ -10,13 +10,15 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
-use super::{error::ConfigError, pci_args_check};
+use super::{check_rerror, error::ConfigError, pci_args_check};
 use crate::config::{
-    CmdParser, ConfigCheck, VmConfig, DEFAULT_VIRTQUEUE_SIZE, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
+    memory_unit_conversion, BlockErrorPolicy, CmdParser, ConfigCheck, VmConfig,
+    DEFAULT_VIRTQUEUE_SIZE, MAX_STRING_LENGTH, MAX_VIRTIO_QUEUE,
 };
 use util::aio::AioEngine;
+use util::num_ops::str_to_usize;
 
 /// According to Virtio Spec.
 /// Max_channel should be 0.
@@ -27,13 +29,22 @@ pub const VIRTIO_SCSI_MAX_LUN: u16 = 16383;
 
 /// Only support peripheral device addressing format(8 bits for lun) in stratovirt now.
 /// So, max lun id supported is 255 (2^8 - 1).
-const SUPPORT_SCSI_MAX_LUN: u16 = 255;
+pub const SUPPORT_SCSI_MAX_LUN: u16 = 255;
 
 // Seg_max = queue_size - 2. So, size of each virtqueue for virtio-scsi should be larger than 2.
 const MIN_QUEUE_SIZE_SCSI: u16 = 2;
 // Max size of each virtqueue for virtio-scsi.
 const MAX_QUEUE_SIZE_SCSI: u16 = 1024;
 
+/// Default max sectors of a command, the largest multiple of 8 in 16 bits.
+pub const DEFAULT_SCSI_MAX_SECTORS: u32 = 0xFFF8;
+/// Max sectors of a command must be a multiple of 8, so that it's aligned to 4KiB.
+const SCSI_MAX_SECTORS_ALIGN: u32 = 8;
+const MAX_SCSI_MAX_SECTORS: u32 = 0xFFFF;
+/// Default max number of in-flight commands of each lun.
+pub const DEFAULT_SCSI_CMD_PER_LUN: u32 = 128;
+const MAX_SCSI_CMD_PER_LUN: u32 = MAX_QUEUE_SIZE_SCSI as u32;
+
 #[derive(Debug, Clone)]
 pub struct ScsiCntlrConfig {
     /// Virtio-scsi-pci device id.
@@ -46,6 +57,10 @@ pub struct ScsiCntlrConfig {
     pub boot_prefix: Option<String>,
     /// Virtqueue size for all queues.
     pub queue_size: u16,
+    /// Max sectors of 512 bytes transferred by a command.
+    pub max_sectors: u32,
+    /// Max number of in-flight commands of each lun, the excess ones wait in the queue.
+    pub cmd_per_lun: u32,
 }
 
 impl Default for ScsiCntlrConfig {
@@ -57,6 +72,8 @@ impl Default for ScsiCntlrConfig {
             queues: 1,
             boot_prefix: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            max_sectors: DEFAULT_SCSI_MAX_SECTORS,
+            cmd_per_lun: DEFAULT_SCSI_CMD_PER_LUN,
         }
     }
 }
@@ -101,6 +118,32 @@ impl ConfigCheck for ScsiCntlrConfig {
             bail!("Virtqueue size should be power of 2!");
         }
 
+        if self.max_sectors < SCSI_MAX_SECTORS_ALIGN || self.max_sectors > MAX_SCSI_MAX_SECTORS {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "max-sectors of scsi controller".to_string(),
+                SCSI_MAX_SECTORS_ALIGN as u64,
+                true,
+                MAX_SCSI_MAX_SECTORS as u64,
+                true
+            )));
+        }
+        if self.max_sectors & (SCSI_MAX_SECTORS_ALIGN - 1) != 0 {
+            bail!(
+                "max-sectors of scsi controller should be a multiple of {}",
+                SCSI_MAX_SECTORS_ALIGN
+            );
+        }
+
+        if self.cmd_per_lun < 1 || self.cmd_per_lun > MAX_SCSI_CMD_PER_LUN {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "cmd-per-lun of scsi controller".to_string(),
+                1,
+                true,
+                MAX_SCSI_CMD_PER_LUN as u64,
+                true
+            )));
+        }
+
         Ok(())
     }
 }
@@ -118,7 +161,9 @@ pub fn parse_scsi_controller(
         .push("multifunction")
         .push("iothread")
         .push("num-queues")
-        .push("queue-size");
+        .push("queue-size")
+        .push("max-sectors")
+        .push("cmd-per-lun");
 
     cmd_parser.parse(drive_config)?;
 
@@ -140,6 +185,16 @@ pub fn parse_scsi_controller(
     }
 
     if let Some(queues) = cmd_parser.get_value::<u32>("num-queues")? {
+        // Each vCPU has its own request queue at most.
+        if let Some(max_queues) = queues_auto.filter(|max| queues > *max as u32) {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "queues number of scsi controller".to_string(),
+                1,
+                true,
+                max_queues as u64,
+                true,
+            )));
+        }
         cntlr_cfg.queues = queues;
     } else if let Some(queues) = queues_auto {
         cntlr_cfg.queues = queues as u32;
@@ -149,6 +204,14 @@ pub fn parse_scsi_controller(
         cntlr_cfg.queue_size = size;
     }
 
+    if let Some(max_sectors) = cmd_parser.get_value::<u32>("max-sectors")? {
+        cntlr_cfg.max_sectors = max_sectors;
+    }
+
+    if let Some(cmd_per_lun) = cmd_parser.get_value::<u32>("cmd-per-lun")? {
+        cntlr_cfg.cmd_per_lun = cmd_per_lun;
+    }
+
     cntlr_cfg.check()?;
     Ok(cntlr_cfg)
 }
@@ -161,6 +224,8 @@ pub struct ScsiDevConfig {
     pub path_on_host: String,
     /// Serial number of the scsi device.
     pub serial: Option<String>,
+    /// World wide name of the scsi device.
+    pub wwn: Option<u64>,
     /// Scsi bus which the scsi device attaches to.
     pub bus: String,
     /// Scsi device can not do write operation.
@@ -175,6 +240,13 @@ pub struct ScsiDevConfig {
     pub channel: u8,
     pub target: u8,
     pub lun: u16,
+    /// Action on write errors.
+    pub werror: BlockErrorPolicy,
+    /// Action on read errors.
+    pub rerror: BlockErrorPolicy,
+    /// Max transfer length in bytes of WRITE AND VERIFY which compares the written data,
+    /// the data-out buffer is kept in memory until the write completes.
+    pub write_verify_max: u64,
 }
 
 impl Default for ScsiDevConfig {
@@ -183,6 +255,7 @@ impl Default for ScsiDevConfig {
             id: "".to_string(),
             path_on_host: "".to_string(),
             serial: None,
+            wwn: None,
             bus: "".to_string(),
             read_only: false,
             direct: true,
@@ -191,10 +264,21 @@ impl Default for ScsiDevConfig {
             channel: 0,
             target: 0,
             lun: 0,
+            werror: BlockErrorPolicy::default(),
+            rerror: BlockErrorPolicy::default(),
+            write_verify_max: DEFAULT_SCSI_WRITE_VERIFY_MAX,
         }
     }
 }
 
+/// Default max transfer length of WRITE AND VERIFY which compares the written data, 4MiB.
+pub const DEFAULT_SCSI_WRITE_VERIFY_MAX: u64 = 4 << 20;
+/// Upper limit of `write-verify-max`, 256MiB.
+const MAX_SCSI_WRITE_VERIFY_MAX: u64 = 256 << 20;
+
+/// Properties of scsi devices which can be set by `-global`.
+pub const SCSI_DEVICE_GLOBAL_PROPERTIES: &[&str] = &["werror", "rerror"];
+
 pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result<ScsiDevConfig> {
     let mut cmd_parser = CmdParser::new("scsi-device");
     cmd_parser
@@ -204,10 +288,15 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         .push("scsi-id")
         .push("lun")
         .push("serial")
+        .push("wwn")
         .push("bootindex")
-        .push("drive");
+        .push("drive")
+        .push("werror")
+        .push("rerror")
+        .push("write-verify-max");
 
     cmd_parser.parse(drive_config)?;
+    cmd_parser.apply_global_config(&vm_config.global_config);
 
     let mut scsi_dev_cfg = ScsiDevConfig::default();
 
@@ -225,6 +314,11 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         scsi_dev_cfg.serial = Some(serial);
     }
 
+    if let Some(wwn) = cmd_parser.get_value::<String>("wwn")? {
+        let wwn = str_to_usize(wwn.clone()).with_context(|| format!("Invalid wwn: {}", wwn))?;
+        scsi_dev_cfg.wwn = Some(wwn as u64);
+    }
+
     if let Some(id) = cmd_parser.get_value::<String>("id")? {
         scsi_dev_cfg.id = id;
     } else {
@@ -271,7 +365,79 @@ pub fn parse_scsi_device(vm_config: &mut VmConfig, drive_config: &str) -> Result
         scsi_dev_cfg.read_only = drive_arg.read_only;
         scsi_dev_cfg.direct = drive_arg.direct;
         scsi_dev_cfg.aio_type = drive_arg.aio;
+        scsi_dev_cfg.werror = drive_arg.werror;
+        scsi_dev_cfg.rerror = drive_arg.rerror;
+    }
+    if let Some(werror) = cmd_parser.get_value::<BlockErrorPolicy>("werror")? {
+        scsi_dev_cfg.werror = werror;
+    }
+    if let Some(rerror) = cmd_parser.get_value::<BlockErrorPolicy>("rerror")? {
+        scsi_dev_cfg.rerror = rerror;
+    }
+    check_rerror(scsi_dev_cfg.rerror)?;
+
+    if let Some(max) = cmd_parser.get_value::<String>("write-verify-max")? {
+        let max = memory_unit_conversion(&max)?;
+        if max == 0 || max > MAX_SCSI_WRITE_VERIFY_MAX {
+            return Err(anyhow!(ConfigError::IllegalValue(
+                "write-verify-max of scsi device".to_string(),
+                1,
+                true,
+                MAX_SCSI_WRITE_VERIFY_MAX,
+                true,
+            )));
+        }
+        scsi_dev_cfg.write_verify_max = max;
     }
 
     Ok(scsi_dev_cfg)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_scsi_controller() {
+        let cfg =
+            parse_scsi_controller("virtio-scsi-pci,id=scsi0,bus=pcie.0,addr=0x3", Some(4)).unwrap();
+        assert_eq!(cfg.queues, 4);
+        assert_eq!(cfg.max_sectors, DEFAULT_SCSI_MAX_SECTORS);
+        assert_eq!(cfg.cmd_per_lun, DEFAULT_SCSI_CMD_PER_LUN);
+
+        let cfg = parse_scsi_controller(
+            "virtio-scsi-pci,id=scsi0,bus=pcie.0,addr=0x3,num-queues=2,max-sectors=1024,cmd-per-lun=16",
+            Some(4),
+        )
+        .unwrap();
+        assert_eq!(cfg.queues, 2);
+        assert_eq!(cfg.max_sectors, 1024);
+        assert_eq!(cfg.cmd_per_lun, 16);
+
+        // The request queues are no more than vCPUs.
+        let cfg = "virtio-scsi-pci,id=scsi0,bus=pcie.0,addr=0x3,num-queues=4";
+        assert!(parse_scsi_controller(cfg, Some(4)).is_ok());
+        assert!(parse_scsi_controller(cfg, Some(2)).is_err());
+        assert!(parse_scsi_controller(cfg, None).is_ok());
+
+        for max_sectors in ["0", "4", "1020", "65535", "65536"] {
+            let cfg = format!(
+                "virtio-scsi-pci,id=scsi0,bus=pcie.0,addr=0x3,max-sectors={}",
+                max_sectors
+            );
+            assert!(parse_scsi_controller(&cfg, None).is_err());
+        }
+        let cfg = "virtio-scsi-pci,id=scsi0,bus=pcie.0,addr=0x3,max-sectors=8";
+        assert_eq!(parse_scsi_controller(cfg, None).unwrap().max_sectors, 8);
+
+        for cmd_per_lun in ["0", "1025", "-1"] {
+            let cfg = format!(
+                "virtio-scsi-pci,id=scsi0,bus=pcie.0,addr=0x3,cmd-per-lun={}",
+                cmd_per_lun
+            );
+            assert!(parse_scsi_controller(&cfg, None).is_err());
+        }
+        let cfg = "virtio-scsi-pci,id=scsi0,bus=pcie.0,addr=0x3,cmd-per-lun=1024";
+        assert_eq!(parse_scsi_controller(cfg, None).unwrap().cmd_per_lun, 1024);
+    }
+}
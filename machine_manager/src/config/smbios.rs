@@ -0,0 +1,145 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{anyhow, bail, Result};
+use serde::{Deserialize, Serialize};
+
+use super::{error::ConfigError, parse_uuid, CmdParser, VmConfig};
+
+/// Max length of a string in SMBIOS tables.
+pub const SMBIOS_MAX_STRING_LENGTH: usize = 64;
+
+/// Fields of SMBIOS type 1 (System Information) set by `-smbios type=1`.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct SmbiosType1Config {
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+    pub version: Option<String>,
+    pub serial: Option<String>,
+    pub sku: Option<String>,
+    pub family: Option<String>,
+    /// Uuid in format `xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx`, the uuid of the VM is used if
+    /// it's not set.
+    pub uuid: Option<String>,
+}
+
+/// Overrides of the default SMBIOS tables.
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct SmbiosConfig {
+    pub type1: SmbiosType1Config,
+}
+
+/// Check a string of SMBIOS tables. It's terminated by nul in the string set of a
+/// structure, and an empty string would end the string set.
+fn check_smbios_string(field: &str, value: String) -> Result<String> {
+    if value.is_empty() {
+        bail!("The {} of smbios can't be empty", field);
+    }
+    if value.len() > SMBIOS_MAX_STRING_LENGTH {
+        return Err(anyhow!(ConfigError::StringLengthTooLong(
+            format!("smbios {}", field),
+            SMBIOS_MAX_STRING_LENGTH,
+        )));
+    }
+    if value.contains('\0') {
+        bail!("The {} of smbios can't contain nul", field);
+    }
+    Ok(value)
+}
+
+impl VmConfig {
+    /// Add argument `smbios` to `VmConfig`, the fields given by several `-smbios` of the
+    /// same type are merged.
+    ///
+    /// # Arguments
+    ///
+    /// * `smbios` - The config of smbios, e.g. `type=1,manufacturer=vendor,serial=1234`.
+    pub fn add_smbios(&mut self, smbios: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::for_option("smbios");
+        cmd_parser.parse(smbios)?;
+
+        match cmd_parser.get_value::<u8>("type")? {
+            Some(1) => {}
+            Some(smbios_type) => bail!("Smbios type {} is not supported", smbios_type),
+            None => return Err(anyhow!(ConfigError::FieldIsMissing("type", "smbios"))),
+        }
+
+        let type1 = &mut self.smbios.type1;
+        for (field, value) in [
+            ("manufacturer", &mut type1.manufacturer),
+            ("product", &mut type1.product),
+            ("version", &mut type1.version),
+            ("serial", &mut type1.serial),
+            ("sku", &mut type1.sku),
+            ("family", &mut type1.family),
+        ] {
+            if let Some(s) = cmd_parser.get_value::<String>(field)? {
+                *value = Some(check_smbios_string(field, s)?);
+            }
+        }
+        if let Some(uuid) = cmd_parser.get_value::<String>("uuid")? {
+            parse_uuid(&uuid)?;
+            type1.uuid = Some(uuid.to_lowercase());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_smbios() {
+        let mut vm_config = VmConfig::default();
+        vm_config
+            .add_smbios("type=1,manufacturer=Vendor,product=Cloud VM,serial=SN-0001")
+            .unwrap();
+        vm_config
+            .add_smbios("type=1,uuid=A1B2C3D4-0000-1111-2222-333344445555,family=virt")
+            .unwrap();
+        let type1 = &vm_config.smbios.type1;
+        assert_eq!(type1.manufacturer.as_deref(), Some("Vendor"));
+        assert_eq!(type1.product.as_deref(), Some("Cloud VM"));
+        assert_eq!(type1.serial.as_deref(), Some("SN-0001"));
+        assert_eq!(type1.family.as_deref(), Some("virt"));
+        assert_eq!(
+            type1.uuid.as_deref(),
+            Some("a1b2c3d4-0000-1111-2222-333344445555")
+        );
+        assert!(type1.version.is_none());
+        assert!(type1.sku.is_none());
+
+        // Later value overrides the former one.
+        vm_config.add_smbios("type=1,serial=SN-0002").unwrap();
+        assert_eq!(vm_config.smbios.type1.serial.as_deref(), Some("SN-0002"));
+    }
+
+    #[test]
+    fn test_add_smbios_invalid() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_smbios("manufacturer=Vendor").is_err());
+        assert!(vm_config.add_smbios("type=0,vendor=Vendor").is_err());
+        assert!(vm_config.add_smbios("type=2,manufacturer=Vendor").is_err());
+        assert!(vm_config.add_smbios("type=1,asset=1234").is_err());
+        assert!(vm_config.add_smbios("type=1,serial=").is_err());
+        assert!(vm_config.add_smbios("type=1,uuid=1234").is_err());
+        let long_serial = "s".repeat(SMBIOS_MAX_STRING_LENGTH + 1);
+        assert!(vm_config
+            .add_smbios(&format!("type=1,serial={}", long_serial))
+            .is_err());
+        let serial = "s".repeat(SMBIOS_MAX_STRING_LENGTH);
+        assert!(vm_config
+            .add_smbios(&format!("type=1,serial={}", serial))
+            .is_ok());
+    }
+}
@@ -0,0 +1,110 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::hash_map::Entry;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use super::drive::MAX_IOPS;
+use crate::config::{
+    ConfigError, {CmdParser, VmConfig},
+};
+
+/// Config of `throttle-group` object, whose limits are shared by all the drives
+/// referencing it by `throttling.group`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ThrottleGroupConfig {
+    /// Object Id.
+    pub id: String,
+    /// Total iops of all the member drives.
+    pub iops: u64,
+}
+
+impl VmConfig {
+    pub fn add_throttle_group(&mut self, throttle_group_config: &str) -> Result<()> {
+        let mut cmd_parser = CmdParser::new("throttle-group");
+        cmd_parser.push("").push("id").push("limits.iops-total");
+        cmd_parser.parse(throttle_group_config)?;
+
+        let mut throttle_group = ThrottleGroupConfig::default();
+        if let Some(id) = cmd_parser.get_value::<String>("id")? {
+            throttle_group.id = id;
+        } else {
+            return Err(anyhow!(ConfigError::FieldIsMissing("id", "throttle-group")));
+        }
+
+        if let Some(iops) = cmd_parser.get_value::<u64>("limits.iops-total")? {
+            if iops == 0 || iops > MAX_IOPS {
+                return Err(anyhow!(ConfigError::IllegalValue(
+                    "iops of throttle group".to_string(),
+                    1,
+                    true,
+                    MAX_IOPS,
+                    true,
+                )));
+            }
+            throttle_group.iops = iops;
+        } else {
+            return Err(anyhow!(ConfigError::FieldIsMissing(
+                "limits.iops-total",
+                "throttle-group"
+            )));
+        }
+
+        match self.object.throttle_group.entry(throttle_group.id.clone()) {
+            Entry::Vacant(entry) => {
+                entry.insert(throttle_group);
+            }
+            Entry::Occupied(entry) => {
+                return Err(anyhow!(ConfigError::IdRepeat(
+                    "throttle-group".to_string(),
+                    entry.key().clone()
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_throttle_group() {
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config
+            .add_object("throttle-group,id=tg0,limits.iops-total=500")
+            .is_ok());
+        let group = vm_config.object.throttle_group.get("tg0").unwrap();
+        assert_eq!(group.iops, 500);
+
+        // Id is repeated.
+        assert!(vm_config
+            .add_object("throttle-group,id=tg0,limits.iops-total=100")
+            .is_err());
+        // Limit is missing or invalid.
+        assert!(vm_config.add_object("throttle-group,id=tg1").is_err());
+        assert!(vm_config
+            .add_object("throttle-group,id=tg1,limits.iops-total=0")
+            .is_err());
+        assert!(vm_config
+            .add_object("throttle-group,id=tg1,limits.iops-total=1000001")
+            .is_err());
+        // Id is missing.
+        assert!(vm_config
+            .add_object("throttle-group,limits.iops-total=100")
+            .is_err());
+    }
+}
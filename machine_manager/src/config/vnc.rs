@@ -30,6 +30,10 @@ pub struct VncConfig {
     pub sasl: bool,
     /// Configuration of authentication.
     pub sasl_authz: String,
+    /// File of `username:password` lines for VeNCrypt plain authentication.
+    pub password_file: Option<String>,
+    /// Listening port for clients over websocket.
+    pub websocket: Option<String>,
 }
 
 const VNC_MAX_PORT_NUM: i32 = 65535;
@@ -38,12 +42,7 @@ const VNC_PORT_OFFSET: i32 = 5900;
 impl VmConfig {
     /// Make configuration for vnc: "chardev" -> "vnc".
     pub fn add_vnc(&mut self, vnc_config: &str) -> Result<()> {
-        let mut cmd_parser = CmdParser::new("vnc");
-        cmd_parser
-            .push("")
-            .push("tls-creds")
-            .push("sasl")
-            .push("sasl-authz");
+        let mut cmd_parser = CmdParser::for_option("vnc");
         cmd_parser.parse(vnc_config)?;
 
         let mut vnc_config = VncConfig::default();
@@ -66,6 +65,29 @@ impl VmConfig {
         if let Some(sasl_authz) = cmd_parser.get_value::<String>("sasl-authz")? {
             vnc_config.sasl_authz = sasl_authz;
         }
+        if let Some(password_file) = cmd_parser.get_value::<String>("password-file")? {
+            // The password is sent in clear text, so it's only allowed over tls.
+            if vnc_config.tls_creds.is_empty() {
+                return Err(anyhow!("Vnc password-file must be used with tls-creds"));
+            }
+            if vnc_config.sasl {
+                return Err(anyhow!("Vnc password-file can't be used with sasl"));
+            }
+            vnc_config.password_file = Some(password_file);
+        }
+        if let Some(ws_port) = cmd_parser.get_value::<u16>("websocket")? {
+            if ws_port == 0 || ws_port.to_string() == vnc_config.port {
+                return Err(anyhow!(ConfigError::InvalidParam(
+                    ws_port.to_string(),
+                    "websocket".to_string()
+                )));
+            }
+            // Only binary frames over plain TCP are supported for websocket.
+            if !vnc_config.tls_creds.is_empty() {
+                return Err(anyhow!("Vnc websocket does not support tls-creds"));
+            }
+            vnc_config.websocket = Some(ws_port.to_string());
+        }
 
         self.vnc = Some(vnc_config);
         Ok(())
@@ -126,6 +148,41 @@ mod tests {
         let vnc_config = vm_config.vnc.unwrap();
         assert_eq!(vnc_config.tls_creds, "".to_string());
 
+        let mut vm_config = VmConfig::default();
+        let config_line = "0.0.0.0:1,tls-creds=vnc-tls-creds0,password-file=/etc/vnc/passwd";
+        assert!(vm_config.add_vnc(config_line).is_ok());
+        let vnc_config = vm_config.vnc.unwrap();
+        assert_eq!(
+            vnc_config.password_file,
+            Some(String::from("/etc/vnc/passwd"))
+        );
+        let config_lines = [
+            "0.0.0.0:1,password-file=/etc/vnc/passwd",
+            "0.0.0.0:1,tls-creds=vnc-tls-creds0,sasl,password-file=/etc/vnc/passwd",
+        ];
+        for config_line in config_lines {
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config.add_vnc(config_line).is_err());
+        }
+
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_vnc("0.0.0.0:1,websocket=5700").is_ok());
+        let vnc_config = vm_config.vnc.unwrap();
+        assert_eq!(vnc_config.websocket, Some(String::from("5700")));
+        let mut vm_config = VmConfig::default();
+        assert!(vm_config.add_vnc("0.0.0.0:1").is_ok());
+        assert!(vm_config.vnc.unwrap().websocket.is_none());
+        let config_lines = [
+            "0.0.0.0:1,websocket=0",
+            "0.0.0.0:1,websocket=5901",
+            "0.0.0.0:1,websocket=65536",
+            "0.0.0.0:1,websocket=5700,tls-creds=vnc-tls-creds0",
+        ];
+        for config_line in config_lines {
+            let mut vm_config = VmConfig::default();
+            assert!(vm_config.add_vnc(config_line).is_err());
+        }
+
         // Invalie format of ip:port.
         let config_lines = [
             "tls-creds=vnc-tls-creds0", // No ip:port.
@@ -12,6 +12,7 @@
 
 use std::collections::HashMap;
 use std::os::unix::prelude::RawFd;
+use std::os::unix::thread::JoinHandleExt;
 use std::sync::{Arc, Mutex};
 use std::{process, thread};
 
@@ -21,6 +22,7 @@ use crate::qmp::qmp_schema::IothreadInfo;
 
 use anyhow::bail;
 use log::info;
+use once_cell::sync::Lazy;
 use util::loop_context::{
     gen_delete_notifiers, get_notifiers_fds, EventLoopContext, EventLoopManager, EventNotifier,
 };
@@ -39,6 +41,9 @@ pub struct EventLoop {
 }
 
 static mut GLOBAL_EVENT_LOOP: Option<EventLoop> = None;
+/// Threads running the io-thread loops.
+static IOTHREAD_HANDLES: Lazy<Mutex<HashMap<String, thread::JoinHandle<()>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
 impl EventLoop {
     /// Init GLOBAL_EVENT_LOOP, include main loop and io-threads loop
@@ -65,21 +70,23 @@ impl EventLoop {
 
                 if let Some(event_loop) = GLOBAL_EVENT_LOOP.as_mut() {
                     for (id, ctx) in &mut event_loop.io_threads {
-                        thread::Builder::new().name(id.to_string()).spawn(move || {
-                            let iothread_info = IothreadInfo {
-                                shrink: 0,
-                                pid: process::id(),
-                                grow: 0,
-                                max: 0,
-                                id: id.to_string(),
-                            };
-                            IOTHREADS.lock().unwrap().push(iothread_info);
-                            while let Ok(ret) = ctx.iothread_run() {
-                                if !ret {
-                                    break;
+                        let handle =
+                            thread::Builder::new().name(id.to_string()).spawn(move || {
+                                let iothread_info = IothreadInfo {
+                                    shrink: 0,
+                                    pid: process::id(),
+                                    grow: 0,
+                                    max: 0,
+                                    id: id.to_string(),
+                                };
+                                IOTHREADS.lock().unwrap().push(iothread_info);
+                                while let Ok(ret) = ctx.iothread_run() {
+                                    if !ret {
+                                        break;
+                                    }
                                 }
-                            }
-                        })?;
+                            })?;
+                        IOTHREAD_HANDLES.lock().unwrap().insert(id.clone(), handle);
                     }
                 } else {
                     bail!("Global Event Loop have not been initialized.")
@@ -110,6 +117,19 @@ impl EventLoop {
         panic!("Global Event Loop have not been initialized.");
     }
 
+    /// Pin the io-thread specified by `name` to the host cpus.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The id of the io-thread.
+    /// * `cpus` - Index of the host cpus.
+    pub fn set_iothread_affinity(name: &str, cpus: &[usize]) -> util::Result<()> {
+        match IOTHREAD_HANDLES.lock().unwrap().get(name) {
+            Some(handle) => util::unix::set_thread_affinity(handle.as_pthread_t(), cpus),
+            None => bail!("IOThread {} is not found.", name),
+        }
+    }
+
     /// Set a `manager` to event loop
     ///
     /// # Arguments
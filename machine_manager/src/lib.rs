@@ -20,13 +20,17 @@
 //! 1. A communication way to handle VM outside.
 //! 2. The API interface over VM inside and outside.
 //! 3. Configuration for VM and its devices.
+//! 4. Object model of VM for device introspection.
+//! 5. Client of the guest agent.
 
 pub mod cmdline;
 pub mod config;
 pub mod error;
 pub mod event_loop;
 pub mod machine;
+pub mod qga;
 pub mod qmp;
+pub mod qom;
 pub mod signal_handler;
 pub mod socket;
 pub mod temp_cleaner;
@@ -16,19 +16,18 @@ use std::sync::Mutex;
 use once_cell::sync::Lazy;
 use strum::VariantNames;
 
-use crate::config::ShutdownAction;
+use crate::cmdline::query_command_line_options;
+use crate::config::{RebootAction, ShutdownAction};
+use crate::event;
 use crate::qmp::qmp_schema::{
-    BlockDevAddArgument, CharDevAddArgument, ChardevInfo, Cmd, CmdLine, DeviceAddArgument,
-    DeviceProps, Events, GicCap, IothreadInfo, KvmInfo, MachineInfo, MigrateCapabilities,
-    NetDevAddArgument, PropList, QmpCommand, QmpEvent, Target, TypeLists, UpdateRegionArgument,
+    AddfdInfo, BackendOptions, BlockDevAddArgument, BlockJobInfo, BlockStatsInfo,
+    CharDevAddArgument, ChardevInfo, Cmd, CpuModelInfo, DeviceAddArgument, DeviceProps,
+    DumpGuestMemoryArgument, Events, GicCap, IothreadInfo, KvmInfo, MachineInfo,
+    MigrateCapabilities, MigrateSetParametersArgument, MigrationInfo, NetDevAddArgument,
+    QmpCommand, QmpErrorClass, QmpEvent, Shutdown, Target, TypeLists, UpdateRegionArgument,
 };
-use crate::qmp::{Response, Version};
-
-#[derive(Clone)]
-pub struct PathInfo {
-    pub path: String,
-    pub label: String,
-}
+use crate::qmp::{QmpChannel, Response, Version};
+use crate::qom;
 
 /// State for KVM VM.
 #[derive(PartialEq, Eq, Copy, Clone, Debug)]
@@ -39,6 +38,8 @@ pub enum KvmVmState {
     Migrated = 4,
     Paused = 5,
     Shutdown = 6,
+    Suspended = 7,
+    Destroyed = 8,
 }
 
 /// Event over StratoVirt lifetime.
@@ -59,7 +60,10 @@ pub enum VmEvent {
 /// `Created` --`(start)`--> `Running`
 /// `Running` --`(pause)`--> `Paused`
 /// `Paused` --`(resume)`--> `Running`
-/// `KVM_VMSTATE_*` --`(destroy)`--> `None`
+/// `Suspended` --`(wakeup)`--> `Running`
+/// `Running` --`(guest shutdown without poweroff)`--> `Shutdown`
+/// `Shutdown` --`(reset)`--> `Running`
+/// `KVM_VMSTATE_*` --`(destroy)`--> `Destroyed`
 ///
 /// **Notice**:
 ///    1. Migrate state(`Migrated` and `InMigrating`),
@@ -89,17 +93,17 @@ pub trait MachineLifecycle {
 
     /// Close VM or Device, stop running.
     fn destroy(&self) -> bool {
-        self.notify_lifecycle(KvmVmState::Running, KvmVmState::Shutdown)
+        self.notify_lifecycle(KvmVmState::Running, KvmVmState::Destroyed)
     }
 
     /// Close VM by power_button.
     fn powerdown(&self) -> bool {
-        self.notify_lifecycle(KvmVmState::Running, KvmVmState::Shutdown)
+        self.notify_lifecycle(KvmVmState::Running, KvmVmState::Destroyed)
     }
 
     /// Reset VM, stop running and restart a new VM.
     fn reset(&mut self) -> bool {
-        self.notify_lifecycle(KvmVmState::Running, KvmVmState::Shutdown)
+        self.notify_lifecycle(KvmVmState::Running, KvmVmState::Destroyed)
     }
 
     /// When VM or Device life state changed, notify concerned entry.
@@ -114,6 +118,55 @@ pub trait MachineLifecycle {
     fn get_shutdown_action(&self) -> ShutdownAction {
         ShutdownAction::ShutdownActionPoweroff
     }
+
+    /// Get reboot_action to determine the operation when guest resets.
+    fn get_reboot_action(&self) -> RebootAction {
+        RebootAction::Reset
+    }
+
+    /// Handle the shutdown requested by guest according to the shutdown action.
+    /// With `pause`, the VM is stopped in `Shutdown` state and kept alive until it's
+    /// reset or destroyed. The SHUTDOWN event is sent first, then the STOP event.
+    ///
+    /// # Arguments
+    ///
+    /// * `reason` - The reason reported in the SHUTDOWN event.
+    fn shutdown_by_guest(&self, reason: &str) -> bool {
+        if QmpChannel::is_connected() {
+            let shutdown_msg = Shutdown {
+                guest: true,
+                reason: reason.to_string(),
+            };
+            event!(Shutdown; shutdown_msg);
+        }
+
+        match self.get_shutdown_action() {
+            ShutdownAction::ShutdownActionPoweroff => self.destroy(),
+            ShutdownAction::ShutdownActionPause => {
+                if !self.notify_lifecycle(KvmVmState::Running, KvmVmState::Shutdown) {
+                    return false;
+                }
+                event!(Stop);
+                true
+            }
+        }
+    }
+
+    /// Handle the reset requested by guest according to the reboot action.
+    /// Returns `true` if the VM should go on resetting.
+    fn reboot_by_guest(&self) -> bool {
+        match self.get_reboot_action() {
+            RebootAction::Reset => true,
+            RebootAction::Shutdown => {
+                self.shutdown_by_guest("guest-reset");
+                false
+            }
+            RebootAction::Pause => {
+                self.pause();
+                false
+            }
+        }
+    }
 }
 
 /// `AddressSpace` access interface of `Machine`.
@@ -154,6 +207,12 @@ pub trait DeviceInterface {
     /// Query each cpu's the topology info.
     fn query_cpus(&self) -> Response;
 
+    /// Expand the CPU model to its feature flags.
+    fn query_cpu_model_expansion(&self, expansion_type: String, model: CpuModelInfo) -> Response;
+
+    /// Query the cpu models supported by StratoVirt.
+    fn query_cpu_definitions(&self) -> Response;
+
     /// Query each `hotpluggable_cpus`'s topology info and hotplug message.
     fn query_hotpluggable_cpus(&self) -> Response;
 
@@ -180,18 +239,146 @@ pub trait DeviceInterface {
     /// Remove a chardev device.
     fn chardev_remove(&mut self, _id: String) -> Response;
 
+    /// Change the backend of a chardev device.
+    fn chardev_change(&mut self, _id: String, _backend: BackendOptions) -> Response;
+
     /// Receive a file descriptor via SCM rights and assign it a name.
     fn getfd(&self, fd_name: String, if_fd: Option<RawFd>) -> Response;
 
+    /// Close a file descriptor received by `getfd`.
+    fn closefd(&self, fd_name: String) -> Response {
+        match QmpChannel::remove_fd(&fd_name) {
+            Some(fd) => {
+                // SAFETY: the fd is removed from qmp channel and not used anymore.
+                unsafe { libc::close(fd) };
+                Response::create_empty_response()
+            }
+            None => Response::create_error_response(
+                QmpErrorClass::GenericError(format!(
+                    "File descriptor named '{}' not found",
+                    fd_name
+                )),
+                None,
+            ),
+        }
+    }
+
+    /// Receive a file descriptor via SCM rights and add it to a fdset.
+    fn add_fd(
+        &self,
+        fdset_id: Option<u64>,
+        opaque: Option<String>,
+        if_fd: Option<RawFd>,
+    ) -> Response {
+        let fd = match if_fd {
+            Some(fd) => fd,
+            None => {
+                return Response::create_error_response(
+                    QmpErrorClass::GenericError("Invalid SCM message".to_string()),
+                    None,
+                );
+            }
+        };
+        match QmpChannel::add_fdset_fd(fdset_id, fd, opaque) {
+            Ok(fdset_id) => {
+                let info = AddfdInfo { fdset_id, fd };
+                Response::create_response(serde_json::to_value(info).unwrap(), None)
+            }
+            Err(e) => {
+                Response::create_error_response(QmpErrorClass::GenericError(e.to_string()), None)
+            }
+        }
+    }
+
+    /// Remove a file descriptor from a fdset, or the whole fdset if `fd` is absent.
+    fn remove_fd(&self, fdset_id: u64, fd: Option<RawFd>) -> Response {
+        match QmpChannel::remove_fdset_fd(fdset_id, fd) {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                Response::create_error_response(QmpErrorClass::GenericError(e.to_string()), None)
+            }
+        }
+    }
+
+    /// Query the fdsets and the file descriptors in them.
+    fn query_fdsets(&self) -> Response {
+        let fdsets = QmpChannel::query_fdsets();
+        Response::create_response(serde_json::to_value(fdsets).unwrap(), None)
+    }
+
     /// Query balloon's size.
     fn query_balloon(&self) -> Response;
 
+    /// Query the counters of guest memory aging.
+    fn query_mem_aging(&self) -> Response;
+
+    /// Query whether guest memory is locked in host RAM.
+    fn query_memory_lock(&self) -> Response;
+
+    /// Query the placement of the VM on host NUMA nodes.
+    fn query_placement(&self) -> Response;
+
     /// Query the info of vnc server.
     fn query_vnc(&self) -> Response;
 
+    /// Query the name of the guest.
+    fn query_name(&self) -> Response;
+
+    /// Query the uuid of the guest.
+    fn query_uuid(&self) -> Response;
+
+    /// Query the guest-cid and vhostfd of vsock devices.
+    fn query_vsock(&self) -> Response;
+
+    /// Query block devices and their error statistics.
+    fn query_block(&self, reset_errors: Option<bool>) -> Response;
+
+    /// Query net devices and their error statistics.
+    fn query_netdev(&self, reset_errors: Option<bool>) -> Response;
+
+    /// Set the write threshold of a disk.
+    fn block_set_write_threshold(&self, device: String, write_threshold: u64) -> Response;
+
+    /// Restore the write access of a disk turned read-only after ENOSPC.
+    fn block_set_writable(&self, device: String) -> Response;
+
+    /// Resize a disk and notify the guest of the new capacity.
+    fn block_resize(&self, device: String, size: u64, allow_shrink: Option<bool>) -> Response;
+
+    /// Eject the medium of a removable device.
+    fn eject(&self, device: String, force: Option<bool>) -> Response;
+
+    /// Start a backup job of a disk.
+    fn blockdev_backup(&self, device: String, target: String, sync: Option<String>) -> Response;
+
+    /// Cancel the block job of a disk.
+    fn block_job_cancel(&self, device: String) -> Response;
+
+    /// Dump the guest memory and vCPU registers to an ELF core file.
+    fn dump_guest_memory(&mut self, args: DumpGuestMemoryArgument) -> Response;
+
+    /// Save the surface of the active display console to an image file.
+    fn screendump(&self, _filename: String, _format: Option<String>) -> Response {
+        Response::create_error_response(
+            QmpErrorClass::GenericError("The display is not supported".to_string()),
+            None,
+        )
+    }
+
     /// Set balloon's size.
     fn balloon(&self, size: u64) -> Response;
 
+    /// Inject NMI to all the vCPUs.
+    fn inject_nmi(&self) -> Response;
+
+    /// Wake up the suspended guest.
+    fn system_wakeup(&self) -> Response;
+
+    /// Reset the count of the lost RTC interrupts, nothing to do as they are never reinjected.
+    fn rtc_reset_reinjection(&self) -> Response {
+        Response::create_empty_response()
+    }
+
     /// Query the version of StratoVirt.
     fn query_version(&self) -> Response {
         let version = Version::new(1, 0, 5);
@@ -246,44 +433,8 @@ pub trait DeviceInterface {
 
     /// Query machine types supported by StratoVirt.
     fn query_machines(&self) -> Response {
-        let mut vec_machine = Vec::new();
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "none".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        vec_machine.push(machine_info);
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "microvm".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        vec_machine.push(machine_info);
-        #[cfg(target_arch = "x86_64")]
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "q35".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        #[cfg(target_arch = "x86_64")]
-        vec_machine.push(machine_info);
-        #[cfg(target_arch = "aarch64")]
-        let machine_info = MachineInfo {
-            hotplug: false,
-            name: "virt".to_string(),
-            numa_mem_support: false,
-            cpu_max: 255,
-            deprecated: false,
-        };
-        #[cfg(target_arch = "aarch64")]
-        vec_machine.push(machine_info);
-        Response::create_response(serde_json::to_value(&vec_machine).unwrap(), None)
+        let machine_types = MACHINE_TYPES.lock().unwrap();
+        Response::create_response(serde_json::to_value(&*machine_types).unwrap(), None)
     }
 
     /// Get the list type
@@ -351,13 +502,19 @@ pub trait DeviceInterface {
         Response::create_response(serde_json::to_value(tpm_types).unwrap(), None)
     }
 
-    fn query_command_line_options(&self) -> Response {
-        let cmd_lines = Vec::<CmdLine>::new();
-        Response::create_response(serde_json::to_value(cmd_lines).unwrap(), None)
+    fn query_command_line_options(&self, option: Option<String>) -> Response {
+        match query_command_line_options(option.as_deref()) {
+            Ok(cmd_lines) => {
+                Response::create_response(serde_json::to_value(cmd_lines).unwrap(), None)
+            }
+            Err(e) => {
+                Response::create_error_response(QmpErrorClass::GenericError(e.to_string()), None)
+            }
+        }
     }
 
     fn query_migrate_capabilities(&self) -> Response {
-        let caps = Vec::<MigrateCapabilities>::new();
+        let caps = QmpChannel::migration_state().lock().unwrap().capabilities();
         Response::create_response(serde_json::to_value(caps).unwrap(), None)
     }
 
@@ -370,34 +527,39 @@ pub trait DeviceInterface {
     }
 
     fn query_chardev(&self) -> Response {
-        let mut vec_chardev_info: Vec<ChardevInfo> = Vec::new();
-        let locked_paths = PTY_PATH.lock().unwrap().clone();
-        for path in locked_paths.iter() {
-            let chardev_path = &path.path;
-            let chardev_label = &path.label;
-            let info = ChardevInfo {
-                open: true,
-                filename: chardev_path.to_string().replace('\"', ""),
-                label: chardev_label.to_string().replace('\"', ""),
-            };
-            vec_chardev_info.push(info);
+        let vec_chardev_info: Vec<ChardevInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_chardev_info).unwrap(), None)
+    }
+
+    /// List the children and properties of the object at `path`.
+    fn qom_list(&self, path: String) -> Response {
+        match qom::list_object(&path) {
+            Some(props) => Response::create_response(serde_json::to_value(props).unwrap(), None),
+            None => Response::create_error_response(
+                QmpErrorClass::DeviceNotFound(format!("Device '{}' not found", path)),
+                None,
+            ),
         }
-        Response::create_response(serde_json::to_value(&vec_chardev_info).unwrap(), None)
-    }
-
-    fn qom_list(&self) -> Response {
-        let vec_cmd: Vec<PropList> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
     }
 
-    fn qom_get(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
-    }
-
-    fn query_block(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+    /// Get the value of the `property` of the object at `path`.
+    fn qom_get(&self, path: String, property: String) -> Response {
+        if !qom::object_exists(&path) {
+            return Response::create_error_response(
+                QmpErrorClass::DeviceNotFound(format!("Device '{}' not found", path)),
+                None,
+            );
+        }
+        match qom::get_object_property(&path, &property) {
+            Some(value) => Response::create_response(value, None),
+            None => Response::create_error_response(
+                QmpErrorClass::GenericError(format!(
+                    "Property '{}' not found in '{}'",
+                    property, path
+                )),
+                None,
+            ),
+        }
     }
 
     fn query_named_block_nodes(&self) -> Response {
@@ -406,14 +568,13 @@ pub trait DeviceInterface {
     }
 
     fn query_blockstats(&self) -> Response {
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_stats: Vec<BlockStatsInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_stats).unwrap(), None)
     }
 
     fn query_block_jobs(&self) -> Response {
-        // Fix me: qmp command call, return none temporarily.
-        let vec_cmd: Vec<ChardevInfo> = Vec::new();
-        Response::create_response(serde_json::to_value(vec_cmd).unwrap(), None)
+        let vec_jobs: Vec<BlockJobInfo> = Vec::new();
+        Response::create_response(serde_json::to_value(vec_jobs).unwrap(), None)
     }
 
     fn query_gic_capabilities(&self) -> Response {
@@ -451,12 +612,45 @@ pub trait MigrateInterface {
 
     /// Returns information about current migration.
     fn query_migrate(&self) -> Response {
-        Response::create_empty_response()
+        let migration_info = MigrationInfo {
+            status: Some("none".to_string()),
+        };
+        Response::create_response(serde_json::to_value(migration_info).unwrap(), None)
     }
 
     fn cancel_migrate(&self) -> Response {
         Response::create_empty_response()
     }
+
+    /// Enable or disable the capabilities of migration.
+    fn migrate_set_capabilities(&self, capabilities: Vec<MigrateCapabilities>) -> Response {
+        let migration = QmpChannel::migration_state();
+        let result = migration.lock().unwrap().set_capabilities(&capabilities);
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                Response::create_error_response(QmpErrorClass::GenericError(e.to_string()), None)
+            }
+        }
+    }
+
+    /// Set the parameters of migration.
+    fn migrate_set_parameters(&self, args: MigrateSetParametersArgument) -> Response {
+        let migration = QmpChannel::migration_state();
+        let result = migration.lock().unwrap().set_parameters(&args);
+        match result {
+            Ok(()) => Response::create_empty_response(),
+            Err(e) => {
+                Response::create_error_response(QmpErrorClass::GenericError(e.to_string()), None)
+            }
+        }
+    }
+
+    /// Query the parameters of migration.
+    fn query_migrate_parameters(&self) -> Response {
+        let params = QmpChannel::migration_state().lock().unwrap().parameters();
+        Response::create_response(serde_json::to_value(params).unwrap(), None)
+    }
 }
 
 /// Machine interface which is exposed to inner hypervisor.
@@ -468,5 +662,119 @@ pub trait MachineExternalInterface: MachineLifecycle + DeviceInterface + Migrate
 /// Machine interface which is exposed to test server.
 pub trait MachineTestInterface: MachineAddressInterface {}
 
-pub static PTY_PATH: Lazy<Mutex<Vec<PathInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
 pub static IOTHREADS: Lazy<Mutex<Vec<IothreadInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Machine types registered by the machine crate, which are reported by `query-machines`.
+pub static MACHINE_TYPES: Lazy<Mutex<Vec<MachineInfo>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Register the machine type to `query-machines`, replacing the registered one with the
+/// same name.
+pub fn register_machine_type(info: MachineInfo) {
+    let mut machine_types = MACHINE_TYPES.lock().unwrap();
+    match machine_types.iter_mut().find(|m| m.name == info.name) {
+        Some(machine) => *machine = info,
+        None => machine_types.push(info),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use util::loop_context::EventLoopManager;
+
+    struct TestVm {
+        state: Mutex<KvmVmState>,
+        shutdown_action: ShutdownAction,
+        reboot_action: RebootAction,
+    }
+
+    impl TestVm {
+        fn new(shutdown_action: ShutdownAction, reboot_action: RebootAction) -> Self {
+            TestVm {
+                state: Mutex::new(KvmVmState::Running),
+                shutdown_action,
+                reboot_action,
+            }
+        }
+
+        fn state(&self) -> KvmVmState {
+            *self.state.lock().unwrap()
+        }
+    }
+
+    impl MachineLifecycle for TestVm {
+        fn notify_lifecycle(&self, old: KvmVmState, new: KvmVmState) -> bool {
+            let mut state = self.state.lock().unwrap();
+            if *state != old {
+                return false;
+            }
+            *state = new;
+            true
+        }
+
+        fn get_shutdown_action(&self) -> ShutdownAction {
+            self.shutdown_action
+        }
+
+        fn get_reboot_action(&self) -> RebootAction {
+            self.reboot_action
+        }
+    }
+
+    impl EventLoopManager for TestVm {
+        fn loop_should_exit(&self) -> bool {
+            self.state() == KvmVmState::Destroyed
+        }
+
+        fn loop_cleanup(&self) -> util::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_shutdown_action() {
+        QmpChannel::object_init();
+        let vm = TestVm::new(ShutdownAction::ShutdownActionPoweroff, RebootAction::Reset);
+        assert!(vm.shutdown_by_guest("guest-shutdown"));
+        assert_eq!(vm.state(), KvmVmState::Destroyed);
+        assert!(vm.loop_should_exit());
+
+        // With `-no-shutdown`, the VM is kept in `Shutdown` state after the guest powers
+        // off, and the main loop goes on until quit.
+        let vm = TestVm::new(ShutdownAction::ShutdownActionPause, RebootAction::Reset);
+        assert!(vm.shutdown_by_guest("guest-shutdown"));
+        assert_eq!(vm.state(), KvmVmState::Shutdown);
+        assert!(!vm.loop_should_exit());
+        // The VM shut down can't be resumed.
+        assert!(!vm.resume());
+        assert!(!vm.shutdown_by_guest("guest-shutdown"));
+        assert_eq!(vm.state(), KvmVmState::Shutdown);
+        assert!(!vm.loop_should_exit());
+        assert!(vm.notify_lifecycle(KvmVmState::Shutdown, KvmVmState::Destroyed));
+        assert!(vm.loop_should_exit());
+    }
+
+    #[test]
+    fn test_reboot_action() {
+        QmpChannel::object_init();
+        let vm = TestVm::new(ShutdownAction::ShutdownActionPoweroff, RebootAction::Reset);
+        assert!(vm.reboot_by_guest());
+        assert_eq!(vm.state(), KvmVmState::Running);
+
+        let vm = TestVm::new(ShutdownAction::ShutdownActionPoweroff, RebootAction::Pause);
+        assert!(!vm.reboot_by_guest());
+        assert_eq!(vm.state(), KvmVmState::Paused);
+
+        let vm = TestVm::new(
+            ShutdownAction::ShutdownActionPoweroff,
+            RebootAction::Shutdown,
+        );
+        assert!(!vm.reboot_by_guest());
+        assert_eq!(vm.state(), KvmVmState::Destroyed);
+
+        // The shutdown action applies to the reset turned into shutdown.
+        let vm = TestVm::new(ShutdownAction::ShutdownActionPause, RebootAction::Shutdown);
+        assert!(!vm.reboot_by_guest());
+        assert_eq!(vm.state(), KvmVmState::Shutdown);
+    }
+}
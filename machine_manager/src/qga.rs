@@ -0,0 +1,582 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Client of the guest agent for `guest-agent-command`.
+//!
+//! The guest agent, such as qemu-guest-agent, listens on the virtio-serial port
+//! `org.qemu.guest_agent.0`. StratoVirt talks to the port in the process: the commands
+//! are passed to the port directly, and the output of the port is taken from the
+//! backend of its chardev while a command is in flight. It all runs in the main loop,
+//! and the commands are sent one by one. If a command times out, the late reply may
+//! still be in the channel, so the channel is resynchronized by `guest-sync-delimited`
+//! before the next command.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, warn};
+use once_cell::sync::Lazy;
+use serde_json::{json, Value};
+
+use crate::event_loop::EventLoop;
+
+/// Name of the virtio-serial port of the guest agent.
+pub const GUEST_AGENT_PORT_NAME: &str = "org.qemu.guest_agent.0";
+/// Default timeout in milliseconds to wait for the reply of the guest agent.
+pub const DEFAULT_GUEST_AGENT_TIMEOUT_MS: u64 = 5000;
+/// The byte which resets the parser of the guest agent, it also precedes the reply
+/// of `guest-sync-delimited`.
+const SYNC_BYTE: u8 = 0xFF;
+
+/// State of the guest agent channel, the commands are queued in it.
+static GUEST_AGENT: Lazy<Mutex<GuestAgent>> = Lazy::new(|| Mutex::new(GuestAgent::default()));
+
+/// The port of the guest agent, which is talked to in the process.
+pub trait GuestAgentChannel: Send {
+    /// Pass the data to the guest agent.
+    fn send(&mut self, buf: &[u8]) -> Result<()>;
+}
+
+/// Called with the value of the reply once the command is completed.
+pub type GuestAgentDone = Box<dyn FnOnce(Result<Value>) + Send>;
+
+type Completion = (GuestAgentDone, Result<Value>);
+
+/// Register the port of the guest agent.
+pub fn register_guest_agent_channel(channel: Arc<Mutex<dyn GuestAgentChannel>>) {
+    GUEST_AGENT.lock().unwrap().channel = Some(channel);
+}
+
+/// Queue the command to the guest agent, `done` is called with the value of its reply
+/// once the guest agent replies, or with the error if it doesn't reply in `timeout`.
+/// It's called in the main loop.
+///
+/// # Arguments
+///
+/// * `command` - The json command of the guest agent, e.g. `{"execute":"guest-ping"}`.
+/// * `timeout` - Time to wait for the reply once the command is sent.
+/// * `done` - Called when the command is completed.
+pub fn guest_agent_command(command: Value, timeout: Duration, done: GuestAgentDone) -> Result<()> {
+    GUEST_AGENT.lock().unwrap().submit(command, timeout, done)?;
+    flush_guest_agent();
+    Ok(())
+}
+
+/// Handle the output of the guest agent port. Return false if no command is in flight,
+/// then the output is written to the backend of the chardev as usual.
+pub fn guest_agent_output(buf: &[u8]) -> bool {
+    let mut agent = GUEST_AGENT.lock().unwrap();
+    let (handled, completed) = agent.receive(buf);
+    // The port is held by the caller, send the next command later in the main loop.
+    if agent.has_work() {
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            ctx.delay_call(Box::new(flush_guest_agent), 0);
+        }
+    }
+    drop(agent);
+    complete(completed);
+    handled
+}
+
+/// Send the queued commands, and start the timer of the command in flight.
+fn flush_guest_agent() {
+    let mut agent = GUEST_AGENT.lock().unwrap();
+    let completed = agent.flush();
+    if let Some((seq, timeout)) = agent.timer.take() {
+        match EventLoop::get_ctx(None) {
+            Some(ctx) => ctx.delay_call(
+                Box::new(move || guest_agent_timeout(seq)),
+                timeout.as_nanos() as u64,
+            ),
+            None => error!("Main loop is not available for the timer of guest agent"),
+        }
+    }
+    drop(agent);
+    complete(completed);
+}
+
+fn guest_agent_timeout(seq: u64) {
+    let completed = GUEST_AGENT.lock().unwrap().timeout(seq);
+    complete(completed);
+    flush_guest_agent();
+}
+
+fn complete(completed: Vec<Completion>) {
+    for (done, ret) in completed {
+        done(ret);
+    }
+}
+
+/// Progress of the command at the front of the queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Phase {
+    /// The command is not sent yet.
+    Idle,
+    /// Waiting for the reply of `guest-sync-delimited` with the `id`, the output before
+    /// the sync byte is discarded until `delimited` is true.
+    Syncing { id: u64, delimited: bool },
+    /// The channel is resynchronized, the command is not sent yet.
+    Synced,
+    /// Waiting for the reply of the command.
+    Sent,
+}
+
+struct Request {
+    command: Value,
+    /// Id of the command, used to match the reply.
+    id: Value,
+    timeout: Duration,
+    done: GuestAgentDone,
+}
+
+/// State of the channel to the guest agent.
+pub struct GuestAgent {
+    channel: Option<Arc<Mutex<dyn GuestAgentChannel>>>,
+    /// The reply of a command may be left in the channel, it has to be resynchronized.
+    desynced: bool,
+    /// Id of the next command, used to match the reply.
+    next_id: u64,
+    /// The queued commands, the front one is in flight once it's started.
+    requests: VecDeque<Request>,
+    phase: Phase,
+    /// Output received but not parsed yet.
+    pending: Vec<u8>,
+    /// Sequence number of the command in flight, to match its timer.
+    seq: u64,
+    /// Timer to be started for the command in flight.
+    timer: Option<(u64, Duration)>,
+}
+
+impl Default for GuestAgent {
+    fn default() -> Self {
+        GuestAgent {
+            channel: None,
+            desynced: false,
+            next_id: 0,
+            requests: VecDeque::new(),
+            phase: Phase::Idle,
+            pending: Vec::new(),
+            seq: 0,
+            timer: None,
+        }
+    }
+}
+
+impl GuestAgent {
+    /// Queue the command, it's sent by `flush`.
+    fn submit(
+        &mut self,
+        mut command: Value,
+        timeout: Duration,
+        done: GuestAgentDone,
+    ) -> Result<()> {
+        if self.channel.is_none() {
+            bail!("No virtio-serial port named {}", GUEST_AGENT_PORT_NAME);
+        }
+        let request = command
+            .as_object_mut()
+            .with_context(|| "Guest agent command must be a json object")?;
+        if !request.contains_key("execute") {
+            bail!("Guest agent command must have the member 'execute'");
+        }
+        let id = request
+            .entry("id")
+            .or_insert_with(|| json!(self.alloc_id()))
+            .clone();
+        self.requests.push_back(Request {
+            command,
+            id,
+            timeout,
+            done,
+        });
+        Ok(())
+    }
+
+    /// Whether `flush` has anything to send.
+    fn has_work(&self) -> bool {
+        !self.requests.is_empty() && matches!(self.phase, Phase::Idle | Phase::Synced)
+    }
+
+    /// Send the command at the front of the queue, resynchronize the channel first if
+    /// needed. The commands failed to be sent are completed with the error.
+    fn flush(&mut self) -> Vec<Completion> {
+        let mut completed = Vec::new();
+        while !self.requests.is_empty() {
+            let ret = match self.phase {
+                Phase::Idle => self.start(),
+                Phase::Synced => {
+                    self.phase = Phase::Sent;
+                    self.send_command()
+                }
+                Phase::Syncing { .. } | Phase::Sent => break,
+            };
+            if let Err(e) = ret {
+                completed.extend(self.finish(Err(e)));
+            }
+        }
+        completed
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.seq = self.seq.wrapping_add(1);
+        self.timer = Some((self.seq, self.requests.front().unwrap().timeout));
+        self.pending.clear();
+        // Assume the channel is out of sync until the reply is received, in case of
+        // timeout or broken channel.
+        if std::mem::replace(&mut self.desynced, true) {
+            let id = self.alloc_id();
+            self.phase = Phase::Syncing {
+                id,
+                delimited: false,
+            };
+            let sync = json!({
+                "execute": "guest-sync-delimited",
+                "arguments": { "id": id }
+            });
+            let mut msg = vec![SYNC_BYTE];
+            msg.extend_from_slice(format!("{}\n", sync).as_bytes());
+            self.write(&msg)
+        } else {
+            self.phase = Phase::Sent;
+            self.send_command()
+        }
+    }
+
+    fn send_command(&mut self) -> Result<()> {
+        let msg = format!("{}\n", self.requests.front().unwrap().command);
+        self.write(msg.as_bytes())
+    }
+
+    fn write(&self, buf: &[u8]) -> Result<()> {
+        self.channel
+            .as_ref()
+            .with_context(|| format!("No virtio-serial port named {}", GUEST_AGENT_PORT_NAME))?
+            .lock()
+            .unwrap()
+            .send(buf)
+            .with_context(|| "Failed to write guest agent channel")
+    }
+
+    /// Complete the command in flight, and move on to the next one.
+    fn finish(&mut self, ret: Result<Value>) -> Option<Completion> {
+        let request = self.requests.pop_front()?;
+        self.phase = Phase::Idle;
+        self.pending.clear();
+        Some((request.done, ret))
+    }
+
+    /// Complete the command in flight with error if it's the one of the timer `seq`.
+    fn timeout(&mut self, seq: u64) -> Vec<Completion> {
+        if seq != self.seq || self.phase == Phase::Idle {
+            return Vec::new();
+        }
+        self.finish(Err(anyhow!(
+            "Timed out waiting for the reply of guest agent"
+        )))
+        .into_iter()
+        .collect()
+    }
+
+    /// Handle the output of the guest agent. Return whether it's taken by the command
+    /// in flight, and the command completed by it.
+    fn receive(&mut self, buf: &[u8]) -> (bool, Vec<Completion>) {
+        match self.phase {
+            Phase::Idle => return (false, Vec::new()),
+            // The command isn't sent yet, so it's stale.
+            Phase::Synced => return (true, Vec::new()),
+            Phase::Syncing { .. } | Phase::Sent => {}
+        }
+        self.pending.extend_from_slice(buf);
+        loop {
+            if let Phase::Syncing { id, delimited } = self.phase {
+                if !delimited {
+                    match self.pending.iter().position(|b| *b == SYNC_BYTE) {
+                        Some(pos) => {
+                            self.pending.drain(..=pos);
+                            self.phase = Phase::Syncing {
+                                id,
+                                delimited: true,
+                            };
+                        }
+                        None => {
+                            self.pending.clear();
+                            return (true, Vec::new());
+                        }
+                    }
+                }
+                match self.parse_reply() {
+                    Ok(Some(reply)) => {
+                        if reply.get("return") == Some(&json!(id)) {
+                            self.phase = Phase::Synced;
+                            self.pending.clear();
+                            return (true, Vec::new());
+                        }
+                    }
+                    Ok(None) => return (true, Vec::new()),
+                    Err(e) => return (true, self.finish(Err(e)).into_iter().collect()),
+                }
+                continue;
+            }
+
+            let mut reply = match self.parse_reply() {
+                Ok(Some(reply)) => reply,
+                Ok(None) => return (true, Vec::new()),
+                Err(e) => return (true, self.finish(Err(e)).into_iter().collect()),
+            };
+            // Drop the late reply of the command timed out before.
+            let id = &self.requests.front().unwrap().id;
+            if reply.get("id").is_some_and(|reply_id| reply_id != id) {
+                warn!("Drop stale reply of guest agent: {}", reply);
+                continue;
+            }
+            self.desynced = false;
+            let ret = match reply.get_mut("return") {
+                Some(value) => Ok(value.take()),
+                None => {
+                    let desc = reply
+                        .pointer("/error/desc")
+                        .and_then(Value::as_str)
+                        .map_or_else(|| reply.to_string(), |desc| desc.to_string());
+                    Err(anyhow!("Guest agent returned error: {}", desc))
+                }
+            };
+            return (true, self.finish(ret).into_iter().collect());
+        }
+    }
+
+    /// Parse the next reply from the output received.
+    fn parse_reply(&mut self) -> Result<Option<Value>> {
+        // The sync byte left in the channel is not part of the reply.
+        self.pending.retain(|b| *b != SYNC_BYTE);
+        let mut replies = serde_json::Deserializer::from_slice(&self.pending).into_iter::<Value>();
+        match replies.next() {
+            Some(Ok(reply)) => {
+                let offset = replies.byte_offset();
+                self.pending.drain(..offset);
+                Ok(Some(reply))
+            }
+            Some(Err(e)) if !e.is_eof() => {
+                // Drop the garbage, the channel has to be resynchronized.
+                self.pending.clear();
+                bail!("Invalid reply of guest agent: {}", e);
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn alloc_id(&mut self) -> u64 {
+        self.next_id = self.next_id.wrapping_add(1);
+        self.next_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Results = Arc<Mutex<Vec<std::result::Result<Value, String>>>>;
+
+    /// The port of the guest agent, which records the data sent to it.
+    #[derive(Default)]
+    struct MockChannel {
+        sent: Vec<u8>,
+        broken: bool,
+    }
+
+    impl GuestAgentChannel for MockChannel {
+        fn send(&mut self, buf: &[u8]) -> Result<()> {
+            if self.broken {
+                bail!("Port is not opened by guest");
+            }
+            self.sent.extend_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn mock_agent() -> (GuestAgent, Arc<Mutex<MockChannel>>) {
+        let channel = Arc::new(Mutex::new(MockChannel::default()));
+        let agent = GuestAgent {
+            channel: Some(channel.clone()),
+            ..Default::default()
+        };
+        (agent, channel)
+    }
+
+    /// Take the commands sent to the guest agent, and whether the sync byte precedes them.
+    fn take_commands(channel: &Arc<Mutex<MockChannel>>) -> Vec<(bool, Value)> {
+        let sent = std::mem::take(&mut channel.lock().unwrap().sent);
+        sent.split(|b| *b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let synced = line.first() == Some(&SYNC_BYTE);
+                let start = line.iter().position(|b| *b != SYNC_BYTE).unwrap();
+                (synced, serde_json::from_slice(&line[start..]).unwrap())
+            })
+            .collect()
+    }
+
+    fn submit(agent: &mut GuestAgent, command: Value, timeout: Duration, results: &Results) {
+        let results = results.clone();
+        agent
+            .submit(
+                command,
+                timeout,
+                Box::new(move |ret| results.lock().unwrap().push(ret.map_err(|e| e.to_string()))),
+            )
+            .unwrap();
+    }
+
+    fn reply(agent: &mut GuestAgent, reply: &[u8]) -> bool {
+        let (handled, completed) = agent.receive(reply);
+        complete(completed);
+        handled
+    }
+
+    #[test]
+    fn test_guest_agent_reply_match() {
+        let (mut agent, channel) = mock_agent();
+        let results = Results::default();
+        let timeout = Duration::from_secs(5);
+
+        // The output of guest agent isn't taken if no command is in flight.
+        assert!(!reply(&mut agent, b"{\"return\": {}}\n"));
+
+        // The commands are sent one by one.
+        submit(
+            &mut agent,
+            json!({"execute": "guest-info"}),
+            timeout,
+            &results,
+        );
+        submit(
+            &mut agent,
+            json!({"execute": "guest-foo", "id": "user-id"}),
+            timeout,
+            &results,
+        );
+        assert!(agent.flush().is_empty());
+        let cmds = take_commands(&channel);
+        assert_eq!(cmds.len(), 1);
+        assert!(!cmds[0].0);
+        assert_eq!(cmds[0].1["execute"], "guest-info");
+        assert!(agent.timer.take().is_some());
+
+        // The reply of another command is dropped.
+        let stale = json!({"return": {}, "id": 1000});
+        let info = json!({"return": {"version": "7.2"}, "id": cmds[0].1["id"]});
+        assert!(reply(
+            &mut agent,
+            format!("{}\n{}\n", stale, info).as_bytes()
+        ));
+        assert_eq!(
+            results.lock().unwrap().pop(),
+            Some(Ok(json!({"version": "7.2"})))
+        );
+
+        assert!(agent.has_work());
+        assert!(agent.flush().is_empty());
+        let cmds = take_commands(&channel);
+        assert_eq!(cmds[0].1["id"], "user-id");
+        let err =
+            json!({"error": {"class": "CommandNotFound", "desc": "not found"}, "id": "user-id"});
+        assert!(reply(&mut agent, format!("{}\n", err).as_bytes()));
+        let ret = results.lock().unwrap().pop().unwrap();
+        assert!(ret.unwrap_err().contains("not found"));
+        assert!(!agent.desynced);
+        assert!(!agent.has_work());
+
+        // Invalid commands are not queued.
+        let done: GuestAgentDone = Box::new(|_| {});
+        assert!(agent.submit(json!("guest-ping"), timeout, done).is_err());
+        let done: GuestAgentDone = Box::new(|_| {});
+        assert!(agent.submit(json!({}), timeout, done).is_err());
+        let done: GuestAgentDone = Box::new(|_| {});
+        assert!(GuestAgent::default()
+            .submit(json!({"execute": "guest-ping"}), timeout, done)
+            .is_err());
+
+        // The command fails at once if the port is broken.
+        channel.lock().unwrap().broken = true;
+        submit(
+            &mut agent,
+            json!({"execute": "guest-ping"}),
+            timeout,
+            &results,
+        );
+        complete(agent.flush());
+        assert!(results.lock().unwrap().pop().unwrap().is_err());
+        assert!(!agent.has_work());
+    }
+
+    #[test]
+    fn test_guest_agent_timeout_recovery() {
+        let (mut agent, channel) = mock_agent();
+        let results = Results::default();
+
+        // The agent doesn't reply in time.
+        submit(
+            &mut agent,
+            json!({"execute": "guest-fsfreeze-freeze"}),
+            Duration::from_millis(50),
+            &results,
+        );
+        assert!(agent.flush().is_empty());
+        let late = take_commands(&channel).pop().unwrap().1;
+        let (seq, timeout) = agent.timer.take().unwrap();
+        assert_eq!(timeout, Duration::from_millis(50));
+        // The timer of another command has no effect.
+        assert!(agent.timeout(seq + 1).is_empty());
+        complete(agent.timeout(seq));
+        let ret = results.lock().unwrap().pop().unwrap();
+        assert!(ret.unwrap_err().contains("Timed out"));
+        assert!(agent.desynced);
+
+        // The channel is resynchronized before the next command.
+        submit(
+            &mut agent,
+            json!({"execute": "guest-ping"}),
+            Duration::from_secs(5),
+            &results,
+        );
+        assert!(agent.flush().is_empty());
+        let (synced, sync) = take_commands(&channel).pop().unwrap();
+        assert!(synced);
+        assert_eq!(sync["execute"], "guest-sync-delimited");
+
+        // The late reply and a partial one left in the channel before the sync are dropped.
+        let late_reply = json!({"return": 1, "id": late["id"]});
+        assert!(reply(
+            &mut agent,
+            format!("{}\n{{\"return\": ", late_reply).as_bytes()
+        ));
+        let mut sync_reply = vec![SYNC_BYTE];
+        sync_reply.extend_from_slice(
+            format!("{}\n", json!({"return": sync["arguments"]["id"]})).as_bytes(),
+        );
+        assert!(reply(&mut agent, &sync_reply));
+        assert!(results.lock().unwrap().is_empty());
+
+        assert!(agent.has_work());
+        assert!(agent.flush().is_empty());
+        let (synced, cmd) = take_commands(&channel).pop().unwrap();
+        assert!(!synced);
+        assert_eq!(cmd["execute"], "guest-ping");
+        assert!(reply(
+            &mut agent,
+            format!("{}\n", json!({"return": {}, "id": cmd["id"]})).as_bytes()
+        ));
+        assert_eq!(results.lock().unwrap().pop(), Some(Ok(json!({}))));
+        assert!(!agent.desynced);
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::collections::BTreeMap;
+use std::os::unix::io::RawFd;
+
+use anyhow::{anyhow, bail, Result};
+
+use super::qmp_schema::{FdsetFdInfo, FdsetInfo};
+
+/// Prefix of the path which refers to a fdset, e.g. `/dev/fdset/1`.
+pub const FDSET_PATH_PREFIX: &str = "/dev/fdset/";
+
+/// Get the fdset id from the path `/dev/fdset/<id>`, or `None` if it's a normal path.
+pub fn parse_fdset_path(path: &str) -> Option<u64> {
+    path.strip_prefix(FDSET_PATH_PREFIX)?.parse::<u64>().ok()
+}
+
+/// A file descriptor added by `add-fd`, it's closed when removed from the fdset.
+struct FdsetFd {
+    fd: RawFd,
+    opaque: Option<String>,
+}
+
+impl Drop for FdsetFd {
+    fn drop(&mut self) {
+        // SAFETY: the fd is owned by the fdset and is not used anymore.
+        unsafe { libc::close(self.fd) };
+    }
+}
+
+#[derive(Default)]
+struct Fdset {
+    fds: Vec<FdsetFd>,
+    /// A duplicate of the fds has been handed out to open a file.
+    used: bool,
+}
+
+/// The fdsets which hold the file descriptors received by `add-fd`, so that the
+/// files can be referred to by `/dev/fdset/<id>`.
+#[derive(Default)]
+pub struct Fdsets {
+    sets: BTreeMap<u64, Fdset>,
+}
+
+impl Fdsets {
+    /// Add `fd` to the fdset `fdset_id`, or to a new fdset with the lowest unused id.
+    /// The fdset owns `fd` from now on, and it's closed even if the adding fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - Id of the fdset.
+    /// * `fd` - The fd received from client.
+    /// * `opaque` - A free-form string to identify the fd.
+    pub fn add_fd(
+        &mut self,
+        fdset_id: Option<u64>,
+        fd: RawFd,
+        opaque: Option<String>,
+    ) -> Result<u64> {
+        let fdset_fd = FdsetFd { fd, opaque };
+        // SAFETY: checking the fd flags doesn't change anything.
+        if unsafe { libc::fcntl(fd, libc::F_GETFL) } < 0 {
+            bail!("Invalid fd {}", fd);
+        }
+        let fdset_id = fdset_id.unwrap_or_else(|| {
+            (0..)
+                .find(|id| !self.sets.contains_key(id))
+                .unwrap_or_default()
+        });
+        self.sets.entry(fdset_id).or_default().fds.push(fdset_fd);
+        Ok(fdset_id)
+    }
+
+    /// Remove `fd` from the fdset `fdset_id`, or the whole fdset if `fd` is `None`.
+    /// The fdset is removed once it becomes empty.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - Id of the fdset.
+    /// * `fd` - The fd to remove.
+    pub fn remove_fd(&mut self, fdset_id: u64, fd: Option<RawFd>) -> Result<()> {
+        let fdset = self
+            .sets
+            .get_mut(&fdset_id)
+            .ok_or_else(|| anyhow!("Fdset {} is not found", fdset_id))?;
+        if let Some(fd) = fd {
+            let index = fdset
+                .fds
+                .iter()
+                .position(|f| f.fd == fd)
+                .ok_or_else(|| anyhow!("Fd {} is not found in fdset {}", fd, fdset_id))?;
+            fdset.fds.remove(index);
+            if !fdset.fds.is_empty() {
+                return Ok(());
+            }
+        }
+        self.sets.remove(&fdset_id);
+        Ok(())
+    }
+
+    /// Query all the fdsets.
+    pub fn query(&self) -> Vec<FdsetInfo> {
+        self.sets
+            .iter()
+            .map(|(id, fdset)| FdsetInfo {
+                fdset_id: *id,
+                fds: fdset
+                    .fds
+                    .iter()
+                    .map(|f| FdsetFdInfo {
+                        fd: f.fd,
+                        opaque: f.opaque.clone(),
+                    })
+                    .collect(),
+            })
+            .collect()
+    }
+
+    /// Duplicate a fd of the fdset `fdset_id` whose access mode is `O_RDONLY` if
+    /// `read_only` or `O_RDWR` otherwise. The caller owns the returned fd.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - Id of the fdset.
+    /// * `read_only` - The file is opened read only.
+    pub fn dup_fd(&mut self, fdset_id: u64, read_only: bool) -> Result<RawFd> {
+        let fdset = self
+            .sets
+            .get_mut(&fdset_id)
+            .ok_or_else(|| anyhow!("Fdset {} is not found", fdset_id))?;
+        let access_mode = if read_only {
+            libc::O_RDONLY
+        } else {
+            libc::O_RDWR
+        };
+        let fdset_fd = fdset
+            .fds
+            .iter()
+            // SAFETY: checking the fd flags doesn't change anything.
+            .find(|f| unsafe { libc::fcntl(f.fd, libc::F_GETFL) } & libc::O_ACCMODE == access_mode)
+            .ok_or_else(|| {
+                anyhow!(
+                    "No fd with {} access mode in fdset {}",
+                    if read_only { "read-only" } else { "read-write" },
+                    fdset_id
+                )
+            })?;
+        // SAFETY: the fd is valid and the new fd is owned by the caller.
+        let fd = unsafe { libc::fcntl(fdset_fd.fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            bail!(
+                "Failed to duplicate fd {} of fdset {}: {}",
+                fdset_fd.fd,
+                fdset_id,
+                std::io::Error::last_os_error()
+            );
+        }
+        fdset.used = true;
+        Ok(fd)
+    }
+
+    /// Remove the fdsets which have never been used to open a file, it's called when
+    /// the last qmp client disconnects and nobody can use them anymore.
+    pub fn cleanup_unused(&mut self) {
+        self.sets.retain(|_, fdset| fdset.used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::Write;
+    use std::os::unix::io::{FromRawFd, IntoRawFd};
+    use std::os::unix::net::UnixStream;
+
+    use super::*;
+
+    /// Create a read-only fd, and the peer used to check if it's closed.
+    fn create_rdonly_fd() -> (RawFd, File) {
+        let mut fds = [0; 2];
+        // SAFETY: fds is big enough for the pipe.
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        // SAFETY: the write end is owned by the returned file.
+        (fds[0], unsafe { File::from_raw_fd(fds[1]) })
+    }
+
+    /// Create a read-write fd, and the peer used to check if it's closed.
+    fn create_rdwr_fd() -> (RawFd, UnixStream) {
+        let (stream, peer) = UnixStream::pair().unwrap();
+        (stream.into_raw_fd(), peer)
+    }
+
+    fn is_closed(peer: &mut impl Write) -> bool {
+        peer.write(&[0]).is_err()
+    }
+
+    #[test]
+    fn test_parse_fdset_path() {
+        assert_eq!(parse_fdset_path("/dev/fdset/0"), Some(0));
+        assert_eq!(parse_fdset_path("/dev/fdset/12"), Some(12));
+        assert_eq!(parse_fdset_path("/dev/fdset/"), None);
+        assert_eq!(parse_fdset_path("/dev/fdset/a"), None);
+        assert_eq!(parse_fdset_path("/var/lib/disk.img"), None);
+    }
+
+    #[test]
+    fn test_fdsets() {
+        let mut fdsets = Fdsets::default();
+        let (rdonly_fd, mut rdonly_peer) = create_rdonly_fd();
+        let (rdwr_fd, mut rdwr_peer) = create_rdwr_fd();
+        assert_eq!(fdsets.add_fd(None, rdonly_fd, None).unwrap(), 0);
+        assert_eq!(
+            fdsets
+                .add_fd(Some(0), rdwr_fd, Some("rdwr".to_string()))
+                .unwrap(),
+            0
+        );
+        assert_eq!(
+            fdsets.query(),
+            vec![FdsetInfo {
+                fdset_id: 0,
+                fds: vec![
+                    FdsetFdInfo {
+                        fd: rdonly_fd,
+                        opaque: None
+                    },
+                    FdsetFdInfo {
+                        fd: rdwr_fd,
+                        opaque: Some("rdwr".to_string())
+                    },
+                ],
+            }]
+        );
+
+        // The fd with matching access mode is duplicated.
+        let fd = fdsets.dup_fd(0, false).unwrap();
+        assert_ne!(fd, rdwr_fd);
+        // SAFETY: the duplicated fd is owned by the test.
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(b"data").unwrap();
+        drop(file);
+        assert!(fdsets.dup_fd(1, false).is_err());
+
+        // Removed fds are closed.
+        assert!(!is_closed(&mut rdwr_peer));
+        assert!(fdsets.remove_fd(0, Some(rdwr_fd)).is_ok());
+        assert!(is_closed(&mut rdwr_peer));
+        assert!(fdsets.dup_fd(0, false).is_err());
+        assert!(fdsets.remove_fd(0, Some(rdwr_fd)).is_err());
+        assert!(!is_closed(&mut rdonly_peer));
+        assert!(fdsets.remove_fd(0, None).is_ok());
+        assert!(is_closed(&mut rdonly_peer));
+        assert!(fdsets.query().is_empty());
+        assert!(fdsets.remove_fd(0, None).is_err());
+
+        // Unused fdsets are cleaned up.
+        let (used_fd, mut used_peer) = create_rdonly_fd();
+        let (unused_fd, mut unused_peer) = create_rdonly_fd();
+        fdsets.add_fd(Some(3), used_fd, None).unwrap();
+        fdsets.add_fd(Some(5), unused_fd, None).unwrap();
+        // SAFETY: the duplicated fd is owned by the test.
+        drop(unsafe { File::from_raw_fd(fdsets.dup_fd(3, true).unwrap()) });
+        fdsets.cleanup_unused();
+        assert!(!is_closed(&mut used_peer));
+        assert!(is_closed(&mut unused_peer));
+        assert_eq!(fdsets.query().len(), 1);
+        assert_eq!(fdsets.add_fd(None, create_rdonly_fd().0, None).unwrap(), 0);
+    }
+}
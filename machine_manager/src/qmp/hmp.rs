@@ -0,0 +1,183 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Interpreter of `human-monitor-command`.
+//!
+//! Management tools fall back to human monitor commands when a QMP equivalent is
+//! missing. A few of them are supported by translating them to QMP commands and
+//! rendering the results as text. Unsupported commands are reported in the output
+//! instead of as QMP errors, so that clients can probe for them.
+
+use serde::de::DeserializeOwned;
+
+use super::qmp_schema::{BalloonInfo, BlockInfo, NetdevInfo, RunState, StatusInfo};
+use super::Response;
+use crate::machine::DeviceInterface;
+
+/// Line ending of the human monitor output.
+const HMP_EOL: &str = "\r\n";
+
+/// Execute the human monitor command `command_line`, return its output.
+pub fn human_monitor_command<T: DeviceInterface + ?Sized>(
+    executor: &T,
+    command_line: &str,
+) -> String {
+    let args = command_line.split_whitespace().collect::<Vec<&str>>();
+    match args.as_slice() {
+        ["info", "status"] => render(executor.query_status(), render_status),
+        ["info", "block"] => render(executor.query_block(None), render_block),
+        ["info", "network"] => render(executor.query_netdev(None), render_network),
+        ["info", "balloon"] => render(executor.query_balloon(), render_balloon),
+        ["balloon", size] => match size.parse::<u64>() {
+            // The target size is in MiB.
+            Ok(size) if size.checked_mul(1 << 20).is_some() => {
+                render(executor.balloon(size << 20), |_: serde_json::Value| {
+                    String::new()
+                })
+            }
+            _ => format!("invalid size: '{}'{}", size, HMP_EOL),
+        },
+        ["nmi"] => render(executor.inject_nmi(), |_: serde_json::Value| String::new()),
+        ["system_wakeup"] => render(executor.system_wakeup(), |_: serde_json::Value| {
+            String::new()
+        }),
+        [] => String::new(),
+        _ => format!("unknown command: '{}'{}", command_line.trim(), HMP_EOL),
+    }
+}
+
+/// Render the result of a QMP command by `f`, or its error.
+fn render<R: DeserializeOwned>(resp: Response, f: impl FnOnce(R) -> String) -> String {
+    if let Some(err) = resp.error {
+        return format!("Error: {}{}", err.desc, HMP_EOL);
+    }
+    match resp.return_.map(serde_json::from_value::<R>) {
+        Some(Ok(ret)) => f(ret),
+        _ => format!("Error: unexpected result{}", HMP_EOL),
+    }
+}
+
+fn render_status(info: StatusInfo) -> String {
+    let status = match info.status {
+        RunState::running => "running".to_string(),
+        RunState::paused => "paused".to_string(),
+        status => format!(
+            "paused ({})",
+            serde_json::to_value(status).unwrap().as_str().unwrap_or("")
+        ),
+    };
+    format!("VM status: {}{}", status, HMP_EOL)
+}
+
+fn render_block(blocks: Vec<BlockInfo>) -> String {
+    let mut output = String::new();
+    for block in blocks {
+        let ro = if block.read_only {
+            ", read-only"
+        } else if block.write_protected {
+            ", read-only after ENOSPC"
+        } else {
+            ""
+        };
+        output += &format!("{}: {} (raw{}){}", block.device, block.file, ro, HMP_EOL);
+        if block.write_threshold != 0 {
+            output += &format!("    Write threshold: {}{}", block.write_threshold, HMP_EOL);
+        }
+        let errors = &block.errors;
+        output += &format!(
+            "    Errors: backend-eio {}, enospc {}, invalid-request {}, throttled-drop {}{}",
+            errors.backend_eio,
+            errors.enospc,
+            errors.invalid_request,
+            errors.throttled_drop,
+            HMP_EOL
+        );
+    }
+    output
+}
+
+fn render_network(netdevs: Vec<NetdevInfo>) -> String {
+    netdevs
+        .iter()
+        .map(|netdev| format!("{}: ifname={}{}", netdev.id, netdev.ifname, HMP_EOL))
+        .collect()
+}
+
+fn render_balloon(info: BalloonInfo) -> String {
+    format!("balloon: actual={}{}", info.actual >> 20, HMP_EOL)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::qmp::tests::TestController;
+
+    #[test]
+    fn test_hmp_commands() {
+        let dev = TestController {
+            running: true,
+            balloon_size: Mutex::new(2 << 30),
+            ..Default::default()
+        };
+        for (command, output) in [
+            ("info status", "VM status: running\r\n"),
+            (
+                "info block",
+                "drive-0: /path/to/rootfs (raw)\r\n    Write threshold: 1073741824\r\n    \
+                 Errors: backend-eio 0, enospc 1, invalid-request 0, throttled-drop 0\r\n\
+                 cdrom-0: /path/to/iso (raw, read-only)\r\n    \
+                 Errors: backend-eio 0, enospc 0, invalid-request 0, throttled-drop 0\r\n",
+            ),
+            ("info network", "net-0: ifname=tap0\r\n"),
+            ("info balloon", "balloon: actual=2048\r\n"),
+            ("balloon 1024", ""),
+            ("  info   balloon ", "balloon: actual=1024\r\n"),
+            (
+                "balloon 0",
+                "Error: No balloon device has been activated\r\n",
+            ),
+            ("balloon 1G", "invalid size: '1G'\r\n"),
+            ("nmi", ""),
+            (
+                "system_wakeup",
+                "Error: Unable to wake up: guest is not in suspended state\r\n",
+            ),
+            ("", ""),
+        ] {
+            assert_eq!(human_monitor_command(&dev, command), output, "{}", command);
+        }
+
+        let dev = TestController::default();
+        assert_eq!(
+            human_monitor_command(&dev, "info status"),
+            "VM status: paused\r\n"
+        );
+    }
+
+    #[test]
+    fn test_hmp_unknown_commands() {
+        let dev = TestController::default();
+        for command in [
+            "drive_add 0 if=none,file=/path",
+            "info cpus",
+            "info",
+            "help",
+        ] {
+            assert_eq!(
+                human_monitor_command(&dev, command),
+                format!("unknown command: '{}'\r\n", command)
+            );
+        }
+    }
+}
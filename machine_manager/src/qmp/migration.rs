@@ -0,0 +1,189 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use anyhow::{bail, Result};
+
+use super::qmp_schema::{MigrateCapabilities, MigrateSetParametersArgument, MigrationParameters};
+
+/// Default maximum speed of migration in bytes per second, 128MiB/s.
+pub const DEFAULT_MIGRATE_BANDWIDTH: u64 = 128 << 20;
+/// Default maximum tolerated downtime of migration in milliseconds.
+pub const DEFAULT_MIGRATE_DOWNTIME_MS: u64 = 300;
+/// Maximum downtime limit of migration in milliseconds, 2000 seconds.
+pub const MAX_MIGRATE_DOWNTIME_MS: u64 = 2_000_000;
+
+const CAPABILITY_XBZRLE: &str = "xbzrle";
+const CAPABILITY_AUTO_CONVERGE: &str = "auto-converge";
+const CAPABILITY_POSTCOPY_RAM: &str = "postcopy-ram";
+
+/// The capabilities and parameters of migration set by `migrate-set-capabilities` and
+/// `migrate-set-parameters`, which are used by the migration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MigrationState {
+    /// Compress the pages which are sent repeatedly with XBZRLE.
+    pub xbzrle: bool,
+    /// Throttle down the vcpus if the migration doesn't converge.
+    pub auto_converge: bool,
+    /// Allow switching to postcopy migration.
+    pub postcopy_ram: bool,
+    /// Maximum speed of migration in bytes per second.
+    pub max_bandwidth: u64,
+    /// Maximum tolerated downtime of migration in milliseconds.
+    pub downtime_limit: u64,
+}
+
+impl Default for MigrationState {
+    fn default() -> Self {
+        MigrationState {
+            xbzrle: false,
+            auto_converge: false,
+            postcopy_ram: false,
+            max_bandwidth: DEFAULT_MIGRATE_BANDWIDTH,
+            downtime_limit: DEFAULT_MIGRATE_DOWNTIME_MS,
+        }
+    }
+}
+
+impl MigrationState {
+    fn capability_mut(&mut self, name: &str) -> Option<&mut bool> {
+        match name {
+            CAPABILITY_XBZRLE => Some(&mut self.xbzrle),
+            CAPABILITY_AUTO_CONVERGE => Some(&mut self.auto_converge),
+            CAPABILITY_POSTCOPY_RAM => Some(&mut self.postcopy_ram),
+            _ => None,
+        }
+    }
+
+    /// Set the capabilities of migration. Nothing is changed if any of them is invalid.
+    ///
+    /// # Arguments
+    ///
+    /// * `caps` - The capabilities and their states.
+    pub fn set_capabilities(&mut self, caps: &[MigrateCapabilities]) -> Result<()> {
+        let mut state = self.clone();
+        for cap in caps {
+            match state.capability_mut(&cap.capability) {
+                Some(enabled) => *enabled = cap.state,
+                None => bail!("Invalid migration capability {}", cap.capability),
+            }
+        }
+        *self = state;
+        Ok(())
+    }
+
+    /// Get the states of all the capabilities of migration.
+    pub fn capabilities(&self) -> Vec<MigrateCapabilities> {
+        [
+            (CAPABILITY_XBZRLE, self.xbzrle),
+            (CAPABILITY_AUTO_CONVERGE, self.auto_converge),
+            (CAPABILITY_POSTCOPY_RAM, self.postcopy_ram),
+        ]
+        .iter()
+        .map(|(capability, state)| MigrateCapabilities {
+            state: *state,
+            capability: capability.to_string(),
+        })
+        .collect()
+    }
+
+    /// Set the parameters of migration, the parameters which are not given are unchanged.
+    /// Nothing is changed if any of them is out of range.
+    ///
+    /// # Arguments
+    ///
+    /// * `params` - The parameters to be set.
+    pub fn set_parameters(&mut self, params: &MigrateSetParametersArgument) -> Result<()> {
+        if params.max_bandwidth == Some(0) {
+            bail!("Parameter max-bandwidth should be larger than 0");
+        }
+        if let Some(downtime) = params.downtime_limit {
+            if downtime > MAX_MIGRATE_DOWNTIME_MS {
+                bail!(
+                    "Parameter downtime-limit {} should be in range [0, {}]",
+                    downtime,
+                    MAX_MIGRATE_DOWNTIME_MS
+                );
+            }
+        }
+
+        if let Some(bandwidth) = params.max_bandwidth {
+            self.max_bandwidth = bandwidth;
+        }
+        if let Some(downtime) = params.downtime_limit {
+            self.downtime_limit = downtime;
+        }
+        Ok(())
+    }
+
+    /// Get the parameters of migration.
+    pub fn parameters(&self) -> MigrationParameters {
+        MigrationParameters {
+            max_bandwidth: self.max_bandwidth,
+            downtime_limit: self.downtime_limit,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cap(capability: &str, state: bool) -> MigrateCapabilities {
+        MigrateCapabilities {
+            state,
+            capability: capability.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_migration_capabilities() {
+        let mut state = MigrationState::default();
+        assert!(state.capabilities().iter().all(|c| !c.state));
+
+        state
+            .set_capabilities(&[cap("xbzrle", true), cap("postcopy-ram", true)])
+            .unwrap();
+        assert_eq!(
+            state.capabilities(),
+            vec![
+                cap("xbzrle", true),
+                cap("auto-converge", false),
+                cap("postcopy-ram", true)
+            ]
+        );
+
+        // An invalid capability fails the whole setting.
+        assert!(state
+            .set_capabilities(&[cap("auto-converge", true), cap("compress", true)])
+            .is_err());
+        assert!(!state.auto_converge);
+    }
+
+    #[test]
+    fn test_migration_parameters() {
+        let mut state = MigrationState::default();
+        let params = MigrateSetParametersArgument {
+            max_bandwidth: Some(1 << 30),
+            downtime_limit: None,
+        };
+        state.set_parameters(&params).unwrap();
+        assert_eq!(state.max_bandwidth, 1 << 30);
+        assert_eq!(state.downtime_limit, DEFAULT_MIGRATE_DOWNTIME_MS);
+
+        let params = MigrateSetParametersArgument {
+            max_bandwidth: Some(0),
+            downtime_limit: Some(100),
+        };
+        assert!(state.set_parameters(&params).is_err());
+        assert_eq!(state.downtime_limit, DEFAULT_MIGRATE_DOWNTIME_MS);
+    }
+}
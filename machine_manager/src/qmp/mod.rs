@@ -26,18 +26,25 @@
 //! `qmp-schema.json`. It's can be compatible by Qemu's zoology. Those
 //! transformed structures can be found in `machine_manager/src/qmp/qmp_schema.rs`
 
+pub mod fdset;
+mod hmp;
+pub mod migration;
+mod qmp_compat;
 #[allow(non_upper_case_globals)]
 #[allow(non_camel_case_types)]
 #[allow(non_snake_case)]
 pub mod qmp_schema;
 
+pub use qmp_compat::{DeprecatedWarning, QMP_SCHEMA_VERSION};
+
 use std::collections::BTreeMap;
 use std::io::Write;
 use std::os::unix::io::RawFd;
-use std::sync::{Arc, Mutex, RwLock};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::{Arc, Condvar, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use log::{error, info, warn};
+use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -45,14 +52,28 @@ use util::leak_bucket::LeakBucket;
 use util::set_termi_canon_mode;
 use util::time::NANOSECONDS_PER_SECOND;
 
+use self::fdset::Fdsets;
+use self::migration::MigrationState;
 use self::qmp_schema::{self as schema, QmpCommand};
+use crate::config::QmpCompatPolicy;
 use crate::event_loop::EventLoop;
-use crate::machine::MachineExternalInterface;
+use crate::machine::{DeviceInterface, KvmVmState, MachineExternalInterface};
+use crate::qga::{guest_agent_command, DEFAULT_GUEST_AGENT_TIMEOUT_MS};
 use crate::socket::SocketRWHandler;
 use crate::temp_cleaner::TempCleaner;
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 
 static mut QMP_CHANNEL: Option<Arc<QmpChannel>> = None;
+/// Capability of out-of-band execution advertised in the greeting.
+const QMP_CAPABILITY_OOB: &str = "oob";
+/// Commands which can be executed out-of-band by `exec-oob`.
+const QMP_OOB_COMMANDS: [&str; 3] = ["quit", "query-status", "device_del"];
+/// Commands from all the qmp connections are executed one by one under this lock,
+/// except the out-of-band ones.
+static QMP_DISPATCHER: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// State of the vm shared with the machine.
+type VmState = Arc<(Mutex<KvmVmState>, Condvar)>;
 
 /// Macro `event!`: send event to qmp-client.
 ///
@@ -158,6 +179,8 @@ pub struct QmpGreeting {
 struct Greeting {
     version: Version,
     capabilities: Vec<String>,
+    #[serde(rename = "schema-version")]
+    schema_version: u32,
 }
 
 #[derive(Default, Debug, Serialize, Deserialize, PartialEq)]
@@ -202,7 +225,8 @@ impl QmpGreeting {
             minor,
             major,
         };
-        let cap: Vec<String> = Default::default();
+        // Out-of-band execution is the only capability for now.
+        let cap = vec![QMP_CAPABILITY_OOB.to_string()];
         let version = Version {
             application: version_number,
             package: "".to_string(),
@@ -210,6 +234,7 @@ impl QmpGreeting {
         let greeting = Greeting {
             version,
             capabilities: cap,
+            schema_version: QMP_SCHEMA_VERSION,
         };
         QmpGreeting { qmp: greeting }
     }
@@ -229,6 +254,8 @@ pub struct Response {
     error: Option<ErrorMessage>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    deprecated: Option<Vec<DeprecatedWarning>>,
 }
 
 impl Response {
@@ -244,6 +271,7 @@ impl Response {
             return_: Some(v),
             error: None,
             id,
+            deprecated: None,
         }
     }
 
@@ -253,6 +281,7 @@ impl Response {
             return_: Some(serde_json::to_value(Empty {}).unwrap()),
             error: None,
             id: None,
+            deprecated: None,
         }
     }
 
@@ -267,12 +296,21 @@ impl Response {
             return_: None,
             error: Some(ErrorMessage::new(&err_class)),
             id,
+            deprecated: None,
         }
     }
 
     fn change_id(&mut self, id: Option<String>) {
         self.id = id;
     }
+
+    fn set_deprecated(&mut self, warnings: Vec<DeprecatedWarning>) {
+        self.deprecated = if warnings.is_empty() {
+            None
+        } else {
+            Some(warnings)
+        };
+    }
 }
 
 impl From<bool> for Response {
@@ -342,11 +380,82 @@ pub fn create_timestamp() -> TimeStamp {
     }
 }
 
+/// Capabilities negotiation state of a qmp connection.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QmpConnState {
+    /// The greeting has been sent to the client.
+    pub greeted: bool,
+    /// The client has negotiated capabilities by `qmp_capabilities`, events are
+    /// only sent to negotiated clients.
+    pub negotiated: bool,
+    /// The client enabled out-of-band execution in `qmp_capabilities`.
+    pub oob: bool,
+    /// The events suppressed by `set-event-mask`, bit `n` masks the event `n`
+    /// in the registry `QmpEvent::VARIANTS`.
+    pub event_mask: u64,
+}
+
+impl QmpConnState {
+    /// Enable the capabilities requested by `qmp_capabilities`. Negotiating again
+    /// is accepted and keeps the connection negotiated.
+    ///
+    /// # Arguments
+    ///
+    /// * `enable` - Capabilities requested by the client.
+    pub fn negotiate(
+        &mut self,
+        enable: &[String],
+    ) -> std::result::Result<(), schema::QmpErrorClass> {
+        if let Some(cap) = enable.iter().find(|cap| *cap != QMP_CAPABILITY_OOB) {
+            return Err(schema::QmpErrorClass::GenericError(format!(
+                "Capability '{}' is not available",
+                cap
+            )));
+        }
+        self.negotiated = true;
+        self.oob = enable.iter().any(|cap| cap == QMP_CAPABILITY_OOB);
+        Ok(())
+    }
+
+    /// Replace the events suppressed on the connection. Nothing is changed if any
+    /// of them is unknown.
+    ///
+    /// # Arguments
+    ///
+    /// * `events` - Names of the events to suppress.
+    pub fn set_event_mask(
+        &mut self,
+        events: &[String],
+    ) -> std::result::Result<(), schema::QmpErrorClass> {
+        let mut mask = 0_u64;
+        for event in events {
+            match schema::QmpEvent::index_of(event) {
+                Some(index) => mask |= 1 << index,
+                None => {
+                    return Err(schema::QmpErrorClass::GenericError(format!(
+                        "Event '{}' is not available",
+                        event
+                    )))
+                }
+            }
+        }
+        self.event_mask = mask;
+        Ok(())
+    }
+
+    /// Check whether the `event` is suppressed on the connection.
+    pub fn is_event_masked(&self, event: &schema::QmpEvent) -> bool {
+        schema::QmpEvent::index_of(event.name())
+            .map_or(false, |index| self.event_mask & (1 << index) != 0)
+    }
+}
+
 /// Accept qmp command, analyze and exec it.
 ///
 /// # Arguments
 ///
 /// * `stream_fd` - The input stream file description.
+/// * `state` - Capabilities negotiation state of the connection.
 /// * `controller` - The controller which execute actual qmp command.
 /// * `leak_bucket` - The LeakBucket flow controller for qmp command.
 ///
@@ -355,6 +464,7 @@ pub fn create_timestamp() -> TimeStamp {
 /// This function will fail when json parser failed or socket file description broke.
 pub fn handle_qmp(
     stream_fd: RawFd,
+    state: &Mutex<QmpConnState>,
     controller: &Arc<Mutex<dyn MachineExternalInterface>>,
     leak_bucket: &mut LeakBucket,
 ) -> Result<()> {
@@ -377,8 +487,20 @@ pub fn handle_qmp(
         (Ok(None), _) => Ok(()),
         (Ok(buffer), if_fd) => {
             info!("QMP: <-- {:?}", buffer);
-            let qmp_command: schema::QmpCommand = buffer.unwrap();
-            let (return_msg, shutdown_flag) = qmp_command_exec(qmp_command, controller, if_fd);
+            let request: Value = buffer.unwrap();
+            let policy = QmpChannel::compat_policy();
+            let (return_msg, shutdown_flag) = if request.get("exec-oob").is_some() {
+                qmp_oob_request_exec(request, state, controller, policy)
+            } else if is_guest_agent_command(&request) {
+                match guest_agent_command_deferred(request, stream_fd, policy) {
+                    Some(err_msg) => (err_msg, false),
+                    // The response is sent once the guest agent replies.
+                    None => return Ok(()),
+                }
+            } else {
+                let _dispatcher = QMP_DISPATCHER.lock().unwrap();
+                qmp_request_exec(request, state, controller, if_fd, policy)
+            };
             info!("QMP: --> {:?}", return_msg);
             qmp_service.send_str(&return_msg)?;
 
@@ -408,12 +530,235 @@ pub fn handle_qmp(
     }
 }
 
+/// Execute the human monitor command `command_line` which doesn't come from a qmp
+/// connection, e.g. from the monitor of the stdio console. It's executed in turn with
+/// the qmp commands.
+pub fn exec_human_monitor_command<T: DeviceInterface + ?Sized>(
+    executor: &T,
+    command_line: &str,
+) -> String {
+    let _dispatcher = QMP_DISPATCHER.lock().unwrap();
+    hmp::human_monitor_command(executor, command_line)
+}
+
+/// Resolve the deprecated names in a raw qmp request, then parse it to `QmpCommand`.
+fn parse_qmp_request(
+    mut request: Value,
+    policy: QmpCompatPolicy,
+) -> std::result::Result<(QmpCommand, Vec<DeprecatedWarning>), schema::QmpErrorClass> {
+    let warnings = qmp_compat::resolve_deprecated(&mut request, policy)?;
+    serde_json::from_value::<QmpCommand>(request)
+        .map(|qmp_command| (qmp_command, warnings))
+        .map_err(|e| {
+            warn!("Qmp json parser made an error:{}", e);
+            schema::QmpErrorClass::GenericError(format!("{}", e))
+        })
+}
+
+/// Decode and parse a qmp request from the bytes sent by the client, in the same way as
+/// `handle_qmp` but without executing it.
+///
+/// It's the entry of the qmp fuzz target.
+pub fn decode_qmp_request(buf: &[u8], policy: QmpCompatPolicy) -> Result<QmpCommand> {
+    let msg = crate::socket::decode_socket_msg(buf)?;
+    let request: Value = serde_json::from_str(&msg)?;
+    parse_qmp_request(request, policy)
+        .map(|(qmp_command, _)| qmp_command)
+        .map_err(|e| anyhow!("{:?}", e))
+}
+
+/// Resolve the deprecated names in a raw qmp request, then parse and exec it.
+fn qmp_request_exec(
+    request: Value,
+    state: &Mutex<QmpConnState>,
+    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+    if_fd: Option<RawFd>,
+    policy: QmpCompatPolicy,
+) -> (String, bool) {
+    let id = request
+        .get("id")
+        .and_then(Value::as_str)
+        .map(|id| id.to_string());
+
+    match parse_qmp_request(request, policy) {
+        Ok((qmp_command, warnings)) => {
+            for warning in warnings.iter() {
+                warn!("QMP: deprecated usage {:?}", warning);
+            }
+            qmp_command_exec(qmp_command, state, controller, if_fd, warnings)
+        }
+        Err(err_resp) => (
+            serde_json::to_string(&Response::create_error_response(err_resp, id)).unwrap(),
+            false,
+        ),
+    }
+}
+
+/// Check that the out-of-band request is allowed on the connection, rename `exec-oob`
+/// to `execute`, then parse it to `QmpCommand`.
+fn parse_qmp_oob_request(
+    mut request: Value,
+    state: &Mutex<QmpConnState>,
+    policy: QmpCompatPolicy,
+) -> std::result::Result<(QmpCommand, Vec<DeprecatedWarning>), schema::QmpErrorClass> {
+    if !state.lock().unwrap().oob {
+        return Err(schema::QmpErrorClass::GenericError(
+            "Out-of-band execution is not enabled by qmp_capabilities".to_string(),
+        ));
+    }
+    if let Some(members) = request.as_object_mut() {
+        if members.contains_key("execute") {
+            return Err(schema::QmpErrorClass::GenericError(
+                "'execute' and 'exec-oob' can't be used together".to_string(),
+            ));
+        }
+        let command = members.remove("exec-oob").unwrap_or_default();
+        match command.as_str() {
+            Some(name) if QMP_OOB_COMMANDS.contains(&name) => {}
+            _ => {
+                return Err(schema::QmpErrorClass::GenericError(format!(
+                    "The command {} does not support OOB",
+                    command
+                )))
+            }
+        }
+        members.insert("execute".to_string(), command);
+    }
+    parse_qmp_request(request, policy)
+}
+
+/// Parse and exec an out-of-band request `{"exec-oob": ...}`. It doesn't wait for the
+/// in-band command in flight, so neither `QMP_DISPATCHER` nor the controller is locked,
+/// and its response may be sent before the one of the in-band command.
+fn qmp_oob_request_exec(
+    request: Value,
+    state: &Mutex<QmpConnState>,
+    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+    policy: QmpCompatPolicy,
+) -> (String, bool) {
+    let id = request
+        .get("id")
+        .and_then(Value::as_str)
+        .map(|id| id.to_string());
+    let qmp_command = match parse_qmp_oob_request(request, state, policy) {
+        Ok((qmp_command, _)) => qmp_command,
+        Err(err_resp) => {
+            return (
+                serde_json::to_string(&Response::create_error_response(err_resp, id)).unwrap(),
+                false,
+            )
+        }
+    };
+
+    let mut qmp_response = Response::create_empty_response();
+    let mut shutdown_flag = false;
+    match qmp_command {
+        // The vm is not destroyed by the controller, the process exits right away.
+        QmpCommand::quit { .. } => shutdown_flag = true,
+        QmpCommand::query_status { .. } => {
+            qmp_response = match QmpChannel::vm_status() {
+                Some(status) => {
+                    Response::create_response(serde_json::to_value(status).unwrap(), None)
+                }
+                None => Response::create_error_response(
+                    schema::QmpErrorClass::GenericError("VM state is not available".to_string()),
+                    None,
+                ),
+            };
+        }
+        // The unplug request is sent after the in-band command in flight, and the
+        // `DEVICE_DELETED` event is emitted once the device is deleted.
+        QmpCommand::device_del { arguments, .. } => {
+            if let Err(e) = device_del_deferred(controller, arguments.id) {
+                qmp_response = Response::create_error_response(e, None);
+            }
+        }
+        _ => {}
+    }
+
+    qmp_response.change_id(id);
+    (serde_json::to_string(&qmp_response).unwrap(), shutdown_flag)
+}
+
+/// Delete the device `device_id` in the main loop, in turn with the in-band commands.
+fn device_del_deferred(
+    controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+    device_id: String,
+) -> std::result::Result<(), schema::QmpErrorClass> {
+    let ctx = EventLoop::get_ctx(None).ok_or_else(|| {
+        schema::QmpErrorClass::GenericError("Main loop is not available".to_string())
+    })?;
+    let controller = controller.clone();
+    ctx.delay_call(
+        Box::new(move || {
+            let _dispatcher = QMP_DISPATCHER.lock().unwrap();
+            let resp = controller.lock().unwrap().device_del(device_id.clone());
+            if let Some(err) = resp.error {
+                error!("Failed to delete device {}: {}", device_id, err.desc);
+            }
+        }),
+        0,
+    );
+    Ok(())
+}
+
+fn is_guest_agent_command(request: &Value) -> bool {
+    request.get("execute").and_then(Value::as_str) == Some("guest-agent-command")
+}
+
+/// Queue `guest-agent-command` to the guest agent, as its reply is received by the
+/// main loop later. The response is sent to the connection `stream_fd` once the agent
+/// replies or the command times out. Return the error response if the command can't
+/// be queued.
+fn guest_agent_command_deferred(
+    request: Value,
+    stream_fd: RawFd,
+    policy: QmpCompatPolicy,
+) -> Option<String> {
+    let id = request
+        .get("id")
+        .and_then(Value::as_str)
+        .map(|id| id.to_string());
+    let error_response = |err_resp, id| {
+        Some(serde_json::to_string(&Response::create_error_response(err_resp, id)).unwrap())
+    };
+    let arguments = match parse_qmp_request(request, policy) {
+        Ok((QmpCommand::guest_agent_command { arguments, .. }, _)) => arguments,
+        Ok(_) => {
+            let err_resp =
+                schema::QmpErrorClass::GenericError("Not a guest-agent-command".to_string());
+            return error_response(err_resp, id);
+        }
+        Err(err_resp) => return error_response(err_resp, id),
+    };
+
+    let timeout = arguments.timeout.unwrap_or(DEFAULT_GUEST_AGENT_TIMEOUT_MS);
+    let cloned_id = id.clone();
+    let done = Box::new(move |ret: Result<Value>| {
+        let mut qmp_response = match ret {
+            Ok(value) => Response::create_response(value, None),
+            Err(e) => Response::create_error_response(
+                schema::QmpErrorClass::GenericError(format!("{:?}", e)),
+                None,
+            ),
+        };
+        qmp_response.change_id(cloned_id);
+        QmpChannel::send_response(stream_fd, &qmp_response);
+    });
+    match guest_agent_command(arguments.command, Duration::from_millis(timeout), done) {
+        Ok(()) => None,
+        Err(e) => error_response(schema::QmpErrorClass::GenericError(format!("{:?}", e)), id),
+    }
+}
+
 /// Create a match , where `qmp_command` and its arguments matching by handle
 /// function, and exec this qmp command.
 fn qmp_command_exec(
     qmp_command: QmpCommand,
+    state: &Mutex<QmpConnState>,
     controller: &Arc<Mutex<dyn MachineExternalInterface>>,
     if_fd: Option<RawFd>,
+    deprecated: Vec<DeprecatedWarning>,
 ) -> (String, bool) {
     let mut qmp_response = Response::create_empty_response();
     let mut shutdown_flag = false;
@@ -425,23 +770,26 @@ fn qmp_command_exec(
         (cont, resume),
         (system_powerdown, powerdown),
         (system_reset, reset),
+        (system_wakeup, system_wakeup),
+        (inject_nmi, inject_nmi),
+        (rtc_reset_reinjection, rtc_reset_reinjection),
         (query_status, query_status),
         (query_version, query_version),
         (query_commands, query_commands),
         (query_target, query_target),
         (query_kvm, query_kvm),
+        (query_name, query_name),
+        (query_uuid, query_uuid),
+        (query_vsock, query_vsock),
         (query_events, query_events),
         (query_machines, query_machines),
+        (query_cpu_definitions, query_cpu_definitions),
         (query_tpm_models, query_tpm_models),
         (query_tpm_types, query_tpm_types),
-        (query_command_line_options, query_command_line_options),
         (query_migrate_capabilities, query_migrate_capabilities),
         (query_qmp_schema, query_qmp_schema),
         (query_sev_capabilities, query_sev_capabilities),
         (query_chardev, query_chardev),
-        (qom_list, qom_list),
-        (qom_get, qom_get),
-        (query_block, query_block),
         (query_named_block_nodes, query_named_block_nodes),
         (query_blockstats, query_blockstats),
         (query_block_jobs, query_block_jobs),
@@ -449,9 +797,14 @@ fn qmp_command_exec(
         (query_iothreads, query_iothreads),
         (query_migrate, query_migrate),
         (cancel_migrate, cancel_migrate),
+        (query_migrate_parameters, query_migrate_parameters),
         (query_cpus, query_cpus),
         (query_balloon, query_balloon),
+        (query_mem_aging, query_mem_aging),
+        (query_memory_lock, query_memory_lock),
+        (query_placement, query_placement),
         (query_vnc, query_vnc),
+        (query_fdsets, query_fdsets),
         (list_type, list_type),
         (query_hotpluggable_cpus, query_hotpluggable_cpus);
         (input_event, input_event, key, value),
@@ -460,18 +813,55 @@ fn qmp_command_exec(
         (blockdev_del, blockdev_del, node_name),
         (netdev_del, netdev_del, id),
         (chardev_remove, chardev_remove, id),
+        (chardev_change, chardev_change, id, backend),
+        (qom_list, qom_list, path),
+        (qom_get, qom_get, path, property),
+        (closefd, closefd, fd_name),
+        (remove_fd, remove_fd, fdset_id, fd),
         (balloon, balloon, value),
-        (migrate, migrate, uri);
+        (query_block, query_block, reset_errors),
+        (query_netdev, query_netdev, reset_errors),
+        (block_set_write_threshold, block_set_write_threshold, device, write_threshold),
+        (block_set_writable, block_set_writable, device),
+        (block_resize, block_resize, device, size, allow_shrink),
+        (eject, eject, device, force),
+        (screendump, screendump, filename, format),
+        (
+            query_cpu_model_expansion,
+            query_cpu_model_expansion,
+            expansion_type,
+            model
+        ),
+        (blockdev_backup, blockdev_backup, device, target, sync),
+        (block_job_cancel, block_job_cancel, device),
+        (query_command_line_options, query_command_line_options, option),
+        (migrate, migrate, uri),
+        (migrate_set_capabilities, migrate_set_capabilities, capabilities);
         (device_add, device_add),
         (blockdev_add, blockdev_add),
         (netdev_add, netdev_add),
         (chardev_add, chardev_add),
+        (dump_guest_memory, dump_guest_memory),
+        (migrate_set_parameters, migrate_set_parameters),
         (update_region, update_region)
     );
 
     // Handle the Qmp command which macro can't cover
     if id.is_none() {
         id = match qmp_command {
+            QmpCommand::qmp_capabilities { arguments, id } => {
+                let enable = arguments.enable.unwrap_or_default();
+                if let Err(e) = state.lock().unwrap().negotiate(&enable) {
+                    qmp_response = Response::create_error_response(e, None);
+                }
+                id
+            }
+            QmpCommand::set_event_mask { arguments, id } => {
+                if let Err(e) = state.lock().unwrap().set_event_mask(&arguments.events) {
+                    qmp_response = Response::create_error_response(e, None);
+                }
+                id
+            }
             QmpCommand::quit { id, .. } => {
                 controller.lock().unwrap().destroy();
                 shutdown_flag = true;
@@ -481,24 +871,56 @@ fn qmp_command_exec(
                 qmp_response = controller.lock().unwrap().getfd(arguments.fd_name, if_fd);
                 id
             }
+            QmpCommand::add_fd { arguments, id } => {
+                qmp_response =
+                    controller
+                        .lock()
+                        .unwrap()
+                        .add_fd(arguments.fdset_id, arguments.opaque, if_fd);
+                id
+            }
+            QmpCommand::human_monitor_command { arguments, id } => {
+                let output = hmp::human_monitor_command(
+                    &*controller.lock().unwrap(),
+                    &arguments.command_line,
+                );
+                qmp_response = Response::create_response(Value::String(output), None);
+                id
+            }
             _ => None,
         }
     }
 
     // Change response id with input qmp message
     qmp_response.change_id(id);
+    qmp_response.set_deprecated(deprecated);
     (serde_json::to_string(&qmp_response).unwrap(), shutdown_flag)
 }
 
 /// The struct `QmpChannel` is the only struct can handle Global variable
 /// `QMP_CHANNEL`.
-/// It is used to send event to qmp client and restore some file descriptor
+/// It is used to send event to qmp clients and restore some file descriptor
 /// which was sended by client.
 pub struct QmpChannel {
-    /// The `writer` to send `QmpEvent`.
-    event_writer: RwLock<Option<SocketRWHandler>>,
+    /// The `writer`s to send `QmpEvent` to each connection, by the fd of the connection.
+    event_writers: RwLock<BTreeMap<RawFd, EventWriter>>,
     /// Restore file descriptor received from client.
     fds: Arc<RwLock<BTreeMap<String, RawFd>>>,
+    /// The fdsets of file descriptors received by `add-fd`.
+    fdsets: Mutex<Fdsets>,
+    /// How to handle deprecated commands and arguments.
+    compat: RwLock<QmpCompatPolicy>,
+    /// State of the vm, which is queried out-of-band without locking the machine.
+    vm_state: RwLock<Option<VmState>>,
+    /// Capabilities and parameters of migration.
+    migration: Arc<Mutex<MigrationState>>,
+}
+
+/// Event writer of a qmp connection.
+struct EventWriter {
+    writer: SocketRWHandler,
+    /// Capabilities negotiation state of the connection.
+    state: Arc<Mutex<QmpConnState>>,
 }
 
 impl QmpChannel {
@@ -507,30 +929,52 @@ impl QmpChannel {
         unsafe {
             if QMP_CHANNEL.is_none() {
                 QMP_CHANNEL = Some(Arc::new(QmpChannel {
-                    event_writer: RwLock::new(None),
+                    event_writers: RwLock::new(BTreeMap::new()),
                     fds: Arc::new(RwLock::new(BTreeMap::new())),
+                    fdsets: Mutex::new(Fdsets::default()),
+                    compat: RwLock::new(QmpCompatPolicy::default()),
+                    vm_state: RwLock::new(None),
+                    migration: Arc::new(Mutex::new(MigrationState::default())),
                 }));
             }
         }
     }
 
-    /// Bind a `SocketRWHandler` to `QMP_CHANNEL`.
+    /// Bind a qmp connection to `QMP_CHANNEL`, events are sent to it once it's negotiated.
     ///
     /// # Arguments
     ///
-    /// * `writer` - The `SocketRWHandler` used to communicate with client.
-    pub fn bind_writer(writer: SocketRWHandler) {
-        *Self::inner().event_writer.write().unwrap() = Some(writer);
+    /// * `stream_fd` - The socket fd used to communicate with client.
+    /// * `state` - Capabilities negotiation state of the connection.
+    pub fn bind_writer(stream_fd: RawFd, state: Arc<Mutex<QmpConnState>>) {
+        let writer = EventWriter {
+            writer: SocketRWHandler::new(stream_fd),
+            state,
+        };
+        Self::inner()
+            .event_writers
+            .write()
+            .unwrap()
+            .insert(stream_fd, writer);
     }
 
-    /// Unbind `SocketRWHandler` from `QMP_CHANNEL`.
-    pub fn unbind() {
-        *Self::inner().event_writer.write().unwrap() = None;
+    /// Unbind a qmp connection from `QMP_CHANNEL`. The fdsets which have never been
+    /// used are closed once the last connection is unbound.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The socket fd of the connection.
+    pub fn unbind(stream_fd: RawFd) {
+        let mut writers = Self::inner().event_writers.write().unwrap();
+        writers.remove(&stream_fd);
+        if writers.is_empty() {
+            Self::inner().fdsets.lock().unwrap().cleanup_unused();
+        }
     }
 
-    /// Check whether a `SocketRWHandler` bind with `QMP_CHANNEL` or not.
+    /// Check whether any qmp connection binds with `QMP_CHANNEL` or not.
     pub fn is_connected() -> bool {
-        Self::inner().event_writer.read().unwrap().is_some()
+        !Self::inner().event_writers.read().unwrap().is_empty()
     }
 
     /// Restore extern file descriptor in `QMP_CHANNEL`.
@@ -552,7 +996,116 @@ impl QmpChannel {
         Self::inner().fds.read().unwrap().get(name).copied()
     }
 
-    /// Send a `QmpEvent` to client.
+    /// Remove extern file descriptor restored in `QMP_CHANNEL`, the caller owns it.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of file descriptor.
+    pub fn remove_fd(name: &str) -> Option<RawFd> {
+        Self::inner().fds.write().unwrap().remove(name)
+    }
+
+    /// Add extern file descriptor to a fdset, and return the id of the fdset.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - Id of the fdset, a new fdset is created if it's `None`.
+    /// * `fd` - File descriptor sent by client.
+    /// * `opaque` - A free-form string to identify the fd.
+    pub fn add_fdset_fd(fdset_id: Option<u64>, fd: RawFd, opaque: Option<String>) -> Result<u64> {
+        Self::inner()
+            .fdsets
+            .lock()
+            .unwrap()
+            .add_fd(fdset_id, fd, opaque)
+    }
+
+    /// Remove file descriptor `fd` from a fdset, or the whole fdset if `fd` is `None`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - Id of the fdset.
+    /// * `fd` - File descriptor in the fdset.
+    pub fn remove_fdset_fd(fdset_id: u64, fd: Option<RawFd>) -> Result<()> {
+        Self::inner().fdsets.lock().unwrap().remove_fd(fdset_id, fd)
+    }
+
+    /// Query all the fdsets.
+    pub fn query_fdsets() -> Vec<schema::FdsetInfo> {
+        Self::inner().fdsets.lock().unwrap().query()
+    }
+
+    /// Get the capabilities and parameters of migration, which are shared with the migration.
+    pub fn migration_state() -> Arc<Mutex<MigrationState>> {
+        Self::inner().migration.clone()
+    }
+
+    /// Duplicate a file descriptor of a fdset with the access mode of the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `fdset_id` - Id of the fdset.
+    /// * `read_only` - The file is opened read only.
+    pub fn dup_fdset_fd(fdset_id: u64, read_only: bool) -> Result<RawFd> {
+        Self::inner()
+            .fdsets
+            .lock()
+            .unwrap()
+            .dup_fd(fdset_id, read_only)
+    }
+
+    /// Set the policy for deprecated commands and arguments.
+    ///
+    /// # Arguments
+    ///
+    /// * `policy` - Reject deprecated usage or warn in response.
+    pub fn set_compat_policy(policy: QmpCompatPolicy) {
+        *Self::inner().compat.write().unwrap() = policy;
+    }
+
+    fn compat_policy() -> QmpCompatPolicy {
+        *Self::inner().compat.read().unwrap()
+    }
+
+    /// Set the state of the vm, which is reported by the out-of-band `query-status`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm_state` - The state of the vm shared with the machine.
+    pub fn set_vm_state(vm_state: VmState) {
+        *Self::inner().vm_state.write().unwrap() = Some(vm_state);
+    }
+
+    fn vm_status() -> Option<schema::StatusInfo> {
+        let vm_state = Self::inner().vm_state.read().unwrap();
+        let state = *vm_state.as_ref()?.0.lock().unwrap();
+        let status = match state {
+            KvmVmState::Running => schema::StatusInfo {
+                singlestep: false,
+                running: true,
+                status: schema::RunState::running,
+            },
+            KvmVmState::Paused => schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: schema::RunState::paused,
+            },
+            KvmVmState::Suspended => schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: schema::RunState::suspended,
+            },
+            KvmVmState::Shutdown => schema::StatusInfo {
+                singlestep: false,
+                running: false,
+                status: schema::RunState::shutdown,
+            },
+            _ => Default::default(),
+        };
+        Some(status)
+    }
+
+    /// Send a `QmpEvent` to all the negotiated clients which don't mask it.
     ///
     /// # Arguments
     ///
@@ -561,20 +1114,55 @@ impl QmpChannel {
     pub fn send_event(event: &schema::QmpEvent) {
         if Self::is_connected() {
             let mut event_str = serde_json::to_string(&event).unwrap();
-            let mut writer_unlocked = Self::inner().event_writer.write().unwrap();
-            let writer = writer_unlocked.as_mut().unwrap();
-
-            if let Err(e) = writer.flush() {
-                error!("flush err, {:?}", e);
-                return;
-            }
             event_str.push_str("\r\n");
-            if let Err(e) = writer.write(event_str.as_bytes()) {
-                error!("write err, {:?}", e);
+            let mut writers = Self::inner().event_writers.write().unwrap();
+            for (fd, event_writer) in writers.iter_mut() {
+                let state = *event_writer.state.lock().unwrap();
+                if !state.negotiated || state.is_event_masked(event) {
+                    continue;
+                }
+                let writer = &mut event_writer.writer;
+                if let Err(e) = writer.flush() {
+                    error!("flush err on qmp connection {}, {:?}", fd, e);
+                    continue;
+                }
+                // The connection which is broken is removed when it hangs up.
+                if let Err(e) = writer.write(event_str.as_bytes()) {
+                    error!("write err on qmp connection {}, {:?}", fd, e);
+                    continue;
+                }
+                info!("EVENT: --> {:?}", event);
+            }
+        }
+    }
+
+    /// Send the response of a command executed asynchronously to the connection.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The socket fd of the connection which sends the command.
+    /// * `response` - The response of the command.
+    #[allow(clippy::unused_io_amount)]
+    pub fn send_response(stream_fd: RawFd, response: &Response) {
+        let mut resp_str = serde_json::to_string(response).unwrap();
+        resp_str.push_str("\r\n");
+        let mut writers = Self::inner().event_writers.write().unwrap();
+        let writer = match writers.get_mut(&stream_fd) {
+            Some(event_writer) => &mut event_writer.writer,
+            None => {
+                warn!("Qmp connection {} is closed, drop response", stream_fd);
                 return;
             }
-            info!("EVENT: --> {:?}", event);
+        };
+        if let Err(e) = writer.flush() {
+            error!("flush err on qmp connection {}, {:?}", stream_fd, e);
+            return;
+        }
+        if let Err(e) = writer.write(resp_str.as_bytes()) {
+            error!("write err on qmp connection {}, {:?}", stream_fd, e);
+            return;
         }
+        info!("QMP: --> {:?}", resp_str);
     }
 
     fn inner() -> &'static std::sync::Arc<QmpChannel> {
@@ -594,6 +1182,7 @@ mod tests {
     use super::*;
     use serde_json;
     use std::os::unix::net::{UnixListener, UnixStream};
+    use std::sync::Barrier;
 
     #[test]
     fn test_qmp_greeting_msg() {
@@ -610,7 +1199,8 @@ mod tests {
                         },
                         "package": ""
                     },
-                    "capabilities": []
+                    "capabilities": ["oob"],
+                    "schema-version": 1
                 }
             }
         "#;
@@ -688,7 +1278,7 @@ mod tests {
 
     #[test]
     fn test_qmp_event_macro() {
-        use crate::socket::{Socket, SocketRWHandler};
+        use crate::socket::Socket;
         use std::io::Read;
 
         // Pre test. Environment preparation
@@ -698,8 +1288,23 @@ mod tests {
 
         // Use event! macro to send event msg to client
         let socket = Socket::from_unix_listener(listener, None);
-        socket.bind_unix_stream(server);
-        QmpChannel::bind_writer(SocketRWHandler::new(socket.get_stream_fd()));
+        let negotiated = socket.bind_unix_stream(server);
+        negotiated
+            .get_state()
+            .lock()
+            .unwrap()
+            .negotiate(&[])
+            .unwrap();
+        QmpChannel::bind_writer(negotiated.get_stream_fd(), negotiated.get_state().clone());
+
+        // Events are not sent to the client which doesn't negotiate capabilities.
+        let mut unnegotiated_client = UnixStream::connect("test_06.sock").unwrap();
+        unnegotiated_client.set_nonblocking(true).unwrap();
+        let unnegotiated = socket.accept().unwrap();
+        QmpChannel::bind_writer(
+            unnegotiated.get_stream_fd(),
+            unnegotiated.get_state().clone(),
+        );
 
         // 1.send no-content event
         event!(Stop);
@@ -732,8 +1337,14 @@ mod tests {
             }
             _ => assert!(false),
         }
+        assert_eq!(
+            unnegotiated_client.read(&mut buffer).unwrap_err().kind(),
+            std::io::ErrorKind::WouldBlock
+        );
 
         // After test. Environment Recover
+        QmpChannel::unbind(negotiated.get_stream_fd());
+        QmpChannel::unbind(unnegotiated.get_stream_fd());
         recover_unix_socket_environment("06");
     }
 
@@ -748,19 +1359,20 @@ mod tests {
 
         // Use event! macro to send event msg to client
         let socket = Socket::from_unix_listener(listener, None);
-        socket.bind_unix_stream(server);
+        let client_handler = socket.bind_unix_stream(server);
 
         // 1.send greeting response
-        let res = socket.send_response(true);
+        let res = client_handler.send_response(true);
         let length = client.read(&mut buffer).unwrap();
         let qmp_response: QmpGreeting =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
         let qmp_greeting = QmpGreeting::create_greeting(1, 0, 5);
         assert_eq!(qmp_greeting, qmp_response);
         assert_eq!(res.is_err(), false);
+        assert!(client_handler.get_state().lock().unwrap().greeted);
 
         // 2.send empty response
-        let res = socket.send_response(false);
+        let res = client_handler.send_response(false);
         let length = client.read(&mut buffer).unwrap();
         let qmp_response: Response =
             serde_json::from_str(&(String::from_utf8_lossy(&buffer[..length]))).unwrap();
@@ -773,6 +1385,643 @@ mod tests {
         drop(socket);
     }
 
+    /// Machine mock shared by the QMP and human monitor tests.
+    #[derive(Default)]
+    pub(super) struct TestController {
+        pub(super) boot_index: Option<u8>,
+        pub(super) running: bool,
+        pub(super) balloon_size: Mutex<u64>,
+        /// Holds `query-balloon` until the test passes it twice, once when the command
+        /// is in flight and once to let it finish.
+        pub(super) balloon_gate: Option<Arc<Barrier>>,
+    }
+
+    fn to_response<T: serde::Serialize>(ret: T) -> Response {
+        Response::create_response(serde_json::to_value(ret).unwrap(), None)
+    }
+
+    impl crate::machine::MachineLifecycle for TestController {
+        fn pause(&self) -> bool {
+            event!(Stop);
+            true
+        }
+
+        fn notify_lifecycle(&self, _old: KvmVmState, _new: KvmVmState) -> bool {
+            true
+        }
+    }
+
+    impl crate::machine::DeviceInterface for TestController {
+        fn query_status(&self) -> Response {
+            to_response(schema::StatusInfo {
+                singlestep: false,
+                running: self.running,
+                status: if self.running {
+                    schema::RunState::running
+                } else {
+                    schema::RunState::paused
+                },
+            })
+        }
+
+        fn query_cpus(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_cpu_model_expansion(
+            &self,
+            _expansion_type: String,
+            _model: schema::CpuModelInfo,
+        ) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_cpu_definitions(&self) -> Response {
+            let host = schema::CpuDefinitionInfo {
+                name: "host".to_string(),
+                typename: "host-x86-cpu".to_string(),
+                ..Default::default()
+            };
+            Response::create_response(serde_json::to_value(vec![host]).unwrap(), None)
+        }
+
+        fn query_hotpluggable_cpus(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn device_add(&mut self, args: Box<schema::DeviceAddArgument>) -> Response {
+            self.boot_index = args.boot_index;
+            Response::create_empty_response()
+        }
+
+        fn device_del(&mut self, _device_id: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn blockdev_add(&self, _args: Box<schema::BlockDevAddArgument>) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn blockdev_del(&self, _node_name: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn netdev_add(&mut self, _args: Box<schema::NetDevAddArgument>) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn netdev_del(&mut self, _id: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn chardev_add(&mut self, _args: schema::CharDevAddArgument) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn chardev_remove(&mut self, _id: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn chardev_change(&mut self, _id: String, _backend: schema::BackendOptions) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn getfd(&self, _fd_name: String, _if_fd: Option<RawFd>) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_balloon(&self) -> Response {
+            if let Some(gate) = &self.balloon_gate {
+                gate.wait();
+                gate.wait();
+            }
+            to_response(schema::BalloonInfo {
+                actual: *self.balloon_size.lock().unwrap(),
+                stats: None,
+            })
+        }
+
+        fn query_mem_aging(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_memory_lock(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_placement(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_vnc(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn query_name(&self) -> Response {
+            let name_info = qmp_schema::NameInfo::new("vm1");
+            Response::create_response(serde_json::to_value(name_info).unwrap(), None)
+        }
+
+        fn query_uuid(&self) -> Response {
+            let uuid_info = qmp_schema::UuidInfo::new(None);
+            Response::create_response(serde_json::to_value(uuid_info).unwrap(), None)
+        }
+
+        fn query_vsock(&self) -> Response {
+            let vsocks = vec![qmp_schema::VsockInfo {
+                id: "vsock0".to_string(),
+                guest_cid: 3,
+                vhost_fd: None,
+            }];
+            Response::create_response(serde_json::to_value(vsocks).unwrap(), None)
+        }
+
+        fn query_block(&self, _reset_errors: Option<bool>) -> Response {
+            to_response(vec![
+                schema::BlockInfo {
+                    device: "drive-0".to_string(),
+                    file: "/path/to/rootfs".to_string(),
+                    read_only: false,
+                    errors: schema::DeviceErrorInfo {
+                        enospc: 1,
+                        ..Default::default()
+                    },
+                    write_threshold: 1 << 30,
+                    wr_highest_offset: 4096,
+                    write_protected: false,
+                },
+                schema::BlockInfo {
+                    device: "cdrom-0".to_string(),
+                    file: "/path/to/iso".to_string(),
+                    read_only: true,
+                    ..Default::default()
+                },
+            ])
+        }
+
+        fn query_netdev(&self, _reset_errors: Option<bool>) -> Response {
+            to_response(vec![schema::NetdevInfo {
+                id: "net-0".to_string(),
+                ifname: "tap0".to_string(),
+                errors: schema::DeviceErrorInfo::default(),
+            }])
+        }
+
+        fn block_set_write_threshold(&self, _device: String, _write_threshold: u64) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn block_set_writable(&self, _device: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn block_resize(
+            &self,
+            _device: String,
+            _size: u64,
+            _allow_shrink: Option<bool>,
+        ) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn eject(&self, _device: String, _force: Option<bool>) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn blockdev_backup(
+            &self,
+            _device: String,
+            _target: String,
+            _sync: Option<String>,
+        ) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn block_job_cancel(&self, _device: String) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn dump_guest_memory(&mut self, _args: schema::DumpGuestMemoryArgument) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn balloon(&self, size: u64) -> Response {
+            if size == 0 {
+                return Response::create_error_response(
+                    schema::QmpErrorClass::GenericError(
+                        "No balloon device has been activated".to_string(),
+                    ),
+                    None,
+                );
+            }
+            *self.balloon_size.lock().unwrap() = size;
+            Response::create_empty_response()
+        }
+
+        fn inject_nmi(&self) -> Response {
+            Response::create_empty_response()
+        }
+
+        fn system_wakeup(&self) -> Response {
+            Response::create_error_response(
+                schema::QmpErrorClass::GenericError(
+                    "Unable to wake up: guest is not in suspended state".to_string(),
+                ),
+                None,
+            )
+        }
+
+        fn update_region(&mut self, _args: schema::UpdateRegionArgument) -> Response {
+            Response::create_empty_response()
+        }
+    }
+
+    impl crate::machine::MigrateInterface for TestController {}
+
+    impl MachineExternalInterface for TestController {}
+
+    fn exec_request(
+        controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+        request: &str,
+        policy: QmpCompatPolicy,
+    ) -> Value {
+        let request: Value = serde_json::from_str(request).unwrap();
+        let state = Mutex::new(QmpConnState::default());
+        let (resp, shutdown) = qmp_request_exec(request, &state, controller, None, policy);
+        assert!(!shutdown);
+        serde_json::from_str(&resp).unwrap()
+    }
+
+    #[test]
+    fn test_qmp_human_monitor_command() {
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+
+        // Unknown human monitor commands are not QMP errors.
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"human-monitor-command","arguments":{"command-line":"drive_add 0 if=none"},"id":"1"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": "unknown command: 'drive_add 0 if=none'\r\n", "id": "1"})
+        );
+    }
+
+    #[test]
+    fn test_qmp_query_name_uuid() {
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-name"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {"name": "vm1"}}));
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-uuid","id":"2"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": {"UUID": "00000000-0000-0000-0000-000000000000"}, "id": "2"})
+        );
+
+        // The name is omitted if not set.
+        assert_eq!(
+            serde_json::to_value(qmp_schema::NameInfo::new("")).unwrap(),
+            serde_json::json!({})
+        );
+        assert_eq!(
+            serde_json::to_value(qmp_schema::UuidInfo::new(Some(
+                "550e8400-e29b-41d4-a716-446655440000"
+            )))
+            .unwrap(),
+            serde_json::json!({"UUID": "550e8400-e29b-41d4-a716-446655440000"})
+        );
+    }
+
+    #[test]
+    fn test_qmp_query_target_machines_cpu_definitions() {
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-target"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": {"arch": std::env::consts::ARCH}})
+        );
+
+        // The machine type registered again with the same name replaces the old one.
+        crate::machine::register_machine_type(schema::MachineInfo {
+            name: "microvm".to_string(),
+            cpu_max: 1,
+            ..Default::default()
+        });
+        crate::machine::register_machine_type(schema::MachineInfo {
+            name: "microvm".to_string(),
+            cpu_max: 254,
+            is_default: true,
+            ..Default::default()
+        });
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-machines"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": [{
+                "hotpluggable-cpus": false, "name": "microvm", "numa-mem-supported": false,
+                "cpu-max": 254, "deprecated": false, "is-default": true
+            }]})
+        );
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-cpu-definitions","id":"3"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": [{
+                "name": "host", "typename": "host-x86-cpu", "static": false,
+                "migration-safe": false, "unavailable-features": [], "deprecated": false
+            }], "id": "3"})
+        );
+    }
+
+    #[test]
+    fn test_qmp_query_vsock() {
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-vsock"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": [{"id": "vsock0", "guest-cid": 3}]})
+        );
+    }
+
+    #[test]
+    fn test_qmp_migrate_parameters() {
+        QmpChannel::object_init();
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-migrate"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {"status": "none"}}));
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"migrate-set-capabilities","arguments":{"capabilities":[{"capability":"auto-converge","state":true}]}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}}));
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-migrate-capabilities"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": [
+                {"capability": "xbzrle", "state": false},
+                {"capability": "auto-converge", "state": true},
+                {"capability": "postcopy-ram", "state": false}
+            ]})
+        );
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"migrate-set-parameters","arguments":{"max-bandwidth":1073741824,"downtime-limit":500}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}}));
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"query-migrate-parameters"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": {"max-bandwidth": 1073741824_u64, "downtime-limit": 500}})
+        );
+
+        // Out of range downtime is rejected, and the parameters are unchanged.
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"migrate-set-parameters","arguments":{"max-bandwidth":1024,"downtime-limit":2000001}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp["error"]["class"], "GenericError");
+        let params = QmpChannel::migration_state().lock().unwrap().parameters();
+        assert_eq!(params.max_bandwidth, 1 << 30);
+        assert_eq!(params.downtime_limit, 500);
+    }
+
+    #[test]
+    fn test_qmp_qom_list_and_get() {
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+        crate::qom::register_object(
+            "/qom-dispatch/unattached/device[0]",
+            crate::qom::QomObject::new("host-x86-cpu"),
+        );
+        crate::qom::register_object(
+            "/qom-dispatch/peripheral/blk0",
+            crate::qom::QomObject::new("virtio-blk-pci").with_prop("drive", "drive-0"),
+        );
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom-list","arguments":{"path":"/qom-dispatch"}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": [
+                {"name": "peripheral", "type": "child<container>"},
+                {"name": "unattached", "type": "child<container>"},
+                {"name": "type", "type": "string"}
+            ]})
+        );
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom-list","arguments":{"path":"/qom-dispatch/peripheral/blk0"}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": [
+                {"name": "type", "type": "string"},
+                {"name": "drive", "type": "string"},
+                {"name": "realized", "type": "bool"}
+            ]})
+        );
+
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom_get","arguments":{"path":"/qom-dispatch/peripheral/blk0","property":"drive"}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp, serde_json::json!({"return": "drive-0"}));
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom_get","arguments":{"path":"/qom-dispatch/unattached/device[0]","property":"realized"}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp, serde_json::json!({"return": true}));
+
+        // Unknown paths are not found, and unknown properties are generic errors.
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom-list","arguments":{"path":"/qom-dispatch/unattached/device[1]"}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp["error"]["class"], "DeviceNotFound");
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom_get","arguments":{"path":"/qom-dispatch/peripheral/blk1","property":"type"}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp["error"]["class"], "DeviceNotFound");
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom_get","arguments":{"path":"/qom-dispatch/peripheral/blk0","property":"bus"}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp["error"]["class"], "GenericError");
+    }
+
+    #[test]
+    fn test_qmp_deprecated_alias() {
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl.clone();
+
+        crate::qom::register_object("/qom-alias/device[0]", crate::qom::QomObject::new("cpu"));
+
+        // 1.Current names work without warning.
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom_get","arguments":{"path":"/qom-alias/device[0]","property":"type"},"id":"1"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(resp, serde_json::json!({"return": "cpu", "id": "1"}));
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"device_add","arguments":{"id":"blk0","driver":"virtio-blk-pci","boot_index":2}}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(test_ctrl.lock().unwrap().boot_index, Some(2));
+        assert_eq!(resp, serde_json::json!({"return": {}}));
+
+        // 2.Deprecated command name works with a warning.
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"qom-get","arguments":{"path":"/qom-alias/device[0]","property":"type"},"id":"2"}"#,
+            QmpCompatPolicy::Warn,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({
+                "return": "cpu",
+                "id": "2",
+                "deprecated": [{"command": "qom-get", "replacement": "qom_get"}]
+            })
+        );
+
+        // 3.Deprecated argument name works with a warning.
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"device_add","arguments":{"id":"blk0","driver":"virtio-blk-pci","bootindex":3}}"#,
+            QmpCompatPolicy::Warn,
+        );
+        assert_eq!(test_ctrl.lock().unwrap().boot_index, Some(3));
+        assert_eq!(
+            resp,
+            serde_json::json!({
+                "return": {},
+                "deprecated": [{
+                    "command": "device_add",
+                    "argument": "bootindex",
+                    "replacement": "boot_index"
+                }]
+            })
+        );
+
+        // 4.Deprecated names are rejected in strict mode.
+        test_ctrl.lock().unwrap().boot_index = None;
+        let resp = exec_request(
+            &controller,
+            r#"{"execute":"device_add","arguments":{"id":"blk0","driver":"virtio-blk-pci","bootindex":3},"id":"4"}"#,
+            QmpCompatPolicy::Strict,
+        );
+        assert_eq!(test_ctrl.lock().unwrap().boot_index, None);
+        assert_eq!(resp["id"], "4");
+        assert_eq!(resp["error"]["class"], "GenericError");
+        assert!(resp.get("deprecated").is_none());
+    }
+
+    #[test]
+    fn test_decode_qmp_request_smoke() {
+        // A short run of the qmp fuzz target, with mutations of valid requests.
+        let seeds = [
+            r#"{"execute":"query-status"}"#,
+            r#"{"execute":"device_add","arguments":{"id":"blk0","driver":"virtio-blk-pci","boot_index":3},"id":"1"}"#,
+            r#"{"execute":"blockdev-add","arguments":{"node-name":"drive-0","file":{"driver":"file","filename":"/path/to/img"},"cache":{"direct":true}}}"#,
+            r#"{"execute":"human-monitor-command","arguments":{"command-line":"info status"}}"#,
+            r#"{"execute":"qom-get","arguments":{}}"#,
+        ];
+        for seed in seeds {
+            assert!(decode_qmp_request(seed.as_bytes(), QmpCompatPolicy::Warn).is_ok());
+        }
+        assert!(decode_qmp_request(seeds[4].as_bytes(), QmpCompatPolicy::Strict).is_err());
+        assert!(decode_qmp_request(&[b' '; 8193], QmpCompatPolicy::Warn).is_err());
+
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as usize
+        };
+        for i in 0..4096 {
+            let mut buf = seeds[i % seeds.len()].as_bytes().to_vec();
+            for _ in 0..next() % 4 + 1 {
+                let pos = next() % (buf.len() + 1);
+                match next() % 3 {
+                    0 => buf.insert(pos, next() as u8),
+                    1 if pos < buf.len() => {
+                        buf.remove(pos);
+                    }
+                    _ => buf.truncate(pos),
+                }
+            }
+            let policy = if i % 2 == 0 {
+                QmpCompatPolicy::Warn
+            } else {
+                QmpCompatPolicy::Strict
+            };
+            let _ = decode_qmp_request(&buf, policy);
+        }
+    }
+
     #[test]
     fn test_create_error_response() {
         let strange_msg = "!?/.,、。’】=  -~1！@#￥%……&*（）——+".to_string();
@@ -809,4 +2058,607 @@ mod tests {
         let resp = Response::create_error_response(qmp_err, None);
         assert_eq!(resp.error, Some(msg));
     }
+
+    fn exec_client_request(
+        client: &mut (
+            std::io::BufReader<UnixStream>,
+            Arc<crate::socket::SocketClient>,
+        ),
+        controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+        leak_bucket: &mut LeakBucket,
+        request: &str,
+    ) -> Value {
+        use std::io::{BufRead, Write};
+
+        client.0.get_mut().write_all(request.as_bytes()).unwrap();
+        let server = &client.1;
+        handle_qmp(
+            server.get_stream_fd(),
+            server.get_state(),
+            controller,
+            leak_bucket,
+        )
+        .unwrap();
+
+        // Skip the events sent by other tests.
+        loop {
+            let mut line = String::new();
+            client.0.read_line(&mut line).unwrap();
+            let msg: Value = serde_json::from_str(&line).unwrap();
+            if msg.get("event").is_none() {
+                return msg;
+            }
+        }
+    }
+
+    #[test]
+    fn test_qmp_multiple_clients() {
+        use crate::event_loop::EventLoop;
+        use crate::socket::{Socket, LEAK_BUCKET_LIMIT};
+        use std::io::{BufRead, BufReader};
+
+        QmpChannel::object_init();
+        EventLoop::object_init(&None).unwrap();
+        let socket_name = "test_08.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let socket = Socket::from_unix_listener(UnixListener::bind(socket_name).unwrap(), None);
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+        let mut leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT).unwrap();
+
+        // 1.Two clients connect at the same time, both are greeted.
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let stream = UnixStream::connect(socket_name).unwrap();
+            let server = socket.accept().unwrap();
+            QmpChannel::bind_writer(server.get_stream_fd(), server.get_state().clone());
+            server.send_response(true).unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut greeting = String::new();
+            reader.read_line(&mut greeting).unwrap();
+            assert!(greeting.contains("\"QMP\""));
+            clients.push((reader, server));
+        }
+        assert_eq!(socket.clients_num(), 2);
+
+        // 2.Negotiate capabilities on both, each connection has its own state.
+        let resp = exec_client_request(
+            &mut clients[0],
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"qmp_capabilities","arguments":{"enable":["oob","foo"]},"id":"a0"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"error": {"class": "GenericError", "desc": "Capability 'foo' is not available"}, "id": "a0"})
+        );
+        assert!(!clients[0].1.get_state().lock().unwrap().negotiated);
+        let resp = exec_client_request(
+            &mut clients[0],
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"qmp_capabilities","arguments":{"enable":["oob"]},"id":"a0"}"#,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}, "id": "a0"}));
+        assert!(clients[0].1.get_state().lock().unwrap().oob);
+        for (i, client) in clients.iter_mut().enumerate() {
+            let request = format!(r#"{{"execute":"qmp_capabilities","id":"a{}"}}"#, i);
+            let resp = exec_client_request(client, &controller, &mut leak_bucket, &request);
+            assert_eq!(
+                resp,
+                serde_json::json!({"return": {}, "id": format!("a{}", i)})
+            );
+            let state = *client.1.get_state().lock().unwrap();
+            assert!(state.greeted && state.negotiated && !state.oob);
+        }
+
+        // 3.Run query-status on each connection.
+        for (i, client) in clients.iter_mut().enumerate() {
+            let request = format!(r#"{{"execute":"query-status","id":"b{}"}}"#, i);
+            let resp = exec_client_request(client, &controller, &mut leak_bucket, &request);
+            assert_eq!(
+                resp,
+                serde_json::json!({"return": {"running": false, "singlestep": false, "status": "paused"}, "id": format!("b{}", i)})
+            );
+        }
+
+        // 4.Drop the first client, the second one continues.
+        let (reader, server) = clients.remove(0);
+        drop(reader);
+        QmpChannel::unbind(server.get_stream_fd());
+        socket.drop_stream(server.get_stream_fd());
+        drop(server);
+        assert_eq!(socket.clients_num(), 1);
+        let resp = exec_client_request(
+            &mut clients[0],
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"query-status","id":"c1"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": {"running": false, "singlestep": false, "status": "paused"}, "id": "c1"})
+        );
+
+        QmpChannel::unbind(clients[0].1.get_stream_fd());
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_event_mask() {
+        use crate::event_loop::EventLoop;
+        use crate::socket::{Socket, LEAK_BUCKET_LIMIT};
+        use std::io::{BufRead, BufReader, Write};
+        use strum::VariantNames;
+
+        QmpChannel::object_init();
+        EventLoop::object_init(&None).unwrap();
+        let socket_name = "test_12.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let socket = Socket::from_unix_listener(UnixListener::bind(socket_name).unwrap(), None);
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+        let mut leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT).unwrap();
+
+        let mut clients = Vec::new();
+        for i in 0..2 {
+            let stream = UnixStream::connect(socket_name).unwrap();
+            let server = socket.accept().unwrap();
+            QmpChannel::bind_writer(server.get_stream_fd(), server.get_state().clone());
+            let mut client = (BufReader::new(stream), server);
+            let request = format!(r#"{{"execute":"qmp_capabilities","id":"a{}"}}"#, i);
+            exec_client_request(&mut client, &controller, &mut leak_bucket, &request);
+            clients.push(client);
+        }
+
+        // 1.The registry lists the events by their names.
+        let resp = exec_client_request(
+            &mut clients[0],
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"query-events","id":"b"}"#,
+        );
+        let events = resp["return"].as_array().unwrap();
+        assert_eq!(events.len(), schema::QmpEvent::VARIANTS.len());
+        assert!(events.contains(&serde_json::json!({"name": "STOP"})));
+        assert!(schema::QmpEvent::VARIANTS.len() <= u64::BITS as usize);
+
+        // 2.Unknown events are rejected, and the mask is unchanged.
+        let resp = exec_client_request(
+            &mut clients[0],
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"set-event-mask","arguments":{"events":["STOP","Stop"]},"id":"c"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"error": {"class": "GenericError", "desc": "Event 'Stop' is not available"}, "id": "c"})
+        );
+        assert_eq!(clients[0].1.get_state().lock().unwrap().event_mask, 0);
+
+        // 3.Mask STOP on the first connection, then pause the VM from it.
+        let resp = exec_client_request(
+            &mut clients[0],
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"set-event-mask","arguments":{"events":["STOP"]},"id":"d"}"#,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}, "id": "d"}));
+        clients[0]
+            .0
+            .get_mut()
+            .write_all(br#"{"execute":"stop","id":"e"}"#)
+            .unwrap();
+        let server = &clients[0].1;
+        handle_qmp(
+            server.get_stream_fd(),
+            server.get_state(),
+            &controller,
+            &mut leak_bucket,
+        )
+        .unwrap();
+
+        // The event is sent before the response, it's never received by the masked
+        // connection. Events sent by other tests are skipped.
+        let read_msg = |client: &mut (BufReader<UnixStream>, Arc<crate::socket::SocketClient>)| {
+            let mut line = String::new();
+            client.0.read_line(&mut line).unwrap();
+            serde_json::from_str::<Value>(&line).unwrap()
+        };
+        loop {
+            let msg = read_msg(&mut clients[0]);
+            assert_ne!(msg["event"], "STOP");
+            if msg.get("event").is_none() {
+                assert_eq!(msg, serde_json::json!({"return": {}, "id": "e"}));
+                break;
+            }
+        }
+        while read_msg(&mut clients[1])["event"] != "STOP" {}
+
+        for (_, server) in clients.iter() {
+            QmpChannel::unbind(server.get_stream_fd());
+        }
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_oob_exec() {
+        use crate::event_loop::EventLoop;
+        use crate::socket::{Socket, LEAK_BUCKET_LIMIT};
+        use std::io::{BufRead, BufReader, Write};
+
+        QmpChannel::object_init();
+        EventLoop::object_init(&None).unwrap();
+        let vm_state = Arc::new((Mutex::new(KvmVmState::Running), Condvar::new()));
+        QmpChannel::set_vm_state(vm_state.clone());
+        let socket_name = "test_09.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let socket = Socket::from_unix_listener(UnixListener::bind(socket_name).unwrap(), None);
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+        let mut leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT).unwrap();
+
+        let stream = UnixStream::connect(socket_name).unwrap();
+        let server = socket.accept().unwrap();
+        server.send_response(true).unwrap();
+        let mut client = (BufReader::new(stream), server);
+        let mut greeting = String::new();
+        client.0.read_line(&mut greeting).unwrap();
+        let greeting: Value = serde_json::from_str(&greeting).unwrap();
+        assert_eq!(greeting["QMP"]["capabilities"], serde_json::json!(["oob"]));
+
+        // 1.Out-of-band execution must be enabled by qmp_capabilities.
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"qmp_capabilities","id":"a"}"#,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}, "id": "a"}));
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"exec-oob":"query-status","id":"b"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"error": {"class": "GenericError", "desc": "Out-of-band execution is not enabled by qmp_capabilities"}, "id": "b"})
+        );
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"qmp_capabilities","arguments":{"enable":["oob"]},"id":"c"}"#,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}, "id": "c"}));
+
+        // 2.Only the allow-listed commands can be executed out-of-band.
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"exec-oob":"query-balloon","id":"d"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"error": {"class": "GenericError", "desc": "The command \"query-balloon\" does not support OOB"}, "id": "d"})
+        );
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"query-status","exec-oob":"query-status","id":"e"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"error": {"class": "GenericError", "desc": "'execute' and 'exec-oob' can't be used together"}, "id": "e"})
+        );
+
+        // 3.The out-of-band query-status is answered while the slow query-balloon is in flight.
+        let stream_fd = client.1.get_stream_fd();
+        let state = client.1.get_state().clone();
+        client
+            .0
+            .get_mut()
+            .write_all(br#"{"execute":"query-balloon","id":"slow"}"#)
+            .unwrap();
+        let gate = Arc::new(Barrier::new(2));
+        let slow_gate = gate.clone();
+        let slow = std::thread::spawn(move || {
+            let test_ctrl = Arc::new(Mutex::new(TestController {
+                balloon_gate: Some(slow_gate),
+                ..Default::default()
+            }));
+            let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+            let mut leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT).unwrap();
+            handle_qmp(stream_fd, &state, &controller, &mut leak_bucket).unwrap();
+        });
+        gate.wait();
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"exec-oob":"query-status","id":"fast"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": {"running": true, "singlestep": false, "status": "running"}, "id": "fast"})
+        );
+        gate.wait();
+        slow.join().unwrap();
+        let mut line = String::new();
+        client.0.read_line(&mut line).unwrap();
+        let resp: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": {"actual": 0}, "id": "slow"})
+        );
+
+        // 4.The VM kept alive after guest shutdown is reported as shutdown.
+        *vm_state.0.lock().unwrap() = KvmVmState::Shutdown;
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"exec-oob":"query-status","id":"f"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": {"running": false, "singlestep": false, "status": "shutdown"}, "id": "f"})
+        );
+
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    /// Send the request with `fd` attached by SCM_RIGHTS, and execute it.
+    fn exec_client_request_with_fd(
+        client: &mut (
+            std::io::BufReader<UnixStream>,
+            Arc<crate::socket::SocketClient>,
+        ),
+        controller: &Arc<Mutex<dyn MachineExternalInterface>>,
+        leak_bucket: &mut LeakBucket,
+        request: &str,
+        fd: RawFd,
+    ) -> Value {
+        use std::io::BufRead;
+        use std::os::unix::io::AsRawFd;
+
+        let mut iov = libc::iovec {
+            iov_base: request.as_ptr() as *mut libc::c_void,
+            iov_len: request.len(),
+        };
+        // SAFETY: the control buffer is big enough for one fd.
+        let cmsg_space = unsafe { libc::CMSG_SPACE(std::mem::size_of::<RawFd>() as u32) };
+        let mut cmsg_buf = vec![0_u8; cmsg_space as usize];
+        // SAFETY: all the pointers of the message header refer to valid buffers.
+        unsafe {
+            let mut mhdr: libc::msghdr = std::mem::zeroed();
+            mhdr.msg_iov = &mut iov;
+            mhdr.msg_iovlen = 1;
+            mhdr.msg_control = cmsg_buf.as_mut_ptr() as *mut libc::c_void;
+            mhdr.msg_controllen = cmsg_space as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&mhdr);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(std::mem::size_of::<RawFd>() as u32) as _;
+            std::ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+            assert!(libc::sendmsg(client.0.get_ref().as_raw_fd(), &mhdr, 0) >= 0);
+        }
+
+        let server = &client.1;
+        handle_qmp(
+            server.get_stream_fd(),
+            server.get_state(),
+            controller,
+            leak_bucket,
+        )
+        .unwrap();
+        loop {
+            let mut line = String::new();
+            client.0.read_line(&mut line).unwrap();
+            let msg: Value = serde_json::from_str(&line).unwrap();
+            if msg.get("event").is_none() {
+                return msg;
+            }
+        }
+    }
+
+    #[test]
+    fn test_qmp_fdset_drive() {
+        use crate::config::VmConfig;
+        use crate::event_loop::EventLoop;
+        use crate::socket::{Socket, LEAK_BUCKET_LIMIT};
+        use std::collections::HashMap;
+        use std::io::{BufReader, Read, Seek, SeekFrom, Write};
+        use std::os::unix::io::{AsRawFd, IntoRawFd};
+
+        QmpChannel::object_init();
+        EventLoop::object_init(&None).unwrap();
+        let socket_name = "test_10.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let socket = Socket::from_unix_listener(UnixListener::bind(socket_name).unwrap(), None);
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+        let mut leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT).unwrap();
+
+        let stream = UnixStream::connect(socket_name).unwrap();
+        let server = socket.accept().unwrap();
+        // Keep the connection bound, so that the fdset is not cleaned up.
+        QmpChannel::bind_writer(server.get_stream_fd(), server.get_state().clone());
+        let mut client = (BufReader::new(stream), server);
+
+        let image = "test_fdset_drive.img";
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(image)
+            .unwrap();
+        file.write_all(&[0x5a; 512]).unwrap();
+
+        // 1.The fd passed by SCM_RIGHTS is added to the fdset.
+        let resp = exec_client_request_with_fd(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"add-fd","arguments":{"fdset-id":10,"opaque":"rdwr"},"id":"a"}"#,
+            file.as_raw_fd(),
+        );
+        assert_eq!(resp["return"]["fdset-id"], 10);
+        let fd = resp["return"]["fd"].as_i64().unwrap();
+        assert_ne!(fd, file.as_raw_fd() as i64);
+        drop(file);
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"query-fdsets","id":"b"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"return": [{"fdset-id": 10, "fds": [{"fd": fd, "opaque": "rdwr"}]}], "id": "b"})
+        );
+
+        // 2.The drive opens the fdset by its path.
+        let mut drive_files = HashMap::new();
+        assert!(VmConfig::add_drive_file(&mut drive_files, "/dev/fdset/10", true, false).is_err());
+        VmConfig::add_drive_file(&mut drive_files, "/dev/fdset/10", false, false).unwrap();
+        let mut drive_file = VmConfig::fetch_drive_file(&drive_files, "/dev/fdset/10").unwrap();
+        let mut buf = [0_u8; 512];
+        drive_file.seek(SeekFrom::Start(0)).unwrap();
+        drive_file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x5a; 512]);
+
+        // 3.The fdset is removed, and the opened drive is not affected.
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"remove-fd","arguments":{"fdset-id":10},"id":"c"}"#,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}, "id": "c"}));
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"remove-fd","arguments":{"fdset-id":10},"id":"d"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"error": {"class": "GenericError", "desc": "Fdset 10 is not found"}, "id": "d"})
+        );
+        drive_file.seek(SeekFrom::Start(0)).unwrap();
+        drive_file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, [0x5a; 512]);
+        VmConfig::remove_drive_file(&mut drive_files, "/dev/fdset/10").unwrap();
+        assert!(VmConfig::add_drive_file(&mut drive_files, "/dev/fdset/10", false, false).is_err());
+
+        // 4.The fd received by getfd is closed by closefd.
+        let (fd_stream, mut fd_peer) = UnixStream::pair().unwrap();
+        QmpChannel::set_fd("fdset-test".to_string(), fd_stream.into_raw_fd());
+        assert!(fd_peer.write(&[0]).is_ok());
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"closefd","arguments":{"fdname":"fdset-test"},"id":"e"}"#,
+        );
+        assert_eq!(resp, serde_json::json!({"return": {}, "id": "e"}));
+        assert!(fd_peer.write(&[0]).is_err());
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"closefd","arguments":{"fdname":"fdset-test"},"id":"f"}"#,
+        );
+        assert_eq!(
+            resp,
+            serde_json::json!({"error": {"class": "GenericError", "desc": "File descriptor named 'fdset-test' not found"}, "id": "f"})
+        );
+
+        QmpChannel::unbind(client.1.get_stream_fd());
+        std::fs::remove_file(image).unwrap();
+        std::fs::remove_file(socket_name).unwrap();
+    }
+
+    #[test]
+    fn test_qmp_guest_agent_command() {
+        use crate::event_loop::EventLoop;
+        use crate::qga::{guest_agent_output, register_guest_agent_channel, GuestAgentChannel};
+        use crate::socket::{Socket, LEAK_BUCKET_LIMIT};
+        use std::io::{BufRead, BufReader, Write};
+
+        /// The port of the guest agent, which records the data sent to it.
+        struct TestChannel(Arc<Mutex<Vec<u8>>>);
+
+        impl GuestAgentChannel for TestChannel {
+            fn send(&mut self, buf: &[u8]) -> Result<()> {
+                self.0.lock().unwrap().extend_from_slice(buf);
+                Ok(())
+            }
+        }
+
+        QmpChannel::object_init();
+        EventLoop::object_init(&None).unwrap();
+        let socket_name = "test_11.sock";
+        let _ = std::fs::remove_file(socket_name);
+        let socket = Socket::from_unix_listener(UnixListener::bind(socket_name).unwrap(), None);
+        let test_ctrl = Arc::new(Mutex::new(TestController::default()));
+        let controller: Arc<Mutex<dyn MachineExternalInterface>> = test_ctrl;
+        let mut leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT).unwrap();
+
+        let stream = UnixStream::connect(socket_name).unwrap();
+        let server = socket.accept().unwrap();
+        QmpChannel::bind_writer(server.get_stream_fd(), server.get_state().clone());
+        let mut client = (BufReader::new(stream), server);
+
+        // The command is passed to the port of the guest agent, and the response is sent
+        // once the guest agent replies.
+        let sent = Arc::new(Mutex::new(Vec::new()));
+        register_guest_agent_channel(Arc::new(Mutex::new(TestChannel(sent.clone()))));
+        client
+            .0
+            .get_mut()
+            .write_all(
+                br#"{"execute":"guest-agent-command","arguments":{"command":{"execute":"guest-ping"}},"id":"a"}"#,
+            )
+            .unwrap();
+        handle_qmp(
+            client.1.get_stream_fd(),
+            client.1.get_state(),
+            &controller,
+            &mut leak_bucket,
+        )
+        .unwrap();
+        let cmd: Value = serde_json::from_slice(&sent.lock().unwrap()).unwrap();
+        assert_eq!(cmd["execute"], "guest-ping");
+        let reply = serde_json::json!({"return": {}, "id": cmd["id"]});
+        assert!(guest_agent_output(format!("{}\n", reply).as_bytes()));
+        let resp = loop {
+            let mut line = String::new();
+            client.0.read_line(&mut line).unwrap();
+            let msg: Value = serde_json::from_str(&line).unwrap();
+            // Skip the events sent by other tests.
+            if msg.get("event").is_none() {
+                break msg;
+            }
+        };
+        assert_eq!(resp, serde_json::json!({"return": {}, "id": "a"}));
+
+        // The invalid arguments are rejected at once.
+        let resp = exec_client_request(
+            &mut client,
+            &controller,
+            &mut leak_bucket,
+            r#"{"execute":"guest-agent-command","arguments":{"timeout":10},"id":"b"}"#,
+        );
+        assert_eq!(resp["id"], "b");
+        assert_eq!(resp["error"]["class"], "GenericError");
+
+        QmpChannel::unbind(client.1.get_stream_fd());
+        std::fs::remove_file(socket_name).unwrap();
+    }
 }
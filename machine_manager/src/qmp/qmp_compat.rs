@@ -0,0 +1,221 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Deprecated aliases of QMP commands and arguments.
+//!
+//! Renamed commands and arguments keep working under their old names. Before a
+//! request is parsed into `QmpCommand`, the old names are rewritten to the current
+//! ones, and each rewrite is reported to the client in the `deprecated` member of
+//! the response. With `-compat qmp=strict`, using an old name is an error instead.
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::qmp_schema::QmpErrorClass;
+use crate::config::QmpCompatPolicy;
+
+/// Version of the QMP schema reported in the greeting. Bump it when commands or
+/// arguments are renamed, added to the alias tables or removed.
+pub const QMP_SCHEMA_VERSION: u32 = 1;
+
+/// Deprecated command names: (old name, current name).
+const DEPRECATED_COMMANDS: &[(&str, &str)] = &[("qom-get", "qom_get")];
+
+/// Deprecated argument names: (current command name, old argument, current argument).
+const DEPRECATED_ARGUMENTS: &[(&str, &str, &str)] = &[("device_add", "bootindex", "boot_index")];
+
+/// Warning about the usage of a deprecated command or argument.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct DeprecatedWarning {
+    /// The command name used by the client.
+    pub command: String,
+    /// The argument name used by the client, if the argument is deprecated.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub argument: Option<String>,
+    /// The name which should be used instead.
+    pub replacement: String,
+}
+
+impl DeprecatedWarning {
+    fn to_error(&self) -> QmpErrorClass {
+        let msg = match &self.argument {
+            Some(arg) => format!(
+                "Argument '{}' of command '{}' is deprecated, use '{}' instead",
+                arg, self.command, self.replacement
+            ),
+            None => format!(
+                "Command '{}' is deprecated, use '{}' instead",
+                self.command, self.replacement
+            ),
+        };
+        QmpErrorClass::GenericError(msg)
+    }
+}
+
+/// Rewrite the deprecated command and argument names of a raw QMP request.
+///
+/// # Arguments
+///
+/// * `request` - The QMP request in json, not parsed to `QmpCommand` yet.
+/// * `policy` - Whether to accept deprecated names or reject them.
+///
+/// # Errors
+///
+/// With `QmpCompatPolicy::Strict`, the usage of any deprecated name is an error.
+/// Passing both the deprecated and the current name of an argument is an error too.
+pub fn resolve_deprecated(
+    request: &mut Value,
+    policy: QmpCompatPolicy,
+) -> std::result::Result<Vec<DeprecatedWarning>, QmpErrorClass> {
+    let mut warnings = Vec::new();
+    let request = match request.as_object_mut() {
+        Some(req) => req,
+        // Leave the malformed request to the parser.
+        None => return Ok(warnings),
+    };
+    let mut command = match request.get("execute").and_then(Value::as_str) {
+        Some(cmd) => cmd.to_string(),
+        None => return Ok(warnings),
+    };
+
+    if let Some((old, new)) = DEPRECATED_COMMANDS.iter().find(|(old, _)| *old == command) {
+        let warning = DeprecatedWarning {
+            command: old.to_string(),
+            argument: None,
+            replacement: new.to_string(),
+        };
+        if policy == QmpCompatPolicy::Strict {
+            return Err(warning.to_error());
+        }
+        request.insert("execute".to_string(), Value::String(new.to_string()));
+        command = new.to_string();
+        warnings.push(warning);
+    }
+
+    let args = match request.get_mut("arguments").and_then(Value::as_object_mut) {
+        Some(args) => args,
+        None => return Ok(warnings),
+    };
+    for (_, old, new) in DEPRECATED_ARGUMENTS
+        .iter()
+        .filter(|(cmd, _, _)| *cmd == command)
+    {
+        let value = match args.remove(*old) {
+            Some(v) => v,
+            None => continue,
+        };
+        let warning = DeprecatedWarning {
+            command: command.clone(),
+            argument: Some(old.to_string()),
+            replacement: new.to_string(),
+        };
+        if policy == QmpCompatPolicy::Strict {
+            return Err(warning.to_error());
+        }
+        if args.contains_key(*new) {
+            return Err(QmpErrorClass::GenericError(format!(
+                "Argument '{}' and its deprecated alias '{}' are both specified",
+                new, old
+            )));
+        }
+        args.insert(new.to_string(), value);
+        warnings.push(warning);
+    }
+
+    Ok(warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmp::qmp_schema::QmpCommand;
+
+    #[test]
+    fn test_alias_tables() {
+        // Aliases must resolve to real commands, and never to another alias.
+        for (old, new) in DEPRECATED_COMMANDS {
+            let req = serde_json::json!({ "execute": new });
+            assert!(serde_json::from_value::<QmpCommand>(req).is_ok());
+            let req = serde_json::json!({ "execute": old });
+            assert!(serde_json::from_value::<QmpCommand>(req).is_err());
+            assert!(!DEPRECATED_COMMANDS.iter().any(|(o, _)| o == new));
+        }
+        for (cmd, old, new) in DEPRECATED_ARGUMENTS {
+            assert!(!DEPRECATED_COMMANDS.iter().any(|(o, _)| o == cmd));
+            assert_ne!(old, new);
+        }
+    }
+
+    #[test]
+    fn test_resolve_deprecated() {
+        // Current names are left untouched.
+        let mut req = serde_json::json!({ "execute": "qom_get", "id": "1" });
+        let origin = req.clone();
+        let warnings = resolve_deprecated(&mut req, QmpCompatPolicy::Strict).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(req, origin);
+        let mut req = serde_json::json!({
+            "execute": "device_add",
+            "arguments": { "id": "blk0", "driver": "virtio-blk-pci", "boot_index": 1 }
+        });
+        let origin = req.clone();
+        let warnings = resolve_deprecated(&mut req, QmpCompatPolicy::Strict).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(req, origin);
+
+        // Deprecated command and argument names are rewritten.
+        let mut req = serde_json::json!({ "execute": "qom-get" });
+        let warnings = resolve_deprecated(&mut req, QmpCompatPolicy::Warn).unwrap();
+        assert_eq!(req, serde_json::json!({ "execute": "qom_get" }));
+        assert_eq!(
+            serde_json::to_value(&warnings).unwrap(),
+            serde_json::json!([{ "command": "qom-get", "replacement": "qom_get" }])
+        );
+
+        let mut req = serde_json::json!({
+            "execute": "device_add",
+            "arguments": { "id": "blk0", "driver": "virtio-blk-pci", "bootindex": 1 }
+        });
+        let warnings = resolve_deprecated(&mut req, QmpCompatPolicy::Warn).unwrap();
+        assert_eq!(req["arguments"]["boot_index"], 1);
+        assert!(req["arguments"].get("bootindex").is_none());
+        assert_eq!(
+            serde_json::to_value(&warnings).unwrap(),
+            serde_json::json!([{
+                "command": "device_add",
+                "argument": "bootindex",
+                "replacement": "boot_index"
+            }])
+        );
+
+        // Deprecated names are errors in strict mode.
+        let mut req = serde_json::json!({ "execute": "qom-get" });
+        let err = resolve_deprecated(&mut req, QmpCompatPolicy::Strict).unwrap_err();
+        assert_eq!(
+            err.to_content(),
+            "Command 'qom-get' is deprecated, use 'qom_get' instead"
+        );
+
+        // Deprecated and current names of the same argument conflict.
+        let mut req = serde_json::json!({
+            "execute": "device_add",
+            "arguments": { "id": "blk0", "driver": "virtio-blk-pci", "bootindex": 1, "boot_index": 2 }
+        });
+        assert!(resolve_deprecated(&mut req, QmpCompatPolicy::Warn).is_err());
+
+        // Malformed requests are left to the parser.
+        let mut req = serde_json::json!(["qom-get"]);
+        assert!(resolve_deprecated(&mut req, QmpCompatPolicy::Strict)
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -10,9 +10,12 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeMap;
+
 use serde::{Deserialize, Serialize};
 pub use serde_json::Value as Any;
-use strum_macros::{EnumIter, EnumString, EnumVariantNames};
+use strum::VariantNames;
+use strum_macros::{EnumIter, EnumString, EnumVariantNames, IntoStaticStr};
 
 use super::Version;
 use crate::qmp::{Command, Empty, TimeStamp};
@@ -92,6 +95,28 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    system_wakeup {
+        #[serde(default)]
+        arguments: system_wakeup,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "inject-nmi")]
+    #[strum(serialize = "inject-nmi")]
+    inject_nmi {
+        #[serde(default)]
+        arguments: inject_nmi,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "rtc-reset-reinjection")]
+    #[strum(serialize = "rtc-reset-reinjection")]
+    rtc_reset_reinjection {
+        #[serde(default)]
+        arguments: rtc_reset_reinjection,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     device_add {
         arguments: Box<device_add>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -114,6 +139,12 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "chardev-change")]
+    chardev_change {
+        arguments: chardev_change,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     netdev_add {
         arguments: Box<netdev_add>,
         #[serde(default, skip_serializing_if = "Option::is_none")]
@@ -140,6 +171,21 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-cpu-model-expansion")]
+    #[strum(serialize = "query-cpu-model-expansion")]
+    query_cpu_model_expansion {
+        arguments: query_cpu_model_expansion,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-cpu-definitions")]
+    #[strum(serialize = "query-cpu-definitions")]
+    query_cpu_definitions {
+        #[serde(default)]
+        arguments: query_cpu_definitions,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-status")]
     query_status {
         #[serde(default)]
@@ -152,6 +198,41 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    closefd {
+        arguments: closefd,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "add-fd")]
+    #[strum(serialize = "add-fd")]
+    add_fd {
+        #[serde(default)]
+        arguments: add_fd,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "remove-fd")]
+    #[strum(serialize = "remove-fd")]
+    remove_fd {
+        arguments: remove_fd,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-fdsets")]
+    #[strum(serialize = "query-fdsets")]
+    query_fdsets {
+        #[serde(default)]
+        arguments: query_fdsets,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "human-monitor-command")]
+    #[strum(serialize = "human-monitor-command")]
+    human_monitor_command {
+        arguments: human_monitor_command,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "blockdev-add")]
     blockdev_add {
         arguments: Box<blockdev_add>,
@@ -178,6 +259,30 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-mem-aging")]
+    #[strum(serialize = "query-mem-aging")]
+    query_mem_aging {
+        #[serde(default)]
+        arguments: query_mem_aging,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-memory-lock")]
+    #[strum(serialize = "query-memory-lock")]
+    query_memory_lock {
+        #[serde(default)]
+        arguments: query_memory_lock,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-placement")]
+    #[strum(serialize = "query-placement")]
+    query_placement {
+        #[serde(default)]
+        arguments: query_placement,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-vnc")]
     #[strum(serialize = "query-vnc")]
     query_vnc {
@@ -186,6 +291,11 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    screendump {
+        arguments: screendump,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "migrate")]
     migrate {
         arguments: migrate,
@@ -206,6 +316,36 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "migrate-set-capabilities")]
+    #[strum(serialize = "migrate-set-capabilities")]
+    migrate_set_capabilities {
+        arguments: migrate_set_capabilities,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "migrate-set-parameters")]
+    #[strum(serialize = "migrate-set-parameters")]
+    migrate_set_parameters {
+        #[serde(default)]
+        arguments: migrate_set_parameters,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-migrate-parameters")]
+    #[strum(serialize = "query-migrate-parameters")]
+    query_migrate_parameters {
+        #[serde(default)]
+        arguments: query_migrate_parameters,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "dump-guest-memory")]
+    #[strum(serialize = "dump-guest-memory")]
+    dump_guest_memory {
+        arguments: dump_guest_memory,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-version")]
     query_version {
         #[serde(default)]
@@ -234,6 +374,30 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-name")]
+    #[strum(serialize = "query-name")]
+    query_name {
+        #[serde(default)]
+        arguments: query_name,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-uuid")]
+    #[strum(serialize = "query-uuid")]
+    query_uuid {
+        #[serde(default)]
+        arguments: query_uuid,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "query-vsock")]
+    #[strum(serialize = "query-vsock")]
+    query_vsock {
+        #[serde(default)]
+        arguments: query_vsock,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-machines")]
     query_machines {
         #[serde(default)]
@@ -249,6 +413,13 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "set-event-mask")]
+    #[strum(serialize = "set-event-mask")]
+    set_event_mask {
+        arguments: set_event_mask,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "qom-list-types")]
     list_type {
         #[serde(default)]
@@ -337,6 +508,13 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "guest-agent-command")]
+    #[strum(serialize = "guest-agent-command")]
+    guest_agent_command {
+        arguments: guest_agent_command,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-block")]
     #[strum(serialize = "query-block")]
     query_block {
@@ -345,6 +523,54 @@ pub enum QmpCommand {
         #[serde(default, skip_serializing_if = "Option::is_none")]
         id: Option<String>,
     },
+    #[serde(rename = "query-netdev")]
+    #[strum(serialize = "query-netdev")]
+    query_netdev {
+        #[serde(default)]
+        arguments: query_netdev,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-set-write-threshold")]
+    #[strum(serialize = "block-set-write-threshold")]
+    block_set_write_threshold {
+        arguments: block_set_write_threshold,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-set-writable")]
+    #[strum(serialize = "block-set-writable")]
+    block_set_writable {
+        arguments: block_set_writable,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block_resize")]
+    #[strum(serialize = "block_resize")]
+    block_resize {
+        arguments: block_resize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    eject {
+        arguments: eject,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "blockdev-backup")]
+    #[strum(serialize = "blockdev-backup")]
+    blockdev_backup {
+        arguments: blockdev_backup,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
+    #[serde(rename = "block-job-cancel")]
+    #[strum(serialize = "block-job-cancel")]
+    block_job_cancel {
+        arguments: block_job_cancel,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        id: Option<String>,
+    },
     #[serde(rename = "query-named-block-nodes")]
     #[strum(serialize = "query-named-block-nodes")]
     query_named_block_nodes {
@@ -405,6 +631,10 @@ pub enum QmpCommand {
 ///
 /// Enable QMP capabilities.
 ///
+/// # Arguments
+///
+/// * `enable` - Capabilities to enable, which must be advertised in the greeting.
+///
 /// # Examples
 ///
 /// ```text
@@ -413,7 +643,10 @@ pub enum QmpCommand {
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct qmp_capabilities {}
+pub struct qmp_capabilities {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub enable: Option<Vec<String>>,
+}
 
 impl Command for qmp_capabilities {
     type Res = Empty;
@@ -536,6 +769,73 @@ impl Command for system_reset {
     }
 }
 
+/// system_wakeup
+///
+/// Wake up the suspended guest. It fails if the guest is not suspended.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "system_wakeup" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct system_wakeup {}
+
+impl Command for system_wakeup {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// inject-nmi
+///
+/// Inject NMI to all the guest VCPUs.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "inject-nmi" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct inject_nmi {}
+
+impl Command for inject_nmi {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// rtc-reset-reinjection
+///
+/// Reset the count of the RTC interrupts which are lost and to be reinjected. The RTC
+/// interrupts are never reinjected, so it does nothing.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "rtc-reset-reinjection" }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct rtc_reset_reinjection {}
+
+impl Command for rtc_reset_reinjection {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// device_add
 ///
 /// # Arguments
@@ -562,6 +862,8 @@ pub struct device_add {
     pub driver: String,
     #[serde(rename = "addr")]
     pub addr: Option<String>,
+    #[serde(rename = "scsi-id")]
+    pub scsi_id: Option<u8>,
     #[serde(rename = "lun")]
     pub lun: Option<usize>,
     #[serde(rename = "drive")]
@@ -587,6 +889,8 @@ pub struct device_add {
     #[serde(rename = "serial")]
     pub serial_num: Option<String>,
     pub iothread: Option<String>,
+    #[serde(rename = "queue-iothreads")]
+    pub queue_iothreads: Option<String>,
     pub multifunction: Option<bool>,
     pub host: Option<String>,
     #[serde(rename = "num-queues")]
@@ -595,6 +899,14 @@ pub struct device_add {
     pub sysfsdev: Option<String>,
     #[serde(rename = "queue-size")]
     pub queue_size: Option<u16>,
+    #[serde(rename = "socket-id")]
+    pub socket_id: Option<u16>,
+    #[serde(rename = "die-id", alias = "die_id")]
+    pub die_id: Option<u16>,
+    #[serde(rename = "core-id")]
+    pub core_id: Option<u16>,
+    #[serde(rename = "thread-id")]
+    pub thread_id: Option<u16>,
 }
 
 pub type DeviceAddArgument = device_add;
@@ -688,6 +1000,8 @@ pub struct CacheOptions {
 /// * `file` - the backend file information.
 /// * `cache` - if use direct io.
 /// * `read_only` - if readonly.
+/// * `werror` - action on write errors: report, stop, ignore or enospc.
+/// * `rerror` - action on read errors: report, stop, ignore or enospc.
 ///
 /// Additional arguments depend on the type.
 ///
@@ -718,6 +1032,8 @@ pub struct blockdev_add {
     pub options: Option<String>,
     #[serde(rename = "throttling.iops-total")]
     pub iops: Option<u64>,
+    pub werror: Option<String>,
+    pub rerror: Option<String>,
 }
 
 pub type BlockDevAddArgument = blockdev_add;
@@ -795,7 +1111,9 @@ pub struct AddrOptions {
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct BackendDataOptions {
+    #[serde(default)]
     pub addr: AddrOptions,
+    #[serde(default)]
     pub server: bool,
 }
 
@@ -804,7 +1122,7 @@ pub struct BackendDataOptions {
 pub struct BackendOptions {
     #[serde(rename = "type")]
     pub backend_type: String,
-    #[serde(rename = "data")]
+    #[serde(rename = "data", default)]
     pub backend_data: BackendDataOptions,
 }
 
@@ -875,6 +1193,40 @@ impl Command for chardev_remove {
     }
 }
 
+/// chardev-change
+///
+/// Change the backend of a chardev which is used by a device, e.g. serial or
+/// virtconsole. Changing to `stdio` is not supported.
+///
+/// # Arguments
+///
+/// * `id` - The ID of the character device.
+/// * `backend` - The new chardev backend info, `null` or `socket`.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "chardev-change",
+///      "arguments": { "id": "chardev_id", "backend": { "type": "socket", "data": {
+///            "addr": { "type": "unix", "data": { "path": "/path/to/socket" } },
+///            "server": true }}}}
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct chardev_change {
+    pub id: String,
+    pub backend: BackendOptions,
+}
+
+impl Command for chardev_change {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// device_del
 ///
 /// Remove a device from a guest
@@ -1057,7 +1409,9 @@ pub struct CpuInstanceProperties {
 ///             "halted":false,
 ///             "qom_path":"/machine/unattached/device[0]",
 ///             "arch":"x86",
-///             "thread_id":3134
+///             "thread_id":3134,
+///             "host-cpus":[2],
+///             "tsc-khz":2903998
 ///          },
 ///          {
 ///             "CPU":1,
@@ -1065,7 +1419,8 @@ pub struct CpuInstanceProperties {
 ///             "halted":true,
 ///             "qom_path":"/machine/unattached/device[2]",
 ///             "arch":"x86",
-///             "thread_id":3135
+///             "thread_id":3135,
+///             "tsc-khz":2903998
 ///          }
 ///       ]
 ///    }
@@ -1096,6 +1451,9 @@ pub struct CpuInfoCommon {
     pub CPU: isize,
     #[serde(rename = "thread_id")]
     pub thread_id: isize,
+    /// The host cpus the vCPU thread is pinned to, absent if it's not pinned.
+    #[serde(rename = "host-cpus", default, skip_serializing_if = "Option::is_none")]
+    pub host_cpus: Option<Vec<usize>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -1120,11 +1478,103 @@ pub enum CpuInfo {
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct CpuInfoX86 {}
+pub struct CpuInfoX86 {
+    /// TSC frequency of the vCPU in kHz.
+    #[serde(rename = "tsc-khz", default, skip_serializing_if = "Option::is_none")]
+    pub tsc_khz: Option<u32>,
+}
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct CpuInfoArm {}
 
+/// query-cpu-model-expansion
+///
+/// Expand a CPU model to its feature flags, and whether they are enabled for the vCPUs
+/// of the VM. Only the `host` model is supported, with the flags set or cleared by `-cpu`.
+///
+/// # Arguments
+///
+/// * `type` - Expansion type, `static` or `full`. They are the same as there is only one model.
+/// * `model` - The CPU model to expand, its `props` are ignored.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-cpu-model-expansion",
+///      "arguments": { "type": "full", "model": { "name": "host" } } }
+/// <- { "return": { "model": { "name": "host",
+///                             "props": { "vmx": true, "x2apic": false } } } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_cpu_model_expansion {
+    #[serde(rename = "type")]
+    pub expansion_type: String,
+    pub model: CpuModelInfo,
+}
+
+impl Command for query_cpu_model_expansion {
+    type Res = CpuModelExpansionInfo;
+
+    fn back(self) -> CpuModelExpansionInfo {
+        Default::default()
+    }
+}
+
+/// query-cpu-definitions:
+///
+/// Query the cpu models supported by StratoVirt.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-cpu-definitions" }
+/// <- { "return": [ { "name": "host", "typename": "host-x86-cpu", "static": false,
+///                    "migration-safe": false, "unavailable-features": [],
+///                    "deprecated": false } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_cpu_definitions {}
+
+impl Command for query_cpu_definitions {
+    type Res = Vec<CpuDefinitionInfo>;
+
+    fn back(self) -> Vec<CpuDefinitionInfo> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CpuDefinitionInfo {
+    pub name: String,
+    /// Type name of the cpu object of the model.
+    pub typename: String,
+    /// Whether the model never changes across StratoVirt versions and hosts.
+    #[serde(rename = "static")]
+    pub static_model: bool,
+    /// Whether the model is safe to be migrated to another host.
+    #[serde(rename = "migration-safe")]
+    pub migration_safe: bool,
+    /// Features which prevent the model from running on this host.
+    #[serde(rename = "unavailable-features")]
+    pub unavailable_features: Vec<String>,
+    pub deprecated: bool,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct CpuModelInfo {
+    pub name: String,
+    /// Whether each feature flag of the model is enabled.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub props: Option<BTreeMap<String, bool>>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct CpuModelExpansionInfo {
+    pub model: CpuModelInfo,
+}
+
 /// query-status
 ///
 /// Query the run status of all VCPUs.
@@ -1256,31 +1706,201 @@ impl Command for cancel_migrate {
     }
 }
 
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
-pub struct MigrationInfo {
-    #[serde(rename = "status", default, skip_serializing_if = "Option::is_none")]
-    pub status: Option<String>,
-}
-
-/// getfd
+/// migrate-set-capabilities
 ///
-/// Receive a file descriptor via SCM rights and assign it a name
+/// Enable or disable the capabilities of migration.
 ///
 /// # Arguments
 ///
-/// * `fdname` - File descriptor name.
+/// * `capabilities` - The capabilities to be set, `xbzrle`, `auto-converge` or `postcopy-ram`.
 ///
-/// # Examples
+/// # Example
 ///
 /// ```text
-/// -> { "execute": "getfd", "arguments": { "fdname": "fd1" } }
-/// <- { "return": {} }
+/// -> { "execute": "migrate-set-capabilities",
+///      "arguments": { "capabilities": [ { "capability": "xbzrle", "state": true } ] } }
+/// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct getfd {
-    #[serde(rename = "fdname")]
-    pub fd_name: String,
+pub struct migrate_set_capabilities {
+    pub capabilities: Vec<MigrateCapabilities>,
+}
+
+impl Command for migrate_set_capabilities {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// migrate-set-parameters
+///
+/// Set the parameters of migration, the parameters which are not given are unchanged.
+///
+/// # Arguments
+///
+/// * `max-bandwidth` - Maximum speed of migration in bytes per second.
+/// * `downtime-limit` - Maximum tolerated downtime of migration in milliseconds.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "migrate-set-parameters", "arguments": { "downtime-limit": 500 } }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct migrate_set_parameters {
+    #[serde(rename = "max-bandwidth", default)]
+    pub max_bandwidth: Option<u64>,
+    #[serde(rename = "downtime-limit", default)]
+    pub downtime_limit: Option<u64>,
+}
+
+pub type MigrateSetParametersArgument = migrate_set_parameters;
+
+impl Command for migrate_set_parameters {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-migrate-parameters
+///
+/// Query the parameters of migration.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-migrate-parameters" }
+/// <- {"return":{"max-bandwidth":134217728,"downtime-limit":300}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_migrate_parameters {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MigrationParameters {
+    #[serde(rename = "max-bandwidth")]
+    pub max_bandwidth: u64,
+    #[serde(rename = "downtime-limit")]
+    pub downtime_limit: u64,
+}
+
+impl Command for query_migrate_parameters {
+    type Res = MigrationParameters;
+
+    fn back(self) -> MigrationParameters {
+        Default::default()
+    }
+}
+
+/// dump-guest-memory
+///
+/// Dump the guest memory to an ELF64 core file, with the registers of each vCPU
+/// in NT_PRSTATUS notes. The VM is paused during the dump.
+///
+/// # Arguments
+///
+/// * `paging` - Translate the guest virtual addresses, only `false` is supported.
+/// * `protocol` - Destination of the dump, in the form of `file:<path>`.
+/// * `detach` - Dump in background, only `false` is supported.
+/// * `begin` - Start of the guest physical range to dump, not supported.
+/// * `length` - Size of the guest physical range to dump, not supported.
+/// * `format` - Format of the dump, only `elf` is supported.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "dump-guest-memory",
+///      "arguments": { "paging": false, "protocol": "file:/tmp/vm.core" } }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct dump_guest_memory {
+    #[serde(rename = "paging")]
+    pub paging: bool,
+    #[serde(rename = "protocol")]
+    pub protocol: String,
+    #[serde(rename = "detach")]
+    pub detach: Option<bool>,
+    #[serde(rename = "begin")]
+    pub begin: Option<u64>,
+    #[serde(rename = "length")]
+    pub length: Option<u64>,
+    #[serde(rename = "format")]
+    pub format: Option<String>,
+}
+
+pub type DumpGuestMemoryArgument = dump_guest_memory;
+
+impl Command for dump_guest_memory {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// screendump
+///
+/// Save the surface of the active display console to an image file.
+///
+/// # Arguments
+///
+/// * `filename` - Path of the image file, which is replaced atomically.
+/// * `format` - Format of the image, `ppm` or `png`, `ppm` by default.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "screendump",
+///      "arguments": { "filename": "/tmp/screen.png", "format": "png" } }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct screendump {
+    #[serde(rename = "filename")]
+    pub filename: String,
+    #[serde(rename = "format")]
+    pub format: Option<String>,
+}
+
+impl Command for screendump {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MigrationInfo {
+    #[serde(rename = "status", default, skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+}
+
+/// getfd
+///
+/// Receive a file descriptor via SCM rights and assign it a name
+///
+/// # Arguments
+///
+/// * `fdname` - File descriptor name.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "getfd", "arguments": { "fdname": "fd1" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct getfd {
+    #[serde(rename = "fdname")]
+    pub fd_name: String,
 }
 
 impl Command for getfd {
@@ -1291,6 +1911,174 @@ impl Command for getfd {
     }
 }
 
+/// closefd
+///
+/// Close a file descriptor received by `getfd` and remove its name.
+///
+/// # Arguments
+///
+/// * `fdname` - File descriptor name.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "closefd", "arguments": { "fdname": "fd1" } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct closefd {
+    #[serde(rename = "fdname")]
+    pub fd_name: String,
+}
+
+impl Command for closefd {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// add-fd
+///
+/// Receive a file descriptor via SCM rights and add it to a fdset.
+///
+/// # Arguments
+///
+/// * `fdset-id` - The fdset to add the fd to, a new fdset is created if it's absent.
+/// * `opaque` - A free-form string to identify the fd.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "add-fd", "arguments": { "fdset-id": 1, "opaque": "rdwr" } }
+/// <- { "return": { "fdset-id": 1, "fd": 33 } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct add_fd {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: Option<u64>,
+    pub opaque: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AddfdInfo {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: u64,
+    pub fd: i32,
+}
+
+impl Command for add_fd {
+    type Res = AddfdInfo;
+
+    fn back(self) -> AddfdInfo {
+        Default::default()
+    }
+}
+
+/// remove-fd
+///
+/// Remove a fd from a fdset, or the whole fdset if `fd` is absent. The removed fds
+/// are closed.
+///
+/// # Arguments
+///
+/// * `fdset-id` - The fdset the fd belongs to.
+/// * `fd` - The fd to remove.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "remove-fd", "arguments": { "fdset-id": 1, "fd": 33 } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct remove_fd {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: u64,
+    pub fd: Option<i32>,
+}
+
+impl Command for remove_fd {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// query-fdsets
+///
+/// Query the fdsets and the fds in them.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "query-fdsets" }
+/// <- { "return": [ { "fdset-id": 1, "fds": [ { "fd": 33, "opaque": "rdwr" } ] } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_fdsets {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FdsetFdInfo {
+    pub fd: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub opaque: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct FdsetInfo {
+    #[serde(rename = "fdset-id")]
+    pub fdset_id: u64,
+    pub fds: Vec<FdsetFdInfo>,
+}
+
+impl Command for query_fdsets {
+    type Res = Vec<FdsetInfo>;
+
+    fn back(self) -> Vec<FdsetInfo> {
+        Default::default()
+    }
+}
+
+/// human-monitor-command
+///
+/// Execute a command of the human monitor, which is interpreted by a small set of
+/// QMP commands. Unsupported commands are reported in the output, not as errors.
+///
+/// # Arguments
+///
+/// * `command-line` - The human monitor command.
+/// * `cpu-index` - The default cpu of the command, which is ignored.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "human-monitor-command",
+///      "arguments": { "command-line": "info status" } }
+/// <- { "return": "VM status: running\r\n" }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct human_monitor_command {
+    #[serde(rename = "command-line")]
+    pub command_line: String,
+    #[serde(rename = "cpu-index")]
+    pub cpu_index: Option<isize>,
+}
+
+impl Command for human_monitor_command {
+    type Res = String;
+
+    fn back(self) -> String {
+        Default::default()
+    }
+}
+
 /// Shutdown
 ///
 /// Emitted when the virtual machine has shut down, indicating that StratoVirt is
@@ -1298,7 +2086,7 @@ impl Command for getfd {
 ///
 /// # Notes
 ///
-/// If the command-line option "-no-shutdown" has been specified, StratoVirt
+/// If the command-line option "-no-shutdown" or "-action shutdown=pause" has been specified, StratoVirt
 /// will not exit, and a STOP event will eventually follow the SHUTDOWN event
 #[derive(Debug, Clone, Serialize, Deserialize, Default)]
 #[serde(deny_unknown_fields)]
@@ -1345,6 +2133,13 @@ pub struct Resume {}
 #[serde(deny_unknown_fields)]
 pub struct Powerdown {}
 
+/// Wakeup
+///
+/// Emitted when the guest has woken up from suspend
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Wakeup {}
+
 /// DeviceDeleted
 ///
 /// Emitted whenever the device removal completion is acknowledged by the guest.
@@ -1370,44 +2165,255 @@ pub struct DeviceDeleted {
     pub path: String,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, EnumIter, EnumVariantNames, EnumString)]
+/// BlockIoError
+///
+/// Emitted when the backend of a disk fails a guest request.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_IO_ERROR",
+///      "data": { "device": "drive-0", "operation": "write", "action": "stop",
+///                "nospace": true, "reason": "No space left on device" },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockIoError {
+    /// Device id.
+    pub device: String,
+    /// I/O operation, "read" or "write".
+    pub operation: String,
+    /// Action taken according to the error policy, "report", "ignore", "stop" or "read-only".
+    pub action: String,
+    /// True if the error is caused by a full host filesystem.
+    pub nospace: bool,
+    /// Human readable description of the error.
+    pub reason: String,
+}
+
+/// BlockIoSlow
+///
+/// Emitted once for a guest request which is in flight for longer than the
+/// `slow-io-warn-ms` of the disk, e.g. the backend storage hangs.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_IO_SLOW",
+///      "data": { "device": "drive-0", "operation": "write", "age": 5002 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockIoSlow {
+    /// Device id.
+    pub device: String,
+    /// I/O operation, "read", "write", "flush" or "discard".
+    pub operation: String,
+    /// Time in milliseconds since the request was submitted.
+    pub age: u64,
+}
+
+/// BlockWriteThreshold
+///
+/// Emitted when the guest writes beyond the write threshold of a disk.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_WRITE_THRESHOLD",
+///      "data": { "device": "drive-0", "amount-exceeded": 65536,
+///                "write-threshold": 17179869184 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockWriteThreshold {
+    /// Device id.
+    pub device: String,
+    /// Bytes written beyond the threshold by the request.
+    #[serde(rename = "amount-exceeded")]
+    pub amount_exceeded: u64,
+    /// The threshold which is exceeded.
+    #[serde(rename = "write-threshold")]
+    pub write_threshold: u64,
+}
+
+/// DeviceTrayMoved
+///
+/// Emitted when the tray of a removable device is opened or closed, by `eject` or by the
+/// guest.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "DEVICE_TRAY_MOVED",
+///      "data": { "device": "scsi-cd0", "tray-open": true },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceTrayMoved {
+    /// Device id.
+    pub device: String,
+    /// True if the tray is open, and the medium is not present.
+    #[serde(rename = "tray-open")]
+    pub tray_open: bool,
+}
+
+/// BlockJobCompleted
+///
+/// Emitted when a block job finishes, `error` is set if it fails.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_COMPLETED",
+///      "data": { "type": "backup", "device": "drive-0", "len": 10737418240,
+///                "offset": 10737418240, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCompleted {
+    #[serde(rename = "type")]
+    pub job_type: String,
+    pub device: String,
+    pub len: u64,
+    pub offset: u64,
+    pub speed: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// BlockJobCancelled
+///
+/// Emitted when a block job is stopped by `block-job-cancel`.
+///
+/// # Examples
+///
+/// ```text
+/// <- { "event": "BLOCK_JOB_CANCELLED",
+///      "data": { "type": "backup", "device": "drive-0", "len": 10737418240,
+///                "offset": 134217728, "speed": 0 },
+///      "timestamp": { "seconds": 1265044230, "microseconds": 450486 } }
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct BlockJobCancelled {
+    #[serde(rename = "type")]
+    pub job_type: String,
+    pub device: String,
+    pub len: u64,
+    pub offset: u64,
+    pub speed: u64,
+}
+
+/// All the events which can be emitted, the names of the variants are the registry
+/// of events reported by `query-events` and masked by `set-event-mask`.
+#[derive(
+    Debug, Clone, Serialize, Deserialize, EnumIter, EnumVariantNames, EnumString, IntoStaticStr,
+)]
 #[serde(tag = "event")]
 pub enum QmpEvent {
     #[serde(rename = "SHUTDOWN")]
+    #[strum(serialize = "SHUTDOWN")]
     Shutdown {
         data: Shutdown,
         timestamp: TimeStamp,
     },
     #[serde(rename = "RESET")]
+    #[strum(serialize = "RESET")]
     Reset { data: Reset, timestamp: TimeStamp },
     #[serde(rename = "STOP")]
+    #[strum(serialize = "STOP")]
     Stop {
         #[serde(default)]
         data: Stop,
         timestamp: TimeStamp,
     },
     #[serde(rename = "RESUME")]
+    #[strum(serialize = "RESUME")]
     Resume {
         #[serde(default)]
         data: Resume,
         timestamp: TimeStamp,
     },
     #[serde(rename = "POWERDOWN")]
+    #[strum(serialize = "POWERDOWN")]
     Powerdown {
         #[serde(default)]
         data: Powerdown,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "WAKEUP")]
+    #[strum(serialize = "WAKEUP")]
+    Wakeup {
+        #[serde(default)]
+        data: Wakeup,
+        timestamp: TimeStamp,
+    },
     #[serde(rename = "DEVICE_DELETED")]
+    #[strum(serialize = "DEVICE_DELETED")]
     DeviceDeleted {
         data: DeviceDeleted,
         timestamp: TimeStamp,
     },
-    #[serde(rename = "BALLOON_CHANGED")]
-    BalloonChanged {
+    #[serde(rename = "BALLOON_CHANGE")]
+    #[strum(serialize = "BALLOON_CHANGE")]
+    BalloonChange {
         data: BalloonInfo,
         timestamp: TimeStamp,
     },
+    #[serde(rename = "BLOCK_IO_ERROR")]
+    #[strum(serialize = "BLOCK_IO_ERROR")]
+    BlockIoError {
+        data: BlockIoError,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_IO_SLOW")]
+    #[strum(serialize = "BLOCK_IO_SLOW")]
+    BlockIoSlow {
+        data: BlockIoSlow,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_WRITE_THRESHOLD")]
+    #[strum(serialize = "BLOCK_WRITE_THRESHOLD")]
+    BlockWriteThreshold {
+        data: BlockWriteThreshold,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "DEVICE_TRAY_MOVED")]
+    #[strum(serialize = "DEVICE_TRAY_MOVED")]
+    DeviceTrayMoved {
+        data: DeviceTrayMoved,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_COMPLETED")]
+    #[strum(serialize = "BLOCK_JOB_COMPLETED")]
+    BlockJobCompleted {
+        data: BlockJobCompleted,
+        timestamp: TimeStamp,
+    },
+    #[serde(rename = "BLOCK_JOB_CANCELLED")]
+    #[strum(serialize = "BLOCK_JOB_CANCELLED")]
+    BlockJobCancelled {
+        data: BlockJobCancelled,
+        timestamp: TimeStamp,
+    },
+}
+
+impl QmpEvent {
+    /// Get the name of the event, e.g. `STOP`.
+    pub fn name(&self) -> &'static str {
+        self.into()
+    }
+
+    /// Get the index of the event named `name` in the registry of events.
+    pub fn index_of(name: &str) -> Option<usize> {
+        Self::VARIANTS.iter().position(|event| *event == name)
+    }
 }
 
 /// query-balloon:
@@ -1416,13 +2422,16 @@ pub enum QmpEvent {
 ///
 /// # Returns
 ///
-/// `BalloonInfo` includs the actual size of memory
+/// `BalloonInfo` includs the actual size of memory, and the latest memory
+/// statistics of guest if `monitor-interval` of balloon is set.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-balloon" }
-/// <- {"return":{"actual":8589934592}}
+/// <- {"return":{"actual":8589934592,"stats":{"swap-in":0,"swap-out":0,
+///     "free-mem":7516192768,"total-mem":8214794240,"available-mem":7902633984,
+///     "reclaimable-mem":7902633984,"last-update":1700000000}}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_balloon {}
@@ -1434,8 +2443,193 @@ impl Command for query_balloon {
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct BalloonInfo {
-    pub actual: u64,
+pub struct BalloonInfo {
+    pub actual: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub stats: Option<BalloonStats>,
+}
+
+/// Memory statistics reported by guest, in bytes. The statistics which are
+/// not reported by guest are omitted.
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BalloonStats {
+    #[serde(rename = "swap-in", default, skip_serializing_if = "Option::is_none")]
+    pub swap_in: Option<u64>,
+    #[serde(rename = "swap-out", default, skip_serializing_if = "Option::is_none")]
+    pub swap_out: Option<u64>,
+    #[serde(
+        rename = "major-faults",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub major_faults: Option<u64>,
+    #[serde(
+        rename = "minor-faults",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub minor_faults: Option<u64>,
+    #[serde(rename = "free-mem", default, skip_serializing_if = "Option::is_none")]
+    pub free_mem: Option<u64>,
+    #[serde(rename = "total-mem", default, skip_serializing_if = "Option::is_none")]
+    pub total_mem: Option<u64>,
+    #[serde(
+        rename = "available-mem",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub available_mem: Option<u64>,
+    #[serde(
+        rename = "disk-caches",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub disk_caches: Option<u64>,
+    /// Estimate of the memory the guest could give back to the host.
+    #[serde(
+        rename = "reclaimable-mem",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub reclaimable_mem: Option<u64>,
+    /// Time of the last update in seconds since the Unix epoch.
+    #[serde(rename = "last-update")]
+    pub last_update: u64,
+}
+
+/// query-mem-aging:
+///
+/// Query the counters of guest memory aging.
+///
+/// # Returns
+///
+/// `MemAgingInfo` includes the counters of scan passes and aged pages.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-mem-aging" }
+/// <- {"return":{"scans":12,"aborted-scans":1,"aged-pages":52480,"pinned-pages":0}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_mem_aging {}
+impl Command for query_mem_aging {
+    type Res = MemAgingInfo;
+    fn back(self) -> MemAgingInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MemAgingInfo {
+    /// Number of finished scan passes.
+    pub scans: u64,
+    /// Number of scan passes aborted because of heavy IO.
+    #[serde(rename = "aborted-scans")]
+    pub aborted_scans: u64,
+    /// Number of pages advised to the host.
+    #[serde(rename = "aged-pages")]
+    pub aged_pages: u64,
+    /// Number of idle pages skipped because they are pinned for DMA.
+    #[serde(rename = "pinned-pages")]
+    pub pinned_pages: u64,
+}
+
+/// query-memory-lock:
+///
+/// Query whether guest memory is locked in host RAM, which is set by `-overcommit mem-lock`.
+///
+/// # Returns
+///
+/// `MemoryLockInfo` includes the mode of `mem-lock` and the size of locked guest memory.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-memory-lock" }
+/// <- {"return":{"mem-lock":"on","locked-size":2147483648}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_memory_lock {}
+impl Command for query_memory_lock {
+    type Res = MemoryLockInfo;
+    fn back(self) -> MemoryLockInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct MemoryLockInfo {
+    /// Mode of `mem-lock`, `on`, `off` or `on-fault`.
+    #[serde(rename = "mem-lock")]
+    pub mem_lock: String,
+    /// Size of guest memory locked in host RAM in bytes.
+    #[serde(rename = "locked-size")]
+    pub locked_size: u64,
+}
+
+/// query-placement:
+///
+/// Query the placement of the VM on host NUMA nodes, which is computed by
+/// `-auto-placement numa`.
+///
+/// # Returns
+///
+/// `PlacementInfo` includes the host node of each guest NUMA node and iothread.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-placement" }
+/// <- {"return":{"policy":"numa","nodes":[{"node":0,"host-node":1,"vcpus":[0,1],
+///     "host-cpus":[8,9,10,11],"memory-host-nodes":[1]}],
+///     "iothreads":[{"id":"iothread1","host-node":1,"host-cpus":[8,9,10,11]}]}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_placement {}
+impl Command for query_placement {
+    type Res = PlacementInfo;
+    fn back(self) -> PlacementInfo {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct PlacementInfo {
+    /// The placement policy.
+    pub policy: String,
+    /// Placement of the guest NUMA nodes.
+    pub nodes: Vec<NodePlacement>,
+    /// Placement of the iothreads.
+    pub iothreads: Vec<IothreadPlacement>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NodePlacement {
+    /// Id of the guest NUMA node, 0 if the guest has no NUMA config.
+    pub node: u32,
+    /// The host node the vCPUs of the guest node are pinned to.
+    #[serde(rename = "host-node")]
+    pub host_node: u32,
+    pub vcpus: Vec<u16>,
+    /// The host cpus the vCPUs are pinned to.
+    #[serde(rename = "host-cpus")]
+    pub host_cpus: Vec<usize>,
+    /// The host nodes the memory of the guest node is bound to, which are set by
+    /// `host-nodes` of the memory backend if given.
+    #[serde(rename = "memory-host-nodes")]
+    pub memory_host_nodes: Vec<u32>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct IothreadPlacement {
+    pub id: String,
+    /// The host node of the drives served by the iothread, not set if it's unknown.
+    #[serde(rename = "host-node", skip_serializing_if = "Option::is_none")]
+    pub host_node: Option<u32>,
+    /// The host cpus the iothread is pinned to, empty if it's not pinned.
+    #[serde(rename = "host-cpus")]
+    pub host_cpus: Vec<usize>,
 }
 
 /// query-vnc:
@@ -1619,14 +2813,14 @@ impl Command for query_target {
 ///
 /// ```text
 /// -> { "execute": "query-machines" }
-/// <- {"return":[{"cpu-max":255,"deprecated":false,"hotpluggable-cpus":true,"name":"none","numa-mem-supported":false},
-/// {"cpu-max":255,"deprecated":false,"hotpluggable-cpus":true,"name":"microvm","numa-mem-supported":false},
-/// {"cpu-max":255,"deprecated":false,"hotpluggable-cpus":true,"name":"standardvm","numa-mem-supported":false}]}
+/// <- {"return":[{"cpu-max":1,"deprecated":false,"hotpluggable-cpus":false,"is-default":false,"name":"none","numa-mem-supported":false},
+/// {"cpu-max":254,"deprecated":false,"hotpluggable-cpus":false,"is-default":true,"name":"microvm","numa-mem-supported":false},
+/// {"cpu-max":1024,"deprecated":false,"hotpluggable-cpus":true,"is-default":false,"name":"q35","numa-mem-supported":true}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_machines {}
 
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MachineInfo {
     #[serde(rename = "hotpluggable-cpus")]
     pub hotplug: bool,
@@ -1634,8 +2828,11 @@ pub struct MachineInfo {
     #[serde(rename = "numa-mem-supported")]
     pub numa_mem_support: bool,
     #[serde(rename = "cpu-max")]
-    pub cpu_max: u8,
+    pub cpu_max: u16,
     pub deprecated: bool,
+    /// Whether it's the machine type used without `-machine`.
+    #[serde(rename = "is-default")]
+    pub is_default: bool,
 }
 
 impl Command for query_machines {
@@ -1654,9 +2851,9 @@ impl Command for query_machines {
 ///
 /// ```text
 /// -> { "execute": "query-events" }
-/// <- {"return":[{"name":"Shutdown"},{"name":"Reset"},
-/// {"name":"Stop"},{"name":"Resume"},{"name":"DeviceDeleted"},
-/// {"name":"BalloonChanged"}]}
+/// <- {"return":[{"name":"SHUTDOWN"},{"name":"RESET"},
+/// {"name":"STOP"},{"name":"RESUME"},{"name":"POWERDOWN"},
+/// {"name":"WAKEUP"},{"name":"DEVICE_DELETED"},...]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Events {
@@ -1674,6 +2871,35 @@ impl Command for query_events {
     }
 }
 
+/// set-event-mask
+///
+/// Suppress the events on the current connection. The mask replaces the previous
+/// one, and an empty list receives all the events again. It isn't a qemu command.
+///
+/// # Arguments
+///
+/// * `events` - The names of events which are not sent to the connection.
+///
+/// # Examples
+///
+/// ```text
+/// -> { "execute": "set-event-mask", "arguments": { "events": [ "STOP", "RESUME" ] } }
+/// <- { "return": {} }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct set_event_mask {
+    pub events: Vec<String>,
+}
+
+impl Command for set_event_mask {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
 /// Query KVM:
 ///
 /// Query if KVM is enabled.
@@ -1701,6 +2927,108 @@ impl Command for query_kvm {
     }
 }
 
+/// query-name
+///
+/// Query the name of the guest set by `-name`.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-name" }
+/// <- { "return": { "name": "vm1" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_name {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NameInfo {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+}
+
+impl NameInfo {
+    pub fn new(guest_name: &str) -> Self {
+        NameInfo {
+            name: (!guest_name.is_empty()).then(|| guest_name.to_string()),
+        }
+    }
+}
+
+impl Command for query_name {
+    type Res = NameInfo;
+
+    fn back(self) -> NameInfo {
+        Default::default()
+    }
+}
+
+/// query-uuid
+///
+/// Query the uuid of the guest set by `-uuid`, it's all zero if not set.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-uuid" }
+/// <- { "return": { "UUID": "550e8400-e29b-41d4-a716-446655440000" } }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_uuid {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct UuidInfo {
+    #[serde(rename = "UUID")]
+    pub uuid: String,
+}
+
+impl UuidInfo {
+    pub fn new(uuid: Option<&str>) -> Self {
+        UuidInfo {
+            uuid: uuid
+                .unwrap_or("00000000-0000-0000-0000-000000000000")
+                .to_string(),
+        }
+    }
+}
+
+impl Command for query_uuid {
+    type Res = UuidInfo;
+
+    fn back(self) -> UuidInfo {
+        Default::default()
+    }
+}
+
+/// query-vsock
+///
+/// Query the guest-cid and vhostfd of vsock devices.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-vsock" }
+/// <- { "return": [ { "id": "vsock0", "guest-cid": 3, "vhostfd": 4 } ] }
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_vsock {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct VsockInfo {
+    pub id: String,
+    #[serde(rename = "guest-cid")]
+    pub guest_cid: u64,
+    #[serde(rename = "vhostfd", skip_serializing_if = "Option::is_none")]
+    pub vhost_fd: Option<i32>,
+}
+
+impl Command for query_vsock {
+    type Res = Vec<VsockInfo>;
+
+    fn back(self) -> Vec<VsockInfo> {
+        Default::default()
+    }
+}
+
 /// List all Qom type.
 ///
 /// # Example
@@ -1735,153 +3063,439 @@ impl Command for list_type {
     }
 }
 
-/// Get device list properties.
+/// Get device list properties.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "device-list-properties", "arguments": {"typename": "virtio-blk-pci"} }
+/// <- {"return":[]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct device_list_properties {
+    pub typename: String,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceProps {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub prop_type: String,
+}
+
+impl Command for device_list_properties {
+    type Res = Vec<DeviceProps>;
+
+    fn back(self) -> Vec<DeviceProps> {
+        Default::default()
+    }
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct block_commit {}
+
+impl Command for block_commit {
+    type Res = Vec<DeviceProps>;
+
+    fn back(self) -> Vec<DeviceProps> {
+        Default::default()
+    }
+}
+
+/// Query tpm models of StratoVirt.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-tpm-models" }
+/// <- {"return":[]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_tpm_models {}
+
+impl Command for query_tpm_models {
+    type Res = Vec<String>;
+
+    fn back(self) -> Vec<String> {
+        Default::default()
+    }
+}
+
+/// Query target of StratoVirt.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-tpm-types" }
+/// <- {"return":[]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_tpm_types {}
+
+impl Command for query_tpm_types {
+    type Res = Vec<String>;
+
+    fn back(self) -> Vec<String> {
+        Default::default()
+    }
+}
+
+/// Query command line options and their parameters.
+///
+/// # Arguments
+///
+/// * `option` - Only query the option of this name.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-command-line-options", "arguments": { "option": "boot" } }
+/// <- {"return":[{"option":"boot","multiple":false,"parameters":[
+///     {"name":"order","type":"string"},
+///     {"name":"strict","type":"boolean","default":"off","values":["on","off"]},
+///     {"name":"menu","type":"boolean","values":["on","off"]}]}]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct query_command_line_options {
+    pub option: Option<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CmdParameter {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub param_type: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<String>,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CmdLine {
+    pub option: String,
+    pub multiple: bool,
+    pub parameters: Vec<CmdParameter>,
+}
+
+impl Command for query_command_line_options {
+    type Res = Vec<CmdLine>;
+
+    fn back(self) -> Vec<CmdLine> {
+        Default::default()
+    }
+}
+
+/// Query capabilities of migration.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-migrate-capabilities" }
+/// <- {"return":[{"state":false,"capability":"xbzrle"},
+///              {"state":false,"capability":"auto-converge"},
+///              {"state":false,"capability":"postcopy-ram"}]}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_migrate_capabilities {}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MigrateCapabilities {
+    pub state: bool,
+    pub capability: String,
+}
+
+impl Command for query_migrate_capabilities {
+    type Res = Vec<MigrateCapabilities>;
+
+    fn back(self) -> Vec<MigrateCapabilities> {
+        Default::default()
+    }
+}
+
+/// Query target of StratoVirt.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-qmp-schema" }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_qmp_schema {}
+
+impl Command for query_qmp_schema {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// Query capabilities of sev.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-sev-capabilities" }
+/// <- {"return":{}}
+/// ```
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct query_sev_capabilities {}
+
+impl Command for query_sev_capabilities {
+    type Res = Empty;
+
+    fn back(self) -> Empty {
+        Default::default()
+    }
+}
+
+/// List the children and properties of an object.
+///
+/// # Arguments
+///
+/// * `path` - The canonical path of the object.
+///
+/// # Errors
+///
+/// If `path` is not a valid object, DeviceNotFound.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "device-list-properties", "arguments": {"typename": "virtio-blk-pci"} }
-/// <- {"return":[]}
+/// -> { "execute": "qom-list", "arguments": { "path": "/machine/peripheral/blk0" } }
+/// <- {"return":[{"name":"type","type":"string"},{"name":"drive","type":"string"},
+/// {"name":"realized","type":"bool"}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct device_list_properties {
-    pub typename: String,
+#[serde(default)]
+pub struct qom_list {
+    pub path: String,
 }
 
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct DeviceProps {
+pub struct PropList {
     pub name: String,
     #[serde(rename = "type")]
     pub prop_type: String,
 }
 
-impl Command for device_list_properties {
-    type Res = Vec<DeviceProps>;
+impl Command for qom_list {
+    type Res = Vec<PropList>;
 
-    fn back(self) -> Vec<DeviceProps> {
+    fn back(self) -> Vec<PropList> {
         Default::default()
     }
 }
 
+/// Query char devices.
+///
+/// # Example
+///
+/// ```text
+/// -> { "execute": "query-chardev" }
+/// <- { "return": [ { "frontend-open": true, "filename": "pty:/dev/pts/2",
+///                    "label": "charconsole0" } ] }
+/// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct block_commit {}
+pub struct query_chardev {}
 
-impl Command for block_commit {
-    type Res = Vec<DeviceProps>;
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ChardevInfo {
+    #[serde(rename = "frontend-open")]
+    pub open: bool,
+    pub filename: String,
+    pub label: String,
+}
 
-    fn back(self) -> Vec<DeviceProps> {
+impl Command for query_chardev {
+    type Res = Vec<ChardevInfo>;
+
+    fn back(self) -> Vec<ChardevInfo> {
         Default::default()
     }
 }
 
-/// Query tpm models of StratoVirt.
+/// Get the value of an object property.
+///
+/// # Arguments
+///
+/// * `path` - The canonical path of the object.
+/// * `property` - The name of the property.
+///
+/// # Errors
+///
+/// If `path` is not a valid object, DeviceNotFound.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-tpm-models" }
-/// <- {"return":[]}
+/// -> { "execute": "qom_get",
+///      "arguments": { "path": "/machine/peripheral/blk0", "property": "drive" } }
+/// <- {"return":"drive-0"}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_tpm_models {}
+#[serde(default)]
+pub struct qom_get {
+    pub path: String,
+    pub property: String,
+}
 
-impl Command for query_tpm_models {
-    type Res = Vec<String>;
+impl Command for qom_get {
+    type Res = serde_json::Value;
 
-    fn back(self) -> Vec<String> {
+    fn back(self) -> serde_json::Value {
         Default::default()
     }
 }
 
-/// Query target of StratoVirt.
+/// Forward a command to the guest agent over the virtio-serial port
+/// `org.qemu.guest_agent.0`, and return the value of its reply.
+///
+/// # Arguments
+///
+/// * `command` - The json command of the guest agent.
+/// * `timeout` - Time in milliseconds to wait for the reply, default is 5000.
+///
+/// # Errors
+///
+/// If the guest agent returns an error or doesn't reply in time, GenericError.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-tpm-types" }
-/// <- {"return":[]}
+/// -> { "execute": "guest-agent-command",
+///      "arguments": { "command": { "execute": "guest-get-osinfo" }, "timeout": 3000 } }
+/// <- {"return":{"id":"ubuntu","kernel-release":"5.15.0-58-generic","machine":"x86_64"}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_tpm_types {}
+#[serde(deny_unknown_fields)]
+pub struct guest_agent_command {
+    pub command: serde_json::Value,
+    #[serde(default)]
+    pub timeout: Option<u64>,
+}
 
-impl Command for query_tpm_types {
-    type Res = Vec<String>;
+impl Command for guest_agent_command {
+    type Res = serde_json::Value;
 
-    fn back(self) -> Vec<String> {
+    fn back(self) -> serde_json::Value {
         Default::default()
     }
 }
 
-/// Query command line options.
+/// Query blocks of StratoVirt and their error statistics.
+///
+/// # Arguments
+///
+/// * `reset-errors` - Reset the error statistics after they are queried.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-command-line-options" }
-/// <- {"return":[]}
+/// -> { "execute": "query-block", "arguments": { "reset-errors": true } }
+/// <- {"return":[{"device":"drive-0","file":"/path/to/rootfs","ro":false,"write-protected":false,
+///     "errors":{"backend-eio":0,"enospc":1,"invalid-request":0,"throttled-drop":0,
+///     "last-error":{"errno":28,"message":"No space left on device (os error 28)",
+///     "timestamp":{"seconds":1685000000,"microseconds":4321}},
+///     "requests":[{"category":"enospc","errno":28,"opcode":1,"offset":4096,"length":512,
+///     "timestamp":{"seconds":1685000000,"microseconds":4321}}]}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_command_line_options {}
-
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct CmdParameter {
-    name: String,
-    help: String,
-    #[serde(rename = "type")]
-    paramter_type: String,
-}
-
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct CmdLine {
-    pub parameters: Vec<CmdParameter>,
-    pub option: String,
+pub struct query_block {
+    #[serde(rename = "reset-errors")]
+    pub reset_errors: Option<bool>,
 }
 
-impl Command for query_command_line_options {
-    type Res = Vec<CmdLine>;
+impl Command for query_block {
+    type Res = Vec<BlockInfo>;
 
-    fn back(self) -> Vec<CmdLine> {
+    fn back(self) -> Vec<BlockInfo> {
         Default::default()
     }
 }
 
-/// Query capabilities of migration.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockInfo {
+    #[serde(rename = "device")]
+    pub device: String,
+    #[serde(rename = "file")]
+    pub file: String,
+    #[serde(rename = "ro")]
+    pub read_only: bool,
+    #[serde(rename = "errors")]
+    pub errors: DeviceErrorInfo,
+    /// The write threshold in bytes, 0 if it's not set.
+    #[serde(rename = "write-threshold")]
+    pub write_threshold: u64,
+    /// The highest offset written by the guest.
+    #[serde(rename = "wr-highest-offset")]
+    pub wr_highest_offset: u64,
+    /// The disk is turned read-only by the `read-only` werror policy, until
+    /// `block-set-writable`.
+    #[serde(rename = "write-protected")]
+    pub write_protected: bool,
+}
+
+/// block-set-write-threshold
+///
+/// Set the write threshold of a disk. `BLOCK_WRITE_THRESHOLD` is emitted once when
+/// the guest writes beyond it, after which the threshold is cleared.
+///
+/// # Arguments
+///
+/// * `device` - Id of the disk, `node-name` is accepted as an alias.
+/// * `write-threshold` - Offset in bytes, 0 disables the threshold.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-migrate-capabilities" }
-/// <- {"return":[]}
+/// -> { "execute": "block-set-write-threshold",
+///      "arguments": { "device": "drive-0", "write-threshold": 17179869184 } }
+/// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_migrate_capabilities {}
-
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct MigrateCapabilities {
-    pub state: bool,
-    pub capability: String,
+pub struct block_set_write_threshold {
+    #[serde(rename = "device", alias = "node-name")]
+    pub device: String,
+    #[serde(rename = "write-threshold")]
+    pub write_threshold: u64,
 }
 
-impl Command for query_migrate_capabilities {
-    type Res = Vec<MigrateCapabilities>;
+impl Command for block_set_write_threshold {
+    type Res = Empty;
 
-    fn back(self) -> Vec<MigrateCapabilities> {
+    fn back(self) -> Empty {
         Default::default()
     }
 }
 
-/// Query target of StratoVirt.
+/// block-set-writable
+///
+/// Restore the write access of a disk which is turned read-only by the `read-only`
+/// werror policy after the backend ran out of space. It does nothing if the disk is
+/// writable.
+///
+/// # Arguments
+///
+/// * `device` - Id of the disk, `node-name` is accepted as an alias.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-qmp-schema" }
+/// -> { "execute": "block-set-writable", "arguments": { "device": "drive-0" } }
 /// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_qmp_schema {}
+#[serde(deny_unknown_fields)]
+pub struct block_set_writable {
+    #[serde(rename = "device", alias = "node-name")]
+    pub device: String,
+}
 
-impl Command for query_qmp_schema {
+impl Command for block_set_writable {
     type Res = Empty;
 
     fn back(self) -> Empty {
@@ -1889,18 +3503,35 @@ impl Command for query_qmp_schema {
     }
 }
 
-/// Query capabilities of sev.
+/// block_resize
+///
+/// Resize a raw image and notify the guest of the new capacity: by a config change interrupt
+/// for virtio-blk, by a CAPACITY DATA HAS CHANGED unit attention and a parameter change event
+/// for virtio-scsi. A host block device is not resized, it's accepted only if it's large enough.
+///
+/// # Arguments
+///
+/// * `device` - Id of the disk, `node-name` is accepted as an alias.
+/// * `size` - New size in bytes, a multiple of the block size of the disk.
+/// * `allow-shrink` - Allow a size smaller than the current one, false by default.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-sev-capabilities" }
+/// -> { "execute": "block_resize",
+///      "arguments": { "device": "drive-0", "size": 21474836480 } }
 /// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_sev_capabilities {}
+pub struct block_resize {
+    #[serde(rename = "device", alias = "node-name")]
+    pub device: String,
+    pub size: u64,
+    #[serde(rename = "allow-shrink")]
+    pub allow_shrink: Option<bool>,
+}
 
-impl Command for query_sev_capabilities {
+impl Command for block_resize {
     type Res = Empty;
 
     fn back(self) -> Empty {
@@ -1908,97 +3539,187 @@ impl Command for query_sev_capabilities {
     }
 }
 
-/// List all Qom.
+/// eject
+///
+/// Eject the medium of a removable scsi device, such as scsi-cd. `DEVICE_TRAY_MOVED` is
+/// emitted when the tray is opened.
+///
+/// # Arguments
+///
+/// * `device` - Id of the device.
+/// * `force` - Eject even if the guest prevents the removal of the medium, false by default.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "qom-list" }
-/// <- {"return":[]}
+/// -> { "execute": "eject", "arguments": { "device": "scsi-cd0" } }
+/// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct qom_list {}
-
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct PropList {
-    pub name: String,
-    #[serde(rename = "type")]
-    pub prop_type: String,
+pub struct eject {
+    #[serde(rename = "device", alias = "id")]
+    pub device: String,
+    pub force: Option<bool>,
 }
 
-impl Command for qom_list {
-    type Res = Vec<PropList>;
+impl Command for eject {
+    type Res = Empty;
 
-    fn back(self) -> Vec<PropList> {
+    fn back(self) -> Empty {
         Default::default()
     }
 }
 
-/// Query char devices.
+/// blockdev-backup
+///
+/// Start a background job to copy the image of a disk to a target file. The backup is
+/// consistent with the image at the time the job starts. `BLOCK_JOB_COMPLETED` is emitted
+/// when the job finishes.
+///
+/// # Arguments
+///
+/// * `device` - Id of the disk.
+/// * `target` - Path of the target file, which is created or truncated.
+/// * `sync` - Only `full` is supported.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-chardev" }
-/// <- {"return":[]}
+/// -> { "execute": "blockdev-backup",
+///      "arguments": { "device": "drive-0", "target": "/path/to/backup.img", "sync": "full" } }
+/// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_chardev {}
-
-#[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct ChardevInfo {
-    #[serde(rename = "frontend-open")]
-    pub open: bool,
-    pub filename: String,
-    pub label: String,
+pub struct blockdev_backup {
+    pub device: String,
+    pub target: String,
+    pub sync: Option<String>,
 }
 
-impl Command for query_chardev {
-    type Res = Vec<ChardevInfo>;
+impl Command for blockdev_backup {
+    type Res = Empty;
 
-    fn back(self) -> Vec<ChardevInfo> {
+    fn back(self) -> Empty {
         Default::default()
     }
 }
 
-/// Get qom properties.
+/// block-job-cancel
+///
+/// Cancel the block job of a disk. `BLOCK_JOB_CANCELLED` is emitted when the job stops.
+///
+/// # Arguments
+///
+/// * `device` - Id of the disk.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "qom_get" }
-/// <- {"return":[]}
+/// -> { "execute": "block-job-cancel", "arguments": { "device": "drive-0" } }
+/// <- {"return":{}}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct qom_get {}
+pub struct block_job_cancel {
+    pub device: String,
+}
 
-impl Command for qom_get {
-    type Res = bool;
+impl Command for block_job_cancel {
+    type Res = Empty;
 
-    fn back(self) -> bool {
+    fn back(self) -> Empty {
         Default::default()
     }
 }
 
-/// Query blocks of StratoVirt.
+/// Error statistics of a device.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct DeviceErrorInfo {
+    #[serde(rename = "backend-eio")]
+    pub backend_eio: u64,
+    #[serde(rename = "enospc")]
+    pub enospc: u64,
+    #[serde(rename = "invalid-request")]
+    pub invalid_request: u64,
+    #[serde(rename = "throttled-drop")]
+    pub throttled_drop: u64,
+    #[serde(rename = "last-error", skip_serializing_if = "Option::is_none")]
+    pub last_error: Option<LastErrorInfo>,
+    /// The guest requests which triggered the last errors, the newest first.
+    #[serde(rename = "requests")]
+    pub requests: Vec<ErrorRequestInfo>,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct LastErrorInfo {
+    #[serde(rename = "errno")]
+    pub errno: i32,
+    #[serde(rename = "message")]
+    pub message: String,
+    #[serde(rename = "timestamp")]
+    pub timestamp: TimestampInfo,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct ErrorRequestInfo {
+    #[serde(rename = "category")]
+    pub category: String,
+    #[serde(rename = "errno")]
+    pub errno: i32,
+    #[serde(rename = "opcode")]
+    pub opcode: u32,
+    #[serde(rename = "offset")]
+    pub offset: u64,
+    #[serde(rename = "length")]
+    pub length: u64,
+    #[serde(rename = "timestamp")]
+    pub timestamp: TimestampInfo,
+}
+
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct TimestampInfo {
+    #[serde(rename = "seconds")]
+    pub seconds: u64,
+    #[serde(rename = "microseconds")]
+    pub microseconds: u64,
+}
+
+/// Query net devices of StratoVirt and their error statistics.
+///
+/// # Arguments
+///
+/// * `reset-errors` - Reset the error statistics after they are queried.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-block" }
-/// <- {"return":[]}
+/// -> { "execute": "query-netdev" }
+/// <- {"return":[{"id":"net-0","ifname":"tap0","errors":{"backend-eio":0,"enospc":0,
+///     "invalid-request":0,"throttled-drop":0,"requests":[]}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
-pub struct query_block {}
+pub struct query_netdev {
+    #[serde(rename = "reset-errors")]
+    pub reset_errors: Option<bool>,
+}
 
-impl Command for query_block {
-    type Res = Vec<Cmd>;
+impl Command for query_netdev {
+    type Res = Vec<NetdevInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<NetdevInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct NetdevInfo {
+    #[serde(rename = "id")]
+    pub id: String,
+    #[serde(rename = "ifname")]
+    pub ifname: String,
+    #[serde(rename = "errors")]
+    pub errors: DeviceErrorInfo,
+}
+
 /// Query named block node.
 ///
 /// # Example
@@ -2018,44 +3739,96 @@ impl Command for query_named_block_nodes {
     }
 }
 
-/// Query status of blocks.
+/// Query I/O statistics of blocks.
 ///
 /// # Example
 ///
 /// ```text
 /// -> { "execute": "query-blockstats" }
-/// <- {"return":[]}
+/// <- {"return":[{"device":"drive-0","stats":{"rd_bytes":4096,"wr_bytes":0,
+///     "rd_operations":1,"wr_operations":0,"flush_operations":0,
+///     "failed_rd_operations":0,"failed_wr_operations":0,"failed_flush_operations":0,
+///     "rd_total_time_ns":52000,"wr_total_time_ns":0,"flush_total_time_ns":0,
+///     "max_rd_latency_ns":52000,"max_wr_latency_ns":0,"max_flush_latency_ns":0,
+///     "wr_highest_offset":0}}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_blockstats {}
 
 impl Command for query_blockstats {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockStatsInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockStatsInfo> {
         Default::default()
     }
 }
 
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockStatsInfo {
+    pub device: String,
+    pub stats: BlockDeviceStats,
+}
+
+/// I/O statistics of a block device, the names are compatible with QEMU.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockDeviceStats {
+    pub rd_bytes: u64,
+    pub wr_bytes: u64,
+    pub rd_operations: u64,
+    pub wr_operations: u64,
+    pub flush_operations: u64,
+    pub failed_rd_operations: u64,
+    pub failed_wr_operations: u64,
+    pub failed_flush_operations: u64,
+    pub rd_total_time_ns: u64,
+    pub wr_total_time_ns: u64,
+    pub flush_total_time_ns: u64,
+    pub max_rd_latency_ns: u64,
+    pub max_wr_latency_ns: u64,
+    pub max_flush_latency_ns: u64,
+    pub wr_highest_offset: u64,
+}
+
 /// Query jobs of blocks.
 ///
 /// # Example
 ///
 /// ```text
-/// -> { "execute": "query-block_jobs" }
-/// <- {"return":[]}
+/// -> { "execute": "query-block-jobs" }
+/// <- {"return":[{"type":"backup","device":"drive-0","len":10737418240,"offset":134217728,
+///     "busy":true,"paused":false,"speed":0,"ready":false,"io-status":"ok"}]}
 /// ```
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct query_block_jobs {}
 
 impl Command for query_block_jobs {
-    type Res = Vec<Cmd>;
+    type Res = Vec<BlockJobInfo>;
 
-    fn back(self) -> Vec<Cmd> {
+    fn back(self) -> Vec<BlockJobInfo> {
         Default::default()
     }
 }
 
+/// Progress of a block job.
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
+pub struct BlockJobInfo {
+    #[serde(rename = "type")]
+    pub job_type: String,
+    /// Id of the disk.
+    pub device: String,
+    /// Bytes to be copied.
+    pub len: u64,
+    /// Bytes copied.
+    pub offset: u64,
+    pub busy: bool,
+    pub paused: bool,
+    /// Rate limit in bytes per second, 0 means unlimited.
+    pub speed: u64,
+    pub ready: bool,
+    #[serde(rename = "io-status")]
+    pub io_status: String,
+}
+
 /// Query capabilities of gic.
 ///
 /// # Example
@@ -2586,6 +4359,19 @@ mod tests {
         };
         let part_msg = r#"ok"#;
         assert!(err_msg.contains(part_msg));
+
+        // query-cpu-definitions
+        let json_msg = r#"
+        {
+            "execute": "query-cpu-definitions"
+        }
+        "#;
+        let err_msg = match serde_json::from_str::<QmpCommand>(json_msg) {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        };
+        let part_msg = r#"ok"#;
+        assert!(err_msg.contains(part_msg));
     }
 
     #[test]
@@ -2686,4 +4472,33 @@ mod tests {
         let part_msg = r#"ok"#;
         assert!(err_msg.contains(part_msg));
     }
+
+    #[test]
+    fn test_qmp_screendump() {
+        let json_msg = r#"
+        {
+            "execute": "screendump",
+            "arguments": {
+                "filename": "/tmp/screen.png",
+                "format": "png"
+            }
+        }
+        "#;
+        match serde_json::from_str::<QmpCommand>(json_msg).unwrap() {
+            QmpCommand::screendump { arguments, .. } => {
+                assert_eq!(arguments.filename, "/tmp/screen.png");
+                assert_eq!(arguments.format, Some("png".to_string()));
+            }
+            _ => panic!("Unexpected command"),
+        }
+
+        // The filename is mandatory.
+        let json_msg = r#"
+        {
+            "execute": "screendump",
+            "arguments": {}
+        }
+        "#;
+        assert!(serde_json::from_str::<QmpCommand>(json_msg).is_err());
+    }
 }
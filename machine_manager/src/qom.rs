@@ -0,0 +1,281 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Lightweight object model for device introspection by `qom-list` and `qom_get`.
+//!
+//! Devices and vCPUs register themselves with a canonical path, such as
+//! `/machine/unattached/device[0]`. The parent paths are containers, which exist as
+//! long as they have registered descendants.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde_json::Value;
+
+use crate::qmp::qmp_schema::PropList;
+
+const CONTAINER_TYPE: &str = "container";
+const TYPE_PROP: &str = "type";
+const PERIPHERAL_PATH: &str = "/machine/peripheral";
+const PERIPHERAL_ANON_PATH: &str = "/machine/peripheral-anon";
+
+/// Object registered in the QOM tree.
+#[derive(Clone, Debug)]
+pub struct QomObject {
+    /// Type of the object, such as the driver of a device.
+    type_name: String,
+    /// Readable properties of the object apart from `type`.
+    props: BTreeMap<String, Value>,
+}
+
+impl QomObject {
+    /// Create a realized object of the type.
+    pub fn new(type_name: &str) -> Self {
+        let mut props = BTreeMap::new();
+        props.insert("realized".to_string(), Value::Bool(true));
+        QomObject {
+            type_name: type_name.to_string(),
+            props,
+        }
+    }
+
+    /// Add a readable property to the object.
+    pub fn with_prop<T: Into<Value>>(mut self, name: &str, value: T) -> Self {
+        self.props.insert(name.to_string(), value.into());
+        self
+    }
+}
+
+static QOM_OBJECTS: Lazy<Mutex<BTreeMap<String, QomObject>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Register the object at the path, the object registered at it before is replaced.
+pub fn register_object(path: &str, object: QomObject) {
+    QOM_OBJECTS.lock().unwrap().insert(path.to_string(), object);
+}
+
+/// Unregister the object at the path.
+pub fn unregister_object(path: &str) {
+    QOM_OBJECTS.lock().unwrap().remove(path);
+}
+
+/// Register the device added by `-device` or `device_add`. The device is placed at
+/// `/machine/peripheral/<id>`, or `/machine/peripheral-anon/device[n]` if it has no id.
+/// Return the path of the device.
+pub fn register_device(id: &str, object: QomObject) -> String {
+    let mut objects = QOM_OBJECTS.lock().unwrap();
+    let path = if id.is_empty() {
+        (0..)
+            .map(|index| format!("{}/device[{}]", PERIPHERAL_ANON_PATH, index))
+            .find(|path| !objects.contains_key(path))
+            .unwrap()
+    } else {
+        format!("{}/{}", PERIPHERAL_PATH, id)
+    };
+    objects.insert(path.clone(), object);
+    path
+}
+
+/// Unregister the device with the id, which is removed by `device_del`.
+pub fn unregister_device(id: &str) {
+    unregister_object(&format!("{}/{}", PERIPHERAL_PATH, id));
+}
+
+fn normalize_path(path: &str) -> &str {
+    match path.trim_end_matches('/') {
+        "" if path.starts_with('/') => "/",
+        trimmed => trimmed,
+    }
+}
+
+fn child_prefix(path: &str) -> String {
+    if path == "/" {
+        path.to_string()
+    } else {
+        format!("{}/", path)
+    }
+}
+
+/// Return the type of the object at the path, none if it doesn't exist.
+fn object_type(objects: &BTreeMap<String, QomObject>, path: &str) -> Option<String> {
+    if let Some(object) = objects.get(path) {
+        return Some(object.type_name.clone());
+    }
+    if path == "/" {
+        return Some(CONTAINER_TYPE.to_string());
+    }
+    if !path.starts_with('/') {
+        return None;
+    }
+    let prefix = child_prefix(path);
+    objects
+        .range(prefix.clone()..)
+        .next()
+        .filter(|(key, _)| key.starts_with(&prefix))
+        .map(|_| CONTAINER_TYPE.to_string())
+}
+
+/// Return the names of the children of the object at the path.
+fn object_children(objects: &BTreeMap<String, QomObject>, path: &str) -> BTreeSet<String> {
+    let prefix = child_prefix(path);
+    objects
+        .range(prefix.clone()..)
+        .take_while(|(key, _)| key.starts_with(&prefix))
+        .filter_map(|(key, _)| key[prefix.len()..].split('/').next())
+        .filter(|name| !name.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+fn value_type(value: &Value) -> &'static str {
+    match value {
+        Value::Bool(_) => "bool",
+        Value::Number(_) => "int",
+        Value::String(_) => "string",
+        _ => "any",
+    }
+}
+
+/// Check whether the object exists at the path.
+pub fn object_exists(path: &str) -> bool {
+    object_type(&QOM_OBJECTS.lock().unwrap(), normalize_path(path)).is_some()
+}
+
+/// List the children and properties of the object at the path, none if it doesn't exist.
+pub fn list_object(path: &str) -> Option<Vec<PropList>> {
+    let path = normalize_path(path);
+    let objects = QOM_OBJECTS.lock().unwrap();
+    object_type(&objects, path)?;
+
+    let prefix = child_prefix(path);
+    let mut props = Vec::new();
+    for child in object_children(&objects, path) {
+        let child_type = object_type(&objects, &format!("{}{}", prefix, child)).unwrap();
+        props.push(PropList {
+            name: child,
+            prop_type: format!("child<{}>", child_type),
+        });
+    }
+    props.push(PropList {
+        name: TYPE_PROP.to_string(),
+        prop_type: "string".to_string(),
+    });
+    if let Some(object) = objects.get(path) {
+        for (name, value) in object.props.iter() {
+            props.push(PropList {
+                name: name.clone(),
+                prop_type: value_type(value).to_string(),
+            });
+        }
+    }
+    Some(props)
+}
+
+/// Get the property of the object at the path, none if the object or property doesn't
+/// exist. The value of a child property is the path of the child.
+pub fn get_object_property(path: &str, name: &str) -> Option<Value> {
+    let path = normalize_path(path);
+    let objects = QOM_OBJECTS.lock().unwrap();
+    let type_name = object_type(&objects, path)?;
+    if name == TYPE_PROP {
+        return Some(Value::String(type_name));
+    }
+    if let Some(value) = objects.get(path).and_then(|object| object.props.get(name)) {
+        return Some(value.clone());
+    }
+    if object_children(&objects, path).contains(name) {
+        return Some(Value::String(format!("{}{}", child_prefix(path), name)));
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prop_names(props: &[PropList]) -> Vec<(&str, &str)> {
+        props
+            .iter()
+            .map(|prop| (prop.name.as_str(), prop.prop_type.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn test_qom_registry() {
+        register_object(
+            "/qom-test/unattached/device[0]",
+            QomObject::new("test-cpu").with_prop("cpu-index", 0),
+        );
+        let blk = QomObject::new("virtio-blk-pci").with_prop("drive", "drive-0");
+        assert_eq!(
+            register_device("", blk.clone()),
+            "/machine/peripheral-anon/device[0]"
+        );
+        assert_eq!(
+            register_device("", blk),
+            "/machine/peripheral-anon/device[1]"
+        );
+
+        let props = list_object("/qom-test").unwrap();
+        assert_eq!(
+            prop_names(&props),
+            vec![("unattached", "child<container>"), ("type", "string")]
+        );
+        let props = list_object("/qom-test/unattached/").unwrap();
+        assert_eq!(
+            prop_names(&props),
+            vec![("device[0]", "child<test-cpu>"), ("type", "string")]
+        );
+        let props = list_object("/qom-test/unattached/device[0]").unwrap();
+        assert_eq!(
+            prop_names(&props),
+            vec![
+                ("type", "string"),
+                ("cpu-index", "int"),
+                ("realized", "bool")
+            ]
+        );
+        let props = list_object("/").unwrap();
+        assert!(props.iter().any(|prop| prop.name == "qom-test"));
+
+        assert_eq!(
+            get_object_property("/qom-test/unattached/device[0]", "type"),
+            Some(Value::from("test-cpu"))
+        );
+        assert_eq!(
+            get_object_property("/machine/peripheral-anon/device[1]", "drive"),
+            Some(Value::from("drive-0"))
+        );
+        assert_eq!(
+            get_object_property("/qom-test/unattached", "device[0]"),
+            Some(Value::from("/qom-test/unattached/device[0]"))
+        );
+        assert_eq!(
+            get_object_property("/qom-test", "type"),
+            Some(Value::from("container"))
+        );
+        assert!(get_object_property("/qom-test/unattached/device[0]", "drive").is_none());
+
+        // Paths which are not registered or are partial names don't exist.
+        assert!(list_object("/qom-test/unattached/device").is_none());
+        assert!(list_object("/qom-tes").is_none());
+        assert!(list_object("qom-test").is_none());
+        assert!(list_object("").is_none());
+        assert!(!object_exists("/qom-test/attached"));
+
+        unregister_object("/qom-test/unattached/device[0]");
+        assert!(!object_exists("/qom-test"));
+        unregister_object("/machine/peripheral-anon/device[0]");
+        unregister_object("/machine/peripheral-anon/device[1]");
+    }
+}
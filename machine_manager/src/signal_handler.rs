@@ -13,6 +13,7 @@ use crate::temp_cleaner::TempCleaner;
 use std::io::Write;
 
 use libc::{c_int, c_void, siginfo_t};
+use util::logger::request_log_reopen;
 use util::set_termi_canon_mode;
 use vmm_sys_util::signal::register_signal_handler;
 
@@ -56,6 +57,10 @@ extern "C" fn handle_signal_sys(_: c_int, info: *mut siginfo_t, _: *mut c_void)
     exit_with_code(VM_EXIT_GENE_ERR);
 }
 
+extern "C" fn handle_signal_hup(_: c_int, _: *mut siginfo_t, _: *mut c_void) {
+    request_log_reopen();
+}
+
 /// Register kill signal handler. Signals supported now are SIGTERM and SIGSYS.
 pub fn register_kill_signal() {
     register_signal_handler(libc::SIGTERM, handle_signal_kill)
@@ -65,3 +70,9 @@ pub fn register_kill_signal() {
     register_signal_handler(libc::SIGINT, handle_signal_kill)
         .expect("Register signal handler for SIGINT failed!");
 }
+
+/// Register SIGHUP handler to reopen the log file after it's moved by logrotate.
+pub fn register_log_reopen_signal() {
+    register_signal_handler(libc::SIGHUP, handle_signal_hup)
+        .expect("Register signal handler for SIGHUP failed!");
+}
@@ -10,6 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind, Read, Write};
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -27,7 +28,7 @@ use serde::Deserialize;
 use vmm_sys_util::epoll::EventSet;
 
 use crate::machine::MachineExternalInterface;
-use crate::qmp::{QmpChannel, QmpGreeting, Response};
+use crate::qmp::{QmpChannel, QmpConnState, QmpGreeting, Response};
 use util::leak_bucket::LeakBucket;
 use util::loop_context::{
     gen_delete_notifiers, read_fd, EventNotifier, EventNotifierHelper, NotifierCallback,
@@ -43,6 +44,9 @@ pub(crate) const LEAK_BUCKET_LIMIT: u64 = 100;
 
 /// The wrapper over Unix socket and socket handler.
 ///
+/// The listener keeps accepting while clients are connected, each accepted
+/// connection is a `SocketClient` with its own qmp capabilities state.
+///
 /// # Example
 ///
 /// ```no_run
@@ -58,9 +62,10 @@ pub(crate) const LEAK_BUCKET_LIMIT: u64 = 100;
 ///     assert!(!socket.is_connected());
 ///
 ///     let client_stream = UnixStream::connect("/path/to/my/socket")?;
-///     let server_stream = socket.accept_unix_stream();
-///     socket.bind_unix_stream(server_stream);
+///     let server_stream = socket.accept_unix_stream()?;
+///     let client = socket.bind_unix_stream(server_stream);
 ///     assert!(socket.is_connected());
+///     client.send_response(true)?;
 ///     Ok(())
 /// }
 /// ```
@@ -69,8 +74,8 @@ pub struct Socket {
     sock_type: SocketType,
     /// Socket listener tuple
     listener: UnixListener,
-    /// Socket stream with RwLock
-    stream: RwLock<Option<SocketStream>>,
+    /// Connected clients, by the fd of the stream
+    clients: RwLock<BTreeMap<RawFd, Arc<SocketClient>>>,
     /// Perform socket command
     performer: Option<Arc<Mutex<dyn MachineExternalInterface>>>,
 }
@@ -89,7 +94,7 @@ impl Socket {
         Socket {
             sock_type: SocketType::Unix,
             listener,
-            stream: RwLock::new(None),
+            clients: RwLock::new(BTreeMap::new()),
             performer,
         }
     }
@@ -99,20 +104,20 @@ impl Socket {
         self.listener.as_raw_fd()
     }
 
-    /// Accept stream and bind to Socket.
-    pub fn accept(&self) {
+    /// Accept a new client and bind it to `Socket`.
+    pub fn accept(&self) -> std::io::Result<Arc<SocketClient>> {
         match self.sock_type {
             SocketType::Unix => {
-                let stream = self.accept_unix_stream();
-                self.bind_unix_stream(stream);
+                let stream = self.accept_unix_stream()?;
+                Ok(self.bind_unix_stream(stream))
             }
         }
     }
 
     /// Accept a new incoming connection unix stream from unix listener.
-    pub fn accept_unix_stream(&self) -> UnixStream {
-        let (stream, _) = self.listener.accept().unwrap();
-        stream
+    pub fn accept_unix_stream(&self) -> std::io::Result<UnixStream> {
+        let (stream, _) = self.listener.accept()?;
+        Ok(stream)
     }
 
     /// Get socket type from `Socket`.
@@ -120,61 +125,44 @@ impl Socket {
         self.sock_type
     }
 
-    /// Bind `Socket` with a `UnixStream`.
+    /// Bind a `UnixStream` to `Socket` as a new client.
     ///
     /// # Arguments
     ///
     /// * `unix_stream` - The `UnixStream` bind to `Socket`.
-    pub fn bind_unix_stream(&self, unix_stream: UnixStream) {
-        let stream = SocketStream::from_unix_stream(unix_stream);
-        *self.stream.write().unwrap() = Some(stream);
-    }
-
-    /// Unbind stream from `Socket`, reset the state.
-    pub fn drop_stream(&self) {
-        *self.stream.write().unwrap() = None;
+    pub fn bind_unix_stream(&self, unix_stream: UnixStream) -> Arc<SocketClient> {
+        let client = Arc::new(SocketClient::new(SocketStream::from_unix_stream(
+            unix_stream,
+        )));
+        self.clients
+            .write()
+            .unwrap()
+            .insert(client.get_stream_fd(), client.clone());
+        client
+    }
+
+    /// Unbind the client whose stream fd is `stream_fd` from `Socket`, the others
+    /// are not affected.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream_fd` - The fd of the stream of the client.
+    pub fn drop_stream(&self, stream_fd: RawFd) {
+        self.clients.write().unwrap().remove(&stream_fd);
     }
 
-    /// Confirm whether socket stream bind to `Socket` or not.
+    /// Confirm whether any client is bound to `Socket` or not.
     pub fn is_connected(&self) -> bool {
-        self.stream.read().unwrap().is_some()
-    }
-
-    /// Get socket fd from `Socket`, it a private function.
-    pub fn get_stream_fd(&self) -> RawFd {
-        if self.is_connected() {
-            self.stream.read().unwrap().as_ref().unwrap().as_raw_fd()
-        } else {
-            panic!("Failed to get socket fd!");
-        }
-    }
-
-    /// Get a `SocketHandler` from `Socket`.
-    pub fn get_socket_handler(&self) -> SocketHandler {
-        SocketHandler::new(self.get_stream_fd())
+        !self.clients.read().unwrap().is_empty()
     }
 
-    /// In qmp feature, send empty or greeting response to client.
-    ///
-    /// # Arguments
-    ///
-    /// * `is_greeting` - Whether sending greeting response or not.
-    pub fn send_response(&self, is_greeting: bool) -> std::io::Result<()> {
-        if self.is_connected() {
-            let mut handler = self.get_socket_handler();
-            let resp = if is_greeting {
-                serde_json::to_string(&QmpGreeting::create_greeting(1, 0, 5)).unwrap()
-            } else {
-                serde_json::to_string(&Response::create_empty_response()).unwrap()
-            };
-            handler.send_str(&resp)?;
-            info!("QMP: --> {:?}", resp);
-        }
-        Ok(())
+    /// Get the number of clients bound to `Socket`.
+    pub fn clients_num(&self) -> usize {
+        self.clients.read().unwrap().len()
     }
 
-    /// Create socket's accepted stream to `event_notifier`.
-    fn create_event_notifier(&mut self, shared_socket: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+    /// Accept a client and send greeting to it, and create the notifiers of the client.
+    fn create_event_notifier(&self, shared_socket: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
         let mut notifiers = Vec::new();
 
         let leak_bucket = LeakBucket::new(LEAK_BUCKET_LIMIT);
@@ -186,32 +174,39 @@ impl Socket {
         let shared_leak_bucket = leak_bucket.clone();
         let leak_bucket_fd = leak_bucket.lock().unwrap().as_raw_fd();
 
-        self.accept();
-        QmpChannel::bind_writer(SocketRWHandler::new(self.get_stream_fd()));
-        if let Err(e) = self.send_response(true) {
+        let client = match self.accept() {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to accept qmp client, {:?}", e);
+                return notifiers;
+            }
+        };
+        let stream_fd = client.get_stream_fd();
+        QmpChannel::bind_writer(stream_fd, client.state.clone());
+        if let Err(e) = client.send_response(true) {
             error!("{:?}", e);
-            QmpChannel::unbind();
+            QmpChannel::unbind(stream_fd);
+            self.drop_stream(stream_fd);
             return notifiers;
         }
+
+        let performer = self.performer.clone();
         let handler: Rc<NotifierCallback> = Rc::new(move |event, _| {
             if event == EventSet::IN {
-                let socket_mutexed = shared_socket.lock().unwrap();
-                let stream_fd = socket_mutexed.get_stream_fd();
-
-                let performer = &socket_mutexed.performer.as_ref().unwrap();
-                if let Err(e) = crate::qmp::handle_qmp(
-                    stream_fd,
-                    performer,
-                    &mut shared_leak_bucket.lock().unwrap(),
-                ) {
-                    error!("{:?}", e);
+                if let Some(performer) = performer.as_ref() {
+                    if let Err(e) = crate::qmp::handle_qmp(
+                        stream_fd,
+                        &client.state,
+                        performer,
+                        &mut shared_leak_bucket.lock().unwrap(),
+                    ) {
+                        error!("{:?}", e);
+                    }
                 }
             }
             if event & EventSet::HANG_UP == EventSet::HANG_UP {
-                let socket_mutexed = shared_socket.lock().unwrap();
-                let stream_fd = socket_mutexed.get_stream_fd();
-
-                QmpChannel::unbind();
+                QmpChannel::unbind(stream_fd);
+                shared_socket.lock().unwrap().drop_stream(stream_fd);
                 Some(gen_delete_notifiers(&[stream_fd, leak_bucket_fd]))
             } else {
                 None
@@ -219,8 +214,8 @@ impl Socket {
         });
         let qmp_notifier = EventNotifier::new(
             NotifierOperation::AddShared,
-            self.get_stream_fd(),
-            Some(self.get_listener_fd()),
+            stream_fd,
+            None,
             EventSet::IN | EventSet::HANG_UP,
             vec![handler],
         );
@@ -263,6 +258,58 @@ impl EventNotifierHelper for Socket {
     }
 }
 
+/// A connection accepted by `Socket`.
+pub struct SocketClient {
+    /// Socket stream of the connection
+    stream: SocketStream,
+    /// Capabilities negotiation state of the connection
+    state: Arc<Mutex<QmpConnState>>,
+}
+
+impl SocketClient {
+    fn new(stream: SocketStream) -> Self {
+        SocketClient {
+            stream,
+            state: Arc::new(Mutex::new(QmpConnState::default())),
+        }
+    }
+
+    /// Get socket fd of the client.
+    pub fn get_stream_fd(&self) -> RawFd {
+        self.stream.as_raw_fd()
+    }
+
+    /// Get a `SocketHandler` of the client.
+    pub fn get_socket_handler(&self) -> SocketHandler {
+        SocketHandler::new(self.get_stream_fd())
+    }
+
+    /// Get capabilities negotiation state of the client.
+    pub fn get_state(&self) -> &Arc<Mutex<QmpConnState>> {
+        &self.state
+    }
+
+    /// In qmp feature, send empty or greeting response to client.
+    ///
+    /// # Arguments
+    ///
+    /// * `is_greeting` - Whether sending greeting response or not.
+    pub fn send_response(&self, is_greeting: bool) -> std::io::Result<()> {
+        let mut handler = self.get_socket_handler();
+        let resp = if is_greeting {
+            serde_json::to_string(&QmpGreeting::create_greeting(1, 0, 5)).unwrap()
+        } else {
+            serde_json::to_string(&Response::create_empty_response()).unwrap()
+        };
+        handler.send_str(&resp)?;
+        if is_greeting {
+            self.state.lock().unwrap().greeted = true;
+        }
+        info!("QMP: --> {:?}", resp);
+        Ok(())
+    }
+}
+
 /// Type for api socket.
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum SocketType {
@@ -335,11 +382,7 @@ impl SocketRWHandler {
 
     /// Get inner buf as a `String`.
     pub fn get_buf_string(&mut self) -> Result<String> {
-        if self.buf.len() > MAX_SOCKET_MSG_LENGTH {
-            bail!("The socket message is too long.");
-        }
-
-        Ok(String::from_utf8_lossy(&self.buf).trim().to_string())
+        decode_socket_msg(&self.buf)
     }
 
     /// Get the last file descriptor read from `scm_fd`.
@@ -508,6 +551,15 @@ impl Write for SocketRWHandler {
     }
 }
 
+/// Decode the bytes received from socket as a trimmed string.
+pub fn decode_socket_msg(buf: &[u8]) -> Result<String> {
+    if buf.len() > MAX_SOCKET_MSG_LENGTH {
+        bail!("The socket message is too long.");
+    }
+
+    Ok(String::from_utf8_lossy(buf).trim().to_string())
+}
+
 /// The handler to handle socket stream and parse socket stream bytes to
 /// json-string.
 ///
@@ -635,6 +687,7 @@ mod tests {
     use serde::{Deserialize, Serialize};
 
     use super::{Socket, SocketHandler, SocketRWHandler, SocketType};
+    use crate::qmp::QmpConnState;
 
     // Environment Preparation for UnixSocket
     fn prepare_unix_socket_environment(socket_id: &str) -> (UnixListener, UnixStream, UnixStream) {
@@ -818,19 +871,23 @@ mod tests {
         assert_eq!(socket.is_connected(), false);
 
         // 2.Connected
-        socket.bind_unix_stream(server);
+        let client = socket.bind_unix_stream(server);
         assert_eq!(socket.is_connected(), true);
         assert_eq!(socket.get_socket_type(), SocketType::Unix);
+        assert_eq!(*client.get_state().lock().unwrap(), QmpConnState::default());
 
-        // 3.Unbind SocketStream, reset state
-        socket.drop_stream();
-        assert_eq!(socket.is_connected(), false);
+        // 3.Accept another client while the first one is connected
+        let _new_client = UnixStream::connect("test_04.sock").unwrap();
+        let new_server = socket.accept().unwrap();
+        assert_eq!(socket.clients_num(), 2);
+        assert_ne!(new_server.get_stream_fd(), client.get_stream_fd());
 
-        // 4.Accept and reconnect a new UnixStream
-        let _new_client = UnixStream::connect("test_04.sock");
-        let new_server = socket.accept_unix_stream();
-        socket.bind_unix_stream(new_server);
+        // 4.Unbind one client, the other one is still connected
+        socket.drop_stream(client.get_stream_fd());
+        assert_eq!(socket.clients_num(), 1);
         assert_eq!(socket.is_connected(), true);
+        socket.drop_stream(new_server.get_stream_fd());
+        assert_eq!(socket.is_connected(), false);
 
         // After test. Environment Recover
         recover_unix_socket_environment("04");
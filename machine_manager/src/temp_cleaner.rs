@@ -10,10 +10,20 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::collections::HashMap;
 use std::fs;
 use std::io::Write;
+use std::sync::{Arc, Mutex};
+
+use once_cell::sync::Lazy;
 
 static mut GLOBAL_TEMP_CLEANER: Option<TempCleaner> = None;
+/// Notifiers called when the VM exits, indexed by the id of their owner.
+static EXIT_NOTIFIERS: Lazy<Mutex<HashMap<String, Arc<ExitNotifier>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Function called when the VM exits, e.g. to run the ifdown script of a tap.
+pub type ExitNotifier = dyn Fn() + Send + Sync;
 
 /// This structure used to keep temporary file which was created by program, and would be deleted
 /// when Vm exit.
@@ -40,8 +50,26 @@ impl TempCleaner {
         }
     }
 
-    /// Clean the temporary files
+    /// Add a notifier called when the VM exits, it replaces the notifier of the same id.
+    pub fn add_exit_notifier(id: String, notifier: Arc<ExitNotifier>) {
+        EXIT_NOTIFIERS.lock().unwrap().insert(id, notifier);
+    }
+
+    /// Remove the exit notifier of `id` and return it, e.g. to call it when the
+    /// device is unplugged before the VM exits.
+    pub fn remove_exit_notifier(id: &str) -> Option<Arc<ExitNotifier>> {
+        EXIT_NOTIFIERS.lock().unwrap().remove(id)
+    }
+
+    /// Clean the temporary files and call the exit notifiers.
     pub fn clean() {
+        // The lock is released before the notifiers are called, they may take a while.
+        // It's called by the panic hook, so a poisoned lock is still taken to run them.
+        let notifiers =
+            std::mem::take(&mut *EXIT_NOTIFIERS.lock().unwrap_or_else(|e| e.into_inner()));
+        for notifier in notifiers.into_values() {
+            notifier();
+        }
         unsafe {
             if let Some(tmp) = GLOBAL_TEMP_CLEANER.as_mut() {
                 while let Some(path) = tmp.paths.pop() {
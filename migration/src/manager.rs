@@ -23,7 +23,7 @@ use once_cell::sync::Lazy;
 use crate::general::translate_id;
 use crate::migration::DirtyBitmap;
 use crate::protocol::{DeviceStateDesc, MemBlock, MigrationStatus, StateTransfer};
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use machine_manager::config::VmConfig;
 use machine_manager::machine::MachineLifecycle;
 use util::byte_code::ByteCode;
@@ -109,6 +109,18 @@ pub trait MigrationHook: StateTransfer {
         Ok(())
     }
 
+    /// Restore memory state from memory lazily, the memory data is populated from the
+    /// file when it's accessed.
+    ///
+    /// # Arguments
+    ///
+    /// * _memory - The file of memory data.
+    /// * _state - device state from memory.
+    /// * _prefetch - Populate the memory data which is not accessed in background or not.
+    fn restore_memory_lazy(&self, _memory: &File, _state: &[u8], _prefetch: bool) -> Result<()> {
+        bail!("Lazy memory restore is not supported")
+    }
+
     /// Send memory data to `Write` trait.
     ///
     /// # Arguments
@@ -256,7 +268,7 @@ impl MigrationManager {
     /// * `cpu_desc` - The `DeviceStateDesc` of CPU instance.
     /// * `cpu` - CPU device instance with MigrationHook trait.
     /// * `id` - The unique id for CPU device.
-    pub fn register_cpu_instance<T>(cpu_desc: DeviceStateDesc, cpu: Arc<T>, id: u8)
+    pub fn register_cpu_instance<T>(cpu_desc: DeviceStateDesc, cpu: Arc<T>, id: u16)
     where
         T: MigrationHook + Sync + Send + 'static,
     {
@@ -15,11 +15,14 @@ use crate::manager::{MigrationManager, MIGRATION_MANAGER};
 use crate::protocol::{DeviceStateDesc, FileFormat, MigrationStatus, HEADER_LENGTH};
 use crate::MigrationError;
 use anyhow::{anyhow, bail, Context, Result};
+use log::warn;
+use machine_manager::config::LazyRestoreConfig;
 use std::collections::HashMap;
 use std::fs::{create_dir, File};
 use std::io::{Read, Write};
 use std::path::PathBuf;
 use util::unix::host_page_size;
+use util::userfaultfd::Userfaultfd;
 
 pub const SERIAL_SNAPSHOT_ID: &str = "serial";
 pub const KVM_SNAPSHOT_ID: &str = "kvm";
@@ -96,7 +99,9 @@ impl MigrationManager {
     /// # Argument
     ///
     /// * `path` - snapshot dir path.
-    pub fn restore_snapshot(path: &str) -> Result<()> {
+    /// * `lazy` - Restore memory lazily if it's set, the memory is populated from the
+    ///   snapshot on demand so the VM can start running immediately.
+    pub fn restore_snapshot(path: &str, lazy: Option<LazyRestoreConfig>) -> Result<()> {
         // Set status to `Active`
         MigrationManager::set_status(MigrationStatus::Active)?;
 
@@ -123,7 +128,8 @@ impl MigrationManager {
             bail!("Invalid device state snapshot file");
         }
 
-        Self::restore_memory(&mut memory_file).with_context(|| "Failed to load snapshot memory")?;
+        Self::restore_memory(&mut memory_file, lazy)
+            .with_context(|| "Failed to load snapshot memory")?;
         let snapshot_desc_db =
             Self::restore_desc_db(&mut device_state_file, device_state_header.desc_len)
                 .with_context(|| "Failed to load device descriptor db")?;
@@ -156,15 +162,19 @@ impl MigrationManager {
     /// # Arguments
     ///
     /// * `file` - snapshot memory file.
-    fn restore_memory(file: &mut File) -> Result<()> {
+    /// * `lazy` - Restore memory lazily if it's set and supported by host.
+    fn restore_memory(file: &mut File, lazy: Option<LazyRestoreConfig>) -> Result<()> {
         let mut state_bytes = [0_u8].repeat((host_page_size() as usize) * 2 - HEADER_LENGTH);
         file.read_exact(&mut state_bytes)?;
         let locked_vmm = MIGRATION_MANAGER.vmm.read().unwrap();
-        locked_vmm
-            .memory
-            .as_ref()
-            .unwrap()
-            .restore_memory(Some(file), &state_bytes)?;
+        let memory = locked_vmm.memory.as_ref().unwrap();
+        if let Some(lazy) = lazy {
+            if Userfaultfd::is_supported() {
+                return memory.restore_memory_lazy(file, &state_bytes, lazy.prefetch);
+            }
+            warn!("Userfaultfd is not supported by host, restore snapshot memory eagerly");
+        }
+        memory.restore_memory(Some(file), &state_bytes)?;
 
         Ok(())
     }
@@ -20,6 +20,7 @@ use super::config::{
     BRIDGE_CONTROL, BRIDGE_CTL_SEC_BUS_RESET, SECONDARY_BUS_NUM, SUBORDINATE_BUS_NUM,
 };
 use super::hotplug::HotplugOps;
+use super::intx::PciIntxState;
 use super::PciDevOps;
 use anyhow::{bail, Context, Result};
 
@@ -42,6 +43,10 @@ pub struct PciBus {
     pub mem_region: Region,
     /// Hot Plug controller for obtaining hot plug ops.
     pub hotplug_controller: Option<Weak<Mutex<dyn HotplugOps>>>,
+    /// Legacy interrupt routing of the pci host, shared by all the buses.
+    pub intx_state: Option<Arc<Mutex<PciIntxState>>>,
+    /// Swizzle of INTx pins introduced by the bridges between the bus and the root bus.
+    pub intx_swizzle: u8,
 }
 
 impl PciBus {
@@ -66,6 +71,8 @@ impl PciBus {
             io_region,
             mem_region,
             hotplug_controller: None,
+            intx_state: None,
+            intx_swizzle: 0,
         }
     }
 
@@ -11,18 +11,20 @@
 // See the Mulan PSL v2 for more details.
 
 use std::collections::HashSet;
+use std::sync::atomic::Ordering;
 use std::sync::{Arc, Mutex};
 
 use address_space::Region;
 use log::{error, warn};
 
-use crate::msix::Msix;
+use crate::intx::Intx;
+use crate::msix::{Msix, MSIX_CAP_CONTROL, MSIX_CAP_ENABLE, MSIX_CAP_FUNC_MASK};
 use crate::{
-    le_read_u16, le_read_u32, le_read_u64, le_write_u16, le_write_u32, le_write_u64,
-    pci_ext_cap_next, PciBus, BDF_FUNC_SHIFT,
+    le_read_u16, le_read_u32, le_read_u64, le_write_clear_value_u16, le_write_u16, le_write_u32,
+    le_write_u64, pci_ext_cap_next, PciBus, BDF_FUNC_SHIFT,
 };
 use crate::{ranges_overlap, PciError};
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 /// Size in bytes of the configuration space of legacy PCI device.
 pub const PCI_CONFIG_SPACE_SIZE: usize = 256;
@@ -102,6 +104,7 @@ pub const IO_LIMIT: u8 = 0x1d;
 pub const PREF_MEM_BASE_UPPER: u8 = 0x28;
 const CAP_LIST: u8 = 0x34;
 const INTERRUPT_LINE: u8 = 0x3c;
+pub const INTERRUPT_PIN: u8 = 0x3d;
 pub const BRIDGE_CONTROL: u8 = 0x3e;
 
 const BRIDGE_CTL_PARITY_ENABLE: u16 = 0x0001;
@@ -383,6 +386,8 @@ pub struct PciConfig {
     pub last_ext_cap_end: u16,
     /// MSI-X information.
     pub msix: Option<Arc<Mutex<Msix>>>,
+    /// INTx information.
+    pub intx: Option<Arc<Mutex<Intx>>>,
     /// Offset of the PCI express capability.
     pub pci_express_cap_offset: u16,
 }
@@ -416,6 +421,7 @@ impl PciConfig {
             last_ext_cap_offset: 0,
             last_ext_cap_end: PCI_CONFIG_SPACE_SIZE as u16,
             msix: None,
+            intx: None,
             pci_express_cap_offset: PCI_CONFIG_HEAD_END as u16,
         }
     }
@@ -619,9 +625,29 @@ impl PciConfig {
         Ok(())
     }
 
+    /// Clear the base addresses programmed to the BARs and expansion ROM, only the read-only
+    /// type bits are kept.
+    fn reset_bar_regs(&mut self) {
+        let bar_num = if self.config[HEADER_TYPE as usize] == HEADER_TYPE_BRIDGE {
+            BAR_NUM_MAX_FOR_BRIDGE
+        } else {
+            BAR_NUM_MAX_FOR_ENDPOINT
+        };
+        let bar_end = BAR_0 as usize + REG_SIZE * bar_num as usize;
+        let rom_range = if self.config[HEADER_TYPE as usize] == HEADER_TYPE_BRIDGE {
+            ROM_ADDRESS1..ROM_ADDRESS1 + REG_SIZE
+        } else {
+            ROM_ADDRESS..ROM_ADDRESS + REG_SIZE
+        };
+        for offset in (BAR_0 as usize..bar_end).chain(rom_range) {
+            self.config[offset] &= !self.write_mask[offset];
+        }
+    }
+
     /// General reset process for pci devices
     pub fn reset(&mut self) -> Result<()> {
         self.reset_common_regs()?;
+        self.reset_bar_regs();
 
         if let Err(e) = self.update_bar_mapping(
             #[cfg(target_arch = "x86_64")]
@@ -632,7 +658,66 @@ impl PciConfig {
         }
 
         if let Some(msix) = &self.msix {
-            msix.lock().unwrap().reset();
+            let mut locked_msix = msix.lock().unwrap();
+            locked_msix.reset();
+            let offset = locked_msix.msix_cap_offset as usize + MSIX_CAP_CONTROL as usize;
+            le_write_clear_value_u16(
+                &mut self.config,
+                offset,
+                MSIX_CAP_ENABLE | MSIX_CAP_FUNC_MASK,
+            )?;
+        }
+
+        if let Some(intx) = &self.intx {
+            intx.lock().unwrap().reset();
+        }
+
+        Ok(())
+    }
+
+    /// Get the content of configuration space, which can be restored by `set_state`.
+    pub fn get_state(&self) -> Vec<u8> {
+        self.config.clone()
+    }
+
+    /// Restore the content of configuration space, the BARs and MSI-X are updated
+    /// according to it.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - Content of configuration space got by `get_state`.
+    /// * `io_region`: IO space region which the parent bridge manages.
+    /// * `mem_region`: Memory space region which the parent bridge manages.
+    pub fn set_state(
+        &mut self,
+        state: &[u8],
+        #[cfg(target_arch = "x86_64")] io_region: Option<&Region>,
+        mem_region: Option<&Region>,
+    ) -> Result<()> {
+        if state.len() != self.config.len() {
+            bail!(
+                "Invalid config space state length {}, expected {}",
+                state.len(),
+                self.config.len()
+            );
+        }
+        self.config.copy_from_slice(state);
+        self.update_bar_mapping(
+            #[cfg(target_arch = "x86_64")]
+            io_region,
+            mem_region,
+        )?;
+
+        if let Some(msix) = &self.msix {
+            let mut locked_msix = msix.lock().unwrap();
+            let offset = locked_msix.msix_cap_offset as usize + MSIX_CAP_CONTROL as usize;
+            let dev_id = locked_msix.dev_id.load(Ordering::Acquire);
+            locked_msix.write_config(
+                &self.config,
+                dev_id,
+                offset,
+                &self.config[offset..offset + 2],
+            );
         }
 
         Ok(())
@@ -853,7 +938,7 @@ impl PciConfig {
                 io_region,
                 mem_region,
             ) {
-                return Ok(());
+                continue;
             }
 
             if new_addr != BAR_SPACE_UNMAPPED {
@@ -1477,4 +1562,116 @@ mod tests {
 
         assert!(pci_config.unregister_bars(&bus).is_ok());
     }
+
+    #[test]
+    fn test_reset_and_restore_bars() {
+        let read_ops = move |_data: &mut [u8], _addr: GuestAddress, _offset: u64| -> bool { true };
+        let write_ops = move |_data: &[u8], _addr: GuestAddress, _offset: u64| -> bool { true };
+        let region_ops = RegionOps {
+            read: Arc::new(read_ops),
+            write: Arc::new(write_ops),
+        };
+        let mut pci_config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 3);
+        pci_config.init_common_write_mask().unwrap();
+        pci_config.init_common_write_clear_mask().unwrap();
+        pci_config
+            .register_bar(
+                1,
+                Region::init_io_region(8192, region_ops.clone()),
+                RegionType::Mem32Bit,
+                false,
+                8192,
+            )
+            .unwrap();
+        pci_config
+            .register_bar(
+                2,
+                Region::init_io_region(8192, region_ops),
+                RegionType::Mem64Bit,
+                true,
+                8192,
+            )
+            .unwrap();
+
+        #[cfg(target_arch = "x86_64")]
+        let sys_io = AddressSpace::new(Region::init_container_region(1 << 16)).unwrap();
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let is_mapped = |addr: u64| {
+            let mut buf = [0_u8; 4];
+            sys_mem
+                .read(&mut buf.as_mut(), GuestAddress(addr), 4)
+                .is_ok()
+        };
+        let write = |pci_config: &mut PciConfig, offset: usize, data: &[u8]| {
+            pci_config.write(
+                offset,
+                data,
+                0,
+                #[cfg(target_arch = "x86_64")]
+                Some(sys_io.root()),
+                Some(sys_mem.root()),
+            );
+        };
+
+        // Program the BARs and enable memory space.
+        write(
+            &mut pci_config,
+            BAR_0 as usize + REG_SIZE,
+            &0x10000_u32.to_le_bytes(),
+        );
+        write(
+            &mut pci_config,
+            BAR_0 as usize + 2 * REG_SIZE,
+            &0x20000_u32.to_le_bytes(),
+        );
+        write(
+            &mut pci_config,
+            COMMAND as usize,
+            &COMMAND_MEMORY_SPACE.to_le_bytes(),
+        );
+        assert_eq!(pci_config.bars[1].address, 0x10000);
+        assert_eq!(pci_config.bars[2].address, 0x20000);
+        assert!(is_mapped(0x10000));
+        assert!(is_mapped(0x20000));
+        let state = pci_config.get_state();
+
+        // All the BARs are unmapped and the config space has default values after reset.
+        pci_config.reset().unwrap();
+        assert_eq!(pci_config.bars[1].address, BAR_SPACE_UNMAPPED);
+        assert_eq!(pci_config.bars[2].address, BAR_SPACE_UNMAPPED);
+        assert!(!is_mapped(0x10000));
+        assert!(!is_mapped(0x20000));
+        let mut data = [0_u8; 4];
+        pci_config.read(COMMAND as usize, &mut data[..2]);
+        assert_eq!(le_read_u16(&data, 0).unwrap(), 0);
+        pci_config.read(BAR_0 as usize + REG_SIZE, &mut data);
+        assert_eq!(le_read_u32(&data, 0).unwrap(), 0);
+        pci_config.read(BAR_0 as usize + 2 * REG_SIZE, &mut data);
+        assert_eq!(
+            le_read_u32(&data, 0).unwrap(),
+            (BAR_MEM_64BIT | BAR_PREFETCH) as u32
+        );
+
+        // Restore the state saved before reset.
+        assert!(pci_config
+            .set_state(
+                &state[..PCI_CONFIG_HEAD_END as usize],
+                #[cfg(target_arch = "x86_64")]
+                Some(sys_io.root()),
+                Some(sys_mem.root())
+            )
+            .is_err());
+        pci_config
+            .set_state(
+                &state,
+                #[cfg(target_arch = "x86_64")]
+                Some(sys_io.root()),
+                Some(sys_mem.root()),
+            )
+            .unwrap();
+        assert_eq!(pci_config.bars[1].address, 0x10000);
+        assert_eq!(pci_config.bars[2].address, 0x20000);
+        assert!(is_mapped(0x10000));
+        assert!(is_mapped(0x20000));
+    }
 }
@@ -15,17 +15,18 @@ use std::sync::{Arc, Mutex};
 use acpi::{
     AmlAddressSpaceDecode, AmlAnd, AmlArg, AmlBuilder, AmlCacheable, AmlCreateDWordField,
     AmlDWordDesc, AmlDevice, AmlEisaId, AmlElse, AmlEqual, AmlISARanges, AmlIf, AmlInteger,
-    AmlLNot, AmlLocal, AmlMethod, AmlName, AmlNameDecl, AmlOr, AmlReadAndWrite, AmlResTemplate,
-    AmlReturn, AmlScopeBuilder, AmlStore, AmlToUuid, AmlWordDesc, AmlZero,
+    AmlLNot, AmlLocal, AmlMethod, AmlName, AmlNameDecl, AmlOr, AmlPackage, AmlReadAndWrite,
+    AmlResTemplate, AmlReturn, AmlScopeBuilder, AmlStore, AmlToUuid, AmlWordDesc, AmlZero,
 };
 #[cfg(target_arch = "x86_64")]
 use acpi::{AmlIoDecode, AmlIoResource};
 #[cfg(target_arch = "aarch64")]
 use acpi::{AmlOne, AmlQWordDesc};
 use address_space::{AddressSpace, GuestAddress, RegionOps};
-use anyhow::Context;
+use anyhow::{Context, Result};
 use sysbus::SysBusDevOps;
 
+use crate::intx::{swizzle_map_irq, PciIntxState, PCI_PIN_NUM};
 use crate::{bus::PciBus, PciDevOps};
 #[cfg(target_arch = "x86_64")]
 use crate::{le_read_u32, le_write_u32};
@@ -44,6 +45,7 @@ const CONFIG_DEVFN_MASK: u32 = 0xff;
 const ECAM_BUS_SHIFT: u32 = 20;
 const ECAM_DEVFN_SHIFT: u32 = 12;
 const ECAM_OFFSET_MASK: u64 = 0xfff;
+const PCI_SLOT_MAX: u8 = 32;
 
 #[derive(Clone)]
 pub struct PciHost {
@@ -99,6 +101,11 @@ impl PciHost {
         }
     }
 
+    /// Reset all the devices in the pci bus tree, including the bridges.
+    pub fn reset_all(&self) -> Result<()> {
+        self.root_bus.lock().unwrap().reset()
+    }
+
     pub fn find_device(&self, bus_num: u8, devfn: u8) -> Option<Arc<Mutex<dyn PciDevOps>>> {
         let locked_root_bus = self.root_bus.lock().unwrap();
         if bus_num == 0 {
@@ -251,14 +258,29 @@ impl SysBusDevOps for PciHost {
     }
 
     fn reset(&mut self) -> sysbus::Result<()> {
-        for (_id, pci_dev) in self.root_bus.lock().unwrap().devices.iter_mut() {
-            sysbus::Result::with_context(pci_dev.lock().unwrap().reset(true), || {
-                "Fail to reset pci device under pci host"
-            })?;
-        }
+        sysbus::Result::with_context(self.reset_all(), || {
+            "Fail to reset pci devices under pci host"
+        })
+    }
+}
 
-        Ok(())
+/// Build "_PRT" which routes INTx of every slot on the root bus to the interrupt lines.
+fn build_prt_for_aml(pci_host_bridge: &mut AmlDevice, intx_state: &PciIntxState) {
+    let mut prt_pkg = AmlPackage::new(PCI_SLOT_MAX * PCI_PIN_NUM);
+    for slot in 0..PCI_SLOT_MAX {
+        for pin in 0..PCI_PIN_NUM {
+            let irq_pin = swizzle_map_irq(slot << 3, pin) as u32;
+            // Package(4) {Address, Pin, Source, Source Index}, Source 0 means
+            // Source Index is the global system interrupt.
+            let mut pkg = AmlPackage::new(4);
+            pkg.append_child(AmlInteger(((slot as u64) << 16) | 0xFFFF));
+            pkg.append_child(AmlInteger(pin as u64));
+            pkg.append_child(AmlZero);
+            pkg.append_child(AmlInteger(intx_state.gsi(irq_pin) as u64));
+            prt_pkg.append_child(pkg);
+        }
     }
+    pci_host_bridge.append_child(AmlNameDecl::new("_PRT", prt_pkg));
 }
 
 #[cfg(target_arch = "x86_64")]
@@ -376,6 +398,9 @@ impl AmlBuilder for PciHost {
         }
 
         build_osc_for_aml(&mut pci_host_bridge);
+        if let Some(intx_state) = &self.root_bus.lock().unwrap().intx_state {
+            build_prt_for_aml(&mut pci_host_bridge, &intx_state.lock().unwrap());
+        }
 
         let pcie_ecam = self.pcie_ecam_range;
         let pcie_mmio = self.pcie_mmio_range;
@@ -0,0 +1,253 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::sync::{Arc, Mutex, Weak};
+
+#[cfg(target_arch = "aarch64")]
+use acpi::{INTERRUPT_PPIS_COUNT, INTERRUPT_SGIS_COUNT};
+use anyhow::Result;
+use hypervisor::kvm::KVM_FDS;
+use log::error;
+
+use crate::config::{PciConfig, INTERRUPT_PIN};
+use crate::{PciBus, BDF_FUNC_SHIFT};
+
+/// Number of legacy interrupt pins (INTA# ~ INTD#).
+pub const PCI_PIN_NUM: u8 = 4;
+
+/// Handler to set the level of an interrupt line, which is given the interrupt number
+/// of the line. It's used if the interrupt controller is emulated in userspace.
+pub type IrqLineHandler = Arc<dyn Fn(u32, bool) -> Result<()> + Send + Sync>;
+
+/// Legacy interrupt routing of the pci host. INTA# ~ INTD# of the root bus are
+/// level-triggered lines connected to `irq_base` ~ `irq_base + 3` of the interrupt controller.
+pub struct PciIntxState {
+    /// Interrupt number of INTA#: GSI on x86_64, SPI number on aarch64.
+    pub irq_base: u32,
+    /// Number of devices asserting each pin, the line is high while it is not zero.
+    irq_count: [u32; PCI_PIN_NUM as usize],
+    /// Handler of the lines, the lines of in-kernel interrupt controller are set if none.
+    irq_handler: Option<IrqLineHandler>,
+}
+
+impl PciIntxState {
+    pub fn new(irq_base: u32) -> Self {
+        Self {
+            irq_base,
+            irq_count: [0; PCI_PIN_NUM as usize],
+            irq_handler: None,
+        }
+    }
+
+    /// Route the lines to the interrupt controller emulated in userspace.
+    pub fn set_irq_handler(&mut self, handler: IrqLineHandler) {
+        self.irq_handler = Some(handler);
+    }
+
+    /// Interrupt number of the pin as seen by the guest firmware tables.
+    pub fn gsi(&self, irq_pin: u32) -> u32 {
+        #[cfg(target_arch = "x86_64")]
+        let gsi = self.irq_base + irq_pin;
+        // SPI start at interrupt number 32 on aarch64 platform.
+        #[cfg(target_arch = "aarch64")]
+        let gsi = self.irq_base + irq_pin + INTERRUPT_PPIS_COUNT + INTERRUPT_SGIS_COUNT;
+        gsi
+    }
+
+    fn change_irq_level(&mut self, irq_pin: u32, level: bool) {
+        let count = &mut self.irq_count[irq_pin as usize];
+        let old_level = *count > 0;
+        if level {
+            *count += 1;
+        } else {
+            *count = count.saturating_sub(1);
+        }
+
+        let new_level = *count > 0;
+        if old_level != new_level {
+            if let Err(e) = self.set_irq_line(irq_pin, new_level) {
+                error!("Failed to set pci irq line {}: {:?}", irq_pin, e);
+            }
+        }
+    }
+
+    fn set_irq_line(&self, irq_pin: u32, level: bool) -> Result<()> {
+        if let Some(handler) = self.irq_handler.as_ref() {
+            return handler(self.gsi(irq_pin), level);
+        }
+
+        let kvm_fds = KVM_FDS.load();
+        let vm_fd = match kvm_fds.vm_fd.as_ref() {
+            Some(fd) => fd,
+            None => return Ok(()),
+        };
+
+        #[cfg(target_arch = "x86_64")]
+        let irq = self.irq_base + irq_pin;
+        #[cfg(target_arch = "aarch64")]
+        let irq = (kvm_bindings::KVM_ARM_IRQ_TYPE_SPI << kvm_bindings::KVM_ARM_IRQ_TYPE_SHIFT)
+            | self.gsi(irq_pin);
+        vm_fd.set_irq_line(irq, level)?;
+        Ok(())
+    }
+}
+
+/// INTx# of a pci device.
+pub struct Intx {
+    /// Name of the device.
+    pub device_name: String,
+    /// Pin of the root bus which the INTx# of the device is routed to.
+    pub irq_pin: u32,
+    /// Whether the device is asserting the interrupt.
+    pub level: bool,
+    /// Interrupt routing of the pci host, none if the host has no legacy interrupt.
+    pub intx_state: Option<Arc<Mutex<PciIntxState>>>,
+}
+
+impl Intx {
+    pub fn new(
+        device_name: String,
+        irq_pin: u32,
+        intx_state: Option<Arc<Mutex<PciIntxState>>>,
+    ) -> Self {
+        Self {
+            device_name,
+            irq_pin,
+            level: false,
+            intx_state,
+        }
+    }
+
+    /// Assert or deassert the interrupt of the device.
+    pub fn notify(&mut self, level: bool) {
+        if self.level == level {
+            return;
+        }
+        self.level = level;
+
+        match &self.intx_state {
+            Some(state) => state.lock().unwrap().change_irq_level(self.irq_pin, level),
+            None => error!(
+                "Failed to notify intx of {}: no legacy interrupt in pci host",
+                self.device_name
+            ),
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.notify(false);
+    }
+}
+
+/// Swizzle the interrupt pin of a device behind a bridge to the pin of its parent bus.
+///
+/// # Arguments
+///
+/// * `devfn` - Device number << 3 | Function number of the device.
+/// * `pin` - Interrupt pin of the device, 0 for INTA#.
+pub fn swizzle_map_irq(devfn: u8, pin: u8) -> u8 {
+    ((devfn >> BDF_FUNC_SHIFT) + pin) % PCI_PIN_NUM
+}
+
+/// INTx initialization, the device uses INTA#.
+///
+/// # Arguments
+///
+/// * `name` - Name of the device.
+/// * `config` - The PCI config.
+/// * `parent_bus` - Parent bus of the device.
+/// * `devfn` - Devfn of the device.
+pub fn init_intx(
+    name: String,
+    config: &mut PciConfig,
+    parent_bus: Weak<Mutex<PciBus>>,
+    devfn: u8,
+) -> Result<()> {
+    config.config[INTERRUPT_PIN as usize] = 0x01;
+
+    let (irq_pin, intx_state) = match parent_bus.upgrade() {
+        Some(bus) => {
+            let locked_bus = bus.lock().unwrap();
+            (
+                swizzle_map_irq(devfn, locked_bus.intx_swizzle) as u32,
+                locked_bus.intx_state.clone(),
+            )
+        }
+        None => (0, None),
+    };
+    config.intx = Some(Arc::new(Mutex::new(Intx::new(name, irq_pin, intx_state))));
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::PCI_CONFIG_SPACE_SIZE;
+
+    #[test]
+    fn test_swizzle_map_irq() {
+        assert_eq!(swizzle_map_irq(0, 0), 0);
+        assert_eq!(swizzle_map_irq(1 << 3, 0), 1);
+        assert_eq!(swizzle_map_irq(3 << 3 | 2, 2), 1);
+        assert_eq!(swizzle_map_irq(31 << 3, 3), 2);
+    }
+
+    #[test]
+    fn test_intx_level() {
+        let intx_state = Arc::new(Mutex::new(PciIntxState::new(16)));
+        let root_bus = Arc::new(Mutex::new(PciBus::new(
+            "pcie.0".to_string(),
+            #[cfg(target_arch = "x86_64")]
+            address_space::Region::init_container_region(1 << 16),
+            address_space::Region::init_container_region(u64::max_value()),
+        )));
+        root_bus.lock().unwrap().intx_state = Some(intx_state.clone());
+
+        // Slot 1 and slot 5 share INTB# of the root bus.
+        let mut config1 = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 0);
+        init_intx(
+            "dev1".to_string(),
+            &mut config1,
+            Arc::downgrade(&root_bus),
+            1 << 3,
+        )
+        .unwrap();
+        let mut config2 = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 0);
+        init_intx(
+            "dev2".to_string(),
+            &mut config2,
+            Arc::downgrade(&root_bus),
+            5 << 3,
+        )
+        .unwrap();
+        assert_eq!(config1.config[INTERRUPT_PIN as usize], 1);
+        let intx1 = config1.intx.unwrap();
+        let intx2 = config2.intx.unwrap();
+        assert_eq!(intx1.lock().unwrap().irq_pin, 1);
+        assert_eq!(intx2.lock().unwrap().irq_pin, 1);
+
+        // Asserting twice from the same device counts once.
+        intx1.lock().unwrap().notify(true);
+        intx1.lock().unwrap().notify(true);
+        assert_eq!(intx_state.lock().unwrap().irq_count[1], 1);
+        intx2.lock().unwrap().notify(true);
+        assert_eq!(intx_state.lock().unwrap().irq_count[1], 2);
+
+        // The shared line stays high until the last device deasserts it.
+        intx1.lock().unwrap().reset();
+        assert_eq!(intx_state.lock().unwrap().irq_count[1], 1);
+        intx2.lock().unwrap().notify(false);
+        intx2.lock().unwrap().notify(false);
+        assert_eq!(intx_state.lock().unwrap().irq_count[1], 0);
+    }
+}
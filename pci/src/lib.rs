@@ -15,6 +15,7 @@ pub use error::PciError;
 pub mod config;
 pub mod demo_dev;
 pub mod hotplug;
+pub mod intx;
 pub mod msix;
 
 mod bus;
@@ -24,6 +25,7 @@ mod root_port;
 
 pub use bus::PciBus;
 pub use host::PciHost;
+pub use intx::{init_intx, IrqLineHandler, PciIntxState};
 pub use msix::init_msix;
 pub use root_port::RootPort;
 use util::AsAny;
@@ -175,6 +177,20 @@ pub trait PciDevOps: Send + AsAny {
         Ok(())
     }
 
+    /// Get the content of configuration space.
+    fn get_config_state(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restore the configuration space by the content got from `get_config_state`.
+    ///
+    /// # Arguments
+    ///
+    /// * `state` - Content of configuration space.
+    fn set_config_state(&mut self, _state: &[u8]) -> Result<()> {
+        bail!("Restoring config space of {} is not supported", self.name());
+    }
+
     /// Get device devfn
     fn devfn(&self) -> Option<u8> {
         None
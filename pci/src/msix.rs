@@ -22,11 +22,9 @@ use migration::{
     DeviceStateDesc, FieldDesc, MigrationError, MigrationHook, MigrationManager, StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
-use util::{
-    byte_code::ByteCode,
-    num_ops::round_up,
-    test_helper::{add_msix_msg, is_test_enabled},
-};
+#[cfg(not(test))]
+use util::test_helper::{add_msix_msg, is_test_enabled};
+use util::{byte_code::ByteCode, num_ops::round_up};
 use vmm_sys_util::eventfd::EventFd;
 
 use crate::config::{CapId, PciConfig, RegionType, MINMUM_BAR_SIZE_FOR_MMIO, SECONDARY_BUS_NUM};
@@ -118,7 +116,7 @@ impl Msix {
             table: vec![0; table_size as usize],
             pba: vec![0; pba_size as usize],
             func_masked: true,
-            enabled: true,
+            enabled: false,
             msix_cap_offset,
             dev_id,
             gsi_msi_routes: HashMap::new(),
@@ -128,10 +126,10 @@ impl Msix {
     }
 
     pub fn reset(&mut self) {
-        self.table.resize_with(self.table.len(), || 0);
-        self.pba.resize_with(self.pba.len(), || 0);
+        self.table.fill(0);
+        self.pba.fill(0);
         self.func_masked = true;
-        self.enabled = true;
+        self.enabled = false;
         self.mask_all_vectors();
     }
 
@@ -368,7 +366,7 @@ impl Msix {
             let vector: u16 = offset as u16 / MSIX_TABLE_ENTRY_SIZE;
             let was_masked: bool = locked_msix.is_vector_masked(vector);
             let offset = offset as usize;
-            locked_msix.table[offset..(offset + 4)].copy_from_slice(data);
+            locked_msix.table[offset..(offset + data.len())].copy_from_slice(data);
 
             let is_masked: bool = locked_msix.is_vector_masked(vector);
             if was_masked != is_masked && locked_msix.update_irq_routing(vector, is_masked).is_err()
@@ -589,6 +587,12 @@ fn is_msix_func_masked(msix_cap_offset: usize, config: &[u8]) -> bool {
     false
 }
 
+#[cfg(test)]
+fn send_msix(msg: Message, _dev_id: u16) {
+    tests::MSIX_SINK.with(|sink| sink.borrow_mut().push(msg));
+}
+
+#[cfg(not(test))]
 fn send_msix(msg: Message, dev_id: u16) {
     #[cfg(target_arch = "aarch64")]
     let flags: u32 = kvm_bindings::KVM_MSI_VALID_DEVID;
@@ -720,10 +724,63 @@ pub fn update_dev_id(parent_bus: &Weak<Mutex<PciBus>>, devfn: u8, dev_id: &Arc<A
 }
 
 #[cfg(test)]
-mod tests {
+pub mod tests {
+    use std::cell::RefCell;
+
+    use address_space::AddressSpace;
+
     use super::*;
     use crate::config::PCI_CONFIG_SPACE_SIZE;
 
+    thread_local! {
+        /// Fake interrupt sink which records the MSI-X messages sent in unit tests.
+        pub static MSIX_SINK: RefCell<Vec<Message>> = RefCell::new(Vec::new());
+    }
+
+    fn sent_msix_msgs() -> Vec<u32> {
+        MSIX_SINK.with(|sink| sink.borrow_mut().drain(..).map(|msg| msg.data).collect())
+    }
+
+    fn create_msix_space(nr_vector: u32) -> (Arc<AddressSpace>, PciConfig) {
+        let space = AddressSpace::new(Region::init_container_region(0x1000)).unwrap();
+        let mut pci_config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 2);
+        init_msix(
+            0,
+            nr_vector,
+            &mut pci_config,
+            Arc::new(AtomicU16::new(0)),
+            "msix",
+            Some(space.root()),
+            Some((0, 0x800)),
+        )
+        .unwrap();
+        (space, pci_config)
+    }
+
+    fn write_msix_control(pci_config: &mut PciConfig, ctl: u16) {
+        let msix = pci_config.msix.as_ref().unwrap().clone();
+        let mut locked_msix = msix.lock().unwrap();
+        let offset = locked_msix.msix_cap_offset as usize + MSIX_CAP_CONTROL as usize;
+        let val = le_read_u16(&pci_config.config, offset).unwrap();
+        let val = (val & !(MSIX_CAP_ENABLE | MSIX_CAP_FUNC_MASK)) | ctl;
+        le_write_u16(&mut pci_config.config, offset, val).unwrap();
+        locked_msix.write_config(&pci_config.config, 0, offset, &val.to_le_bytes());
+    }
+
+    fn space_write(space: &AddressSpace, addr: u64, data: &[u8]) {
+        space
+            .write(&mut &data[..], GuestAddress(addr), data.len() as u64)
+            .unwrap();
+    }
+
+    fn space_read_u64(space: &AddressSpace, addr: u64) -> u64 {
+        let mut buf = [0_u8; 8];
+        space
+            .read(&mut buf.as_mut(), GuestAddress(addr), 8)
+            .unwrap();
+        u64::from_le_bytes(buf)
+    }
+
     #[test]
     fn test_init_msix() {
         let mut pci_config = PciConfig::new(PCI_CONFIG_SPACE_SIZE, 2);
@@ -799,6 +856,7 @@ mod tests {
                 > 0
         );
         assert!(msix.is_vector_masked(0));
+        msix.enabled = true;
         msix.func_masked = false;
         assert!(msix.is_vector_masked(1));
         msix.table[(MSIX_TABLE_ENTRY_SIZE + MSIX_TABLE_VEC_CTL) as usize] &= !MSIX_TABLE_MASK_BIT;
@@ -868,4 +926,97 @@ mod tests {
         assert!(!locked_msix.func_masked);
         assert!(locked_msix.enabled);
     }
+
+    #[test]
+    fn test_table_read_write() {
+        let (space, _pci_config) = create_msix_space(2);
+        let entry = MSIX_TABLE_ENTRY_SIZE as u64;
+
+        // All vectors are masked after initialization.
+        let mut vec_ctl = [0_u8; 4];
+        space
+            .read(
+                &mut vec_ctl.as_mut(),
+                GuestAddress(entry + MSIX_TABLE_VEC_CTL as u64),
+                4,
+            )
+            .unwrap();
+        assert_eq!(vec_ctl, [MSIX_TABLE_MASK_BIT, 0, 0, 0]);
+
+        // Dword and qword accesses.
+        space_write(&space, entry, &0xfee0_1000_u32.to_le_bytes());
+        space_write(
+            &space,
+            entry + MSIX_MSG_DATA as u64,
+            &0x41_u32.to_le_bytes(),
+        );
+        assert_eq!(space_read_u64(&space, entry), 0xfee0_1000);
+        space_write(&space, entry, &0x1_fee0_2000_u64.to_le_bytes());
+        assert_eq!(space_read_u64(&space, entry), 0x1_fee0_2000);
+
+        // Sub-dword write only touches the written bytes.
+        space_write(&space, entry + MSIX_MSG_DATA as u64 + 1, &[0x12]);
+        let mut data = [0_u8; 4];
+        space
+            .read(
+                &mut data.as_mut(),
+                GuestAddress(entry + MSIX_MSG_DATA as u64),
+                4,
+            )
+            .unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x1241);
+
+        // PBA is read only.
+        space_write(&space, 0x800, &u64::MAX.to_le_bytes());
+        assert_eq!(space_read_u64(&space, 0x800), 0);
+    }
+
+    #[test]
+    fn test_pending_bit() {
+        let (space, mut pci_config) = create_msix_space(2);
+        let msix = pci_config.msix.as_ref().unwrap().clone();
+        let vec_ctl = MSIX_TABLE_ENTRY_SIZE as u64 + MSIX_TABLE_VEC_CTL as u64;
+        space_write(
+            &space,
+            MSIX_TABLE_ENTRY_SIZE as u64 + MSIX_MSG_DATA as u64,
+            &0x42_u32.to_le_bytes(),
+        );
+
+        // Interrupt of a masked vector only sets the pending bit.
+        write_msix_control(&mut pci_config, MSIX_CAP_ENABLE);
+        msix.lock().unwrap().notify(1, 0);
+        assert_eq!(space_read_u64(&space, 0x800), 0b10);
+        assert!(sent_msix_msgs().is_empty());
+
+        // Unmasking the vector delivers the pending interrupt and clears the bit.
+        space_write(&space, vec_ctl, &0_u32.to_le_bytes());
+        assert_eq!(sent_msix_msgs(), vec![0x42]);
+        assert_eq!(space_read_u64(&space, 0x800), 0);
+
+        // Unmasked vector is delivered directly.
+        msix.lock().unwrap().notify(1, 0);
+        assert_eq!(sent_msix_msgs(), vec![0x42]);
+        assert_eq!(space_read_u64(&space, 0x800), 0);
+
+        // Function mask holds the interrupt pending until it is cleared.
+        write_msix_control(&mut pci_config, MSIX_CAP_ENABLE | MSIX_CAP_FUNC_MASK);
+        msix.lock().unwrap().notify(1, 0);
+        assert_eq!(space_read_u64(&space, 0x800), 0b10);
+        assert!(sent_msix_msgs().is_empty());
+        write_msix_control(&mut pci_config, MSIX_CAP_ENABLE);
+        assert_eq!(sent_msix_msgs(), vec![0x42]);
+        assert_eq!(space_read_u64(&space, 0x800), 0);
+
+        // Masking a vector with an interrupt pending keeps the bit set.
+        space_write(&space, vec_ctl, &1_u32.to_le_bytes());
+        msix.lock().unwrap().notify(1, 0);
+        space_write(&space, vec_ctl, &1_u32.to_le_bytes());
+        assert_eq!(space_read_u64(&space, 0x800), 0b10);
+        assert!(sent_msix_msgs().is_empty());
+
+        // Reset clears the pending bits.
+        msix.lock().unwrap().reset();
+        assert_eq!(space_read_u64(&space, 0x800), 0);
+        assert!(!msix.lock().unwrap().enabled);
+    }
 }
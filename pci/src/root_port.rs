@@ -30,14 +30,16 @@ use super::config::{
     DEVICE_ID, HEADER_TYPE, HEADER_TYPE_BRIDGE, IO_BASE, MEMORY_BASE, PCIE_CONFIG_SPACE_SIZE,
     PCI_EXP_HP_EV_ABP, PCI_EXP_HP_EV_CCI, PCI_EXP_HP_EV_PDC, PCI_EXP_LNKSTA,
     PCI_EXP_LNKSTA_CLS_2_5GB, PCI_EXP_LNKSTA_DLLLA, PCI_EXP_LNKSTA_NLW_X1, PCI_EXP_SLOTSTA_EVENTS,
-    PCI_EXP_SLTCTL, PCI_EXP_SLTCTL_PCC, PCI_EXP_SLTCTL_PIC, PCI_EXP_SLTCTL_PWR_IND_BLINK,
-    PCI_EXP_SLTCTL_PWR_IND_OFF, PCI_EXP_SLTCTL_PWR_IND_ON, PCI_EXP_SLTCTL_PWR_OFF, PCI_EXP_SLTSTA,
-    PCI_EXP_SLTSTA_PDC, PCI_EXP_SLTSTA_PDS, PCI_VENDOR_ID_REDHAT, PREF_MEMORY_BASE,
-    PREF_MEMORY_LIMIT, PREF_MEM_RANGE_64BIT, SUB_CLASS_CODE, VENDOR_ID,
+    PCI_EXP_SLTCTL, PCI_EXP_SLTCTL_HPIE, PCI_EXP_SLTCTL_PCC, PCI_EXP_SLTCTL_PIC,
+    PCI_EXP_SLTCTL_PWR_IND_BLINK, PCI_EXP_SLTCTL_PWR_IND_OFF, PCI_EXP_SLTCTL_PWR_IND_ON,
+    PCI_EXP_SLTCTL_PWR_OFF, PCI_EXP_SLTSTA, PCI_EXP_SLTSTA_PDC, PCI_EXP_SLTSTA_PDS,
+    PCI_VENDOR_ID_REDHAT, PREF_MEMORY_BASE, PREF_MEMORY_LIMIT, PREF_MEM_RANGE_64BIT,
+    SUB_CLASS_CODE, VENDOR_ID,
 };
 use crate::bus::PciBus;
 use crate::config::{BRIDGE_CONTROL, BRIDGE_CTL_SEC_BUS_RESET};
 use crate::hotplug::HotplugOps;
+use crate::intx::swizzle_map_irq;
 use crate::msix::init_msix;
 use crate::{init_multifunction, PciError};
 use crate::{
@@ -129,7 +131,24 @@ impl RootPort {
         }
     }
 
+    /// Whether a hotplug interrupt should be raised, i.e. the guest has enabled hotplug
+    /// interrupts and at least one of the enabled slot events is pending.
+    fn hotplug_event_pending(&self) -> bool {
+        let cap_offset = self.config.pci_express_cap_offset;
+        let sltctl =
+            le_read_u16(&self.config.config, (cap_offset + PCI_EXP_SLTCTL) as usize).unwrap();
+        let sltsta =
+            le_read_u16(&self.config.config, (cap_offset + PCI_EXP_SLTSTA) as usize).unwrap();
+        let events = PCI_EXP_HP_EV_ABP | PCI_EXP_HP_EV_PDC | PCI_EXP_HP_EV_CCI;
+
+        sltctl & PCI_EXP_SLTCTL_HPIE != 0 && (sltctl & sltsta & events) != 0
+    }
+
     fn hotplug_event_notify(&mut self) {
+        if !self.hotplug_event_pending() {
+            return;
+        }
+
         if let Some(msix) = self.config.msix.as_mut() {
             msix.lock()
                 .unwrap()
@@ -338,6 +357,12 @@ impl PciDevOps for RootPort {
 
         let parent_bus = self.parent_bus.upgrade().unwrap();
         let mut locked_parent_bus = parent_bus.lock().unwrap();
+        {
+            let mut locked_sec_bus = self.sec_bus.lock().unwrap();
+            locked_sec_bus.intx_state = locked_parent_bus.intx_state.clone();
+            locked_sec_bus.intx_swizzle =
+                swizzle_map_irq(self.devfn, locked_parent_bus.intx_swizzle);
+        }
         #[cfg(target_arch = "x86_64")]
         locked_parent_bus
             .io_region
@@ -617,6 +642,7 @@ impl MigrationHook for RootPort {}
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::{PCI_EXP_SLTCTL_PDCE, PRIMARY_BUS_NUM, SECONDARY_BUS_NUM};
     use crate::host::tests::create_pci_host;
 
     #[test]
@@ -656,4 +682,116 @@ mod tests {
             .read_config(PCIE_CONFIG_SPACE_SIZE - 1, &mut buf);
         assert_eq!(buf, [0_u8]);
     }
+
+    #[test]
+    fn test_bus_number_assignment() {
+        let pci_host = create_pci_host();
+        let root_bus = Arc::downgrade(&pci_host.lock().unwrap().root_bus);
+        let root_port = RootPort::new("pcie.1".to_string(), 8, 0, root_bus, false);
+        root_port.realize().unwrap();
+        let root_port = pci_host.lock().unwrap().find_device(0, 8).unwrap();
+
+        // Guest firmware enumerates the root port and assigns bus 1 to its secondary side.
+        root_port
+            .lock()
+            .unwrap()
+            .write_config(PRIMARY_BUS_NUM as usize, &[0, 1, 1]);
+        let mut buf = [0_u8; 2];
+        root_port
+            .lock()
+            .unwrap()
+            .read_config(SECONDARY_BUS_NUM as usize, &mut buf);
+        assert_eq!(buf, [1, 1]);
+
+        let root_bus = pci_host.lock().unwrap().root_bus.clone();
+        let sec_bus = PciBus::find_bus_by_name(&root_bus, "pcie.1").unwrap();
+        assert!(PciBus::find_bus_by_num(&sec_bus, 1).is_some());
+        assert!(PciBus::find_bus_by_num(&sec_bus, 2).is_none());
+        assert!(pci_host.lock().unwrap().find_device(1, 0).is_none());
+
+        // Renumber the secondary bus, the old number no longer reaches it.
+        root_port
+            .lock()
+            .unwrap()
+            .write_config(SECONDARY_BUS_NUM as usize, &[2, 2]);
+        assert!(PciBus::find_bus_by_num(&sec_bus, 1).is_none());
+        assert!(PciBus::find_bus_by_num(&sec_bus, 2).is_some());
+    }
+
+    #[test]
+    fn test_bridge_window() {
+        let pci_host = create_pci_host();
+        let root_bus = Arc::downgrade(&pci_host.lock().unwrap().root_bus);
+        let root_port = RootPort::new("pcie.1".to_string(), 8, 0, root_bus, false);
+        root_port.realize().unwrap();
+        let root_port = pci_host.lock().unwrap().find_device(0, 8).unwrap();
+
+        // Memory window 0x1000_0000 - 0x100f_ffff, the low 4 bits are read-only.
+        root_port
+            .lock()
+            .unwrap()
+            .write_config(MEMORY_BASE as usize, &[0x0f, 0x10, 0x0f, 0x10]);
+        let mut buf = [0_u8; 4];
+        root_port
+            .lock()
+            .unwrap()
+            .read_config(MEMORY_BASE as usize, &mut buf);
+        assert_eq!(buf, [0x00, 0x10, 0x00, 0x10]);
+
+        // Prefetchable window keeps advertising 64-bit decoding.
+        root_port
+            .lock()
+            .unwrap()
+            .write_config(PREF_MEMORY_BASE as usize, &[0xf0, 0x20, 0xf0, 0x2f]);
+        root_port
+            .lock()
+            .unwrap()
+            .read_config(PREF_MEMORY_BASE as usize, &mut buf);
+        assert_eq!(buf, [0xf1, 0x20, 0xf1, 0x2f]);
+    }
+
+    #[test]
+    fn test_hotplug_event() {
+        let pci_host = create_pci_host();
+        let root_bus = Arc::downgrade(&pci_host.lock().unwrap().root_bus);
+        let mut root_port = RootPort::new("pcie.1".to_string(), 8, 0, root_bus, false);
+        root_port.init_write_mask().unwrap();
+        root_port.init_write_clear_mask().unwrap();
+        root_port
+            .config
+            .add_pcie_cap(8, 0, PcieDevType::RootPort as u8)
+            .unwrap();
+        let cap_offset = root_port.config.pci_express_cap_offset as usize;
+        let sltctl = cap_offset + PCI_EXP_SLTCTL as usize;
+        let sltsta = cap_offset + PCI_EXP_SLTSTA as usize;
+
+        le_write_set_value_u16(
+            &mut root_port.config.config,
+            sltsta,
+            PCI_EXP_SLTSTA_PDS | PCI_EXP_HP_EV_PDC,
+        )
+        .unwrap();
+        // Hotplug interrupt is not enabled by the guest yet.
+        assert!(!root_port.hotplug_event_pending());
+
+        // Only the presence detect event is enabled.
+        le_write_u16(
+            &mut root_port.config.config,
+            sltctl,
+            PCI_EXP_SLTCTL_HPIE | PCI_EXP_SLTCTL_PDCE,
+        )
+        .unwrap();
+        assert!(root_port.hotplug_event_pending());
+
+        // Guest acknowledges the event by writing 1 to clear.
+        root_port.write_config(sltsta, &PCI_EXP_HP_EV_PDC.to_le_bytes());
+        let status = le_read_u16(&root_port.config.config, sltsta).unwrap();
+        assert_eq!(status & PCI_EXP_HP_EV_PDC, 0);
+        assert_eq!(status & PCI_EXP_SLTSTA_PDS, PCI_EXP_SLTSTA_PDS);
+        assert!(!root_port.hotplug_event_pending());
+
+        // Attention button event is pending but not enabled.
+        le_write_set_value_u16(&mut root_port.config.config, sltsta, PCI_EXP_HP_EV_ABP).unwrap();
+        assert!(!root_port.hotplug_event_pending());
+    }
 }
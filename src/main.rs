@@ -11,26 +11,32 @@
 // See the Mulan PSL v2 for more details.
 
 use std::io::Write;
-use std::os::unix::fs::OpenOptionsExt;
 use std::sync::{Arc, Mutex};
 
 use anyhow::{bail, Context, Result};
 use log::{error, info};
 use machine::{LightMachine, MachineOps, StdMachine};
 use machine_manager::{
-    cmdline::{check_api_channel, create_args_parser, create_vmconfig},
-    config::MachineType,
-    config::VmConfig,
+    cmdline::{check_api_channel, create_args_parser, create_vmconfig, dump_options_json},
+    config::{dump_vmconfig, parse_log_config, MachineType, VmConfig},
     event_loop::EventLoop,
     qmp::QmpChannel,
-    signal_handler::{exit_with_code, register_kill_signal, VM_EXIT_GENE_ERR},
+    signal_handler::{
+        exit_with_code, register_kill_signal, register_log_reopen_signal, VM_EXIT_GENE_ERR,
+    },
     socket::Socket,
     temp_cleaner::TempCleaner,
     test_server::TestSock,
 };
+use util::aio::start_discard_worker;
+use util::daemonize::{daemonize, notify_daemon_error, notify_daemon_ready};
+use util::logger::{LogFilter, RotatingFile};
 use util::loop_context::EventNotifierHelper;
+use util::tap::start_tap_script_helper;
 use util::test_helper::{is_test_enabled, set_test_enabled};
-use util::{arg_parser, daemonize::daemonize, logger, set_termi_canon_mode};
+use util::throttle_group::register_throttle_group;
+use util::unix::set_thread_name;
+use util::{arg_parser, logger, set_termi_canon_mode};
 
 use thiserror::Error;
 
@@ -90,27 +96,16 @@ fn main() {
 fn run() -> Result<()> {
     let cmd_args = create_args_parser().get_matches()?;
 
+    if cmd_args.is_present("dump-options-json") {
+        println!("{}", dump_options_json()?);
+        return Ok(());
+    }
+
     if cmd_args.is_present("mod-test") {
         set_test_enabled();
     }
 
-    if let Some(logfile_path) = cmd_args.value_of("display log") {
-        if logfile_path.is_empty() {
-            logger::init_logger_with_env(Some(Box::new(std::io::stdout())))
-                .with_context(|| "Failed to init logger.")?;
-        } else {
-            let logfile = std::fs::OpenOptions::new()
-                .read(false)
-                .write(true)
-                .append(true)
-                .create(true)
-                .mode(0o640)
-                .open(logfile_path)
-                .with_context(|| "Failed to open log file")?;
-            logger::init_logger_with_env(Some(Box::new(logfile)))
-                .with_context(|| "Failed to init logger.")?;
-        }
-    }
+    init_log(&cmd_args)?;
 
     std::panic::set_hook(Box::new(|panic_msg| {
         set_termi_canon_mode().expect("Failed to set terminal to canonical mode.");
@@ -128,7 +123,15 @@ fn run() -> Result<()> {
         exit_with_code(VM_EXIT_GENE_ERR);
     }));
 
-    let mut vm_config: VmConfig = create_vmconfig(&cmd_args)?;
+    let mut vm_config: VmConfig = create_vmconfig(&cmd_args).map_err(|e| {
+        // Config errors land in the log file too.
+        error!("{:?}", e);
+        e
+    })?;
+    if cmd_args.is_present("dump-config") {
+        println!("{}", dump_vmconfig(&vm_config)?);
+        return Ok(());
+    }
     info!("VmConfig is {:?}", vm_config);
 
     match real_main(&cmd_args, &mut vm_config) {
@@ -139,6 +142,7 @@ fn run() -> Result<()> {
         }
         Err(ref e) => {
             set_termi_canon_mode().expect("Failed to set terminal to canonical mode.");
+            notify_daemon_error(&format!("{:?}", e));
             if cmd_args.is_present("display log") {
                 error!("{}", format!("{:?}\r\n", e));
             } else {
@@ -154,6 +158,34 @@ fn run() -> Result<()> {
     Ok(())
 }
 
+fn init_log(cmd_args: &arg_parser::ArgMatches) -> Result<()> {
+    let filter = match cmd_args.value_of("log-level") {
+        Some(spec) => Some(
+            spec.parse::<LogFilter>()
+                .with_context(|| "Failed to parse log level")?,
+        ),
+        None => None,
+    };
+    let logfile: Box<dyn Write + Send> = match cmd_args.value_of("display log") {
+        Some(log_config) if !log_config.is_empty() => {
+            let config = parse_log_config(&log_config)?;
+            let file = RotatingFile::new(&config.path, config.max_size, config.max_files)
+                .with_context(|| "Failed to open log file")?;
+            register_log_reopen_signal();
+            Box::new(file)
+        }
+        Some(_) => Box::new(std::io::stdout()),
+        None if filter.is_some() => Box::new(std::io::stderr()),
+        None => return Ok(()),
+    };
+
+    match filter {
+        Some(filter) => logger::init_logger(filter, Some(logfile)),
+        None => logger::init_logger_with_env(Some(logfile)),
+    }
+    .with_context(|| "Failed to init logger.")
+}
+
 fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Result<()> {
     TempCleaner::object_init();
 
@@ -171,8 +203,22 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
         bail!("-pidfile must be used with -daemonize together.");
     }
 
+    if let Some(process_name) = &vm_config.process_name {
+        set_thread_name(process_name).with_context(|| "Failed to set process name")?;
+    }
+
+    // The tap scripts can't be executed under the seccomp filter, so the helper running
+    // them is forked before any other thread is created.
+    start_tap_script_helper(vm_config.tap_scripts())
+        .with_context(|| "Failed to start the tap script helper")?;
+
     QmpChannel::object_init();
+    QmpChannel::set_compat_policy(vm_config.qmp_compat);
+    machine::register_machine_types();
     EventLoop::object_init(&vm_config.iothreads)?;
+    for group in vm_config.object.throttle_group.values() {
+        register_throttle_group(&group.id, group.iops)?;
+    }
     register_kill_signal();
 
     let listeners = check_api_channel(cmd_args, vm_config)?;
@@ -233,6 +279,7 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
             vm
         }
     };
+    QmpChannel::set_vm_state(vm.lock().unwrap().get_vm_state().clone());
 
     for socket in sockets {
         EventLoop::update_event(
@@ -244,13 +291,16 @@ fn real_main(cmd_args: &arg_parser::ArgMatches, vm_config: &mut VmConfig) -> Res
 
     machine::vm_run(&vm, cmd_args).with_context(|| "Failed to start VM.")?;
 
+    start_discard_worker().with_context(|| "Failed to start the discard worker")?;
     let balloon_switch_on = vm_config.dev_name.get("balloon").is_some();
-    if !cmd_args.is_present("disable-seccomp") {
-        vm.lock()
-            .unwrap()
-            .register_seccomp(balloon_switch_on)
-            .with_context(|| "Failed to register seccomp rules.")?;
-    }
+    vm.lock()
+        .unwrap()
+        .register_seccomp(balloon_switch_on, vm_config.machine_config.seccomp_mode)
+        .with_context(|| "Failed to register seccomp rules.")?;
+
+    // VM is ready and the QMP sockets are listening, so the original process
+    // can exit if StratoVirt is daemonized.
+    notify_daemon_ready();
 
     EventLoop::loop_run().with_context(|| "MainLoop exits unexpectedly: error occurs")?;
     Ok(())
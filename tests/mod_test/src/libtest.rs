@@ -110,6 +110,9 @@ impl TestState {
         let resp: Value =
             serde_json::from_slice(self.qmp_sock.read_line(timeout).as_bytes()).unwrap();
         assert!(resp.get("QMP").is_some());
+        // Events are only sent to the negotiated client.
+        let resp = self.qmp("{\"execute\": \"qmp_capabilities\"}");
+        assert!(resp.get("return").is_some());
     }
 
     pub fn wait_qmp_event(&self) -> Value {
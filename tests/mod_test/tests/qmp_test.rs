@@ -0,0 +1,66 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+#[cfg(target_arch = "aarch64")]
+use serde_json::json;
+
+#[cfg(target_arch = "aarch64")]
+use mod_test::libtest::{test_init, TestState};
+
+#[cfg(target_arch = "aarch64")]
+fn set_up() -> TestState {
+    let args: Vec<&str> = "-machine virt -drive file=/usr/share/edk2/aarch64/QEMU_EFI-pflash.raw,if=pflash,unit=0,readonly=true"
+        .split(' ')
+        .collect();
+    test_init(args)
+}
+
+/// Query the target, machine types and cpu models which are used by libvirt to probe the
+/// capabilities.
+///
+/// Steps
+/// 1. Send qmp command "query-target", expect "aarch64".
+/// 2. Send qmp command "query-machines", expect "none", "microvm" as the default machine
+///    and "virt" with 254 vCPUs at most.
+/// 3. Send qmp command "query-cpu-definitions", expect the "host" model.
+#[test]
+#[cfg(target_arch = "aarch64")]
+fn test_query_target_machines_cpu_definitions() {
+    let mut ts = set_up();
+
+    let value = ts.qmp("{\"execute\": \"query-target\"}");
+    assert_eq!(value, json!({"return": {"arch": "aarch64"}}));
+
+    let value = ts.qmp("{\"execute\": \"query-machines\"}");
+    assert_eq!(
+        value,
+        json!({"return": [
+            {"hotpluggable-cpus": false, "name": "none", "numa-mem-supported": false,
+             "cpu-max": 1, "deprecated": false, "is-default": false},
+            {"hotpluggable-cpus": false, "name": "microvm", "numa-mem-supported": false,
+             "cpu-max": 254, "deprecated": false, "is-default": true},
+            {"hotpluggable-cpus": false, "name": "virt", "numa-mem-supported": true,
+             "cpu-max": 254, "deprecated": false, "is-default": false}
+        ]})
+    );
+
+    let value = ts.qmp("{\"execute\": \"query-cpu-definitions\"}");
+    assert_eq!(
+        value,
+        json!({"return": [
+            {"name": "host", "typename": "host-aarch64-cpu", "static": false,
+             "migration-safe": false, "unavailable-features": [], "deprecated": false}
+        ]})
+    );
+
+    ts.stop();
+}
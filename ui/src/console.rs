@@ -12,9 +12,9 @@
 
 use crate::pixman::{
     create_pixman_image, get_image_height, get_image_width, pixman_glyph_from_vgafont,
-    pixman_glyph_render, unref_pixman_image, ColorNames, COLOR_TABLE_RGB,
+    pixman_glyph_render, ref_pixman_image, unref_pixman_image, ColorNames, COLOR_TABLE_RGB,
 };
-use anyhow::Result;
+use anyhow::{bail, Result};
 use log::error;
 use machine_manager::event_loop::EventLoop;
 use once_cell::sync::Lazy;
@@ -102,6 +102,17 @@ pub trait DisplayChangeListenerOperations {
 pub trait HardWareOperations {
     /// Update image.
     fn hw_update(&self, _con: Arc<Mutex<DisplayConsole>>) {}
+    /// The UI requests the display size to be changed to `width` x `height`.
+    /// The device is expected to notify the guest, which decides whether to
+    /// switch to the requested mode.
+    fn hw_ui_info(
+        &self,
+        _con: Arc<Mutex<DisplayConsole>>,
+        _width: u32,
+        _height: u32,
+    ) -> Result<()> {
+        bail!("The display device does not support resizing")
+    }
 }
 
 /// Listen to the change of image and call the related
@@ -425,6 +436,27 @@ pub fn graphic_hardware_update(con_id: Option<usize>) {
     }
 }
 
+/// Forward the display size requested by the UI to the graphic hardware.
+///
+/// # Arguments
+///
+/// * `con_id` - console id in console list, the activate console is used if it is none.
+/// * `width` - requested width of the display.
+/// * `height` - requested height of the display.
+pub fn graphic_hardware_ui_info(con_id: Option<usize>, width: u32, height: u32) -> Result<()> {
+    if !(1..=MAX_WINDOW_WIDTH as u32).contains(&width)
+        || !(1..=MAX_WINDOW_HEIGHT as u32).contains(&height)
+    {
+        bail!("The requested display size {}x{} is invalid", width, height);
+    }
+    let console = match CONSOLES.lock().unwrap().get_console_by_id(con_id) {
+        Some(con) => con,
+        None => bail!("No display device is found"),
+    };
+    let con_opts = console.lock().unwrap().dev_opts.clone();
+    (*con_opts).hw_ui_info(console, width, height)
+}
+
 /// Register a dcl and return the id.
 pub fn register_display(dcl: &Arc<Mutex<DisplayChangeListener>>) -> Result<()> {
     let mut dcl_id = 0;
@@ -617,6 +649,19 @@ pub fn console_select(con_id: Option<usize>) -> Result<()> {
     display_graphic_update(&Some(Arc::downgrade(&con)), 0, 0, width, height)
 }
 
+/// Get a reference to the image of the active console, which is released by
+/// `unref_pixman_image`. The reference is taken under the lock of the console,
+/// so the image stays valid even if the surface is replaced later.
+pub fn console_ref_surface() -> Option<*mut pixman_image_t> {
+    let con = CONSOLES.lock().unwrap().get_console_by_id(None)?;
+    let locked_con = con.lock().unwrap();
+    let image = ref_pixman_image(locked_con.surface.as_ref()?.image);
+    if image.is_null() {
+        return None;
+    }
+    Some(image)
+}
+
 /// Create a default image to display messages.
 ///
 /// # Arguments
@@ -625,8 +670,8 @@ pub fn console_select(con_id: Option<usize>) -> Result<()> {
 /// * `height` - height of image.
 /// * `msg` - test messages showed in display.
 fn create_msg_surface(width: i32, height: i32, msg: String) -> Option<DisplaySurface> {
-    if !(0..MAX_WINDOW_WIDTH as i32).contains(&width)
-        || !(0..MAX_WINDOW_HEIGHT as i32).contains(&height)
+    if !(0..=MAX_WINDOW_WIDTH as i32).contains(&width)
+        || !(0..=MAX_WINDOW_HEIGHT as i32).contains(&height)
     {
         error!("The size of image is invalid!");
         return None;
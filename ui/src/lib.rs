@@ -15,5 +15,6 @@ mod data;
 pub mod error;
 pub mod input;
 pub mod pixman;
+pub mod screendump;
 pub mod utils;
 pub mod vnc;
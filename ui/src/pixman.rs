@@ -236,6 +236,106 @@ pub fn pixman_image_linebuf_fill(
     };
 }
 
+/// Convert the lines from `y` to `y + height` of the framebuffer to the strip buffer,
+/// which starts with line 0.
+pub fn pixman_image_strip_fill(
+    strip_buf: *mut pixman_image_t,
+    fb: *mut pixman_image_t,
+    width: i32,
+    y: i32,
+    height: i32,
+) {
+    if strip_buf.is_null()
+        || fb.is_null()
+        || !(0..MAX_IMAGE_SIZE).contains(&width)
+        || !(0..MAX_IMAGE_SIZE).contains(&y)
+        || !(0..=get_image_height(strip_buf)).contains(&height)
+    {
+        return;
+    };
+
+    unsafe {
+        pixman_image_composite(
+            pixman_op_t::PIXMAN_OP_SRC,
+            fb as *mut pixman_image_t,
+            ptr::null_mut(),
+            strip_buf as *mut pixman_image_t,
+            0,
+            y as i16,
+            0,
+            0,
+            0,
+            0,
+            width as u16,
+            height as u16,
+        );
+    };
+}
+
+/// Compare the tile at (`x`, `y`) of `dst` with the tile at (`x`, `src_y`) of `src`,
+/// and copy it to `dst` if the content changed. Both images are PIXMAN_x8r8g8b8.
+///
+/// Return whether the tile changed, the tile rewritten with the same content,
+/// e.g. by a blinking cursor, doesn't need to be sent to the client.
+///
+/// # Arguments
+///
+/// * `src` - The image to update from.
+/// * `src_y` - The line of `src` which the tile starts with.
+/// * `dst` - The image to update.
+/// * `x` `y` `w` `h` - coordinate, width, height of the tile in `dst`.
+pub fn compare_and_update_tile(
+    src: *mut pixman_image_t,
+    src_y: i32,
+    dst: *mut pixman_image_t,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
+) -> bool {
+    if src.is_null()
+        || dst.is_null()
+        || x < 0
+        || y < 0
+        || src_y < 0
+        || w <= 0
+        || h <= 0
+        || x + w > std::cmp::min(get_image_width(src), get_image_width(dst))
+        || src_y + h > get_image_height(src)
+        || y + h > get_image_height(dst)
+    {
+        return false;
+    }
+
+    let bpp = bytes_per_pixel();
+    let src_stride = get_image_stride(src) as usize;
+    let dst_stride = get_image_stride(dst) as usize;
+    let src_data = get_image_data(src) as *mut u8;
+    let dst_data = get_image_data(dst) as *mut u8;
+    let line_bytes = w as usize * bpp;
+    let mut changed = false;
+    for i in 0..h as usize {
+        // SAFETY: the tile has been checked to be inside both images.
+        let (src_line, dst_line) = unsafe {
+            (
+                std::slice::from_raw_parts(
+                    src_data.add((src_y as usize + i) * src_stride + x as usize * bpp),
+                    line_bytes,
+                ),
+                std::slice::from_raw_parts_mut(
+                    dst_data.add((y as usize + i) * dst_stride + x as usize * bpp),
+                    line_bytes,
+                ),
+            )
+        };
+        if src_line != dst_line {
+            dst_line.copy_from_slice(src_line);
+            changed = true;
+        }
+    }
+    changed
+}
+
 pub enum ColorNames {
     ColorBlack = 0,
     ColorBlue = 1,
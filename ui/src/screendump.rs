@@ -0,0 +1,293 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+
+use anyhow::{bail, Context, Result};
+
+use crate::console::console_ref_surface;
+use crate::pixman::{
+    get_image_data, get_image_format, get_image_height, get_image_stride, get_image_width,
+    pixman_image_linebuf_create, pixman_image_linebuf_fill, unref_pixman_image, ColorInfo,
+    PixelFormat,
+};
+use util::pixman::{pixman_format_code_t, pixman_image_t};
+
+/// PNG file signature.
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+/// Max length of a stored deflate block.
+const DEFLATE_STORED_MAX: usize = 0xffff;
+/// Modulus of Adler-32.
+const ADLER32_MOD: u32 = 65521;
+
+/// Format of the screen dump file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum DumpFormat {
+    Ppm,
+    Png,
+}
+
+/// Save the surface of the active console to `filename`, in format `ppm` by default.
+/// The file is written to `<filename>.tmp` first and renamed, so the readers never see
+/// a partial image.
+pub fn qmp_screendump(filename: &str, format: Option<&str>) -> Result<()> {
+    let format = match format.unwrap_or("ppm") {
+        "ppm" => DumpFormat::Ppm,
+        "png" => DumpFormat::Png,
+        f => bail!(
+            "Invalid screendump format {}, only ppm and png are supported",
+            f
+        ),
+    };
+
+    // Hold a reference to the image, the console may replace its surface meanwhile.
+    let image = console_ref_surface().with_context(|| "There is no display console")?;
+    let width = get_image_width(image) as usize;
+    let height = get_image_height(image) as usize;
+    if width == 0 || height == 0 {
+        unref_pixman_image(image);
+        bail!("The display surface is empty");
+    }
+    let rgb = surface_to_rgb888(image);
+    unref_pixman_image(image);
+
+    let tmp = format!("{}.tmp", filename);
+    let ret = write_dump(&tmp, format, width, height, &rgb)
+        .and_then(|_| fs::rename(&tmp, filename).with_context(|| "Failed to rename dump file"));
+    if ret.is_err() {
+        let _ = fs::remove_file(&tmp);
+    }
+    ret.with_context(|| format!("Failed to dump screen to {}", filename))
+}
+
+fn write_dump(
+    path: &str,
+    format: DumpFormat,
+    width: usize,
+    height: usize,
+    rgb: &[u8],
+) -> Result<()> {
+    let file = File::create(path).with_context(|| format!("Failed to create {}", path))?;
+    let mut writer = BufWriter::new(&file);
+    match format {
+        DumpFormat::Ppm => write_ppm(&mut writer, width, height, rgb)?,
+        DumpFormat::Png => write_png(&mut writer, width, height, rgb)?,
+    }
+    writer.flush()?;
+    drop(writer);
+    file.sync_data()?;
+    Ok(())
+}
+
+/// Scale the channel value of `pixel` described by `info` to 8 bits.
+fn channel_to_u8(pixel: u32, info: &ColorInfo) -> u8 {
+    let max = info.max as u32;
+    if max == 0 {
+        return 0;
+    }
+    let value = (pixel >> info.shift) & max;
+    (value * 0xff / max) as u8
+}
+
+/// Convert the image to packed RGB888 lines. Images in other formats are converted
+/// to PIXMAN_x8r8g8b8 one line at a time first.
+fn surface_to_rgb888(image: *mut pixman_image_t) -> Vec<u8> {
+    let width = get_image_width(image) as usize;
+    let height = get_image_height(image) as usize;
+    let mut pf = PixelFormat::default();
+    pf.init_pixelformat();
+
+    let mut line_buf = std::ptr::null_mut();
+    if get_image_format(image) != pixman_format_code_t::PIXMAN_x8r8g8b8 {
+        line_buf = pixman_image_linebuf_create(pixman_format_code_t::PIXMAN_x8r8g8b8, width as i32);
+    }
+
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        let line = if line_buf.is_null() {
+            let stride = get_image_stride(image) as usize;
+            // SAFETY: the line is within the image, which is referenced by the caller.
+            unsafe { (get_image_data(image) as *const u8).add(y * stride) as *const u32 }
+        } else {
+            pixman_image_linebuf_fill(line_buf, image, width as i32, 0, y as i32);
+            get_image_data(line_buf) as *const u32
+        };
+        if line.is_null() {
+            rgb.resize(rgb.len() + width * 3, 0);
+            continue;
+        }
+        // SAFETY: a line of PIXMAN_x8r8g8b8 holds `width` pixels of u32.
+        let pixels = unsafe { std::slice::from_raw_parts(line, width) };
+        for pixel in pixels {
+            rgb.push(channel_to_u8(*pixel, &pf.red));
+            rgb.push(channel_to_u8(*pixel, &pf.green));
+            rgb.push(channel_to_u8(*pixel, &pf.blue));
+        }
+    }
+    unref_pixman_image(line_buf);
+    rgb
+}
+
+/// Write a binary PPM (P6) image.
+fn write_ppm<W: Write>(writer: &mut W, width: usize, height: usize, rgb: &[u8]) -> Result<()> {
+    write!(writer, "P6\n{} {}\n255\n", width, height)?;
+    writer.write_all(rgb)?;
+    Ok(())
+}
+
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1_u32, 0_u32);
+    // 5552 bytes can be summed up without overflow of u32.
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += *byte as u32;
+            b += a;
+        }
+        a %= ADLER32_MOD;
+        b %= ADLER32_MOD;
+    }
+    (b << 16) | a
+}
+
+fn write_png_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(crc32(0, kind), data);
+    writer.write_all(&crc.to_be_bytes())?;
+    Ok(())
+}
+
+/// Write a truecolor PNG image. The pixels are not compressed, the zlib stream of
+/// IDAT consists of stored deflate blocks.
+fn write_png<W: Write>(writer: &mut W, width: usize, height: usize, rgb: &[u8]) -> Result<()> {
+    writer.write_all(&PNG_SIGNATURE)?;
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, color type truecolor, deflate, adaptive filtering, no interlace.
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_png_chunk(writer, b"IHDR", &ihdr)?;
+
+    // Each line starts with filter type None.
+    let mut raw = Vec::with_capacity(height * (width * 3 + 1));
+    for line in rgb.chunks(width * 3).take(height) {
+        raw.push(0);
+        raw.extend_from_slice(line);
+    }
+
+    let blocks = raw.len().div_ceil(DEFLATE_STORED_MAX).max(1);
+    let mut idat = Vec::with_capacity(raw.len() + blocks * 5 + 6);
+    // CMF: deflate with 32K window, FLG: no dictionary, fastest compression.
+    idat.extend_from_slice(&[0x78, 0x01]);
+    for index in 0..blocks {
+        let start = index * DEFLATE_STORED_MAX;
+        let end = std::cmp::min(start + DEFLATE_STORED_MAX, raw.len());
+        let len = (end - start) as u16;
+        idat.push((index + 1 == blocks) as u8);
+        idat.extend_from_slice(&len.to_le_bytes());
+        idat.extend_from_slice(&(!len).to_le_bytes());
+        idat.extend_from_slice(&raw[start..end]);
+    }
+    idat.extend_from_slice(&adler32(&raw).to_be_bytes());
+    write_png_chunk(writer, b"IDAT", &idat)?;
+
+    write_png_chunk(writer, b"IEND", &[])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pixman::create_pixman_image;
+
+    fn create_test_image(format: pixman_format_code_t, pixels: &mut [u32]) -> *mut pixman_image_t {
+        // 2x2 image.
+        let bpp = if format == pixman_format_code_t::PIXMAN_r5g6b5 {
+            2
+        } else {
+            4
+        };
+        create_pixman_image(format, 2, 2, pixels.as_mut_ptr(), 2 * bpp)
+    }
+
+    #[test]
+    fn test_screendump_ppm() {
+        let mut pixels = [0x00ff_0000, 0x0000_ff00, 0x0000_00ff, 0x0012_3456];
+        let image = create_test_image(pixman_format_code_t::PIXMAN_x8r8g8b8, &mut pixels);
+        assert!(!image.is_null());
+        let rgb = surface_to_rgb888(image);
+        unref_pixman_image(image);
+
+        let mut ppm = Vec::new();
+        write_ppm(&mut ppm, 2, 2, &rgb).unwrap();
+        let header = b"P6\n2 2\n255\n";
+        assert_eq!(&ppm[..header.len()], header);
+        assert_eq!(
+            &ppm[header.len()..],
+            &[0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0x12, 0x34, 0x56]
+        );
+
+        // Other formats are converted to RGB888.
+        let mut pixels = [0xf800_001f_u32, 0x07e0_ffff];
+        let image = create_test_image(pixman_format_code_t::PIXMAN_r5g6b5, &mut pixels);
+        assert!(!image.is_null());
+        let rgb = surface_to_rgb888(image);
+        unref_pixman_image(image);
+        assert_eq!(rgb.len(), 12);
+        // Pixman stores the pixels of r5g6b5 as native endian u16.
+        assert_eq!(&rgb[..3], &[0, 0, 0xff]);
+        assert_eq!(&rgb[3..6], &[0xff, 0, 0]);
+        assert_eq!(&rgb[6..9], &[0xff, 0xff, 0xff]);
+        assert_eq!(&rgb[9..], &[0, 0xff, 0]);
+    }
+
+    #[test]
+    fn test_screendump_png() {
+        assert_eq!(crc32(0, b"IEND"), 0xae42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+
+        let rgb = [0xff, 0, 0, 0, 0xff, 0, 0, 0, 0xff, 0x12, 0x34, 0x56];
+        let mut png = Vec::new();
+        write_png(&mut png, 2, 2, &rgb).unwrap();
+        assert_eq!(&png[..8], &PNG_SIGNATURE);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!(&png[16..20], &2_u32.to_be_bytes());
+        assert_eq!(&png[20..24], &2_u32.to_be_bytes());
+        // The IDAT holds the zlib header, a final stored block of 2 lines and the checksum.
+        assert_eq!(&png[33..37], &(2 + 5 + 14 + 4_u32).to_be_bytes());
+        assert_eq!(&png[37..41], b"IDAT");
+        assert_eq!(&png[41..48], &[0x78, 0x01, 1, 14, 0, !14, 0xff]);
+        assert_eq!(&png[48..55], &[0, 0xff, 0, 0, 0, 0xff, 0]);
+        assert_eq!(&png[png.len() - 12..png.len() - 8], &0_u32.to_be_bytes());
+        assert_eq!(&png[png.len() - 8..png.len() - 4], b"IEND");
+
+        assert!(qmp_screendump("/tmp/screendump", Some("bmp")).is_err());
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use crate::{
+    error::VncError,
+    vnc::client_io::{vnc_flush, vnc_write, ClientIoHandler},
+};
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use std::{collections::HashMap, fs};
+
+/// Max length of the username or password of plain authentication.
+const PLAIN_AUTH_MAX_LEN: u32 = 1024;
+/// Size of the lengths of username and password sent by client.
+const PLAIN_AUTH_HEADER_LEN: usize = 8;
+
+/// Credentials of VeNCrypt plain authentication.
+#[derive(Debug, Clone, Default)]
+pub struct PlainAuth {
+    /// Password of the users, indexed by username.
+    users: HashMap<String, String>,
+}
+
+impl PlainAuth {
+    /// Load the credentials from the file of `username:password` lines.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - path of the password file.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read vnc password file {}", path))?;
+        Self::parse(&content)
+    }
+
+    /// Parse the `username:password` lines, empty lines and the lines starting with
+    /// `#` are skipped.
+    fn parse(content: &str) -> Result<Self> {
+        let mut users = HashMap::new();
+        for line in content.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((username, password)) if !username.is_empty() => {
+                    users.insert(username.to_string(), password.to_string());
+                }
+                _ => bail!("Invalid line of vnc password file, expect username:password"),
+            }
+        }
+        if users.is_empty() {
+            bail!("No user is found in vnc password file");
+        }
+        Ok(PlainAuth { users })
+    }
+
+    /// Check the username and password. The password is compared in constant time
+    /// to not leak how many bytes match.
+    fn verify(&self, username: &[u8], password: &[u8]) -> bool {
+        let expected = match std::str::from_utf8(username)
+            .ok()
+            .and_then(|name| self.users.get(name))
+        {
+            Some(expected) => expected.as_bytes(),
+            None => return false,
+        };
+        if expected.len() != password.len() {
+            return false;
+        }
+        expected
+            .iter()
+            .zip(password.iter())
+            .fold(0_u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+    }
+}
+
+impl ClientIoHandler {
+    /// Get the lengths of username and password sent by client. The lengths are kept
+    /// in the buffer, and the whole message is handled when the credentials arrive.
+    pub fn get_plain_auth_length(&mut self) -> Result<()> {
+        let buf = self.read_incoming_msg();
+        let username_len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let password_len = u32::from_be_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        if username_len > PLAIN_AUTH_MAX_LEN || password_len > PLAIN_AUTH_MAX_LEN {
+            self.plain_auth_failed("Username or password too long");
+            return Err(anyhow!(VncError::AuthFailed(
+                "get_plain_auth_length".to_string(),
+                "username or password too long".to_string()
+            )));
+        }
+
+        self.expect = PLAIN_AUTH_HEADER_LEN + (username_len + password_len) as usize;
+        self.msg_handler = ClientIoHandler::check_plain_auth;
+        Ok(())
+    }
+
+    /// Check the username and password sent by client.
+    fn check_plain_auth(&mut self) -> Result<()> {
+        let buf = self.read_incoming_msg();
+        let username_len = u32::from_be_bytes([buf[0], buf[1], buf[2], buf[3]]) as usize;
+        let username = &buf[PLAIN_AUTH_HEADER_LEN..PLAIN_AUTH_HEADER_LEN + username_len];
+        let password = &buf[PLAIN_AUTH_HEADER_LEN + username_len..];
+        let passed = match self.server.security_type.borrow().plainauth.as_ref() {
+            Some(plainauth) => plainauth.verify(username, password),
+            None => false,
+        };
+        if !passed {
+            self.plain_auth_failed("Authentication failed");
+            return Err(anyhow!(VncError::AuthFailed(
+                "check_plain_auth".to_string(),
+                "invalid username or password".to_string()
+            )));
+        }
+
+        info!(
+            "Vnc client {} passed plain authentication as {}",
+            self.client.addr,
+            String::from_utf8_lossy(username)
+        );
+        let client = self.client.clone();
+        // Security result: OK.
+        vnc_write(&client, (0_u32).to_be_bytes().to_vec());
+        vnc_flush(&client);
+        self.update_event_handler(1, ClientIoHandler::handle_client_init);
+        Ok(())
+    }
+
+    /// Send the failed security result to client.
+    fn plain_auth_failed(&mut self, msg: &str) {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.append(&mut (1_u32).to_be_bytes().to_vec());
+        // If the RFB protocol version is above 3.8, an error reason will be returned.
+        if self.client.conn_state.lock().unwrap().version.minor >= 8 {
+            buf.append(&mut (msg.len() as u32).to_be_bytes().to_vec());
+            buf.append(&mut msg.as_bytes().to_vec());
+        }
+        let client = self.client.clone();
+        vnc_write(&client, buf);
+        vnc_flush(&client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        input::KeyBoardState,
+        vnc::{
+            auth_sasl::{AuthState, SubAuthState},
+            client_io::{ClientState, IoChannel},
+            server_io::VncServer,
+        },
+    };
+    use std::{
+        cell::RefCell,
+        net::{TcpListener, TcpStream},
+        ptr,
+        rc::Rc,
+        sync::Arc,
+    };
+
+    fn fake_client(plainauth: Option<PlainAuth>) -> ClientIoHandler {
+        let server = Arc::new(VncServer::new(
+            ptr::null_mut(),
+            Rc::new(RefCell::new(KeyBoardState::new(0))),
+            HashMap::new(),
+            None,
+        ));
+        {
+            let mut security_type = server.security_type.borrow_mut();
+            security_type.auth = AuthState::Vencrypt;
+            security_type.subauth = SubAuthState::VncAuthVencryptX509Plain;
+            security_type.plainauth = plainauth;
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        let io_channel = Rc::new(RefCell::new(IoChannel::new(stream.try_clone().unwrap())));
+        let client = Arc::new(ClientState::new(addr.to_string()));
+        ClientIoHandler::new(stream, io_channel, client, server)
+    }
+
+    /// Feed the scripted message of client to the handler.
+    fn feed(handler: &mut ClientIoHandler, msg: &[u8]) -> Result<()> {
+        let client = handler.client.clone();
+        client.in_buffer.lock().unwrap().append_limit(msg.to_vec());
+        while client.in_buffer.lock().unwrap().len() >= handler.expect {
+            (handler.msg_handler)(handler)?;
+            if handler.expect == 0 {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Take the message sent to client.
+    fn output(handler: &ClientIoHandler) -> Vec<u8> {
+        let mut locked_buffer = handler.client.out_buffer.lock().unwrap();
+        let len = locked_buffer.len();
+        let mut buf = vec![0_u8; len];
+        locked_buffer.read_front(&mut buf, len);
+        locked_buffer.remove_front(len);
+        buf
+    }
+
+    fn plain_auth_msg(username: &str, password: &str) -> Vec<u8> {
+        let mut msg = Vec::new();
+        msg.append(&mut (username.len() as u32).to_be_bytes().to_vec());
+        msg.append(&mut (password.len() as u32).to_be_bytes().to_vec());
+        msg.append(&mut username.as_bytes().to_vec());
+        msg.append(&mut password.as_bytes().to_vec());
+        msg
+    }
+
+    #[test]
+    fn test_plain_auth_parse() {
+        let plainauth = PlainAuth::parse("# comment\nadmin:pass:word\n\nguest:\n").unwrap();
+        assert!(plainauth.verify(b"admin", b"pass:word"));
+        assert!(plainauth.verify(b"guest", b""));
+        assert!(!plainauth.verify(b"admin", b"pass"));
+        assert!(!plainauth.verify(b"admin", b"pass:worD"));
+        assert!(!plainauth.verify(b"root", b"pass:word"));
+
+        assert!(PlainAuth::parse("admin\n").is_err());
+        assert!(PlainAuth::parse(":password\n").is_err());
+        assert!(PlainAuth::parse("# no user\n").is_err());
+    }
+
+    #[test]
+    fn test_vencrypt_negotiation() {
+        let plainauth = PlainAuth::parse("admin:secret\n").unwrap();
+        let mut handler = fake_client(Some(plainauth));
+
+        // VeNCrypt is the only security type offered.
+        feed(&mut handler, b"RFB 003.008\n").unwrap();
+        assert_eq!(output(&handler), vec![1, AuthState::Vencrypt as u8]);
+        feed(&mut handler, &[AuthState::Vencrypt as u8]).unwrap();
+        // VeNCrypt version 0.2.
+        assert_eq!(output(&handler), vec![0, 2]);
+        feed(&mut handler, &[0, 2]).unwrap();
+        let mut expected = vec![0, 1];
+        expected.append(
+            &mut (SubAuthState::VncAuthVencryptX509Plain as u32)
+                .to_be_bytes()
+                .to_vec(),
+        );
+        assert_eq!(output(&handler), expected);
+
+        // Sub auth not offered is rejected before tls handshake.
+        let ret = feed(
+            &mut handler,
+            &(SubAuthState::VncAuthVencryptX509None as u32).to_be_bytes(),
+        );
+        assert!(ret.is_err());
+        assert_eq!(output(&handler), vec![0]);
+
+        // Unsupported VeNCrypt version.
+        let mut handler = fake_client(None);
+        feed(&mut handler, b"RFB 003.008\n").unwrap();
+        feed(&mut handler, &[AuthState::Vencrypt as u8]).unwrap();
+        output(&handler);
+        assert!(feed(&mut handler, &[0, 1]).is_err());
+        assert_eq!(output(&handler), vec![0]);
+    }
+
+    #[test]
+    fn test_vencrypt_plain_auth() {
+        let plainauth = PlainAuth::parse("admin:secret\n").unwrap();
+        let mut handler = fake_client(Some(plainauth.clone()));
+        handler.client.conn_state.lock().unwrap().version.minor = 8;
+
+        // Plain sub auth starts when tls handshake is done. The message may arrive
+        // in pieces.
+        handler.handle_vencrypt_subauth().unwrap();
+        let msg = plain_auth_msg("admin", "secret");
+        feed(&mut handler, &msg[..6]).unwrap();
+        feed(&mut handler, &msg[6..12]).unwrap();
+        assert!(output(&handler).is_empty());
+        feed(&mut handler, &msg[12..]).unwrap();
+        assert_eq!(output(&handler), vec![0, 0, 0, 0]);
+        assert_eq!(handler.expect, 1);
+        assert!(handler.client.in_buffer.lock().unwrap().is_empty());
+
+        // Wrong password.
+        let mut handler = fake_client(Some(plainauth.clone()));
+        handler.client.conn_state.lock().unwrap().version.minor = 8;
+        handler.handle_vencrypt_subauth().unwrap();
+        assert!(feed(&mut handler, &plain_auth_msg("admin", "secreT")).is_err());
+        let reason = "Authentication failed";
+        let mut expected = vec![0, 0, 0, 1];
+        expected.append(&mut (reason.len() as u32).to_be_bytes().to_vec());
+        expected.append(&mut reason.as_bytes().to_vec());
+        assert_eq!(output(&handler), expected);
+
+        // Unknown user, RFB 3.7 has no failure reason.
+        let mut handler = fake_client(Some(plainauth));
+        handler.client.conn_state.lock().unwrap().version.minor = 7;
+        handler.handle_vencrypt_subauth().unwrap();
+        assert!(feed(&mut handler, &plain_auth_msg("root", "secret")).is_err());
+        assert_eq!(output(&handler), vec![0, 0, 0, 1]);
+
+        // Too long username.
+        let mut handler = fake_client(None);
+        handler.handle_vencrypt_subauth().unwrap();
+        let mut msg = (PLAIN_AUTH_MAX_LEN + 1).to_be_bytes().to_vec();
+        msg.append(&mut (0_u32).to_be_bytes().to_vec());
+        assert!(feed(&mut handler, &msg).is_err());
+    }
+}
@@ -53,8 +53,12 @@ pub enum SubAuthState {
     VncAuthVencryptPlain = 256,
     /// Tls vencry with anon + no auth.
     VncAuthVencryptTlNone = 257,
+    /// Tls vencrypt with anon + plain username and password.
+    VncAuthVencryptTlsPlain = 259,
     /// Tls vencrypt with x509 + no auth.
     VncAuthVencryptX509None = 260,
+    /// Tls vencrypt with x509 + plain username and password.
+    VncAuthVencryptX509Plain = 262,
     /// Tls vencrypt with x509 + sasl.
     VncAuthVencryptX509Sasl = 263,
     /// Tls vencrypt + sasl.
@@ -148,7 +148,11 @@ impl ClientIoHandler {
             .borrow()
             .tls_config
             .clone()
-            .unwrap();
+            .ok_or_else(|| {
+                anyhow!(VncError::MakeTlsConnectionFailed(String::from(
+                    "Tls config is not found",
+                )))
+            })?;
         let tls_conn = ServerConnection::new(tls_config)?;
         let tls_io_channel = Rc::new(RefCell::new(TlsIoChannel::new(
             self.stream.try_clone().unwrap(),
@@ -221,7 +225,7 @@ impl ClientIoHandler {
         Ok(())
     }
 
-    fn handle_vencrypt_subauth(&mut self) -> Result<()> {
+    pub fn handle_vencrypt_subauth(&mut self) -> Result<()> {
         let subauth = self.server.security_type.borrow().subauth;
         let client = self.client.clone();
         match subauth {
@@ -237,6 +241,11 @@ impl ClientIoHandler {
                 self.expect = 1;
                 self.msg_handler = ClientIoHandler::handle_client_init;
             }
+            SubAuthState::VncAuthVencryptX509Plain | SubAuthState::VncAuthVencryptTlsPlain => {
+                // Lengths of username and password.
+                self.expect = 8;
+                self.msg_handler = ClientIoHandler::get_plain_auth_length;
+            }
             _ => {
                 let mut buf: Vec<u8> = Vec::new();
                 buf.append(&mut (0_u8).to_be_bytes().to_vec());
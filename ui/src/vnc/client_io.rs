@@ -11,7 +11,7 @@
 // See the Mulan PSL v2 for more details.
 
 use crate::{
-    console::{console_select, DisplayMouse},
+    console::{console_select, graphic_hardware_ui_info, DisplayMouse},
     error::VncError,
     input::{
         key_event, point_event, KeyboardModifier, ABS_MAX, ASCII_A, ASCII_Z, INPUT_POINT_LEFT,
@@ -20,9 +20,9 @@ use crate::{
     pixman::{bytes_per_pixel, get_image_height, get_image_width, PixelFormat},
     utils::BuffPool,
     vnc::{
-        auth_sasl::AuthState, framebuffer_upadate, round_up_div, server_io::VncServer,
-        set_area_dirty, write_pixel, BIT_PER_BYTE, DIRTY_PIXELS_NUM, DIRTY_WIDTH_BITS,
-        MAX_IMAGE_SIZE, MAX_WINDOW_HEIGHT, MIN_OUTPUT_LIMIT, OUTPUT_THROTTLE_SCALE,
+        auth_sasl::AuthState, framebuffer_upadate, new_dirty_bitmap, round_up_div,
+        server_io::VncServer, set_area_dirty, write_pixel, BIT_PER_BYTE, DIRTY_TILE_SIZE,
+        MAX_IMAGE_SIZE, MIN_OUTPUT_LIMIT, OUTPUT_THROTTLE_SCALE, VNC_BITMAP_WIDTH,
     },
 };
 use anyhow::{anyhow, bail, Result};
@@ -66,6 +66,13 @@ const ENCODING_DESKTOP_RESIZE_EXT: i32 = -308;
 pub const ENCODING_ALPHA_CURSOR: i32 = -314;
 const ENCODING_WMVI: i32 = 1464686185;
 
+// Reason and status of the ExtendedDesktopSize pseudo-encoding.
+const DESKTOP_SIZE_REASON_SERVER: u16 = 0;
+const DESKTOP_SIZE_REASON_CLIENT: u16 = 1;
+const DESKTOP_SIZE_SUCCESS: u16 = 0;
+const DESKTOP_SIZE_PROHIBITED: u16 = 1;
+const DESKTOP_SIZE_INVALID_LAYOUT: u16 = 3;
+
 /// This trait is used to send bytes,
 /// the return is the total number of bytes sented.
 pub trait IoOperations {
@@ -100,6 +107,7 @@ pub enum ClientMsg {
     KeyEvent = 4,
     PointerEvent = 5,
     ClientCutText = 6,
+    SetDesktopSize = 251,
     InvalidMsg,
 }
 
@@ -118,6 +126,7 @@ impl From<u8> for ClientMsg {
             4 => ClientMsg::KeyEvent,
             5 => ClientMsg::PointerEvent,
             6 => ClientMsg::ClientCutText,
+            251 => ClientMsg::SetDesktopSize,
             _ => ClientMsg::InvalidMsg,
         }
     }
@@ -236,17 +245,18 @@ impl Clone for RectInfo {
     }
 }
 
-pub struct IoChannel {
-    stream: TcpStream,
+/// Io channel over a plain byte stream, e.g. `TcpStream` or `WsStream`.
+pub struct IoChannel<S: Read + Write = TcpStream> {
+    stream: S,
 }
 
-impl IoChannel {
-    pub fn new(stream: TcpStream) -> Self {
+impl<S: Read + Write> IoChannel<S> {
+    pub fn new(stream: S) -> Self {
         Self { stream }
     }
 }
 
-impl IoOperations for IoChannel {
+impl<S: Read + Write> IoOperations for IoChannel<S> {
     fn channel_write(&mut self, buf: &[u8]) -> Result<usize> {
         let buf_size = buf.len();
         let mut offset = 0;
@@ -276,9 +286,13 @@ impl IoOperations for IoChannel {
         loop {
             let mut bytes = vec![0_u8; MAX_RECVBUF_LEN];
             match self.stream.read(&mut bytes) {
+                Ok(0) => {}
+                // Read until the stream is drained, as a framing layer may hold data
+                // which is not signaled by the socket any more.
                 Ok(ret) => {
                     buf.append(&mut bytes[..ret].to_vec());
                     len += ret;
+                    continue;
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     return Ok(len);
@@ -377,10 +391,7 @@ impl ClientState {
             out_buffer: Arc::new(Mutex::new(BuffPool::new())),
             client_dpm: Arc::new(Mutex::new(DisplayMode::default())),
             conn_state: Arc::new(Mutex::new(ConnState::default())),
-            dirty_bitmap: Arc::new(Mutex::new(Bitmap::<u64>::new(
-                MAX_WINDOW_HEIGHT as usize
-                    * round_up_div(DIRTY_WIDTH_BITS as u64, u64::BITS as u64) as usize,
-            ))),
+            dirty_bitmap: Arc::new(Mutex::new(new_dirty_bitmap())),
         }
     }
 }
@@ -667,6 +678,9 @@ impl ClientIoHandler {
             ClientMsg::ClientCutText => {
                 self.client_cut_event();
             }
+            ClientMsg::SetDesktopSize => {
+                self.set_desktop_size();
+            }
             _ => {
                 self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
             }
@@ -995,6 +1009,60 @@ impl ClientIoHandler {
         self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
     }
 
+    /// Client requests to change the desktop size. The request is forwarded to the
+    /// display device, and the framebuffer is resized once the guest switches to
+    /// the new mode.
+    pub fn set_desktop_size(&mut self) {
+        if self.expect == 1 {
+            self.expect = 8;
+            return;
+        }
+        let buf = self.read_incoming_msg();
+        let num_screens = buf[6] as usize;
+        if self.expect == 8 && num_screens > 0 {
+            self.expect += num_screens * 16;
+            return;
+        }
+
+        let width = u16::from_be_bytes([buf[2], buf[3]]) as u32;
+        let height = u16::from_be_bytes([buf[4], buf[5]]) as u32;
+        let status = if num_screens == 0 {
+            DESKTOP_SIZE_INVALID_LAYOUT
+        } else {
+            let con_id = self
+                .server
+                .display_listener
+                .as_ref()
+                .and_then(|dcl| dcl.upgrade())
+                .and_then(|dcl| dcl.lock().unwrap().con_id);
+            match graphic_hardware_ui_info(con_id, width, height) {
+                Ok(()) => DESKTOP_SIZE_SUCCESS,
+                Err(e) => {
+                    error!("Set desktop size error: {:?}", e);
+                    DESKTOP_SIZE_PROHIBITED
+                }
+            }
+        };
+
+        // Reply with the current size, it is updated after the guest changes mode.
+        let locked_surface = self.server.vnc_surface.lock().unwrap();
+        let cur_width = get_image_width(locked_surface.server_image);
+        let cur_height = get_image_height(locked_surface.server_image);
+        drop(locked_surface);
+        let mut buf: Vec<u8> = Vec::new();
+        desktop_resize_ext(
+            DESKTOP_SIZE_REASON_CLIENT,
+            status,
+            cur_width,
+            cur_height,
+            &mut buf,
+        );
+        let client = self.client.clone();
+        vnc_write(&client, buf);
+        vnc_flush(&client);
+        self.update_event_handler(1, ClientIoHandler::handle_protocol_msg);
+    }
+
     /// Invalid authentication, send 1 to reject.
     fn auth_failed(&mut self, msg: &str) {
         let auth_rej: u8 = 1;
@@ -1144,6 +1212,10 @@ impl EventNotifierHelper for ClientIoHandler {
 
 /// Generate the data that needs to be sent.
 /// Add to send queue
+///
+/// The update is only generated after the client requested it by FramebufferUpdateRequest,
+/// and is postponed until the client consumed the previous one. The dirty tiles are kept
+/// in the bitmap meanwhile and coalesced into the next update.
 pub fn get_rects(client: &Arc<ClientState>, server: &Arc<VncServer>, dirty_num: i32) -> Result<()> {
     let mut locked_state = client.conn_state.lock().unwrap();
     let num = locked_state.dirty_num;
@@ -1153,69 +1225,90 @@ pub fn get_rects(client: &Arc<ClientState>, server: &Arc<VncServer>, dirty_num:
     }
     drop(locked_state);
 
-    let mut x: u64;
-    let mut y: u64 = 0;
-    let mut h: u64;
-    let mut x2: u64;
-    let mut rects = Vec::new();
+    if is_update_pending(client, server) {
+        return Ok(());
+    }
+
     let locked_dpm = client.client_dpm.lock().unwrap();
-    let height = locked_dpm.client_height as u64;
-    let width = locked_dpm.client_width as u64;
+    let height = locked_dpm.client_height;
+    let width = locked_dpm.client_width;
     drop(locked_dpm);
-    let mut locked_dirty = client.dirty_bitmap.lock().unwrap();
-    let bpl = locked_dirty.vol() / MAX_WINDOW_HEIGHT as usize;
+    let rects = coalesce_dirty_tiles(&mut client.dirty_bitmap.lock().unwrap(), width, height)?;
 
-    loop {
-        // Find the first non-zero bit in dirty bitmap.
-        let offset = locked_dirty.find_next_bit(y as usize * bpl).unwrap() as u64;
-        if offset >= height * bpl as u64 {
-            break;
-        }
+    server
+        .rect_jobs
+        .lock()
+        .unwrap()
+        .push(RectInfo::new(client, rects));
 
-        x = offset % bpl as u64;
-        y = offset / bpl as u64;
-        // Find value in one line to the end.
-        x2 = locked_dirty.find_next_zero(offset as usize).unwrap() as u64 % bpl as u64;
-        let mut i = y;
-        while i < height {
-            if !locked_dirty.contain((i * bpl as u64 + x) as usize).unwrap() {
+    client.conn_state.lock().unwrap().clear_update_state();
+    Ok(())
+}
+
+/// Whether the previous update of client is still queued or not yet written to socket.
+fn is_update_pending(client: &Arc<ClientState>, server: &Arc<VncServer>) -> bool {
+    !client.out_buffer.lock().unwrap().is_empty()
+        || server
+            .rect_jobs
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|job| Arc::ptr_eq(&job.client, client))
+}
+
+/// Coalesce the dirty tiles into rectangles and clear them in bitmap. Runs of dirty
+/// tiles in a tile row are merged into one rectangle, which is extended downward while
+/// the tile rows below have the same run dirty.
+///
+/// # Arguments
+///
+/// * `dirty` - dirty bitmap of client.
+/// * `width` `height` - size of the client's framebuffer.
+fn coalesce_dirty_tiles(
+    dirty: &mut Bitmap<u64>,
+    width: i32,
+    height: i32,
+) -> Result<Vec<Rectangle>> {
+    let tile = DIRTY_TILE_SIZE as i32;
+    let bpl = VNC_BITMAP_WIDTH as usize;
+    let cols = cmp::min(round_up_div(width as u64, tile as u64) as usize, bpl);
+    let rows = round_up_div(height as u64, tile as u64) as usize;
+    let mut rects = Vec::new();
+
+    for row in 0..rows {
+        let row_start = row * bpl;
+        let mut col = 0;
+        loop {
+            let offset = dirty.find_next_bit(row_start + col)?;
+            if offset >= row_start + cols {
                 break;
             }
-            let start = (i * bpl as u64 + x) as usize;
-            let len = (x2 - x) as usize;
-            locked_dirty.clear_range(start, len)?;
-            i += 1;
-        }
+            let x1 = offset - row_start;
+            let x2 = cmp::min(dirty.find_next_zero(offset)? - row_start, cols);
+
+            let mut row_end = row + 1;
+            while row_end < rows
+                && (x1..x2).all(|x| dirty.contain(row_end * bpl + x).unwrap_or(false))
+            {
+                row_end += 1;
+            }
+            for i in row..row_end {
+                dirty.clear_range(i * bpl + x1, x2 - x1)?;
+            }
 
-        h = i - y;
-        x2 = cmp::min(x2, width / DIRTY_PIXELS_NUM as u64);
-        if x2 > x {
+            let x = x1 as i32 * tile;
+            let y = row as i32 * tile;
             rects.push(Rectangle::new(
-                (x * DIRTY_PIXELS_NUM as u64) as i32,
-                y as i32,
-                ((x2 - x) * DIRTY_PIXELS_NUM as u64) as i32,
-                h as i32,
+                x,
+                y,
+                cmp::min(x2 as i32 * tile, width) - x,
+                cmp::min(row_end as i32 * tile, height) - y,
             ));
-        }
-
-        if x == 0 && x2 == width / DIRTY_PIXELS_NUM as u64 {
-            y += h;
-            if y == height {
-                break;
-            }
+            col = x2;
         }
     }
 
-    drop(locked_dirty);
-
-    server
-        .rect_jobs
-        .lock()
-        .unwrap()
-        .push(RectInfo::new(client, rects));
-
-    client.conn_state.lock().unwrap().clear_update_state();
-    Ok(())
+    Ok(rects)
 }
 
 /// Set pixformat for client.
@@ -1261,8 +1354,19 @@ pub fn desktop_resize(
     }
     locked_dpm.client_width = width;
     locked_dpm.client_height = height;
+    let resize_ext = locked_dpm.has_feature(VncFeatures::VncFeatureResizeExt);
     drop(locked_dpm);
 
+    if resize_ext {
+        desktop_resize_ext(
+            DESKTOP_SIZE_REASON_SERVER,
+            DESKTOP_SIZE_SUCCESS,
+            width,
+            height,
+            buf,
+        );
+        return Ok(());
+    }
     buf.append(&mut (ServerMsg::FramebufferUpdate as u8).to_be_bytes().to_vec());
     buf.append(&mut (0_u8).to_be_bytes().to_vec());
     buf.append(&mut (1_u16).to_be_bytes().to_vec());
@@ -1270,6 +1374,81 @@ pub fn desktop_resize(
     Ok(())
 }
 
+/// Clear the area of client which is out of the image, it happens when the
+/// image shrinks and the client doesn't support desktop resize. The image is
+/// displayed at the top-left corner of the client.
+///
+/// # Arguments
+///
+/// * `client` - the vnc client.
+/// * `width` - width of the image.
+/// * `height` - height of the image.
+/// * `buf` - send buffer.
+pub fn clear_out_of_image(client: &Arc<ClientState>, width: i32, height: i32, buf: &mut Vec<u8>) {
+    let locked_dpm = client.client_dpm.lock().unwrap();
+    let client_width = locked_dpm.client_width;
+    let client_height = locked_dpm.client_height;
+    let pixel_bytes = locked_dpm.pf.pixel_bytes as usize;
+    drop(locked_dpm);
+
+    let rects: Vec<Rectangle> = [
+        Rectangle::new(width, 0, client_width - width, client_height),
+        Rectangle::new(
+            0,
+            height,
+            cmp::min(width, client_width),
+            client_height - height,
+        ),
+    ]
+    .into_iter()
+    .filter(|rect| rect.w > 0 && rect.h > 0)
+    .collect();
+    if rects.is_empty() {
+        return;
+    }
+
+    buf.append(&mut (ServerMsg::FramebufferUpdate as u8).to_be_bytes().to_vec());
+    buf.append(&mut (0_u8).to_be_bytes().to_vec());
+    buf.append(&mut (rects.len() as u16).to_be_bytes().to_vec());
+    for rect in rects {
+        framebuffer_upadate(rect.x, rect.y, rect.w, rect.h, ENCODING_RAW, buf);
+        buf.resize(buf.len() + (rect.w * rect.h) as usize * pixel_bytes, 0);
+    }
+}
+
+/// Set Desktop Size with the ExtendedDesktopSize pseudo-encoding, the framebuffer
+/// is described as a single screen.
+///
+/// # Arguments
+///
+/// * `reason` - why the size is sent, i.e. changed by server or requested by client.
+/// * `status` - result of the client request.
+/// * `width` - width of the framebuffer.
+/// * `height` - height of the framebuffer.
+/// * `buf` - send buffer.
+fn desktop_resize_ext(reason: u16, status: u16, width: i32, height: i32, buf: &mut Vec<u8>) {
+    buf.append(&mut (ServerMsg::FramebufferUpdate as u8).to_be_bytes().to_vec());
+    buf.append(&mut (0_u8).to_be_bytes().to_vec());
+    buf.append(&mut (1_u16).to_be_bytes().to_vec());
+    framebuffer_upadate(
+        reason as i32,
+        status as i32,
+        width,
+        height,
+        ENCODING_DESKTOP_RESIZE_EXT,
+        buf,
+    );
+    // Number of screens and padding.
+    buf.append(&mut [1_u8, 0, 0, 0].to_vec());
+    // Screen id, x, y, width, height and flags.
+    buf.append(&mut (0_u32).to_be_bytes().to_vec());
+    buf.append(&mut (0_u16).to_be_bytes().to_vec());
+    buf.append(&mut (0_u16).to_be_bytes().to_vec());
+    buf.append(&mut (width as u16).to_be_bytes().to_vec());
+    buf.append(&mut (height as u16).to_be_bytes().to_vec());
+    buf.append(&mut (0_u32).to_be_bytes().to_vec());
+}
+
 /// Set color depth for client.
 pub fn set_color_depth(client: &Arc<ClientState>, buf: &mut Vec<u8>) {
     let mut locked_dpm = client.client_dpm.lock().unwrap();
@@ -1417,3 +1596,279 @@ pub fn vnc_disconnect_start(client: &Arc<ClientState>) {
         .write(1)
         .unwrap_or_else(|e| error!("Error occurrs during disconnection: {:?}", e));
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        console::DisplaySurface,
+        input::KeyBoardState,
+        pixman::{compare_and_update_tile, create_pixman_image, unref_pixman_image},
+        vnc::{raw_send_framebuffer_update, vnc_switch_surface, VNC_SERVERS},
+    };
+    use std::ptr;
+    use util::pixman::pixman_format_code_t;
+
+    const WIDTH: i32 = 640;
+    const HEIGHT: i32 = 480;
+
+    #[test]
+    fn test_compare_and_update_tile() {
+        let mut guest_data = vec![0_u32; (WIDTH * HEIGHT) as usize];
+        let format = pixman_format_code_t::PIXMAN_x8r8g8b8;
+        let guest_image =
+            create_pixman_image(format, WIDTH, HEIGHT, guest_data.as_mut_ptr(), WIDTH * 4);
+        let server_image = create_pixman_image(format, WIDTH, HEIGHT, ptr::null_mut(), 0);
+
+        assert!(!compare_and_update_tile(
+            guest_image,
+            0,
+            server_image,
+            0,
+            0,
+            64,
+            64
+        ));
+        guest_data[(70 * WIDTH + 10) as usize] = 0xffffff;
+        assert!(!compare_and_update_tile(
+            guest_image,
+            0,
+            server_image,
+            0,
+            0,
+            64,
+            64
+        ));
+        assert!(compare_and_update_tile(
+            guest_image,
+            64,
+            server_image,
+            0,
+            64,
+            64,
+            64
+        ));
+        // The same content is written again, e.g. the cursor blinks back.
+        assert!(!compare_and_update_tile(
+            guest_image,
+            64,
+            server_image,
+            0,
+            64,
+            64,
+            64
+        ));
+        // Tile out of image.
+        assert!(!compare_and_update_tile(
+            guest_image,
+            0,
+            server_image,
+            600,
+            0,
+            64,
+            64
+        ));
+
+        unref_pixman_image(guest_image);
+        unref_pixman_image(server_image);
+    }
+
+    #[test]
+    fn test_coalesce_dirty_tiles() {
+        let mut dirty = new_dirty_bitmap();
+        set_area_dirty(&mut dirty, 0, 0, 128, 128, WIDTH, HEIGHT).unwrap();
+        set_area_dirty(&mut dirty, 10, 130, 20, 20, WIDTH, HEIGHT).unwrap();
+        set_area_dirty(&mut dirty, 600, 470, 100, 100, WIDTH, HEIGHT).unwrap();
+        let rects = coalesce_dirty_tiles(&mut dirty, WIDTH, HEIGHT).unwrap();
+        let rects: Vec<(i32, i32, i32, i32)> = rects.iter().map(|r| (r.x, r.y, r.w, r.h)).collect();
+        assert_eq!(
+            rects,
+            vec![(0, 0, 128, 128), (0, 128, 64, 64), (576, 448, 64, 32)]
+        );
+        assert_eq!(dirty.find_next_bit(0).unwrap(), dirty.vol());
+    }
+
+    #[test]
+    fn test_single_character_update() {
+        let mut guest_data = vec![0_u32; (WIDTH * HEIGHT) as usize];
+        let format = pixman_format_code_t::PIXMAN_x8r8g8b8;
+        let guest_image =
+            create_pixman_image(format, WIDTH, HEIGHT, guest_data.as_mut_ptr(), WIDTH * 4);
+        let server = Arc::new(VncServer::new(
+            guest_image,
+            Rc::new(RefCell::new(KeyBoardState::new(0))),
+            HashMap::new(),
+            None,
+        ));
+        server.vnc_surface.lock().unwrap().server_image =
+            create_pixman_image(format, WIDTH, HEIGHT, ptr::null_mut(), 0);
+        let client = Arc::new(ClientState::new("127.0.0.1:5900".to_string()));
+        client.client_dpm.lock().unwrap().client_width = WIDTH;
+        client.client_dpm.lock().unwrap().client_height = HEIGHT;
+        server
+            .client_handlers
+            .lock()
+            .unwrap()
+            .insert(client.addr.clone(), client.clone());
+        VNC_SERVERS.lock().unwrap().push(server.clone());
+
+        // Draw a 8x16 character.
+        for y in 200..216 {
+            for x in 100..108 {
+                guest_data[(y * WIDTH + x) as usize] = 0x00ff_ffff;
+            }
+        }
+        let mut locked_surface = server.vnc_surface.lock().unwrap();
+        set_area_dirty(
+            &mut locked_surface.guest_dirty_bitmap,
+            100,
+            200,
+            8,
+            16,
+            WIDTH,
+            HEIGHT,
+        )
+        .unwrap();
+        let dirty_num = locked_surface.update_server_image().unwrap();
+        let server_image = locked_surface.server_image;
+        drop(locked_surface);
+        assert_eq!(dirty_num, 1);
+
+        // No update before the client requests it.
+        get_rects(&client, &server, dirty_num).unwrap();
+        assert!(server.rect_jobs.lock().unwrap().is_empty());
+
+        // The previous update is not consumed by the client.
+        client.conn_state.lock().unwrap().update_state = UpdateState::Incremental;
+        client.out_buffer.lock().unwrap().append_limit(vec![0; 16]);
+        get_rects(&client, &server, 0).unwrap();
+        assert!(server.rect_jobs.lock().unwrap().is_empty());
+
+        client.out_buffer.lock().unwrap().remove_front(16);
+        get_rects(&client, &server, 0).unwrap();
+        let job = server.rect_jobs.lock().unwrap().remove(0);
+        assert_eq!(job.rects.len(), 1);
+        let rect = &job.rects[0];
+        assert_eq!((rect.x, rect.y, rect.w, rect.h), (64, 192, 64, 64));
+        let mut buf = Vec::new();
+        let dpm = client.client_dpm.lock().unwrap().clone();
+        raw_send_framebuffer_update(server_image, rect, &dpm, &mut buf);
+        assert_eq!(buf.len(), 64 * 64 * bytes_per_pixel());
+
+        // The character is rewritten with the same content.
+        let mut locked_surface = server.vnc_surface.lock().unwrap();
+        set_area_dirty(
+            &mut locked_surface.guest_dirty_bitmap,
+            100,
+            200,
+            8,
+            16,
+            WIDTH,
+            HEIGHT,
+        )
+        .unwrap();
+        assert_eq!(locked_surface.update_server_image().unwrap(), 0);
+        drop(locked_surface);
+
+        VNC_SERVERS.lock().unwrap().clear();
+        let mut locked_surface = server.vnc_surface.lock().unwrap();
+        unref_pixman_image(locked_surface.server_image);
+        unref_pixman_image(locked_surface.guest_image);
+        locked_surface.server_image = ptr::null_mut();
+        locked_surface.guest_image = ptr::null_mut();
+    }
+
+    fn take_output(client: &Arc<ClientState>) -> Vec<u8> {
+        let mut locked_buffer = client.out_buffer.lock().unwrap();
+        let len = locked_buffer.len();
+        let mut buf = vec![0_u8; len];
+        locked_buffer.read_front(&mut buf, len);
+        locked_buffer.remove_front(len);
+        buf
+    }
+
+    #[test]
+    fn test_desktop_resize() {
+        let format = pixman_format_code_t::PIXMAN_x8r8g8b8;
+        let guest_image = create_pixman_image(format, WIDTH, HEIGHT, ptr::null_mut(), 0);
+        let server = Arc::new(VncServer::new(
+            guest_image,
+            Rc::new(RefCell::new(KeyBoardState::new(0))),
+            HashMap::new(),
+            None,
+        ));
+        server.vnc_surface.lock().unwrap().server_image =
+            create_pixman_image(format, WIDTH, HEIGHT, ptr::null_mut(), 0);
+        let resizable = Arc::new(ClientState::new("127.0.0.1:5900".to_string()));
+        let legacy = Arc::new(ClientState::new("127.0.0.1:5901".to_string()));
+        for client in [&resizable, &legacy] {
+            let mut locked_dpm = client.client_dpm.lock().unwrap();
+            locked_dpm.client_width = WIDTH;
+            locked_dpm.client_height = HEIGHT;
+            locked_dpm.pf.init_pixelformat();
+            drop(locked_dpm);
+            server
+                .client_handlers
+                .lock()
+                .unwrap()
+                .insert(client.addr.clone(), client.clone());
+        }
+        resizable.client_dpm.lock().unwrap().feature |= 1 << VncFeatures::VncFeatureResize as usize;
+
+        // Switch to a larger surface.
+        let image = create_pixman_image(format, 800, 600, ptr::null_mut(), 0);
+        vnc_switch_surface(&server, &DisplaySurface { format, image }).unwrap();
+        unref_pixman_image(image);
+        assert_eq!(
+            take_output(&resizable),
+            vec![0, 0, 0, 1, 0, 0, 0, 0, 0x03, 0x20, 0x02, 0x58, 0xff, 0xff, 0xff, 0x21]
+        );
+        let locked_dpm = resizable.client_dpm.lock().unwrap();
+        assert_eq!(
+            (locked_dpm.client_width, locked_dpm.client_height),
+            (800, 600)
+        );
+        drop(locked_dpm);
+        let rects =
+            coalesce_dirty_tiles(&mut resizable.dirty_bitmap.lock().unwrap(), 800, 600).unwrap();
+        let rects: Vec<(i32, i32, i32, i32)> = rects.iter().map(|r| (r.x, r.y, r.w, r.h)).collect();
+        assert_eq!(rects, vec![(0, 0, 800, 600)]);
+        // The legacy client keeps its geometry, and the image covers it.
+        assert!(take_output(&legacy).is_empty());
+        let locked_dpm = legacy.client_dpm.lock().unwrap();
+        assert_eq!(
+            (locked_dpm.client_width, locked_dpm.client_height),
+            (WIDTH, HEIGHT)
+        );
+        drop(locked_dpm);
+
+        // Switch to a smaller surface, the legacy client clears the area out of the image.
+        let image = create_pixman_image(format, 320, 200, ptr::null_mut(), 0);
+        vnc_switch_surface(&server, &DisplaySurface { format, image }).unwrap();
+        unref_pixman_image(image);
+        assert_eq!(take_output(&resizable)[8..12], [0x01, 0x40, 0x00, 0xc8]);
+        let buf = take_output(&legacy);
+        assert_eq!(buf[..4], [0, 0, 0, 2]);
+        assert_eq!(
+            buf[4..16],
+            [0x01, 0x40, 0, 0, 0x01, 0x40, 0x01, 0xe0, 0, 0, 0, 0]
+        );
+        let offset = 16 + 320 * 480 * 4;
+        assert_eq!(
+            buf[offset..offset + 12],
+            [0, 0, 0, 0xc8, 0x01, 0x40, 0x01, 0x18, 0, 0, 0, 0]
+        );
+        assert_eq!(buf.len(), offset + 12 + 320 * 280 * 4);
+        assert!(buf[16..offset].iter().all(|b| *b == 0));
+        let rects =
+            coalesce_dirty_tiles(&mut legacy.dirty_bitmap.lock().unwrap(), 320, 200).unwrap();
+        let rects: Vec<(i32, i32, i32, i32)> = rects.iter().map(|r| (r.x, r.y, r.w, r.h)).collect();
+        assert_eq!(rects, vec![(0, 0, 320, 200)]);
+
+        let mut locked_surface = server.vnc_surface.lock().unwrap();
+        unref_pixman_image(locked_surface.server_image);
+        unref_pixman_image(locked_surface.guest_image);
+        locked_surface.server_image = ptr::null_mut();
+        locked_surface.guest_image = ptr::null_mut();
+    }
+}
@@ -10,11 +10,13 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+pub mod auth_plain;
 pub mod auth_sasl;
 pub mod auth_vencrypt;
 pub mod client_io;
 pub mod encoding;
 pub mod server_io;
+pub mod websocket;
 
 use crate::{
     console::{
@@ -31,8 +33,8 @@ use crate::{
     },
     vnc::{
         client_io::{
-            desktop_resize, display_cursor_define, get_rects, set_color_depth, vnc_flush,
-            vnc_update_output_throttle, vnc_write, DisplayMode, Rectangle, ServerMsg,
+            clear_out_of_image, desktop_resize, display_cursor_define, get_rects, set_color_depth,
+            vnc_flush, vnc_update_output_throttle, vnc_write, DisplayMode, Rectangle, ServerMsg,
             ENCODING_HEXTILE, ENCODING_RAW,
         },
         encoding::enc_hextile::hextile_send_framebuffer_update,
@@ -63,13 +65,18 @@ use util::{
     pixman::{pixman_format_code_t, pixman_image_t},
 };
 
-/// The number of dirty pixels represented bt one bit in dirty bitmap.
-pub const DIRTY_PIXELS_NUM: u16 = 16;
+/// The width of the server image is aligned to this number of pixels.
+pub const IMAGE_WIDTH_ALIGN: u16 = 16;
+/// The width and height of the tile represented by one bit in dirty bitmap.
+pub const DIRTY_TILE_SIZE: u16 = 64;
 /// The default max window width.
-pub const MAX_WINDOW_WIDTH: u16 = round_up(2560, DIRTY_PIXELS_NUM as u64) as u16;
+pub const MAX_WINDOW_WIDTH: u16 = round_up(2560, DIRTY_TILE_SIZE as u64) as u16;
 /// The default max window height.
 pub const MAX_WINDOW_HEIGHT: u16 = 2048;
-pub const DIRTY_WIDTH_BITS: u16 = MAX_WINDOW_WIDTH / DIRTY_PIXELS_NUM;
+pub const DIRTY_WIDTH_BITS: u16 = MAX_WINDOW_WIDTH / DIRTY_TILE_SIZE;
+pub const DIRTY_HEIGHT_BITS: u16 =
+    round_up_div(MAX_WINDOW_HEIGHT as u64, DIRTY_TILE_SIZE as u64) as u16;
+/// The number of bits of one tile row in dirty bitmap.
 pub const VNC_BITMAP_WIDTH: u64 =
     round_up_div(DIRTY_WIDTH_BITS as u64, u64::BITS as u64) * u64::BITS as u64;
 pub const MAX_IMAGE_SIZE: i32 = 65535;
@@ -99,57 +106,7 @@ impl DisplayChangeListenerOperations for VncInterface {
             return Ok(());
         }
         let server = VNC_SERVERS.lock().unwrap()[0].clone();
-        let mut locked_vnc_surface = server.vnc_surface.lock().unwrap();
-        let need_resize = check_surface(&mut locked_vnc_surface, surface);
-        unref_pixman_image(locked_vnc_surface.guest_image);
-
-        // Vnc_pixman_image_ref
-        locked_vnc_surface.guest_image = ref_pixman_image(surface.image);
-        locked_vnc_surface.guest_format = surface.format;
-
-        let guest_width: i32 = get_image_width(locked_vnc_surface.guest_image);
-        let guest_height: i32 = get_image_height(locked_vnc_surface.guest_image);
-        if !need_resize {
-            set_area_dirty(
-                &mut locked_vnc_surface.guest_dirty_bitmap,
-                0,
-                0,
-                guest_width,
-                guest_height,
-                guest_width,
-                guest_height,
-            )?;
-            return Ok(());
-        }
-        drop(locked_vnc_surface);
-        update_server_surface(&server)?;
-
-        let mut locked_handlers = server.client_handlers.lock().unwrap();
-        for client in locked_handlers.values_mut() {
-            let width = vnc_width(guest_width);
-            let height = vnc_height(guest_height);
-            let mut buf: Vec<u8> = Vec::new();
-            // Set Color depth.
-            set_color_depth(client, &mut buf);
-            // Desktop_resize.
-            desktop_resize(client, &server, &mut buf)?;
-            // Cursor define.
-            display_cursor_define(client, &server, &mut buf);
-            vnc_write(client, buf);
-            vnc_flush(client);
-            client.dirty_bitmap.lock().unwrap().clear_all();
-            set_area_dirty(
-                &mut client.dirty_bitmap.lock().unwrap(),
-                0,
-                0,
-                width,
-                height,
-                guest_width,
-                guest_height,
-            )?;
-            vnc_update_output_throttle(client);
-        }
-        Ok(())
+        vnc_switch_surface(&server, surface)
     }
 
     /// Refresh server_image to guest_image.
@@ -268,19 +225,12 @@ pub fn vnc_init(vnc: &Option<VncConfig>, object: &ObjectConfig) -> Result<()> {
         None => return Ok(()),
     };
 
-    let addr = format!("{}:{}", vnc_cfg.ip, vnc_cfg.port);
-    let listener: TcpListener = match TcpListener::bind(addr.as_str()) {
-        Ok(l) => l,
-        Err(e) => {
-            let msg = format!("Bind {} failed {}", addr, e);
-            return Err(anyhow!(VncError::TcpBindFailed(msg)));
-        }
+    let listener = vnc_listen(&vnc_cfg.ip, &vnc_cfg.port)?;
+    let ws_listener = match &vnc_cfg.websocket {
+        Some(port) => Some(vnc_listen(&vnc_cfg.ip, port)?),
+        None => None,
     };
 
-    listener
-        .set_nonblocking(true)
-        .expect("Set noblocking for vnc socket failed");
-
     let mut keysym2keycode: HashMap<u16, u16> = HashMap::new();
 
     let mut max_keycode: u16 = 0;
@@ -313,15 +263,39 @@ pub fn vnc_init(vnc: &Option<VncConfig>, object: &ObjectConfig) -> Result<()> {
     register_display(&dcl)?;
 
     // Register the event to listen for client's connection.
-    let vnc_io = Arc::new(Mutex::new(VncConnHandler::new(listener, server)));
+    let vnc_io = Arc::new(Mutex::new(VncConnHandler::new(
+        listener,
+        false,
+        server.clone(),
+    )));
 
     // Vnc_thread: a thread to send the framebuffer
     start_vnc_thread()?;
 
     EventLoop::update_event(EventNotifierHelper::internal_notifiers(vnc_io), None)?;
+    if let Some(ws_listener) = ws_listener {
+        let ws_io = Arc::new(Mutex::new(VncConnHandler::new(ws_listener, true, server)));
+        EventLoop::update_event(EventNotifierHelper::internal_notifiers(ws_io), None)?;
+    }
     Ok(())
 }
 
+fn vnc_listen(ip: &str, port: &str) -> Result<TcpListener> {
+    let addr = format!("{}:{}", ip, port);
+    let listener: TcpListener = match TcpListener::bind(addr.as_str()) {
+        Ok(l) => l,
+        Err(e) => {
+            let msg = format!("Bind {} failed {}", addr, e);
+            return Err(anyhow!(VncError::TcpBindFailed(msg)));
+        }
+    };
+
+    listener
+        .set_nonblocking(true)
+        .expect("Set noblocking for vnc socket failed");
+    Ok(listener)
+}
+
 fn start_vnc_thread() -> Result<()> {
     let interval = DEFAULT_REFRESH_INTERVAL;
     let server = VNC_SERVERS.lock().unwrap()[0].clone();
@@ -355,8 +329,15 @@ fn start_vnc_thread() -> Result<()> {
             for rect in rect_info.rects.iter_mut() {
                 let locked_surface = server.vnc_surface.lock().unwrap();
                 let dpm = rect_info.client.client_dpm.lock().unwrap().clone();
-                let width = dpm.client_width;
-                let height = dpm.client_height;
+                // The client may be larger than the image if it can't be resized.
+                let width = cmp::min(
+                    dpm.client_width,
+                    get_image_width(locked_surface.server_image),
+                );
+                let height = cmp::min(
+                    dpm.client_height,
+                    get_image_height(locked_surface.server_image),
+                );
                 if check_rect(rect, width, height) {
                     let n =
                         send_framebuffer_update(locked_surface.server_image, rect, &dpm, &mut buf);
@@ -404,31 +385,37 @@ pub fn qmp_query_vnc() -> Option<VncInfo> {
     Some(vnc_info)
 }
 
-/// Set dirty in bitmap.
+/// Create a dirty bitmap which covers the max window.
+pub fn new_dirty_bitmap() -> Bitmap<u64> {
+    Bitmap::<u64>::new(DIRTY_HEIGHT_BITS as usize * (VNC_BITMAP_WIDTH / u64::BITS as u64) as usize)
+}
+
+/// Set the tiles covered by the area dirty in bitmap.
 pub fn set_area_dirty(
     dirty: &mut Bitmap<u64>,
-    mut x: i32,
-    mut y: i32,
-    mut w: i32,
-    mut h: i32,
+    x: i32,
+    y: i32,
+    w: i32,
+    h: i32,
     g_w: i32,
     g_h: i32,
 ) -> Result<()> {
     let width: i32 = vnc_width(g_w);
     let height: i32 = vnc_height(g_h);
+    let tile = DIRTY_TILE_SIZE as i32;
 
-    w += x % DIRTY_PIXELS_NUM as i32;
-    x -= x % DIRTY_PIXELS_NUM as i32;
-
-    x = cmp::min(x, width);
-    y = cmp::min(y, height);
-    w = cmp::min(x + w, width) - x;
-    h = cmp::min(y + h, height);
-    while y < h {
-        let pos = (y * VNC_BITMAP_WIDTH as i32 + x / DIRTY_PIXELS_NUM as i32) as usize;
-        let len = round_up_div(w as u64, DIRTY_PIXELS_NUM as u64) as usize;
-        dirty.set_range(pos, len)?;
-        y += 1;
+    let x1 = cmp::min(cmp::max(x, 0), width);
+    let y1 = cmp::min(cmp::max(y, 0), height);
+    let x2 = cmp::min(x.saturating_add(w), width);
+    let y2 = cmp::min(y.saturating_add(h), height);
+    if x2 <= x1 || y2 <= y1 {
+        return Ok(());
+    }
+
+    let start = (x1 / tile) as usize;
+    let len = round_up_div(x2 as u64, tile as u64) as usize - start;
+    for row in (y1 / tile)..round_up_div(y2 as u64, tile as u64) as i32 {
+        dirty.set_range(row as usize * VNC_BITMAP_WIDTH as usize + start, len)?;
     }
     Ok(())
 }
@@ -437,7 +424,7 @@ pub fn set_area_dirty(
 pub fn vnc_width(width: i32) -> i32 {
     cmp::min(
         MAX_WINDOW_WIDTH as i32,
-        round_up(width as u64, DIRTY_PIXELS_NUM as u64) as i32,
+        round_up(width as u64, IMAGE_WIDTH_ALIGN as u64) as i32,
     )
 }
 
@@ -446,6 +433,65 @@ fn vnc_height(height: i32) -> i32 {
     cmp::min(MAX_WINDOW_HEIGHT as i32, height)
 }
 
+/// Switch the guest surface of vnc server. If the size of the surface changes,
+/// clients which support the DesktopSize or ExtendedDesktopSize pseudo-encoding
+/// are resized to the new surface, others keep their geometry and get the new
+/// surface clipped at the top-left corner with the rest of the screen cleared.
+/// The dirty bitmap of each client is reallocated and marks the whole surface.
+///
+/// # Arguments
+///
+/// * `server` - the vnc server.
+/// * `surface` - the new surface of guest.
+pub fn vnc_switch_surface(server: &Arc<VncServer>, surface: &DisplaySurface) -> Result<()> {
+    let mut locked_vnc_surface = server.vnc_surface.lock().unwrap();
+    let need_resize = check_surface(&mut locked_vnc_surface, surface);
+    unref_pixman_image(locked_vnc_surface.guest_image);
+
+    // Vnc_pixman_image_ref
+    locked_vnc_surface.guest_image = ref_pixman_image(surface.image);
+    locked_vnc_surface.guest_format = surface.format;
+
+    let guest_width: i32 = get_image_width(locked_vnc_surface.guest_image);
+    let guest_height: i32 = get_image_height(locked_vnc_surface.guest_image);
+    if !need_resize {
+        set_area_dirty(
+            &mut locked_vnc_surface.guest_dirty_bitmap,
+            0,
+            0,
+            guest_width,
+            guest_height,
+            guest_width,
+            guest_height,
+        )?;
+        return Ok(());
+    }
+    drop(locked_vnc_surface);
+    update_server_surface(server)?;
+
+    let width = vnc_width(guest_width);
+    let height = vnc_height(guest_height);
+    let mut locked_handlers = server.client_handlers.lock().unwrap();
+    for client in locked_handlers.values_mut() {
+        let mut buf: Vec<u8> = Vec::new();
+        // Set Color depth.
+        set_color_depth(client, &mut buf);
+        // Desktop_resize.
+        desktop_resize(client, server, &mut buf)?;
+        // Clear the screen of clients which can't be resized.
+        clear_out_of_image(client, width, height, &mut buf);
+        // Cursor define.
+        display_cursor_define(client, server, &mut buf);
+        vnc_write(client, buf);
+        vnc_flush(client);
+        let mut dirty = new_dirty_bitmap();
+        set_area_dirty(&mut dirty, 0, 0, width, height, guest_width, guest_height)?;
+        *client.dirty_bitmap.lock().unwrap() = dirty;
+        vnc_update_output_throttle(client);
+    }
+    Ok(())
+}
+
 /// Update server image
 pub fn update_server_surface(server: &Arc<VncServer>) -> Result<()> {
     let mut locked_vnc_surface = server.vnc_surface.lock().unwrap();
@@ -15,16 +15,19 @@ use crate::{
     error::VncError,
     input::KeyBoardState,
     pixman::{
-        bytes_per_pixel, get_image_data, get_image_format, get_image_height, get_image_stride,
-        get_image_width, pixman_image_linebuf_create, pixman_image_linebuf_fill,
-        unref_pixman_image,
+        compare_and_update_tile, create_pixman_image, get_image_height, get_image_width,
+        pixman_image_strip_fill, unref_pixman_image,
     },
     vnc::{
+        auth_plain::PlainAuth,
         auth_sasl::{AuthState, SaslAuth, SaslConfig, SubAuthState},
         auth_vencrypt::{make_vencrypt_config, TlsCreds, ANON_CERT, X509_CERT},
-        client_io::{vnc_flush, vnc_write, ClientIoHandler, ClientState, IoChannel, RectInfo},
-        round_up_div, update_server_surface, DIRTY_PIXELS_NUM, MAX_WINDOW_HEIGHT, MAX_WINDOW_WIDTH,
-        VNC_BITMAP_WIDTH, VNC_SERVERS,
+        client_io::{
+            vnc_flush, vnc_write, ClientIoHandler, ClientState, IoChannel, IoOperations, RectInfo,
+        },
+        new_dirty_bitmap, round_up_div, update_server_surface,
+        websocket::WsStream,
+        DIRTY_TILE_SIZE, VNC_BITMAP_WIDTH, VNC_SERVERS,
     },
 };
 use anyhow::{anyhow, Result};
@@ -48,7 +51,7 @@ use util::{
     loop_context::{
         read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
     },
-    pixman::{pixman_format_bpp, pixman_format_code_t, pixman_image_t},
+    pixman::{pixman_format_code_t, pixman_image_t},
 };
 use vmm_sys_util::epoll::EventSet;
 
@@ -110,13 +113,19 @@ impl VncServer {
 pub struct VncConnHandler {
     /// Tcp connection listened by server.
     listener: TcpListener,
+    /// Clients of the listener talk RFB over websocket.
+    websocket: bool,
     /// VncServer.
     server: Arc<VncServer>,
 }
 
 impl VncConnHandler {
-    pub fn new(listener: TcpListener, server: Arc<VncServer>) -> Self {
-        VncConnHandler { listener, server }
+    pub fn new(listener: TcpListener, websocket: bool, server: Arc<VncServer>) -> Self {
+        VncConnHandler {
+            listener,
+            websocket,
+            server,
+        }
     }
 }
 
@@ -125,12 +134,13 @@ impl EventNotifierHelper for VncConnHandler {
     fn internal_notifiers(vnc_io: Arc<Mutex<VncConnHandler>>) -> Vec<EventNotifier> {
         let vnc_io_clone = vnc_io.clone();
         let server = vnc_io.lock().unwrap().server.clone();
+        let websocket = vnc_io.lock().unwrap().websocket;
         // Register event notifier for connection.
         let handler: Rc<NotifierCallback> = Rc::new(move |_event, fd: RawFd| {
             read_fd(fd);
             match vnc_io_clone.clone().lock().unwrap().listener.accept() {
                 Ok((stream, addr)) => {
-                    if let Err(e) = handle_connection(&server, stream, addr) {
+                    if let Err(e) = handle_connection(&server, stream, addr, websocket) {
                         error!("{:?}", e);
                     }
                 }
@@ -150,32 +160,6 @@ impl EventNotifierHelper for VncConnHandler {
     }
 }
 
-/// Info of image.
-/// stride is not always equal to stride because of memory alignment.
-pub struct ImageInfo {
-    /// The start pointer to image.
-    data: *mut u8,
-    /// The memory size of each line for image.
-    stride: i32,
-    /// The memory size of each line to store pixel for image
-    length: i32,
-    /// Middle pointer.
-    ptr: *mut u8,
-}
-
-impl ImageInfo {
-    fn new(image: *mut pixman_image_t) -> Self {
-        let bpp = pixman_format_bpp(get_image_format(image) as u32);
-        let length = get_image_width(image) * round_up_div(bpp as u64, 8) as i32;
-        ImageInfo {
-            data: get_image_data(image) as *mut u8,
-            stride: get_image_stride(image),
-            length,
-            ptr: ptr::null_mut(),
-        }
-    }
-}
-
 /// Security type for connection and transport.
 pub struct SecurityType {
     /// Configuration for tls connection.
@@ -184,6 +168,8 @@ pub struct SecurityType {
     pub saslauth: Option<SaslAuth>,
     /// Configuration for sasl Authentication.
     pub saslconfig: SaslConfig,
+    /// Credentials for plain authentication.
+    pub plainauth: Option<PlainAuth>,
     /// Configuration to make tls channel.
     pub tls_config: Option<Arc<rustls::ServerConfig>>,
     /// Auth type.
@@ -198,6 +184,7 @@ impl Default for SecurityType {
             tlscreds: None,
             saslauth: None,
             saslconfig: SaslConfig::default(),
+            plainauth: None,
             tls_config: None,
             auth: AuthState::No,
             subauth: SubAuthState::VncAuthVencryptPlain,
@@ -233,6 +220,11 @@ impl SecurityType {
             self.saslauth = Some(SaslAuth::new(sasl_auth.identity.clone()));
         }
 
+        // Plain authentication configuration.
+        if let Some(password_file) = &vnc_cfg.password_file {
+            self.plainauth = Some(PlainAuth::from_file(password_file)?);
+        }
+
         Ok(())
     }
 
@@ -241,6 +233,7 @@ impl SecurityType {
         let is_x509: bool;
         let is_anon: bool;
         let is_sasl: bool = self.saslauth.is_some();
+        let is_plain: bool = self.plainauth.is_some();
 
         if let Some(tlscred) = self.tlscreds.clone() {
             is_x509 = tlscred.cred_type == *X509_CERT;
@@ -263,6 +256,12 @@ impl SecurityType {
             } else {
                 self.subauth = SubAuthState::VncAuthVencryptTlssasl;
             }
+        } else if is_plain {
+            if is_x509 {
+                self.subauth = SubAuthState::VncAuthVencryptX509Plain;
+            } else {
+                self.subauth = SubAuthState::VncAuthVencryptTlsPlain;
+            }
         } else if is_x509 {
             self.subauth = SubAuthState::VncAuthVencryptX509None;
         } else {
@@ -297,13 +296,7 @@ impl VncSurface {
     fn new(guest_image: *mut pixman_image_t) -> Self {
         VncSurface {
             guest_image,
-            guest_dirty_bitmap: Bitmap::<u64>::new(
-                MAX_WINDOW_HEIGHT as usize
-                    * round_up_div(
-                        (MAX_WINDOW_WIDTH / DIRTY_PIXELS_NUM) as u64,
-                        u64::BITS as u64,
-                    ) as usize,
-            ),
+            guest_dirty_bitmap: new_dirty_bitmap(),
             server_image: ptr::null_mut(),
             guest_format: pixman_format_code_t::PIXMAN_x8r8g8b8,
         }
@@ -325,147 +318,77 @@ impl VncSurface {
         )
     }
 
-    /// Flush dirty data from guest_image to server_image.
-    /// Return the number of dirty area.
+    /// Flush dirty tiles from guest_image to server_image, only the tiles
+    /// whose content changed are marked dirty for clients.
+    /// Return the number of changed tiles.
     pub fn update_server_image(&mut self) -> Result<i32> {
         let mut dirty_num = 0;
-        let height = self.get_min_height() as usize;
-        let g_bpl = self.guest_dirty_bitmap.vol() / MAX_WINDOW_HEIGHT as usize;
-        let total_dirty_bits = height.checked_mul(g_bpl).unwrap_or(0);
+        let width = self.get_min_width();
+        let height = self.get_min_height();
+        let tile = DIRTY_TILE_SIZE as i32;
+        let bpl = VNC_BITMAP_WIDTH as usize;
+        let total_dirty_bits = round_up_div(height as u64, tile as u64) as usize * bpl;
         let mut offset = self
             .guest_dirty_bitmap
             .find_next_bit(0)
             .unwrap_or(total_dirty_bits);
-
         if offset >= total_dirty_bits {
             return Ok(dirty_num);
         }
 
-        let mut s_info = ImageInfo::new(self.server_image);
-        let mut g_info = ImageInfo::new(self.guest_image);
-
-        // The guset image is not changed, so there is no
-        // need to update the server image.
-        let cmp_bytes = cmp::min(
-            DIRTY_PIXELS_NUM as usize * bytes_per_pixel(),
-            s_info.stride as usize,
-        );
-
-        let mut line_buf = ptr::null_mut();
+        // Guest image in other formats is converted to PIXMAN_x8r8g8b8
+        // one tile row at a time before comparing.
+        let mut strip_buf = ptr::null_mut();
         if self.guest_format != pixman_format_code_t::PIXMAN_x8r8g8b8 {
-            line_buf = pixman_image_linebuf_create(
+            strip_buf = create_pixman_image(
                 pixman_format_code_t::PIXMAN_x8r8g8b8,
-                get_image_width(self.server_image),
+                width,
+                tile,
+                ptr::null_mut(),
+                0,
             );
-            g_info.stride = s_info.stride;
-            g_info.length = g_info.stride;
         }
-
-        loop {
-            let mut y = offset / g_bpl;
-            let x = offset % g_bpl;
-            s_info.ptr =
-                (s_info.data as usize + y * s_info.stride as usize + x * cmp_bytes) as *mut u8;
-
-            if self.guest_format != pixman_format_code_t::PIXMAN_x8r8g8b8 {
-                pixman_image_linebuf_fill(
-                    line_buf,
-                    self.guest_image,
-                    self.get_min_width(),
-                    0_i32,
-                    y as i32,
-                );
-                g_info.ptr = get_image_data(line_buf) as *mut u8;
-            } else {
-                g_info.ptr = (g_info.data as usize + y * g_info.stride as usize) as *mut u8;
+        let mut strip_row = None;
+
+        while offset < total_dirty_bits {
+            self.guest_dirty_bitmap.clear(offset)?;
+            let tile_x = offset % bpl;
+            let tile_y = offset / bpl;
+            let x = tile_x as i32 * tile;
+            let y = tile_y as i32 * tile;
+            if x < width {
+                let w = cmp::min(tile, width - x);
+                let h = cmp::min(tile, height - y);
+                let changed = if strip_buf.is_null() {
+                    compare_and_update_tile(self.guest_image, y, self.server_image, x, y, w, h)
+                } else {
+                    if strip_row != Some(tile_y) {
+                        pixman_image_strip_fill(strip_buf, self.guest_image, width, y, h);
+                        strip_row = Some(tile_y);
+                    }
+                    compare_and_update_tile(strip_buf, 0, self.server_image, x, y, w, h)
+                };
+                if changed {
+                    set_dirty_for_each_clients(tile_x, tile_y)?;
+                    dirty_num += 1;
+                }
             }
-            g_info.ptr = (g_info.ptr as usize + x * cmp_bytes) as *mut u8;
-            dirty_num += self.update_one_line(x, y, &mut s_info, &mut g_info, cmp_bytes)?;
-            y += 1;
             offset = self
                 .guest_dirty_bitmap
-                .find_next_bit(y * g_bpl)
+                .find_next_bit(offset + 1)
                 .unwrap_or(total_dirty_bits);
-            if offset >= total_dirty_bits {
-                break;
-            }
         }
 
-        unref_pixman_image(line_buf);
+        unref_pixman_image(strip_buf);
         Ok(dirty_num)
     }
-
-    /// Update each line
-    ///
-    /// # Arguments
-    ///
-    /// * `x` `y` - start coordinate in image to refresh
-    /// * `s_info` - Info of Server image.
-    /// * `g_info` - Info of Guest image.
-    fn update_one_line(
-        &mut self,
-        mut x: usize,
-        y: usize,
-        s_info: &mut ImageInfo,
-        g_info: &mut ImageInfo,
-        cmp_bytes: usize,
-    ) -> Result<i32> {
-        let mut count = 0;
-        let width = self.get_min_width();
-        let line_bytes = cmp::min(s_info.stride, g_info.length);
-
-        while x < round_up_div(width as u64, DIRTY_PIXELS_NUM as u64) as usize {
-            if !self
-                .guest_dirty_bitmap
-                .contain(x + y * VNC_BITMAP_WIDTH as usize)
-                .unwrap_or(false)
-            {
-                x += 1;
-                g_info.ptr = (g_info.ptr as usize + cmp_bytes) as *mut u8;
-                s_info.ptr = (s_info.ptr as usize + cmp_bytes) as *mut u8;
-                continue;
-            }
-            self.guest_dirty_bitmap
-                .clear(x + y * VNC_BITMAP_WIDTH as usize)?;
-            let mut _cmp_bytes = cmp_bytes;
-            if (x + 1) * cmp_bytes > line_bytes as usize {
-                _cmp_bytes = line_bytes as usize - x * cmp_bytes;
-            }
-
-            // SAFETY: it can be ensure the raw pointer will not exceed the range.
-            unsafe {
-                if libc::memcmp(
-                    s_info.ptr as *mut libc::c_void,
-                    g_info.ptr as *mut libc::c_void,
-                    _cmp_bytes,
-                ) == 0
-                {
-                    x += 1;
-                    g_info.ptr = (g_info.ptr as usize + cmp_bytes) as *mut u8;
-                    s_info.ptr = (s_info.ptr as usize + cmp_bytes) as *mut u8;
-                    continue;
-                }
-
-                ptr::copy(g_info.ptr, s_info.ptr, _cmp_bytes);
-            };
-
-            set_dirty_for_each_clients(x, y)?;
-            count += 1;
-
-            x += 1;
-            g_info.ptr = (g_info.ptr as usize + cmp_bytes) as *mut u8;
-            s_info.ptr = (s_info.ptr as usize + cmp_bytes) as *mut u8;
-        }
-
-        Ok(count)
-    }
 }
 
 /// Set diry for each client.
 ///
 /// # Arguments
 ///
-/// * `x` `y`- coordinates of dirty area.
+/// * `x` `y`- coordinates of dirty tile.
 fn set_dirty_for_each_clients(x: usize, y: usize) -> Result<()> {
     let server = VNC_SERVERS.lock().unwrap()[0].clone();
     let mut locked_handlers = server.client_handlers.lock().unwrap();
@@ -485,17 +408,24 @@ fn set_dirty_for_each_clients(x: usize, y: usize) -> Result<()> {
 ///
 /// * `stream` - TcpStream.
 /// * `addr`- SocketAddr.
+/// * `websocket` - The client talks RFB over websocket.
 pub fn handle_connection(
     server: &Arc<VncServer>,
     stream: TcpStream,
     addr: SocketAddr,
+    websocket: bool,
 ) -> Result<()> {
     info!("New Connection: {:?}", stream);
+    let io_channel: Rc<RefCell<dyn IoOperations>> = if websocket {
+        let ws_stream = WsStream::accept(stream.try_clone()?)?;
+        Rc::new(RefCell::new(IoChannel::new(ws_stream)))
+    } else {
+        Rc::new(RefCell::new(IoChannel::new(stream.try_clone()?)))
+    };
     stream
         .set_nonblocking(true)
         .expect("set nonblocking failed");
 
-    let io_channel = Rc::new(RefCell::new(IoChannel::new(stream.try_clone().unwrap())));
     // Register event notifier for vnc client.
     let client = Arc::new(ClientState::new(addr.to_string()));
     let client_io = Arc::new(Mutex::new(ClientIoHandler::new(
@@ -0,0 +1,631 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! RFB over websocket (RFC 6455), used by browser based clients such as noVNC.
+//!
+//! The client starts with an HTTP upgrade request, then RFB messages are carried
+//! in binary frames. `WsStream` decodes and encodes the frames, and implements
+//! `Read` and `Write` of the RFB byte stream, so the rest of the VNC code does
+//! not care about the transport.
+
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail, Result};
+use log::warn;
+use ring::digest;
+
+use crate::error::VncError;
+
+/// Appended to the key of the client to compute `Sec-WebSocket-Accept`.
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+/// Max length of the HTTP upgrade request.
+const MAX_HANDSHAKE_LEN: usize = 4096;
+/// The client must finish the handshake in time, as it blocks the main loop.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(3);
+/// Max payload length of a frame from the client.
+const MAX_FRAME_PAYLOAD: u64 = 16 * 1024 * 1024;
+/// Max payload length of a control frame.
+const MAX_CONTROL_PAYLOAD: usize = 125;
+/// Size of the chunk read from the socket each time.
+const RECV_CHUNK_LEN: usize = 4096;
+
+const WS_OPCODE_CONTINUATION: u8 = 0x0;
+const WS_OPCODE_TEXT: u8 = 0x1;
+const WS_OPCODE_BINARY: u8 = 0x2;
+const WS_OPCODE_CLOSE: u8 = 0x8;
+const WS_OPCODE_PING: u8 = 0x9;
+const WS_OPCODE_PONG: u8 = 0xA;
+
+const WS_FIN: u8 = 0x80;
+const WS_RSV_MASK: u8 = 0x70;
+const WS_OPCODE_MASK: u8 = 0x0F;
+const WS_MASKED: u8 = 0x80;
+const WS_LEN_MASK: u8 = 0x7F;
+const WS_LEN_16: u8 = 126;
+const WS_LEN_64: u8 = 127;
+
+/// Status codes of close frames.
+const WS_CLOSE_NORMAL: u16 = 1000;
+const WS_CLOSE_PROTOCOL_ERROR: u16 = 1002;
+const WS_CLOSE_UNSUPPORTED_DATA: u16 = 1003;
+const WS_CLOSE_TOO_BIG: u16 = 1009;
+
+/// Errors in the frames from the client, which close the connection.
+#[derive(Debug, PartialEq, Eq)]
+pub enum WsFrameError {
+    /// The frame violates RFC 6455.
+    Protocol,
+    /// Text frames are not supported.
+    UnsupportedData,
+    /// The payload exceeds `MAX_FRAME_PAYLOAD`.
+    TooBig,
+}
+
+impl WsFrameError {
+    fn close_code(&self) -> u16 {
+        match self {
+            WsFrameError::Protocol => WS_CLOSE_PROTOCOL_ERROR,
+            WsFrameError::UnsupportedData => WS_CLOSE_UNSUPPORTED_DATA,
+            WsFrameError::TooBig => WS_CLOSE_TOO_BIG,
+        }
+    }
+}
+
+/// A websocket frame, with the payload unmasked.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WsFrame {
+    /// The last frame of a message.
+    pub fin: bool,
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Encode a frame. Frames from the client are masked, those from the server are not.
+///
+/// # Arguments
+///
+/// * `fin` - The last frame of a message.
+/// * `opcode` - Opcode of the frame.
+/// * `payload` - Payload of the frame.
+/// * `mask` - Masking key of the frame.
+pub fn encode_frame(fin: bool, opcode: u8, payload: &[u8], mask: Option<[u8; 4]>) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(payload.len() + 14);
+    buf.push(if fin { WS_FIN } else { 0 } | (opcode & WS_OPCODE_MASK));
+    let masked = if mask.is_some() { WS_MASKED } else { 0 };
+    let len = payload.len();
+    if len < WS_LEN_16 as usize {
+        buf.push(masked | len as u8);
+    } else if len <= u16::MAX as usize {
+        buf.push(masked | WS_LEN_16);
+        buf.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        buf.push(masked | WS_LEN_64);
+        buf.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+    match mask {
+        Some(key) => {
+            buf.extend_from_slice(&key);
+            buf.extend(payload.iter().enumerate().map(|(i, b)| b ^ key[i % 4]));
+        }
+        None => buf.extend_from_slice(payload),
+    }
+    buf
+}
+
+/// Decode a frame from the client at the head of `buf`.
+///
+/// Return the frame and the number of bytes it takes, or None if the frame is
+/// not complete yet.
+pub fn decode_frame(buf: &[u8]) -> std::result::Result<Option<(WsFrame, usize)>, WsFrameError> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    if buf[0] & WS_RSV_MASK != 0 {
+        // No extension is negotiated.
+        return Err(WsFrameError::Protocol);
+    }
+    let fin = buf[0] & WS_FIN != 0;
+    let opcode = buf[0] & WS_OPCODE_MASK;
+    if buf[1] & WS_MASKED == 0 {
+        return Err(WsFrameError::Protocol);
+    }
+    let (len, mut offset) = match buf[1] & WS_LEN_MASK {
+        WS_LEN_16 => {
+            if buf.len() < 4 {
+                return Ok(None);
+            }
+            (u16::from_be_bytes([buf[2], buf[3]]) as u64, 4)
+        }
+        WS_LEN_64 => {
+            if buf.len() < 10 {
+                return Ok(None);
+            }
+            let mut bytes = [0_u8; 8];
+            bytes.copy_from_slice(&buf[2..10]);
+            (u64::from_be_bytes(bytes), 10)
+        }
+        len => (len as u64, 2),
+    };
+    if opcode >= WS_OPCODE_CLOSE && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+        return Err(WsFrameError::Protocol);
+    }
+    if len > MAX_FRAME_PAYLOAD {
+        return Err(WsFrameError::TooBig);
+    }
+    let len = len as usize;
+    if buf.len() < offset + 4 + len {
+        return Ok(None);
+    }
+    let mut key = [0_u8; 4];
+    key.copy_from_slice(&buf[offset..offset + 4]);
+    offset += 4;
+    let payload = buf[offset..offset + len]
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ key[i % 4])
+        .collect();
+    Ok(Some((
+        WsFrame {
+            fin,
+            opcode,
+            payload,
+        },
+        offset + len,
+    )))
+}
+
+/// Compute `Sec-WebSocket-Accept` from `Sec-WebSocket-Key` of the client.
+fn accept_key(key: &str) -> String {
+    let mut ctx = digest::Context::new(&digest::SHA1_FOR_LEGACY_USE_ONLY);
+    ctx.update(key.as_bytes());
+    ctx.update(WS_GUID.as_bytes());
+    base64::encode(ctx.finish().as_ref())
+}
+
+/// Build the response to the HTTP upgrade request of a websocket client.
+///
+/// # Arguments
+///
+/// * `request` - The request, without the empty line at the end.
+pub fn handshake_response(request: &str) -> Result<String> {
+    let mut lines = request.split("\r\n");
+    let request_line = lines.next().unwrap_or_default();
+    let mut parts = request_line.split(' ');
+    if parts.next() != Some("GET") || parts.nth(1) != Some("HTTP/1.1") {
+        bail!("Invalid websocket request: {}", request_line);
+    }
+
+    let mut upgrade = false;
+    let mut connection = false;
+    let mut version = false;
+    let mut key = None;
+    let mut binary = false;
+    for line in lines {
+        let (name, value) = match line.split_once(':') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), value.trim()),
+            None => bail!("Invalid header in websocket request: {}", line),
+        };
+        let has_token = |token: &str| {
+            value
+                .split(',')
+                .any(|t| t.trim().eq_ignore_ascii_case(token))
+        };
+        match name.as_str() {
+            "upgrade" => upgrade = has_token("websocket"),
+            "connection" => connection = has_token("upgrade"),
+            "sec-websocket-version" => version = value == "13",
+            "sec-websocket-key" => key = Some(value),
+            "sec-websocket-protocol" => binary = has_token("binary"),
+            _ => {}
+        }
+    }
+    if !upgrade || !connection {
+        bail!("Not a websocket upgrade request");
+    }
+    if !version {
+        bail!("Unsupported websocket version");
+    }
+    let key = key.ok_or_else(|| anyhow!("Missing Sec-WebSocket-Key in websocket request"))?;
+
+    let mut response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n",
+        accept_key(key)
+    );
+    if binary {
+        response.push_str("Sec-WebSocket-Protocol: binary\r\n");
+    }
+    response.push_str("\r\n");
+    Ok(response)
+}
+
+/// RFB byte stream carried by websocket binary frames.
+pub struct WsStream {
+    stream: TcpStream,
+    /// Bytes received but not decoded yet.
+    recv_buf: Vec<u8>,
+    /// Payload decoded but not read yet.
+    payload: Vec<u8>,
+    /// A fragmented message is in progress.
+    fragmented: bool,
+    /// The close frame is sent.
+    closed: bool,
+}
+
+impl WsStream {
+    /// Do the websocket handshake with a new client. The stream is in blocking mode
+    /// during the handshake.
+    pub fn accept(mut stream: TcpStream) -> Result<Self> {
+        stream.set_nonblocking(false)?;
+        let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+
+        let mut buf = Vec::new();
+        let end = loop {
+            if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos;
+            }
+            if buf.len() > MAX_HANDSHAKE_LEN {
+                bail!(VncError::MakeConnectionFailed(
+                    "Websocket request is too long".to_string()
+                ));
+            }
+            let timeout = deadline.saturating_duration_since(Instant::now());
+            if timeout.is_zero() {
+                bail!(VncError::MakeConnectionFailed(
+                    "Websocket handshake timed out".to_string()
+                ));
+            }
+            stream.set_read_timeout(Some(timeout))?;
+            let mut bytes = [0_u8; RECV_CHUNK_LEN];
+            match stream.read(&mut bytes) {
+                Ok(0) => bail!(VncError::MakeConnectionFailed(
+                    "Websocket client disconnected during handshake".to_string()
+                )),
+                Ok(n) => buf.extend_from_slice(&bytes[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => bail!(VncError::MakeConnectionFailed(format!(
+                    "Websocket handshake failed: {}",
+                    e
+                ))),
+            }
+        };
+        let request = String::from_utf8_lossy(&buf[..end]).to_string();
+        let response = match handshake_response(&request) {
+            Ok(response) => response,
+            Err(e) => {
+                let _ = stream.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n");
+                return Err(anyhow!(VncError::MakeConnectionFailed(e.to_string())));
+            }
+        };
+        stream.write_all(response.as_bytes())?;
+        stream.set_read_timeout(None)?;
+
+        Ok(WsStream {
+            stream,
+            // The client may send frames right after the request.
+            recv_buf: buf[end + 4..].to_vec(),
+            payload: Vec::new(),
+            fragmented: false,
+            closed: false,
+        })
+    }
+
+    fn send_frame(&mut self, opcode: u8, payload: &[u8]) -> std::io::Result<()> {
+        let frame = encode_frame(true, opcode, payload, None);
+        let mut offset = 0;
+        while offset < frame.len() {
+            match self.stream.write(&frame[offset..]) {
+                Ok(0) => return Err(Error::from(ErrorKind::WriteZero)),
+                Ok(n) => offset += n,
+                // A frame can not be sent partially, wait for the client.
+                Err(ref e)
+                    if e.kind() == ErrorKind::WouldBlock || e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, code: u16) -> Error {
+        if !self.closed {
+            self.closed = true;
+            if let Err(e) = self.send_frame(WS_OPCODE_CLOSE, &code.to_be_bytes()) {
+                warn!("Failed to send websocket close frame: {}", e);
+            }
+        }
+        Error::new(
+            ErrorKind::ConnectionAborted,
+            format!("websocket closed with {}", code),
+        )
+    }
+
+    /// Decode the received frames. Return false if no complete frame is received.
+    fn decode_frames(&mut self) -> std::io::Result<bool> {
+        let mut decoded = false;
+        loop {
+            let (frame, len) = match decode_frame(&self.recv_buf) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(decoded),
+                Err(e) => return Err(self.close(e.close_code())),
+            };
+            self.recv_buf.drain(..len);
+            decoded = true;
+            match frame.opcode {
+                WS_OPCODE_BINARY | WS_OPCODE_CONTINUATION => {
+                    // A fragmented message continues until the frame with fin.
+                    if (frame.opcode == WS_OPCODE_CONTINUATION) != self.fragmented {
+                        return Err(self.close(WS_CLOSE_PROTOCOL_ERROR));
+                    }
+                    self.fragmented = !frame.fin;
+                    self.payload.extend_from_slice(&frame.payload);
+                }
+                WS_OPCODE_TEXT => return Err(self.close(WS_CLOSE_UNSUPPORTED_DATA)),
+                WS_OPCODE_PING => self.send_frame(WS_OPCODE_PONG, &frame.payload)?,
+                WS_OPCODE_PONG => {}
+                WS_OPCODE_CLOSE => {
+                    let code = match frame.payload.len() {
+                        0 => WS_CLOSE_NORMAL,
+                        1 => WS_CLOSE_PROTOCOL_ERROR,
+                        _ => u16::from_be_bytes([frame.payload[0], frame.payload[1]]),
+                    };
+                    return Err(self.close(code));
+                }
+                _ => return Err(self.close(WS_CLOSE_PROTOCOL_ERROR)),
+            }
+        }
+    }
+}
+
+impl Read for WsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if !self.payload.is_empty() {
+                let len = std::cmp::min(buf.len(), self.payload.len());
+                buf[..len].copy_from_slice(&self.payload[..len]);
+                self.payload.drain(..len);
+                return Ok(len);
+            }
+            if self.closed {
+                return Err(Error::from(ErrorKind::ConnectionAborted));
+            }
+            if self.decode_frames()? {
+                continue;
+            }
+            let mut bytes = [0_u8; RECV_CHUNK_LEN];
+            match self.stream.read(&mut bytes)? {
+                0 => return Ok(0),
+                n => self.recv_buf.extend_from_slice(&bytes[..n]),
+            }
+        }
+    }
+}
+
+impl Write for WsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.closed {
+            return Err(Error::from(ErrorKind::ConnectionAborted));
+        }
+        self.send_frame(WS_OPCODE_BINARY, buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+
+    const CLIENT_MASK: [u8; 4] = [0x12, 0x34, 0x56, 0x78];
+
+    #[test]
+    fn test_handshake_response() {
+        // The example in RFC 6455.
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+
+        let request = "GET /websockify HTTP/1.1\r\n\
+                       Host: 127.0.0.1:5700\r\n\
+                       Upgrade: websocket\r\n\
+                       Connection: keep-alive, Upgrade\r\n\
+                       Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       Sec-WebSocket-Protocol: binary, base64\r\n\
+                       Sec-WebSocket-Version: 13";
+        assert_eq!(
+            handshake_response(request).unwrap(),
+            "HTTP/1.1 101 Switching Protocols\r\n\
+             Upgrade: websocket\r\n\
+             Connection: Upgrade\r\n\
+             Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\
+             Sec-WebSocket-Protocol: binary\r\n\r\n"
+        );
+
+        let request = "GET / HTTP/1.1\r\n\
+                       upgrade: WebSocket\r\n\
+                       connection: upgrade\r\n\
+                       sec-websocket-key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                       sec-websocket-version: 13";
+        let response = handshake_response(request).unwrap();
+        assert!(response.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n"));
+        assert!(!response.contains("Sec-WebSocket-Protocol"));
+
+        // Missing key, wrong version, or not an upgrade.
+        assert!(handshake_response(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13"
+        )
+        .is_err());
+        assert!(handshake_response(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 8"
+        )
+        .is_err());
+        assert!(handshake_response(
+            "GET / HTTP/1.1\r\nConnection: keep-alive\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13"
+        )
+        .is_err());
+        assert!(handshake_response("POST / HTTP/1.1").is_err());
+    }
+
+    #[test]
+    fn test_frame_encode_decode() {
+        // Payload length in 7 bits, 16 bits and 64 bits.
+        for len in [0_usize, 125, 126, 65535, 65536, 200_000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let frame = encode_frame(true, WS_OPCODE_BINARY, &payload, None);
+            let header_len = match len {
+                0..=125 => 2,
+                126..=65535 => 4,
+                _ => 10,
+            };
+            assert_eq!(frame.len(), header_len + len);
+            assert_eq!(&frame[header_len..], payload.as_slice());
+
+            let frame = encode_frame(true, WS_OPCODE_BINARY, &payload, Some(CLIENT_MASK));
+            // An incomplete frame waits for more data.
+            assert_eq!(decode_frame(&frame[..frame.len() - 1]), Ok(None));
+            let (decoded, size) = decode_frame(&frame).unwrap().unwrap();
+            assert_eq!(size, frame.len());
+            assert!(decoded.fin);
+            assert_eq!(decoded.opcode, WS_OPCODE_BINARY);
+            assert_eq!(decoded.payload, payload);
+        }
+
+        // Frames from the client must be masked.
+        let frame = encode_frame(true, WS_OPCODE_BINARY, b"rfb", None);
+        assert_eq!(decode_frame(&frame), Err(WsFrameError::Protocol));
+        // Control frames can not be fragmented.
+        let frame = encode_frame(false, WS_OPCODE_PING, b"ping", Some(CLIENT_MASK));
+        assert_eq!(decode_frame(&frame), Err(WsFrameError::Protocol));
+        // Reserved bits are not supported.
+        let mut frame = encode_frame(true, WS_OPCODE_BINARY, b"rfb", Some(CLIENT_MASK));
+        frame[0] |= 0x40;
+        assert_eq!(decode_frame(&frame), Err(WsFrameError::Protocol));
+        let mut frame = vec![WS_FIN | WS_OPCODE_BINARY, WS_MASKED | WS_LEN_64];
+        frame.extend_from_slice(&(MAX_FRAME_PAYLOAD + 1).to_be_bytes());
+        assert_eq!(decode_frame(&frame), Err(WsFrameError::TooBig));
+    }
+
+    #[test]
+    fn test_ws_stream() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+
+        // A fragmented message right after the request, and a ping in the middle.
+        let mut data = b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                         Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                         Sec-WebSocket-Version: 13\r\n\r\n"
+            .to_vec();
+        data.extend(encode_frame(
+            false,
+            WS_OPCODE_BINARY,
+            b"RFB ",
+            Some(CLIENT_MASK),
+        ));
+        data.extend(encode_frame(true, WS_OPCODE_PING, b"hi", Some(CLIENT_MASK)));
+        data.extend(encode_frame(
+            true,
+            WS_OPCODE_CONTINUATION,
+            b"003.008\n",
+            Some(CLIENT_MASK),
+        ));
+        client.write_all(&data).unwrap();
+
+        let mut ws = WsStream::accept(server).unwrap();
+        let mut buf = [0_u8; 12];
+        ws.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"RFB 003.008\n");
+        ws.write_all(b"RFB 003.008\n").unwrap();
+
+        let mut expect = b"HTTP/1.1 101 Switching Protocols\r\n\
+                           Upgrade: websocket\r\n\
+                           Connection: Upgrade\r\n\
+                           Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo=\r\n\r\n"
+            .to_vec();
+        expect.extend(encode_frame(true, WS_OPCODE_PONG, b"hi", None));
+        expect.extend(encode_frame(true, WS_OPCODE_BINARY, b"RFB 003.008\n", None));
+        let mut received = vec![0_u8; expect.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expect);
+
+        // The close frame is echoed, and the stream is closed.
+        let code = 1001_u16.to_be_bytes();
+        client
+            .write_all(&encode_frame(
+                true,
+                WS_OPCODE_CLOSE,
+                &code,
+                Some(CLIENT_MASK),
+            ))
+            .unwrap();
+        assert!(ws.read(&mut buf).is_err());
+        let expect = encode_frame(true, WS_OPCODE_CLOSE, &code, None);
+        let mut received = vec![0_u8; expect.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expect);
+        assert!(ws.write(b"RFB").is_err());
+
+        // Text frames are not supported.
+        let (mut client, mut ws) = {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (server, _) = listener.accept().unwrap();
+            client
+                .write_all(
+                    b"GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+                      Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\
+                      Sec-WebSocket-Version: 13\r\n\r\n",
+                )
+                .unwrap();
+            let ws = WsStream::accept(server).unwrap();
+            let mut response = vec![0_u8; expect_response_len()];
+            client.read_exact(&mut response).unwrap();
+            (client, ws)
+        };
+        client
+            .write_all(&encode_frame(
+                true,
+                WS_OPCODE_TEXT,
+                b"RFB",
+                Some(CLIENT_MASK),
+            ))
+            .unwrap();
+        assert!(ws.read(&mut buf).is_err());
+        let expect = encode_frame(
+            true,
+            WS_OPCODE_CLOSE,
+            &WS_CLOSE_UNSUPPORTED_DATA.to_be_bytes(),
+            None,
+        );
+        let mut received = vec![0_u8; expect.len()];
+        client.read_exact(&mut received).unwrap();
+        assert_eq!(received, expect);
+    }
+
+    fn expect_response_len() -> usize {
+        handshake_response(
+            "GET / HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+             Sec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\nSec-WebSocket-Version: 13",
+        )
+        .unwrap()
+        .len()
+    }
+}
@@ -0,0 +1,375 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::cmp;
+use std::fs::File;
+use std::ops::Range;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, bail, Context, Result};
+use libc::c_void;
+
+use super::{iov_discard_front_direct, iov_from_buf_direct, raw_read, raw_write, Iovec};
+use crate::bitmap::Bitmap;
+use crate::file::{get_file_extent, get_file_size, is_block_device};
+use crate::unix::host_page_size;
+
+/// Min size in bytes of the clusters, which is the max alignment of direct IO.
+const MIN_CLUSTER_SIZE: u64 = 4096;
+
+/// Read-only backing image of an overlay image. The clusters not allocated in the overlay
+/// are read from the backing image, and they are copied up to the overlay before written,
+/// so the backing image is never modified.
+pub struct BackingImage {
+    /// Path of the backing image.
+    path: String,
+    /// The backing image, which is opened read-only.
+    file: File,
+    /// Size in bytes of both the backing image and the overlay.
+    size: u64,
+    /// Size in bytes of the allocation unit of the overlay.
+    cluster_size: u64,
+    /// Clusters allocated in the overlay.
+    allocated: Mutex<Bitmap<u64>>,
+}
+
+impl BackingImage {
+    /// Create the backing image of `overlay`, the clusters allocated in the overlay are
+    /// detected by `SEEK_DATA`/`SEEK_HOLE`. A cluster is allocated if any part of it is.
+    ///
+    /// # Arguments
+    ///
+    /// * `overlay` - The overlay image, which must be a regular file.
+    /// * `file` - The backing image, which must have the same size as the overlay.
+    /// * `path` - Path of the backing image.
+    pub fn new(overlay: &File, file: File, path: &str) -> Result<Self> {
+        if is_block_device(overlay) {
+            bail!(
+                "The overlay of backing image {} is not a regular file",
+                path
+            );
+        }
+        let size = get_file_size(&file)?;
+        let overlay_size = get_file_size(overlay)?;
+        if overlay_size != size {
+            bail!(
+                "The size {} of the overlay differs from the size {} of backing image {}",
+                overlay_size,
+                size,
+                path
+            );
+        }
+        let blksize = overlay
+            .metadata()
+            .with_context(|| "Failed to get metadata of the overlay")?
+            .blksize();
+        let cluster_size = cmp::max(blksize.next_power_of_two(), MIN_CLUSTER_SIZE);
+        let clusters = size.div_ceil(cluster_size) as usize;
+        let mut allocated = Bitmap::new(clusters.div_ceil(64).max(1));
+        let mut offset = 0;
+        while offset < size {
+            let (is_data, end) = get_file_extent(overlay, offset, size);
+            if is_data {
+                for cluster in offset / cluster_size..end.div_ceil(cluster_size) {
+                    allocated.set(cluster as usize)?;
+                }
+            }
+            offset = end;
+        }
+
+        Ok(BackingImage {
+            path: path.to_string(),
+            file,
+            size,
+            cluster_size,
+            allocated: Mutex::new(allocated),
+        })
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Check whether `len` bytes at `offset` are all allocated in the overlay, so that they
+    /// can be read from the overlay directly.
+    pub fn is_allocated(&self, offset: u64, len: u64) -> bool {
+        let allocated = self.allocated.lock().unwrap();
+        self.clusters(offset, len)
+            .all(|cluster| allocated.contain(cluster as usize).unwrap_or(true))
+    }
+
+    /// Read `len` bytes at `offset` to `iovec`. The allocated clusters are read from the
+    /// overlay `fd`, and the others from the backing image.
+    pub fn readv(&self, fd: RawFd, iovec: &[Iovec], offset: u64, len: u64) -> Result<()> {
+        self.check_range(offset, len)?;
+        let buf = ClusterBuf::new(self.cluster_size)?;
+        let mut iovec = iovec.to_vec();
+        let mut iovecs = &mut iovec[..];
+        let end = offset + len;
+        let mut pos = offset;
+        while pos < end {
+            let cluster = pos / self.cluster_size;
+            let allocated = self
+                .allocated
+                .lock()
+                .unwrap()
+                .contain(cluster as usize)
+                .unwrap_or(true);
+            let src_fd = if allocated { fd } else { self.file.as_raw_fd() };
+            let start = self.read_cluster(src_fd, cluster, &buf)?;
+
+            let copy_end = cmp::min(start + self.cluster_size, end);
+            // SAFETY: the buffer has `cluster_size` bytes, and the range is in the cluster.
+            let src = unsafe {
+                std::slice::from_raw_parts(
+                    (buf.addr() + pos - start) as *const u8,
+                    (copy_end - pos) as usize,
+                )
+            };
+            if iov_from_buf_direct(iovecs, src)? != src.len() {
+                bail!("Failed to copy cluster {} to iovec", cluster);
+            }
+            pos = copy_end;
+            if pos < end {
+                iovecs = iov_discard_front_direct(iovecs, src.len() as u64)
+                    .ok_or_else(|| anyhow!("Failed to adjust iovec for reading overlay"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Copy the clusters of `len` bytes at `offset` which are not allocated from the backing
+    /// image to the overlay `fd`, before they are written. The clusters fully covered by
+    /// the write are only marked allocated.
+    pub fn copy_up(&self, fd: RawFd, offset: u64, len: u64) -> Result<()> {
+        self.check_range(offset, len)?;
+        let mut allocated = self.allocated.lock().unwrap();
+        let mut buf = None;
+        for cluster in self.clusters(offset, len) {
+            if allocated.contain(cluster as usize)? {
+                continue;
+            }
+            let start = cluster * self.cluster_size;
+            let cluster_len = cmp::min(self.cluster_size, self.size - start);
+            if offset > start || offset + len < start + cluster_len {
+                if buf.is_none() {
+                    buf = Some(ClusterBuf::new(self.cluster_size)?);
+                }
+                let buf = buf.as_ref().unwrap();
+                self.read_cluster(self.file.as_raw_fd(), cluster, buf)?;
+                let ret = raw_write(fd, buf.addr(), cluster_len as usize, start as usize);
+                if ret < 0 || ret as u64 != cluster_len {
+                    bail!(
+                        "Failed to copy up cluster at {} of backing image {}, ret {}",
+                        start,
+                        self.path,
+                        ret
+                    );
+                }
+            }
+            allocated.set(cluster as usize)?;
+        }
+        Ok(())
+    }
+
+    fn check_range(&self, offset: u64, len: u64) -> Result<()> {
+        if offset
+            .checked_add(len)
+            .filter(|&end| end <= self.size)
+            .is_none()
+        {
+            bail!(
+                "Request offset {} len {} is beyond backing image {} of size {}",
+                offset,
+                len,
+                self.path,
+                self.size
+            );
+        }
+        Ok(())
+    }
+
+    fn clusters(&self, offset: u64, len: u64) -> Range<u64> {
+        if len == 0 {
+            return 0..0;
+        }
+        offset / self.cluster_size..(offset + len).div_ceil(self.cluster_size)
+    }
+
+    /// Read the whole `cluster` of `fd` to `buf`, return the offset of the cluster.
+    fn read_cluster(&self, fd: RawFd, cluster: u64, buf: &ClusterBuf) -> Result<u64> {
+        let start = cluster * self.cluster_size;
+        let cluster_len = cmp::min(self.cluster_size, self.size - start);
+        let ret = raw_read(fd, buf.addr(), cluster_len as usize, start as usize);
+        if ret < 0 || ret as u64 != cluster_len {
+            bail!(
+                "Failed to read cluster at {} of {} image {}, ret {}",
+                start,
+                if fd == self.file.as_raw_fd() {
+                    "backing"
+                } else {
+                    "overlay"
+                },
+                self.path,
+                ret
+            );
+        }
+        Ok(start)
+    }
+}
+
+/// Aligned buffer of one cluster, which works with `O_DIRECT`.
+struct ClusterBuf {
+    buf: *mut c_void,
+}
+
+impl ClusterBuf {
+    fn new(size: u64) -> Result<Self> {
+        // SAFETY: we allocate aligned memory and free it on drop.
+        let buf = unsafe { libc::memalign(host_page_size() as usize, size as usize) };
+        if buf.is_null() {
+            bail!("Failed to alloc {} bytes for cluster buffer", size);
+        }
+        Ok(ClusterBuf { buf })
+    }
+
+    fn addr(&self) -> u64 {
+        self.buf as u64
+    }
+}
+
+impl Drop for ClusterBuf {
+    fn drop(&mut self) {
+        // SAFETY: the memory is allocated by us and will not be used anymore.
+        unsafe { libc::free(self.buf) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::FileExt;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    const IMAGE_SIZE: u64 = 0x10_0000;
+
+    fn read(backing: &BackingImage, overlay: &File, offset: u64, len: u64) -> Vec<u8> {
+        let mut buf = vec![0_u8; len as usize];
+        // Split the buffer to check that the iovec is walked across the clusters.
+        let (head, tail) = buf.split_at_mut(len as usize / 3);
+        let iovec = vec![
+            Iovec {
+                iov_base: head.as_mut_ptr() as u64,
+                iov_len: head.len() as u64,
+            },
+            Iovec {
+                iov_base: tail.as_mut_ptr() as u64,
+                iov_len: tail.len() as u64,
+            },
+        ];
+        backing
+            .readv(overlay.as_raw_fd(), &iovec, offset, len)
+            .unwrap();
+        buf
+    }
+
+    fn write(backing: &BackingImage, overlay: &File, data: &[u8], offset: u64) {
+        backing
+            .copy_up(overlay.as_raw_fd(), offset, data.len() as u64)
+            .unwrap();
+        overlay.write_all_at(data, offset).unwrap();
+    }
+
+    #[test]
+    fn test_backing_image_copy_on_write() {
+        let base = TempFile::new().unwrap();
+        let base_data: Vec<u8> = (0..IMAGE_SIZE).map(|i| (i % 251) as u8).collect();
+        base.as_file().write_all_at(&base_data, 0).unwrap();
+        let overlay = TempFile::new().unwrap();
+        let overlay = overlay.as_file();
+        overlay.set_len(IMAGE_SIZE).unwrap();
+
+        let backing = BackingImage::new(
+            overlay,
+            File::open(base.as_path()).unwrap(),
+            base.as_path().to_str().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(backing.size(), IMAGE_SIZE);
+        let cluster = backing.cluster_size;
+        assert!(!backing.is_allocated(0, IMAGE_SIZE));
+        assert_eq!(read(&backing, overlay, 0, IMAGE_SIZE), base_data);
+
+        // Partial write of a cluster keeps the rest of it from the base.
+        let offset = cluster + 512;
+        write(&backing, overlay, &[0xaa; 1024], offset);
+        assert!(backing.is_allocated(cluster, cluster));
+        assert!(!backing.is_allocated(0, cluster));
+        // Write across a cluster boundary, fully covering the cluster in the middle.
+        let offset2 = 3 * cluster - 512;
+        write(
+            &backing,
+            overlay,
+            &vec![0xbb; cluster as usize + 1024],
+            offset2,
+        );
+        assert!(backing.is_allocated(2 * cluster, 3 * cluster));
+
+        let mut expected = base_data.clone();
+        expected[offset as usize..offset as usize + 1024].fill(0xaa);
+        expected[offset2 as usize..(offset2 + cluster) as usize + 1024].fill(0xbb);
+        assert_eq!(read(&backing, overlay, 0, IMAGE_SIZE), expected);
+        let (start, len) = (cluster - 100, 4 * cluster);
+        assert_eq!(
+            read(&backing, overlay, start, len),
+            expected[start as usize..(start + len) as usize]
+        );
+
+        // The base is untouched.
+        let mut data = vec![0_u8; IMAGE_SIZE as usize];
+        base.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert_eq!(data, base_data);
+
+        // The allocated clusters are detected when the overlay is opened again.
+        let backing =
+            BackingImage::new(overlay, File::open(base.as_path()).unwrap(), "base").unwrap();
+        assert!(backing.is_allocated(cluster, cluster));
+        assert!(!backing.is_allocated(0, cluster));
+        assert_eq!(read(&backing, overlay, 0, IMAGE_SIZE), expected);
+
+        // Requests beyond the image are rejected.
+        assert!(backing
+            .copy_up(overlay.as_raw_fd(), IMAGE_SIZE - 512, 1024)
+            .is_err());
+    }
+
+    #[test]
+    fn test_backing_image_size_mismatch() {
+        let base = TempFile::new().unwrap();
+        base.as_file().set_len(IMAGE_SIZE).unwrap();
+        let overlay = TempFile::new().unwrap();
+        overlay.as_file().set_len(IMAGE_SIZE * 2).unwrap();
+        assert!(BackingImage::new(
+            overlay.as_file(),
+            File::open(base.as_path()).unwrap(),
+            "base"
+        )
+        .is_err());
+    }
+}
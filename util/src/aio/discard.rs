@@ -0,0 +1,96 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::os::unix::io::AsRawFd;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Context, Result};
+use log::error;
+use vmm_sys_util::eventfd::EventFd;
+
+use super::raw_discard;
+
+/// Sender of the requests to the discard worker, it's None if the worker is not started.
+static DISCARD_WORKER: Mutex<Option<Sender<DiscardRequest>>> = Mutex::new(None);
+
+/// Discard request issued by the discard worker.
+pub(super) struct DiscardRequest {
+    /// The file duplicated from the one of the request, which is kept open until the
+    /// request is completed.
+    pub file: File,
+    pub offset: u64,
+    pub nbytes: u64,
+    pub user_data: u64,
+    pub done: Arc<DiscardDone>,
+}
+
+/// Discard requests completed by the worker, which are collected by the `Aio` issuing them.
+pub(super) struct DiscardDone {
+    /// The `user_data` and the result of the completed requests.
+    results: Mutex<Vec<(u64, i64)>>,
+    /// The eventfd of the `Aio`, it's notified when a request is completed.
+    evt: EventFd,
+}
+
+impl DiscardDone {
+    pub fn new(evt: EventFd) -> Self {
+        DiscardDone {
+            results: Mutex::new(Vec::new()),
+            evt,
+        }
+    }
+
+    /// Take the requests completed since last time.
+    pub fn take(&self) -> Vec<(u64, i64)> {
+        std::mem::take(&mut self.results.lock().unwrap())
+    }
+
+    fn complete(&self, user_data: u64, res: i64) {
+        self.results.lock().unwrap().push((user_data, res));
+        if let Err(e) = self.evt.write(1) {
+            error!("Failed to notify the completion of discard: {:?}", e);
+        }
+    }
+}
+
+/// Start the worker issuing the discard requests of all the aio instances. Discard isn't
+/// supported by the aio engines, and a large range may take long, so it's not done by the
+/// threads processing the IO. The worker can't be created under the seccomp filter, so
+/// it's started before the filter is installed.
+pub fn start_discard_worker() -> Result<()> {
+    let mut worker = DISCARD_WORKER.lock().unwrap();
+    if worker.is_some() {
+        return Ok(());
+    }
+    let (sender, receiver) = channel::<DiscardRequest>();
+    thread::Builder::new()
+        .name("discard worker".to_string())
+        .spawn(move || {
+            while let Ok(req) = receiver.recv() {
+                let res = raw_discard(req.file.as_raw_fd(), req.offset, req.nbytes);
+                // Close the file before the request is reported to be completed.
+                drop(req.file);
+                req.done.complete(req.user_data, res);
+            }
+        })
+        .with_context(|| "Failed to create the discard worker")?;
+    *worker = Some(sender);
+    Ok(())
+}
+
+/// Get the sender of the requests to the discard worker if it's started.
+pub(super) fn discard_worker() -> Option<Sender<DiscardRequest>> {
+    DISCARD_WORKER.lock().unwrap().clone()
+}
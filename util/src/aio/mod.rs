@@ -10,14 +10,20 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+mod backing;
+mod discard;
 mod libaio;
 mod raw;
 mod uring;
 
 use std::clone::Clone;
+use std::collections::HashSet;
+use std::fs::File;
 use std::io::Write;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{cmp, str::FromStr};
 
 use libc::c_void;
@@ -29,6 +35,9 @@ use super::link_list::{List, Node};
 use crate::num_ops::{round_down, round_up};
 use crate::unix::host_page_size;
 use anyhow::{anyhow, bail, Context, Result};
+pub use backing::BackingImage;
+pub use discard::start_discard_worker;
+use discard::{discard_worker, DiscardDone, DiscardRequest};
 use libaio::LibaioContext;
 pub use raw::*;
 use uring::IoUringContext;
@@ -45,6 +54,15 @@ const AIO_IOURING: &str = "io_uring";
 /// Max bytes of bounce buffer for misaligned IO.
 const MAX_LEN_BOUNCE_BUFF: u64 = 1 << 20;
 
+/// Total bytes of read/write requests submitted by all aio instances.
+static SUBMITTED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+/// Get the total bytes of read/write requests submitted so far. Sample it
+/// periodically to estimate the block IO load of the VM.
+pub fn aio_submitted_bytes() -> u64 {
+    SUBMITTED_BYTES.load(Ordering::Relaxed)
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize, Clone, Copy)]
 pub enum AioEngine {
     Off = 0,
@@ -77,6 +95,14 @@ trait AioContext<T: Clone> {
     fn submit(&mut self, iocbp: &[*const AioCb<T>]) -> Result<usize>;
     /// Get the IO events of the requests sumbitted earlier.
     fn get_events(&mut self) -> &[AioEvent];
+    /// Register the files to the OS, the files registered before are replaced.
+    fn register_files(&mut self, _fds: &[RawFd]) -> Result<()> {
+        bail!("Registering files is not supported by the aio engine")
+    }
+    /// Register the buffers to the OS, the buffers registered before are replaced.
+    fn register_buffers(&mut self, _bufs: &[Iovec]) -> Result<()> {
+        bail!("Registering buffers is not supported by the aio engine")
+    }
 }
 
 pub struct AioEvent {
@@ -91,6 +117,7 @@ pub enum OpCode {
     Preadv = 1,
     Pwritev = 2,
     Fdsync = 3,
+    Discard = 4,
 }
 
 pub struct AioCb<T: Clone> {
@@ -104,10 +131,32 @@ pub struct AioCb<T: Clone> {
     pub nbytes: u64,
     pub user_data: u64,
     pub iocompletecb: T,
+    /// Time when the request is submitted, it's set by `submit_request`.
+    pub submit_time: Option<Instant>,
+    /// Backing image of the file, which the clusters not allocated in the file are read from.
+    pub backing: Option<Arc<BackingImage>>,
+}
+
+impl<T: Clone> AioCb<T> {
+    /// Get the time in nanoseconds elapsed since the request was submitted.
+    pub fn latency_ns(&self) -> u64 {
+        self.submit_time
+            .map_or(0, |time| time.elapsed().as_nanos() as u64)
+    }
 }
 
 pub type AioCompleteFunc<T> = fn(&AioCb<T>, i64) -> Result<()>;
 
+/// A request which has been in flight for longer than the threshold.
+#[derive(Clone, Copy)]
+pub struct SlowRequest {
+    pub opcode: OpCode,
+    pub offset: usize,
+    pub nbytes: u64,
+    /// Time elapsed since the request was submitted.
+    pub age: Duration,
+}
+
 pub struct Aio<T: Clone + 'static> {
     ctx: Option<Box<dyn AioContext<T>>>,
     engine: AioEngine,
@@ -116,6 +165,14 @@ pub struct Aio<T: Clone + 'static> {
     pub aio_in_flight: CbList<T>,
     max_events: usize,
     complete_func: Arc<AioCompleteFunc<T>>,
+    /// Asynchronous write requests which are queued or in flight, by their `user_data`.
+    inflight_writes: HashSet<u64>,
+    /// Flush requests waiting for the writes submitted before them to complete.
+    pending_flushes: Vec<(AioCb<T>, HashSet<u64>)>,
+    /// Requests in flight which have been reported slow, by their `user_data`.
+    slow_reported: HashSet<u64>,
+    /// Discard requests completed by the discard worker.
+    discard_done: Arc<DiscardDone>,
 }
 
 pub fn aio_probe(engine: AioEngine) -> Result<()> {
@@ -143,6 +200,7 @@ impl<T: Clone + 'static> Aio<T> {
             AioEngine::IoUring => Some(Box::new(IoUringContext::new(max_events as u32, &fd)?)),
         };
 
+        let discard_done = Arc::new(DiscardDone::new(fd.try_clone()?));
         Ok(Aio {
             ctx,
             engine,
@@ -151,6 +209,10 @@ impl<T: Clone + 'static> Aio<T> {
             aio_in_flight: List::new(),
             max_events,
             complete_func: func,
+            inflight_writes: HashSet::new(),
+            pending_flushes: Vec::new(),
+            slow_reported: HashSet::new(),
+            discard_done,
         })
     }
 
@@ -159,6 +221,33 @@ impl<T: Clone + 'static> Aio<T> {
     }
 
     pub fn submit_request(&mut self, mut cb: AioCb<T>) -> Result<()> {
+        cb.submit_time = Some(Instant::now());
+        if matches!(cb.opcode, OpCode::Preadv | OpCode::Pwritev) {
+            SUBMITTED_BYTES.fetch_add(cb.nbytes, Ordering::Relaxed);
+        }
+        if let Some(backing) = cb.backing.clone() {
+            let offset = cb.offset as u64;
+            match cb.opcode {
+                // The overlay is read directly if the request doesn't involve the backing image.
+                OpCode::Preadv if !backing.is_allocated(offset, cb.nbytes) => {
+                    let res = match backing.readv(cb.file_fd, &cb.iovec, offset, cb.nbytes) {
+                        Ok(()) => cb.nbytes as i64,
+                        Err(e) => {
+                            error!("{:?}", e);
+                            -libc::EIO as i64
+                        }
+                    };
+                    return (self.complete_func)(&cb, res);
+                }
+                OpCode::Pwritev => {
+                    if let Err(e) = backing.copy_up(cb.file_fd, offset, cb.nbytes) {
+                        error!("{:?}", e);
+                        return (self.complete_func)(&cb, -libc::EIO as i64);
+                    }
+                }
+                _ => {}
+            }
+        }
         if self.request_misaligned(&cb) {
             let max_len = round_down(cb.nbytes + cb.req_align as u64 * 2, cb.req_align as u64)
                 .ok_or_else(|| anyhow!("Failed to round down request length."))?;
@@ -201,11 +290,48 @@ impl<T: Clone + 'static> Aio<T> {
                     self.flush_sync(cb)
                 }
             }
+            OpCode::Discard => {
+                if self.ctx.is_some() {
+                    self.discard_async(cb)
+                } else {
+                    self.discard_sync(cb)
+                }
+            }
             OpCode::Noop => Err(anyhow!("Aio opcode is not specified.")),
         }
     }
 
-    pub fn flush_request(&mut self) -> Result<()> {
+    /// Register the files which the requests are issued to. Return false if they
+    /// can't be registered, e.g. the engine doesn't support it or the limit of the host
+    /// is hit, and the requests are issued as the unregistered ones.
+    pub fn register_files(&mut self, fds: &[RawFd]) -> bool {
+        match self.ctx.as_mut().map(|ctx| ctx.register_files(fds)) {
+            Some(Ok(())) => true,
+            Some(Err(e)) => {
+                warn!("Fall back to unregistered files, {:?}", e);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Register the memory which the data of the requests is transferred from/to, such as
+    /// the guest memory. Return false if it can't be registered, and the requests are
+    /// issued as the unregistered ones.
+    pub fn register_buffers(&mut self, bufs: &[Iovec]) -> bool {
+        match self.ctx.as_mut().map(|ctx| ctx.register_buffers(bufs)) {
+            Some(Ok(())) => true,
+            Some(Err(e)) => {
+                warn!("Fall back to unregistered buffers, {:?}", e);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Submit the requests queued by `submit_request` in batch. The devices call it after
+    /// draining their queues, so that all the requests are submitted by one syscall.
+    pub fn submit_pending(&mut self) -> Result<()> {
         if self.ctx.is_some() {
             self.process_list()
         } else {
@@ -231,19 +357,87 @@ impl<T: Clone + 'static> Aio<T> {
                         "Async IO request failed, status {} res {}",
                         evt.status, evt.res
                     );
-                    -1
+                    // Pass the errno to the completion, a short transfer is an IO error.
+                    if evt.res < 0 {
+                        evt.res
+                    } else {
+                        -libc::EIO as i64
+                    }
                 };
 
                 (self.complete_func)(&(*node).value, res)?;
+                if !self.slow_reported.is_empty() {
+                    self.slow_reported.remove(&evt.user_data);
+                }
+                if (*node).value.opcode == OpCode::Pwritev {
+                    self.inflight_writes.remove(&evt.user_data);
+                    for (_, writes) in self.pending_flushes.iter_mut() {
+                        writes.remove(&evt.user_data);
+                    }
+                }
                 self.aio_in_flight.unlink(&(*node));
                 // Construct Box to free mem automatically.
                 drop(Box::from_raw(node));
             }
         }
+        for (user_data, res) in self.discard_done.take() {
+            // SAFETY: user_data is specified by discard_async and not dropped at other place.
+            unsafe {
+                let node = user_data as *mut CbNode<T>;
+                if res == 0 {
+                    done = true;
+                }
+                (self.complete_func)(&(*node).value, res)?;
+                self.slow_reported.remove(&user_data);
+                self.aio_in_flight.unlink(&(*node));
+                drop(Box::from_raw(node));
+            }
+        }
+        self.queue_ready_flushes();
         self.process_list()?;
         Ok(done)
     }
 
+    /// Get the requests in flight for longer than `threshold`. Each request is
+    /// returned once, until it completes.
+    pub fn take_slow_requests(&mut self, threshold: Duration) -> Vec<SlowRequest> {
+        let mut slow = Vec::new();
+        for cb in self.aio_in_flight.iter() {
+            let age = cb.submit_time.map_or(Duration::ZERO, |time| time.elapsed());
+            if age >= threshold && self.slow_reported.insert(cb.user_data) {
+                slow.push(SlowRequest {
+                    opcode: cb.opcode,
+                    offset: cb.offset,
+                    nbytes: cb.nbytes,
+                    age,
+                });
+            }
+        }
+        slow
+    }
+
+    /// Forget a write request which is completed, the flushes submitted after it no longer
+    /// wait for it.
+    fn write_done(&mut self, user_data: u64) {
+        if self.inflight_writes.remove(&user_data) {
+            for (_, writes) in self.pending_flushes.iter_mut() {
+                writes.remove(&user_data);
+            }
+        }
+    }
+
+    /// Queue the flush requests whose preceding writes are all completed.
+    fn queue_ready_flushes(&mut self) {
+        let (ready, waiting): (Vec<_>, Vec<_>) = self
+            .pending_flushes
+            .drain(..)
+            .partition(|(_, writes)| writes.is_empty());
+        self.pending_flushes = waiting;
+        for (cb, _) in ready {
+            self.queue_request(cb);
+        }
+    }
+
     fn process_list(&mut self) -> Result<()> {
         if self.ctx.is_none() {
             warn!("Can not process aio list with invalid ctx.");
@@ -285,6 +479,8 @@ impl<T: Clone + 'static> Aio<T> {
                 // Fail one request, retry the rest.
                 if let Some(node) = self.aio_in_queue.pop_tail() {
                     (self.complete_func)(&(node).value, -1)?;
+                    self.write_done(node.value.user_data);
+                    self.queue_ready_flushes();
                 }
             } else if nr == 0 {
                 // If can't submit any request, break the loop
@@ -295,11 +491,17 @@ impl<T: Clone + 'static> Aio<T> {
         Ok(())
     }
 
-    fn rw_async(&mut self, cb: AioCb<T>) -> Result<()> {
+    fn queue_request(&mut self, cb: AioCb<T>) {
         let mut node = Box::new(Node::new(cb));
         node.value.user_data = (&mut (*node) as *mut CbNode<T>) as u64;
-
+        if node.value.opcode == OpCode::Pwritev {
+            self.inflight_writes.insert(node.value.user_data);
+        }
         self.aio_in_queue.add_head(node);
+    }
+
+    fn rw_async(&mut self, cb: AioCb<T>) -> Result<()> {
+        self.queue_request(cb);
         if self.aio_in_queue.len + self.aio_in_flight.len >= self.max_events {
             self.process_list()?;
         }
@@ -317,7 +519,7 @@ impl<T: Clone + 'static> Aio<T> {
             error!("Failed to do sync read/write.");
         } else if ret as u64 != cb.nbytes {
             error!("Incomplete sync read/write.");
-            ret = -1;
+            ret = -libc::EIO as i64;
         }
         (self.complete_func)(&cb, ret)
     }
@@ -400,7 +602,8 @@ impl<T: Clone + 'static> Aio<T> {
             }
             OpCode::Pwritev => {
                 // Load the head from file before fill iovec to buffer.
-                if cb.offset as u64 > offset_align {
+                let head_loaded = cb.offset as u64 > offset_align;
+                if head_loaded {
                     let len = raw_read(
                         cb.file_fd,
                         bounce_buffer as u64,
@@ -411,8 +614,9 @@ impl<T: Clone + 'static> Aio<T> {
                         bail!("Failed to load head for misaligned write.");
                     }
                 }
-                // Is head and tail in the same alignment section?
-                let tail_loaded = (offset_align + cb.req_align as u64) >= high;
+                // Is head and tail in the same alignment section? The tail must be loaded
+                // for a sub-section write at an aligned offset, as the head is not loaded.
+                let tail_loaded = head_loaded && (offset_align + cb.req_align as u64) >= high;
                 let need_tail = !tail_loaded && (high_align > high);
 
                 let mut offset = offset_align;
@@ -477,8 +681,57 @@ impl<T: Clone + 'static> Aio<T> {
         }
     }
 
+    /// The kernel may complete a flush before the writes in flight, so the flush is held
+    /// until all the writes submitted before it are completed.
     fn flush_async(&mut self, cb: AioCb<T>) -> Result<()> {
-        self.rw_async(cb)
+        if self.inflight_writes.is_empty() {
+            return self.rw_async(cb);
+        }
+        self.pending_flushes
+            .push((cb, self.inflight_writes.clone()));
+        Ok(())
+    }
+
+    /// The discard requests are issued by the discard worker, or synchronously if the
+    /// worker is not started.
+    fn discard_async(&mut self, cb: AioCb<T>) -> Result<()> {
+        let worker = match discard_worker() {
+            Some(worker) => worker,
+            None => return self.discard_sync(cb),
+        };
+        // SAFETY: the fd of the request is valid.
+        let fd = unsafe { libc::fcntl(cb.file_fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if fd < 0 {
+            error!(
+                "Failed to duplicate fd for discard: {:?}",
+                std::io::Error::last_os_error()
+            );
+            return (self.complete_func)(&cb, -libc::EIO as i64);
+        }
+        // SAFETY: the fd is duplicated above and owned by the file only.
+        let file = unsafe { File::from_raw_fd(fd) };
+        let (offset, nbytes) = (cb.offset as u64, cb.nbytes);
+        let mut node = Box::new(Node::new(cb));
+        let user_data = (&mut (*node) as *mut CbNode<T>) as u64;
+        node.value.user_data = user_data;
+        let req = DiscardRequest {
+            file,
+            offset,
+            nbytes,
+            user_data,
+            done: self.discard_done.clone(),
+        };
+        if worker.send(req).is_err() {
+            error!("Failed to send the discard request to the worker.");
+            return (self.complete_func)(&node.value, -libc::EIO as i64);
+        }
+        self.aio_in_flight.add_head(node);
+        Ok(())
+    }
+
+    fn discard_sync(&mut self, cb: AioCb<T>) -> Result<()> {
+        let ret = raw_discard(cb.file_fd, cb.offset as u64, cb.nbytes);
+        (self.complete_func)(&cb, ret)
     }
 
     fn flush_sync(&mut self, cb: AioCb<T>) -> Result<()> {
@@ -551,3 +804,480 @@ pub fn iov_discard_front_direct(iovec: &mut [Iovec], mut size: u64) -> Option<&m
     }
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::io::AsRawFd;
+    use std::sync::Mutex;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    use super::*;
+
+    /// Completion results of the requests, by the opcode.
+    type Completed = Arc<Mutex<Vec<(u8, i64)>>>;
+
+    fn complete_func(aiocb: &AioCb<Completed>, ret: i64) -> Result<()> {
+        aiocb
+            .iocompletecb
+            .lock()
+            .unwrap()
+            .push((aiocb.opcode as u8, ret));
+        Ok(())
+    }
+
+    /// Aio context which records the submitted requests and completes them on demand.
+    struct MockContext {
+        submitted: Arc<Mutex<Vec<(u8, u64)>>>,
+        /// Number of the submitting syscalls.
+        submits: Arc<AtomicU64>,
+        finished: Arc<Mutex<Vec<AioEvent>>>,
+        events: Vec<AioEvent>,
+    }
+
+    impl<T: Clone> AioContext<T> for MockContext {
+        fn submit(&mut self, iocbp: &[*const AioCb<T>]) -> Result<usize> {
+            for iocb in iocbp {
+                // SAFETY: iocb is valid until request is finished.
+                let cb = unsafe { &*(*iocb) };
+                self.submitted
+                    .lock()
+                    .unwrap()
+                    .push((cb.opcode as u8, cb.user_data));
+            }
+            self.submits.fetch_add(1, Ordering::SeqCst);
+            Ok(iocbp.len())
+        }
+
+        fn get_events(&mut self) -> &[AioEvent] {
+            self.events = self.finished.lock().unwrap().drain(..).collect();
+            &self.events
+        }
+    }
+
+    fn aiocb(file_fd: RawFd, opcode: OpCode, completed: &Completed) -> AioCb<Completed> {
+        AioCb {
+            direct: false,
+            req_align: 1,
+            buf_align: 1,
+            file_fd,
+            opcode,
+            iovec: Vec::new(),
+            offset: 0,
+            nbytes: 0,
+            user_data: 0,
+            iocompletecb: completed.clone(),
+            submit_time: None,
+            backing: None,
+        }
+    }
+
+    #[test]
+    fn test_aio_sync_flush() {
+        let completed = Completed::default();
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+
+        let file = TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+        aio.submit_request(aiocb(fd, OpCode::Fdsync, &completed))
+            .unwrap();
+        assert_eq!(*completed.lock().unwrap(), vec![(OpCode::Fdsync as u8, 0)]);
+
+        // The error of fdatasync is passed to the completion.
+        let (sock, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+        aio.submit_request(aiocb(sock.as_raw_fd(), OpCode::Fdsync, &completed))
+            .unwrap();
+        assert_eq!(
+            completed.lock().unwrap()[1],
+            (OpCode::Fdsync as u8, -libc::EINVAL as i64)
+        );
+    }
+
+    #[test]
+    fn test_aio_discard() {
+        let completed = Completed::default();
+        let file = TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+
+        // The discard is done synchronously without the aio engine.
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        aio.submit_request(aiocb(fd, OpCode::Discard, &completed))
+            .unwrap();
+        assert_eq!(
+            *completed.lock().unwrap(),
+            vec![(OpCode::Discard as u8, -libc::ENOTTY as i64)]
+        );
+
+        // Otherwise it's issued by the discard worker, rather than the aio engine.
+        start_discard_worker().unwrap();
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        aio.ctx = Some(Box::new(MockContext {
+            submitted: submitted.clone(),
+            submits: Arc::new(AtomicU64::new(0)),
+            finished: Arc::new(Mutex::new(Vec::new())),
+            events: Vec::new(),
+        }));
+        completed.lock().unwrap().clear();
+        aio.submit_request(aiocb(fd, OpCode::Discard, &completed))
+            .unwrap();
+        aio.submit_pending().unwrap();
+        assert!(submitted.lock().unwrap().is_empty());
+        assert_eq!(aio.aio_in_flight.len, 1);
+
+        let mut pollfd = libc::pollfd {
+            fd: aio.fd.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        // SAFETY: pollfd is valid.
+        assert_eq!(unsafe { libc::poll(&mut pollfd, 1, 5000) }, 1);
+        aio.fd.read().unwrap();
+        aio.handle_complete().unwrap();
+        assert_eq!(
+            *completed.lock().unwrap(),
+            vec![(OpCode::Discard as u8, -libc::ENOTTY as i64)]
+        );
+        assert_eq!(aio.aio_in_flight.len, 0);
+    }
+
+    #[test]
+    fn test_aio_flush_after_writes() {
+        let completed = Completed::default();
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        aio.ctx = Some(Box::new(MockContext {
+            submitted: submitted.clone(),
+            submits: Arc::new(AtomicU64::new(0)),
+            finished: finished.clone(),
+            events: Vec::new(),
+        }));
+        let finish = |user_data: u64, res: i64| {
+            finished.lock().unwrap().push(AioEvent {
+                user_data,
+                status: 0,
+                res,
+            });
+        };
+
+        // The flush is held while the write submitted before it is in flight.
+        aio.submit_request(aiocb(0, OpCode::Pwritev, &completed))
+            .unwrap();
+        aio.submit_request(aiocb(0, OpCode::Fdsync, &completed))
+            .unwrap();
+        aio.submit_request(aiocb(0, OpCode::Pwritev, &completed))
+            .unwrap();
+        aio.submit_pending().unwrap();
+        let writes = submitted.lock().unwrap().clone();
+        assert_eq!(writes.len(), 2);
+        assert!(writes.iter().all(|(op, _)| *op == OpCode::Pwritev as u8));
+
+        // The write submitted after the flush doesn't hold it.
+        finish(writes[0].1, 0);
+        aio.handle_complete().unwrap();
+        let flush = submitted.lock().unwrap()[2];
+        assert_eq!(flush.0, OpCode::Fdsync as u8);
+        assert!(aio.pending_flushes.is_empty());
+
+        // The fsync error reaches the completion.
+        finish(flush.1, -libc::EIO as i64);
+        finish(writes[1].1, 0);
+        aio.handle_complete().unwrap();
+        assert_eq!(
+            *completed.lock().unwrap(),
+            vec![
+                (OpCode::Pwritev as u8, 0),
+                (OpCode::Fdsync as u8, -libc::EIO as i64),
+                (OpCode::Pwritev as u8, 0),
+            ]
+        );
+        assert!(aio.inflight_writes.is_empty());
+
+        // No write is in flight, the flush is submitted at once.
+        aio.submit_request(aiocb(0, OpCode::Fdsync, &completed))
+            .unwrap();
+        aio.submit_pending().unwrap();
+        assert_eq!(submitted.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn test_aio_submit_in_batch() {
+        let completed = Completed::default();
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let submits = Arc::new(AtomicU64::new(0));
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        aio.ctx = Some(Box::new(MockContext {
+            submitted: submitted.clone(),
+            submits: submits.clone(),
+            finished: Arc::new(Mutex::new(Vec::new())),
+            events: Vec::new(),
+        }));
+
+        // The mock engine can't register anything, the requests are issued as usual.
+        assert!(!aio.register_files(&[0]));
+        assert!(!aio.register_buffers(&[Iovec {
+            iov_base: 0,
+            iov_len: 4096,
+        }]));
+
+        // The requests are queued until the device submits them.
+        for _ in 0..8 {
+            aio.submit_request(aiocb(0, OpCode::Preadv, &completed))
+                .unwrap();
+        }
+        assert!(submitted.lock().unwrap().is_empty());
+        aio.submit_pending().unwrap();
+        assert_eq!(submitted.lock().unwrap().len(), 8);
+        assert_eq!(submits.load(Ordering::SeqCst), 1);
+        assert_eq!(aio.aio_in_flight.len, 8);
+
+        // Nothing is pending, no syscall is made.
+        aio.submit_pending().unwrap();
+        assert_eq!(submits.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_aio_slow_requests() {
+        let completed = Completed::default();
+        let submitted = Arc::new(Mutex::new(Vec::new()));
+        let finished = Arc::new(Mutex::new(Vec::new()));
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        aio.ctx = Some(Box::new(MockContext {
+            submitted: submitted.clone(),
+            submits: Arc::new(AtomicU64::new(0)),
+            finished: finished.clone(),
+            events: Vec::new(),
+        }));
+
+        // The requests queued are not in flight.
+        let mut cb = aiocb(0, OpCode::Preadv, &completed);
+        cb.offset = 4096;
+        cb.nbytes = 512;
+        aio.submit_request(cb).unwrap();
+        assert!(aio.take_slow_requests(Duration::ZERO).is_empty());
+
+        // The stuck request is reported once.
+        aio.submit_pending().unwrap();
+        assert!(aio.take_slow_requests(Duration::from_secs(3600)).is_empty());
+        let slow = aio.take_slow_requests(Duration::ZERO);
+        assert_eq!(slow.len(), 1);
+        assert!(slow[0].opcode == OpCode::Preadv);
+        assert_eq!((slow[0].offset, slow[0].nbytes), (4096, 512));
+        assert!(aio.take_slow_requests(Duration::ZERO).is_empty());
+
+        // The report is cleared once the request completes.
+        let user_data = submitted.lock().unwrap()[0].1;
+        finished.lock().unwrap().push(AioEvent {
+            user_data,
+            status: 0,
+            res: 512,
+        });
+        aio.handle_complete().unwrap();
+        assert!(aio.slow_reported.is_empty());
+        aio.submit_request(aiocb(0, OpCode::Pwritev, &completed))
+            .unwrap();
+        aio.submit_pending().unwrap();
+        assert_eq!(aio.take_slow_requests(Duration::ZERO).len(), 1);
+    }
+
+    /// Submit the requests in batch and wait for them to complete.
+    fn submit_and_wait(
+        aio: &mut Aio<Completed>,
+        cbs: Vec<AioCb<Completed>>,
+        completed: &Completed,
+    ) {
+        let nr = cbs.len() + completed.lock().unwrap().len();
+        for cb in cbs {
+            aio.submit_request(cb).unwrap();
+        }
+        aio.submit_pending().unwrap();
+        while completed.lock().unwrap().len() < nr {
+            aio.handle_complete().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_aio_iouring_register() {
+        if aio_probe(AioEngine::IoUring).is_err() {
+            return;
+        }
+        let completed = Completed::default();
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::IoUring).unwrap();
+        let file = TempFile::new().unwrap();
+        let fd = file.as_file().as_raw_fd();
+        let mut buf = vec![0_u8; 8 * 512];
+        let iov = |buf: &mut [u8], index: usize| Iovec {
+            iov_base: buf[index * 512..].as_mut_ptr() as u64,
+            iov_len: 512,
+        };
+        let rw = |opcode: OpCode, iovec: Iovec, offset: usize| {
+            let mut cb = aiocb(fd, opcode, &completed);
+            cb.nbytes = iovec.iov_len;
+            cb.iovec = vec![iovec];
+            cb.offset = offset;
+            cb
+        };
+
+        // Write by the registered file and buffer. The buffer isn't registered if
+        // RLIMIT_MEMLOCK is hit, the data is transferred either way.
+        assert!(aio.register_files(&[fd]));
+        aio.register_buffers(&[Iovec {
+            iov_base: buf.as_mut_ptr() as u64,
+            iov_len: buf.len() as u64,
+        }]);
+        for (i, byte) in buf.iter_mut().enumerate() {
+            *byte = (i / 512) as u8 + 1;
+        }
+        let cbs = (0..4)
+            .map(|i| rw(OpCode::Pwritev, iov(&mut buf, i), i * 512))
+            .collect();
+        submit_and_wait(&mut aio, cbs, &completed);
+
+        // Registering fails on the memory not mapped, read by the unregistered buffer.
+        assert!(!aio.register_buffers(&[Iovec {
+            iov_base: 0,
+            iov_len: 4096,
+        }]));
+        let cbs = (0..4)
+            .map(|i| rw(OpCode::Preadv, iov(&mut buf, i + 4), i * 512))
+            .collect();
+        submit_and_wait(&mut aio, cbs, &completed);
+
+        assert!(completed.lock().unwrap().iter().all(|(_, res)| *res == 512));
+        assert_eq!(buf[..4 * 512], buf[4 * 512..]);
+    }
+
+    /// Submit a direct request to a backend with 4K logical block size, the misaligned
+    /// requests are done by bounce buffer.
+    fn rw_4k_block(
+        aio: &mut Aio<Completed>,
+        file: &TempFile,
+        opcode: OpCode,
+        buf: &mut [u8],
+        offset: usize,
+        completed: &Completed,
+    ) {
+        let mut cb = aiocb(file.as_file().as_raw_fd(), opcode, completed);
+        cb.direct = true;
+        cb.req_align = 4096;
+        cb.buf_align = 4096;
+        cb.offset = offset;
+        cb.nbytes = buf.len() as u64;
+        // Split the buffer to check the iovecs are walked through correctly.
+        let half = buf.len() / 2;
+        cb.iovec = vec![
+            Iovec {
+                iov_base: buf.as_mut_ptr() as u64,
+                iov_len: half as u64,
+            },
+            Iovec {
+                iov_base: buf[half..].as_mut_ptr() as u64,
+                iov_len: (buf.len() - half) as u64,
+            },
+        ];
+        aio.submit_request(cb).unwrap();
+        assert_eq!(completed.lock().unwrap().pop().unwrap(), (opcode as u8, 0));
+    }
+
+    #[test]
+    fn test_aio_misaligned_rmw() {
+        let completed = Completed::default();
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        let file = TempFile::new().unwrap();
+        let disk_len = 4 * 4096;
+        let mut disk: Vec<u8> = (0..disk_len).map(|i| (i % 251) as u8).collect();
+        file.as_file().write_all(&disk).unwrap();
+
+        // (offset, len): sub-sector write at aligned offset, sub-sector write in the middle
+        // of a sector, write crossing sectors, write ending at sector boundary, write across
+        // the whole disk.
+        let writes = [
+            (0, 512),
+            (4096 + 1024, 512),
+            (4096 * 2 - 512, 1024),
+            (4096 * 3 + 512, 4096 - 512),
+            (512, disk_len - 1024),
+        ];
+        for (i, (offset, len)) in writes.iter().enumerate() {
+            let mut data = vec![0xa0 + i as u8; *len];
+            rw_4k_block(
+                &mut aio,
+                &file,
+                OpCode::Pwritev,
+                &mut data,
+                *offset,
+                &completed,
+            );
+            disk[*offset..*offset + *len].copy_from_slice(&data);
+
+            let mut content = vec![0_u8; disk_len];
+            raw_read(
+                file.as_file().as_raw_fd(),
+                content.as_mut_ptr() as u64,
+                disk_len,
+                0,
+            );
+            assert_eq!(content, disk);
+        }
+
+        // Misaligned read gets the data in the range only.
+        let mut data = vec![0_u8; 1536];
+        rw_4k_block(
+            &mut aio,
+            &file,
+            OpCode::Preadv,
+            &mut data,
+            4096 - 512,
+            &completed,
+        );
+        assert_eq!(data, disk[4096 - 512..4096 + 1024]);
+    }
+
+    #[test]
+    fn test_aio_backing_image() {
+        use std::fs::File;
+        use std::os::unix::fs::FileExt;
+
+        let completed = Completed::default();
+        let mut aio = Aio::new(Arc::new(complete_func), AioEngine::Off).unwrap();
+        let base = TempFile::new().unwrap();
+        base.as_file().write_all_at(&[0x5a; 0x10000], 0).unwrap();
+        let overlay = TempFile::new().unwrap();
+        overlay.as_file().set_len(0x10000).unwrap();
+        let backing = Arc::new(
+            BackingImage::new(
+                overlay.as_file(),
+                File::open(base.as_path()).unwrap(),
+                "base",
+            )
+            .unwrap(),
+        );
+        let fd = overlay.as_file().as_raw_fd();
+        let mut rw = |opcode: OpCode, buf: &mut [u8], offset: usize| {
+            let mut cb = aiocb(fd, opcode, &completed);
+            cb.iovec = vec![Iovec {
+                iov_base: buf.as_mut_ptr() as u64,
+                iov_len: buf.len() as u64,
+            }];
+            cb.offset = offset;
+            cb.nbytes = buf.len() as u64;
+            cb.backing = Some(backing.clone());
+            aio.submit_request(cb).unwrap();
+            let res = completed.lock().unwrap().pop().unwrap();
+            assert_eq!(res, (opcode as u8, buf.len() as i64));
+        };
+
+        // Write a pattern to the overlay, the reads merge it with the base.
+        rw(OpCode::Pwritev, &mut [0xa5; 1024], 0x2000);
+        let mut data = vec![0_u8; 0x4000];
+        rw(OpCode::Preadv, &mut data, 0x1000);
+        assert!(data[..0x1000].iter().all(|b| *b == 0x5a));
+        assert!(data[0x1000..0x1400].iter().all(|b| *b == 0xa5));
+        assert!(data[0x1400..].iter().all(|b| *b == 0x5a));
+
+        // The base is untouched.
+        let mut data = vec![0_u8; 0x10000];
+        base.as_file().read_exact_at(&mut data, 0).unwrap();
+        assert!(data.iter().all(|b| *b == 0x5a));
+    }
+}
@@ -11,6 +11,7 @@
 // See the Mulan PSL v2 for more details.
 
 use super::Iovec;
+use crate::file::BLKDISCARD;
 use libc::{c_int, c_void, fdatasync, iovec, off_t, pread, preadv, pwrite, pwritev, size_t};
 use log::error;
 use std::os::unix::io::RawFd;
@@ -32,13 +33,12 @@ pub fn raw_read(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let errno = errno::errno().0;
         error!(
             "Failed to pread: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0
+            buf, size, offset, errno,
         );
+        ret = -errno as i64;
     }
     ret
 }
@@ -60,11 +60,9 @@ pub fn raw_readv(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to preadv: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let errno = errno::errno().0;
+        error!("Failed to preadv: offset{}, errno{}.", offset, errno,);
+        ret = -errno as i64;
     }
     ret
 }
@@ -86,13 +84,12 @@ pub fn raw_write(fd: RawFd, buf: u64, size: usize, offset: usize) -> i64 {
         }
     }
     if ret < 0 {
+        let errno = errno::errno().0;
         error!(
             "Failed to pwrite: buf{}, size{}, offset{}, errno{}.",
-            buf,
-            size,
-            offset,
-            errno::errno().0,
+            buf, size, offset, errno,
         );
+        ret = -errno as i64;
     }
     ret
 }
@@ -114,20 +111,35 @@ pub fn raw_writev(fd: RawFd, iovec: &[Iovec], offset: usize) -> i64 {
         }
     }
     if ret < 0 {
-        error!(
-            "Failed to pwritev: offset{}, errno{}.",
-            offset,
-            errno::errno().0,
-        );
+        let errno = errno::errno().0;
+        error!("Failed to pwritev: offset{}, errno{}.", offset, errno,);
+        ret = -errno as i64;
     }
     ret
 }
 
 pub fn raw_datasync(fd: RawFd) -> i64 {
     // SAFETY: fd is valid.
-    let ret = unsafe { i64::from(fdatasync(fd)) };
+    let mut ret = unsafe { i64::from(fdatasync(fd)) };
     if ret < 0 {
-        error!("Failed to fdatasync: errno{}.", errno::errno().0);
+        let errno = errno::errno().0;
+        error!("Failed to fdatasync: errno{}.", errno);
+        ret = -errno as i64;
+    }
+    ret
+}
+
+pub fn raw_discard(fd: RawFd, offset: u64, size: u64) -> i64 {
+    let range: [u64; 2] = [offset, size];
+    // SAFETY: fd is valid and range is a valid [u64; 2].
+    let mut ret = unsafe { i64::from(libc::ioctl(fd, BLKDISCARD() as _, &range)) };
+    if ret < 0 {
+        let errno = errno::errno().0;
+        error!(
+            "Failed to discard: offset{}, size{}, errno{}.",
+            offset, size, errno
+        );
+        ret = -errno as i64;
     }
     ret
 }
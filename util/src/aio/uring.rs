@@ -10,19 +10,73 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use libc;
-use std::os::unix::io::AsRawFd;
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use anyhow::{bail, Context};
 use io_uring::{opcode, squeue, types, IoUring};
+use libc;
 use vmm_sys_util::eventfd::EventFd;
 
-use super::{AioCb, AioContext, AioEvent, OpCode, Result};
+use super::{AioCb, AioContext, AioEvent, Iovec, OpCode, Result};
+
+/// Max length of a registered buffer, which is limited by kernel.
+const MAX_FIXED_BUFFER_LEN: u64 = 1 << 30;
+
+/// Build the entry of the request on `$fd`, which is either a `types::Fd` or a
+/// `types::Fixed` of the registered file. The data is transferred by the registered
+/// buffer if `$buf_index` is given.
+macro_rules! build_entry {
+    ($fd:expr, $cb:expr, $buf_index:expr) => {
+        match ($cb.opcode, $buf_index) {
+            (OpCode::Preadv, Some(index)) => opcode::ReadFixed::new(
+                $fd,
+                $cb.iovec[0].iov_base as *mut u8,
+                $cb.iovec[0].iov_len as u32,
+                index,
+            )
+            .offset($cb.offset as libc::off_t)
+            .build(),
+            (OpCode::Preadv, None) => opcode::Readv::new(
+                $fd,
+                $cb.iovec.as_ptr() as *const libc::iovec,
+                $cb.iovec.len() as u32,
+            )
+            .offset($cb.offset as libc::off_t)
+            .build(),
+            (OpCode::Pwritev, Some(index)) => opcode::WriteFixed::new(
+                $fd,
+                $cb.iovec[0].iov_base as *const u8,
+                $cb.iovec[0].iov_len as u32,
+                index,
+            )
+            .offset($cb.offset as libc::off_t)
+            .build(),
+            (OpCode::Pwritev, None) => opcode::Writev::new(
+                $fd,
+                $cb.iovec.as_ptr() as *const libc::iovec,
+                $cb.iovec.len() as u32,
+            )
+            .offset($cb.offset as libc::off_t)
+            .build(),
+            (OpCode::Fdsync, _) => opcode::Fsync::new($fd)
+                .flags(types::FsyncFlags::DATASYNC)
+                .build(),
+            _ => {
+                bail!("Invalid entry code");
+            }
+        }
+    };
+}
 
 /// The io-uring context.
 pub(crate) struct IoUringContext {
     ring: IoUring,
     events: Vec<AioEvent>,
+    /// Index of the registered files, by their fd.
+    fixed_files: HashMap<RawFd, u32>,
+    /// Host address ranges (base, len) of the registered buffers, by their index.
+    fixed_buffers: Vec<(u64, u64)>,
 }
 
 impl IoUringContext {
@@ -42,46 +96,53 @@ impl IoUringContext {
             .register_eventfd(eventfd.as_raw_fd())
             .with_context(|| "Failed to register event fd")?;
         let events = Vec::with_capacity(entries as usize);
-        Ok(IoUringContext { ring, events })
+        Ok(IoUringContext {
+            ring,
+            events,
+            fixed_files: HashMap::new(),
+            fixed_buffers: Vec::new(),
+        })
+    }
+
+    /// Get the index of the registered buffer which contains the data of the request.
+    /// Only the request with a single iovec can use the registered buffer.
+    fn fixed_buffer_index<T: Clone>(&self, cb: &AioCb<T>) -> Option<u16> {
+        if cb.iovec.len() != 1 {
+            return None;
+        }
+        let iov = &cb.iovec[0];
+        self.fixed_buffers
+            .iter()
+            .position(|(base, len)| {
+                iov.iov_base >= *base && iov.iov_base + iov.iov_len <= base + len
+            })
+            .map(|index| index as u16)
     }
 }
 
 impl<T: Clone> AioContext<T> for IoUringContext {
     fn submit(&mut self, iocbp: &[*const AioCb<T>]) -> Result<usize> {
+        // Prepare the entries of all the requests, and submit them by one syscall.
+        let mut prepared = 0;
         for iocb in iocbp.iter() {
             // SAFETY: iocb is valid until request is finished.
             let cb = unsafe { &*(*iocb) };
-            let offset = cb.offset as libc::off_t;
-            let data = cb.user_data;
-            let len = cb.iovec.len();
-            let iovs = cb.iovec.as_ptr();
-            let fd = types::Fd(cb.file_fd);
-            let entry = match cb.opcode {
-                OpCode::Preadv => opcode::Readv::new(fd, iovs as *const libc::iovec, len as u32)
-                    .offset(offset)
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(data),
-                OpCode::Pwritev => opcode::Writev::new(fd, iovs as *const libc::iovec, len as u32)
-                    .offset(offset)
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(data),
-                OpCode::Fdsync => opcode::Fsync::new(fd)
-                    .build()
-                    .flags(squeue::Flags::ASYNC)
-                    .user_data(data),
-                _ => {
-                    bail!("Invalid entry code");
-                }
-            };
+            let buf_index = self.fixed_buffer_index(cb);
+            let entry = match self.fixed_files.get(&cb.file_fd) {
+                Some(index) => build_entry!(types::Fixed(*index), cb, buf_index),
+                None => build_entry!(types::Fd(cb.file_fd), cb, buf_index),
+            }
+            .flags(squeue::Flags::ASYNC)
+            .user_data(cb.user_data);
             // SAFETY: parameters of the entry are valid until request is finished.
-            unsafe {
-                self.ring
-                    .submission()
-                    .push(&entry)
-                    .with_context(|| "Failed to push entry")?;
+            if unsafe { self.ring.submission().push(&entry) }.is_err() {
+                // The submission queue is full, the rest are left to the next submission.
+                break;
             }
+            prepared += 1;
+        }
+        if prepared == 0 {
+            bail!("Failed to push entry");
         }
         self.ring.submit().with_context(|| "Failed to submit sqe")
     }
@@ -98,4 +159,59 @@ impl<T: Clone> AioContext<T> for IoUringContext {
         }
         &self.events
     }
+
+    fn register_files(&mut self, fds: &[RawFd]) -> Result<()> {
+        let submitter = self.ring.submitter();
+        if !self.fixed_files.is_empty() {
+            self.fixed_files.clear();
+            submitter
+                .unregister_files()
+                .with_context(|| "Failed to unregister files")?;
+        }
+        submitter
+            .register_files(fds)
+            .with_context(|| "Failed to register files")?;
+        self.fixed_files = fds
+            .iter()
+            .enumerate()
+            .map(|(index, fd)| (*fd, index as u32))
+            .collect();
+        Ok(())
+    }
+
+    fn register_buffers(&mut self, bufs: &[Iovec]) -> Result<()> {
+        let submitter = self.ring.submitter();
+        if !self.fixed_buffers.is_empty() {
+            self.fixed_buffers.clear();
+            submitter
+                .unregister_buffers()
+                .with_context(|| "Failed to unregister buffers")?;
+        }
+        // The buffers larger than the limit are split.
+        let mut ranges = Vec::new();
+        for buf in bufs {
+            let mut offset = 0;
+            while offset < buf.iov_len {
+                let len = std::cmp::min(buf.iov_len - offset, MAX_FIXED_BUFFER_LEN);
+                ranges.push((buf.iov_base + offset, len));
+                offset += len;
+            }
+        }
+        if ranges.len() > u16::MAX as usize {
+            bail!("Too many buffers to register: {}", ranges.len());
+        }
+        let iovecs: Vec<libc::iovec> = ranges
+            .iter()
+            .map(|(base, len)| libc::iovec {
+                iov_base: *base as *mut libc::c_void,
+                iov_len: *len as usize,
+            })
+            .collect();
+        // The buffers are pinned by kernel, which is limited by RLIMIT_MEMLOCK.
+        submitter
+            .register_buffers(&iovecs)
+            .with_context(|| "Failed to register buffers")?;
+        self.fixed_buffers = ranges;
+        Ok(())
+    }
 }
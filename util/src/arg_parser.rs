@@ -507,6 +507,13 @@ impl<'a> Arg<'a> {
                         self.value_name.unwrap_or(self.name)
                     )
                 }
+            } else if self.value.is_none() && self.values.is_none() {
+                format!(
+                    "{}{}{}",
+                    EIGHT_BLANK,
+                    PREFIX_OPT_LONG,
+                    self.opt_long.unwrap()
+                )
             } else {
                 format!(
                     "{}{}{}={}",
@@ -639,8 +646,9 @@ fn parse_cmdline(
 
         if cmd_arg.starts_with(PREFIX_OPT_LONG) {
             let splits = cmd_arg.split('=').collect::<Vec<&str>>();
-            // It has two arguments. e.g. "--modcaps=+sys_admin".
-            if splits.len() != 2 {
+            // It has two arguments, e.g. "--modcaps=+sys_admin", or is a flag without
+            // value, e.g. "--dump-options-json".
+            if splits.len() > 2 {
                 return Err(anyhow!(UtilError::UnexpectedArguments(cmd_arg.to_string())));
             }
             if !allow_list.contains(&splits[0].to_string()) {
@@ -652,10 +660,12 @@ fn parse_cmdline(
             } else {
                 multi_vec.push(arg_str.to_string());
             }
-            arg_map
-                .get_mut(arg_str.as_str())
-                .unwrap()
-                .push(splits[1].to_string());
+            if let Some(value) = splits.get(1) {
+                arg_map
+                    .get_mut(arg_str.as_str())
+                    .unwrap()
+                    .push(value.to_string());
+            }
         } else if cmd_arg.starts_with(PREFIX_CHARS_LONG) {
             let arg_str = split_arg(cmd_arg, PREFIX_CHARS_LONG);
 
@@ -766,6 +776,19 @@ mod tests {
                     .takes_value(false)
                     .required(false),
             )
+            .arg(
+                Arg::with_name("dump")
+                    .opt_long("dump")
+                    .help("dump the arguments")
+                    .takes_value(false),
+            )
+            .arg(
+                Arg::with_name("modcaps")
+                    .opt_long("modcaps")
+                    .value_name("capabilities_list")
+                    .help("modify the list of capabilities")
+                    .takes_value(true),
+            )
     }
 
     fn create_test_arg_matches(cmdline_str: &str) -> ArgMatches {
@@ -870,5 +893,32 @@ mod tests {
             arg_matches.values_of("drive").as_ref().unwrap(),
             &vec!["file=/path/to/rootfs,id=rootfs"]
         );
+        assert!(!arg_matches.is_present("dump"));
+        assert!(!arg_matches.is_present("modcaps"));
+    }
+
+    #[test]
+    fn test_opt_long_arg() {
+        let arg_matches =
+            create_test_arg_matches("stratovirt -qmp unix:sv.sock --dump --modcaps=-LEASE,+KILL");
+        assert!(arg_matches.is_present("dump"));
+        assert_eq!(
+            arg_matches.value_of("modcaps").as_ref().unwrap(),
+            "-LEASE,+KILL"
+        );
+
+        let arg_parser = create_test_arg();
+        for cmdline in ["stratovirt --dump=on", "stratovirt --modcaps"] {
+            let input_vec = cmdline
+                .split(' ')
+                .map(|item| item.to_string())
+                .collect::<Vec<String>>();
+            let (arg_hash, multi_vec, _) =
+                parse_cmdline(&input_vec, &arg_parser.allow_list).unwrap();
+            let mut args = arg_parser.args.clone();
+            assert!(args
+                .values_mut()
+                .any(|arg| arg.parse_from_hash(&arg_hash, &multi_vec).is_err()));
+        }
     }
 }
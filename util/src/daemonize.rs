@@ -35,15 +35,28 @@ use std::cmp::Ordering;
 use std::fs::{File, OpenOptions};
 use std::io::prelude::*;
 use std::os::unix::fs::OpenOptionsExt;
-use std::os::unix::io::RawFd;
-use std::path::Path;
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
 use std::process::exit;
+use std::sync::Mutex;
 
 use crate::UtilError;
 use anyhow::{anyhow, Result};
+use once_cell::sync::Lazy;
 
-/// Write process id to pid file.
-fn create_pid_file(path: &str) -> Result<()> {
+/// Status sent to the original process when the daemon starts successfully.
+const DAEMON_STATUS_OK: u8 = 0;
+/// Status sent to the original process when the daemon fails to start. It is
+/// followed by the error message.
+const DAEMON_STATUS_ERR: u8 = 1;
+
+/// The daemon side of the pipe connected to the original process, which waits
+/// for the startup result of the daemon.
+static DAEMON_NOTIFIER: Lazy<Mutex<Option<File>>> = Lazy::new(|| Mutex::new(None));
+
+/// Create pid file, lock it and write process id to it.
+///
+/// The lock is released once the returned file is closed.
+fn lock_pid_file(path: &str) -> Result<File> {
     let pid: u32 = std::process::id();
 
     let mut pid_file: File = OpenOptions::new()
@@ -51,30 +64,118 @@ fn create_pid_file(path: &str) -> Result<()> {
         .create(true)
         .mode(0o600)
         .open(path)?;
+    // SAFETY: the fd of pid file is valid.
+    let ret = unsafe { libc::flock(pid_file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+    if ret < 0 {
+        return Err(anyhow!(UtilError::PidFileLocked(path.to_string())));
+    }
+    pid_file.set_len(0)?;
     write!(pid_file, "{}", pid)?;
 
+    Ok(pid_file)
+}
+
+/// Write process id to pid file and hold the lock of it until process exits.
+fn create_pid_file(path: &str) -> Result<()> {
+    let pid_file = lock_pid_file(path)?;
+    // Leak the fd on purpose to keep the pid file locked during the lifetime of
+    // the process.
+    let _ = pid_file.into_raw_fd();
+
     Ok(())
 }
 
+/// Create a pipe, return the read end and the write end.
+fn create_pipe() -> Result<(File, File)> {
+    let mut fds: [RawFd; 2] = [-1; 2];
+    // SAFETY: fds is a valid array of two fds.
+    let ret = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    if ret < 0 {
+        return Err(anyhow!(std::io::Error::last_os_error()));
+    }
+    // SAFETY: both fds are newly created and owned by nobody else.
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Send the startup result of the daemon.
+fn send_daemon_status<W: Write>(writer: &mut W, result: &std::result::Result<(), String>) {
+    let mut buf = Vec::new();
+    match result {
+        Ok(()) => buf.push(DAEMON_STATUS_OK),
+        Err(msg) => {
+            buf.push(DAEMON_STATUS_ERR);
+            buf.extend_from_slice(msg.as_bytes());
+        }
+    }
+    // The original process may be killed. Nothing can be done in such case.
+    let _ = writer.write_all(&buf);
+}
+
+/// Receive the startup result of the daemon. If the daemon exits before
+/// sending any result, it's regarded as a failure.
+fn recv_daemon_status<R: Read>(reader: &mut R) -> std::result::Result<(), String> {
+    let mut buf = Vec::new();
+    if let Err(e) = reader.read_to_end(&mut buf) {
+        return Err(format!("Failed to get the status of daemon: {}", e));
+    }
+
+    match buf.first() {
+        Some(&DAEMON_STATUS_OK) => Ok(()),
+        Some(&DAEMON_STATUS_ERR) => Err(String::from_utf8_lossy(&buf[1..]).to_string()),
+        _ => Err("Daemon exited before finishing initialization".to_string()),
+    }
+}
+
+/// Wait for the startup result of the daemon in the original process, and
+/// return the exit code of the original process.
+fn wait_daemon_status(mut reader: File) -> i32 {
+    match recv_daemon_status(&mut reader) {
+        Ok(()) => 0,
+        Err(msg) => {
+            let _ = write!(&mut std::io::stderr(), "{}\r\n", msg);
+            1
+        }
+    }
+}
+
+fn notify_daemon_status(result: std::result::Result<(), String>) {
+    if let Some(mut writer) = DAEMON_NOTIFIER.lock().unwrap().take() {
+        send_daemon_status(&mut writer, &result);
+    }
+}
+
+/// Tell the original process that the daemon has started successfully, then
+/// the original process exits with zero code. It does nothing if the process
+/// is not daemonized or the result has been reported.
+pub fn notify_daemon_ready() {
+    notify_daemon_status(Ok(()));
+}
+
+/// Tell the original process that the daemon failed to start, then the
+/// original process prints `err` and exits with non-zero code. It does nothing
+/// if the process is not daemonized or the result has been reported.
+pub fn notify_daemon_error(err: &str) {
+    notify_daemon_status(Err(err.to_string()));
+}
+
 /// [fork(2)](https://man7.org/linux/man-pages/man2/fork.2.html)
 /// fork() creates a new process by duplicating the calling process. The new
 /// process is referred to as the child process. The calling process is referred
 /// to as the parent process.
 /// **libc::fork()** may have three kinds ret:
-/// if ret > 0 : current process is parent process, it's not expected, so exit
+/// if ret > 0 : current process is parent process, return child's pid
 /// if ret < 0 : error occurred in fork()
-/// if ret = 0 : current process is child process, it's expected
+/// if ret = 0 : current process is child process, return zero
 ///
 /// # Errors
 ///
 /// `DaemonFork` Error, the ret of `libc::fork()` is less than zero.
-fn fork() -> Result<()> {
+fn fork() -> Result<libc::pid_t> {
     let ret = unsafe { libc::fork() };
 
     match ret.cmp(&0) {
         Ordering::Less => Err(anyhow!(UtilError::DaemonFork)),
-        Ordering::Greater => exit(0),
-        Ordering::Equal => Ok(()),
+        _ => Ok(ret),
     }
 }
 
@@ -143,25 +244,41 @@ fn redirect_stdio(fd: RawFd) -> Result<()> {
 /// 2. Run in the background use fork.
 /// 3. Ignore all terminal I/O signals.
 /// 4. Disassociate from the control terminal.
-/// 5. Write pid to pidfile.
+/// 5. Write pid to pidfile and lock it.
+///
+/// The original process does not exit until the daemon reports its startup
+/// result by `notify_daemon_ready` or `notify_daemon_error`, and it exits with
+/// non-zero code if the daemon fails to start.
 pub fn daemonize(pid_file: Option<String>) -> Result<()> {
-    if let Some(path) = pid_file.as_ref() {
-        if Path::new(path).exists() {
-            return Err(anyhow!(UtilError::PidFileExist));
-        }
-    }
+    let (reader, writer) = create_pipe()?;
 
     // The first fork make parent process quit, child process inherit parent's
     // session ID and have a new process ID. It can guarantee child
     // process will not be the first process in a session.
-    fork()?;
+    if fork()? > 0 {
+        drop(writer);
+        exit(wait_daemon_status(reader));
+    }
+    drop(reader);
+    *DAEMON_NOTIFIER.lock().unwrap() = Some(writer);
+
+    let result = daemonize_child(pid_file);
+    if let Err(ref e) = result {
+        notify_daemon_error(&format!("{:?}", e));
+    }
+    result
+}
+
+fn daemonize_child(pid_file: Option<String>) -> Result<()> {
     // Create a new session for process. Now parent process quit will not
     // influence stratovirt process. But stratovirt becomes the first process in
     // new section.
     set_sid()?;
     // The second fork make stratovirt run as daemonize process. It won't be the
     // first process in this session and never get terminal control.
-    fork()?;
+    if fork()? > 0 {
+        exit(0);
+    }
     // Redirect stdio to `/dev/null`.
     redirect_stdio(libc::STDIN_FILENO)?;
     redirect_stdio(libc::STDOUT_FILENO)?;
@@ -175,3 +292,50 @@ pub fn daemonize(pid_file: Option<String>) -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempfile::TempFile;
+
+    #[test]
+    fn test_pid_file_lock() {
+        let tmp = TempFile::new().unwrap();
+        let path = tmp.as_path().to_str().unwrap().to_string();
+
+        let pid_file = lock_pid_file(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, std::process::id().to_string());
+
+        // The pid file is locked, it can't be used by others.
+        assert!(lock_pid_file(&path).is_err());
+
+        // The lock is released after the pid file is closed. The stale content is overwritten.
+        drop(pid_file);
+        std::fs::write(&path, "123456789").unwrap();
+        let _pid_file = lock_pid_file(&path).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content, std::process::id().to_string());
+    }
+
+    #[test]
+    fn test_daemon_status() {
+        let (mut reader, mut writer) = create_pipe().unwrap();
+        send_daemon_status(&mut writer, &Ok(()));
+        drop(writer);
+        assert!(recv_daemon_status(&mut reader).is_ok());
+
+        let (mut reader, mut writer) = create_pipe().unwrap();
+        send_daemon_status(&mut writer, &Err("Failed to realize".to_string()));
+        drop(writer);
+        assert_eq!(
+            recv_daemon_status(&mut reader),
+            Err("Failed to realize".to_string())
+        );
+
+        // Daemon exits without reporting anything.
+        let (mut reader, writer) = create_pipe().unwrap();
+        drop(writer);
+        assert!(recv_daemon_status(&mut reader).is_err());
+    }
+}
@@ -82,6 +82,12 @@ pub struct FdtReserveEntry {
     size: u64,
 }
 
+impl FdtReserveEntry {
+    pub fn new(address: u64, size: u64) -> Self {
+        FdtReserveEntry { address, size }
+    }
+}
+
 fn check_mem_reserve_overlap(mem_reservations: &[FdtReserveEntry]) -> bool {
     if mem_reservations.len() <= 1 {
         return true;
@@ -50,7 +50,7 @@ impl EdidInfo {
         }
     }
 
-    pub fn edid_array_fulfill(&mut self, edid_array: &mut Vec<u8>) {
+    pub fn edid_array_fulfill(&mut self, edid_array: &mut [u8]) {
         // The format follows VESA ENHANCED EXTENDED DISPLAY IDENTIFICATION DATA STANDARD
         if self.vendor.len() != 3 {
             // HWV for 'HUAWEI TECHNOLOGIES CO., INC.'
@@ -74,7 +74,8 @@ impl EdidInfo {
         let mut dta_offset: usize = 0;
         if edid_array.len() >= 256 {
             dta_offset = 128;
-            edid_array[126] += 1;
+            // Number of extension blocks.
+            edid_array[126] = 1;
             self.fullfill_ext_dta(edid_array, dta_offset);
         }
 
@@ -101,10 +102,10 @@ impl EdidInfo {
 
         // Video Input Definition: digital, 8bpc, displayport
         edid_array[20] = 0xa5;
-        // Horizontal Screen Size or Aspect Ratio
-        edid_array[21] = (self.prefx * self.dpi / 2540) as u8;
-        // Vertical Screen Size or Aspect Ratio
-        edid_array[22] = (self.prefy * self.dpi / 2540) as u8;
+        // Horizontal Screen Size or Aspect Ratio, in cm.
+        edid_array[21] = (self.dpi_to_mm(self.prefx) / 10).min(255) as u8;
+        // Vertical Screen Size or Aspect Ratio, in cm.
+        edid_array[22] = (self.dpi_to_mm(self.prefy) / 10).min(255) as u8;
         // Display Transfer Characteristic: display gamma is 2.2
         edid_array[23] = 220 - 100;
         // Feature Support: std sRGB, preferred timing
@@ -149,7 +150,15 @@ impl EdidInfo {
         self.fullfill_modes(edid_array, xtra3_offset, dta_offset);
 
         // EXTENSION Flag and Checksum
-        self.fullfill_checksum(edid_array)
+        self.fullfill_checksum(&mut edid_array[0..128]);
+        if dta_offset != 0 {
+            self.fullfill_checksum(&mut edid_array[dta_offset..dta_offset + 128]);
+        }
+    }
+
+    /// Convert the number of pixels to the physical size in mm.
+    fn dpi_to_mm(&self, res: u32) -> u32 {
+        res * 254 / 10 / self.dpi
     }
 
     fn fullfill_ext_dta(&mut self, edid_array: &mut [u8], offset: usize) {
@@ -163,13 +172,13 @@ impl EdidInfo {
 
     fn fullfill_color_space(&mut self, edid_array: &mut [u8], arr: [f32; 8]) {
         let red_x: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
-        let red_y: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
-        let green_x: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
-        let green_y: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
-        let blue_x: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
-        let blue_y: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
-        let white_x: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
-        let white_y: u32 = (arr[0] * 1024_f32 + 0.5) as u32;
+        let red_y: u32 = (arr[1] * 1024_f32 + 0.5) as u32;
+        let green_x: u32 = (arr[2] * 1024_f32 + 0.5) as u32;
+        let green_y: u32 = (arr[3] * 1024_f32 + 0.5) as u32;
+        let blue_x: u32 = (arr[4] * 1024_f32 + 0.5) as u32;
+        let blue_y: u32 = (arr[5] * 1024_f32 + 0.5) as u32;
+        let white_x: u32 = (arr[6] * 1024_f32 + 0.5) as u32;
+        let white_y: u32 = (arr[7] * 1024_f32 + 0.5) as u32;
 
         edid_array[25] = (((red_x & 0x03) << 6)
             | ((red_y & 0x03) << 4)
@@ -191,17 +200,21 @@ impl EdidInfo {
 
     fn fullfill_desc_timing(&mut self, edid_array: &mut [u8], offset: usize) {
         // physical display size
-        let xmm: u32 = self.prefx * self.dpi / 254;
-        let ymm: u32 = self.prefy * self.dpi / 254;
+        let xmm: u32 = self.dpi_to_mm(self.prefx);
+        let ymm: u32 = self.dpi_to_mm(self.prefy);
         let xfront: u32 = self.prefx * 25 / 100;
         let xsync: u32 = self.prefx * 3 / 100;
         let xblank: u32 = self.prefx * 35 / 100;
         let yfront: u32 = self.prefy * 5 / 1000;
         let ysync: u32 = self.prefy * 5 / 1000;
         let yblank: u32 = self.prefy * 35 / 1000;
-        let clock: u32 = 75 * (self.prefx + xblank) * (self.prefy + yblank);
+        let clock: u64 = 75 * (self.prefx + xblank) as u64 * (self.prefy + yblank) as u64;
 
-        LittleEndian::write_u16(&mut edid_array[offset..offset + 2], clock as u16);
+        // Pixel clock in 10 kHz units.
+        LittleEndian::write_u16(
+            &mut edid_array[offset..offset + 2],
+            (clock / 10000).min(u16::MAX as u64) as u16,
+        );
         edid_array[offset + 2] = (self.prefx & 0xff) as u8;
         edid_array[offset + 3] = (xblank & 0xff) as u8;
         edid_array[offset + 4] = (((self.prefx & 0xf00) >> 4) | ((xblank & 0xf00) >> 8)) as u8;
@@ -246,10 +259,13 @@ impl EdidInfo {
             edid_array[offset + i] = b' ';
         }
         if desc_type == 0xfc {
-            // name
-            for (index, c) in self.name.iter().enumerate() {
+            // name, at most 13 characters and terminated by '\n' if shorter.
+            for (index, c) in self.name.iter().take(13).enumerate() {
                 edid_array[offset + 5 + index] = (*c) as u8;
             }
+            if self.name.len() < 13 {
+                edid_array[offset + 5 + self.name.len()] = b'\n';
+            }
         } else if desc_type == 0xff {
             // serial
             LittleEndian::write_u32(&mut edid_array[offset + 5..offset + 9], self.serial);
@@ -434,7 +450,7 @@ impl EdidInfo {
             if mode.byte != 0 {
                 edid_array[mode.byte as usize] |= (1 << mode.bit) as u8;
             } else if mode.xtra3 != 0 && xtra3_offset != 0 {
-                edid_array[xtra3_offset] |= (1 << mode.bit) as u8;
+                edid_array[xtra3_offset + mode.xtra3 as usize] |= (1 << mode.bit) as u8;
             } else if std_offset < 54
                 && self.fullfill_std_mode(edid_array, std_offset, mode.xres, mode.yres) == 0
             {
@@ -477,29 +493,89 @@ impl EdidInfo {
             return -1;
         }
 
-        if (xres / 8) - 31 > 255 {
-            return -1;
-        }
-        edid_array[std_offset] = ((xres / 8) - 31) as u8;
+        let xcode = match (xres / 8).checked_sub(31) {
+            Some(code) if code > 0 && code <= 255 => code,
+            _ => return -1,
+        };
+        edid_array[std_offset] = xcode as u8;
         edid_array[std_offset + 1] = (aspect << 6) as u8;
         0
     }
 
     fn fullfill_ext_dta_mode(&mut self, edid_array: &mut [u8], dta_offset: usize, dta: u32) {
         let index = edid_array[dta_offset + 2] as usize;
-        edid_array[index] = dta as u8;
+        edid_array[dta_offset + index] = dta as u8;
         edid_array[dta_offset + 2] += 1;
         edid_array[dta_offset + 4] += 1;
     }
 
-    fn fullfill_checksum(&mut self, edid_array: &mut [u8]) {
-        let mut sum: u32 = 0;
-        for elem in edid_array.iter() {
-            sum += *elem as u32;
-        }
-        sum &= 0xff;
-        if sum != 0 {
-            edid_array[127] = (0x100 - sum) as u8;
-        }
+    /// Fill the checksum of a 128 bytes block in its last byte.
+    fn fullfill_checksum(&mut self, block: &mut [u8]) {
+        block[127] = 0;
+        let sum = block.iter().fold(0_u8, |sum, elem| sum.wrapping_add(*elem));
+        block[127] = 0_u8.wrapping_sub(sum);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn checksum(block: &[u8]) -> u8 {
+        block.iter().fold(0_u8, |sum, elem| sum.wrapping_add(*elem))
+    }
+
+    #[test]
+    fn test_edid_base_block() {
+        let mut edid = vec![0_u8; 128];
+        EdidInfo::new("HWV", "STRA Monitor", 100, 1280, 800).edid_array_fulfill(&mut edid);
+
+        assert_eq!(edid[0..8], [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+        assert_eq!(edid[126], 0);
+        assert_eq!(checksum(&edid), 0);
+        // 1280x800 at 100 dpi is 325mm x 203mm.
+        assert_eq!(edid[21], 32);
+        assert_eq!(edid[22], 20);
+
+        // The preferred timing is the first detailed timing descriptor.
+        let desc = &edid[54..72];
+        assert_ne!(LittleEndian::read_u16(&desc[0..2]), 0);
+        assert_eq!(desc[2] as u32 | ((desc[4] as u32 & 0xf0) << 4), 1280);
+        assert_eq!(desc[5] as u32 | ((desc[7] as u32 & 0xf0) << 4), 800);
+        assert_eq!(desc[12] as u32 | ((desc[14] as u32 & 0xf0) << 4), 325);
+        assert_eq!(desc[13] as u32 | ((desc[14] as u32 & 0x0f) << 8), 203);
+
+        // Monitor name.
+        assert_eq!(&edid[72 + 18 + 5..72 + 18 + 18], b"STRA Monitor\n");
+        // Modes larger than the preferred one are not advertised.
+        assert_eq!(edid[36] & (1 << 3), 1 << 3);
+        let xtra3 = 72 + 18 * 2;
+        assert_eq!(edid[xtra3 + 3], 0xf7);
+        assert_eq!(edid[xtra3 + 7] & (1 << 6), 1 << 6);
+        assert_eq!(edid[xtra3 + 7] & (1 << 1), 0);
+    }
+
+    #[test]
+    fn test_edid_extension_block() {
+        let mut edid = vec![0_u8; 256];
+        EdidInfo::new("HWV", "STRA Monitor", 100, 1920, 1080).edid_array_fulfill(&mut edid);
+
+        assert_eq!(edid[126], 1);
+        assert_eq!(checksum(&edid[0..128]), 0);
+        assert_eq!(checksum(&edid[128..256]), 0);
+        // CEA extension with one video data block which holds VIC 31.
+        assert_eq!(edid[128], 0x02);
+        assert_eq!(edid[128 + 4], 0x41);
+        assert_eq!(edid[128 + 5], 31);
+        assert_eq!(edid[128 + 2], 6);
+    }
+
+    #[test]
+    fn test_edid_small_resolution() {
+        let mut edid = vec![0_u8; 128];
+        EdidInfo::new("HWV", "STRA Monitor", 100, 64, 48).edid_array_fulfill(&mut edid);
+        assert_eq!(checksum(&edid), 0);
+        // No standard timing fits, all slots are unused.
+        assert!(edid[38..54].iter().all(|b| *b == 0x01));
     }
 }
@@ -55,8 +55,8 @@ pub enum UtilError {
     DaemonSetsid,
     #[error("Unable to redirect standard streams to /dev/null.")]
     DaemonRedirectStdio,
-    #[error("Pidfile path is existed yet.")]
-    PidFileExist,
+    #[error("Pidfile {0} is locked by another process.")]
+    PidFileLocked(String),
     // epoll_context error
     #[error("Found bad syscall, error is {0} .")]
     BadSyscall(std::io::Error),
@@ -10,15 +10,23 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::fs::{File, OpenOptions};
-use std::os::unix::fs::OpenOptionsExt;
+use std::fs::{read_to_string, File, OpenOptions};
+use std::os::unix::fs::{FileTypeExt, MetadataExt, OpenOptionsExt};
 use std::os::unix::io::AsRawFd;
 
 use anyhow::{bail, Context, Result};
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::{ioctl_io_nr, ioctl_ioc_nr, ioctl_ior_nr};
 
 const MIN_FILE_ALIGN: u32 = 512;
 const MAX_FILE_ALIGN: u32 = 4096;
 
+// See: https://elixir.bootlin.com/linux/v4.19.123/source/include/uapi/linux/fs.h
+ioctl_io_nr!(BLKSSZGET, 0x12, 104);
+ioctl_ior_nr!(BLKGETSIZE64, 0x12, 114, u64);
+ioctl_io_nr!(BLKDISCARD, 0x12, 119);
+ioctl_io_nr!(BLKPBSZGET, 0x12, 123);
+
 pub fn open_file(path: &str, read_only: bool, direct: bool) -> Result<File> {
     let mut options = OpenOptions::new();
     options.read(true).write(!read_only);
@@ -127,3 +135,189 @@ pub fn unlock_file(file: &File, path: &str) -> Result<()> {
 
     Ok(())
 }
+
+/// Check whether the file is a host block device.
+pub fn is_block_device(file: &File) -> bool {
+    file.metadata()
+        .map(|meta| meta.file_type().is_block_device())
+        .unwrap_or(false)
+}
+
+/// Get the size in bytes of a regular file or a host block device, whose
+/// `st_size` is always 0.
+pub fn get_file_size(file: &File) -> Result<u64> {
+    if !is_block_device(file) {
+        let meta = file
+            .metadata()
+            .with_context(|| "Failed to get metadata of file")?;
+        return Ok(meta.len());
+    }
+
+    let mut size = 0_u64;
+    // SAFETY: the file has a valid raw fd and size is a valid u64.
+    let ret = unsafe { ioctl_with_mut_ref(file, BLKGETSIZE64(), &mut size) };
+    if ret < 0 {
+        bail!(
+            "Failed to get size of block device. Error: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(size)
+}
+
+/// Get the logical and physical sector size of a host block device.
+pub fn get_block_device_sector_size(file: &File) -> Result<(u32, u32)> {
+    let mut logical: libc::c_int = 0;
+    let mut physical: libc::c_uint = 0;
+    // SAFETY: the file has a valid raw fd and the arguments are valid integers.
+    let ret = unsafe { ioctl_with_mut_ref(file, BLKSSZGET(), &mut logical) };
+    if ret < 0 || logical <= 0 {
+        bail!(
+            "Failed to get logical sector size of block device. Error: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    // SAFETY: same as above.
+    let ret = unsafe { ioctl_with_mut_ref(file, BLKPBSZGET(), &mut physical) };
+    if ret < 0 {
+        bail!(
+            "Failed to get physical sector size of block device. Error: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    let logical = logical as u32;
+    Ok((logical, std::cmp::max(logical, physical)))
+}
+
+/// Get the max bytes of one discard request of a host block device, 0 means
+/// discard is not supported.
+pub fn get_block_device_max_discard(file: &File) -> u64 {
+    let rdev = match file.metadata() {
+        Ok(meta) => meta.rdev(),
+        Err(_) => return 0,
+    };
+    // SAFETY: major() and minor() only do the bit operations.
+    let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+    let path = format!("/sys/dev/block/{}:{}/queue/discard_max_bytes", major, minor);
+    read_to_string(path)
+        .ok()
+        .and_then(|s| s.trim().parse::<u64>().ok())
+        .unwrap_or(0)
+}
+
+/// Discard `len` bytes from `offset` of a host block device.
+pub fn discard_block_device(file: &File, offset: u64, len: u64) -> Result<()> {
+    let range: [u64; 2] = [offset, len];
+    // SAFETY: the file has a valid raw fd and range is a valid [u64; 2].
+    let ret = unsafe { ioctl_with_ref(file, BLKDISCARD(), &range) };
+    if ret < 0 {
+        bail!(
+            "Failed to discard block device, offset {} len {}. Error: {}",
+            offset,
+            len,
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+/// Get the allocation state of a file from `offset`: whether the bytes from `offset` are
+/// data or a hole, and where the data or the hole ends, which is not beyond `end`.
+///
+/// Everything is data for host block devices and file systems not reporting holes.
+pub fn get_file_extent(file: &File, offset: u64, end: u64) -> (bool, u64) {
+    if is_block_device(file) {
+        return (true, end);
+    }
+    // SAFETY: the file has a valid raw fd. The file offset is changed, which is not used
+    // by the positional IO of the disk image.
+    let data = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) };
+    if data < 0 {
+        // ENXIO means there is no data beyond `offset`.
+        let is_hole = std::io::Error::last_os_error().raw_os_error() == Some(libc::ENXIO);
+        return (!is_hole, end);
+    }
+    if data as u64 > offset {
+        return (false, std::cmp::min(data as u64, end));
+    }
+    // SAFETY: same as above.
+    let hole = unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_HOLE) };
+    if hole <= offset as libc::off_t {
+        return (true, end);
+    }
+    (true, std::cmp::min(hole as u64, end))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    use vmm_sys_util::tempfile::TempFile;
+
+    /// Loop device backed by a temporary file, detached on drop.
+    struct LoopDevice {
+        path: String,
+        _backing: TempFile,
+    }
+
+    impl LoopDevice {
+        /// Set up a loop device of `size` bytes with `losetup`, which requires root.
+        fn new(size: u64, sector_size: u32) -> Self {
+            let backing = TempFile::new().unwrap();
+            backing.as_file().set_len(size).unwrap();
+            let output = Command::new("losetup")
+                .args(["-f", "--show", "--sector-size", &sector_size.to_string()])
+                .arg(backing.as_path())
+                .output()
+                .unwrap();
+            assert!(
+                output.status.success(),
+                "Failed to set up loop device: {}",
+                String::from_utf8_lossy(&output.stderr)
+            );
+            let path = String::from_utf8(output.stdout).unwrap().trim().to_string();
+            LoopDevice {
+                path,
+                _backing: backing,
+            }
+        }
+    }
+
+    impl Drop for LoopDevice {
+        fn drop(&mut self) {
+            let _ = Command::new("losetup").args(["-d", &self.path]).status();
+        }
+    }
+
+    #[test]
+    fn test_regular_file_size() {
+        let file = TempFile::new().unwrap();
+        file.as_file().set_len(0x10_0000).unwrap();
+        assert!(!is_block_device(file.as_file()));
+        assert_eq!(get_file_size(file.as_file()).unwrap(), 0x10_0000);
+        assert!(get_block_device_sector_size(file.as_file()).is_err());
+    }
+
+    /// Run it as root with `cargo test -p util -- --ignored test_block_device`.
+    #[test]
+    #[ignore = "requires root to set up loop devices"]
+    fn test_block_device() {
+        for sector_size in [512, 4096] {
+            let loop_dev = LoopDevice::new(0x80_0000, sector_size);
+            let file = open_file(&loop_dev.path, false, true).unwrap();
+            assert!(is_block_device(&file));
+            assert_eq!(file.metadata().unwrap().len(), 0);
+            assert_eq!(get_file_size(&file).unwrap(), 0x80_0000);
+            let (logical, physical) = get_block_device_sector_size(&file).unwrap();
+            assert_eq!(logical, sector_size);
+            assert!(physical >= logical);
+            assert_eq!(get_file_alignment(&file, true).0, sector_size);
+
+            if get_block_device_max_discard(&file) != 0 {
+                assert!(discard_block_device(&file, 0, sector_size as u64).is_ok());
+            }
+            assert!(discard_block_device(&file, 0x100_0000, sector_size as u64).is_err());
+        }
+    }
+}
@@ -10,7 +10,7 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-/// We use Leaky Bucket Algorithm to limit iops of block device and qmp.
+/// We use Leaky Bucket Algorithm to limit iops of block device, bandwidth of net device and qmp.
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::sync::Arc;
 use std::time::Instant;
@@ -72,18 +72,10 @@ impl LeakBucket {
         }
 
         // update the water level
-        let now = get_current_time();
-        let nanos = (now - self.prev_time).as_nanos();
-        if nanos > (self.level * NANOSECONDS_PER_SECOND / self.capacity) as u128 {
-            self.level = 0;
-        } else {
-            self.level -= nanos as u64 * self.capacity / NANOSECONDS_PER_SECOND;
-        }
-
-        self.prev_time = now;
+        self.leak(get_current_time());
 
         // need to be throttled
-        if self.level > self.capacity {
+        if let Some(delay) = self.overflow_delay() {
             let wakeup_clone = self.timer_wakeup.clone();
             let func = Box::new(move || {
                 wakeup_clone
@@ -91,21 +83,51 @@ impl LeakBucket {
                     .unwrap_or_else(|e| error!("LeakBucket send event to device failed {:?}", e));
             });
 
-            loop_context.delay_call(
-                func,
-                (self.level - self.capacity) * NANOSECONDS_PER_SECOND / self.capacity,
-            );
+            loop_context.delay_call(func, delay);
 
             self.timer_started = true;
 
             return true;
         }
 
-        self.level += need_units * ACCURACY_SCALE;
+        self.consume(need_units);
 
         false
     }
 
+    /// Add the units which have been used to the bucket. It's used when the units are
+    /// only known after the operation, and the following operations are throttled if
+    /// the bucket overflows.
+    pub fn consume(&mut self, units: u64) {
+        if self.capacity != 0 {
+            self.level = self
+                .level
+                .saturating_add(units.saturating_mul(ACCURACY_SCALE));
+        }
+    }
+
+    /// Leak the water for the time elapsed from last update. The computation is in u128
+    /// as the level of byte based buckets multiplied by nanoseconds overflows u64.
+    fn leak(&mut self, now: Instant) {
+        let nanos = now.saturating_duration_since(self.prev_time).as_nanos();
+        let leaked = nanos * self.capacity as u128 / NANOSECONDS_PER_SECOND as u128;
+        self.level = self
+            .level
+            .saturating_sub(leaked.min(u64::MAX as u128) as u64);
+        self.prev_time = now;
+    }
+
+    /// Return the delay in nanoseconds until the bucket is not overflowed, or `None` if
+    /// the bucket is not overflowed.
+    fn overflow_delay(&self) -> Option<u64> {
+        if self.level <= self.capacity {
+            return None;
+        }
+        let delay = (self.level - self.capacity) as u128 * NANOSECONDS_PER_SECOND as u128
+            / self.capacity as u128;
+        Some(delay.min(u64::MAX as u128) as u64)
+    }
+
     /// Clear the timer state.
     pub fn clear_timer(&mut self) {
         self.timer_started = false;
@@ -116,3 +138,57 @@ impl LeakBucket {
         self.timer_wakeup.as_raw_fd()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_leak_bucket_level() {
+        let mut lb = LeakBucket::new(100).unwrap();
+        let start = lb.prev_time;
+
+        // Burst up to the capacity of one second is allowed.
+        lb.consume(100);
+        assert_eq!(lb.overflow_delay(), None);
+        lb.consume(10);
+        assert_eq!(lb.overflow_delay(), Some(NANOSECONDS_PER_SECOND / 10));
+
+        // 20 units leak in 200ms.
+        lb.leak(start + Duration::from_millis(200));
+        assert_eq!(lb.level, 90 * ACCURACY_SCALE);
+        assert_eq!(lb.overflow_delay(), None);
+
+        // Time going backwards doesn't change the level.
+        lb.leak(start);
+        assert_eq!(lb.level, 90 * ACCURACY_SCALE);
+
+        // The bucket is empty after a long time.
+        lb.leak(start + Duration::from_secs(10));
+        assert_eq!(lb.level, 0);
+    }
+
+    #[test]
+    fn test_leak_bucket_large_rate() {
+        // 10 GB per second doesn't overflow the computation.
+        let rate = 10 * 1024 * 1024 * 1024;
+        let mut lb = LeakBucket::new(rate).unwrap();
+        let start = lb.prev_time;
+        lb.consume(rate * 2);
+        assert_eq!(lb.overflow_delay(), Some(NANOSECONDS_PER_SECOND));
+        lb.leak(start + Duration::from_millis(500));
+        assert_eq!(lb.level, rate * 3 / 2 * ACCURACY_SCALE);
+        assert_eq!(lb.overflow_delay(), Some(NANOSECONDS_PER_SECOND / 2));
+    }
+
+    #[test]
+    fn test_leak_bucket_unlimited() {
+        let mut lb = LeakBucket::new(0).unwrap();
+        let mut ctx = EventLoopContext::new();
+        lb.consume(u64::MAX);
+        assert_eq!(lb.level, 0);
+        assert!(!lb.throttled(&mut ctx, 1));
+    }
+}
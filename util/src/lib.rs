@@ -30,13 +30,16 @@ pub mod offsetof;
 #[cfg(not(target_env = "musl"))]
 pub mod pixman;
 pub mod reader;
+pub mod rt;
 pub mod seccomp;
 pub mod syscall;
 pub mod tap;
 pub mod test_helper;
+pub mod throttle_group;
 pub mod time;
 pub mod trace;
 pub mod unix;
+pub mod userfaultfd;
 pub use anyhow::Result;
 pub use error::UtilError;
 use libc::{tcgetattr, tcsetattr, termios, OPOST, TCSANOW};
@@ -27,6 +27,25 @@ pub struct List<T> {
     marker: PhantomData<Box<Node<T>>>,
 }
 
+/// Iterator over the values of a `List` from head to tail.
+pub struct Iter<'a, T> {
+    next: Option<NonNull<Node<T>>>,
+    marker: PhantomData<&'a Node<T>>,
+}
+
+impl<'a, T> Iterator for Iter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next.map(|node| {
+            // SAFETY: the nodes are owned by the list, which is borrowed by the iterator.
+            let node = unsafe { &*node.as_ptr() };
+            self.next = node.next;
+            &node.value
+        })
+    }
+}
+
 impl<T> Drop for List<T> {
     fn drop(&mut self) {
         while self.pop_head().is_some() {}
@@ -53,6 +72,14 @@ impl<T> List<T> {
         }
     }
 
+    /// Iterate over the values from head to tail.
+    pub fn iter(&self) -> Iter<'_, T> {
+        Iter {
+            next: self.head,
+            marker: PhantomData,
+        }
+    }
+
     #[inline]
     pub fn add_tail(&mut self, mut node: Box<Node<T>>) {
         node.prev = self.tail;
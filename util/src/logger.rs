@@ -10,12 +10,21 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
+use std::fs::{remove_file, rename, File, OpenOptions};
 use std::io::prelude::*;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Mutex;
 
 use crate::unix::gettid;
+use anyhow::{bail, Result};
 use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
 
+/// Set by SIGHUP, the log file is reopened before the next record is written.
+static LOG_REOPEN: AtomicBool = AtomicBool::new(false);
+
 fn format_now() -> String {
     let mut ts = libc::timespec {
         tv_sec: 0,
@@ -40,67 +49,316 @@ fn format_now() -> String {
     )
 }
 
+fn parse_level(level: &str) -> Result<LevelFilter> {
+    match level.to_lowercase().as_str() {
+        "off" => Ok(LevelFilter::Off),
+        "error" => Ok(LevelFilter::Error),
+        "warn" => Ok(LevelFilter::Warn),
+        "info" => Ok(LevelFilter::Info),
+        "debug" => Ok(LevelFilter::Debug),
+        "trace" => Ok(LevelFilter::Trace),
+        _ => bail!("Invalid log level {}", level),
+    }
+}
+
+/// Log levels of targets, e.g. `info,virtio::scsi=debug,machine_manager::qmp=trace`.
+///
+/// A target filter matches a log target if the first components (crate names) are
+/// equal and the rest components of the filter appear in the target in order, so
+/// `virtio::scsi` matches `virtio::device::scsi::bus`. The filter with the most
+/// components wins, and the default level is used if no filter matches.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LogFilter {
+    default: LevelFilter,
+    targets: Vec<(Vec<String>, LevelFilter)>,
+}
+
+impl LogFilter {
+    pub fn new(default: LevelFilter) -> Self {
+        LogFilter {
+            default,
+            targets: Vec::new(),
+        }
+    }
+
+    /// Get the level of the log target.
+    pub fn level(&self, target: &str) -> LevelFilter {
+        let target: Vec<&str> = target.split("::").collect();
+        let mut level = self.default;
+        let mut matched = 0;
+        for (filter, filter_level) in self.targets.iter() {
+            if filter.len() >= matched && Self::target_matches(filter, &target) {
+                level = *filter_level;
+                matched = filter.len();
+            }
+        }
+        level
+    }
+
+    /// Get the highest level of all targets.
+    pub fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+
+    fn target_matches(filter: &[String], target: &[&str]) -> bool {
+        if filter.first().map(String::as_str) != target.first().copied() {
+            return false;
+        }
+        let mut target_iter = target.iter().skip(1);
+        filter
+            .iter()
+            .skip(1)
+            .all(|component| target_iter.any(|t| t == component))
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut filter = LogFilter::new(LevelFilter::Info);
+        for item in s.split(',') {
+            match item.split_once('=') {
+                Some((target, level)) => {
+                    if target.is_empty() || target.split("::").any(str::is_empty) {
+                        bail!("Invalid log target {}", target);
+                    }
+                    let components = target.split("::").map(String::from).collect();
+                    filter.targets.push((components, parse_level(level)?));
+                }
+                None => filter.default = parse_level(item)?,
+            }
+        }
+        Ok(filter)
+    }
+}
+
+fn open_log_file(path: &str) -> std::io::Result<File> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o640)
+        .open(path)
+}
+
+/// Log file which is rotated when it grows beyond `max_size`. The old files are
+/// renamed to `<path>.1` ... `<path>.<max_files>`, and the oldest one is dropped.
+pub struct RotatingFile {
+    path: String,
+    file: File,
+    size: u64,
+    /// Rotate the file when it grows beyond the size, 0 means never.
+    max_size: u64,
+    /// Number of old files to keep.
+    max_files: u32,
+}
+
+impl RotatingFile {
+    pub fn new(path: &str, max_size: u64, max_files: u32) -> std::io::Result<Self> {
+        let file = open_log_file(path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path: path.to_string(),
+            file,
+            size,
+            max_size,
+            max_files,
+        })
+    }
+
+    fn reopen(&mut self) -> std::io::Result<()> {
+        self.file = open_log_file(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> std::io::Result<()> {
+        if self.max_files == 0 {
+            remove_file(&self.path)?;
+        } else {
+            for i in (1..self.max_files).rev() {
+                let old = format!("{}.{}", self.path, i);
+                if Path::new(&old).exists() {
+                    rename(&old, format!("{}.{}", self.path, i + 1))?;
+                }
+            }
+            rename(&self.path, format!("{}.1", self.path))?;
+        }
+        self.reopen()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if LOG_REOPEN.swap(false, Ordering::SeqCst) {
+            self.reopen()?;
+        }
+        if self.max_size != 0 && self.size != 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let len = self.file.write(buf)?;
+        self.size += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+}
+
+/// Reopen the log file before the next record, e.g. after it's moved by logrotate.
+/// It's async-signal-safe to be called in the SIGHUP handler.
+pub fn request_log_reopen() {
+    LOG_REOPEN.store(true, Ordering::SeqCst);
+}
+
 /// Format like "%year-%mon-%dayT%hour:%min:%sec.%nsec
 struct VmLogger {
     handler: Option<Mutex<Box<dyn Write + Send>>>,
-    level: Level,
+    filter: LogFilter,
 }
 
 impl Log for VmLogger {
     fn enabled(&self, metadata: &Metadata) -> bool {
-        self.handler.is_some() && metadata.level() <= self.level
+        self.handler.is_some() && metadata.level() <= self.filter.level(metadata.target())
     }
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
             let pid = unsafe { libc::getpid() };
             let tid = gettid();
+            // Write the record at once, so that it's not split by rotation.
+            let line = format!(
+                "{:<5}: [{}][{}][{}: {}]:{}: {}\n",
+                format_now(),
+                pid,
+                tid,
+                record.file().unwrap_or(""),
+                record.line().unwrap_or(0),
+                record.level(),
+                record.args()
+            );
 
             self.handler.as_ref().map(|writer| {
-                writer.lock().unwrap().write_fmt(format_args!(
-                    "{:<5}: [{}][{}][{}: {}]:{}: {}\n",
-                    format_now(),
-                    pid,
-                    tid,
-                    record.file().unwrap_or(""),
-                    record.line().unwrap_or(0),
-                    record.level(),
-                    record.args()
-                ))
+                let mut writer = writer.lock().unwrap();
+                let ret = writer.write_all(line.as_bytes());
+                if record.level() == Level::Error {
+                    return writer.flush();
+                }
+                ret
             });
         }
     }
 
-    fn flush(&self) {}
+    fn flush(&self) {
+        if let Some(writer) = self.handler.as_ref() {
+            let _ = writer.lock().unwrap().flush();
+        }
+    }
 }
 
-fn init_vm_logger(
-    level: Option<Level>,
+/// Init the global logger with levels of targets.
+pub fn init_logger(
+    filter: LogFilter,
     logfile: Option<Box<dyn Write + Send>>,
-) -> Result<(), log::SetLoggerError> {
-    let buffer = logfile.map(Mutex::new);
+) -> Result<(), SetLoggerError> {
+    let max_level = filter.max_level();
     let logger = VmLogger {
-        level: level.unwrap_or(Level::Info),
-        handler: buffer,
+        handler: logfile.map(Mutex::new),
+        filter,
     };
 
-    log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(LevelFilter::Trace))
+    log::set_boxed_logger(Box::new(logger)).map(|()| log::set_max_level(max_level))
 }
 
 pub fn init_logger_with_env(logfile: Option<Box<dyn Write + Send>>) -> Result<(), SetLoggerError> {
     let level = match std::env::var("STRATOVIRT_LOG_LEVEL") {
-        Ok(l) => match l.to_lowercase().as_str() {
-            "error" => Level::Error,
-            "warn" => Level::Warn,
-            "info" => Level::Info,
-            "debug" => Level::Debug,
-            "trace" => Level::Trace,
-            _ => Level::Info,
-        },
-        _ => Level::Info,
+        Ok(l) => parse_level(&l).unwrap_or(LevelFilter::Info),
+        _ => LevelFilter::Info,
     };
 
-    init_vm_logger(Some(level), logfile)?;
+    init_logger(LogFilter::new(level), logfile)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vmm_sys_util::tempdir::TempDir;
+
+    #[test]
+    fn test_log_filter() {
+        let filter = LogFilter::from_str("warn,virtio::scsi=debug,virtio=info").unwrap();
+        assert_eq!(filter.level("machine::standard_vm"), LevelFilter::Warn);
+        assert_eq!(filter.level("virtio::device::block"), LevelFilter::Info);
+        assert_eq!(
+            filter.level("virtio::device::scsi::bus"),
+            LevelFilter::Debug
+        );
+        assert_eq!(filter.level("virtio::scsi"), LevelFilter::Debug);
+        // The crate name must be equal.
+        assert_eq!(filter.level("devices::scsi"), LevelFilter::Warn);
+        assert_eq!(filter.level("virtio_scsi"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
 
-    Ok(())
+        let filter = LogFilter::from_str("machine_manager::qmp=trace").unwrap();
+        assert_eq!(
+            filter.level("machine_manager::qmp::qmp_schema"),
+            LevelFilter::Trace
+        );
+        assert_eq!(filter.level("machine_manager::socket"), LevelFilter::Info);
+
+        assert!(LogFilter::from_str("verbose").is_err());
+        assert!(LogFilter::from_str("virtio=loud").is_err());
+        assert!(LogFilter::from_str("=debug").is_err());
+        assert!(LogFilter::from_str("virtio::=debug").is_err());
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.as_path().join("vm.log");
+        let path = path.to_str().unwrap();
+        let mut file = RotatingFile::new(path, 16, 2).unwrap();
+
+        file.write_all(b"0123456789\n").unwrap();
+        file.write_all(b"abcd\n").unwrap();
+        assert!(!Path::new(&format!("{}.1", path)).exists());
+        // Writing beyond the max size triggers rotation before the record.
+        file.write_all(b"second\n").unwrap();
+        assert_eq!(
+            std::fs::read(format!("{}.1", path)).unwrap(),
+            b"0123456789\nabcd\n"
+        );
+        assert_eq!(std::fs::read(path).unwrap(), b"second\n");
+
+        file.write_all(b"third\n").unwrap();
+        // A record longer than the max size is not split.
+        file.write_all(b"0123456789abcdefghij\n").unwrap();
+        assert_eq!(
+            std::fs::read(format!("{}.2", path)).unwrap(),
+            b"0123456789\nabcd\n"
+        );
+        assert_eq!(
+            std::fs::read(format!("{}.1", path)).unwrap(),
+            b"second\nthird\n"
+        );
+        assert_eq!(std::fs::read(path).unwrap(), b"0123456789abcdefghij\n");
+        // Only two old files are kept.
+        file.write_all(b"fourth\n").unwrap();
+        assert!(!Path::new(&format!("{}.3", path)).exists());
+        assert_eq!(
+            std::fs::read(format!("{}.2", path)).unwrap(),
+            b"second\nthird\n"
+        );
+        assert_eq!(std::fs::read(path).unwrap(), b"fourth\n");
+
+        // Reopen after the file is moved away.
+        rename(path, format!("{}.moved", path)).unwrap();
+        request_log_reopen();
+        file.write_all(b"reopen\n").unwrap();
+        assert_eq!(std::fs::read(path).unwrap(), b"reopen\n");
+    }
 }
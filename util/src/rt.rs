@@ -0,0 +1,335 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Building blocks for threads which must not allocate or block on their hot path,
+//! e.g. vCPU threads running in realtime mode.
+//!
+//! * `RtRing` is a preallocated single-producer/single-consumer queue used to hand
+//!   rare-path work (logging, error reporting) to another thread.
+//! * `LatencyHistogram` records durations with fixed buckets and atomic counters.
+//! * `RtSection` marks a region in which heap allocation is forbidden. With the
+//!   `rt_alloc_check` feature, allocating inside such a region aborts the process.
+
+use std::cell::UnsafeCell;
+use std::mem::MaybeUninit;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+/// Fixed-capacity lock-free ring buffer with one producer and one consumer.
+///
+/// All storage is allocated in `new`, `push` and `pop` never allocate. When the
+/// ring is full, `push` drops the element and counts it in `dropped`.
+pub struct RtRing<T: Copy> {
+    /// Slots of the ring, the length is a power of two.
+    slots: Box<[UnsafeCell<MaybeUninit<T>>]>,
+    /// Index of the next slot to be popped, only written by the consumer.
+    head: AtomicUsize,
+    /// Index of the next slot to be pushed, only written by the producer.
+    tail: AtomicUsize,
+    /// Number of elements dropped because the ring was full.
+    dropped: AtomicU64,
+}
+
+// SAFETY: a slot is only accessed by the producer before `tail` is published, and
+// only by the consumer after it has observed the published `tail`.
+unsafe impl<T: Copy + Send> Send for RtRing<T> {}
+unsafe impl<T: Copy + Send> Sync for RtRing<T> {}
+
+impl<T: Copy> RtRing<T> {
+    /// Create a ring which holds at least `capacity` elements.
+    pub fn new(capacity: usize) -> Self {
+        let size = capacity.max(1).next_power_of_two();
+        let slots = (0..size)
+            .map(|_| UnsafeCell::new(MaybeUninit::uninit()))
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        RtRing {
+            slots,
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Push `elem` to the ring, return false if it is dropped because the ring is full.
+    /// Must only be called from the single producer.
+    pub fn push(&self, elem: T) -> bool {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if tail.wrapping_sub(head) == self.slots.len() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return false;
+        }
+        let slot = &self.slots[tail & (self.slots.len() - 1)];
+        // SAFETY: the slot is not visible to the consumer until `tail` is published.
+        unsafe { (*slot.get()).write(elem) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        true
+    }
+
+    /// Pop the oldest element. Must only be called from the single consumer.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let slot = &self.slots[head & (self.slots.len() - 1)];
+        // SAFETY: the slot was initialized by the producer before `tail` was published.
+        let elem = unsafe { (*slot.get()).assume_init() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(elem)
+    }
+
+    /// Get and reset the number of dropped elements.
+    pub fn take_dropped(&self) -> u64 {
+        self.dropped.swap(0, Ordering::Relaxed)
+    }
+}
+
+/// Number of sub buckets in each power-of-two range of `LatencyHistogram`.
+const HISTOGRAM_SUB_BITS: u32 = 3;
+const HISTOGRAM_SUB_BUCKETS: usize = 1 << HISTOGRAM_SUB_BITS;
+/// Bucket count needed to cover the whole u64 range.
+const HISTOGRAM_BUCKETS: usize = (64 - HISTOGRAM_SUB_BITS as usize + 1) * HISTOGRAM_SUB_BUCKETS;
+
+/// Log-linear histogram of durations in nanoseconds, with a relative error of
+/// at most 1/8. Recording is lock-free and allocation-free.
+pub struct LatencyHistogram {
+    buckets: Box<[AtomicU64]>,
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        LatencyHistogram {
+            buckets: (0..HISTOGRAM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    fn bucket_index(value: u64) -> usize {
+        if value < HISTOGRAM_SUB_BUCKETS as u64 {
+            return value as usize;
+        }
+        let msb = 63 - value.leading_zeros();
+        let sub = (value >> (msb - HISTOGRAM_SUB_BITS)) as usize & (HISTOGRAM_SUB_BUCKETS - 1);
+        (msb - HISTOGRAM_SUB_BITS + 1) as usize * HISTOGRAM_SUB_BUCKETS + sub
+    }
+
+    /// Largest value which falls into bucket `index`.
+    fn bucket_upper_bound(index: usize) -> u64 {
+        if index < HISTOGRAM_SUB_BUCKETS {
+            return index as u64;
+        }
+        let shift = (index / HISTOGRAM_SUB_BUCKETS) as u32 - 1;
+        let sub = (index % HISTOGRAM_SUB_BUCKETS) as u64;
+        let lower = (HISTOGRAM_SUB_BUCKETS as u64 + sub) << shift;
+        lower + ((1_u64 << shift) - 1)
+    }
+
+    /// Record one sample of `nanos` nanoseconds.
+    pub fn record(&self, nanos: u64) {
+        self.buckets[Self::bucket_index(nanos)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Total number of recorded samples.
+    pub fn count(&self) -> u64 {
+        self.buckets.iter().map(|b| b.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Get the upper bound of the `permille`-th per mille of recorded samples,
+    /// e.g. 999 for p99.9. Return 0 if there is no sample.
+    pub fn percentile(&self, permille: u64) -> u64 {
+        let total = self.count();
+        if total == 0 {
+            return 0;
+        }
+        let permille = permille.min(1000);
+        // Rank of the sample, rounded up, and at least the first one.
+        let rank = (total as u128 * permille as u128).div_ceil(1000).max(1) as u64;
+        let mut seen = 0;
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            seen += bucket.load(Ordering::Relaxed);
+            if seen >= rank {
+                return Self::bucket_upper_bound(index);
+            }
+        }
+        u64::MAX
+    }
+
+    /// Clear all recorded samples.
+    pub fn reset(&self) {
+        for bucket in self.buckets.iter() {
+            bucket.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(feature = "rt_alloc_check")]
+mod alloc_check {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    thread_local!(pub(super) static IN_RT_SECTION: Cell<bool> = const { Cell::new(false) });
+
+    /// Global allocator which aborts when memory is allocated inside a `RtSection`.
+    struct RtCheckAllocator;
+
+    fn check() {
+        if IN_RT_SECTION.with(|s| s.get()) {
+            const MSG: &[u8] = b"heap allocation inside realtime section, abort\n";
+            // SAFETY: only write a static message to stderr and abort, neither allocates.
+            unsafe {
+                libc::write(
+                    libc::STDERR_FILENO,
+                    MSG.as_ptr() as *const libc::c_void,
+                    MSG.len(),
+                );
+                libc::abort();
+            }
+        }
+    }
+
+    // SAFETY: all requests are forwarded to the system allocator.
+    unsafe impl GlobalAlloc for RtCheckAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            check();
+            System.alloc(layout)
+        }
+
+        unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+            check();
+            System.alloc_zeroed(layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            check();
+            System.realloc(ptr, layout, new_size)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: RtCheckAllocator = RtCheckAllocator;
+}
+
+/// Guard of a region in which the current thread must not allocate heap memory.
+/// It does nothing unless the `rt_alloc_check` feature is enabled.
+pub struct RtSection {
+    #[cfg(feature = "rt_alloc_check")]
+    prev: bool,
+}
+
+impl RtSection {
+    pub fn enter() -> Self {
+        #[cfg(feature = "rt_alloc_check")]
+        {
+            RtSection {
+                prev: alloc_check::IN_RT_SECTION.with(|s| s.replace(true)),
+            }
+        }
+        #[cfg(not(feature = "rt_alloc_check"))]
+        RtSection {}
+    }
+}
+
+impl Drop for RtSection {
+    fn drop(&mut self) {
+        #[cfg(feature = "rt_alloc_check")]
+        alloc_check::IN_RT_SECTION.with(|s| s.set(self.prev));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rt_ring() {
+        let ring = RtRing::<u32>::new(3);
+        assert_eq!(ring.pop(), None);
+        for i in 0..4 {
+            assert!(ring.push(i));
+        }
+        assert!(!ring.push(4));
+        assert_eq!(ring.take_dropped(), 1);
+        assert_eq!(ring.take_dropped(), 0);
+
+        for i in 0..4 {
+            assert_eq!(ring.pop(), Some(i));
+        }
+        assert_eq!(ring.pop(), None);
+
+        // Wrap around the end of the slots.
+        for i in 10..13 {
+            assert!(ring.push(i));
+            assert_eq!(ring.pop(), Some(i));
+        }
+    }
+
+    #[test]
+    fn test_latency_histogram_bucket() {
+        for value in [0, 1, 7, 8, 9, 15, 16, 17, 100, 1000, 123456, u64::MAX] {
+            let index = LatencyHistogram::bucket_index(value);
+            assert!(index < HISTOGRAM_BUCKETS);
+            let upper = LatencyHistogram::bucket_upper_bound(index);
+            assert!(upper >= value);
+            // Relative error is bounded by the sub bucket width.
+            assert!(upper - value <= value / HISTOGRAM_SUB_BUCKETS as u64);
+            if index > 0 {
+                assert!(LatencyHistogram::bucket_upper_bound(index - 1) < value);
+            }
+        }
+    }
+
+    #[test]
+    fn test_latency_histogram_percentile() {
+        let hist = LatencyHistogram::new();
+        assert_eq!(hist.percentile(999), 0);
+
+        for _ in 0..998 {
+            hist.record(100);
+        }
+        hist.record(5000);
+        hist.record(100_000);
+        assert_eq!(hist.count(), 1000);
+
+        let p50 = hist.percentile(500);
+        assert!((100..=112).contains(&p50));
+        let p999 = hist.percentile(999);
+        assert!((5000..=5632).contains(&p999));
+        let max = hist.percentile(1000);
+        assert!((100_000..=112_500).contains(&max));
+
+        hist.reset();
+        assert_eq!(hist.count(), 0);
+    }
+
+    #[test]
+    fn test_rt_section_nested() {
+        let outer = RtSection::enter();
+        {
+            let _inner = RtSection::enter();
+        }
+        drop(outer);
+        // Allocation is allowed again after leaving all sections.
+        let v: Vec<u8> = Vec::with_capacity(16);
+        assert!(v.capacity() >= 16);
+    }
+}
@@ -393,17 +393,21 @@ impl SyscallFilter {
         self.sock_filters.append(&mut bpf_rule.as_vec());
     }
 
+    /// Get the final bpf program, the default action for all syscall call
+    /// not in rules is appended at the end of it.
+    fn bpf_program(mut self) -> Vec<SockFilter> {
+        self.sock_filters.append(&mut handle_process(self.opt));
+        self.sock_filters
+    }
+
     /// Make seccomp take effect.
     ///
     /// # Notice
     /// After use this function, all rules in seccomp will take effect whatever
     /// this structure dropped or not. You can only use this function once in
     /// a thread. Otherwise you will get an error.
-    pub fn realize(mut self) -> Result<()> {
-        //Add opt as a bpf_filter to sock_filters
-        self.sock_filters.append(&mut handle_process(self.opt));
-
-        let sock_bpf_vec = self.sock_filters;
+    pub fn realize(self) -> Result<()> {
+        let sock_bpf_vec = self.bpf_program();
 
         // This operation can guarantee seccomp make use for all users and subprocess.
         let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
@@ -589,4 +593,40 @@ mod tests {
 
         assert_eq!(seccomp_filter.sock_filters, bpf_vec);
     }
+
+    #[test]
+    fn test_default_action() {
+        // The default action is the last bpf_filter of the program, and rules
+        // for allowed syscalls still return `SECCOMP_RET_ALLOW` in any mode.
+        for (opt, action) in [
+            (SeccompOpt::Trap, 0x0003_0000),
+            (SeccompOpt::Kill, 0),
+            (SeccompOpt::Log, 0x7ffc_0000),
+            (SeccompOpt::Errno(libc::EPERM as u32), 0x0005_0001),
+        ] {
+            let mut seccomp_filter = SyscallFilter::new(opt);
+            seccomp_filter.push(&mut BpfRule::new(libc::SYS_read));
+            let program = seccomp_filter.bpf_program();
+
+            assert_eq!(program.len(), 7);
+            assert_eq!(
+                program[5],
+                SockFilter {
+                    code: 0x06,
+                    jt: 0,
+                    jf: 0,
+                    k: 0x7fff_0000,
+                }
+            );
+            assert_eq!(
+                program[6],
+                SockFilter {
+                    code: 0x06,
+                    jt: 0,
+                    jf: 0,
+                    k: action,
+                }
+            );
+        }
+    }
 }
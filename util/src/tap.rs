@@ -11,10 +11,16 @@
 // See the Mulan PSL v2 for more details.
 
 use anyhow::{anyhow, bail, Context};
+use log::error;
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Result as IoResult, Write};
+use std::io::{Error as IoError, Read, Result as IoResult, Write};
 use std::os::unix::fs::OpenOptionsExt;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref, ioctl_with_val};
 use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iow_nr};
 
@@ -29,15 +35,37 @@ pub const TUN_F_VIRTIO: u32 = TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6 | TUN_F_UFO;
 
 const IFF_TAP: u16 = 0x02;
 pub const IFF_MULTI_QUEUE: u16 = 0x100;
+const IFF_ATTACH_QUEUE: u16 = 0x200;
+const IFF_DETACH_QUEUE: u16 = 0x400;
 const IFF_NO_PI: u16 = 0x1000;
 const IFF_VNET_HDR: u16 = 0x4000;
 const TUNTAP_PATH: &str = "/dev/net/tun";
 const IFNAME_SIZE: usize = 16;
+/// Name of a tap which is generated by the kernel, `%d` is replaced by the first free number.
+pub const TAP_NAME_TEMPLATE: &str = "tap%d";
+/// Value of `script` and `downscript` which disables the script.
+pub const TAP_SCRIPT_NONE: &str = "no";
+/// PATH of the ifup and ifdown scripts, which don't inherit the environment of StratoVirt.
+const TAP_SCRIPT_PATH_ENV: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+// Size of the kernel `struct ifreq`, which is written back entirely by TUNGETIFF.
+const IFREQ_SIZE: usize = 40;
+/// The only capability kept by the ifup and ifdown scripts, to configure the tap.
+const CAP_NET_ADMIN: libc::c_ulong = 12;
+/// Max length of a request to the tap script helper: the script kind, the netdev id, a NUL
+/// and the tap name.
+const TAP_SCRIPT_REQ_MAX: usize = 1 + 4096 + 1 + IFNAME_SIZE;
+
+/// Socket to the helper process which runs the tap scripts, see `start_tap_script_helper`.
+///
+/// It's kept as a file, so that only `read` and `write` are needed under the seccomp filter.
+static TAP_SCRIPT_HELPER: Lazy<Mutex<Option<File>>> = Lazy::new(|| Mutex::new(None));
 
 ioctl_iow_nr!(TUNSETIFF, 84, 202, ::std::os::raw::c_int);
 ioctl_ior_nr!(TUNGETFEATURES, 84, 207, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETOFFLOAD, 84, 208, ::std::os::raw::c_int);
+ioctl_ior_nr!(TUNGETIFF, 84, 210, ::std::os::raw::c_uint);
 ioctl_iow_nr!(TUNSETVNETHDRSZ, 84, 216, ::std::os::raw::c_int);
+ioctl_iow_nr!(TUNSETQUEUE, 84, 217, ::std::os::raw::c_int);
 
 #[repr(C)]
 pub struct IfReq {
@@ -92,6 +120,21 @@ impl Tap {
                 libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
                 File::from_raw_fd(fd)
             };
+
+            // The flags of an attached tap can not be changed, so the tap passed by fd
+            // must have been created with IFF_VNET_HDR.
+            let mut if_req = [0_u8; IFREQ_SIZE];
+            let ret = unsafe { ioctl_with_mut_ref(&file, TUNGETIFF(), &mut if_req) };
+            if ret < 0 {
+                return Err(anyhow!(
+                    "Failed to get tap ifr flags, error is {}",
+                    std::io::Error::last_os_error()
+                ));
+            }
+            let ifr_flags = u16::from_ne_bytes([if_req[IFNAME_SIZE], if_req[IFNAME_SIZE + 1]]);
+            if ifr_flags & IFF_VNET_HDR == 0 {
+                bail!("Tap passed by fd {} is not created with IFF_VNET_HDR", fd);
+            }
         } else {
             return Err(anyhow!(
                 "Open tap failed, unsupported operation, error is {}",
@@ -133,9 +176,72 @@ impl Tap {
         Ok(())
     }
 
-    pub fn has_ufo(&self) -> bool {
-        let flags = TUN_F_CSUM | TUN_F_UFO;
-        (unsafe { ioctl_with_val(&self.file, TUNSETOFFLOAD(), flags as libc::c_ulong) }) >= 0
+    /// Attach or detach the queue of a multiqueue tap, the kernel only delivers packets
+    /// to attached queues.
+    pub fn set_queue(&self, enable: bool) -> Result<()> {
+        let mut if_req = IfReq {
+            ifr_name: [0_u8; IFNAME_SIZE],
+            ifr_flags: if enable {
+                IFF_ATTACH_QUEUE
+            } else {
+                IFF_DETACH_QUEUE
+            },
+        };
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, TUNSETQUEUE(), &mut if_req) };
+        if ret < 0 {
+            return Err(anyhow!(
+                "ioctl TUNSETQUEUE failed, error is {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Probe the offloads supported by the tap by trying to set them one by one, and
+    /// return the supported `TUN_F_*` flags. All offloads are disabled after probing,
+    /// they will be enabled again according to the features negotiated with guest.
+    pub fn probe_offloads(&self) -> u32 {
+        // Every offload needs checksum offload to work.
+        if self.set_offload(TUN_F_CSUM).is_err() {
+            return 0;
+        }
+
+        let mut supported = TUN_F_CSUM;
+        for offload in [TUN_F_TSO4, TUN_F_TSO6, TUN_F_UFO] {
+            if self.set_offload(TUN_F_CSUM | offload).is_ok() {
+                supported |= offload;
+            }
+        }
+        if supported & (TUN_F_TSO4 | TUN_F_TSO6) != 0
+            && self
+                .set_offload(supported & !TUN_F_UFO | TUN_F_TSO_ECN)
+                .is_ok()
+        {
+            supported |= TUN_F_TSO_ECN;
+        }
+
+        if let Err(e) = self.set_offload(0) {
+            error!("Failed to disable tap offloads after probing: {:?}", e);
+        }
+        supported
+    }
+
+    /// Get the name of the tap, which is useful if the name is generated by the kernel.
+    pub fn ifname(&self) -> Result<String> {
+        let mut if_req = [0_u8; IFREQ_SIZE];
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, TUNGETIFF(), &mut if_req) };
+        if ret < 0 {
+            return Err(anyhow!(
+                "Failed to get tap name, error is {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+        let len = if_req[..IFNAME_SIZE]
+            .iter()
+            .position(|c| *c == 0)
+            .unwrap_or(IFNAME_SIZE);
+        Ok(String::from_utf8_lossy(&if_req[..len]).to_string())
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> IoResult<usize> {
@@ -158,3 +264,402 @@ impl Clone for Tap {
         }
     }
 }
+
+/// Build the command which runs the ifup or ifdown `script` with the tap name as its
+/// only argument. The script doesn't inherit the environment and stdin of StratoVirt,
+/// and runs with the privileges dropped by `drop_script_privileges`.
+fn tap_script_command(script: &str, ifname: &str) -> Command {
+    let mut cmd = Command::new(script);
+    cmd.arg(ifname)
+        .env_clear()
+        .env("PATH", TAP_SCRIPT_PATH_ENV)
+        .stdin(Stdio::null());
+    // SAFETY: the closure only issues syscalls, which is safe in the forked child.
+    unsafe { cmd.pre_exec(drop_script_privileges) };
+    cmd
+}
+
+/// Drop the privileges which are not needed to configure a tap, before the script is
+/// executed: the capabilities other than CAP_NET_ADMIN are removed from the bounding set,
+/// and the ids gained by a set-user-ID or set-group-ID binary are reset to the real ids.
+fn drop_script_privileges() -> IoResult<()> {
+    for cap in 0.. {
+        if cap == CAP_NET_ADMIN {
+            continue;
+        }
+        // SAFETY: PR_CAPBSET_DROP doesn't access the memory of the process.
+        if unsafe { libc::prctl(libc::PR_CAPBSET_DROP, cap, 0, 0, 0) } < 0 {
+            match IoError::last_os_error().raw_os_error() {
+                // All the capabilities known by the kernel are dropped.
+                Some(libc::EINVAL) => break,
+                // The bounding set can't be changed without CAP_SETPCAP, which an
+                // unprivileged StratoVirt doesn't have.
+                Some(libc::EPERM) => break,
+                _ => return Err(IoError::last_os_error()),
+            }
+        }
+    }
+
+    // SAFETY: the calls don't access the memory of the process.
+    unsafe {
+        let gid = libc::getgid();
+        if libc::setresgid(gid, gid, gid) < 0 {
+            return Err(IoError::last_os_error());
+        }
+        let uid = libc::getuid();
+        if libc::setresuid(uid, uid, uid) < 0 {
+            return Err(IoError::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The ifup and ifdown scripts of a netdev, which the tap script helper is allowed to run.
+#[derive(Debug, Clone, Default)]
+pub struct TapScripts {
+    /// Name of the tap, or `TAP_NAME_TEMPLATE` if it's named by the kernel.
+    pub ifname: String,
+    pub script: Option<String>,
+    pub downscript: Option<String>,
+}
+
+/// Kind of the tap script run by the helper.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TapScriptKind {
+    /// The ifup script, `script` of the netdev.
+    Up = 0,
+    /// The ifdown script, `downscript` of the netdev.
+    Down = 1,
+}
+
+impl TapScriptKind {
+    fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(TapScriptKind::Up),
+            1 => Some(TapScriptKind::Down),
+            _ => None,
+        }
+    }
+}
+
+/// Fork the helper process which runs the ifup and ifdown scripts of taps for StratoVirt.
+///
+/// The scripts can't be executed under the seccomp filter of the VM, so they are run by
+/// the helper, which is forked before the filter is installed. The helper only runs the
+/// `scripts` of the netdevs given here, indexed by the netdev id, and it isn't forked if
+/// there is none. It must be called before any other thread is created, and the helper
+/// exits with StratoVirt.
+pub fn start_tap_script_helper(scripts: HashMap<String, TapScripts>) -> Result<()> {
+    if scripts.is_empty() {
+        return Ok(());
+    }
+
+    let mut fds = [0 as RawFd; 2];
+    // SAFETY: `fds` is valid to store the two fds of the socket pair.
+    let ret = unsafe {
+        libc::socketpair(
+            libc::AF_UNIX,
+            libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+            0,
+            fds.as_mut_ptr(),
+        )
+    };
+    if ret < 0 {
+        bail!(
+            "Failed to create socket pair for the tap script helper: {}",
+            IoError::last_os_error()
+        );
+    }
+    // SAFETY: the fds are just created and owned by nobody else.
+    let (parent, child) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+    // SAFETY: no other thread is running, the child runs the helper loop and exits.
+    match unsafe { libc::fork() } {
+        -1 => bail!(
+            "Failed to fork the tap script helper: {}",
+            IoError::last_os_error()
+        ),
+        0 => {
+            drop(parent);
+            close_inherited_fds(child.as_raw_fd());
+            // SAFETY: PR_SET_PDEATHSIG doesn't access the memory of the process.
+            unsafe { libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL) };
+            tap_script_helper_loop(child, &scripts);
+            // SAFETY: exit without running the exit handlers inherited from StratoVirt.
+            unsafe { libc::_exit(0) };
+        }
+        _ => {
+            drop(child);
+            *TAP_SCRIPT_HELPER.lock().unwrap() = Some(parent);
+        }
+    }
+    Ok(())
+}
+
+/// Close the fds inherited from StratoVirt except stdio and `keep`, e.g. the pipe to the
+/// original process of the daemon, which waits until all its write ends are closed.
+fn close_inherited_fds(keep: RawFd) {
+    let fds: Vec<RawFd> = match std::fs::read_dir("/proc/self/fd") {
+        Ok(dir) => dir
+            .filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok())
+            .collect(),
+        Err(_) => return,
+    };
+    for fd in fds {
+        if fd > libc::STDERR_FILENO && fd != keep {
+            // SAFETY: the fds are not used by the helper, the fd of the read dir is closed.
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// Serve the requests of StratoVirt until it exits. A request is the script kind, then the
+/// netdev id and the tap name separated by NUL, and the reply is the raw wait status of the
+/// script, or the negative errno if it's refused or can't be executed.
+fn tap_script_helper_loop(mut sock: File, scripts: &HashMap<String, TapScripts>) {
+    let mut req = [0_u8; TAP_SCRIPT_REQ_MAX];
+    loop {
+        let len = match sock.read(&mut req) {
+            Ok(0) | Err(_) => return,
+            Ok(len) => len,
+        };
+        let ret = match allowed_tap_script(scripts, &req[..len]) {
+            Ok((script, ifname)) => match tap_script_command(script, ifname).status() {
+                Ok(status) => status.into_raw(),
+                Err(e) => -e.raw_os_error().unwrap_or(libc::EIO),
+            },
+            Err(errno) => -errno,
+        };
+        if sock.write_all(&ret.to_le_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn parse_tap_script_req(req: &[u8]) -> Option<(TapScriptKind, &str, &str)> {
+    let (kind, req) = req.split_first()?;
+    let kind = TapScriptKind::from_byte(*kind)?;
+    let pos = req.iter().position(|b| *b == 0)?;
+    let id = std::str::from_utf8(&req[..pos]).ok()?;
+    let ifname = std::str::from_utf8(&req[pos + 1..]).ok()?;
+    Some((kind, id, ifname))
+}
+
+/// Get the script and the tap name to run it with for the request, from the scripts given
+/// to the helper at fork time. The tap name of the request is only accepted if the tap is
+/// named by the kernel, and it must be a name generated by `TAP_NAME_TEMPLATE`.
+fn allowed_tap_script<'a>(
+    scripts: &'a HashMap<String, TapScripts>,
+    req: &'a [u8],
+) -> std::result::Result<(&'a str, &'a str), i32> {
+    let (kind, id, ifname) = parse_tap_script_req(req).ok_or(libc::EINVAL)?;
+    let netdev = scripts.get(id).ok_or(libc::EACCES)?;
+    let script = match kind {
+        TapScriptKind::Up => netdev.script.as_deref(),
+        TapScriptKind::Down => netdev.downscript.as_deref(),
+    }
+    .ok_or(libc::EACCES)?;
+    let allowed = if netdev.ifname == TAP_NAME_TEMPLATE {
+        is_generated_tap_name(ifname)
+    } else {
+        ifname == netdev.ifname
+    };
+    if !allowed {
+        return Err(libc::EACCES);
+    }
+    Ok((script, ifname))
+}
+
+fn is_generated_tap_name(ifname: &str) -> bool {
+    let prefix = TAP_NAME_TEMPLATE.trim_end_matches("%d");
+    ifname.len() < IFNAME_SIZE
+        && ifname
+            .strip_prefix(prefix)
+            .is_some_and(|num| !num.is_empty() && num.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Ask the helper to run the `kind` script of netdev `id` with tap `ifname`, and wait for
+/// its exit status.
+fn run_tap_script_by_helper(
+    helper: &mut File,
+    id: &str,
+    kind: TapScriptKind,
+    ifname: &str,
+) -> Result<ExitStatus> {
+    let req = [&[kind as u8], id.as_bytes(), &[0], ifname.as_bytes()].concat();
+    if req.len() > TAP_SCRIPT_REQ_MAX {
+        bail!("Id of netdev {} is too long", id);
+    }
+    helper.write_all(&req)?;
+    let mut ret = [0_u8; 4];
+    helper.read_exact(&mut ret)?;
+    let ret = i32::from_le_bytes(ret);
+    if ret < 0 {
+        return Err(IoError::from_raw_os_error(-ret).into());
+    }
+    Ok(ExitStatus::from_raw(ret))
+}
+
+/// Run the ifup or ifdown script of netdev `id` by the helper with tap `ifname`, it fails
+/// if the script exits with non-zero status. Only the scripts given to the helper by
+/// `start_tap_script_helper` can be run.
+pub fn run_tap_script(id: &str, kind: TapScriptKind, ifname: &str) -> Result<()> {
+    // It's called by the exit notifier in the panic hook, so a poisoned lock is still taken.
+    let mut helper = TAP_SCRIPT_HELPER.lock().unwrap_or_else(|e| e.into_inner());
+    let helper = helper
+        .as_mut()
+        .ok_or_else(|| anyhow!("No script is allowed for netdev {}", id))?;
+    let status = run_tap_script_by_helper(helper, id, kind, ifname).with_context(|| {
+        format!(
+            "Failed to run {:?} script of netdev {} with tap {}",
+            kind, id, ifname
+        )
+    })?;
+    if !status.success() {
+        bail!(
+            "{:?} script of netdev {} with tap {} failed with {}",
+            kind,
+            id,
+            ifname,
+            status
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::OwnedFd;
+    use std::os::unix::net::UnixStream;
+    use std::thread::JoinHandle;
+
+    use super::*;
+
+    fn create_script(path: &str, content: &str) {
+        fs::write(path, content).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[test]
+    fn test_tap_script_command() {
+        let cmd = tap_script_command("/etc/stratovirt-ifup", "tap0");
+        assert_eq!(cmd.get_program(), "/etc/stratovirt-ifup");
+        assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["tap0"]);
+        // Only PATH is passed to the script.
+        let envs = cmd.get_envs().collect::<Vec<_>>();
+        assert_eq!(envs.len(), 1);
+        assert_eq!(envs[0].0, "PATH");
+    }
+
+    fn start_test_helper(scripts: HashMap<String, TapScripts>) -> (File, JoinHandle<()>) {
+        let (helper, child) = UnixStream::pair().unwrap();
+        let helper = File::from(OwnedFd::from(helper));
+        let child = File::from(OwnedFd::from(child));
+        let handle = std::thread::spawn(move || tap_script_helper_loop(child, &scripts));
+        (helper, handle)
+    }
+
+    #[test]
+    fn test_run_tap_script() {
+        let dir = format!("/tmp/test_tap_script_{}", std::process::id());
+        fs::create_dir_all(&dir).unwrap();
+        let ifup = format!("{}/ifup", dir);
+        let output = format!("{}/output", dir);
+        create_script(&ifup, &format!("#!/bin/sh\necho \"$1\" > {}\n", output));
+        let failed = format!("{}/failed", dir);
+        create_script(&failed, "#!/bin/sh\nexit 3\n");
+
+        // No script is run without the helper.
+        assert!(run_tap_script("net0", TapScriptKind::Up, "tap0").is_err());
+
+        let scripts = HashMap::from([(
+            "net0".to_string(),
+            TapScripts {
+                ifname: "tap0".to_string(),
+                script: Some(ifup),
+                downscript: Some(failed),
+            },
+        )]);
+        let (helper, handle) = start_test_helper(scripts);
+        *TAP_SCRIPT_HELPER.lock().unwrap() = Some(helper);
+
+        assert!(run_tap_script("net0", TapScriptKind::Up, "tap0").is_ok());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "tap0\n");
+        let err = run_tap_script("net0", TapScriptKind::Down, "tap0").unwrap_err();
+        assert!(format!("{:?}", err).contains("exit status: 3"));
+
+        *TAP_SCRIPT_HELPER.lock().unwrap() = None;
+        handle.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_tap_script_helper() {
+        let dir = format!("/tmp/test_tap_script_helper_{}", std::process::id());
+        fs::create_dir_all(&dir).unwrap();
+        let ifup = format!("{}/ifup", dir);
+        let output = format!("{}/output", dir);
+        create_script(&ifup, &format!("#!/bin/sh\necho \"$1\" > {}\n", output));
+        let failed = format!("{}/failed", dir);
+        create_script(&failed, "#!/bin/sh\nexit 3\n");
+
+        let scripts = HashMap::from([
+            (
+                "net0".to_string(),
+                TapScripts {
+                    ifname: TAP_NAME_TEMPLATE.to_string(),
+                    script: Some(ifup.clone()),
+                    downscript: None,
+                },
+            ),
+            (
+                "net1".to_string(),
+                TapScripts {
+                    ifname: "tap1".to_string(),
+                    script: Some(failed),
+                    downscript: Some(format!("{}/nonexistent", dir)),
+                },
+            ),
+        ]);
+        let (mut helper, handle) = start_test_helper(scripts);
+        let errno = |res: Result<ExitStatus>| {
+            res.unwrap_err()
+                .downcast_ref::<IoError>()
+                .unwrap()
+                .raw_os_error()
+        };
+
+        // The tap named by the kernel is passed to the script.
+        let status = run_tap_script_by_helper(&mut helper, "net0", TapScriptKind::Up, "tap12");
+        assert!(status.unwrap().success());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "tap12\n");
+        let status = run_tap_script_by_helper(&mut helper, "net1", TapScriptKind::Up, "tap1");
+        assert_eq!(status.unwrap().code(), Some(3));
+        let status = run_tap_script_by_helper(&mut helper, "net1", TapScriptKind::Down, "tap1");
+        assert_eq!(errno(status), Some(libc::ENOENT));
+
+        // The scripts which are not given to the helper are refused.
+        let refused = [
+            ("net0", TapScriptKind::Down, "tap12"),
+            ("net0", TapScriptKind::Up, "eth0"),
+            ("net0", TapScriptKind::Up, "tap"),
+            ("net1", TapScriptKind::Up, "tap2"),
+            ("net2", TapScriptKind::Up, "tap1"),
+        ];
+        for (id, kind, ifname) in refused {
+            let status = run_tap_script_by_helper(&mut helper, id, kind, ifname);
+            assert_eq!(errno(status), Some(libc::EACCES));
+        }
+        helper.write_all(b"/usr/bin/id\0tap1").unwrap();
+        let mut ret = [0_u8; 4];
+        helper.read_exact(&mut ret).unwrap();
+        assert_eq!(i32::from_le_bytes(ret), -libc::EINVAL);
+
+        // The helper exits when StratoVirt closes the socket.
+        drop(helper);
+        handle.join().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
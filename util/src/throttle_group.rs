@@ -0,0 +1,385 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+/// Throttle groups share one token bucket among several devices, so that the IO
+/// limit applies to all of them together.
+use std::collections::{HashMap, VecDeque};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Instant;
+
+use anyhow::{bail, Result};
+use log::error;
+use once_cell::sync::Lazy;
+use vmm_sys_util::eventfd::EventFd;
+
+use crate::loop_context::{get_current_time, EventLoopContext};
+use crate::time::NANOSECONDS_PER_SECOND;
+
+/// Used to improve the accuracy of the token count.
+const ACCURACY_SCALE: u64 = 1000;
+
+static THROTTLE_GROUPS: Lazy<Mutex<HashMap<String, Arc<ThrottleGroup>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+struct GroupState {
+    /// Units refilled per second.
+    units_ps: u64,
+    /// Tokens available now, scaled by `ACCURACY_SCALE`. It's capped at the tokens
+    /// of one second.
+    tokens: u64,
+    /// Last time the tokens were refilled.
+    prev_time: Instant,
+    /// Wakeup events of the members, indexed by member id.
+    members: HashMap<u64, Arc<EventFd>>,
+    /// Id of the next member to join.
+    next_id: u64,
+    /// Members waiting for tokens, served in order.
+    waiters: VecDeque<u64>,
+    /// Whether the refill timer is pending.
+    timer_armed: bool,
+    /// Bumped when the timer is deregistered, so the pending timer does nothing.
+    timer_epoch: u64,
+}
+
+impl GroupState {
+    fn refill(&mut self) {
+        let now = get_current_time();
+        let nanos = (now - self.prev_time).as_nanos();
+        let capacity = self.units_ps * ACCURACY_SCALE;
+        let refill = nanos * capacity as u128 / NANOSECONDS_PER_SECOND as u128;
+        self.tokens = (self.tokens as u128 + refill).min(capacity as u128) as u64;
+        self.prev_time = now;
+    }
+
+    /// Wake up the first waiter if there are enough tokens for it.
+    fn wakeup_front(&self) {
+        if self.tokens < ACCURACY_SCALE {
+            return;
+        }
+        if let Some(evt) = self.waiters.front().and_then(|id| self.members.get(id)) {
+            evt.write(1)
+                .unwrap_or_else(|e| error!("Throttle group failed to wake up member {:?}", e));
+        }
+    }
+}
+
+/// A token bucket shared by the members of a throttle group.
+pub struct ThrottleGroup {
+    id: String,
+    state: Arc<Mutex<GroupState>>,
+}
+
+impl ThrottleGroup {
+    /// Construct function
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - id of the throttle group.
+    /// * `units_ps` - units per second shared by all members.
+    pub fn new(id: &str, units_ps: u64) -> Self {
+        ThrottleGroup {
+            id: id.to_string(),
+            state: Arc::new(Mutex::new(GroupState {
+                units_ps,
+                tokens: units_ps * ACCURACY_SCALE,
+                prev_time: get_current_time(),
+                members: HashMap::new(),
+                next_id: 0,
+                waiters: VecDeque::new(),
+                timer_armed: false,
+                timer_epoch: 0,
+            })),
+        }
+    }
+
+    /// Get the id of the throttle group.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Add a member to the throttle group.
+    pub fn join(self: &Arc<Self>) -> Result<ThrottleGroupMember> {
+        let wakeup = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.members.insert(id, wakeup.clone());
+        Ok(ThrottleGroupMember {
+            group: self.clone(),
+            id,
+            wakeup,
+        })
+    }
+
+    fn leave(&self, member: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.members.remove(&member);
+        let was_front = state.waiters.front() == Some(&member);
+        state.waiters.retain(|id| *id != member);
+        if state.members.is_empty() {
+            // Deregister the timer, nobody is waiting for it.
+            state.timer_armed = false;
+            state.timer_epoch += 1;
+        } else if was_front {
+            state.refill();
+            state.wakeup_front();
+        }
+    }
+
+    fn arm_timer(
+        &self,
+        state: &mut GroupState,
+        loop_context: &mut EventLoopContext,
+        need_tokens: u64,
+    ) {
+        if state.timer_armed || state.tokens >= need_tokens {
+            return;
+        }
+        let capacity = state.units_ps * ACCURACY_SCALE;
+        let delay = (need_tokens - state.tokens) * NANOSECONDS_PER_SECOND / capacity;
+        let weak_state: Weak<Mutex<GroupState>> = Arc::downgrade(&self.state);
+        let epoch = state.timer_epoch;
+        let func = Box::new(move || {
+            if let Some(state) = weak_state.upgrade() {
+                let mut locked_state = state.lock().unwrap();
+                if !locked_state.timer_armed || locked_state.timer_epoch != epoch {
+                    return;
+                }
+                locked_state.timer_armed = false;
+                locked_state.refill();
+                locked_state.wakeup_front();
+            }
+        });
+        loop_context.delay_call(func, delay);
+        state.timer_armed = true;
+    }
+
+    fn throttled(&self, member: u64, loop_context: &mut EventLoopContext, need_units: u64) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.units_ps == 0 {
+            return false;
+        }
+        let waiting = state.waiters.contains(&member);
+        if need_units == 0 {
+            return waiting;
+        }
+
+        state.refill();
+        // Members get the tokens in turn when the group is throttled.
+        let turn = match state.waiters.front() {
+            Some(front) => *front == member,
+            None => true,
+        };
+        let need_tokens = need_units * ACCURACY_SCALE;
+        if turn && state.tokens >= need_tokens {
+            state.tokens -= need_tokens;
+            if waiting {
+                state.waiters.pop_front();
+                state.wakeup_front();
+            }
+            return false;
+        }
+
+        if !waiting {
+            state.waiters.push_back(member);
+        }
+        self.arm_timer(&mut state, loop_context, need_tokens.max(ACCURACY_SCALE));
+        true
+    }
+}
+
+/// A device, or one queue of it, which consumes the tokens of a throttle group. It
+/// leaves the group when dropped.
+pub struct ThrottleGroupMember {
+    group: Arc<ThrottleGroup>,
+    id: u64,
+    /// Written when it's the member's turn to get the tokens. This FD should be
+    /// listened by IO thread.
+    wakeup: Arc<EventFd>,
+}
+
+impl ThrottleGroupMember {
+    /// Return true if the member must wait for the tokens, and caller must return
+    /// directly instead of launching IO. The member is woken up by the wakeup event
+    /// when it's its turn. If `need_units` is 0, it just checks whether the member
+    /// is waiting.
+    ///
+    /// # Arguments
+    ///
+    /// * `loop_context` - used for the refill timer.
+    /// * `need_units` - units the IO needs.
+    pub fn throttled(&self, loop_context: &mut EventLoopContext, need_units: u64) -> bool {
+        self.group.throttled(self.id, loop_context, need_units)
+    }
+
+    /// Get the id of the throttle group.
+    pub fn group_id(&self) -> &str {
+        self.group.id()
+    }
+}
+
+impl AsRawFd for ThrottleGroupMember {
+    fn as_raw_fd(&self) -> RawFd {
+        self.wakeup.as_raw_fd()
+    }
+}
+
+impl Drop for ThrottleGroupMember {
+    fn drop(&mut self) {
+        self.group.leave(self.id);
+    }
+}
+
+/// Create the throttle group `id` and make it available to the devices.
+///
+/// # Arguments
+///
+/// * `id` - id of the throttle group.
+/// * `units_ps` - units per second shared by all members.
+pub fn register_throttle_group(id: &str, units_ps: u64) -> Result<()> {
+    let mut groups = THROTTLE_GROUPS.lock().unwrap();
+    if groups.contains_key(id) {
+        bail!("Throttle group {} has been registered", id);
+    }
+    groups.insert(id.to_string(), Arc::new(ThrottleGroup::new(id, units_ps)));
+    Ok(())
+}
+
+/// Add a member to the registered throttle group `id`.
+pub fn join_throttle_group(id: &str) -> Result<ThrottleGroupMember> {
+    let group = match THROTTLE_GROUPS.lock().unwrap().get(id) {
+        Some(group) => group.clone(),
+        None => bail!("Throttle group {} is not found", id),
+    };
+    group.join()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn test_throttle_group_shared() {
+        const IOPS: u64 = 200;
+        let group = Arc::new(ThrottleGroup::new("tg0", IOPS));
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut counts = Vec::new();
+        let mut threads = Vec::new();
+        let start = Instant::now();
+        for _ in 0..2 {
+            let member = group.join().unwrap();
+            let stop = stop.clone();
+            let count = Arc::new(AtomicU64::new(0));
+            counts.push(count.clone());
+            threads.push(thread::spawn(move || {
+                let mut ctx = EventLoopContext::new();
+                while !stop.load(Ordering::SeqCst) {
+                    if member.throttled(&mut ctx, 1) {
+                        ctx.run_timers();
+                        thread::sleep(Duration::from_micros(200));
+                    } else {
+                        count.fetch_add(1, Ordering::SeqCst);
+                    }
+                }
+            }));
+        }
+        thread::sleep(Duration::from_millis(500));
+        stop.store(true, Ordering::SeqCst);
+        for t in threads {
+            t.join().unwrap();
+        }
+        let elapsed = start.elapsed().as_millis() as u64;
+
+        let counts: Vec<u64> = counts.iter().map(|c| c.load(Ordering::SeqCst)).collect();
+        let total: u64 = counts.iter().sum();
+        // The burst of one second plus the refilled tokens.
+        assert!(total >= IOPS);
+        assert!(total <= IOPS + IOPS * elapsed / 1000 + 2);
+        // Both devices make progress after the burst.
+        for count in counts {
+            assert!(count > 0);
+        }
+    }
+
+    #[test]
+    fn test_throttle_group_round_robin() {
+        let group = Arc::new(ThrottleGroup::new("tg0", 2));
+        let mut ctx = EventLoopContext::new();
+        let member0 = group.join().unwrap();
+        let member1 = group.join().unwrap();
+
+        // The burst is consumed by member0, then both of them wait.
+        assert!(!member0.throttled(&mut ctx, 1));
+        assert!(!member0.throttled(&mut ctx, 1));
+        assert!(member0.throttled(&mut ctx, 1));
+        assert!(member1.throttled(&mut ctx, 1));
+        assert!(member0.throttled(&mut ctx, 0));
+        assert!(member1.throttled(&mut ctx, 0));
+
+        // member0 gets the refilled token first, then it's member1's turn.
+        group.state.lock().unwrap().tokens = 2 * ACCURACY_SCALE;
+        group.state.lock().unwrap().prev_time = get_current_time();
+        assert!(member1.throttled(&mut ctx, 1));
+        assert!(!member0.throttled(&mut ctx, 1));
+        assert!(member0.throttled(&mut ctx, 1));
+        assert!(!member1.throttled(&mut ctx, 1));
+        assert!(!member1.throttled(&mut ctx, 0));
+        assert!(member0.throttled(&mut ctx, 0));
+
+        // The waiting member is woken up when the front member leaves.
+        group.state.lock().unwrap().tokens = ACCURACY_SCALE;
+        drop(member0);
+        assert!(!member1.throttled(&mut ctx, 1));
+    }
+
+    #[test]
+    fn test_throttle_group_timer() {
+        let group = Arc::new(ThrottleGroup::new("tg0", 1000));
+        let mut ctx = EventLoopContext::new();
+        let member = group.join().unwrap();
+        group.state.lock().unwrap().tokens = 0;
+        group.state.lock().unwrap().prev_time = get_current_time();
+        assert!(member.throttled(&mut ctx, 1));
+        assert!(group.state.lock().unwrap().timer_armed);
+
+        // The refill timer wakes up the waiting member.
+        thread::sleep(Duration::from_millis(2));
+        ctx.run_timers();
+        assert!(!group.state.lock().unwrap().timer_armed);
+        assert_eq!(member.wakeup.read().unwrap(), 1);
+        assert!(!member.throttled(&mut ctx, 1));
+
+        // The timer is deregistered when the last member leaves.
+        group.state.lock().unwrap().tokens = 0;
+        group.state.lock().unwrap().prev_time = get_current_time();
+        assert!(member.throttled(&mut ctx, 1));
+        assert!(group.state.lock().unwrap().timer_armed);
+        drop(member);
+        assert!(!group.state.lock().unwrap().timer_armed);
+        assert!(group.state.lock().unwrap().members.is_empty());
+        assert!(group.state.lock().unwrap().waiters.is_empty());
+    }
+
+    #[test]
+    fn test_throttle_group_register() {
+        assert!(join_throttle_group("tg-register").is_err());
+        register_throttle_group("tg-register", 100).unwrap();
+        assert!(register_throttle_group("tg-register", 100).is_err());
+        let member = join_throttle_group("tg-register").unwrap();
+        assert_eq!(member.group_id(), "tg-register");
+    }
+}
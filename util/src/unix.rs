@@ -11,6 +11,7 @@
 // See the Mulan PSL v2 for more details.
 
 use anyhow::anyhow;
+use std::ffi::CString;
 use std::fs::File;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
@@ -50,6 +51,115 @@ pub fn host_page_size() -> u64 {
     unsafe { libc::sysconf(libc::_SC_PAGESIZE) as u64 }
 }
 
+/// Get the host cpus the calling thread is allowed to run on.
+pub fn get_thread_affinity() -> Result<Vec<usize>> {
+    // SAFETY: cpu_set_t is a plain bitmap, all zero means an empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    // SAFETY: The size passed is the size of `set`.
+    let ret = unsafe { libc::sched_getaffinity(0, size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| "Failed to get cpu affinity of thread");
+    }
+    Ok((0..libc::CPU_SETSIZE as usize)
+        // SAFETY: The cpu index is less than CPU_SETSIZE.
+        .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+        .collect())
+}
+
+/// Get the number of cpus configured on the host, including the offline ones.
+pub fn host_cpu_count() -> usize {
+    // SAFETY: sysconf has no side effect.
+    let count = unsafe { libc::sysconf(libc::_SC_NPROCESSORS_CONF) };
+    std::cmp::max(count, 1) as usize
+}
+
+/// Build the affinity mask of the host cpus.
+pub fn cpu_set(cpus: &[usize]) -> Result<libc::cpu_set_t> {
+    // SAFETY: cpu_set_t is a plain bitmap, all zero means an empty set.
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            bail!("Host cpu {} is out of range", cpu);
+        }
+        // SAFETY: The cpu index is checked above.
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    Ok(set)
+}
+
+/// Pin the thread to the host cpus.
+///
+/// # Arguments
+///
+/// * `thread` - The pthread to be pinned.
+/// * `cpus` - Index of the host cpus.
+pub fn set_thread_affinity(thread: libc::pthread_t, cpus: &[usize]) -> Result<()> {
+    let set = cpu_set(cpus)?;
+    // SAFETY: The size passed is the size of `set`.
+    let ret = unsafe { libc::pthread_setaffinity_np(thread, size_of::<libc::cpu_set_t>(), &set) };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret))
+            .with_context(|| format!("Failed to pin thread to host cpus {:?}", cpus));
+    }
+    Ok(())
+}
+
+/// Set the name of the calling thread, which is also the process name shown on the host
+/// if it's called by the main thread. The name is truncated to 15 bytes by the kernel.
+pub fn set_thread_name(name: &str) -> Result<()> {
+    let name_cstr =
+        CString::new(name).with_context(|| format!("Invalid thread name {:?}", name))?;
+    // SAFETY: The name is a nul-terminated string which lives during the call.
+    let ret = unsafe {
+        libc::prctl(
+            libc::PR_SET_NAME,
+            name_cstr.as_ptr() as libc::c_ulong,
+            0,
+            0,
+            0,
+        )
+    };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Failed to set thread name to {}", name));
+    }
+    Ok(())
+}
+
+/// Set the realtime scheduling policy and priority of the calling thread.
+///
+/// # Arguments
+///
+/// * `policy` - `SCHED_FIFO` or `SCHED_RR`.
+/// * `priority` - The realtime priority, from 1 to 99.
+pub fn set_thread_sched_priority(policy: libc::c_int, priority: i32) -> Result<()> {
+    let param = libc::sched_param {
+        sched_priority: priority,
+    };
+    // SAFETY: The param lives during the call, and 0 means the calling thread.
+    let ret = unsafe { libc::sched_setscheduler(0, policy, &param) };
+    if ret != 0 {
+        let err = std::io::Error::last_os_error();
+        if err.raw_os_error() == Some(libc::EPERM) {
+            bail!(
+                "Permission denied to set realtime priority {}, CAP_SYS_NICE or RLIMIT_RTPRIO is required",
+                priority
+            );
+        }
+        return Err(err).with_context(|| format!("Failed to set realtime priority {}", priority));
+    }
+    Ok(())
+}
+
+/// Check whether the realtime scheduling policy and priority can be set to the threads
+/// of the process, by setting them to a temporary thread.
+pub fn check_thread_sched_priority(policy: libc::c_int, priority: i32) -> Result<()> {
+    std::thread::spawn(move || set_thread_sched_priority(policy, priority))
+        .join()
+        .map_err(|_| anyhow!("Failed to check realtime priority {}", priority))?
+}
+
 /// Parse unix uri to unix path.
 ///
 /// # Notions
@@ -218,6 +328,11 @@ impl UnixSock {
         Ok(())
     }
 
+    /// Use a stream connected elsewhere, e.g. by a socket chardev.
+    pub fn set_stream(&mut self, stream: UnixStream) {
+        self.sock = Some(stream);
+    }
+
     /// Get Stream's fd from `UnixSock`.
     pub fn get_stream_raw_fd(&self) -> RawFd {
         self.sock.as_ref().unwrap().as_raw_fd()
@@ -442,7 +557,7 @@ mod tests {
 
     use libc::{c_void, iovec};
 
-    use super::{parse_unix_uri, UnixSock};
+    use super::{cpu_set, host_cpu_count, parse_unix_uri, set_thread_name, UnixSock};
 
     #[test]
     fn test_parse_uri() {
@@ -460,6 +575,33 @@ mod tests {
         assert!(parse_unix_uri(test_uri_03).is_err());
     }
 
+    #[test]
+    fn test_set_thread_name() {
+        std::thread::spawn(|| {
+            set_thread_name("vm-test").unwrap();
+            let comm = std::fs::read_to_string("/proc/thread-self/comm").unwrap();
+            assert_eq!(comm, "vm-test\n");
+            assert!(set_thread_name("vm\0test").is_err());
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn test_cpu_set() {
+        let set = cpu_set(&[0, 3, 64]).unwrap();
+        // SAFETY: The cpu indexes are less than CPU_SETSIZE.
+        let cpus = (0..libc::CPU_SETSIZE as usize)
+            .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+            .collect::<Vec<usize>>();
+        assert_eq!(cpus, vec![0, 3, 64]);
+        // SAFETY: The set is valid.
+        assert_eq!(unsafe { libc::CPU_COUNT(&cpu_set(&[]).unwrap()) }, 0);
+        assert!(cpu_set(&[libc::CPU_SETSIZE as usize]).is_err());
+
+        assert!(host_cpu_count() >= 1);
+    }
+
     #[test]
     fn test_create_unix_socket() {
         let path_name = String::from("test_socket1.sock");
@@ -0,0 +1,294 @@
+// Copyright (c) 2022 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::fs::File;
+use std::io::Read;
+use std::mem::size_of;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+
+use anyhow::{bail, Result};
+use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
+use vmm_sys_util::{ioctl_ioc_nr, ioctl_ior_nr, ioctl_iowr_nr};
+
+use crate::byte_code::ByteCode;
+
+// See: https://elixir.bootlin.com/linux/v5.10/source/include/uapi/linux/userfaultfd.h
+const UFFD_API: u64 = 0xAA;
+const UFFDIO: u32 = 0xAA;
+const UFFD_EVENT_PAGEFAULT: u8 = 0x12;
+const UFFDIO_REGISTER_MODE_MISSING: u64 = 1;
+
+ioctl_iowr_nr!(UFFDIO_REGISTER, UFFDIO, 0x00, UffdioRegister);
+ioctl_ior_nr!(UFFDIO_UNREGISTER, UFFDIO, 0x01, UffdioRange);
+ioctl_iowr_nr!(UFFDIO_COPY, UFFDIO, 0x03, UffdioCopy);
+ioctl_iowr_nr!(UFFDIO_API, UFFDIO, 0x3F, UffdioApi);
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioApi {
+    api: u64,
+    features: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRange {
+    start: u64,
+    len: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioRegister {
+    range: UffdioRange,
+    mode: u64,
+    ioctls: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct UffdioCopy {
+    dst: u64,
+    src: u64,
+    len: u64,
+    mode: u64,
+    copy: i64,
+}
+
+/// The message read from userfaultfd, only the page fault event is used.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct UffdMsg {
+    event: u8,
+    reserved1: u8,
+    reserved2: u16,
+    reserved3: u32,
+    flags: u64,
+    address: u64,
+    feat: u64,
+}
+
+impl ByteCode for UffdMsg {}
+
+/// Userfaultfd of the host, the missing pages of the registered memory ranges are
+/// reported to it, and are populated by `copy` in user space.
+pub struct Userfaultfd {
+    file: File,
+}
+
+impl Userfaultfd {
+    /// Create a userfaultfd and handshake the API with kernel.
+    pub fn new() -> Result<Self> {
+        // SAFETY: The syscall has no memory side effect, and the fd is checked.
+        let fd = unsafe { libc::syscall(libc::SYS_userfaultfd, libc::O_CLOEXEC | libc::O_NONBLOCK) }
+            as RawFd;
+        if fd < 0 {
+            bail!(
+                "Failed to create userfaultfd, error is {}",
+                std::io::Error::last_os_error()
+            );
+        }
+        // SAFETY: The fd is just created and owned by nobody else.
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let mut api = UffdioApi {
+            api: UFFD_API,
+            ..Default::default()
+        };
+        // SAFETY: The file is a valid userfaultfd and the argument is checked by kernel.
+        let ret = unsafe { ioctl_with_mut_ref(&file, UFFDIO_API(), &mut api) };
+        if ret < 0 {
+            bail!(
+                "Failed to handshake userfaultfd api, error is {}",
+                std::io::Error::last_os_error()
+            );
+        }
+
+        Ok(Userfaultfd { file })
+    }
+
+    /// Check whether userfaultfd is usable on the host. It may be unavailable because
+    /// of the kernel config or the `vm.unprivileged_userfaultfd` sysctl.
+    pub fn is_supported() -> bool {
+        Self::new().is_ok()
+    }
+
+    /// Register the memory range to report its missing pages.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start host address of the range, aligned to page size.
+    /// * `len` - Length of the range, aligned to page size.
+    pub fn register(&self, addr: u64, len: u64) -> Result<()> {
+        let mut reg = UffdioRegister {
+            range: UffdioRange { start: addr, len },
+            mode: UFFDIO_REGISTER_MODE_MISSING,
+            ioctls: 0,
+        };
+        // SAFETY: The file is a valid userfaultfd and the range is checked by kernel.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_REGISTER(), &mut reg) };
+        if ret < 0 {
+            bail!(
+                "Failed to register range 0x{:x}+0x{:x} to userfaultfd, error is {}",
+                addr,
+                len,
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    /// Unregister the memory range, its missing pages are handled by kernel afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Start host address of the range.
+    /// * `len` - Length of the range.
+    pub fn unregister(&self, addr: u64, len: u64) -> Result<()> {
+        let range = UffdioRange { start: addr, len };
+        // SAFETY: The file is a valid userfaultfd and the range is checked by kernel.
+        let ret = unsafe { ioctl_with_ref(&self.file, UFFDIO_UNREGISTER(), &range) };
+        if ret < 0 {
+            bail!(
+                "Failed to unregister range 0x{:x}+0x{:x} from userfaultfd, error is {}",
+                addr,
+                len,
+                std::io::Error::last_os_error()
+            );
+        }
+        Ok(())
+    }
+
+    /// Populate the missing page(s) at `addr` with `data` atomically, and wake up the
+    /// threads waiting for them. It's not an error if the page has been populated.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Host address of the page, aligned to page size.
+    /// * `data` - The content of the page(s).
+    pub fn copy(&self, addr: u64, data: &[u8]) -> Result<()> {
+        let mut copy = UffdioCopy {
+            dst: addr,
+            src: data.as_ptr() as u64,
+            len: data.len() as u64,
+            ..Default::default()
+        };
+        // SAFETY: The source buffer is valid for `len` bytes, and the destination is
+        // checked by kernel to be in a registered range.
+        let ret = unsafe { ioctl_with_mut_ref(&self.file, UFFDIO_COPY(), &mut copy) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EEXIST) {
+                bail!("Failed to copy page 0x{:x} by userfaultfd: {}", addr, err);
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for the next page fault, return the faulting host address, or `None` if
+    /// there is no page fault in `timeout_ms` milliseconds.
+    ///
+    /// # Arguments
+    ///
+    /// * `timeout_ms` - Timeout of waiting in milliseconds.
+    pub fn wait_fault(&self, timeout_ms: i64) -> Result<Option<u64>> {
+        let mut pollfd = libc::pollfd {
+            fd: self.file.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = libc::timespec {
+            tv_sec: timeout_ms / 1000,
+            tv_nsec: (timeout_ms % 1000) * 1_000_000,
+        };
+        // SAFETY: The pollfd and timeout are valid during the call.
+        let ret = unsafe { libc::ppoll(&mut pollfd, 1, &timeout, std::ptr::null()) };
+        if ret < 0 {
+            let err = std::io::Error::last_os_error();
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                return Ok(None);
+            }
+            bail!("Failed to poll userfaultfd: {}", err);
+        }
+        if ret == 0 {
+            return Ok(None);
+        }
+
+        let mut msg = UffdMsg::default();
+        match (&self.file).read(msg.as_mut_bytes()) {
+            Ok(len) if len == size_of::<UffdMsg>() => {}
+            Ok(len) => bail!("Invalid userfaultfd message length {}", len),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(None),
+            Err(e) => bail!("Failed to read userfaultfd: {}", e),
+        }
+        if msg.event != UFFD_EVENT_PAGEFAULT {
+            return Ok(None);
+        }
+        Ok(Some(msg.address))
+    }
+}
+
+impl AsRawFd for Userfaultfd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_userfaultfd_abi() {
+        assert_eq!(size_of::<UffdioApi>(), 24);
+        assert_eq!(size_of::<UffdioRegister>(), 32);
+        assert_eq!(size_of::<UffdioCopy>(), 40);
+        assert_eq!(size_of::<UffdMsg>(), 32);
+    }
+
+    #[test]
+    fn test_userfaultfd_copy() {
+        // Userfaultfd may be disabled on the host running the test.
+        let uffd = match Userfaultfd::new() {
+            Ok(uffd) => uffd,
+            Err(_) => return,
+        };
+        let page_size = crate::unix::host_page_size();
+        // SAFETY: Map an anonymous private region, it's unmapped at the end.
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                page_size as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let addr = addr as u64;
+        assert!(uffd.register(addr, page_size).is_ok());
+        assert_eq!(uffd.wait_fault(0).unwrap(), None);
+
+        let data = vec![0x5a_u8; page_size as usize];
+        assert!(uffd.copy(addr, &data).is_ok());
+        // Copy to the populated page is not an error.
+        assert!(uffd.copy(addr, &data).is_ok());
+        // SAFETY: The page has been populated.
+        assert_eq!(unsafe { *(addr as *const u8) }, 0x5a);
+
+        assert!(uffd.unregister(addr, page_size).is_ok());
+        // SAFETY: The region is mapped above.
+        unsafe { libc::munmap(addr as *mut libc::c_void, page_size as usize) };
+    }
+}
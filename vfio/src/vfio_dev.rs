@@ -247,6 +247,8 @@ impl VfioContainer {
                 )
             },
         )?;
+        // Pages mapped for DMA are pinned, keep memory aging away from them.
+        address_space::pin_host_range(userspace_addr, memory_size);
         Ok(())
     }
 
@@ -263,6 +265,9 @@ impl VfioContainer {
                 guest_phys_addr, size
             )
         })?;
+        if let Some(hva) = fr.owner.get_host_address() {
+            address_space::unpin_host_range(hva + fr.offset_in_region, size);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,508 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Block jobs of virtio block disks.
+//!
+//! `blockdev-backup` copies the image of a running disk to a target file in the background.
+//! The backup is consistent with the image at the time the job starts: before a guest write
+//! lands on a cluster which is not copied yet, the old data of the cluster is copied to the
+//! target first (copy-before-write). Guest writes are intercepted by the write filters of
+//! the disk.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fs::{File, OpenOptions};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::{error, info};
+use machine_manager::event;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use once_cell::sync::Lazy;
+use util::bitmap::Bitmap;
+
+/// Granularity of copy-before-write.
+pub const BACKUP_CLUSTER_SIZE: u64 = 64 * 1024;
+
+/// Write filters of all realized disks, keyed by device id.
+static WRITE_FILTERS: Lazy<Mutex<BTreeMap<String, Arc<WriteFilters>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Running block jobs, keyed by device id.
+static BLOCK_JOBS: Lazy<Mutex<BTreeMap<String, Arc<BackupJob>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Filter of the guest writes to a disk.
+pub trait WriteFilter: Send + Sync {
+    /// Called before a guest write of `len` bytes at `offset` is submitted to the image.
+    fn before_write(&self, offset: u64, len: u64);
+}
+
+/// Write filters of a disk, shared by its io handlers.
+pub struct WriteFilters {
+    /// Id of the disk.
+    id: String,
+    /// Path of the image file.
+    path: Mutex<String>,
+    filters: Mutex<Vec<Arc<dyn WriteFilter>>>,
+}
+
+impl WriteFilters {
+    pub fn new(id: &str, path: &str) -> Self {
+        WriteFilters {
+            id: id.to_string(),
+            path: Mutex::new(path.to_string()),
+            filters: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Set the path of the image file after the backend is changed.
+    pub fn set_path(&self, path: &str) {
+        *self.path.lock().unwrap() = path.to_string();
+    }
+
+    /// Run the filters before a guest write of `len` bytes at `offset`.
+    pub fn before_write(&self, offset: u64, len: u64) {
+        for filter in self.filters.lock().unwrap().iter() {
+            filter.before_write(offset, len);
+        }
+    }
+
+    fn add(&self, filter: Arc<dyn WriteFilter>) {
+        self.filters.lock().unwrap().push(filter);
+    }
+
+    fn remove(&self, filter: &Arc<dyn WriteFilter>) {
+        self.filters
+            .lock()
+            .unwrap()
+            .retain(|f| !Arc::ptr_eq(f, filter));
+    }
+}
+
+/// Register the write filters of a disk to be used by block jobs.
+pub fn register_write_filters(filters: Arc<WriteFilters>) {
+    WRITE_FILTERS
+        .lock()
+        .unwrap()
+        .insert(filters.id.clone(), filters);
+}
+
+pub fn unregister_write_filters(id: &str) {
+    WRITE_FILTERS.lock().unwrap().remove(id);
+}
+
+/// Image accessed by the backup job.
+pub trait BackupImage: Send + Sync {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()>;
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()>;
+}
+
+impl BackupImage for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+        self.read_exact_at(buf, offset)
+            .with_context(|| format!("Failed to read {} bytes at {}", buf.len(), offset))
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+        self.write_all_at(buf, offset)
+            .with_context(|| format!("Failed to write {} bytes at {}", buf.len(), offset))
+    }
+}
+
+/// Full backup of a disk image.
+pub struct BackupJob {
+    /// Id of the disk.
+    device: String,
+    source: Arc<dyn BackupImage>,
+    target: Arc<dyn BackupImage>,
+    /// Size of the image in bytes.
+    len: u64,
+    /// Clusters which have been copied to the target.
+    copied: Mutex<Bitmap<u64>>,
+    /// Bytes copied to the target.
+    offset: AtomicU64,
+    cancelled: AtomicBool,
+    /// The first error of copying, which fails the job.
+    error: Mutex<Option<String>>,
+}
+
+impl BackupJob {
+    pub fn new(
+        device: &str,
+        source: Arc<dyn BackupImage>,
+        target: Arc<dyn BackupImage>,
+        len: u64,
+    ) -> Self {
+        let clusters = len.div_ceil(BACKUP_CLUSTER_SIZE) as usize;
+        BackupJob {
+            device: device.to_string(),
+            source,
+            target,
+            len,
+            copied: Mutex::new(Bitmap::new(clusters.div_ceil(64).max(1))),
+            offset: AtomicU64::new(0),
+            cancelled: AtomicBool::new(false),
+            error: Mutex::new(None),
+        }
+    }
+
+    fn clusters(&self) -> u64 {
+        self.len.div_ceil(BACKUP_CLUSTER_SIZE)
+    }
+
+    /// Copy the cluster to the target if it's not copied yet. The caller holds the lock of
+    /// the bitmap, so the cluster can't be copied twice.
+    fn copy_cluster(&self, copied: &mut Bitmap<u64>, cluster: u64) -> Result<()> {
+        if copied.contain(cluster as usize)? {
+            return Ok(());
+        }
+        let start = cluster * BACKUP_CLUSTER_SIZE;
+        let size = cmp::min(BACKUP_CLUSTER_SIZE, self.len - start);
+        let mut buf = vec![0_u8; size as usize];
+        self.source.read_at(&mut buf, start)?;
+        self.target.write_at(&buf, start)?;
+        copied.set(cluster as usize)?;
+        self.offset.fetch_add(size, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn set_error(&self, e: &anyhow::Error) {
+        let mut error = self.error.lock().unwrap();
+        if error.is_none() {
+            error!("Backup job of {} failed: {:?}", self.device, e);
+            *error = Some(format!("{:#}", e));
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.error.lock().unwrap().is_some()
+    }
+
+    /// Copy all clusters which are not copied by guest writes. Return false if the job
+    /// is cancelled.
+    pub fn run(&self) -> Result<bool> {
+        for cluster in 0..self.clusters() {
+            if self.cancelled.load(Ordering::SeqCst) {
+                return Ok(false);
+            }
+            if let Some(e) = self.error.lock().unwrap().as_ref() {
+                bail!("{}", e);
+            }
+            let mut copied = self.copied.lock().unwrap();
+            if let Err(e) = self.copy_cluster(&mut copied, cluster) {
+                self.set_error(&e);
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn info(&self) -> qmp_schema::BlockJobInfo {
+        qmp_schema::BlockJobInfo {
+            job_type: "backup".to_string(),
+            device: self.device.clone(),
+            len: self.len,
+            offset: self.offset.load(Ordering::SeqCst),
+            busy: true,
+            paused: false,
+            speed: 0,
+            ready: false,
+            io_status: if self.failed() { "failed" } else { "ok" }.to_string(),
+        }
+    }
+}
+
+impl WriteFilter for BackupJob {
+    fn before_write(&self, offset: u64, len: u64) {
+        if len == 0 || offset >= self.len || self.failed() {
+            return;
+        }
+        let end = cmp::min(offset.saturating_add(len), self.len);
+        let mut copied = self.copied.lock().unwrap();
+        for cluster in offset / BACKUP_CLUSTER_SIZE..end.div_ceil(BACKUP_CLUSTER_SIZE) {
+            if let Err(e) = self.copy_cluster(&mut copied, cluster) {
+                // The guest write goes on, only the backup fails.
+                self.set_error(&e);
+                return;
+            }
+        }
+    }
+}
+
+/// Start a backup job of the disk `device` to the file `target`, for `blockdev-backup`.
+pub fn qmp_blockdev_backup(device: &str, target: &str, sync: Option<&str>) -> Result<()> {
+    if !matches!(sync, None | Some("full")) {
+        bail!("Sync mode {} is not supported", sync.unwrap_or_default());
+    }
+    let filters = WRITE_FILTERS
+        .lock()
+        .unwrap()
+        .get(device)
+        .cloned()
+        .ok_or_else(|| anyhow!("Disk {} is not found", device))?;
+    let mut jobs = BLOCK_JOBS.lock().unwrap();
+    if jobs.contains_key(device) {
+        bail!("Disk {} already has a block job", device);
+    }
+
+    let path = filters.path.lock().unwrap().clone();
+    let source = File::open(&path).with_context(|| format!("Failed to open the image {}", path))?;
+    let len = source
+        .metadata()
+        .with_context(|| "Failed to get the size of the image")?
+        .len();
+    let target_file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(target)
+        .with_context(|| format!("Failed to open the backup target {}", target))?;
+    target_file
+        .set_len(len)
+        .with_context(|| format!("Failed to resize the backup target {}", target))?;
+
+    let job = Arc::new(BackupJob::new(
+        device,
+        Arc::new(source),
+        Arc::new(target_file),
+        len,
+    ));
+    let filter: Arc<dyn WriteFilter> = job.clone();
+    filters.add(filter.clone());
+    jobs.insert(device.to_string(), job.clone());
+    drop(jobs);
+
+    let (job_filters, job_filter) = (filters.clone(), filter.clone());
+    let spawned = std::thread::Builder::new()
+        .name(format!("backup-{}", device))
+        .spawn(move || {
+            let result = job.run();
+            job_filters.remove(&job_filter);
+            BLOCK_JOBS.lock().unwrap().remove(&job.device);
+            complete_backup_job(&job, result);
+        });
+    if let Err(e) = spawned {
+        filters.remove(&filter);
+        BLOCK_JOBS.lock().unwrap().remove(device);
+        bail!("Failed to start the backup job of {}: {:?}", device, e);
+    }
+    info!("Backup job of {} to {} is started", device, target);
+    Ok(())
+}
+
+fn complete_backup_job(job: &BackupJob, result: Result<bool>) {
+    let offset = job.offset.load(Ordering::SeqCst);
+    match result {
+        Ok(false) => {
+            info!("Backup job of {} is cancelled", job.device);
+            let cancelled = qmp_schema::BlockJobCancelled {
+                job_type: "backup".to_string(),
+                device: job.device.clone(),
+                len: job.len,
+                offset,
+                speed: 0,
+            };
+            event!(BlockJobCancelled; cancelled);
+        }
+        result => {
+            info!("Backup job of {} is completed", job.device);
+            let completed = qmp_schema::BlockJobCompleted {
+                job_type: "backup".to_string(),
+                device: job.device.clone(),
+                len: job.len,
+                offset,
+                speed: 0,
+                error: result.err().map(|e| format!("{:#}", e)),
+            };
+            event!(BlockJobCompleted; completed);
+        }
+    }
+}
+
+/// Cancel the block job of the disk `device`, for `block-job-cancel`.
+pub fn qmp_block_job_cancel(device: &str) -> Result<()> {
+    let jobs = BLOCK_JOBS.lock().unwrap();
+    let job = jobs
+        .get(device)
+        .ok_or_else(|| anyhow!("No block job of disk {} is found", device))?;
+    job.cancel();
+    Ok(())
+}
+
+/// Get the running block jobs for `query-block-jobs`.
+pub fn qmp_query_block_jobs() -> Vec<qmp_schema::BlockJobInfo> {
+    BLOCK_JOBS
+        .lock()
+        .unwrap()
+        .values()
+        .map(|job| job.info())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const TEST_IMAGE_SIZE: u64 = 16 * BACKUP_CLUSTER_SIZE + 4096;
+
+    /// In-memory image which counts the reads.
+    struct MemImage {
+        data: Mutex<Vec<u8>>,
+        reads: AtomicU64,
+    }
+
+    impl MemImage {
+        fn new(data: Vec<u8>) -> Self {
+            MemImage {
+                data: Mutex::new(data),
+                reads: AtomicU64::new(0),
+            }
+        }
+    }
+
+    impl BackupImage for MemImage {
+        fn read_at(&self, buf: &mut [u8], offset: u64) -> Result<()> {
+            let data = self.data.lock().unwrap();
+            buf.copy_from_slice(&data[offset as usize..offset as usize + buf.len()]);
+            self.reads.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn write_at(&self, buf: &[u8], offset: u64) -> Result<()> {
+            let mut data = self.data.lock().unwrap();
+            data[offset as usize..offset as usize + buf.len()].copy_from_slice(buf);
+            Ok(())
+        }
+    }
+
+    fn pattern(len: u64) -> Vec<u8> {
+        (0..len).map(|i| (i % 253) as u8).collect()
+    }
+
+    /// Write to the source as the guest does, the filter runs before the data lands.
+    fn guest_write(job: &BackupJob, source: &MemImage, offset: u64, len: u64, value: u8) {
+        job.before_write(offset, len);
+        source.write_at(&vec![value; len as usize], offset).unwrap();
+    }
+
+    #[test]
+    fn test_backup_copy_before_write() {
+        let snapshot = pattern(TEST_IMAGE_SIZE);
+        let source = Arc::new(MemImage::new(snapshot.clone()));
+        let target = Arc::new(MemImage::new(vec![0; TEST_IMAGE_SIZE as usize]));
+        let job = BackupJob::new("drive-0", source.clone(), target.clone(), TEST_IMAGE_SIZE);
+        assert_eq!(job.clusters(), 17);
+
+        // A write across two clusters copies both of them before it lands.
+        guest_write(&job, &source, BACKUP_CLUSTER_SIZE - 512, 1024, 0xaa);
+        assert_eq!(source.reads.load(Ordering::SeqCst), 2);
+        assert_eq!(job.info().offset, 2 * BACKUP_CLUSTER_SIZE);
+        // The cluster is copied only once.
+        guest_write(&job, &source, BACKUP_CLUSTER_SIZE, 512, 0xbb);
+        assert_eq!(source.reads.load(Ordering::SeqCst), 2);
+        // Writes beyond the image are ignored, the last cluster is partial.
+        guest_write(&job, &source, TEST_IMAGE_SIZE - 512, 512, 0xcc);
+        job.before_write(TEST_IMAGE_SIZE, 4096);
+        assert_eq!(source.reads.load(Ordering::SeqCst), 3);
+
+        assert!(job.run().unwrap());
+        assert_eq!(source.reads.load(Ordering::SeqCst), 17);
+        assert_eq!(job.info().offset, TEST_IMAGE_SIZE);
+        assert_eq!(*target.data.lock().unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_backup_concurrent_writes() {
+        let snapshot = pattern(TEST_IMAGE_SIZE);
+        let source = Arc::new(MemImage::new(snapshot.clone()));
+        let target = Arc::new(MemImage::new(vec![0; TEST_IMAGE_SIZE as usize]));
+        let job = Arc::new(BackupJob::new(
+            "drive-0",
+            source.clone(),
+            target.clone(),
+            TEST_IMAGE_SIZE,
+        ));
+
+        let writers: Vec<_> = (0..4_u64)
+            .map(|i| {
+                let job = job.clone();
+                let source = source.clone();
+                thread::spawn(move || {
+                    for j in 0..64_u64 {
+                        let offset = ((i * 64 + j) * 7919 * 512) % (TEST_IMAGE_SIZE - 8192);
+                        guest_write(&job, &source, offset, 8192, i as u8 + 1);
+                    }
+                })
+            })
+            .collect();
+        assert!(job.run().unwrap());
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // The target is the image at the time the job starts.
+        assert_eq!(*target.data.lock().unwrap(), snapshot);
+        assert_ne!(*source.data.lock().unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_backup_cancel() {
+        let source = Arc::new(MemImage::new(pattern(TEST_IMAGE_SIZE)));
+        let target = Arc::new(MemImage::new(vec![0; TEST_IMAGE_SIZE as usize]));
+        let job = BackupJob::new("drive-0", source, target, TEST_IMAGE_SIZE);
+        job.cancel();
+        assert!(!job.run().unwrap());
+        assert_eq!(job.info().offset, 0);
+    }
+
+    #[test]
+    fn test_blockdev_backup() {
+        let image = TempFile::new().unwrap();
+        let data = pattern(TEST_IMAGE_SIZE);
+        image.as_file().write_all_at(&data, 0).unwrap();
+        let target = TempFile::new().unwrap();
+        let target_path = target.as_path().to_str().unwrap().to_string();
+
+        assert!(qmp_blockdev_backup("drive-backup", &target_path, None).is_err());
+        let filters = Arc::new(WriteFilters::new(
+            "drive-backup",
+            image.as_path().to_str().unwrap(),
+        ));
+        register_write_filters(filters.clone());
+        assert!(qmp_blockdev_backup("drive-backup", &target_path, Some("top")).is_err());
+        qmp_blockdev_backup("drive-backup", &target_path, None).unwrap();
+
+        // Wait for the job to finish, the filter is removed then.
+        while !qmp_query_block_jobs()
+            .iter()
+            .all(|job| job.device != "drive-backup")
+        {
+            thread::sleep(std::time::Duration::from_millis(1));
+        }
+        assert!(filters.filters.lock().unwrap().is_empty());
+        assert!(qmp_block_job_cancel("drive-backup").is_err());
+
+        let mut copy = vec![0_u8; TEST_IMAGE_SIZE as usize];
+        target.as_file().read_exact_at(&mut copy, 0).unwrap();
+        assert_eq!(copy, data);
+        unregister_write_filters("drive-backup");
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Online resizing of virtio block and scsi disks by `block_resize`.
+//!
+//! The image file is truncated to the new size first, then the capacity of the disk is
+//! changed under the lock of the device and the guest is notified: by a config change
+//! interrupt for virtio-blk, by a CAPACITY DATA HAS CHANGED unit attention and a parameter
+//! change event for virtio-scsi.
+
+use std::fs::File;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{anyhow, bail, Context, Result};
+use log::info;
+use once_cell::sync::Lazy;
+use util::file::{get_file_size, is_block_device};
+
+use crate::Block;
+use crate::ScsiBus::scsi_device_capacity_changed;
+use crate::ScsiDisk::ScsiDevice;
+
+/// Disks which can be resized. The id of a disk may change after it's hot replaced, so
+/// the disks are looked up by their current ids.
+static RESIZABLE_DISKS: Lazy<Mutex<Vec<ResizableDisk>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+enum ResizableDisk {
+    Block(Weak<Mutex<Block>>),
+    Scsi(Weak<Mutex<ScsiDevice>>),
+}
+
+impl ResizableDisk {
+    fn is_alive(&self) -> bool {
+        match self {
+            ResizableDisk::Block(block) => block.strong_count() > 0,
+            ResizableDisk::Scsi(dev) => dev.strong_count() > 0,
+        }
+    }
+
+    fn is_scsi_device(&self, dev: &Arc<Mutex<ScsiDevice>>) -> bool {
+        matches!(self, ResizableDisk::Scsi(d) if Weak::ptr_eq(d, &Arc::downgrade(dev)))
+    }
+
+    fn scsi_device(&self, id: &str) -> Option<Arc<Mutex<ScsiDevice>>> {
+        match self {
+            ResizableDisk::Scsi(dev) => dev
+                .upgrade()
+                .filter(|dev| dev.lock().unwrap().config.id == id),
+            _ => None,
+        }
+    }
+
+    fn resize(&self, id: &str, size: u64, allow_shrink: bool) -> Result<bool> {
+        match self {
+            ResizableDisk::Block(block) => {
+                let block = match block.upgrade() {
+                    Some(block) => block,
+                    None => return Ok(false),
+                };
+                let mut locked_block = block.lock().unwrap();
+                if locked_block.id() != id {
+                    return Ok(false);
+                }
+                locked_block.resize(size, allow_shrink)?;
+            }
+            ResizableDisk::Scsi(dev) => {
+                let dev = match dev.upgrade() {
+                    Some(dev) => dev,
+                    None => return Ok(false),
+                };
+                let mut locked_dev = dev.lock().unwrap();
+                if locked_dev.config.id != id {
+                    return Ok(false);
+                }
+                locked_dev.resize(size, allow_shrink)?;
+                drop(locked_dev);
+                scsi_device_capacity_changed(&dev);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Register a virtio block device to be resized by `block_resize`, it's unregistered
+/// when the device is dropped.
+pub fn register_resizable_block(block: &Arc<Mutex<Block>>) {
+    let mut disks = RESIZABLE_DISKS.lock().unwrap();
+    disks.retain(|disk| disk.is_alive());
+    disks.push(ResizableDisk::Block(Arc::downgrade(block)));
+}
+
+/// Register a scsi device to be resized by `block_resize`.
+pub fn register_resizable_scsi_device(dev: &Arc<Mutex<ScsiDevice>>) {
+    let mut disks = RESIZABLE_DISKS.lock().unwrap();
+    disks.retain(|disk| disk.is_alive() && !disk.is_scsi_device(dev));
+    disks.push(ResizableDisk::Scsi(Arc::downgrade(dev)));
+}
+
+pub fn unregister_resizable_scsi_device(dev: &Arc<Mutex<ScsiDevice>>) {
+    RESIZABLE_DISKS
+        .lock()
+        .unwrap()
+        .retain(|disk| disk.is_alive() && !disk.is_scsi_device(dev));
+}
+
+/// Find the scsi device `id` attached to a scsi bus.
+pub(crate) fn find_scsi_device(id: &str) -> Option<Arc<Mutex<ScsiDevice>>> {
+    RESIZABLE_DISKS
+        .lock()
+        .unwrap()
+        .iter()
+        .find_map(|disk| disk.scsi_device(id))
+}
+
+/// Truncate the image of a disk from `old_size` to `new_size` bytes. A host block device
+/// can't be truncated, it's only accepted if it's already large enough.
+///
+/// # Arguments
+///
+/// * `file` - The image file.
+/// * `old_size` - Current size of the disk in bytes.
+/// * `new_size` - New size of the disk in bytes.
+/// * `block_size` - The new size must be a multiple of the block size of the disk.
+/// * `read_only` - Whether the disk is read only.
+/// * `allow_shrink` - Whether the disk can be shrunk, which may discard the guest data.
+pub(crate) fn resize_image(
+    file: &File,
+    old_size: u64,
+    new_size: u64,
+    block_size: u64,
+    read_only: bool,
+    allow_shrink: bool,
+) -> Result<()> {
+    if new_size == 0 || !new_size.is_multiple_of(block_size) {
+        bail!(
+            "The size {} is not a positive multiple of the block size {}",
+            new_size,
+            block_size
+        );
+    }
+    if read_only {
+        bail!("Read-only disk can't be resized");
+    }
+    if new_size < old_size && !allow_shrink {
+        bail!(
+            "Shrinking the disk from {} to {} bytes is not allowed without allow-shrink",
+            old_size,
+            new_size
+        );
+    }
+
+    if is_block_device(file) {
+        let dev_size =
+            get_file_size(file).with_context(|| "Failed to get the size of the block device")?;
+        if dev_size < new_size {
+            bail!(
+                "The block device has only {} bytes, it can't be resized to {} bytes",
+                dev_size,
+                new_size
+            );
+        }
+        return Ok(());
+    }
+    file.set_len(new_size)
+        .with_context(|| format!("Failed to truncate the image to {} bytes", new_size))
+}
+
+/// Resize the disk `device` to `size` bytes, for `block_resize`.
+pub fn qmp_block_resize(device: &str, size: u64, allow_shrink: bool) -> Result<()> {
+    let disks = RESIZABLE_DISKS.lock().unwrap();
+    for disk in disks.iter() {
+        if disk.resize(device, size, allow_shrink)? {
+            info!("Disk {} is resized to {} bytes", device, size);
+            return Ok(());
+        }
+    }
+    Err(anyhow!("Disk {} is not found", device))
+}
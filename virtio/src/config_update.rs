@@ -0,0 +1,256 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Device initiated changes of the virtio config space.
+//!
+//! The config space may be changed by threads other than the vCPU reading it. Every change
+//! is made under a generation counter in the manner of a seqlock: the counter is odd while
+//! the change is in progress, and is reported to the guest through the `config_generation`
+//! field of the transport, so that the guest retries a read which overlaps with a change.
+//! Config change interrupts raised within a short window are coalesced into one.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::error;
+use machine_manager::event_loop::EventLoop;
+
+use crate::{VirtioInterrupt, VirtioInterruptType};
+
+/// Minimum interval between two config change interrupts of a device.
+const CONFIG_NOTIFY_WINDOW: Duration = Duration::from_millis(20);
+
+#[derive(Default)]
+struct NotifyState {
+    /// Callback to send the config change interrupt, set when the device is activated.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// Time when the last config change interrupt was sent.
+    last_notify: Option<Instant>,
+    /// A config change interrupt has been scheduled.
+    pending: bool,
+}
+
+/// Config-update helper of a virtio device.
+pub struct ConfigUpdater {
+    /// Name of the device, used in logs.
+    name: String,
+    /// Generation of the config space, odd while a change is in progress.
+    generation: AtomicU32,
+    notify: Mutex<NotifyState>,
+}
+
+impl ConfigUpdater {
+    pub fn new(name: &str) -> Arc<Self> {
+        Arc::new(ConfigUpdater {
+            name: name.to_string(),
+            generation: AtomicU32::new(0),
+            notify: Mutex::new(NotifyState::default()),
+        })
+    }
+
+    /// Set the callback to send config change interrupts. It's set to None when the
+    /// device is deactivated, after which config changes don't raise interrupts.
+    pub fn set_interrupt_cb(&self, interrupt_cb: Option<Arc<VirtioInterrupt>>) {
+        let mut notify = self.notify.lock().unwrap();
+        notify.interrupt_cb = interrupt_cb;
+        notify.pending = false;
+    }
+
+    /// Get the generation of the config space.
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    /// Change the config space by `f` without notifying the guest, which is used for the
+    /// changes made before the device is activated or requested by the guest itself.
+    pub fn write<R>(&self, f: impl FnOnce() -> R) -> R {
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        let ret = f();
+        self.generation.fetch_add(1, Ordering::AcqRel);
+        ret
+    }
+
+    /// Change the config space by `f` and notify the guest.
+    pub fn update<R>(self: &Arc<Self>, f: impl FnOnce() -> R) -> R {
+        let ret = self.write(f);
+        self.notify();
+        ret
+    }
+
+    /// Read the config space by `f`, and retry if a change happens in the middle.
+    pub fn read<R>(&self, mut f: impl FnMut() -> R) -> R {
+        loop {
+            let generation = self.generation();
+            if generation & 1 == 0 {
+                let ret = f();
+                if self.generation() == generation {
+                    return ret;
+                }
+            }
+            std::hint::spin_loop();
+        }
+    }
+
+    /// Send the config change interrupt to the guest. Interrupts within
+    /// `CONFIG_NOTIFY_WINDOW` after the last one are coalesced and sent when the window ends.
+    pub fn notify(self: &Arc<Self>) {
+        let mut notify = self.notify.lock().unwrap();
+        if notify.interrupt_cb.is_none() || notify.pending {
+            return;
+        }
+
+        let now = Instant::now();
+        let elapsed = notify
+            .last_notify
+            .map_or(CONFIG_NOTIFY_WINDOW, |last| now.duration_since(last));
+        if elapsed >= CONFIG_NOTIFY_WINDOW {
+            notify.last_notify = Some(now);
+            let interrupt_cb = notify.interrupt_cb.clone();
+            drop(notify);
+            self.send_interrupt(interrupt_cb);
+            return;
+        }
+
+        notify.pending = true;
+        drop(notify);
+        let updater = self.clone();
+        let func = Box::new(move || updater.flush());
+        if let Some(ctx) = EventLoop::get_ctx(None) {
+            ctx.delay_call(func, (CONFIG_NOTIFY_WINDOW - elapsed).as_nanos() as u64);
+        }
+    }
+
+    /// Send the coalesced config change interrupt.
+    fn flush(&self) {
+        let mut notify = self.notify.lock().unwrap();
+        if !notify.pending {
+            return;
+        }
+        notify.pending = false;
+        notify.last_notify = Some(Instant::now());
+        let interrupt_cb = notify.interrupt_cb.clone();
+        drop(notify);
+        self.send_interrupt(interrupt_cb);
+    }
+
+    fn send_interrupt(&self, interrupt_cb: Option<Arc<VirtioInterrupt>>) {
+        if let Some(interrupt_cb) = interrupt_cb {
+            if let Err(e) = interrupt_cb(&VirtioInterruptType::Config, None, false) {
+                error!("Failed to notify config change of {}, {:?}", self.name, e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicBool;
+    use std::thread;
+
+    use super::*;
+    use crate::Queue;
+    use machine_manager::config::IothreadConfig;
+
+    fn counting_interrupt() -> (Arc<VirtioInterrupt>, Arc<AtomicU32>) {
+        let count = Arc::new(AtomicU32::new(0));
+        let cloned_count = count.clone();
+        let cb = Arc::new(Box::new(
+            move |int_type: &VirtioInterruptType, _queue: Option<&Queue>, _needs_reset: bool| {
+                if let VirtioInterruptType::Config = int_type {
+                    cloned_count.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(())
+            },
+        ) as VirtioInterrupt);
+        (cb, count)
+    }
+
+    #[test]
+    fn test_config_update_coalesce() {
+        // The global event loop is shared with other tests, which may need the io thread.
+        let io_conf = IothreadConfig {
+            id: "io1".to_string(),
+        };
+        EventLoop::object_init(&Some(vec![io_conf])).unwrap();
+        let updater = ConfigUpdater::new("test");
+        let config = Mutex::new(0_u64);
+
+        // No interrupt before the device is activated.
+        updater.update(|| *config.lock().unwrap() = 1);
+        assert_eq!(updater.generation(), 2);
+
+        let (cb, count) = counting_interrupt();
+        updater.set_interrupt_cb(Some(cb));
+        updater.update(|| *config.lock().unwrap() = 2);
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+
+        // Changes within the window are coalesced into one interrupt.
+        for i in 3..10 {
+            updater.update(|| *config.lock().unwrap() = i);
+        }
+        assert_eq!(count.load(Ordering::SeqCst), 1);
+        assert_eq!(updater.generation(), 18);
+        thread::sleep(CONFIG_NOTIFY_WINDOW);
+        EventLoop::get_ctx(None).unwrap().run_timers();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        EventLoop::get_ctx(None).unwrap().run_timers();
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        // Guest writes don't raise interrupts.
+        thread::sleep(CONFIG_NOTIFY_WINDOW);
+        updater.write(|| *config.lock().unwrap() = 10);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+        updater.set_interrupt_cb(None);
+        updater.update(|| *config.lock().unwrap() = 11);
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_config_update_no_torn_read() {
+        // Two halves of a config field are read separately by the guest, as 32-bit accesses
+        // to a 64-bit field.
+        let updater = ConfigUpdater::new("test");
+        let config = Arc::new(Mutex::new([0_u32; 2]));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        let guest_updater = updater.clone();
+        let guest_config = config.clone();
+        let guest_stop = stop.clone();
+        let guest = thread::spawn(move || {
+            let mut reads = 0;
+            loop {
+                let (low, high) = guest_updater.read(|| {
+                    let low = guest_config.lock().unwrap()[0];
+                    thread::yield_now();
+                    let high = guest_config.lock().unwrap()[1];
+                    (low, high)
+                });
+                assert_eq!(low, high);
+                reads += 1;
+                if guest_stop.load(Ordering::SeqCst) {
+                    return reads;
+                }
+            }
+        });
+
+        for i in 1..10000_u32 {
+            updater.update(|| {
+                config.lock().unwrap()[0] = i;
+                thread::yield_now();
+                config.lock().unwrap()[1] = i;
+            });
+        }
+        stop.store(true, Ordering::SeqCst);
+        assert!(guest.join().unwrap() > 0);
+    }
+}
@@ -13,24 +13,24 @@ use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 use std::{
     cmp::{self, Reverse},
-    time::Duration,
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use crate::report_virtio_error;
 use address_space::{
     AddressSpace, FlatRange, GuestAddress, Listener, ListenerReqType, RegionIoEventFd, RegionType,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use log::{error, warn};
 use machine_manager::{
     config::{BalloonConfig, DEFAULT_VIRTQUEUE_SIZE},
     event,
-    event_loop::{register_event_helper, unregister_event_helper},
-    qmp::qmp_schema::BalloonInfo,
+    event_loop::{register_event_helper, unregister_event_helper, EventLoop},
+    qmp::qmp_schema::{BalloonInfo, BalloonStats},
     qmp::QmpChannel,
 };
 use util::{
@@ -41,15 +41,18 @@ use util::{
     },
     num_ops::{read_u32, round_down},
     seccomp::BpfRule,
+    time::NANOSECONDS_PER_SECOND,
     unix::host_page_size,
 };
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd, timerfd::TimerFd};
 
 use crate::{
-    error::*, virtio_has_feature, Element, Queue, VirtioDevice, VirtioInterrupt,
-    VirtioInterruptType, VirtioTrace, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BALLOON,
+    error::*, iov_to_buf as iovec_to_buf, virtio_has_feature, ConfigUpdater, Element, Queue,
+    VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioTrace, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_VERSION_1, VIRTIO_TYPE_BALLOON,
 };
 
+const VIRTIO_BALLOON_F_STATS_VQ: u32 = 1;
 const VIRTIO_BALLOON_F_DEFLATE_ON_OOM: u32 = 2;
 const VIRTIO_BALLOON_F_REPORTING: u32 = 5;
 const VIRTIO_BALLOON_PFN_SHIFT: u32 = 12;
@@ -61,6 +64,20 @@ const IN_IOVEC: bool = true;
 const OUT_IOVEC: bool = false;
 const BITS_OF_TYPE_U64: u64 = 64;
 
+// Tags of the memory statistics reported by guest.
+const VIRTIO_BALLOON_S_SWAP_IN: u16 = 0;
+const VIRTIO_BALLOON_S_SWAP_OUT: u16 = 1;
+const VIRTIO_BALLOON_S_MAJFLT: u16 = 2;
+const VIRTIO_BALLOON_S_MINFLT: u16 = 3;
+const VIRTIO_BALLOON_S_MEMFREE: u16 = 4;
+const VIRTIO_BALLOON_S_MEMTOT: u16 = 5;
+const VIRTIO_BALLOON_S_AVAIL: u16 = 6;
+const VIRTIO_BALLOON_S_CACHES: u16 = 7;
+/// Size of a statistic: le16 tag and le64 value.
+const BALLOON_STAT_SIZE: usize = 10;
+/// Max number of statistics read from a stats buffer.
+const MAX_BALLOON_STATS: usize = 64;
+
 static mut BALLOON_DEV: Option<Arc<Mutex<Balloon>>> = None;
 
 /// IO vector, used to find memory segments.
@@ -159,6 +176,40 @@ fn memory_advise(addr: *mut libc::c_void, len: libc::size_t, advice: libc::c_int
         );
     }
 }
+/// Parse the memory statistics in `buf` reported by guest into `stats`. The
+/// statistics with unknown tags are ignored.
+fn parse_balloon_stats(buf: &[u8], stats: &mut BalloonStats) {
+    for stat in buf.chunks_exact(BALLOON_STAT_SIZE) {
+        let tag = u16::from_le_bytes([stat[0], stat[1]]);
+        let mut val = [0_u8; 8];
+        val.copy_from_slice(&stat[2..]);
+        let val = Some(u64::from_le_bytes(val));
+        match tag {
+            VIRTIO_BALLOON_S_SWAP_IN => stats.swap_in = val,
+            VIRTIO_BALLOON_S_SWAP_OUT => stats.swap_out = val,
+            VIRTIO_BALLOON_S_MAJFLT => stats.major_faults = val,
+            VIRTIO_BALLOON_S_MINFLT => stats.minor_faults = val,
+            VIRTIO_BALLOON_S_MEMFREE => stats.free_mem = val,
+            VIRTIO_BALLOON_S_MEMTOT => stats.total_mem = val,
+            VIRTIO_BALLOON_S_AVAIL => stats.available_mem = val,
+            VIRTIO_BALLOON_S_CACHES => stats.disk_caches = val,
+            _ => {}
+        }
+    }
+    stats.reclaimable_mem = reclaimable_estimate(stats);
+}
+
+/// Estimate the memory which the guest could give back: the available memory if
+/// the guest reports it, otherwise the free memory plus the disk caches.
+fn reclaimable_estimate(stats: &BalloonStats) -> Option<u64> {
+    stats
+        .available_mem
+        .or_else(|| match (stats.free_mem, stats.disk_caches) {
+            (None, None) => None,
+            (free, caches) => Some(free.unwrap_or(0).saturating_add(caches.unwrap_or(0))),
+        })
+}
+
 struct Request {
     /// The index of descriptor for the request.
     desc_index: u16,
@@ -516,13 +567,27 @@ struct BalloonIoHandler {
     report_queue: Option<Arc<Mutex<Queue>>>,
     /// Reporting EventFd.
     report_evt: Option<Arc<EventFd>>,
+    /// Statistics queue.
+    stats_queue: Option<Arc<Mutex<Queue>>>,
+    /// Statistics EventFd.
+    stats_evt: Option<Arc<EventFd>>,
+    /// Index of the stats buffer held until the next statistics request.
+    stats_desc: Option<u16>,
+    /// The stats buffer is returned to guest, and the statistics are not received yet.
+    stats_pending: bool,
+    /// Latest memory statistics of guest.
+    stats: Arc<Mutex<Option<BalloonStats>>>,
+    /// Interval in nanoseconds to request the statistics.
+    stats_interval: u64,
+    /// Bumped when the device is deactivated, which stops the stats timer.
+    stats_epoch: Arc<AtomicU64>,
     /// Device is broken or not.
     device_broken: Arc<AtomicBool>,
     /// The interrupt call back function.
     interrupt_cb: Arc<VirtioInterrupt>,
     /// Balloon Memory information.
     mem_info: Arc<Mutex<BlnMemInfo>>,
-    /// Event timer for BALLOON_CHANGED event.
+    /// Event timer for BALLOON_CHANGE event.
     event_timer: Arc<Mutex<TimerFd>>,
     /// Actual balloon size
     balloon_actual: Arc<AtomicU32>,
@@ -563,13 +628,18 @@ impl BalloonIoHandler {
                 .vring
                 .add_used(&self.mem_space, req.desc_index, req.elem_cnt)
                 .with_context(|| "Failed to add balloon response into used queue")?;
-            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue), false)
-                .with_context(|| {
-                    anyhow!(VirtioError::InterruptTrigger(
-                        "balloon",
-                        VirtioInterruptType::Vring
-                    ))
-                })?
+            if locked_queue
+                .vring
+                .should_notify(&self.mem_space, self.driver_features)
+            {
+                (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue), false)
+                    .with_context(|| {
+                        anyhow!(VirtioError::InterruptTrigger(
+                            "balloon",
+                            VirtioInterruptType::Vring
+                        ))
+                    })?;
+            }
         }
 
         Ok(())
@@ -600,31 +670,137 @@ impl BalloonIoHandler {
                 .vring
                 .add_used(&self.mem_space, req.desc_index, req.elem_cnt)
                 .with_context(|| "Failed to add balloon response into used queue")?;
-            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue), false)
-                .with_context(|| {
-                    anyhow!(VirtioError::InterruptTrigger(
-                        "balloon",
-                        VirtioInterruptType::Vring
-                    ))
-                })?;
+            if locked_queue
+                .vring
+                .should_notify(&self.mem_space, self.driver_features)
+            {
+                (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue), false)
+                    .with_context(|| {
+                        anyhow!(VirtioError::InterruptTrigger(
+                            "balloon",
+                            VirtioInterruptType::Vring
+                        ))
+                    })?;
+            }
         }
 
         Ok(())
     }
 
-    /// Send balloon changed event.
-    fn send_balloon_changed_event(&self) {
-        let ram_size = self.mem_info.lock().unwrap().get_ram_size();
-        let balloon_size = self.get_balloon_memory_size();
+    /// Receive the memory statistics from guest. The buffer is held by device,
+    /// and returned to guest to request the statistics again.
+    fn stats_evt_handler(&mut self) -> Result<()> {
+        let queue = match self.stats_queue.as_ref() {
+            Some(queue) => queue,
+            None => return Err(anyhow!(VirtioError::VirtQueueIsNone)),
+        };
+        let mut locked_queue = queue.lock().unwrap();
+        loop {
+            let elem = locked_queue
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)
+                .with_context(|| "Failed to pop avail ring for balloon statistics")?;
+            if elem.desc_num == 0 {
+                break;
+            }
+
+            let mut buf = vec![0_u8; BALLOON_STAT_SIZE * MAX_BALLOON_STATS];
+            let size = iovec_to_buf(&self.mem_space, &elem.out_iovec, &mut buf)
+                .with_context(|| "Failed to read balloon statistics")?;
+            let mut locked_stats = self.stats.lock().unwrap();
+            let stats = locked_stats.get_or_insert_with(BalloonStats::default);
+            parse_balloon_stats(&buf[..size], stats);
+            stats.last_update = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |time| time.as_secs());
+            drop(locked_stats);
+            self.stats_pending = false;
+
+            // Guest should not give more than one stats buffer, return the old one.
+            if let Some(old_desc) = self.stats_desc.replace(elem.index) {
+                locked_queue
+                    .vring
+                    .add_used(&self.mem_space, old_desc, 0)
+                    .with_context(|| "Failed to add balloon stats buffer into used queue")?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Request the memory statistics by returning the stats buffer to guest. If the
+    /// last request is not answered within the stats interval, it times out and the
+    /// outstanding buffer is forgotten, the statistics are requested again once the
+    /// guest gives a new buffer.
+    fn request_stats(&mut self) -> Result<()> {
+        let (queue, desc_index) = match (self.stats_queue.as_ref(), self.stats_desc.take()) {
+            (Some(queue), Some(desc_index)) => (queue, desc_index),
+            _ => {
+                if self.stats_pending {
+                    warn!("Balloon statistics request is not answered by guest in time");
+                    self.stats_pending = false;
+                }
+                return Ok(());
+            }
+        };
+        self.stats_pending = true;
+        let mut locked_queue = queue.lock().unwrap();
+        locked_queue
+            .vring
+            .add_used(&self.mem_space, desc_index, 0)
+            .with_context(|| "Failed to add balloon stats buffer into used queue")?;
+        if !locked_queue
+            .vring
+            .should_notify(&self.mem_space, self.driver_features)
+        {
+            return Ok(());
+        }
+        (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&locked_queue), false).with_context(
+            || {
+                anyhow!(VirtioError::InterruptTrigger(
+                    "balloon",
+                    VirtioInterruptType::Vring
+                ))
+            },
+        )
+    }
+
+    /// Send BALLOON_CHANGE event with the actual memory size of guest.
+    fn send_balloon_change_event(&self) {
         let msg = BalloonInfo {
-            actual: ram_size - balloon_size,
+            actual: guest_memory_size(&self.mem_info, &self.balloon_actual),
+            stats: None,
         };
-        event!(BalloonChanged; msg);
+        event!(BalloonChange; msg);
     }
+}
 
-    /// Get the memory size of balloon.
-    fn get_balloon_memory_size(&self) -> u64 {
-        (self.balloon_actual.load(Ordering::Acquire) as u64) << VIRTIO_BALLOON_PFN_SHIFT
+/// Request the memory statistics of guest after the stats interval, and then
+/// again every interval until the device is deactivated in `epoch`.
+fn balloon_stats_timer(balloon_io: Arc<Mutex<BalloonIoHandler>>, epoch: u64) {
+    let interval = balloon_io.lock().unwrap().stats_interval;
+    let cloned_balloon_io = balloon_io.clone();
+    let func = Box::new(move || {
+        let mut locked_balloon_io = cloned_balloon_io.lock().unwrap();
+        if locked_balloon_io.stats_epoch.load(Ordering::Acquire) != epoch
+            || locked_balloon_io.device_broken.load(Ordering::SeqCst)
+        {
+            return;
+        }
+        if let Err(e) = locked_balloon_io.request_stats() {
+            error!("Failed to request balloon statistics: {:?}", e);
+            report_virtio_error(
+                locked_balloon_io.interrupt_cb.clone(),
+                locked_balloon_io.driver_features,
+                &locked_balloon_io.device_broken,
+            );
+            return;
+        }
+        drop(locked_balloon_io);
+        balloon_stats_timer(cloned_balloon_io.clone(), epoch);
+    });
+    if let Some(ctx) = EventLoop::get_ctx(None) {
+        ctx.delay_call(func, interval);
     }
 }
 
@@ -718,6 +894,28 @@ impl EventNotifierHelper for BalloonIoHandler {
             notifiers.push(build_event_notifier(report_evt.as_raw_fd(), handler));
         }
 
+        // register event notifier for statistics event.
+        if let Some(stats_evt) = locked_balloon_io.stats_evt.as_ref() {
+            let cloned_balloon_io = balloon_io.clone();
+            let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                let mut locked_balloon_io = cloned_balloon_io.lock().unwrap();
+                if locked_balloon_io.device_broken.load(Ordering::SeqCst) {
+                    return None;
+                }
+                if let Err(e) = locked_balloon_io.stats_evt_handler() {
+                    error!("Failed to receive balloon statistics: {:?}", e);
+                    report_virtio_error(
+                        locked_balloon_io.interrupt_cb.clone(),
+                        locked_balloon_io.driver_features,
+                        &locked_balloon_io.device_broken,
+                    );
+                }
+                None
+            });
+            notifiers.push(build_event_notifier(stats_evt.as_raw_fd(), handler));
+        }
+
         // register event notifier for timer event.
         let cloned_balloon_io = balloon_io.clone();
         let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
@@ -726,7 +924,7 @@ impl EventNotifierHelper for BalloonIoHandler {
             if locked_balloon_io.device_broken.load(Ordering::SeqCst) {
                 return None;
             }
-            locked_balloon_io.send_balloon_changed_event();
+            locked_balloon_io.send_balloon_change_event();
             None
         });
         notifiers.push(build_event_notifier(
@@ -755,16 +953,26 @@ pub struct Balloon {
     num_pages: u32,
     /// Interrupt callback function.
     interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// Helper to change the config space.
+    config_updater: Arc<ConfigUpdater>,
     /// Balloon memory information.
     mem_info: Arc<Mutex<BlnMemInfo>>,
     /// Memory space
     mem_space: Arc<AddressSpace>,
-    /// Event timer for BALLOON_CHANGED event.
+    /// Event timer for BALLOON_CHANGE event, the events of the changes in one second are merged.
     event_timer: Arc<Mutex<TimerFd>>,
+    /// Minimum memory size of guest in bytes.
+    min_guest_memory: u64,
     /// EventFd for device deactivate.
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
+    /// Interval in seconds to request the memory statistics of guest.
+    monitor_interval: u64,
+    /// Latest memory statistics of guest.
+    stats: Arc<Mutex<Option<BalloonStats>>>,
+    /// Bumped when the device is deactivated, which stops the stats timer.
+    stats_epoch: Arc<AtomicU64>,
 }
 
 impl Balloon {
@@ -774,13 +982,16 @@ impl Balloon {
     ///
     /// * `bln_cfg` - Balloon configuration.
     pub fn new(bln_cfg: &BalloonConfig, mem_space: Arc<AddressSpace>, mem_share: bool) -> Balloon {
-        let mut device_features = 1u64 << VIRTIO_F_VERSION_1;
+        let mut device_features = 1u64 << VIRTIO_F_VERSION_1 | 1u64 << VIRTIO_F_RING_EVENT_IDX;
         if bln_cfg.deflate_on_oom {
             device_features |= 1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM;
         }
         if bln_cfg.free_page_reporting {
             device_features |= 1u64 << VIRTIO_BALLOON_F_REPORTING;
         }
+        if bln_cfg.monitor_interval > 0 {
+            device_features |= 1u64 << VIRTIO_BALLOON_F_STATS_VQ;
+        }
 
         Balloon {
             device_features,
@@ -788,11 +999,16 @@ impl Balloon {
             actual: Arc::new(AtomicU32::new(0)),
             num_pages: 0u32,
             interrupt_cb: None,
+            config_updater: ConfigUpdater::new("balloon"),
             mem_info: Arc::new(Mutex::new(BlnMemInfo::new(mem_share))),
             mem_space,
             event_timer: Arc::new(Mutex::new(TimerFd::new().unwrap())),
+            min_guest_memory: bln_cfg.min_guest_memory,
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
+            monitor_interval: bln_cfg.monitor_interval,
+            stats: Arc::new(Mutex::new(None)),
+            stats_epoch: Arc::new(AtomicU64::new(0)),
         }
     }
 
@@ -807,56 +1023,47 @@ impl Balloon {
         }
     }
 
-    /// Notify configuration changes to VM.
-    fn signal_config_change(&self) -> Result<()> {
-        if let Some(interrupt_cb) = &self.interrupt_cb {
-            interrupt_cb(&VirtioInterruptType::Config, None, false).with_context(|| {
-                anyhow!(VirtioError::InterruptTrigger(
-                    "balloon",
-                    VirtioInterruptType::Config
-                ))
-            })
-        } else {
-            Err(anyhow!(VirtioError::DeviceNotActivated(
-                "balloon".to_string()
-            )))
-        }
-    }
-
     /// Set the target memory size of guest. Note that
-    /// the actual size may not be the same as the target size.
+    /// the actual size may not be the same as the target size,
+    /// BALLOON_CHANGE event is sent after the guest adjusts it.
     ///
     /// # Argument
     ///
-    /// * `size` - Target momery size.
+    /// * `size` - Target momery size, between the minimum memory size of guest and the ram size.
     pub fn set_guest_memory_size(&mut self, size: u64) -> Result<()> {
         let host_page_size = host_page_size();
         if host_page_size > BALLOON_PAGE_SIZE && !self.mem_info.lock().unwrap().has_huge_page() {
             warn!("Balloon used with backing page size > 4kiB, this may not be reliable");
         }
+        if self.interrupt_cb.is_none() {
+            return Err(anyhow!(VirtioError::DeviceNotActivated(
+                "balloon".to_string()
+            )));
+        }
+        let ram_size = self.mem_info.lock().unwrap().get_ram_size();
+        if size < self.min_guest_memory || size > ram_size {
+            bail!(
+                "Balloon target {} is out of the allowed range [{}, {}]",
+                size,
+                self.min_guest_memory,
+                ram_size
+            );
+        }
         let target = (size >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
-        let address_space_ram_size =
-            (self.mem_info.lock().unwrap().get_ram_size() >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
-        let vm_target = cmp::min(target, address_space_ram_size);
-        self.num_pages = address_space_ram_size - vm_target;
-        self.signal_config_change().with_context(|| {
-            "Failed to notify about configuration change after setting balloon memory"
-        })?;
-        let msg = BalloonInfo {
-            actual: self.get_guest_memory_size(),
-        };
-        event!(BalloonChanged; msg);
+        let address_space_ram_size = (ram_size >> VIRTIO_BALLOON_PFN_SHIFT) as u32;
+        let config_updater = self.config_updater.clone();
+        config_updater.update(|| self.num_pages = address_space_ram_size - target);
         Ok(())
     }
 
-    /// Get the size of memory that reclaimed by balloon.
-    fn get_balloon_memory_size(&self) -> u64 {
-        (self.actual.load(Ordering::Acquire) as u64) << VIRTIO_BALLOON_PFN_SHIFT
-    }
-
     /// Get the actual memory size of guest.
     pub fn get_guest_memory_size(&self) -> u64 {
-        self.mem_info.lock().unwrap().get_ram_size() - self.get_balloon_memory_size()
+        guest_memory_size(&self.mem_info, &self.actual)
+    }
+
+    /// Get the latest memory statistics of guest.
+    fn get_guest_memory_stats(&self) -> Option<BalloonStats> {
+        self.stats.lock().unwrap().clone()
     }
 }
 
@@ -877,6 +1084,9 @@ impl VirtioDevice for Balloon {
     /// Get the number of balloon-device queues.
     fn queue_num(&self) -> usize {
         let mut queue_num = QUEUE_NUM_BALLOON;
+        if virtio_has_feature(self.device_features, VIRTIO_BALLOON_F_STATS_VQ) {
+            queue_num += 1;
+        }
         if virtio_has_feature(self.device_features, VIRTIO_BALLOON_F_REPORTING) {
             queue_num += 1;
         }
@@ -941,15 +1151,17 @@ impl VirtioDevice for Balloon {
     ///
     /// * `_offset` - Offset from base address.
     fn write_config(&mut self, _offset: u64, data: &[u8]) -> Result<()> {
-        // Guest update actual balloon size
+        // Guest update actual balloon size after an inflate or deflate batch.
         // Safe, because the results will be checked.
-        let old_actual = self.actual.load(Ordering::Acquire);
         let new_actual = match unsafe { data.align_to::<u32>() } {
             (_, [new_config], _) => *new_config,
             _ => {
                 return Err(anyhow!(VirtioError::FailedToWriteConfig));
             }
         };
+        let old_actual = self
+            .config_updater
+            .write(|| self.actual.swap(new_actual, Ordering::AcqRel));
         if old_actual != new_actual {
             let mut timer = self.event_timer.lock().unwrap();
             if let Ok(ret) = timer.is_armed() {
@@ -960,11 +1172,14 @@ impl VirtioDevice for Balloon {
                 }
             }
         }
-        self.actual.store(new_actual, Ordering::Release);
 
         Ok(())
     }
 
+    fn config_generation(&self) -> u32 {
+        self.config_updater.generation()
+    }
+
     /// Active balloon device.
     ///
     /// # Arguments
@@ -981,41 +1196,45 @@ impl VirtioDevice for Balloon {
         queues: &[Arc<Mutex<Queue>>],
         mut queue_evts: Vec<Arc<EventFd>>,
     ) -> Result<()> {
-        if queues.len() != self.queue_num() {
+        if queues.len() != self.queue_num() || queue_evts.len() != self.queue_num() {
             return Err(anyhow!(VirtioError::IncorrectQueueNum(
-                QUEUE_NUM_BALLOON,
+                self.queue_num(),
                 queues.len()
             )));
         }
 
+        // The queues are ordered as inflate, deflate, stats and reporting, of which the
+        // last two only exist with the corresponding features.
         let inf_queue = queues[0].clone();
         let inf_evt = queue_evts.remove(0);
         let def_queue = queues[1].clone();
         let def_evt = queue_evts.remove(0);
 
-        let mut current_queue_index = 1;
-        let (report_queue, report_evt) =
-            if virtio_has_feature(self.device_features, VIRTIO_BALLOON_F_REPORTING)
-                && current_queue_index + 1 < self.queue_num()
-                && !queue_evts.is_empty()
-            {
+        let mut current_queue_index = QUEUE_NUM_BALLOON;
+        let (stats_queue, stats_evt) =
+            if virtio_has_feature(self.device_features, VIRTIO_BALLOON_F_STATS_VQ) {
                 current_queue_index += 1;
+                (
+                    Some(queues[current_queue_index - 1].clone()),
+                    Some(queue_evts.remove(0)),
+                )
+            } else {
+                (None, None)
+            };
+        let (report_queue, report_evt) =
+            if virtio_has_feature(self.device_features, VIRTIO_BALLOON_F_REPORTING) {
                 (
                     Some(queues[current_queue_index].clone()),
                     Some(queue_evts.remove(0)),
                 )
             } else {
-                if current_queue_index + 1 >= self.queue_num() {
-                    error!(
-                        "Queue index: {} is invalid, correct index is from 0 to {}!",
-                        current_queue_index + 1,
-                        self.queue_num()
-                    )
-                }
                 (None, None)
             };
+        let stats_enabled = virtio_has_feature(self.driver_features, VIRTIO_BALLOON_F_STATS_VQ);
 
         self.interrupt_cb = Some(interrupt_cb.clone());
+        self.config_updater
+            .set_interrupt_cb(Some(interrupt_cb.clone()));
         let handler = BalloonIoHandler {
             driver_features: self.driver_features,
             mem_space,
@@ -1025,6 +1244,13 @@ impl VirtioDevice for Balloon {
             def_evt,
             report_queue,
             report_evt,
+            stats_queue,
+            stats_evt,
+            stats_desc: None,
+            stats_pending: false,
+            stats: self.stats.clone(),
+            stats_interval: self.monitor_interval * NANOSECONDS_PER_SECOND,
+            stats_epoch: self.stats_epoch.clone(),
             device_broken: self.broken.clone(),
             interrupt_cb,
             mem_info: self.mem_info.clone(),
@@ -1032,43 +1258,60 @@ impl VirtioDevice for Balloon {
             balloon_actual: self.actual.clone(),
         };
 
-        let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+        let balloon_io = Arc::new(Mutex::new(handler));
+        let notifiers = EventNotifierHelper::internal_notifiers(balloon_io.clone());
         register_event_helper(notifiers, None, &mut self.deactivate_evts)
             .with_context(|| "Failed to register balloon event notifier to MainLoop")?;
         self.broken.store(false, Ordering::SeqCst);
+        if stats_enabled {
+            balloon_stats_timer(balloon_io, self.stats_epoch.load(Ordering::Acquire));
+        }
 
         Ok(())
     }
 
     fn deactivate(&mut self) -> Result<()> {
+        self.config_updater.set_interrupt_cb(None);
+        // Stop the stats timer, and drop the statistics of the old guest.
+        self.stats_epoch.fetch_add(1, Ordering::AcqRel);
+        *self.stats.lock().unwrap() = None;
         unregister_event_helper(None, &mut self.deactivate_evts)
     }
 }
 
-pub fn qmp_balloon(target: u64) -> bool {
+/// Memory size of guest which isn't reclaimed by balloon, shared by `query-balloon`
+/// and BALLOON_CHANGE event.
+fn guest_memory_size(mem_info: &Mutex<BlnMemInfo>, actual: &AtomicU32) -> u64 {
+    let balloon_size = (actual.load(Ordering::Acquire) as u64) << VIRTIO_BALLOON_PFN_SHIFT;
+    mem_info
+        .lock()
+        .unwrap()
+        .get_ram_size()
+        .saturating_sub(balloon_size)
+}
+
+/// Set the target memory size of guest, `VirtioError::DeviceNotActivated` is returned
+/// if there is no activated balloon device.
+pub fn qmp_balloon(target: u64) -> Result<()> {
     // Safe, because there is no confliction when writing global variable BALLOON_DEV, in other words,
     // this function will not be called simultaneously.
     if let Some(dev) = unsafe { &BALLOON_DEV } {
-        match dev.lock().unwrap().set_guest_memory_size(target) {
-            Ok(()) => {
-                return true;
-            }
-            Err(ref e) => {
-                error!("Failed to set balloon memory size: {}, :{:?}", target, e);
-                return false;
-            }
-        }
+        return dev.lock().unwrap().set_guest_memory_size(target);
     }
-    error!("Balloon device not configured");
-    false
+    Err(anyhow!(VirtioError::DeviceNotActivated(
+        "balloon".to_string()
+    )))
 }
 
-pub fn qmp_query_balloon() -> Option<u64> {
+pub fn qmp_query_balloon() -> Option<BalloonInfo> {
     // Safe, because there is no confliction when writing global variable BALLOON_DEV, in other words,
     // this function will not be called simultaneously.
     if let Some(dev) = unsafe { &BALLOON_DEV } {
         let unlocked_dev = dev.lock().unwrap();
-        return Some(unlocked_dev.get_guest_memory_size());
+        return Some(BalloonInfo {
+            actual: unlocked_dev.get_guest_memory_size(),
+            stats: unlocked_dev.get_guest_memory_stats(),
+        });
     }
     None
 }
@@ -1089,7 +1332,10 @@ mod tests {
     pub use super::*;
     pub use crate::*;
 
+    use std::thread;
+
     use address_space::{AddressRange, HostMemMapping, Region};
+    use machine_manager::config::IothreadConfig;
 
     const MEMORY_SIZE: u64 = 1024 * 1024;
     const QUEUE_SIZE: u16 = 256;
@@ -1140,6 +1386,8 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: Default::default(),
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
 
         let mem_space = address_space_init();
@@ -1148,7 +1396,9 @@ mod tests {
         assert_eq!(bln.actual.load(Ordering::Acquire), 0);
         assert_eq!(bln.num_pages, 0);
         assert!(bln.interrupt_cb.is_none());
-        let feature = (1u64 << VIRTIO_F_VERSION_1) | (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
+        let feature = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_F_RING_EVENT_IDX)
+            | (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM);
         assert_eq!(bln.device_features, feature);
 
         let fts = bln.get_device_features(0);
@@ -1186,6 +1436,8 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: Default::default(),
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
 
         let mem_space = address_space_init();
@@ -1193,7 +1445,7 @@ mod tests {
         let ret_data = [0, 0, 0, 0, 1, 0, 0, 0];
         let mut read_data: Vec<u8> = vec![0; 8];
         let addr = 0x00;
-        assert_eq!(balloon.get_balloon_memory_size(), 0);
+        assert_eq!(balloon.actual.load(Ordering::Acquire), 0);
         balloon.actual.store(1, Ordering::Release);
         balloon.read_config(addr, &mut read_data).unwrap();
         assert_eq!(read_data, ret_data);
@@ -1205,6 +1457,8 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: Default::default(),
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
 
         let mem_space = address_space_init();
@@ -1212,7 +1466,7 @@ mod tests {
         let ret_data = [1, 0, 0, 0, 0, 0, 0, 0];
         let mut read_data: Vec<u8> = vec![0; 8];
         let addr = 0x4;
-        assert_eq!(balloon.get_balloon_memory_size(), 0);
+        assert_eq!(balloon.actual.load(Ordering::Acquire), 0);
         balloon.actual.store(1, Ordering::Release);
         balloon.read_config(addr, &mut read_data).unwrap();
         assert_eq!(read_data, ret_data);
@@ -1224,13 +1478,15 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: Default::default(),
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
 
         let mem_space = address_space_init();
         let balloon = Balloon::new(&bln_cfg, mem_space, false);
         let mut read_data: Vec<u8> = vec![0; 8];
         let addr: u64 = 0xffff_ffff_ffff_ffff;
-        assert_eq!(balloon.get_balloon_memory_size(), 0);
+        assert_eq!(balloon.actual.load(Ordering::Acquire), 0);
         balloon.actual.store(1, Ordering::Release);
         let ret = balloon.read_config(addr, &mut read_data);
         assert!(ret.is_err());
@@ -1242,17 +1498,70 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: Default::default(),
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
 
         let mem_space = address_space_init();
         let mut balloon = Balloon::new(&bln_cfg, mem_space, false);
         let write_data = [1, 0, 0, 0];
         let addr = 0x00;
-        assert_eq!(balloon.get_balloon_memory_size(), 0);
+        assert_eq!(balloon.actual.load(Ordering::Acquire), 0);
         balloon.write_config(addr, &write_data).unwrap();
         assert_eq!(balloon.actual.load(Ordering::Acquire), 1);
     }
 
+    #[test]
+    fn test_balloon_change() {
+        let bln_cfg = BalloonConfig {
+            id: "bln".to_string(),
+            min_guest_memory: MEMORY_SIZE / 4,
+            ..Default::default()
+        };
+        let mem_space = address_space_init();
+        let mut bln = Balloon::new(&bln_cfg, mem_space, false);
+        bln.realize().unwrap();
+        assert!(bln.set_guest_memory_size(MEMORY_SIZE / 2).is_err());
+
+        let cb = Arc::new(Box::new(
+            move |_: &VirtioInterruptType, _: Option<&Queue>, _: bool| Ok(()),
+        ) as VirtioInterrupt);
+        bln.interrupt_cb = Some(cb);
+
+        // Targets out of [min-guest-memory, ram size] are rejected.
+        let err = bln.set_guest_memory_size(MEMORY_SIZE / 8).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Balloon target {} is out of the allowed range [{}, {}]",
+                MEMORY_SIZE / 8,
+                MEMORY_SIZE / 4,
+                MEMORY_SIZE
+            )
+        );
+        assert!(bln.set_guest_memory_size(MEMORY_SIZE + 1).is_err());
+        assert_eq!(bln.num_pages, 0);
+
+        bln.set_guest_memory_size(MEMORY_SIZE / 2).unwrap();
+        let target_pages = (MEMORY_SIZE / 2) >> VIRTIO_BALLOON_PFN_SHIFT;
+        assert_eq!(bln.num_pages as u64, target_pages);
+        // No event until the guest inflates the balloon.
+        assert!(!bln.event_timer.lock().unwrap().is_armed().unwrap());
+        assert_eq!(bln.get_guest_memory_size(), MEMORY_SIZE);
+
+        // The guest completes the inflate batch.
+        bln.write_config(0, (target_pages as u32).as_bytes())
+            .unwrap();
+        assert!(bln.event_timer.lock().unwrap().is_armed().unwrap());
+        let event_actual = guest_memory_size(&bln.mem_info, &bln.actual);
+        assert_eq!(event_actual, MEMORY_SIZE / 2);
+        assert_eq!(bln.get_guest_memory_size(), event_actual);
+
+        // The guest deflates the balloon.
+        bln.write_config(0, 0_u32.as_bytes()).unwrap();
+        assert_eq!(guest_memory_size(&bln.mem_info, &bln.actual), MEMORY_SIZE);
+    }
+
     #[test]
     fn test_balloon_process() {
         let mem_space = address_space_init();
@@ -1260,6 +1569,8 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: Default::default(),
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
         let mut bln = Balloon::new(&bln_cfg, mem_space.clone(), false);
         bln.realize().unwrap();
@@ -1335,6 +1646,13 @@ mod tests {
             def_evt: event_def,
             report_queue: None,
             report_evt: None,
+            stats_queue: None,
+            stats_evt: None,
+            stats_desc: None,
+            stats_pending: false,
+            stats: bln.stats.clone(),
+            stats_interval: 0,
+            stats_epoch: bln.stats_epoch.clone(),
             device_broken: bln.broken.clone(),
             interrupt_cb: cb.clone(),
             mem_info: bln.mem_info.clone(),
@@ -1346,7 +1664,7 @@ mod tests {
         Balloon::object_init(balloon);
 
         // Query balloon.
-        assert_eq!(qmp_query_balloon(), Some(MEMORY_SIZE));
+        assert_eq!(qmp_query_balloon().unwrap().actual, MEMORY_SIZE);
 
         // Create SplitVringDesc and set addr to be 0x2000.
         let desc = SplitVringDesc {
@@ -1376,8 +1694,8 @@ mod tests {
             .unwrap();
 
         assert!(handler.process_balloon_queue(BALLOON_INFLATE_EVENT).is_ok());
-        assert_eq!(handler.get_balloon_memory_size(), 0);
-        assert_eq!(qmp_query_balloon(), Some(MEMORY_SIZE));
+        assert_eq!(handler.balloon_actual.load(Ordering::Acquire), 0);
+        assert_eq!(qmp_query_balloon().unwrap().actual, MEMORY_SIZE);
 
         // SplitVringDesc for deflate.
         let desc = SplitVringDesc {
@@ -1439,6 +1757,8 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: Default::default(),
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
         let mut bln = Balloon::new(&bln_cfg, mem_space.clone(), false);
         assert!(bln
@@ -1503,6 +1823,8 @@ mod tests {
             id: "bln".to_string(),
             deflate_on_oom: true,
             free_page_reporting: true,
+            monitor_interval: 0,
+            min_guest_memory: 0,
         };
         let mem_space = address_space_init();
         let mut bln = Balloon::new(&bln_cfg, mem_space, false);
@@ -1511,6 +1833,7 @@ mod tests {
         assert_eq!(bln.num_pages, 0);
         assert!(bln.interrupt_cb.is_none());
         let feature = (1u64 << VIRTIO_F_VERSION_1)
+            | (1u64 << VIRTIO_F_RING_EVENT_IDX)
             | (1u64 << VIRTIO_BALLOON_F_DEFLATE_ON_OOM | 1u64 << VIRTIO_BALLOON_F_REPORTING);
         assert_eq!(bln.device_features, feature);
 
@@ -1530,4 +1853,224 @@ mod tests {
 
         assert!(bln.update_config(None).is_err());
     }
+
+    fn stats_buf(stats: &[(u16, u64)]) -> Vec<u8> {
+        let mut buf = Vec::new();
+        for (tag, val) in stats {
+            buf.extend_from_slice(&tag.to_le_bytes());
+            buf.extend_from_slice(&val.to_le_bytes());
+        }
+        buf
+    }
+
+    #[test]
+    fn test_balloon_stats_parse() {
+        let mut stats = BalloonStats::default();
+        let buf = stats_buf(&[
+            (VIRTIO_BALLOON_S_SWAP_IN, 1),
+            (VIRTIO_BALLOON_S_SWAP_OUT, 2),
+            (VIRTIO_BALLOON_S_MEMFREE, 0x1000),
+            (VIRTIO_BALLOON_S_MEMTOT, 0x4000),
+            (VIRTIO_BALLOON_S_AVAIL, 0x2000),
+            // Unknown tag is ignored.
+            (100, 3),
+        ]);
+        parse_balloon_stats(&buf, &mut stats);
+        assert_eq!(stats.swap_in, Some(1));
+        assert_eq!(stats.swap_out, Some(2));
+        assert_eq!(stats.free_mem, Some(0x1000));
+        assert_eq!(stats.total_mem, Some(0x4000));
+        assert_eq!(stats.available_mem, Some(0x2000));
+        assert_eq!(stats.major_faults, None);
+        assert_eq!(stats.disk_caches, None);
+        assert_eq!(stats.reclaimable_mem, Some(0x2000));
+
+        // The truncated statistic is ignored, and the others are updated.
+        let mut buf = stats_buf(&[
+            (VIRTIO_BALLOON_S_MEMFREE, 0x800),
+            (VIRTIO_BALLOON_S_CACHES, 7),
+        ]);
+        buf.truncate(buf.len() - 1);
+        parse_balloon_stats(&buf, &mut stats);
+        assert_eq!(stats.free_mem, Some(0x800));
+        assert_eq!(stats.total_mem, Some(0x4000));
+        assert_eq!(stats.disk_caches, None);
+
+        // The unknown tags between the known ones are skipped. Without the available
+        // memory, the free memory and the disk caches are reclaimable.
+        let mut stats = BalloonStats::default();
+        let buf = stats_buf(&[
+            (8, 0x100),
+            (VIRTIO_BALLOON_S_MEMFREE, 0x1000),
+            (0xffff, u64::MAX),
+            (VIRTIO_BALLOON_S_CACHES, 0x3000),
+            (10, 0x200),
+        ]);
+        parse_balloon_stats(&buf, &mut stats);
+        assert_eq!(stats.free_mem, Some(0x1000));
+        assert_eq!(stats.disk_caches, Some(0x3000));
+        assert_eq!(stats.reclaimable_mem, Some(0x4000));
+        assert_eq!(
+            BalloonStats {
+                last_update: 0,
+                ..stats
+            },
+            BalloonStats {
+                free_mem: Some(0x1000),
+                disk_caches: Some(0x3000),
+                reclaimable_mem: Some(0x4000),
+                ..Default::default()
+            }
+        );
+        let mut stats = BalloonStats::default();
+        parse_balloon_stats(&stats_buf(&[(VIRTIO_BALLOON_S_SWAP_IN, 1)]), &mut stats);
+        assert_eq!(stats.reclaimable_mem, None);
+    }
+
+    #[test]
+    fn test_balloon_stats_timer() {
+        // The global event loop is shared with other tests, which may need the io thread.
+        let io_conf = IothreadConfig {
+            id: "io1".to_string(),
+        };
+        EventLoop::object_init(&Some(vec![io_conf])).unwrap();
+        let mem_space = address_space_init();
+        let bln_cfg = BalloonConfig {
+            id: "bln".to_string(),
+            deflate_on_oom: false,
+            free_page_reporting: false,
+            monitor_interval: 1,
+            min_guest_memory: 0,
+        };
+        let bln = Balloon::new(&bln_cfg, mem_space.clone(), false);
+        assert!(virtio_has_feature(
+            bln.device_features,
+            VIRTIO_BALLOON_F_STATS_VQ
+        ));
+        assert_eq!(bln.queue_num(), 3);
+
+        let vring_count = Arc::new(AtomicU32::new(0));
+        let cloned_count = vring_count.clone();
+        let cb = Arc::new(Box::new(
+            move |int_type: &VirtioInterruptType, _queue: Option<&Queue>, _needs_reset: bool| {
+                if let VirtioInterruptType::Vring = int_type {
+                    cloned_count.fetch_add(1, Ordering::SeqCst);
+                }
+                Ok(())
+            },
+        ) as VirtioInterrupt);
+
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0x100);
+        queue_config.addr_cache.desc_table_host =
+            mem_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.avail_ring = GuestAddress(0x1100);
+        queue_config.addr_cache.avail_ring_host =
+            mem_space.get_host_address(queue_config.avail_ring).unwrap();
+        queue_config.used_ring = GuestAddress(0x1600);
+        queue_config.addr_cache.used_ring_host =
+            mem_space.get_host_address(queue_config.used_ring).unwrap();
+        queue_config.ready = true;
+        queue_config.size = QUEUE_SIZE;
+        let used_idx_addr = GuestAddress(queue_config.used_ring.0 + 2);
+
+        let inf_queue = Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap()));
+        let def_queue = Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap()));
+        let stats_queue = Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap()));
+        let handler = BalloonIoHandler {
+            driver_features: bln.device_features,
+            mem_space: mem_space.clone(),
+            inf_queue,
+            inf_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()),
+            def_queue,
+            def_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()),
+            report_queue: None,
+            report_evt: None,
+            stats_queue: Some(stats_queue),
+            stats_evt: Some(Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap())),
+            stats_desc: None,
+            stats_pending: false,
+            stats: bln.stats.clone(),
+            // Request the statistics every 1ms.
+            stats_interval: 1_000_000,
+            stats_epoch: bln.stats_epoch.clone(),
+            device_broken: bln.broken.clone(),
+            interrupt_cb: cb,
+            mem_info: bln.mem_info.clone(),
+            event_timer: bln.event_timer.clone(),
+            balloon_actual: bln.actual.clone(),
+        };
+        let balloon_io = Arc::new(Mutex::new(handler));
+
+        // Guest gives the stats buffer in descriptor `index` with free memory `free`, and
+        // waits for it to be used.
+        let give_stats = |index: u16, free: u64| {
+            let buf = stats_buf(&[(VIRTIO_BALLOON_S_MEMFREE, free)]);
+            let desc = SplitVringDesc {
+                addr: GuestAddress(0x2000 + u64::from(index) * 0x100),
+                len: buf.len() as u32,
+                flags: 0,
+                next: 0,
+            };
+            mem_space
+                .write_object::<SplitVringDesc>(
+                    &desc,
+                    GuestAddress(queue_config.desc_table.0 + u64::from(index) * 16),
+                )
+                .unwrap();
+            mem_space
+                .write(&mut buf.as_slice(), desc.addr, buf.len() as u64)
+                .unwrap();
+            mem_space
+                .write_object::<u16>(
+                    &index,
+                    GuestAddress(queue_config.avail_ring.0 + 4 + u64::from(index) * 2),
+                )
+                .unwrap();
+            mem_space
+                .write_object::<u16>(&(index + 1), GuestAddress(queue_config.avail_ring.0 + 2))
+                .unwrap();
+            mem_space
+                .write_object::<u16>(
+                    &index,
+                    GuestAddress(queue_config.avail_ring.0 + 4 + u64::from(QUEUE_SIZE) * 2),
+                )
+                .unwrap();
+            balloon_io.lock().unwrap().stats_evt_handler().unwrap();
+        };
+        let run_timers = || {
+            thread::sleep(Duration::from_millis(5));
+            EventLoop::get_ctx(None).unwrap().run_timers();
+        };
+        let free_mem = || bln.get_guest_memory_stats().unwrap().free_mem;
+
+        // The first statistics are received, and held until the timer fires.
+        give_stats(0, 0x1000);
+        assert_eq!(free_mem(), Some(0x1000));
+        assert!(bln.get_guest_memory_stats().unwrap().last_update > 0);
+        balloon_stats_timer(balloon_io.clone(), 0);
+        assert_eq!(mem_space.read_object::<u16>(used_idx_addr).unwrap(), 0);
+        run_timers();
+        assert_eq!(mem_space.read_object::<u16>(used_idx_addr).unwrap(), 1);
+        assert_eq!(vring_count.load(Ordering::SeqCst), 1);
+
+        // The timer is rescheduled, and does nothing without a stats buffer. The request
+        // which is not answered in time is reset.
+        assert!(balloon_io.lock().unwrap().stats_pending);
+        run_timers();
+        assert_eq!(vring_count.load(Ordering::SeqCst), 1);
+        assert!(!balloon_io.lock().unwrap().stats_pending);
+        give_stats(1, 0x2000);
+        assert_eq!(free_mem(), Some(0x2000));
+        run_timers();
+        assert_eq!(mem_space.read_object::<u16>(used_idx_addr).unwrap(), 2);
+        assert_eq!(vring_count.load(Ordering::SeqCst), 2);
+
+        // The timer stops after the device is reset.
+        bln.stats_epoch.fetch_add(1, Ordering::AcqRel);
+        give_stats(2, 0x3000);
+        run_timers();
+        assert_eq!(mem_space.read_object::<u16>(used_idx_addr).unwrap(), 2);
+        assert_eq!(vring_count.load(Ordering::SeqCst), 2);
+    }
 }
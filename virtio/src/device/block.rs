@@ -13,23 +13,30 @@
 use std::cmp;
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Seek, SeekFrom, Write};
+use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, Weak};
 use std::time::Instant;
 
 use crate::VirtioError;
 use crate::{
-    iov_discard_back, iov_discard_front, iov_to_buf, report_virtio_error, virtio_has_feature,
-    Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioTrace,
-    VIRTIO_BLK_F_FLUSH, VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX,
+    iov_discard_back, iov_discard_front, iov_to_buf, register_block_error_stats,
+    register_block_io_stats, register_write_filters, register_write_protect,
+    register_write_threshold, report_virtio_error, resize_image, unregister_block_error_stats,
+    unregister_block_io_stats, unregister_write_filters, unregister_write_protect,
+    unregister_write_threshold, virtio_has_feature, BlockIoStats, ConfigUpdater, DeviceErrorStats,
+    Element, ErrorAction, ErrorCategory, IoErrorPolicy, Queue, SlowIoDetector, StoppedRequests,
+    VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioTrace, WriteFilters, WriteProtect,
+    WriteThreshold, VIRTIO_BLK_F_BLK_SIZE, VIRTIO_BLK_F_DISCARD, VIRTIO_BLK_F_FLUSH,
+    VIRTIO_BLK_F_MQ, VIRTIO_BLK_F_RO, VIRTIO_BLK_F_SEG_MAX, VIRTIO_BLK_F_TOPOLOGY,
     VIRTIO_BLK_ID_BYTES, VIRTIO_BLK_S_IOERR, VIRTIO_BLK_S_OK, VIRTIO_BLK_S_UNSUPP,
-    VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN, VIRTIO_BLK_T_OUT,
-    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
+    VIRTIO_BLK_T_DISCARD, VIRTIO_BLK_T_FLUSH, VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_T_IN,
+    VIRTIO_BLK_T_OUT, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
+    VIRTIO_TYPE_BLOCK,
 };
 use address_space::{AddressSpace, GuestAddress};
 use anyhow::{anyhow, bail, Context, Result};
@@ -37,19 +44,27 @@ use byteorder::{ByteOrder, LittleEndian};
 use log::{error, warn};
 use machine_manager::config::{BlkDevConfig, ConfigCheck, DriveFile, VmConfig};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper, EventLoop};
+use machine_manager::machine::MachineLifecycle;
 use migration::{
     migration::Migratable, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
     StateTransfer,
 };
 use migration_derive::{ByteCode, Desc};
-use util::aio::{iov_from_buf_direct, raw_datasync, Aio, AioCb, AioEngine, Iovec, OpCode};
+use util::aio::{
+    iov_from_buf_direct, iov_to_buf_direct, raw_datasync, Aio, AioCb, AioEngine, BackingImage,
+    Iovec, OpCode,
+};
 use util::byte_code::ByteCode;
+use util::file::{
+    get_block_device_max_discard, get_block_device_sector_size, get_file_size, is_block_device,
+};
 use util::leak_bucket::LeakBucket;
 use util::loop_context::{
     read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
 };
 use util::num_ops::read_u32;
 use util::offset_of;
+use util::throttle_group::{join_throttle_group, ThrottleGroupMember};
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 /// Number of virtqueues.
 const QUEUE_NUM_BLK: usize = 1;
@@ -70,12 +85,15 @@ const MAX_MILLIS_TIME_PROCESS_QUEUE: u16 = 100;
 
 type SenderConfig = (
     Option<Arc<File>>,
+    Option<Arc<BackingImage>>,
     u32,
     u32,
     u64,
     Option<String>,
     bool,
+    bool,
     AioEngine,
+    Arc<IoErrorPolicy>,
 );
 
 fn get_serial_num_config(serial_num: &str) -> Vec<u8> {
@@ -97,6 +115,17 @@ struct RequestOutHeader {
 
 impl ByteCode for RequestOutHeader {}
 
+/// Segment of the discard request.
+#[repr(C)]
+#[derive(Default, Clone, Copy)]
+struct DiscardSegment {
+    sector: u64,
+    num_sectors: u32,
+    flags: u32,
+}
+
+impl ByteCode for DiscardSegment {}
+
 #[derive(Clone)]
 pub struct AioCompleteCb {
     queue: Arc<Mutex<Queue>>,
@@ -105,22 +134,28 @@ pub struct AioCompleteCb {
     req: Rc<Request>,
     interrupt_cb: Arc<VirtioInterrupt>,
     driver_features: u64,
+    /// Error statistics of the block device.
+    error_stats: Arc<DeviceErrorStats>,
+    /// I/O statistics of the block device.
+    io_stats: Arc<BlockIoStats>,
+    /// Read/write error policies of the block device.
+    io_error: Arc<IoErrorPolicy>,
+    /// Requests failed with the `stop` action.
+    stopped_reqs: Rc<StoppedRequests<Rc<Request>>>,
 }
 
 impl AioCompleteCb {
-    fn new(
-        queue: Arc<Mutex<Queue>>,
-        mem_space: Arc<AddressSpace>,
-        req: Rc<Request>,
-        interrupt_cb: Arc<VirtioInterrupt>,
-        driver_features: u64,
-    ) -> Self {
+    fn new(handler: &BlockIoHandler, req: Rc<Request>) -> Self {
         AioCompleteCb {
-            queue,
-            mem_space,
+            queue: handler.queue.clone(),
+            mem_space: handler.mem_space.clone(),
             req,
-            interrupt_cb,
-            driver_features,
+            interrupt_cb: handler.interrupt_cb.clone(),
+            driver_features: handler.driver_features,
+            error_stats: handler.error_stats.clone(),
+            io_stats: handler.io_stats.clone(),
+            io_error: handler.io_error.clone(),
+            stopped_reqs: handler.stopped_reqs.clone(),
         }
     }
 
@@ -233,9 +268,9 @@ impl Request {
         }
 
         match out_header.request_type {
-            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_GET_ID | VIRTIO_BLK_T_OUT => {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_GET_ID | VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD => {
                 let data_iovec = match out_header.request_type {
-                    VIRTIO_BLK_T_OUT => {
+                    VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD => {
                         iov_discard_front(&mut elem.out_iovec, size_of::<RequestOutHeader>() as u64)
                     }
                     // Otherwise discard the last "status" byte.
@@ -261,11 +296,37 @@ impl Request {
             VIRTIO_BLK_T_FLUSH => (),
             others => {
                 error!("Request type {} is not supported for block", others);
+                request.record_error(
+                    &handler.error_stats,
+                    ErrorCategory::InvalidRequest,
+                    libc::ENOTSUP,
+                );
                 *status = VIRTIO_BLK_S_UNSUPP;
             }
         }
 
-        if !request.io_range_valid(handler.disk_sectors) {
+        if (handler.read_only || handler.io_error.is_write_protected())
+            && matches!(
+                out_header.request_type,
+                VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_DISCARD
+            )
+        {
+            error!(
+                "Request type {} is rejected by read-only block device",
+                out_header.request_type
+            );
+            request.record_error(
+                &handler.error_stats,
+                ErrorCategory::InvalidRequest,
+                libc::EROFS,
+            );
+            *status = VIRTIO_BLK_S_IOERR;
+        } else if !request.io_range_valid(handler.disk_sectors) {
+            request.record_error(
+                &handler.error_stats,
+                ErrorCategory::InvalidRequest,
+                libc::EINVAL,
+            );
             *status = VIRTIO_BLK_S_IOERR;
         }
 
@@ -313,6 +374,12 @@ impl Request {
             }
             VIRTIO_BLK_T_OUT => {
                 aiocb.opcode = OpCode::Pwritev;
+                iohandler
+                    .write_threshold
+                    .update(aiocb.offset as u64, aiocb.nbytes);
+                iohandler
+                    .write_filters
+                    .before_write(aiocb.offset as u64, aiocb.nbytes);
                 aio.submit_request(aiocb)
                     .with_context(|| "Failed to process block request for writing")?;
             }
@@ -327,18 +394,73 @@ impl Request {
                 let status = iov_from_buf_direct(&self.iovec, &serial_vec).map_or_else(
                     |e| {
                         error!("Failed to process block request for getting id, {:?}", e);
+                        self.record_error(
+                            &iohandler.error_stats,
+                            ErrorCategory::InvalidRequest,
+                            libc::EFAULT,
+                        );
                         VIRTIO_BLK_S_IOERR
                     },
                     |_| VIRTIO_BLK_S_OK,
                 );
                 aiocb.iocompletecb.complete_request(status)?;
             }
+            VIRTIO_BLK_T_DISCARD => match self.discard_range(iohandler.disk_sectors) {
+                Ok((offset, nbytes)) => {
+                    aiocb.opcode = OpCode::Discard;
+                    aiocb.iovec.clear();
+                    aiocb.offset = offset as usize;
+                    aiocb.nbytes = nbytes;
+                    iohandler.write_filters.before_write(offset, nbytes);
+                    aio.submit_request(aiocb)
+                        .with_context(|| "Failed to process block request for discarding")?;
+                }
+                Err((status, errno)) => {
+                    self.record_error(&iohandler.error_stats, ErrorCategory::InvalidRequest, errno);
+                    aiocb.iocompletecb.complete_request(status)?;
+                }
+            },
             // The illegal request type has been handled in method new().
             _ => {}
         };
         Ok(())
     }
 
+    /// Get the byte range of the only segment of the discard request, return the request
+    /// status and the errno if it's invalid.
+    fn discard_range(&self, disk_sectors: u64) -> std::result::Result<(u64, u64), (u8, i32)> {
+        if self.data_len != size_of::<DiscardSegment>() as u64 {
+            error!(
+                "Invalid discard request with data length {}, only one segment is supported",
+                self.data_len
+            );
+            return Err((VIRTIO_BLK_S_UNSUPP, libc::EINVAL));
+        }
+        let mut segment = DiscardSegment::default();
+        if let Err(e) = iov_to_buf_direct(&self.iovec, segment.as_mut_bytes()) {
+            error!("Failed to get discard segment, {:?}", e);
+            return Err((VIRTIO_BLK_S_IOERR, libc::EINVAL));
+        }
+        let sector = LittleEndian::read_u64(segment.sector.as_bytes());
+        let num_sectors = LittleEndian::read_u32(segment.num_sectors.as_bytes()) as u64;
+        // The unmap flag is only valid for write zeroes.
+        if LittleEndian::read_u32(segment.flags.as_bytes()) != 0 {
+            return Err((VIRTIO_BLK_S_UNSUPP, libc::EINVAL));
+        }
+        if sector
+            .checked_add(num_sectors)
+            .filter(|&end| end <= disk_sectors)
+            .is_none()
+        {
+            error!(
+                "Discard sector {} num {} invalid, disk sector {}",
+                sector, num_sectors, disk_sectors
+            );
+            return Err((VIRTIO_BLK_S_IOERR, libc::EINVAL));
+        }
+        Ok((sector << SECTOR_SHIFT, num_sectors << SECTOR_SHIFT))
+    }
+
     fn io_range_valid(&self, disk_sectors: u64) -> bool {
         match self.out_header.request_type {
             VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
@@ -367,6 +489,16 @@ impl Request {
     fn get_req_sector_num(&self) -> u64 {
         self.data_len / SECTOR_SIZE
     }
+
+    fn record_error(&self, error_stats: &DeviceErrorStats, category: ErrorCategory, errno: i32) {
+        error_stats.record(
+            category,
+            errno,
+            self.out_header.request_type,
+            self.out_header.sector << SECTOR_SHIFT,
+            self.data_len,
+        );
+    }
 }
 
 /// Control block of Block IO.
@@ -379,6 +511,8 @@ struct BlockIoHandler {
     mem_space: Arc<AddressSpace>,
     /// The image file opened by the block device.
     disk_image: Option<Arc<File>>,
+    /// Backing image of the disk image.
+    backing: Option<Arc<BackingImage>>,
     /// The align requirement of request(offset/len).
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
@@ -389,8 +523,12 @@ struct BlockIoHandler {
     serial_num: Option<String>,
     /// If use direct access io.
     direct: bool,
+    /// If the block device is read-only, write requests are rejected.
+    read_only: bool,
     /// Aio context.
     aio: Box<Aio<AioCompleteCb>>,
+    /// Register the disk image and the guest memory to the aio context.
+    aio_register: bool,
     /// Bit mask of features negotiated by the backend and the frontend.
     driver_features: u64,
     /// The receiving half of Rust's channel to receive the image file.
@@ -401,13 +539,56 @@ struct BlockIoHandler {
     device_broken: Arc<AtomicBool>,
     /// Callback to trigger an interrupt.
     interrupt_cb: Arc<VirtioInterrupt>,
+    /// Helper to notify the guest of config space changes.
+    config_updater: Arc<ConfigUpdater>,
     /// thread name of io handler
     iothread: Option<String>,
     /// Using the leak bucket to implement IO limits
     leak_bucket: Option<LeakBucket>,
+    /// Membership of the throttle group whose IO limits are shared with other drives.
+    throttle_member: Option<ThrottleGroupMember>,
+    /// Error statistics of the block device.
+    error_stats: Arc<DeviceErrorStats>,
+    /// I/O statistics of the block device.
+    io_stats: Arc<BlockIoStats>,
+    /// Read/write error policies of the block device.
+    io_error: Arc<IoErrorPolicy>,
+    /// Requests failed with the `stop` action, resubmitted when the VM is resumed.
+    stopped_reqs: Rc<StoppedRequests<Rc<Request>>>,
+    /// Write threshold of the block device.
+    write_threshold: Arc<WriteThreshold>,
+    /// Filters of the guest writes, used by block jobs.
+    write_filters: Arc<WriteFilters>,
+    /// Detector of the requests in flight for longer than `slow-io-warn-ms`.
+    slow_io: Option<SlowIoDetector>,
 }
 
 impl BlockIoHandler {
+    /// Register the disk image and the guest memory to the aio context if the device
+    /// opts in, the requests are issued as the unregistered ones on failure.
+    fn register_aio(&mut self) {
+        if !self.aio_register {
+            return;
+        }
+        if let Some(disk_img) = self.disk_image.as_ref() {
+            self.aio.register_files(&[disk_img.as_raw_fd()]);
+        }
+        let bufs: Vec<Iovec> = self
+            .mem_space
+            .ram_ranges()
+            .iter()
+            .filter_map(|range| {
+                self.mem_space
+                    .get_host_address(range.base)
+                    .map(|hva| Iovec {
+                        iov_base: hva,
+                        iov_len: range.size,
+                    })
+            })
+            .collect();
+        self.aio.register_buffers(&bufs);
+    }
+
     fn merge_req_queue(&self, mut req_queue: Vec<Request>) -> Vec<Request> {
         req_queue.sort_by(|a, b| a.out_header.sector.cmp(&b.out_header.sector));
 
@@ -475,18 +656,20 @@ impl BlockIoHandler {
                     }
                 };
             }
+            if let Some(member) = self.throttle_member.as_ref() {
+                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                    if member.throttled(ctx, 1_u64) {
+                        queue.vring.push_back();
+                        break;
+                    }
+                };
+            }
 
             // Init and put valid request into request queue.
             let mut status = VIRTIO_BLK_S_OK;
             let req = Request::new(self, &mut elem, &mut status)?;
             if status != VIRTIO_BLK_S_OK {
-                let aiocompletecb = AioCompleteCb::new(
-                    self.queue.clone(),
-                    self.mem_space.clone(),
-                    Rc::new(req),
-                    self.interrupt_cb.clone(),
-                    self.driver_features,
-                );
+                let aiocompletecb = AioCompleteCb::new(self, Rc::new(req));
                 // unlock queue, because it will be hold below.
                 drop(queue);
                 aiocompletecb.complete_request(status)?;
@@ -506,38 +689,59 @@ impl BlockIoHandler {
 
         let merge_req_queue = self.merge_req_queue(req_queue);
         for req in merge_req_queue.into_iter() {
-            let req_rc = Rc::new(req);
-            let aiocompletecb = AioCompleteCb::new(
-                self.queue.clone(),
-                self.mem_space.clone(),
-                req_rc.clone(),
-                self.interrupt_cb.clone(),
-                self.driver_features,
-            );
-            if let Some(disk_img) = self.disk_image.as_ref() {
-                let aiocb = AioCb {
-                    direct: self.direct,
-                    req_align: self.req_align,
-                    buf_align: self.buf_align,
-                    file_fd: disk_img.as_raw_fd(),
-                    opcode: OpCode::Noop,
-                    iovec: Vec::new(),
-                    offset: (req_rc.out_header.sector << SECTOR_SHIFT) as usize,
-                    nbytes: 0,
-                    user_data: 0,
-                    iocompletecb: aiocompletecb,
-                };
-                req_rc.execute(self, aiocb)?;
-            } else {
-                warn!("Failed to execute block request, disk_img not specified");
-                aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR)?;
-            }
+            self.submit_request(Rc::new(req))?;
         }
-        self.aio.flush_request()?;
+        self.aio.submit_pending()?;
 
         Ok(done)
     }
 
+    fn submit_request(&mut self, req_rc: Rc<Request>) -> Result<()> {
+        let aiocompletecb = AioCompleteCb::new(self, req_rc.clone());
+        if let Some(disk_img) = self.disk_image.as_ref() {
+            let aiocb = AioCb {
+                direct: self.direct,
+                req_align: self.req_align,
+                buf_align: self.buf_align,
+                file_fd: disk_img.as_raw_fd(),
+                opcode: OpCode::Noop,
+                iovec: Vec::new(),
+                offset: (req_rc.out_header.sector << SECTOR_SHIFT) as usize,
+                nbytes: 0,
+                user_data: 0,
+                iocompletecb: aiocompletecb,
+                submit_time: None,
+                backing: self.backing.clone(),
+            };
+            req_rc.execute(self, aiocb)
+        } else {
+            warn!("Failed to execute block request, disk_img not specified");
+            req_rc.record_error(
+                &self.error_stats,
+                ErrorCategory::BackendEio,
+                libc::ENOMEDIUM,
+            );
+            aiocompletecb.complete_request(VIRTIO_BLK_S_IOERR)
+        }
+    }
+
+    /// Resubmit the requests stopped by the error policy. Return false if the VM has not
+    /// been resumed yet, and new requests should not be processed either.
+    fn retry_stopped_requests(&mut self) -> Result<bool> {
+        let reqs = match self.stopped_reqs.take() {
+            Some(reqs) => reqs,
+            None => return Ok(false),
+        };
+        if reqs.is_empty() {
+            return Ok(true);
+        }
+        for req in reqs {
+            self.submit_request(req)?;
+        }
+        self.aio.submit_pending()?;
+        Ok(true)
+    }
+
     fn process_queue_suppress_notify(&mut self) -> Result<bool> {
         let mut done = false;
         let start_time = Instant::now();
@@ -546,6 +750,9 @@ impl BlockIoHandler {
             done = true;
             return Ok(done);
         }
+        if !self.retry_stopped_requests()? {
+            return Ok(done);
+        }
         while self
             .queue
             .lock()
@@ -584,6 +791,13 @@ impl BlockIoHandler {
                     }
                 }
             }
+            if let Some(member) = self.throttle_member.as_ref() {
+                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                    if member.throttled(ctx, 0) {
+                        break;
+                    }
+                }
+            }
         }
         Ok(done)
     }
@@ -602,24 +816,46 @@ impl BlockIoHandler {
     }
 
     fn complete_func(aiocb: &AioCb<AioCompleteCb>, ret: i64) -> Result<()> {
-        let mut status = if ret < 0 {
-            VIRTIO_BLK_S_IOERR
-        } else {
-            VIRTIO_BLK_S_OK
-        };
+        let mut errno = if ret < 0 { -ret as i32 } else { 0 };
 
         let complete_cb = &aiocb.iocompletecb;
+        complete_cb.io_stats.account_aio(aiocb, ret);
         // When driver does not accept FLUSH feature, the device must be of
         // writethrough cache type, so flush data before updating used ring.
         if !virtio_has_feature(complete_cb.driver_features, VIRTIO_BLK_F_FLUSH)
             && aiocb.opcode == OpCode::Pwritev
             && ret >= 0
-            && raw_datasync(aiocb.file_fd) < 0
         {
-            error!("Failed to flush data before send response to guest.");
-            status = VIRTIO_BLK_S_IOERR;
+            let sync_ret = raw_datasync(aiocb.file_fd);
+            if sync_ret < 0 {
+                error!("Failed to flush data before send response to guest.");
+                errno = -sync_ret as i32;
+            }
         }
 
+        let status = if errno != 0 {
+            let request_type = complete_cb.req.out_header.request_type;
+            complete_cb.error_stats.record(
+                ErrorCategory::from_errno(errno),
+                errno,
+                request_type,
+                aiocb.offset as u64,
+                aiocb.nbytes,
+            );
+            let is_write = request_type != VIRTIO_BLK_T_IN;
+            match complete_cb.io_error.handle_error(is_write, errno) {
+                ErrorAction::Report | ErrorAction::ReadOnly => VIRTIO_BLK_S_IOERR,
+                ErrorAction::Ignore => VIRTIO_BLK_S_OK,
+                ErrorAction::Stop => {
+                    // Keep the request and resubmit it when the VM is resumed.
+                    complete_cb.stopped_reqs.push(complete_cb.req.clone());
+                    return Ok(());
+                }
+            }
+        } else {
+            VIRTIO_BLK_S_OK
+        };
+
         complete_cb.complete_request(status)
     }
 
@@ -637,19 +873,34 @@ impl BlockIoHandler {
     fn update_evt_handler(&mut self) {
         let aio_engine;
         match self.receiver.recv() {
-            Ok((image, req_align, buf_align, disk_sectors, serial_num, direct, aio)) => {
+            Ok((
+                image,
+                backing,
+                req_align,
+                buf_align,
+                disk_sectors,
+                serial_num,
+                direct,
+                read_only,
+                aio,
+                io_error,
+            )) => {
                 self.disk_sectors = disk_sectors;
                 self.disk_image = image;
+                self.backing = backing;
                 self.req_align = req_align;
                 self.buf_align = buf_align;
                 self.serial_num = serial_num;
                 self.direct = direct;
+                self.read_only = read_only;
                 aio_engine = aio;
+                self.io_error = io_error;
             }
             Err(e) => {
                 error!("Failed to receive config in updating handler {:?}", e);
                 self.disk_sectors = 0;
                 self.disk_image = None;
+                self.backing = None;
                 self.req_align = 1;
                 self.buf_align = 1;
                 self.serial_num = None;
@@ -674,20 +925,10 @@ impl BlockIoHandler {
                 }
             }
         }
+        // The image may be changed, the registered one is replaced.
+        self.register_aio();
 
-        if let Err(e) = (self.interrupt_cb)(&VirtioInterruptType::Config, None, false) {
-            error!(
-                "{:?}. {:?}",
-                VirtioError::InterruptTrigger("block", VirtioInterruptType::Config),
-                e
-            );
-            report_virtio_error(
-                self.interrupt_cb.clone(),
-                self.driver_features,
-                &self.device_broken,
-            );
-            return;
-        }
+        self.config_updater.notify();
 
         if let Err(ref e) = self.process_queue() {
             error!("Failed to handle block IO for updating handler {:?}", e);
@@ -695,6 +936,38 @@ impl BlockIoHandler {
     }
 }
 
+/// Scan the requests in flight of the io handler for the slow ones periodically, until
+/// the handler is dropped when the device is deactivated.
+fn slow_io_timer(handler: Weak<Mutex<BlockIoHandler>>, iothread: Option<String>) {
+    let interval = match handler.upgrade() {
+        Some(h) => match h.lock().unwrap().slow_io.as_ref() {
+            Some(slow_io) => slow_io.scan_interval(),
+            None => return,
+        },
+        None => return,
+    };
+    let cloned_iothread = iothread.clone();
+    let func = Box::new(move || {
+        let h = match handler.upgrade() {
+            Some(h) => h,
+            None => return,
+        };
+        let mut h_lock = h.lock().unwrap();
+        if h_lock.device_broken.load(Ordering::SeqCst) {
+            return;
+        }
+        let locked_handler = &mut *h_lock;
+        if let Some(slow_io) = locked_handler.slow_io.as_mut() {
+            slow_io.check(&mut *locked_handler.aio);
+        }
+        drop(h_lock);
+        slow_io_timer(handler.clone(), cloned_iothread.clone());
+    });
+    if let Some(ctx) = EventLoop::get_ctx(iothread.as_ref()) {
+        ctx.delay_call(func, interval.as_nanos() as u64);
+    }
+}
+
 fn build_event_notifier(
     fd: RawFd,
     handlers: Vec<Rc<NotifierCallback>>,
@@ -792,6 +1065,23 @@ impl EventNotifierHelper for BlockIoHandler {
             notifiers.push(build_event_notifier(lb.as_raw_fd(), vec![h], None));
         }
 
+        // Register wakeup event notifier of the throttle group
+        if let Some(member) = handler_raw.throttle_member.as_ref() {
+            let h_clone = handler.clone();
+            let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                let mut h_lock = h_clone.lock().unwrap();
+                if h_lock.device_broken.load(Ordering::SeqCst) {
+                    return None;
+                }
+                if let Err(ref e) = h_lock.process_queue() {
+                    error!("Failed to handle block IO {:?}", e);
+                }
+                None
+            });
+            notifiers.push(build_event_notifier(member.as_raw_fd(), vec![h], None));
+        }
+
         // Register event notifier for aio.
         let h_clone = handler.clone();
         let h: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
@@ -914,6 +1204,8 @@ pub struct Block {
     blk_cfg: BlkDevConfig,
     /// Image file opened.
     disk_image: Option<Arc<File>>,
+    /// Backing image of the image file.
+    backing: Option<Arc<BackingImage>>,
     /// The align requirement of request(offset/len).
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
@@ -934,6 +1226,20 @@ pub struct Block {
     broken: Arc<AtomicBool>,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// Error statistics shared with the io handlers.
+    error_stats: Arc<DeviceErrorStats>,
+    /// I/O statistics shared with the io handlers.
+    io_stats: Arc<BlockIoStats>,
+    /// Write threshold shared with the io handlers.
+    write_threshold: Arc<WriteThreshold>,
+    /// Write filters shared with the io handlers.
+    write_filters: Arc<WriteFilters>,
+    /// Write protection set by the `read-only` error policy.
+    write_protect: Arc<WriteProtect>,
+    /// The machine to be paused by the `stop` error policy.
+    vm: Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>>,
+    /// Helper to change the config space.
+    config_updater: Arc<ConfigUpdater>,
 }
 
 impl Block {
@@ -941,9 +1247,14 @@ impl Block {
         blk_cfg: BlkDevConfig,
         drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
     ) -> Block {
+        let config_updater = ConfigUpdater::new(&blk_cfg.id);
+        let write_threshold = Arc::new(WriteThreshold::new(&blk_cfg.id));
+        let write_filters = Arc::new(WriteFilters::new(&blk_cfg.id, &blk_cfg.path_on_host));
+        let write_protect = Arc::new(WriteProtect::new(&blk_cfg.id));
         Self {
             blk_cfg,
             disk_image: None,
+            backing: None,
             req_align: 1,
             buf_align: 1,
             disk_sectors: 0,
@@ -954,16 +1265,146 @@ impl Block {
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
             drive_files,
+            error_stats: Arc::new(DeviceErrorStats::default()),
+            io_stats: Arc::new(BlockIoStats::default()),
+            write_threshold,
+            write_filters,
+            write_protect,
+            vm: None,
+            config_updater,
         }
     }
 
+    /// Set the machine to be paused when a request fails with the `stop` error policy.
+    pub fn set_vm(&mut self, vm: Weak<Mutex<dyn MachineLifecycle + Send + Sync>>) {
+        self.vm = Some(vm);
+    }
+
+    /// Detach the device from its drive backend file, so that the drive can be deleted.
+    fn detach_drive_file(&self) {
+        if self.blk_cfg.path_on_host.is_empty() {
+            return;
+        }
+        let mut drive_files = self.drive_files.lock().unwrap();
+        VmConfig::detach_drive_file(
+            &mut drive_files,
+            &self.blk_cfg.path_on_host,
+            &self.blk_cfg.id,
+        );
+    }
+
+    fn io_error_policy(&self) -> Arc<IoErrorPolicy> {
+        Arc::new(IoErrorPolicy::new(
+            &self.blk_cfg.id,
+            self.blk_cfg.rerror,
+            self.blk_cfg.werror,
+            self.vm.clone(),
+            self.write_protect.clone(),
+        ))
+    }
+
     fn build_device_config_space(&mut self) {
+        self.state.config_space = VirtioBlkConfig::default();
         // capacity: 64bits
         let num_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
         self.state.config_space.capacity = num_sectors;
         // seg_max = queue_size - 2: 32bits
         self.state.config_space.seg_max = self.queue_size() as u32 - 2;
     }
+
+    /// Advertise the sector sizes and discard capability of the host block device.
+    fn build_topology_config_space(&mut self, file: &File) -> Result<()> {
+        let (logical, physical) = get_block_device_sector_size(file)?;
+        self.state.device_features |= 1_u64 << VIRTIO_BLK_F_BLK_SIZE;
+        self.state.device_features |= 1_u64 << VIRTIO_BLK_F_TOPOLOGY;
+        let config = &mut self.state.config_space;
+        config.blk_size = logical;
+        config.physical_block_exp = (physical / logical).trailing_zeros() as u8;
+        config.min_io_size = 1;
+
+        let max_discard = get_block_device_max_discard(file);
+        if max_discard == 0 || self.blk_cfg.read_only {
+            return Ok(());
+        }
+        let alignment = logical >> SECTOR_SHIFT;
+        let max_discard_sectors = cmp::min(max_discard >> SECTOR_SHIFT, u32::MAX as u64) as u32;
+        self.state.device_features |= 1_u64 << VIRTIO_BLK_F_DISCARD;
+        let config = &mut self.state.config_space;
+        config.max_discard_sectors = max_discard_sectors - max_discard_sectors % alignment;
+        config.max_discard_seg = 1;
+        config.discard_sector_alignment = alignment;
+        Ok(())
+    }
+
+    /// Send the image and config to the io handlers, which notify the guest of the config
+    /// change after switching to them.
+    fn update_handlers(&self) -> Result<()> {
+        for sender in &self.senders {
+            sender
+                .send((
+                    self.disk_image.clone(),
+                    self.backing.clone(),
+                    self.req_align,
+                    self.buf_align,
+                    self.disk_sectors,
+                    self.blk_cfg.serial_num.clone(),
+                    self.blk_cfg.direct,
+                    self.blk_cfg.read_only,
+                    self.blk_cfg.aio,
+                    self.io_error_policy(),
+                ))
+                .with_context(|| anyhow!(VirtioError::ChannelSend("image fd".to_string())))?;
+        }
+        for update_evt in &self.update_evts {
+            update_evt
+                .write(1)
+                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn id(&self) -> &str {
+        &self.blk_cfg.id
+    }
+
+    /// Resize the disk to `size` bytes, for `block_resize`.
+    pub fn resize(&mut self, size: u64, allow_shrink: bool) -> Result<()> {
+        let file = self
+            .disk_image
+            .clone()
+            .with_context(|| format!("Block {} has no medium", self.blk_cfg.id))?;
+        if self.backing.is_some() {
+            bail!(
+                "Block {} is an overlay of backing image, it can't be resized",
+                self.blk_cfg.id
+            );
+        }
+        resize_image(
+            &file,
+            self.disk_sectors << SECTOR_SHIFT,
+            size,
+            SECTOR_SIZE,
+            self.blk_cfg.read_only,
+            allow_shrink,
+        )?;
+
+        let config_updater = self.config_updater.clone();
+        config_updater.write(|| {
+            self.disk_sectors = size >> SECTOR_SHIFT;
+            self.state.config_space.capacity = self.disk_sectors;
+        });
+        self.update_handlers()
+    }
+
+    /// Get the length of the config space which is visible to guest.
+    fn config_space_len(&self) -> u64 {
+        if virtio_has_feature(self.state.device_features, VIRTIO_BLK_F_DISCARD) {
+            offset_of!(VirtioBlkConfig, max_write_zeroes_sectors) as u64
+        } else {
+            offset_of!(VirtioBlkConfig, max_discard_sectors) as u64
+        }
+    }
 }
 
 impl VirtioDevice for Block {
@@ -995,16 +1436,31 @@ impl VirtioDevice for Block {
         }
 
         self.disk_image = None;
+        self.backing = None;
         self.disk_sectors = DUMMY_IMG_SIZE >> SECTOR_SHIFT;
         self.req_align = 1;
         self.buf_align = 1;
         if !self.blk_cfg.path_on_host.is_empty() {
-            let drive_files = self.drive_files.lock().unwrap();
-            let mut file = VmConfig::fetch_drive_file(&drive_files, &self.blk_cfg.path_on_host)?;
+            let mut drive_files = self.drive_files.lock().unwrap();
+            let file = VmConfig::fetch_drive_file(&drive_files, &self.blk_cfg.path_on_host)?;
             let alignments = VmConfig::fetch_drive_align(&drive_files, &self.blk_cfg.path_on_host)?;
-            let disk_size = file
-                .seek(SeekFrom::End(0))
-                .with_context(|| "Failed to seek the end for block")?;
+            self.backing = VmConfig::fetch_drive_backing(&drive_files, &self.blk_cfg.path_on_host)?;
+            VmConfig::attach_drive_file(
+                &mut drive_files,
+                &self.blk_cfg.path_on_host,
+                &self.blk_cfg.id,
+            )?;
+            drop(drive_files);
+            let disk_size =
+                get_file_size(&file).with_context(|| "Failed to get the size for block")?;
+            if is_block_device(&file) {
+                self.build_topology_config_space(&file)?;
+            } else if self.blk_cfg.direct && alignments.0 as u64 > SECTOR_SIZE {
+                // Let the guest issue requests aligned to the block size of the host file
+                // system, the misaligned ones need read-modify-write by bounce buffer.
+                self.state.device_features |= 1_u64 << VIRTIO_BLK_F_BLK_SIZE;
+                self.state.config_space.blk_size = alignments.0;
+            }
 
             self.disk_image = Some(Arc::new(file));
             self.disk_sectors = disk_size >> SECTOR_SHIFT;
@@ -1012,12 +1468,29 @@ impl VirtioDevice for Block {
             self.buf_align = alignments.1;
         }
         self.state.config_space.capacity = self.disk_sectors;
+        register_block_error_stats(
+            &self.blk_cfg.id,
+            &self.blk_cfg.path_on_host,
+            self.blk_cfg.read_only,
+            self.error_stats.clone(),
+        );
+        register_block_io_stats(&self.blk_cfg.id, self.io_stats.clone());
+        register_write_threshold(self.write_threshold.clone());
+        self.write_filters.set_path(&self.blk_cfg.path_on_host);
+        register_write_filters(self.write_filters.clone());
+        register_write_protect(self.write_protect.clone());
 
         Ok(())
     }
 
     fn unrealize(&mut self) -> Result<()> {
+        self.detach_drive_file();
         MigrationManager::unregister_device_instance(BlockState::descriptor(), &self.blk_cfg.id);
+        unregister_block_error_stats(&self.blk_cfg.id);
+        unregister_block_io_stats(&self.blk_cfg.id);
+        unregister_write_threshold(&self.blk_cfg.id);
+        unregister_write_filters(&self.blk_cfg.id);
+        unregister_write_protect(&self.blk_cfg.id);
         Ok(())
     }
 
@@ -1053,8 +1526,7 @@ impl VirtioDevice for Block {
 
     /// Read data of config from guest.
     fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
-        // F_DISCARD is not supported for now, so related config does not exist.
-        let config_len = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let config_len = self.config_space_len();
         let read_end = offset as usize + data.len();
         if offset
             .checked_add(data.len() as u64)
@@ -1072,8 +1544,7 @@ impl VirtioDevice for Block {
 
     /// Write data to config from guest.
     fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()> {
-        // F_DISCARD is not supported for now, so related config does not exist.
-        let config_len = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let config_len = self.config_space_len();
         if offset
             .checked_add(data.len() as u64)
             .filter(|&end| end <= config_len)
@@ -1087,6 +1558,10 @@ impl VirtioDevice for Block {
         Ok(())
     }
 
+    fn config_generation(&self) -> u32 {
+        self.config_updater.generation()
+    }
+
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     fn activate(
@@ -1097,6 +1572,9 @@ impl VirtioDevice for Block {
         mut queue_evts: Vec<Arc<EventFd>>,
     ) -> Result<()> {
         self.interrupt_cb = Some(interrupt_cb.clone());
+        self.config_updater
+            .set_interrupt_cb(Some(interrupt_cb.clone()));
+        let io_error = self.io_error_policy();
         for queue in queues.iter() {
             let queue_evt = queue_evts.remove(0);
             if !queue.lock().unwrap().is_enabled() {
@@ -1108,30 +1586,50 @@ impl VirtioDevice for Block {
                 Arc::new(BlockIoHandler::complete_func),
                 self.blk_cfg.aio,
             )?);
-            let handler = BlockIoHandler {
+            let stopped_reqs = Rc::new(StoppedRequests::new(queue_evt.clone()));
+            let mut handler = BlockIoHandler {
                 queue: queue.clone(),
                 queue_evt,
                 mem_space: mem_space.clone(),
                 disk_image: self.disk_image.clone(),
+                backing: self.backing.clone(),
                 req_align: self.req_align,
                 buf_align: self.buf_align,
                 disk_sectors: self.disk_sectors,
                 direct: self.blk_cfg.direct,
+                read_only: self.blk_cfg.read_only,
                 serial_num: self.blk_cfg.serial_num.clone(),
                 aio,
+                aio_register: self.blk_cfg.aio_register,
                 driver_features: self.state.driver_features,
                 receiver,
                 update_evt: update_evt.clone(),
                 device_broken: self.broken.clone(),
                 interrupt_cb: interrupt_cb.clone(),
+                config_updater: self.config_updater.clone(),
                 iothread: self.blk_cfg.iothread.clone(),
                 leak_bucket: match self.blk_cfg.iops {
                     Some(iops) => Some(LeakBucket::new(iops)?),
                     None => None,
                 },
+                throttle_member: match self.blk_cfg.throttle_group.as_ref() {
+                    Some(group) => Some(join_throttle_group(group)?),
+                    None => None,
+                },
+                error_stats: self.error_stats.clone(),
+                io_stats: self.io_stats.clone(),
+                io_error: io_error.clone(),
+                stopped_reqs,
+                write_threshold: self.write_threshold.clone(),
+                write_filters: self.write_filters.clone(),
+                slow_io: SlowIoDetector::new(&self.blk_cfg.id, self.blk_cfg.slow_io_warn_ms),
             };
 
-            let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+            handler.register_aio();
+
+            let handler = Arc::new(Mutex::new(handler));
+            slow_io_timer(Arc::downgrade(&handler), self.blk_cfg.iothread.clone());
+            let notifiers = EventNotifierHelper::internal_notifiers(handler);
             register_event_helper(
                 notifiers,
                 self.blk_cfg.iothread.as_ref(),
@@ -1147,12 +1645,14 @@ impl VirtioDevice for Block {
 
     fn deactivate(&mut self) -> Result<()> {
         unregister_event_helper(self.blk_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
+        self.config_updater.set_interrupt_cb(None);
         self.update_evts.clear();
         self.senders.clear();
         Ok(())
     }
 
     fn update_config(&mut self, dev_config: Option<Arc<dyn ConfigCheck>>) -> Result<()> {
+        self.detach_drive_file();
         if let Some(conf) = dev_config {
             self.blk_cfg = conf
                 .as_any()
@@ -1165,28 +1665,10 @@ impl VirtioDevice for Block {
             self.blk_cfg = Default::default();
         }
 
-        self.realize()?;
-
-        for sender in &self.senders {
-            sender
-                .send((
-                    self.disk_image.clone(),
-                    self.req_align,
-                    self.buf_align,
-                    self.disk_sectors,
-                    self.blk_cfg.serial_num.clone(),
-                    self.blk_cfg.direct,
-                    self.blk_cfg.aio,
-                ))
-                .with_context(|| anyhow!(VirtioError::ChannelSend("image fd".to_string())))?;
-        }
-        for update_evt in &self.update_evts {
-            update_evt
-                .write(1)
-                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
-        }
-
-        Ok(())
+        // The guest is notified after the io handlers have switched to the new image.
+        let config_updater = self.config_updater.clone();
+        config_updater.write(|| self.realize())?;
+        self.update_handlers()
     }
 }
 
@@ -1229,6 +1711,7 @@ mod tests {
     use crate::*;
     use address_space::{AddressSpace, GuestAddress, HostMemMapping, Region};
     use machine_manager::config::{IothreadConfig, VmConfig, DEFAULT_VIRTQUEUE_SIZE};
+    use std::os::unix::fs::FileExt;
     use std::sync::atomic::{AtomicU32, Ordering};
     use std::{thread, time::Duration};
     use vmm_sys_util::tempfile::TempFile;
@@ -1244,6 +1727,7 @@ mod tests {
             Block {
                 blk_cfg: Default::default(),
                 disk_image: None,
+                backing: None,
                 req_align: 1,
                 buf_align: 1,
                 disk_sectors: 0,
@@ -1254,6 +1738,13 @@ mod tests {
                 deactivate_evts: Vec::new(),
                 broken: Arc::new(AtomicBool::new(false)),
                 drive_files: Arc::new(Mutex::new(HashMap::new())),
+                error_stats: Arc::new(DeviceErrorStats::default()),
+                io_stats: Arc::new(BlockIoStats::default()),
+                write_threshold: Arc::new(WriteThreshold::new("block")),
+                write_filters: Arc::new(WriteFilters::new("block", "")),
+                write_protect: Arc::new(WriteProtect::new("block")),
+                vm: None,
+                config_updater: ConfigUpdater::new("block"),
             }
         }
     }
@@ -1284,6 +1775,22 @@ mod tests {
         sys_space
     }
 
+    fn queue_config_init(mem_space: &Arc<AddressSpace>) -> QueueConfig {
+        let mut queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.addr_cache.desc_table_host =
+            mem_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.avail_ring = GuestAddress(16 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.avail_ring_host =
+            mem_space.get_host_address(queue_config.avail_ring).unwrap();
+        queue_config.used_ring = GuestAddress(32 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.used_ring_host =
+            mem_space.get_host_address(queue_config.used_ring).unwrap();
+        queue_config.size = DEFAULT_VIRTQUEUE_SIZE;
+        queue_config.ready = true;
+        queue_config
+    }
+
     // Use different input parameters to verify block `new()` and `realize()` functionality.
     #[test]
     fn test_block_init() {
@@ -1316,6 +1823,81 @@ mod tests {
         assert_eq!(block.queue_size(), DEFAULT_VIRTQUEUE_SIZE);
     }
 
+    // Test the block size of the file system, which direct io must be aligned to, is
+    // reported to the guest.
+    #[test]
+    fn test_block_direct_blk_size() {
+        let mut block = Block::default();
+        block.blk_cfg.direct = false;
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(16 * 4096).unwrap();
+        block.blk_cfg.path_on_host = f.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            block.blk_cfg.read_only,
+            block.blk_cfg.direct,
+        )
+        .unwrap();
+        block.realize().unwrap();
+        assert_eq!(
+            block.state.device_features & (1 << VIRTIO_BLK_F_BLK_SIZE),
+            0
+        );
+
+        // Simulate a file system with 4K block size.
+        block
+            .drive_files
+            .lock()
+            .unwrap()
+            .get_mut(&block.blk_cfg.path_on_host)
+            .unwrap()
+            .req_align = 4096;
+        block.blk_cfg.direct = true;
+        block.realize().unwrap();
+        assert_ne!(
+            block.state.device_features & (1 << VIRTIO_BLK_F_BLK_SIZE),
+            0
+        );
+        assert_eq!({ block.state.config_space.blk_size }, 4096);
+        assert_eq!(block.req_align, 4096);
+    }
+
+    // Test `resize()`: the image is truncated, and the capacity in the config space and the
+    // disk size of the io handlers are updated.
+    #[test]
+    fn test_block_resize() {
+        let mut block = Block::default();
+        block.blk_cfg.direct = false;
+        let f = TempFile::new().unwrap();
+        f.as_file().set_len(16 * SECTOR_SIZE).unwrap();
+        block.blk_cfg.path_on_host = f.as_path().to_str().unwrap().to_string();
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            block.blk_cfg.read_only,
+            block.blk_cfg.direct,
+        )
+        .unwrap();
+        block.realize().unwrap();
+        let (sender, receiver) = channel();
+        block.senders.push(sender);
+
+        block.resize(32 * SECTOR_SIZE, false).unwrap();
+        assert_eq!(f.as_file().metadata().unwrap().len(), 32 * SECTOR_SIZE);
+        let mut capacity = [0_u8; 8];
+        block.read_config(0, &mut capacity).unwrap();
+        assert_eq!(u64::from_le_bytes(capacity), 32);
+        assert_eq!(receiver.try_recv().unwrap().4, 32);
+
+        assert!(block.resize(8 * SECTOR_SIZE, false).is_err());
+        assert!(block.resize(8 * SECTOR_SIZE + 1, true).is_err());
+        block.resize(8 * SECTOR_SIZE, true).unwrap();
+        block.read_config(0, &mut capacity).unwrap();
+        assert_eq!(u64::from_le_bytes(capacity), 8);
+        assert_eq!(receiver.try_recv().unwrap().4, 8);
+    }
+
     // Test `write_config` and `read_config`. The main contests include: compare expect data and
     // read data are not same; Input invalid offset or data length, it will failed.
     #[test]
@@ -1342,6 +1924,23 @@ mod tests {
             .is_err());
     }
 
+    // Test the config space related to discard is only visible when F_DISCARD is offered.
+    #[test]
+    fn test_discard_config() {
+        let mut block = Block::default();
+        block.realize().unwrap();
+        let discard_offset = offset_of!(VirtioBlkConfig, max_discard_sectors) as u64;
+        let mut data = [0u8; 4];
+        assert!(block.read_config(discard_offset, &mut data).is_err());
+
+        block.state.device_features |= 1_u64 << VIRTIO_BLK_F_DISCARD;
+        block.state.config_space.max_discard_sectors = 0x1000;
+        block.read_config(discard_offset, &mut data).unwrap();
+        assert_eq!(u32::from_le_bytes(data), 0x1000);
+        let write_zeroes_offset = offset_of!(VirtioBlkConfig, max_write_zeroes_sectors) as u64;
+        assert!(block.read_config(write_zeroes_offset, &mut data).is_err());
+    }
+
     // Test `get_device_features` and `set_driver_features`. The main contests include: If the
     // device feature is 0, all driver features are not supported; If both the device feature bit
     // and the front-end driver feature bit are supported at the same time,  this driver feature
@@ -1466,19 +2065,7 @@ mod tests {
             },
         ) as VirtioInterrupt);
 
-        let mut queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
-        queue_config.desc_table = GuestAddress(0);
-        queue_config.addr_cache.desc_table_host =
-            mem_space.get_host_address(queue_config.desc_table).unwrap();
-        queue_config.avail_ring = GuestAddress(16 * DEFAULT_VIRTQUEUE_SIZE as u64);
-        queue_config.addr_cache.avail_ring_host =
-            mem_space.get_host_address(queue_config.avail_ring).unwrap();
-        queue_config.used_ring = GuestAddress(32 * DEFAULT_VIRTQUEUE_SIZE as u64);
-        queue_config.addr_cache.used_ring_host =
-            mem_space.get_host_address(queue_config.used_ring).unwrap();
-        queue_config.size = DEFAULT_VIRTQUEUE_SIZE;
-        queue_config.ready = true;
-
+        let queue_config = queue_config_init(&mem_space);
         let queues: Vec<Arc<Mutex<Queue>>> =
             vec![Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap()))];
         let event = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
@@ -1560,4 +2147,138 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_read_only_request() {
+        // The global event loop is shared with other tests, which may need the io thread.
+        let io_conf = IothreadConfig {
+            id: "io1".to_string(),
+        };
+        EventLoop::object_init(&Some(vec![io_conf])).unwrap();
+
+        let mut block = Block::default();
+        let file = TempFile::new().unwrap();
+        file.as_file()
+            .write_all_at(&[0x5a_u8; 16 * SECTOR_SIZE as usize], 0)
+            .unwrap();
+        block.blk_cfg.id = "blk-ro".to_string();
+        block.blk_cfg.path_on_host = file.as_path().to_str().unwrap().to_string();
+        block.blk_cfg.direct = false;
+        block.blk_cfg.iothread = Some("io1".to_string());
+        block.blk_cfg.read_only = true;
+        // The image is opened writable, so the write can only be rejected by the device.
+        VmConfig::add_drive_file(
+            &mut block.drive_files.lock().unwrap(),
+            &block.blk_cfg.path_on_host,
+            false,
+            block.blk_cfg.direct,
+        )
+        .unwrap();
+        block.realize().unwrap();
+
+        let mem_space = address_space_init();
+        let interrupt_cb = Arc::new(Box::new(
+            move |_: &VirtioInterruptType, _: Option<&Queue>, _: bool| Ok(()),
+        ) as VirtioInterrupt);
+        let queue_config = queue_config_init(&mem_space);
+        let queues: Vec<Arc<Mutex<Queue>>> =
+            vec![Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap()))];
+        let event = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        block
+            .activate(
+                mem_space.clone(),
+                interrupt_cb,
+                &queues,
+                vec![event.clone()],
+            )
+            .unwrap();
+
+        // The driver posts a request of one sector at sector 2, returns the guest address of
+        // the status byte.
+        let mut avail_idx = 0_u16;
+        let mut post_request = |request_type: u32, data: &[u8]| {
+            let head_addr = GuestAddress(0x40000 + avail_idx as u64 * 0x1000);
+            let data_addr = head_addr.unchecked_add(0x100);
+            let status_addr = head_addr.unchecked_add(0x800);
+            let req_head = RequestOutHeader {
+                request_type,
+                io_prio: 0,
+                sector: 2,
+            };
+            mem_space.write_object(&req_head, head_addr).unwrap();
+            mem_space
+                .write(&mut &data[..], data_addr, data.len() as u64)
+                .unwrap();
+            mem_space.write_object(&0xff_u8, status_addr).unwrap();
+
+            let data_flags = match request_type {
+                VIRTIO_BLK_T_OUT => VIRTQ_DESC_F_NEXT,
+                _ => VIRTQ_DESC_F_NEXT | VIRTQ_DESC_F_WRITE,
+            };
+            let head = avail_idx * 3;
+            let descs = [
+                (head_addr, size_of::<RequestOutHeader>(), VIRTQ_DESC_F_NEXT),
+                (data_addr, data.len(), data_flags),
+                (status_addr, 1, VIRTQ_DESC_F_WRITE),
+            ];
+            for (i, (addr, len, flags)) in descs.into_iter().enumerate() {
+                let desc = SplitVringDesc {
+                    addr,
+                    len: len as u32,
+                    flags,
+                    next: head + i as u16 + 1,
+                };
+                let desc_addr = queue_config.desc_table.0 + (head as u64 + i as u64) * 16;
+                mem_space
+                    .write_object(&desc, GuestAddress(desc_addr))
+                    .unwrap();
+            }
+            let ring_addr = queue_config.avail_ring.0 + 4 + avail_idx as u64 * 2;
+            mem_space
+                .write_object::<u16>(&head, GuestAddress(ring_addr))
+                .unwrap();
+            avail_idx += 1;
+            mem_space
+                .write_object::<u16>(&avail_idx, GuestAddress(queue_config.avail_ring.0 + 2))
+                .unwrap();
+            event.write(1).unwrap();
+            status_addr
+        };
+        let wait_used = |idx: u16| {
+            for _ in 0..100 {
+                let used_idx = mem_space
+                    .read_object::<u16>(GuestAddress(queue_config.used_ring.0 + 2))
+                    .unwrap();
+                if used_idx == idx {
+                    return;
+                }
+                thread::sleep(Duration::from_millis(20));
+            }
+            panic!("Request {} is not completed", idx);
+        };
+
+        // The write is failed and the image is left untouched.
+        let status_addr = post_request(VIRTIO_BLK_T_OUT, &[0xa5_u8; SECTOR_SIZE as usize]);
+        wait_used(1);
+        assert_eq!(
+            mem_space.read_object::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_IOERR
+        );
+        let mut data = [0_u8; SECTOR_SIZE as usize];
+        file.as_file()
+            .read_exact_at(&mut data, 2 * SECTOR_SIZE)
+            .unwrap();
+        assert_eq!(data, [0x5a_u8; SECTOR_SIZE as usize]);
+
+        // Reads are still allowed.
+        let status_addr = post_request(VIRTIO_BLK_T_IN, &[0_u8; SECTOR_SIZE as usize]);
+        wait_used(2);
+        assert_eq!(
+            mem_space.read_object::<u8>(status_addr).unwrap(),
+            VIRTIO_BLK_S_OK
+        );
+
+        block.deactivate().unwrap();
+        block.unrealize().unwrap();
+    }
 }
@@ -17,16 +17,18 @@ use crate::{
     VIRTIO_GPU_CMD_GET_EDID, VIRTIO_GPU_CMD_MOVE_CURSOR, VIRTIO_GPU_CMD_RESOURCE_ATTACH_BACKING,
     VIRTIO_GPU_CMD_RESOURCE_CREATE_2D, VIRTIO_GPU_CMD_RESOURCE_DETACH_BACKING,
     VIRTIO_GPU_CMD_RESOURCE_FLUSH, VIRTIO_GPU_CMD_RESOURCE_UNREF, VIRTIO_GPU_CMD_SET_SCANOUT,
-    VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, VIRTIO_GPU_CMD_UPDATE_CURSOR, VIRTIO_GPU_FLAG_FENCE,
-    VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER, VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID,
-    VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID, VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY,
-    VIRTIO_GPU_RESP_ERR_UNSPEC, VIRTIO_GPU_RESP_OK_DISPLAY_INFO, VIRTIO_GPU_RESP_OK_EDID,
-    VIRTIO_GPU_RESP_OK_NODATA, VIRTIO_TYPE_GPU,
+    VIRTIO_GPU_CMD_TRANSFER_TO_HOST_2D, VIRTIO_GPU_CMD_UPDATE_CURSOR, VIRTIO_GPU_EVENT_DISPLAY,
+    VIRTIO_GPU_FLAG_FENCE, VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER,
+    VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID, VIRTIO_GPU_RESP_ERR_INVALID_SCANOUT_ID,
+    VIRTIO_GPU_RESP_ERR_OUT_OF_MEMORY, VIRTIO_GPU_RESP_ERR_UNSPEC, VIRTIO_GPU_RESP_OK_DISPLAY_INFO,
+    VIRTIO_GPU_RESP_OK_EDID, VIRTIO_GPU_RESP_OK_NODATA, VIRTIO_TYPE_GPU,
 };
 use address_space::{AddressSpace, GuestAddress};
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use log::{error, warn};
-use machine_manager::config::{GpuDevConfig, DEFAULT_VIRTQUEUE_SIZE, VIRTIO_GPU_MAX_SCANOUTS};
+use machine_manager::config::{
+    GpuDevConfig, DEFAULT_VIRTQUEUE_SIZE, VIRTIO_GPU_MAX_SCANOUTS, VIRTIO_GPU_MIN_RESOLUTION,
+};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use migration::{DeviceStateDesc, FieldDesc, MigrationManager};
 use migration_derive::{ByteCode, Desc};
@@ -59,6 +61,8 @@ use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 
 // number of virtqueues
 const QUEUE_NUM_GPU: usize = 2;
+/// Size of the EDID blob: the base block and a CEA extension block.
+pub const VIRTIO_GPU_EDID_SIZE: usize = 256;
 
 #[derive(Debug)]
 struct GpuResource {
@@ -245,9 +249,60 @@ struct VirtioGpuResourceDetachBacking {
 
 impl ByteCode for VirtioGpuResourceDetachBacking {}
 
+/// Display modes of the scanouts requested by the UI, shared by the device,
+/// its consoles and the io handler.
 #[derive(Default)]
-pub struct GpuOpts {}
-impl HardWareOperations for GpuOpts {}
+struct GpuOutputs {
+    /// The number of scanouts.
+    num_scanouts: u32,
+    /// States of all request in scanout.
+    req_states: [VirtioGpuReqState; VIRTIO_GPU_MAX_SCANOUTS],
+    /// Pending events, reported in `events_read` of the config space.
+    events_read: u32,
+    /// Callback to trigger the config change interrupt, set when the device is activated.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+}
+
+/// Handles the requests from the UI to the console of a scanout.
+pub struct GpuOpts {
+    outputs: Arc<Mutex<GpuOutputs>>,
+    scanout_id: usize,
+}
+
+impl HardWareOperations for GpuOpts {
+    fn hw_ui_info(&self, _con: Arc<Mutex<DisplayConsole>>, width: u32, height: u32) -> Result<()> {
+        let mut locked_outputs = self.outputs.lock().unwrap();
+        if self.scanout_id >= locked_outputs.num_scanouts as usize {
+            bail!("The scanout {} of gpu is not enabled", self.scanout_id);
+        }
+        if width < VIRTIO_GPU_MIN_RESOLUTION || height < VIRTIO_GPU_MIN_RESOLUTION {
+            bail!("The display size {}x{} is too small for gpu", width, height);
+        }
+
+        let req_state = &mut locked_outputs.req_states[self.scanout_id];
+        if req_state.width == width && req_state.height == height {
+            return Ok(());
+        }
+        req_state.width = width;
+        req_state.height = height;
+        // The guest reads the new mode by GET_DISPLAY_INFO and GET_EDID, and is free
+        // to keep its current mode. The console always follows the scanout set by guest.
+        locked_outputs.events_read |= VIRTIO_GPU_EVENT_DISPLAY;
+        if let Some(interrupt_cb) = &locked_outputs.interrupt_cb {
+            interrupt_cb(&VirtioInterruptType::Config, None, false).with_context(|| {
+                VirtioError::InterruptTrigger("gpu", VirtioInterruptType::Config)
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Build the EDID blob which advertises `width` x `height` as the preferred mode.
+pub fn gpu_build_edid(width: u32, height: u32) -> Vec<u8> {
+    let mut edid = vec![0_u8; VIRTIO_GPU_EDID_SIZE];
+    EdidInfo::new("HWV", "STRA Monitor", 100, width, height).edid_array_fulfill(&mut edid);
+    edid
+}
 
 #[allow(unused)]
 #[derive(Default, Clone)]
@@ -397,8 +452,8 @@ struct GpuIoHandler {
     enable_output_bitmask: u32,
     /// The number of scanouts
     num_scanouts: u32,
-    /// States of all request in scanout.
-    req_states: [VirtioGpuReqState; VIRTIO_GPU_MAX_SCANOUTS],
+    /// Display modes requested by the UI.
+    outputs: Arc<Mutex<GpuOutputs>>,
     /// Scanouts of gpu, mouse doesn't realize copy trait, so it is a vector.
     scanouts: Vec<GpuScanout>,
     /// Max host mem for resource.
@@ -653,15 +708,17 @@ impl GpuIoHandler {
     fn cmd_get_display_info(&mut self, req: &VirtioGpuRequest) -> Result<()> {
         let mut display_info = VirtioGpuDisplayInfo::default();
         display_info.header.hdr_type = VIRTIO_GPU_RESP_OK_DISPLAY_INFO;
+        let locked_outputs = self.outputs.lock().unwrap();
         for i in 0..self.num_scanouts {
             if (self.enable_output_bitmask & (1 << i)) != 0 {
                 let i = i as usize;
                 display_info.pmodes[i].enabled = 1;
-                display_info.pmodes[i].rect.width = self.req_states[i].width;
-                display_info.pmodes[i].rect.height = self.req_states[i].height;
+                display_info.pmodes[i].rect.width = locked_outputs.req_states[i].width;
+                display_info.pmodes[i].rect.height = locked_outputs.req_states[i].height;
                 display_info.pmodes[i].flags = 0;
             }
         }
+        drop(locked_outputs);
 
         if (req.header.flags & VIRTIO_GPU_FLAG_FENCE) != 0 {
             display_info.header.flags |= VIRTIO_GPU_FLAG_FENCE;
@@ -691,15 +748,10 @@ impl GpuIoHandler {
             edid_resp.header.ctx_id = req.header.ctx_id;
         }
 
-        let mut edid_info = EdidInfo::new(
-            "HWV",
-            "STRA Monitor",
-            100,
-            self.req_states[edid_req.scanouts as usize].width,
-            self.req_states[edid_req.scanouts as usize].height,
-        );
-        edid_info.edid_array_fulfill(&mut edid_resp.edid.to_vec());
-        edid_resp.size = edid_resp.edid.len() as u32;
+        let req_state = self.outputs.lock().unwrap().req_states[edid_req.scanouts as usize];
+        let edid = gpu_build_edid(req_state.width, req_state.height);
+        edid_resp.edid[..edid.len()].copy_from_slice(&edid);
+        edid_resp.size = edid.len() as u32;
 
         self.send_response(req, &edid_resp)?;
 
@@ -1424,11 +1476,8 @@ impl GpuIoHandler {
 
 impl Drop for GpuIoHandler {
     fn drop(&mut self) {
-        for scanout in &self.scanouts {
-            console_close(&scanout.con)
-                .unwrap_or_else(|e| error!("Error occurs during console closing:{:?}", e));
-        }
-
+        // Consoles belong to the device, destroying the resources switches them back
+        // to the placeholder surface.
         while !self.resources_list.is_empty() {
             self.resource_destroy(0);
             self.resources_list.remove(0);
@@ -1507,18 +1556,26 @@ pub struct Gpu {
     cfg: GpuDevConfig,
     /// Status of the GPU device.
     state: GpuState,
-    /// Callback to trigger interrupt.
-    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// Display modes requested by the UI and pending events.
+    outputs: Arc<Mutex<GpuOutputs>>,
+    /// Consoles of the scanouts, they live as long as the device is realized.
+    consoles: Vec<Option<Weak<Mutex<DisplayConsole>>>>,
     /// Eventfd for device deactivate.
     deactivate_evts: Vec<RawFd>,
 }
 
+// SAFETY: The consoles are only created when the device is realized, handed to the io
+// handler when it is activated and closed when it is unrealized, all with the device
+// locked. So implement Send is safe.
+unsafe impl Send for Gpu {}
+
 impl Gpu {
     pub fn new(cfg: GpuDevConfig) -> Gpu {
         Self {
             cfg,
             state: GpuState::default(),
-            interrupt_cb: None,
+            outputs: Arc::new(Mutex::new(GpuOutputs::default())),
+            consoles: Vec::new(),
             deactivate_evts: Vec::new(),
         }
     }
@@ -1549,11 +1606,39 @@ impl VirtioDevice for Gpu {
 
         self.build_device_config_space();
 
+        let mut locked_outputs = self.outputs.lock().unwrap();
+        locked_outputs.num_scanouts = self.cfg.max_outputs;
+        locked_outputs.req_states[0].width = self.cfg.xres;
+        locked_outputs.req_states[0].height = self.cfg.yres;
+        drop(locked_outputs);
+
+        for i in 0..VIRTIO_GPU_MAX_SCANOUTS {
+            let gpu_opts = Arc::new(GpuOpts {
+                outputs: self.outputs.clone(),
+                scanout_id: i,
+            });
+            let con = console_init(gpu_opts);
+            if i == 0 {
+                // Show the placeholder in the preferred size before the guest driver
+                // sets the scanout, so the UI needn't resize again.
+                if let Some(c) = con.as_ref().and_then(|c| c.upgrade()) {
+                    let mut locked_con = c.lock().unwrap();
+                    locked_con.width = self.cfg.xres as i32;
+                    locked_con.height = self.cfg.yres as i32;
+                }
+                display_replace_surface(&con, None)?;
+            }
+            self.consoles.push(con);
+        }
+
         Ok(())
     }
 
     /// Unrealize low level device.
     fn unrealize(&mut self) -> Result<()> {
+        for con in self.consoles.drain(..) {
+            console_close(&con)?;
+        }
         MigrationManager::unregister_device_instance(GpuState::descriptor(), &self.cfg.id);
         Ok(())
     }
@@ -1590,7 +1675,9 @@ impl VirtioDevice for Gpu {
 
     /// Read data of config from guest.
     fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
-        let config_slice = self.state.config_space.as_bytes();
+        let mut config_space = self.state.config_space;
+        config_space.events_read = self.outputs.lock().unwrap().events_read;
+        let config_slice = config_space.as_bytes();
         let config_len = config_slice.len() as u64;
 
         if offset
@@ -1622,8 +1709,8 @@ impl VirtioDevice for Gpu {
         }
 
         config_cpy_slice[(offset as usize)..(offset as usize + data.len())].copy_from_slice(data);
-        if self.state.config_space.events_clear != 0 {
-            self.state.config_space.events_read &= !config_cpy.events_clear;
+        if config_cpy.events_clear != 0 {
+            self.outputs.lock().unwrap().events_read &= !config_cpy.events_clear;
         }
 
         Ok(())
@@ -1645,13 +1732,13 @@ impl VirtioDevice for Gpu {
             )));
         }
 
-        self.interrupt_cb = Some(interrupt_cb.clone());
-        let req_states = [VirtioGpuReqState::default(); VIRTIO_GPU_MAX_SCANOUTS];
+        self.outputs.lock().unwrap().interrupt_cb = Some(interrupt_cb.clone());
         let mut scanouts = vec![];
-        for _i in 0..VIRTIO_GPU_MAX_SCANOUTS {
-            let mut scanout = GpuScanout::default();
-            let gpu_opts = Arc::new(GpuOpts::default());
-            scanout.con = console_init(gpu_opts);
+        for con in self.consoles.iter() {
+            let scanout = GpuScanout {
+                con: con.clone(),
+                ..Default::default()
+            };
             scanouts.push(scanout);
         }
 
@@ -1666,14 +1753,11 @@ impl VirtioDevice for Gpu {
             resources_list: Vec::new(),
             enable_output_bitmask: 1,
             num_scanouts: self.cfg.max_outputs,
-            req_states,
+            outputs: self.outputs.clone(),
             scanouts,
             max_hostmem: self.cfg.max_hostmem,
             used_hostmem: 0,
         };
-        handler.req_states[0].width = self.cfg.xres;
-        handler.req_states[0].height = self.cfg.yres;
-
         let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
         register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
 
@@ -1681,6 +1765,10 @@ impl VirtioDevice for Gpu {
     }
 
     fn deactivate(&mut self) -> Result<()> {
+        let mut locked_outputs = self.outputs.lock().unwrap();
+        locked_outputs.interrupt_cb = None;
+        locked_outputs.events_read = 0;
+        drop(locked_outputs);
         unregister_event_helper(None, &mut self.deactivate_evts)
     }
 }
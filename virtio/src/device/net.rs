@@ -22,31 +22,35 @@ use std::sync::{Arc, Mutex};
 use std::{cmp, fs, mem};
 
 use crate::{
-    iov_discard_front, iov_to_buf, mem_to_buf, report_virtio_error, virtio_has_feature, ElemIovec,
-    Element, VirtioError,
+    iov_discard_front, iov_to_buf, mem_to_buf, register_net_error_stats, report_virtio_error,
+    unregister_net_error_stats, virtio_has_feature, ConfigUpdater, DeviceErrorStats, ElemIovec,
+    Element, ErrorCategory, VirtioError,
 };
 use crate::{
     Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioNetHdr, VirtioTrace,
-    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MAC,
+    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1,
+    VIRTIO_NET_CTRL_GUEST_OFFLOADS, VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET, VIRTIO_NET_CTRL_MAC,
     VIRTIO_NET_CTRL_MAC_ADDR_SET, VIRTIO_NET_CTRL_MAC_TABLE_SET, VIRTIO_NET_CTRL_MQ,
     VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
     VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET, VIRTIO_NET_CTRL_RX, VIRTIO_NET_CTRL_RX_ALLMULTI,
     VIRTIO_NET_CTRL_RX_ALLUNI, VIRTIO_NET_CTRL_RX_NOBCAST, VIRTIO_NET_CTRL_RX_NOMULTI,
     VIRTIO_NET_CTRL_RX_NOUNI, VIRTIO_NET_CTRL_RX_PROMISC, VIRTIO_NET_CTRL_VLAN,
     VIRTIO_NET_CTRL_VLAN_ADD, VIRTIO_NET_CTRL_VLAN_DEL, VIRTIO_NET_ERR, VIRTIO_NET_F_CSUM,
-    VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX, VIRTIO_NET_F_CTRL_RX_EXTRA,
-    VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO,
-    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC,
-    VIRTIO_NET_F_MQ, VIRTIO_NET_OK, VIRTIO_TYPE_NET,
+    VIRTIO_NET_F_CTRL_GUEST_OFFLOADS, VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_RX,
+    VIRTIO_NET_F_CTRL_RX_EXTRA, VIRTIO_NET_F_CTRL_VLAN, VIRTIO_NET_F_CTRL_VQ,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_ECN, VIRTIO_NET_F_GUEST_TSO4,
+    VIRTIO_NET_F_GUEST_TSO6, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4,
+    VIRTIO_NET_F_HOST_TSO6, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_OK, VIRTIO_TYPE_NET,
 };
 use address_space::{AddressSpace, RegionCache};
 use anyhow::{anyhow, bail, Context, Result};
-use log::{error, warn};
+use log::{error, info, warn};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use machine_manager::{
     config::{ConfigCheck, NetworkInterfaceConfig},
     event_loop::EventLoop,
+    temp_cleaner::TempCleaner,
 };
 use migration::{
     migration::Migratable, DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager,
@@ -54,13 +58,16 @@ use migration::{
 };
 use migration_derive::{ByteCode, Desc};
 use util::byte_code::ByteCode;
+use util::leak_bucket::LeakBucket;
 use util::loop_context::gen_delete_notifiers;
 use util::loop_context::{
-    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+    read_fd, EventLoopContext, EventNotifier, EventNotifierHelper, NotifierCallback,
+    NotifierOperation,
 };
 use util::num_ops::{read_u32, str_to_usize};
 use util::tap::{
-    Tap, IFF_MULTI_QUEUE, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6, TUN_F_TSO_ECN, TUN_F_UFO,
+    run_tap_script, Tap, TapScriptKind, IFF_MULTI_QUEUE, TUN_F_CSUM, TUN_F_TSO4, TUN_F_TSO6,
+    TUN_F_TSO_ECN, TUN_F_UFO, TUN_F_VIRTIO,
 };
 use vmm_sys_util::{epoll::EventSet, eventfd::EventFd};
 /// Number of virtqueues(rx/tx/ctrl).
@@ -81,6 +88,10 @@ const NET_HDR_LENGTH: usize = mem::size_of::<VirtioNetHdr>();
 const VLAN_TAG_LENGTH: usize = 4;
 /// The offset of vlan tpid for 802.1Q tag.
 const VLAN_TPID_LENGTH: usize = 2;
+/// Opcode of the rx requests recorded in the error statistics.
+const NET_ERROR_OPCODE_RX: u32 = 0;
+/// Opcode of the tx requests recorded in the error statistics.
+const NET_ERROR_OPCODE_TX: u32 = 1;
 
 type SenderConfig = Option<Tap>;
 
@@ -169,15 +180,18 @@ pub struct CtrlInfo {
     vlan_map: HashMap<u16, u32>,
     /// The net device status.
     state: Arc<Mutex<VirtioNetState>>,
+    /// Helper to change the config space of the net device.
+    config_updater: Arc<ConfigUpdater>,
 }
 
 impl CtrlInfo {
-    pub fn new(state: Arc<Mutex<VirtioNetState>>) -> Self {
+    pub fn new(state: Arc<Mutex<VirtioNetState>>, config_updater: Arc<ConfigUpdater>) -> Self {
         CtrlInfo {
             rx_mode: CtrlRxMode::default(),
             mac_info: CtrlMacInfo::default(),
             vlan_map: HashMap::new(),
             state,
+            config_updater,
         }
     }
 
@@ -293,12 +307,14 @@ impl CtrlInfo {
                 if ack == VIRTIO_NET_ERR {
                     return VIRTIO_NET_ERR;
                 }
-                self.state
-                    .lock()
-                    .unwrap()
-                    .config_space
-                    .mac
-                    .copy_from_slice(&mac);
+                self.config_updater.write(|| {
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .config_space
+                        .mac
+                        .copy_from_slice(&mac)
+                });
             }
             VIRTIO_NET_CTRL_MAC_TABLE_SET => {
                 ack = self
@@ -363,6 +379,7 @@ impl CtrlInfo {
     fn handle_mq(
         &mut self,
         mem_space: &AddressSpace,
+        taps: Option<&[Tap]>,
         cmd: u8,
         data_iovec: &mut Vec<ElemIovec>,
     ) -> u8 {
@@ -379,12 +396,25 @@ impl CtrlInfo {
                 return ack;
             }
 
+            let max_pairs = self.state.lock().unwrap().config_space.max_virtqueue_pairs;
             if !(VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN..=VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX)
                 .contains(&queue_pairs)
+                || queue_pairs > max_pairs
             {
-                error!("Invalid queue pairs {}", queue_pairs);
-                ack = VIRTIO_NET_ERR;
+                error!(
+                    "Invalid queue pairs {}, max queue pairs {}",
+                    queue_pairs, max_pairs
+                );
+                return VIRTIO_NET_ERR;
             }
+
+            if let Some(taps) = taps {
+                if let Err(e) = set_tap_queue_pairs(taps, queue_pairs) {
+                    error!("Failed to set queue pairs {}, {:?}", queue_pairs, e);
+                    return VIRTIO_NET_ERR;
+                }
+            }
+            self.state.lock().unwrap().queue_pairs = queue_pairs;
         } else {
             error!(
                 "Control queue header command can't match {}",
@@ -396,6 +426,49 @@ impl CtrlInfo {
         ack
     }
 
+    fn handle_guest_offloads(
+        &mut self,
+        mem_space: &AddressSpace,
+        taps: Option<&[Tap]>,
+        driver_features: u64,
+        cmd: u8,
+        data_iovec: &mut Vec<ElemIovec>,
+    ) -> u8 {
+        if cmd != VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET {
+            error!(
+                "Control queue header command can't match {}",
+                VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET
+            );
+            return VIRTIO_NET_ERR;
+        }
+
+        let mut offloads: u64 = 0;
+        match get_buf_and_discard(mem_space, data_iovec, offloads.as_mut_bytes()) {
+            Ok(iovec) => *data_iovec = iovec,
+            Err(e) => {
+                error!("Failed to get guest offloads {}", e);
+                return VIRTIO_NET_ERR;
+            }
+        }
+
+        let flags = match get_guest_offloads_flags(offloads, driver_features) {
+            Ok(flags) => flags,
+            Err(e) => {
+                error!("Invalid guest offloads {:#x}, {:?}", offloads, e);
+                return VIRTIO_NET_ERR;
+            }
+        };
+        if let Some(taps) = taps {
+            for tap in taps.iter() {
+                if let Err(e) = tap.set_offload(flags) {
+                    error!("Failed to set tap offload {:#x}, {:?}", flags, e);
+                    return VIRTIO_NET_ERR;
+                }
+            }
+        }
+        VIRTIO_NET_OK
+    }
+
     fn filter_packets(&mut self, buf: &[u8]) -> bool {
         // Broadcast address: 0xff:0xff:0xff:0xff:0xff:0xff.
         let bcast = [0xff; MAC_ADDR_LEN];
@@ -512,6 +585,8 @@ pub struct NetCtrlHandler {
     pub driver_features: u64,
     /// Device is broken or not.
     pub device_broken: Arc<AtomicBool>,
+    /// Taps of the queue pairs, used to enable the queue pairs set by guest.
+    pub taps: Option<Vec<Tap>>,
 }
 
 #[repr(C, packed)]
@@ -588,6 +663,16 @@ impl NetCtrlHandler {
                 VIRTIO_NET_CTRL_MQ => {
                     ack = self.ctrl.ctrl_info.lock().unwrap().handle_mq(
                         &self.mem_space,
+                        self.taps.as_deref(),
+                        ctrl_hdr.cmd,
+                        &mut data_iovec,
+                    );
+                }
+                VIRTIO_NET_CTRL_GUEST_OFFLOADS => {
+                    ack = self.ctrl.ctrl_info.lock().unwrap().handle_guest_offloads(
+                        &self.mem_space,
+                        self.taps.as_deref(),
+                        self.driver_features,
                         ctrl_hdr.cmd,
                         &mut data_iovec,
                     );
@@ -664,29 +749,101 @@ impl EventNotifierHelper for NetCtrlHandler {
     }
 }
 
+/// Rate limit of one direction of a queue pair, by bytes and packets per second.
+struct NetRateLimiter {
+    bytes: Option<LeakBucket>,
+    packets: Option<LeakBucket>,
+}
+
+impl NetRateLimiter {
+    fn new(bps: Option<u64>, pps: Option<u64>) -> Result<Self> {
+        Ok(NetRateLimiter {
+            bytes: bps.map(LeakBucket::new).transpose()?,
+            packets: pps.map(LeakBucket::new).transpose()?,
+        })
+    }
+
+    /// Return true if the allowance is exhausted. The timer to wake up is started, and the
+    /// packets should not be processed until it expires.
+    fn throttled(&mut self, ctx: &mut EventLoopContext) -> bool {
+        self.bytes.as_mut().map_or(false, |lb| lb.throttled(ctx, 0))
+            || self
+                .packets
+                .as_mut()
+                .map_or(false, |lb| lb.throttled(ctx, 0))
+    }
+
+    /// Account one packet of `bytes` which has been processed.
+    fn consume(&mut self, bytes: u64) {
+        if let Some(lb) = self.bytes.as_mut() {
+            lb.consume(bytes);
+        }
+        if let Some(lb) = self.packets.as_mut() {
+            lb.consume(1);
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.bytes.is_some() || self.packets.is_some()
+    }
+
+    fn clear_timer(&mut self) {
+        for lb in self.bytes.iter_mut().chain(self.packets.iter_mut()) {
+            lb.clear_timer();
+        }
+    }
+
+    /// Fds written when the timers of the buckets expire.
+    fn timer_fds(&self) -> Vec<RawFd> {
+        self.bytes
+            .iter()
+            .chain(self.packets.iter())
+            .map(|lb| lb.as_raw_fd())
+            .collect()
+    }
+}
+
 struct TxVirtio {
     queue: Arc<Mutex<Queue>>,
     queue_evt: Arc<EventFd>,
+    rate_limiter: NetRateLimiter,
 }
 
 impl TxVirtio {
-    fn new(queue: Arc<Mutex<Queue>>, queue_evt: Arc<EventFd>) -> Self {
-        TxVirtio { queue, queue_evt }
+    fn new(
+        queue: Arc<Mutex<Queue>>,
+        queue_evt: Arc<EventFd>,
+        rate_limiter: NetRateLimiter,
+    ) -> Self {
+        TxVirtio {
+            queue,
+            queue_evt,
+            rate_limiter,
+        }
     }
 }
 
 struct RxVirtio {
     queue_full: bool,
+    /// The rx is paused by the rate limiter until its timer expires.
+    rate_limited: bool,
     queue: Arc<Mutex<Queue>>,
     queue_evt: Arc<EventFd>,
+    rate_limiter: NetRateLimiter,
 }
 
 impl RxVirtio {
-    fn new(queue: Arc<Mutex<Queue>>, queue_evt: Arc<EventFd>) -> Self {
+    fn new(
+        queue: Arc<Mutex<Queue>>,
+        queue_evt: Arc<EventFd>,
+        rate_limiter: NetRateLimiter,
+    ) -> Self {
         RxVirtio {
             queue_full: false,
+            rate_limited: false,
             queue,
             queue_evt,
+            rate_limiter,
         }
     }
 }
@@ -705,10 +862,14 @@ struct NetIoHandler {
     is_listening: bool,
     ctrl_info: Arc<Mutex<CtrlInfo>>,
     queue_size: u16,
+    /// Error statistics of the net device.
+    error_stats: Arc<DeviceErrorStats>,
+    /// Iothread of the queue pair, whose event loop runs the timers of the rate limiters.
+    iothread: Option<String>,
 }
 
 impl NetIoHandler {
-    fn read_from_tap(iovecs: &[libc::iovec], tap: &mut Tap) -> i32 {
+    fn read_from_tap(iovecs: &[libc::iovec], tap: &mut Tap, error_stats: &DeviceErrorStats) -> i32 {
         // SAFETY: the arguments of readv has been checked and is correct.
         let size = unsafe {
             libc::readv(
@@ -722,6 +883,14 @@ impl NetIoHandler {
             if e.kind() == std::io::ErrorKind::WouldBlock {
                 return size;
             }
+            let errno = e.raw_os_error().unwrap_or(libc::EIO);
+            error_stats.record(
+                ErrorCategory::from_errno(errno),
+                errno,
+                NET_ERROR_OPCODE_RX,
+                0,
+                iovecs.iter().map(|iov| iov.iov_len as u64).sum(),
+            );
 
             // If the backend tap device is removed, readv returns less than 0.
             // At this time, the content in the tap needs to be cleaned up.
@@ -769,6 +938,14 @@ impl NetIoHandler {
 
         let mut rx_packets = 0;
         while let Some(tap) = self.tap.as_mut() {
+            if self.rx.rate_limiter.is_enabled() {
+                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                    if self.rx.rate_limiter.throttled(ctx) {
+                        self.rx.rate_limited = true;
+                        break;
+                    }
+                }
+            }
             let elem = queue
                 .vring
                 .pop_avail(&self.mem_space, self.driver_features)
@@ -794,7 +971,7 @@ impl NetIoHandler {
             }
 
             // Read the data from the tap device.
-            let size = NetIoHandler::read_from_tap(&iovecs, tap);
+            let size = NetIoHandler::read_from_tap(&iovecs, tap, &self.error_stats);
             if size < (NET_HDR_LENGTH + ETHERNET_HDR_LENGTH + VLAN_TAG_LENGTH) as i32 {
                 queue.vring.push_back();
                 break;
@@ -830,6 +1007,9 @@ impl NetIoHandler {
                         elem.index, size
                     )
                 })?;
+            self.rx
+                .rate_limiter
+                .consume((size as usize - NET_HDR_LENGTH) as u64);
 
             if queue
                 .vring
@@ -874,7 +1054,23 @@ impl NetIoHandler {
                     ErrorKind::Interrupted => continue,
                     ErrorKind::WouldBlock => return -1_i8,
                     // Ignore other errors which can not be handled.
-                    _ => error!("Failed to call writev for net handle_tx: {}", e),
+                    _ => {
+                        error!("Failed to call writev for net handle_tx: {}", e);
+                        let errno = e.raw_os_error().unwrap_or(libc::EIO);
+                        // The packet is dropped by the tap device whose queue is full.
+                        let category = if errno == libc::ENOBUFS {
+                            ErrorCategory::ThrottledDrop
+                        } else {
+                            ErrorCategory::from_errno(errno)
+                        };
+                        self.error_stats.record(
+                            category,
+                            errno,
+                            NET_ERROR_OPCODE_TX,
+                            0,
+                            iovecs.iter().map(|iov| iov.iov_len as u64).sum(),
+                        );
+                    }
                 }
             }
             break;
@@ -890,6 +1086,14 @@ impl NetIoHandler {
         }
         let mut tx_packets = 0;
         loop {
+            // The tx queue is processed again when the timer of rate limiter expires.
+            if self.tx.rate_limiter.is_enabled() {
+                if let Some(ctx) = EventLoop::get_ctx(self.iothread.as_ref()) {
+                    if self.tx.rate_limiter.throttled(ctx) {
+                        break;
+                    }
+                }
+            }
             let elem = queue
                 .vring
                 .pop_avail(&self.mem_space, self.driver_features)
@@ -917,6 +1121,10 @@ impl NetIoHandler {
                 })?;
                 return Ok(());
             }
+            let len: usize = iovecs.iter().map(|iov| iov.iov_len).sum();
+            self.tx
+                .rate_limiter
+                .consume(len.saturating_sub(NET_HDR_LENGTH) as u64);
 
             queue
                 .vring
@@ -972,12 +1180,63 @@ impl NetIoHandler {
         if old_tap_fd != -1 {
             notifiers_fds.push(old_tap_fd);
         }
+        notifiers_fds.append(&mut locked_net_io.rx.rate_limiter.timer_fds());
+        notifiers_fds.append(&mut locked_net_io.tx.rate_limiter.timer_fds());
         let mut notifiers = gen_delete_notifiers(&notifiers_fds);
         drop(locked_net_io);
 
         notifiers.append(&mut EventNotifierHelper::internal_notifiers(net_io.clone()));
         notifiers
     }
+
+    /// Receive packets from tap, and stop listening to tap if the rx queue is full or
+    /// the rx is paused by the rate limiter.
+    fn handle_rx_event(&mut self) -> Option<Vec<EventNotifier>> {
+        if let Err(ref e) = self.handle_rx() {
+            error!("Failed to handle rx(tap event), {:?}", e);
+            report_virtio_error(
+                self.interrupt_cb.clone(),
+                self.driver_features,
+                &self.device_broken,
+            );
+            return None;
+        }
+
+        if let Some(tap) = self.tap.as_ref() {
+            if self.rx.queue_full || self.rx.rate_limited {
+                let notifier = vec![EventNotifier::new(
+                    NotifierOperation::Park,
+                    tap.as_raw_fd(),
+                    None,
+                    EventSet::IN | EventSet::EDGE_TRIGGERED,
+                    Vec::new(),
+                )];
+                self.is_listening = false;
+                self.rx.queue_full = false;
+                self.rx.rate_limited = false;
+                return Some(notifier);
+            }
+        }
+        None
+    }
+
+    /// Resume the rx when the timer of rate limiter expires. The tap is listened again
+    /// if it's parked, otherwise the packets left in tap are received now.
+    fn resume_rx(&mut self) -> Option<Vec<EventNotifier>> {
+        self.rx.rate_limiter.clear_timer();
+        let tap_fd = self.tap.as_ref()?.as_raw_fd();
+        if self.is_listening {
+            return self.handle_rx_event();
+        }
+        self.is_listening = true;
+        Some(vec![EventNotifier::new(
+            NotifierOperation::Resume,
+            tap_fd,
+            None,
+            EventSet::IN | EventSet::EDGE_TRIGGERED,
+            Vec::new(),
+        )])
+    }
 }
 
 fn get_net_header(iovec: &[libc::iovec], buf: &mut [u8]) -> Result<usize> {
@@ -1100,39 +1359,61 @@ impl EventNotifierHelper for NetIoHandler {
                 if locked_net_io.device_broken.load(Ordering::SeqCst) {
                     return None;
                 }
+                locked_net_io.handle_rx_event()
+            });
+            let tap_fd = tap.as_raw_fd();
+            notifiers.push(build_event_notifier(
+                tap_fd,
+                Some(handler),
+                NotifierOperation::AddShared,
+                EventSet::IN | EventSet::EDGE_TRIGGERED,
+            ));
+        }
+
+        // Register timer event notifiers for rx rate limit.
+        for fd in locked_net_io.rx.rate_limiter.timer_fds() {
+            let cloned_net_io = net_io.clone();
+            let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                let mut locked_net_io = cloned_net_io.lock().unwrap();
+                if locked_net_io.device_broken.load(Ordering::SeqCst) {
+                    return None;
+                }
+                locked_net_io.resume_rx()
+            });
+            notifiers.push(build_event_notifier(
+                fd,
+                Some(handler),
+                NotifierOperation::AddShared,
+                EventSet::IN,
+            ));
+        }
 
-                if let Err(ref e) = locked_net_io.handle_rx() {
-                    error!("Failed to handle rx(tap event), {:?}", e);
+        // Register timer event notifiers for tx rate limit.
+        for fd in locked_net_io.tx.rate_limiter.timer_fds() {
+            let cloned_net_io = net_io.clone();
+            let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+                read_fd(fd);
+                let mut locked_net_io = cloned_net_io.lock().unwrap();
+                if locked_net_io.device_broken.load(Ordering::SeqCst) {
+                    return None;
+                }
+                locked_net_io.tx.rate_limiter.clear_timer();
+                if let Err(ref e) = locked_net_io.handle_tx() {
+                    error!("Failed to handle tx(rate limit timer) for net, {:?}", e);
                     report_virtio_error(
                         locked_net_io.interrupt_cb.clone(),
                         locked_net_io.driver_features,
                         &locked_net_io.device_broken,
                     );
-                    return None;
-                }
-
-                if let Some(tap) = locked_net_io.tap.as_ref() {
-                    if locked_net_io.rx.queue_full {
-                        let notifier = vec![EventNotifier::new(
-                            NotifierOperation::Park,
-                            tap.as_raw_fd(),
-                            None,
-                            EventSet::IN | EventSet::EDGE_TRIGGERED,
-                            Vec::new(),
-                        )];
-                        locked_net_io.is_listening = false;
-                        locked_net_io.rx.queue_full = false;
-                        return Some(notifier);
-                    }
                 }
                 None
             });
-            let tap_fd = tap.as_raw_fd();
             notifiers.push(build_event_notifier(
-                tap_fd,
+                fd,
                 Some(handler),
                 NotifierOperation::AddShared,
-                EventSet::IN | EventSet::EDGE_TRIGGERED,
+                EventSet::IN,
             ));
         }
 
@@ -1153,6 +1434,9 @@ pub struct VirtioNetState {
     pub config_space: VirtioNetConfig,
     /// Device broken status.
     broken: bool,
+    /// Number of the queue pairs enabled by guest through the control queue, 0 if it's
+    /// never set since the device is activated.
+    queue_pairs: u16,
 }
 
 /// Network device structure.
@@ -1169,10 +1453,16 @@ pub struct Net {
     update_evts: Vec<Arc<EventFd>>,
     /// Eventfd for device deactivate.
     deactivate_evts: Vec<RawFd>,
+    /// Eventfd for device deactivate of the queue pairs running in their own iothreads.
+    queue_deactivate_evts: HashMap<String, Vec<RawFd>>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
     /// The information about control command.
     ctrl_info: Option<Arc<Mutex<CtrlInfo>>>,
+    /// Error statistics shared with the io handlers.
+    error_stats: Arc<DeviceErrorStats>,
+    /// Helper to change the config space.
+    config_updater: Arc<ConfigUpdater>,
 }
 
 impl Default for Net {
@@ -1184,14 +1474,18 @@ impl Default for Net {
             senders: None,
             update_evts: Vec::new(),
             deactivate_evts: Vec::new(),
+            queue_deactivate_evts: HashMap::new(),
             broken: Arc::new(AtomicBool::new(false)),
             ctrl_info: None,
+            error_stats: Arc::new(DeviceErrorStats::default()),
+            config_updater: ConfigUpdater::new("net"),
         }
     }
 }
 
 impl Net {
     pub fn new(net_cfg: NetworkInterfaceConfig) -> Self {
+        let config_updater = ConfigUpdater::new(&net_cfg.id);
         Self {
             net_cfg,
             taps: None,
@@ -1199,10 +1493,19 @@ impl Net {
             senders: None,
             update_evts: Vec::new(),
             deactivate_evts: Vec::new(),
+            queue_deactivate_evts: HashMap::new(),
             broken: Arc::new(AtomicBool::new(false)),
             ctrl_info: None,
+            error_stats: Arc::new(DeviceErrorStats::default()),
+            config_updater,
         }
     }
+
+    /// Get the number of the enabled queue pairs. Only the first queue pair is enabled
+    /// until guest sets the queue pairs through the control queue.
+    fn enabled_queue_pairs(&self) -> u16 {
+        cmp::max(self.state.lock().unwrap().queue_pairs, 1)
+    }
 }
 
 /// Set Mac address configured into the virtio configuration, and return features mask with
@@ -1309,6 +1612,7 @@ pub fn create_tap(
         error!("Create tap: fd and file_path exist meanwhile (use fd by default)");
     }
 
+    let mut dev_name = host_dev_name.map(String::from);
     let mut taps = Vec::with_capacity(queue_pairs as usize);
     for index in 0..queue_pairs {
         let tap = if let Some(fds) = net_fds {
@@ -1319,14 +1623,23 @@ pub fn create_tap(
                 .with_context(|| format!("Failed to create tap, index is {}", index))?
         } else {
             // `unwrap()` won't fail because the arguments have been checked
-            let dev_name = host_dev_name.unwrap();
-            check_mq(dev_name, queue_pairs)?;
-            Tap::new(Some(dev_name), None, queue_pairs).with_context(|| {
+            let name = dev_name.as_deref().unwrap();
+            // A name with `%d` is a template, the kernel creates a new tap for it.
+            let is_template = name.contains('%');
+            if !is_template {
+                check_mq(name, queue_pairs)?;
+            }
+            let tap = Tap::new(Some(name), None, queue_pairs).with_context(|| {
                 format!(
                     "Failed to create tap with name {}, index is {}",
-                    dev_name, index
+                    name, index
                 )
-            })?
+            })?;
+            // The other queue pairs attach to the tap created by the first one.
+            if is_template {
+                dev_name = Some(tap.ifname()?);
+            }
+            tap
         };
 
         tap.set_hdr_size(NET_HDR_LENGTH as u32)
@@ -1338,6 +1651,77 @@ pub fn create_tap(
     Ok(Some(taps))
 }
 
+/// Create the taps of the net device by `host_dev_name`, which is replaced by the name
+/// given by the kernel if it's a template, and run the ifup script of the tap. The ifdown
+/// script is run by `run_tap_ifdown` at unrealize, or when the VM exits.
+///
+/// # Arguments
+///
+/// * `net_cfg` - Configuration of the net device.
+/// * `queue_pairs` - The number of virtio queue pairs.
+pub fn create_tap_by_name(
+    net_cfg: &mut NetworkInterfaceConfig,
+    queue_pairs: u16,
+) -> Result<Option<Vec<Tap>>> {
+    let taps = match create_tap(None, Some(&net_cfg.host_dev_name), queue_pairs)? {
+        Some(taps) if !taps.is_empty() => taps,
+        _ => return Ok(None),
+    };
+    let ifname = taps[0].ifname()?;
+    if ifname != net_cfg.host_dev_name {
+        info!("Tap {} is created for net device {}", ifname, net_cfg.id);
+        net_cfg.host_dev_name = ifname.clone();
+    }
+
+    if net_cfg.script.is_some() {
+        run_tap_script(&net_cfg.netdev, TapScriptKind::Up, &ifname)?;
+    }
+    if net_cfg.downscript.is_some() {
+        let netdev = net_cfg.netdev.clone();
+        TempCleaner::add_exit_notifier(
+            tap_ifdown_id(&net_cfg.id),
+            Arc::new(move || {
+                if let Err(e) = run_tap_script(&netdev, TapScriptKind::Down, &ifname) {
+                    error!("{:?}", e);
+                }
+            }),
+        );
+    }
+    Ok(Some(taps))
+}
+
+fn tap_ifdown_id(id: &str) -> String {
+    format!("tap-ifdown-{}", id)
+}
+
+/// Run the ifdown script of the tap of net device `id`, which is registered by
+/// `create_tap_by_name`.
+pub fn run_tap_ifdown(id: &str) {
+    if let Some(ifdown) = TempCleaner::remove_exit_notifier(&tap_ifdown_id(id)) {
+        ifdown();
+    }
+}
+
+/// Attach the taps of the first `queue_pairs` queue pairs and detach the others, so
+/// the host only delivers packets to the queues enabled by guest.
+///
+/// # Arguments
+///
+/// * `taps` - Taps of all the queue pairs.
+/// * `queue_pairs` - The number of enabled queue pairs.
+fn set_tap_queue_pairs(taps: &[Tap], queue_pairs: u16) -> Result<()> {
+    // Single queue tap can not be detached.
+    if taps.len() <= 1 {
+        return Ok(());
+    }
+    for (index, tap) in taps.iter().enumerate() {
+        tap.set_queue(index < queue_pairs as usize)
+            .with_context(|| format!("Failed to set tap queue, index is {}", index))?;
+    }
+
+    Ok(())
+}
+
 /// Get the tap offload flags from driver features.
 ///
 /// # Arguments
@@ -1363,6 +1747,61 @@ fn get_tap_offload_flags(features: u64) -> u32 {
     flags
 }
 
+/// Get the tap offload flags from the guest offloads set by control queue.
+///
+/// # Arguments
+///
+/// * `offloads` - The guest offloads set by driver.
+/// * `driver_features` - The driver features.
+fn get_guest_offloads_flags(offloads: u64, driver_features: u64) -> Result<u32> {
+    if !virtio_has_feature(driver_features, VIRTIO_NET_F_CTRL_GUEST_OFFLOADS) {
+        bail!("Guest offloads control is not negotiated");
+    }
+    let negotiated = driver_features
+        & (1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_TSO4
+            | 1 << VIRTIO_NET_F_GUEST_TSO6
+            | 1 << VIRTIO_NET_F_GUEST_ECN
+            | 1 << VIRTIO_NET_F_GUEST_UFO);
+    if offloads & !negotiated != 0 {
+        bail!(
+            "Guest offloads {:#x} are not in negotiated offloads {:#x}",
+            offloads,
+            negotiated
+        );
+    }
+    Ok(get_tap_offload_flags(offloads))
+}
+
+/// Get the offload features which can be offered to guest from the offloads supported
+/// by tap. The offloads all depend on checksum offload.
+///
+/// # Arguments
+///
+/// * `tap_offloads` - The `TUN_F_*` flags supported by tap.
+fn get_offload_features(tap_offloads: u32) -> u64 {
+    if tap_offloads & TUN_F_CSUM == 0 {
+        return 0;
+    }
+
+    let mut features = 1 << VIRTIO_NET_F_CSUM
+        | 1 << VIRTIO_NET_F_GUEST_CSUM
+        | 1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS;
+    if tap_offloads & TUN_F_TSO4 != 0 {
+        features |= 1 << VIRTIO_NET_F_GUEST_TSO4 | 1 << VIRTIO_NET_F_HOST_TSO4;
+    }
+    if tap_offloads & TUN_F_TSO6 != 0 {
+        features |= 1 << VIRTIO_NET_F_GUEST_TSO6 | 1 << VIRTIO_NET_F_HOST_TSO6;
+    }
+    if tap_offloads & TUN_F_TSO_ECN != 0 && tap_offloads & (TUN_F_TSO4 | TUN_F_TSO6) != 0 {
+        features |= 1 << VIRTIO_NET_F_GUEST_ECN;
+    }
+    if tap_offloads & TUN_F_UFO != 0 {
+        features |= 1 << VIRTIO_NET_F_GUEST_UFO | 1 << VIRTIO_NET_F_HOST_UFO;
+    }
+    features
+}
+
 impl VirtioDevice for Net {
     /// Realize virtio network device.
     fn realize(&mut self) -> Result<()> {
@@ -1375,17 +1814,17 @@ impl VirtioDevice for Net {
                 self.net_cfg.iothread,
             );
         }
+        for iothread in self.net_cfg.queue_iothreads.iter().flatten() {
+            if EventLoop::get_ctx(Some(iothread)).is_none() {
+                bail!(
+                    "IOThread {:?} of Net is not configured in params.",
+                    iothread
+                );
+            }
+        }
 
         let mut locked_state = self.state.lock().unwrap();
         locked_state.device_features = 1 << VIRTIO_F_VERSION_1
-            | 1 << VIRTIO_NET_F_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_CSUM
-            | 1 << VIRTIO_NET_F_GUEST_TSO4
-            | 1 << VIRTIO_NET_F_GUEST_TSO6
-            | 1 << VIRTIO_NET_F_GUEST_UFO
-            | 1 << VIRTIO_NET_F_HOST_TSO4
-            | 1 << VIRTIO_NET_F_HOST_TSO6
-            | 1 << VIRTIO_NET_F_HOST_UFO
             | 1 << VIRTIO_NET_F_CTRL_RX
             | 1 << VIRTIO_NET_F_CTRL_VLAN
             | 1 << VIRTIO_NET_F_CTRL_RX_EXTRA
@@ -1405,7 +1844,7 @@ impl VirtioDevice for Net {
 
         if !self.net_cfg.host_dev_name.is_empty() {
             self.taps = None;
-            self.taps = create_tap(None, Some(&self.net_cfg.host_dev_name), queue_pairs)
+            self.taps = create_tap_by_name(&mut self.net_cfg, queue_pairs)
                 .with_context(|| "Failed to open tap with file path")?;
         } else if let Some(fds) = self.net_cfg.tap_fds.as_mut() {
             let mut created_fds = 0;
@@ -1425,13 +1864,13 @@ impl VirtioDevice for Net {
             self.taps = None;
         }
 
-        // Using the first tap to test if all the taps have ufo.
-        if let Some(tap) = self.taps.as_ref().map(|t| &t[0]) {
-            if !tap.has_ufo() {
-                locked_state.device_features &=
-                    !(1 << VIRTIO_NET_F_GUEST_UFO | 1 << VIRTIO_NET_F_HOST_UFO);
-            }
-        }
+        // Using the first tap to probe the offloads supported by all the taps, the
+        // offloads refused by tap are not offered to guest.
+        let tap_offloads = self
+            .taps
+            .as_ref()
+            .map_or(TUN_F_VIRTIO, |taps| taps[0].probe_offloads());
+        locked_state.device_features |= get_offload_features(tap_offloads);
 
         if let Some(mac) = &self.net_cfg.mac {
             locked_state.device_features |=
@@ -1446,6 +1885,11 @@ impl VirtioDevice for Net {
             // For microvm which will call realize() twice for one virtio-net-device.
             locked_state.device_features |= 1 << VIRTIO_NET_F_MAC;
         }
+        register_net_error_stats(
+            &self.net_cfg.id,
+            &self.net_cfg.host_dev_name,
+            self.error_stats.clone(),
+        );
 
         Ok(())
     }
@@ -1456,6 +1900,8 @@ impl VirtioDevice for Net {
             VirtioNetState::descriptor(),
             &self.net_cfg.id,
         );
+        unregister_net_error_stats(&self.net_cfg.id);
+        run_tap_ifdown(&self.net_cfg.id);
         Ok(())
     }
 
@@ -1532,12 +1978,18 @@ impl VirtioDevice for Net {
             && !virtio_has_feature(driver_features, VIRTIO_F_VERSION_1)
             && *data != config_slice[offset as usize..(offset as usize + data_len)]
         {
-            config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data);
+            self.config_updater.write(|| {
+                config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data)
+            });
         }
 
         Ok(())
     }
 
+    fn config_generation(&self) -> u32 {
+        self.config_updater.generation()
+    }
+
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     fn activate(
@@ -1548,7 +2000,12 @@ impl VirtioDevice for Net {
         mut queue_evts: Vec<Arc<EventFd>>,
     ) -> Result<()> {
         let queue_num = queues.len();
-        let ctrl_info = Arc::new(Mutex::new(CtrlInfo::new(self.state.clone())));
+        self.config_updater
+            .set_interrupt_cb(Some(interrupt_cb.clone()));
+        let ctrl_info = Arc::new(Mutex::new(CtrlInfo::new(
+            self.state.clone(),
+            self.config_updater.clone(),
+        )));
         self.ctrl_info = Some(ctrl_info.clone());
         let driver_features = self.state.lock().unwrap().driver_features;
         if (driver_features & 1 << VIRTIO_NET_F_CTRL_VQ != 0) && (queue_num % 2 != 0) {
@@ -1561,6 +2018,7 @@ impl VirtioDevice for Net {
                 interrupt_cb: interrupt_cb.clone(),
                 driver_features,
                 device_broken: self.broken.clone(),
+                taps: self.taps.clone(),
             };
 
             let notifiers =
@@ -1576,6 +2034,11 @@ impl VirtioDevice for Net {
         let features = self.get_driver_features(0_u32);
         let flags = get_tap_offload_flags(features as u64);
 
+        // The queue pairs set by guest are kept if the state is restored by migration.
+        if let Some(taps) = &self.taps {
+            set_tap_queue_pairs(taps, self.enabled_queue_pairs())?;
+        }
+
         let mut senders = Vec::new();
         let queue_pairs = queue_num / 2;
         for index in 0..queue_pairs {
@@ -1592,10 +2055,19 @@ impl VirtioDevice for Net {
                     .with_context(|| "Failed to set tap offload")?;
             }
 
+            let queue_iothread = self
+                .net_cfg
+                .queue_iothreads
+                .as_ref()
+                .and_then(|iothreads| iothreads.get(index));
+            let rx_limiter =
+                NetRateLimiter::new(self.net_cfg.rate_limit_bps, self.net_cfg.rate_limit_pps)?;
+            let tx_limiter =
+                NetRateLimiter::new(self.net_cfg.rate_limit_bps, self.net_cfg.rate_limit_pps)?;
             let update_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK)?);
             let mut handler = NetIoHandler {
-                rx: RxVirtio::new(rx_queue, rx_queue_evt),
-                tx: TxVirtio::new(tx_queue, tx_queue_evt),
+                rx: RxVirtio::new(rx_queue, rx_queue_evt, rx_limiter),
+                tx: TxVirtio::new(tx_queue, tx_queue_evt, tx_limiter),
                 tap: self.taps.as_ref().map(|t| t[index].clone()),
                 tap_fd: -1,
                 mem_space: mem_space.clone(),
@@ -1607,17 +2079,27 @@ impl VirtioDevice for Net {
                 is_listening: true,
                 ctrl_info: ctrl_info.clone(),
                 queue_size: self.queue_size(),
+                error_stats: self.error_stats.clone(),
+                iothread: queue_iothread.or(self.net_cfg.iothread.as_ref()).cloned(),
             };
             if let Some(tap) = &handler.tap {
                 handler.tap_fd = tap.as_raw_fd();
             }
 
             let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
-            register_event_helper(
-                notifiers,
-                self.net_cfg.iothread.as_ref(),
-                &mut self.deactivate_evts,
-            )?;
+            if let Some(iothread) = queue_iothread {
+                let deactivate_evts = self
+                    .queue_deactivate_evts
+                    .entry(iothread.clone())
+                    .or_default();
+                register_event_helper(notifiers, Some(iothread), deactivate_evts)?;
+            } else {
+                register_event_helper(
+                    notifiers,
+                    self.net_cfg.iothread.as_ref(),
+                    &mut self.deactivate_evts,
+                )?;
+            }
             self.update_evts.push(update_evt);
         }
         self.senders = Some(senders);
@@ -1680,8 +2162,14 @@ impl VirtioDevice for Net {
 
     fn deactivate(&mut self) -> Result<()> {
         unregister_event_helper(self.net_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
+        for (iothread, deactivate_evts) in self.queue_deactivate_evts.iter_mut() {
+            unregister_event_helper(Some(iothread), deactivate_evts)?;
+        }
+        self.queue_deactivate_evts.clear();
         self.update_evts.clear();
         self.ctrl_info = None;
+        self.config_updater.set_interrupt_cb(None);
+        self.state.lock().unwrap().queue_pairs = 0;
         Ok(())
     }
 }
@@ -1705,6 +2193,12 @@ impl StateTransfer for Net {
         let mut locked_state = self.state.lock().unwrap();
         locked_state.as_mut_bytes().copy_from_slice(state);
         self.broken.store(locked_state.broken, Ordering::SeqCst);
+        drop(locked_state);
+
+        // Guest doesn't set the queue pairs again after migration.
+        if let Some(taps) = &self.taps {
+            set_tap_queue_pairs(taps, self.enabled_queue_pairs())?;
+        }
 
         Ok(())
     }
@@ -1726,6 +2220,7 @@ impl VirtioTrace for NetIoHandler {}
 mod tests {
     pub use super::super::*;
     pub use super::*;
+    use address_space::{GuestAddress, HostMemMapping, Region};
 
     #[test]
     fn test_net_init() {
@@ -1823,7 +2318,10 @@ mod tests {
 
     #[test]
     fn test_net_filter_vlan() {
-        let mut ctrl_info = CtrlInfo::new(Arc::new(Mutex::new(VirtioNetState::default())));
+        let mut ctrl_info = CtrlInfo::new(
+            Arc::new(Mutex::new(VirtioNetState::default())),
+            ConfigUpdater::new("net"),
+        );
         ctrl_info.rx_mode.promisc = false;
         let mut buf = [
             0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x81, 0x00,
@@ -1889,6 +2387,277 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_net_mq_config_space() {
+        let mut net = Net::default();
+        net.net_cfg.mq = true;
+        net.net_cfg.queues = 8;
+        net.realize().unwrap();
+        assert_eq!(net.queue_num(), 9);
+        assert!(virtio_has_feature(
+            net.state.lock().unwrap().device_features,
+            VIRTIO_NET_F_MQ
+        ));
+
+        // max_virtqueue_pairs is right after mac and status.
+        let mut data = [0_u8; 2];
+        net.read_config(8, &mut data).unwrap();
+        assert_eq!(u16::from_le_bytes(data), 4);
+
+        // Queue iothreads must be configured.
+        EventLoop::object_init(&None).unwrap();
+        net.net_cfg.queue_iothreads = Some(vec!["queue_iothread".to_string()]);
+        assert!(net.realize().is_err());
+    }
+
+    #[test]
+    fn test_net_ctrl_mq() {
+        let root = Region::init_container_region(1 << 36);
+        let mem_space = AddressSpace::new(root.clone()).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x10000, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(host_mmap), 0)
+            .unwrap();
+
+        let state = Arc::new(Mutex::new(VirtioNetState::default()));
+        state.lock().unwrap().config_space.max_virtqueue_pairs = 4;
+        let mut ctrl_info = CtrlInfo::new(state, ConfigUpdater::new("net"));
+        let addr = GuestAddress(0x1000);
+        let cmd = VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8;
+        for (queue_pairs, ack) in [
+            (1_u16, VIRTIO_NET_OK),
+            (4, VIRTIO_NET_OK),
+            (0, VIRTIO_NET_ERR),
+            (5, VIRTIO_NET_ERR),
+        ] {
+            mem_space.write_object(&queue_pairs, addr).unwrap();
+            let mut data_iovec = vec![ElemIovec { addr, len: 2 }];
+            assert_eq!(
+                ctrl_info.handle_mq(&mem_space, None, cmd, &mut data_iovec),
+                ack
+            );
+        }
+
+        // Missing queue pairs data.
+        let mut data_iovec = vec![ElemIovec { addr, len: 1 }];
+        assert_eq!(
+            ctrl_info.handle_mq(&mem_space, None, cmd, &mut data_iovec),
+            VIRTIO_NET_ERR
+        );
+        // Unknown command.
+        let mut data_iovec = vec![ElemIovec { addr, len: 2 }];
+        assert_eq!(
+            ctrl_info.handle_mq(&mem_space, None, cmd + 1, &mut data_iovec),
+            VIRTIO_NET_ERR
+        );
+    }
+
+    #[test]
+    fn test_net_mq_migration() {
+        let root = Region::init_container_region(1 << 36);
+        let mem_space = AddressSpace::new(root.clone()).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x10000, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(host_mmap), 0)
+            .unwrap();
+
+        let new_net = || {
+            let mut net = Net::default();
+            net.net_cfg.mq = true;
+            net.net_cfg.queues = 8;
+            net.realize().unwrap();
+            net
+        };
+        let mut src = new_net();
+        assert_eq!(src.enabled_queue_pairs(), 1);
+
+        // Guest enables 3 queue pairs through the control queue.
+        let mut ctrl_info = CtrlInfo::new(src.state.clone(), ConfigUpdater::new("net"));
+        let addr = GuestAddress(0x1000);
+        mem_space.write_object(&3_u16, addr).unwrap();
+        let mut data_iovec = vec![ElemIovec { addr, len: 2 }];
+        let cmd = VIRTIO_NET_CTRL_MQ_VQ_PAIRS_SET as u8;
+        assert_eq!(
+            ctrl_info.handle_mq(&mem_space, None, cmd, &mut data_iovec),
+            VIRTIO_NET_OK
+        );
+        assert_eq!(src.enabled_queue_pairs(), 3);
+
+        // The queue pairs are kept after migration, as guest doesn't set them again.
+        let state = src.get_state_vec().unwrap();
+        let mut dst = new_net();
+        dst.set_state_mut(&state).unwrap();
+        assert_eq!(dst.enabled_queue_pairs(), 3);
+
+        // Guest needs to set them again after the device is reset.
+        src.deactivate().unwrap();
+        assert_eq!(src.enabled_queue_pairs(), 1);
+    }
+
+    #[test]
+    fn test_net_offload_features() {
+        // Tap refuses all the offloads.
+        assert_eq!(get_offload_features(0), 0);
+        assert_eq!(get_offload_features(TUN_F_TSO4 | TUN_F_UFO), 0);
+
+        let csum_features = 1 << VIRTIO_NET_F_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS;
+        assert_eq!(get_offload_features(TUN_F_CSUM), csum_features);
+        assert_eq!(
+            get_offload_features(TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6),
+            csum_features
+                | 1 << VIRTIO_NET_F_GUEST_TSO4
+                | 1 << VIRTIO_NET_F_HOST_TSO4
+                | 1 << VIRTIO_NET_F_GUEST_TSO6
+                | 1 << VIRTIO_NET_F_HOST_TSO6
+        );
+        // ECN needs TSO.
+        assert_eq!(
+            get_offload_features(TUN_F_CSUM | TUN_F_TSO_ECN),
+            csum_features
+        );
+        let features = get_offload_features(TUN_F_VIRTIO | TUN_F_TSO_ECN);
+        for feature in [
+            VIRTIO_NET_F_GUEST_ECN,
+            VIRTIO_NET_F_GUEST_UFO,
+            VIRTIO_NET_F_HOST_UFO,
+        ] {
+            assert!(virtio_has_feature(features, feature));
+        }
+        // Tap refuses UFO.
+        let features = get_offload_features(TUN_F_CSUM | TUN_F_TSO4);
+        assert!(!virtio_has_feature(features, VIRTIO_NET_F_GUEST_UFO));
+        assert!(!virtio_has_feature(features, VIRTIO_NET_F_HOST_UFO));
+
+        // Offload features are all offered without tap.
+        let mut net = Net::default();
+        net.realize().unwrap();
+        let device_features = net.state.lock().unwrap().device_features;
+        assert_eq!(
+            device_features & get_offload_features(TUN_F_VIRTIO),
+            get_offload_features(TUN_F_VIRTIO)
+        );
+    }
+
+    #[test]
+    fn test_net_ctrl_guest_offloads() {
+        let root = Region::init_container_region(1 << 36);
+        let mem_space = AddressSpace::new(root.clone()).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x10000, None, false, false, false).unwrap(),
+        );
+        root.add_subregion(Region::init_ram_region(host_mmap), 0)
+            .unwrap();
+
+        let state = Arc::new(Mutex::new(VirtioNetState::default()));
+        let mut ctrl_info = CtrlInfo::new(state, ConfigUpdater::new("net"));
+        let addr = GuestAddress(0x1000);
+        let cmd = VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET;
+        let driver_features = 1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS
+            | 1 << VIRTIO_NET_F_GUEST_CSUM
+            | 1 << VIRTIO_NET_F_GUEST_TSO4;
+        for (offloads, ack) in [
+            (0_u64, VIRTIO_NET_OK),
+            (1 << VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_OK),
+            (
+                1 << VIRTIO_NET_F_GUEST_CSUM | 1 << VIRTIO_NET_F_GUEST_TSO4,
+                VIRTIO_NET_OK,
+            ),
+            // Not negotiated offload.
+            (1 << VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_ERR),
+            // Not an offload.
+            (1 << VIRTIO_NET_F_CTRL_GUEST_OFFLOADS, VIRTIO_NET_ERR),
+        ] {
+            mem_space.write_object(&offloads, addr).unwrap();
+            let mut data_iovec = vec![ElemIovec { addr, len: 8 }];
+            assert_eq!(
+                ctrl_info.handle_guest_offloads(
+                    &mem_space,
+                    None,
+                    driver_features,
+                    cmd,
+                    &mut data_iovec
+                ),
+                ack
+            );
+        }
+
+        assert_eq!(
+            get_guest_offloads_flags(
+                1 << VIRTIO_NET_F_GUEST_CSUM | 1 << VIRTIO_NET_F_GUEST_TSO4,
+                driver_features
+            )
+            .unwrap(),
+            TUN_F_CSUM | TUN_F_TSO4
+        );
+        // Guest offloads control is not negotiated.
+        assert!(get_guest_offloads_flags(
+            1 << VIRTIO_NET_F_GUEST_CSUM,
+            1 << VIRTIO_NET_F_GUEST_CSUM
+        )
+        .is_err());
+
+        // Missing offloads data.
+        let mut data_iovec = vec![ElemIovec { addr, len: 4 }];
+        assert_eq!(
+            ctrl_info.handle_guest_offloads(
+                &mem_space,
+                None,
+                driver_features,
+                cmd,
+                &mut data_iovec
+            ),
+            VIRTIO_NET_ERR
+        );
+        // Unknown command.
+        let mut data_iovec = vec![ElemIovec { addr, len: 8 }];
+        assert_eq!(
+            ctrl_info.handle_guest_offloads(
+                &mem_space,
+                None,
+                driver_features,
+                cmd + 1,
+                &mut data_iovec
+            ),
+            VIRTIO_NET_ERR
+        );
+    }
+
+    #[test]
+    fn test_net_rate_limiter() {
+        let mut ctx = EventLoopContext::new();
+
+        let mut limiter = NetRateLimiter::new(None, None).unwrap();
+        assert!(!limiter.is_enabled());
+        assert!(limiter.timer_fds().is_empty());
+        limiter.consume(u64::MAX);
+        assert!(!limiter.throttled(&mut ctx));
+
+        // Limit to 1000 bytes and 10 packets per second.
+        let mut limiter = NetRateLimiter::new(Some(1000), Some(10)).unwrap();
+        assert!(limiter.is_enabled());
+        assert_eq!(limiter.timer_fds().len(), 2);
+        for _ in 0..10 {
+            assert!(!limiter.throttled(&mut ctx));
+            limiter.consume(1);
+        }
+        // The packets bucket overflows.
+        limiter.consume(1);
+        assert!(limiter.throttled(&mut ctx));
+        // Keep paused until the timer expires.
+        assert!(limiter.throttled(&mut ctx));
+
+        // The bytes bucket overflows as well.
+        let mut limiter = NetRateLimiter::new(Some(1000), None).unwrap();
+        limiter.consume(1500);
+        assert!(limiter.throttled(&mut ctx));
+        limiter.clear_timer();
+        assert!(limiter.throttled(&mut ctx));
+    }
+
     #[test]
     fn test_iothread() {
         let mut net = Net::default();
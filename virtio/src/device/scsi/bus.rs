@@ -12,24 +12,36 @@
 
 use std::cmp;
 use std::collections::HashMap;
-use std::io::Write;
+use std::os::unix::fs::FileExt;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
 
 use anyhow::{anyhow, bail, Context, Result};
 
+use crate::device::scsi::reservation::{
+    PrError, PR_IN_READ_KEYS, PR_IN_READ_RESERVATION, PR_OUT_CLEAR, PR_OUT_PARAM_LEN,
+    PR_OUT_REGISTER, PR_OUT_RELEASE, PR_OUT_RESERVE,
+};
 use crate::ScsiCntlr::{
     ScsiCntlr, ScsiCompleteCb, ScsiXferMode, VirtioScsiCmdReq, VirtioScsiCmdResp,
     VirtioScsiRequest, VIRTIO_SCSI_CDB_DEFAULT_SIZE, VIRTIO_SCSI_S_OK,
 };
 use crate::ScsiDisk::{
     ScsiDevice, DEFAULT_SECTOR_SIZE, SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-    SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT, SCSI_DISK_F_DPOFUA, SCSI_DISK_F_REMOVABLE, SCSI_TYPE_DISK,
-    SCSI_TYPE_ROM, SECTOR_SHIFT,
+    SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT, SCSI_DISK_F_DPOFUA, SCSI_TYPE_DISK, SCSI_TYPE_ROM,
+    SECTOR_SHIFT,
 };
-use address_space::AddressSpace;
+use crate::{find_scsi_device, register_resizable_scsi_device, unregister_resizable_scsi_device};
+use address_space::{AddressSpace, HostRange};
 use byteorder::{BigEndian, ByteOrder};
 use log::{debug, error, info};
-use util::aio::{Aio, AioCb, Iovec, OpCode};
+use machine_manager::config::DEFAULT_SCSI_MAX_SECTORS;
+use util::aio::{iov_to_buf_direct, raw_datasync, raw_read, Aio, AioCb, Iovec, OpCode};
+use util::file::get_file_extent;
+use util::num_ops::{round_down, round_up};
+use util::unix::host_page_size;
 
 /// Scsi Operation code.
 pub const TEST_UNIT_READY: u8 = 0x00;
@@ -174,8 +186,30 @@ pub const STATUS_MASK: u8 = 0x3e;
 pub const SCSI_CMD_BUF_SIZE: usize = 16;
 pub const SCSI_SENSE_BUF_SIZE: usize = 252;
 
+/// Max time to wait for the in-flight requests of a hot unplugged scsi device.
+const SCSI_DRAIN_TIMEOUT_MS: u64 = 1000;
+
 /// SERVICE ACTION IN subcodes.
 pub const SUBCODE_READ_CAPACITY_16: u8 = 0x10;
+pub const SUBCODE_GET_LBA_STATUS: u8 = 0x12;
+/// MAINTENANCE IN subcodes.
+pub const SUBCODE_REPORT_SUPPORTED_OPCODES: u8 = 0x0c;
+
+/// Max number of LBA status descriptors returned by GET LBA STATUS.
+const GET_LBA_STATUS_MAX_DESCRIPTORS: usize = 1024;
+/// Provisioning status of LBA status descriptors.
+const LBA_STATUS_MAPPED: u8 = 0;
+const LBA_STATUS_DEALLOCATED: u8 = 1;
+
+/// Log pages supported by LOG SENSE.
+const LOG_PAGE_SUPPORTED_PAGES: u8 = 0x00;
+const LOG_PAGE_TEMPERATURE: u8 = 0x0d;
+const LOG_PAGE_INFORMATIONAL_EXCEPTIONS: u8 = 0x2f;
+/// Page control of LOG SENSE: current cumulative values.
+const LOG_PC_CUMULATIVE: u8 = 0x01;
+/// Static temperature and reference temperature reported in Celsius.
+const LOG_TEMPERATURE: u8 = 40;
+const LOG_REFERENCE_TEMPERATURE: u8 = 70;
 
 /// Sense Keys.
 pub const NO_SENSE: u8 = 0x00;
@@ -214,6 +248,7 @@ pub const SCSI_SENSE_INVALID_FIELD: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x24
 pub const SCSI_SENSE_INVALID_PARAM: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x00);
 pub const SCSI_SENSE_INVALID_PARAM_VALUE: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x01);
 pub const SCSI_SENSE_INVALID_PARAM_LEN: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x1a, 0x00);
+pub const SCSI_SENSE_INVALID_RELEASE: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x26, 0x04);
 pub const SCSI_SENSE_LUN_NOT_SUPPORTED: ScsiSense = scsisense!(ILLEGAL_REQUEST, 0x25, 0x00);
 pub const SCSI_SENSE_SAVING_PARAMS_NOT_SUPPORTED: ScsiSense =
     scsisense!(ILLEGAL_REQUEST, 0x39, 0x00);
@@ -230,6 +265,7 @@ pub const SCSI_SENSE_LUN_NOT_RESPONDING: ScsiSense = scsisense!(ABORTED_COMMAND,
 pub const SCSI_SENSE_COMMAND_TIMEOUT: ScsiSense = scsisense!(ABORTED_COMMAND, 0x2e, 0x02);
 pub const SCSI_SENSE_COMMAND_ABORTED: ScsiSense = scsisense!(ABORTED_COMMAND, 0x2f, 0x02);
 pub const SCSI_SENSE_READ_ERROR: ScsiSense = scsisense!(MEDIUM_ERROR, 0x11, 0x00);
+pub const SCSI_SENSE_WRITE_ERROR: ScsiSense = scsisense!(MEDIUM_ERROR, 0x0c, 0x00);
 pub const SCSI_SENSE_NOT_READY: ScsiSense = scsisense!(NOT_READY, 0x04, 0x00);
 pub const SCSI_SENSE_CAPACITY_CHANGED: ScsiSense = scsisense!(UNIT_ATTENTION, 0x2a, 0x09);
 pub const SCSI_SENSE_RESET: ScsiSense = scsisense!(UNIT_ATTENTION, 0x29, 0x00);
@@ -240,8 +276,9 @@ pub const SCSI_SENSE_REPORTED_LUNS_CHANGED: ScsiSense = scsisense!(UNIT_ATTENTIO
 pub const SCSI_SENSE_DEVICE_INTERNAL_RESET: ScsiSense = scsisense!(UNIT_ATTENTION, 0x29, 0x04);
 pub const SCSI_SENSE_WRITE_PROTECTED: ScsiSense = scsisense!(DATA_PROTECT, 0x27, 0x00);
 pub const SCSI_SENSE_SPACE_ALLOC_FAILED: ScsiSense = scsisense!(DATA_PROTECT, 0x27, 0x07);
+pub const SCSI_SENSE_MISCOMPARE_DURING_VERIFY: ScsiSense = scsisense!(MISCOMPARE, 0x1d, 0x00);
 
-#[derive(Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct ScsiSense {
     /// Sense key.
     pub key: u8,
@@ -264,11 +301,23 @@ pub const MODE_PAGE_CAPABILITIES: u8 = 0x2a;
 pub const MODE_PAGE_ALLS: u8 = 0x3f;
 
 pub const SCSI_MAX_INQUIRY_LEN: u32 = 256;
+/// Length of the standard INQUIRY data up to the product revision level.
+const SCSI_MIN_INQUIRY_LEN: u32 = 36;
 pub const SCSI_INQUIRY_PRODUCT_MAX_LEN: usize = 16;
 pub const SCSI_INQUIRY_VENDOR_MAX_LEN: usize = 8;
 pub const SCSI_INQUIRY_VERSION_MAX_LEN: usize = 4;
 pub const SCSI_INQUIRY_VPD_SERIAL_NUMBER_MAX_LEN: usize = 32;
 
+/// Code sets of designation descriptors in the Device Identification VPD page.
+const DESIGNATOR_CODE_SET_BINARY: u8 = 0x1;
+const DESIGNATOR_CODE_SET_ASCII: u8 = 0x2;
+/// Designator types of designation descriptors in the Device Identification VPD page.
+const DESIGNATOR_TYPE_VENDOR_SPECIFIC: u8 = 0x0;
+const DESIGNATOR_TYPE_T10_VENDOR_ID: u8 = 0x1;
+const DESIGNATOR_TYPE_NAA: u8 = 0x3;
+/// NAA field of the NAA IEEE Registered Extended designator.
+const NAA_IEEE_REGISTERED_EXTENDED: u64 = 0x6;
+
 const SCSI_TARGET_INQUIRY_LEN: u32 = 36;
 
 /// |     bit7 - bit 5     |     bit 4 - bit 0      |
@@ -363,6 +412,8 @@ pub struct ScsiBus {
     pub devices: HashMap<(u8, u16), Arc<Mutex<ScsiDevice>>>,
     /// Scsi Controller which the bus orignates from.
     pub parent_cntlr: Weak<Mutex<ScsiCntlr>>,
+    /// Max sectors of 512 bytes transferred by a command, set by the controller.
+    pub max_sectors: u32,
 }
 
 impl ScsiBus {
@@ -371,6 +422,7 @@ impl ScsiBus {
             name: bus_name,
             devices: HashMap::new(),
             parent_cntlr,
+            max_sectors: DEFAULT_SCSI_MAX_SECTORS,
         }
     }
 
@@ -439,15 +491,155 @@ impl ScsiBus {
             mode: scsi_cdb_xfer_mode(&cdb),
         })
     }
+
+    /// Establish a REPORTED LUNS DATA HAS CHANGED unit attention on the luns of `target`
+    /// other than `lun`, after `lun` is hot plugged or unplugged.
+    fn report_luns_changed(&self, target: u8, lun: u16) {
+        for ((target_id, lun_id), device) in self.devices.iter() {
+            if *target_id == target && *lun_id != lun {
+                device.lock().unwrap().unit_attention = Some(SCSI_SENSE_REPORTED_LUNS_CHANGED);
+            }
+        }
+    }
+
+    /// Find the scsi device by its id.
+    pub fn find_device_by_id(&self, id: &str) -> Option<Arc<Mutex<ScsiDevice>>> {
+        self.devices
+            .values()
+            .find(|dev| dev.lock().unwrap().config.id == id)
+            .cloned()
+    }
 }
 
 pub fn create_scsi_bus(bus_name: &str, scsi_cntlr: &Arc<Mutex<ScsiCntlr>>) -> Result<()> {
     let mut locked_scsi_cntlr = scsi_cntlr.lock().unwrap();
-    let bus = ScsiBus::new(bus_name.to_string(), Arc::downgrade(scsi_cntlr));
+    let mut bus = ScsiBus::new(bus_name.to_string(), Arc::downgrade(scsi_cntlr));
+    bus.max_sectors = locked_scsi_cntlr.config.max_sectors;
     locked_scsi_cntlr.bus = Some(Arc::new(Mutex::new(bus)));
     Ok(())
 }
 
+/// Hot plug the scsi device `dev` to `bus` at the address of its config.
+///
+/// The other luns of the target get a unit attention, and a rescan event is sent by the
+/// event queue of the controller if the guest negotiated VIRTIO_SCSI_F_HOTPLUG.
+pub fn scsi_bus_attach_device(
+    bus: &Arc<Mutex<ScsiBus>>,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<()> {
+    let mut locked_dev = dev.lock().unwrap();
+    let (target, lun) = (locked_dev.config.target, locked_dev.config.lun);
+    let mut locked_bus = bus.lock().unwrap();
+    if locked_bus.devices.contains_key(&(target, lun)) {
+        bail!(
+            "Scsi device with scsi-id {} and lun {} already exists on bus {}",
+            target,
+            lun,
+            locked_bus.name
+        );
+    }
+    locked_dev.parent_bus = Arc::downgrade(bus);
+    locked_dev.max_sectors = locked_bus.max_sectors;
+    drop(locked_dev);
+    locked_bus.devices.insert((target, lun), dev.clone());
+    locked_bus.report_luns_changed(target, lun);
+    let cntlr = locked_bus.parent_cntlr.upgrade();
+    drop(locked_bus);
+
+    if let Some(cntlr) = cntlr {
+        cntlr.lock().unwrap().notify_hotplug(target, lun, true);
+    }
+    register_resizable_scsi_device(dev);
+    Ok(())
+}
+
+/// Hot unplug the scsi device at `target` and `lun` from `bus` after its in-flight requests
+/// are completed. The guest is notified in the same way as `scsi_bus_attach_device`.
+pub fn scsi_bus_detach_device(
+    bus: &Arc<Mutex<ScsiBus>>,
+    target: u8,
+    lun: u16,
+) -> Result<Arc<Mutex<ScsiDevice>>> {
+    let dev = bus
+        .lock()
+        .unwrap()
+        .devices
+        .remove(&(target, lun))
+        .with_context(|| format!("No scsi device with scsi-id {} and lun {}", target, lun))?;
+
+    // New requests to the lun are responded with VIRTIO_SCSI_S_BAD_TARGET once it's removed
+    // from the bus, so only the submitted requests need to be waited for.
+    let inflight = dev.lock().unwrap().inflight.clone();
+    let start = Instant::now();
+    while inflight.load(Ordering::SeqCst) != 0 {
+        if start.elapsed() > Duration::from_millis(SCSI_DRAIN_TIMEOUT_MS) {
+            bus.lock().unwrap().devices.insert((target, lun), dev);
+            bail!(
+                "Failed to drain {} in-flight requests of scsi device",
+                inflight.load(Ordering::SeqCst)
+            );
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    let locked_bus = bus.lock().unwrap();
+    locked_bus.report_luns_changed(target, lun);
+    let cntlr = locked_bus.parent_cntlr.upgrade();
+    drop(locked_bus);
+
+    if let Some(cntlr) = cntlr {
+        cntlr.lock().unwrap().notify_hotplug(target, lun, false);
+    }
+    unregister_resizable_scsi_device(&dev);
+    Ok(dev)
+}
+
+/// Establish a CAPACITY DATA HAS CHANGED unit attention on the scsi device after its capacity
+/// is changed, and send a parameter change event by the event queue of the controller if the
+/// guest negotiated VIRTIO_SCSI_F_CHANGE.
+pub fn scsi_device_capacity_changed(dev: &Arc<Mutex<ScsiDevice>>) {
+    let mut locked_dev = dev.lock().unwrap();
+    locked_dev.unit_attention = Some(SCSI_SENSE_CAPACITY_CHANGED);
+    let (target, lun) = (locked_dev.config.target, locked_dev.config.lun);
+    let bus = locked_dev.parent_bus.upgrade();
+    drop(locked_dev);
+
+    let cntlr = bus.and_then(|bus| bus.lock().unwrap().parent_cntlr.upgrade());
+    if let Some(cntlr) = cntlr {
+        cntlr
+            .lock()
+            .unwrap()
+            .notify_param_change(target, lun, SCSI_SENSE_CAPACITY_CHANGED);
+    }
+}
+
+/// Eject the medium of the removable scsi device `device`, for `eject`. The removal
+/// prevented by the guest is overridden if `force` is set.
+pub fn qmp_eject(device: &str, force: bool) -> Result<()> {
+    let dev = find_scsi_device(device)
+        .with_context(|| format!("Removable device {} is not found", device))?;
+    dev.lock().unwrap().eject(force)?;
+    info!("Medium of device {} is ejected", device);
+    Ok(())
+}
+
+/// Reference of a request to the in-flight counter of its scsi device, which is shared by
+/// all copies of the request and released when the request is completed.
+struct InflightRef(Arc<AtomicU64>);
+
+impl InflightRef {
+    fn new(counter: Arc<AtomicU64>) -> Self {
+        counter.fetch_add(1, Ordering::SeqCst);
+        InflightRef(counter)
+    }
+}
+
+impl Drop for InflightRef {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
 #[derive(Clone)]
 pub struct ScsiCommand {
     /// The Command Descriptor Block(CDB).
@@ -472,7 +664,8 @@ pub struct ScsiRequest {
     _resid: u32,
     pub opstype: u32,
     pub virtioscsireq: Arc<Mutex<VirtioScsiRequest<VirtioScsiCmdReq, VirtioScsiCmdResp>>>,
-    dev: Arc<Mutex<ScsiDevice>>,
+    pub dev: Arc<Mutex<ScsiDevice>>,
+    _inflight: Arc<InflightRef>,
 }
 
 impl ScsiRequest {
@@ -481,9 +674,7 @@ impl ScsiRequest {
         scsibus: Arc<Mutex<ScsiBus>>,
         scsidevice: Arc<Mutex<ScsiDevice>>,
     ) -> Result<Self> {
-        let req_lock = req.lock().unwrap();
-        let cdb = req_lock.req.cdb;
-        let req_size = req_lock.data_len;
+        let cdb = req.lock().unwrap().req.cdb;
 
         if let Some(cmd) = scsibus
             .lock()
@@ -493,33 +684,7 @@ impl ScsiRequest {
             let ops = cmd.command;
             let opstype = scsi_operation_type(ops);
             let _resid = cmd.xfer;
-
-            if ops == WRITE_10 || ops == READ_10 {
-                let dev_lock = scsidevice.lock().unwrap();
-                let disk_size = dev_lock.disk_sectors << SECTOR_SHIFT;
-                let disk_type = dev_lock.scsi_type;
-                drop(dev_lock);
-                let offset_shift = match disk_type {
-                    SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-                    _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
-                };
-                let offset = if let Some(off) = cmd.lba.checked_shl(offset_shift) {
-                    off
-                } else {
-                    bail!("Too large offset IO!");
-                };
-
-                if offset
-                    .checked_add(req_size as u64)
-                    .filter(|&off| off <= disk_size)
-                    .is_none()
-                {
-                    bail!(
-                        "Error CDB! ops {}, read/write length {} from {} is larger than disk size {}",
-                        ops, req_size, offset, disk_size,
-                    );
-                }
-            }
+            let inflight = InflightRef::new(scsidevice.lock().unwrap().inflight.clone());
 
             Ok(ScsiRequest {
                 cmd,
@@ -529,6 +694,7 @@ impl ScsiRequest {
                 opstype,
                 virtioscsireq: req.clone(),
                 dev: scsidevice,
+                _inflight: Arc::new(inflight),
             })
         } else {
             bail!("Error CDB!");
@@ -540,28 +706,40 @@ impl ScsiRequest {
         aio: &mut Box<Aio<ScsiCompleteCb>>,
         mut aiocb: AioCb<ScsiCompleteCb>,
     ) -> Result<u32> {
-        let dev_lock = self.dev.lock().unwrap();
-        let offset = match dev_lock.scsi_type {
-            SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
-            _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
+        // Don't hold the device lock, the request may be completed synchronously.
+        let (offset, write_threshold) = {
+            let dev = self.dev.lock().unwrap();
+            let offset = match dev.scsi_type {
+                SCSI_TYPE_DISK => SCSI_DISK_DEFAULT_BLOCK_SIZE_SHIFT,
+                _ => SCSI_CDROM_DEFAULT_BLOCK_SIZE_SHIFT,
+            };
+            (offset, dev.write_threshold.clone())
         };
         aiocb.offset = (self.cmd.lba << offset) as usize;
 
+        if matches!(self.cmd.command, SYNCHRONIZE_CACHE | SYNCHRONIZE_CACHE_16) {
+            // The aio layer holds the flush until the writes submitted before it complete.
+            aiocb.opcode = OpCode::Fdsync;
+            aio.submit_request(aiocb)
+                .with_context(|| "Failed to process scsi request for flushing")?;
+            return Ok(0);
+        }
+
+        // Only transfer the length in CDB, the rest of data buffer is reported as resid.
+        // The data buffer has been checked to be no less than it in `check_rw_request`.
+        let xfer = self.cmd.xfer as u64;
         for iov in self.virtioscsireq.lock().unwrap().iovec.iter() {
+            if aiocb.nbytes == xfer {
+                break;
+            }
+            let iov_len = cmp::min(iov.iov_len, xfer - aiocb.nbytes);
             let iovec = Iovec {
                 iov_base: iov.iov_base,
-                iov_len: iov.iov_len,
+                iov_len,
             };
             aiocb.iovec.push(iovec);
             // Note: total len of each req is no more than DESC_CHAIN_MAX_TOTAL_LEN (1 << 32).
-            aiocb.nbytes += iov.iov_len;
-        }
-
-        if self.cmd.command == SYNCHRONIZE_CACHE {
-            aiocb.opcode = OpCode::Fdsync;
-            aio.submit_request(aiocb)
-                .with_context(|| "Failed to process scsi request for flushing")?;
-            return Ok(0);
+            aiocb.nbytes += iov_len;
         }
 
         match self.cmd.mode {
@@ -572,6 +750,19 @@ impl ScsiRequest {
             }
             ScsiXferMode::ScsiXferToDev => {
                 aiocb.opcode = OpCode::Pwritev;
+                match write_verify_bytchk(&self.cmd) {
+                    Some(0) => aiocb.iocompletecb.set_write_verify(WriteVerify::Sync),
+                    Some(_) => {
+                        // Buffer the payload, the guest may change it before the comparison.
+                        let mut data = vec![0_u8; aiocb.nbytes as usize];
+                        iov_to_buf_direct(&aiocb.iovec, &mut data)?;
+                        aiocb
+                            .iocompletecb
+                            .set_write_verify(WriteVerify::Compare(Arc::new(data)));
+                    }
+                    None => {}
+                }
+                write_threshold.update(aiocb.offset as u64, aiocb.nbytes);
                 aio.submit_request(aiocb)
                     .with_context(|| "Failed to process block request for writing")?;
             }
@@ -582,6 +773,102 @@ impl ScsiRequest {
         Ok(0)
     }
 
+    /// Validate READ/WRITE commands before they are submitted to the backend.
+    ///
+    /// Return true if the request should be submitted. Otherwise the request has been
+    /// completed, because it's invalid or has nothing to transfer.
+    pub fn check_rw_request(&self, mem_space: &Arc<AddressSpace>) -> Result<bool> {
+        if !matches!(
+            self.cmd.command,
+            READ_6
+                | READ_10
+                | READ_12
+                | READ_16
+                | WRITE_6
+                | WRITE_10
+                | WRITE_12
+                | WRITE_16
+                | WRITE_VERIFY_10
+                | WRITE_VERIFY_12
+                | WRITE_VERIFY_16
+        ) {
+            return Ok(true);
+        }
+
+        let mut req = self.virtioscsireq.lock().unwrap();
+        let medium_sense = self.dev.lock().unwrap().check_medium();
+        if let Some(sense) = medium_sense.or_else(|| scsi_check_write_protect(&self.cmd, &self.dev))
+        {
+            debug!(
+                "Scsi command {:#x} is rejected by the device without medium or read-only device",
+                self.cmd.command
+            );
+            req.resp.response = VIRTIO_SCSI_S_OK;
+            req.resp.set_scsi_sense(sense);
+            req.resp.status = CHECK_CONDITION;
+            req.resp.resid = req.data_len;
+            req.complete(mem_space)?;
+            return Ok(false);
+        }
+
+        let write = matches!(self.cmd.mode, ScsiXferMode::ScsiXferToDev);
+        if self
+            .dev
+            .lock()
+            .unwrap()
+            .reservation
+            .check_access(req.req.initiator(), write)
+            .is_err()
+        {
+            debug!(
+                "Scsi command {:#x} conflicts with the persistent reservation",
+                self.cmd.command
+            );
+            req.resp.response = VIRTIO_SCSI_S_OK;
+            req.resp.status = RESERVATION_CONFLICT;
+            req.resp.resid = req.data_len;
+            req.complete(mem_space)?;
+            return Ok(false);
+        }
+
+        let sense = scsi_check_rw_range(&self.cmd, &self.dev, req.data_len as u64);
+        if sense.is_none() && self.cmd.xfer != 0 {
+            return Ok(true);
+        }
+
+        req.resp.response = VIRTIO_SCSI_S_OK;
+        match sense {
+            Some(sense) => {
+                debug!(
+                    "Invalid scsi command {:#x}, lba {}, xfer {}, data len {}",
+                    self.cmd.command, self.cmd.lba, self.cmd.xfer, req.data_len
+                );
+                req.resp.set_scsi_sense(sense);
+                req.resp.status = CHECK_CONDITION;
+            }
+            None => req.resp.status = GOOD,
+        }
+        // Nothing is transferred.
+        req.resp.resid = req.data_len;
+        req.complete(mem_space)?;
+        Ok(false)
+    }
+
+    /// Take the pending unit attention condition of the device if the request should be
+    /// terminated by it. INQUIRY and REPORT LUNS are not affected by unit attention, and
+    /// REQUEST SENSE reports it as the sense data.
+    pub fn take_unit_attention(&self, req_lun_id: u16) -> Option<ScsiSense> {
+        if matches!(self.cmd.command, INQUIRY | REPORT_LUNS | REQUEST_SENSE) {
+            return None;
+        }
+        let mut locked_dev = self.dev.lock().unwrap();
+        // Target request is not addressed to the lun of the device.
+        if locked_dev.config.lun != req_lun_id {
+            return None;
+        }
+        locked_dev.unit_attention.take()
+    }
+
     pub fn emulate_execute(
         &self,
         iocompletecb: ScsiCompleteCb,
@@ -591,6 +878,8 @@ impl ScsiRequest {
         debug!("scsi command is {:#x}", self.cmd.command);
         let mut not_supported_flag = false;
         let mut sense = None;
+        let mut sense_info = None;
+        let mut status = GOOD;
 
         // Requested lun id is not equal to found device id means it may be a target request.
         // REPORT LUNS is also a target request command.
@@ -612,49 +901,135 @@ impl ScsiRequest {
                     Err(anyhow!("Invalid emulation target scsi command"))
                 }
             }
+        } else if let Some(op_sense) =
+            scsi_check_opcode(&self.cmd, self.dev.lock().unwrap().scsi_type)
+        {
+            info!(
+                "emulation scsi command {:#x} is not supported",
+                self.cmd.command
+            );
+            status = CHECK_CONDITION;
+            sense = Some(op_sense);
+            Ok(Vec::new())
+        } else if let Some(wp_sense) = scsi_check_write_protect(&self.cmd, &self.dev) {
+            status = CHECK_CONDITION;
+            sense = Some(wp_sense);
+            Ok(Vec::new())
         } else {
             // It's not a target request.
             match self.cmd.command {
                 REQUEST_SENSE => {
-                    sense = Some(SCSI_SENSE_NO_SENSE);
+                    let unit_attention = self.dev.lock().unwrap().unit_attention.take();
+                    sense = Some(unit_attention.unwrap_or(SCSI_SENSE_NO_SENSE));
                     Ok(Vec::new())
                 }
                 TEST_UNIT_READY => {
                     let dev_lock = self.dev.lock().unwrap();
-                    if dev_lock.disk_image.is_none() {
+                    if let Some(medium_sense) = dev_lock.check_medium() {
+                        status = CHECK_CONDITION;
+                        sense = Some(medium_sense);
+                        Ok(Vec::new())
+                    } else if dev_lock.disk_image.is_none() {
                         Err(anyhow!("No scsi backend!"))
                     } else {
                         Ok(Vec::new())
                     }
                 }
-                INQUIRY => scsi_command_emulate_inquiry(&self.cmd, &self.dev),
-                READ_CAPACITY_10 => scsi_command_emulate_read_capacity_10(&self.cmd, &self.dev),
-                MODE_SENSE | MODE_SENSE_10 => scsi_command_emulate_mode_sense(&self.cmd, &self.dev),
-                SERVICE_ACTION_IN_16 => {
-                    scsi_command_emulate_service_action_in_16(&self.cmd, &self.dev)
+                START_STOP => {
+                    // Byte4: bit0: START, bit1: LOEJ(load eject).
+                    let start = self.cmd.buf[4] & 0x1 != 0;
+                    let loej = self.cmd.buf[4] & 0x2 != 0;
+                    if let Some(ss_sense) = self.dev.lock().unwrap().start_stop(start, loej) {
+                        status = CHECK_CONDITION;
+                        sense = Some(ss_sense);
+                    }
+                    Ok(Vec::new())
+                }
+                ALLOW_MEDIUM_REMOVAL => {
+                    // Byte4: bits[0-1]: PREVENT. Removal of a non-removable medium is
+                    // always prevented.
+                    let mut dev_lock = self.dev.lock().unwrap();
+                    if dev_lock.is_removable() {
+                        dev_lock.prevent_removal = self.cmd.buf[4] & 0x3 != 0;
+                    }
+                    Ok(Vec::new())
                 }
-                READ_DISC_INFORMATION => {
-                    scsi_command_emulate_read_disc_information(&self.cmd, &self.dev)
+                VERIFY_10 | VERIFY_12 | VERIFY_16 => {
+                    let iovec = self.virtioscsireq.lock().unwrap().iovec.clone();
+                    if let Err(PrError::Conflict) = self
+                        .dev
+                        .lock()
+                        .unwrap()
+                        .reservation
+                        .check_access(self.initiator(), false)
+                    {
+                        status = RESERVATION_CONFLICT;
+                        Ok(Vec::new())
+                    } else {
+                        scsi_command_emulate_verify(&self.cmd, &self.dev, &iovec).map(|check| {
+                            if let Some((verify_sense, info)) = check {
+                                status = CHECK_CONDITION;
+                                sense = Some(verify_sense);
+                                sense_info = info;
+                            }
+                            Vec::new()
+                        })
+                    }
                 }
-                GET_EVENT_STATUS_NOTIFICATION => {
-                    scsi_command_emulate_get_event_status_notification(&self.cmd, &self.dev)
+                PERSISTENT_RESERVE_IN | PERSISTENT_RESERVE_OUT => {
+                    let result = if self.cmd.command == PERSISTENT_RESERVE_IN {
+                        scsi_command_emulate_persistent_reserve_in(&self.cmd, &self.dev)
+                    } else {
+                        let iovec = self.virtioscsireq.lock().unwrap().iovec.clone();
+                        scsi_command_emulate_persistent_reserve_out(
+                            &self.cmd,
+                            &self.dev,
+                            self.initiator(),
+                            &iovec,
+                        )
+                    };
+                    result.map(|ret| match ret {
+                        Ok(outbuf) => outbuf,
+                        Err(PrError::Conflict) => {
+                            status = RESERVATION_CONFLICT;
+                            Vec::new()
+                        }
+                        Err(PrError::Sense(pr_sense)) => {
+                            status = CHECK_CONDITION;
+                            sense = Some(pr_sense);
+                            Vec::new()
+                        }
+                    })
                 }
-                READ_TOC => scsi_command_emulate_read_toc(&self.cmd, &self.dev),
-                GET_CONFIGURATION => scsi_command_emulate_get_configuration(&self.cmd, &self.dev),
-                _ => {
-                    not_supported_flag = true;
-                    Err(anyhow!("Emulation scsi command is not supported now!"))
+                SERVICE_ACTION_IN_16 if self.cmd.buf[1] & 0x1f == SUBCODE_GET_LBA_STATUS => {
+                    scsi_command_emulate_get_lba_status(&self.cmd, &self.dev).map(|ret| {
+                        ret.unwrap_or_else(|lba_sense| {
+                            status = CHECK_CONDITION;
+                            sense = Some(lba_sense);
+                            Vec::new()
+                        })
+                    })
                 }
+                _ => match scsi_command_emulate_data_in(&self.cmd, &self.dev) {
+                    Some(result) => result,
+                    None => {
+                        not_supported_flag = true;
+                        Err(anyhow!("Emulation scsi command is not supported now!"))
+                    }
+                },
             }
         };
 
         match result {
-            Ok(outbuf) => {
+            Ok(mut outbuf) => {
+                // Data beyond the allocation length is not transferred.
+                outbuf.truncate(self.cmd.xfer as usize);
                 self.cmd_complete(
                     &iocompletecb.mem_space,
                     VIRTIO_SCSI_S_OK,
-                    GOOD,
+                    status,
                     sense,
+                    sense_info,
                     &outbuf,
                 )?;
             }
@@ -669,6 +1044,7 @@ impl ScsiRequest {
                         VIRTIO_SCSI_S_OK,
                         CHECK_CONDITION,
                         Some(SCSI_SENSE_INVALID_OPCODE),
+                        None,
                         &Vec::new(),
                     )?;
                 } else {
@@ -681,6 +1057,7 @@ impl ScsiRequest {
                         VIRTIO_SCSI_S_OK,
                         CHECK_CONDITION,
                         Some(SCSI_SENSE_INVALID_FIELD),
+                        None,
                         &Vec::new(),
                     )?;
                 }
@@ -690,55 +1067,477 @@ impl ScsiRequest {
         Ok(())
     }
 
+    /// Get the initiator of the request.
+    fn initiator(&self) -> u64 {
+        self.virtioscsireq.lock().unwrap().req.initiator()
+    }
+
     fn cmd_complete(
         &self,
         mem_space: &Arc<AddressSpace>,
         response: u8,
         status: u8,
         scsisense: Option<ScsiSense>,
+        sense_info: Option<u32>,
         outbuf: &[u8],
     ) -> Result<()> {
         let mut req = self.virtioscsireq.lock().unwrap();
 
         if let Some(sense) = scsisense {
             req.resp.set_scsi_sense(sense);
+            if let Some(info) = sense_info {
+                req.resp.set_scsi_sense_info(info);
+            }
         }
         req.resp.response = response;
         req.resp.status = status;
-        req.resp.resid = 0;
-
-        if !outbuf.is_empty() {
-            for (idx, iov) in req.iovec.iter().enumerate() {
-                if outbuf.len() as u64 > iov.iov_len {
+        req.resp.resid = match self.cmd.mode {
+            // The parameter list of data-out commands is consumed as a whole.
+            ScsiXferMode::ScsiXferToDev => 0,
+            _ => {
+                if outbuf.len() > req.data_len as usize {
                     debug!(
-                        "cmd is {:x}, outbuf len is {}, iov_len is {}, idx is {}, iovec size is {}",
+                        "cmd is {:x}, outbuf len is {}, data len is {}, iovec size is {}",
                         self.cmd.command,
                         outbuf.len(),
-                        iov.iov_len,
-                        idx,
+                        req.data_len,
                         req.iovec.len()
                     );
                 }
-
-                write_buf_mem(outbuf, iov.iov_len, iov.iov_base)
-                    .with_context(|| "Failed to write buf for virtio scsi iov")?;
+                scsi_data_in_resid(mem_space, &req.data_ranges, req.data_len, outbuf)
+                    .with_context(|| "Failed to write buf for virtio scsi iov")?
             }
-        }
+        };
 
         req.complete(mem_space)?;
         Ok(())
     }
 }
 
-fn write_buf_mem(buf: &[u8], max: u64, hva: u64) -> Result<()> {
-    let mut slice = unsafe {
-        std::slice::from_raw_parts_mut(hva as *mut u8, cmp::min(buf.len(), max as usize))
+/// Emulate the commands which only return data to the guest, regardless of the data
+/// buffer of the request. Return None if `cmd` is not one of them.
+fn scsi_command_emulate_data_in(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Option<Result<Vec<u8>>> {
+    let result = match cmd.command {
+        INQUIRY => scsi_command_emulate_inquiry(cmd, dev),
+        READ_CAPACITY_10 => scsi_command_emulate_read_capacity_10(cmd, dev),
+        MODE_SENSE | MODE_SENSE_10 => scsi_command_emulate_mode_sense(cmd, dev),
+        SERVICE_ACTION_IN_16 => scsi_command_emulate_service_action_in_16(cmd, dev),
+        READ_DISC_INFORMATION => scsi_command_emulate_read_disc_information(cmd, dev),
+        GET_EVENT_STATUS_NOTIFICATION => {
+            scsi_command_emulate_get_event_status_notification(cmd, dev)
+        }
+        READ_TOC => scsi_command_emulate_read_toc(cmd, dev),
+        GET_CONFIGURATION => scsi_command_emulate_get_configuration(cmd, dev),
+        MAINTENANCE_IN => scsi_command_emulate_maintenance_in(cmd, dev),
+        LOG_SENSE => scsi_command_emulate_log_sense(cmd),
+        READ_DEFECT_DATA | READ_DEFECT_DATA_12 => scsi_command_emulate_read_defect_data(cmd),
+        _ => return None,
     };
-    (&mut slice)
-        .write(buf)
-        .with_context(|| format!("Failed to write buf(hva:{})", hva))?;
+    Some(result)
+}
 
-    Ok(())
+/// Parse `cdb` and emulate it on `dev` as a request to `lun`, in the same way as
+/// `ScsiRequest::emulate_execute` but without a request from the guest. Only the commands
+/// which return data regardless of the data buffer are emulated, otherwise None is returned.
+///
+/// It's the entry of the scsi cdb fuzz target.
+pub fn scsi_emulate_cdb(
+    cdb: [u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE],
+    dev: &Arc<Mutex<ScsiDevice>>,
+    lun: u16,
+) -> Option<Result<Vec<u8>>> {
+    let bus = ScsiBus::new(String::new(), Weak::new());
+    let cmd = bus.scsi_bus_parse_req_cdb(cdb, dev.clone())?;
+    let found_lun = dev.lock().unwrap().config.lun;
+    let result = if lun != found_lun || cmd.command == REPORT_LUNS {
+        match cmd.command {
+            REPORT_LUNS => scsi_command_emulate_report_luns(&cmd, dev),
+            INQUIRY => scsi_command_emulate_target_inquiry(lun, &cmd),
+            _ => return None,
+        }
+    } else {
+        let scsi_type = dev.lock().unwrap().scsi_type;
+        if scsi_check_opcode(&cmd, scsi_type).is_some() {
+            return None;
+        }
+        scsi_command_emulate_data_in(&cmd, dev)?
+    };
+    Some(result.map(|mut outbuf| {
+        outbuf.truncate(cmd.xfer as usize);
+        outbuf
+    }))
+}
+
+/// Check whether the command modifies the medium of a read-only device.
+///
+/// Return DATA PROTECT sense if the command must be rejected before any IO is issued.
+fn scsi_check_write_protect(cmd: &ScsiCommand, dev: &Arc<Mutex<ScsiDevice>>) -> Option<ScsiSense> {
+    let write = matches!(
+        cmd.command,
+        WRITE_6
+            | WRITE_10
+            | WRITE_12
+            | WRITE_16
+            | WRITE_VERIFY_10
+            | WRITE_VERIFY_12
+            | WRITE_VERIFY_16
+            | WRITE_SAME_10
+            | WRITE_SAME_16
+            | WRITE_LONG_10
+            | WRITE_LONG_16
+            | UNMAP
+            | FORMAT_UNIT
+    );
+    let dev = dev.lock().unwrap();
+    if write && (dev.config.read_only || dev.io_error.is_write_protected()) {
+        return Some(SCSI_SENSE_WRITE_PROTECTED);
+    }
+    None
+}
+
+/// Check the LBA range and the data buffer of READ/WRITE commands.
+///
+/// Return the sense which should be reported to the guest if the command is invalid.
+fn scsi_check_rw_range(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+    data_len: u64,
+) -> Option<ScsiSense> {
+    let dev_lock = dev.lock().unwrap();
+    let block_size = dev_lock.block_size as u64;
+    let disk_blocks = dev_lock.disk_sectors / (block_size / DEFAULT_SECTOR_SIZE as u64);
+    let write_verify_max = dev_lock.config.write_verify_max;
+    drop(dev_lock);
+
+    // `xfer` is in bytes, and transfer length 0 of READ_6/WRITE_6 has been converted to
+    // 256 blocks.
+    let nb_blocks = cmd.xfer as u64 / block_size;
+    if cmd
+        .lba
+        .checked_add(nb_blocks)
+        .filter(|&end| end <= disk_blocks)
+        .is_none()
+    {
+        return Some(SCSI_SENSE_LBA_OUT_OF_RANGE);
+    }
+    if data_len < cmd.xfer as u64 {
+        return Some(SCSI_SENSE_INVALID_FIELD);
+    }
+    match write_verify_bytchk(cmd) {
+        // The data-out buffer to be compared is kept in memory until the write completes.
+        Some(1) if cmd.xfer as u64 > write_verify_max => Some(SCSI_SENSE_INVALID_FIELD),
+        Some(bytchk) if bytchk > 1 => Some(SCSI_SENSE_INVALID_FIELD),
+        _ => None,
+    }
+}
+
+/// Get the BYTCHK of WRITE AND VERIFY(10/12/16), None for other commands.
+fn write_verify_bytchk(cmd: &ScsiCommand) -> Option<u8> {
+    match cmd.command {
+        // Byte1: bits[1-2]: BYTCHK.
+        // 00b: The written data is not compared.
+        // 01b: The written data is compared with the data-out buffer.
+        // 11b: Not supported.
+        WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16 => Some((cmd.buf[1] >> 1) & 0x3),
+        _ => None,
+    }
+}
+
+/// The verification of WRITE AND VERIFY after the data is written.
+#[derive(Clone)]
+pub enum WriteVerify {
+    /// BYTCHK=0: the written data is synced to the medium.
+    Sync,
+    /// BYTCHK=1: the written data is synced, read back and compared with the data-out
+    /// buffer, which is copied before the write is submitted.
+    Compare(Arc<Vec<u8>>),
+}
+
+/// Verify the data written by WRITE AND VERIFY at `offset` of the backend `fd`.
+///
+/// Return None if the verification succeeds. Otherwise, return the sense which should be
+/// reported to the guest together with the value of the sense INFORMATION field, if any.
+///
+/// # Arguments
+///
+/// * `req_align` - Alignment of the offset and length of reads, for `O_DIRECT` backends.
+pub fn scsi_write_verify(
+    fd: RawFd,
+    offset: u64,
+    req_align: u32,
+    verify: &WriteVerify,
+) -> Option<(ScsiSense, Option<u32>)> {
+    if raw_datasync(fd) < 0 {
+        return Some((SCSI_SENSE_WRITE_ERROR, None));
+    }
+    let data = match verify {
+        WriteVerify::Sync => return None,
+        WriteVerify::Compare(data) => data,
+    };
+
+    let written = match scsi_read_back(fd, offset, data.len() as u64, req_align) {
+        Ok(buf) => buf,
+        Err(e) => {
+            error!("Failed to read back for WRITE AND VERIFY command: {:?}", e);
+            return Some((SCSI_SENSE_READ_ERROR, None));
+        }
+    };
+    written
+        .iter()
+        .zip(data.iter())
+        .position(|(disk, data)| disk != data)
+        .map(|idx| {
+            (
+                SCSI_SENSE_MISCOMPARE_DURING_VERIFY,
+                Some(cmp::min(idx, u32::MAX as usize) as u32),
+            )
+        })
+}
+
+/// Read `len` bytes at `offset` of the backend `fd`. The read is extended to `req_align`
+/// and done with an aligned buffer, so that it works with `O_DIRECT`.
+fn scsi_read_back(fd: RawFd, offset: u64, len: u64, req_align: u32) -> Result<Vec<u8>> {
+    let align = cmp::max(req_align, 1) as u64;
+    let start = round_down(offset, align).with_context(|| "Failed to align read back offset")?;
+    let end = offset
+        .checked_add(len)
+        .and_then(|end| round_up(end, align))
+        .with_context(|| "Failed to align read back length")?;
+    let size = (end - start) as usize;
+    // SAFETY: the aligned memory is allocated here and freed below.
+    let buf = unsafe { libc::memalign(host_page_size() as usize, size) };
+    if buf.is_null() {
+        bail!("Failed to alloc {} bytes for read back", size);
+    }
+    let ret = raw_read(fd, buf as u64, size, start as usize);
+    let result = if ret < 0 || (ret as u64) < offset + len - start {
+        Err(anyhow!(
+            "Failed to read {} bytes at {}, ret {}",
+            size,
+            start,
+            ret
+        ))
+    } else {
+        // SAFETY: the memory is allocated by us and `size` bytes of it are read.
+        let data = unsafe {
+            std::slice::from_raw_parts((buf as u64 + offset - start) as *const u8, len as usize)
+        };
+        Ok(data.to_vec())
+    };
+    // SAFETY: the memory is allocated by us and will not be used anymore.
+    unsafe { libc::free(buf) };
+    result
+}
+
+/// Emulate PERSISTENT RESERVE IN.
+///
+/// The inner result is the parameter data, or the failure which should be reported to the guest.
+fn scsi_command_emulate_persistent_reserve_in(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<std::result::Result<Vec<u8>, PrError>> {
+    // Byte1: bits[0-4]: service action.
+    let service_action = cmd.buf[1] & 0x1f;
+    let ret = dev.lock().unwrap().reservation.reserve_in(service_action);
+    Ok(ret.map(|mut outbuf| {
+        // Bytes[7-8]: allocation length.
+        outbuf.truncate(cmd.xfer as usize);
+        outbuf
+    }))
+}
+
+/// Emulate PERSISTENT RESERVE OUT.
+///
+/// The inner result is the failure which should be reported to the guest, if any.
+fn scsi_command_emulate_persistent_reserve_out(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+    initiator: u64,
+    iovec: &[Iovec],
+) -> Result<std::result::Result<Vec<u8>, PrError>> {
+    // Byte1: bits[0-4]: service action. Byte2: bits[4-7]: scope, bits[0-3]: type.
+    let service_action = cmd.buf[1] & 0x1f;
+    let scope_type = cmd.buf[2];
+    // Bytes[5-8]: parameter list length.
+    let param_len = cmd.xfer as usize;
+    if param_len != PR_OUT_PARAM_LEN {
+        return Ok(Err(PrError::Sense(SCSI_SENSE_INVALID_PARAM_LEN)));
+    }
+    let mut param = [0_u8; PR_OUT_PARAM_LEN];
+    if iov_to_buf_direct(iovec, &mut param)? < PR_OUT_PARAM_LEN {
+        bail!("PERSISTENT RESERVE OUT data-out buffer is less than the parameter list");
+    }
+
+    let mut dev_lock = dev.lock().unwrap();
+    let ret = dev_lock
+        .reservation
+        .reserve_out(initiator, service_action, scope_type, &param);
+    if ret.is_ok() {
+        debug!(
+            "Scsi device {} persistent reservation service action {:#x} from initiator {:#x}",
+            dev_lock.config.id, service_action, initiator
+        );
+    }
+    Ok(ret.map(|_| Vec::new()))
+}
+
+/// Max bytes read from the backend at a time when verifying the medium.
+const SCSI_VERIFY_CHUNK_SIZE: u64 = 1 << 20;
+
+/// Emulate VERIFY(10/12/16).
+///
+/// Return None if the verification succeeds. Otherwise, return the sense which should be
+/// reported to the guest together with the value of the sense INFORMATION field, if any.
+fn scsi_command_emulate_verify(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+    iovec: &[Iovec],
+) -> Result<Option<(ScsiSense, Option<u32>)>> {
+    // Byte1: bits[1-2]: BYTCHK.
+    // 00b: The medium is verified without any data comparison.
+    // 01b: The data-out buffer is compared with the data read from the medium.
+    // 10b/11b: Not supported.
+    let bytchk = (cmd.buf[1] >> 1) & 0x3;
+    if bytchk > 1 {
+        bail!("Unsupported BYTCHK {} in VERIFY command", bytchk);
+    }
+
+    // Verification length in logical blocks, which has the same position as the transfer length.
+    let nb_blocks = match cmd.command {
+        VERIFY_10 => BigEndian::read_u16(&cmd.buf[7..9]) as u64,
+        VERIFY_12 => BigEndian::read_u32(&cmd.buf[6..10]) as u64,
+        _ => BigEndian::read_u32(&cmd.buf[10..14]) as u64,
+    };
+
+    let dev_lock = dev.lock().unwrap();
+    let block_size = dev_lock.block_size as u64;
+    let disk_blocks = dev_lock.disk_sectors / (block_size / DEFAULT_SECTOR_SIZE as u64);
+    let disk_image = dev_lock.disk_image.clone();
+    let backing = dev_lock.backing.clone();
+    drop(dev_lock);
+
+    if cmd
+        .lba
+        .checked_add(nb_blocks)
+        .filter(|&end| end <= disk_blocks)
+        .is_none()
+    {
+        return Ok(Some((SCSI_SENSE_LBA_OUT_OF_RANGE, None)));
+    }
+    if nb_blocks == 0 {
+        return Ok(None);
+    }
+    let disk_image = match disk_image {
+        Some(file) => file,
+        None => bail!("No scsi backend for VERIFY command!"),
+    };
+
+    let len = nb_blocks * block_size;
+    if bytchk == 1 {
+        let data_len = iovec.iter().map(|iov| iov.iov_len).sum::<u64>();
+        if data_len < len {
+            bail!(
+                "VERIFY data-out buffer length {} is less than verification length {}",
+                data_len,
+                len
+            );
+        }
+    }
+
+    let start = cmd.lba * block_size;
+    let mut buf = vec![0_u8; cmp::min(len, SCSI_VERIFY_CHUNK_SIZE) as usize];
+    let mut pos: u64 = 0;
+    let mut iov_idx = 0;
+    let mut iov_off: u64 = 0;
+    while pos < len {
+        let chunk = &mut buf[..cmp::min(len - pos, SCSI_VERIFY_CHUNK_SIZE) as usize];
+        let read = match &backing {
+            // The clusters not allocated in the overlay are read from the backing image.
+            Some(backing) => {
+                let iovec = [Iovec {
+                    iov_base: chunk.as_mut_ptr() as u64,
+                    iov_len: chunk.len() as u64,
+                }];
+                backing.readv(
+                    disk_image.as_raw_fd(),
+                    &iovec,
+                    start + pos,
+                    chunk.len() as u64,
+                )
+            }
+            None => disk_image
+                .read_exact_at(chunk, start + pos)
+                .map_err(anyhow::Error::from),
+        };
+        if let Err(e) = read {
+            error!(
+                "Failed to read {} bytes at {} for VERIFY command: {:?}",
+                chunk.len(),
+                start + pos,
+                e
+            );
+            return Ok(Some((SCSI_SENSE_READ_ERROR, None)));
+        }
+
+        if bytchk == 1 {
+            let mut checked: usize = 0;
+            while checked < chunk.len() {
+                let iov = &iovec[iov_idx];
+                let n = cmp::min((iov.iov_len - iov_off) as usize, chunk.len() - checked);
+                // SAFETY: the iovec is the guest memory of the data-out buffer which has been
+                // checked to be no less than the verification length.
+                let data =
+                    unsafe { std::slice::from_raw_parts((iov.iov_base + iov_off) as *const u8, n) };
+                if let Some(idx) = chunk[checked..checked + n]
+                    .iter()
+                    .zip(data.iter())
+                    .position(|(disk, data)| disk != data)
+                {
+                    let offset = pos + (checked + idx) as u64;
+                    return Ok(Some((
+                        SCSI_SENSE_MISCOMPARE_DURING_VERIFY,
+                        Some(cmp::min(offset, u32::MAX as u64) as u32),
+                    )));
+                }
+                checked += n;
+                iov_off += n as u64;
+                if iov_off == iov.iov_len {
+                    iov_idx += 1;
+                    iov_off = 0;
+                }
+            }
+        }
+        pos += chunk.len() as u64;
+    }
+
+    Ok(None)
+}
+
+/// Copy the emulated data `outbuf` to the data-in buffer `ranges` of `data_len` bytes,
+/// consecutive chunks of it to consecutive ranges. Return the resid of the request, which
+/// is the length of the data-in buffer not written.
+///
+/// The data is written by guest address, so it fails instead of touching the memory if a
+/// range is no longer guest memory, e.g. it's hot unplugged while the command is emulated.
+fn scsi_data_in_resid(
+    mem_space: &AddressSpace,
+    ranges: &[HostRange],
+    data_len: u32,
+    outbuf: &[u8],
+) -> Result<u32> {
+    let mut written = 0;
+    for range in ranges {
+        if written >= outbuf.len() {
+            break;
+        }
+        let len = cmp::min(range.len as usize, outbuf.len() - written);
+        mem_space.write(&mut &outbuf[written..written + len], range.gpa, len as u64)?;
+        written += len;
+    }
+    Ok(data_len.saturating_sub(written as u32))
 }
 
 // Scsi Commands which are emulated in stratovirt and do noting to the backend.
@@ -749,13 +1548,242 @@ pub const NON_EMULATE_SCSI_OPS: u32 = 1;
 fn scsi_operation_type(op: u8) -> u32 {
     match op {
         READ_6 | READ_10 | READ_12 | READ_16 | WRITE_6 | WRITE_10 | WRITE_12 | WRITE_16
-        | WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16 | SYNCHRONIZE_CACHE => {
-            NON_EMULATE_SCSI_OPS
-        }
+        | WRITE_VERIFY_10 | WRITE_VERIFY_12 | WRITE_VERIFY_16 | SYNCHRONIZE_CACHE
+        | SYNCHRONIZE_CACHE_16 => NON_EMULATE_SCSI_OPS,
         _ => EMULATE_SCSI_OPS,
     }
 }
 
+/// A command supported by the scsi device, which is reported by REPORT SUPPORTED
+/// OPERATION CODES.
+struct ScsiOpcodeDesc {
+    opcode: u8,
+    /// Service action, for the commands which have service actions.
+    service_action: Option<u8>,
+    /// Only supported by cdrom.
+    rom_only: bool,
+    /// CDB usage data following the operation code: the bits of each CDB byte which are
+    /// used by the emulation. The CDB length is one more than its length.
+    usage: &'static [u8],
+}
+
+impl ScsiOpcodeDesc {
+    const fn new(opcode: u8, service_action: Option<u8>, usage: &'static [u8]) -> Self {
+        ScsiOpcodeDesc {
+            opcode,
+            service_action,
+            rom_only: false,
+            usage,
+        }
+    }
+
+    const fn rom(opcode: u8, usage: &'static [u8]) -> Self {
+        ScsiOpcodeDesc {
+            opcode,
+            service_action: None,
+            rom_only: true,
+            usage,
+        }
+    }
+
+    fn supported_by(&self, scsi_type: u32) -> bool {
+        !self.rom_only || scsi_type == SCSI_TYPE_ROM
+    }
+
+    fn cdb_len(&self) -> u16 {
+        self.usage.len() as u16 + 1
+    }
+}
+
+/// The commands supported by the scsi device, in order of operation code and service
+/// action. The commands not in it are rejected before they are dispatched.
+const SCSI_SUPPORTED_OPCODES: &[ScsiOpcodeDesc] = &[
+    ScsiOpcodeDesc::new(TEST_UNIT_READY, None, &[0, 0, 0, 0, 0]),
+    ScsiOpcodeDesc::new(REQUEST_SENSE, None, &[0x01, 0, 0, 0xff, 0]),
+    ScsiOpcodeDesc::new(READ_6, None, &[0x1f, 0xff, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::new(WRITE_6, None, &[0x1f, 0xff, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::new(INQUIRY, None, &[0x01, 0xff, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::new(MODE_SENSE, None, &[0x08, 0xff, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::new(START_STOP, None, &[0x01, 0, 0, 0x03, 0]),
+    ScsiOpcodeDesc::new(ALLOW_MEDIUM_REMOVAL, None, &[0, 0, 0, 0x03, 0]),
+    ScsiOpcodeDesc::new(READ_CAPACITY_10, None, &[0, 0, 0, 0, 0, 0, 0, 0, 0]),
+    ScsiOpcodeDesc::new(
+        READ_10,
+        None,
+        &[0x18, 0xff, 0xff, 0xff, 0xff, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        WRITE_10,
+        None,
+        &[0x18, 0xff, 0xff, 0xff, 0xff, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        WRITE_VERIFY_10,
+        None,
+        &[0x10, 0xff, 0xff, 0xff, 0xff, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        VERIFY_10,
+        None,
+        &[0x06, 0xff, 0xff, 0xff, 0xff, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        SYNCHRONIZE_CACHE,
+        None,
+        &[0, 0xff, 0xff, 0xff, 0xff, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        READ_DEFECT_DATA,
+        None,
+        &[0, 0x1f, 0, 0, 0, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::rom(READ_TOC, &[0x02, 0x0f, 0, 0, 0, 0xff, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::rom(GET_CONFIGURATION, &[0, 0, 0, 0, 0, 0, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::rom(
+        GET_EVENT_STATUS_NOTIFICATION,
+        &[0x01, 0, 0, 0xff, 0, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(LOG_SENSE, None, &[0x01, 0xff, 0xff, 0, 0, 0, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::rom(READ_DISC_INFORMATION, &[0x07, 0, 0, 0, 0, 0, 0xff, 0xff, 0]),
+    ScsiOpcodeDesc::new(
+        MODE_SENSE_10,
+        None,
+        &[0x18, 0xff, 0xff, 0, 0, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        PERSISTENT_RESERVE_IN,
+        Some(PR_IN_READ_KEYS),
+        &[0x1f, 0, 0, 0, 0, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        PERSISTENT_RESERVE_IN,
+        Some(PR_IN_READ_RESERVATION),
+        &[0x1f, 0, 0, 0, 0, 0, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_OUT_REGISTER),
+        &[0x1f, 0xff, 0, 0, 0xff, 0xff, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_OUT_RESERVE),
+        &[0x1f, 0xff, 0, 0, 0xff, 0xff, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_OUT_RELEASE),
+        &[0x1f, 0xff, 0, 0, 0xff, 0xff, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        PERSISTENT_RESERVE_OUT,
+        Some(PR_OUT_CLEAR),
+        &[0x1f, 0xff, 0, 0, 0xff, 0xff, 0xff, 0xff, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        READ_16,
+        None,
+        &[
+            0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0,
+        ],
+    ),
+    ScsiOpcodeDesc::new(
+        WRITE_16,
+        None,
+        &[
+            0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0,
+        ],
+    ),
+    ScsiOpcodeDesc::new(
+        WRITE_VERIFY_16,
+        None,
+        &[
+            0x10, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0,
+        ],
+    ),
+    ScsiOpcodeDesc::new(
+        VERIFY_16,
+        None,
+        &[
+            0x06, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0,
+        ],
+    ),
+    ScsiOpcodeDesc::new(
+        SYNCHRONIZE_CACHE_16,
+        None,
+        &[
+            0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0,
+        ],
+    ),
+    ScsiOpcodeDesc::new(
+        SERVICE_ACTION_IN_16,
+        Some(SUBCODE_READ_CAPACITY_16),
+        &[0x1f, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        SERVICE_ACTION_IN_16,
+        Some(SUBCODE_GET_LBA_STATUS),
+        &[
+            0x1f, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0,
+        ],
+    ),
+    ScsiOpcodeDesc::new(
+        REPORT_LUNS,
+        None,
+        &[0, 0xff, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        MAINTENANCE_IN,
+        Some(SUBCODE_REPORT_SUPPORTED_OPCODES),
+        &[0x1f, 0x87, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        READ_12,
+        None,
+        &[0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        WRITE_12,
+        None,
+        &[0x18, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        WRITE_VERIFY_12,
+        None,
+        &[0x10, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        VERIFY_12,
+        None,
+        &[0x06, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+    ScsiOpcodeDesc::new(
+        READ_DEFECT_DATA_12,
+        None,
+        &[0x1f, 0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0, 0],
+    ),
+];
+
+/// Check whether the command is supported by the device of `scsi_type`.
+///
+/// Return INVALID COMMAND OPERATION CODE sense if the operation code is not supported,
+/// or INVALID FIELD IN CDB sense if its service action is not supported.
+fn scsi_check_opcode(cmd: &ScsiCommand, scsi_type: u32) -> Option<ScsiSense> {
+    // Byte1: bits[0-4]: service action, for the commands which have service actions.
+    let service_action = cmd.buf[1] & 0x1f;
+    let mut sense = SCSI_SENSE_INVALID_OPCODE;
+    for desc in SCSI_SUPPORTED_OPCODES
+        .iter()
+        .filter(|desc| desc.opcode == cmd.command && desc.supported_by(scsi_type))
+    {
+        if desc.service_action.map_or(true, |sa| sa == service_action) {
+            return None;
+        }
+        sense = SCSI_SENSE_INVALID_FIELD;
+    }
+    Some(sense)
+}
+
 //   lun: [u8, 8]
 //   | Byte 0 | Byte 1 | Byte 2 | Byte 3 | Byte 4 | Byte 5 | Byte 6 | Byte 7 |
 //   |    1   | target |       lun       |                 0                 |
@@ -812,15 +1840,30 @@ fn scsi_cdb_xfer(cdb: &[u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE], dev: Arc<Mutex<ScsiDe
         WRITE_6 | READ_6 => {
             // length 0 means 256 blocks.
             if xfer == 0 {
-                xfer = 256 * block_size;
+                xfer = 256;
             }
+            // Transfer length larger than i32::MAX is invalid.
+            xfer = xfer.checked_mul(block_size).unwrap_or(-1);
         }
-        WRITE_10 | WRITE_12 | WRITE_16 | READ_10 | READ_12 | READ_16 => {
-            xfer *= block_size;
+        WRITE_10 | WRITE_12 | WRITE_16 | READ_10 | READ_12 | READ_16 | WRITE_VERIFY_10
+        | WRITE_VERIFY_12 | WRITE_VERIFY_16 => {
+            // WRITE AND VERIFY transfers the data-out buffer whatever BYTCHK is.
+            xfer = xfer.checked_mul(block_size).unwrap_or(-1);
+        }
+        VERIFY_10 | VERIFY_12 | VERIFY_16 => {
+            // Byte1: bits[1-2]: BYTCHK. Data-out buffer is transferred only if BYTCHK is not zero.
+            if cdb[1] & 0x6 == 0 {
+                xfer = 0;
+            } else {
+                xfer = xfer.checked_mul(block_size).unwrap_or(-1);
+            }
         }
         INQUIRY => {
             xfer = i32::from(cdb[4]) | i32::from(cdb[3]) << 8;
         }
+        PERSISTENT_RESERVE_OUT => {
+            xfer = BigEndian::read_u32(&cdb[5..]) as i32;
+        }
         _ => {}
     }
     xfer
@@ -942,21 +1985,53 @@ fn scsi_command_emulate_vpd_page(
         }
         0x83 => {
             // Device Identification.
-            let mut len: u8 = dev_lock.state.device_id.len() as u8;
-            if len > (255 - 8) {
-                len = 255 - 8;
+            let len = cmp::min(dev_lock.state.device_id.len(), 255);
+            if len > 0 {
+                // Vendor specific designator.
+                let mut device_id_vec = dev_lock.state.device_id.as_bytes().to_vec();
+                device_id_vec.truncate(len);
+                outbuf.append(&mut scsi_designator(
+                    DESIGNATOR_CODE_SET_ASCII,
+                    DESIGNATOR_TYPE_VENDOR_SPECIFIC,
+                    &device_id_vec,
+                ));
             }
 
-            if len > 0 {
-                // 0x2: Code Set: ASCII, Protocol Identifier: reserved.
-                // 0: Identifier Type, Association, Reserved, Piv.
-                // 0: Reserved.
-                // len: identifier length.
-                outbuf.append(&mut [0x2_u8, 0_u8, 0_u8, len].to_vec());
+            if !dev_lock.state.serial.is_empty() {
+                // T10 vendor identification designator: vendor(8 bytes) | product(16 bytes) |
+                // serial, vendor and product are padded with spaces.
+                let mut t10_id =
+                    vec![b' '; SCSI_INQUIRY_VENDOR_MAX_LEN + SCSI_INQUIRY_PRODUCT_MAX_LEN];
+                let vendor_bytes = dev_lock.state.vendor.as_bytes();
+                let vendor_len = cmp::min(vendor_bytes.len(), SCSI_INQUIRY_VENDOR_MAX_LEN);
+                t10_id[..vendor_len].copy_from_slice(&vendor_bytes[..vendor_len]);
+                let product_bytes = dev_lock.state.product.as_bytes();
+                let product_len = cmp::min(product_bytes.len(), SCSI_INQUIRY_PRODUCT_MAX_LEN);
+                t10_id[SCSI_INQUIRY_VENDOR_MAX_LEN..SCSI_INQUIRY_VENDOR_MAX_LEN + product_len]
+                    .copy_from_slice(&product_bytes[..product_len]);
+                let serial_bytes = dev_lock.state.serial.as_bytes();
+                let serial_len =
+                    cmp::min(serial_bytes.len(), SCSI_INQUIRY_VPD_SERIAL_NUMBER_MAX_LEN);
+                t10_id.extend_from_slice(&serial_bytes[..serial_len]);
+                outbuf.append(&mut scsi_designator(
+                    DESIGNATOR_CODE_SET_ASCII,
+                    DESIGNATOR_TYPE_T10_VENDOR_ID,
+                    &t10_id,
+                ));
+            }
 
-                let mut device_id_vec = dev_lock.state.device_id.as_bytes().to_vec();
-                device_id_vec.truncate(len as usize);
-                outbuf.append(&mut device_id_vec);
+            // NAA designator is derived from the wwn, or from the serial number if the wwn is
+            // not configured. Otherwise there is nothing unique to build it from.
+            let wwn = dev_lock.config.wwn.or_else(|| {
+                (!dev_lock.state.serial.is_empty())
+                    .then(|| scsi_serial_hash(dev_lock.state.serial.as_bytes()))
+            });
+            if let Some(wwn) = wwn {
+                outbuf.append(&mut scsi_designator(
+                    DESIGNATOR_CODE_SET_BINARY,
+                    DESIGNATOR_TYPE_NAA,
+                    &scsi_naa_registered_extended_id(wwn),
+                ));
             }
             buflen = outbuf.len();
         }
@@ -984,9 +2059,12 @@ fn scsi_command_emulate_vpd_page(
             // Bytes[56-59] = 0: Maximum Atomic Transfer Length With Atomic Boundary.
             // Bytes[60-63] = 0: Maximum Atomic Boundary Size.
             outbuf[4] = 1;
-            let max_xfer_length: u32 = u32::MAX / 512;
-            BigEndian::write_u32(&mut outbuf[8..12], max_xfer_length);
-            BigEndian::write_u64(&mut outbuf[36..44], max_xfer_length as u64);
+            // The maximum transfer length agrees with max_sectors of the controller.
+            let max_xfer_length = (u64::from(dev_lock.max_sectors) << SECTOR_SHIFT)
+                / u64::from(cmp::max(dev_lock.block_size, DEFAULT_SECTOR_SIZE));
+            BigEndian::write_u32(&mut outbuf[8..12], max_xfer_length as u32);
+            let max_write_same_length: u32 = u32::MAX / 512;
+            BigEndian::write_u64(&mut outbuf[36..44], max_write_same_length as u64);
             buflen = outbuf.len();
         }
         0xb1 => {
@@ -1012,11 +2090,47 @@ fn scsi_command_emulate_vpd_page(
         }
     }
 
-    // It's OK for just using outbuf byte 3, because all page_code's buflen in stratovirt is less than 255 now.
-    outbuf[3] = buflen as u8 - 4;
+    // Byte[2-3]: Page Length.
+    BigEndian::write_u16(&mut outbuf[2..4], (buflen - 4) as u16);
     Ok(outbuf)
 }
 
+/// Build a designation descriptor of the Device Identification VPD page, associated with
+/// the logical unit.
+fn scsi_designator(code_set: u8, designator_type: u8, designator: &[u8]) -> Vec<u8> {
+    // Byte0: bits[4-7]: Protocol Identifier, bits[0-3]: Code Set.
+    // Byte1: bit7: PIV, bits[4-5]: Association, bits[0-3]: Designator Type.
+    // Byte2: Reserved.
+    // Byte3: Designator Length.
+    let mut descriptor = vec![
+        code_set & 0xf,
+        designator_type & 0xf,
+        0,
+        designator.len() as u8,
+    ];
+    descriptor.extend_from_slice(designator);
+    descriptor
+}
+
+/// NAA IEEE Registered Extended designator(16 bytes). The IEEE company id and the vendor
+/// specific identifier are taken from the low 60 bits of the wwn, and the vendor specific
+/// identifier extension is zero.
+fn scsi_naa_registered_extended_id(wwn: u64) -> [u8; 16] {
+    let mut id = [0_u8; 16];
+    BigEndian::write_u64(
+        &mut id[0..8],
+        (NAA_IEEE_REGISTERED_EXTENDED << 60) | (wwn & 0x0fff_ffff_ffff_ffff),
+    );
+    id
+}
+
+/// FNV-1a hash of the serial number, which is stable across builds and hosts.
+fn scsi_serial_hash(serial: &[u8]) -> u64 {
+    serial.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, b| {
+        (hash ^ *b as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
 fn scsi_command_emulate_target_inquiry(lun: u16, cmd: &ScsiCommand) -> Result<Vec<u8>> {
     let mut outbuf: Vec<u8> = vec![0; 4];
 
@@ -1047,8 +2161,8 @@ fn scsi_command_emulate_target_inquiry(lun: u16, cmd: &ScsiCommand) -> Result<Ve
         bail!("Invalid standatd inquiry command!");
     }
 
+    // It's truncated to the allocation length when the command completes.
     outbuf.resize(SCSI_TARGET_INQUIRY_LEN as usize, 0);
-    let len = cmp::min(cmd.xfer, SCSI_TARGET_INQUIRY_LEN);
 
     // outbuf.
     // Byte0: Peripheral Qualifier | peripheral device type.
@@ -1067,11 +2181,7 @@ fn scsi_command_emulate_target_inquiry(lun: u16, cmd: &ScsiCommand) -> Result<Ve
         outbuf[2] = 5;
         // HISUP(hierarchical support). Response Data Format(must be 2).
         outbuf[3] = 0x12;
-        outbuf[4] = if len <= 5 {
-            bail!("Invalid xfer field in INQUIRY command");
-        } else {
-            len as u8 - 5
-        };
+        outbuf[4] = (SCSI_TARGET_INQUIRY_LEN - 5) as u8;
         // SYNC, CMDQUE(the logical unit supports the task management model).
         outbuf[7] = 0x12;
     }
@@ -1092,15 +2202,18 @@ fn scsi_command_emulate_inquiry(
         bail!("Invalid INQUIRY!");
     }
 
-    let buflen = cmp::min(cmd.xfer, SCSI_MAX_INQUIRY_LEN);
+    // The whole standard INQUIRY data is built even if the allocation length is shorter, and
+    // it's truncated to the allocation length when the command completes.
+    let buflen = cmp::min(cmd.xfer, SCSI_MAX_INQUIRY_LEN).max(SCSI_MIN_INQUIRY_LEN);
     let mut outbuf: Vec<u8> = vec![0; buflen as usize];
 
     let dev_lock = dev.lock().unwrap();
 
     outbuf[0] = (dev_lock.scsi_type & 0x1f) as u8;
-    outbuf[1] = match dev_lock.state.features & SCSI_DISK_F_REMOVABLE {
-        1 => 0x80,
-        _ => 0,
+    // Byte1: bit7: RMB(removable medium).
+    outbuf[1] = match dev_lock.is_removable() {
+        true => 0x80,
+        false => 0,
     };
 
     let product_bytes = dev_lock.state.product.as_bytes();
@@ -1124,11 +2237,7 @@ fn scsi_command_emulate_inquiry(
     outbuf[2] = 5;
     outbuf[3] = (2 | 0x10) as u8;
 
-    if buflen > 36 {
-        outbuf[4] = (buflen - 5) as u8;
-    } else {
-        outbuf[4] = 36 - 5;
-    }
+    outbuf[4] = (buflen - 5) as u8;
 
     outbuf[7] = 0x12;
 
@@ -1147,13 +2256,17 @@ fn scsi_command_emulate_read_capacity_10(
     let dev_lock = dev.lock().unwrap();
     let block_size = dev_lock.block_size;
     let mut outbuf: Vec<u8> = vec![0; 8];
-    let mut nb_sectors = cmp::min(dev_lock.disk_sectors as u32, u32::MAX);
-    nb_sectors /= block_size / DEFAULT_SECTOR_SIZE;
-    nb_sectors -= 1;
+    let nb_blocks = dev_lock.disk_sectors / u64::from(block_size / DEFAULT_SECTOR_SIZE);
+    if nb_blocks == 0 {
+        bail!("No medium in the scsi device");
+    }
+    // The last LBA is reported as 0xFFFFFFFF if it doesn't fit in 32 bits, which tells
+    // the guest to use READ CAPACITY(16) instead.
+    let last_lba = cmp::min(nb_blocks - 1, u64::from(u32::MAX)) as u32;
 
     // Bytes[0-3]: Returned Logical Block Address(the logical block address of the last logical block).
     // Bytes[4-7]: Logical Block Length In Bytes.
-    BigEndian::write_u32(&mut outbuf[0..4], nb_sectors);
+    BigEndian::write_u32(&mut outbuf[0..4], last_lba);
     BigEndian::write_u32(&mut outbuf[4..8], block_size);
 
     Ok(outbuf)
@@ -1388,7 +2501,10 @@ fn scsi_command_emulate_report_luns(
         );
     }
 
-    let scsi_bus = dev_lock.parent_bus.upgrade().unwrap();
+    let scsi_bus = dev_lock
+        .parent_bus
+        .upgrade()
+        .with_context(|| "The scsi device is not attached to a bus")?;
     let scsi_bus_clone = scsi_bus.lock().unwrap();
 
     drop(dev_lock);
@@ -1429,6 +2545,9 @@ fn scsi_command_emulate_service_action_in_16(
         let mut outbuf: Vec<u8> = vec![0; 32];
         let mut nb_sectors = dev_lock.disk_sectors;
         nb_sectors /= (block_size / DEFAULT_SECTOR_SIZE) as u64;
+        if nb_sectors == 0 {
+            bail!("No medium in the scsi device");
+        }
         nb_sectors -= 1;
 
         drop(dev_lock);
@@ -1440,6 +2559,10 @@ fn scsi_command_emulate_service_action_in_16(
 
         return Ok(outbuf);
     }
+    if cmd.buf[1] & 0x1f == SUBCODE_GET_LBA_STATUS {
+        return scsi_command_emulate_get_lba_status(cmd, dev)?
+            .map_err(|_| anyhow!("LBA {} is out of range", cmd.lba));
+    }
 
     bail!(
         "Invalid combination Scsi Command, operation code ({:x}), service action ({:x})",
@@ -1448,6 +2571,259 @@ fn scsi_command_emulate_service_action_in_16(
     );
 }
 
+/// Emulate GET LBA STATUS, which reports whether the logical blocks from the starting LBA
+/// are mapped or deallocated, according to the holes of the image file.
+///
+/// Return the sense instead of the data if the starting LBA is out of range.
+fn scsi_command_emulate_get_lba_status(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<std::result::Result<Vec<u8>, ScsiSense>> {
+    // Byte 0: Operation Code(0x9e)
+    // Byte 1: bit0 - bit4: Service Action(0x12).
+    // Bytes[2-9]: Starting Logical Block Address.
+    // Bytes[10-13]: Allocation Length.
+    let dev_lock = dev.lock().unwrap();
+    let block_size = dev_lock.block_size as u64;
+    let disk_blocks = dev_lock.disk_sectors / (block_size / DEFAULT_SECTOR_SIZE as u64);
+    let disk_image = dev_lock.disk_image.clone();
+    let has_backing = dev_lock.backing.is_some();
+    drop(dev_lock);
+
+    if cmd.lba >= disk_blocks {
+        return Ok(Err(SCSI_SENSE_LBA_OUT_OF_RANGE));
+    }
+    let disk_image = match disk_image {
+        Some(file) => file,
+        None => bail!("No scsi backend for GET LBA STATUS command!"),
+    };
+
+    // At least one descriptor is returned, the data beyond the allocation length is
+    // truncated.
+    let max_descriptors =
+        (cmd.xfer.saturating_sub(8) as usize / 16).clamp(1, GET_LBA_STATUS_MAX_DESCRIPTORS);
+    // Descriptors of (LBA, number of logical blocks, provisioning status).
+    let mut descriptors: Vec<(u64, u64, u8)> = Vec::new();
+    let disk_end = disk_blocks * block_size;
+    let mut lba = cmd.lba;
+    while lba < disk_blocks {
+        // The holes of an overlay are filled by the backing image, which are mapped.
+        let (is_data, extent_end) = match has_backing {
+            true => (true, disk_end),
+            false => get_file_extent(&disk_image, lba * block_size, disk_end),
+        };
+        // A logical block is mapped if any part of it is data.
+        let (provisioning, end) = match is_data {
+            true => (
+                LBA_STATUS_MAPPED,
+                (extent_end + block_size - 1) / block_size,
+            ),
+            false if extent_end / block_size > lba => {
+                (LBA_STATUS_DEALLOCATED, extent_end / block_size)
+            }
+            false => (LBA_STATUS_MAPPED, lba + 1),
+        };
+        // The number of logical blocks of a descriptor is limited to 32 bits.
+        let end = cmp::min(end, disk_blocks).min(lba + u32::MAX as u64);
+        match descriptors.last_mut() {
+            Some((_, nb_blocks, last))
+                if *last == provisioning && *nb_blocks + end - lba <= u32::MAX as u64 =>
+            {
+                *nb_blocks += end - lba;
+            }
+            _ => {
+                if descriptors.len() == max_descriptors {
+                    break;
+                }
+                descriptors.push((lba, end - lba, provisioning));
+            }
+        }
+        lba = end;
+    }
+
+    // Bytes[0-3]: Parameter Data Length(n - 3).
+    // Bytes[4-7]: Reserved.
+    // LBA status descriptors of 16 bytes:
+    //   Bytes[0-7]: Logical Block Address.
+    //   Bytes[8-11]: Number of Logical Blocks.
+    //   Byte12: bits[0-3]: Provisioning Status.
+    let mut outbuf = vec![0_u8; 8];
+    for (lba, nb_blocks, provisioning) in descriptors {
+        let mut desc = [0_u8; 16];
+        BigEndian::write_u64(&mut desc[0..8], lba);
+        BigEndian::write_u32(&mut desc[8..12], nb_blocks as u32);
+        desc[12] = provisioning;
+        outbuf.extend_from_slice(&desc);
+    }
+    let data_len = outbuf.len() as u32 - 4;
+    BigEndian::write_u32(&mut outbuf[0..4], data_len);
+
+    Ok(Ok(outbuf))
+}
+
+fn scsi_command_emulate_maintenance_in(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<Vec<u8>> {
+    // Byte1: bits[0-4]: Service Action.
+    match cmd.buf[1] & 0x1f {
+        SUBCODE_REPORT_SUPPORTED_OPCODES => scsi_command_emulate_report_supported_opcodes(cmd, dev),
+        service_action => bail!(
+            "Invalid combination Scsi Command, operation code ({:x}), service action ({:x})",
+            MAINTENANCE_IN,
+            service_action
+        ),
+    }
+}
+
+/// Emulate REPORT SUPPORTED OPERATION CODES, which reports the commands in
+/// `SCSI_SUPPORTED_OPCODES` supported by the device.
+fn scsi_command_emulate_report_supported_opcodes(
+    cmd: &ScsiCommand,
+    dev: &Arc<Mutex<ScsiDevice>>,
+) -> Result<Vec<u8>> {
+    // Byte2: bit7: RCTD(Return Commands Timeouts Descriptor), bits[0-2]: Reporting Options.
+    // Byte3: Requested Operation Code.
+    // Bytes[4-5]: Requested Service Action.
+    // Bytes[6-9]: Allocation Length.
+    if cmd.buf[2] & 0x80 != 0 {
+        bail!("Command timeouts descriptor is not supported");
+    }
+    let reporting_options = cmd.buf[2] & 0x7;
+    let requested_opcode = cmd.buf[3];
+    let requested_sa = BigEndian::read_u16(&cmd.buf[4..6]);
+    let scsi_type = dev.lock().unwrap().scsi_type;
+    let supported = SCSI_SUPPORTED_OPCODES
+        .iter()
+        .filter(|desc| desc.supported_by(scsi_type));
+
+    if reporting_options == 0 {
+        // All commands parameter data: 4 bytes header, then 8 bytes command descriptor
+        // for each command.
+        // Descriptor: Byte0: Operation Code. Bytes[2-3]: Service Action.
+        // Byte5: bit0: SERVACTV(the service action is valid). Bytes[6-7]: CDB Length.
+        let mut outbuf = vec![0_u8; 4];
+        for desc in supported {
+            let mut descriptor = [0_u8; 8];
+            descriptor[0] = desc.opcode;
+            if let Some(sa) = desc.service_action {
+                BigEndian::write_u16(&mut descriptor[2..4], sa as u16);
+                descriptor[5] = 1;
+            }
+            BigEndian::write_u16(&mut descriptor[6..8], desc.cdb_len());
+            outbuf.extend_from_slice(&descriptor);
+        }
+        let len = outbuf.len() as u32 - 4;
+        BigEndian::write_u32(&mut outbuf[0..4], len);
+        return Ok(outbuf);
+    }
+
+    let mut descs = supported.filter(|desc| desc.opcode == requested_opcode);
+    let has_sa = descs.clone().any(|desc| desc.service_action.is_some());
+    let desc = match reporting_options {
+        // The requested service action is ignored.
+        1 => {
+            if has_sa {
+                bail!("Operation code {:#x} has service actions", requested_opcode);
+            }
+            descs.next()
+        }
+        2 | 3 => {
+            if reporting_options == 2 && !has_sa && descs.clone().next().is_some() {
+                bail!(
+                    "Operation code {:#x} has no service action",
+                    requested_opcode
+                );
+            }
+            descs.find(|desc| {
+                desc.service_action
+                    .map_or(true, |sa| sa as u16 == requested_sa)
+            })
+        }
+        _ => bail!("Invalid reporting options {}", reporting_options),
+    };
+
+    // One command parameter data.
+    // Byte1: bits[0-2]: SUPPORT. Bytes[2-3]: CDB Size. Bytes[4-n]: CDB Usage Data.
+    let mut outbuf = vec![0_u8; 4];
+    match desc {
+        Some(desc) => {
+            // Supported in conformance with a SCSI standard.
+            outbuf[1] = 0x3;
+            BigEndian::write_u16(&mut outbuf[2..4], desc.cdb_len());
+            outbuf.push(desc.opcode);
+            outbuf.extend_from_slice(desc.usage);
+        }
+        // Not supported.
+        None => outbuf[1] = 0x1,
+    }
+    Ok(outbuf)
+}
+
+/// Emulate LOG SENSE with the supported pages page, the temperature page and the
+/// informational exceptions page, which are polled by the SMART tools in guest.
+fn scsi_command_emulate_log_sense(cmd: &ScsiCommand) -> Result<Vec<u8>> {
+    // Byte1: bit0: SP(Save Parameters).
+    // Byte2: bits[6-7]: PC(Page Control), bits[0-5]: Page Code.
+    // Byte3: Subpage Code.
+    // Bytes[7-8]: Allocation Length.
+    if cmd.buf[1] & 0x1 != 0 {
+        bail!("Saving log parameters is not supported");
+    }
+    let page_control = cmd.buf[2] >> 6;
+    if page_control != LOG_PC_CUMULATIVE {
+        bail!("Log page control {} is not supported", page_control);
+    }
+    let page_code = cmd.buf[2] & 0x3f;
+    if cmd.buf[3] != 0 {
+        bail!("Log subpage {:#x} is not supported", cmd.buf[3]);
+    }
+
+    // Log page: Byte0: bits[0-5]: Page Code. Byte1: Subpage Code. Bytes[2-3]: Page Length.
+    let mut outbuf = vec![page_code, 0, 0, 0];
+    match page_code {
+        LOG_PAGE_SUPPORTED_PAGES => outbuf.extend_from_slice(&[
+            LOG_PAGE_SUPPORTED_PAGES,
+            LOG_PAGE_TEMPERATURE,
+            LOG_PAGE_INFORMATIONAL_EXCEPTIONS,
+        ]),
+        // Log parameter: Bytes[0-1]: Parameter Code. Byte2: Control, 0x03 means binary
+        // format list. Byte3: Parameter Length.
+        LOG_PAGE_TEMPERATURE => {
+            // Temperature and Reference Temperature: Byte5: temperature in Celsius.
+            outbuf.extend_from_slice(&[0, 0, 0x03, 2, 0, LOG_TEMPERATURE]);
+            outbuf.extend_from_slice(&[0, 1, 0x03, 2, 0, LOG_REFERENCE_TEMPERATURE]);
+        }
+        LOG_PAGE_INFORMATIONAL_EXCEPTIONS => {
+            // Informational Exceptions General: Byte4: Additional Sense Code. Byte5:
+            // Additional Sense Code Qualifier, both 0 means no failure predicted.
+            // Byte6: Most Recent Temperature Reading.
+            outbuf.extend_from_slice(&[0, 0, 0x03, 4, 0, 0, LOG_TEMPERATURE, 0]);
+        }
+        _ => bail!("Log page {:#x} is not supported", page_code),
+    }
+    let len = outbuf.len() as u16 - 4;
+    BigEndian::write_u16(&mut outbuf[2..4], len);
+    Ok(outbuf)
+}
+
+/// Emulate READ DEFECT DATA (10) and (12), the defect lists are always empty.
+fn scsi_command_emulate_read_defect_data(cmd: &ScsiCommand) -> Result<Vec<u8>> {
+    // READ DEFECT DATA (10): Byte2: bit4: REQ_PLIST, bit3: REQ_GLIST, bits[0-2]: Defect
+    // List Format.
+    // READ DEFECT DATA (12): Byte1: the same as Byte2 of READ DEFECT DATA (10).
+    let (req, header_len) = if cmd.command == READ_DEFECT_DATA {
+        (cmd.buf[2] & 0x1f, 4)
+    } else {
+        (cmd.buf[1] & 0x1f, 8)
+    };
+    // Header: Byte1: bit4: PLISTV, bit3: GLISTV, bits[0-2]: Defect List Format. Then the
+    // Defect List Length, Bytes[2-3] in (10) and Bytes[4-7] in (12), which is 0.
+    let mut outbuf = vec![0_u8; header_len];
+    outbuf[1] = req;
+    Ok(outbuf)
+}
+
 fn scsi_command_emulate_read_disc_information(
     cmd: &ScsiCommand,
     dev: &Arc<Mutex<ScsiDevice>>,
@@ -1661,10 +3037,12 @@ fn scsi_command_emulate_get_event_status_notification(
         // Byte6: Start Slot.
         // Byte7: End Slot.
 
-        // Do not support hot-plug/hot-unplug scsi cd which will be present all the time once vm starts.
-        // To do: this outbuf event code and media status should be changed after allowing hot-plug.
+        // The medium is absent only when the tray is open.
         outbuf[4] = GESN_EC_NOCHG;
-        outbuf[5] = 1 << GESN_MS_MEDIA_PRESENT_BIT;
+        outbuf[5] = match dev_lock.tray_open {
+            true => 1 << GESN_MS_DOOR_OR_TRAY_OPEN_BIT,
+            false => 1 << GESN_MS_MEDIA_PRESENT_BIT,
+        };
     } else {
         // NCE = 1.
         outbuf[2] = 0x80;
@@ -1742,3 +3120,1227 @@ fn cdrom_read_formatted_toc(nb_sectors: u32, msf: u8, track_number: u8) -> Resul
 
     Ok(outbuf)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qmp_block_resize;
+    use crate::ScsiDisk::SCSI_DISK_DEFAULT_BLOCK_SIZE;
+    use address_space::{GuestAddress, HostMemMapping, Region};
+    use machine_manager::config::ScsiDevConfig;
+    use machine_manager::qmp::QmpChannel;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use util::aio::iov_from_buf_direct;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const TEST_DISK_SECTORS: u64 = 16;
+
+    fn create_test_device(image: &TempFile) -> Arc<Mutex<ScsiDevice>> {
+        let file = image.as_file();
+        let content: Vec<u8> = (0..TEST_DISK_SECTORS * DEFAULT_SECTOR_SIZE as u64)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        file.write_all_at(&content, 0).unwrap();
+
+        let mut dev = ScsiDevice::new(
+            ScsiDevConfig::default(),
+            SCSI_TYPE_DISK,
+            Arc::new(Mutex::new(HashMap::new())),
+        );
+        dev.realize().unwrap();
+        dev.disk_image = Some(Arc::new(File::open(image.as_path()).unwrap()));
+        dev.disk_sectors = TEST_DISK_SECTORS;
+        Arc::new(Mutex::new(dev))
+    }
+
+    fn verify_10_cmd(bytchk: u8, lba: u32, nb_blocks: u16) -> ScsiCommand {
+        let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+        buf[0] = VERIFY_10;
+        buf[1] = bytchk << 1;
+        BigEndian::write_u32(&mut buf[2..6], lba);
+        BigEndian::write_u16(&mut buf[7..9], nb_blocks);
+        ScsiCommand {
+            buf,
+            command: VERIFY_10,
+            len: 10,
+            xfer: 0,
+            lba: lba as u64,
+            mode: ScsiXferMode::ScsiXferToDev,
+        }
+    }
+
+    fn data_iovec(data: &[u8]) -> Vec<Iovec> {
+        // Split the payload into two iovecs to cover the comparison across iovec boundary.
+        let half = data.len() / 2;
+        vec![
+            Iovec {
+                iov_base: data.as_ptr() as u64,
+                iov_len: half as u64,
+            },
+            Iovec {
+                iov_base: data[half..].as_ptr() as u64,
+                iov_len: (data.len() - half) as u64,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_scsi_cdb_xfer_verify() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = VERIFY_10;
+        BigEndian::write_u16(&mut cdb[7..9], 4);
+        assert_eq!(scsi_cdb_xfer(&cdb, dev.clone()), 0);
+
+        cdb[1] = 1 << 1;
+        assert_eq!(
+            scsi_cdb_xfer(&cdb, dev),
+            4 * SCSI_DISK_DEFAULT_BLOCK_SIZE as i32
+        );
+    }
+
+    #[test]
+    fn test_scsi_emulate_verify() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        let block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE as usize;
+
+        // BYTCHK = 0: only check the range is readable.
+        let cmd = verify_10_cmd(0, 2, 4);
+        assert!(scsi_command_emulate_verify(&cmd, &dev, &[])
+            .unwrap()
+            .is_none());
+
+        // Out of range.
+        let cmd = verify_10_cmd(0, 14, 4);
+        assert_eq!(
+            scsi_command_emulate_verify(&cmd, &dev, &[]).unwrap(),
+            Some((SCSI_SENSE_LBA_OUT_OF_RANGE, None))
+        );
+
+        // BYTCHK = 1: matching payload.
+        let mut data = vec![0_u8; 4 * block_size];
+        image
+            .as_file()
+            .read_exact_at(&mut data, 2 * block_size as u64)
+            .unwrap();
+        let cmd = verify_10_cmd(1, 2, 4);
+        assert!(scsi_command_emulate_verify(&cmd, &dev, &data_iovec(&data))
+            .unwrap()
+            .is_none());
+
+        // BYTCHK = 1: mismatching payload reports the offset of the first different byte.
+        let mismatch = 3 * block_size + 7;
+        data[mismatch] = !data[mismatch];
+        data[mismatch + 1] = !data[mismatch + 1];
+        assert_eq!(
+            scsi_command_emulate_verify(&cmd, &dev, &data_iovec(&data)).unwrap(),
+            Some((SCSI_SENSE_MISCOMPARE_DURING_VERIFY, Some(mismatch as u32)))
+        );
+
+        // Data-out buffer is shorter than the verification length.
+        assert!(scsi_command_emulate_verify(&cmd, &dev, &data_iovec(&data[..block_size])).is_err());
+
+        // BYTCHK = 2 is not supported.
+        let cmd = verify_10_cmd(2, 2, 4);
+        assert!(scsi_command_emulate_verify(&cmd, &dev, &[]).is_err());
+    }
+
+    fn rw_cmd(
+        cdb: &[u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE],
+        dev: &Arc<Mutex<ScsiDevice>>,
+    ) -> ScsiCommand {
+        let scsibus = ScsiBus::new("test".to_string(), Weak::new());
+        scsibus.scsi_bus_parse_req_cdb(*cdb, dev.clone()).unwrap()
+    }
+
+    #[test]
+    fn test_scsi_synchronize_cache_cdb() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        for (opcode, len) in [(SYNCHRONIZE_CACHE, 10), (SYNCHRONIZE_CACHE_16, 16)] {
+            // Both are flushed by the backend, they never transfer data.
+            let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+            cdb[0] = opcode;
+            cdb[8] = 8;
+            cdb[13] = 8;
+            let cmd = rw_cmd(&cdb, &dev);
+            assert_eq!(cmd.len, len);
+            assert_eq!(cmd.xfer, 0);
+            assert_eq!(scsi_operation_type(opcode), NON_EMULATE_SCSI_OPS);
+        }
+    }
+
+    #[test]
+    fn test_scsi_rw_6_cdb() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        let block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE;
+
+        // LBA of 6 bytes commands is the low 21 bits of Byte[1-3].
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = READ_6;
+        cdb[1] = 0xe0 | 0x01;
+        cdb[2] = 0x02;
+        cdb[3] = 0x03;
+        cdb[4] = 4;
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(cmd.lba, 0x010203);
+        assert_eq!(cmd.xfer, 4 * block_size);
+
+        // Transfer length 0 means 256 blocks.
+        cdb[0] = WRITE_6;
+        cdb[4] = 0;
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(cmd.xfer, 256 * block_size);
+
+        // But means no transfer for 10 bytes commands.
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = READ_10;
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(cmd.xfer, 0);
+    }
+
+    #[test]
+    fn test_scsi_check_rw_range() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        let block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE as u64;
+        let disk_blocks = TEST_DISK_SECTORS * DEFAULT_SECTOR_SIZE as u64 / block_size;
+
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = READ_6;
+        cdb[3] = (disk_blocks - 4) as u8;
+        cdb[4] = 4;
+        // The last blocks of the disk.
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(scsi_check_rw_range(&cmd, &dev, 4 * block_size), None);
+        // Data buffer longer than the transfer length is fine, the rest is resid.
+        assert_eq!(scsi_check_rw_range(&cmd, &dev, 8 * block_size), None);
+        // Short data buffer.
+        assert_eq!(
+            scsi_check_rw_range(&cmd, &dev, 4 * block_size - 1),
+            Some(SCSI_SENSE_INVALID_FIELD)
+        );
+
+        // One block beyond the end of the disk.
+        cdb[3] = (disk_blocks - 3) as u8;
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(
+            scsi_check_rw_range(&cmd, &dev, 4 * block_size),
+            Some(SCSI_SENSE_LBA_OUT_OF_RANGE)
+        );
+
+        // Zero transfer length of READ_6 is 256 blocks which exceeds the small disk.
+        cdb[0] = WRITE_6;
+        cdb[3] = 0;
+        cdb[4] = 0;
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(
+            scsi_check_rw_range(&cmd, &dev, 256 * block_size),
+            Some(SCSI_SENSE_LBA_OUT_OF_RANGE)
+        );
+
+        // Zero transfer length of READ_10 is valid even at the end of disk.
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = READ_10;
+        BigEndian::write_u32(&mut cdb[2..6], disk_blocks as u32);
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(scsi_check_rw_range(&cmd, &dev, 0), None);
+
+        // Huge LBA of 16 bytes commands.
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = WRITE_16;
+        BigEndian::write_u64(&mut cdb[2..10], i64::MAX as u64);
+        BigEndian::write_u32(&mut cdb[10..14], 1);
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(
+            scsi_check_rw_range(&cmd, &dev, block_size),
+            Some(SCSI_SENSE_LBA_OUT_OF_RANGE)
+        );
+    }
+
+    fn write_verify_10_cdb(
+        bytchk: u8,
+        lba: u32,
+        nb_blocks: u16,
+    ) -> [u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE] {
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = WRITE_VERIFY_10;
+        cdb[1] = bytchk << 1;
+        BigEndian::write_u32(&mut cdb[2..6], lba);
+        BigEndian::write_u16(&mut cdb[7..9], nb_blocks);
+        cdb
+    }
+
+    #[test]
+    fn test_scsi_write_verify_cdb() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        let block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE as u64;
+
+        // The data-out buffer is transferred whatever BYTCHK is.
+        for bytchk in 0..2 {
+            let cmd = rw_cmd(&write_verify_10_cdb(bytchk, 2, 4), &dev);
+            assert_eq!(cmd.xfer as u64, 4 * block_size);
+            assert_eq!(write_verify_bytchk(&cmd), Some(bytchk));
+            assert_eq!(scsi_check_rw_range(&cmd, &dev, 4 * block_size), None);
+        }
+        let cmd = rw_cmd(&write_verify_10_cdb(3, 2, 4), &dev);
+        assert_eq!(
+            scsi_check_rw_range(&cmd, &dev, 4 * block_size),
+            Some(SCSI_SENSE_INVALID_FIELD)
+        );
+
+        // The buffered data-out buffer is bounded, only for BYTCHK=1.
+        dev.lock().unwrap().config.write_verify_max = 2 * block_size;
+        let cmd = rw_cmd(&write_verify_10_cdb(1, 2, 4), &dev);
+        assert_eq!(
+            scsi_check_rw_range(&cmd, &dev, 4 * block_size),
+            Some(SCSI_SENSE_INVALID_FIELD)
+        );
+        let cmd = rw_cmd(&write_verify_10_cdb(0, 2, 4), &dev);
+        assert_eq!(scsi_check_rw_range(&cmd, &dev, 4 * block_size), None);
+
+        let cmd = rw_cmd(&write_verify_10_cdb(0, 2, 4), &dev);
+        assert_eq!(scsi_operation_type(cmd.command), NON_EMULATE_SCSI_OPS);
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = WRITE_10;
+        assert_eq!(write_verify_bytchk(&rw_cmd(&cdb, &dev)), None);
+    }
+
+    #[test]
+    fn test_scsi_write_verify() {
+        let image = TempFile::new().unwrap();
+        let _dev = create_test_device(&image);
+        let file = image.as_file();
+        let fd = file.as_raw_fd();
+        let block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE as u64;
+
+        // BYTCHK = 0: the written data is only synced.
+        assert_eq!(scsi_write_verify(fd, 0, 1, &WriteVerify::Sync), None);
+
+        // BYTCHK = 1: the data read back matches the data-out buffer.
+        let offset = 2 * block_size;
+        let mut data = vec![0_u8; 4 * block_size as usize];
+        file.read_exact_at(&mut data, offset).unwrap();
+        let verify = WriteVerify::Compare(Arc::new(data.clone()));
+        assert_eq!(scsi_write_verify(fd, offset, 1, &verify), None);
+        // The read back is aligned for O_DIRECT backends.
+        assert_eq!(scsi_write_verify(fd, offset, 4096, &verify), None);
+
+        // The medium differs from the data-out buffer.
+        let mismatch = block_size as usize + 3;
+        file.write_all_at(&[!data[mismatch]], offset + mismatch as u64)
+            .unwrap();
+        assert_eq!(
+            scsi_write_verify(fd, offset, 1, &verify),
+            Some((SCSI_SENSE_MISCOMPARE_DURING_VERIFY, Some(mismatch as u32)))
+        );
+
+        // The data can't be read back beyond the end of the backend.
+        let end = TEST_DISK_SECTORS * DEFAULT_SECTOR_SIZE as u64;
+        assert_eq!(
+            scsi_write_verify(fd, end, 1, &verify),
+            Some((SCSI_SENSE_READ_ERROR, None))
+        );
+    }
+
+    #[test]
+    fn test_scsi_data_in_resid() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = INQUIRY;
+        BigEndian::write_u16(&mut cdb[3..5], 40);
+        let outbuf = scsi_command_emulate_inquiry(&rw_cmd(&cdb, &dev), &dev).unwrap();
+        assert_eq!(outbuf.len(), 40);
+
+        // Two ram regions of 4K: [0, 4K) and [4K, 8K).
+        let root = Region::init_container_region(1 << 16);
+        let mem_space = AddressSpace::new(root.clone()).unwrap();
+        let mut regions = Vec::new();
+        for base in [0, 0x1000] {
+            let mapping =
+                HostMemMapping::new(GuestAddress(base), None, 0x1000, None, false, false, false);
+            let region = Region::init_ram_region(Arc::new(mapping.unwrap()));
+            root.add_subregion(region.clone(), base).unwrap();
+            regions.push(region);
+        }
+        let iovec = |ranges: &[HostRange]| -> Vec<Iovec> {
+            ranges
+                .iter()
+                .map(|range| Iovec {
+                    iov_base: range.hva,
+                    iov_len: range.len,
+                })
+                .collect()
+        };
+        let mut data = vec![0_u8; 64];
+
+        // The data-in buffer of 64 bytes crosses the boundary of the regions.
+        let ranges = mem_space.get_host_ranges(GuestAddress(0xfe0), 64).unwrap();
+        assert_eq!(ranges.len(), 2);
+        iov_from_buf_direct(&iovec(&ranges), &[0xff_u8; 64]).unwrap();
+        assert_eq!(
+            scsi_data_in_resid(&mem_space, &ranges, 64, &outbuf).unwrap(),
+            24
+        );
+        iov_to_buf_direct(&iovec(&ranges), &mut data).unwrap();
+        assert_eq!(&data[..40], &outbuf[..]);
+        assert!(data[40..].iter().all(|b| *b == 0xff));
+
+        // Data longer than the data-in buffer is truncated.
+        let ranges = mem_space.get_host_ranges(GuestAddress(0x100), 24).unwrap();
+        assert_eq!(
+            scsi_data_in_resid(&mem_space, &ranges, 24, &outbuf).unwrap(),
+            0
+        );
+        iov_to_buf_direct(&iovec(&ranges), &mut data[..24]).unwrap();
+        assert_eq!(&data[..24], &outbuf[..24]);
+
+        // The second region is removed while the command is emulated, the completion fails
+        // instead of writing to it, and the memory stays mapped for the request.
+        let ranges = mem_space.get_host_ranges(GuestAddress(0x1100), 64).unwrap();
+        root.delete_subregion(&regions.pop().unwrap()).unwrap();
+        assert!(scsi_data_in_resid(&mem_space, &ranges, 64, &outbuf).is_err());
+        assert_eq!(iov_to_buf_direct(&iovec(&ranges), &mut data).unwrap(), 64);
+        assert!(data.iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_scsi_emulate_cdb_smoke() {
+        // A short run of the scsi cdb fuzz target with pseudo-random cdbs.
+        let bus = Arc::new(Mutex::new(ScsiBus::new("test".to_string(), Weak::new())));
+        let opcodes = [
+            INQUIRY,
+            READ_CAPACITY_10,
+            MODE_SENSE,
+            MODE_SENSE_10,
+            SERVICE_ACTION_IN_16,
+            READ_DISC_INFORMATION,
+            GET_EVENT_STATUS_NOTIFICATION,
+            READ_TOC,
+            GET_CONFIGURATION,
+            REPORT_LUNS,
+            MAINTENANCE_IN,
+        ];
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        for scsi_type in [SCSI_TYPE_DISK, SCSI_TYPE_ROM] {
+            let mut dev = ScsiDevice::new(
+                ScsiDevConfig::default(),
+                scsi_type,
+                Arc::new(Mutex::new(HashMap::new())),
+            );
+            dev.realize().unwrap();
+            // The cdrom has no medium.
+            if scsi_type == SCSI_TYPE_DISK {
+                dev.disk_sectors = TEST_DISK_SECTORS;
+            }
+            dev.parent_bus = Arc::downgrade(&bus);
+            let dev = Arc::new(Mutex::new(dev));
+            bus.lock().unwrap().devices.insert((0, 0), dev.clone());
+
+            for i in 0..4096 {
+                let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+                for byte in cdb.iter_mut() {
+                    // Small values are more likely to be valid fields.
+                    *byte = match next() % 4 {
+                        0 => 0,
+                        1 => next() as u8 & 0x3,
+                        _ => next() as u8,
+                    };
+                }
+                cdb[0] = match i % 4 {
+                    0 => next() as u8,
+                    _ => opcodes[next() as usize % opcodes.len()],
+                };
+                let lun = next() as u16 & 1;
+                if let Some(Ok(outbuf)) = scsi_emulate_cdb(cdb, &dev, lun) {
+                    assert!(outbuf.len() <= rw_cmd(&cdb, &dev).xfer as usize);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_scsi_write_protect() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = WRITE_10;
+        BigEndian::write_u16(&mut cdb[7..9], 1);
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(scsi_check_write_protect(&cmd, &dev), None);
+
+        dev.lock().unwrap().config.read_only = true;
+        for opcode in [
+            WRITE_10,
+            WRITE_16,
+            WRITE_SAME_10,
+            WRITE_SAME_16,
+            UNMAP,
+            FORMAT_UNIT,
+        ] {
+            cdb[0] = opcode;
+            let cmd = rw_cmd(&cdb, &dev);
+            let sense = scsi_check_write_protect(&cmd, &dev).unwrap();
+            let mut resp = VirtioScsiCmdResp::default();
+            resp.set_scsi_sense(sense);
+            assert_eq!(resp.sense[2], DATA_PROTECT);
+            assert_eq!(resp.sense[12], 0x27);
+        }
+        // Reads are still allowed.
+        cdb[0] = READ_10;
+        let cmd = rw_cmd(&cdb, &dev);
+        assert_eq!(scsi_check_write_protect(&cmd, &dev), None);
+
+        // MODE SENSE reports the WP bit in the device-specific parameter.
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = MODE_SENSE;
+        cdb[2] = 0x3f;
+        cdb[4] = 0xff;
+        let outbuf = scsi_emulate_cdb(cdb, &dev, 0).unwrap().unwrap();
+        assert_eq!(outbuf[2] & 0x80, 0x80);
+    }
+
+    #[test]
+    fn test_scsi_sense_info() {
+        let mut resp = VirtioScsiCmdResp::default();
+        resp.set_scsi_sense(SCSI_SENSE_MISCOMPARE_DURING_VERIFY);
+        resp.set_scsi_sense_info(0x1234);
+        assert_eq!(resp.sense[0], 0xf0);
+        assert_eq!(resp.sense[2], MISCOMPARE);
+        assert_eq!(&resp.sense[3..7], &[0, 0, 0x12, 0x34]);
+        assert_eq!(resp.sense[12], 0x1d);
+        assert_eq!(resp.sense[13], 0x00);
+    }
+
+    fn hotplug_test_device(id: &str, target: u8, lun: u16) -> Arc<Mutex<ScsiDevice>> {
+        let config = ScsiDevConfig {
+            id: id.to_string(),
+            target,
+            lun,
+            ..Default::default()
+        };
+        Arc::new(Mutex::new(ScsiDevice::new(
+            config,
+            SCSI_TYPE_DISK,
+            Arc::new(Mutex::new(HashMap::new())),
+        )))
+    }
+
+    #[test]
+    fn test_scsi_bus_hotplug() {
+        let bus = Arc::new(Mutex::new(ScsiBus::new("scsi0.0".to_string(), Weak::new())));
+        let dev0 = hotplug_test_device("disk0", 1, 0);
+        let dev1 = hotplug_test_device("disk1", 1, 1);
+        let dev2 = hotplug_test_device("disk2", 2, 0);
+        scsi_bus_attach_device(&bus, &dev0).unwrap();
+        scsi_bus_attach_device(&bus, &dev2).unwrap();
+        assert!(dev0.lock().unwrap().parent_bus.upgrade().is_some());
+        assert_eq!(dev0.lock().unwrap().unit_attention, None);
+
+        // Plugging lun 1 of target 1 is reported to lun 0 of the same target only.
+        scsi_bus_attach_device(&bus, &dev1).unwrap();
+        assert_eq!(
+            dev0.lock().unwrap().unit_attention,
+            Some(SCSI_SENSE_REPORTED_LUNS_CHANGED)
+        );
+        assert_eq!(dev1.lock().unwrap().unit_attention, None);
+        assert_eq!(dev2.lock().unwrap().unit_attention, None);
+        assert_eq!(
+            bus.lock()
+                .unwrap()
+                .find_device_by_id("disk1")
+                .unwrap()
+                .lock()
+                .unwrap()
+                .config
+                .lun,
+            1
+        );
+
+        // The same address can't be used twice.
+        let dup = hotplug_test_device("disk3", 1, 1);
+        assert!(scsi_bus_attach_device(&bus, &dup).is_err());
+        assert_eq!(bus.lock().unwrap().devices.len(), 3);
+
+        dev0.lock().unwrap().unit_attention = None;
+        let removed = scsi_bus_detach_device(&bus, 1, 1).unwrap();
+        assert!(Arc::ptr_eq(&removed, &dev1));
+        assert!(bus.lock().unwrap().find_device_by_id("disk1").is_none());
+        assert_eq!(
+            dev0.lock().unwrap().unit_attention,
+            Some(SCSI_SENSE_REPORTED_LUNS_CHANGED)
+        );
+        assert!(scsi_bus_detach_device(&bus, 1, 1).is_err());
+    }
+
+    #[test]
+    fn test_scsi_bus_detach_inflight() {
+        let bus = Arc::new(Mutex::new(ScsiBus::new("scsi0.0".to_string(), Weak::new())));
+        let dev = hotplug_test_device("disk0", 0, 0);
+        scsi_bus_attach_device(&bus, &dev).unwrap();
+
+        // The device is kept on the bus if its requests are not completed in time.
+        let inflight = Arc::new(InflightRef::new(dev.lock().unwrap().inflight.clone()));
+        let req = inflight.clone();
+        assert!(scsi_bus_detach_device(&bus, 0, 0).is_err());
+        assert!(bus.lock().unwrap().find_device_by_id("disk0").is_some());
+
+        // Copies of a request are counted once.
+        drop(inflight);
+        assert_eq!(dev.lock().unwrap().inflight.load(Ordering::SeqCst), 1);
+        drop(req);
+        assert_eq!(dev.lock().unwrap().inflight.load(Ordering::SeqCst), 0);
+        scsi_bus_detach_device(&bus, 0, 0).unwrap();
+        assert!(bus.lock().unwrap().devices.is_empty());
+    }
+
+    fn read_capacity_16(dev: &Arc<Mutex<ScsiDevice>>) -> (u64, u32) {
+        let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+        buf[0] = SERVICE_ACTION_IN_16;
+        buf[1] = SUBCODE_READ_CAPACITY_16;
+        let cmd = ScsiCommand {
+            buf,
+            command: SERVICE_ACTION_IN_16,
+            len: 16,
+            xfer: 32,
+            lba: 0,
+            mode: ScsiXferMode::ScsiXferFromDev,
+        };
+        let outbuf = scsi_command_emulate_service_action_in_16(&cmd, dev).unwrap();
+        (
+            BigEndian::read_u64(&outbuf[0..8]),
+            BigEndian::read_u32(&outbuf[8..12]),
+        )
+    }
+
+    fn read_capacity_10_cmd() -> ScsiCommand {
+        let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+        buf[0] = READ_CAPACITY_10;
+        ScsiCommand {
+            buf,
+            command: READ_CAPACITY_10,
+            len: 10,
+            xfer: 8,
+            lba: 0,
+            mode: ScsiXferMode::ScsiXferFromDev,
+        }
+    }
+
+    fn read_capacity_10(dev: &Arc<Mutex<ScsiDevice>>) -> (u32, u32) {
+        let outbuf = scsi_command_emulate_read_capacity_10(&read_capacity_10_cmd(), dev).unwrap();
+        (
+            BigEndian::read_u32(&outbuf[0..4]),
+            BigEndian::read_u32(&outbuf[4..8]),
+        )
+    }
+
+    #[test]
+    fn test_scsi_read_capacity() {
+        const TIB_SECTORS: u64 = 1 << 31;
+        let dev = test_device(SCSI_TYPE_DISK);
+
+        // (disk sectors, block size, last LBA of READ CAPACITY(10), of READ CAPACITY(16))
+        for (disk_sectors, block_size, last_lba_10, last_lba_16) in [
+            (TEST_DISK_SECTORS, 512, 15, 15),
+            (1, 512, 0, 0),
+            (2 * TIB_SECTORS - 1, 512, 0xffff_fffe, 0xffff_fffe),
+            // Exactly 2TiB, the last LBA is the max value of 32 bits.
+            (2 * TIB_SECTORS, 512, 0xffff_ffff, 0xffff_ffff),
+            (2 * TIB_SECTORS + 1, 512, 0xffff_ffff, 0x1_0000_0000),
+            (4 * TIB_SECTORS, 512, 0xffff_ffff, 0x1_ffff_ffff),
+            (4 * TIB_SECTORS, 4096, 0x3fff_ffff, 0x3fff_ffff),
+            (64 * TIB_SECTORS, 4096, 0xffff_ffff, 0x3_ffff_ffff),
+        ] {
+            dev.lock().unwrap().disk_sectors = disk_sectors;
+            dev.lock().unwrap().block_size = block_size;
+            assert_eq!(
+                read_capacity_10(&dev),
+                (last_lba_10, block_size),
+                "disk sectors {}",
+                disk_sectors
+            );
+            assert_eq!(
+                read_capacity_16(&dev),
+                (last_lba_16, block_size),
+                "disk sectors {}",
+                disk_sectors
+            );
+        }
+
+        // Less than one block.
+        dev.lock().unwrap().disk_sectors = 7;
+        assert!(scsi_command_emulate_read_capacity_10(&read_capacity_10_cmd(), &dev).is_err());
+    }
+
+    #[test]
+    fn test_scsi_block_resize() {
+        let image = TempFile::new().unwrap();
+        let dev = create_test_device(&image);
+        dev.lock().unwrap().config.id = "scsi-resize".to_string();
+        dev.lock().unwrap().disk_image = Some(Arc::new(image.as_file().try_clone().unwrap()));
+        let bus = Arc::new(Mutex::new(ScsiBus::new("scsi0.0".to_string(), Weak::new())));
+        assert!(qmp_block_resize("scsi-resize", 32 * 512, false).is_err());
+        scsi_bus_attach_device(&bus, &dev).unwrap();
+        assert_eq!(read_capacity_16(&dev), (TEST_DISK_SECTORS - 1, 512));
+
+        // Grow the image, the guest sees the new capacity after the unit attention.
+        qmp_block_resize("scsi-resize", 32 * 512, false).unwrap();
+        assert_eq!(image.as_file().metadata().unwrap().len(), 32 * 512);
+        assert_eq!(read_capacity_16(&dev), (31, 512));
+        assert_eq!(
+            dev.lock().unwrap().unit_attention,
+            Some(SCSI_SENSE_CAPACITY_CHANGED)
+        );
+
+        // Unaligned sizes and shrinking without allow-shrink are refused.
+        assert!(qmp_block_resize("scsi-resize", 32 * 512 + 1, false).is_err());
+        assert!(qmp_block_resize("scsi-resize", 8 * 512, false).is_err());
+        assert_eq!(read_capacity_16(&dev), (31, 512));
+        qmp_block_resize("scsi-resize", 8 * 512, true).unwrap();
+        assert_eq!(image.as_file().metadata().unwrap().len(), 8 * 512);
+        assert_eq!(read_capacity_16(&dev), (7, 512));
+
+        dev.lock().unwrap().config.read_only = true;
+        assert!(qmp_block_resize("scsi-resize", 64 * 512, false).is_err());
+
+        // The detached device can't be resized any more.
+        scsi_bus_detach_device(&bus, 0, 0).unwrap();
+        assert!(qmp_block_resize("scsi-resize", 64 * 512, false).is_err());
+    }
+
+    #[test]
+    fn test_scsi_start_stop_unit() {
+        // DEVICE_TRAY_MOVED is emitted by the tray movements.
+        QmpChannel::object_init();
+
+        // START STOP UNIT of a non-removable disk only returns GOOD.
+        let disk = test_device(SCSI_TYPE_DISK);
+        let mut locked_disk = disk.lock().unwrap();
+        assert!(!locked_disk.is_removable());
+        assert_eq!(locked_disk.start_stop(false, true), None);
+        assert!(!locked_disk.tray_open);
+        assert_eq!(locked_disk.check_medium(), None);
+        assert!(locked_disk.eject(true).is_err());
+        drop(locked_disk);
+
+        let cd = hotplug_test_device("scsi-cd0", 0, 0);
+        cd.lock().unwrap().scsi_type = SCSI_TYPE_ROM;
+        let bus = Arc::new(Mutex::new(ScsiBus::new("scsi0.0".to_string(), Weak::new())));
+        scsi_bus_attach_device(&bus, &cd).unwrap();
+        let mut locked_cd = cd.lock().unwrap();
+        assert!(locked_cd.is_removable());
+
+        // START without LOEJ doesn't move the tray.
+        assert_eq!(locked_cd.start_stop(false, false), None);
+        assert!(!locked_cd.tray_open);
+        // Unload the medium, the commands accessing it get NOT READY, MEDIUM NOT PRESENT.
+        assert_eq!(locked_cd.start_stop(false, true), None);
+        assert!(locked_cd.tray_open);
+        let mut resp = VirtioScsiCmdResp::default();
+        resp.set_scsi_sense(locked_cd.check_medium().unwrap());
+        assert_eq!(resp.sense[2], NOT_READY);
+        assert_eq!(resp.sense[12], 0x3a);
+        // Load it again.
+        assert_eq!(locked_cd.start_stop(true, true), None);
+        assert_eq!(locked_cd.check_medium(), None);
+
+        // The removal prevented by the guest fails unless it's forced.
+        locked_cd.prevent_removal = true;
+        assert_eq!(
+            locked_cd.start_stop(false, true),
+            Some(SCSI_SENSE_NOT_READY_REMOVAL_PREVENTED)
+        );
+        assert!(!locked_cd.tray_open);
+        drop(locked_cd);
+        assert!(qmp_eject("scsi-cd0", false).is_err());
+        assert!(!cd.lock().unwrap().tray_open);
+        qmp_eject("scsi-cd0", true).unwrap();
+        assert!(cd.lock().unwrap().tray_open);
+        assert!(!cd.lock().unwrap().prevent_removal);
+        // Ejecting an open tray is accepted.
+        qmp_eject("scsi-cd0", false).unwrap();
+
+        // GET EVENT STATUS NOTIFICATION reports the open tray.
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = GET_EVENT_STATUS_NOTIFICATION;
+        cdb[1] = 1;
+        cdb[4] = 1 << GESN_MEDIA;
+        BigEndian::write_u16(&mut cdb[7..9], 8);
+        let outbuf = scsi_emulate_cdb(cdb, &cd, 0).unwrap().unwrap();
+        assert_eq!(outbuf[5], 1 << GESN_MS_DOOR_OR_TRAY_OPEN_BIT);
+
+        scsi_bus_detach_device(&bus, 0, 0).unwrap();
+        assert!(qmp_eject("scsi-cd0", true).is_err());
+    }
+
+    fn get_lba_status_cmd(lba: u64, alloc_len: u32) -> ScsiCommand {
+        let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+        buf[0] = SERVICE_ACTION_IN_16;
+        buf[1] = SUBCODE_GET_LBA_STATUS;
+        BigEndian::write_u64(&mut buf[2..10], lba);
+        BigEndian::write_u32(&mut buf[10..14], alloc_len);
+        ScsiCommand {
+            buf,
+            command: SERVICE_ACTION_IN_16,
+            len: 16,
+            xfer: alloc_len,
+            lba,
+            mode: ScsiXferMode::ScsiXferFromDev,
+        }
+    }
+
+    fn get_lba_status(
+        dev: &Arc<Mutex<ScsiDevice>>,
+        lba: u64,
+        alloc_len: u32,
+    ) -> std::result::Result<Vec<(u64, u32, u8)>, ScsiSense> {
+        let cmd = get_lba_status_cmd(lba, alloc_len);
+        let outbuf = scsi_command_emulate_get_lba_status(&cmd, dev).unwrap()?;
+        assert_eq!(
+            BigEndian::read_u32(&outbuf[0..4]) as usize,
+            outbuf.len() - 4
+        );
+        Ok(outbuf[8..]
+            .chunks(16)
+            .map(|desc| {
+                (
+                    BigEndian::read_u64(&desc[0..8]),
+                    BigEndian::read_u32(&desc[8..12]),
+                    desc[12],
+                )
+            })
+            .collect())
+    }
+
+    #[test]
+    fn test_scsi_get_lba_status() {
+        // Sparse image of 128 sectors, with data in sectors [0, 8) and [64, 72).
+        let image = TempFile::new().unwrap();
+        let file = image.as_file();
+        file.set_len(128 * 512).unwrap();
+        file.write_all_at(&[0x5a_u8; 4096], 0).unwrap();
+        file.write_all_at(&[0x5a_u8; 4096], 64 * 512).unwrap();
+        let mut dev = ScsiDevice::new(
+            ScsiDevConfig::default(),
+            SCSI_TYPE_DISK,
+            Arc::new(Mutex::new(HashMap::new())),
+        );
+        dev.realize().unwrap();
+        let dev = Arc::new(Mutex::new(dev));
+        // No backend.
+        assert!(
+            scsi_command_emulate_service_action_in_16(&get_lba_status_cmd(0, 24), &dev).is_err()
+        );
+        dev.lock().unwrap().disk_image = Some(Arc::new(image.as_file().try_clone().unwrap()));
+        dev.lock().unwrap().disk_sectors = 128;
+
+        assert_eq!(
+            get_lba_status(&dev, 0, 1024),
+            Ok(vec![
+                (0, 8, LBA_STATUS_MAPPED),
+                (8, 56, LBA_STATUS_DEALLOCATED),
+                (64, 8, LBA_STATUS_MAPPED),
+                (72, 56, LBA_STATUS_DEALLOCATED),
+            ])
+        );
+        // The first descriptor starts at the starting LBA, and the number of descriptors
+        // is limited by the allocation length.
+        assert_eq!(
+            get_lba_status(&dev, 10, 8 + 2 * 16),
+            Ok(vec![
+                (10, 54, LBA_STATUS_DEALLOCATED),
+                (64, 8, LBA_STATUS_MAPPED)
+            ])
+        );
+        assert_eq!(
+            get_lba_status(&dev, 70, 0),
+            Ok(vec![(70, 2, LBA_STATUS_MAPPED)])
+        );
+        assert_eq!(
+            get_lba_status(&dev, 128, 1024),
+            Err(SCSI_SENSE_LBA_OUT_OF_RANGE)
+        );
+
+        // Blocks beyond the end of the image are deallocated.
+        dev.lock().unwrap().disk_sectors = 160;
+        assert_eq!(
+            get_lba_status(&dev, 64, 1024),
+            Ok(vec![
+                (64, 8, LBA_STATUS_MAPPED),
+                (72, 88, LBA_STATUS_DEALLOCATED)
+            ])
+        );
+
+        // A logical block partly written is mapped.
+        dev.lock().unwrap().disk_sectors = 128;
+        dev.lock().unwrap().block_size = 4096 * 4;
+        file.write_all_at(&[0x5a_u8; 512], 5 * 4096).unwrap();
+        assert_eq!(
+            get_lba_status(&dev, 0, 1024),
+            Ok(vec![
+                (0, 3, LBA_STATUS_MAPPED),
+                (3, 1, LBA_STATUS_DEALLOCATED)
+            ])
+        );
+    }
+
+    fn report_opcodes_cmd(options: u8, opcode: u8, service_action: u16) -> ScsiCommand {
+        let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+        buf[0] = MAINTENANCE_IN;
+        buf[1] = SUBCODE_REPORT_SUPPORTED_OPCODES;
+        buf[2] = options;
+        buf[3] = opcode;
+        BigEndian::write_u16(&mut buf[4..6], service_action);
+        BigEndian::write_u32(&mut buf[6..10], 4096);
+        ScsiCommand {
+            buf,
+            command: MAINTENANCE_IN,
+            len: 12,
+            xfer: 4096,
+            lba: 0,
+            mode: ScsiXferMode::ScsiXferFromDev,
+        }
+    }
+
+    fn test_device(scsi_type: u32) -> Arc<Mutex<ScsiDevice>> {
+        let mut dev = ScsiDevice::new(
+            ScsiDevConfig::default(),
+            scsi_type,
+            Arc::new(Mutex::new(HashMap::new())),
+        );
+        dev.realize().unwrap();
+        Arc::new(Mutex::new(dev))
+    }
+
+    #[test]
+    fn test_scsi_supported_opcodes_table() {
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        for (i, desc) in SCSI_SUPPORTED_OPCODES.iter().enumerate() {
+            cdb[0] = desc.opcode;
+            assert_eq!(desc.cdb_len() as i32, scsi_cdb_length(&cdb));
+            if i > 0 {
+                let prev = &SCSI_SUPPORTED_OPCODES[i - 1];
+                assert!((prev.opcode, prev.service_action) < (desc.opcode, desc.service_action));
+            }
+        }
+
+        // The commands are rejected according to the table before they are dispatched.
+        let cmd = |opcode: u8, service_action: u8| {
+            let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+            buf[0] = opcode;
+            buf[1] = service_action;
+            ScsiCommand {
+                buf,
+                command: opcode,
+                len: 0,
+                xfer: 0,
+                lba: 0,
+                mode: ScsiXferMode::ScsiXferFromDev,
+            }
+        };
+        let cases = [
+            (READ_10, 0, SCSI_TYPE_DISK, None),
+            (START_STOP, 0, SCSI_TYPE_DISK, None),
+            (
+                FORMAT_UNIT,
+                0,
+                SCSI_TYPE_DISK,
+                Some(SCSI_SENSE_INVALID_OPCODE),
+            ),
+            (READ_TOC, 0, SCSI_TYPE_DISK, Some(SCSI_SENSE_INVALID_OPCODE)),
+            (READ_TOC, 0, SCSI_TYPE_ROM, None),
+            (SERVICE_ACTION_IN_16, 0x10, SCSI_TYPE_DISK, None),
+            (
+                SERVICE_ACTION_IN_16,
+                0x11,
+                SCSI_TYPE_DISK,
+                Some(SCSI_SENSE_INVALID_FIELD),
+            ),
+            (MAINTENANCE_IN, 0x0c, SCSI_TYPE_ROM, None),
+            (
+                MAINTENANCE_IN,
+                0x0a,
+                SCSI_TYPE_ROM,
+                Some(SCSI_SENSE_INVALID_FIELD),
+            ),
+        ];
+        for (opcode, service_action, scsi_type, sense) in cases {
+            assert_eq!(
+                scsi_check_opcode(&cmd(opcode, service_action), scsi_type),
+                sense
+            );
+        }
+    }
+
+    #[test]
+    fn test_scsi_report_supported_opcodes() {
+        let disk = test_device(SCSI_TYPE_DISK);
+        let rom = test_device(SCSI_TYPE_ROM);
+
+        // One command: reporting options, operation code, service action, device, and the
+        // expected data which is none if the command is terminated by INVALID FIELD IN CDB.
+        let mut read_10 = vec![0, 3, 0, 10, READ_10, 0x18, 0xff, 0xff, 0xff, 0xff, 0];
+        read_10.extend_from_slice(&[0xff, 0xff, 0]);
+        let mut get_lba_status = vec![0, 3, 0, 16, SERVICE_ACTION_IN_16, 0x1f];
+        get_lba_status.extend_from_slice(&[0xff; 12]);
+        get_lba_status.extend_from_slice(&[0, 0]);
+        let mut report_opcodes = vec![0, 3, 0, 12, MAINTENANCE_IN, 0x1f, 0x87];
+        report_opcodes.extend_from_slice(&[0xff; 7]);
+        report_opcodes.extend_from_slice(&[0, 0]);
+        let read_toc = vec![
+            0, 3, 0, 10, READ_TOC, 0x02, 0x0f, 0, 0, 0, 0xff, 0xff, 0xff, 0,
+        ];
+        let unsupported = vec![0, 1, 0, 0];
+        let cases = [
+            (1, READ_10, 0, &disk, Some(read_10.clone())),
+            (3, READ_10, 5, &disk, Some(read_10)),
+            (2, READ_10, 0, &disk, None),
+            (1, SERVICE_ACTION_IN_16, 0x12, &disk, None),
+            (
+                2,
+                SERVICE_ACTION_IN_16,
+                0x12,
+                &disk,
+                Some(get_lba_status.clone()),
+            ),
+            (3, SERVICE_ACTION_IN_16, 0x12, &disk, Some(get_lba_status)),
+            (
+                2,
+                SERVICE_ACTION_IN_16,
+                0x11,
+                &disk,
+                Some(unsupported.clone()),
+            ),
+            (2, MAINTENANCE_IN, 0x0c, &rom, Some(report_opcodes)),
+            (1, FORMAT_UNIT, 0, &disk, Some(unsupported.clone())),
+            (2, FORMAT_UNIT, 0, &disk, Some(unsupported.clone())),
+            (1, READ_TOC, 0, &disk, Some(unsupported)),
+            (1, READ_TOC, 0, &rom, Some(read_toc)),
+            // RCTD is not supported.
+            (0x81, READ_10, 0, &disk, None),
+            (0x80, 0, 0, &disk, None),
+            (4, READ_10, 0, &disk, None),
+        ];
+        for (options, opcode, service_action, dev, expected) in cases {
+            let cmd = report_opcodes_cmd(options, opcode, service_action);
+            let outbuf = scsi_command_emulate_report_supported_opcodes(&cmd, dev).ok();
+            assert_eq!(outbuf, expected, "options {} opcode {:#x}", options, opcode);
+        }
+
+        // All commands: 4 bytes header, then the command descriptors.
+        let cmd = report_opcodes_cmd(0, 0, 0);
+        let disk_all = scsi_command_emulate_report_supported_opcodes(&cmd, &disk).unwrap();
+        let rom_all = scsi_command_emulate_report_supported_opcodes(&cmd, &rom).unwrap();
+        assert_eq!(
+            BigEndian::read_u32(&disk_all[0..4]) as usize,
+            disk_all.len() - 4
+        );
+        assert_eq!(rom_all.len(), SCSI_SUPPORTED_OPCODES.len() * 8 + 4);
+        // READ TOC, GET CONFIGURATION, GET EVENT STATUS NOTIFICATION and READ DISC
+        // INFORMATION are only supported by cdrom.
+        assert_eq!(disk_all.len(), rom_all.len() - 4 * 8);
+        let descriptors: Vec<&[u8]> = disk_all[4..].chunks(8).collect();
+        assert_eq!(descriptors[0], [TEST_UNIT_READY, 0, 0, 0, 0, 0, 0, 6]);
+        assert!(descriptors.contains(&&[READ_16, 0, 0, 0, 0, 0, 0, 16][..]));
+        assert!(descriptors.contains(&&[SERVICE_ACTION_IN_16, 0, 0, 0x10, 0, 1, 0, 16][..]));
+        assert!(descriptors.contains(&&[MAINTENANCE_IN, 0, 0, 0x0c, 0, 1, 0, 12][..]));
+        assert!(descriptors.contains(&&[PERSISTENT_RESERVE_OUT, 0, 0, 3, 0, 1, 0, 10][..]));
+        assert!(!descriptors.contains(&&[READ_TOC, 0, 0, 0, 0, 0, 0, 10][..]));
+        assert!(rom_all[4..]
+            .chunks(8)
+            .any(|d| d == [READ_TOC, 0, 0, 0, 0, 0, 0, 10]));
+    }
+
+    #[test]
+    fn test_scsi_log_sense_and_read_defect_data() {
+        let disk = test_device(SCSI_TYPE_DISK);
+        let log_sense = |pc_page: u8, alloc_len: u16| {
+            let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+            cdb[0] = LOG_SENSE;
+            cdb[2] = pc_page;
+            BigEndian::write_u16(&mut cdb[7..9], alloc_len);
+            scsi_emulate_cdb(cdb, &disk, 0).unwrap().ok()
+        };
+
+        assert_eq!(
+            log_sense(0x40, 0xff).unwrap(),
+            vec![0, 0, 0, 3, 0x00, 0x0d, 0x2f]
+        );
+        assert_eq!(
+            log_sense(0x4d, 0xff).unwrap(),
+            vec![0x0d, 0, 0, 12, 0, 0, 3, 2, 0, 40, 0, 1, 3, 2, 0, 70]
+        );
+        assert_eq!(
+            log_sense(0x6f, 0xff).unwrap(),
+            vec![0x2f, 0, 0, 8, 0, 0, 3, 4, 0, 0, 40, 0]
+        );
+        // The page length is not changed when the data is truncated.
+        assert_eq!(log_sense(0x4d, 4).unwrap(), vec![0x0d, 0, 0, 12]);
+        assert_eq!(log_sense(0x40, 5).unwrap(), vec![0, 0, 0, 3, 0]);
+        assert_eq!(log_sense(0x40, 0).unwrap(), Vec::<u8>::new());
+        // Only the current cumulative values of the supported pages are reported.
+        for pc_page in [0x00, 0x8d, 0xef, 0x43] {
+            assert!(log_sense(pc_page, 0xff).is_none());
+        }
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = LOG_SENSE;
+        cdb[1] = 1;
+        cdb[2] = 0x40;
+        cdb[8] = 0xff;
+        assert!(scsi_emulate_cdb(cdb, &disk, 0).unwrap().is_err());
+
+        // Empty defect lists with the requested lists and format.
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = READ_DEFECT_DATA;
+        cdb[2] = 0x1d;
+        cdb[8] = 0xff;
+        assert_eq!(
+            scsi_emulate_cdb(cdb, &disk, 0).unwrap().unwrap(),
+            vec![0, 0x1d, 0, 0]
+        );
+        cdb[8] = 2;
+        assert_eq!(
+            scsi_emulate_cdb(cdb, &disk, 0).unwrap().unwrap(),
+            vec![0, 0x1d]
+        );
+        let mut cdb = [0_u8; VIRTIO_SCSI_CDB_DEFAULT_SIZE];
+        cdb[0] = READ_DEFECT_DATA_12;
+        cdb[1] = 0x0c;
+        BigEndian::write_u32(&mut cdb[6..10], 0x100);
+        assert_eq!(
+            scsi_emulate_cdb(cdb, &disk, 0).unwrap().unwrap(),
+            vec![0, 0x0c, 0, 0, 0, 0, 0, 0]
+        );
+    }
+
+    fn vpd_test_device(serial: Option<&str>, wwn: Option<u64>) -> Arc<Mutex<ScsiDevice>> {
+        let config = ScsiDevConfig {
+            serial: serial.map(|s| s.to_string()),
+            wwn,
+            ..Default::default()
+        };
+        let mut dev = ScsiDevice::new(config, SCSI_TYPE_DISK, Arc::new(Mutex::new(HashMap::new())));
+        dev.realize().unwrap();
+        Arc::new(Mutex::new(dev))
+    }
+
+    fn vpd_cmd(page_code: u8) -> ScsiCommand {
+        let mut buf = [0_u8; SCSI_CMD_BUF_SIZE];
+        buf[0] = INQUIRY;
+        buf[1] = 0x1;
+        buf[2] = page_code;
+        BigEndian::write_u16(&mut buf[3..5], 0xffff);
+        ScsiCommand {
+            buf,
+            command: INQUIRY,
+            len: 6,
+            xfer: 0xffff,
+            lba: 0,
+            mode: ScsiXferMode::ScsiXferFromDev,
+        }
+    }
+
+    fn t10_designator(serial: &str) -> Vec<u8> {
+        let mut expected = vec![0x02, 0x01, 0x00, (24 + serial.len()) as u8];
+        expected.extend_from_slice(b"STRA    STRA HARDDISK   ");
+        expected.extend_from_slice(serial.as_bytes());
+        expected
+    }
+
+    #[test]
+    fn test_scsi_vpd_device_identification_wwn() {
+        let dev = vpd_test_device(Some("123456"), Some(0x5000c50015ea71ac));
+        let outbuf = scsi_command_emulate_vpd_page(&vpd_cmd(0x83), &dev).unwrap();
+
+        // sg_inq --page=0x83:
+        //   Designation descriptor number 1, descriptor length: 34
+        //     designator_type: T10 vendor identification,  code_set: ASCII
+        //     associated with the Addressed logical unit
+        //       vendor id: STRA
+        //       vendor specific: STRA HARDDISK   123456
+        //   Designation descriptor number 2, descriptor length: 20
+        //     designator_type: NAA,  code_set: Binary
+        //     associated with the Addressed logical unit
+        //       NAA 6, IEEE Company_id: 0x00c50
+        //       0x6000c50015ea71ac0000000000000000
+        let mut expected = vec![0x00, 0x83, 0x00, 0x36];
+        expected.append(&mut t10_designator("123456"));
+        expected.extend_from_slice(&[0x01, 0x03, 0x00, 0x10]);
+        expected.extend_from_slice(&[0x60, 0x00, 0xc5, 0x00, 0x15, 0xea, 0x71, 0xac]);
+        expected.extend_from_slice(&[0; 8]);
+        assert_eq!(outbuf, expected);
+    }
+
+    #[test]
+    fn test_scsi_vpd_device_identification_serial() {
+        // The NAA designator is hashed from the serial number if the wwn is not configured.
+        let dev = vpd_test_device(Some("123456"), None);
+        let outbuf = scsi_command_emulate_vpd_page(&vpd_cmd(0x83), &dev).unwrap();
+        let mut expected = vec![0x00, 0x83, 0x00, 0x36];
+        expected.append(&mut t10_designator("123456"));
+        expected.extend_from_slice(&[0x01, 0x03, 0x00, 0x10]);
+        expected.extend_from_slice(&[0x66, 0xe3, 0xed, 0x7e, 0x0e, 0x67, 0x29, 0x0a]);
+        expected.extend_from_slice(&[0; 8]);
+        assert_eq!(outbuf, expected);
+
+        // Nothing unique to identify the device without serial number and wwn.
+        let dev = vpd_test_device(None, None);
+        let outbuf = scsi_command_emulate_vpd_page(&vpd_cmd(0x83), &dev).unwrap();
+        assert_eq!(outbuf, vec![0x00, 0x83, 0x00, 0x00]);
+
+        let dev = vpd_test_device(None, Some(0x5000c50015ea71ac));
+        let outbuf = scsi_command_emulate_vpd_page(&vpd_cmd(0x83), &dev).unwrap();
+        assert_eq!(
+            &outbuf[..8],
+            &[0x00, 0x83, 0x00, 0x14, 0x01, 0x03, 0x00, 0x10]
+        );
+    }
+
+    #[test]
+    fn test_scsi_vpd_device_identification_page_length() {
+        let dev = vpd_test_device(Some("123456"), Some(0x5000c50015ea71ac));
+        dev.lock().unwrap().state.device_id = "d".repeat(300);
+        let outbuf = scsi_command_emulate_vpd_page(&vpd_cmd(0x83), &dev).unwrap();
+
+        // The vendor specific designator is truncated to 255 bytes, and the page length
+        // takes two bytes.
+        assert_eq!(&outbuf[4..8], &[0x02, 0x00, 0x00, 0xff]);
+        assert_eq!(outbuf.len(), 4 + 259 + 34 + 20);
+        assert_eq!(
+            BigEndian::read_u16(&outbuf[2..4]) as usize,
+            outbuf.len() - 4
+        );
+        assert_eq!(&outbuf[263..297], t10_designator("123456").as_slice());
+    }
+
+    #[test]
+    fn test_scsi_vpd_block_limits_max_transfer() {
+        let dev = vpd_test_device(None, None);
+        let outbuf = scsi_command_emulate_vpd_page(&vpd_cmd(0xb0), &dev).unwrap();
+        assert_eq!(
+            BigEndian::read_u32(&outbuf[8..12]),
+            DEFAULT_SCSI_MAX_SECTORS
+        );
+
+        // The maximum transfer length follows max_sectors of the bus attached to.
+        let bus = Arc::new(Mutex::new(ScsiBus::new("scsi0.0".to_string(), Weak::new())));
+        bus.lock().unwrap().max_sectors = 1024;
+        scsi_bus_attach_device(&bus, &dev).unwrap();
+        let outbuf = scsi_command_emulate_vpd_page(&vpd_cmd(0xb0), &dev).unwrap();
+        assert_eq!(BigEndian::read_u32(&outbuf[8..12]), 1024);
+        assert_eq!(BigEndian::read_u64(&outbuf[36..44]), u32::MAX as u64 / 512);
+    }
+}
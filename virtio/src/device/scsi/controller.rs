@@ -11,27 +11,30 @@
 // See the Mulan PSL v2 for more details.
 
 use std::cmp;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::io::Write;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, RawFd};
 use std::rc::Rc;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 use anyhow::{anyhow, bail, Context, Result};
 
 use crate::ScsiBus::{
-    virtio_scsi_get_lun, ScsiBus, ScsiRequest, ScsiSense, CHECK_CONDITION, EMULATE_SCSI_OPS, GOOD,
-    SCSI_SENSE_INVALID_OPCODE,
+    scsi_write_verify, virtio_scsi_get_lun, ScsiBus, ScsiRequest, ScsiSense, WriteVerify, BUSY,
+    CHECK_CONDITION, EMULATE_SCSI_OPS, GOOD, SCSI_SENSE_INVALID_OPCODE, SCSI_SENSE_WRITE_ERROR,
+    SCSI_SENSE_WRITE_PROTECTED,
 };
+use crate::ScsiDisk::ScsiDevice;
 use crate::VirtioError;
 use crate::{
-    report_virtio_error, Element, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType,
-    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_SCSI_F_CHANGE,
-    VIRTIO_SCSI_F_HOTPLUG, VIRTIO_TYPE_SCSI,
+    report_virtio_error, virtio_has_feature, ConfigUpdater, Element, ErrorAction, Queue,
+    StoppedRequests, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VIRTIO_F_RING_EVENT_IDX,
+    VIRTIO_F_RING_INDIRECT_DESC, VIRTIO_F_VERSION_1, VIRTIO_SCSI_F_CHANGE, VIRTIO_SCSI_F_HOTPLUG,
+    VIRTIO_TYPE_SCSI,
 };
-use address_space::{AddressSpace, GuestAddress};
+use address_space::{AddressSpace, GuestAddress, HostRange};
 use log::{debug, error, info};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use machine_manager::{
@@ -96,6 +99,22 @@ pub const VIRTIO_SCSI_S_FUNCTION_SUCCEEDED: u8 = 10;
 pub const VIRTIO_SCSI_S_FUNCTION_REJECTED: u8 = 11;
 pub const VIRTIO_SCSI_S_INCORRECT_LUN: u8 = 12;
 
+/// Event types of the event queue.
+pub const VIRTIO_SCSI_T_NO_EVENT: u32 = 0;
+pub const VIRTIO_SCSI_T_TRANSPORT_RESET: u32 = 1;
+pub const VIRTIO_SCSI_T_ASYNC_NOTIFY: u32 = 2;
+pub const VIRTIO_SCSI_T_PARAM_CHANGE: u32 = 3;
+/// Flag of the event type, set when events were dropped for lack of buffers.
+pub const VIRTIO_SCSI_T_EVENTS_MISSED: u32 = 0x8000_0000;
+
+/// Max number of events waiting for the event queue to be activated.
+const SCSI_EVENT_MAX_PENDING: usize = 64;
+
+/// Reasons of the transport reset event.
+pub const VIRTIO_SCSI_EVT_RESET_HARD: u32 = 0;
+pub const VIRTIO_SCSI_EVT_RESET_RESCAN: u32 = 1;
+pub const VIRTIO_SCSI_EVT_RESET_REMOVED: u32 = 2;
+
 #[derive(Clone)]
 pub enum ScsiXferMode {
     /// TEST_UNIT_READY, ...
@@ -123,6 +142,106 @@ struct VirtioScsiConfig {
 
 impl ByteCode for VirtioScsiConfig {}
 
+/// Event reported to the guest by the event queue.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct VirtioScsiEvent {
+    pub event: u32,
+    pub lun: [u8; 8],
+    pub reason: u32,
+}
+
+impl ByteCode for VirtioScsiEvent {}
+
+impl VirtioScsiEvent {
+    /// Transport reset event of the lun, which makes the guest rescan or remove it.
+    pub fn transport_reset(target: u8, lun: u16, reason: u32) -> Self {
+        VirtioScsiEvent {
+            event: VIRTIO_SCSI_T_TRANSPORT_RESET,
+            lun: virtio_scsi_encode_lun(target, lun),
+            reason,
+        }
+    }
+
+    /// Parameter change event of the lun, the reason is the ASC and ASCQ of the unit attention.
+    pub fn param_change(target: u8, lun: u16, sense: ScsiSense) -> Self {
+        VirtioScsiEvent {
+            event: VIRTIO_SCSI_T_PARAM_CHANGE,
+            lun: virtio_scsi_encode_lun(target, lun),
+            reason: sense.asc as u32 | (sense.ascq as u32) << 8,
+        }
+    }
+}
+
+/// Events waiting for the buffers of the event queue.
+///
+/// Like a real HBA, events are dropped if the guest has no buffer for them when they are
+/// handled, and the next event reported to the guest carries VIRTIO_SCSI_T_EVENTS_MISSED
+/// so that the guest rescans the bus.
+#[derive(Default)]
+pub struct ScsiPendingEvents {
+    events: VecDeque<VirtioScsiEvent>,
+    /// Events are dropped since the last event reported to the guest.
+    dropped: bool,
+}
+
+impl ScsiPendingEvents {
+    fn push(&mut self, event: VirtioScsiEvent) {
+        if self.events.len() >= SCSI_EVENT_MAX_PENDING {
+            self.dropped = true;
+            return;
+        }
+        self.events.push_back(event);
+    }
+
+    /// The next event to report, a VIRTIO_SCSI_T_NO_EVENT event is reported if only
+    /// the dropped flag is pending.
+    fn front(&self) -> Option<VirtioScsiEvent> {
+        let mut event = match self.events.front() {
+            Some(event) => *event,
+            None if self.dropped => VirtioScsiEvent {
+                event: VIRTIO_SCSI_T_NO_EVENT,
+                ..Default::default()
+            },
+            None => return None,
+        };
+        if self.dropped {
+            event.event |= VIRTIO_SCSI_T_EVENTS_MISSED;
+        }
+        Some(event)
+    }
+
+    fn pop_front(&mut self) {
+        self.events.pop_front();
+        self.dropped = false;
+    }
+
+    /// Drop the pending events as there are no buffers for them.
+    fn drop_all(&mut self) {
+        if !self.events.is_empty() {
+            self.events.clear();
+            self.dropped = true;
+        }
+    }
+
+    fn clear(&mut self) {
+        self.events.clear();
+        self.dropped = false;
+    }
+}
+
+/// Encode the lun field of virtio scsi requests and events, the opposite of
+/// `virtio_scsi_get_lun`.
+pub fn virtio_scsi_encode_lun(target: u8, lun: u16) -> [u8; 8] {
+    let mut buf = [0_u8; 8];
+    buf[0] = 1;
+    buf[1] = target;
+    // Flat space addressing method.
+    buf[2] = 0x40 | (lun >> 8) as u8;
+    buf[3] = lun as u8;
+    buf
+}
+
 /// State of virtio scsi controller.
 #[derive(Clone, Copy, Default)]
 pub struct ScsiCntlrState {
@@ -146,16 +265,61 @@ pub struct ScsiCntlr {
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
+    /// Helper to change the config space.
+    config_updater: Arc<ConfigUpdater>,
+    /// Events waiting for buffers of the event queue.
+    events: Arc<Mutex<ScsiPendingEvents>>,
+    /// EventFd to kick the event queue handler after the device is activated.
+    event_queue_evt: Option<Arc<EventFd>>,
 }
 
 impl ScsiCntlr {
     pub fn new(config: ScsiCntlrConfig) -> ScsiCntlr {
+        let config_updater = ConfigUpdater::new(&config.id);
         Self {
             config,
             state: ScsiCntlrState::default(),
             bus: None,
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
+            config_updater,
+            events: Arc::new(Mutex::new(ScsiPendingEvents::default())),
+            event_queue_evt: None,
+        }
+    }
+
+    /// Notify the guest that the lun is hot plugged or unplugged, by the event queue if
+    /// VIRTIO_SCSI_F_HOTPLUG is negotiated.
+    pub fn notify_hotplug(&self, target: u8, lun: u16, plugged: bool) {
+        if !virtio_has_feature(self.state.driver_features, VIRTIO_SCSI_F_HOTPLUG) {
+            return;
+        }
+        let reason = if plugged {
+            VIRTIO_SCSI_EVT_RESET_RESCAN
+        } else {
+            VIRTIO_SCSI_EVT_RESET_REMOVED
+        };
+        self.push_event(VirtioScsiEvent::transport_reset(target, lun, reason));
+    }
+
+    /// Notify the guest that the parameters of the lun changed, such as the capacity, by
+    /// the event queue if VIRTIO_SCSI_F_CHANGE is negotiated.
+    pub fn notify_param_change(&self, target: u8, lun: u16, sense: ScsiSense) {
+        if !virtio_has_feature(self.state.driver_features, VIRTIO_SCSI_F_CHANGE) {
+            return;
+        }
+        self.push_event(VirtioScsiEvent::param_change(target, lun, sense));
+    }
+
+    fn push_event(&self, event: VirtioScsiEvent) {
+        self.events.lock().unwrap().push(event);
+        if let Some(evt) = &self.event_queue_evt {
+            if let Err(e) = evt.write(1) {
+                error!(
+                    "Failed to kick the event queue of {}: {:?}",
+                    self.config.id, e
+                );
+            }
         }
     }
 }
@@ -173,17 +337,18 @@ impl VirtioDevice for ScsiCntlr {
             );
         }
 
+        // num_queues: request queues number.
         self.state.config_space.num_queues = self.config.queues;
-
-        self.state.config_space.max_sectors = 0xFFFF_u32;
-        // cmd_per_lun: maximum nuber of linked commands can be sent to one LUN. 32bit.
-        self.state.config_space.cmd_per_lun = 128;
         // seg_max: queue size - 2, 32 bit.
         self.state.config_space.seg_max = self.queue_size() as u32 - 2;
+        self.state.config_space.max_sectors = self.config.max_sectors;
+        // cmd_per_lun: maximum number of commands can be sent to one LUN. 32bit.
+        self.state.config_space.cmd_per_lun = self.config.cmd_per_lun;
+        self.state.config_space.event_info_size = size_of::<VirtioScsiEvent>() as u32;
+        self.state.config_space.sense_size = VIRTIO_SCSI_SENSE_DEFAULT_SIZE as u32;
+        self.state.config_space.cdb_size = VIRTIO_SCSI_CDB_DEFAULT_SIZE as u32;
         self.state.config_space.max_target = VIRTIO_SCSI_MAX_TARGET;
         self.state.config_space.max_lun = VIRTIO_SCSI_MAX_LUN as u32;
-        // num_queues: request queues number.
-        self.state.config_space.num_queues = self.config.queues;
 
         self.state.device_features |= (1_u64 << VIRTIO_F_VERSION_1)
             | (1_u64 << VIRTIO_SCSI_F_HOTPLUG)
@@ -262,6 +427,10 @@ impl VirtioDevice for ScsiCntlr {
         Ok(())
     }
 
+    fn config_generation(&self) -> u32 {
+        self.config_updater.generation()
+    }
+
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     fn activate(
@@ -275,6 +444,8 @@ impl VirtioDevice for ScsiCntlr {
         if queue_num < SCSI_MIN_QUEUE_NUM {
             bail!("virtio scsi controller queues num can not be less than 3!");
         }
+        self.config_updater
+            .set_interrupt_cb(Some(interrupt_cb.clone()));
 
         let ctrl_queue = queues[0].clone();
         let ctrl_queue_evt = queue_evts.remove(0);
@@ -295,13 +466,15 @@ impl VirtioDevice for ScsiCntlr {
 
         let event_queue = queues[1].clone();
         let event_queue_evt = queue_evts.remove(0);
+        self.event_queue_evt = Some(event_queue_evt.clone());
         let event_handler = ScsiEventHandler {
-            _queue: event_queue,
+            queue: event_queue,
             queue_evt: event_queue_evt,
-            _mem_space: mem_space.clone(),
-            _interrupt_cb: interrupt_cb.clone(),
-            _driver_features: self.state.driver_features,
+            mem_space: mem_space.clone(),
+            interrupt_cb: interrupt_cb.clone(),
+            driver_features: self.state.driver_features,
             device_broken: self.broken.clone(),
+            events: self.events.clone(),
         };
         let notifiers =
             EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(event_handler)));
@@ -311,14 +484,23 @@ impl VirtioDevice for ScsiCntlr {
             &mut self.deactivate_evts,
         )?;
 
+        let throttle = Arc::new(ScsiCmdThrottle {
+            cmd_per_lun: u64::from(self.config.cmd_per_lun),
+            held: AtomicUsize::new(0),
+            queue_evts: queue_evts.clone(),
+        });
         let queues_num = queues.len();
         for cmd_queue in queues.iter().take(queues_num).skip(2) {
             if let Some(bus) = &self.bus {
+                let queue_evt = queue_evts.remove(0);
                 let mut cmd_handler = ScsiCmdHandler {
                     aio: None,
                     scsibus: bus.clone(),
                     queue: cmd_queue.clone(),
-                    queue_evt: queue_evts.remove(0),
+                    stopped_reqs: Arc::new(StoppedRequests::new(queue_evt.clone())),
+                    held_reqs: VecDeque::new(),
+                    throttle: throttle.clone(),
+                    queue_evt,
                     mem_space: mem_space.clone(),
                     interrupt_cb: interrupt_cb.clone(),
                     driver_features: self.state.driver_features,
@@ -344,6 +526,9 @@ impl VirtioDevice for ScsiCntlr {
     }
 
     fn deactivate(&mut self) -> Result<()> {
+        self.config_updater.set_interrupt_cb(None);
+        self.event_queue_evt = None;
+        self.events.lock().unwrap().clear();
         unregister_event_helper(self.config.iothread.as_ref(), &mut self.deactivate_evts)
     }
 }
@@ -411,6 +596,14 @@ pub struct VirtioScsiCmdReq {
 
 impl ByteCode for VirtioScsiCmdReq {}
 
+impl VirtioScsiCmdReq {
+    /// Get the I_T nexus of the request, which identifies the initiator of persistent
+    /// reservations.
+    pub fn initiator(&self) -> u64 {
+        u64::from_be_bytes(self.lun)
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy)]
 pub struct VirtioScsiCmdResp {
@@ -452,6 +645,14 @@ impl VirtioScsiCmdResp {
         self.sense[13] = sense.ascq;
         self.sense_len = SCSI_SENSE_LEN;
     }
+
+    /// Set the INFORMATION field of the fixed format sense data and mark it valid.
+    pub fn set_scsi_sense_info(&mut self, info: u32) {
+        // Byte0: bit7: VALID.
+        self.sense[0] |= 0x80;
+        // Bytes[3-6]: INFORMATION.
+        self.sense[3..7].copy_from_slice(&info.to_be_bytes());
+    }
 }
 
 impl ByteCode for VirtioScsiCmdResp {}
@@ -460,8 +661,11 @@ impl ByteCode for VirtioScsiCmdResp {}
 pub struct VirtioScsiRequest<T: Clone + ByteCode, U: Clone + ByteCode> {
     queue: Arc<Mutex<Queue>>,
     desc_index: u16,
-    /// Read or Write data, HVA, except resp.
+    /// Read or Write data, HVA, except resp. It's kept mapped by the guards of `data_ranges`
+    /// while the request is alive, including its aio.
     pub iovec: Vec<Iovec>,
+    /// The data buffer in guest memory, which is the same as `iovec`.
+    pub data_ranges: Vec<HostRange>,
     pub data_len: u32,
     _cdb_size: u32,
     _sense_size: u32,
@@ -521,6 +725,7 @@ impl<T: Clone + ByteCode, U: Clone + ByteCode> VirtioScsiRequest<T, U> {
             queue,
             desc_index: elem.index,
             iovec: Vec::with_capacity(elem.desc_num as usize),
+            data_ranges: Vec::with_capacity(elem.desc_num as usize),
             data_len: 0,
             _cdb_size: VIRTIO_SCSI_CDB_DEFAULT_SIZE as u32,
             _sense_size: VIRTIO_SCSI_SENSE_DEFAULT_SIZE as u32,
@@ -537,15 +742,11 @@ impl<T: Clone + ByteCode, U: Clone + ByteCode> VirtioScsiRequest<T, U> {
         for (_index, elem_iov) in elem.out_iovec.iter().enumerate() {
             if skip_out_size >= elem_iov.len {
                 skip_out_size -= elem_iov.len;
-            } else if let Some(hva) = mem_space.get_host_address(elem_iov.addr) {
+            } else {
                 let len = elem_iov.len - skip_out_size;
-                let iov = Iovec {
-                    iov_base: hva + skip_out_size as u64,
-                    iov_len: u64::from(len),
-                };
+                request.add_data_range(mem_space, elem_iov.addr, skip_out_size, len)?;
                 out_len += len;
                 skip_out_size = 0;
-                request.iovec.push(iov);
             }
         }
 
@@ -558,16 +759,10 @@ impl<T: Clone + ByteCode, U: Clone + ByteCode> VirtioScsiRequest<T, U> {
                 if out_len > 0 {
                     bail!("Wrong scsi request!");
                 }
-                if let Some(hva) = mem_space.get_host_address(elem_iov.addr) {
-                    let len = elem_iov.len - skip_in_size;
-                    let iov = Iovec {
-                        iov_base: hva + skip_in_size as u64,
-                        iov_len: u64::from(len),
-                    };
-                    in_len += len;
-                    skip_in_size = 0;
-                    request.iovec.push(iov);
-                }
+                let len = elem_iov.len - skip_in_size;
+                request.add_data_range(mem_space, elem_iov.addr, skip_in_size, len)?;
+                in_len += len;
+                skip_in_size = 0;
             }
         }
 
@@ -582,6 +777,34 @@ impl<T: Clone + ByteCode, U: Clone + ByteCode> VirtioScsiRequest<T, U> {
         Ok(request)
     }
 
+    /// Add the data buffer of `len` bytes at `skip` bytes of the descriptor at `addr`. The
+    /// whole buffer must be guest memory, and it's kept mapped until the request is dropped.
+    fn add_data_range(
+        &mut self,
+        mem_space: &Arc<AddressSpace>,
+        addr: GuestAddress,
+        skip: u32,
+        len: u32,
+    ) -> Result<()> {
+        let ranges = mem_space
+            .get_host_ranges(addr.unchecked_add(u64::from(skip)), u64::from(len))
+            .with_context(|| {
+                format!(
+                    "Invalid data buffer of scsi request, addr {:#x}, len {}",
+                    addr.0 + u64::from(skip),
+                    len
+                )
+            })?;
+        for range in ranges {
+            self.iovec.push(Iovec {
+                iov_base: range.hva,
+                iov_len: range.len,
+            });
+            self.data_ranges.push(range);
+        }
+        Ok(())
+    }
+
     pub fn complete(&self, mem_space: &Arc<AddressSpace>) -> Result<()> {
         if let Err(ref e) = mem_space.write_object(&self.resp, self.resp_addr) {
             bail!("Failed to write the scsi response {:?}", e);
@@ -736,17 +959,19 @@ impl EventNotifierHelper for ScsiCtrlHandler {
 
 pub struct ScsiEventHandler {
     /// The Event virtqueue.
-    _queue: Arc<Mutex<Queue>>,
+    queue: Arc<Mutex<Queue>>,
     /// EventFd for the Event virtqueue.
     queue_evt: Arc<EventFd>,
     /// The address space to which the scsi HBA belongs.
-    _mem_space: Arc<AddressSpace>,
+    mem_space: Arc<AddressSpace>,
     /// The interrupt callback function.
-    _interrupt_cb: Arc<VirtioInterrupt>,
+    interrupt_cb: Arc<VirtioInterrupt>,
     /// Bit mask of features negotiated by the backend and the frontend.
-    _driver_features: u64,
+    driver_features: u64,
     /// Device is broken or not.
     device_broken: Arc<AtomicBool>,
+    /// Events waiting for buffers of the event queue.
+    events: Arc<Mutex<ScsiPendingEvents>>,
 }
 
 impl EventNotifierHelper for ScsiEventHandler {
@@ -774,10 +999,90 @@ impl EventNotifierHelper for ScsiEventHandler {
 
 impl ScsiEventHandler {
     fn handle_event(&mut self) -> Result<()> {
+        let result = self.handle_event_request();
+        if result.is_err() {
+            report_virtio_error(
+                self.interrupt_cb.clone(),
+                self.driver_features,
+                &self.device_broken,
+            );
+        }
+
+        result
+    }
+
+    /// Fill the buffers posted by the guest with the pending events. The buffers are held
+    /// until there are events for them, and the events without buffers are dropped.
+    fn handle_event_request(&mut self) -> Result<()> {
+        if !self.queue.lock().unwrap().is_enabled() {
+            return Ok(());
+        }
+
+        let mut events = self.events.lock().unwrap();
+        while let Some(event) = events.front() {
+            let mut queue = self.queue.lock().unwrap();
+            let elem = queue
+                .vring
+                .pop_avail(&self.mem_space, self.driver_features)?;
+            if elem.desc_num == 0 {
+                events.drop_all();
+                break;
+            }
+
+            let in_iov = match elem.in_iovec.get(0) {
+                Some(iov) if iov.len >= size_of::<VirtioScsiEvent>() as u32 => iov,
+                _ => bail!("Invalid virtio scsi event buffer"),
+            };
+            self.mem_space
+                .write_object(&event, in_iov.addr)
+                .with_context(|| "Failed to write the scsi event")?;
+            queue
+                .vring
+                .add_used(
+                    &self.mem_space,
+                    elem.index,
+                    size_of::<VirtioScsiEvent>() as u32,
+                )
+                .with_context(|| "Failed to add used ring(scsi event)")?;
+            events.pop_front();
+
+            if queue
+                .vring
+                .should_notify(&self.mem_space, self.driver_features)
+            {
+                (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue), false)
+                    .with_context(|| "Failed to trigger interrupt(scsi event)")?;
+            }
+        }
+
         Ok(())
     }
 }
 
+/// Limit of in-flight commands of each lun, shared by the cmd queues of a controller.
+pub struct ScsiCmdThrottle {
+    /// Max number of in-flight commands of each lun.
+    cmd_per_lun: u64,
+    /// Number of commands held back in all the cmd queues.
+    held: AtomicUsize,
+    /// EventFds of the cmd queues, kicked to resubmit the held commands.
+    queue_evts: Vec<Arc<EventFd>>,
+}
+
+impl ScsiCmdThrottle {
+    /// Kick the cmd queues to resubmit the held commands after a command completes.
+    fn kick(&self) {
+        if self.held.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        for queue_evt in self.queue_evts.iter() {
+            if let Err(e) = queue_evt.write(1) {
+                error!("Failed to kick scsi cmd queue: {:?}", e);
+            }
+        }
+    }
+}
+
 pub struct ScsiCmdHandler {
     /// The scsi controller.
     scsibus: Arc<Mutex<ScsiBus>>,
@@ -795,6 +1100,15 @@ pub struct ScsiCmdHandler {
     aio: Option<Box<Aio<ScsiCompleteCb>>>,
     /// Device is broken or not.
     device_broken: Arc<AtomicBool>,
+    /// Requests failed with the `stop` error policy, resubmitted when the VM is resumed.
+    stopped_reqs: Arc<StoppedRequests<ScsiRequest>>,
+    /// Commands waiting for their luns to complete in-flight commands, in order.
+    held_reqs: VecDeque<(
+        Arc<Mutex<VirtioScsiRequest<VirtioScsiCmdReq, VirtioScsiCmdResp>>>,
+        Arc<Mutex<ScsiDevice>>,
+    )>,
+    /// Limit of in-flight commands of each lun.
+    throttle: Arc<ScsiCmdThrottle>,
 }
 
 impl EventNotifierHelper for ScsiCmdHandler {
@@ -864,6 +1178,17 @@ impl ScsiCmdHandler {
             return Ok(());
         }
 
+        match self.stopped_reqs.take() {
+            Some(reqs) => {
+                for scsi_req in reqs {
+                    self.submit_rw_request(&scsi_req)?;
+                }
+            }
+            // The VM is stopped by the error policy, don't process new requests.
+            None => return Ok(()),
+        }
+        self.resubmit_held_requests()?;
+
         loop {
             let mut queue = self.queue.lock().unwrap();
             let elem = queue
@@ -874,109 +1199,226 @@ impl ScsiCmdHandler {
             }
             drop(queue);
 
-            let mut cmd = VirtioScsiRequest::<VirtioScsiCmdReq, VirtioScsiCmdResp>::new(
+            let cmd = VirtioScsiRequest::<VirtioScsiCmdReq, VirtioScsiCmdResp>::new(
                 &self.mem_space,
                 self.queue.clone(),
                 self.interrupt_cb.clone(),
                 self.driver_features,
                 &elem,
             )?;
+            self.handle_scsi_cmd(Arc::new(Mutex::new(cmd)))?;
+        }
 
-            let lun = cmd.req.lun;
-            let scsibus = self.scsibus.lock().unwrap();
-            let req_lun_id = virtio_scsi_get_lun(lun);
+        // Submit the requests popped from the queue in batch.
+        if let Some(ref mut aio) = self.aio {
+            aio.submit_pending()?;
+        }
+        Ok(())
+    }
 
-            let scsidevice = if let Some(scsi_device) = scsibus.get_device(lun[1], req_lun_id) {
-                scsi_device
-            } else {
-                // No such target. Response VIRTIO_SCSI_S_BAD_TARGET to guest scsi drivers.
-                // It's not an error!
-                cmd.resp.response = VIRTIO_SCSI_S_BAD_TARGET;
-                cmd.complete(&self.mem_space)?;
-                debug!(
-                    "no such scsi device target {}, lun {}",
-                    lun[1],
-                    virtio_scsi_get_lun(lun)
-                );
-                continue;
-            };
-            drop(scsibus);
+    fn handle_scsi_cmd(
+        &mut self,
+        cmd_h: Arc<Mutex<VirtioScsiRequest<VirtioScsiCmdReq, VirtioScsiCmdResp>>>,
+    ) -> Result<()> {
+        let lun = cmd_h.lock().unwrap().req.lun;
+        let scsibus = self.scsibus.lock().unwrap();
+        let req_lun_id = virtio_scsi_get_lun(lun);
 
-            let cmd_h = Arc::new(Mutex::new(cmd));
-            let scsi_req = if let Ok(req) =
-                ScsiRequest::new(cmd_h.clone(), self.scsibus.clone(), scsidevice.clone())
-            {
-                req
-            } else {
-                // Wrong scsi cdb. Response CHECK_CONDITION / SCSI_SENSE_INVALID_OPCODE to guest scsi drivers.
-                let mut cmd_lock = cmd_h.lock().unwrap();
-                cmd_lock.resp.set_scsi_sense(SCSI_SENSE_INVALID_OPCODE);
-                cmd_lock.resp.status = CHECK_CONDITION;
-                cmd_lock.complete(&self.mem_space)?;
-                drop(cmd_lock);
-
-                error!("Failed to create scsi request");
-                continue;
-            };
+        let scsidevice = if let Some(scsi_device) = scsibus.get_device(lun[1], req_lun_id) {
+            scsi_device
+        } else {
+            // No such target. Response VIRTIO_SCSI_S_BAD_TARGET to guest scsi drivers.
+            // It's not an error!
+            let mut cmd_lock = cmd_h.lock().unwrap();
+            cmd_lock.resp.response = VIRTIO_SCSI_S_BAD_TARGET;
+            cmd_lock.complete(&self.mem_space)?;
+            debug!(
+                "no such scsi device target {}, lun {}",
+                lun[1],
+                virtio_scsi_get_lun(lun)
+            );
+            return Ok(());
+        };
+        drop(scsibus);
 
-            let scsi_device_lock = scsidevice.lock().unwrap();
-            if scsi_req.opstype == EMULATE_SCSI_OPS {
-                let lun = scsi_device_lock.config.lun;
-                drop(scsi_device_lock);
-                let scsicompletecb = ScsiCompleteCb::new(
-                    self.mem_space.clone(),
-                    Arc::new(Mutex::new(scsi_req.clone())),
-                );
-                // If found device's lun id is not equal to request lun id, this request is a target request.
-                scsi_req.emulate_execute(scsicompletecb, req_lun_id, lun)?;
-            } else {
-                let direct = scsi_device_lock.config.direct;
-                let disk_img = scsi_device_lock.disk_image.as_ref().unwrap().clone();
-                let req_align = scsi_device_lock.req_align;
-                let buf_align = scsi_device_lock.buf_align;
-                drop(scsi_device_lock);
-
-                let scsicompletecb = ScsiCompleteCb::new(
-                    self.mem_space.clone(),
-                    Arc::new(Mutex::new(scsi_req.clone())),
-                );
-                if let Some(ref mut aio) = self.aio {
-                    let aiocb = AioCb {
-                        direct,
-                        req_align,
-                        buf_align,
-                        file_fd: disk_img.as_raw_fd(),
-                        opcode: OpCode::Noop,
-                        iovec: Vec::new(),
-                        offset: 0,
-                        nbytes: 0,
-                        user_data: 0,
-                        iocompletecb: scsicompletecb,
-                    };
-                    scsi_req.execute(aio, aiocb)?;
-                    aio.flush_request()?;
-                }
+        if self.lun_throttled(&scsidevice) {
+            return self.hold_request(cmd_h, scsidevice);
+        }
+
+        let scsi_req = if let Ok(req) =
+            ScsiRequest::new(cmd_h.clone(), self.scsibus.clone(), scsidevice.clone())
+        {
+            req
+        } else {
+            // Wrong scsi cdb. Response CHECK_CONDITION / SCSI_SENSE_INVALID_OPCODE to guest scsi drivers.
+            let mut cmd_lock = cmd_h.lock().unwrap();
+            cmd_lock.resp.set_scsi_sense(SCSI_SENSE_INVALID_OPCODE);
+            cmd_lock.resp.status = CHECK_CONDITION;
+            cmd_lock.complete(&self.mem_space)?;
+            drop(cmd_lock);
+
+            error!("Failed to create scsi request");
+            return Ok(());
+        };
+
+        if let Some(sense) = scsi_req.take_unit_attention(req_lun_id) {
+            let mut cmd_lock = cmd_h.lock().unwrap();
+            cmd_lock.resp.set_scsi_sense(sense);
+            cmd_lock.resp.status = CHECK_CONDITION;
+            return cmd_lock.complete(&self.mem_space);
+        }
+
+        let scsi_device_lock = scsidevice.lock().unwrap();
+        if scsi_req.opstype == EMULATE_SCSI_OPS {
+            let lun = scsi_device_lock.config.lun;
+            drop(scsi_device_lock);
+            let scsicompletecb = ScsiCompleteCb::new(
+                self.mem_space.clone(),
+                Arc::new(Mutex::new(scsi_req.clone())),
+                self.stopped_reqs.clone(),
+                self.throttle.clone(),
+            );
+            // If found device's lun id is not equal to request lun id, this request is a target request.
+            scsi_req.emulate_execute(scsicompletecb, req_lun_id, lun)?;
+        } else {
+            drop(scsi_device_lock);
+
+            if !scsi_req.check_rw_request(&self.mem_space)? {
+                return Ok(());
             }
+            self.submit_rw_request(&scsi_req)?;
+        }
+        Ok(())
+    }
+
+    /// Whether the command to `dev` must wait, because the lun has `cmd_per_lun` commands in
+    /// flight, or commands held before it.
+    fn lun_throttled(&self, dev: &Arc<Mutex<ScsiDevice>>) -> bool {
+        dev.lock().unwrap().inflight.load(Ordering::SeqCst) >= self.throttle.cmd_per_lun
+            || self
+                .held_reqs
+                .iter()
+                .any(|(_, held)| Arc::ptr_eq(held, dev))
+    }
+
+    /// Hold the command back until the lun completes in-flight commands. If `cmd_per_lun`
+    /// commands of the lun are already held, the lun is saturated and the command completes
+    /// with BUSY status, which makes the guest retry it later.
+    fn hold_request(
+        &mut self,
+        cmd_h: Arc<Mutex<VirtioScsiRequest<VirtioScsiCmdReq, VirtioScsiCmdResp>>>,
+        dev: Arc<Mutex<ScsiDevice>>,
+    ) -> Result<()> {
+        let held = self
+            .held_reqs
+            .iter()
+            .filter(|(_, held)| Arc::ptr_eq(held, &dev))
+            .count() as u64;
+        if held >= self.throttle.cmd_per_lun {
+            let mut cmd_lock = cmd_h.lock().unwrap();
+            cmd_lock.resp.response = VIRTIO_SCSI_S_OK;
+            cmd_lock.resp.status = BUSY;
+            return cmd_lock.complete(&self.mem_space);
+        }
+
+        self.held_reqs.push_back((cmd_h, dev));
+        self.throttle.held.fetch_add(1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Resubmit the held commands in order, the ones of luns still busy are held again.
+    fn resubmit_held_requests(&mut self) -> Result<()> {
+        let mut held_reqs = std::mem::take(&mut self.held_reqs);
+        self.throttle
+            .held
+            .fetch_sub(held_reqs.len(), Ordering::SeqCst);
+        while let Some((cmd_h, _)) = held_reqs.pop_front() {
+            self.handle_scsi_cmd(cmd_h)?;
         }
+        Ok(())
+    }
 
+    fn submit_rw_request(&mut self, scsi_req: &ScsiRequest) -> Result<()> {
+        let scsi_device_lock = scsi_req.dev.lock().unwrap();
+        let direct = scsi_device_lock.config.direct;
+        let disk_img = scsi_device_lock.disk_image.as_ref().unwrap().clone();
+        let backing = scsi_device_lock.backing.clone();
+        let req_align = scsi_device_lock.req_align;
+        let buf_align = scsi_device_lock.buf_align;
+        drop(scsi_device_lock);
+
+        let scsicompletecb = ScsiCompleteCb::new(
+            self.mem_space.clone(),
+            Arc::new(Mutex::new(scsi_req.clone())),
+            self.stopped_reqs.clone(),
+            self.throttle.clone(),
+        );
+        if let Some(ref mut aio) = self.aio {
+            let aiocb = AioCb {
+                direct,
+                req_align,
+                buf_align,
+                file_fd: disk_img.as_raw_fd(),
+                opcode: OpCode::Noop,
+                iovec: Vec::new(),
+                offset: 0,
+                nbytes: 0,
+                user_data: 0,
+                iocompletecb: scsicompletecb,
+                submit_time: None,
+                backing,
+            };
+            scsi_req.execute(aio, aiocb)?;
+        }
         Ok(())
     }
 
-    fn complete_func(aiocb: &AioCb<ScsiCompleteCb>, ret: i64) -> Result<()> {
+    fn complete_func(aiocb: &AioCb<ScsiCompleteCb>, mut ret: i64) -> Result<()> {
         let complete_cb = &aiocb.iocompletecb;
         let request = &aiocb.iocompletecb.req.lock().unwrap();
+        request.dev.lock().unwrap().io_stats.account_aio(aiocb, ret);
+        let mut sense = None;
+        if ret >= 0 {
+            if let Some(verify) = &complete_cb.write_verify {
+                sense =
+                    scsi_write_verify(aiocb.file_fd, aiocb.offset as u64, aiocb.req_align, verify);
+            }
+        }
+        if ret < 0 {
+            let io_error = request.dev.lock().unwrap().io_error.clone();
+            let is_write = aiocb.opcode != OpCode::Preadv;
+            match io_error.handle_error(is_write, -ret as i32) {
+                ErrorAction::Report => {}
+                // The disk runs out of space and turns read-only.
+                ErrorAction::ReadOnly => sense = Some((SCSI_SENSE_WRITE_PROTECTED, None)),
+                ErrorAction::Ignore => ret = aiocb.nbytes as i64,
+                ErrorAction::Stop => {
+                    // Keep the request and resubmit it when the VM is resumed.
+                    complete_cb.stopped_reqs.push((**request).clone());
+                    return Ok(());
+                }
+            }
+        }
         let mut virtio_scsi_req = request.virtioscsireq.lock().unwrap();
-
-        virtio_scsi_req.resp.response = if ret < 0 {
-            VIRTIO_SCSI_S_FAILURE
-        } else {
-            VIRTIO_SCSI_S_OK
-        };
-
-        virtio_scsi_req.resp.status = GOOD;
-        virtio_scsi_req.resp.resid = 0;
-        virtio_scsi_req.resp.sense_len = 0;
-        virtio_scsi_req.complete(&complete_cb.mem_space)
+        let data_len = virtio_scsi_req.data_len;
+        aio_complete_resp(
+            &mut virtio_scsi_req.resp,
+            aiocb.opcode,
+            data_len,
+            aiocb.nbytes,
+            ret,
+        );
+        if let Some((sense, info)) = sense {
+            virtio_scsi_req.resp.response = VIRTIO_SCSI_S_OK;
+            virtio_scsi_req.resp.status = CHECK_CONDITION;
+            virtio_scsi_req.resp.set_scsi_sense(sense);
+            if let Some(info) = info {
+                virtio_scsi_req.resp.set_scsi_sense_info(info);
+            }
+        }
+        virtio_scsi_req.complete(&complete_cb.mem_space)?;
+        complete_cb.throttle.kick();
+        Ok(())
     }
 
     fn build_aio(&self) -> Result<Box<Aio<ScsiCompleteCb>>> {
@@ -987,14 +1429,527 @@ impl ScsiCmdHandler {
     }
 }
 
+/// Fill the response of a request completed by the backend with `ret`.
+///
+/// A failed flush is reported as MEDIUM ERROR, the data written before may be lost.
+fn aio_complete_resp(
+    resp: &mut VirtioScsiCmdResp,
+    opcode: OpCode,
+    data_len: u32,
+    nbytes: u64,
+    ret: i64,
+) {
+    resp.sense_len = 0;
+    if ret < 0 && opcode == OpCode::Fdsync {
+        resp.response = VIRTIO_SCSI_S_OK;
+        resp.status = CHECK_CONDITION;
+        resp.set_scsi_sense(SCSI_SENSE_WRITE_ERROR);
+        resp.resid = data_len;
+        return;
+    }
+
+    resp.response = if ret < 0 {
+        VIRTIO_SCSI_S_FAILURE
+    } else {
+        VIRTIO_SCSI_S_OK
+    };
+    resp.status = GOOD;
+    // Bytes of the data buffer which are not transferred.
+    resp.resid = if ret < 0 {
+        data_len
+    } else {
+        data_len.saturating_sub(cmp::min(nbytes, ret as u64) as u32)
+    };
+}
+
 #[derive(Clone)]
 pub struct ScsiCompleteCb {
     pub mem_space: Arc<AddressSpace>,
     req: Arc<Mutex<ScsiRequest>>,
+    stopped_reqs: Arc<StoppedRequests<ScsiRequest>>,
+    /// Limit of in-flight commands, whose held commands are resubmitted on completion.
+    throttle: Arc<ScsiCmdThrottle>,
+    /// Verification of WRITE AND VERIFY after the data is written.
+    write_verify: Option<WriteVerify>,
 }
 
 impl ScsiCompleteCb {
-    fn new(mem_space: Arc<AddressSpace>, req: Arc<Mutex<ScsiRequest>>) -> Self {
-        ScsiCompleteCb { mem_space, req }
+    fn new(
+        mem_space: Arc<AddressSpace>,
+        req: Arc<Mutex<ScsiRequest>>,
+        stopped_reqs: Arc<StoppedRequests<ScsiRequest>>,
+        throttle: Arc<ScsiCmdThrottle>,
+    ) -> Self {
+        ScsiCompleteCb {
+            mem_space,
+            req,
+            stopped_reqs,
+            throttle,
+            write_verify: None,
+        }
+    }
+
+    pub fn set_write_verify(&mut self, verify: WriteVerify) {
+        self.write_verify = Some(verify);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ScsiBus::{
+        create_scsi_bus, scsi_bus_attach_device, scsi_bus_detach_device,
+        scsi_device_capacity_changed, DATA_PROTECT, SCSI_SENSE_CAPACITY_CHANGED, WRITE_10,
+    };
+    use crate::ScsiDisk::{ScsiDevice, SCSI_DISK_DEFAULT_BLOCK_SIZE, SCSI_TYPE_DISK, SECTOR_SHIFT};
+    use crate::{QueueConfig, SplitVringDesc};
+    use address_space::{HostMemMapping, Region};
+    use machine_manager::config::{ScsiDevConfig, DEFAULT_VIRTQUEUE_SIZE};
+    use std::fs::OpenOptions;
+    use std::os::unix::fs::FileExt;
+    use vmm_sys_util::tempfile::TempFile;
+
+    const VIRTQ_DESC_F_NEXT: u16 = 0x01;
+    const VIRTQ_DESC_F_WRITE: u16 = 0x02;
+
+    #[test]
+    fn test_scsi_event_lun() {
+        let event = VirtioScsiEvent::transport_reset(3, 0x105, VIRTIO_SCSI_EVT_RESET_RESCAN);
+        assert_eq!(event.event, VIRTIO_SCSI_T_TRANSPORT_RESET);
+        assert_eq!(event.lun[..4], [1, 3, 0x41, 0x05]);
+        assert_eq!(virtio_scsi_get_lun(event.lun), 0x105);
+        assert_eq!(event.as_bytes().len(), 16);
+
+        let event = VirtioScsiEvent::param_change(1, 2, SCSI_SENSE_CAPACITY_CHANGED);
+        assert_eq!(event.event, VIRTIO_SCSI_T_PARAM_CHANGE);
+        assert_eq!(event.lun[..4], [1, 1, 0x40, 0x02]);
+        assert_eq!(event.reason, 0x092a);
+    }
+
+    #[test]
+    fn test_scsi_aio_complete_resp() {
+        let mut resp = VirtioScsiCmdResp::default();
+        aio_complete_resp(&mut resp, OpCode::Fdsync, 0, 0, 0);
+        assert_eq!((resp.response, resp.status), (VIRTIO_SCSI_S_OK, GOOD));
+        assert_eq!(resp.sense_len, 0);
+
+        // The failed fdatasync is reported as MEDIUM ERROR.
+        aio_complete_resp(&mut resp, OpCode::Fdsync, 0, 0, -libc::EIO as i64);
+        assert_eq!(
+            (resp.response, resp.status),
+            (VIRTIO_SCSI_S_OK, CHECK_CONDITION)
+        );
+        assert_eq!(resp.sense_len, SCSI_SENSE_LEN);
+        assert_eq!(resp.sense[2], SCSI_SENSE_WRITE_ERROR.key);
+        assert_eq!(resp.sense[12..14], [0x0c, 0x00]);
+
+        aio_complete_resp(&mut resp, OpCode::Pwritev, 512, 512, 256);
+        assert_eq!((resp.response, resp.status), (VIRTIO_SCSI_S_OK, GOOD));
+        assert_eq!((resp.sense_len, resp.resid), (0, 256));
+        aio_complete_resp(&mut resp, OpCode::Preadv, 512, 512, -libc::EIO as i64);
+        assert_eq!(resp.response, VIRTIO_SCSI_S_FAILURE);
+        assert_eq!(resp.resid, 512);
+    }
+
+    #[test]
+    fn test_scsi_config_space() {
+        let mut cntlr = ScsiCntlr::new(ScsiCntlrConfig {
+            queues: 4,
+            max_sectors: 1024,
+            cmd_per_lun: 16,
+            ..Default::default()
+        });
+        cntlr.realize().unwrap();
+        // One request queue for each of `num-queues`, plus the ctrl and event queue.
+        assert_eq!(cntlr.queue_num(), 6);
+
+        let mut config = [0xff_u8; 36];
+        cntlr.read_config(0, &mut config).unwrap();
+        let config_u32 =
+            |offset: usize| u32::from_le_bytes(config[offset..offset + 4].try_into().unwrap());
+        let config_u16 =
+            |offset: usize| u16::from_le_bytes(config[offset..offset + 2].try_into().unwrap());
+        assert_eq!(size_of::<VirtioScsiConfig>(), 36);
+        assert_eq!(config_u32(0), 4);
+        assert_eq!(config_u32(4), DEFAULT_VIRTQUEUE_SIZE as u32 - 2);
+        assert_eq!(config_u32(8), 1024);
+        assert_eq!(config_u32(12), 16);
+        assert_eq!(config_u32(16), size_of::<VirtioScsiEvent>() as u32);
+        assert_eq!(config_u32(20), VIRTIO_SCSI_SENSE_DEFAULT_SIZE as u32);
+        assert_eq!(config_u32(24), VIRTIO_SCSI_CDB_DEFAULT_SIZE as u32);
+        assert_eq!(config_u16(28), 0);
+        assert_eq!(config_u16(30), VIRTIO_SCSI_MAX_TARGET);
+        assert_eq!(config_u32(32), VIRTIO_SCSI_MAX_LUN as u32);
+
+        // The fields can be read one by one.
+        let mut max_sectors = [0_u8; 4];
+        cntlr.read_config(8, &mut max_sectors).unwrap();
+        assert_eq!(u32::from_le_bytes(max_sectors), 1024);
+        assert!(cntlr.read_config(36, &mut max_sectors).is_err());
+    }
+
+    #[test]
+    fn test_scsi_hotplug_event() {
+        let cntlr = Arc::new(Mutex::new(ScsiCntlr::new(ScsiCntlrConfig::default())));
+        create_scsi_bus("scsi0.0", &cntlr).unwrap();
+        let bus = cntlr.lock().unwrap().bus.clone().unwrap();
+        let dev = Arc::new(Mutex::new(ScsiDevice::new(
+            ScsiDevConfig {
+                id: "disk0".to_string(),
+                target: 1,
+                ..Default::default()
+            },
+            SCSI_TYPE_DISK,
+            Arc::new(Mutex::new(HashMap::new())),
+        )));
+
+        // No event is queued if the guest doesn't support hotplug.
+        scsi_bus_attach_device(&bus, &dev).unwrap();
+        scsi_bus_detach_device(&bus, 1, 0).unwrap();
+        assert!(cntlr
+            .lock()
+            .unwrap()
+            .events
+            .lock()
+            .unwrap()
+            .front()
+            .is_none());
+
+        cntlr.lock().unwrap().state.driver_features = 1_u64 << VIRTIO_SCSI_F_HOTPLUG;
+        scsi_bus_attach_device(&bus, &dev).unwrap();
+        scsi_bus_detach_device(&bus, 1, 0).unwrap();
+        let events: Vec<VirtioScsiEvent> = cntlr
+            .lock()
+            .unwrap()
+            .events
+            .lock()
+            .unwrap()
+            .events
+            .drain(..)
+            .collect();
+        assert_eq!(
+            events,
+            vec![
+                VirtioScsiEvent::transport_reset(1, 0, VIRTIO_SCSI_EVT_RESET_RESCAN),
+                VirtioScsiEvent::transport_reset(1, 0, VIRTIO_SCSI_EVT_RESET_REMOVED),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_scsi_capacity_change_event() {
+        let cntlr = Arc::new(Mutex::new(ScsiCntlr::new(ScsiCntlrConfig::default())));
+        create_scsi_bus("scsi0.0", &cntlr).unwrap();
+        let bus = cntlr.lock().unwrap().bus.clone().unwrap();
+        let dev = Arc::new(Mutex::new(ScsiDevice::new(
+            ScsiDevConfig {
+                id: "disk0".to_string(),
+                target: 1,
+                lun: 2,
+                ..Default::default()
+            },
+            SCSI_TYPE_DISK,
+            Arc::new(Mutex::new(HashMap::new())),
+        )));
+        scsi_bus_attach_device(&bus, &dev).unwrap();
+
+        // The unit attention is established even if the guest doesn't support the event.
+        scsi_device_capacity_changed(&dev);
+        assert_eq!(
+            dev.lock().unwrap().unit_attention,
+            Some(SCSI_SENSE_CAPACITY_CHANGED)
+        );
+        assert!(cntlr
+            .lock()
+            .unwrap()
+            .events
+            .lock()
+            .unwrap()
+            .front()
+            .is_none());
+
+        cntlr.lock().unwrap().state.driver_features = 1_u64 << VIRTIO_SCSI_F_CHANGE;
+        scsi_device_capacity_changed(&dev);
+        let event = cntlr.lock().unwrap().events.lock().unwrap().front();
+        assert_eq!(
+            event,
+            Some(VirtioScsiEvent::param_change(
+                1,
+                2,
+                SCSI_SENSE_CAPACITY_CHANGED
+            ))
+        );
+    }
+
+    fn test_mem_space() -> Arc<AddressSpace> {
+        let root = Region::init_container_region(1 << 36);
+        let mem_space = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x10_0000, None, false, false, false)
+                .unwrap(),
+        );
+        mem_space
+            .root()
+            .add_subregion(Region::init_ram_region(host_mmap), 0)
+            .unwrap();
+        mem_space
+    }
+
+    fn test_queue_config(mem_space: &Arc<AddressSpace>) -> QueueConfig {
+        let mut queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.addr_cache.desc_table_host =
+            mem_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.avail_ring = GuestAddress(16 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.avail_ring_host =
+            mem_space.get_host_address(queue_config.avail_ring).unwrap();
+        queue_config.used_ring = GuestAddress(32 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.used_ring_host =
+            mem_space.get_host_address(queue_config.used_ring).unwrap();
+        queue_config.size = DEFAULT_VIRTQUEUE_SIZE;
+        queue_config.ready = true;
+        queue_config
+    }
+
+    #[test]
+    fn test_scsi_events_missed() {
+        let mem_space = test_mem_space();
+        let queue_config = test_queue_config(&mem_space);
+
+        let events = Arc::new(Mutex::new(ScsiPendingEvents::default()));
+        let mut handler = ScsiEventHandler {
+            queue: Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap())),
+            queue_evt: Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap()),
+            mem_space,
+            interrupt_cb: Arc::new(Box::new(
+                move |_: &VirtioInterruptType, _: Option<&Queue>, _: bool| Ok(()),
+            ) as VirtioInterrupt),
+            driver_features: 0,
+            device_broken: Arc::new(AtomicBool::new(false)),
+            events: events.clone(),
+        };
+        // The driver posts a buffer of the event queue, returns the guest address of it.
+        let mut avail_idx = 0_u16;
+        let mut post_buffer = |mem_space: &Arc<AddressSpace>| {
+            let buf_addr = 0x40000 + avail_idx as u64 * 0x100;
+            let desc = SplitVringDesc {
+                addr: GuestAddress(buf_addr),
+                len: size_of::<VirtioScsiEvent>() as u32,
+                flags: VIRTQ_DESC_F_WRITE,
+                next: 0,
+            };
+            let desc_addr = queue_config.desc_table.0 + avail_idx as u64 * 16;
+            mem_space
+                .write_object(&desc, GuestAddress(desc_addr))
+                .unwrap();
+            let ring_addr = queue_config.avail_ring.0 + 4 + avail_idx as u64 * 2;
+            mem_space
+                .write_object::<u16>(&avail_idx, GuestAddress(ring_addr))
+                .unwrap();
+            avail_idx += 1;
+            mem_space
+                .write_object::<u16>(&avail_idx, GuestAddress(queue_config.avail_ring.0 + 2))
+                .unwrap();
+            GuestAddress(buf_addr)
+        };
+        let read_event = |mem_space: &Arc<AddressSpace>, addr: GuestAddress| {
+            mem_space.read_object::<VirtioScsiEvent>(addr).unwrap()
+        };
+        let rescan = VirtioScsiEvent::transport_reset(1, 0, VIRTIO_SCSI_EVT_RESET_RESCAN);
+        let removed = VirtioScsiEvent::transport_reset(2, 0, VIRTIO_SCSI_EVT_RESET_REMOVED);
+
+        // The buffer is held until an event occurs.
+        let buf0 = post_buffer(&handler.mem_space);
+        handler.handle_event_request().unwrap();
+        events.lock().unwrap().push(rescan);
+        handler.handle_event_request().unwrap();
+        assert_eq!(read_event(&handler.mem_space, buf0), rescan);
+        assert!(events.lock().unwrap().front().is_none());
+
+        // Both events are dropped for lack of buffers.
+        events.lock().unwrap().push(rescan);
+        events.lock().unwrap().push(removed);
+        handler.handle_event_request().unwrap();
+        let mut missed = VirtioScsiEvent {
+            event: VIRTIO_SCSI_T_NO_EVENT | VIRTIO_SCSI_T_EVENTS_MISSED,
+            ..Default::default()
+        };
+        assert_eq!(events.lock().unwrap().front(), Some(missed));
+
+        // The next event carries the missed flag, the ones after it don't.
+        events.lock().unwrap().push(removed);
+        events.lock().unwrap().push(rescan);
+        let buf1 = post_buffer(&handler.mem_space);
+        let buf2 = post_buffer(&handler.mem_space);
+        handler.handle_event_request().unwrap();
+        missed = removed;
+        missed.event |= VIRTIO_SCSI_T_EVENTS_MISSED;
+        assert_eq!(read_event(&handler.mem_space, buf1), missed);
+        assert_eq!(read_event(&handler.mem_space, buf2), rescan);
+
+        // Only the missed flag is reported if no event is left after dropping.
+        events.lock().unwrap().push(rescan);
+        handler.handle_event_request().unwrap();
+        let buf3 = post_buffer(&handler.mem_space);
+        handler.handle_event_request().unwrap();
+        assert_eq!(
+            read_event(&handler.mem_space, buf3).event,
+            VIRTIO_SCSI_T_NO_EVENT | VIRTIO_SCSI_T_EVENTS_MISSED
+        );
+        assert!(events.lock().unwrap().front().is_none());
+
+        // Events exceeding the limit before the queue is activated are dropped.
+        let mut pending = ScsiPendingEvents::default();
+        for _ in 0..SCSI_EVENT_MAX_PENDING + 1 {
+            pending.push(rescan);
+        }
+        assert_eq!(pending.events.len(), SCSI_EVENT_MAX_PENDING);
+        assert!(pending.dropped);
+        pending.clear();
+        assert!(pending.front().is_none());
+    }
+
+    #[test]
+    fn test_scsi_write_protect_request() {
+        let mem_space = test_mem_space();
+        let queue_config = test_queue_config(&mem_space);
+        let image = TempFile::new().unwrap();
+        let content = vec![0x5a_u8; 16 * SCSI_DISK_DEFAULT_BLOCK_SIZE as usize];
+        image.as_file().write_all_at(&content, 0).unwrap();
+
+        let cntlr = Arc::new(Mutex::new(ScsiCntlr::new(ScsiCntlrConfig::default())));
+        create_scsi_bus("scsi0.0", &cntlr).unwrap();
+        let bus = cntlr.lock().unwrap().bus.clone().unwrap();
+        let mut dev = ScsiDevice::new(
+            ScsiDevConfig {
+                id: "scsi-wp-disk".to_string(),
+                ..Default::default()
+            },
+            SCSI_TYPE_DISK,
+            Arc::new(Mutex::new(HashMap::new())),
+        );
+        dev.realize().unwrap();
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(image.as_path())
+            .unwrap();
+        dev.disk_image = Some(Arc::new(file));
+        dev.disk_sectors = content.len() as u64 >> SECTOR_SHIFT;
+        let dev = Arc::new(Mutex::new(dev));
+        scsi_bus_attach_device(&bus, &dev).unwrap();
+
+        let queue_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let mut handler = ScsiCmdHandler {
+            scsibus: bus,
+            queue: Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap())),
+            queue_evt: queue_evt.clone(),
+            mem_space,
+            interrupt_cb: Arc::new(Box::new(
+                move |_: &VirtioInterruptType, _: Option<&Queue>, _: bool| Ok(()),
+            ) as VirtioInterrupt),
+            driver_features: 0,
+            aio: None,
+            device_broken: Arc::new(AtomicBool::new(false)),
+            stopped_reqs: Arc::new(StoppedRequests::new(queue_evt.clone())),
+            held_reqs: VecDeque::new(),
+            throttle: Arc::new(ScsiCmdThrottle {
+                cmd_per_lun: 16,
+                held: AtomicUsize::new(0),
+                queue_evts: vec![queue_evt],
+            }),
+        };
+        handler.aio = Some(handler.build_aio().unwrap());
+
+        // The driver posts a WRITE(10) of one block to lba 2 with `data`, returns the guest
+        // address of the response.
+        let mut avail_idx = 0_u16;
+        let mut post_write = |mem_space: &Arc<AddressSpace>, data: &[u8]| {
+            let mut req = VirtioScsiCmdReq {
+                lun: [1, 0, 0x40, 0, 0, 0, 0, 0],
+                ..Default::default()
+            };
+            req.cdb[0] = WRITE_10;
+            req.cdb[5] = 2;
+            req.cdb[8] = 1;
+            let req_addr = GuestAddress(0x40000 + avail_idx as u64 * 0x1000);
+            let data_addr = req_addr.unchecked_add(0x100);
+            let resp_addr = req_addr.unchecked_add(0x800);
+            mem_space.write_object(&req, req_addr).unwrap();
+            mem_space
+                .write(&mut &data[..], data_addr, data.len() as u64)
+                .unwrap();
+
+            let head = avail_idx * 3;
+            let descs = [
+                (req_addr, size_of::<VirtioScsiCmdReq>(), VIRTQ_DESC_F_NEXT),
+                (data_addr, data.len(), VIRTQ_DESC_F_NEXT),
+                (
+                    resp_addr,
+                    size_of::<VirtioScsiCmdResp>(),
+                    VIRTQ_DESC_F_WRITE,
+                ),
+            ];
+            for (i, (addr, len, flags)) in descs.into_iter().enumerate() {
+                let desc = SplitVringDesc {
+                    addr,
+                    len: len as u32,
+                    flags,
+                    next: head + i as u16 + 1,
+                };
+                let desc_addr = queue_config.desc_table.0 + (head as u64 + i as u64) * 16;
+                mem_space
+                    .write_object(&desc, GuestAddress(desc_addr))
+                    .unwrap();
+            }
+            let ring_addr = queue_config.avail_ring.0 + 4 + avail_idx as u64 * 2;
+            mem_space
+                .write_object::<u16>(&head, GuestAddress(ring_addr))
+                .unwrap();
+            avail_idx += 1;
+            mem_space
+                .write_object::<u16>(&avail_idx, GuestAddress(queue_config.avail_ring.0 + 2))
+                .unwrap();
+            resp_addr
+        };
+        let block_size = SCSI_DISK_DEFAULT_BLOCK_SIZE as usize;
+        let read_block = || {
+            let mut buf = vec![0_u8; block_size];
+            image
+                .as_file()
+                .read_exact_at(&mut buf, 2 * block_size as u64)
+                .unwrap();
+            buf
+        };
+
+        // The write reaches the image if the device is writable.
+        let resp_addr = post_write(&handler.mem_space, &[0xa5_u8; 512]);
+        handler.handle_cmd_request().unwrap();
+        let resp = handler
+            .mem_space
+            .read_object::<VirtioScsiCmdResp>(resp_addr)
+            .unwrap();
+        assert_eq!((resp.response, resp.status), (VIRTIO_SCSI_S_OK, GOOD));
+        assert_eq!(read_block(), vec![0xa5_u8; block_size]);
+
+        // The write is rejected with DATA PROTECT and the image is left untouched.
+        dev.lock().unwrap().config.read_only = true;
+        let resp_addr = post_write(&handler.mem_space, &[0x3c_u8; 512]);
+        handler.handle_cmd_request().unwrap();
+        let resp = handler
+            .mem_space
+            .read_object::<VirtioScsiCmdResp>(resp_addr)
+            .unwrap();
+        assert_eq!(
+            (resp.response, resp.status),
+            (VIRTIO_SCSI_S_OK, CHECK_CONDITION)
+        );
+        assert_eq!(resp.sense[2], DATA_PROTECT);
+        assert_eq!(resp.sense[12..14], [0x27, 0x00]);
+        assert_eq!(resp.resid, block_size as u32);
+        assert_eq!(read_block(), vec![0xa5_u8; block_size]);
+        assert_eq!(dev.lock().unwrap().inflight.load(Ordering::SeqCst), 0);
+
+        dev.lock().unwrap().unrealize();
     }
 }
@@ -12,13 +12,27 @@
 
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{Seek, SeekFrom};
+use std::sync::atomic::AtomicU64;
 use std::sync::{Arc, Mutex, Weak};
 
 use anyhow::{bail, Context, Result};
 
-use crate::ScsiBus::ScsiBus;
-use machine_manager::config::{DriveFile, ScsiDevConfig, VmConfig};
+use crate::device::scsi::reservation::PersistentReservation;
+use crate::ScsiBus::{
+    ScsiBus, ScsiSense, SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED,
+    SCSI_SENSE_NOT_READY_REMOVAL_PREVENTED, SCSI_SENSE_NO_MEDIUM,
+};
+use crate::{
+    register_block_io_stats, register_write_protect, register_write_threshold, resize_image,
+    unregister_block_io_stats, unregister_write_protect, unregister_write_threshold, BlockIoStats,
+    IoErrorPolicy, WriteProtect, WriteThreshold,
+};
+use machine_manager::config::{DriveFile, ScsiDevConfig, VmConfig, DEFAULT_SCSI_MAX_SECTORS};
+use machine_manager::event;
+use machine_manager::machine::MachineLifecycle;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use util::aio::BackingImage;
+use util::file::get_file_size;
 
 /// SCSI DEVICE TYPES.
 pub const SCSI_TYPE_DISK: u32 = 0x00;
@@ -94,6 +108,8 @@ pub struct ScsiDevice {
     pub state: ScsiDevState,
     /// Image file opened.
     pub disk_image: Option<Arc<File>>,
+    /// Backing image of the image file.
+    pub backing: Option<Arc<BackingImage>>,
     /// The align requirement of request(offset/len).
     pub req_align: u32,
     /// The align requirement of buffer(iova_base).
@@ -106,8 +122,29 @@ pub struct ScsiDevice {
     pub scsi_type: u32,
     /// Scsi Bus attached to.
     pub parent_bus: Weak<Mutex<ScsiBus>>,
+    /// Max sectors of 512 bytes transferred by a command, which is set by the controller
+    /// of the bus attached to.
+    pub max_sectors: u32,
     /// Drive backend files.
     drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
+    /// Read/write error policies of the scsi device.
+    pub io_error: Arc<IoErrorPolicy>,
+    /// Persistent reservation state of the scsi device.
+    pub reservation: PersistentReservation,
+    /// Write threshold of the scsi device.
+    pub write_threshold: Arc<WriteThreshold>,
+    /// Write protection set by the `read-only` error policy.
+    pub write_protect: Arc<WriteProtect>,
+    /// Unit attention condition reported to the guest by the next command.
+    pub unit_attention: Option<ScsiSense>,
+    /// Number of requests to the scsi device which are not completed yet.
+    pub inflight: Arc<AtomicU64>,
+    /// I/O statistics of the scsi device.
+    pub io_stats: Arc<BlockIoStats>,
+    /// The tray of the removable device is open, and the medium is not present.
+    pub tray_open: bool,
+    /// Removal of the medium is prevented by PREVENT ALLOW MEDIUM REMOVAL.
+    pub prevent_removal: bool,
 }
 
 impl ScsiDevice {
@@ -116,20 +153,51 @@ impl ScsiDevice {
         scsi_type: u32,
         drive_files: Arc<Mutex<HashMap<String, DriveFile>>>,
     ) -> ScsiDevice {
+        let write_protect = Arc::new(WriteProtect::new(&config.id));
+        let io_error = Arc::new(IoErrorPolicy::new(
+            &config.id,
+            config.rerror,
+            config.werror,
+            None,
+            write_protect.clone(),
+        ));
+        let write_threshold = Arc::new(WriteThreshold::new(&config.id));
         ScsiDevice {
             config,
             state: ScsiDevState::new(),
             disk_image: None,
+            backing: None,
             req_align: 1,
             buf_align: 1,
             disk_sectors: 0,
             block_size: 0,
             scsi_type,
             parent_bus: Weak::new(),
+            max_sectors: DEFAULT_SCSI_MAX_SECTORS,
             drive_files,
+            io_error,
+            reservation: PersistentReservation::default(),
+            write_threshold,
+            write_protect,
+            unit_attention: None,
+            inflight: Arc::new(AtomicU64::new(0)),
+            io_stats: Arc::new(BlockIoStats::default()),
+            tray_open: false,
+            prevent_removal: false,
         }
     }
 
+    /// Set the machine to be paused when a request fails with the `stop` error policy.
+    pub fn set_vm(&mut self, vm: Weak<Mutex<dyn MachineLifecycle + Send + Sync>>) {
+        self.io_error = Arc::new(IoErrorPolicy::new(
+            &self.config.id,
+            self.config.rerror,
+            self.config.werror,
+            Some(vm),
+            self.write_protect.clone(),
+        ));
+    }
+
     pub fn realize(&mut self) -> Result<()> {
         match self.scsi_type {
             SCSI_TYPE_DISK => {
@@ -153,24 +221,135 @@ impl ScsiDevice {
         if !self.config.path_on_host.is_empty() {
             self.disk_image = None;
 
-            let drive_files = self.drive_files.lock().unwrap();
-            let mut file = VmConfig::fetch_drive_file(&drive_files, &self.config.path_on_host)?;
-            disk_size = file
-                .seek(SeekFrom::End(0))
-                .with_context(|| "Failed to seek the end for scsi device")?;
+            let mut drive_files = self.drive_files.lock().unwrap();
+            let file = VmConfig::fetch_drive_file(&drive_files, &self.config.path_on_host)?;
+            disk_size =
+                get_file_size(&file).with_context(|| "Failed to get the size for scsi device")?;
             self.disk_image = Some(Arc::new(file));
+            self.backing = VmConfig::fetch_drive_backing(&drive_files, &self.config.path_on_host)?;
 
             let alignments = VmConfig::fetch_drive_align(&drive_files, &self.config.path_on_host)?;
             self.req_align = alignments.0;
             self.buf_align = alignments.1;
+            VmConfig::attach_drive_file(
+                &mut drive_files,
+                &self.config.path_on_host,
+                &self.config.id,
+            )?;
         } else {
             self.disk_image = None;
+            self.backing = None;
             self.req_align = 1;
             self.buf_align = 1;
         }
 
         self.disk_sectors = disk_size >> SECTOR_SHIFT;
+        register_write_threshold(self.write_threshold.clone());
+        register_write_protect(self.write_protect.clone());
+        register_block_io_stats(&self.config.id, self.io_stats.clone());
+
+        Ok(())
+    }
+
+    pub fn unrealize(&mut self) {
+        unregister_write_threshold(&self.config.id);
+        unregister_write_protect(&self.config.id);
+        unregister_block_io_stats(&self.config.id);
+        self.disk_image = None;
+        self.backing = None;
+        if !self.config.path_on_host.is_empty() {
+            let mut drive_files = self.drive_files.lock().unwrap();
+            VmConfig::detach_drive_file(
+                &mut drive_files,
+                &self.config.path_on_host,
+                &self.config.id,
+            );
+        }
+    }
+
+    /// Whether the medium of the scsi device is removable.
+    pub fn is_removable(&self) -> bool {
+        self.scsi_type == SCSI_TYPE_ROM || self.state.features & (1 << SCSI_DISK_F_REMOVABLE) != 0
+    }
+
+    /// Return NOT READY sense if the medium is not present, for the commands which access it.
+    pub fn check_medium(&self) -> Option<ScsiSense> {
+        if self.tray_open {
+            return Some(SCSI_SENSE_NO_MEDIUM);
+        }
+        None
+    }
 
+    fn set_tray_open(&mut self, open: bool) {
+        if self.tray_open == open {
+            return;
+        }
+        self.tray_open = open;
+        let tray_moved = qmp_schema::DeviceTrayMoved {
+            device: self.config.id.clone(),
+            tray_open: open,
+        };
+        event!(DeviceTrayMoved; tray_moved);
+    }
+
+    /// Emulate START STOP UNIT. The medium of a removable device is unloaded if LOEJ is set
+    /// and START is not, or loaded if both are set. The other cases, and all the cases of a
+    /// non-removable device, only change the power condition which is not emulated.
+    ///
+    /// Return the sense if the medium can't be unloaded because its removal is prevented.
+    pub fn start_stop(&mut self, start: bool, loej: bool) -> Option<ScsiSense> {
+        if !self.is_removable() || !loej {
+            return None;
+        }
+        if !start && !self.tray_open && self.prevent_removal {
+            return Some(if self.disk_image.is_some() {
+                SCSI_SENSE_ILLEGAL_REQ_REMOVAL_PREVENTED
+            } else {
+                SCSI_SENSE_NOT_READY_REMOVAL_PREVENTED
+            });
+        }
+        self.set_tray_open(!start);
+        None
+    }
+
+    /// Eject the medium of the removable device, for `eject`. The removal prevented by the
+    /// guest is overridden if `force` is set.
+    pub fn eject(&mut self, force: bool) -> Result<()> {
+        if !self.is_removable() {
+            bail!("Device {} is not removable", self.config.id);
+        }
+        if self.prevent_removal {
+            if !force {
+                bail!("Device {} is locked by the guest", self.config.id);
+            }
+            self.prevent_removal = false;
+        }
+        self.set_tray_open(true);
+        Ok(())
+    }
+
+    /// Resize the disk to `size` bytes, for `block_resize`. The caller establishes the unit
+    /// attention after the lock of the device is released.
+    pub fn resize(&mut self, size: u64, allow_shrink: bool) -> Result<()> {
+        let file = self
+            .disk_image
+            .clone()
+            .with_context(|| format!("Scsi device {} has no medium", self.config.id))?;
+        if self.backing.is_some() {
+            bail!(
+                "Scsi device {} is an overlay of backing image, it can't be resized",
+                self.config.id
+            );
+        }
+        resize_image(
+            &file,
+            self.disk_sectors << SECTOR_SHIFT,
+            size,
+            self.block_size as u64,
+            self.config.read_only,
+            allow_shrink,
+        )?;
+        self.disk_sectors = size >> SECTOR_SHIFT;
         Ok(())
     }
 }
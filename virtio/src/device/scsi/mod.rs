@@ -13,3 +13,4 @@
 pub mod bus;
 pub mod controller;
 pub mod disk;
+pub mod reservation;
@@ -0,0 +1,426 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! In-memory persistent reservations (SPC-4) of scsi devices.
+//!
+//! An initiator is identified by the I_T nexus of the virtio-scsi request, and the
+//! reservations are lost when the VM exits.
+
+use std::collections::BTreeMap;
+
+use byteorder::{BigEndian, ByteOrder};
+
+use crate::ScsiBus::{
+    ScsiSense, SCSI_SENSE_INVALID_FIELD, SCSI_SENSE_INVALID_PARAM_LEN, SCSI_SENSE_INVALID_RELEASE,
+};
+
+/// PERSISTENT RESERVE IN service actions.
+pub const PR_IN_READ_KEYS: u8 = 0x00;
+pub const PR_IN_READ_RESERVATION: u8 = 0x01;
+
+/// PERSISTENT RESERVE OUT service actions.
+pub const PR_OUT_REGISTER: u8 = 0x00;
+pub const PR_OUT_RESERVE: u8 = 0x01;
+pub const PR_OUT_RELEASE: u8 = 0x02;
+pub const PR_OUT_CLEAR: u8 = 0x03;
+
+/// Persistent reservation types.
+pub const PR_TYPE_WRITE_EXCLUSIVE: u8 = 0x01;
+pub const PR_TYPE_EXCLUSIVE_ACCESS: u8 = 0x03;
+pub const PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY: u8 = 0x05;
+pub const PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY: u8 = 0x06;
+pub const PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS: u8 = 0x07;
+pub const PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS: u8 = 0x08;
+
+/// Length of the parameter list of PERSISTENT RESERVE OUT.
+pub const PR_OUT_PARAM_LEN: usize = 24;
+
+/// Failure of a persistent reservation command.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrError {
+    /// Complete the command with RESERVATION CONFLICT status.
+    Conflict,
+    /// Complete the command with CHECK CONDITION status and the sense.
+    Sense(ScsiSense),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Reservation {
+    /// Initiator holding the reservation.
+    holder: u64,
+    /// Reservation type.
+    pr_type: u8,
+}
+
+/// Persistent reservation state of a scsi device.
+#[derive(Clone, Debug, Default)]
+pub struct PersistentReservation {
+    /// PRgeneration, increased by REGISTER and CLEAR.
+    generation: u32,
+    /// Registered reservation keys, indexed by initiator.
+    registrants: BTreeMap<u64, u64>,
+    /// The current persistent reservation.
+    reservation: Option<Reservation>,
+}
+
+fn pr_type_valid(pr_type: u8) -> bool {
+    matches!(
+        pr_type,
+        PR_TYPE_WRITE_EXCLUSIVE
+            | PR_TYPE_EXCLUSIVE_ACCESS
+            | PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY
+            | PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY
+            | PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS
+            | PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS
+    )
+}
+
+fn pr_type_all_regs(pr_type: u8) -> bool {
+    matches!(
+        pr_type,
+        PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS | PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS
+    )
+}
+
+impl PersistentReservation {
+    fn is_holder(&self, initiator: u64) -> bool {
+        match self.reservation {
+            Some(rsv) if pr_type_all_regs(rsv.pr_type) => self.registrants.contains_key(&initiator),
+            Some(rsv) => rsv.holder == initiator,
+            None => false,
+        }
+    }
+
+    /// Check that `initiator` is registered with reservation key `key`.
+    fn check_key(&self, initiator: u64, key: u64) -> Result<(), PrError> {
+        match self.registrants.get(&initiator) {
+            Some(&registered) if registered == key => Ok(()),
+            _ => Err(PrError::Conflict),
+        }
+    }
+
+    /// Check whether the medium access of `initiator` conflicts with the reservation.
+    ///
+    /// # Arguments
+    ///
+    /// * `initiator` - The initiator sending the command.
+    /// * `write` - The command writes to the medium.
+    pub fn check_access(&self, initiator: u64, write: bool) -> Result<(), PrError> {
+        let rsv = match self.reservation {
+            Some(rsv) => rsv,
+            None => return Ok(()),
+        };
+        if self.is_holder(initiator) {
+            return Ok(());
+        }
+
+        let registered = self.registrants.contains_key(&initiator);
+        let allowed = match rsv.pr_type {
+            PR_TYPE_WRITE_EXCLUSIVE => !write,
+            PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY | PR_TYPE_WRITE_EXCLUSIVE_ALL_REGS => {
+                !write || registered
+            }
+            PR_TYPE_EXCLUSIVE_ACCESS_REGS_ONLY | PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS => registered,
+            _ => false,
+        };
+        if allowed {
+            Ok(())
+        } else {
+            Err(PrError::Conflict)
+        }
+    }
+
+    /// Emulate PERSISTENT RESERVE IN, return the parameter data.
+    ///
+    /// # Arguments
+    ///
+    /// * `service_action` - The service action in byte 1 of the CDB.
+    pub fn reserve_in(&self, service_action: u8) -> Result<Vec<u8>, PrError> {
+        let mut outbuf = vec![0_u8; 8];
+        BigEndian::write_u32(&mut outbuf[0..4], self.generation);
+        match service_action {
+            PR_IN_READ_KEYS => {
+                for key in self.registrants.values() {
+                    outbuf.extend_from_slice(&key.to_be_bytes());
+                }
+            }
+            PR_IN_READ_RESERVATION => {
+                if let Some(rsv) = self.reservation {
+                    // Byte[0-7]: reservation key. Byte[13]: bits[4-7]: scope, bits[0-3]: type.
+                    // The key of all registrants reservation is zero.
+                    let key = if pr_type_all_regs(rsv.pr_type) {
+                        0
+                    } else {
+                        self.registrants.get(&rsv.holder).copied().unwrap_or(0)
+                    };
+                    let mut desc = [0_u8; 16];
+                    BigEndian::write_u64(&mut desc[0..8], key);
+                    desc[13] = rsv.pr_type;
+                    outbuf.extend_from_slice(&desc);
+                }
+            }
+            _ => return Err(PrError::Sense(SCSI_SENSE_INVALID_FIELD)),
+        }
+        let additional_len = (outbuf.len() - 8) as u32;
+        BigEndian::write_u32(&mut outbuf[4..8], additional_len);
+        Ok(outbuf)
+    }
+
+    /// Emulate PERSISTENT RESERVE OUT.
+    ///
+    /// # Arguments
+    ///
+    /// * `initiator` - The initiator sending the command.
+    /// * `service_action` - The service action in byte 1 of the CDB.
+    /// * `scope_type` - Byte 2 of the CDB, bits[4-7]: scope, bits[0-3]: type.
+    /// * `param` - The parameter list.
+    pub fn reserve_out(
+        &mut self,
+        initiator: u64,
+        service_action: u8,
+        scope_type: u8,
+        param: &[u8],
+    ) -> Result<(), PrError> {
+        if param.len() != PR_OUT_PARAM_LEN {
+            return Err(PrError::Sense(SCSI_SENSE_INVALID_PARAM_LEN));
+        }
+        let key = BigEndian::read_u64(&param[0..8]);
+        let sa_key = BigEndian::read_u64(&param[8..16]);
+        let pr_type = scope_type & 0xf;
+
+        match service_action {
+            PR_OUT_REGISTER => self.register(initiator, key, sa_key),
+            PR_OUT_RESERVE | PR_OUT_RELEASE => {
+                // Only LU_SCOPE is supported.
+                if scope_type >> 4 != 0 || !pr_type_valid(pr_type) {
+                    return Err(PrError::Sense(SCSI_SENSE_INVALID_FIELD));
+                }
+                if service_action == PR_OUT_RESERVE {
+                    self.reserve(initiator, key, pr_type)
+                } else {
+                    self.release(initiator, key, pr_type)
+                }
+            }
+            PR_OUT_CLEAR => {
+                self.check_key(initiator, key)?;
+                self.registrants.clear();
+                self.reservation = None;
+                self.generation = self.generation.wrapping_add(1);
+                Ok(())
+            }
+            _ => Err(PrError::Sense(SCSI_SENSE_INVALID_FIELD)),
+        }
+    }
+
+    fn register(&mut self, initiator: u64, key: u64, sa_key: u64) -> Result<(), PrError> {
+        match self.registrants.get(&initiator) {
+            None if key != 0 => return Err(PrError::Conflict),
+            // Unregistering an unregistered initiator does nothing.
+            None if sa_key == 0 => return Ok(()),
+            None => {
+                self.registrants.insert(initiator, sa_key);
+            }
+            Some(&registered) if registered != key => return Err(PrError::Conflict),
+            Some(_) if sa_key == 0 => {
+                let holder = self.is_holder(initiator);
+                self.registrants.remove(&initiator);
+                // The reservation is released when the holder is unregistered, or the
+                // last registrant is unregistered for all registrants reservation.
+                if holder
+                    && self.reservation.map_or(false, |rsv| {
+                        !pr_type_all_regs(rsv.pr_type) || self.registrants.is_empty()
+                    })
+                {
+                    self.reservation = None;
+                }
+            }
+            Some(_) => {
+                self.registrants.insert(initiator, sa_key);
+            }
+        }
+        self.generation = self.generation.wrapping_add(1);
+        Ok(())
+    }
+
+    fn reserve(&mut self, initiator: u64, key: u64, pr_type: u8) -> Result<(), PrError> {
+        self.check_key(initiator, key)?;
+        match self.reservation {
+            // Reserving again with the same type is allowed for the holder.
+            Some(rsv) if self.is_holder(initiator) && rsv.pr_type == pr_type => Ok(()),
+            Some(_) => Err(PrError::Conflict),
+            None => {
+                self.reservation = Some(Reservation {
+                    holder: initiator,
+                    pr_type,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    fn release(&mut self, initiator: u64, key: u64, pr_type: u8) -> Result<(), PrError> {
+        self.check_key(initiator, key)?;
+        match self.reservation {
+            Some(rsv) if self.is_holder(initiator) => {
+                if rsv.pr_type != pr_type {
+                    return Err(PrError::Sense(SCSI_SENSE_INVALID_RELEASE));
+                }
+                self.reservation = None;
+                Ok(())
+            }
+            // Releasing by a non holder does nothing.
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn param(key: u64, sa_key: u64) -> Vec<u8> {
+        let mut buf = vec![0_u8; PR_OUT_PARAM_LEN];
+        BigEndian::write_u64(&mut buf[0..8], key);
+        BigEndian::write_u64(&mut buf[8..16], sa_key);
+        buf
+    }
+
+    #[test]
+    fn test_register_reserve_release() {
+        let mut pr = PersistentReservation::default();
+        let (a, b, c) = (1_u64, 2_u64, 3_u64);
+
+        // Register a and b.
+        assert!(pr
+            .reserve_out(a, PR_OUT_REGISTER, 0, &param(0, 0xa))
+            .is_ok());
+        assert!(pr
+            .reserve_out(b, PR_OUT_REGISTER, 0, &param(0, 0xb))
+            .is_ok());
+        // Registering with a wrong key conflicts.
+        assert_eq!(
+            pr.reserve_out(a, PR_OUT_REGISTER, 0, &param(0xb, 0xc)),
+            Err(PrError::Conflict)
+        );
+        let keys = pr.reserve_in(PR_IN_READ_KEYS).unwrap();
+        assert_eq!(BigEndian::read_u32(&keys[0..4]), 2);
+        assert_eq!(BigEndian::read_u32(&keys[4..8]), 16);
+        assert_eq!(BigEndian::read_u64(&keys[8..16]), 0xa);
+        assert_eq!(BigEndian::read_u64(&keys[16..24]), 0xb);
+
+        // a reserves Write Exclusive.
+        let rsv = pr.reserve_in(PR_IN_READ_RESERVATION).unwrap();
+        assert_eq!(BigEndian::read_u32(&rsv[4..8]), 0);
+        assert!(pr
+            .reserve_out(a, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, &param(0xa, 0))
+            .is_ok());
+        let rsv = pr.reserve_in(PR_IN_READ_RESERVATION).unwrap();
+        assert_eq!(BigEndian::read_u32(&rsv[4..8]), 16);
+        assert_eq!(BigEndian::read_u64(&rsv[8..16]), 0xa);
+        assert_eq!(rsv[21], PR_TYPE_WRITE_EXCLUSIVE);
+
+        // Other initiators conflict with the reservation.
+        assert_eq!(
+            pr.reserve_out(b, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, &param(0xb, 0)),
+            Err(PrError::Conflict)
+        );
+        assert_eq!(
+            pr.reserve_out(c, PR_OUT_RESERVE, PR_TYPE_WRITE_EXCLUSIVE, &param(0, 0)),
+            Err(PrError::Conflict)
+        );
+        assert!(pr.check_access(a, true).is_ok());
+        assert!(pr.check_access(b, false).is_ok());
+        assert_eq!(pr.check_access(b, true), Err(PrError::Conflict));
+        assert_eq!(pr.check_access(c, true), Err(PrError::Conflict));
+
+        // Releasing by a non holder does nothing, and the type must match.
+        assert!(pr
+            .reserve_out(b, PR_OUT_RELEASE, PR_TYPE_WRITE_EXCLUSIVE, &param(0xb, 0))
+            .is_ok());
+        assert_eq!(pr.check_access(b, true), Err(PrError::Conflict));
+        assert_eq!(
+            pr.reserve_out(a, PR_OUT_RELEASE, PR_TYPE_EXCLUSIVE_ACCESS, &param(0xa, 0)),
+            Err(PrError::Sense(SCSI_SENSE_INVALID_RELEASE))
+        );
+        assert!(pr
+            .reserve_out(a, PR_OUT_RELEASE, PR_TYPE_WRITE_EXCLUSIVE, &param(0xa, 0))
+            .is_ok());
+        assert!(pr.check_access(b, true).is_ok());
+        assert!(pr.check_access(c, true).is_ok());
+    }
+
+    #[test]
+    fn test_reservation_types() {
+        let mut pr = PersistentReservation::default();
+        let (a, b, c) = (1_u64, 2_u64, 3_u64);
+        pr.reserve_out(a, PR_OUT_REGISTER, 0, &param(0, 0xa))
+            .unwrap();
+        pr.reserve_out(b, PR_OUT_REGISTER, 0, &param(0, 0xb))
+            .unwrap();
+
+        // Registrants only: registrants have access, others can only read.
+        pr.reserve_out(
+            a,
+            PR_OUT_RESERVE,
+            PR_TYPE_WRITE_EXCLUSIVE_REGS_ONLY,
+            &param(0xa, 0),
+        )
+        .unwrap();
+        assert!(pr.check_access(b, true).is_ok());
+        assert!(pr.check_access(c, false).is_ok());
+        assert_eq!(pr.check_access(c, true), Err(PrError::Conflict));
+
+        // Unregistering the holder releases the reservation.
+        pr.reserve_out(a, PR_OUT_REGISTER, 0, &param(0xa, 0))
+            .unwrap();
+        assert!(pr.check_access(c, true).is_ok());
+
+        // All registrants exclusive access: the reservation is kept until the last
+        // registrant is unregistered.
+        pr.reserve_out(a, PR_OUT_REGISTER, 0, &param(0, 0xa))
+            .unwrap();
+        pr.reserve_out(
+            b,
+            PR_OUT_RESERVE,
+            PR_TYPE_EXCLUSIVE_ACCESS_ALL_REGS,
+            &param(0xb, 0),
+        )
+        .unwrap();
+        assert_eq!(pr.check_access(c, false), Err(PrError::Conflict));
+        pr.reserve_out(b, PR_OUT_REGISTER, 0, &param(0xb, 0))
+            .unwrap();
+        assert_eq!(pr.check_access(c, false), Err(PrError::Conflict));
+        assert!(pr.check_access(a, true).is_ok());
+        let rsv = pr.reserve_in(PR_IN_READ_RESERVATION).unwrap();
+        assert_eq!(BigEndian::read_u64(&rsv[8..16]), 0);
+
+        // CLEAR removes all registrants and the reservation.
+        assert_eq!(
+            pr.reserve_out(c, PR_OUT_CLEAR, 0, &param(0, 0)),
+            Err(PrError::Conflict)
+        );
+        pr.reserve_out(a, PR_OUT_CLEAR, 0, &param(0xa, 0)).unwrap();
+        assert!(pr.check_access(c, true).is_ok());
+        let keys = pr.reserve_in(PR_IN_READ_KEYS).unwrap();
+        assert_eq!(keys.len(), 8);
+
+        // Invalid parameter list length or type.
+        assert_eq!(
+            pr.reserve_out(a, PR_OUT_REGISTER, 0, &[0_u8; 8]),
+            Err(PrError::Sense(SCSI_SENSE_INVALID_PARAM_LEN))
+        );
+        assert_eq!(
+            pr.reserve_out(a, PR_OUT_RESERVE, 0x2, &param(0, 0)),
+            Err(PrError::Sense(SCSI_SENSE_INVALID_FIELD))
+        );
+    }
+}
@@ -0,0 +1,925 @@
+// Copyright (c) 2020 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+use std::io::Write;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::{cmp, usize};
+
+use crate::VirtioError;
+use crate::{
+    iov_to_buf, Queue, VirtioDevice, VirtioInterrupt, VirtioInterruptType, VirtioTrace,
+    VIRTIO_CONSOLE_F_MULTIPORT, VIRTIO_CONSOLE_F_SIZE, VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1,
+    VIRTIO_TYPE_CONSOLE,
+};
+use address_space::AddressSpace;
+use anyhow::{anyhow, bail, Context, Result};
+use devices::legacy::{register_chardev, Chardev, InputReceiver};
+use log::{debug, error, warn};
+use machine_manager::{
+    config::{VirtioSerialInfo, VirtioSerialPort, DEFAULT_VIRTQUEUE_SIZE},
+    event_loop::EventLoop,
+    event_loop::{register_event_helper, unregister_event_helper},
+};
+use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
+use migration_derive::{ByteCode, Desc};
+use util::byte_code::ByteCode;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
+use util::num_ops::read_u32;
+use vmm_sys_util::epoll::EventSet;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Number of virtqueues of each port, the control port also has two virtqueues.
+const QUEUE_NUM_PER_PORT: usize = 2;
+/// Index of the control receiveq, which is used to send control messages to guest.
+const CONTROL_RX_QUEUE: usize = 2;
+/// Index of the control transmitq, which is used to receive control messages from guest.
+const CONTROL_TX_QUEUE: usize = 3;
+
+const BUFF_SIZE: usize = 4096;
+
+// Events of control messages, refer to Virtio Spec.
+/// Guest driver is ready, sent by guest.
+const VIRTIO_CONSOLE_DEVICE_READY: u16 = 0;
+/// A port is added, sent by host.
+const VIRTIO_CONSOLE_PORT_ADD: u16 = 1;
+/// Port is ready in guest, sent by guest.
+const VIRTIO_CONSOLE_PORT_READY: u16 = 3;
+/// The port is a console port, sent by host.
+const VIRTIO_CONSOLE_CONSOLE_PORT: u16 = 4;
+/// Port is opened or closed, sent by both host and guest.
+const VIRTIO_CONSOLE_PORT_OPEN: u16 = 6;
+/// Name of the port, sent by host.
+const VIRTIO_CONSOLE_PORT_NAME: u16 = 7;
+
+#[derive(Copy, Clone, Debug, Default)]
+#[repr(C)]
+struct VirtioConsoleConfig {
+    cols: u16,
+    rows: u16,
+    max_nr_ports: u32,
+    emerg_wr: u32,
+}
+
+impl ByteCode for VirtioConsoleConfig {}
+
+impl VirtioConsoleConfig {
+    /// Create configuration of virtio-serial devices.
+    pub fn new(max_nr_ports: u32) -> Self {
+        VirtioConsoleConfig {
+            cols: 0_u16,
+            rows: 0_u16,
+            max_nr_ports,
+            emerg_wr: 0_u32,
+        }
+    }
+}
+
+/// Control message exchanged on the control virtqueues.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+#[repr(C)]
+struct VirtioConsoleControl {
+    /// Port number.
+    id: u32,
+    /// The kind of control event.
+    event: u16,
+    /// Extra information for the event.
+    value: u16,
+}
+
+impl ByteCode for VirtioConsoleControl {}
+
+impl VirtioConsoleControl {
+    fn new(id: u32, event: u16, value: u16) -> Self {
+        VirtioConsoleControl { id, event, value }
+    }
+}
+
+/// Get the index of the receiveq of port `nr`, the transmitq of the port follows it.
+/// Port 0 uses queue 0 and 1, the control port uses queue 2 and 3, and the other
+/// ports start from queue 4.
+fn port_rx_queue_index(nr: u32) -> usize {
+    if nr == 0 {
+        0
+    } else {
+        (nr as usize + 1) * QUEUE_NUM_PER_PORT
+    }
+}
+
+/// Port of virtio-serial device.
+pub struct SerialPort {
+    /// Name of the port, which is used by guest to create /dev/virtio-ports symlinks.
+    name: Option<String>,
+    /// Character device for redirection.
+    chardev: Arc<Mutex<Chardev>>,
+    /// Number of the port.
+    nr: u32,
+    /// Whether the port is a console port.
+    is_console: bool,
+    /// Whether the port is opened by guest.
+    guest_connected: bool,
+}
+
+impl SerialPort {
+    /// Create a port of virtio-serial device.
+    ///
+    /// # Arguments
+    ///
+    /// * `port_cfg` - Port configuration set by user.
+    pub fn new(port_cfg: VirtioSerialPort) -> Self {
+        SerialPort {
+            name: port_cfg.name,
+            chardev: Arc::new(Mutex::new(Chardev::new(port_cfg.chardev))),
+            nr: port_cfg.nr,
+            is_console: port_cfg.is_console,
+            guest_connected: false,
+        }
+    }
+}
+
+/// Write `buf` to the in iovecs of an element popped from `queue`, and return the length written.
+fn write_to_queue(
+    queue: &mut Queue,
+    mem_space: &Arc<AddressSpace>,
+    driver_features: u64,
+    buf: &[u8],
+) -> Result<usize> {
+    let elem = queue
+        .vring
+        .pop_avail(mem_space, driver_features)
+        .with_context(|| "Failed to pop avail ring")?;
+    if elem.desc_num == 0 {
+        return Ok(0);
+    }
+
+    let mut write_count = 0_usize;
+    for elem_iov in elem.in_iovec.iter() {
+        let allow_write_count = cmp::min(write_count + elem_iov.len as usize, buf.len());
+        let mut source_slice = &buf[write_count..allow_write_count];
+        mem_space
+            .write(
+                &mut source_slice,
+                elem_iov.addr,
+                (allow_write_count - write_count) as u64,
+            )
+            .with_context(|| {
+                format!(
+                    "Failed to write slice: addr {:X} len {}",
+                    elem_iov.addr.0,
+                    allow_write_count - write_count
+                )
+            })?;
+        write_count = allow_write_count;
+        if write_count >= buf.len() {
+            break;
+        }
+    }
+
+    queue
+        .vring
+        .add_used(mem_space, elem.index, write_count as u32)
+        .with_context(|| {
+            format!(
+                "Failed to add used ring, index: {} len: {}",
+                elem.index, write_count
+            )
+        })?;
+    Ok(write_count)
+}
+
+struct SerialPortHandler {
+    input_queue: Arc<Mutex<Queue>>,
+    output_queue: Arc<Mutex<Queue>>,
+    output_queue_evt: Arc<EventFd>,
+    mem_space: Arc<AddressSpace>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    driver_features: u64,
+    port: Arc<Mutex<SerialPort>>,
+    chardev: Arc<Mutex<Chardev>>,
+}
+
+impl InputReceiver for SerialPortHandler {
+    fn input_handle(&mut self, buffer: &[u8]) {
+        if buffer.is_empty() {
+            return;
+        }
+        if !self.port.lock().unwrap().guest_connected {
+            debug!("Port is not opened by guest, drop the input data");
+            return;
+        }
+
+        let mut queue_lock = self.input_queue.lock().unwrap();
+        let mut write_count = 0_usize;
+        while write_count < buffer.len() {
+            match write_to_queue(
+                &mut queue_lock,
+                &self.mem_space,
+                self.driver_features,
+                &buffer[write_count..],
+            ) {
+                Ok(0) => break,
+                Ok(count) => write_count += count,
+                Err(ref e) => {
+                    error!("Failed to write input data for serial port: {:?}", e);
+                    break;
+                }
+            }
+        }
+
+        if !queue_lock
+            .vring
+            .should_notify(&self.mem_space, self.driver_features)
+        {
+            return;
+        }
+        if let Err(ref e) =
+            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue_lock), false)
+        {
+            error!(
+                "Failed to trigger interrupt for serial port, int-type {:?} {:?} ",
+                VirtioInterruptType::Vring,
+                e
+            )
+        }
+    }
+
+    fn get_remain_space_size(&mut self) -> usize {
+        BUFF_SIZE
+    }
+
+    fn backend_changed(&mut self) {
+        self.output_handle();
+    }
+}
+
+impl SerialPortHandler {
+    fn output_handle(&mut self) {
+        self.trace_request("Serial".to_string(), "to IO".to_string());
+        let mut queue_lock = self.output_queue.lock().unwrap();
+        let mut buffer = [0_u8; BUFF_SIZE];
+
+        while let Ok(elem) = queue_lock
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+        {
+            if elem.desc_num == 0 {
+                break;
+            }
+            let read_count = match iov_to_buf(&self.mem_space, &elem.out_iovec, &mut buffer) {
+                Ok(count) => count,
+                Err(ref e) => {
+                    error!("Failed to read buffer for serial port output: {:?}", e);
+                    0
+                }
+            };
+            // The output taken by the client in the process isn't written to the backend.
+            let hook = self.chardev.lock().unwrap().output_hook();
+            if !hook.is_some_and(|hook| hook(&buffer[..read_count])) {
+                self.write_backend(&buffer[..read_count]);
+            }
+
+            if let Err(ref e) = queue_lock.vring.add_used(&self.mem_space, elem.index, 0) {
+                error!(
+                    "Failed to add used ring for serial port output, index: {} len: {} {:?}",
+                    elem.index, 0, e
+                );
+                break;
+            }
+        }
+    }
+
+    fn write_backend(&self, buf: &[u8]) {
+        if let Some(output) = &mut self.chardev.lock().unwrap().output {
+            let mut locked_output = output.lock().unwrap();
+            if let Err(e) = locked_output.write_all(buf) {
+                error!("Failed to write to serial port output: {:?}", e);
+            }
+            if let Err(e) = locked_output.flush() {
+                error!("Failed to flush serial port output: {:?}", e);
+            }
+        } else {
+            debug!("Failed to get output fd");
+        }
+    }
+}
+
+impl EventNotifierHelper for SerialPortHandler {
+    fn internal_notifiers(port_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+
+        let cloned_handler = port_handler.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            cloned_handler.lock().unwrap().output_handle();
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            port_handler.lock().unwrap().output_queue_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        ));
+
+        notifiers
+    }
+}
+
+struct SerialControlHandler {
+    input_queue: Arc<Mutex<Queue>>,
+    output_queue: Arc<Mutex<Queue>>,
+    output_queue_evt: Arc<EventFd>,
+    mem_space: Arc<AddressSpace>,
+    interrupt_cb: Arc<VirtioInterrupt>,
+    driver_features: u64,
+    ports: Vec<Arc<Mutex<SerialPort>>>,
+}
+
+impl SerialControlHandler {
+    fn output_control(&mut self) {
+        let mut queue_lock = self.output_queue.lock().unwrap();
+        let mut msgs = Vec::new();
+
+        while let Ok(elem) = queue_lock
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+        {
+            if elem.desc_num == 0 {
+                break;
+            }
+            let mut ctrl = VirtioConsoleControl::default();
+            match iov_to_buf(&self.mem_space, &elem.out_iovec, ctrl.as_mut_bytes()) {
+                Ok(len) if len == std::mem::size_of::<VirtioConsoleControl>() => msgs.push(ctrl),
+                Ok(len) => error!("Invalid length {} of serial control message", len),
+                Err(ref e) => error!("Failed to read serial control message: {:?}", e),
+            }
+
+            if let Err(ref e) = queue_lock.vring.add_used(&self.mem_space, elem.index, 0) {
+                error!(
+                    "Failed to add used ring for serial control output, index: {} {:?}",
+                    elem.index, e
+                );
+                break;
+            }
+        }
+        drop(queue_lock);
+
+        for ctrl in msgs.iter() {
+            self.handle_control_message(ctrl);
+        }
+    }
+
+    fn handle_control_message(&mut self, ctrl: &VirtioConsoleControl) {
+        if ctrl.event == VIRTIO_CONSOLE_DEVICE_READY {
+            if ctrl.value == 0 {
+                error!("Guest failed to initialize virtio-serial device");
+                return;
+            }
+            let nrs: Vec<u32> = self.ports.iter().map(|p| p.lock().unwrap().nr).collect();
+            for nr in nrs {
+                self.send_control_event(nr, VIRTIO_CONSOLE_PORT_ADD, 1);
+            }
+            return;
+        }
+
+        let port = match self.ports.iter().find(|p| p.lock().unwrap().nr == ctrl.id) {
+            Some(port) => port.clone(),
+            None => {
+                error!("Invalid port {} in serial control message", ctrl.id);
+                return;
+            }
+        };
+
+        match ctrl.event {
+            VIRTIO_CONSOLE_PORT_READY => {
+                if ctrl.value == 0 {
+                    error!("Guest failed to add port {} of virtio-serial", ctrl.id);
+                    return;
+                }
+                let (is_console, name) = {
+                    let locked_port = port.lock().unwrap();
+                    (locked_port.is_console, locked_port.name.clone())
+                };
+                if is_console {
+                    self.send_control_event(ctrl.id, VIRTIO_CONSOLE_CONSOLE_PORT, 1);
+                }
+                if let Some(name) = name {
+                    let mut msg = VirtioConsoleControl::new(ctrl.id, VIRTIO_CONSOLE_PORT_NAME, 1)
+                        .as_bytes()
+                        .to_vec();
+                    msg.extend_from_slice(name.as_bytes());
+                    self.send_control_message(&msg);
+                }
+                // The backend of port is always connected.
+                self.send_control_event(ctrl.id, VIRTIO_CONSOLE_PORT_OPEN, 1);
+            }
+            VIRTIO_CONSOLE_PORT_OPEN => {
+                port.lock().unwrap().guest_connected = ctrl.value != 0;
+            }
+            _ => {
+                warn!(
+                    "Unsupported serial control event {} for port {}",
+                    ctrl.event, ctrl.id
+                );
+            }
+        }
+    }
+
+    fn send_control_event(&mut self, id: u32, event: u16, value: u16) {
+        let ctrl = VirtioConsoleControl::new(id, event, value);
+        self.send_control_message(ctrl.as_bytes());
+    }
+
+    fn send_control_message(&mut self, msg: &[u8]) {
+        let mut queue_lock = self.input_queue.lock().unwrap();
+        match write_to_queue(&mut queue_lock, &self.mem_space, self.driver_features, msg) {
+            Ok(0) => {
+                error!("No buffer available for serial control message, drop it");
+                return;
+            }
+            Ok(_) => {}
+            Err(ref e) => {
+                error!("Failed to send serial control message: {:?}", e);
+                return;
+            }
+        }
+
+        if !queue_lock
+            .vring
+            .should_notify(&self.mem_space, self.driver_features)
+        {
+            return;
+        }
+        if let Err(ref e) =
+            (self.interrupt_cb)(&VirtioInterruptType::Vring, Some(&queue_lock), false)
+        {
+            error!(
+                "Failed to trigger interrupt for serial control, int-type {:?} {:?} ",
+                VirtioInterruptType::Vring,
+                e
+            )
+        }
+    }
+}
+
+impl EventNotifierHelper for SerialControlHandler {
+    fn internal_notifiers(control_handler: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let mut notifiers = Vec::new();
+
+        let cloned_handler = control_handler.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            cloned_handler.lock().unwrap().output_control();
+            None
+        });
+        notifiers.push(EventNotifier::new(
+            NotifierOperation::AddShared,
+            control_handler.lock().unwrap().output_queue_evt.as_raw_fd(),
+            None,
+            EventSet::IN,
+            vec![handler],
+        ));
+
+        notifiers
+    }
+}
+
+/// Status of virtio-serial device.
+#[repr(C)]
+#[derive(Copy, Clone, Desc, ByteCode)]
+#[desc_version(compat_version = "0.1.0")]
+pub struct VirtioSerialState {
+    /// Bit mask of features supported by the backend.
+    device_features: u64,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// Virtio Console config space.
+    config_space: VirtioConsoleConfig,
+}
+
+/// Virtio serial device structure.
+pub struct Serial {
+    /// Status of virtio-serial device.
+    state: VirtioSerialState,
+    /// EventFd for device deactivate.
+    deactivate_evts: Vec<RawFd>,
+    /// Ports attached to the device.
+    ports: Vec<Arc<Mutex<SerialPort>>>,
+    /// Whether the device is activated by guest.
+    activated: bool,
+}
+
+impl Serial {
+    /// Create a virtio-serial device.
+    ///
+    /// # Arguments
+    ///
+    /// * `serial_cfg` - Device configuration set by user.
+    pub fn new(serial_cfg: VirtioSerialInfo) -> Self {
+        Serial {
+            state: VirtioSerialState {
+                device_features: 0_u64,
+                driver_features: 0_u64,
+                config_space: VirtioConsoleConfig::new(serial_cfg.max_ports),
+            },
+            deactivate_evts: Vec::new(),
+            ports: Vec::new(),
+            activated: false,
+        }
+    }
+
+    /// Find a free port number. Console port prefers port 0, while other ports
+    /// start from port 1.
+    pub fn find_free_port_nr(&self, is_console: bool) -> Result<u32> {
+        let start = if is_console { 0 } else { 1 };
+        (start..self.state.config_space.max_nr_ports)
+            .find(|nr| !self.ports.iter().any(|p| p.lock().unwrap().nr == *nr))
+            .with_context(|| "No free port on virtio-serial device")
+    }
+
+    /// Add a port to the virtio-serial device.
+    ///
+    /// # Arguments
+    ///
+    /// * `port_cfg` - Port configuration set by user.
+    pub fn add_port(&mut self, port_cfg: VirtioSerialPort) -> Result<()> {
+        if self.activated {
+            bail!("Hotplugging port to an activated virtio-serial device is not supported");
+        }
+        if port_cfg.nr >= self.state.config_space.max_nr_ports {
+            bail!(
+                "Port number {} exceeds the max_ports {} of virtio-serial device",
+                port_cfg.nr,
+                self.state.config_space.max_nr_ports
+            );
+        }
+        for port in self.ports.iter() {
+            let locked_port = port.lock().unwrap();
+            if locked_port.nr == port_cfg.nr {
+                bail!("Port number {} is already in use", port_cfg.nr);
+            }
+            if port_cfg.name.is_some() && locked_port.name == port_cfg.name {
+                bail!("Port name {:?} is already in use", port_cfg.name.unwrap());
+            }
+        }
+
+        let port = SerialPort::new(port_cfg);
+        let chardev = port.chardev.clone();
+        chardev
+            .lock()
+            .unwrap()
+            .realize()
+            .with_context(|| "Failed to realize chardev")?;
+        chardev.lock().unwrap().deactivated = true;
+        register_chardev(&chardev);
+        EventLoop::update_event(EventNotifierHelper::internal_notifiers(chardev), None)?;
+        self.ports.push(Arc::new(Mutex::new(port)));
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Serial {
+    /// Realize virtio serial device.
+    fn realize(&mut self) -> Result<()> {
+        self.state.device_features = 1_u64 << VIRTIO_F_VERSION_1
+            | 1_u64 << VIRTIO_CONSOLE_F_SIZE
+            | 1_u64 << VIRTIO_CONSOLE_F_MULTIPORT
+            | 1_u64 << VIRTIO_F_RING_EVENT_IDX;
+        Ok(())
+    }
+
+    /// Get the virtio device type, refer to Virtio Spec.
+    fn device_type(&self) -> u32 {
+        VIRTIO_TYPE_CONSOLE
+    }
+
+    /// Get the count of virtio device queues.
+    fn queue_num(&self) -> usize {
+        // Each port has a receiveq and a transmitq, the control port included.
+        (self.state.config_space.max_nr_ports as usize + 1) * QUEUE_NUM_PER_PORT
+    }
+
+    /// Get the queue size of virtio device.
+    fn queue_size(&self) -> u16 {
+        DEFAULT_VIRTQUEUE_SIZE
+    }
+
+    /// Get device features from host.
+    fn get_device_features(&self, features_select: u32) -> u32 {
+        read_u32(self.state.device_features, features_select)
+    }
+
+    /// Set driver features by guest.
+    fn set_driver_features(&mut self, page: u32, value: u32) {
+        self.state.driver_features = self.checked_driver_features(page, value);
+    }
+
+    /// Get driver features by guest.
+    fn get_driver_features(&self, features_select: u32) -> u32 {
+        read_u32(self.state.driver_features, features_select)
+    }
+
+    /// Read data of config from guest.
+    fn read_config(&self, offset: u64, mut data: &mut [u8]) -> Result<()> {
+        let config_slice = self.state.config_space.as_bytes();
+        let config_len = config_slice.len() as u64;
+        if offset >= config_len {
+            return Err(anyhow!(VirtioError::DevConfigOverflow(offset, config_len)));
+        }
+
+        if let Some(end) = offset.checked_add(data.len() as u64) {
+            data.write_all(&config_slice[offset as usize..cmp::min(end, config_len) as usize])?;
+        }
+
+        Ok(())
+    }
+
+    /// Write data to config from guest.
+    fn write_config(&mut self, _offset: u64, _data: &[u8]) -> Result<()> {
+        bail!("Device config space for virtio-serial is not supported")
+    }
+
+    /// Activate the virtio device, this function is called by vcpu thread when frontend
+    /// virtio driver is ready and write `DRIVER_OK` to backend.
+    fn activate(
+        &mut self,
+        mem_space: Arc<AddressSpace>,
+        interrupt_cb: Arc<VirtioInterrupt>,
+        queues: &[Arc<Mutex<Queue>>],
+        queue_evts: Vec<Arc<EventFd>>,
+    ) -> Result<()> {
+        let multiport = self.state.driver_features & (1_u64 << VIRTIO_CONSOLE_F_MULTIPORT) != 0;
+
+        for port in self.ports.iter() {
+            // Chardev calls the input handler which locks the port, so don't hold the
+            // port lock while locking chardev.
+            let (nr, chardev) = {
+                let mut locked_port = port.lock().unwrap();
+                // Without multiport, port 0 is regarded as opened by guest.
+                locked_port.guest_connected = !multiport;
+                (locked_port.nr, locked_port.chardev.clone())
+            };
+            // Only port 0 works if multiport is not negotiated.
+            if !multiport && nr != 0 {
+                continue;
+            }
+
+            let rx_queue = port_rx_queue_index(nr);
+            let handler = SerialPortHandler {
+                input_queue: queues[rx_queue].clone(),
+                output_queue: queues[rx_queue + 1].clone(),
+                output_queue_evt: queue_evts[rx_queue + 1].clone(),
+                mem_space: mem_space.clone(),
+                interrupt_cb: interrupt_cb.clone(),
+                driver_features: self.state.driver_features,
+                port: port.clone(),
+                chardev: chardev.clone(),
+            };
+
+            let dev = Arc::new(Mutex::new(handler));
+            let notifiers = EventNotifierHelper::internal_notifiers(dev.clone());
+            register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
+
+            let mut locked_chardev = chardev.lock().unwrap();
+            locked_chardev.set_input_callback(&dev);
+            locked_chardev.deactivated = false;
+        }
+
+        if multiport {
+            let handler = SerialControlHandler {
+                input_queue: queues[CONTROL_RX_QUEUE].clone(),
+                output_queue: queues[CONTROL_TX_QUEUE].clone(),
+                output_queue_evt: queue_evts[CONTROL_TX_QUEUE].clone(),
+                mem_space,
+                interrupt_cb,
+                driver_features: self.state.driver_features,
+                ports: self.ports.clone(),
+            };
+            let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
+            register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
+        }
+
+        self.activated = true;
+        Ok(())
+    }
+
+    fn deactivate(&mut self) -> Result<()> {
+        for port in self.ports.iter() {
+            let chardev = {
+                let mut locked_port = port.lock().unwrap();
+                locked_port.guest_connected = false;
+                locked_port.chardev.clone()
+            };
+            chardev.lock().unwrap().deactivated = true;
+        }
+        self.activated = false;
+        unregister_event_helper(None, &mut self.deactivate_evts)
+    }
+}
+
+impl StateTransfer for Serial {
+    fn get_state_vec(&self) -> migration::Result<Vec<u8>> {
+        Ok(self.state.as_bytes().to_vec())
+    }
+
+    fn set_state_mut(&mut self, state: &[u8]) -> migration::Result<()> {
+        self.state = *VirtioSerialState::from_bytes(state)
+            .ok_or_else(|| anyhow!(migration::error::MigrationError::FromBytesError("SERIAL")))?;
+
+        Ok(())
+    }
+
+    fn get_device_alias(&self) -> u64 {
+        if let Some(alias) = MigrationManager::get_desc_alias(&VirtioSerialState::descriptor().name)
+        {
+            alias
+        } else {
+            !0
+        }
+    }
+}
+
+impl MigrationHook for Serial {}
+
+impl VirtioTrace for SerialPortHandler {}
+
+#[cfg(test)]
+mod tests {
+    pub use super::super::*;
+    pub use super::*;
+    use std::mem::size_of;
+
+    use machine_manager::config::{ChardevConfig, ChardevType};
+
+    fn serial_port(nr: u32, is_console: bool) -> Arc<Mutex<SerialPort>> {
+        let port_cfg = VirtioSerialPort {
+            id: format!("port{}", nr),
+            chardev: ChardevConfig {
+                id: format!("chardev{}", nr),
+                backend: ChardevType::Stdio,
+            },
+            nr,
+            name: None,
+            is_console,
+        };
+        Arc::new(Mutex::new(SerialPort::new(port_cfg)))
+    }
+
+    #[test]
+    fn test_set_driver_features() {
+        let mut console = Serial::new(VirtioSerialInfo {
+            id: "serial".to_string(),
+            pci_bdf: None,
+            multifunction: false,
+            max_ports: 1,
+        });
+
+        //If the device feature is 0, all driver features are not supported.
+        console.state.device_features = 0;
+        let driver_feature: u32 = 0xFF;
+        let page = 0_u32;
+        console.set_driver_features(page, driver_feature);
+        assert_eq!(console.state.driver_features, 0_u64);
+        assert_eq!(console.get_driver_features(page) as u64, 0_u64);
+
+        let driver_feature: u32 = 0xFF;
+        let page = 1_u32;
+        console.set_driver_features(page, driver_feature);
+        assert_eq!(console.state.driver_features, 0_u64);
+        assert_eq!(console.get_driver_features(page) as u64, 0_u64);
+
+        //If both the device feature bit and the front-end driver feature bit are
+        //supported at the same time,  this driver feature bit is supported.
+        console.state.device_features =
+            1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE;
+        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_SIZE) as u32;
+        let page = 0_u32;
+        console.set_driver_features(page, driver_feature);
+        assert_eq!(
+            console.state.driver_features,
+            (1_u64 << VIRTIO_CONSOLE_F_SIZE)
+        );
+        assert_eq!(
+            console.get_driver_features(page) as u64,
+            (1_u64 << VIRTIO_CONSOLE_F_SIZE)
+        );
+        console.state.driver_features = 0;
+
+        console.state.device_features = 1_u64 << VIRTIO_F_VERSION_1;
+        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_SIZE) as u32;
+        let page = 0_u32;
+        console.set_driver_features(page, driver_feature);
+        assert_eq!(console.state.driver_features, 0);
+        console.state.driver_features = 0;
+
+        console.state.device_features =
+            1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE;
+        let driver_feature: u32 = (1_u64 << VIRTIO_CONSOLE_F_SIZE) as u32;
+        let page = 0_u32;
+        console.set_driver_features(page, driver_feature);
+        assert_eq!(
+            console.state.driver_features,
+            (1_u64 << VIRTIO_CONSOLE_F_SIZE)
+        );
+
+        let driver_feature: u32 = ((1_u64 << VIRTIO_F_VERSION_1) >> 32) as u32;
+        let page = 1_u32;
+        console.set_driver_features(page, driver_feature);
+        assert_eq!(
+            console.state.driver_features,
+            (1_u64 << VIRTIO_F_VERSION_1 | 1_u64 << VIRTIO_CONSOLE_F_SIZE)
+        );
+    }
+
+    #[test]
+    fn test_read_config() {
+        let mut console = Serial::new(VirtioSerialInfo {
+            id: "serial".to_string(),
+            pci_bdf: None,
+            multifunction: false,
+            max_ports: 1,
+        });
+
+        //The offset of configuration that needs to be read exceeds the maximum
+        let offset = size_of::<VirtioConsoleConfig>() as u64;
+        let mut read_data: Vec<u8> = vec![0; 8];
+        assert_eq!(console.read_config(offset, &mut read_data).is_ok(), false);
+
+        //Check the configuration that needs to be read
+        let offset = 0_u64;
+        let mut read_data: Vec<u8> = vec![0; 12];
+        let expect_data: Vec<u8> = vec![0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(console.read_config(offset, &mut read_data).is_ok(), true);
+        assert_eq!(read_data, expect_data);
+
+        let offset = 4_u64;
+        let mut read_data: Vec<u8> = vec![0; 1];
+        let expect_data: Vec<u8> = vec![1];
+        assert_eq!(console.read_config(offset, &mut read_data).is_ok(), true);
+        assert_eq!(read_data, expect_data);
+    }
+
+    #[test]
+    fn test_control_message() {
+        let ctrl = VirtioConsoleControl::new(3, VIRTIO_CONSOLE_PORT_OPEN, 1);
+        assert_eq!(ctrl.as_bytes(), &[3, 0, 0, 0, 6, 0, 1, 0]);
+
+        let ctrl = VirtioConsoleControl::new(0x0102, VIRTIO_CONSOLE_PORT_NAME, 1);
+        assert_eq!(ctrl.as_bytes(), &[2, 1, 0, 0, 7, 0, 1, 0]);
+
+        let msg = [5_u8, 0, 0, 0, 3, 0, 1, 0];
+        let mut ctrl = VirtioConsoleControl::default();
+        ctrl.as_mut_bytes().copy_from_slice(&msg);
+        assert_eq!(
+            ctrl,
+            VirtioConsoleControl::new(5, VIRTIO_CONSOLE_PORT_READY, 1)
+        );
+    }
+
+    #[test]
+    fn test_port_queue_index() {
+        assert_eq!(port_rx_queue_index(0), 0);
+        assert_eq!(port_rx_queue_index(1), 4);
+        assert_eq!(port_rx_queue_index(2), 6);
+        assert_eq!(port_rx_queue_index(30), 62);
+
+        let mut serial = Serial::new(VirtioSerialInfo {
+            id: "serial".to_string(),
+            pci_bdf: None,
+            multifunction: false,
+            max_ports: 31,
+        });
+        // The last port uses the last two queues.
+        assert_eq!(serial.queue_num(), 64);
+        assert_eq!(port_rx_queue_index(30) + 1, serial.queue_num() - 1);
+
+        assert_eq!(serial.find_free_port_nr(true).unwrap(), 0);
+        assert_eq!(serial.find_free_port_nr(false).unwrap(), 1);
+        serial.ports.push(serial_port(0, true));
+        serial.ports.push(serial_port(1, false));
+        assert_eq!(serial.find_free_port_nr(true).unwrap(), 2);
+        assert_eq!(serial.find_free_port_nr(false).unwrap(), 2);
+
+        let mut serial = Serial::new(VirtioSerialInfo {
+            id: "serial".to_string(),
+            pci_bdf: None,
+            multifunction: false,
+            max_ports: 2,
+        });
+        serial.ports.push(serial_port(1, false));
+        assert_eq!(serial.find_free_port_nr(true).unwrap(), 0);
+        assert!(serial.find_free_port_nr(false).is_err());
+    }
+}
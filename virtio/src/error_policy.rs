@@ -0,0 +1,475 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Read/write error policies of virtio block and scsi disks.
+//!
+//! When the backend fails a request, the `rerror`/`werror` policy of the disk decides
+//! whether the error is reported to the guest, ignored, or the VM is paused. Requests
+//! failed with the `stop` action are kept by their io handler, which is kicked to
+//! resubmit them once the VM is resumed. With the `read-only` action, the disk rejects
+//! writes after it runs out of space, until `block-set-writable` restores it.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::{anyhow, Result};
+use log::{error, info, warn};
+use machine_manager::config::BlockErrorPolicy;
+use machine_manager::event;
+use machine_manager::machine::MachineLifecycle;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use once_cell::sync::Lazy;
+use vmm_sys_util::eventfd::EventFd;
+
+/// Io handlers holding stopped requests, which are kicked when the VM is resumed.
+static STOPPED_HANDLERS: Lazy<Mutex<Vec<Arc<RetryNotifier>>>> =
+    Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Write protection states of all realized disks, keyed by device id.
+static WRITE_PROTECTS: Lazy<Mutex<BTreeMap<String, Arc<WriteProtect>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Action taken for a failed request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Complete the request with an error.
+    Report,
+    /// Complete the request successfully.
+    Ignore,
+    /// Keep the request and pause the VM.
+    Stop,
+    /// Complete the request with an error and reject the following writes.
+    ReadOnly,
+}
+
+impl ErrorAction {
+    /// Get the action of `policy` for a request failed with `errno`.
+    pub fn from_policy(policy: BlockErrorPolicy, errno: i32) -> Self {
+        match policy {
+            BlockErrorPolicy::Report => ErrorAction::Report,
+            BlockErrorPolicy::Ignore => ErrorAction::Ignore,
+            BlockErrorPolicy::Stop => ErrorAction::Stop,
+            BlockErrorPolicy::Enospc if errno == libc::ENOSPC => ErrorAction::Stop,
+            BlockErrorPolicy::Enospc => ErrorAction::Report,
+            BlockErrorPolicy::ReadOnly if errno == libc::ENOSPC => ErrorAction::ReadOnly,
+            BlockErrorPolicy::ReadOnly => ErrorAction::Report,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ErrorAction::Report => "report",
+            ErrorAction::Ignore => "ignore",
+            ErrorAction::Stop => "stop",
+            ErrorAction::ReadOnly => "read-only",
+        }
+    }
+}
+
+/// Write protection of a disk, set by the `read-only` action and cleared by
+/// `block-set-writable`. It's owned by the device, so that it survives the
+/// re-activation of the io handlers.
+pub struct WriteProtect {
+    /// Id of the disk.
+    id: String,
+    /// Writes are rejected.
+    protected: AtomicBool,
+}
+
+impl WriteProtect {
+    pub fn new(id: &str) -> Self {
+        WriteProtect {
+            id: id.to_string(),
+            protected: AtomicBool::new(false),
+        }
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.protected.load(Ordering::SeqCst)
+    }
+
+    /// Reject the following writes. Return false if they were rejected already.
+    fn protect(&self) -> bool {
+        !self.protected.swap(true, Ordering::SeqCst)
+    }
+
+    /// Accept writes again. Return false if they were accepted already.
+    fn unprotect(&self) -> bool {
+        self.protected.swap(false, Ordering::SeqCst)
+    }
+}
+
+/// Register the write protection of a disk to be cleared by `block-set-writable`.
+pub fn register_write_protect(write_protect: Arc<WriteProtect>) {
+    WRITE_PROTECTS
+        .lock()
+        .unwrap()
+        .insert(write_protect.id.clone(), write_protect);
+}
+
+pub fn unregister_write_protect(id: &str) {
+    WRITE_PROTECTS.lock().unwrap().remove(id);
+}
+
+/// Get whether the disk `id` is write protected by the `read-only` action.
+pub fn query_write_protect(id: &str) -> Option<bool> {
+    WRITE_PROTECTS
+        .lock()
+        .unwrap()
+        .get(id)
+        .map(|wp| wp.is_protected())
+}
+
+/// Restore the write access of the disk `id` for `block-set-writable`.
+pub fn qmp_block_set_writable(id: &str) -> Result<()> {
+    let protects = WRITE_PROTECTS.lock().unwrap();
+    let write_protect = protects
+        .get(id)
+        .ok_or_else(|| anyhow!("Disk {} is not found", id))?;
+    if write_protect.unprotect() {
+        info!("Disk {} is writable again", id);
+    }
+    Ok(())
+}
+
+/// Error policies of a disk, shared by its io handlers.
+#[derive(Clone)]
+pub struct IoErrorPolicy {
+    /// Id of the disk, reported in `BLOCK_IO_ERROR`.
+    id: String,
+    /// Policy of read errors.
+    rerror: BlockErrorPolicy,
+    /// Policy of write errors.
+    werror: BlockErrorPolicy,
+    /// The machine to be paused by the `stop` action.
+    vm: Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>>,
+    /// Write protection set by the `read-only` action.
+    write_protect: Arc<WriteProtect>,
+}
+
+impl IoErrorPolicy {
+    pub fn new(
+        id: &str,
+        rerror: BlockErrorPolicy,
+        werror: BlockErrorPolicy,
+        vm: Option<Weak<Mutex<dyn MachineLifecycle + Send + Sync>>>,
+        write_protect: Arc<WriteProtect>,
+    ) -> Self {
+        IoErrorPolicy {
+            id: id.to_string(),
+            rerror,
+            werror,
+            vm,
+            write_protect,
+        }
+    }
+
+    /// Writes to the disk are rejected by the `read-only` action.
+    pub fn is_write_protected(&self) -> bool {
+        self.write_protect.is_protected()
+    }
+
+    /// Handle a request failed by the backend, return the action taken for it.
+    /// `BLOCK_IO_ERROR` is emitted, the VM is paused for the `stop` action and the
+    /// disk is write protected for the `read-only` action.
+    ///
+    /// # Arguments
+    ///
+    /// * `is_write` - The request writes to the backend.
+    /// * `errno` - The errno of the failure.
+    pub fn handle_error(&self, is_write: bool, errno: i32) -> ErrorAction {
+        let policy = if is_write { self.werror } else { self.rerror };
+        let mut action = ErrorAction::from_policy(policy, errno);
+        if action == ErrorAction::Stop && !self.stop_vm() {
+            action = ErrorAction::Report;
+        }
+        if action == ErrorAction::ReadOnly && self.write_protect.protect() {
+            warn!(
+                "Disk {} is out of space, reject the following writes",
+                self.id
+            );
+        }
+
+        let event = self.io_error_event(is_write, errno, action);
+        event!(BlockIoError; event);
+        action
+    }
+
+    /// Pause the VM. Return false if there is no machine to pause.
+    fn stop_vm(&self) -> bool {
+        match self.vm.as_ref().and_then(|vm| vm.upgrade()) {
+            Some(vm) => {
+                // The VM may have been paused by another failed request.
+                vm.lock().unwrap().pause();
+                true
+            }
+            None => {
+                warn!("Disk {} can't stop the VM, report the error", self.id);
+                false
+            }
+        }
+    }
+
+    fn io_error_event(
+        &self,
+        is_write: bool,
+        errno: i32,
+        action: ErrorAction,
+    ) -> qmp_schema::BlockIoError {
+        qmp_schema::BlockIoError {
+            device: self.id.clone(),
+            operation: if is_write { "write" } else { "read" }.to_string(),
+            action: action.name().to_string(),
+            nospace: errno == libc::ENOSPC,
+            reason: std::io::Error::from_raw_os_error(errno).to_string(),
+        }
+    }
+}
+
+/// Kicks an io handler to resubmit its stopped requests after the VM is resumed.
+pub struct RetryNotifier {
+    /// The VM has been resumed since the requests were stopped.
+    resumed: AtomicBool,
+    /// Eventfd to wake up the io handler.
+    kick: Arc<EventFd>,
+}
+
+impl RetryNotifier {
+    fn new(kick: Arc<EventFd>) -> Self {
+        RetryNotifier {
+            resumed: AtomicBool::new(false),
+            kick,
+        }
+    }
+
+    fn arm(self: &Arc<Self>) {
+        self.resumed.store(false, Ordering::SeqCst);
+        let mut handlers = STOPPED_HANDLERS.lock().unwrap();
+        if !handlers.iter().any(|h| Arc::ptr_eq(h, self)) {
+            handlers.push(self.clone());
+        }
+    }
+
+    fn notify(&self) {
+        self.resumed.store(true, Ordering::SeqCst);
+        if let Err(e) = self.kick.write(1) {
+            error!(
+                "Failed to kick io handler to retry stopped requests, {:?}",
+                e
+            );
+        }
+    }
+}
+
+/// Requests of an io handler failed with the `stop` action.
+pub struct StoppedRequests<T> {
+    reqs: Mutex<Vec<T>>,
+    notifier: Arc<RetryNotifier>,
+}
+
+impl<T> StoppedRequests<T> {
+    /// Create the queue of stopped requests of an io handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `kick` - Eventfd to wake up the io handler when the VM is resumed.
+    pub fn new(kick: Arc<EventFd>) -> Self {
+        StoppedRequests {
+            reqs: Mutex::new(Vec::new()),
+            notifier: Arc::new(RetryNotifier::new(kick)),
+        }
+    }
+
+    /// Keep a request until the VM is resumed.
+    pub fn push(&self, req: T) {
+        self.reqs.lock().unwrap().push(req);
+        self.notifier.arm();
+    }
+
+    /// Take the stopped requests to resubmit them. Return None if the VM has not been
+    /// resumed yet, in which case the io handler should not process new requests either.
+    pub fn take(&self) -> Option<Vec<T>> {
+        let mut reqs = self.reqs.lock().unwrap();
+        if reqs.is_empty() {
+            return Some(Vec::new());
+        }
+        if !self.notifier.resumed.swap(false, Ordering::SeqCst) {
+            return None;
+        }
+        Some(reqs.drain(..).collect())
+    }
+
+    pub fn len(&self) -> usize {
+        self.reqs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Kick all io handlers holding stopped requests to resubmit them. It's called when
+/// the VM is resumed.
+pub fn retry_stopped_requests() {
+    let handlers: Vec<Arc<RetryNotifier>> = STOPPED_HANDLERS.lock().unwrap().drain(..).collect();
+    for handler in handlers {
+        handler.notify();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use machine_manager::machine::KvmVmState;
+
+    #[derive(Default)]
+    struct TestVm {
+        pauses: Mutex<u32>,
+    }
+
+    impl MachineLifecycle for TestVm {
+        fn pause(&self) -> bool {
+            *self.pauses.lock().unwrap() += 1;
+            true
+        }
+
+        fn notify_lifecycle(&self, _old: KvmVmState, _new: KvmVmState) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn test_error_action() {
+        use BlockErrorPolicy::*;
+
+        assert_eq!(
+            ErrorAction::from_policy(Report, libc::ENOSPC),
+            ErrorAction::Report
+        );
+        assert_eq!(
+            ErrorAction::from_policy(Ignore, libc::EIO),
+            ErrorAction::Ignore
+        );
+        assert_eq!(ErrorAction::from_policy(Stop, libc::EIO), ErrorAction::Stop);
+        assert_eq!(
+            ErrorAction::from_policy(Enospc, libc::ENOSPC),
+            ErrorAction::Stop
+        );
+        assert_eq!(
+            ErrorAction::from_policy(Enospc, libc::EIO),
+            ErrorAction::Report
+        );
+        assert_eq!(
+            ErrorAction::from_policy(ReadOnly, libc::ENOSPC),
+            ErrorAction::ReadOnly
+        );
+        assert_eq!(
+            ErrorAction::from_policy(ReadOnly, libc::EIO),
+            ErrorAction::Report
+        );
+    }
+
+    #[test]
+    fn test_io_error_policy() {
+        QmpChannel::object_init();
+        let vm = Arc::new(Mutex::new(TestVm::default()));
+        let vm_dyn: Arc<Mutex<dyn MachineLifecycle + Send + Sync>> = vm.clone();
+        let policy = IoErrorPolicy::new(
+            "drive-0",
+            BlockErrorPolicy::Report,
+            BlockErrorPolicy::Enospc,
+            Some(Arc::downgrade(&vm_dyn)),
+            Arc::new(WriteProtect::new("drive-0")),
+        );
+
+        let event = policy.io_error_event(true, libc::ENOSPC, ErrorAction::Stop);
+        assert_eq!(event.device, "drive-0");
+        assert_eq!(event.operation, "write");
+        assert_eq!(event.action, "stop");
+        assert!(event.nospace);
+
+        assert_eq!(policy.handle_error(true, libc::ENOSPC), ErrorAction::Stop);
+        assert_eq!(*vm.lock().unwrap().pauses.lock().unwrap(), 1);
+        assert_eq!(policy.handle_error(true, libc::EIO), ErrorAction::Report);
+        assert_eq!(
+            policy.handle_error(false, libc::ENOSPC),
+            ErrorAction::Report
+        );
+        assert_eq!(*vm.lock().unwrap().pauses.lock().unwrap(), 1);
+
+        // Without a machine to pause, the error is reported.
+        drop(vm_dyn);
+        drop(vm);
+        assert_eq!(policy.handle_error(true, libc::ENOSPC), ErrorAction::Report);
+    }
+
+    #[test]
+    fn test_write_protect() {
+        QmpChannel::object_init();
+        let write_protect = Arc::new(WriteProtect::new("drive-wp"));
+        register_write_protect(write_protect.clone());
+        let policy = IoErrorPolicy::new(
+            "drive-wp",
+            BlockErrorPolicy::Report,
+            BlockErrorPolicy::ReadOnly,
+            None,
+            write_protect,
+        );
+        assert_eq!(query_write_protect("drive-wp"), Some(false));
+
+        // Other errors than ENOSPC don't protect the disk.
+        assert_eq!(policy.handle_error(true, libc::EIO), ErrorAction::Report);
+        assert!(!policy.is_write_protected());
+
+        assert_eq!(
+            policy.handle_error(true, libc::ENOSPC),
+            ErrorAction::ReadOnly
+        );
+        assert!(policy.is_write_protected());
+        assert_eq!(query_write_protect("drive-wp"), Some(true));
+        let event = policy.io_error_event(true, libc::ENOSPC, ErrorAction::ReadOnly);
+        assert_eq!(event.action, "read-only");
+
+        // The protection is shared by the io handlers of the disk.
+        let cloned = policy.clone();
+        assert!(cloned.is_write_protected());
+
+        qmp_block_set_writable("drive-wp").unwrap();
+        assert!(!policy.is_write_protected());
+        assert!(!cloned.is_write_protected());
+        assert_eq!(query_write_protect("drive-wp"), Some(false));
+        // Restoring a writable disk is harmless.
+        qmp_block_set_writable("drive-wp").unwrap();
+
+        unregister_write_protect("drive-wp");
+        assert!(qmp_block_set_writable("drive-wp").is_err());
+        assert_eq!(query_write_protect("drive-wp"), None);
+    }
+
+    #[test]
+    fn test_stopped_requests() {
+        let kick = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let stopped = StoppedRequests::new(kick.clone());
+        assert_eq!(stopped.take(), Some(Vec::new()));
+
+        stopped.push(1_u32);
+        stopped.push(2_u32);
+        assert_eq!(stopped.take(), None);
+        assert_eq!(stopped.len(), 2);
+
+        retry_stopped_requests();
+        assert_eq!(kick.read().unwrap(), 1);
+        assert_eq!(stopped.take(), Some(vec![1, 2]));
+        assert!(stopped.is_empty());
+    }
+}
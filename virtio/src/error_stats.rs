@@ -0,0 +1,389 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Error statistics of virtio block and net devices.
+//!
+//! Every device owns a `DeviceErrorStats` which is shared by its io handlers. Errors are
+//! counted by category, and the guest requests which triggered the last errors are kept
+//! in a fixed size ring. Nothing is recorded or allocated on the error-free path.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use machine_manager::qmp::qmp_schema::{
+    BlockInfo, DeviceErrorInfo, ErrorRequestInfo, LastErrorInfo, NetdevInfo, TimestampInfo,
+};
+use once_cell::sync::Lazy;
+
+use crate::{query_write_protect, query_write_threshold};
+
+/// Number of guest requests kept for the last errors.
+const ERROR_RING_SIZE: usize = 8;
+
+/// Error statistics of all realized block devices, keyed by device id.
+static BLOCK_ERROR_STATS: Lazy<Mutex<BTreeMap<String, BlockErrorEntry>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+/// Error statistics of all realized net devices, keyed by device id.
+static NET_ERROR_STATS: Lazy<Mutex<BTreeMap<String, NetErrorEntry>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Category of device errors, each of which has its own counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorCategory {
+    /// I/O error returned by the backend file or tap device.
+    BackendEio = 0,
+    /// The backend has no space left.
+    Enospc,
+    /// The guest request is malformed or not supported.
+    InvalidRequest,
+    /// The request is dropped because the backend is throttled.
+    ThrottledDrop,
+}
+
+const ERROR_CATEGORY_NUM: usize = 4;
+
+impl ErrorCategory {
+    fn name(&self) -> &'static str {
+        match self {
+            ErrorCategory::BackendEio => "backend-eio",
+            ErrorCategory::Enospc => "enospc",
+            ErrorCategory::InvalidRequest => "invalid-request",
+            ErrorCategory::ThrottledDrop => "throttled-drop",
+        }
+    }
+
+    /// Get the category of a backend error by its errno.
+    pub fn from_errno(errno: i32) -> Self {
+        match errno {
+            libc::ENOSPC | libc::EDQUOT => ErrorCategory::Enospc,
+            _ => ErrorCategory::BackendEio,
+        }
+    }
+}
+
+/// The guest request which triggered an error.
+#[derive(Clone, Copy, Debug)]
+struct ErrorRecord {
+    category: ErrorCategory,
+    errno: i32,
+    /// Device specific request type, e.g. the virtio-blk request type.
+    opcode: u32,
+    offset: u64,
+    len: u64,
+    secs: u64,
+    usecs: u32,
+}
+
+impl Default for ErrorRecord {
+    fn default() -> Self {
+        ErrorRecord {
+            category: ErrorCategory::BackendEio,
+            errno: 0,
+            opcode: 0,
+            offset: 0,
+            len: 0,
+            secs: 0,
+            usecs: 0,
+        }
+    }
+}
+
+impl ErrorRecord {
+    fn timestamp(&self) -> TimestampInfo {
+        TimestampInfo {
+            seconds: self.secs,
+            microseconds: self.usecs as u64,
+        }
+    }
+}
+
+#[derive(Default)]
+struct ErrorRing {
+    records: [ErrorRecord; ERROR_RING_SIZE],
+    /// Index of the next record to write.
+    head: usize,
+    /// Number of valid records.
+    len: usize,
+}
+
+impl ErrorRing {
+    fn push(&mut self, record: ErrorRecord) {
+        self.records[self.head] = record;
+        self.head = (self.head + 1) % ERROR_RING_SIZE;
+        if self.len < ERROR_RING_SIZE {
+            self.len += 1;
+        }
+    }
+
+    /// Iterate the records from the newest to the oldest.
+    fn iter(&self) -> impl Iterator<Item = &ErrorRecord> {
+        (1..=self.len)
+            .map(move |i| &self.records[(self.head + ERROR_RING_SIZE - i) % ERROR_RING_SIZE])
+    }
+}
+
+/// Error statistics of one device.
+#[derive(Default)]
+pub struct DeviceErrorStats {
+    counters: [AtomicU64; ERROR_CATEGORY_NUM],
+    ring: Mutex<ErrorRing>,
+}
+
+impl DeviceErrorStats {
+    /// Record an error and the guest request which triggered it.
+    ///
+    /// # Arguments
+    ///
+    /// * `category` - Category of the error.
+    /// * `errno` - Errno of the error.
+    /// * `opcode` - Device specific type of the guest request.
+    /// * `offset` - Offset in bytes of the guest request.
+    /// * `len` - Length in bytes of the guest request.
+    pub fn record(&self, category: ErrorCategory, errno: i32, opcode: u32, offset: u64, len: u64) {
+        self.counters[category as usize].fetch_add(1, Ordering::Relaxed);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.ring.lock().unwrap().push(ErrorRecord {
+            category,
+            errno,
+            opcode,
+            offset,
+            len,
+            secs: now.as_secs(),
+            usecs: now.subsec_micros(),
+        });
+    }
+
+    pub fn count(&self, category: ErrorCategory) -> u64 {
+        self.counters[category as usize].load(Ordering::Relaxed)
+    }
+
+    pub fn reset(&self) {
+        let mut ring = self.ring.lock().unwrap();
+        for counter in self.counters.iter() {
+            counter.store(0, Ordering::Relaxed);
+        }
+        *ring = ErrorRing::default();
+    }
+
+    fn query(&self) -> DeviceErrorInfo {
+        let ring = self.ring.lock().unwrap();
+        let info = DeviceErrorInfo {
+            backend_eio: self.count(ErrorCategory::BackendEio),
+            enospc: self.count(ErrorCategory::Enospc),
+            invalid_request: self.count(ErrorCategory::InvalidRequest),
+            throttled_drop: self.count(ErrorCategory::ThrottledDrop),
+            last_error: ring.iter().next().map(|r| LastErrorInfo {
+                errno: r.errno,
+                message: std::io::Error::from_raw_os_error(r.errno).to_string(),
+                timestamp: r.timestamp(),
+            }),
+            requests: ring
+                .iter()
+                .map(|r| ErrorRequestInfo {
+                    category: r.category.name().to_string(),
+                    errno: r.errno,
+                    opcode: r.opcode,
+                    offset: r.offset,
+                    length: r.len,
+                    timestamp: r.timestamp(),
+                })
+                .collect(),
+        };
+        info
+    }
+}
+
+struct BlockErrorEntry {
+    file: String,
+    read_only: bool,
+    stats: Arc<DeviceErrorStats>,
+}
+
+struct NetErrorEntry {
+    ifname: String,
+    stats: Arc<DeviceErrorStats>,
+}
+
+/// Register the error statistics of a block device to be queried by `query-block`.
+pub fn register_block_error_stats(
+    id: &str,
+    file: &str,
+    read_only: bool,
+    stats: Arc<DeviceErrorStats>,
+) {
+    BLOCK_ERROR_STATS.lock().unwrap().insert(
+        id.to_string(),
+        BlockErrorEntry {
+            file: file.to_string(),
+            read_only,
+            stats,
+        },
+    );
+}
+
+pub fn unregister_block_error_stats(id: &str) {
+    BLOCK_ERROR_STATS.lock().unwrap().remove(id);
+}
+
+/// Register the error statistics of a net device to be queried by `query-netdev`.
+pub fn register_net_error_stats(id: &str, ifname: &str, stats: Arc<DeviceErrorStats>) {
+    NET_ERROR_STATS.lock().unwrap().insert(
+        id.to_string(),
+        NetErrorEntry {
+            ifname: ifname.to_string(),
+            stats,
+        },
+    );
+}
+
+pub fn unregister_net_error_stats(id: &str) {
+    NET_ERROR_STATS.lock().unwrap().remove(id);
+}
+
+/// Get the information of all block devices for `query-block`.
+///
+/// # Arguments
+///
+/// * `reset_errors` - Reset the error statistics after they are queried.
+pub fn qmp_query_block(reset_errors: bool) -> Vec<BlockInfo> {
+    let entries = BLOCK_ERROR_STATS.lock().unwrap();
+    entries
+        .iter()
+        .map(|(id, entry)| {
+            let errors = entry.stats.query();
+            if reset_errors {
+                entry.stats.reset();
+            }
+            let (write_threshold, wr_highest_offset) =
+                query_write_threshold(id).unwrap_or_default();
+            BlockInfo {
+                device: id.clone(),
+                file: entry.file.clone(),
+                read_only: entry.read_only,
+                errors,
+                write_threshold,
+                wr_highest_offset,
+                write_protected: query_write_protect(id).unwrap_or_default(),
+            }
+        })
+        .collect()
+}
+
+/// Get the information of all net devices for `query-netdev`.
+///
+/// # Arguments
+///
+/// * `reset_errors` - Reset the error statistics after they are queried.
+pub fn qmp_query_netdev(reset_errors: bool) -> Vec<NetdevInfo> {
+    let entries = NET_ERROR_STATS.lock().unwrap();
+    entries
+        .iter()
+        .map(|(id, entry)| {
+            let errors = entry.stats.query();
+            if reset_errors {
+                entry.stats.reset();
+            }
+            NetdevInfo {
+                id: id.clone(),
+                ifname: entry.ifname.clone(),
+                errors,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_stats_record() {
+        let stats = DeviceErrorStats::default();
+        let info = stats.query();
+        assert_eq!(info.backend_eio, 0);
+        assert!(info.last_error.is_none());
+        assert!(info.requests.is_empty());
+
+        stats.record(
+            ErrorCategory::from_errno(libc::EIO),
+            libc::EIO,
+            0,
+            512,
+            4096,
+        );
+        stats.record(
+            ErrorCategory::from_errno(libc::ENOSPC),
+            libc::ENOSPC,
+            1,
+            0,
+            512,
+        );
+        stats.record(ErrorCategory::InvalidRequest, libc::ENOTSUP, 99, 0, 0);
+        let info = stats.query();
+        assert_eq!(info.backend_eio, 1);
+        assert_eq!(info.enospc, 1);
+        assert_eq!(info.invalid_request, 1);
+        assert_eq!(info.throttled_drop, 0);
+        let last = info.last_error.unwrap();
+        assert_eq!(last.errno, libc::ENOTSUP);
+        assert!(!last.message.is_empty());
+        // The newest request comes first.
+        let opcodes: Vec<u32> = info.requests.iter().map(|r| r.opcode).collect();
+        assert_eq!(opcodes, vec![99, 1, 0]);
+        assert_eq!(info.requests[1].category, "enospc");
+        assert_eq!(info.requests[2].offset, 512);
+        assert_eq!(info.requests[2].length, 4096);
+
+        stats.reset();
+        let info = stats.query();
+        assert_eq!(info.backend_eio, 0);
+        assert_eq!(info.enospc, 0);
+        assert!(info.last_error.is_none());
+        assert!(info.requests.is_empty());
+    }
+
+    #[test]
+    fn test_error_stats_ring() {
+        let stats = DeviceErrorStats::default();
+        for i in 0..(ERROR_RING_SIZE as u32 + 3) {
+            stats.record(ErrorCategory::ThrottledDrop, libc::ENOBUFS, i, 0, 0);
+        }
+        let info = stats.query();
+        assert_eq!(info.throttled_drop, ERROR_RING_SIZE as u64 + 3);
+        assert_eq!(info.requests.len(), ERROR_RING_SIZE);
+        assert_eq!(info.requests[0].opcode, ERROR_RING_SIZE as u32 + 2);
+        assert_eq!(info.requests[ERROR_RING_SIZE - 1].opcode, 3);
+    }
+
+    #[test]
+    fn test_query_block_reset() {
+        let stats = Arc::new(DeviceErrorStats::default());
+        register_block_error_stats("test-blk-errors", "/tmp/test.img", true, stats.clone());
+        stats.record(ErrorCategory::BackendEio, libc::EIO, 0, 0, 512);
+
+        let info = qmp_query_block(true);
+        let blk = info.iter().find(|b| b.device == "test-blk-errors").unwrap();
+        assert_eq!(blk.file, "/tmp/test.img");
+        assert!(blk.read_only);
+        assert_eq!(blk.errors.backend_eio, 1);
+        assert_eq!(stats.count(ErrorCategory::BackendEio), 0);
+
+        unregister_block_error_stats("test-blk-errors");
+        assert!(qmp_query_block(false)
+            .iter()
+            .all(|b| b.device != "test-blk-errors"));
+    }
+}
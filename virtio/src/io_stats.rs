@@ -0,0 +1,208 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! I/O statistics of virtio block and scsi disks.
+//!
+//! Every disk owns a `BlockIoStats` which is updated on the completion of each aio
+//! request. Only relaxed atomics are used, so the statistics are cheap to keep on the
+//! I/O path, and a query may observe counters of one request partially updated.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use machine_manager::qmp::qmp_schema::{BlockDeviceStats, BlockStatsInfo};
+use once_cell::sync::Lazy;
+use util::aio::{AioCb, OpCode};
+
+use crate::query_write_threshold;
+
+/// I/O statistics of all realized disks, keyed by device id.
+static BLOCK_IO_STATS: Lazy<Mutex<BTreeMap<String, Arc<BlockIoStats>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Counters of one kind of request.
+#[derive(Default)]
+struct IoCounters {
+    bytes: AtomicU64,
+    operations: AtomicU64,
+    failed_operations: AtomicU64,
+    total_time_ns: AtomicU64,
+    max_latency_ns: AtomicU64,
+}
+
+impl IoCounters {
+    fn account(&self, bytes: u64, latency_ns: u64, failed: bool) {
+        if failed {
+            self.failed_operations.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.operations.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.total_time_ns.fetch_add(latency_ns, Ordering::Relaxed);
+        self.max_latency_ns.fetch_max(latency_ns, Ordering::Relaxed);
+    }
+}
+
+/// I/O statistics of one disk.
+#[derive(Default)]
+pub struct BlockIoStats {
+    read: IoCounters,
+    write: IoCounters,
+    flush: IoCounters,
+}
+
+impl BlockIoStats {
+    /// Account a completed request.
+    ///
+    /// # Arguments
+    ///
+    /// * `opcode` - Operation of the request.
+    /// * `bytes` - Length in bytes of the request.
+    /// * `latency_ns` - Time elapsed from the submission to the completion.
+    /// * `failed` - Whether the request is failed, which is counted without bytes or latency.
+    pub fn account(&self, opcode: OpCode, bytes: u64, latency_ns: u64, failed: bool) {
+        let counters = match opcode {
+            OpCode::Preadv => &self.read,
+            OpCode::Pwritev => &self.write,
+            OpCode::Fdsync => &self.flush,
+            OpCode::Discard | OpCode::Noop => return,
+        };
+        counters.account(bytes, latency_ns, failed);
+    }
+
+    /// Account a request completed by aio with the result `ret`.
+    pub fn account_aio<T: Clone>(&self, aiocb: &AioCb<T>, ret: i64) {
+        self.account(aiocb.opcode, aiocb.nbytes, aiocb.latency_ns(), ret < 0);
+    }
+
+    fn query(&self) -> BlockDeviceStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        BlockDeviceStats {
+            rd_bytes: load(&self.read.bytes),
+            wr_bytes: load(&self.write.bytes),
+            rd_operations: load(&self.read.operations),
+            wr_operations: load(&self.write.operations),
+            flush_operations: load(&self.flush.operations),
+            failed_rd_operations: load(&self.read.failed_operations),
+            failed_wr_operations: load(&self.write.failed_operations),
+            failed_flush_operations: load(&self.flush.failed_operations),
+            rd_total_time_ns: load(&self.read.total_time_ns),
+            wr_total_time_ns: load(&self.write.total_time_ns),
+            flush_total_time_ns: load(&self.flush.total_time_ns),
+            max_rd_latency_ns: load(&self.read.max_latency_ns),
+            max_wr_latency_ns: load(&self.write.max_latency_ns),
+            max_flush_latency_ns: load(&self.flush.max_latency_ns),
+            wr_highest_offset: 0,
+        }
+    }
+}
+
+/// Register the I/O statistics of a disk to be queried by `query-blockstats`.
+pub fn register_block_io_stats(id: &str, stats: Arc<BlockIoStats>) {
+    BLOCK_IO_STATS.lock().unwrap().insert(id.to_string(), stats);
+}
+
+pub fn unregister_block_io_stats(id: &str) {
+    BLOCK_IO_STATS.lock().unwrap().remove(id);
+}
+
+/// Get the I/O statistics of all disks for `query-blockstats`.
+pub fn qmp_query_blockstats() -> Vec<BlockStatsInfo> {
+    let entries = BLOCK_IO_STATS.lock().unwrap();
+    entries
+        .iter()
+        .map(|(id, stats)| {
+            let mut stats = stats.query();
+            if let Some((_, wr_highest_offset)) = query_write_threshold(id) {
+                stats.wr_highest_offset = wr_highest_offset;
+            }
+            BlockStatsInfo {
+                device: id.clone(),
+                stats,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_io_stats_account() {
+        let stats = Arc::new(BlockIoStats::default());
+        stats.account(OpCode::Preadv, 4096, 1000, false);
+        stats.account(OpCode::Preadv, 512, 3000, false);
+        stats.account(OpCode::Preadv, 512, 0, true);
+        stats.account(OpCode::Pwritev, 8192, 2000, false);
+        stats.account(OpCode::Fdsync, 0, 500, false);
+        stats.account(OpCode::Fdsync, 0, 0, true);
+        stats.account(OpCode::Noop, 512, 100, false);
+
+        register_block_io_stats("test-blk-stats", stats);
+        let info = qmp_query_blockstats();
+        let blk = info.iter().find(|b| b.device == "test-blk-stats").unwrap();
+        assert_eq!(blk.stats.rd_bytes, 4608);
+        assert_eq!(blk.stats.rd_operations, 2);
+        assert_eq!(blk.stats.failed_rd_operations, 1);
+        assert_eq!(blk.stats.rd_total_time_ns, 4000);
+        assert_eq!(blk.stats.max_rd_latency_ns, 3000);
+        assert_eq!(blk.stats.wr_bytes, 8192);
+        assert_eq!(blk.stats.wr_operations, 1);
+        assert_eq!(blk.stats.failed_wr_operations, 0);
+        assert_eq!(blk.stats.wr_total_time_ns, 2000);
+        assert_eq!(blk.stats.max_wr_latency_ns, 2000);
+        assert_eq!(blk.stats.flush_operations, 1);
+        assert_eq!(blk.stats.failed_flush_operations, 1);
+        assert_eq!(blk.stats.flush_total_time_ns, 500);
+        assert_eq!(blk.stats.max_flush_latency_ns, 500);
+
+        unregister_block_io_stats("test-blk-stats");
+        assert!(qmp_query_blockstats()
+            .iter()
+            .all(|b| b.device != "test-blk-stats"));
+    }
+
+    #[test]
+    fn test_block_io_stats_aio() {
+        let stats = BlockIoStats::default();
+        let mut aiocb = AioCb {
+            direct: false,
+            req_align: 512,
+            buf_align: 512,
+            file_fd: -1,
+            opcode: OpCode::Pwritev,
+            iovec: Vec::new(),
+            offset: 0,
+            nbytes: 1024,
+            user_data: 0,
+            iocompletecb: (),
+            submit_time: None,
+            backing: None,
+        };
+        // The latency is zero if the request is never submitted.
+        stats.account_aio(&aiocb, 1024);
+        aiocb.submit_time = Some(std::time::Instant::now());
+        std::thread::sleep(std::time::Duration::from_millis(1));
+        stats.account_aio(&aiocb, 1024);
+        stats.account_aio(&aiocb, -(libc::EIO as i64));
+
+        let info = stats.query();
+        assert_eq!(info.wr_operations, 2);
+        assert_eq!(info.wr_bytes, 2048);
+        assert_eq!(info.failed_wr_operations, 1);
+        assert!(info.max_wr_latency_ns >= 1_000_000);
+        assert_eq!(info.wr_total_time_ns, info.max_wr_latency_ns);
+        assert_eq!(info.rd_operations, 0);
+    }
+}
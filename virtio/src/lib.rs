@@ -25,15 +25,25 @@
 //! - `x86_64`
 //! - `aarch64`
 
+mod block_job;
+mod block_resize;
+mod config_update;
 pub mod device;
 pub mod error;
+mod error_policy;
+mod error_stats;
+mod io_stats;
 mod queue;
+mod slow_io;
 mod transport;
 pub mod vhost;
+mod write_threshold;
 pub use anyhow::Result;
+pub use block_job::*;
+pub use block_resize::*;
+pub use config_update::*;
 pub use device::balloon::*;
 pub use device::block::{Block, BlockState};
-pub use device::console::{Console, VirtioConsoleState};
 #[cfg(not(target_env = "musl"))]
 pub use device::gpu::*;
 pub use device::net::*;
@@ -41,14 +51,20 @@ pub use device::rng::{Rng, RngState};
 pub use device::scsi::bus as ScsiBus;
 pub use device::scsi::controller as ScsiCntlr;
 pub use device::scsi::disk as ScsiDisk;
+pub use device::serial::{Serial, SerialPort, VirtioSerialState};
 pub use error::VirtioError;
 pub use error::*;
+pub use error_policy::*;
+pub use error_stats::*;
+pub use io_stats::*;
 use log::{error, warn};
 pub use queue::*;
+pub use slow_io::*;
 pub use transport::virtio_mmio::{VirtioMmioDevice, VirtioMmioState};
 pub use transport::virtio_pci::VirtioPciDevice;
 pub use vhost::kernel as VhostKern;
 pub use vhost::user as VhostUser;
+pub use write_threshold::*;
 
 use std::cmp;
 use std::sync::atomic::{AtomicBool, Ordering};
@@ -104,6 +120,10 @@ pub const VIRTIO_F_RING_PACKED: u32 = 34;
 pub const VIRTIO_NET_F_CSUM: u32 = 0;
 /// Driver handles packets with partial checksum.
 pub const VIRTIO_NET_F_GUEST_CSUM: u32 = 1;
+/// Control channel offloads reconfiguration support.
+pub const VIRTIO_NET_F_CTRL_GUEST_OFFLOADS: u32 = 2;
+/// Device maximum MTU reporting is supported.
+pub const VIRTIO_NET_F_MTU: u32 = 3;
 /// Device has given MAC address.
 pub const VIRTIO_NET_F_MAC: u32 = 5;
 /// Driver can receive TSOv4.
@@ -136,6 +156,8 @@ pub const VIRTIO_NET_F_MQ: u32 = 22;
 pub const VIRTIO_NET_F_CTRL_MAC_ADDR: u32 = 23;
 /// Configuration cols and rows are valid.
 pub const VIRTIO_CONSOLE_F_SIZE: u64 = 0;
+/// Device has support for multiple ports and the control virtqueues.
+pub const VIRTIO_CONSOLE_F_MULTIPORT: u64 = 1;
 /// Maximum size of any single segment is in size_max.
 pub const VIRTIO_BLK_F_SIZE_MAX: u32 = 1;
 /// Maximum number of segments in a request is in seg_max.
@@ -191,6 +213,11 @@ pub const VIRTIO_NET_CTRL_VLAN_ADD: u8 = 0;
 /// The driver adds a vlan id from the vlan filtering table.
 pub const VIRTIO_NET_CTRL_VLAN_DEL: u8 = 1;
 
+/// The driver can send control commands for guest offloads.
+pub const VIRTIO_NET_CTRL_GUEST_OFFLOADS: u8 = 5;
+/// The driver sets the guest offloads which are enabled.
+pub const VIRTIO_NET_CTRL_GUEST_OFFLOADS_SET: u8 = 0;
+
 /// Driver configure the class before enabling virtqueue.
 pub const VIRTIO_NET_CTRL_MQ: u8 = 4;
 /// Driver configure the command before enabling virtqueue.
@@ -222,6 +249,8 @@ pub const VIRTIO_BLK_T_OUT: u32 = 1;
 pub const VIRTIO_BLK_T_FLUSH: u32 = 4;
 /// Device id
 pub const VIRTIO_BLK_T_GET_ID: u32 = 8;
+/// Discard.
+pub const VIRTIO_BLK_T_DISCARD: u32 = 11;
 /// Device id length
 pub const VIRTIO_BLK_ID_BYTES: u32 = 20;
 /// Success
@@ -273,6 +302,8 @@ pub const VIRTIO_GPU_RESP_ERR_INVALID_RESOURCE_ID: u32 = 0x1203;
 pub const VIRTIO_GPU_RESP_ERR_INVALID_PARAMETER: u32 = 0x1205;
 /// Flags in virtio gpu cmd which means need a fence.
 pub const VIRTIO_GPU_FLAG_FENCE: u32 = 1 << 0;
+/// Event in virtio gpu config space which means the display configuration has changed.
+pub const VIRTIO_GPU_EVENT_DISPLAY: u32 = 1 << 0;
 
 /// Interrupt status: Used Buffer Notification
 pub const VIRTIO_MMIO_INT_VRING: u32 = 0x01;
@@ -357,6 +388,12 @@ pub trait VirtioDevice: Send {
     /// Write data to config from guest.
     fn write_config(&mut self, offset: u64, data: &[u8]) -> Result<()>;
 
+    /// Get the generation of the config space changed by the device, which is added to
+    /// the config generation of the transport.
+    fn config_generation(&self) -> u32 {
+        0
+    }
+
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     ///
@@ -50,6 +50,12 @@ const VRING_IDX_POSITION: u64 = size_of::<u16>() as u64;
 /// The length of virtio descriptor.
 const DESCRIPTOR_LEN: u64 = size_of::<SplitVringDesc>() as u64;
 
+/// Return true if the ring index moving from `old` to `new` crosses `event_idx`, that is
+/// `event_idx` is in `[old, new)` with the wrap-around of the 16-bit index.
+fn vring_need_event(event_idx: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event_idx).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
 #[derive(Default, Clone, Copy)]
 pub struct VirtioAddrCache {
     /// Host virtual address of the descriptor table.
@@ -566,7 +572,7 @@ impl SplitVring {
         let valid = self.signal_used_valid;
         self.signal_used_valid = true;
         self.last_signal_used = new;
-        !valid || (new - used_event_idx - Wrapping(1)) < (new - old)
+        !valid || vring_need_event(used_event_idx.0, new.0, old.0)
     }
 
     fn is_overlap(
@@ -1820,6 +1826,89 @@ mod tests {
         assert_eq!(avail_idx, 1);
     }
 
+    #[test]
+    fn test_pop_avail_smoke() {
+        // A short run of the virtqueue fuzz target, with pseudo-random descriptor chains.
+        let sys_space = address_space_init();
+        let mut seed = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            seed
+        };
+        // Indirect descriptor tables are placed in [0x10000, 0x20000).
+        let indirect_base = 0x10000_u64;
+
+        for _ in 0..32 {
+            let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+            queue_config.desc_table = GuestAddress(0);
+            queue_config.addr_cache.desc_table_host =
+                sys_space.get_host_address(queue_config.desc_table).unwrap();
+            queue_config.avail_ring = GuestAddress((QUEUE_SIZE as u64) * DESCRIPTOR_LEN);
+            queue_config.addr_cache.avail_ring_host =
+                sys_space.get_host_address(queue_config.avail_ring).unwrap();
+            queue_config.used_ring = GuestAddress(align(
+                (QUEUE_SIZE as u64) * DESCRIPTOR_LEN
+                    + VRING_AVAIL_LEN_EXCEPT_AVAILELEM
+                    + AVAILELEM_LEN * (QUEUE_SIZE as u64),
+                4096,
+            ));
+            queue_config.addr_cache.used_ring_host =
+                sys_space.get_host_address(queue_config.used_ring).unwrap();
+            queue_config.ready = true;
+            queue_config.size = QUEUE_SIZE;
+            let mut vring = SplitVring::new(queue_config);
+
+            for i in 0..QUEUE_SIZE {
+                let flags = (next() % 8) as u16;
+                let (addr, len) = if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                    (
+                        indirect_base + (next() % 0x1000) * 16,
+                        (next() % 16 + 1) as u32 * 16,
+                    )
+                } else if next() % 4 == 0 {
+                    // Likely out of the guest memory.
+                    (SYSTEM_SPACE_SIZE - next() % 64, next() as u32)
+                } else {
+                    (next() % SYSTEM_SPACE_SIZE, (next() % 256) as u32)
+                };
+                let next_index = (next() % (QUEUE_SIZE as u64 + 16)) as u16;
+                vring
+                    .set_desc(&sys_space, i, GuestAddress(addr), len, flags, next_index)
+                    .unwrap();
+                vring
+                    .set_avail_ring_elem(&sys_space, i, (next() % 300) as u16)
+                    .unwrap();
+            }
+            for i in 0..0x1000 {
+                let desc = SplitVringDesc {
+                    addr: GuestAddress(next() % SYSTEM_SPACE_SIZE),
+                    len: (next() % 256) as u32,
+                    flags: (next() % 8) as u16,
+                    next: (next() % 16) as u16,
+                };
+                sys_space
+                    .write_object(&desc, GuestAddress(indirect_base + i * DESCRIPTOR_LEN))
+                    .unwrap();
+            }
+            vring.set_avail_ring_idx(&sys_space, next() as u16).unwrap();
+
+            let features = next() & (1 << VIRTIO_F_RING_EVENT_IDX);
+            for _ in 0..QUEUE_SIZE {
+                match vring.pop_avail(&sys_space, features) {
+                    Ok(elem) if elem.desc_num != 0 => {
+                        assert!(
+                            elem.desc_num as usize == elem.in_iovec.len() + elem.out_iovec.len()
+                        );
+                        vring.add_used(&sys_space, elem.index, 0).unwrap();
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+
     #[test]
     fn test_add_used() {
         let sys_space = address_space_init();
@@ -1919,4 +2008,99 @@ mod tests {
         assert!(vring.set_used_event_idx(&sys_space, 4).is_ok()); //event_idx
         assert_eq!(vring.should_notify(&sys_space, features), false);
     }
+
+    #[test]
+    fn test_vring_need_event() {
+        // The event index is in [old, new).
+        assert!(vring_need_event(5, 10, 5));
+        assert!(vring_need_event(6, 10, 5));
+        assert!(vring_need_event(9, 10, 5));
+        assert!(!vring_need_event(10, 10, 5));
+        assert!(!vring_need_event(4, 10, 5));
+        assert!(!vring_need_event(5, 5, 5));
+
+        // The index wraps around between old and new.
+        assert!(vring_need_event(0xfffe, 2, 0xfffe));
+        assert!(vring_need_event(0xffff, 2, 0xfffe));
+        assert!(vring_need_event(0, 2, 0xfffe));
+        assert!(vring_need_event(1, 2, 0xfffe));
+        assert!(!vring_need_event(2, 2, 0xfffe));
+        assert!(!vring_need_event(0xfffd, 2, 0xfffe));
+
+        // The whole index space is moved.
+        assert!(vring_need_event(0x1234, 0xffff, 0));
+        assert!(!vring_need_event(0xffff, 0xffff, 0));
+    }
+
+    #[test]
+    fn test_event_idx_batching() {
+        let sys_space = address_space_init();
+
+        let mut queue_config = QueueConfig::new(QUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.addr_cache.desc_table_host =
+            sys_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.avail_ring = GuestAddress((QUEUE_SIZE as u64) * DESCRIPTOR_LEN);
+        queue_config.addr_cache.avail_ring_host =
+            sys_space.get_host_address(queue_config.avail_ring).unwrap();
+        queue_config.used_ring = GuestAddress(align(
+            (QUEUE_SIZE as u64) * DESCRIPTOR_LEN
+                + VRING_AVAIL_LEN_EXCEPT_AVAILELEM
+                + AVAILELEM_LEN * (QUEUE_SIZE as u64),
+            4096,
+        ));
+        queue_config.addr_cache.used_ring_host =
+            sys_space.get_host_address(queue_config.used_ring).unwrap();
+        queue_config.ready = true;
+        queue_config.size = QUEUE_SIZE;
+        let mut vring = SplitVring::new(queue_config);
+        assert!(vring.is_valid(&sys_space));
+
+        // The guest gives `count` requests from avail index `start`, and returns
+        // whether it kicks the device according to the avail event.
+        let give_requests = |vring: &SplitVring, start: u16, count: u16| -> bool {
+            for i in start..start + count {
+                vring
+                    .set_desc(&sys_space, i, GuestAddress(0x10000), 16, 0, 0)
+                    .unwrap();
+                vring.set_avail_ring_elem(&sys_space, i, i).unwrap();
+            }
+            vring.set_avail_ring_idx(&sys_space, start + count).unwrap();
+            let avail_event = vring.get_avail_event(&sys_space).unwrap();
+            vring_need_event(avail_event, start + count, start)
+        };
+        // The device completes `count` requests one by one, and returns the number of
+        // interrupts sent to guest.
+        let complete_requests = |vring: &mut SplitVring, count: u16, features: u64| -> u32 {
+            let mut interrupts = 0;
+            for _ in 0..count {
+                let elem = vring.pop_avail(&sys_space, features).unwrap();
+                assert_eq!(elem.desc_num, 1);
+                vring.add_used(&sys_space, elem.index, 0).unwrap();
+                if vring.should_notify(&sys_space, features) {
+                    interrupts += 1;
+                }
+            }
+            interrupts
+        };
+
+        let features = 1 << VIRTIO_F_RING_EVENT_IDX as u64;
+        // The guest waits for the first completion.
+        vring.set_used_event_idx(&sys_space, 0).unwrap();
+        assert!(give_requests(&vring, 0, 1));
+        assert_eq!(complete_requests(&mut vring, 1, features), 1);
+
+        // The guest is batching, it gives 8 requests and only asks for an interrupt
+        // after 6 of them are completed.
+        vring.set_used_event_idx(&sys_space, 6).unwrap();
+        assert!(give_requests(&vring, 1, 8));
+        assert_eq!(complete_requests(&mut vring, 3, features), 0);
+        // No kick is needed for requests given while the device is still popping.
+        assert!(!give_requests(&vring, 9, 2));
+        assert_eq!(complete_requests(&mut vring, 7, features), 1);
+        assert_eq!(vring.get_used_ring_idx(&sys_space).unwrap(), 11);
+
+        // All requests are done, the next request given by guest kicks the device.
+        assert!(give_requests(&vring, 11, 1));
+    }
 }
@@ -0,0 +1,156 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Slow request detection of virtio block disks.
+//!
+//! The requests in flight are scanned periodically. A request which stays in flight for
+//! longer than the `slow-io-warn-ms` of the disk is reported by `BLOCK_IO_SLOW` once, so
+//! that a hanging backend, e.g. NFS, is noticed on the host.
+
+use std::cmp;
+use std::time::{Duration, Instant};
+
+use log::warn;
+use machine_manager::event;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use util::aio::{Aio, OpCode, SlowRequest};
+
+/// Minimum interval between two logs of the slow requests of a disk.
+const SLOW_IO_LOG_INTERVAL: Duration = Duration::from_secs(60);
+/// Minimum interval of scanning the requests in flight.
+const MIN_SLOW_IO_SCAN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Slow request detector of a disk, owned by an io handler.
+pub struct SlowIoDetector {
+    /// Id of the disk, reported in `BLOCK_IO_SLOW`.
+    id: String,
+    /// Requests in flight for longer than it are slow.
+    threshold: Duration,
+    /// Time of the last log.
+    last_log: Option<Instant>,
+    /// Number of the slow requests found since the last log.
+    unlogged: u64,
+}
+
+impl SlowIoDetector {
+    /// Create the detector of the disk `id`, return `None` if `threshold_ms` is 0.
+    pub fn new(id: &str, threshold_ms: u64) -> Option<Self> {
+        if threshold_ms == 0 {
+            return None;
+        }
+        Some(SlowIoDetector {
+            id: id.to_string(),
+            threshold: Duration::from_millis(threshold_ms),
+            last_log: None,
+            unlogged: 0,
+        })
+    }
+
+    /// Interval of scanning the requests in flight, a request is reported within half
+    /// of the threshold after it becomes slow.
+    pub fn scan_interval(&self) -> Duration {
+        cmp::max(self.threshold / 2, MIN_SLOW_IO_SCAN_INTERVAL)
+    }
+
+    /// Build the events of the slow requests, and log them at most once per
+    /// `SLOW_IO_LOG_INTERVAL`.
+    fn slow_events(&mut self, slow: &[SlowRequest]) -> Vec<qmp_schema::BlockIoSlow> {
+        if slow.is_empty() {
+            return Vec::new();
+        }
+        self.unlogged += slow.len() as u64;
+        if self
+            .last_log
+            .map_or(true, |time| time.elapsed() >= SLOW_IO_LOG_INTERVAL)
+        {
+            let oldest = slow.iter().map(|req| req.age).max().unwrap_or_default();
+            warn!(
+                "Disk {}: {} requests are in flight for longer than {}ms, the oldest one for {}ms",
+                self.id,
+                self.unlogged,
+                self.threshold.as_millis(),
+                oldest.as_millis()
+            );
+            self.last_log = Some(Instant::now());
+            self.unlogged = 0;
+        }
+
+        slow.iter()
+            .map(|req| qmp_schema::BlockIoSlow {
+                device: self.id.clone(),
+                operation: match req.opcode {
+                    OpCode::Preadv => "read",
+                    OpCode::Pwritev => "write",
+                    OpCode::Fdsync => "flush",
+                    OpCode::Discard => "discard",
+                    OpCode::Noop => "none",
+                }
+                .to_string(),
+                age: req.age.as_millis() as u64,
+            })
+            .collect()
+    }
+
+    /// Report the requests of `aio` which become slow since the last check.
+    pub fn check<T: Clone>(&mut self, aio: &mut Aio<T>) {
+        let slow = aio.take_slow_requests(self.threshold);
+        for event in self.slow_events(&slow) {
+            event!(BlockIoSlow; event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slow_request(opcode: OpCode, age_ms: u64) -> SlowRequest {
+        SlowRequest {
+            opcode,
+            offset: 0,
+            nbytes: 512,
+            age: Duration::from_millis(age_ms),
+        }
+    }
+
+    #[test]
+    fn test_slow_io_detector() {
+        assert!(SlowIoDetector::new("drive-0", 0).is_none());
+        let mut detector = SlowIoDetector::new("drive-0", 5000).unwrap();
+        assert_eq!(detector.scan_interval(), Duration::from_millis(2500));
+        assert_eq!(
+            SlowIoDetector::new("drive-0", 1).unwrap().scan_interval(),
+            MIN_SLOW_IO_SCAN_INTERVAL
+        );
+
+        assert!(detector.slow_events(&[]).is_empty());
+        assert!(detector.last_log.is_none());
+
+        let events = detector.slow_events(&[
+            slow_request(OpCode::Pwritev, 5002),
+            slow_request(OpCode::Fdsync, 6000),
+        ]);
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].device, "drive-0");
+        assert_eq!(events[0].operation, "write");
+        assert_eq!(events[0].age, 5002);
+        assert_eq!(events[1].operation, "flush");
+        assert!(detector.last_log.is_some());
+        assert_eq!(detector.unlogged, 0);
+
+        // The log is rate limited, but every request is still reported.
+        let events = detector.slow_events(&[slow_request(OpCode::Preadv, 5001)]);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].operation, "read");
+        assert_eq!(detector.unlogged, 1);
+    }
+}
@@ -235,7 +235,9 @@ impl VirtioMmioCommonConfig {
                 self.interrupt_status
             }
             STATUS_REG => self.device_status,
-            CONFIG_GENERATION_REG => self.config_generation,
+            CONFIG_GENERATION_REG => self
+                .config_generation
+                .wrapping_add(device.lock().unwrap().config_generation()),
             _ => {
                 return Err(anyhow!(VirtioError::MmioRegErr(offset)));
             }
@@ -418,7 +420,8 @@ impl VirtioMmioDevice {
                 .get_host_address(q_config.used_ring)
                 .unwrap_or(0);
             let queue = Queue::new(*q_config, queue_type)?;
-            if !queue.is_valid(&self.mem_space) {
+            // Devices like virtio-serial may not use all of their queues.
+            if q_config.ready && !queue.is_valid(&self.mem_space) {
                 bail!("Invalid queue");
             }
             self.queues.push(Arc::new(Mutex::new(queue)));
@@ -468,7 +471,8 @@ impl VirtioMmioDevice {
                                 return Ok(());
                             }
                         }
-                        locked_state.config_space.config_generation += 1;
+                        locked_state.config_space.config_generation =
+                            locked_state.config_space.config_generation.wrapping_add(1);
                         // Use (CONFIG | VRING) instead of CONFIG, it can be used to solve the
                         // IO stuck problem by change the device configure.
                         VIRTIO_MMIO_INT_CONFIG | VIRTIO_MMIO_INT_VRING
@@ -29,8 +29,8 @@ use pci::config::{
 use pci::msix::{update_dev_id, MsixState, MSIX_TABLE_ENTRY_SIZE};
 use pci::Result as PciResult;
 use pci::{
-    config::PciConfig, init_msix, init_multifunction, le_write_u16, le_write_u32, ranges_overlap,
-    PciBus, PciDevOps, PciError,
+    config::PciConfig, init_intx, init_msix, init_multifunction, le_write_u16, le_write_u32,
+    ranges_overlap, PciBus, PciDevOps, PciError,
 };
 use util::byte_code::ByteCode;
 use util::num_ops::{read_data_u32, write_data_u32};
@@ -282,7 +282,10 @@ impl VirtioPciCommonConfig {
             COMMON_MSIX_REG => self.msix_config as u32,
             COMMON_NUMQ_REG => self.queues_config.len() as u32,
             COMMON_STATUS_REG => self.device_status,
-            COMMON_CFGGENERATION_REG => self.config_generation as u32,
+            COMMON_CFGGENERATION_REG => self
+                .config_generation
+                .wrapping_add(device.lock().unwrap().config_generation() as u8)
+                as u32,
             COMMON_Q_SELECT_REG => self.queue_select as u32,
             COMMON_Q_SIZE_REG => self
                 .get_queue_config()
@@ -400,6 +403,9 @@ impl VirtioPciCommonConfig {
                     virtio_pci_dev.activate_device(self);
                 } else if old_status != 0 && self.device_status == 0 {
                     self.reset();
+                    if let Some(intx) = &virtio_pci_dev.config.intx {
+                        intx.lock().unwrap().reset();
+                    }
                     // FIXME: handle deactivation failure.
                     virtio_pci_dev.deactivate_device();
                 }
@@ -652,6 +658,7 @@ impl VirtioPciDevice {
     fn assign_interrupt_cb(&mut self) {
         let cloned_common_cfg = self.common_config.clone();
         let cloned_msix = self.config.msix.clone();
+        let cloned_intx = self.config.intx.clone();
         let dev_id = self.dev_id.clone();
         let cb = Arc::new(Box::new(
             move |int_type: &VirtioInterruptType, queue: Option<&Queue>, needs_reset: bool| {
@@ -668,7 +675,8 @@ impl VirtioPciDevice {
                         // IO stuck problem by change the device configure.
                         locked_common_cfg.interrupt_status |=
                             VIRTIO_MMIO_INT_CONFIG | VIRTIO_MMIO_INT_VRING;
-                        locked_common_cfg.config_generation += 1;
+                        locked_common_cfg.config_generation =
+                            locked_common_cfg.config_generation.wrapping_add(1);
                         locked_common_cfg.msix_config
                     }
                     VirtioInterruptType::Vring => {
@@ -676,6 +684,22 @@ impl VirtioPciDevice {
                     }
                 };
 
+                // Fall back to INTx if the guest doesn't enable MSI-X. The line is
+                // deasserted when the guest reads the ISR.
+                let msix_enabled = cloned_msix
+                    .as_ref()
+                    .map_or(false, |msix| msix.lock().unwrap().enabled);
+                if !msix_enabled {
+                    if let Some(intx) = &cloned_intx {
+                        let mut locked_common_cfg = cloned_common_cfg.lock().unwrap();
+                        if let VirtioInterruptType::Vring = int_type {
+                            locked_common_cfg.interrupt_status |= VIRTIO_MMIO_INT_VRING;
+                        }
+                        intx.lock().unwrap().notify(true);
+                        return Ok(());
+                    }
+                }
+
                 if let Some(msix) = &cloned_msix {
                     msix.lock()
                         .unwrap()
@@ -885,11 +909,15 @@ impl VirtioPciDevice {
 
         // 2. PCI ISR cap sub-region.
         let cloned_common_cfg = self.common_config.clone();
+        let cloned_intx = self.config.intx.clone();
         let isr_read = move |data: &mut [u8], _: GuestAddress, _: u64| -> bool {
             if let Some(val) = data.get_mut(0) {
                 let mut common_cfg_lock = cloned_common_cfg.lock().unwrap();
                 *val = common_cfg_lock.interrupt_status as u8;
                 common_cfg_lock.interrupt_status = 0;
+                if let Some(intx) = &cloned_intx {
+                    intx.lock().unwrap().notify(false);
+                }
             }
             true
         };
@@ -1005,7 +1033,7 @@ impl VirtioPciDevice {
         }
     }
 
-    pub fn virtio_pci_auto_queues_num(queues_fixed: u16, nr_cpus: u8, queues_max: usize) -> u16 {
+    pub fn virtio_pci_auto_queues_num(queues_fixed: u16, nr_cpus: u16, queues_max: usize) -> u16 {
         // Give each vcpu a vq, allow the vCPU that submit request can handle
         // its own request completion. i.e, If the vq is not enough, vcpu A will
         // receive completion of request that submitted by vcpu B, then A needs
@@ -1157,6 +1185,13 @@ impl PciDevOps for VirtioPciDevice {
             None,
         )?;
 
+        init_intx(
+            self.name.clone(),
+            &mut self.config,
+            self.parent_bus.clone(),
+            self.devfn,
+        )?;
+
         self.assign_interrupt_cb();
 
         let mut mem_region_size = ((VIRTIO_PCI_CAP_NOTIFY_OFFSET + VIRTIO_PCI_CAP_NOTIFY_LENGTH)
@@ -1267,6 +1302,21 @@ impl PciDevOps for VirtioPciDevice {
         Ok(())
     }
 
+    fn get_config_state(&self) -> Vec<u8> {
+        self.config.get_state()
+    }
+
+    fn set_config_state(&mut self, state: &[u8]) -> PciResult<()> {
+        let parent_bus = self.parent_bus.upgrade().unwrap();
+        let locked_parent_bus = parent_bus.lock().unwrap();
+        self.config.set_state(
+            state,
+            #[cfg(target_arch = "x86_64")]
+            Some(&locked_parent_bus.io_region),
+            Some(&locked_parent_bus.mem_region),
+        )
+    }
+
     fn get_dev_path(&self) -> Option<String> {
         let parent_bus = self.parent_bus.upgrade().unwrap();
         match self.device.lock().unwrap().device_type() {
@@ -1430,6 +1480,18 @@ impl MigrationHook for VirtioPciDevice {
             }
         }
 
+        // The level of INTx is not saved, raise it again for the pending ISR.
+        let msix_enabled = self
+            .config
+            .msix
+            .as_ref()
+            .map_or(false, |msix| msix.lock().unwrap().enabled);
+        if !msix_enabled && self.common_config.lock().unwrap().interrupt_status != 0 {
+            if let Some(intx) = &self.config.intx {
+                intx.lock().unwrap().notify(true);
+            }
+        }
+
         Ok(())
     }
 }
@@ -1441,7 +1503,7 @@ mod tests {
     use address_space::{AddressSpace, GuestAddress, HostMemMapping};
     use pci::{
         config::{HEADER_TYPE, HEADER_TYPE_MULTIFUNC},
-        le_read_u16,
+        le_read_u16, PciIntxState,
     };
     use util::num_ops::read_u32;
     use vmm_sys_util::eventfd::EventFd;
@@ -1816,6 +1878,79 @@ mod tests {
         assert_eq!(virtio_pci.device_activated.load(Ordering::Relaxed), false);
     }
 
+    #[test]
+    fn test_intx_fallback() {
+        let sys_mem = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let virtio_dev: Arc<Mutex<dyn VirtioDevice>> =
+            Arc::new(Mutex::new(VirtioDeviceTest::new()));
+        let parent_bus = Arc::new(Mutex::new(PciBus::new(
+            String::from("test bus"),
+            #[cfg(target_arch = "x86_64")]
+            Region::init_container_region(1 << 16),
+            sys_mem.root().clone(),
+        )));
+        parent_bus.lock().unwrap().intx_state = Some(Arc::new(Mutex::new(PciIntxState::new(16))));
+        let mut virtio_pci = VirtioPciDevice::new(
+            String::from("test device"),
+            0,
+            sys_mem,
+            virtio_dev,
+            Arc::downgrade(&parent_bus),
+            false,
+        );
+        init_msix(
+            VIRTIO_PCI_MSIX_BAR_IDX as usize,
+            virtio_pci.device.lock().unwrap().queue_num() as u32 + 1,
+            &mut virtio_pci.config,
+            virtio_pci.dev_id.clone(),
+            &virtio_pci.name,
+            None,
+            None,
+        )
+        .unwrap();
+        init_intx(
+            virtio_pci.name.clone(),
+            &mut virtio_pci.config,
+            virtio_pci.parent_bus.clone(),
+            virtio_pci.devfn,
+        )
+        .unwrap();
+        virtio_pci.assign_interrupt_cb();
+        let isr_space = AddressSpace::new(Region::init_container_region(0x4000)).unwrap();
+        virtio_pci.modern_mem_region_init(isr_space.root()).unwrap();
+        let intx = virtio_pci.config.intx.clone().unwrap();
+        let cb = virtio_pci.interrupt_cb.clone().unwrap();
+
+        // MSI-X is not enabled by the guest, the queue interrupt goes to INTx.
+        cb(&VirtioInterruptType::Vring, None, false).unwrap();
+        assert!(intx.lock().unwrap().level);
+
+        // Reading ISR returns the cause and deasserts the line.
+        let mut isr = [0_u8; 1];
+        isr_space
+            .read(
+                &mut isr.as_mut(),
+                GuestAddress(VIRTIO_PCI_CAP_ISR_OFFSET as u64),
+                1,
+            )
+            .unwrap();
+        assert_eq!(isr[0], VIRTIO_MMIO_INT_VRING as u8);
+        assert!(!intx.lock().unwrap().level);
+        assert_eq!(virtio_pci.common_config.lock().unwrap().interrupt_status, 0);
+
+        // INTx is not used once MSI-X is enabled.
+        virtio_pci
+            .config
+            .msix
+            .as_ref()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .enabled = true;
+        cb(&VirtioInterruptType::Vring, None, false).unwrap();
+        assert!(!intx.lock().unwrap().level);
+    }
+
     #[test]
     fn test_multifunction() {
         let virtio_dev: Arc<Mutex<dyn VirtioDevice>> =
@@ -14,7 +14,7 @@ mod net;
 mod vsock;
 
 pub use net::Net;
-pub use vsock::{Vsock, VsockState};
+pub use vsock::{reset_vsock_transports, Vsock, VsockState};
 
 use std::fs::{File, OpenOptions};
 use std::os::unix::fs::OpenOptionsExt;
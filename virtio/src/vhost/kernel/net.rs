@@ -32,12 +32,15 @@ use super::super::{VhostNotify, VhostOps};
 use super::{VhostBackend, VhostIoHandler, VhostVringFile, VHOST_NET_SET_BACKEND};
 use crate::virtio_has_feature;
 use crate::{
-    device::net::{build_device_config_space, create_tap, CtrlInfo, VirtioNetState, MAC_ADDR_LEN},
-    CtrlVirtio, NetCtrlHandler, Queue, VirtioDevice, VirtioInterrupt, VIRTIO_F_ACCESS_PLATFORM,
-    VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
-    VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MQ, VIRTIO_TYPE_NET,
+    device::net::{
+        build_device_config_space, create_tap, create_tap_by_name, run_tap_ifdown, CtrlInfo,
+        VirtioNetState, MAC_ADDR_LEN,
+    },
+    ConfigUpdater, CtrlVirtio, NetCtrlHandler, Queue, VirtioDevice, VirtioInterrupt,
+    VIRTIO_F_ACCESS_PLATFORM, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_F_CSUM, VIRTIO_NET_F_CTRL_MAC_ADDR,
+    VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MQ, VIRTIO_TYPE_NET,
 };
 
 /// Number of virtqueues.
@@ -93,6 +96,8 @@ pub struct Net {
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
+    /// Helper to change the config space.
+    config_updater: Arc<ConfigUpdater>,
 }
 
 impl Net {
@@ -106,6 +111,7 @@ impl Net {
             mem_space: mem_space.clone(),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
+            config_updater: ConfigUpdater::new(&cfg.id),
         }
     }
 }
@@ -159,13 +165,12 @@ impl VirtioDevice for Net {
             device_features |= build_device_config_space(&mut locked_state.config_space, mac);
         }
 
-        let host_dev_name = match self.net_cfg.host_dev_name.as_str() {
-            "" => None,
-            _ => Some(self.net_cfg.host_dev_name.as_str()),
-        };
-
-        self.taps = create_tap(self.net_cfg.tap_fds.as_ref(), host_dev_name, queue_pairs)
-            .with_context(|| "Failed to create tap for vhost net")?;
+        self.taps = if self.net_cfg.tap_fds.is_none() && !self.net_cfg.host_dev_name.is_empty() {
+            create_tap_by_name(&mut self.net_cfg, queue_pairs)
+        } else {
+            create_tap(self.net_cfg.tap_fds.as_ref(), None, queue_pairs)
+        }
+        .with_context(|| "Failed to create tap for vhost net")?;
         self.backends = Some(backends);
         locked_state.device_features = device_features;
         self.vhost_features = vhost_features;
@@ -174,6 +179,7 @@ impl VirtioDevice for Net {
     }
 
     fn unrealize(&mut self) -> Result<()> {
+        run_tap_ifdown(&self.net_cfg.id);
         Ok(())
     }
 
@@ -239,12 +245,18 @@ impl VirtioDevice for Net {
             && data_len == MAC_ADDR_LEN
             && *data != config_slice[0..data_len]
         {
-            config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data);
+            self.config_updater.write(|| {
+                config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data)
+            });
         }
 
         Ok(())
     }
 
+    fn config_generation(&self) -> u32 {
+        self.config_updater.generation()
+    }
+
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     fn activate(
@@ -259,7 +271,10 @@ impl VirtioDevice for Net {
         if (driver_features & 1 << VIRTIO_NET_F_CTRL_VQ != 0) && (queue_num % 2 != 0) {
             let ctrl_queue = queues[queue_num - 1].clone();
             let ctrl_queue_evt = queue_evts.remove(queue_num - 1);
-            let ctrl_info = Arc::new(Mutex::new(CtrlInfo::new(self.state.clone())));
+            let ctrl_info = Arc::new(Mutex::new(CtrlInfo::new(
+                self.state.clone(),
+                self.config_updater.clone(),
+            )));
 
             let ctrl_handler = NetCtrlHandler {
                 ctrl: CtrlVirtio::new(ctrl_queue, ctrl_queue_evt, ctrl_info),
@@ -267,6 +282,7 @@ impl VirtioDevice for Net {
                 interrupt_cb: interrupt_cb.clone(),
                 driver_features,
                 device_broken: self.broken.clone(),
+                taps: None,
             };
 
             let notifiers =
@@ -445,16 +461,25 @@ mod tests {
     fn test_vhost_net_realize() {
         let net1 = NetworkInterfaceConfig {
             id: "eth1".to_string(),
+            netdev: "".to_string(),
             host_dev_name: "tap1".to_string(),
+            script: None,
+            downscript: None,
             mac: Some("1F:2C:3E:4A:5B:6D".to_string()),
             vhost_type: Some("vhost-kernel".to_string()),
             tap_fds: Some(vec![4]),
             vhost_fds: Some(vec![5]),
             iothread: None,
+            queue_iothreads: None,
             queues: 2,
             mq: false,
             socket_path: None,
+            chardev: None,
+            socket_reconnect: 0,
+            mtu: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            rate_limit_bps: None,
+            rate_limit_pps: None,
         };
         let conf = vec![net1];
         let confs = Some(conf);
@@ -467,16 +492,25 @@ mod tests {
 
         let net1 = NetworkInterfaceConfig {
             id: "eth0".to_string(),
+            netdev: "".to_string(),
             host_dev_name: "".to_string(),
+            script: None,
+            downscript: None,
             mac: Some("1A:2B:3C:4D:5E:6F".to_string()),
             vhost_type: Some("vhost-kernel".to_string()),
             tap_fds: None,
             vhost_fds: None,
             iothread: None,
+            queue_iothreads: None,
             queues: 2,
             mq: false,
             socket_path: None,
+            chardev: None,
+            socket_reconnect: 0,
+            mtu: None,
             queue_size: DEFAULT_VIRTQUEUE_SIZE,
+            rate_limit_bps: None,
+            rate_limit_pps: None,
         };
         let conf = vec![net1];
         let confs = Some(conf);
@@ -10,19 +10,26 @@
 // NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
 // See the Mulan PSL v2 for more details.
 
-use std::os::unix::io::RawFd;
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
 use byteorder::{ByteOrder, LittleEndian};
+use log::error;
 use machine_manager::config::{VsockConfig, DEFAULT_VIRTQUEUE_SIZE};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use migration::{DeviceStateDesc, FieldDesc, MigrationHook, MigrationManager, StateTransfer};
 use migration_derive::{ByteCode, Desc};
+use once_cell::sync::Lazy;
 use util::byte_code::ByteCode;
-use util::loop_context::EventNotifierHelper;
+use util::loop_context::{
+    read_fd, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
+};
 use util::num_ops::read_u32;
+use vmm_sys_util::epoll::EventSet;
 use vmm_sys_util::eventfd::EventFd;
 use vmm_sys_util::ioctl::ioctl_with_ref;
 
@@ -88,6 +95,147 @@ pub struct VsockState {
     broken: bool,
 }
 
+/// Vsock event for the guest, refer to `struct virtio_vsock_event` in Virtio Spec.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+struct VirtioVsockEvent {
+    /// Event id, only `VIRTIO_VSOCK_EVENT_TRANSPORT_RESET` is defined.
+    id: u32,
+}
+
+impl ByteCode for VirtioVsockEvent {}
+
+/// Event queues of all vsock devices, transport reset events are pushed to them when
+/// the VM is resumed.
+static VSOCK_EVENT_QUEUES: Lazy<Mutex<HashMap<String, Arc<Mutex<VsockEventQueue>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Event queue of vsock, which is handled by StratoVirt rather than vhost.
+struct VsockEventQueue {
+    /// System address space.
+    mem_space: Arc<AddressSpace>,
+    /// Bit mask of features negotiated by the backend and the frontend.
+    driver_features: u64,
+    /// The event queue, none if the device is not activated.
+    queue: Option<Arc<Mutex<Queue>>>,
+    /// EventFd for the event queue.
+    queue_evt: Option<Arc<EventFd>>,
+    /// Callback to trigger interrupt.
+    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    /// A transport reset event is waiting for an available buffer of the event queue.
+    reset_pending: bool,
+}
+
+impl VsockEventQueue {
+    fn new(mem_space: &Arc<AddressSpace>) -> Self {
+        VsockEventQueue {
+            mem_space: mem_space.clone(),
+            driver_features: 0,
+            queue: None,
+            queue_evt: None,
+            interrupt_cb: None,
+            reset_pending: false,
+        }
+    }
+
+    /// The `VIRTIO_VSOCK_EVENT_TRANSPORT_RESET` event indicates that communication has
+    /// been interrupted. The driver shuts down established connections and the guest_cid
+    /// configuration field is fetched again. The event is kept pending until the driver
+    /// provides a buffer if the event queue is empty or the device is not activated.
+    fn transport_reset(&mut self) -> Result<()> {
+        self.reset_pending = true;
+        self.process_queue()
+    }
+
+    fn process_queue(&mut self) -> Result<()> {
+        if !self.reset_pending {
+            return Ok(());
+        }
+        let queue = match self.queue.as_ref() {
+            Some(queue) => queue,
+            None => return Ok(()),
+        };
+
+        let mut locked_queue = queue.lock().unwrap();
+        let element = locked_queue
+            .vring
+            .pop_avail(&self.mem_space, self.driver_features)
+            .with_context(|| "Failed to get avail ring element.")?;
+        if element.desc_num == 0 {
+            return Ok(());
+        }
+
+        let event = VirtioVsockEvent {
+            id: VIRTIO_VSOCK_EVENT_TRANSPORT_RESET,
+        };
+        let event_len = event.as_bytes().len();
+        match element.in_iovec.first() {
+            Some(iov) if iov.len as usize >= event_len => {
+                self.mem_space
+                    .write_object(&event, iov.addr)
+                    .with_context(|| "Failed to write buf for virtio vsock event")?;
+            }
+            _ => bail!(
+                "Invalid buffer for virtio vsock event, at least {} bytes is required",
+                event_len
+            ),
+        }
+        locked_queue
+            .vring
+            .add_used(&self.mem_space, element.index, event_len as u32)
+            .with_context(|| format!("Failed to add used ring {}", element.index))?;
+        self.reset_pending = false;
+
+        if let Some(interrupt_cb) = &self.interrupt_cb {
+            interrupt_cb(&VirtioInterruptType::Vring, Some(&*locked_queue), false)
+                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl EventNotifierHelper for VsockEventQueue {
+    fn internal_notifiers(event_queue: Arc<Mutex<Self>>) -> Vec<EventNotifier> {
+        let queue_evt = match event_queue.lock().unwrap().queue_evt.as_ref() {
+            Some(evt) => evt.as_raw_fd(),
+            None => return Vec::new(),
+        };
+
+        let event_queue_clone = event_queue.clone();
+        let handler: Rc<NotifierCallback> = Rc::new(move |_, fd: RawFd| {
+            read_fd(fd);
+            if let Err(ref e) = event_queue_clone.lock().unwrap().process_queue() {
+                error!("Failed to process event queue for vsock, err: {:?}", e);
+            }
+            None
+        });
+        vec![EventNotifier::new(
+            NotifierOperation::AddShared,
+            queue_evt,
+            None,
+            EventSet::IN,
+            vec![handler],
+        )]
+    }
+}
+
+/// Push a transport reset event to all vsock devices. It's called when the VM is resumed,
+/// so that the guest reconnects the connections which may be stale.
+pub fn reset_vsock_transports() {
+    let event_queues: Vec<Arc<Mutex<VsockEventQueue>>> = VSOCK_EVENT_QUEUES
+        .lock()
+        .unwrap()
+        .values()
+        .cloned()
+        .collect();
+    for event_queue in event_queues {
+        if let Err(e) = event_queue.lock().unwrap().transport_reset() {
+            error!("Failed to send vsock transport reset event, err: {:?}", e);
+        }
+    }
+}
+
 /// Vsock device structure.
 pub struct Vsock {
     /// Configuration of the vsock device.
@@ -99,9 +247,7 @@ pub struct Vsock {
     /// System address space.
     mem_space: Arc<AddressSpace>,
     /// Event queue for vsock.
-    event_queue: Option<Arc<Mutex<Queue>>>,
-    /// Callback to trigger interrupt.
-    interrupt_cb: Option<Arc<VirtioInterrupt>>,
+    event_queue: Arc<Mutex<VsockEventQueue>>,
     /// EventFd for device deactivate.
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
@@ -115,53 +261,14 @@ impl Vsock {
             backend: None,
             state: VsockState::default(),
             mem_space: mem_space.clone(),
-            event_queue: None,
-            interrupt_cb: None,
+            event_queue: Arc::new(Mutex::new(VsockEventQueue::new(mem_space))),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
         }
     }
 
-    /// The `VIRTIO_VSOCK_EVENT_TRANSPORT_RESET` event indicates that communication has
-    /// been interrupted. The driver shuts down established connections and the guest_cid
-    /// configuration field is fetched again.
     fn transport_reset(&self) -> Result<()> {
-        if let Some(evt_queue) = self.event_queue.as_ref() {
-            let mut event_queue_locked = evt_queue.lock().unwrap();
-            let element = event_queue_locked
-                .vring
-                .pop_avail(&self.mem_space, self.state.driver_features)
-                .with_context(|| "Failed to get avail ring element.")?;
-            if element.desc_num == 0 {
-                return Ok(());
-            }
-
-            self.mem_space
-                .write_object(
-                    &VIRTIO_VSOCK_EVENT_TRANSPORT_RESET,
-                    element.in_iovec[0].addr,
-                )
-                .with_context(|| "Failed to write buf for virtio vsock event")?;
-            event_queue_locked
-                .vring
-                .add_used(
-                    &self.mem_space,
-                    element.index,
-                    VIRTIO_VSOCK_EVENT_TRANSPORT_RESET.as_bytes().len() as u32,
-                )
-                .with_context(|| format!("Failed to add used ring {}", element.index))?;
-
-            if let Some(interrupt_cb) = &self.interrupt_cb {
-                interrupt_cb(
-                    &VirtioInterruptType::Vring,
-                    Some(&*event_queue_locked),
-                    false,
-                )
-                .with_context(|| anyhow!(VirtioError::EventFdWrite))?;
-            }
-        }
-
-        Ok(())
+        self.event_queue.lock().unwrap().transport_reset()
     }
 }
 
@@ -178,10 +285,22 @@ impl VirtioDevice for Vsock {
             .get_features()
             .with_context(|| "Failed to get features for vsock")?;
         self.backend = Some(backend);
+        VSOCK_EVENT_QUEUES
+            .lock()
+            .unwrap()
+            .insert(self.vsock_cfg.id.clone(), self.event_queue.clone());
 
         Ok(())
     }
 
+    fn unrealize(&mut self) -> Result<()> {
+        VSOCK_EVENT_QUEUES
+            .lock()
+            .unwrap()
+            .remove(&self.vsock_cfg.id);
+        Ok(())
+    }
+
     /// Get the virtio device type, refer to Virtio Spec.
     fn device_type(&self) -> u32 {
         VIRTIO_TYPE_VSOCK
@@ -258,8 +377,12 @@ impl VirtioDevice for Vsock {
         // The receive queue and transmit queue will be handled in vhost.
         let vhost_queues = queues[..2].to_vec();
         // This event queue will be handled.
-        self.event_queue = Some(queues[2].clone());
-        self.interrupt_cb = Some(interrupt_cb.clone());
+        let mut locked_event_queue = self.event_queue.lock().unwrap();
+        locked_event_queue.driver_features = self.state.driver_features;
+        locked_event_queue.queue = Some(queues[2].clone());
+        locked_event_queue.queue_evt = Some(queue_evts[2].clone());
+        locked_event_queue.interrupt_cb = Some(interrupt_cb.clone());
+        drop(locked_event_queue);
 
         // Preliminary setup for vhost net.
         let backend = match &self.backend {
@@ -326,17 +449,28 @@ impl VirtioDevice for Vsock {
 
         let notifiers = EventNotifierHelper::internal_notifiers(Arc::new(Mutex::new(handler)));
         register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
+        let notifiers = EventNotifierHelper::internal_notifiers(self.event_queue.clone());
+        register_event_helper(notifiers, None, &mut self.deactivate_evts)?;
         self.broken.store(false, Ordering::SeqCst);
 
-        Ok(())
+        // Deliver the transport reset event which is pending before activation.
+        self.event_queue.lock().unwrap().process_queue()
     }
 
     fn deactivate(&mut self) -> Result<()> {
-        unregister_event_helper(None, &mut self.deactivate_evts)
+        unregister_event_helper(None, &mut self.deactivate_evts)?;
+        let mut locked_event_queue = self.event_queue.lock().unwrap();
+        locked_event_queue.queue = None;
+        locked_event_queue.queue_evt = None;
+        locked_event_queue.interrupt_cb = None;
+        Ok(())
     }
 
     fn reset(&mut self) -> Result<()> {
-        self.backend.as_ref().unwrap().set_running(false)
+        self.backend.as_ref().unwrap().set_running(false)?;
+        // Connections of the guest are stale after reset, notify the driver once it's ready.
+        self.event_queue.lock().unwrap().reset_pending = true;
+        Ok(())
     }
 }
 
@@ -390,8 +524,11 @@ impl MigrationHook for Vsock {
 mod tests {
     pub use super::super::*;
     pub use super::*;
+    use crate::{QueueConfig, SplitVringDesc};
     pub use address_space::*;
 
+    const VIRTQ_DESC_F_WRITE: u16 = 0x02;
+
     fn vsock_address_space_init() -> Arc<AddressSpace> {
         let root = Region::init_container_region(u64::max_value());
         let sys_mem = AddressSpace::new(root).unwrap();
@@ -487,4 +624,93 @@ mod tests {
         assert_eq!(backend.set_guest_cid(2).is_ok(), false);
         assert_eq!(backend.set_guest_cid(0).is_ok(), false);
     }
+
+    #[test]
+    fn test_vsock_transport_reset_event() {
+        let event = VirtioVsockEvent {
+            id: VIRTIO_VSOCK_EVENT_TRANSPORT_RESET,
+        };
+        assert_eq!(std::mem::size_of::<VirtioVsockEvent>(), 4);
+        assert_eq!(event.as_bytes(), &[0_u8; 4]);
+
+        let root = Region::init_container_region(1 << 36);
+        let mem_space = AddressSpace::new(root).unwrap();
+        let host_mmap = Arc::new(
+            HostMemMapping::new(GuestAddress(0), None, 0x10_0000, None, false, false, false)
+                .unwrap(),
+        );
+        mem_space
+            .root()
+            .add_subregion(Region::init_ram_region(host_mmap), 0)
+            .unwrap();
+
+        let interrupt_evt = Arc::new(EventFd::new(libc::EFD_NONBLOCK).unwrap());
+        let cloned_interrupt_evt = interrupt_evt.clone();
+        let interrupt_cb = Arc::new(Box::new(
+            move |_: &VirtioInterruptType, _: Option<&Queue>, _: bool| {
+                interrupt_evt
+                    .write(1)
+                    .with_context(|| anyhow!(VirtioError::EventFdWrite))
+            },
+        ) as VirtioInterrupt);
+
+        let mut queue_config = QueueConfig::new(DEFAULT_VIRTQUEUE_SIZE);
+        queue_config.desc_table = GuestAddress(0);
+        queue_config.addr_cache.desc_table_host =
+            mem_space.get_host_address(queue_config.desc_table).unwrap();
+        queue_config.avail_ring = GuestAddress(16 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.avail_ring_host =
+            mem_space.get_host_address(queue_config.avail_ring).unwrap();
+        queue_config.used_ring = GuestAddress(32 * DEFAULT_VIRTQUEUE_SIZE as u64);
+        queue_config.addr_cache.used_ring_host =
+            mem_space.get_host_address(queue_config.used_ring).unwrap();
+        queue_config.size = DEFAULT_VIRTQUEUE_SIZE;
+        queue_config.ready = true;
+
+        // The event is pending before the device is activated.
+        let mut event_queue = VsockEventQueue::new(&mem_space);
+        assert!(event_queue.transport_reset().is_ok());
+        assert!(event_queue.reset_pending);
+        event_queue.queue = Some(Arc::new(Mutex::new(Queue::new(queue_config, 1).unwrap())));
+        event_queue.interrupt_cb = Some(interrupt_cb);
+
+        // No buffer is available, the event is still pending.
+        assert!(event_queue.process_queue().is_ok());
+        assert!(event_queue.reset_pending);
+
+        // The driver provides a buffer filled with 0xff.
+        let desc = SplitVringDesc {
+            addr: GuestAddress(0x40000),
+            len: 4,
+            flags: VIRTQ_DESC_F_WRITE,
+            next: 0,
+        };
+        mem_space
+            .write_object(&desc, queue_config.desc_table)
+            .unwrap();
+        mem_space
+            .write_object::<u32>(&u32::MAX, GuestAddress(0x40000))
+            .unwrap();
+        mem_space
+            .write_object::<u16>(&0, GuestAddress(queue_config.avail_ring.0 + 4))
+            .unwrap();
+        mem_space
+            .write_object::<u16>(&1, GuestAddress(queue_config.avail_ring.0 + 2))
+            .unwrap();
+
+        assert!(event_queue.process_queue().is_ok());
+        assert!(!event_queue.reset_pending);
+        let id = mem_space.read_object::<u32>(GuestAddress(0x40000)).unwrap();
+        assert_eq!(id, VIRTIO_VSOCK_EVENT_TRANSPORT_RESET);
+        // The used element covers the whole event.
+        let used_idx = mem_space
+            .read_object::<u16>(GuestAddress(queue_config.used_ring.0 + 2))
+            .unwrap();
+        assert_eq!(used_idx, 1);
+        let used_len = mem_space
+            .read_object::<u32>(GuestAddress(queue_config.used_ring.0 + 8))
+            .unwrap();
+        assert_eq!(used_len, 4);
+        assert_eq!(cloned_interrupt_evt.read().unwrap(), 1);
+    }
 }
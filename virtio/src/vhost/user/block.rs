@@ -17,7 +17,7 @@ use std::io::Write;
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
-use machine_manager::config::BlkDevConfig;
+use machine_manager::config::{unregister_socket_chardev, BlkDevConfig};
 use util::byte_code::ByteCode;
 use util::num_ops::read_u32;
 use vmm_sys_util::eventfd::EventFd;
@@ -35,6 +35,10 @@ use crate::{
     VIRTIO_F_VERSION_1, VIRTIO_TYPE_BLOCK,
 };
 
+/// Protocol features supported by vhost-user blk.
+const SUPPORTED_PROTOCOL_FEATURES: u64 =
+    1 << VHOST_USER_PROTOCOL_F_MQ | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
+
 pub struct Block {
     /// Configuration of the block device.
     blk_cfg: BlkDevConfig,
@@ -78,6 +82,9 @@ impl Block {
             .as_ref()
             .map(|path| path.to_string())
             .with_context(|| "vhost-user: socket path is not found")?;
+        if self.blk_cfg.socket_reconnect != 0 {
+            return self.init_reconnect_client(&socket_path);
+        }
         let client = VhostUserClient::new(
             &self.mem_space,
             &socket_path,
@@ -93,6 +100,34 @@ impl Block {
         Ok(())
     }
 
+    /// Connect with spdk by the socket chardev, which reconnects when spdk restarts.
+    fn init_reconnect_client(&mut self, socket_path: &str) -> Result<()> {
+        let chardev_id = self
+            .blk_cfg
+            .chardev
+            .clone()
+            .with_context(|| "vhost-user: chardev is not found")?;
+        let (client, socket) = VhostUserClient::connect_chardev(
+            &self.mem_space,
+            &chardev_id,
+            socket_path,
+            self.blk_cfg.socket_reconnect,
+            self.queue_num() as u64,
+            VhostBackendType::TypeBlock,
+        )
+        .with_context(|| {
+            "Failed to create the client which communicates with the server for vhost-user blk"
+        })?;
+        VhostUserClient::add_chardev_notifier(
+            &client,
+            &socket,
+            &self.blk_cfg.id,
+            SUPPORTED_PROTOCOL_FEATURES,
+        );
+        self.client = Some(client);
+        Ok(())
+    }
+
     /// Negotiate features with spdk.
     fn negotiate_features(&mut self) -> Result<()> {
         let locked_client = self.client.as_ref().unwrap().lock().unwrap();
@@ -104,10 +139,8 @@ impl Block {
             let protocol_features = locked_client
                 .get_protocol_features()
                 .with_context(|| "Failed to get protocol features for vhost-user blk")?;
-            let supported_protocol_features =
-                1 << VHOST_USER_PROTOCOL_F_MQ | 1 << VHOST_USER_PROTOCOL_F_CONFIG;
             locked_client
-                .set_protocol_features(supported_protocol_features & protocol_features)
+                .set_protocol_features(SUPPORTED_PROTOCOL_FEATURES & protocol_features)
                 .with_context(|| "Failed to set protocol features for vhost-user blk")?;
 
             if virtio_has_feature(protocol_features, VHOST_USER_PROTOCOL_F_CONFIG as u32) {
@@ -288,6 +321,11 @@ impl VirtioDevice for Block {
     /// Unrealize device.
     fn unrealize(&mut self) -> Result<()> {
         self.delete_event()?;
+        if self.blk_cfg.socket_reconnect != 0 {
+            if let Some(chardev) = &self.blk_cfg.chardev {
+                unregister_socket_chardev(chardev);
+            }
+        }
         self.call_events.clear();
         self.client = None;
         Ok(())
@@ -13,6 +13,7 @@
 use std::fs::File;
 use std::mem::size_of;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::net::UnixStream;
 use std::rc::Rc;
 use std::slice::from_raw_parts;
 use std::sync::{Arc, Mutex};
@@ -21,6 +22,10 @@ use address_space::{
     AddressSpace, FileBackend, FlatRange, GuestAddress, Listener, ListenerReqType, RegionIoEventFd,
 };
 use log::{error, info, warn};
+use machine_manager::config::{
+    register_socket_chardev, unregister_socket_chardev, ChardevEvent, ChardevNotifier,
+    SocketChardev,
+};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper, EventLoop};
 use util::loop_context::{
     gen_delete_notifiers, EventNotifier, EventNotifierHelper, NotifierCallback, NotifierOperation,
@@ -32,7 +37,7 @@ use super::super::super::{Queue, QueueConfig, VIRTIO_NET_F_CTRL_VQ};
 use super::super::VhostOps;
 use super::message::{
     RegionMemInfo, VhostUserHdrFlag, VhostUserMemContext, VhostUserMemHdr, VhostUserMsgHdr,
-    VhostUserMsgReq, VhostUserVringAddr, VhostUserVringState,
+    VhostUserMsgReq, VhostUserVringAddr, VhostUserVringState, VHOST_USER_F_PROTOCOL_FEATURES,
 };
 use super::sock::VhostUserSock;
 use crate::device::block::VirtioBlkConfig;
@@ -43,6 +48,8 @@ use util::unix::do_mmap;
 
 /// Vhost supports multiple queue
 pub const VHOST_USER_PROTOCOL_F_MQ: u8 = 0;
+/// Vhost supports `VHOST_USER_NET_SET_MTU` msg.
+pub const VHOST_USER_PROTOCOL_F_NET_MTU: u8 = 4;
 /// Vhost supports `VHOST_USER_SET_CONFIG` and `VHOST_USER_GET_CONFIG` msg.
 pub const VHOST_USER_PROTOCOL_F_CONFIG: u8 = 9;
 /// Vhost supports `VHOST_USER_SET_INFLIGHT_FD` and `VHOST_USER_GET_INFLIGHT_FD` msg.
@@ -366,6 +373,8 @@ pub struct VhostUserClient {
     reconnecting: bool,
     inflight: Option<VhostInflight>,
     backend_type: VhostBackendType,
+    /// MTU set to the vhost-user net backend, which is set again after reconnecting.
+    net_mtu: Option<u16>,
 }
 
 impl VhostUserClient {
@@ -382,7 +391,28 @@ impl VhostUserClient {
                 path
             )
         })?;
+        Self::with_sock(mem_space, sock, max_queue_num, backend_type)
+    }
+
+    /// Create the client on a stream connected by a socket chardev.
+    pub fn with_stream(
+        mem_space: &Arc<AddressSpace>,
+        path: &str,
+        stream: UnixStream,
+        max_queue_num: u64,
+        backend_type: VhostBackendType,
+    ) -> Result<Self> {
+        let mut sock = VhostUserSock::new(path);
+        sock.domain.set_stream(stream);
+        Self::with_sock(mem_space, sock, max_queue_num, backend_type)
+    }
 
+    fn with_sock(
+        mem_space: &Arc<AddressSpace>,
+        sock: VhostUserSock,
+        max_queue_num: u64,
+        backend_type: VhostBackendType,
+    ) -> Result<Self> {
         let mem_info = VhostUserMemInfo::new();
         mem_space
             .register_listener(Arc::new(Mutex::new(mem_info.clone())))
@@ -401,9 +431,68 @@ impl VhostUserClient {
             reconnecting: false,
             inflight: None,
             backend_type,
+            net_mtu: None,
         })
     }
 
+    /// Create the client on the socket chardev `chardev`, which reconnects when the backend
+    /// restarts. The chardev is unregistered if the client can't be created.
+    ///
+    /// # Arguments
+    ///
+    /// * `mem_space` - System address space.
+    /// * `chardev` - Id of the socket chardev.
+    /// * `path` - Path of the socket.
+    /// * `reconnect` - Interval in seconds to reconnect the socket.
+    /// * `max_queue_num` - Maximum number of queues of the device.
+    /// * `backend_type` - Type of the vhost-user device.
+    pub fn connect_chardev(
+        mem_space: &Arc<AddressSpace>,
+        chardev: &str,
+        path: &str,
+        reconnect: u64,
+        max_queue_num: u64,
+        backend_type: VhostBackendType,
+    ) -> Result<(Arc<Mutex<Self>>, Arc<Mutex<SocketChardev>>)> {
+        let socket = register_socket_chardev(chardev, path, reconnect)?;
+        let client = SocketChardev::connect(&socket)
+            .and_then(|_| socket.lock().unwrap().get_stream())
+            .and_then(|stream| {
+                Self::with_stream(mem_space, path, stream, max_queue_num, backend_type)
+            });
+        match client {
+            Ok(client) => Ok((Arc::new(Mutex::new(client)), socket)),
+            Err(e) => {
+                unregister_socket_chardev(chardev);
+                Err(e)
+            }
+        }
+    }
+
+    /// Resume the backend by the client of device `dev_id` when the socket chardev reconnects.
+    pub fn add_chardev_notifier(
+        client: &Arc<Mutex<Self>>,
+        socket: &Arc<Mutex<SocketChardev>>,
+        dev_id: &str,
+        protocol_features: u64,
+    ) {
+        let cloned_client = client.clone();
+        let id = dev_id.to_string();
+        let notifier: ChardevNotifier = Arc::new(move |event| {
+            if let ChardevEvent::Connected(stream) = event {
+                match cloned_client
+                    .lock()
+                    .unwrap()
+                    .reconnect(stream, protocol_features)
+                {
+                    Ok(()) => info!("Reconnecting vhost-user {} succeed.", id),
+                    Err(e) => error!("Failed to reconnect vhost-user {}, {:?}", id, e),
+                }
+            }
+        });
+        socket.lock().unwrap().add_notifier(dev_id, notifier);
+    }
+
     /// Save queue info used for reconnection.
     pub fn set_queues(&mut self, queues: &[Arc<Mutex<Queue>>]) {
         for (queue_index, _) in queues.iter().enumerate() {
@@ -469,6 +558,43 @@ impl VhostUserClient {
         Ok(())
     }
 
+    /// Resume the backend on a new connection, e.g. after the backend restarts. The features
+    /// are negotiated again, and the memory table and vrings are re-sent if the device has
+    /// been activated.
+    ///
+    /// # Arguments
+    ///
+    /// * `stream` - The new connection to the backend.
+    /// * `protocol_features` - Protocol features supported by the device.
+    pub fn reconnect(&mut self, stream: UnixStream, protocol_features: u64) -> Result<()> {
+        self.client.lock().unwrap().sock.domain.set_stream(stream);
+
+        let features = self
+            .get_features()
+            .with_context(|| "Failed to get features for vhost-user")?;
+        if self.features & !features != 0 {
+            bail!(
+                "Features {:#x} acked by the guest are not supported by the backend any more",
+                self.features & !features
+            );
+        }
+        if virtio_has_feature(features, VHOST_USER_F_PROTOCOL_FEATURES) {
+            let backend_protocol_features = self
+                .get_protocol_features()
+                .with_context(|| "Failed to get protocol features for vhost-user")?;
+            self.set_protocol_features(protocol_features & backend_protocol_features)
+                .with_context(|| "Failed to set protocol features for vhost-user")?;
+        }
+        if let Some(mtu) = self.net_mtu {
+            self.set_net_mtu(mtu)?;
+        }
+
+        if !self.queues.is_empty() {
+            self.activate_vhost_user()?;
+        }
+        Ok(())
+    }
+
     /// Activate device by vhost-user protocol.
     pub fn activate_vhost_user(&mut self) -> Result<()> {
         self.set_owner()
@@ -666,6 +792,14 @@ impl VhostUserClient {
         Ok(())
     }
 
+    /// Set the MTU of the vhost-user net backend.
+    pub fn set_net_mtu(&mut self, mtu: u16) -> Result<()> {
+        self.set_value(VhostUserMsgReq::NetSetMtu, mtu as u64)
+            .with_context(|| "Failed to set mtu for vhost-user net")?;
+        self.net_mtu = Some(mtu);
+        Ok(())
+    }
+
     /// Get max queues number that vhost supports.
     pub fn get_max_queue_num(&self) -> Result<u64> {
         let request = VhostUserMsgReq::GetQueueNum as u32;
@@ -1001,3 +1135,70 @@ impl VhostOps for VhostUserClient {
         Ok(res.value as u16)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+
+    use address_space::Region;
+
+    use super::*;
+    use crate::VhostUser::VHOST_USER_VERSION;
+
+    fn create_client() -> (VhostUserClient, UnixStream) {
+        let mem_space = AddressSpace::new(Region::init_container_region(u64::max_value())).unwrap();
+        let (stream, peer) = UnixStream::pair().unwrap();
+        let client = VhostUserClient::with_stream(
+            &mem_space,
+            "/tmp/vhost-user-net.sock",
+            stream,
+            2,
+            VhostBackendType::TypeNet,
+        )
+        .unwrap();
+        (client, peer)
+    }
+
+    #[test]
+    fn test_set_net_mtu() {
+        let (mut client, mut peer) = create_client();
+        client.set_net_mtu(9000).unwrap();
+        assert_eq!(client.net_mtu, Some(9000));
+
+        // Header of NetSetMtu with protocol version 1, followed by the u64 mtu.
+        let mut msg = [0_u8; 20];
+        peer.read_exact(&mut msg).unwrap();
+        assert_eq!(&msg[0..4], &20_u32.to_le_bytes());
+        assert_eq!(&msg[4..8], &VHOST_USER_VERSION.to_le_bytes());
+        assert_eq!(&msg[8..12], &8_u32.to_le_bytes());
+        assert_eq!(&msg[12..20], &9000_u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_get_features() {
+        let (client, mut peer) = create_client();
+
+        // Reply of GetFeatures: request 1, version 1 | reply flag, size 8.
+        let features: u64 = 1 << VHOST_USER_F_PROTOCOL_FEATURES | 1 << VIRTIO_NET_F_CTRL_VQ;
+        let mut reply = vec![1, 0, 0, 0, 5, 0, 0, 0, 8, 0, 0, 0];
+        reply.extend_from_slice(&features.to_le_bytes());
+        peer.write_all(&reply).unwrap();
+        assert_eq!(client.get_features().unwrap(), features);
+
+        let mut msg = [0_u8; 12];
+        peer.read_exact(&mut msg).unwrap();
+        assert_eq!(msg, [1, 0, 0, 0, 9, 0, 0, 0, 0, 0, 0, 0]);
+
+        // The reply doesn't match the request.
+        let mut reply = vec![2, 0, 0, 0, 5, 0, 0, 0, 8, 0, 0, 0];
+        reply.extend_from_slice(&features.to_le_bytes());
+        peer.write_all(&reply).unwrap();
+        assert!(client.get_features().is_err());
+
+        // The message is not a reply.
+        let mut reply = vec![1, 0, 0, 0, 1, 0, 0, 0, 8, 0, 0, 0];
+        reply.extend_from_slice(&features.to_le_bytes());
+        peer.write_all(&reply).unwrap();
+        assert!(client.get_features().is_err());
+    }
+}
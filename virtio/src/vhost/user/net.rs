@@ -18,7 +18,7 @@ use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
 
 use address_space::AddressSpace;
-use machine_manager::config::NetworkInterfaceConfig;
+use machine_manager::config::{unregister_socket_chardev, NetworkInterfaceConfig};
 use machine_manager::event_loop::{register_event_helper, unregister_event_helper};
 use util::byte_code::ByteCode;
 use util::loop_context::EventNotifierHelper;
@@ -26,20 +26,27 @@ use util::num_ops::read_u32;
 use vmm_sys_util::eventfd::EventFd;
 
 use super::super::VhostOps;
-use super::{VhostBackendType, VhostUserClient};
+use super::{
+    VhostBackendType, VhostUserClient, VHOST_USER_F_PROTOCOL_FEATURES, VHOST_USER_PROTOCOL_F_MQ,
+    VHOST_USER_PROTOCOL_F_NET_MTU,
+};
 use crate::error::VirtioError;
 use crate::{
     device::net::{build_device_config_space, CtrlInfo, VirtioNetState, MAC_ADDR_LEN},
-    CtrlVirtio, NetCtrlHandler, Queue, VirtioDevice, VirtioInterrupt, VIRTIO_F_RING_EVENT_IDX,
-    VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN,
-    VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_VQ, VIRTIO_NET_F_GUEST_CSUM,
-    VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO, VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO,
-    VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ, VIRTIO_NET_F_MRG_RXBUF, VIRTIO_TYPE_NET,
+    ConfigUpdater, CtrlVirtio, NetCtrlHandler, Queue, VirtioDevice, VirtioInterrupt,
+    VIRTIO_F_RING_EVENT_IDX, VIRTIO_F_VERSION_1, VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MAX,
+    VIRTIO_NET_CTRL_MQ_VQ_PAIRS_MIN, VIRTIO_NET_F_CTRL_MAC_ADDR, VIRTIO_NET_F_CTRL_VQ,
+    VIRTIO_NET_F_GUEST_CSUM, VIRTIO_NET_F_GUEST_TSO4, VIRTIO_NET_F_GUEST_UFO,
+    VIRTIO_NET_F_HOST_TSO4, VIRTIO_NET_F_HOST_UFO, VIRTIO_NET_F_MAC, VIRTIO_NET_F_MQ,
+    VIRTIO_NET_F_MRG_RXBUF, VIRTIO_NET_F_MTU, VIRTIO_TYPE_NET,
 };
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 
 /// Number of virtqueues.
 const QUEUE_NUM_NET: usize = 2;
+/// Protocol features supported by vhost-user net.
+const SUPPORTED_PROTOCOL_FEATURES: u64 =
+    1 << VHOST_USER_PROTOCOL_F_MQ | 1 << VHOST_USER_PROTOCOL_F_NET_MTU;
 
 /// Network device structure.
 pub struct Net {
@@ -57,6 +64,8 @@ pub struct Net {
     deactivate_evts: Vec<RawFd>,
     /// Device is broken or not.
     broken: Arc<AtomicBool>,
+    /// Helper to change the config space.
+    config_updater: Arc<ConfigUpdater>,
 }
 
 impl Net {
@@ -69,6 +78,7 @@ impl Net {
             call_events: Vec::<Arc<EventFd>>::new(),
             deactivate_evts: Vec::new(),
             broken: Arc::new(AtomicBool::new(false)),
+            config_updater: ConfigUpdater::new(&cfg.id),
         }
     }
 
@@ -88,31 +98,50 @@ impl Net {
         {
             unregister_event_helper(self.net_cfg.iothread.as_ref(), &mut self.deactivate_evts)?;
         }
+        if self.net_cfg.socket_reconnect != 0 {
+            if let Some(chardev) = &self.net_cfg.chardev {
+                unregister_socket_chardev(chardev);
+            }
+        }
 
         Ok(())
     }
 
-    fn clean_up(&mut self) -> Result<()> {
-        self.delete_event()?;
-        let mut locked_state = self.state.lock().unwrap();
-        locked_state
-            .as_mut_bytes()
-            .copy_from_slice(&[0_u8; std::mem::size_of::<VirtioNetState>()]);
-        self.client = None;
-
-        Ok(())
-    }
-}
-
-impl VirtioDevice for Net {
-    /// Realize vhost user network device.
-    fn realize(&mut self) -> Result<()> {
+    /// Connect with the backend. The socket chardev reconnects when the backend restarts if
+    /// `reconnect` is set, otherwise the socket is reconnected once it hangs up.
+    fn init_client(&mut self) -> Result<Arc<Mutex<VhostUserClient>>> {
         let socket_path = self
             .net_cfg
             .socket_path
             .as_ref()
             .map(|path| path.to_string())
             .with_context(|| "vhost-user: socket path is not found")?;
+        if self.net_cfg.socket_reconnect != 0 {
+            let chardev = self
+                .net_cfg
+                .chardev
+                .clone()
+                .with_context(|| "vhost-user: chardev is not found")?;
+            let (client, socket) = VhostUserClient::connect_chardev(
+                &self.mem_space,
+                &chardev,
+                &socket_path,
+                self.net_cfg.socket_reconnect,
+                self.queue_num() as u64,
+                VhostBackendType::TypeNet,
+            )
+            .with_context(|| {
+                "Failed to create the client which communicates with the server for vhost-user net"
+            })?;
+            VhostUserClient::add_chardev_notifier(
+                &client,
+                &socket,
+                &self.net_cfg.id,
+                SUPPORTED_PROTOCOL_FEATURES,
+            );
+            return Ok(client);
+        }
+
         let client = VhostUserClient::new(
             &self.mem_space,
             &socket_path,
@@ -124,11 +153,77 @@ impl VirtioDevice for Net {
         })?;
         let client = Arc::new(Mutex::new(client));
         VhostUserClient::add_event(&client)?;
+        Ok(client)
+    }
+
+    /// Negotiate the protocol features, and set the MTU of the backend if it's configured.
+    fn negotiate_protocol_features(
+        &self,
+        client: &mut VhostUserClient,
+        features: u64,
+        state: &mut VirtioNetState,
+    ) -> Result<()> {
+        if !virtio_has_feature(features, VHOST_USER_F_PROTOCOL_FEATURES) {
+            if self.net_cfg.mtu.is_some() {
+                bail!("The vhost-user net backend doesn't support setting mtu");
+            }
+            return Ok(());
+        }
+
+        let protocol_features = client
+            .get_protocol_features()
+            .with_context(|| "Failed to get protocol features for vhost-user net")?;
+        client
+            .set_protocol_features(SUPPORTED_PROTOCOL_FEATURES & protocol_features)
+            .with_context(|| "Failed to set protocol features for vhost-user net")?;
 
+        if self.net_cfg.mq && virtio_has_feature(protocol_features, VHOST_USER_PROTOCOL_F_MQ as u32)
+        {
+            let max_queue_num = client
+                .get_max_queue_num()
+                .with_context(|| "Failed to get queue num for vhost-user net")?;
+            if (self.net_cfg.queues as u64) > max_queue_num {
+                bail!(
+                    "The queue num {} is bigger than {} supported by the vhost-user net backend",
+                    self.net_cfg.queues,
+                    max_queue_num
+                );
+            }
+        }
+
+        if let Some(mtu) = self.net_cfg.mtu {
+            if !virtio_has_feature(protocol_features, VHOST_USER_PROTOCOL_F_NET_MTU as u32) {
+                bail!(
+                    "The vhost-user net backend doesn't support setting mtu, protocol features: {:#b}",
+                    protocol_features
+                );
+            }
+            client.set_net_mtu(mtu)?;
+            state.device_features |= 1 << VIRTIO_NET_F_MTU;
+            state.config_space.mtu = mtu;
+        }
+        Ok(())
+    }
+
+    fn clean_up(&mut self) -> Result<()> {
+        self.delete_event()?;
+        let mut locked_state = self.state.lock().unwrap();
+        locked_state
+            .as_mut_bytes()
+            .copy_from_slice(&[0_u8; std::mem::size_of::<VirtioNetState>()]);
+        self.client = None;
+
+        Ok(())
+    }
+}
+
+impl VirtioDevice for Net {
+    /// Realize vhost user network device.
+    fn realize(&mut self) -> Result<()> {
+        let client = self.init_client()?;
+        let mut locked_client = client.lock().unwrap();
         let mut locked_state = self.state.lock().unwrap();
-        locked_state.device_features = client
-            .lock()
-            .unwrap()
+        let backend_features = locked_client
             .get_features()
             .with_context(|| "Failed to get features for vhost-user net")?;
 
@@ -140,7 +235,9 @@ impl VirtioDevice for Net {
             | 1 << VIRTIO_NET_F_HOST_UFO
             | 1 << VIRTIO_NET_F_MRG_RXBUF
             | 1 << VIRTIO_F_RING_EVENT_IDX;
-        locked_state.device_features &= features;
+        locked_state.device_features = backend_features & features;
+        self.negotiate_protocol_features(&mut locked_client, backend_features, &mut locked_state)?;
+        drop(locked_client);
 
         let queue_pairs = self.net_cfg.queues / 2;
         if self.net_cfg.mq
@@ -225,12 +322,18 @@ impl VirtioDevice for Net {
             && data_len == MAC_ADDR_LEN
             && *data != config_slice[0..data_len]
         {
-            config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data);
+            self.config_updater.write(|| {
+                config_slice[(offset as usize)..(offset as usize + data_len)].copy_from_slice(data)
+            });
         }
 
         Ok(())
     }
 
+    fn config_generation(&self) -> u32 {
+        self.config_updater.generation()
+    }
+
     /// Activate the virtio device, this function is called by vcpu thread when frontend
     /// virtio driver is ready and write `DRIVER_OK` to backend.
     fn activate(
@@ -245,7 +348,10 @@ impl VirtioDevice for Net {
         if ((driver_features & (1 << VIRTIO_NET_F_CTRL_VQ)) != 0) && (queue_num % 2 != 0) {
             let ctrl_queue = queues[queue_num - 1].clone();
             let ctrl_queue_evt = queue_evts.remove(queue_num - 1);
-            let ctrl_info = Arc::new(Mutex::new(CtrlInfo::new(self.state.clone())));
+            let ctrl_info = Arc::new(Mutex::new(CtrlInfo::new(
+                self.state.clone(),
+                self.config_updater.clone(),
+            )));
 
             let ctrl_handler = NetCtrlHandler {
                 ctrl: CtrlVirtio::new(ctrl_queue, ctrl_queue_evt, ctrl_info),
@@ -253,6 +359,7 @@ impl VirtioDevice for Net {
                 interrupt_cb: interrupt_cb.clone(),
                 driver_features,
                 device_broken: self.broken.clone(),
+                taps: None,
             };
 
             let notifiers =
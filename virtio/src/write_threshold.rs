@@ -0,0 +1,167 @@
+// Copyright (c) 2023 Huawei Technologies Co.,Ltd. All rights reserved.
+//
+// StratoVirt is licensed under Mulan PSL v2.
+// You can use this software according to the terms and conditions of the Mulan
+// PSL v2.
+// You may obtain a copy of Mulan PSL v2 at:
+//         http://license.coscl.org.cn/MulanPSL2
+// THIS SOFTWARE IS PROVIDED ON AN "AS IS" BASIS, WITHOUT WARRANTIES OF ANY
+// KIND, EITHER EXPRESS OR IMPLIED, INCLUDING BUT NOT LIMITED TO
+// NON-INFRINGEMENT, MERCHANTABILITY OR FIT FOR A PARTICULAR PURPOSE.
+// See the Mulan PSL v2 for more details.
+
+//! Write threshold of virtio block and scsi disks.
+//!
+//! The highest offset written by the guest is tracked for every disk. Once a write goes
+//! beyond the threshold set by `block-set-write-threshold`, `BLOCK_WRITE_THRESHOLD` is
+//! emitted and the threshold is cleared, so that management can grow a thin provisioned
+//! backend before the guest runs out of space.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, Result};
+use machine_manager::event;
+use machine_manager::qmp::{qmp_schema, QmpChannel};
+use once_cell::sync::Lazy;
+
+/// Write thresholds of all realized disks, keyed by device id.
+static WRITE_THRESHOLDS: Lazy<Mutex<BTreeMap<String, Arc<WriteThreshold>>>> =
+    Lazy::new(|| Mutex::new(BTreeMap::new()));
+
+/// Write threshold of a disk, shared by its io handlers.
+pub struct WriteThreshold {
+    /// Id of the disk, reported in `BLOCK_WRITE_THRESHOLD`.
+    id: String,
+    /// Threshold in bytes, 0 means it's not set.
+    threshold: AtomicU64,
+    /// The highest offset written by the guest.
+    highest_offset: AtomicU64,
+}
+
+impl WriteThreshold {
+    pub fn new(id: &str) -> Self {
+        WriteThreshold {
+            id: id.to_string(),
+            threshold: AtomicU64::new(0),
+            highest_offset: AtomicU64::new(0),
+        }
+    }
+
+    /// Set the threshold in bytes, 0 clears it.
+    pub fn set_threshold(&self, threshold: u64) {
+        self.threshold.store(threshold, Ordering::SeqCst);
+    }
+
+    pub fn threshold(&self) -> u64 {
+        self.threshold.load(Ordering::SeqCst)
+    }
+
+    pub fn highest_offset(&self) -> u64 {
+        self.highest_offset.load(Ordering::SeqCst)
+    }
+
+    /// Account a write request which ends at `end`. Return the event to be emitted if
+    /// the request exceeds the threshold, which is cleared then.
+    fn check(&self, end: u64) -> Option<qmp_schema::BlockWriteThreshold> {
+        self.highest_offset.fetch_max(end, Ordering::SeqCst);
+        let threshold = self.threshold.load(Ordering::SeqCst);
+        if threshold == 0 || end <= threshold {
+            return None;
+        }
+        // Only the first request beyond the threshold reports it.
+        self.threshold
+            .compare_exchange(threshold, 0, Ordering::SeqCst, Ordering::SeqCst)
+            .ok()?;
+        Some(qmp_schema::BlockWriteThreshold {
+            device: self.id.clone(),
+            amount_exceeded: end - threshold,
+            write_threshold: threshold,
+        })
+    }
+
+    /// Account a write request of `len` bytes at `offset`.
+    pub fn update(&self, offset: u64, len: u64) {
+        if let Some(event) = self.check(offset.saturating_add(len)) {
+            event!(BlockWriteThreshold; event);
+        }
+    }
+}
+
+/// Register the write threshold of a disk to be set by `block-set-write-threshold`.
+pub fn register_write_threshold(write_threshold: Arc<WriteThreshold>) {
+    WRITE_THRESHOLDS
+        .lock()
+        .unwrap()
+        .insert(write_threshold.id.clone(), write_threshold);
+}
+
+pub fn unregister_write_threshold(id: &str) {
+    WRITE_THRESHOLDS.lock().unwrap().remove(id);
+}
+
+/// Get the write threshold and the highest written offset of the disk `id`.
+pub fn query_write_threshold(id: &str) -> Option<(u64, u64)> {
+    WRITE_THRESHOLDS
+        .lock()
+        .unwrap()
+        .get(id)
+        .map(|wt| (wt.threshold(), wt.highest_offset()))
+}
+
+/// Set the write threshold of the disk `id` for `block-set-write-threshold`.
+pub fn qmp_block_set_write_threshold(id: &str, threshold: u64) -> Result<()> {
+    let thresholds = WRITE_THRESHOLDS.lock().unwrap();
+    let write_threshold = thresholds
+        .get(id)
+        .ok_or_else(|| anyhow!("Disk {} is not found", id))?;
+    write_threshold.set_threshold(threshold);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_threshold() {
+        let wt = WriteThreshold::new("drive-0");
+        // No threshold is set.
+        assert!(wt.check(4096).is_none());
+        assert_eq!(wt.highest_offset(), 4096);
+
+        wt.set_threshold(1 << 20);
+        assert!(wt.check(1 << 20).is_none());
+        assert!(wt.check(512).is_none());
+        assert_eq!(wt.highest_offset(), 1 << 20);
+
+        let event = wt.check((1 << 20) + 8192).unwrap();
+        assert_eq!(event.device, "drive-0");
+        assert_eq!(event.amount_exceeded, 8192);
+        assert_eq!(event.write_threshold, 1 << 20);
+        // The event is emitted only once.
+        assert_eq!(wt.threshold(), 0);
+        assert!(wt.check((1 << 21) + 4096).is_none());
+        assert_eq!(wt.highest_offset(), (1 << 21) + 4096);
+
+        // A threshold below the highest offset is reported by the next write beyond it.
+        wt.set_threshold(1 << 20);
+        assert!(wt.check(4096).is_none());
+        assert_eq!(wt.check((1 << 20) + 1).unwrap().amount_exceeded, 1);
+    }
+
+    #[test]
+    fn test_write_threshold_registry() {
+        let wt = Arc::new(WriteThreshold::new("drive-wt"));
+        register_write_threshold(wt.clone());
+        assert!(qmp_block_set_write_threshold("drive-wt", 65536).is_ok());
+        assert!(qmp_block_set_write_threshold("drive-none", 65536).is_err());
+        wt.update(0, 4096);
+        assert_eq!(query_write_threshold("drive-wt"), Some((65536, 4096)));
+
+        unregister_write_threshold("drive-wt");
+        assert!(query_write_threshold("drive-wt").is_none());
+        assert!(qmp_block_set_write_threshold("drive-wt", 0).is_err());
+    }
+}